@@ -1,52 +1,226 @@
-use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use console::style;
 
-use vnt::handle::callback::{ConnectInfo, ErrorType};
-use vnt::{DeviceInfo, ErrorInfo, HandshakeInfo, RegisterInfo, VntCallback};
+use crate::exit::{self, ExitReason};
+use crate::seen_devices::SeenDevices;
+use crate::ui::{self, notify};
+
+use vnt::handle::callback::{
+    ConnectInfo, ErrorType, FloodInfo, HealthInfo, PeerClientInfo, ResumeInfo, ServerActionInfo,
+    SubsystemInfo,
+};
+use vnt::handle::flow_table::FlowInfo;
+use vnt::handle::server_action::Action;
+use vnt::{DeviceInfo, ErrorInfo, HandshakeInfo, NoticeInfo, RegisterInfo, VntCallback};
 
 #[derive(Clone)]
-pub struct VntHandler {}
+pub struct VntHandler {
+    // 完成网卡配置后切换到的用户和组
+    #[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+    drop_user: Option<(String, Option<String>)>,
+    dropped: Arc<AtomicBool>,
+    // 虚拟ip对应的设备历史，临时网络中不记录
+    seen_devices: Option<Arc<SeenDevices>>,
+}
+
+impl VntHandler {
+    pub fn new(drop_user: Option<(String, Option<String>)>, seen_history: bool) -> Self {
+        Self {
+            drop_user,
+            dropped: Arc::new(AtomicBool::new(false)),
+            seen_devices: seen_history
+                .then(|| Arc::new(SeenDevices::open(crate::seen_devices::path()))),
+        }
+    }
+    /// 首次注册成功时网卡地址和路由都已配置完成，此时放弃root权限
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn drop_privileges(&self) {
+        let (user, group) = match &self.drop_user {
+            Some(v) => v,
+            None => return,
+        };
+        if self.dropped.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        match crate::root_check::drop_privileges(user, group.as_deref()) {
+            Ok(_) => ui::info(format!("drop privileges, user={} ,group={:?}", user, group)),
+            Err(e) => {
+                ui::error(format!("drop privileges failed {}", e));
+                exit::set_reason(
+                    ExitReason::ConfigError,
+                    format!("drop privileges failed {}", e),
+                );
+                self.stop();
+            }
+        }
+    }
+}
 
 impl VntCallback for VntHandler {
     fn success(&self) {
-        println!(" {} ", style("====== Connect Successfully ======").green())
+        notify(format!(
+            " {} ",
+            style("====== Connect Successfully ======").green()
+        ));
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        self.drop_privileges();
     }
     fn create_tun(&self, info: DeviceInfo) {
-        println!("create_tun {}", info)
+        notify(format!("create_tun {}", info))
     }
 
     fn connect(&self, info: ConnectInfo) {
-        println!("connect {}", info)
+        notify(format!("connect {}", info))
     }
 
     fn handshake(&self, info: HandshakeInfo) -> bool {
-        println!("handshake {}", info);
+        notify(format!("handshake {}", info));
         true
     }
 
     fn register(&self, info: RegisterInfo) -> bool {
-        println!("register {}", style(info).green());
+        notify(format!("register {}", style(info).green()));
         true
     }
 
+    fn peer_client_list(&self, info: Vec<PeerClientInfo>) {
+        let seen_devices = match &self.seen_devices {
+            Some(seen_devices) => seen_devices,
+            None => return,
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let list: Vec<_> = info
+            .into_iter()
+            .map(|v| (v.virtual_ip, v.fingerprint, v.name))
+            .collect();
+        for record in seen_devices.update(now, &list) {
+            log::info!("设备分配变化 {}", record);
+        }
+    }
+
+    fn notice(&self, info: NoticeInfo) {
+        // 已经在vnt中记录日志
+        if !info.message.is_empty() {
+            notify(format!(
+                "{} {}",
+                style("[Server notice]").yellow(),
+                info.message
+            ));
+        }
+        if info.maintenance_at > 0 {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            let minutes = (info.maintenance_at - now) / 60;
+            if minutes > 0 {
+                notify(style(format!("Server maintenance in {} minutes", minutes)).yellow());
+            } else {
+                notify(style("Server maintenance in progress").yellow());
+            }
+        }
+    }
+
+    fn resumed(&self, info: ResumeInfo) {
+        // 已经在vnt中记录日志
+        notify(style(info).yellow());
+    }
+
+    fn inbound_flood(&self, info: FloodInfo) {
+        notify(style(info).yellow());
+    }
+
+    fn health(&self, info: HealthInfo) {
+        // 已经在vnt中记录日志
+        if info.report.ok() {
+            notify(style(info).green());
+        } else {
+            notify(style(info).yellow());
+        }
+    }
+
+    fn subsystem(&self, info: SubsystemInfo) {
+        // 已经在vnt中记录日志
+        notify(style(info).yellow());
+    }
+
+    fn new_flow(&self, info: FlowInfo) {
+        ui::event(format!("new inbound flow {}", info));
+    }
+
+    fn server_action(&self, info: ServerActionInfo) -> Result<String, String> {
+        notify(style(format!("server requested action {}", info)).yellow());
+        match info.action {
+            Action::SetLogLevel => set_log_level(&info.arg),
+            Action::CollectBundle => collect_bundle(),
+            _ => Err("not supported by this client".to_string()),
+        }
+    }
+
     fn error(&self, info: ErrorInfo) {
-        log::error!("error {:?}", info);
+        // 已经在vnt中记录日志
         println!("{}", style(format!("error {}", info)).red());
-        match info.code {
-            ErrorType::TokenError
-            | ErrorType::AddressExhausted
-            | ErrorType::IpAlreadyExists
-            | ErrorType::InvalidIp
-            | ErrorType::LocalIpExists => {
-                self.stop();
-            }
-            _ => {}
+        if info.code == ErrorType::LocalIpExists && self.dropped.load(Ordering::Acquire) {
+            // 放弃权限后无法重新配置网卡
+            println!(
+                "{}",
+                style("Reconfiguring the virtual NIC is unavailable after dropping privileges, please restart").red()
+            );
+        }
+        if let Some(reason) = ExitReason::from_error(info.code) {
+            exit::set_reason(reason, info.to_string());
+            self.stop();
         }
     }
 
     fn stop(&self) {
         println!("stopped");
-        process::exit(0)
+        exit::stopped()
+    }
+}
+
+/// 参数和'loglevel'命令相同，如'punch debug'、'root info'
+#[cfg(feature = "log")]
+fn set_log_level(arg: &str) -> Result<String, String> {
+    if arg.split_whitespace().count() != 2 {
+        return Err("usage: <target> <level|reset>".to_string());
+    }
+    // 成功时返回当前的设置列表，失败时返回错误说明
+    let out = crate::log_level::command(arg);
+    if out.starts_with("root = ") {
+        Ok(format!("log level set: {}", arg))
+    } else {
+        Err(out.trim().to_string())
     }
 }
+
+#[cfg(not(feature = "log"))]
+fn set_log_level(_arg: &str) -> Result<String, String> {
+    Err("built without log support".to_string())
+}
+
+/// 只在本地生成脱敏的诊断包，不上传，由用户决定是否提供给服务端管理员
+#[cfg(feature = "command")]
+fn collect_bundle() -> Result<String, String> {
+    let dir = crate::app_home().map_err(|e| format!("{}", e))?;
+    let client = crate::command::client::CommandClient::new().map_err(|e| format!("{}", e))?;
+    // 生成诊断包需要执行系统命令，不阻塞维护任务
+    std::thread::Builder::new()
+        .name("serverBundle".into())
+        .spawn(move || match client.debug_bundle() {
+            Ok(path) => log::warn!("服务端请求的诊断包 {}", path.display()),
+            Err(e) => log::warn!("服务端请求的诊断包 {:?}", e),
+        })
+        .map_err(|e| format!("{}", e))?;
+    Ok(format!("saving redacted bundle to {}", dir.display()))
+}
+
+#[cfg(not(feature = "command"))]
+fn collect_bundle() -> Result<String, String> {
+    Err("built without command support".to_string())
+}
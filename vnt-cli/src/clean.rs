@@ -0,0 +1,145 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use getopts::{Matches, Options};
+use vnt::tun_tap_device::intent_log::{self, Action, SystemRoutes};
+use vnt::util::state_store;
+
+use crate::config::numeric;
+use crate::data_dir;
+use crate::exit::{self, ExitReason};
+use crate::retention::{self, LastSeen, Retention};
+
+/// 修改系统路由前写入的日志
+pub fn path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join("os-intents"))
+}
+
+/// 'clean'子命令，列出异常退出后留在系统中的修改，'--reconcile-os'撤销它们；
+/// 列出超过保留时间的对端，'--purge-peers'清除它们保存的状态
+pub fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optflag("", "reconcile-os", "撤销残留的系统修改");
+    opts.optflag("", "purge-peers", "清除超过保留时间的对端保存的状态");
+    opts.optopt("", "peer-retention", "对端状态的保留时间", "<duration>");
+    opts.optflag("", "ephemeral-network", "临时网络的保留时间");
+    opts.optopt("", "data-dir", "数据目录", "<path>");
+    opts.optflag("h", "help", "帮助");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(program);
+            exit::config_error(f.to_string());
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(program);
+        return;
+    }
+    data_dir::init(matches.opt_str("data-dir"));
+    let path = match path() {
+        Ok(path) => path,
+        Err(e) => exit::config_error(format!("data dir unavailable: {}", e)),
+    };
+    let retention = retention_of(&matches);
+    let mut failed = !intents(&path, matches.opt_present("reconcile-os"));
+    match crate::app_home() {
+        Ok(dir) => failed |= !peers(&dir, retention, matches.opt_present("purge-peers")),
+        Err(e) => println!("data dir unavailable: {}", e),
+    }
+    if failed {
+        exit::exit(ExitReason::Runtime, "clean failed");
+    }
+}
+
+fn retention_of(matches: &Matches) -> Retention {
+    let mut report = numeric::Report::new();
+    let period = report
+        .value(
+            &numeric::PEER_RETENTION,
+            matches.opt_str("peer-retention").as_deref(),
+        )
+        .map(|v| v.0);
+    if let Err(errors) = report.finish() {
+        exit::config_error(errors);
+    }
+    Retention::new(period, matches.opt_present("ephemeral-network"))
+}
+
+/// 残留的系统修改，失败时返回false
+fn intents(path: &Path, apply: bool) -> bool {
+    let list = match intent_log::reconcile(path, &SystemRoutes, apply) {
+        Ok(list) => list,
+        Err(e) => {
+            println!("failed to read {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    if list.is_empty() {
+        println!("nothing to clean in {}", path.display());
+        return true;
+    }
+    let mut ok = true;
+    for (leftover, action) in &list {
+        ok &= !matches!(action, Action::Failed(_));
+        println!("{}: {}", leftover.intent, action);
+    }
+    ok
+}
+
+/// 超过保留时间的对端，失败时返回false
+fn peers(dir: &Path, retention: Retention, apply: bool) -> bool {
+    let now = retention::now_secs();
+    if !apply {
+        let last_seen = state_store::load_or_default::<LastSeen>(&dir.join(retention::FILE_NAME));
+        for (ip, time) in retention::expired(&last_seen, now, retention.period) {
+            println!(
+                "peer {} last seen {} days ago, '--purge-peers' removes its state",
+                ip,
+                now.saturating_sub(time) / 86400
+            );
+        }
+        return true;
+    }
+    let expired = match retention::tick(dir, &[], now, retention.period) {
+        Ok(list) => list,
+        Err(e) => {
+            println!("failed to update peer retention: {}", e);
+            return false;
+        }
+    };
+    if expired.is_empty() {
+        println!(
+            "no peer state older than {}",
+            numeric::Seconds(retention.period)
+        );
+        return true;
+    }
+    match retention::purge(dir, &expired) {
+        Ok(_) => {
+            for ip in &expired {
+                println!("peer {}: purged", ip);
+            }
+            true
+        }
+        Err(e) => {
+            println!("failed to purge peer state: {}", e);
+            false
+        }
+    }
+}
+
+fn print_usage(program: &str) {
+    println!(
+        "Usage: {} clean [--reconcile-os] [--purge-peers] [--data-dir <path>]",
+        program
+    );
+    println!();
+    println!("列出vnt异常退出后留在系统中的路由修改和超过保留时间的对端,只应在vnt没有运行时使用");
+    println!("Options:");
+    println!("  --reconcile-os       撤销残留的修改并清空记录,需要管理员权限");
+    println!("  --purge-peers        清除超过保留时间没有出现的对端保存的延迟历史、分配历史和屏蔽");
+    println!("  --peer-retention <duration> 对端状态的保留时间,默认30d,不带单位时按小时");
+    println!("  --ephemeral-network  使用临时网络的保留时间6h");
+    println!("  --data-dir <path>    数据目录,和启动vnt时相同");
+}
@@ -1,10 +1,17 @@
 use serde::Deserialize;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::str::FromStr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use vnt::util::state_store;
+
+use crate::command::entity::{
+    ConnectionList, DeviceItem, DnsStatus, DropItem, HealthStatus, Info, MetricItem, PeerStatsItem,
+    ProbeList, RouteItem, SelfTestResult, SocketList, ThreadItem,
+};
+use crate::command::server::CommandPort;
+use crate::config::profile::ConfigItem;
 
 pub struct CommandClient {
     buf: [u8; 10240],
@@ -31,15 +38,12 @@ impl CommandClient {
 }
 fn read_command_port() -> io::Result<u16> {
     let path_buf = crate::app_home()?.join("command-port");
-    let port = std::fs::read_to_string(path_buf)?;
-    match u16::from_str(&port) {
-        Ok(port) => Ok(port),
-        Err(_) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "'command-port' file error",
-            ));
-        }
+    match state_store::load::<CommandPort>(&path_buf) {
+        Some(CommandPort(port)) => Ok(port),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "'command-port' file error",
+        )),
     }
 }
 
@@ -53,6 +57,40 @@ impl CommandClient {
     pub fn info(&mut self) -> io::Result<Info> {
         self.send_cmd(b"info")
     }
+    /// 'list json'或'status json'，原样返回一行json
+    pub fn json(&self, cmd: &str) -> io::Result<String> {
+        self.send_text(cmd.as_bytes())
+    }
+    pub fn drops(&mut self) -> io::Result<Vec<DropItem>> {
+        self.send_cmd(b"stats drops")
+    }
+    pub fn health(&mut self) -> io::Result<HealthStatus> {
+        self.send_cmd(b"health")
+    }
+    pub fn dns(&mut self) -> io::Result<DnsStatus> {
+        self.send_cmd(b"dns")
+    }
+    pub fn probes(&mut self) -> io::Result<ProbeList> {
+        self.send_cmd(b"stats probes")
+    }
+    pub fn sockets(&mut self) -> io::Result<SocketList> {
+        self.send_cmd(b"stats sockets")
+    }
+    pub fn peer_stats(&mut self) -> io::Result<Vec<PeerStatsItem>> {
+        self.send_cmd(b"stats peers")
+    }
+    pub fn threads(&mut self) -> io::Result<Vec<ThreadItem>> {
+        self.send_cmd(b"stats threads")
+    }
+    pub fn metrics(&mut self) -> io::Result<Vec<MetricItem>> {
+        self.send_cmd(b"stats metrics")
+    }
+    pub fn connections(&mut self) -> io::Result<ConnectionList> {
+        self.send_cmd(b"connections")
+    }
+    pub fn config(&mut self) -> io::Result<Vec<ConfigItem>> {
+        self.send_cmd(b"info --config")
+    }
     fn send_cmd<'a, V: Deserialize<'a>>(&'a mut self, cmd: &[u8]) -> io::Result<V> {
         self.udp.send(cmd)?;
         let len = self.udp.recv(&mut self.buf)?;
@@ -64,6 +102,101 @@ impl CommandClient {
             }
         }
     }
+    pub fn block(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("block {}", target).as_bytes())
+    }
+    pub fn unblock(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("unblock {}", target).as_bytes())
+    }
+    pub fn forget(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("forget {}", target).as_bytes())
+    }
+    pub fn punch(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("punch {}", target).as_bytes())
+    }
+    pub fn invite(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("invite {}", args).as_bytes())
+    }
+    pub fn knock(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("knock {}", args).as_bytes())
+    }
+    pub fn requests(&self) -> io::Result<String> {
+        self.send_text(b"requests")
+    }
+    pub fn accept(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("accept {}", args).as_bytes())
+    }
+    pub fn diary(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("diary {}", target).as_bytes())
+    }
+    pub fn estimate(&self, target: &str) -> io::Result<String> {
+        self.send_text(format!("estimate {}", target).as_bytes())
+    }
+    /// 需要等待对端确认
+    pub fn feature(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("feature {}", args).as_bytes())
+    }
+    pub fn limit(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("limit {}", args).as_bytes())
+    }
+    pub fn prefer(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("prefer {}", args).as_bytes())
+    }
+    pub fn subsystem(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("subsystem {}", args).as_bytes())
+    }
+    /// 后台进程把脱敏的诊断包写到数据目录下，返回文件路径
+    pub fn debug_bundle(&self) -> io::Result<PathBuf> {
+        // 需要执行系统命令，比其他命令慢
+        self.udp.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let out = self.send_text(b"debug-bundle")?;
+        match out.strip_prefix("debug bundle saved to ") {
+            Some(path) => Ok(PathBuf::from(path.trim())),
+            None => Err(io::Error::new(io::ErrorKind::Other, out)),
+        }
+    }
+    /// 需要等待对端回复和发送探测包，比其他命令慢
+    pub fn bandwidth(&self, args: &str) -> io::Result<String> {
+        self.udp.set_read_timeout(Some(Duration::from_secs(30)))?;
+        self.send_text(format!("bandwidth {}", args).as_bytes())
+    }
+    /// 每个包最多等待2秒
+    pub fn ping(&self, args: &str) -> io::Result<String> {
+        let count = args
+            .split_whitespace()
+            .nth(1)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(4)
+            .min(100);
+        self.udp
+            .set_read_timeout(Some(Duration::from_secs(count * 2 + 5)))?;
+        self.send_text(format!("ping {}", args).as_bytes())
+    }
+    /// 需要等待回显，比其他命令慢
+    pub fn selftest(&mut self, target: &str) -> io::Result<SelfTestResult> {
+        self.udp.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let cmd = format!("selftest {}", target);
+        self.send_cmd(cmd.trim().as_bytes())
+    }
+    /// 口令只有和后台进程相同的用户才能读取
+    pub fn restart(&self) -> io::Result<String> {
+        let token = crate::warm_restart::read_token().map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot read the restart token: {}", e))
+        })?;
+        self.send_text(format!("restart {}", token).as_bytes())
+    }
+    pub fn telemetry(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("telemetry {}", args).as_bytes())
+    }
+    pub fn policy(&self, args: &str) -> io::Result<String> {
+        self.send_text(format!("policy {}", args).as_bytes())
+    }
+    fn send_text(&self, cmd: &[u8]) -> io::Result<String> {
+        self.udp.send(cmd)?;
+        let mut buf = [0; 10240];
+        let len = self.udp.recv(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf[..len]).to_string())
+    }
     pub fn stop(&self) -> io::Result<String> {
         self.udp.send(b"stop")?;
         let mut buf = [0; 10240];
@@ -0,0 +1,538 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use vnt::core::Vnt;
+
+/// 同时处理的连接数上限
+const MAX_CONNECTIONS: usize = 16;
+/// 请求一行的长度上限
+const MAX_LINE: u64 = 4096;
+/// 连接空闲超过这个时间关闭
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(windows)]
+const DEFAULT_PORT: u16 = 39272;
+
+/// 一行json，如{"cmd":"list"}
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Request {
+    pub cmd: String,
+}
+
+/// 一行json，成功时有data，失败时有error
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlPeer {
+    pub virtual_ip: Ipv4Addr,
+    pub name: String,
+    pub online: bool,
+    // p2p、tcp-p2p、client-relay、server-relay、relay
+    pub route: String,
+    pub p2p: bool,
+    // 毫秒，还没有测出时为空
+    pub rt: Option<i64>,
+    pub nat_type: Option<String>,
+    pub public_ips: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlStatus {
+    pub name: String,
+    pub connect_status: String,
+    pub virtual_ip: Ipv4Addr,
+    pub virtual_gateway: Ipv4Addr,
+    pub virtual_netmask: Ipv4Addr,
+    pub server: String,
+    // 和服务器之间的延迟，毫秒
+    pub server_rt: Option<i64>,
+    pub nat_type: String,
+    pub public_ips: Vec<String>,
+    pub local_ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<String>,
+    // 本地udp端口实际绑定的地址
+    #[serde(default)]
+    pub local_bind: Vec<SocketAddr>,
+    // 虚拟网卡的mtu，0表示不检查
+    #[serde(default)]
+    pub mtu: u32,
+    pub up: u64,
+    pub down: u64,
+    // 所有对端的流量合计，区分直连和中继
+    #[serde(default)]
+    pub peer_tx: u64,
+    #[serde(default)]
+    pub peer_rx: u64,
+    #[serde(default)]
+    pub peer_relay_tx: u64,
+    #[serde(default)]
+    pub peer_relay_rx: u64,
+    #[serde(default)]
+    pub peer_packets: u64,
+    // 允许服务端请求执行的操作
+    #[serde(default)]
+    pub server_actions: Vec<String>,
+}
+
+/// 'list json'的一项，只有p2p和relay两种路径，延迟和最近收到数据的时间未知时为null
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct JsonPeer {
+    pub ip: Ipv4Addr,
+    pub name: String,
+    pub route: String,
+    pub delay_ms: Option<i64>,
+    // unix时间戳，秒
+    pub last_seen: Option<u64>,
+}
+
+impl JsonPeer {
+    /// path是peer_path返回的路径，tcp-p2p也算p2p，其余都是relay
+    pub fn new(
+        ip: Ipv4Addr,
+        name: String,
+        path: &str,
+        delay_ms: Option<i64>,
+        last_seen: Option<u64>,
+    ) -> Self {
+        let route = if path.ends_with("p2p") {
+            "p2p"
+        } else {
+            "relay"
+        };
+        Self {
+            ip,
+            name,
+            route: route.to_string(),
+            delay_ms,
+            last_seen,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct JsonNat {
+    pub public_ip: Option<Ipv4Addr>,
+    pub public_port: Option<u16>,
+}
+
+/// 'status json'
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct JsonStatus {
+    pub virtual_ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub server: String,
+    pub server_rtt_ms: Option<i64>,
+    pub nat: JsonNat,
+}
+
+pub fn json_list(vnt: &Vnt) -> Vec<JsonPeer> {
+    let info = vnt.current_device();
+    let now = SystemTime::now();
+    vnt.device_list()
+        .into_iter()
+        .map(|peer| {
+            let (path, _) = crate::command::peer_path(vnt, &info, &peer.virtual_ip);
+            let delay_ms = vnt.route(&peer.virtual_ip).and_then(|v| v.measured_rt());
+            let last_seen = vnt
+                .peer_last_seen(&peer.virtual_ip)
+                .and_then(|v| now.checked_sub(v))
+                .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+                .map(|v| v.as_secs());
+            JsonPeer::new(peer.virtual_ip, peer.name, path, delay_ms, last_seen)
+        })
+        .collect()
+}
+
+pub fn json_status(vnt: &Vnt) -> JsonStatus {
+    let current_device = vnt.current_device();
+    let nat_info = vnt.nat_info();
+    JsonStatus {
+        virtual_ip: current_device.virtual_ip,
+        gateway: current_device.virtual_gateway,
+        netmask: current_device.virtual_netmask,
+        server: current_device.connect_server.to_string(),
+        server_rtt_ms: vnt
+            .route(&current_device.virtual_gateway)
+            .and_then(|v| v.measured_rt()),
+        nat: JsonNat {
+            public_ip: nat_info.public_ips.first().copied(),
+            public_port: nat_info.public_ports.first().copied().filter(|v| *v != 0),
+        },
+    }
+}
+
+/// 'list json'、'status json'的输出，一行，不带颜色，可以直接用管道处理
+pub fn json_text(cmd: &str, vnt: &Vnt) -> Option<String> {
+    let out = match cmd {
+        "list json" => serde_json::to_string(&json_list(vnt)),
+        "status json" => serde_json::to_string(&json_status(vnt)),
+        _ => return None,
+    };
+    Some(out.unwrap_or_else(|e| format!("{{\"error\":\"{}\"}}", e)))
+}
+
+pub fn control_list(vnt: &Vnt) -> Vec<ControlPeer> {
+    let info = vnt.current_device();
+    vnt.device_list()
+        .into_iter()
+        .map(|peer| {
+            let (route, rt) = crate::command::peer_path(vnt, &info, &peer.virtual_ip);
+            let nat_info = vnt.peer_nat_info(&peer.virtual_ip);
+            ControlPeer {
+                virtual_ip: peer.virtual_ip,
+                name: peer.name,
+                online: peer.status.is_online(),
+                route: route.to_string(),
+                p2p: route.ends_with("p2p"),
+                rt,
+                nat_type: nat_info.as_ref().map(|v| format!("{:?}", v.nat_type)),
+                public_ips: nat_info
+                    .map(|v| v.public_ips.iter().map(|ip| ip.to_string()).collect())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+pub fn control_status(vnt: &Vnt) -> ControlStatus {
+    let current_device = vnt.current_device();
+    let nat_info = vnt.nat_info();
+    let peer_totals = vnt.peer_totals();
+    ControlStatus {
+        name: vnt.name().to_string(),
+        connect_status: format!("{:?}", vnt.connection_status()),
+        virtual_ip: current_device.virtual_ip,
+        virtual_gateway: current_device.virtual_gateway,
+        virtual_netmask: current_device.virtual_netmask,
+        server: current_device.connect_server.to_string(),
+        server_rt: vnt
+            .route(&current_device.virtual_gateway)
+            .map(|v| v.rt)
+            .filter(|v| *v >= 0),
+        nat_type: format!("{:?}", nat_info.nat_type),
+        public_ips: nat_info.public_ips.iter().map(|v| v.to_string()).collect(),
+        local_ipv4: nat_info.local_ipv4(),
+        ipv6: nat_info.ipv6().map(|v| v.to_string()),
+        local_bind: vnt.local_bind(),
+        mtu: vnt.device_mtu(),
+        up: vnt.up_stream(),
+        down: vnt.down_stream(),
+        peer_tx: peer_totals.tx(),
+        peer_rx: peer_totals.rx(),
+        peer_relay_tx: peer_totals.tx_relay,
+        peer_relay_rx: peer_totals.rx_relay,
+        peer_packets: peer_totals.packets(),
+        server_actions: vnt
+            .allowed_server_actions()
+            .iter()
+            .map(|v| v.to_string())
+            .collect(),
+    }
+}
+
+/// 处理一行请求，返回一行json
+fn handle(line: &str, vnt: &Vnt) -> String {
+    let data = match serde_json::from_str::<Request>(line) {
+        Ok(request) => match request.cmd.as_str() {
+            "list" => serde_json::to_value(control_list(vnt)).map_err(|e| e.to_string()),
+            "status" => serde_json::to_value(control_status(vnt)).map_err(|e| e.to_string()),
+            cmd if cmd == "events" || cmd.starts_with("events ") => {
+                crate::command::events::query_json(vnt, &cmd["events".len()..])
+            }
+            cmd => Err(format!(
+                "unknown cmd '{}', available: list,status,events",
+                cmd
+            )),
+        },
+        Err(e) => Err(format!("invalid request: {}", e)),
+    };
+    response(data)
+}
+
+fn response(data: Result<Value, String>) -> String {
+    let response = match data {
+        Ok(data) => Response {
+            ok: true,
+            data: Some(data),
+            error: None,
+        },
+        Err(e) => Response {
+            ok: false,
+            data: None,
+            error: Some(e),
+        },
+    };
+    serde_json::to_string(&response)
+        .unwrap_or_else(|e| format!("{{\"ok\":false,\"error\":\"{}\"}}", e))
+}
+
+/// 一个连接上可以发多个请求，每个请求一行
+fn serve<S>(stream: S, vnt: &Vnt) -> io::Result<()>
+where
+    for<'a> &'a S: Read + Write,
+{
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = (&mut reader).take(MAX_LINE).read_line(&mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && len as u64 == MAX_LINE {
+            let out = response(Err("request too long".to_string()));
+            return (&stream).write_all(format!("{}\n", out).as_bytes());
+        }
+        let request = line.trim();
+        if request.is_empty() {
+            continue;
+        }
+        let out = handle(request, vnt);
+        (&stream).write_all(format!("{}\n", out).as_bytes())?;
+    }
+}
+
+/// 每个连接一个线程，超过上限的连接直接关闭
+fn spawn<S>(stream: S, vnt: &Vnt, active: &Arc<AtomicUsize>)
+where
+    S: Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    if active.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+        active.fetch_sub(1, Ordering::AcqRel);
+        log::warn!("控制通道连接数超过{}", MAX_CONNECTIONS);
+        return;
+    }
+    let vnt = vnt.clone();
+    let active_c = active.clone();
+    let result = std::thread::Builder::new()
+        .name("controlConn".into())
+        .spawn(move || {
+            if let Err(e) = serve(stream, &vnt) {
+                log::debug!("控制通道连接 {:?}", e);
+            }
+            active_c.fetch_sub(1, Ordering::AcqRel);
+        });
+    if let Err(e) = result {
+        active.fetch_sub(1, Ordering::AcqRel);
+        log::warn!("controlConn {:?}", e);
+    }
+}
+
+#[cfg(unix)]
+fn socket_path() -> io::Result<std::path::PathBuf> {
+    Ok(crate::app_home()?.join("control.sock"))
+}
+
+#[cfg(windows)]
+fn port_path() -> io::Result<std::path::PathBuf> {
+    Ok(crate::app_home()?.join("control-port"))
+}
+
+/// 启动本地控制通道，linux和macos上是数据目录下的control.sock，windows上是127.0.0.1的tcp端口
+#[cfg(unix)]
+pub fn start(vnt: Vnt) -> io::Result<()> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+    let path = socket_path()?;
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{:?} is used by another instance", path),
+            ));
+        }
+        // 上次退出时留下的，下次启动时删除
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    log::info!("控制通道:{:?}", path);
+    accept(listener.incoming(), vnt);
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn start(vnt: Vnt) -> io::Result<()> {
+    use std::net::TcpListener;
+    let listener = match TcpListener::bind(("127.0.0.1", DEFAULT_PORT)) {
+        Ok(listener) => listener,
+        Err(_) => TcpListener::bind("127.0.0.1:0")?,
+    };
+    let addr = listener.local_addr()?;
+    log::info!("控制通道:{:?}", addr);
+    if let Err(e) = vnt::util::state_store::save(
+        &port_path()?,
+        &crate::command::server::CommandPort(addr.port()),
+    ) {
+        log::warn!("保存控制通道端口失败：{:?}", e);
+    }
+    accept(listener.incoming(), vnt);
+    Ok(())
+}
+
+fn accept<S, I>(incoming: I, vnt: Vnt)
+where
+    I: Iterator<Item = io::Result<S>>,
+    S: Timeout + Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in incoming {
+        if vnt.is_stopped() {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = stream.idle_timeout(IDLE_TIMEOUT) {
+                    log::warn!("控制通道 {:?}", e);
+                    continue;
+                }
+                spawn(stream, &vnt, &active);
+            }
+            Err(e) => {
+                log::warn!("控制通道 {:?}", e);
+            }
+        }
+    }
+}
+
+trait Timeout {
+    fn idle_timeout(&self, dur: Duration) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl Timeout for std::os::unix::net::UnixStream {
+    fn idle_timeout(&self, dur: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(dur))?;
+        self.set_write_timeout(Some(dur))
+    }
+}
+
+impl Timeout for std::net::TcpStream {
+    fn idle_timeout(&self, dur: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(dur))?;
+        self.set_write_timeout(Some(dur))
+    }
+}
+
+/// 连接正在运行的实例，发送一个请求，返回一行json
+pub fn query(cmd: &str) -> io::Result<String> {
+    let request = serde_json::to_string(&Request {
+        cmd: cmd.to_string(),
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    #[cfg(unix)]
+    let stream = std::os::unix::net::UnixStream::connect(socket_path()?)?;
+    #[cfg(windows)]
+    let stream = {
+        let port = match vnt::util::state_store::load::<crate::command::server::CommandPort>(
+            &port_path()?,
+        ) {
+            Some(port) => port.0,
+            None => DEFAULT_PORT,
+        };
+        std::net::TcpStream::connect(("127.0.0.1", port))?
+    };
+    stream.idle_timeout(Duration::from_secs(5))?;
+    (&stream).write_all(format!("{}\n", request).as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .take(16 << 20)
+        .read_line(&mut line)?;
+    Ok(line)
+}
+
+/// '--control <cmd>'，输出格式化的json
+pub fn command(cmd: &str) {
+    let line = match query(cmd) {
+        Ok(line) => line,
+        Err(e) => {
+            println!("control: {:?}", e);
+            return;
+        }
+    };
+    match serde_json::from_str::<Response>(&line) {
+        Ok(response) => match (response.data, response.error) {
+            (Some(data), _) if response.ok => println!(
+                "{}",
+                serde_json::to_string_pretty(&data).unwrap_or_else(|e| e.to_string())
+            ),
+            (_, error) => println!("error {}", error.unwrap_or_default()),
+        },
+        Err(e) => println!("invalid response {:?}: {}", line.trim(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::command::control::{response, JsonNat, JsonPeer, JsonStatus, Request, Response};
+
+    #[test]
+    fn test_protocol() {
+        let request: Request = serde_json::from_str(r#"{"cmd":"list"}"#).unwrap();
+        assert_eq!(request.cmd, "list");
+        assert!(serde_json::from_str::<Request>(r#"{"command":"list"}"#).is_err());
+
+        let ok = response(Ok(json!([{"virtual_ip": "10.26.0.2"}])));
+        assert_eq!(ok, r#"{"ok":true,"data":[{"virtual_ip":"10.26.0.2"}]}"#);
+        let err: Response = serde_json::from_str(&response(Err("unknown".into()))).unwrap();
+        assert!(!err.ok);
+        assert_eq!(err.error.as_deref(), Some("unknown"));
+        assert!(err.data.is_none());
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let list = vec![
+            JsonPeer::new(
+                "10.26.0.2".parse().unwrap(),
+                "a".into(),
+                "tcp-p2p",
+                Some(12),
+                Some(1700000000),
+            ),
+            JsonPeer::new(
+                "10.26.0.3".parse().unwrap(),
+                "b".into(),
+                "server-relay(tcp)",
+                None,
+                None,
+            ),
+        ];
+        assert_eq!(
+            serde_json::to_string(&list).unwrap(),
+            r#"[{"ip":"10.26.0.2","name":"a","route":"p2p","delay_ms":12,"last_seen":1700000000},{"ip":"10.26.0.3","name":"b","route":"relay","delay_ms":null,"last_seen":null}]"#
+        );
+        let status = JsonStatus {
+            virtual_ip: "10.26.0.2".parse().unwrap(),
+            gateway: "10.26.0.1".parse().unwrap(),
+            netmask: "255.255.255.0".parse().unwrap(),
+            server: "1.2.3.4:29872".into(),
+            server_rtt_ms: None,
+            nat: JsonNat {
+                public_ip: None,
+                public_port: None,
+            },
+        };
+        let text = serde_json::to_string(&status).unwrap();
+        assert!(!text.contains('\x1b') && !text.contains('\n'));
+        assert!(
+            text.contains(r#""server_rtt_ms":null,"nat":{"public_ip":null,"public_port":null}"#)
+        );
+        assert_eq!(serde_json::from_str::<JsonStatus>(&text).unwrap(), status);
+    }
+}
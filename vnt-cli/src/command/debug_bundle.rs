@@ -0,0 +1,618 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use vnt::core::Vnt;
+
+/// 每个日志文件最多收集的字节数
+const LOG_TAIL_SIZE: u64 = 64 * 1024;
+
+/// 诊断包包含的文件，生成前会列出来让用户确认
+pub const BUNDLE_CONTENTS: [(&str, &str); 19] = [
+    (
+        "version.txt",
+        "vnt version, serial number and protocol versions",
+    ),
+    ("config.txt", "effective configuration"),
+    ("info.yaml", "output of 'info'"),
+    ("route.yaml", "output of 'route'"),
+    ("list.yaml", "output of 'list' (known peers and candidates)"),
+    ("drops.yaml", "output of 'stats drops'"),
+    ("metrics.yaml", "output of 'stats metrics'"),
+    ("probes.yaml", "output of 'stats probes'"),
+    ("sockets.yaml", "output of 'stats sockets'"),
+    ("threads.yaml", "output of 'stats threads'"),
+    ("health.yaml", "output of 'health'"),
+    ("dns.yaml", "output of 'dns'"),
+    (
+        "nat.txt",
+        "NAT detection result and peers without a direct path",
+    ),
+    ("mtu.txt", "path MTU blackhole incidents and clamped MSS"),
+    (
+        "diary.txt",
+        "per-peer connection events (punches, path switches, evictions)",
+    ),
+    (
+        "events.txt",
+        "in-memory event history of all peers, oldest first",
+    ),
+    (
+        "punch.txt",
+        "punch events of the last hour and punch results by NAT pair",
+    ),
+    ("system.txt", "OS, network interfaces and routing table"),
+    ("vnt.log", "last 64 KiB of each log file"),
+];
+
+/// 诊断包的脱敏规则
+///
+/// 开启时替换token、密码，以及虚拟网段以外的ip地址；
+/// 回环、广播、组播和掩码这类不能定位到设备的地址保留
+pub struct Redactor {
+    enabled: bool,
+    secrets: Vec<String>,
+    // 虚拟网段，(网络号,掩码)
+    overlay: Option<(u32, u32)>,
+}
+
+impl Redactor {
+    pub fn new(vnt: &Vnt, enabled: bool) -> Self {
+        let config = vnt.config();
+        let mut secrets = vec![config.token.clone()];
+        if let Some(password) = &config.password {
+            secrets.push(password.clone());
+        }
+        let current_device = vnt.current_device();
+        Self::with_secrets(
+            enabled,
+            secrets,
+            current_device.virtual_network,
+            current_device.virtual_netmask,
+        )
+    }
+    pub fn with_secrets(
+        enabled: bool,
+        mut secrets: Vec<String>,
+        network: Ipv4Addr,
+        netmask: Ipv4Addr,
+    ) -> Self {
+        secrets.retain(|v| !v.is_empty());
+        // 先替换长的，避免一个密钥是另一个的子串时残留
+        secrets.sort_by(|a, b| b.len().cmp(&a.len()));
+        let overlay = if network.is_unspecified() {
+            None
+        } else {
+            let mask = u32::from(netmask);
+            Some((u32::from(network) & mask, mask))
+        };
+        Self {
+            enabled,
+            secrets,
+            overlay,
+        }
+    }
+    pub fn redact(&self, text: &str) -> String {
+        if !self.enabled {
+            return text.to_string();
+        }
+        let mut text = text.to_string();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), "<redacted>");
+        }
+        self.redact_ips(&text)
+    }
+    fn redact_ips(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut start = None;
+        for (i, c) in text.char_indices() {
+            if c.is_ascii_hexdigit() || c == '.' || c == ':' {
+                if start.is_none() {
+                    start = Some(i);
+                }
+            } else {
+                if let Some(start) = start.take() {
+                    self.redact_word(&text[start..i], &mut out);
+                }
+                out.push(c);
+            }
+        }
+        if let Some(start) = start {
+            self.redact_word(&text[start..], &mut out);
+        }
+        out
+    }
+    fn redact_word(&self, word: &str, out: &mut String) {
+        if word.matches(':').count() >= 2 {
+            if let Ok(ip) = Ipv6Addr::from_str(word) {
+                if ip.is_loopback() || ip.is_unspecified() {
+                    out.push_str(word);
+                } else {
+                    out.push_str("<ipv6>");
+                }
+                return;
+            }
+        }
+        // ip:port 的形式
+        for (i, part) in word.split(':').enumerate() {
+            if i > 0 {
+                out.push(':');
+            }
+            let ip_str = part.trim_end_matches('.');
+            match Ipv4Addr::from_str(ip_str) {
+                Ok(ip) => {
+                    out.push_str(&self.redact_ipv4(ip, ip_str));
+                    out.push_str(&part[ip_str.len()..]);
+                }
+                Err(_) => out.push_str(part),
+            }
+        }
+    }
+    fn redact_ipv4(&self, ip: Ipv4Addr, origin: &str) -> String {
+        if ip.is_unspecified() || ip.is_loopback() || ip.is_broadcast() || ip.is_multicast() {
+            return origin.to_string();
+        }
+        let v = u32::from(ip);
+        // 掩码
+        if (!v).wrapping_add(1) & !v == 0 {
+            return origin.to_string();
+        }
+        if let Some((network, mask)) = self.overlay {
+            if v & mask == network {
+                return origin.to_string();
+            }
+        }
+        if ip.is_private() || ip.is_link_local() {
+            "<private-ip>".to_string()
+        } else {
+            "<public-ip>".to_string()
+        }
+    }
+}
+
+/// 诊断包，添加的内容都会先经过脱敏
+pub struct Bundle {
+    redactor: Redactor,
+    entries: Vec<(String, String)>,
+}
+
+impl Bundle {
+    pub fn new(redactor: Redactor) -> Self {
+        Self {
+            redactor,
+            entries: Vec::new(),
+        }
+    }
+    pub fn add(&mut self, name: &str, text: &str) {
+        self.entries
+            .push((name.to_string(), self.redactor.redact(text)));
+    }
+    pub fn write<W: Write + Seek>(&self, writer: W) -> io::Result<W> {
+        let mut zip = zip::ZipWriter::new(writer);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, text) in &self.entries {
+            zip.start_file(name.as_str(), options)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+            zip.write_all(text.as_bytes())?;
+        }
+        zip.finish()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
+
+/// 解析'[--no-redact] [path]'，返回(是否脱敏,路径)
+pub fn parse_args(args: &str) -> (bool, &str) {
+    let args = args.trim();
+    match args.strip_prefix("--no-redact") {
+        Some(path) => (false, path.trim()),
+        None => (true, args),
+    }
+}
+
+/// 默认的诊断包文件名
+pub fn default_path() -> PathBuf {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0);
+    PathBuf::from(format!("vnt-debug-{}.zip", secs))
+}
+
+/// 转换成绝对路径，后台运行时诊断包由后台进程写入，工作目录可能不同
+pub fn absolute_path(path: &str) -> io::Result<PathBuf> {
+    let path = if path.is_empty() {
+        default_path()
+    } else {
+        PathBuf::from(path)
+    };
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+/// 列出诊断包的内容，用户确认后返回true
+pub fn confirm(path: &Path, redact: bool) -> bool {
+    println!("The debug bundle {} will include:", path.display());
+    for (name, describe) in BUNDLE_CONTENTS {
+        println!("  {:<12} {}", name, describe);
+    }
+    if redact {
+        println!(
+            "Tokens, passwords and IP addresses outside the virtual network will be redacted."
+        );
+    } else {
+        println!("WARNING: '--no-redact' is set, tokens, passwords and IP addresses are kept.");
+    }
+    print!("Continue? [y/N] ");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return false;
+    }
+    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// 收集诊断信息并写入path
+pub fn create(vnt: &Vnt, path: &Path, redact: bool) -> io::Result<()> {
+    let redactor = Redactor::new(vnt, redact);
+    let mut bundle = Bundle::new(redactor);
+    bundle.add("version.txt", &version_text());
+    bundle.add("config.txt", &config_text(vnt, redact));
+    let mut info = crate::command::command_info(vnt);
+    let mut list = crate::command::command_list(vnt);
+    if redact {
+        // 点对网的网段以数字保存，无法按文本脱敏
+        info.in_ips.clear();
+        info.out_ips.clear();
+        for item in list.iter_mut() {
+            item.client_secret_hash.clear();
+            item.current_client_secret_hash.clear();
+        }
+    }
+    bundle.add("info.yaml", &to_yaml(&info));
+    bundle.add("route.yaml", &to_yaml(&crate::command::command_route(vnt)));
+    bundle.add("list.yaml", &to_yaml(&list));
+    bundle.add("drops.yaml", &to_yaml(&crate::command::command_drops(vnt)));
+    bundle.add(
+        "metrics.yaml",
+        &to_yaml(&crate::command::command_metrics(vnt)),
+    );
+    bundle.add(
+        "probes.yaml",
+        &to_yaml(&crate::command::command_probes(vnt)),
+    );
+    bundle.add(
+        "sockets.yaml",
+        &to_yaml(&crate::command::command_sockets(vnt)),
+    );
+    bundle.add(
+        "threads.yaml",
+        &to_yaml(&crate::command::command_threads(vnt)),
+    );
+    bundle.add(
+        "health.yaml",
+        &to_yaml(&crate::command::command_health(vnt)),
+    );
+    bundle.add("dns.yaml", &to_yaml(&crate::command::command_dns(vnt)));
+    bundle.add("nat.txt", &nat_text(vnt));
+    bundle.add("mtu.txt", &mtu_text(vnt));
+    bundle.add("diary.txt", &diary_text(vnt));
+    bundle.add("events.txt", &crate::command::events::dump(vnt));
+    bundle.add("punch.txt", &punch_text(vnt));
+    bundle.add("system.txt", &system_text());
+    bundle.add("vnt.log", &log_text());
+    // 不覆盖已有的文件
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    bundle.write(file)?.sync_all()
+}
+
+/// 命令端口上的请求，任何本地用户都能发送，所以总是脱敏，并且只写到数据目录下的新文件，
+/// 返回文件路径，由客户端复制到用户指定的位置
+pub fn create_remote(vnt: &Vnt) -> io::Result<PathBuf> {
+    let path = crate::app_home()?.join(default_path());
+    create(vnt, &path, true)?;
+    Ok(path)
+}
+
+/// 复制后台进程生成的诊断包，不覆盖已有的文件
+pub fn copy_to(from: &Path, to: &Path) -> io::Result<()> {
+    if from != to {
+        let mut reader = std::fs::File::open(from)?;
+        let mut writer = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.sync_all()?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn to_yaml<T: serde::Serialize>(val: &T) -> String {
+    serde_yaml::to_string(val).unwrap_or_else(|e| format!("error {:?}", e))
+}
+
+fn version_text() -> String {
+    format!(
+        "version: {}\nserial: {}\nprotocol: {:?},{:?}\nos: {}\n",
+        vnt::VNT_VERSION,
+        crate::generated_serial_number::SERIAL_NUMBER,
+        vnt::protocol::Version::V2,
+        vnt::protocol::Version::V3,
+        os_info::get()
+    )
+}
+
+fn config_text(vnt: &Vnt, redact: bool) -> String {
+    let mut config = vnt.config().clone();
+    let mut text = String::new();
+    if redact {
+        text.push_str(&format!(
+            "in_ips: {} entries\nout_ips: {} entries\n",
+            config.in_ips.len(),
+            config.out_ips.len()
+        ));
+        config.in_ips.clear();
+        config.out_ips.clear();
+    }
+    text.push_str(&format!("{:#?}\n", config));
+    text
+}
+
+fn nat_text(vnt: &Vnt) -> String {
+    let mut text = format!("{:#?}\n", vnt.nat_info());
+    for (ip, evidence) in vnt.no_direct_list() {
+        text.push_str(&format!("no direct path {}: {}\n", ip, evidence));
+    }
+    text
+}
+
+fn mtu_text(vnt: &Vnt) -> String {
+    let incidents = vnt.mtu_incidents();
+    if incidents.is_empty() {
+        return "none\n".to_string();
+    }
+    let mut text = String::new();
+    for incident in incidents {
+        text.push_str(&format!("{} {}\n", incident.time, incident));
+    }
+    text
+}
+
+fn diary_text(vnt: &Vnt) -> String {
+    let peers = vnt.diary_peers();
+    if peers.is_empty() {
+        return "none\n".to_string();
+    }
+    let mut text = String::new();
+    for ip in peers {
+        text.push_str(&format!("==== {} ====\n", ip));
+        text.push_str(&crate::command::diary_lines(vnt, &ip));
+    }
+    text
+}
+
+/// 最近一小时的打洞事件，以及按nat组合累计的打洞结果
+fn punch_text(vnt: &Vnt) -> String {
+    let since = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as u64)
+        .saturating_sub(3_600_000);
+    let filter = vnt::channel::diary::EventFilter {
+        since: Some(since),
+        ..Default::default()
+    };
+    let mut text = String::from("# last hour\n");
+    for entry in vnt.events(&filter) {
+        if entry.event.kind().starts_with("Punch") {
+            text.push_str(&format!(
+                "{}.{:03} {} {}\n",
+                crate::seen_devices::utc_time(entry.time / 1000),
+                entry.time % 1000,
+                entry.peer,
+                entry.event
+            ));
+        }
+    }
+    text.push_str("# by NAT pair (local-remote attempts successes)\n");
+    for record in vnt.punch_records() {
+        text.push_str(&format!(
+            "{:?}-{:?} {} {}\n",
+            record.local, record.remote, record.stats.attempts, record.stats.successes
+        ));
+    }
+    text
+}
+
+fn system_text() -> String {
+    #[cfg(target_os = "linux")]
+    let commands = ["ip addr", "ip route", "ip rule"];
+    #[cfg(target_os = "macos")]
+    let commands = ["ifconfig", "netstat -rn -f inet", "scutil --dns"];
+    #[cfg(target_os = "windows")]
+    let commands = [
+        "ipconfig /all",
+        "route print -4",
+        "netsh interface ipv4 show subinterfaces",
+    ];
+    let mut text = String::new();
+    for cmd in commands {
+        text.push_str(&format!("==== {} ====\n", cmd));
+        #[cfg(target_os = "windows")]
+        let output = Command::new("cmd").arg("/C").arg(cmd).output();
+        #[cfg(not(target_os = "windows"))]
+        let output = Command::new("sh").arg("-c").arg(cmd).output();
+        match output {
+            Ok(output) => {
+                text.push_str(&String::from_utf8_lossy(&output.stdout));
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+            }
+            Err(e) => text.push_str(&format!("error {:?}\n", e)),
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// 从'--log-path'或log4rs.yaml中找到日志文件，读取末尾的内容
+fn log_text() -> String {
+    let paths = match log_paths() {
+        Ok(paths) => paths,
+        Err(e) => return format!("log4rs.yaml: {:?}\n", e),
+    };
+    let mut text = String::new();
+    for path in paths {
+        text.push_str(&format!("==== {} ====\n", path));
+        match tail(Path::new(&path), LOG_TAIL_SIZE) {
+            Ok(v) => text.push_str(&v),
+            Err(e) => text.push_str(&format!("error {:?}\n", e)),
+        }
+    }
+    text
+}
+
+fn log_paths() -> io::Result<Vec<String>> {
+    #[cfg(feature = "log")]
+    if let Some(path) = crate::log_file::file() {
+        return Ok(vec![path.display().to_string()]);
+    }
+    let path = crate::data_dir::log_config()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "log4rs.yaml not found"))?;
+    let conf = std::fs::read_to_string(path)?;
+    let conf: serde_yaml::Value = serde_yaml::from_str(&conf)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    let mut paths = Vec::new();
+    if let Some(appenders) = conf.get("appenders").and_then(|v| v.as_mapping()) {
+        for (_, appender) in appenders {
+            if let Some(path) = appender.get("path").and_then(|v| v.as_str()) {
+                paths.push(path.to_string());
+            }
+        }
+    }
+    Ok(paths)
+}
+
+fn tail(path: &Path, size: u64) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let skip = len.saturating_sub(size);
+    file.seek(SeekFrom::Start(skip))?;
+    let mut buf = Vec::with_capacity((len - skip) as usize);
+    file.read_to_end(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    if skip > 0 {
+        // 丢弃不完整的第一行
+        if let Some(index) = text.find('\n') {
+            return Ok(text[index + 1..].to_string());
+        }
+    }
+    Ok(text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::net::Ipv4Addr;
+
+    use crate::command::debug_bundle::{copy_to, Bundle, Redactor};
+
+    fn redactor() -> Redactor {
+        Redactor::with_secrets(
+            true,
+            vec!["my-token".to_string(), "secret".to_string(), "".to_string()],
+            Ipv4Addr::new(10, 26, 0, 0),
+            Ipv4Addr::new(255, 255, 255, 0),
+        )
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let r = redactor();
+        assert_eq!(
+            r.redact("token: \"my-token\", password: Some(\"secret\")"),
+            "token: \"<redacted>\", password: Some(\"<redacted>\")"
+        );
+        let r = Redactor::with_secrets(
+            false,
+            vec!["my-token".to_string()],
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+        );
+        assert_eq!(r.redact("my-token 8.8.8.8"), "my-token 8.8.8.8");
+    }
+
+    #[test]
+    fn test_redact_ips() {
+        let r = redactor();
+        // 虚拟网段、掩码、回环地址保留
+        assert_eq!(
+            r.redact("10.26.0.2/255.255.255.0 127.0.0.1 0.0.0.0"),
+            "10.26.0.2/255.255.255.0 127.0.0.1 0.0.0.0"
+        );
+        assert_eq!(
+            r.redact("server=8.8.8.8:29872,local=192.168.1.5."),
+            "server=<public-ip>:29872,local=<private-ip>."
+        );
+        assert_eq!(r.redact("[240e:1:2::3]:80 ::1"), "[<ipv6>]:80 ::1");
+        // 不是ip的内容不变
+        assert_eq!(
+            r.redact("2024-05-01 12:30:45.123 version 1.2.9 cafe"),
+            "2024-05-01 12:30:45.123 version 1.2.9 cafe"
+        );
+    }
+
+    #[test]
+    fn test_bundle_write() {
+        let mut bundle = Bundle::new(redactor());
+        bundle.add("config.txt", "token=my-token server=1.1.1.1:29872");
+        bundle.add("info.yaml", "virtual_ip: 10.26.0.2");
+        let cursor = bundle.write(Cursor::new(Vec::new())).unwrap();
+        let mut archive = zip::ZipArchive::new(cursor).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut all = String::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            let mut text = String::new();
+            file.read_to_string(&mut text).unwrap();
+            all.push_str(&text);
+        }
+        assert!(!all.contains("my-token"));
+        assert!(!all.contains("1.1.1.1"));
+        assert!(all.contains("10.26.0.2"));
+        let mut config = String::new();
+        archive
+            .by_name("config.txt")
+            .unwrap()
+            .read_to_string(&mut config)
+            .unwrap();
+        assert_eq!(config, "token=<redacted> server=<public-ip>:29872");
+    }
+
+    #[test]
+    fn test_copy_no_overwrite() {
+        let dir = std::env::temp_dir().join(format!("vnt-bundle-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let from = dir.join("from.zip");
+        let to = dir.join("to.zip");
+        std::fs::write(&from, b"bundle").unwrap();
+        std::fs::write(&to, b"keep").unwrap();
+        assert!(copy_to(&from, &to).is_err());
+        assert_eq!(std::fs::read(&to).unwrap(), b"keep");
+        std::fs::remove_file(&to).unwrap();
+        copy_to(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"bundle");
+        assert!(!from.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
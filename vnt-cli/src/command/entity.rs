@@ -9,11 +9,56 @@ pub struct Info {
     pub connect_status: String,
     pub relay_server: String,
     pub nat_type: String,
+    // nat类型对直连的影响
+    #[serde(default)]
+    pub nat_hint: String,
     pub public_ips: String,
     pub local_addr: String,
     pub ipv6_addr: String,
+    // 本地udp端口绑定的地址，多个用逗号分隔
+    #[serde(default)]
+    pub local_bind: String,
+    // 虚拟网卡的mtu，0表示不检查
+    #[serde(default)]
+    pub mtu: u32,
     pub up: u64,
     pub down: u64,
+    #[serde(default)]
+    pub legacy_peers: usize,
+    #[serde(default)]
+    pub blocked_peers: usize,
+    #[serde(default)]
+    pub effective_uid: Option<u32>,
+    #[serde(default)]
+    pub notice: String,
+    // 是否在心跳中上报中继流量
+    #[serde(default)]
+    pub report_usage: bool,
+    #[serde(default)]
+    pub relay_tx: u64,
+    #[serde(default)]
+    pub relay_rx: u64,
+    // 所有对端的流量合计
+    #[serde(default)]
+    pub peer_tx: u64,
+    #[serde(default)]
+    pub peer_rx: u64,
+    #[serde(default)]
+    pub peer_packets: u64,
+    #[serde(default)]
+    pub peer_rate: u64,
+    // 允许服务端请求执行的操作，逗号分隔，为空时全部拒绝
+    #[serde(default)]
+    pub server_actions: String,
+    // 路由器上的端口映射，没有开启时为空
+    #[serde(default)]
+    pub upnp: String,
+    // 入站改写过的包的校验和处理
+    #[serde(default)]
+    pub tun_checksum: String,
+    // 注册使用的设备标识和来源
+    #[serde(default)]
+    pub device_id: String,
     pub port_mapping_list: Vec<(bool, SocketAddr, String)>,
     pub in_ips: Vec<(u32, u32, Ipv4Addr)>,
     pub out_ips: Vec<(u32, u32)>,
@@ -26,9 +71,27 @@ pub struct RouteItem {
     pub metric: String,
     pub rt: String,
     pub interface: String,
+    // 无法直连的判定依据
+    #[serde(default)]
+    pub direct: String,
+    // 手动调整的压缩/加密
+    #[serde(default)]
+    pub feature: String,
+    // 建立直连的过程和耗时
+    #[serde(default)]
+    pub bring_up: String,
+    // 测得的带宽
+    #[serde(default)]
+    pub bandwidth: String,
+    // 路径分数，越低越好，没有设置路径权重时为空
+    #[serde(default)]
+    pub score: String,
+    // 生效的路径偏好和权重
+    #[serde(default)]
+    pub weights: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DeviceItem {
     pub name: String,
     pub virtual_ip: String,
@@ -43,4 +106,141 @@ pub struct DeviceItem {
     pub client_secret_hash: Vec<u8>,
     pub current_client_secret: bool,
     pub current_client_secret_hash: Vec<u8>,
+    #[serde(default)]
+    pub blocked: bool,
+    // 设备指纹，对端没有公开时为空
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricItem {
+    pub name: String,
+    pub labels: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionList {
+    pub flow_tracking: bool,
+    pub list: Vec<ConnectionItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionItem {
+    pub peer: String,
+    pub protocol: String,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub bytes: u64,
+    pub packets: u64,
+    pub recent_bytes: u64,
+    pub idle_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthStatus {
+    pub live: bool,
+    pub live_failed: Vec<String>,
+    pub ready: bool,
+    pub ready_failed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SelfTestResult {
+    pub passed: bool,
+    pub stages: Vec<SelfTestStage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SelfTestStage {
+    pub name: String,
+    pub ok: bool,
+    // 毫秒
+    pub elapsed: f64,
+    pub detail: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProbeList {
+    pub budget_kbps: u64,
+    pub probes: Vec<ProbeItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProbeItem {
+    pub kind: String,
+    pub priority: String,
+    pub sent: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocketList {
+    pub limit: usize,
+    pub sockets: Vec<SocketItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocketItem {
+    pub purpose: String,
+    pub local_port: u16,
+    pub age_secs: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerStatsItem {
+    pub virtual_ip: Ipv4Addr,
+    pub name: String,
+    pub rx: u64,
+    pub tx: u64,
+    // 经过服务器中继的部分
+    pub rx_relay: u64,
+    pub tx_relay: u64,
+    pub packets: u64,
+    // 上一个采样间隔的速率，字节/秒
+    pub rx_rate: u64,
+    pub tx_rate: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadItem {
+    pub name: String,
+    pub worker: String,
+    pub tid: u64,
+    pub core: Option<usize>,
+    pub priority: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DnsStatus {
+    pub enabled: bool,
+    pub listen: String,
+    pub upstream: Vec<String>,
+    pub routes: Vec<DnsRouteItem>,
+    pub cache_size: usize,
+    pub cache_limit: usize,
+    pub queries: u64,
+    pub cache_hits: u64,
+    pub local: u64,
+    pub servfail: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DnsRouteItem {
+    pub suffix: String,
+    pub server: String,
+    pub hits: u64,
+    pub failures: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DropItem {
+    pub reason: String,
+    pub count: u64,
+    pub peers: Vec<(String, u64)>,
+    pub explain: String,
 }
@@ -0,0 +1,248 @@
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use vnt::channel::diary::{DiaryEvent, EventFilter, HistoryEntry};
+use vnt::core::Vnt;
+
+use crate::config::numeric::{Numeric, Seconds};
+
+const USAGE: &str = "usage: events [--since <10m>] [--peer <ip|name>] [--type <type>] [--json]";
+
+/// 'events --json'和控制通道返回的一项
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct EventItem {
+    // unix时间，毫秒
+    pub time: u64,
+    pub peer: Ipv4Addr,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub message: String,
+}
+
+impl From<HistoryEntry> for EventItem {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            time: entry.time,
+            peer: entry.peer,
+            kind: entry.event.kind().to_string(),
+            message: entry.event.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Query {
+    pub since: Option<Duration>,
+    pub peer: Option<String>,
+    pub kind: Option<&'static str>,
+    pub json: bool,
+}
+
+/// 解析'events'的参数，不带单位的时长按分钟
+pub fn parse(args: &str) -> Result<Query, String> {
+    let mut query = Query::default();
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--json" => query.json = true,
+            "--since" | "--peer" | "--type" => {
+                let value = args.next().ok_or_else(|| USAGE.to_string())?;
+                match arg {
+                    "--since" => {
+                        let since =
+                            Seconds::parse(value, 60_000).map_err(|e| format!("--since {}", e))?;
+                        query.since = Some(since.0);
+                    }
+                    "--peer" => query.peer = Some(value.to_string()),
+                    _ => {
+                        query.kind = Some(DiaryEvent::parse_kind(value).ok_or_else(|| {
+                            format!(
+                                "unknown event type '{}', available: {}",
+                                value,
+                                DiaryEvent::KINDS.join(",")
+                            )
+                        })?)
+                    }
+                }
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(query)
+}
+
+fn filter(vnt: &Vnt, query: &Query) -> Result<EventFilter, String> {
+    let peer = match &query.peer {
+        Some(target) => Some(super::find_peer(vnt, target)?),
+        None => None,
+    };
+    let since = query.since.map(|since| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_millis() as u64);
+        now.saturating_sub(since.as_millis() as u64)
+    });
+    Ok(EventFilter {
+        since,
+        peer,
+        kind: query.kind,
+    })
+}
+
+fn items(vnt: &Vnt, query: &Query) -> Result<Vec<EventItem>, String> {
+    let filter = filter(vnt, query)?;
+    Ok(vnt
+        .events(&filter)
+        .into_iter()
+        .map(EventItem::from)
+        .collect())
+}
+
+/// 每行一个事件，时间为UTC
+fn lines(items: &[EventItem]) -> String {
+    if items.is_empty() {
+        return "no events\n".to_string();
+    }
+    let mut text = String::new();
+    for item in items {
+        text.push_str(&format!(
+            "{}.{:03} {} {} {}\n",
+            crate::seen_devices::utc_time(item.time / 1000),
+            item.time % 1000,
+            item.peer,
+            item.kind,
+            item.message
+        ));
+    }
+    text
+}
+
+fn render(items: &[EventItem], json: bool) -> String {
+    if json {
+        let text = serde_json::to_string(items).unwrap_or_else(|e| format!("error {}", e));
+        format!("{}\n", text)
+    } else {
+        lines(items)
+    }
+}
+
+/// 控制台的'events'命令
+pub fn command_events(vnt: &Vnt, args: &str) -> String {
+    let query = match parse(args) {
+        Ok(query) => query,
+        Err(e) => return format!("{}\n", e),
+    };
+    match items(vnt, &query) {
+        Ok(items) => render(&items, query.json),
+        Err(e) => format!("{}\n", e),
+    }
+}
+
+/// 控制通道的{"cmd":"events ..."}，总是返回json
+pub fn query_json(vnt: &Vnt, args: &str) -> Result<Value, String> {
+    let query = parse(args)?;
+    serde_json::to_value(items(vnt, &query)?).map_err(|e| e.to_string())
+}
+
+/// '--events <args>'，通过控制通道查询正在运行的实例
+pub fn command(args: &str) {
+    let json = match parse(args) {
+        Ok(query) => query.json,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let line = match super::control::query(&format!("events {}", args)) {
+        Ok(line) => line,
+        Err(e) => {
+            println!("control: {:?}", e);
+            return;
+        }
+    };
+    let response = match serde_json::from_str::<super::control::Response>(&line) {
+        Ok(response) => response,
+        Err(e) => {
+            println!("invalid response {:?}: {}", line.trim(), e);
+            return;
+        }
+    };
+    match (response.data, response.error) {
+        (Some(data), _) if response.ok => match serde_json::from_value::<Vec<EventItem>>(data) {
+            Ok(items) => print!("{}", render(&items, json)),
+            Err(e) => println!("invalid response: {}", e),
+        },
+        (_, error) => println!("error {}", error.unwrap_or_default()),
+    }
+}
+
+/// 诊断包中的完整事件历史
+pub fn dump(vnt: &Vnt) -> String {
+    let items: Vec<EventItem> = vnt
+        .events(&EventFilter::default())
+        .into_iter()
+        .map(EventItem::from)
+        .collect();
+    lines(&items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use vnt::channel::diary::{DiaryEvent, HistoryEntry};
+
+    use super::{parse, render, EventItem, Query};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap(), Query::default());
+        assert_eq!(
+            parse("--since 10m --peer 10.26.0.4 --type pathswitch --json").unwrap(),
+            Query {
+                since: Some(Duration::from_secs(600)),
+                peer: Some("10.26.0.4".to_string()),
+                kind: Some("PathSwitch"),
+                json: true,
+            }
+        );
+        // 不带单位按分钟
+        assert_eq!(
+            parse("--since 5").unwrap().since,
+            Some(Duration::from_secs(300))
+        );
+        assert!(parse("--type PathChanged")
+            .unwrap_err()
+            .starts_with("unknown event type 'PathChanged'"));
+        assert!(parse("--peer").unwrap_err().starts_with("usage"));
+        assert!(parse("--since 1x").is_err());
+        assert!(parse("10.26.0.4").unwrap_err().starts_with("usage"));
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(&[], false), "no events\n");
+        assert_eq!(render(&[], true), "[]\n");
+        let items: Vec<EventItem> = vec![EventItem::from(HistoryEntry {
+            time: 1_700_000_000_123,
+            peer: "10.26.0.4".parse().unwrap(),
+            event: DiaryEvent::Blocked,
+        })];
+        let text = render(&items, false);
+        assert!(
+            text.ends_with(".123 10.26.0.4 Blocked blocked\n"),
+            "{}",
+            text
+        );
+        let json = render(&items, true);
+        assert_eq!(
+            json,
+            "[{\"time\":1700000000123,\"peer\":\"10.26.0.4\",\"type\":\"Blocked\",\"message\":\"blocked\"}]\n"
+        );
+        let back: Vec<EventItem> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, items);
+    }
+}
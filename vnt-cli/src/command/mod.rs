@@ -1,11 +1,38 @@
 use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use vnt::channel::bandwidth::format_kbps;
+use vnt::channel::block_list::BlockList;
+use vnt::channel::checksum::ChecksumMode;
+use vnt::channel::inbound_limit::Limit;
+use vnt::channel::path_score::{
+    PathChoice, PathPreferences, PathWeights, PeerPreference, Preference,
+};
+use vnt::channel::peer_feature::Feature;
+use vnt::channel::ping::ms;
+use vnt::channel::route_health::PathTransition;
 use vnt::core::Vnt;
+use vnt::handle::CurrentDeviceInfo;
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use vnt::util::health::HealthReport;
+use vnt::util::metrics::MetricValue;
+use vnt::util::subsystem::{SubsystemOverrides, SubsystemStatus};
+
+use crate::command::entity::{
+    ConnectionItem, ConnectionList, DeviceItem, DnsRouteItem, DnsStatus, DropItem, HealthStatus,
+    Info, MetricItem, PeerStatsItem, ProbeItem, ProbeList, RouteItem, SelfTestResult,
+    SelfTestStage, SocketItem, SocketList, ThreadItem,
+};
+use crate::config::numeric::{Numeric, Seconds};
 use crate::console_out;
 
 pub mod client;
+pub mod control;
+pub mod debug_bundle;
 pub mod entity;
+pub mod events;
 pub mod server;
 
 pub enum CommandEnum {
@@ -14,6 +41,37 @@ pub enum CommandEnum {
     All,
     Info,
     Stop,
+    Block(String),
+    Unblock(String),
+    Forget(String),
+    Drops(bool),
+    Metrics,
+    Probes,
+    Sockets,
+    Threads,
+    Peers,
+    Config,
+    Punch(String),
+    Invite(String),
+    Knock(String),
+    Requests,
+    Accept(String),
+    Diary(String),
+    Estimate(String),
+    Bandwidth(String),
+    Ping(String),
+    Feature(String),
+    Limit(String),
+    Prefer(String),
+    Subsystem(String),
+    Connections,
+    Health,
+    Dns,
+    DebugBundle(String, bool),
+    Telemetry(String),
+    Policy(String),
+    Json(String),
+    Restart,
 }
 
 pub fn command(cmd: CommandEnum) {
@@ -41,17 +99,1067 @@ fn command_(cmd: CommandEnum) -> io::Result<()> {
             let info = command_client.info()?;
             console_out::console_info(info);
         }
+        CommandEnum::Json(cmd) => {
+            println!("{}", command_client.json(&cmd)?);
+        }
         CommandEnum::Stop => {
             command_client.stop()?;
         }
+        CommandEnum::Block(target) => {
+            println!("{}", command_client.block(&target)?);
+        }
+        CommandEnum::Unblock(target) => {
+            println!("{}", command_client.unblock(&target)?);
+        }
+        CommandEnum::Forget(target) => {
+            println!("{}", command_client.forget(&target)?);
+        }
+        CommandEnum::Punch(target) => {
+            println!("{}", command_client.punch(&target)?);
+        }
+        CommandEnum::Invite(args) => {
+            println!("{}", command_client.invite(&args)?);
+        }
+        CommandEnum::Knock(args) => {
+            println!("{}", command_client.knock(&args)?);
+        }
+        CommandEnum::Requests => {
+            print!("{}", command_client.requests()?);
+        }
+        CommandEnum::Accept(args) => {
+            println!("{}", command_client.accept(&args)?);
+        }
+        CommandEnum::Diary(target) => {
+            print!("{}", command_client.diary(&target)?);
+        }
+        CommandEnum::Estimate(target) => {
+            print!("{}", command_client.estimate(&target)?);
+        }
+        CommandEnum::Bandwidth(args) => {
+            println!("{}", command_client.bandwidth(&args)?);
+        }
+        CommandEnum::Ping(args) => {
+            println!("{}", command_client.ping(&args)?);
+        }
+        CommandEnum::Feature(args) => {
+            println!("{}", command_client.feature(&args)?);
+        }
+        CommandEnum::Limit(args) => {
+            println!("{}", command_client.limit(&args)?);
+        }
+        CommandEnum::Prefer(args) => {
+            println!("{}", command_client.prefer(&args)?);
+        }
+        CommandEnum::Subsystem(args) => {
+            println!("{}", command_client.subsystem(&args)?);
+        }
+        CommandEnum::Connections => {
+            let list = command_client.connections()?;
+            console_out::console_connections(list);
+        }
+        CommandEnum::Health => {
+            let status = command_client.health()?;
+            console_out::console_health(status);
+        }
+        CommandEnum::Dns => {
+            let status = command_client.dns()?;
+            console_out::console_dns(status);
+        }
+        CommandEnum::DebugBundle(path, redact) => {
+            if !redact {
+                println!("'--no-redact' is only available in the interactive console");
+                return Ok(());
+            }
+            let path = debug_bundle::absolute_path(&path)?;
+            if path.exists() {
+                println!("{} already exists", path.display());
+                return Ok(());
+            }
+            if !debug_bundle::confirm(&path, redact) {
+                return Ok(());
+            }
+            let saved = command_client.debug_bundle()?;
+            match debug_bundle::copy_to(&saved, &path) {
+                Ok(_) => println!("debug bundle saved to {}", path.display()),
+                // 例如后台进程以其他用户运行，文件没有读权限
+                Err(e) => println!(
+                    "debug bundle saved to {}, copy to {} failed: {}",
+                    saved.display(),
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        CommandEnum::Restart => {
+            println!("{}", command_client.restart()?);
+        }
+        CommandEnum::Telemetry(args) => {
+            print!("{}", command_client.telemetry(&args)?);
+        }
+        CommandEnum::Policy(args) => {
+            println!("{}", command_client.policy(&args)?);
+        }
+        CommandEnum::Drops(explain) => {
+            let list = command_client.drops()?;
+            console_out::console_drops(list, explain);
+        }
+        CommandEnum::Metrics => {
+            let list = command_client.metrics()?;
+            console_out::console_metrics(list);
+        }
+        CommandEnum::Probes => {
+            let list = command_client.probes()?;
+            console_out::console_probes(list);
+        }
+        CommandEnum::Sockets => {
+            let list = command_client.sockets()?;
+            console_out::console_sockets(list);
+        }
+        CommandEnum::Threads => {
+            let list = command_client.threads()?;
+            console_out::console_threads(list);
+        }
+        CommandEnum::Peers => {
+            let list = command_client.peer_stats()?;
+            console_out::console_peer_stats(list);
+        }
+        CommandEnum::Config => {
+            let list = command_client.config()?;
+            console_out::console_config(list);
+        }
     }
     Ok(())
 }
 
+/// 后台运行的实例执行自检，全部通过时返回true，无法连接也算不通过
+pub fn selftest(target: &str) -> bool {
+    let rs = client::CommandClient::new().and_then(|mut client| client.selftest(target));
+    match rs {
+        Ok(result) => {
+            console_out::console_selftest(&result);
+            result.passed
+        }
+        Err(e) => {
+            println!("cmd: {:?}", e);
+            false
+        }
+    }
+}
+
+fn block_list_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(crate::state::BLOCK_LIST_FILE))
+}
+
+/// 启动时恢复屏蔽列表
+pub fn load_block_list(vnt: &Vnt) {
+    let list = match block_list_path().and_then(BlockList::load) {
+        Ok(list) => list,
+        Err(e) => {
+            log::warn!("读取屏蔽列表失败:{:?}", e);
+            return;
+        }
+    };
+    for (ip, fingerprint) in list {
+        if let Err(e) = vnt.block_device(ip, &fingerprint) {
+            log::warn!("屏蔽 {} 失败:{:?}", ip, e);
+        }
+    }
+}
+
+fn path_preferences_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(crate::state::PATH_PREFERENCES_FILE))
+}
+
+/// 启动时恢复对端的路径偏好
+pub fn load_path_preferences(vnt: &Vnt) {
+    let path = match path_preferences_path() {
+        Ok(path) => path,
+        Err(e) => {
+            log::warn!("读取路径偏好失败:{:?}", e);
+            return;
+        }
+    };
+    if let Some(PathPreferences(list)) = vnt::util::state_store::load(&path) {
+        for (ip, preference) in list {
+            vnt.set_path_preference(ip, Some(preference));
+        }
+    }
+}
+
+/// 修改对端的路径偏好，参数为'<ip|name> <p2p|relay|auto> [权重]'，为空时列出当前设置，
+/// 'auto'不带权重时取消覆盖
+pub fn command_prefer(vnt: &Vnt, args: &str) -> String {
+    const USAGE: &str =
+        "usage: prefer [<ip|name> <p2p|relay|auto> [rtt=1,loss=50,jitter=2,bandwidth=0.1]]";
+    let args: Vec<&str> = args.split_whitespace().collect();
+    if args.is_empty() {
+        let mut out = match vnt.path_weights() {
+            Some(weights) => format!("default: {}", weights),
+            None => "default: by latency".to_string(),
+        };
+        for (ip, preference) in vnt.path_preferences() {
+            out.push_str(&format!("\n{}: {}", ip, preference));
+        }
+        return out;
+    }
+    if args.len() < 2 || args.len() > 3 {
+        return USAGE.to_string();
+    }
+    let ip = match find_peer(vnt, args[0]) {
+        Ok(ip) => ip,
+        Err(e) => return e,
+    };
+    let preference = match Preference::from_str(args[1]) {
+        Ok(preference) => preference,
+        Err(e) => return e,
+    };
+    let weights = match args.get(2).map(|v| PathWeights::from_str(v)).transpose() {
+        Ok(weights) => weights,
+        Err(e) => return format!("invalid weights, {}", e),
+    };
+    let preference = if preference == Preference::Auto && weights.is_none() {
+        None
+    } else {
+        Some(PeerPreference {
+            preference,
+            weights,
+        })
+    };
+    vnt.set_path_preference(ip, preference);
+    match path_preferences_path() {
+        Ok(path) => crate::state::STORE.stage(path, &PathPreferences(vnt.path_preferences())),
+        Err(e) => log::warn!("保存路径偏好失败:{:?}", e),
+    }
+    match preference {
+        Some(preference) => format!("{} prefer {}", ip, preference),
+        None => format!("{} prefer auto", ip),
+    }
+}
+
+fn subsystems_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(crate::state::SUBSYSTEMS_FILE))
+}
+
+/// 启动时恢复保存的子系统启停设置
+pub fn load_subsystem_overrides(vnt: &Vnt) {
+    let path = match subsystems_path() {
+        Ok(path) => path,
+        Err(e) => {
+            log::warn!("读取子系统设置失败:{:?}", e);
+            return;
+        }
+    };
+    if let Some(overrides) = vnt::util::state_store::load::<SubsystemOverrides>(&path) {
+        vnt.subsystems().apply(&overrides);
+    }
+}
+
+/// 子系统的状态和启停，参数为'list'、'start <名称>'或'stop <名称> [--cascade]'，为空时同list
+pub fn command_subsystem(vnt: &Vnt, args: &str) -> String {
+    const USAGE: &str = "usage: subsystem [list|start <name>|stop <name> [--cascade]]";
+    let subsystems = vnt.subsystems();
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (name, rs) = match args.as_slice() {
+        [] | ["list"] => return subsystem_table(subsystems.list()),
+        ["start", name] => (*name, subsystems.start(name, "command")),
+        ["stop", name] => (*name, subsystems.stop(name, false, "command")),
+        ["stop", name, "--cascade"] => (*name, subsystems.stop(name, true, "command")),
+        _ => return USAGE.to_string(),
+    };
+    let changed = match rs {
+        Ok(changed) => changed,
+        Err(e) => return e,
+    };
+    if changed.is_empty() {
+        return format!("{} unchanged", name);
+    }
+    match subsystems_path() {
+        Ok(path) => crate::state::STORE.stage(path, &subsystems.overrides()),
+        Err(e) => log::warn!("保存子系统设置失败:{:?}", e),
+    }
+    let action = if args[0] == "start" {
+        "started"
+    } else {
+        "stopped"
+    };
+    format!("{} {}", changed.join(","), action)
+}
+
+fn subsystem_table(list: Vec<SubsystemStatus>) -> String {
+    let mut out = format!(
+        "{:<16}{:<10}{:<10}{:<10}{}",
+        "Name", "State", "Uptime", "Health", "Footprint"
+    );
+    for status in list {
+        let state = if status.core {
+            "core"
+        } else if status.running {
+            "running"
+        } else {
+            "stopped"
+        };
+        let uptime = if status.running {
+            let secs = status.uptime.as_secs();
+            if secs < 60 {
+                format!("{}s", secs)
+            } else if secs < 3600 {
+                format!("{}m{}s", secs / 60, secs % 60)
+            } else {
+                format!("{}h{}m", secs / 3600, secs % 3600 / 60)
+            }
+        } else {
+            "-".to_string()
+        };
+        let health = if !status.running {
+            "-"
+        } else if status.probe.healthy {
+            "ok"
+        } else {
+            "fail"
+        };
+        let mut detail = status.probe.detail;
+        if !status.depends.is_empty() {
+            if !detail.is_empty() {
+                detail.push(' ');
+            }
+            detail.push_str(&format!("(needs {})", status.depends.join(",")));
+        }
+        out.push_str(&format!(
+            "\n{:<16}{:<10}{:<10}{:<10}{}",
+            status.name, state, uptime, health, detail
+        ));
+    }
+    out
+}
+
+/// 端到端自检，参数为空或者要检查的对端'<ip|name>'
+pub fn command_selftest(vnt: &Vnt, target: &str) -> SelfTestResult {
+    let peer = if target.trim().is_empty() {
+        None
+    } else {
+        match find_peer(vnt, target) {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                return SelfTestResult {
+                    passed: false,
+                    stages: vec![SelfTestStage {
+                        name: "peer".to_string(),
+                        ok: false,
+                        elapsed: 0.0,
+                        detail: e,
+                    }],
+                }
+            }
+        }
+    };
+    let report = vnt.selftest(peer);
+    SelfTestResult {
+        passed: report.passed(),
+        stages: report
+            .stages
+            .into_iter()
+            .map(|stage| SelfTestStage {
+                name: stage.name.to_string(),
+                ok: stage.ok,
+                elapsed: stage.elapsed.as_secs_f64() * 1000.0,
+                detail: stage.detail,
+            })
+            .collect(),
+    }
+}
+
+/// 通过虚拟ip或设备名称查找设备
+fn find_peer(vnt: &Vnt, target: &str) -> Result<Ipv4Addr, String> {
+    let target = target.trim();
+    if let Ok(ip) = Ipv4Addr::from_str(target) {
+        return Ok(ip);
+    }
+    let list: Vec<Ipv4Addr> = vnt
+        .device_list()
+        .into_iter()
+        .filter(|peer| peer.name == target)
+        .map(|peer| peer.virtual_ip)
+        .collect();
+    match list.len() {
+        0 => Err(format!("device '{}' not found", target)),
+        1 => Ok(list[0]),
+        _ => Err(format!(
+            "multiple devices named '{}', use ip instead",
+            target
+        )),
+    }
+}
+
+/// 屏蔽/解除屏蔽对端，target可以是虚拟ip或设备名称
+pub fn command_block(vnt: &Vnt, target: &str, block: bool) -> String {
+    let ip = match find_peer(vnt, target) {
+        Ok(ip) => ip,
+        Err(e) => return e,
+    };
+    let changed = if block {
+        match vnt.block_peer(ip) {
+            Ok(changed) => changed,
+            Err(e) => return format!("error {}", e),
+        }
+    } else {
+        vnt.unblock_peer(&ip)
+    };
+    if changed {
+        let block_list = BlockList::new();
+        for (ip, fingerprint) in vnt.blocked_devices() {
+            block_list.block_device(ip, &fingerprint);
+        }
+        match block_list_path() {
+            Ok(path) => crate::state::STORE.stage(path, &block_list),
+            Err(e) => log::warn!("保存屏蔽列表失败:{:?}", e),
+        }
+    }
+    match (block, changed) {
+        (true, true) => format!("blocked {}", ip),
+        (true, false) => format!("{} already blocked", ip),
+        (false, true) => format!("unblocked {}", ip),
+        (false, false) => format!("{} not blocked", ip),
+    }
+}
+
+/// 清除对端保存的所有状态，target可以是虚拟ip或设备名称
+pub fn command_forget(vnt: &Vnt, target: &str) -> String {
+    match find_peer(vnt, target) {
+        Ok(ip) => crate::retention::forget(vnt, ip),
+        Err(e) => e,
+    }
+}
+
+pub fn command_drops(vnt: &Vnt) -> Vec<DropItem> {
+    vnt.drop_stats()
+        .into_iter()
+        .map(|stat| DropItem {
+            reason: stat.reason.name().to_string(),
+            count: stat.count,
+            peers: stat
+                .peers
+                .into_iter()
+                .map(|(ip, count)| (ip.to_string(), count))
+                .collect(),
+            explain: stat.reason.explain().to_string(),
+        })
+        .collect()
+}
+
+pub fn command_health(vnt: &Vnt) -> HealthStatus {
+    let health = vnt.health();
+    let failed = |report: &HealthReport| {
+        report
+            .failed
+            .iter()
+            .map(|(check, reason)| format!("{}: {}", check, reason))
+            .collect()
+    };
+    let live = health.livez();
+    let ready = health.readyz();
+    HealthStatus {
+        live: live.ok(),
+        live_failed: failed(&live),
+        ready: ready.ok(),
+        ready_failed: failed(&ready),
+    }
+}
+
+pub fn command_dns(vnt: &Vnt) -> DnsStatus {
+    let stats = match vnt.dns_stats() {
+        Some(stats) => stats,
+        None => {
+            return DnsStatus {
+                enabled: false,
+                listen: String::new(),
+                upstream: Vec::new(),
+                routes: Vec::new(),
+                cache_size: 0,
+                cache_limit: 0,
+                queries: 0,
+                cache_hits: 0,
+                local: 0,
+                servfail: 0,
+            }
+        }
+    };
+    DnsStatus {
+        enabled: true,
+        listen: stats.listen.map(|v| v.to_string()).unwrap_or_default(),
+        upstream: stats.upstream.iter().map(|v| v.to_string()).collect(),
+        routes: stats
+            .routes
+            .into_iter()
+            .map(|stat| DnsRouteItem {
+                suffix: stat.route.suffix,
+                server: stat.route.server.to_string(),
+                hits: stat.hits,
+                failures: stat.failures,
+            })
+            .collect(),
+        cache_size: stats.cache_size,
+        cache_limit: stats.cache_limit,
+        queries: stats.queries,
+        cache_hits: stats.cache_hits,
+        local: stats.local,
+        servfail: stats.servfail,
+    }
+}
+
+pub fn command_sockets(vnt: &Vnt) -> SocketList {
+    let sockets = vnt
+        .socket_stats()
+        .into_iter()
+        .map(|stat| SocketItem {
+            purpose: stat.purpose.name().to_string(),
+            local_port: stat.local_port,
+            age_secs: stat.age.as_secs(),
+            bytes: stat.bytes,
+        })
+        .collect();
+    SocketList {
+        limit: vnt.socket_limit(),
+        sockets,
+    }
+}
+
+pub fn command_peer_stats(vnt: &Vnt) -> Vec<PeerStatsItem> {
+    let device_list = vnt.device_list();
+    vnt.peer_stats()
+        .into_iter()
+        .map(|traffic| PeerStatsItem {
+            virtual_ip: traffic.peer,
+            name: device_list
+                .iter()
+                .find(|v| v.virtual_ip == traffic.peer)
+                .map(|v| v.name.clone())
+                .unwrap_or_default(),
+            rx: traffic.rx(),
+            tx: traffic.tx(),
+            rx_relay: traffic.rx_relay,
+            tx_relay: traffic.tx_relay,
+            packets: traffic.packets(),
+            rx_rate: traffic.rx_rate,
+            tx_rate: traffic.tx_rate,
+        })
+        .collect()
+}
+
+pub fn command_threads(vnt: &Vnt) -> Vec<ThreadItem> {
+    vnt.thread_placements()
+        .into_iter()
+        .map(|placement| ThreadItem {
+            name: placement.name,
+            worker: placement.worker.name().to_string(),
+            tid: placement.tid,
+            core: placement.core,
+            priority: placement.priority.name().to_string(),
+            error: placement.error,
+        })
+        .collect()
+}
+
+pub fn command_probes(vnt: &Vnt) -> ProbeList {
+    let probes = vnt
+        .probe_stats()
+        .into_iter()
+        .map(|stat| ProbeItem {
+            kind: stat.kind.name().to_string(),
+            priority: stat.kind.priority().to_string(),
+            sent: stat.sent,
+            bytes: stat.bytes,
+            skipped: stat.skipped,
+        })
+        .collect();
+    ProbeList {
+        budget_kbps: vnt.probe_budget(),
+        probes,
+    }
+}
+
+/// 接收方向的活跃流
+pub fn command_connections(vnt: &Vnt) -> ConnectionList {
+    let list = vnt
+        .connections()
+        .into_iter()
+        .map(|info| ConnectionItem {
+            peer: info.key.peer.to_string(),
+            protocol: info.key.protocol_name(),
+            src_port: info.key.src_port,
+            dst_port: info.key.dst_port,
+            bytes: info.bytes,
+            packets: info.packets,
+            recent_bytes: info.recent_bytes,
+            idle_secs: info.idle.as_secs(),
+        })
+        .collect();
+    ConnectionList {
+        flow_tracking: vnt.flow_tracking(),
+        list,
+    }
+}
+
+pub fn command_metrics(vnt: &Vnt) -> Vec<MetricItem> {
+    vnt.metrics()
+        .into_iter()
+        .map(|sample| {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, vnt::util::sanitize::metric_label(v)))
+                .collect();
+            let value = match sample.value {
+                MetricValue::Counter(v) => v.to_string(),
+                MetricValue::Gauge(v) => v.to_string(),
+                MetricValue::Histogram(v) => {
+                    let mut buckets: Vec<String> = v
+                        .bounds
+                        .iter()
+                        .zip(v.buckets.iter())
+                        .map(|(bound, count)| format!("<={}:{}", bound, count))
+                        .collect();
+                    if let (Some(bound), Some(count)) = (v.bounds.last(), v.buckets.last()) {
+                        buckets.push(format!(">{}:{}", bound, count));
+                    }
+                    format!("count={} sum={} {}", v.count, v.sum, buckets.join(" "))
+                }
+            };
+            MetricItem {
+                name: sample.name.to_string(),
+                labels: labels.join(","),
+                value,
+            }
+        })
+        .collect()
+}
+
+/// 手动打洞，target可以是虚拟ip或设备名称
+pub fn command_punch(vnt: &Vnt, target: &str) -> String {
+    let ip = match find_peer(vnt, target) {
+        Ok(ip) => ip,
+        Err(e) => return e,
+    };
+    match vnt.punch(ip) {
+        Ok(_) => format!("punch {} requested", ip),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 直连许可的时长，不带单位时是分钟，不指定时为0，使用默认时长
+fn allowance_duration(text: Option<&str>) -> Result<Duration, String> {
+    match text {
+        Some(text) => Seconds::parse(text, 60_000).map(|v| v.0),
+        None => Ok(Duration::ZERO),
+    }
+}
+
+/// 严格入站时允许和对端直连，并由本机发起打洞
+pub fn command_invite(vnt: &Vnt, args: &str) -> String {
+    let usage = "usage: invite <ip|name> [duration]".to_string();
+    let mut args = args.split_whitespace();
+    let ip = match args.next().map(|v| find_peer(vnt, v)) {
+        Some(Ok(ip)) => ip,
+        Some(Err(e)) => return e,
+        None => return usage,
+    };
+    let duration = match allowance_duration(args.next()) {
+        Ok(duration) => duration,
+        Err(e) => return e,
+    };
+    if args.next().is_some() {
+        return usage;
+    }
+    match vnt.invite(ip, duration) {
+        Ok(duration) => format!("{} allowed for {}, punch requested", ip, Seconds(duration)),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 经服务器请求严格入站的对端发起直连
+pub fn command_knock(vnt: &Vnt, args: &str) -> String {
+    let usage = "usage: knock <ip|name> [duration]".to_string();
+    let mut args = args.split_whitespace();
+    let ip = match args.next().map(|v| find_peer(vnt, v)) {
+        Some(Ok(ip)) => ip,
+        Some(Err(e)) => return e,
+        None => return usage,
+    };
+    let duration = match allowance_duration(args.next()) {
+        Ok(duration) => duration,
+        Err(e) => return e,
+    };
+    if args.next().is_some() {
+        return usage;
+    }
+    match vnt.knock(ip, duration) {
+        Ok(_) => format!("connect request sent to {}, waiting for approval", ip),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 等待批准的连接请求和生效中的直连许可
+pub fn command_requests(vnt: &Vnt) -> String {
+    let mut text = String::new();
+    if !vnt.strict_inbound() {
+        text.push_str("strict inbound is off, connect requests are approved automatically\n");
+    }
+    let requests = vnt.connect_requests();
+    if requests.is_empty() {
+        text.push_str("no pending requests\n");
+    }
+    for request in requests {
+        text.push_str(&format!(
+            "#{} {} wants {} (received {} ago)\n",
+            request.id,
+            request.peer,
+            Seconds(request.duration),
+            Seconds(Duration::from_secs(request.age.as_secs()))
+        ));
+    }
+    for allowance in vnt.allowances() {
+        text.push_str(&format!(
+            "allowed {} for {}\n",
+            allowance.peer,
+            Seconds(Duration::from_secs(allowance.remaining.as_secs()))
+        ));
+    }
+    text
+}
+
+/// 批准连接请求
+pub fn command_accept(vnt: &Vnt, args: &str) -> String {
+    let usage = "usage: accept <id> [duration]".to_string();
+    let mut args = args.split_whitespace();
+    let id = match args
+        .next()
+        .map(|v| v.trim_start_matches('#').parse::<u32>())
+    {
+        Some(Ok(id)) => id,
+        _ => return usage,
+    };
+    let duration = match args.next() {
+        Some(text) => match Seconds::parse(text, 60_000) {
+            Ok(v) => Some(v.0),
+            Err(e) => return e,
+        },
+        None => None,
+    };
+    if args.next().is_some() {
+        return usage;
+    }
+    match vnt.accept_request(id, duration) {
+        Ok((ip, duration)) => format!("{} allowed for {}, punch requested", ip, Seconds(duration)),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 对端的连接日记，target可以是虚拟ip或设备名称
+pub fn command_diary(vnt: &Vnt, target: &str) -> String {
+    let ip = match find_peer(vnt, target) {
+        Ok(ip) => ip,
+        Err(e) => return format!("{}\n", e),
+    };
+    let text = diary_lines(vnt, &ip);
+    if text.is_empty() {
+        return format!("no diary for {}\n", ip);
+    }
+    text
+}
+
+/// 估计能否和对端直连，第一行是结论，之后每行一条依据
+pub fn command_estimate(vnt: &Vnt, target: &str) -> String {
+    match find_peer(vnt, target) {
+        Ok(ip) => format!("{}: {}", ip, vnt.estimate(&ip)),
+        Err(e) => format!("{}\n", e),
+    }
+}
+
+/// 测量到对端的带宽，参数为'<ip|name> [--include-relay]'
+pub fn command_bandwidth(vnt: &Vnt, args: &str) -> String {
+    let usage = "usage: bandwidth <ip|name> [--include-relay]".to_string();
+    let mut include_relay = false;
+    let mut target = None;
+    for arg in args.split_whitespace() {
+        if arg == "--include-relay" {
+            include_relay = true;
+        } else if target.is_none() {
+            target = Some(arg);
+        } else {
+            return usage;
+        }
+    }
+    let ip = match target.map(|v| find_peer(vnt, v)) {
+        Some(Ok(ip)) => ip,
+        Some(Err(e)) => return e,
+        None => return usage,
+    };
+    match vnt.bandwidth(ip, include_relay) {
+        Ok(report) => format!(
+            "{}: {}\npath: {}, {}/{} packets arrived, {:.1}s",
+            ip,
+            report.estimate,
+            report.path,
+            report.received,
+            report.sent,
+            report.elapsed.as_secs_f64()
+        ),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 用虚拟网络协议ping对端，参数为'<ip|name> [count]'
+pub fn command_ping(vnt: &Vnt, args: &str) -> String {
+    let usage = "usage: ping <ip|name> [count]".to_string();
+    let mut args = args.split_whitespace();
+    let ip = match args.next().map(|v| find_peer(vnt, v)) {
+        Some(Ok(ip)) => ip,
+        Some(Err(e)) => return e,
+        None => return usage,
+    };
+    let count = match args.next().map(|v| v.parse::<u32>()) {
+        Some(Ok(count)) => count,
+        Some(Err(_)) => return usage,
+        None => 4,
+    };
+    if args.next().is_some() {
+        return usage;
+    }
+    let report = match vnt.ping(ip, count) {
+        Ok(report) => report,
+        Err(e) => return format!("error {}", e),
+    };
+    let mut text = format!("PING {} via {}\n", report.peer, report.path);
+    for result in &report.results {
+        let path = if result.p2p { "p2p" } else { "relayed" };
+        match result.rtt {
+            Some(rtt) => text.push_str(&format!(
+                "reply from {}: seq={} time={:.2}ms {}\n",
+                report.peer,
+                result.seq,
+                ms(rtt),
+                path
+            )),
+            None => text.push_str(&format!("seq={} timeout ({})\n", result.seq, path)),
+        }
+    }
+    text.push_str(&format!("--- {} ping statistics ---\n", report.peer));
+    text.push_str(&report.summary.to_string());
+    text
+}
+
+/// 每行一个事件，时间为UTC
+pub fn diary_lines(vnt: &Vnt, ip: &Ipv4Addr) -> String {
+    let mut text = String::new();
+    for entry in vnt.diary(ip) {
+        text.push_str(&format!(
+            "{}.{:03} {}\n",
+            crate::seen_devices::utc_time(entry.time / 1000),
+            entry.time % 1000,
+            entry.event
+        ));
+    }
+    text
+}
+
+/// 修改和对端之间的加密，参数为'<ip|name> encrypt <on|off>'
+pub fn command_feature(vnt: &Vnt, args: &str) -> String {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    if args.len() != 3 {
+        return "usage: feature <ip|name> encrypt <on|off>".to_string();
+    }
+    let ip = match find_peer(vnt, args[0]) {
+        Ok(ip) => ip,
+        Err(e) => return e,
+    };
+    let feature = match Feature::from_str(args[1]) {
+        Ok(feature) => feature,
+        Err(e) => return e,
+    };
+    let enabled = match args[2] {
+        "on" => true,
+        "off" => false,
+        v => return format!("not match '{}', enum: on/off", v),
+    };
+    match vnt.set_peer_feature(ip, feature, enabled) {
+        Ok(true) => format!("{} {} {}", ip, feature, args[2]),
+        Ok(false) => format!("{} refused, keep the old setting", ip),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+/// 修改对端的接收限速，参数为'inbound <ip|name> <pps> <mbps>'或'inbound <ip|name> default'，
+/// 只有'inbound'时列出当前设置
+pub fn command_limit(vnt: &Vnt, args: &str) -> String {
+    const USAGE: &str = "usage: limit inbound [<ip|name> <<pps> <mbps>|default>]";
+    let args: Vec<&str> = args.split_whitespace().collect();
+    if args.first() != Some(&"inbound") {
+        return USAGE.to_string();
+    }
+    let format_limit = |limit: Limit| {
+        let pps = if limit.pps == 0 {
+            "unlimited".to_string()
+        } else {
+            format!("{} pkt/s", limit.pps)
+        };
+        let mbps = if limit.bps == 0 {
+            "unlimited".to_string()
+        } else {
+            format!("{} Mbps", limit.bps as f64 * 8.0 / 1_000_000.0)
+        };
+        format!("{}, {}", pps, mbps)
+    };
+    if args.len() == 1 {
+        let mut out = format!("default: {}", format_limit(vnt.inbound_limit()));
+        for (ip, limit, custom, blocked) in vnt.inbound_limits() {
+            out.push_str(&format!("\n{}: {}", ip, format_limit(limit)));
+            if !custom {
+                out.push_str(" (default)");
+            }
+            if let Some(blocked) = blocked {
+                out.push_str(&format!(", auto-blocked for {}s", blocked.as_secs()));
+            }
+        }
+        return out;
+    }
+    let ip = match find_peer(vnt, args[1]) {
+        Ok(ip) => ip,
+        Err(e) => return e,
+    };
+    let limit = match &args[2..] {
+        ["default"] => None,
+        [pps, mbps] => match (pps.parse::<u64>(), mbps.parse::<f64>()) {
+            (Ok(pps), Ok(mbps)) if mbps.is_finite() && mbps >= 0.0 => {
+                Some(Limit::new(pps, (mbps * 1_000_000.0 / 8.0) as u64))
+            }
+            _ => return USAGE.to_string(),
+        },
+        _ => return USAGE.to_string(),
+    };
+    vnt.set_inbound_limit(ip, limit);
+    match limit {
+        Some(limit) => format!("{} inbound limit {}", ip, format_limit(limit)),
+        None => format!("{} inbound limit restored to default", ip),
+    }
+}
+
+/// 生成诊断包，需要先经过用户确认
+pub fn command_debug_bundle(vnt: &Vnt, path: &Path, redact: bool) -> String {
+    match debug_bundle::create(vnt, path, redact) {
+        Ok(_) => format!("debug bundle saved to {}", path.display()),
+        Err(e) => format!("error {} {:?}", path.display(), e),
+    }
+}
+
 pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
     let route_table = vnt.route_table();
+    let no_direct_list = vnt.no_direct_list();
+    let overrides = vnt.peer_features();
+    let feature_of = |ip: &Ipv4Addr| {
+        overrides
+            .iter()
+            .filter(|v| &v.ip == ip)
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    let bring_up_list = vnt.bring_up_list();
+    let bring_up_of = |ip: &Ipv4Addr| {
+        bring_up_list
+            .iter()
+            .find(|(v, _)| v == ip)
+            .map_or(String::new(), |(_, info)| info.to_string())
+    };
+    // 生效的路径偏好和权重，以及中继的分数，没有任何覆盖设置时为空
+    let weights_of = |ip: &Ipv4Addr| {
+        let weights = match vnt.effective_path_weights(ip) {
+            Some(weights) => weights,
+            None => return String::new(),
+        };
+        let preference = vnt
+            .path_preferences()
+            .into_iter()
+            .find(|(v, _)| v == ip)
+            .map_or(Preference::Auto, |(_, v)| v.preference);
+        let mut out = format!("{} {}", preference, weights);
+        if let Some(score) = vnt.relay_score(ip) {
+            out.push_str(&format!(" relay={:.1}", score));
+        }
+        if vnt.path_choice(ip) == PathChoice::Relay {
+            out.push_str(" (relaying)");
+        }
+        out
+    };
+    // 没有直连判定依据的中继对端，给出估计的结论
+    let why_relay = |ip: &Ipv4Addr| vnt.estimate(ip).verdict.to_string();
     let mut route_list = Vec::with_capacity(route_table.len());
+    for (ip, evidence) in &no_direct_list {
+        if !route_table.iter().any(|(destination, _)| destination == ip) {
+            //没有路由的，通过服务器中继
+            route_list.push(RouteItem {
+                destination: ip.to_string(),
+                next_hop: String::new(),
+                metric: String::new(),
+                rt: String::new(),
+                interface: "relay".to_string(),
+                direct: format!("impossible({})", evidence),
+                feature: feature_of(ip),
+                bring_up: bring_up_of(ip),
+                bandwidth: String::new(),
+                score: String::new(),
+                weights: weights_of(ip),
+            });
+        }
+    }
+    for item in &overrides {
+        let ip = &item.ip;
+        // 同一个对端可能有多个设置
+        if !route_table.iter().any(|(destination, _)| destination == ip)
+            && !route_list.iter().any(|v| v.destination == ip.to_string())
+        {
+            route_list.push(RouteItem {
+                destination: ip.to_string(),
+                next_hop: String::new(),
+                metric: String::new(),
+                rt: String::new(),
+                interface: "relay".to_string(),
+                direct: why_relay(ip),
+                feature: feature_of(ip),
+                bring_up: bring_up_of(ip),
+                bandwidth: String::new(),
+                score: String::new(),
+                weights: weights_of(ip),
+            });
+        }
+    }
+    for (ip, info) in &bring_up_list {
+        // 建立中或已超时的对端，通过服务器中继
+        if !route_table.iter().any(|(destination, _)| destination == ip)
+            && !route_list.iter().any(|v| v.destination == ip.to_string())
+        {
+            route_list.push(RouteItem {
+                destination: ip.to_string(),
+                next_hop: String::new(),
+                metric: String::new(),
+                rt: String::new(),
+                interface: "relay".to_string(),
+                direct: why_relay(ip),
+                feature: feature_of(ip),
+                bring_up: info.to_string(),
+                bandwidth: String::new(),
+                score: String::new(),
+                weights: weights_of(ip),
+            });
+        }
+    }
     for (destination, routes) in route_table {
+        let direct = no_direct_list
+            .iter()
+            .find(|(ip, _)| ip == &destination)
+            .map(|(_, evidence)| format!("impossible({})", evidence))
+            .unwrap_or_default();
+        let feature = feature_of(&destination);
+        let bring_up = bring_up_of(&destination);
+        let weights = weights_of(&destination);
         for route in routes {
             let next_hop = vnt
                 .route_key(&route.route_key())
@@ -67,12 +1175,25 @@ pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
             } else {
                 route.addr.to_string()
             };
+            let bandwidth = if route.bandwidth > 0 {
+                format_kbps(route.bandwidth)
+            } else {
+                String::new()
+            };
             let item = RouteItem {
                 destination: destination.to_string(),
                 next_hop,
                 metric,
                 rt,
                 interface,
+                direct: direct.clone(),
+                feature: feature.clone(),
+                bring_up: bring_up.clone(),
+                bandwidth,
+                score: vnt
+                    .path_score(destination, &route)
+                    .map_or(String::new(), |v| format!("{:.1}", v)),
+                weights: weights.clone(),
             };
             route_list.push(item);
         }
@@ -86,7 +1207,9 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
     let mut list = Vec::new();
     let current_client_secret = vnt.client_encrypt();
     let client_encrypt_hash = vnt.client_encrypt_hash().unwrap_or(&[]);
+    let block_list = vnt.block_list();
     for peer in device_list {
+        let blocked = block_list.contains(&peer.virtual_ip);
         let name = peer.name;
         let virtual_ip = peer.virtual_ip.to_string();
         let (nat_type, public_ips, local_ip, ipv6) =
@@ -112,35 +1235,14 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
                     "".to_string(),
                 )
             };
-        let (nat_traversal_type, rt) = if let Some(route) = vnt.route(&peer.virtual_ip) {
-            let nat_traversal_type = if route.metric == 1 {
-                if route.is_tcp {
-                    "tcp-p2p"
-                } else {
-                    "p2p"
-                }
-            } else {
-                let next_hop = vnt.route_key(&route.route_key());
-                if let Some(next_hop) = next_hop {
-                    if info.is_gateway(&next_hop) {
-                        "server-relay"
-                    } else {
-                        "client-relay"
-                    }
-                } else {
-                    "server-relay"
-                }
-            }
-            .to_string();
-            let rt = if route.rt < 0 {
-                "".to_string()
-            } else {
-                route.rt.to_string()
-            };
-            (nat_traversal_type, rt)
-        } else {
-            ("relay".to_string(), "".to_string())
+        let (nat_traversal_type, rt) = peer_path(vnt, &info, &peer.virtual_ip);
+        // 直连断开改用中继，或者刚切回直连时显示切换
+        let nat_traversal_type = match vnt.path_transition(&peer.virtual_ip) {
+            Some(transition @ PathTransition::Down { .. }) => transition.to_string(),
+            Some(transition) if nat_traversal_type.ends_with("p2p") => transition.to_string(),
+            _ => nat_traversal_type.to_string(),
         };
+        let rt = rt.map(|v| v.to_string()).unwrap_or_default();
         let status = format!("{:?}", peer.status);
         let client_secret = peer.client_secret;
         let item = DeviceItem {
@@ -157,12 +1259,55 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
             client_secret_hash: peer.client_secret_hash,
             current_client_secret,
             current_client_secret_hash: client_encrypt_hash.to_vec(),
+            blocked,
+            fingerprint: peer.fingerprint,
         };
         list.push(item);
     }
     list
 }
 
+/// 到对端的通道类型和延迟，没有路由时经过服务器中继
+pub fn peer_path(
+    vnt: &Vnt,
+    info: &CurrentDeviceInfo,
+    ip: &Ipv4Addr,
+) -> (&'static str, Option<i64>) {
+    let route = match vnt.route(ip) {
+        Some(route) => route,
+        None => {
+            let relay = if vnt.is_main_tcp() {
+                "relay(tcp)"
+            } else {
+                "relay"
+            };
+            return (relay, None);
+        }
+    };
+    let path = if route.metric == 1 {
+        if route.is_tcp {
+            "tcp-p2p"
+        } else {
+            "p2p"
+        }
+    } else {
+        let next_hop = vnt.route_key(&route.route_key());
+        if let Some(next_hop) = next_hop {
+            if !info.is_gateway(&next_hop) {
+                "client-relay"
+            } else if route.is_tcp {
+                "server-relay(tcp)"
+            } else {
+                "server-relay"
+            }
+        } else {
+            "server-relay"
+        }
+    };
+    let rt = if route.rt < 0 { None } else { Some(route.rt) };
+    (path, rt)
+}
+
 pub fn command_info(vnt: &Vnt) -> Info {
     let current_device = vnt.current_device();
     let nat_info = vnt.nat_info();
@@ -172,7 +1317,9 @@ pub fn command_info(vnt: &Vnt) -> Info {
     let virtual_netmask = current_device.virtual_netmask.to_string();
     let connect_status = format!("{:?}", vnt.connection_status());
     let relay_server = current_device.connect_server.to_string();
-    let nat_type = format!("{:?}", nat_info.nat_type);
+    let nat_class = nat_info.nat_class();
+    let nat_type = format!("{:?}", nat_class);
+    let nat_hint = nat_class.hint().to_string();
     let public_ips: Vec<String> = nat_info.public_ips.iter().map(|v| v.to_string()).collect();
     let public_ips = public_ips.join(",");
     let local_addr = nat_info
@@ -183,8 +1330,41 @@ pub fn command_info(vnt: &Vnt) -> Info {
         .ipv6()
         .map(|v| v.to_string())
         .unwrap_or("None".to_string());
+    let local_bind: Vec<String> = vnt.local_bind().iter().map(|v| v.to_string()).collect();
+    let local_bind = local_bind.join(",");
+    let mtu = vnt.device_mtu();
     let up = vnt.up_stream();
     let down = vnt.down_stream();
+    let legacy_peers = vnt.legacy_peer_num();
+    let blocked_peers = vnt.block_list().len();
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let effective_uid = Some(crate::root_check::effective_uid());
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let effective_uid = None;
+    let notice = vnt.notice().map(|v| v.to_string()).unwrap_or_default();
+    let report_usage = vnt.report_usage();
+    let (relay_tx, relay_rx) = vnt.relay_usage();
+    let peer_totals = vnt.peer_totals();
+    let server_actions = vnt
+        .allowed_server_actions()
+        .iter()
+        .map(|v| v.name())
+        .collect::<Vec<&str>>()
+        .join(",");
+    let upnp = match vnt.upnp() {
+        Some(mapping) => format!("{} {}", mapping.method, mapping.external),
+        None if vnt.config().upnp => "inactive".to_string(),
+        None => String::new(),
+    };
+    let tun_checksum = match vnt.config().tun_checksum {
+        ChecksumMode::Auto => format!("{} (auto)", vnt.tun_checksum().name()),
+        mode => mode.name().to_string(),
+    };
+    let device_id = format!(
+        "{} ({})",
+        vnt.config().device_id,
+        crate::config::device_id_source()
+    );
     #[cfg(feature = "port_mapping")]
     let port_mapping_list = vnt.config().port_mapping_list.clone();
     #[cfg(not(feature = "port_mapping"))]
@@ -199,11 +1379,29 @@ pub fn command_info(vnt: &Vnt) -> Info {
         connect_status,
         relay_server,
         nat_type,
+        nat_hint,
         public_ips,
         local_addr,
         ipv6_addr,
+        local_bind,
+        mtu,
         up,
         down,
+        legacy_peers,
+        blocked_peers,
+        effective_uid,
+        notice,
+        report_usage,
+        relay_tx,
+        relay_rx,
+        peer_tx: peer_totals.tx(),
+        peer_rx: peer_totals.rx(),
+        peer_packets: peer_totals.packets(),
+        peer_rate: peer_totals.tx_rate + peer_totals.rx_rate,
+        server_actions,
+        upnp,
+        tun_checksum,
+        device_id,
         port_mapping_list,
         in_ips,
         out_ips,
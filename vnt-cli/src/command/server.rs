@@ -1,8 +1,26 @@
 use std::io;
-use std::io::Write;
 use std::net::UdpSocket;
 
 use vnt::core::Vnt;
+use vnt::util::state_store::{self, StateFile};
+
+/// 后台命令端口，版本0是没有文件头的旧文件，内容就是端口号
+pub struct CommandPort(pub u16);
+
+impl StateFile for CommandPort {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        self.0.to_string().into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        std::str::from_utf8(body)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .map(CommandPort)
+    }
+}
 
 pub struct CommandServer {}
 
@@ -24,8 +42,12 @@ impl CommandServer {
         if let Err(e) = save_port(addr.port()) {
             log::warn!("保存后台命令端口失败：{:?}", e);
         }
+        crate::shared_rate::register(addr.port());
+        if let Err(e) = crate::warm_restart::create_token() {
+            log::warn!("生成热重启口令失败：{:?}", e);
+        }
 
-        let mut buf = [0u8; 64];
+        let mut buf = [0u8; 256];
         loop {
             let (len, addr) = udp.recv_from(&mut buf)?;
             match std::str::from_utf8(&buf[..len]) {
@@ -37,6 +59,10 @@ impl CommandServer {
                         if "stopped" == &out {
                             break;
                         }
+                        if "restarting" == &out {
+                            let e = crate::warm_restart::exec();
+                            log::error!("热重启失败:{:?}", e);
+                        }
                     }
                 }
                 Err(e) => {
@@ -49,9 +75,7 @@ impl CommandServer {
 }
 fn save_port(port: u16) -> io::Result<()> {
     let path_buf = crate::app_home()?.join("command-port");
-    let mut file = std::fs::File::create(path_buf)?;
-    file.write_all(port.to_string().as_bytes())?;
-    file.sync_all()
+    state_store::save(&path_buf, &CommandPort(port))
 }
 
 fn command(cmd: &str, vnt: &Vnt) -> io::Result<String> {
@@ -63,15 +87,109 @@ fn command(cmd: &str, vnt: &Vnt) -> io::Result<String> {
             .unwrap_or_else(|e| format!("error {:?}", e)),
         "info" => serde_yaml::to_string(&crate::command::command_info(vnt))
             .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats drops" => serde_yaml::to_string(&crate::command::command_drops(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "info --config" => serde_yaml::to_string(&crate::config::profile::effective())
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "health" => serde_yaml::to_string(&crate::command::command_health(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "dns" => serde_yaml::to_string(&crate::command::command_dns(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats sockets" => serde_yaml::to_string(&crate::command::command_sockets(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats peers" => serde_yaml::to_string(&crate::command::command_peer_stats(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats threads" => serde_yaml::to_string(&crate::command::command_threads(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats probes" => serde_yaml::to_string(&crate::command::command_probes(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "stats metrics" => serde_yaml::to_string(&crate::command::command_metrics(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "connections" => serde_yaml::to_string(&crate::command::command_connections(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "list json" | "status json" => {
+            crate::command::control::json_text(cmd, vnt).unwrap_or_default()
+        }
+        "requests" => crate::command::command_requests(vnt),
+        "quiet on" | "quiet off" => {
+            crate::ui::set_quiet(cmd == "quiet on");
+            cmd.to_string()
+        }
         "stop" => {
+            crate::exit::set_reason(crate::exit::ExitReason::Clean, "stop command");
             vnt.stop();
             "stopped".to_string()
         }
+        // 需要数据目录下的口令，见warm_restart::create_token
+        "restart" => "error restart requires the token in the data directory".to_string(),
         _ => {
-            format!(
-                "command '{}' not found.  Try to enter: 'route'/'list'/'stop' \n",
-                cmd
-            )
+            if let Some(args) = cmd.strip_prefix(crate::shared_rate::LEASE_COMMAND) {
+                crate::shared_rate::handle_lease(args)
+            } else if let Some(token) = cmd.strip_prefix("restart ") {
+                if !crate::warm_restart::check_token(token) {
+                    log::warn!("热重启口令错误");
+                    "error invalid restart token".to_string()
+                } else {
+                    match crate::warm_restart::prepare(vnt) {
+                        Ok(_) => "restarting".to_string(),
+                        Err(e) => format!("error {}", e),
+                    }
+                }
+            } else if let Some(target) = cmd.strip_prefix("block ") {
+                crate::command::command_block(vnt, target, true)
+            } else if let Some(target) = cmd.strip_prefix("unblock ") {
+                crate::command::command_block(vnt, target, false)
+            } else if let Some(target) = cmd.strip_prefix("forget ") {
+                crate::command::command_forget(vnt, target)
+            } else if let Some(target) = cmd.strip_prefix("punch ") {
+                crate::command::command_punch(vnt, target)
+            } else if let Some(args) = cmd.strip_prefix("invite ") {
+                crate::command::command_invite(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("knock ") {
+                crate::command::command_knock(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("accept ") {
+                crate::command::command_accept(vnt, args)
+            } else if let Some(target) = cmd.strip_prefix("diary ") {
+                crate::command::command_diary(vnt, target)
+            } else if let Some(target) = cmd.strip_prefix("estimate ") {
+                crate::command::command_estimate(vnt, target)
+            } else if let Some(args) = cmd.strip_prefix("bandwidth ") {
+                crate::command::command_bandwidth(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("ping ") {
+                crate::command::command_ping(vnt, args)
+            } else if let Some(target) = cmd.strip_prefix("selftest") {
+                serde_yaml::to_string(&crate::command::command_selftest(vnt, target))
+                    .unwrap_or_else(|e| format!("error {:?}", e))
+            } else if let Some(args) = cmd.strip_prefix("telemetry") {
+                crate::telemetry::command(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("policy") {
+                crate::policy::command(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("loglevel") {
+                crate::loglevel_command(args)
+            } else if let Some(args) = cmd.strip_prefix("feature ") {
+                crate::command::command_feature(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("limit ") {
+                crate::command::command_limit(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("prefer") {
+                crate::command::command_prefer(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("subsystem") {
+                crate::command::command_subsystem(vnt, args)
+            } else if let Some(args) = cmd.strip_prefix("debug-bundle") {
+                // 命令端口没有认证，忽略请求中的路径，总是脱敏并写到数据目录下的新文件
+                if !crate::command::debug_bundle::parse_args(args).0 {
+                    "error '--no-redact' is only available in the interactive console".to_string()
+                } else {
+                    match crate::command::debug_bundle::create_remote(vnt) {
+                        Ok(path) => format!("debug bundle saved to {}", path.display()),
+                        Err(e) => format!("error {:?}", e),
+                    }
+                }
+            } else {
+                format!(
+                    "command '{}' not found.  Try to enter: 'route'/'list'/'stop' \n",
+                    cmd
+                )
+            }
         }
     };
     Ok(out_str)
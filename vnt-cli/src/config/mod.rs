@@ -1,31 +1,103 @@
 #[cfg(feature = "file_config")]
 mod file_config;
+pub mod numeric;
+pub mod profile;
+pub mod settings;
+mod startup;
+
+use std::sync::OnceLock;
+
+use vnt::util::state_store::{self, StateFile};
 
 #[cfg(feature = "file_config")]
 pub use file_config::read_config;
+pub use startup::Config;
 
 #[cfg(not(feature = "file_config"))]
 pub fn read_config(_file_path: &str) -> anyhow::Result<(vnt::core::Config, bool)> {
     unimplemented!()
 }
 
+/// 没有机器标识时生成并保存的设备标识
+struct DeviceId(String);
+
+/// 版本0是没有文件头的旧文件，内容就是标识
+impl StateFile for DeviceId {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let id = std::str::from_utf8(body).ok()?;
+        if id.trim().is_empty() {
+            return None;
+        }
+        Some(DeviceId(id.to_string()))
+    }
+}
+
+/// 设备标识的来源，'--info'中显示
+static DEVICE_ID_SOURCE: OnceLock<&'static str> = OnceLock::new();
+
+pub fn device_id_source() -> &'static str {
+    DEVICE_ID_SOURCE.get().copied().unwrap_or("unknown")
+}
+
+/// 标识由参数或'-f'配置文件指定时记录来源，已经生成过标识时不覆盖
+pub fn set_device_id_source(source: &'static str) {
+    let _ = DEVICE_ID_SOURCE.set(source);
+}
+
 pub fn get_device_id() -> String {
+    let (id, source) = resolve_device_id();
+    let _ = DEVICE_ID_SOURCE.set(source);
+    id
+}
+
+fn resolve_device_id() -> (String, &'static str) {
     if let Some(id) = common::identifier::get_unique_identifier() {
-        id
+        return (id, "machine id");
+    }
+    let path_buf = match crate::app_home() {
+        Ok(path_buf) => path_buf.join("device-id"),
+        Err(e) => {
+            // 没有可写的目录时每次启动都会使用新的标识
+            log::warn!("{:?},设备标识无法保存", e);
+            return (uuid::Uuid::new_v4().to_string(), "generated, not saved");
+        }
+    };
+    if let Some(DeviceId(id)) = state_store::load(&path_buf) {
+        (id, "saved")
     } else {
-        let path_buf = match crate::app_home() {
-            Ok(path_buf) => path_buf.join("device-id"),
-            Err(e) => {
-                log::warn!("{:?}", e);
-                return String::new();
-            }
-        };
-        if let Ok(id) = std::fs::read_to_string(path_buf.as_path()) {
-            id
-        } else {
-            let id = uuid::Uuid::new_v4().to_string();
-            let _ = std::fs::write(path_buf, &id);
-            id
+        let id = uuid::Uuid::new_v4().to_string();
+        if let Err(e) = state_store::save(&path_buf, &DeviceId(id.clone())) {
+            log::warn!("{:?},设备标识无法保存", e);
+            return (id, "generated, not saved");
         }
+        (id, "generated")
+    }
+}
+
+/// 系统的主机名，'-n'的默认值
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
     }
+    let len = buf.iter().position(|v| *v == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(target_os = "windows")]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub fn hostname() -> Option<String> {
+    None
 }
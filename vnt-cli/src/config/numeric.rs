@@ -0,0 +1,860 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// 时长，精确到毫秒，如 500ms、30s、5m、1h
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Seconds(pub Duration);
+
+/// 字节数，如 1500、10KB、10MB、1MiB
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Bytes(pub u64);
+
+/// 速率，如 64kbps、20mbps、1gbps
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct BitsPerSec(pub u64);
+
+/// 数量，如 64、20k
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Count(pub u64);
+
+pub trait Numeric: Copy + PartialOrd + Display {
+    /// 不带单位时乘以unit，各类型的基本单位分别是毫秒、字节、bps和个
+    fn parse(text: &str, unit: u64) -> Result<Self, String>;
+    fn is_zero(&self) -> bool;
+}
+
+/// 拆分数字和单位，单位统一转成小写
+fn split(text: &str) -> Result<(f64, String), String> {
+    let text = text.trim();
+    let pos = text
+        .find(|c: char| c.is_ascii_alphabetic() || c == '/')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(pos);
+    let number = number.trim();
+    let value = number
+        .parse::<f64>()
+        .map_err(|_| format!("'{}' is not a number", text))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("'{}' must be a non-negative number", text));
+    }
+    Ok((value, unit.trim().to_lowercase()))
+}
+
+fn scale(text: &str, value: f64, factor: u64) -> Result<u64, String> {
+    let value = (value * factor as f64).round();
+    if value >= u64::MAX as f64 {
+        return Err(format!("'{}' is too large", text));
+    }
+    Ok(value as u64)
+}
+
+/// 用能整除的最大单位显示
+fn display(f: &mut Formatter<'_>, value: u64, units: &[(u64, &str)]) -> std::fmt::Result {
+    for (factor, name) in units {
+        if value != 0 && value % factor == 0 {
+            return write!(f, "{}{}", value / factor, name);
+        }
+    }
+    let (_, name) = units[units.len() - 1];
+    write!(f, "{}{}", value, name)
+}
+
+impl Numeric for Seconds {
+    fn parse(text: &str, unit: u64) -> Result<Self, String> {
+        let (value, suffix) = split(text)?;
+        let factor = match suffix.as_str() {
+            "" => unit,
+            "ms" => 1,
+            "s" | "sec" => 1000,
+            "m" | "min" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            _ => {
+                return Err(format!(
+                    "'{}' unknown unit, expected ms/s/m/h/d",
+                    text.trim()
+                ))
+            }
+        };
+        Ok(Seconds(Duration::from_millis(scale(text, value, factor)?)))
+    }
+    fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl Display for Seconds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        display(
+            f,
+            self.0.as_millis() as u64,
+            &[(3_600_000, "h"), (60_000, "m"), (1000, "s"), (1, "ms")],
+        )
+    }
+}
+
+impl Numeric for Bytes {
+    fn parse(text: &str, unit: u64) -> Result<Self, String> {
+        let (value, suffix) = split(text)?;
+        let factor = match suffix.as_str() {
+            "" => unit,
+            "b" => 1,
+            "k" | "kb" => 1000,
+            "m" | "mb" => 1_000_000,
+            "g" | "gb" => 1_000_000_000,
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            _ => {
+                return Err(format!(
+                    "'{}' unknown unit, expected B/KB/MB/GB/KiB/MiB/GiB",
+                    text.trim()
+                ))
+            }
+        };
+        Ok(Bytes(scale(text, value, factor)?))
+    }
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        display(
+            f,
+            self.0,
+            &[(1_000_000_000, "GB"), (1_000_000, "MB"), (1, "B")],
+        )
+    }
+}
+
+impl Numeric for BitsPerSec {
+    fn parse(text: &str, unit: u64) -> Result<Self, String> {
+        let (value, suffix) = split(text)?;
+        let factor = match suffix.as_str() {
+            "" => unit,
+            "bps" | "bit/s" => 1,
+            "kbps" | "kbit" | "kbit/s" => 1000,
+            "mbps" | "mbit" | "mbit/s" => 1_000_000,
+            "gbps" | "gbit" | "gbit/s" => 1_000_000_000,
+            // 按字节的速率
+            "kb/s" => 8_000,
+            "mb/s" => 8_000_000,
+            "gb/s" => 8_000_000_000,
+            _ => {
+                return Err(format!(
+                    "'{}' unknown unit, expected bps/kbps/mbps/gbps or KB/s/MB/s/GB/s",
+                    text.trim()
+                ))
+            }
+        };
+        Ok(BitsPerSec(scale(text, value, factor)?))
+    }
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitsPerSec {
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.0 / 8
+    }
+    pub fn kbps(&self) -> u64 {
+        self.0 / 1000
+    }
+}
+
+impl Display for BitsPerSec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        display(
+            f,
+            self.0,
+            &[
+                (1_000_000_000, "gbps"),
+                (1_000_000, "mbps"),
+                (1000, "kbps"),
+                (1, "bps"),
+            ],
+        )
+    }
+}
+
+impl Numeric for Count {
+    fn parse(text: &str, _unit: u64) -> Result<Self, String> {
+        let (value, suffix) = split(text)?;
+        let factor = match suffix.as_str() {
+            "" => 1,
+            "k" => 1000,
+            "m" => 1_000_000,
+            _ => return Err(format!("'{}' unknown unit, expected k/m", text.trim())),
+        };
+        if (value * factor as f64).fract() != 0.0 {
+            return Err(format!("'{}' is not an integer", text.trim()));
+        }
+        Ok(Count(scale(text, value, factor)?))
+    }
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Display for Count {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 一个数值选项的取值范围
+pub struct Spec<T> {
+    /// 命令行中的写法，如 "-u"、"--par"
+    pub name: &'static str,
+    /// 不带单位时的倍数
+    pub unit: u64,
+    pub min: T,
+    pub max: T,
+    /// 0表示关闭或不限制，不受下限约束
+    pub zero: bool,
+}
+
+impl<T: Numeric> Spec<T> {
+    /// 限制在范围内，超出时返回提示
+    pub fn clamp(&self, value: T) -> (T, Option<String>) {
+        if self.zero && value.is_zero() {
+            return (value, None);
+        }
+        let clamped = if value < self.min {
+            self.min
+        } else if value > self.max {
+            self.max
+        } else {
+            return (value, None);
+        };
+        let zero = if self.zero { ", 0 means off" } else { "" };
+        (
+            clamped,
+            Some(format!(
+                "'{} {}' out of range {}..={}{}, using {}",
+                self.name, value, self.min, self.max, zero, clamped
+            )),
+        )
+    }
+}
+
+/// 最小的ipv4 mtu
+pub const MTU: Spec<Bytes> = Spec {
+    name: "-u",
+    unit: 1,
+    min: Bytes(576),
+    max: Bytes(9000),
+    zero: false,
+};
+pub const PAR: Spec<Count> = Spec {
+    name: "--par",
+    unit: 1,
+    min: Count(1),
+    max: Count(64),
+    zero: false,
+};
+/// 0表示随机端口
+pub const PORT: Spec<Count> = Spec {
+    name: "--ports",
+    unit: 1,
+    min: Count(0),
+    max: Count(65535),
+    zero: true,
+};
+pub const LOCAL_PORT: Spec<Count> = Spec {
+    name: "--port",
+    unit: 1,
+    min: Count(0),
+    max: Count(65535),
+    zero: true,
+};
+/// 不带单位时是毫秒
+pub const PACKET_DELAY: Spec<Seconds> = Spec {
+    name: "--packet-delay",
+    unit: 1,
+    min: Seconds(Duration::from_millis(1)),
+    max: Seconds(Duration::from_secs(10)),
+    zero: true,
+};
+pub const BRING_UP_RELAY: Spec<Count> = Spec {
+    name: "--bring-up-relay",
+    unit: 1,
+    min: Count(1),
+    max: Count(1_000_000),
+    zero: true,
+};
+/// 不带单位时是毫秒
+pub const BRING_UP_TIMEOUT: Spec<Seconds> = Spec {
+    name: "--bring-up-timeout",
+    unit: 1,
+    min: Seconds(Duration::from_millis(500)),
+    max: Seconds(Duration::from_secs(600)),
+    zero: false,
+};
+/// 每秒未验证的直连请求数
+pub const COOKIE_THRESHOLD: Spec<Count> = Spec {
+    name: "--cookie-threshold",
+    unit: 1,
+    min: Count(8),
+    max: Count(100_000),
+    zero: true,
+};
+/// 对称nat端口预测扫描的范围，上下各这么多个端口
+pub const PUNCH_WINDOW: Spec<Count> = Spec {
+    name: "--punch-window",
+    unit: 1,
+    min: Count(1),
+    max: Count(4096),
+    zero: true,
+};
+/// 不带单位时是秒
+pub const REGISTER_TIMEOUT: Spec<Seconds> = Spec {
+    name: "--register-timeout",
+    unit: 1000,
+    min: Seconds(Duration::from_secs(5)),
+    max: Seconds(Duration::from_secs(7 * 86400)),
+    zero: true,
+};
+/// 不带单位时是秒，心跳间隔3秒，至少容忍丢失一次心跳
+pub const ROUTE_TIMEOUT: Spec<Seconds> = Spec {
+    name: "--route-timeout",
+    unit: 1000,
+    min: Seconds(Duration::from_secs(6)),
+    max: Seconds(Duration::from_secs(600)),
+    zero: false,
+};
+pub const INBOUND_PPS: Spec<Count> = Spec {
+    name: "--inbound-limit",
+    unit: 1,
+    min: Count(100),
+    max: Count(100_000_000),
+    zero: true,
+};
+/// 不带单位时是mbps
+pub const INBOUND_RATE: Spec<BitsPerSec> = Spec {
+    name: "--inbound-limit",
+    unit: 1_000_000,
+    min: BitsPerSec(1_000_000),
+    max: BitsPerSec(1_000_000_000_000),
+    zero: true,
+};
+/// 不带单位时是kbps
+pub const PROBE_BUDGET: Spec<BitsPerSec> = Spec {
+    name: "--probe-budget",
+    unit: 1000,
+    min: BitsPerSec(8000),
+    max: BitsPerSec(100_000_000),
+    zero: true,
+};
+/// 批量申请时会留出8个，下限要比这个多
+pub const SOCKET_LIMIT: Spec<Count> = Spec {
+    name: "--socket-limit",
+    unit: 1,
+    min: Count(16),
+    max: Count(4096),
+    zero: true,
+};
+pub const TCP_FALLBACK: Spec<Count> = Spec {
+    name: "--tcp-fallback",
+    unit: 1,
+    min: Count(2),
+    max: Count(100),
+    zero: true,
+};
+pub const MAX_DEVICES: Spec<Count> = Spec {
+    name: "--max-devices",
+    unit: 1,
+    min: Count(16),
+    max: Count(1_000_000),
+    zero: false,
+};
+pub const EVENT_HISTORY: Spec<Count> = Spec {
+    name: "--event-history",
+    unit: 1,
+    min: Count(100),
+    max: Count(1_000_000),
+    zero: true,
+};
+/// 不带单位时是小时
+pub const PEER_RETENTION: Spec<Seconds> = Spec {
+    name: "--peer-retention",
+    unit: 3_600_000,
+    min: Seconds(Duration::from_secs(3600)),
+    max: Seconds(Duration::from_secs(3650 * 86400)),
+    zero: false,
+};
+/// 不带单位时是mbps
+pub const SHARED_RATE_LIMIT: Spec<BitsPerSec> = Spec {
+    name: "--shared-rate-limit",
+    unit: 1_000_000,
+    min: BitsPerSec(64_000),
+    max: BitsPerSec(1_000_000_000_000),
+    zero: false,
+};
+
+/// 收集所有数值选项的问题，最后一起输出，而不是遇到第一个就退出
+#[derive(Default)]
+pub struct Report {
+    warnings: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 解析并限制在范围内，格式错误时记录并返回None
+    pub fn value<T: Numeric>(&mut self, spec: &Spec<T>, text: Option<&str>) -> Option<T> {
+        let text = text?;
+        match T::parse(text, spec.unit) {
+            Ok(value) => {
+                let (value, warning) = spec.clamp(value);
+                if let Some(warning) = warning {
+                    self.warnings.push(warning);
+                }
+                Some(value)
+            }
+            Err(e) => {
+                self.errors
+                    .push(format!("'{} {}' invalid, {}", spec.name, text, e));
+                None
+            }
+        }
+    }
+    /// 0~1的比例，也可以写成百分比
+    pub fn ratio(&mut self, name: &str, text: Option<&str>) -> Option<f64> {
+        let text = text?;
+        let trimmed = text.trim();
+        let value = match trimmed.strip_suffix('%') {
+            Some(v) => v.trim().parse::<f64>().map(|v| v / 100.0),
+            None => trimmed.parse::<f64>(),
+        };
+        match value {
+            Ok(value) if value.is_finite() => {
+                let clamped = value.clamp(0.0, 1.0);
+                if clamped != value {
+                    self.warnings.push(format!(
+                        "'{} {}' out of range 0..=1, using {}",
+                        name, text, clamped
+                    ));
+                }
+                Some(clamped)
+            }
+            _ => {
+                self.errors.push(format!(
+                    "'{} {}' invalid, expected a ratio like 0.05 or 5%",
+                    name, text
+                ));
+                None
+            }
+        }
+    }
+    pub fn error(&mut self, msg: impl Into<String>) {
+        self.errors.push(msg.into());
+    }
+    pub fn warn(&mut self, msg: impl Into<String>) {
+        self.warnings.push(msg.into());
+    }
+    /// 有错误时返回所有错误，每行一个，否则返回提示
+    pub fn finish(self) -> Result<Vec<String>, String> {
+        if self.errors.is_empty() {
+            Ok(self.warnings)
+        } else {
+            Err(self.errors.join("\n"))
+        }
+    }
+}
+
+/// 需要一起检查的选项
+#[derive(Copy, Clone)]
+pub struct Related {
+    pub mtu: Option<u32>,
+    pub encrypted: bool,
+    pub tcp: bool,
+    pub packet_delay: Duration,
+    pub bring_up_timeout: Duration,
+    /// 0表示不限制
+    pub probe_budget: BitsPerSec,
+    /// 0表示不限制
+    pub shared_rate_limit: BitsPerSec,
+}
+
+/// 底层网络的mtu
+const UNDERLAY_MTU: u32 = 1500;
+/// ip头、udp头、vnt头和加密预留
+const OVERHEAD: u32 = 20 + 8 + 12;
+const ENCRYPTION_OVERHEAD: u32 = 60;
+
+impl Related {
+    pub fn check(&self, report: &mut Report) {
+        if let Some(mtu) = self.mtu {
+            let overhead = OVERHEAD
+                + if self.encrypted {
+                    ENCRYPTION_OVERHEAD
+                } else {
+                    0
+                };
+            if !self.tcp && mtu + overhead > UNDERLAY_MTU {
+                report.warn(format!(
+                    "'-u {}' with {} bytes of overhead exceeds the underlay mtu {}, packets will be fragmented, {} or less avoids it",
+                    mtu,
+                    overhead,
+                    UNDERLAY_MTU,
+                    UNDERLAY_MTU - overhead
+                ));
+            }
+        }
+        if self.packet_delay >= self.bring_up_timeout {
+            report.error(format!(
+                "'--packet-delay {}' must be less than '--bring-up-timeout {}'",
+                Seconds(self.packet_delay),
+                Seconds(self.bring_up_timeout)
+            ));
+        }
+        if !self.probe_budget.is_zero()
+            && !self.shared_rate_limit.is_zero()
+            && self.probe_budget >= self.shared_rate_limit
+        {
+            report.error(format!(
+                "'--probe-budget {}' must be less than '--shared-rate-limit {}'",
+                self.probe_budget, self.shared_rate_limit
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let seconds = [
+            ("30s", 1, Some(30_000)),
+            ("5m", 1, Some(300_000)),
+            ("1.5h", 1, Some(5_400_000)),
+            ("500ms", 1000, Some(500)),
+            ("10000", 1, Some(10_000)),
+            ("3", 1000, Some(3000)),
+            (" 2 s ", 1, Some(2000)),
+            ("5x", 1, None),
+            ("-1s", 1, None),
+            ("s", 1, None),
+            ("inf", 1, None),
+        ];
+        for (text, unit, expect) in seconds {
+            let value = Seconds::parse(text, unit)
+                .ok()
+                .map(|v| v.0.as_millis() as u64);
+            assert_eq!(value, expect, "{}", text);
+        }
+        let bytes = [
+            ("1500", Some(1500)),
+            ("10MB", Some(10_000_000)),
+            ("10kb", Some(10_000)),
+            ("1MiB", Some(1 << 20)),
+            ("2GiB", Some(2 << 30)),
+            ("10mbps", None),
+        ];
+        for (text, expect) in bytes {
+            assert_eq!(Bytes::parse(text, 1).ok().map(|v| v.0), expect, "{}", text);
+        }
+        let rates = [
+            ("20mbps", 1, Some(20_000_000)),
+            ("64kbps", 1, Some(64_000)),
+            ("1gbps", 1, Some(1_000_000_000)),
+            ("10MB/s", 1, Some(80_000_000)),
+            // 不带单位时按选项的单位
+            ("10", 1_000_000, Some(10_000_000)),
+            ("0.5", 1_000_000, Some(500_000)),
+            ("64", 1000, Some(64_000)),
+            ("10MB", 1, None),
+        ];
+        for (text, unit, expect) in rates {
+            let value = BitsPerSec::parse(text, unit).ok().map(|v| v.0);
+            assert_eq!(value, expect, "{}", text);
+        }
+        let counts = [
+            ("64", Some(64)),
+            ("20k", Some(20_000)),
+            ("1.5k", Some(1500)),
+            ("1.5", None),
+            ("abc", None),
+        ];
+        for (text, expect) in counts {
+            assert_eq!(Count::parse(text, 1).ok().map(|v| v.0), expect, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Seconds(Duration::from_secs(300)).to_string(), "5m");
+        assert_eq!(Seconds(Duration::from_millis(1500)).to_string(), "1500ms");
+        assert_eq!(Seconds(Duration::ZERO).to_string(), "0ms");
+        assert_eq!(BitsPerSec(20_000_000).to_string(), "20mbps");
+        assert_eq!(BitsPerSec(64_500).to_string(), "64500bps");
+        assert_eq!(Bytes(1410).to_string(), "1410B");
+        assert_eq!(Count(7).to_string(), "7");
+    }
+
+    /// 文档中的上下限，超出时取边界值并提示
+    #[test]
+    fn test_bounds() {
+        fn check<T: Numeric + std::fmt::Debug>(
+            spec: &Spec<T>,
+            cases: &[(&str, Option<&str>, bool)],
+        ) {
+            for (text, expect, warned) in cases {
+                let mut report = Report::new();
+                let value = report.value(spec, Some(*text));
+                assert_eq!(
+                    value.map(|v| v.to_string()).as_deref(),
+                    *expect,
+                    "{} {}",
+                    spec.name,
+                    text
+                );
+                let errors = expect.is_none();
+                let warnings = report.warnings.len();
+                assert_eq!(report.finish().is_err(), errors, "{} {}", spec.name, text);
+                if !errors {
+                    assert_eq!(warnings == 1, *warned, "{} {}", spec.name, text);
+                }
+            }
+        }
+        check(
+            &MTU,
+            &[
+                ("1410", Some("1410B"), false),
+                ("65536", Some("9000B"), true),
+                ("100", Some("576B"), true),
+                ("0", Some("576B"), true),
+                ("1.4k", Some("1400B"), false),
+                ("mtu", None, false),
+            ],
+        );
+        check(
+            &PAR,
+            &[
+                ("0", Some("1"), true),
+                ("4", Some("4"), false),
+                ("1000", Some("64"), true),
+            ],
+        );
+        check(
+            &PORT,
+            &[
+                ("0", Some("0"), false),
+                ("29872", Some("29872"), false),
+                ("70000", Some("65535"), true),
+            ],
+        );
+        check(
+            &PACKET_DELAY,
+            &[
+                ("0", Some("0ms"), false),
+                ("50", Some("50ms"), false),
+                ("1m", Some("10s"), true),
+            ],
+        );
+        check(
+            &BRING_UP_RELAY,
+            &[("0", Some("0"), false), ("64", Some("64"), false)],
+        );
+        check(
+            &BRING_UP_TIMEOUT,
+            &[
+                ("10000", Some("10s"), false),
+                ("30s", Some("30s"), false),
+                ("0", Some("500ms"), true),
+                ("1h", Some("10m"), true),
+            ],
+        );
+        check(
+            &REGISTER_TIMEOUT,
+            &[
+                ("0", Some("0ms"), false),
+                ("300", Some("5m"), false),
+                ("1s", Some("5s"), true),
+            ],
+        );
+        check(
+            &ROUTE_TIMEOUT,
+            &[
+                ("10", Some("10s"), false),
+                ("1m", Some("1m"), false),
+                ("3s", Some("6s"), true),
+                ("1h", Some("10m"), true),
+            ],
+        );
+        check(
+            &PUNCH_WINDOW,
+            &[
+                ("64", Some("64"), false),
+                ("0", Some("0"), false),
+                ("10000", Some("4096"), true),
+            ],
+        );
+        check(
+            &INBOUND_PPS,
+            &[
+                ("0", Some("0"), false),
+                ("20000", Some("20000"), false),
+                ("10", Some("100"), true),
+            ],
+        );
+        check(
+            &INBOUND_RATE,
+            &[
+                ("200", Some("200mbps"), false),
+                ("0", Some("0bps"), false),
+                ("100kbps", Some("1mbps"), true),
+            ],
+        );
+        check(
+            &PROBE_BUDGET,
+            &[
+                ("64", Some("64kbps"), false),
+                ("1kbps", Some("8kbps"), true),
+                ("0", Some("0bps"), false),
+            ],
+        );
+        check(
+            &SOCKET_LIMIT,
+            &[
+                ("0", Some("0"), false),
+                ("8", Some("16"), true),
+                ("1m", Some("4096"), true),
+            ],
+        );
+        check(
+            &TCP_FALLBACK,
+            &[
+                ("0", Some("0"), false),
+                ("1", Some("2"), true),
+                ("6", Some("6"), false),
+            ],
+        );
+        check(
+            &MAX_DEVICES,
+            &[
+                ("10k", Some("10000"), false),
+                ("0", Some("16"), true),
+                ("2m", Some("1000000"), true),
+            ],
+        );
+        check(
+            &EVENT_HISTORY,
+            &[
+                ("5k", Some("5000"), false),
+                ("0", Some("0"), false),
+                ("10", Some("100"), true),
+            ],
+        );
+        check(
+            &SHARED_RATE_LIMIT,
+            &[
+                ("10", Some("10mbps"), false),
+                ("0", Some("64kbps"), true),
+                ("10gbps", Some("10gbps"), false),
+                ("10g", None, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::new();
+        assert_eq!(report.value(&MTU, None), None);
+        assert_eq!(report.value(&MTU, Some("abc")), None);
+        assert_eq!(report.value(&PAR, Some("-1")), None);
+        assert_eq!(report.ratio("--packet-loss", Some("5%")), Some(0.05));
+        assert_eq!(report.ratio("--packet-loss", Some("2")), Some(1.0));
+        assert_eq!(report.ratio("--packet-loss", Some("x")), None);
+        // 所有问题一起列出
+        let errors = report.finish().unwrap_err();
+        assert_eq!(errors.lines().count(), 3, "{}", errors);
+        assert!(errors.contains("'-u abc'"));
+        assert!(errors.contains("'--par -1'"));
+        assert!(errors.contains("'--packet-loss x'"));
+    }
+
+    #[test]
+    fn test_related() {
+        let base = Related {
+            mtu: None,
+            encrypted: false,
+            tcp: false,
+            packet_delay: Duration::ZERO,
+            bring_up_timeout: Duration::from_secs(10),
+            probe_budget: BitsPerSec(64_000),
+            shared_rate_limit: BitsPerSec(0),
+        };
+        let cases = [
+            (base, 0, 0),
+            (
+                Related {
+                    mtu: Some(1450),
+                    ..base
+                },
+                0,
+                0,
+            ),
+            (
+                Related {
+                    mtu: Some(1450),
+                    encrypted: true,
+                    ..base
+                },
+                1,
+                0,
+            ),
+            (
+                Related {
+                    mtu: Some(9000),
+                    tcp: true,
+                    ..base
+                },
+                0,
+                0,
+            ),
+            (
+                Related {
+                    packet_delay: Duration::from_secs(10),
+                    ..base
+                },
+                0,
+                1,
+            ),
+            (
+                Related {
+                    shared_rate_limit: BitsPerSec(64_000),
+                    ..base
+                },
+                0,
+                1,
+            ),
+            (
+                Related {
+                    mtu: Some(9000),
+                    packet_delay: Duration::from_secs(10),
+                    shared_rate_limit: BitsPerSec(64_000),
+                    ..base
+                },
+                1,
+                2,
+            ),
+        ];
+        for (i, (related, warnings, errors)) in cases.into_iter().enumerate() {
+            let mut report = Report::new();
+            related.check(&mut report);
+            assert_eq!(report.warnings.len(), warnings, "case {}", i);
+            assert_eq!(report.errors.len(), errors, "case {}", i);
+        }
+    }
+}
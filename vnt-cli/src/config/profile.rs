@@ -0,0 +1,329 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+/// 预设的使用场景
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Profile {
+    /// 移动设备，省电优先：单线程、单端口、限制后台探测、不做端口预测打洞、少占内存
+    Mobile,
+    /// 服务器，性能优先：多线程、多端口、优先低延迟、更快切换路径、记录延迟历史
+    Server,
+    /// 网关，在server的基础上打开点对网转发相关的默认值
+    Gateway,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "mobile" => Ok(Profile::Mobile),
+            "server" => Ok(Profile::Server),
+            "gateway" => Ok(Profile::Gateway),
+            _ => Err(format!("not match '{}', enum: mobile/server/gateway", s)),
+        }
+    }
+}
+
+impl Profile {
+    fn index(&self) -> usize {
+        match self {
+            Profile::Mobile => 0,
+            Profile::Server => 1,
+            Profile::Gateway => 2,
+        }
+    }
+}
+
+/// 配置项的取值来源，后面的覆盖前面的
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum Layer {
+    Default,
+    Profile,
+    File,
+    Cli,
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::Default => f.write_str("default"),
+            Layer::Profile => f.write_str("profile"),
+            Layer::File => f.write_str("file"),
+            Layer::Cli => f.write_str("cli"),
+        }
+    }
+}
+
+/// 预设中的取值
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Preset {
+    /// 沿用默认值
+    Inherit,
+    Value(&'static str),
+}
+
+use Preset::{Inherit, Value};
+
+/// 可以由预设调整的配置项
+pub const TUNABLES: [&str; 11] = [
+    "par",
+    "ports",
+    "first-latency",
+    "no-proxy",
+    "probe-budget",
+    "punch-window",
+    "socket-limit",
+    "event-history",
+    "route-timeout",
+    "tcp-fallback",
+    "rtt-history",
+];
+
+/// 各预设的取值，顺序为 mobile, server, gateway
+const PRESETS: [(&str, [Preset; 3]); 11] = [
+    // 处理虚拟网卡数据的线程数
+    ("par", [Value("1"), Value("4"), Value("4")]),
+    // 监听的端口数量
+    ("ports", [Value("1"), Value("4"), Value("4")]),
+    ("first-latency", [Inherit, Value("true"), Value("true")]),
+    // 网关需要内置代理转发点对网数据
+    ("no-proxy", [Inherit, Inherit, Value("false")]),
+    // 省电：心跳以外的后台探测按计量网络限制
+    ("probe-budget", [Value("metered"), Inherit, Inherit]),
+    // 不对对称nat做端口预测的批量打洞，只打观察到的端口
+    ("punch-window", [Value("0"), Inherit, Inherit]),
+    // 少开辅助socket，少保留事件
+    ("socket-limit", [Value("16"), Inherit, Inherit]),
+    ("event-history", [Value("1000"), Inherit, Inherit]),
+    // 直连中断后更快改走中继，服务器不通时更快改用tcp
+    ("route-timeout", [Inherit, Value("6"), Value("6")]),
+    ("tcp-fallback", [Inherit, Value("3"), Value("3")]),
+    // 记录对端的延迟历史
+    ("rtt-history", [Inherit, Value("true"), Value("true")]),
+];
+
+/// 生效的配置项
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigItem {
+    pub key: String,
+    pub value: String,
+    pub layer: Layer,
+}
+
+/// 按 默认值 → 预设 → 配置文件 → 命令行 的顺序确定配置项的值，并记录来源
+pub struct Resolver {
+    profile: Option<Profile>,
+    items: Vec<ConfigItem>,
+}
+
+impl Resolver {
+    pub fn new(profile: Option<Profile>) -> Self {
+        Self {
+            profile,
+            items: Vec::with_capacity(TUNABLES.len()),
+        }
+    }
+    fn preset(&self, key: &str) -> Option<&'static str> {
+        let profile = self.profile?;
+        let (_, presets) = PRESETS.iter().find(|(k, _)| *k == key)?;
+        match presets[profile.index()] {
+            Inherit => None,
+            Value(v) => Some(v),
+        }
+    }
+    pub fn resolve<T: FromStr + ToString>(
+        &mut self,
+        key: &'static str,
+        default: T,
+        file: Option<T>,
+        cli: Option<T>,
+    ) -> T {
+        let mut value = (default, Layer::Default);
+        if let Some(preset) = self.preset(key) {
+            match preset.parse() {
+                Ok(v) => value = (v, Layer::Profile),
+                Err(_) => log::warn!("预设值无效 {}={}", key, preset),
+            }
+        }
+        if let Some(v) = file {
+            value = (v, Layer::File);
+        }
+        if let Some(v) = cli {
+            value = (v, Layer::Cli);
+        }
+        self.items.retain(|item| item.key != key);
+        self.items.push(ConfigItem {
+            key: key.to_string(),
+            value: value.0.to_string(),
+            layer: value.1,
+        });
+        value.0
+    }
+    /// 和'resolve'相同，没有预设、配置文件和命令行的值时返回None，调用方保留原来的默认值
+    pub fn resolve_text(
+        &mut self,
+        key: &'static str,
+        default: String,
+        file: Option<String>,
+        cli: Option<String>,
+    ) -> Option<String> {
+        let value = self.resolve(key, default, file, cli);
+        match self.items.last() {
+            Some(item) if item.layer != Layer::Default => Some(value),
+            _ => None,
+        }
+    }
+    /// '-f'配置文件中的值
+    pub fn extend(&mut self, items: Vec<ConfigItem>) {
+        for item in items {
+            self.items.retain(|v| v.key != item.key);
+            self.items.push(item);
+        }
+    }
+    pub fn items(self) -> Vec<ConfigItem> {
+        self.items
+    }
+}
+
+/// 使用配置文件时，配置项都由配置文件决定
+pub fn from_file(config: &vnt::core::Config) -> Vec<ConfigItem> {
+    let ports = config.ports.as_ref().map_or(2, |v| v.len());
+    let mut items = vec![
+        ("par", config.parallel.to_string()),
+        ("ports", ports.to_string()),
+        ("first-latency", config.first_latency.to_string()),
+    ];
+    #[cfg(feature = "ip_proxy")]
+    items.push(("no-proxy", config.no_proxy.to_string()));
+    items
+        .into_iter()
+        .map(|(key, value)| ConfigItem {
+            key: key.to_string(),
+            value,
+            layer: Layer::File,
+        })
+        .collect()
+}
+
+/// 开关类的取值，配置文件和'--first-latency=false'这样的命令行参数使用
+pub fn parse_switch(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "on" | "1" => Ok(true),
+        "false" | "off" | "0" => Ok(false),
+        _ => Err(format!("'{}' invalid, expected true/false", value)),
+    }
+}
+
+/// 开关类的命令行参数，只写参数名表示打开，'--name=false'可以关闭预设或配置文件打开的值
+pub fn switch(matches: &getopts::Matches, name: &str) -> Result<Option<bool>, String> {
+    if !matches.opt_present(name) {
+        return Ok(None);
+    }
+    match matches.opt_str(name) {
+        Some(value) => parse_switch(&value)
+            .map(Some)
+            .map_err(|e| format!("'--{}' {}", name, e)),
+        None => Ok(Some(true)),
+    }
+}
+
+static EFFECTIVE: OnceLock<Vec<ConfigItem>> = OnceLock::new();
+
+/// 启动时记录生效的配置，供'--show-config'查看
+pub fn set_effective(items: Vec<ConfigItem>) {
+    let _ = EFFECTIVE.set(items);
+}
+
+pub fn effective() -> Vec<ConfigItem> {
+    EFFECTIVE.get().cloned().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::profile::{Layer, Profile, Resolver, PRESETS, TUNABLES};
+
+    #[test]
+    fn test_precedence() {
+        let mut resolver = Resolver::new(Some(Profile::Server));
+        assert_eq!(resolver.resolve("par", 1usize, None, None), 4);
+        assert_eq!(resolver.resolve("par", 1usize, Some(2), None), 2);
+        assert_eq!(resolver.resolve("par", 1usize, Some(2), Some(3)), 3);
+        assert!(!resolver.resolve("no-proxy", false, None, None));
+        let items = resolver.items();
+        assert_eq!(items.len(), 2);
+        let par = items.iter().find(|v| v.key == "par").unwrap();
+        assert_eq!((par.value.as_str(), par.layer), ("3", Layer::Cli));
+        let no_proxy = items.iter().find(|v| v.key == "no-proxy").unwrap();
+        assert_eq!(no_proxy.layer, Layer::Default);
+
+        let mut resolver = Resolver::new(None);
+        assert_eq!(resolver.resolve("par", 1usize, None, None), 1);
+        assert_eq!(resolver.items()[0].layer, Layer::Default);
+
+        let mut resolver = Resolver::new(Some(Profile::Gateway));
+        assert!(!resolver.resolve("no-proxy", true, None, None));
+        assert_eq!(resolver.items()[0].layer, Layer::Profile);
+
+        // 命令行可以关闭预设和配置文件打开的开关
+        let mut resolver = Resolver::new(Some(Profile::Server));
+        assert!(!resolver.resolve("first-latency", false, Some(true), Some(false)));
+        assert_eq!(resolver.items()[0].layer, Layer::Cli);
+
+        let mut resolver = Resolver::new(Some(Profile::Mobile));
+        assert_eq!(
+            resolver.resolve_text("probe-budget", "64".into(), None, None),
+            Some("metered".to_string())
+        );
+        assert_eq!(
+            resolver.resolve_text("route-timeout", "10".into(), None, None),
+            None
+        );
+        assert_eq!(
+            resolver.resolve_text("route-timeout", "10".into(), Some("30".into()), None),
+            Some("30".to_string())
+        );
+    }
+
+    #[test]
+    fn test_switch() {
+        assert_eq!(super::parse_switch("false"), Ok(false));
+        assert_eq!(super::parse_switch(" On"), Ok(true));
+        assert!(super::parse_switch("yes please").is_err());
+    }
+
+    /// 每个配置项在预设表中恰好出现一次，每个预设都要给出取值或者明确沿用默认值
+    #[test]
+    fn test_coverage() {
+        for key in TUNABLES {
+            assert_eq!(
+                PRESETS.iter().filter(|(k, _)| *k == key).count(),
+                1,
+                "{}",
+                key
+            );
+        }
+        assert_eq!(PRESETS.len(), TUNABLES.len());
+        for profile in [Profile::Mobile, Profile::Server, Profile::Gateway] {
+            let mut resolver = Resolver::new(Some(profile));
+            for key in TUNABLES {
+                resolver.resolve(key, String::new(), None, None);
+            }
+            let items = resolver.items();
+            assert_eq!(items.len(), TUNABLES.len());
+            for (key, presets) in PRESETS {
+                let item = items.iter().find(|v| v.key == key).unwrap();
+                match presets[profile.index()] {
+                    super::Preset::Inherit => assert_eq!(item.layer, Layer::Default),
+                    super::Preset::Value(v) => {
+                        assert_eq!(item.layer, Layer::Profile);
+                        assert_eq!(item.value, v);
+                    }
+                }
+            }
+        }
+    }
+}
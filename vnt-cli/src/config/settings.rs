@@ -0,0 +1,346 @@
+use std::path::Path;
+
+use getopts::Matches;
+
+/// '--gen-config'输出的模板
+pub const TEMPLATE: &str = r#"# vnt配置文件，使用'vnt-cli --config <path>'加载，命令行参数优先于这里的值
+# 文件中有token，建议只允许当前用户读取：chmod 600 <path>
+
+# 组网标识，同'-k'，必填
+token = ""
+
+# 注册和中继服务器地址，同'-s'
+# server = "nat1.wherewego.top:29872"
+
+# 设备名称，同'-n'，默认使用系统版本
+# name = "my-pc"
+
+# 本地监听的端口，同'--ports'，0表示随机端口
+# port = 0
+
+# 日志级别，同'--log-level'
+# log_level = "info,punch=debug"
+
+# 日志文件，同'--log-path'，"-"表示输出到stderr
+# log_path = "/var/log/vnt/vnt.log"
+
+# 以下的值覆盖'--profile'的预设，命令行参数优先，含义同名称相同的参数
+# par = 1
+# first_latency = false
+# no_proxy = false
+# probe_budget = "64kbps"
+# punch_window = 64
+# socket_limit = 64
+# event_history = 5000
+# route_timeout = "10s"
+# tcp_fallback = 6
+# rtt_history = false
+"#;
+
+/// 配置文件中的键
+const KEYS: [&str; 6] = ["token", "server", "name", "port", "log_level", "log_path"];
+
+/// 配置文件中可以由预设调整的键，对应的参数名把'_'换成'-'
+const TUNABLE_KEYS: [&str; 10] = [
+    "par",
+    "first_latency",
+    "no_proxy",
+    "probe_budget",
+    "punch_window",
+    "socket_limit",
+    "event_history",
+    "route_timeout",
+    "tcp_fallback",
+    "rtt_history",
+];
+
+/// 命令行参数和'--config'配置文件共同决定的配置，命令行参数优先
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Settings {
+    pub token: Option<String>,
+    pub server: Option<String>,
+    pub name: Option<String>,
+    /// 和'--ports'的格式相同
+    pub ports: Option<String>,
+    pub log_level: Option<String>,
+    /// 文件路径，"-"表示stderr
+    pub log_path: Option<String>,
+    /// '--config'指定的文件
+    file: Option<String>,
+    /// 取自配置文件的键
+    from_file: Vec<&'static str>,
+    /// 配置文件中可以由预设调整的值，和命令行参数的先后由Resolver决定
+    tunables: Vec<(&'static str, String)>,
+}
+
+impl Settings {
+    pub fn from_matches(matches: &Matches) -> Self {
+        Self {
+            token: matches.opt_str("k"),
+            server: matches.opt_str("s"),
+            name: matches.opt_str("n"),
+            ports: matches.opt_str("ports"),
+            #[cfg(feature = "log")]
+            log_level: matches.opt_str("log-level"),
+            #[cfg(not(feature = "log"))]
+            log_level: None,
+            #[cfg(feature = "log")]
+            log_path: matches.opt_str("log-path"),
+            #[cfg(not(feature = "log"))]
+            log_path: None,
+            file: None,
+            from_file: Vec::new(),
+            tunables: Vec::new(),
+        }
+    }
+    /// 命令行参数，指定了'--config'时合并配置文件
+    pub fn from_args(matches: &Matches) -> Result<Self, String> {
+        let settings = Self::from_matches(matches);
+        match matches.opt_str("config") {
+            Some(path) => match Self::load(&path) {
+                Ok(file) => Ok(settings.merge(file)),
+                Err(e) => Err(format!("'--config' {}", e)),
+            },
+            None => Ok(settings),
+        }
+    }
+    /// 解析失败时的错误信息指出是哪个键
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| e.to_string().trim().to_string())?;
+        let mut settings = Settings::default();
+        for (key, value) in table {
+            if let Some(key) = TUNABLE_KEYS.iter().find(|k| **k == key) {
+                let text = match &value {
+                    toml::Value::String(v) => v.trim().to_string(),
+                    toml::Value::Integer(v) => v.to_string(),
+                    toml::Value::Boolean(v) => v.to_string(),
+                    _ => {
+                        return Err(format!(
+                            "'{}' expected a string, integer or boolean, found {}",
+                            key,
+                            value.type_str()
+                        ))
+                    }
+                };
+                settings.tunables.push((*key, text));
+                continue;
+            }
+            let key = match KEYS.iter().find(|k| **k == key) {
+                Some(key) => *key,
+                None => {
+                    return Err(format!(
+                        "unknown key '{}', available: {},{}",
+                        key,
+                        KEYS.join(","),
+                        TUNABLE_KEYS.join(",")
+                    ))
+                }
+            };
+            match key {
+                "port" => {
+                    let port = value.as_integer().ok_or_else(|| {
+                        format!("'port' expected an integer, found {}", value.type_str())
+                    })?;
+                    if !(0..=u16::MAX as i64).contains(&port) {
+                        return Err(format!("'port' {} out of range 0..=65535", port));
+                    }
+                    settings.ports = Some(port.to_string());
+                }
+                _ => {
+                    let text = value.as_str().ok_or_else(|| {
+                        format!("'{}' expected a string, found {}", key, value.type_str())
+                    })?;
+                    if text.trim().is_empty() {
+                        return Err(format!("'{}' is empty", key));
+                    }
+                    let text = Some(text.trim().to_string());
+                    match key {
+                        "token" => settings.token = text,
+                        "server" => settings.server = text,
+                        "name" => settings.name = text,
+                        "log_level" => settings.log_level = text,
+                        _ => settings.log_path = text,
+                    }
+                }
+            }
+            settings.from_file.push(key);
+        }
+        #[cfg(feature = "log")]
+        if let Some(spec) = &settings.log_level {
+            crate::log_level::parse_spec(spec).map_err(|e| format!("'log_level' {}", e))?;
+        }
+        Ok(settings)
+    }
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{} {}", path, e))?;
+        let mut settings = Self::from_toml(&text).map_err(|e| format!("{}: {}", path, e))?;
+        settings.file = Some(path.to_string());
+        if settings.token.is_some() {
+            warn_readable(Path::new(path));
+        }
+        Ok(settings)
+    }
+    /// 命令行参数覆盖配置文件
+    pub fn merge(self, file: Settings) -> Settings {
+        let from_file = file
+            .from_file
+            .iter()
+            .copied()
+            .filter(|key| match *key {
+                "token" => self.token.is_none(),
+                "server" => self.server.is_none(),
+                "name" => self.name.is_none(),
+                "port" => self.ports.is_none(),
+                "log_level" => self.log_level.is_none(),
+                _ => self.log_path.is_none(),
+            })
+            .collect();
+        Settings {
+            token: self.token.or(file.token),
+            server: self.server.or(file.server),
+            name: self.name.or(file.name),
+            ports: self.ports.or(file.ports),
+            log_level: self.log_level.or(file.log_level),
+            log_path: self.log_path.or(file.log_path),
+            file: file.file,
+            from_file,
+            tunables: file.tunables,
+        }
+    }
+    /// 配置文件中的值，key是参数名，如'first-latency'
+    pub fn tunable(&self, key: &str) -> Option<String> {
+        self.tunables
+            .iter()
+            .find(|(k, _)| k.replace('_', "-") == key)
+            .map(|(_, v)| v.clone())
+    }
+    /// '--config'指定的文件
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+    /// 生效的值是否取自配置文件
+    pub fn is_from_file(&self, key: &str) -> bool {
+        self.from_file.contains(&key)
+    }
+}
+
+#[cfg(unix)]
+fn warn_readable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            println!(
+                "warning: {} contains the token and is readable by other users, 'chmod 600' is recommended",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_readable(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::settings::{Settings, TEMPLATE};
+
+    #[test]
+    fn test_from_toml() {
+        // 模板本身要能解析，只有token为空
+        let template = TEMPLATE.replace("token = \"\"", "token = \"abc\"");
+        let settings = Settings::from_toml(&template).unwrap();
+        assert_eq!(settings.token.as_deref(), Some("abc"));
+        assert_eq!(settings.server, None);
+        assert_eq!(
+            Settings::from_toml(TEMPLATE).unwrap_err(),
+            "'token' is empty"
+        );
+
+        let settings = Settings::from_toml(
+            "token = \"abc\"\nserver = \"1.2.3.4:29872\"\nname = \"pc\"\nport = 29873\n",
+        )
+        .unwrap();
+        assert_eq!(settings.ports.as_deref(), Some("29873"));
+        assert_eq!(settings.name.as_deref(), Some("pc"));
+
+        let e = Settings::from_toml("tokn = \"abc\"").unwrap_err();
+        assert!(e.starts_with("unknown key 'tokn'"), "{}", e);
+        let e = Settings::from_toml("token = 123").unwrap_err();
+        assert_eq!(e, "'token' expected a string, found integer");
+        let e = Settings::from_toml("port = 70000").unwrap_err();
+        assert_eq!(e, "'port' 70000 out of range 0..=65535");
+        let e = Settings::from_toml("port = \"1\"").unwrap_err();
+        assert_eq!(e, "'port' expected an integer, found string");
+        assert!(Settings::from_toml("token = ").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let file =
+            Settings::from_toml("token = \"file\"\nserver = \"s:1\"\nport = 1\nlog_path = \"-\"")
+                .unwrap();
+        let cli = Settings {
+            token: Some("cli".to_string()),
+            ..Default::default()
+        };
+        let settings = cli.merge(file);
+        assert_eq!(settings.token.as_deref(), Some("cli"));
+        assert_eq!(settings.server.as_deref(), Some("s:1"));
+        assert!(!settings.is_from_file("token"));
+        assert!(settings.is_from_file("server"));
+        assert!(settings.is_from_file("port"));
+        assert_eq!(settings.log_path.as_deref(), Some("-"));
+        assert!(settings.is_from_file("log_path"));
+    }
+
+    #[test]
+    fn test_from_args() {
+        let path = std::env::temp_dir().join(format!("vnt-settings-{}.toml", std::process::id()));
+        std::fs::write(&path, "token = \"file\"\nserver = \"s:1\"\n").unwrap();
+        let mut opts = getopts::Options::new();
+        for name in ["k", "s", "n"] {
+            opts.optopt(name, "", "", "");
+        }
+        for name in ["ports", "log-level", "log-path", "config"] {
+            opts.optopt("", name, "", "");
+        }
+        let path_str = path.to_string_lossy().to_string();
+        let matches = opts
+            .parse(["-k", "cli", "--config", path_str.as_str()])
+            .unwrap();
+        let settings = Settings::from_args(&matches).unwrap();
+        assert_eq!(settings.token.as_deref(), Some("cli"));
+        assert_eq!(settings.server.as_deref(), Some("s:1"));
+        assert_eq!(settings.file(), Some(path_str.as_str()));
+        std::fs::remove_file(&path).unwrap();
+
+        let settings = Settings::from_args(&opts.parse(["-k", "cli"]).unwrap()).unwrap();
+        assert_eq!(settings.file(), None);
+        // '--config'只接受路径
+        assert!(opts.parse(["--config"]).is_err());
+        let e =
+            Settings::from_args(&opts.parse(["--config", path_str.as_str()]).unwrap()).unwrap_err();
+        assert!(e.starts_with("'--config' "), "{}", e);
+    }
+
+    #[test]
+    fn test_tunables() {
+        let file = Settings::from_toml(
+            "par = 2\nfirst_latency = false\nroute_timeout = \"30s\"\nprobe_budget = \"metered\"",
+        )
+        .unwrap();
+        let settings = Settings::default().merge(file);
+        assert_eq!(settings.tunable("par").as_deref(), Some("2"));
+        assert_eq!(settings.tunable("first-latency").as_deref(), Some("false"));
+        assert_eq!(settings.tunable("route-timeout").as_deref(), Some("30s"));
+        assert_eq!(settings.tunable("probe-budget").as_deref(), Some("metered"));
+        assert_eq!(settings.tunable("tcp-fallback"), None);
+        let e = Settings::from_toml("par = [1]").unwrap_err();
+        assert_eq!(
+            e,
+            "'par' expected a string, integer or boolean, found array"
+        );
+    }
+}
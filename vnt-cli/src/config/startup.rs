@@ -0,0 +1,734 @@
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use common::args_parse::{ips_parse, out_ips_parse};
+use getopts::{Matches, Options};
+use vnt::channel::punch::PunchModel;
+use vnt::channel::UseChannelType;
+use vnt::cipher::CipherModel;
+use vnt::tun_tap_device::existing_tun::ExistingTun;
+
+use crate::config::numeric::{self, Report};
+use crate::config::profile::{Profile, Resolver};
+use crate::config::settings::Settings;
+use crate::exit::ExitReason;
+use crate::{
+    clean, config, daemon, data_dir, exit, policy, register_wait, retention, safe_mode, telemetry,
+    ui, warm_restart, DEFAULT_SERVER,
+};
+
+/// 命令行参数、'--config'配置文件和'-f'配置文件共同决定的启动配置
+pub struct Config {
+    pub vnt: vnt::core::Config,
+    /// 是否读取控制台输入
+    pub cmd: bool,
+    /// 完成网卡配置后切换到的用户和组
+    pub drop_user: Option<(String, Option<String>)>,
+    pub health_listen: Option<SocketAddr>,
+    pub rtt_history: bool,
+    pub telemetry: Option<String>,
+    pub retention: retention::Retention,
+    /// 启动后多久没有注册成功就退出，为0时不限制
+    pub register_timeout: Duration,
+}
+
+impl Config {
+    /// 校验并合并所有来源的配置，出错时输出原因并退出
+    pub fn resolve(program: &str, opts: &Options, matches: &Matches, settings: Settings) -> Self {
+        let profile = match matches.opt_get::<Profile>("profile") {
+            Ok(profile) => profile,
+            Err(e) => {
+                exit::config_error(format!("'--profile' invalid,{}", e));
+            }
+        };
+        let conf = matches.opt_str("f");
+        let mut report = Report::new();
+        if conf.is_some() && settings.file().is_some() {
+            exit::config_error("'-f' and '--config' cannot be used together");
+        }
+        let register_timeout = report
+            .value(
+                &numeric::REGISTER_TIMEOUT,
+                matches.opt_str("register-timeout").as_deref(),
+            )
+            .map_or(Duration::ZERO, |v| v.0);
+        let mut resolver = Resolver::new(profile);
+        // '--config'配置文件中的值，'-f'和'--config'不能同时使用
+        let file_switch = |key: &str| {
+            settings
+                .tunable(key)
+                .map(|v| match config::profile::parse_switch(&v) {
+                    Ok(v) => v,
+                    Err(e) => exit::config_error(format!("'{}' {}", key.replace('-', "_"), e)),
+                })
+        };
+        let cli_switch = |key: &str| match config::profile::switch(matches, key) {
+            Ok(v) => v,
+            Err(e) => exit::config_error(e),
+        };
+        let (config, cmd) = if let Some(conf) = conf {
+            match register_wait::retry(register_timeout, || config::read_config(&conf)) {
+                Ok((config, cmd)) => {
+                    if profile.is_some() {
+                        println!("'-f' sets par,ports,first-latency,no-proxy, '--profile' applies to the other values");
+                    }
+                    resolver.extend(config::profile::from_file(&config));
+                    // 配置文件中没有设备标识时读取配置的过程中已经生成
+                    config::set_device_id_source("config file");
+                    (config, cmd)
+                }
+                Err(e) if register_wait::is_unreachable(&e) => register_wait::unreachable(e),
+                Err(e) => {
+                    exit::config_error(format!("conf err {}", e));
+                }
+            }
+        } else {
+            let token = match settings.token.clone() {
+                Some(token) => token,
+                None => {
+                    crate::print_usage(program, opts);
+                    exit::config_error("parameter -k not found .");
+                }
+            };
+            #[cfg(target_os = "windows")]
+            let tap = matches.opt_present("a");
+            let device_name = matches.opt_str("nic");
+            let device_id = matches.opt_get_default("d", String::new()).unwrap();
+            let device_id = if device_id.is_empty() {
+                config::get_device_id()
+            } else {
+                config::set_device_id_source("--device-id");
+                device_id
+            };
+            if device_id.is_empty() {
+                crate::print_usage(program, opts);
+                exit::config_error("parameter -d not found .");
+            }
+            let name = settings
+                .name
+                .clone()
+                .or_else(config::hostname)
+                .unwrap_or_else(|| os_info::get().to_string());
+            let server_address_str = settings
+                .server
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+
+            let mut stun_server = matches.opt_strs("e");
+            if stun_server.is_empty() {
+                stun_server.push("stun1.l.google.com:19302".to_string());
+                stun_server.push("stun2.l.google.com:19302".to_string());
+                stun_server.push("stun.miwifi.com:3478".to_string());
+            }
+            let dns = matches.opt_strs("dns");
+            if let Err(e) = register_wait::retry(register_timeout, || {
+                Ok(vnt::core::resolve_server(&server_address_str, &dns)?)
+            }) {
+                register_wait::unreachable(e);
+            }
+            let in_ip = matches.opt_strs("i");
+            let in_ip = match ips_parse(&in_ip) {
+                Ok(in_ip) => in_ip,
+                Err(e) => {
+                    crate::print_usage(program, opts);
+                    println!();
+                    println!("-i: {:?} {}", in_ip, e);
+                    println!("example: -i 192.168.0.0/24,10.26.0.3");
+                    exit::exit(ExitReason::ConfigError, format!("-i: {:?} {}", in_ip, e));
+                }
+            };
+            let out_ip = matches.opt_strs("o");
+            let out_ip = match out_ips_parse(&out_ip) {
+                Ok(out_ip) => out_ip,
+                Err(e) => {
+                    crate::print_usage(program, opts);
+                    println!();
+                    println!("-o: {:?} {}", out_ip, e);
+                    println!("example: -o 0.0.0.0/0");
+                    exit::exit(ExitReason::ConfigError, format!("-o: {:?} {}", out_ip, e));
+                }
+            };
+            let password = match (matches.opt_str("w"), matches.opt_str("key")) {
+                (Some(w), Some(key)) if w != key => {
+                    exit::config_error("'-w' and '--key' are the same option, use only one");
+                }
+                (w, key) => w.or(key),
+            };
+            let server_encrypt = matches.opt_present("W");
+            #[cfg(not(feature = "server_encrypt"))]
+            {
+                if server_encrypt {
+                    exit::config_error("Server encryption not supported");
+                }
+            }
+            let mtu = report
+                .value(&numeric::MTU, matches.opt_str("u").as_deref())
+                .map(|v| v.0 as u32);
+            let virtual_ip = match matches.opt_get::<Ipv4Addr>("ip") {
+                Ok(ip) => ip,
+                Err(e) => exit::config_error(format!("'--ip' invalid,{}", e)),
+            };
+            if let Some(virtual_ip) = virtual_ip {
+                if virtual_ip.is_unspecified()
+                    || virtual_ip.is_broadcast()
+                    || virtual_ip.is_multicast()
+                {
+                    exit::config_error(format!("'--ip {}' invalid", virtual_ip));
+                }
+            }
+            let tcp_channel = matches.opt_present("tcp");
+            let relay = matches.opt_present("relay");
+
+            let file_parallel = report
+                .value(&numeric::PAR, settings.tunable("par").as_deref())
+                .map(|v| v.0 as usize);
+            let parallel = report
+                .value(&numeric::PAR, matches.opt_str("par").as_deref())
+                .map(|v| v.0 as usize);
+            let parallel = resolver.resolve("par", 1, file_parallel, parallel);
+
+            let cipher_model = match matches.opt_get::<CipherModel>("model") {
+                Ok(model) => {
+                    #[cfg(not(any(
+                        feature = "aes_gcm",
+                        feature = "server_encrypt",
+                        feature = "aes_cbc",
+                        feature = "aes_ecb",
+                        feature = "sm4_cbc"
+                    )))]
+                    {
+                        if password.is_some() && model.is_none() {
+                            exit::config_error("Encryption not supported");
+                        }
+                    }
+                    #[cfg(not(any(feature = "aes_gcm", feature = "server_encrypt")))]
+                    {
+                        if password.is_some() && model.is_none() {
+                            exit::config_error("'--model ' undefined");
+                        }
+                        model.unwrap_or(CipherModel::None)
+                    }
+                    #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+                    model.unwrap_or(CipherModel::AesGcm)
+                }
+                Err(e) => {
+                    exit::config_error(format!("'--model ' invalid,{}", e));
+                }
+            };
+
+            let finger = matches.opt_present("finger");
+            let punch_model = matches
+                .opt_get::<PunchModel>("punch")
+                .unwrap()
+                .unwrap_or(PunchModel::All);
+            let use_channel_type = matches
+                .opt_get::<UseChannelType>("use-channel")
+                .unwrap()
+                .unwrap_or_else(|| {
+                    if relay {
+                        UseChannelType::Relay
+                    } else {
+                        UseChannelType::All
+                    }
+                });
+
+            let ports = settings.ports.as_ref().map(|v| {
+                v.split(',')
+                    .filter_map(|x| report.value(&numeric::PORT, Some(x)))
+                    .map(|v| v.0 as u16)
+                    .collect::<Vec<u16>>()
+            });
+            let port_num = ports.as_ref().map(|v| v.len());
+            let port_num = if settings.is_from_file("port") {
+                resolver.resolve("ports", 2, port_num, None)
+            } else {
+                resolver.resolve("ports", 2, None, port_num)
+            };
+            let ports = ports.or_else(|| Some(vec![0; port_num]));
+
+            let cmd = matches.opt_present("cmd");
+            #[cfg(feature = "ip_proxy")]
+            let no_proxy = resolver.resolve(
+                "no-proxy",
+                false,
+                file_switch("no-proxy"),
+                cli_switch("no-proxy"),
+            );
+            let first_latency = resolver.resolve(
+                "first-latency",
+                false,
+                file_switch("first-latency"),
+                cli_switch("first-latency"),
+            );
+            let packet_loss =
+                report.ratio("--packet-loss", matches.opt_str("packet-loss").as_deref());
+            let packet_delay = report
+                .value(
+                    &numeric::PACKET_DELAY,
+                    matches.opt_str("packet-delay").as_deref(),
+                )
+                .map_or(0, |v| v.0.as_millis() as u32);
+            #[cfg(feature = "port_mapping")]
+            let port_mapping_list = matches.opt_strs("mapping");
+            let config = match vnt::core::Config::new(
+                #[cfg(target_os = "windows")]
+                tap,
+                token,
+                device_id,
+                name,
+                server_address_str,
+                dns,
+                stun_server,
+                in_ip,
+                out_ip,
+                password,
+                mtu,
+                tcp_channel,
+                virtual_ip,
+                #[cfg(feature = "ip_proxy")]
+                no_proxy,
+                server_encrypt,
+                parallel,
+                cipher_model,
+                finger,
+                punch_model,
+                ports,
+                first_latency,
+                device_name,
+                use_channel_type,
+                packet_loss,
+                packet_delay,
+                #[cfg(feature = "port_mapping")]
+                port_mapping_list,
+            ) {
+                Ok(config) => config,
+                Err(e) if register_wait::is_unreachable(&e) => register_wait::unreachable(e),
+                Err(e) => {
+                    exit::config_error(format!("config error: {}", e));
+                }
+            };
+            (config, cmd)
+        };
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let drop_user = if matches.opt_present("no-drop-privileges") {
+            None
+        } else {
+            let group = matches.opt_str("group");
+            match matches.opt_str("user") {
+                Some(user) => Some((user, group)),
+                // 以root运行时默认切换到nobody
+                None if crate::root_check::effective_uid() == 0 => {
+                    Some((crate::root_check::DEFAULT_USER.to_string(), group))
+                }
+                None => None,
+            }
+        };
+        // 在创建网卡之前确认用户存在，避免连上之后才失败
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some((user, group)) = &drop_user {
+            if let Err(e) = crate::root_check::lookup_user(user, group.as_deref()) {
+                exit::config_error(format!(
+                    "cannot drop privileges to '{}': {}, use '--user' to choose another user or '--no-drop-privileges' to keep running as root",
+                    user, e
+                ));
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let drop_user: Option<(String, Option<String>)> = {
+            if matches.opt_present("user") {
+                exit::config_error("'--user' is only supported on linux/macos");
+            }
+            None
+        };
+        let mut config = config;
+        match (matches.opt_str("existing-tun"), matches.opt_str("tun-fd")) {
+            (Some(_), Some(_)) => {
+                exit::config_error("'--existing-tun' and '--tun-fd' cannot be used together");
+            }
+            (Some(name), None) => {
+                config.existing_tun = Some(ExistingTun::Name(name));
+            }
+            (None, Some(fd)) => match fd.parse::<i32>() {
+                Ok(fd) if fd >= 0 => {
+                    config.existing_tun = Some(ExistingTun::Fd(fd));
+                }
+                _ => {
+                    exit::config_error(format!("'--tun-fd {}' invalid", fd));
+                }
+            },
+            (None, None) => {}
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.existing_tun.is_some() {
+            exit::config_error("'--existing-tun' and '--tun-fd' are only supported on linux");
+        }
+        if matches.opt_present("observer") {
+            if config.existing_tun.is_some() {
+                exit::config_error(
+                    "'--observer' does not use a tun device, remove '--existing-tun'/'--tun-fd'",
+                );
+            }
+            if !config.in_ips.is_empty() || !config.out_ips.is_empty() {
+                exit::config_error("'--observer' does not forward traffic, remove '-i'/'-o'");
+            }
+            #[cfg(feature = "port_mapping")]
+            if !config.port_mapping_list.is_empty() {
+                exit::config_error("'--observer' does not forward traffic, remove '--mapping'");
+            }
+            config.observer = true;
+            println!("Observer mode: no tun device, only the device list and latencies are synced");
+        }
+        if matches.opt_present("report-usage") {
+            if !config.server_encrypt {
+                // 只在加密的服务端通道中上报
+                exit::config_error("'--report-usage' requires '-W'");
+            }
+            config.report_usage = true;
+            println!(
+                "Usage reporting on: only relayed byte totals are sent to the server, no peer details"
+            );
+        }
+        if let Some(list) = matches.opt_str("allow-server-actions") {
+            if !config.server_encrypt {
+                // 只接受加密的服务端通道中的请求
+                exit::config_error("'--allow-server-actions' requires '-W'");
+            }
+            for name in list.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                match name.parse::<vnt::handle::server_action::Action>() {
+                    Ok(action) => {
+                        if !config.server_actions.contains(&action) {
+                            config.server_actions.push(action);
+                        }
+                    }
+                    Err(e) => report.error(format!("'--allow-server-actions' invalid,{}", e)),
+                }
+            }
+        }
+        if let Some(weights) = matches.opt_str("path-weights") {
+            match weights.parse::<vnt::channel::path_score::PathWeights>() {
+                Ok(weights) => config.path_weights = Some(weights),
+                Err(e) => report.error(format!("'--path-weights' invalid,{}", e)),
+            }
+        }
+        config.require_encryption = matches.opt_present("require-encryption");
+        if config.require_encryption && config.password.is_none() {
+            exit::config_error("'--require-encryption' requires '-w'");
+        }
+        config.allow_peer_plaintext = matches.opt_present("allow-peer-plaintext");
+        if config.require_encryption && config.allow_peer_plaintext {
+            exit::config_error("'--allow-peer-plaintext' conflicts with '--require-encryption'");
+        }
+        config.flow_tracking = !matches.opt_present("no-flow-tracking");
+        config.notify_flows = matches.opt_present("notify-flows");
+        config.fingerprint = !matches.opt_present("no-fingerprint");
+        config.bandwidth_probe = !matches.opt_present("no-bandwidth-probe");
+        config.tun_backpressure = !matches.opt_present("no-tun-backpressure");
+        if let Some(n) = report.value(
+            &numeric::BRING_UP_RELAY,
+            matches.opt_str("bring-up-relay").as_deref(),
+        ) {
+            config.bring_up_relay = n.0 as u32;
+        }
+        if let Some(timeout) = report.value(
+            &numeric::BRING_UP_TIMEOUT,
+            matches.opt_str("bring-up-timeout").as_deref(),
+        ) {
+            config.bring_up_timeout = timeout.0;
+        }
+        // 预设、'--config'配置文件和命令行参数依次覆盖
+        let mut tunable = |key: &'static str, default: String| {
+            resolver.resolve_text(key, default, settings.tunable(key), matches.opt_str(key))
+        };
+        if let Some(timeout) = report.value(
+            &numeric::ROUTE_TIMEOUT,
+            tunable(
+                "route-timeout",
+                config.route_idle_timeout.as_secs().to_string(),
+            )
+            .as_deref(),
+        ) {
+            config.route_idle_timeout = timeout.0;
+        }
+        if let Some(n) = report.value(
+            &numeric::PUNCH_WINDOW,
+            tunable("punch-window", config.punch_port_window.to_string()).as_deref(),
+        ) {
+            config.punch_port_window = n.0 as u16;
+        }
+        if let Some(n) = report.value(
+            &numeric::COOKIE_THRESHOLD,
+            matches.opt_str("cookie-threshold").as_deref(),
+        ) {
+            config.cookie_threshold = n.0 as u32;
+        }
+        config.snat_local = matches.opt_present("snat-local");
+        match matches.opt_get::<vnt::channel::checksum::ChecksumMode>("tun-checksum") {
+            Ok(mode) => config.tun_checksum = mode.unwrap_or_default(),
+            Err(e) => report.error(format!("'--tun-checksum' invalid,{}", e)),
+        }
+        config.mdns = matches.opt_present("mdns");
+        config.reorder = matches.opt_present("reorder");
+        if let Some(limit) = matches.opt_str("inbound-limit") {
+            match limit.split_once(',') {
+                Some((pps, rate)) => {
+                    let pps = report.value(&numeric::INBOUND_PPS, Some(pps));
+                    let rate = report.value(&numeric::INBOUND_RATE, Some(rate));
+                    if let (Some(pps), Some(rate)) = (pps, rate) {
+                        config.inbound_limit = (pps.0, rate.bytes_per_sec());
+                    }
+                }
+                None => report.error(format!(
+                    "'--inbound-limit {}' invalid, expected <pps>,<mbps>, 0 means unlimited",
+                    limit
+                )),
+            }
+        }
+        config.auto_block_floods = matches.opt_present("auto-block-floods");
+        config.broadcast = !matches.opt_present("no-broadcast");
+        config.compact_encoding = matches.opt_present("compact-encoding");
+        config.upnp = matches.opt_present("upnp");
+        config.strict_inbound = matches.opt_present("strict-inbound");
+        for route in matches.opt_strs("dns-route") {
+            match route.parse::<vnt::handle::dns::DnsRoute>() {
+                Ok(route) => config.dns_routes.push(route),
+                Err(e) => exit::config_error(format!("'--dns-route' invalid,{}", e)),
+            }
+        }
+        config.dns_listen = match matches.opt_get::<SocketAddr>("dns-listen") {
+            Ok(Some(addr)) => Some(addr),
+            Ok(None) if !config.dns_routes.is_empty() => {
+                Some(vnt::handle::dns::DEFAULT_LISTEN.parse().unwrap())
+            }
+            Ok(None) => None,
+            Err(e) => exit::config_error(format!("'--dns-listen' invalid,{}", e)),
+        };
+        if let Some(budget) = tunable("probe-budget", config.probe_budget.to_string()) {
+            config.probe_budget = match budget.as_str() {
+                "metered" => vnt::channel::probe_budget::METERED_KBPS,
+                "off" => 0,
+                _ => match report.value(&numeric::PROBE_BUDGET, Some(budget.as_str())) {
+                    Some(rate) => rate.kbps(),
+                    None => config.probe_budget,
+                },
+            };
+        }
+        if let Some(limit) = report.value(
+            &numeric::SOCKET_LIMIT,
+            tunable("socket-limit", config.socket_limit.to_string()).as_deref(),
+        ) {
+            config.socket_limit = limit.0 as usize;
+        }
+        if let Some(limit) = report.value(
+            &numeric::MAX_DEVICES,
+            matches.opt_str("max-devices").as_deref(),
+        ) {
+            config.max_devices = limit.0 as usize;
+        }
+        if let Some(limit) = report.value(
+            &numeric::EVENT_HISTORY,
+            tunable("event-history", config.event_history.to_string()).as_deref(),
+        ) {
+            config.event_history = limit.0 as usize;
+        }
+        let retention = retention::Retention::new(
+            report
+                .value(
+                    &numeric::PEER_RETENTION,
+                    matches.opt_str("peer-retention").as_deref(),
+                )
+                .map(|v| v.0),
+            matches.opt_present("ephemeral-network"),
+        );
+        if let Some(count) = report.value(
+            &numeric::TCP_FALLBACK,
+            tunable("tcp-fallback", config.tcp_fallback.to_string()).as_deref(),
+        ) {
+            config.tcp_fallback = count.0 as usize;
+        }
+        if let Some(port) = report.value(&numeric::LOCAL_PORT, matches.opt_str("port").as_deref()) {
+            if matches.opt_present("ports") {
+                exit::config_error("'--port' conflicts with '--ports'");
+            }
+            // 端口被占用时启动失败，不换成其他端口
+            config.ports = Some(vec![port.0 as u16]);
+        }
+        if let Some(bind) = matches.opt_str("bind") {
+            match bind.parse::<IpAddr>() {
+                Ok(ip) => config.bind_ip = Some(ip),
+                Err(e) => report.error(format!("'--bind' invalid,{}", e)),
+            }
+        }
+        if matches.opt_present("ip-fallback") {
+            if config.ip.is_none() {
+                exit::config_error("'--ip-fallback' requires '--ip'");
+            }
+            config.ip_fallback = true;
+        }
+        if let Some(spec) = matches.opt_str("pin-workers") {
+            match spec.parse::<vnt::util::workers::PinSpec>() {
+                Ok(spec) => match vnt::util::workers::allowed_cores() {
+                    Ok(allowed) => match spec.validate(&allowed) {
+                        Ok(_) => config.pin_workers = spec,
+                        Err(e) => report.error(format!("'--pin-workers' invalid,{}", e)),
+                    },
+                    // 不支持时只提示，正常运行
+                    Err(e) => report.warn(format!("'--pin-workers' ignored,{}", e)),
+                },
+                Err(e) => report.error(format!("'--pin-workers' invalid,{}", e)),
+            }
+        }
+        match matches.opt_get::<vnt::util::workers::Priority>("worker-priority") {
+            Ok(priority) => config.worker_priority = priority.unwrap_or_default(),
+            Err(e) => report.error(format!("'--worker-priority' invalid,{}", e)),
+        }
+        let standby_servers = matches.opt_strs("standby-server");
+        if !standby_servers.is_empty() {
+            if let Err(e) = config.set_standby_servers(&standby_servers) {
+                exit::config_error(format!("'--standby-server' invalid,{}", e));
+            }
+        }
+        if let Some(rate) = report.value(
+            &numeric::SHARED_RATE_LIMIT,
+            matches.opt_str("shared-rate-limit").as_deref(),
+        ) {
+            config.rate_limit = rate.bytes_per_sec();
+        }
+        numeric::Related {
+            mtu: config.mtu,
+            encrypted: config.password.is_some(),
+            tcp: config.tcp,
+            packet_delay: Duration::from_millis(config.packet_delay as u64),
+            bring_up_timeout: config.bring_up_timeout,
+            probe_budget: numeric::BitsPerSec(config.probe_budget * 1000),
+            shared_rate_limit: numeric::BitsPerSec(config.rate_limit * 8),
+        }
+        .check(&mut report);
+        match report.finish() {
+            Ok(warnings) => {
+                for warning in warnings {
+                    ui::warn(warning);
+                }
+            }
+            Err(errors) => exit::config_error(errors),
+        }
+        let health_listen = match matches.opt_get::<SocketAddr>("health-listen") {
+            Ok(addr) => addr,
+            Err(e) => exit::config_error(format!("'--health-listen' invalid,{}", e)),
+        };
+        if config.notify_flows && !config.flow_tracking {
+            exit::config_error("'--notify-flows' conflicts with '--no-flow-tracking'");
+        }
+        if matches.opt_present("warm-restart") {
+            config.warm_state = warm_restart::load(&config);
+            if config.warm_state.is_none() {
+                println!("warm restart state invalid, cold start");
+            }
+        }
+        if matches.opt_present("takeover") {
+            #[cfg(not(target_os = "linux"))]
+            exit::config_error("'--takeover' is only supported on linux");
+            #[cfg(target_os = "linux")]
+            {
+                if config.warm_state.is_some() {
+                    exit::config_error("'--takeover' conflicts with '--warm-restart'");
+                }
+                config.takeover = true;
+                match crate::takeover::request(&config) {
+                    Ok(Some(state)) => {
+                        println!("Taking over the running instance");
+                        config.warm_state = Some(state);
+                    }
+                    Ok(None) => {}
+                    Err(e) => exit::config_error(format!("takeover failed: {:?}", e)),
+                }
+            }
+        }
+        if let Some(path) = matches.opt_str("policy") {
+            match policy::install(&mut config, &path) {
+                Ok(text) => println!("{}", text),
+                Err(e) => exit::config_error(format!("'--policy' {}", e)),
+            }
+        }
+        config.diary_log = matches.opt_present("diary-log");
+        config.intent_log = clean::path().ok();
+        let rtt_history = resolver.resolve(
+            "rtt-history",
+            false,
+            file_switch("rtt-history"),
+            cli_switch("rtt-history"),
+        );
+        config::profile::set_effective(resolver.items());
+        let telemetry = matches.opt_str("share-anonymous-stats");
+        if let Some(url) = &telemetry {
+            if let Err(e) = telemetry::check_url(url) {
+                exit::config_error(format!("'--share-anonymous-stats' invalid,{}", e));
+            }
+        }
+        let manual_safe = matches.opt_present("safe-mode");
+        let no_safe = matches.opt_present("no-safe-mode");
+        if manual_safe && no_safe {
+            exit::config_error("'--safe-mode' conflicts with '--no-safe-mode'");
+        }
+        let counter = match data_dir::get() {
+            Ok(dir) => {
+                safe_mode::reset_later(&dir);
+                safe_mode::check(&dir)
+            }
+            Err(e) => {
+                log::warn!("无法读取崩溃计数 {:?}", e);
+                safe_mode::CrashCounter::default()
+            }
+        };
+        let safe_reason = if manual_safe {
+            Some("'--safe-mode'".to_string())
+        } else if counter.triggered() && no_safe {
+            ui::warn(format!(
+                "crashed {} times shortly after startup, safe mode skipped by '--no-safe-mode'",
+                counter.count
+            ));
+            None
+        } else if counter.triggered() {
+            Some(format!(
+                "crashed {} times in a row shortly after startup",
+                counter.count
+            ))
+        } else {
+            None
+        };
+        let (health_listen, rtt_history, telemetry) = match safe_reason {
+            Some(reason) => {
+                let mut features =
+                    safe_mode::Features::take(&mut config, health_listen, rtt_history, telemetry);
+                let changed = features.safe();
+                let changed = if changed.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    changed.join(", ")
+                };
+                ui::warn(format!("Safe mode: {}, disabled: {}", reason, changed));
+                features.store(&mut config)
+            }
+            None => (health_listen, rtt_history, telemetry),
+        };
+        let daemon = matches.opt_present("daemon") || matches.opt_present("nic-only");
+        if daemon && matches.opt_present("cmd") {
+            exit::config_error("'--cmd' and '--daemon' cannot be used together");
+        }
+        let stdin_terminal = io::stdin().is_terminal();
+        if cmd && !daemon && !stdin_terminal {
+            println!("stdin is not a terminal, console input is disabled");
+        }
+        let cmd = daemon::console(cmd, daemon, stdin_terminal);
+        Config {
+            vnt: config,
+            cmd,
+            drop_user,
+            health_listen,
+            rtt_history,
+            telemetry,
+            retention,
+            register_timeout,
+        }
+    }
+}
@@ -1,50 +1,176 @@
 use console::{style, Style};
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use crate::command::entity::{
+    ConnectionList, DeviceItem, DnsStatus, DropItem, HealthStatus, Info, MetricItem, PeerStatsItem,
+    ProbeList, RouteItem, SelfTestResult, SocketList, ThreadItem,
+};
+use crate::config::profile::{ConfigItem, Layer};
 
+/// 控制台命令的输出，命令带'>'重定向时写入文件
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::console_out::redirect::write(format_args!($($arg)*))
+    };
+}
+macro_rules! outln {
+    () => {
+        $crate::console_out::redirect::write(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::console_out::redirect::write(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+pub(crate) use {out, outln};
+
+pub mod redirect;
 pub mod table;
 
+/// 设备名称最多显示的字节数
+const NAME_LIMIT: usize = 64;
+
+/// 过长的名称截断到NAME_LIMIT字节以内
+fn truncate_name(name: &str) -> String {
+    if name.len() <= NAME_LIMIT {
+        return name.to_string();
+    }
+    let mut end = NAME_LIMIT - 3;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &name[..end])
+}
+
+/// 列表中显示的名称，截断后相同的名称加上'*'，返回是否有重名。
+/// 没有名称的设备(旧版本)只显示虚拟ip
+fn display_names(list: &mut [DeviceItem]) -> bool {
+    let mut count: HashMap<String, usize> = HashMap::new();
+    for item in list.iter_mut() {
+        item.name = truncate_name(&item.name);
+        if !item.name.is_empty() {
+            *count.entry(item.name.clone()).or_default() += 1;
+        }
+    }
+    let mut duplicate = false;
+    for item in list.iter_mut() {
+        if count.get(&item.name).copied().unwrap_or(0) > 1 {
+            item.name.push('*');
+            duplicate = true;
+        }
+    }
+    duplicate
+}
+
+fn println_duplicate_note(duplicate: bool) {
+    if duplicate {
+        outln!("* duplicate name, use the virtual ip to tell these devices apart");
+    }
+}
+
 pub fn console_info(status: Info) {
-    println!("Name: {}", style(status.name).green());
-    println!("Virtual ip: {}", style(status.virtual_ip).green());
-    println!("Virtual gateway: {}", style(status.virtual_gateway).green());
-    println!("Virtual netmask: {}", style(status.virtual_netmask).green());
+    outln!("Name: {}", style(status.name).green());
+    if !status.device_id.is_empty() {
+        outln!("Device id: {}", style(status.device_id).green());
+    }
+    outln!("Virtual ip: {}", style(status.virtual_ip).green());
+    outln!("Virtual gateway: {}", style(status.virtual_gateway).green());
+    outln!("Virtual netmask: {}", style(status.virtual_netmask).green());
     if status.connect_status.eq_ignore_ascii_case("Connected") {
-        println!(
+        outln!(
             "Connection status: {}",
             style(status.connect_status).green()
         );
     } else {
-        println!("Connection status: {}", style(status.connect_status).red());
+        outln!("Connection status: {}", style(status.connect_status).red());
     }
 
-    println!("NAT type: {}", style(status.nat_type).green());
-    println!("Relay server: {}", style(status.relay_server).green());
-    println!("Public ips: {}", style(status.public_ips).green());
-    println!("Local addr: {}", style(status.local_addr).green());
-    println!("IPv6: {}", style(status.ipv6_addr).green());
-    println!("Up: {}", style(convert(status.up)).green());
-    println!("Down: {}", style(convert(status.down)).green());
+    if status.nat_hint.is_empty() {
+        outln!("NAT type: {}", style(status.nat_type).green());
+    } else {
+        outln!(
+            "NAT type: {} — {}",
+            style(status.nat_type).green(),
+            status.nat_hint
+        );
+    }
+    outln!("Relay server: {}", style(status.relay_server).green());
+    outln!("Public ips: {}", style(status.public_ips).green());
+    outln!("Local addr: {}", style(status.local_addr).green());
+    outln!("IPv6: {}", style(status.ipv6_addr).green());
+    outln!("Local bind: {}", style(status.local_bind).green());
+    if status.upnp == "inactive" {
+        outln!("UPnP: {}", style(status.upnp).yellow());
+    } else if !status.upnp.is_empty() {
+        outln!("UPnP: {}", style(status.upnp).green());
+    }
+    if status.mtu > 0 {
+        outln!("MTU: {}", style(status.mtu).green());
+    }
+    if !status.tun_checksum.is_empty() {
+        outln!("Tun checksum: {}", style(status.tun_checksum).green());
+    }
+    outln!("Up: {}", style(convert(status.up)).green());
+    outln!("Down: {}", style(convert(status.down)).green());
+    outln!(
+        "Relay: {} up, {} down",
+        style(convert(status.relay_tx)).green(),
+        style(convert(status.relay_rx)).green()
+    );
+    outln!(
+        "Peer traffic: {} up, {} down, {} packets, {}/s",
+        style(convert(status.peer_tx)).green(),
+        style(convert(status.peer_rx)).green(),
+        style(status.peer_packets).green(),
+        style(convert_or_zero(status.peer_rate).trim_end().to_string()).green()
+    );
+    if status.report_usage {
+        outln!("Usage reporting: {}", style("on").yellow());
+    } else {
+        outln!("Usage reporting: {}", style("off").green());
+    }
+    if status.server_actions.is_empty() {
+        outln!("Server actions: {}", style("none").green());
+    } else {
+        outln!("Server actions: {}", style(status.server_actions).yellow());
+    }
+    if status.legacy_peers > 0 {
+        outln!("Legacy peers: {}", style(status.legacy_peers).yellow());
+    } else {
+        outln!("Legacy peers: {}", style(status.legacy_peers).green());
+    }
+    if let Some(uid) = status.effective_uid {
+        if uid == 0 {
+            outln!("Effective uid: {}", style(uid).yellow());
+        } else {
+            outln!("Effective uid: {}", style(uid).green());
+        }
+    }
+    if status.blocked_peers > 0 {
+        outln!("Blocked peers: {}", style(status.blocked_peers).yellow());
+    }
+    if !status.notice.is_empty() {
+        outln!("Notice: {}", style(status.notice).yellow());
+    }
 
     if !status.port_mapping_list.is_empty() {
-        println!("------------------------------------------");
-        println!("Port mapping {}", status.port_mapping_list.len());
+        outln!("------------------------------------------");
+        outln!("Port mapping {}", status.port_mapping_list.len());
         for (is_tcp, addr, dest) in status.port_mapping_list {
             if is_tcp {
-                println!("  TCP: {} -> {}", addr, dest)
+                outln!("  TCP: {} -> {}", addr, dest)
             } else {
-                println!("  UDP: {} -> {}", addr, dest)
+                outln!("  UDP: {} -> {}", addr, dest)
             }
         }
     }
     if !status.in_ips.is_empty() || !status.out_ips.is_empty() {
-        println!("------------------------------------------");
+        outln!("------------------------------------------");
     }
     if !status.in_ips.is_empty() {
-        println!("IP forwarding {}", status.in_ips.len());
+        outln!("IP forwarding {}", status.in_ips.len());
         for (dest, mask, ip) in status.in_ips {
-            println!(
+            outln!(
                 "  -- {} --> {}/{}",
                 ip,
                 Ipv4Addr::from(dest),
@@ -53,9 +179,9 @@ pub fn console_info(status: Info) {
         }
     }
     if !status.out_ips.is_empty() {
-        println!("Allows network {}", status.out_ips.len());
+        outln!("Allows network {}", status.out_ips.len());
         for (dest, mask) in status.out_ips {
-            println!("  {}/{}", Ipv4Addr::from(dest), mask.count_ones())
+            outln!("  {}/{}", Ipv4Addr::from(dest), mask.count_ones())
         }
     }
 }
@@ -83,29 +209,436 @@ fn convert(num: u64) -> String {
     s
 }
 
+pub fn console_drops(list: Vec<DropItem>, explain: bool) {
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    let mut head = vec![
+        ("Reason".to_string(), Style::new()),
+        ("Count".to_string(), Style::new()),
+        ("Peers".to_string(), Style::new()),
+    ];
+    if explain {
+        head.push(("Explain".to_string(), Style::new()));
+    }
+    out_list.push(head);
+    for item in list {
+        let style = if item.count > 0 {
+            Style::new().yellow()
+        } else {
+            Style::new().color256(102)
+        };
+        let peers: Vec<String> = item
+            .peers
+            .iter()
+            .map(|(ip, count)| format!("{}({})", ip, count))
+            .collect();
+        let mut row = vec![
+            (item.reason, style.clone()),
+            (item.count.to_string(), style.clone()),
+            (peers.join(","), style.clone()),
+        ];
+        if explain {
+            row.push((item.explain, style));
+        }
+        out_list.push(row);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_health(status: HealthStatus) {
+    for (name, ok, failed) in [
+        ("Liveness", status.live, status.live_failed),
+        ("Readiness", status.ready, status.ready_failed),
+    ] {
+        if ok {
+            outln!("{}: {}", name, style("ok").green());
+        } else {
+            outln!("{}: {}", name, style("failed").red());
+            for item in failed {
+                outln!("  {}", item);
+            }
+        }
+    }
+}
+
+pub fn console_selftest(result: &SelfTestResult) {
+    for stage in &result.stages {
+        let state = if stage.ok {
+            style("pass").green()
+        } else {
+            style("fail").red()
+        };
+        outln!(
+            "{:<12} {} {:>9.1}ms  {}",
+            stage.name,
+            state,
+            stage.elapsed,
+            stage.detail
+        );
+    }
+    if result.passed {
+        outln!("Selftest: {}", style("passed").green());
+    } else {
+        outln!("Selftest: {}", style("failed").red());
+    }
+}
+
+pub fn console_dns(status: DnsStatus) {
+    if !status.enabled {
+        outln!("DNS disabled, use '--dns-route' or '--dns-listen'");
+        return;
+    }
+    if status.listen.is_empty() {
+        outln!("Listen: {}", style("not listening").red());
+    } else {
+        outln!("Listen: {}", style(status.listen).green());
+    }
+    outln!("Upstream: {}", status.upstream.join(", "));
+    // 内置解析的不计入缓存命中率
+    let cacheable = status.queries.saturating_sub(status.local);
+    let hit_rate = if cacheable == 0 {
+        0.0
+    } else {
+        status.cache_hits as f64 * 100.0 / cacheable as f64
+    };
+    outln!(
+        "Queries: {}, local: {}, cache hits: {} ({:.1}%), servfail: {}",
+        status.queries,
+        status.local,
+        status.cache_hits,
+        hit_rate,
+        status.servfail
+    );
+    outln!("Cache: {}/{}", status.cache_size, status.cache_limit);
+    if status.routes.is_empty() {
+        outln!("No dns route");
+        return;
+    }
+    let mut out_list = Vec::with_capacity(status.routes.len() + 1);
+    out_list.push(vec![
+        ("Domain".to_string(), Style::new()),
+        ("Server".to_string(), Style::new()),
+        ("Hits".to_string(), Style::new()),
+        ("Failures".to_string(), Style::new()),
+    ]);
+    for item in status.routes {
+        let style = if item.failures > 0 {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.suffix, style.clone()),
+            (item.server, style.clone()),
+            (item.hits.to_string(), style.clone()),
+            (item.failures.to_string(), style),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_sockets(list: SocketList) {
+    if list.limit == 0 {
+        outln!("Sockets: {} (unlimited)", list.sockets.len());
+    } else {
+        outln!("Sockets: {}/{}", list.sockets.len(), list.limit);
+    }
+    if list.sockets.is_empty() {
+        return;
+    }
+    let mut out_list = Vec::with_capacity(list.sockets.len() + 1);
+    out_list.push(vec![
+        ("Purpose".to_string(), Style::new()),
+        ("Local port".to_string(), Style::new()),
+        ("Age".to_string(), Style::new()),
+        ("Bytes".to_string(), Style::new()),
+    ]);
+    for item in list.sockets {
+        out_list.push(vec![
+            (item.purpose, Style::new()),
+            (item.local_port.to_string(), Style::new()),
+            (format!("{}s", item.age_secs), Style::new()),
+            (item.bytes.to_string(), Style::new()),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_peer_stats(list: Vec<PeerStatsItem>) {
+    outln!("Peers: {}", list.len());
+    if list.is_empty() {
+        return;
+    }
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Peer".to_string(), Style::new()),
+        ("Name".to_string(), Style::new()),
+        ("Rx".to_string(), Style::new()),
+        ("Tx".to_string(), Style::new()),
+        ("Packets".to_string(), Style::new()),
+        ("Relay".to_string(), Style::new()),
+        ("Rate".to_string(), Style::new()),
+    ]);
+    for item in list {
+        let total = item.rx + item.tx;
+        let relay = if total == 0 {
+            0
+        } else {
+            (item.rx_relay + item.tx_relay) * 100 / total
+        };
+        // 大部分流量经过中继时标黄
+        let style = if relay > 50 {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.virtual_ip.to_string(), style.clone()),
+            (item.name, style.clone()),
+            (convert_or_zero(item.rx), style.clone()),
+            (convert_or_zero(item.tx), style.clone()),
+            (item.packets.to_string(), style.clone()),
+            (format!("{}%", relay), style.clone()),
+            (
+                format!(
+                    "{}/s",
+                    convert_or_zero(item.rx_rate + item.tx_rate).trim_end()
+                ),
+                style,
+            ),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+fn convert_or_zero(num: u64) -> String {
+    if num == 0 {
+        "0".to_string()
+    } else {
+        convert(num)
+    }
+}
+
+pub fn console_threads(list: Vec<ThreadItem>) {
+    outln!("Threads: {}", list.len());
+    if list.is_empty() {
+        return;
+    }
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Name".to_string(), Style::new()),
+        ("Worker".to_string(), Style::new()),
+        ("Tid".to_string(), Style::new()),
+        ("Core".to_string(), Style::new()),
+        ("Priority".to_string(), Style::new()),
+        ("Error".to_string(), Style::new()),
+    ]);
+    for item in list {
+        let style = if item.error.is_some() {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.name, Style::new()),
+            (item.worker, Style::new()),
+            (item.tid.to_string(), Style::new()),
+            (
+                item.core.map(|v| v.to_string()).unwrap_or("-".into()),
+                Style::new(),
+            ),
+            (item.priority, Style::new()),
+            (item.error.unwrap_or_default(), style),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_probes(list: ProbeList) {
+    if list.budget_kbps == 0 {
+        outln!("Probe budget: unlimited");
+    } else {
+        outln!("Probe budget: {} kbps", list.budget_kbps);
+    }
+    let mut out_list = Vec::with_capacity(list.probes.len() + 1);
+    out_list.push(vec![
+        ("Kind".to_string(), Style::new()),
+        ("Priority".to_string(), Style::new()),
+        ("Sent".to_string(), Style::new()),
+        ("Bytes".to_string(), Style::new()),
+        ("Skipped".to_string(), Style::new()),
+    ]);
+    for item in list.probes {
+        let style = if item.skipped > 0 {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.kind, style.clone()),
+            (item.priority, style.clone()),
+            (item.sent.to_string(), style.clone()),
+            (item.bytes.to_string(), style.clone()),
+            (item.skipped.to_string(), style),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_connections(connections: ConnectionList) {
+    if !connections.flow_tracking {
+        outln!("Flow tracking disabled");
+        return;
+    }
+    if connections.list.is_empty() {
+        outln!("No connection found");
+        return;
+    }
+    let mut out_list = Vec::with_capacity(connections.list.len() + 1);
+    out_list.push(vec![
+        ("Peer".to_string(), Style::new()),
+        ("Protocol".to_string(), Style::new()),
+        ("Src Port".to_string(), Style::new()),
+        ("Dst Port".to_string(), Style::new()),
+        ("Recent".to_string(), Style::new()),
+        ("Total".to_string(), Style::new()),
+        ("Packets".to_string(), Style::new()),
+        ("Idle".to_string(), Style::new()),
+    ]);
+    for item in connections.list {
+        let style = if item.recent_bytes > 0 {
+            Style::new().green()
+        } else {
+            Style::new().color256(102)
+        };
+        let port = |port: u16| {
+            if port == 0 {
+                String::new()
+            } else {
+                port.to_string()
+            }
+        };
+        out_list.push(vec![
+            (item.peer, style.clone()),
+            (item.protocol, style.clone()),
+            (port(item.src_port), style.clone()),
+            (port(item.dst_port), style.clone()),
+            (convert(item.recent_bytes), style.clone()),
+            (convert(item.bytes), style.clone()),
+            (item.packets.to_string(), style.clone()),
+            (format!("{}s", item.idle_secs), style),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_metrics(list: Vec<MetricItem>) {
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Name".to_string(), Style::new()),
+        ("Labels".to_string(), Style::new()),
+        ("Value".to_string(), Style::new()),
+    ]);
+    for item in list {
+        out_list.push(vec![
+            (item.name, Style::new().green()),
+            (item.labels, Style::new().green()),
+            (item.value, Style::new().green()),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
+pub fn console_config(list: Vec<ConfigItem>) {
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Key".to_string(), Style::new()),
+        ("Value".to_string(), Style::new()),
+        ("Source".to_string(), Style::new()),
+    ]);
+    for item in list {
+        let style = if item.layer == Layer::Default {
+            Style::new().color256(102)
+        } else {
+            Style::new().green()
+        };
+        out_list.push(vec![
+            (item.key, style.clone()),
+            (item.value, style.clone()),
+            (item.layer.to_string(), style),
+        ]);
+    }
+    table::println_table(out_list)
+}
+
 pub fn console_route_table(mut list: Vec<RouteItem>) {
     if list.is_empty() {
-        println!("No route found");
+        outln!("No route found");
         return;
     }
     list.sort_by(|t1, t2| t1.destination.cmp(&t2.destination));
+    let show_direct = list.iter().any(|item| !item.direct.is_empty());
+    let show_feature = list.iter().any(|item| !item.feature.is_empty());
+    let show_bring_up = list.iter().any(|item| !item.bring_up.is_empty());
+    let show_bandwidth = list.iter().any(|item| !item.bandwidth.is_empty());
+    let show_score = list.iter().any(|item| !item.weights.is_empty());
     let mut out_list = Vec::with_capacity(list.len());
 
-    out_list.push(vec![
+    let mut head = vec![
         ("Destination".to_string(), Style::new()),
         ("Next Hop".to_string(), Style::new()),
         ("Metric".to_string(), Style::new()),
         ("Rt".to_string(), Style::new()),
         ("Interface".to_string(), Style::new()),
-    ]);
+    ];
+    if show_direct {
+        head.push(("Direct".to_string(), Style::new()));
+    }
+    if show_feature {
+        head.push(("Override".to_string(), Style::new()));
+    }
+    if show_bring_up {
+        head.push(("Bring-up".to_string(), Style::new()));
+    }
+    if show_bandwidth {
+        head.push(("Bandwidth".to_string(), Style::new()));
+    }
+    if show_score {
+        head.push(("Score".to_string(), Style::new()));
+        head.push(("Weights".to_string(), Style::new()));
+    }
+    out_list.push(head);
     for item in list {
-        out_list.push(vec![
-            (item.destination, Style::new().green()),
-            (item.next_hop, Style::new().green()),
-            (item.metric, Style::new().green()),
-            (item.rt, Style::new().green()),
-            (item.interface, Style::new().green()),
-        ]);
+        let style = if item.direct.is_empty() {
+            Style::new().green()
+        } else {
+            Style::new().yellow()
+        };
+        let mut row = vec![
+            (item.destination, style.clone()),
+            (item.next_hop, style.clone()),
+            (item.metric, style.clone()),
+            (item.rt, style.clone()),
+            (item.interface, style.clone()),
+        ];
+        if show_direct {
+            row.push((item.direct, style.clone()));
+        }
+        if show_feature {
+            // 手动调整过的单独标出
+            row.push((item.feature, Style::new().magenta()));
+        }
+        if show_bring_up {
+            row.push((item.bring_up, style.clone()));
+        }
+        if show_bandwidth {
+            row.push((item.bandwidth, style.clone()));
+        }
+        if show_score {
+            row.push((item.score, style));
+            // 手动调整过的单独标出
+            row.push((item.weights, Style::new().yellow()));
+        }
+        out_list.push(row);
     }
 
     table::println_table(out_list)
@@ -113,9 +646,10 @@ pub fn console_route_table(mut list: Vec<RouteItem>) {
 
 pub fn console_device_list(mut list: Vec<DeviceItem>) {
     if list.is_empty() {
-        println!("No other devices found");
+        outln!("No other devices found");
         return;
     }
+    let duplicate = display_names(&mut list);
     list.sort_by(|t1, t2| t1.virtual_ip.cmp(&t2.virtual_ip));
     list.sort_by(|t1, t2| t1.status.cmp(&t2.status));
     let mut out_list = Vec::with_capacity(list.len());
@@ -128,7 +662,16 @@ pub fn console_device_list(mut list: Vec<DeviceItem>) {
         ("Rt".to_string(), Style::new()),
     ]);
     for item in list {
-        if &item.status == "Online" {
+        if item.blocked {
+            //已屏蔽的设备
+            out_list.push(vec![
+                (item.name, Style::new().color256(102)),
+                (item.virtual_ip, Style::new().color256(102)),
+                (item.status, Style::new().color256(102)),
+                ("Blocked".to_string(), Style::new().color256(102)),
+                ("".to_string(), Style::new().color256(102)),
+            ]);
+        } else if &item.status == "Online" {
             if item.client_secret != item.current_client_secret
                 || (!item.current_client_secret_hash.is_empty()
                     && !item.client_secret_hash.is_empty()
@@ -143,7 +686,8 @@ pub fn console_device_list(mut list: Vec<DeviceItem>) {
                     ("".to_string(), Style::new().red()),
                 ]);
             } else {
-                if item.nat_traversal_type.contains("p2p") {
+                // "p2p->relay"是中继
+                if item.nat_traversal_type.ends_with("p2p") {
                     out_list.push(vec![
                         (item.name, Style::new().green()),
                         (item.virtual_ip, Style::new().green()),
@@ -171,14 +715,16 @@ pub fn console_device_list(mut list: Vec<DeviceItem>) {
             ]);
         }
     }
-    table::println_table(out_list)
+    table::println_table(out_list);
+    println_duplicate_note(duplicate);
 }
 
 pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
     if list.is_empty() {
-        println!("No other devices found");
+        outln!("No other devices found");
         return;
     }
+    let duplicate = display_names(&mut list);
     list.sort_by(|t1, t2| t1.virtual_ip.cmp(&t2.virtual_ip));
     list.sort_by(|t1, t2| t1.status.cmp(&t2.status));
     let mut out_list = Vec::with_capacity(list.len());
@@ -193,9 +739,24 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
         ("Public Ips".to_string(), Style::new()),
         ("Local Ip".to_string(), Style::new()),
         ("IPv6".to_string(), Style::new()),
+        ("Fingerprint".to_string(), Style::new()),
     ]);
     for item in list {
-        if &item.status == "Online" {
+        if item.blocked {
+            //已屏蔽的设备
+            out_list.push(vec![
+                (item.name, Style::new().color256(102)),
+                (item.virtual_ip, Style::new().color256(102)),
+                (item.status, Style::new().color256(102)),
+                ("Blocked".to_string(), Style::new().color256(102)),
+                ("".to_string(), Style::new().color256(102)),
+                (item.nat_type, Style::new().color256(102)),
+                (item.public_ips, Style::new().color256(102)),
+                (item.local_ip, Style::new().color256(102)),
+                (item.ipv6, Style::new().color256(102)),
+                (item.fingerprint, Style::new().color256(102)),
+            ]);
+        } else if &item.status == "Online" {
             if &item.nat_traversal_type == "p2p" {
                 out_list.push(vec![
                     (item.name, Style::new().green()),
@@ -207,6 +768,7 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
                     (item.public_ips, Style::new().green()),
                     (item.local_ip, Style::new().green()),
                     (item.ipv6, Style::new().green()),
+                    (item.fingerprint, Style::new().green()),
                 ]);
             } else {
                 out_list.push(vec![
@@ -219,6 +781,7 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
                     (item.public_ips, Style::new().yellow()),
                     (item.local_ip, Style::new().yellow()),
                     (item.ipv6, Style::new().yellow()),
+                    (item.fingerprint, Style::new().yellow()),
                 ]);
             }
         } else {
@@ -232,8 +795,39 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
                 ("".to_string(), Style::new().color256(102)),
                 ("".to_string(), Style::new().color256(102)),
                 ("".to_string(), Style::new().color256(102)),
+                (item.fingerprint, Style::new().color256(102)),
             ]);
         }
     }
-    table::println_table(out_list)
+    table::println_table(out_list);
+    println_duplicate_note(duplicate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{display_names, truncate_name, NAME_LIMIT};
+    use crate::command::entity::DeviceItem;
+
+    fn item(name: &str) -> DeviceItem {
+        DeviceItem {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_display_names() {
+        let long = "设备".repeat(20);
+        let short = truncate_name(&long);
+        assert!(short.len() <= NAME_LIMIT && short.ends_with("..."));
+        assert_eq!(truncate_name("pc"), "pc");
+        let mut list = vec![item("pc"), item(""), item("laptop"), item("pc"), item("")];
+        assert!(display_names(&mut list));
+        let names: Vec<&str> = list.iter().map(|v| v.name.as_str()).collect();
+        // 没有名称的不算重名
+        assert_eq!(names, ["pc*", "", "laptop", "pc*", ""]);
+        let mut list = vec![item("pc"), item(&long)];
+        assert!(!display_names(&mut list));
+        assert_eq!(list[1].name, short);
+    }
 }
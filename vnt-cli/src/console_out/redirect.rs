@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    // 重定向时命令的输出先写到这里
+    static CAPTURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// 命令末尾的'> file'或'>> file'
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Redirect {
+    pub target: String,
+    pub append: bool,
+}
+
+/// 拆分命令和末尾的重定向，引号内的'>'不处理。没有重定向时原样返回
+pub fn parse(line: &str) -> Result<(&str, Option<Redirect>), String> {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '>') => {
+                let cmd = &line[..i];
+                if cmd.trim().is_empty() {
+                    return Err("missing command before '>'".to_string());
+                }
+                let rest = &line[i + 1..];
+                let (append, rest) = match rest.strip_prefix('>') {
+                    Some(rest) => (true, rest),
+                    None => (false, rest),
+                };
+                let target = target(rest)?;
+                return Ok((cmd, Some(Redirect { target, append })));
+            }
+            _ => {}
+        }
+    }
+    Ok((line, None))
+}
+
+fn target(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let name = match text.chars().next() {
+        None => return Err("missing file name after '>'".to_string()),
+        Some(q @ ('"' | '\'')) => {
+            let rest = &text[1..];
+            let end = match rest.find(q) {
+                Some(end) => end,
+                None => return Err(format!("unterminated quote in {}", text)),
+            };
+            if !rest[end + 1..].trim().is_empty() {
+                return Err(format!("unexpected text after {}", &text[..end + 2]));
+            }
+            &rest[..end]
+        }
+        Some(_) => {
+            if text.contains('>') {
+                return Err(format!("unexpected '>' in {}", text));
+            }
+            if text.contains(char::is_whitespace) {
+                return Err(format!("file name with spaces must be quoted: {}", text));
+            }
+            text
+        }
+    };
+    if name.is_empty() {
+        return Err("missing file name after '>'".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// 控制台命令的输出，capture期间写入缓冲区
+pub fn write(args: fmt::Arguments) {
+    let captured = CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+        Some(buf) => {
+            let _ = buf.write_fmt(args);
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        print!("{}", args);
+    }
+}
+
+/// 执行f并收集它的输出，和输出到非终端时一样不带颜色
+pub fn capture<F: FnOnce()>(f: F) -> String {
+    let colors = console::colors_enabled();
+    console::set_colors_enabled(false);
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(String::new()));
+    f();
+    let out = CAPTURE.with(|capture| capture.borrow_mut().take());
+    console::set_colors_enabled(colors);
+    out.unwrap_or_default()
+}
+
+/// 相对路径相对于数据目录
+pub fn resolve(target: &str) -> io::Result<PathBuf> {
+    let path = Path::new(target);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(crate::data_dir::get()?.join(path))
+    }
+}
+
+/// 写入重定向的文件，返回实际的路径
+pub fn save(redirect: &Redirect, text: &str) -> io::Result<PathBuf> {
+    let path = resolve(&redirect.target)?;
+    write_file(&path, redirect.append, text)?;
+    Ok(path)
+}
+
+fn write_file(path: &Path, append: bool, text: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    file.write_all(text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use console::style;
+
+    use super::{capture, parse, write_file, Redirect};
+
+    fn redirect(target: &str, append: bool) -> Option<Redirect> {
+        Some(Redirect {
+            target: target.to_string(),
+            append,
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("list\n"), Ok(("list\n", None)));
+        assert_eq!(parse("\n"), Ok(("\n", None)));
+        assert_eq!(
+            parse("list > peers.txt\n"),
+            Ok(("list ", redirect("peers.txt", false)))
+        );
+        assert_eq!(
+            parse("stats drops>>log.txt"),
+            Ok(("stats drops", redirect("log.txt", true)))
+        );
+        assert_eq!(
+            parse("list > \"my peers.txt\"  "),
+            Ok(("list ", redirect("my peers.txt", false)))
+        );
+        assert_eq!(
+            parse("list >> 'C:\\vnt out\\a.txt'"),
+            Ok(("list ", redirect("C:\\vnt out\\a.txt", true)))
+        );
+        // 引号内的'>'属于命令参数
+        assert_eq!(parse("diary \"a>b\""), Ok(("diary \"a>b\"", None)));
+        assert_eq!(
+            parse("diary \"a>b\" > x"),
+            Ok(("diary \"a>b\" ", redirect("x", false)))
+        );
+        for line in [
+            "list >",
+            "list >>  \n",
+            "list > \"\"",
+            "list > \"a b",
+            "list > a b",
+            "list > \"a\" b",
+            "list >>> a",
+            "list > a > b",
+            "> a",
+            "  >> a",
+        ] {
+            assert!(parse(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_capture_without_color() {
+        // 和其他测试一样只关闭颜色，并行执行时不互相影响
+        console::set_colors_enabled(false);
+        let out = capture(|| {
+            crate::console_out::outln!("Name: {}", style("pc").green());
+            crate::console_out::table::println_table(vec![vec![(
+                "10.26.0.2".to_string(),
+                console::Style::new().red(),
+            )]]);
+        });
+        assert_eq!(out, "Name: pc\n10.26.0.2    \n");
+
+        let dir = std::env::temp_dir().join(format!("vnt-redirect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers.txt");
+        write_file(&path, false, &out).unwrap();
+        write_file(&path, true, &out).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(!text.contains('\x1b'));
+        assert_eq!(text, out.repeat(2));
+        write_file(&path, false, "x\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "x\n");
+        // 目录不能作为文件写入，返回错误而不是panic
+        assert!(write_file(&dir, false, "x").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
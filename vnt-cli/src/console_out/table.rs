@@ -1,9 +1,26 @@
 use console::Style;
 
+use crate::console_out::outln;
+
 pub fn println_table(table: Vec<Vec<(String, Style)>>) {
+    for line in format_table(table) {
+        outln!("{}", line);
+    }
+}
+
+/// 单元格中可能有其他设备的名称，输出前去掉控制字符和转义序列，不能破坏表格或者控制终端
+fn format_table(table: Vec<Vec<(String, Style)>>) -> Vec<String> {
     if table.is_empty() {
-        return;
+        return Vec::new();
     }
+    let table: Vec<Vec<(String, Style)>> = table
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|(item, style)| (vnt::util::sanitize::text(&item, 256, false), style))
+                .collect()
+        })
+        .collect();
     let mut width_list = vec![0; table[0].len()];
     for in_list in table.iter() {
         for (index, (item, _)) in in_list.iter().enumerate() {
@@ -13,11 +30,46 @@ pub fn println_table(table: Vec<Vec<(String, Style)>>) {
             }
         }
     }
+    let mut lines = Vec::with_capacity(table.len());
     for in_list in table {
+        let mut line = String::new();
         for (col, (item, style)) in in_list.iter().enumerate() {
             let str = format!("{:1$}", item, width_list[col]);
-            print!("{}", style.apply_to(str));
+            line.push_str(&style.apply_to(str).to_string());
         }
-        println!()
+        lines.push(line);
+    }
+    lines
+}
+
+#[test]
+fn test_escape_in_name() {
+    console::set_colors_enabled(false);
+    let table = vec![
+        vec![
+            ("Name".to_string(), Style::new()),
+            ("Virtual Ip".to_string(), Style::new()),
+        ],
+        vec![
+            (
+                "evil\x1b[2J\x1b]0;title\x07\r\nfake\u{202e}".to_string(),
+                Style::new().green(),
+            ),
+            ("10.26.0.3".to_string(), Style::new().green()),
+        ],
+        vec![
+            ("pc".to_string(), Style::new()),
+            ("10.26.0.4".to_string(), Style::new()),
+        ],
+    ];
+    let lines = format_table(table);
+    assert_eq!(lines.len(), 3);
+    for line in &lines {
+        assert!(!line.contains(|c: char| c.is_control()), "{:?}", line);
     }
+    // 虚拟ip列保持对齐
+    let column = lines[0].find("Virtual Ip").unwrap();
+    assert_eq!(lines[1].find("10.26.0.3"), Some(column));
+    assert_eq!(lines[2].find("10.26.0.4"), Some(column));
+    assert!(lines[1].starts_with("evilfake"));
 }
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use vnt::core::Vnt;
+use vnt::handle::ConnectStatus;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 是否读取控制台输入，'--daemon'或者标准输入不是终端(systemd、服务、重定向)时都不读取
+pub fn console(cmd: bool, daemon: bool, stdin_terminal: bool) -> bool {
+    cmd && !daemon && stdin_terminal
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct State {
+    status: ConnectStatus,
+    online: usize,
+}
+
+impl State {
+    fn of(vnt: &Vnt) -> Self {
+        Self {
+            status: vnt.connection_status(),
+            online: vnt
+                .device_list()
+                .iter()
+                .filter(|v| v.status.is_online())
+                .count(),
+        }
+    }
+}
+
+/// 状态变化时输出的日志
+fn transition(old: &State, new: &State) -> Vec<String> {
+    let mut lines = Vec::new();
+    if old.status != new.status {
+        lines.push(format!("连接状态 {:?} -> {:?}", old.status, new.status));
+    }
+    if old.online != new.online {
+        lines.push(format!("在线设备 {} -> {}", old.online, new.online));
+    }
+    lines
+}
+
+/// 不读取控制台时记录状态变化，主线程等待工作线程退出
+pub fn start(vnt: Vnt) {
+    log::info!("以非交互模式运行，控制台命令请使用'--list'等参数");
+    let result = std::thread::Builder::new()
+        .name("DaemonStatus".into())
+        .spawn(move || {
+            let mut state = State::of(&vnt);
+            log::info!("连接状态 {:?}", state.status);
+            while !vnt.is_stopped() {
+                std::thread::sleep(CHECK_INTERVAL);
+                let current = State::of(&vnt);
+                for line in transition(&state, &current) {
+                    log::info!("{}", line);
+                }
+                state = current;
+            }
+            log::info!("工作线程已停止");
+        });
+    if let Err(e) = result {
+        log::warn!("DaemonStatus {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::{console, transition, State};
+    use vnt::handle::ConnectStatus;
+
+    #[test]
+    fn test_mode() {
+        assert!(console(true, false, true));
+        assert!(!console(false, false, true));
+        assert!(!console(true, true, true));
+        // 标准输入不是终端时自动进入非交互模式
+        assert!(!console(true, false, false));
+    }
+
+    #[test]
+    fn test_transition() {
+        let connecting = State {
+            status: ConnectStatus::Connecting,
+            online: 0,
+        };
+        let connected = State {
+            status: ConnectStatus::Connected,
+            online: 2,
+        };
+        assert!(transition(&connected, &connected).is_empty());
+        assert_eq!(
+            transition(&connecting, &connected),
+            vec![
+                "连接状态 Connecting -> Connected".to_string(),
+                "在线设备 0 -> 2".to_string()
+            ]
+        );
+    }
+}
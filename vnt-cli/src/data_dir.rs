@@ -0,0 +1,273 @@
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// 数据目录的来源，按优先级排列
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// --data-dir参数
+    Arg,
+    /// VNT_HOME环境变量
+    Env,
+    /// 旧版本使用的程序所在目录下的env，已存在时继续使用，保持设备标识不变
+    Legacy,
+    /// 系统的状态目录，XDG_STATE_HOME、ProgramData等
+    State,
+    /// 用户主目录下的.vnt
+    Home,
+    /// 程序所在目录下的env
+    Exe,
+    /// 临时目录，重启后可能丢失
+    Temp,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Source::Arg => "--data-dir",
+            Source::Env => "VNT_HOME",
+            Source::Legacy => "legacy",
+            Source::State => "state dir",
+            Source::Home => "home",
+            Source::Exe => "exe dir",
+            Source::Temp => "temp dir",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DataDir {
+    pub path: PathBuf,
+    pub source: Source,
+    /// 不可写时保存文件的功能降级为只在内存中
+    pub writable: bool,
+}
+
+static DATA_DIR: OnceLock<DataDir> = OnceLock::new();
+
+/// 启动时解析一次，之后日志、状态、后台命令端口都使用这个目录
+pub fn init(arg: Option<String>) -> &'static DataDir {
+    DATA_DIR.get_or_init(|| {
+        let candidates = candidates(
+            arg.map(PathBuf::from),
+            |key| std::env::var_os(key),
+            exe_dir(),
+        );
+        let dir = resolve(candidates, writable);
+        match dir.source {
+            Source::Temp => {
+                println!(
+                    "{}",
+                    console::style(format!(
+                        "warning: no writable data directory, using {}, state will be lost after reboot, use '--data-dir' or VNT_HOME",
+                        dir.path.display()
+                    ))
+                    .red()
+                );
+            }
+            _ if !dir.writable => {
+                println!(
+                    "{}",
+                    console::style(format!(
+                        "warning: data directory {} is read-only, state is kept in memory only",
+                        dir.path.display()
+                    ))
+                    .red()
+                );
+            }
+            _ => {}
+        }
+        dir
+    })
+}
+
+/// 数据目录，不可写时返回错误，调用方需要降级处理
+pub fn get() -> io::Result<PathBuf> {
+    let dir = init(None);
+    if dir.writable {
+        Ok(dir.path.clone())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("data directory {} is read-only", dir.path.display()),
+        ))
+    }
+}
+
+/// 日志配置，优先使用当前目录下的，其次是数据目录下的
+pub fn log_config() -> Option<PathBuf> {
+    let path = PathBuf::from("log4rs.yaml");
+    if path.exists() {
+        return Some(path);
+    }
+    let path = init(None).path.join("log4rs.yaml");
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn exe_dir() -> Option<PathBuf> {
+    match std::env::current_exe() {
+        Ok(path) => path.parent().map(|v| v.to_path_buf()),
+        Err(e) => {
+            log::warn!("current_exe err:{:?}", e);
+            None
+        }
+    }
+}
+
+fn non_empty(value: Option<OsString>) -> Option<PathBuf> {
+    value.filter(|v| !v.is_empty()).map(PathBuf::from)
+}
+
+/// 所有可能的目录，按优先级排列
+fn candidates<F: Fn(&str) -> Option<OsString>>(
+    arg: Option<PathBuf>,
+    env: F,
+    exe_dir: Option<PathBuf>,
+) -> Vec<(PathBuf, Source)> {
+    let mut list = Vec::new();
+    if let Some(path) = arg {
+        list.push((path, Source::Arg));
+    }
+    if let Some(path) = non_empty(env("VNT_HOME")) {
+        list.push((path, Source::Env));
+    }
+    let legacy = exe_dir.map(|v| v.join("env"));
+    if let Some(path) = &legacy {
+        if path.is_dir() {
+            list.push((path.clone(), Source::Legacy));
+        }
+    }
+    let home = non_empty(env("HOME")).or_else(|| non_empty(env("USERPROFILE")));
+    let state = if cfg!(target_os = "windows") {
+        non_empty(env("ProgramData")).map(|v| v.join("vnt"))
+    } else if cfg!(target_os = "macos") {
+        home.as_ref()
+            .map(|v| v.join("Library").join("Application Support").join("vnt"))
+    } else {
+        non_empty(env("XDG_STATE_HOME"))
+            .or_else(|| home.as_ref().map(|v| v.join(".local").join("state")))
+            .map(|v| v.join("vnt"))
+    };
+    if let Some(path) = state {
+        list.push((path, Source::State));
+    }
+    if let Some(path) = home {
+        list.push((path.join(".vnt"), Source::Home));
+    }
+    if let Some(path) = legacy {
+        list.push((path, Source::Exe));
+    }
+    list
+}
+
+/// 选择第一个可写的目录，都不可写时使用临时目录，不会panic
+fn resolve<F: Fn(&Path) -> bool>(candidates: Vec<(PathBuf, Source)>, writable: F) -> DataDir {
+    for (path, source) in candidates {
+        if writable(&path) {
+            return DataDir {
+                path,
+                source,
+                writable: true,
+            };
+        }
+        log::warn!("数据目录不可用 {} {}", source, path.display());
+    }
+    let path = std::env::temp_dir().join("vnt");
+    DataDir {
+        writable: writable(&path),
+        path,
+        source: Source::Temp,
+    }
+}
+
+/// 创建目录并尝试写入一个文件，只读文件系统上会失败
+fn writable(path: &Path) -> bool {
+    if std::fs::create_dir_all(path).is_err() {
+        return false;
+    }
+    let probe = path.join(format!(".probe-{}", std::process::id()));
+    let rs = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    rs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    use super::{candidates, resolve, writable, Source};
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), OsString::from(v)))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn sources(list: &[(PathBuf, Source)]) -> Vec<Source> {
+        list.iter().map(|(_, source)| *source).collect()
+    }
+
+    #[test]
+    fn test_candidates() {
+        let arg = Some(PathBuf::from("/data/vnt"));
+        let all = env(&[
+            ("VNT_HOME", "/opt/vnt"),
+            ("HOME", "/home/u"),
+            ("XDG_STATE_HOME", "/home/u/state"),
+            ("ProgramData", "C:\\ProgramData"),
+        ]);
+        let list = candidates(arg, &all, None);
+        assert_eq!(
+            sources(&list),
+            vec![Source::Arg, Source::Env, Source::State, Source::Home]
+        );
+        assert_eq!(list[0].0, PathBuf::from("/data/vnt"));
+        // systemd DynamicUser、容器中没有HOME，也没有程序目录
+        assert!(candidates(None, env(&[]), None).is_empty());
+        // 空的环境变量当作没有设置
+        let list = candidates(None, env(&[("VNT_HOME", ""), ("HOME", "")]), None);
+        assert!(list.is_empty());
+        let list = candidates(None, env(&[("HOME", "/home/u")]), None);
+        assert_eq!(
+            list.last().unwrap(),
+            &(PathBuf::from("/home/u/.vnt"), Source::Home)
+        );
+        #[cfg(target_os = "linux")]
+        {
+            let list = candidates(None, env(&[("XDG_STATE_HOME", "/s")]), None);
+            assert_eq!(list, vec![(PathBuf::from("/s/vnt"), Source::State)]);
+            let list = candidates(None, env(&[("HOME", "/home/u")]), None);
+            assert_eq!(list[0].0, PathBuf::from("/home/u/.local/state/vnt"));
+        }
+    }
+
+    #[test]
+    fn test_resolve_read_only() {
+        let base = std::env::temp_dir().join(format!("vnt-data-dir-{}", std::process::id()));
+        let read_only = base.join("ro");
+        let ok = base.join("rw");
+        let candidates = vec![(read_only.clone(), Source::Arg), (ok.clone(), Source::Env)];
+        // root不受目录权限限制，这里模拟只读
+        let is_writable = |path: &Path| !path.starts_with(&read_only) && writable(path);
+        let dir = resolve(candidates, is_writable);
+        assert_eq!(dir.source, Source::Env);
+        assert_eq!(dir.path, ok);
+        assert!(dir.writable);
+        // 全部不可写时退到临时目录
+        let dir = resolve(vec![(read_only.clone(), Source::Arg)], |_| false);
+        assert_eq!(dir.source, Source::Temp);
+        assert!(!dir.writable);
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use vnt::handle::callback::ErrorType;
+use vnt::util::state_store;
+
+/// 退出原因，code()是进程退出码，systemd等编排工具据此决定是否重启
+///
+/// | 退出码 | 原因 |
+/// |---|---|
+/// | 0 | 正常退出(stop/exit命令、SIGINT/SIGTERM、windows上的ctrl+c) |
+/// | 2 | 参数或配置错误 |
+/// | 3 | 服务器拒绝token |
+/// | 4 | 网卡、端口等初始化失败 |
+/// | 5 | 运行中出现无法恢复的错误 |
+/// | 6 | 程序崩溃(panic) |
+/// | 7 | '--register-timeout'内没有连上服务器 |
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    Clean,
+    ConfigError,
+    AuthRejected,
+    NetworkSetup,
+    Runtime,
+    Crashed,
+    Unreachable,
+}
+
+impl ExitReason {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitReason::Clean => 0,
+            ExitReason::ConfigError => 2,
+            ExitReason::AuthRejected => 3,
+            ExitReason::NetworkSetup => 4,
+            ExitReason::Runtime => 5,
+            ExitReason::Crashed => 6,
+            ExitReason::Unreachable => 7,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitReason::Clean => "clean",
+            ExitReason::ConfigError => "config_error",
+            ExitReason::AuthRejected => "auth_rejected",
+            ExitReason::NetworkSetup => "network_setup_failure",
+            ExitReason::Runtime => "runtime_failure",
+            ExitReason::Crashed => "crashed",
+            ExitReason::Unreachable => "server_unreachable",
+        }
+    }
+    /// 需要停止运行的错误，其他错误会自动恢复
+    pub fn from_error(code: ErrorType) -> Option<ExitReason> {
+        match code {
+            ErrorType::TokenError => Some(ExitReason::AuthRejected),
+            // 换一个ip或者设备id才能恢复
+            ErrorType::AddressExhausted | ErrorType::IpAlreadyExists | ErrorType::InvalidIp => {
+                Some(ExitReason::ConfigError)
+            }
+            ErrorType::LocalIpExists => Some(ExitReason::NetworkSetup),
+            _ => None,
+        }
+    }
+    /// 启动失败的原因，参数错误以外都当作网络初始化失败(端口被占用、创建网卡失败等)
+    pub fn from_start_error(e: &anyhow::Error) -> ExitReason {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                if e.kind() == std::io::ErrorKind::InvalidInput {
+                    return ExitReason::ConfigError;
+                }
+            }
+        }
+        ExitReason::NetworkSetup
+    }
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+// 第一个记录的原因生效，停止回调里据此决定退出码
+static REASON: Mutex<Option<(ExitReason, String)>> = Mutex::new(None);
+// 多个线程同时退出时只有第一个写文件和退出
+static EXITING: Mutex<()> = Mutex::new(());
+
+/// 启动时调用，记录启动时间，panic时按崩溃退出
+pub fn init() {
+    START.get_or_init(Instant::now);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        exit(ExitReason::Crashed, info.to_string());
+    }));
+}
+
+/// 记录停止的原因，已经有原因时忽略
+pub fn set_reason(reason: ExitReason, message: impl Into<String>) {
+    let mut guard = REASON.lock().unwrap_or_else(|e| e.into_inner());
+    if guard.is_none() {
+        *guard = Some((reason, message.into()));
+    }
+}
+
+/// 程序停止后调用，没有记录原因说明不是用户主动停止的
+pub fn stopped() -> ! {
+    let reason = REASON.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let (reason, message) =
+        reason.unwrap_or((ExitReason::Runtime, "stopped unexpectedly".to_string()));
+    exit(reason, message)
+}
+
+/// 参数错误，输出错误后退出
+pub fn config_error(message: impl Into<String>) -> ! {
+    let message = message.into();
+    println!("{}", message);
+    exit(ExitReason::ConfigError, message)
+}
+
+/// 唯一的退出路径，保存最后一次退出的原因
+pub fn exit(reason: ExitReason, message: impl Into<String>) -> ! {
+    let _guard = EXITING.lock().unwrap_or_else(|e| e.into_inner());
+    let message = message.into();
+    let uptime = START.get().map(|v| v.elapsed().as_secs()).unwrap_or(0);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0);
+    if reason == ExitReason::Clean {
+        log::info!("退出 {} {}", reason.name(), message);
+    } else {
+        log::error!("退出 code={} {} {}", reason.code(), reason.name(), message);
+    }
+    crate::state::STORE.flush();
+    match crate::data_dir::get() {
+        Ok(dir) => {
+            // 外部工具直接读取，保持纯json，不加文件头
+            let text = last_exit_json(reason, &message, timestamp, uptime);
+            if let Err(e) = state_store::atomic_write(&dir.join("last_exit.json"), text.as_bytes())
+            {
+                log::warn!("保存退出原因失败 {:?}", e);
+            }
+        }
+        Err(e) => {
+            log::warn!("保存退出原因失败 {:?}", e);
+        }
+    }
+    std::process::exit(reason.code())
+}
+
+fn last_exit_json(reason: ExitReason, message: &str, timestamp: u64, uptime: u64) -> String {
+    format!(
+        "{{\n  \"reason\": \"{}\",\n  \"code\": {},\n  \"message\": \"{}\",\n  \"timestamp\": {},\n  \"uptime_secs\": {}\n}}\n",
+        reason.name(),
+        reason.code(),
+        escape(message),
+        timestamp,
+        uptime
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// SIGINT/SIGTERM按正常退出处理，第二次收到信号时直接结束
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn handle_signals<F: FnOnce() + Send + 'static>(stop: F) -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+    extern "C" fn on_signal(_: libc::c_int) {
+        // 信号处理函数中只做write
+        let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            unsafe {
+                libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+            }
+        }
+    }
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name("signal".into())
+        .spawn(move || {
+            let mut buf = [0u8; 1];
+            loop {
+                let n = unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, 1) };
+                if n == 1 {
+                    break;
+                }
+                if n < 0
+                    && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted
+                {
+                    continue;
+                }
+                return;
+            }
+            unsafe {
+                libc::signal(libc::SIGINT, libc::SIG_DFL);
+                libc::signal(libc::SIGTERM, libc::SIG_DFL);
+            }
+            set_reason(ExitReason::Clean, "terminated by signal");
+            stop();
+        })?;
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+    }
+    Ok(())
+}
+
+/// ctrl+c和关闭控制台窗口按正常退出处理，第二次ctrl+c交给系统默认处理直接结束
+#[cfg(target_os = "windows")]
+pub fn handle_signals<F: FnOnce() + Send + 'static>(stop: F) -> std::io::Result<()> {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    type Stop = Box<dyn FnOnce() + Send>;
+    static STOP: Mutex<Option<Stop>> = Mutex::new(None);
+    unsafe extern "system" fn on_ctrl(_: DWORD) -> BOOL {
+        let stop = STOP.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match stop {
+            Some(stop) => {
+                set_reason(ExitReason::Clean, "terminated by ctrl+c");
+                // 处理函数返回后才能收到下一次ctrl+c，停止放到单独的线程中
+                std::thread::spawn(stop);
+                TRUE
+            }
+            None => FALSE,
+        }
+    }
+    *STOP.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(stop));
+    if unsafe { winapi::um::consoleapi::SetConsoleCtrlHandler(Some(on_ctrl), TRUE) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use vnt::handle::callback::ErrorType;
+
+    use super::{last_exit_json, ExitReason};
+
+    #[test]
+    fn test_auth_rejected() {
+        let reason = ExitReason::from_error(ErrorType::TokenError).unwrap();
+        assert_eq!(reason.code(), 3);
+        let json = last_exit_json(reason, "token \"abc\" error", 1700000000, 12);
+        assert_eq!(
+            json,
+            "{\n  \"reason\": \"auth_rejected\",\n  \"code\": 3,\n  \"message\": \"token \\\"abc\\\" error\",\n  \"timestamp\": 1700000000,\n  \"uptime_secs\": 12\n}\n"
+        );
+        // 可以自动恢复的错误不退出
+        assert_eq!(ExitReason::from_error(ErrorType::Disconnect), None);
+        assert_eq!(ExitReason::from_error(ErrorType::RouteWarning), None);
+    }
+
+    #[test]
+    fn test_port_in_use() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let e = std::net::UdpSocket::bind(socket.local_addr().unwrap()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+        let e = anyhow::Error::from(e).context("bind port");
+        let reason = ExitReason::from_start_error(&e);
+        assert_eq!(reason, ExitReason::NetworkSetup);
+        assert_eq!(reason.code(), 4);
+        let json = last_exit_json(reason, &format!("{:?}", e), 1, 0);
+        assert!(json.contains("\"reason\": \"network_setup_failure\""));
+        assert!(json.contains("\"code\": 4"));
+        // 多行的错误信息转义后仍然是一行
+        assert_eq!(json.lines().count(), 7);
+    }
+}
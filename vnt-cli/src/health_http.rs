@@ -0,0 +1,166 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use vnt::util::health::Health;
+use vnt::util::subsystem::Switch;
+
+/// 供容器编排探测的http接口，只响应GET /livez和/readyz，
+/// 通过返回200，不通过返回503，内容为失败的检查项
+pub fn start(addr: SocketAddr, health: Health, switch: &Switch) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let switch_ = switch.clone();
+    switch.spawn("healthHttp", move || {
+        for stream in listener.incoming() {
+            if switch_.is_off() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle(stream, &health) {
+                        log::debug!("health http {:?}", e);
+                    }
+                }
+                Err(e) => log::warn!("health http accept {:?}", e),
+            }
+        }
+    })?;
+    Ok(local_addr)
+}
+
+/// 停止监听，连接一次唤醒阻塞在accept上的线程
+pub fn stop(switch: &Switch, local_addr: SocketAddr) {
+    let mut addr = local_addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    switch.stop_with(|| {
+        if let Err(e) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            log::warn!("health http stop {:?}", e);
+        }
+    });
+}
+
+fn handle(mut stream: TcpStream, health: &Health) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+    // 只需要请求行
+    let mut buf = [0u8; 1024];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].contains(&b'\n') {
+        let n = stream.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let (status, body) = response(request.lines().next().unwrap_or(""), health);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// 返回状态和内容
+fn response(request_line: &str, health: &Health) -> (&'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    // 忽略查询参数
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+    if method != "GET" {
+        return (
+            "405 Method Not Allowed",
+            "{\"error\":\"method not allowed\"}".to_string(),
+        );
+    }
+    let report = match path {
+        "/livez" => health.livez(),
+        "/readyz" => health.readyz(),
+        _ => return ("404 Not Found", "{\"error\":\"not found\"}".to_string()),
+    };
+    if report.ok() {
+        ("200 OK", report.to_json())
+    } else {
+        ("503 Service Unavailable", report.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use vnt::util::health::{Health, ReadyCheck};
+    use vnt::util::subsystem::Switch;
+
+    use super::response;
+
+    #[test]
+    fn test_wedged_component() {
+        let health = Health::new();
+        let watchdog = health.watchdog("mock", Duration::from_millis(50));
+        watchdog.beat();
+        assert_eq!(response("GET /livez HTTP/1.1", &health).0, "200 OK");
+        // 不再循环的组件
+        std::thread::sleep(Duration::from_millis(120));
+        let (status, body) = response("GET /livez HTTP/1.1", &health);
+        assert_eq!(status, "503 Service Unavailable");
+        assert!(body.contains("\"check\":\"mock\""), "{}", body);
+        // 存活失败和就绪无关
+        health.ready(ReadyCheck::Registered);
+        health.ready(ReadyCheck::Tun);
+        health.ready(ReadyCheck::DataPath);
+        assert_eq!(response("GET /readyz HTTP/1.1", &health).0, "200 OK");
+        watchdog.beat();
+        assert_eq!(response("GET /livez HTTP/1.1", &health).0, "200 OK");
+    }
+
+    #[test]
+    fn test_failed_registration() {
+        let health = Health::new();
+        health.ready(ReadyCheck::Tun);
+        health.ready(ReadyCheck::DataPath);
+        health.not_ready(ReadyCheck::Registered, "token error");
+        let (status, body) = response("GET /readyz?verbose HTTP/1.1", &health);
+        assert_eq!(status, "503 Service Unavailable");
+        assert_eq!(
+            body,
+            "{\"status\":\"fail\",\"failed\":[{\"check\":\"registered\",\"reason\":\"token error\"}]}"
+        );
+        assert_eq!(response("GET /livez HTTP/1.1", &health).0, "200 OK");
+        assert_eq!(response("GET /x HTTP/1.1", &health).0, "404 Not Found");
+        assert_eq!(
+            response("POST /readyz HTTP/1.1", &health).0,
+            "405 Method Not Allowed"
+        );
+    }
+
+    #[test]
+    fn test_http() {
+        let health = Health::new();
+        health.not_ready(ReadyCheck::Registered, "invalid ip");
+        let switch = Switch::new();
+        let addr = super::start("127.0.0.1:0".parse().unwrap(), health, &switch).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 503"), "{}", out);
+        assert!(out.ends_with("]}"), "{}", out);
+        assert!(out.contains("invalid ip"));
+        // 作为子系统停止后不再监听
+        super::stop(&switch, addr);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use getopts::Options;
+use vnt::handle::loadtest::{self, LoadTestConfig};
+
+use crate::config::numeric::{BitsPerSec, Bytes, Count, Report, Seconds, Spec};
+use crate::exit::{self, ExitReason};
+
+const CLIENTS: Spec<Count> = Spec {
+    name: "--clients",
+    unit: 1,
+    min: Count(1),
+    max: Count(100_000),
+    zero: false,
+};
+const SOCKETS: Spec<Count> = Spec {
+    name: "--sockets",
+    unit: 1,
+    min: Count(1),
+    max: Count(4096),
+    zero: false,
+};
+const TOKENS: Spec<Count> = Spec {
+    name: "--tokens",
+    unit: 1,
+    min: Count(1),
+    max: Count(100_000),
+    zero: false,
+};
+/// 不带单位时是秒
+const DURATION: Spec<Seconds> = Spec {
+    name: "--duration",
+    unit: 1000,
+    min: Seconds(Duration::from_secs(5)),
+    max: Seconds(Duration::from_secs(86_400)),
+    zero: false,
+};
+const RAMP_UP: Spec<Seconds> = Spec {
+    name: "--ramp-up",
+    unit: 1000,
+    min: Seconds(Duration::from_millis(1)),
+    max: Seconds(Duration::from_secs(3600)),
+    zero: true,
+};
+const HEARTBEAT: Spec<Seconds> = Spec {
+    name: "--heartbeat",
+    unit: 1000,
+    min: Seconds(Duration::from_millis(100)),
+    max: Seconds(Duration::from_secs(60)),
+    zero: false,
+};
+const LIST_INTERVAL: Spec<Seconds> = Spec {
+    name: "--list-interval",
+    unit: 1000,
+    min: Seconds(Duration::from_secs(1)),
+    max: Seconds(Duration::from_secs(3600)),
+    zero: true,
+};
+/// 不带单位时是mbps
+const TRAFFIC: Spec<BitsPerSec> = Spec {
+    name: "--traffic",
+    unit: 1_000_000,
+    min: BitsPerSec(8000),
+    max: BitsPerSec(100_000_000_000),
+    zero: true,
+};
+const PACKET_SIZE: Spec<Bytes> = Spec {
+    name: "--packet-size",
+    unit: 1,
+    min: Bytes(64),
+    max: Bytes(1450),
+    zero: false,
+};
+
+/// 'vnt-cli loadtest ...'，模拟大量客户端压测自己的服务器
+pub fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("s", "", "服务器地址", "<server>");
+    opts.optopt("k", "", "组网标识", "<token>");
+    opts.optopt("", "clients", "模拟的客户端数量", "<N>");
+    opts.optopt("", "sockets", "使用的udp socket数量", "<N>");
+    opts.optopt("", "tokens", "不同组网标识的数量", "<N>");
+    opts.optopt("", "duration", "压测时长", "<time>");
+    opts.optopt("", "ramp-up", "逐步注册的时间", "<time>");
+    opts.optopt("", "heartbeat", "心跳间隔", "<time>");
+    opts.optopt("", "list-interval", "拉取设备列表的间隔", "<time>");
+    opts.optopt("", "traffic", "经服务器中继的总流量", "<rate>");
+    opts.optopt("", "packet-size", "中继数据包大小", "<bytes>");
+    opts.optflag("", "i-own-this-server", "确认压测的是自己的服务器");
+    opts.optflag("h", "help", "帮助");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(program);
+            exit::config_error(f.to_string());
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(program);
+        return;
+    }
+    let server = match matches.opt_str("s") {
+        Some(server) => server,
+        None => {
+            print_usage(program);
+            exit::config_error("loadtest requires '-s'");
+        }
+    };
+    let token = match matches.opt_str("k") {
+        Some(token) => token,
+        None => exit::config_error("loadtest requires '-k'"),
+    };
+    if !matches.opt_present("i-own-this-server") && is_public_server(&server) {
+        exit::config_error(format!(
+            "refusing to load test the public server {}, run your own server and pass '--i-own-this-server'",
+            server
+        ));
+    }
+    let mut report = Report::new();
+    if !matches.opt_present("clients") {
+        report.error("loadtest requires '--clients'");
+    }
+    let clients = report
+        .value(&CLIENTS, matches.opt_str("clients").as_deref())
+        .map_or(1, |v| v.0 as usize);
+    // 默认每个socket承载不超过64个客户端
+    let sockets = report
+        .value(&SOCKETS, matches.opt_str("sockets").as_deref())
+        .map_or(((clients + 63) / 64).max(8).min(clients), |v| v.0 as usize);
+    let tokens = report
+        .value(&TOKENS, matches.opt_str("tokens").as_deref())
+        .map_or(1, |v| v.0 as usize);
+    let duration = report
+        .value(&DURATION, matches.opt_str("duration").as_deref())
+        .map_or(Duration::from_secs(60), |v| v.0);
+    let ramp_up = report
+        .value(&RAMP_UP, matches.opt_str("ramp-up").as_deref())
+        .map_or(Duration::from_secs(10), |v| v.0);
+    let heartbeat = report
+        .value(&HEARTBEAT, matches.opt_str("heartbeat").as_deref())
+        .map_or(Duration::from_secs(3), |v| v.0);
+    let list_interval = report
+        .value(&LIST_INTERVAL, matches.opt_str("list-interval").as_deref())
+        .map_or(Duration::from_secs(30), |v| v.0);
+    let traffic = report
+        .value(&TRAFFIC, matches.opt_str("traffic").as_deref())
+        .map_or(0, |v| v.bytes_per_sec());
+    let packet_size = report
+        .value(&PACKET_SIZE, matches.opt_str("packet-size").as_deref())
+        .map_or(1200, |v| v.0 as usize);
+    if ramp_up >= duration {
+        report.error(format!(
+            "'--ramp-up {}' must be shorter than '--duration {}'",
+            Seconds(ramp_up),
+            Seconds(duration)
+        ));
+    }
+    if traffic > 0 && tokens >= clients {
+        report.warn("every client has its own token, '--traffic' has no destination");
+    }
+    match report.finish() {
+        Ok(warnings) => {
+            for warning in warnings {
+                println!("{}", warning);
+            }
+        }
+        Err(e) => exit::config_error(e),
+    }
+    let server = match resolve(&server) {
+        Some(addr) => addr,
+        None => exit::config_error(format!("server '{}' could not be resolved", server)),
+    };
+    let config = LoadTestConfig {
+        server,
+        token,
+        tokens,
+        clients,
+        sockets,
+        duration,
+        ramp_up,
+        heartbeat,
+        list_interval,
+        traffic,
+        packet_size,
+    };
+    println!(
+        "Load testing {} with {} clients over {} sockets for {}",
+        server,
+        clients,
+        sockets.min(clients),
+        Seconds(duration)
+    );
+    match loadtest::run(&config) {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            println!("loadtest failed: {}", e);
+            exit::exit(ExitReason::Runtime, format!("loadtest failed: {}", e));
+        }
+    }
+}
+
+fn resolve(server: &str) -> Option<SocketAddr> {
+    server.to_socket_addrs().ok()?.next()
+}
+
+/// 是否是默认的公共服务器，按名称和解析出的地址比较
+fn is_public_server(server: &str) -> bool {
+    let public = crate::DEFAULT_SERVER;
+    let host = |v: &str| {
+        v.trim()
+            .rsplit_once(':')
+            .map_or(v.trim(), |(h, _)| h)
+            .to_lowercase()
+    };
+    if host(server) == host(public) {
+        return true;
+    }
+    let public: HashSet<_> = match public.to_socket_addrs() {
+        Ok(addrs) => addrs.map(|v| v.ip()).collect(),
+        // 无法解析公共服务器时，只按名称判断
+        Err(_) => return false,
+    };
+    match server.to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|v| public.contains(&v.ip())),
+        Err(_) => false,
+    }
+}
+
+fn print_usage(program: &str) {
+    println!(
+        "Usage: {} loadtest -s <server> -k <token> --clients <N> [options]",
+        program
+    );
+    println!();
+    println!("模拟大量客户端注册、心跳、同步设备列表和中继数据,用于评估自己的中继服务器的容量");
+    println!("Options:");
+    println!("  -s <server>          自己的服务器地址");
+    println!("  -k <token>           组网标识");
+    println!("  --clients <N>        模拟的客户端数量,取值1~100000");
+    println!("  --sockets <N>        使用的udp socket数量,默认每64个客户端一个,至少8个;服务器按来源地址区分客户端时要设为和客户端数相同");
+    println!("  --tokens <N>         使用N个组网标识'<token>-0'~'<token>-(N-1)',客户端轮流分配,默认1即共用'-k'");
+    println!("  --duration <time>    压测时长,默认60s,不带单位时是秒");
+    println!("  --ramp-up <time>     在这段时间内均匀地发起注册,默认10s");
+    println!("  --heartbeat <time>   心跳间隔,默认3s和客户端相同,实际间隔在±20%内随机");
+    println!("  --list-interval <time> 定期拉取设备列表的间隔,默认30s,0表示只在纪元变化时拉取");
+    println!("  --traffic <rate>     同一组网标识下的客户端之间经服务器中继的总流量,如10mbps,不带单位时是mbps,默认0不发送");
+    println!("  --packet-size <bytes> 中继数据包大小,默认1200");
+    println!("  --i-own-this-server  确认压测的是自己的服务器,压测默认的公共服务器时必须指定,否则拒绝运行");
+    println!();
+    println!("结束后输出注册耗时的p50/p90/p99、心跳丢失率和实际收到的中继吞吐;不支持服务端加密");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_public_server;
+
+    #[test]
+    fn test_public_server() {
+        assert!(is_public_server(crate::DEFAULT_SERVER));
+        assert!(is_public_server(" NAT1.wherewego.top:1234"));
+    }
+}
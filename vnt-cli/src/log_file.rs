@@ -0,0 +1,123 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::pattern::PatternEncoder;
+
+/// 日志文件达到这个大小时滚动
+pub const ROTATE_SIZE: u64 = 10 * 1024 * 1024;
+/// 保留的历史文件数，vnt.log.1最新
+pub const ARCHIVES: u32 = 5;
+const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.3f)} [{f}:{L}] {h({l})} {M}:{m}{n}";
+
+/// 正在写入的日志文件，诊断包从这里读取
+static FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// '--log-path'，"-"表示stderr，适合systemd/journald
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogOutput {
+    Stderr,
+    File(PathBuf),
+}
+
+impl FromStr for LogOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("empty log path".to_string()),
+            "-" => Ok(LogOutput::Stderr),
+            path => Ok(LogOutput::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl Display for LogOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogOutput::Stderr => f.write_str("stderr"),
+            LogOutput::File(path) => write!(
+                f,
+                "{} (rotate at {}MB, keep {})",
+                path.display(),
+                ROTATE_SIZE / 1024 / 1024,
+                ARCHIVES
+            ),
+        }
+    }
+}
+
+/// 按'--log-path'生成配置，文件无法创建时返回错误，由调用方改为stderr
+pub fn config(output: &LogOutput) -> Result<log4rs::Config, String> {
+    let appender: Box<dyn log4rs::append::Append> = match output {
+        LogOutput::Stderr => Box::new(
+            ConsoleAppender::builder()
+                .target(Target::Stderr)
+                .encoder(Box::new(PatternEncoder::new(PATTERN)))
+                .build(),
+        ),
+        LogOutput::File(path) => {
+            if let Some(parent) = path.parent().filter(|v| !v.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            let roller = FixedWindowRoller::builder()
+                .base(1)
+                .build(&archive_pattern(path), ARCHIVES)
+                .map_err(|e| e.to_string())?;
+            let policy =
+                CompoundPolicy::new(Box::new(SizeTrigger::new(ROTATE_SIZE)), Box::new(roller));
+            let appender = RollingFileAppender::builder()
+                .encoder(Box::new(PatternEncoder::new(PATTERN)))
+                .build(path, Box::new(policy))
+                .map_err(|e| e.to_string())?;
+            let _ = FILE.set(path.clone());
+            Box::new(appender)
+        }
+    };
+    log4rs::Config::builder()
+        .appender(Appender::builder().build("vnt", appender))
+        .build(Root::builder().appender("vnt").build(LevelFilter::Info))
+        .map_err(|e| e.to_string())
+}
+
+/// '--log-path'指定的日志文件
+pub fn file() -> Option<&'static Path> {
+    FILE.get().map(|v| v.as_path())
+}
+
+/// 历史文件的命名规则，'{}'是序号
+fn archive_pattern(path: &Path) -> String {
+    // log4rs用'{}'作为占位符，路径中的花括号无法转义，替换掉
+    let path = path.display().to_string().replace(['{', '}'], "_");
+    format!("{}.{{}}", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::log_file::{archive_pattern, LogOutput};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("-".parse::<LogOutput>(), Ok(LogOutput::Stderr));
+        assert_eq!(
+            " /var/log/vnt.log".parse::<LogOutput>(),
+            Ok(LogOutput::File(PathBuf::from("/var/log/vnt.log")))
+        );
+        assert!("".parse::<LogOutput>().is_err());
+        assert_eq!(
+            archive_pattern(Path::new("/var/log/vnt.log")),
+            "/var/log/vnt.log.{}"
+        );
+        assert_eq!(archive_pattern(Path::new("a{b}.log")), "a_b_.log.{}");
+    }
+}
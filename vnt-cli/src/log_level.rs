@@ -0,0 +1,410 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// 'loglevel'命令列出的日志目标，只包含本项目的模块，也可以写模块名的最后一段，如'punch'
+pub const TARGETS: &[&str] = &[
+    "vnt::channel",
+    "vnt::channel::punch",
+    "vnt::channel::udp_channel",
+    "vnt::channel::tcp_channel",
+    "vnt::cipher",
+    "vnt::core",
+    "vnt::external_route",
+    "vnt::handle",
+    "vnt::handle::maintain",
+    "vnt::handle::recv_data",
+    "vnt::handle::tun_tap",
+    "vnt::ip_proxy",
+    "vnt::nat",
+    "vnt::port_mapping",
+    "vnt::protocol",
+    "vnt::tun_tap_device",
+    "vnt::util",
+    "vnt_cli",
+    "common",
+];
+
+/// 单条日志最多保留的字符数
+const MSG_MAX_LEN: usize = 16 * 1024;
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// 全局级别和按目标覆盖的级别
+///
+/// 没有覆盖时只比较一次原子变量；有覆盖时按最长匹配的目标决定级别。
+/// log::max_level取所有级别中最高的，更低的日志在宏里就被过滤掉
+pub struct Levels {
+    base: AtomicUsize,
+    active: AtomicBool,
+    overrides: RwLock<Vec<(String, LevelFilter)>>,
+}
+
+pub static GLOBAL: Levels = Levels::new();
+
+impl Levels {
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicUsize::new(LevelFilter::Info as usize),
+            active: AtomicBool::new(false),
+            overrides: RwLock::new(Vec::new()),
+        }
+    }
+    pub fn base(&self) -> LevelFilter {
+        LEVELS[self.base.load(Ordering::Relaxed)]
+    }
+    pub fn set_base(&self, level: LevelFilter) {
+        self.base.store(level as usize, Ordering::Relaxed);
+    }
+    /// 设置目标的级别，None表示取消覆盖
+    pub fn set(&self, target: &str, level: Option<LevelFilter>) {
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.retain(|(k, _)| k != target);
+        if let Some(level) = level {
+            overrides.push((target.to_string(), level));
+        }
+        self.active.store(!overrides.is_empty(), Ordering::Relaxed);
+    }
+    pub fn overrides(&self) -> Vec<(String, LevelFilter)> {
+        self.overrides.read().unwrap().clone()
+    }
+    /// 所有级别中最高的，用于log::set_max_level
+    pub fn max(&self) -> LevelFilter {
+        self.overrides
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.base(), |a, b| a.max(b))
+    }
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        if !self.active.load(Ordering::Relaxed) {
+            return self.base();
+        }
+        let overrides = self.overrides.read().unwrap();
+        overrides
+            .iter()
+            .filter(|(k, _)| target_matches(k, target))
+            .max_by_key(|(k, _)| k.len())
+            .map_or_else(|| self.base(), |(_, level)| *level)
+    }
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+}
+
+/// 目标是模块路径的前缀，或者是路径中的某一段(及其子模块)
+fn target_matches(key: &str, target: &str) -> bool {
+    if target == key {
+        return true;
+    }
+    if let Some(rest) = target.strip_prefix(key) {
+        if rest.starts_with("::") {
+            return true;
+        }
+    }
+    if key.contains("::") {
+        return false;
+    }
+    target.split("::").skip(1).any(|v| v == key)
+}
+
+/// 包装实际输出日志的logger，先按目标过滤
+pub struct ScopedLogger {
+    levels: &'static Levels,
+    inner: Box<dyn Log>,
+}
+
+impl ScopedLogger {
+    pub fn new(levels: &'static Levels, inner: Box<dyn Log>) -> Self {
+        Self { levels, inner }
+    }
+}
+
+impl Log for ScopedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.levels.enabled(metadata) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.levels.enabled(record.metadata()) {
+            let msg = plain(&record.args().to_string());
+            self.inner.log(
+                &Record::builder()
+                    .metadata(record.metadata().clone())
+                    .args(format_args!("{}", msg))
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            )
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// 日志每条一行，不带颜色和控制字符，多行的内容(如错误链)用' | '连接
+fn plain(msg: &str) -> String {
+    let msg = vnt::util::sanitize::text(msg, MSG_MAX_LEN, true);
+    if !msg.contains('\n') {
+        return msg;
+    }
+    let lines: Vec<&str> = msg
+        .lines()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect();
+    lines.join(" | ")
+}
+
+/// 安装logger，inner自身的级别要放开，由这里过滤
+pub fn init(inner: Box<dyn Log>) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(ScopedLogger::new(&GLOBAL, inner)))?;
+    log::set_max_level(GLOBAL.max());
+    Ok(())
+}
+
+/// 解析'--log-level'，如'info,punch=trace,udp_channel=warn'，不带'='的是全局级别
+pub fn parse_spec(spec: &str) -> Result<(Option<LevelFilter>, Vec<(String, LevelFilter)>), String> {
+    let mut base = None;
+    let mut overrides = Vec::new();
+    for item in spec.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        match item.split_once('=') {
+            Some((target, level)) => {
+                let target = parse_target(target)?;
+                overrides.push((target, parse_level(level)?));
+            }
+            None => base = Some(parse_level(item)?),
+        }
+    }
+    Ok((base, overrides))
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.trim().parse().map_err(|_| {
+        format!(
+            "invalid log level '{}', available: off,error,warn,info,debug,trace",
+            level.trim()
+        )
+    })
+}
+
+fn parse_target(target: &str) -> Result<String, String> {
+    let target = target.trim();
+    let valid = !target.is_empty()
+        && target
+            .split("::")
+            .all(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    if valid {
+        Ok(target.to_string())
+    } else {
+        Err(format!("invalid log target '{}'", target))
+    }
+}
+
+/// 'loglevel'命令，不带参数时列出当前设置，'loglevel <target> <level>'修改，level为reset时取消覆盖，
+/// target为root时修改全局级别
+pub fn command(args: &str) -> String {
+    command_(&GLOBAL, args, log::set_max_level)
+}
+
+fn command_(levels: &Levels, args: &str, set_max_level: fn(LevelFilter)) -> String {
+    let mut args = args.split_whitespace();
+    let (target, level) = match (args.next(), args.next(), args.next()) {
+        (None, _, _) => return list(levels),
+        (Some(target), Some(level), None) => (target, level),
+        _ => return "usage: loglevel [<target> <level|reset>]\n".to_string(),
+    };
+    if target == "root" {
+        match parse_level(level) {
+            Ok(level) => levels.set_base(level),
+            Err(e) => return format!("{}\n", e),
+        }
+    } else {
+        let target = match parse_target(target) {
+            Ok(target) => target,
+            Err(e) => return format!("{}\n", e),
+        };
+        if level == "reset" {
+            levels.set(&target, None);
+        } else {
+            match parse_level(level) {
+                Ok(level) => levels.set(&target, Some(level)),
+                Err(e) => return format!("{}\n", e),
+            }
+        }
+    }
+    set_max_level(levels.max());
+    list(levels)
+}
+
+fn list(levels: &Levels) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "root = {}", levels.base());
+    for (target, level) in levels.overrides() {
+        let _ = writeln!(out, "{} = {}", target, level);
+    }
+    let _ = writeln!(out, "targets: {}", TARGETS.join(", "));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    use super::{command_, parse_spec, target_matches, Levels, ScopedLogger};
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for &'static Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.target().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &ScopedLogger, target: &str, level: Level) {
+        logger.log(
+            &Record::builder()
+                .target(target)
+                .level(level)
+                .args(format_args!("x"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let levels: &'static Levels = Box::leak(Box::new(Levels::new()));
+        let capture: &'static Capture = Box::leak(Box::new(Capture(Mutex::new(Vec::new()))));
+        let logger = ScopedLogger::new(levels, Box::new(capture));
+        log(&logger, "vnt::channel::punch", Level::Debug);
+        assert!(capture.0.lock().unwrap().is_empty());
+
+        levels.set("punch", Some(LevelFilter::Trace));
+        levels.set("vnt::channel::udp_channel", Some(LevelFilter::Warn));
+        assert_eq!(levels.max(), LevelFilter::Trace);
+        log(&logger, "vnt::channel::punch", Level::Trace);
+        log(&logger, "vnt::handle::maintain::punch", Level::Debug);
+        log(&logger, "vnt::channel::udp_channel", Level::Info);
+        log(&logger, "vnt::channel::context", Level::Debug);
+        log(&logger, "vnt::channel::context", Level::Info);
+        assert_eq!(
+            *capture.0.lock().unwrap(),
+            vec![
+                "vnt::channel::punch",
+                "vnt::handle::maintain::punch",
+                "vnt::channel::context"
+            ]
+        );
+
+        assert!(command_(levels, "punch reset", |_| {})
+            .starts_with("root = INFO\nvnt::channel::udp_channel = WARN\n"));
+        assert_eq!(levels.level_for("vnt::channel::punch"), LevelFilter::Info);
+        assert!(command_(levels, "punch loud", |_| {}).starts_with("invalid log level"));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(target_matches("vnt::channel", "vnt::channel::punch"));
+        assert!(!target_matches("vnt::chan", "vnt::channel::punch"));
+        assert!(!target_matches("vnt", "vnt_cli"));
+        // 只有一段时不匹配crate名以外的前缀
+        assert!(!target_matches("channel", "channel_x::a"));
+        assert!(target_matches("channel", "vnt::channel::punch"));
+        let (base, overrides) = parse_spec("info, punch=trace,udp_channel=warn").unwrap();
+        assert_eq!(base, Some(LevelFilter::Info));
+        assert_eq!(
+            overrides,
+            vec![
+                ("punch".to_string(), LevelFilter::Trace),
+                ("udp_channel".to_string(), LevelFilter::Warn)
+            ]
+        );
+        assert!(parse_spec("punch=loud").is_err());
+        assert!(parse_spec("a b=info").is_err());
+    }
+
+    struct Messages(Mutex<Vec<(String, String)>>);
+
+    impl Log for &'static Messages {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.target().to_string(), record.args().to_string()));
+        }
+        fn flush(&self) {}
+    }
+
+    /// 启动和出错时经过ui和回调输出的内容，日志中不能有转义序列、换行和连续重复的消息
+    #[test]
+    fn test_plain_log() {
+        use std::time::Duration;
+
+        use vnt::handle::callback::{ErrorType, ResumeInfo};
+        use vnt::{ErrorInfo, VntCallback};
+
+        let messages: &'static Messages = Box::leak(Box::new(Messages(Mutex::new(Vec::new()))));
+        super::init(Box::new(messages)).unwrap();
+        console::set_colors_enabled(true);
+
+        crate::ui::warn("Safe mode: crashed 3 times in a row, disabled: telemetry");
+        crate::ui::info("Health check listening on http://127.0.0.1:8080/livez and /readyz");
+        crate::ui::error("'--health-listen 127.0.0.1:8080' failed: bind\n\nCaused by:\n    in use");
+        crate::ui::event(format!(
+            "new inbound flow {}",
+            console::style("10.26.0.2").red()
+        ));
+        log::warn!("设备名称 \x1b[31mred\x1b[0m\r\n\tnext");
+        // vnt中已经记录日志，回调只输出到控制台
+        let handler = crate::callback::VntHandler::new(None, false);
+        handler.error(ErrorInfo::new_msg(
+            ErrorType::RouteWarning,
+            "route conflict".to_string(),
+        ));
+        handler.resumed(ResumeInfo::new(Duration::from_secs(60), true));
+
+        // 其他测试可能同时写日志，只检查本模块和ui、回调的
+        let list: Vec<String> = messages
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(target, _)| target.starts_with("vnt_cli"))
+            .map(|(_, msg)| msg.clone())
+            .collect();
+        assert_eq!(list.len(), 5);
+        for msg in &list {
+            assert!(!msg.contains('\x1b'), "{:?}", msg);
+            assert!(!msg.contains('\n') && !msg.contains('\r'), "{:?}", msg);
+        }
+        assert!(list.windows(2).all(|v| v[0] != v[1]));
+        assert_eq!(
+            list[2],
+            "'--health-listen 127.0.0.1:8080' failed: bind | Caused by: | in use"
+        );
+        assert_eq!(list[3], "new inbound flow 10.26.0.2");
+        assert_eq!(list[4], "设备名称 red | next");
+    }
+}
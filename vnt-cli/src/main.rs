@@ -1,338 +1,652 @@
 use std::io;
-use std::net::Ipv4Addr;
 use std::path::PathBuf;
-use std::str::FromStr;
 
-use console::style;
 use getopts::Options;
 
-use common::args_parse::{ips_parse, out_ips_parse};
-use vnt::channel::punch::PunchModel;
-use vnt::channel::UseChannelType;
-use vnt::cipher::CipherModel;
-use vnt::core::{Config, Vnt};
+use vnt::core::Vnt;
+use vnt::util::subsystem::{Probe, Subsystem, Switch};
 
+use crate::config::settings::{self, Settings};
+#[cfg(feature = "command")]
+use crate::console_out::{out, outln};
+use crate::exit::ExitReason;
+use crate::ui::{green, yellow};
+
+mod clean;
 #[cfg(feature = "command")]
 mod command;
 mod config;
 #[cfg(feature = "command")]
 mod console_out;
+mod daemon;
+mod data_dir;
+mod exit;
 mod generated_serial_number;
+mod health_http;
+#[cfg(feature = "loadtest")]
+mod loadtest;
+#[cfg(feature = "log")]
+mod log_file;
+#[cfg(feature = "log")]
+mod log_level;
+mod policy;
+mod register_wait;
+mod retention;
 mod root_check;
+mod rtt_history;
+mod safe_mode;
+mod seen_devices;
+mod setup;
+#[cfg(feature = "command")]
+mod shared_rate;
+mod state;
+#[cfg(target_os = "linux")]
+mod takeover;
+mod telemetry;
+mod ui;
+mod warm_restart;
 
+/// 不指定'-s'时使用的公共服务器
+pub const DEFAULT_SERVER: &str = "nat1.wherewego.top:29872";
+
+/// 保存状态的目录，不可写时返回错误
 pub fn app_home() -> io::Result<PathBuf> {
-    let root_path = match std::env::current_exe() {
-        Ok(path) => {
-            if let Some(v) = path.as_path().parent() {
-                v.to_path_buf()
-            } else {
-                log::warn!("current_exe parent none:{:?}", path);
-                PathBuf::new()
+    data_dir::get()
+}
+
+/// 返回实际使用的日志配置，启动时打印一次
+#[cfg(feature = "log")]
+fn init_log(spec: Option<String>, path: Option<String>) -> String {
+    let (base, overrides) = match spec.as_deref().map(log_level::parse_spec) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => exit::config_error(format!("'--log-level' {}", e)),
+        None => (None, Vec::new()),
+    };
+    let output = match path.as_deref().map(str::parse::<log_file::LogOutput>) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(e)) => exit::config_error(format!("'--log-path' {}", e)),
+        None => None,
+    };
+    // '--log-path'优先于log4rs.yaml
+    let (config, describe) = match (output, data_dir::log_config()) {
+        (Some(output), _) => match log_file::config(&output) {
+            Ok(config) => (Some(config), output.to_string()),
+            Err(e) => {
+                // 目录无法创建或者没有权限时改为输出到stderr
+                eprintln!("log path {} error {}, logging to stderr", output, e);
+                (stderr_log_config(), "stderr".to_string())
             }
-        }
-        Err(e) => {
-            log::warn!("current_exe err:{:?}", e);
-            PathBuf::new()
-        }
+        },
+        (None, Some(path)) => match log4rs::config::load_config_file(&path, Default::default()) {
+            Ok(config) => (Some(config), path.display().to_string()),
+            Err(e) => {
+                // 日志文件无法创建时(例如只读文件系统)改为输出到stderr
+                eprintln!(
+                    "log config {} error {:?}, logging to stderr",
+                    path.display(),
+                    e
+                );
+                (stderr_log_config(), "stderr".to_string())
+            }
+        },
+        // 没有配置文件时只有指定了'--log-level'才输出到stderr
+        (None, None) if spec.is_some() => (stderr_log_config(), "stderr".to_string()),
+        (None, None) => (None, String::new()),
+    };
+    let mut config = match config {
+        Some(config) => config,
+        None => return "log: disabled, use '--log-level' or '--log-path' to enable".to_string(),
     };
-    let path = root_path.join("env");
-    if !path.exists() {
-        std::fs::create_dir_all(&path)?;
+    // 配置文件里的级别作为全局级别，log4rs本身不再过滤，由log_level按目标过滤
+    let base = base.unwrap_or(config.root().level());
+    log_level::GLOBAL.set_base(base);
+    let overrides_len = overrides.len();
+    for (target, level) in overrides {
+        log_level::GLOBAL.set(&target, Some(level));
     }
-    Ok(path)
+    config.root_mut().set_level(log::LevelFilter::Trace);
+    if let Err(e) = log_level::init(Box::new(log4rs::Logger::new(config))) {
+        return format!("log: init error {:?}", e);
+    }
+    if overrides_len > 0 {
+        format!(
+            "log: {} ({} module overrides) -> {}",
+            base, overrides_len, describe
+        )
+    } else {
+        format!("log: {} -> {}", base, describe)
+    }
+}
+
+/// 'loglevel'命令，查看和修改按模块的日志级别
+#[cfg(all(feature = "command", feature = "log"))]
+pub fn loglevel_command(args: &str) -> String {
+    log_level::command(args)
+}
+
+#[cfg(all(feature = "command", not(feature = "log")))]
+pub fn loglevel_command(_args: &str) -> String {
+    "logging is not enabled in this build\n".to_string()
+}
+
+#[cfg(feature = "log")]
+fn stderr_log_config() -> Option<log4rs::Config> {
+    use log4rs::append::console::{ConsoleAppender, Target};
+    use log4rs::config::{Appender, Root};
+    let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
+    log4rs::Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .build(
+            Root::builder()
+                .appender("stderr")
+                .build(log::LevelFilter::Warn),
+        )
+        .ok()
 }
 
 fn main() {
-    #[cfg(feature = "log")]
-    let _ = log4rs::init_file("log4rs.yaml", Default::default());
+    exit::init();
     let args: Vec<String> = std::env::args().collect();
     let program = args[0].clone();
+    #[cfg(feature = "loadtest")]
+    if args.get(1).map(|v| v.as_str()) == Some("loadtest") {
+        loadtest::main(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|v| v.as_str()) == Some("clean") {
+        clean::main(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|v| v.as_str()) == Some("setup") {
+        setup::main(&program, &args[2..]);
+        return;
+    }
     let mut opts = Options::new();
     opts.optopt("k", "", "组网标识", "<token>");
-    opts.optopt("n", "", "设备名称", "<name>");
-    opts.optopt("d", "", "设备标识", "<id>");
+    opts.optopt("n", "name", "设备名称", "<name>");
+    opts.optopt("d", "device-id", "设备标识", "<id>");
     opts.optflag("c", "", "关闭交互式命令");
     opts.optopt("s", "", "注册和中继服务器地址", "<server>");
     opts.optmulti("e", "", "stun服务器", "<stun-server>");
     opts.optflag("a", "", "使用tap模式");
     opts.optopt("", "nic", "虚拟网卡名称,windows下使用tap则必填", "<tun0>");
+    opts.optopt("", "existing-tun", "使用预先创建的tun网卡", "<name>");
+    opts.optopt("", "tun-fd", "使用继承的tun描述符", "<fd>");
+    opts.optflag("", "observer", "观察者模式,不创建网卡");
     opts.optmulti("i", "", "配置点对网(IP代理)入站时使用", "<in-ip>");
     opts.optmulti("o", "", "配置点对网出站时使用", "<out-ip>");
     opts.optopt("w", "", "客户端加密", "<password>");
+    opts.optopt("", "key", "同'-w'", "<password>");
     opts.optflag("W", "", "服务端加密");
-    opts.optopt("u", "", "自定义mtu(默认为1430)", "<mtu>");
+    opts.optopt(
+        "u",
+        "mtu",
+        "自定义mtu(不加密默认为1450,加密默认为1410)",
+        "<mtu>",
+    );
     opts.optflag("", "tcp", "tcp");
+    opts.optopt(
+        "",
+        "tcp-fallback",
+        "udp连续失败多少次后改用tcp连接服务器",
+        "<N>",
+    );
     opts.optopt("", "ip", "指定虚拟ip", "<ip>");
+    opts.optflag("", "ip-fallback", "指定的ip不可用时由服务端分配");
     opts.optflag("", "relay", "仅使用服务器转发");
     opts.optopt("", "par", "任务并行度(必须为正整数)", "<parallel>");
+    opts.optopt("", "pin-workers", "工作线程绑定的核心", "<spec>");
+    opts.optopt("", "worker-priority", "工作线程的优先级", "<normal|high>");
     opts.optopt("", "model", "加密模式", "<model>");
     opts.optflag("", "finger", "指纹校验");
     opts.optopt("", "punch", "取值ipv4/ipv6", "<punch>");
     opts.optopt("", "ports", "监听的端口", "<port,port>");
+    opts.optopt("", "port", "只监听这一个udp端口", "<port>");
+    opts.optopt("", "bind", "监听的本地ip", "<ip>");
     opts.optflag("", "cmd", "开启窗口输入");
-    opts.optflag("", "no-proxy", "关闭内置代理");
-    opts.optflag("", "first-latency", "优先延迟");
+    opts.optflag("", "daemon", "非交互模式运行");
+    opts.optflag("", "nic-only", "同'--daemon'");
+    opts.optflagopt("", "no-proxy", "关闭内置代理", "<true|false>");
+    opts.optflagopt("", "first-latency", "优先延迟", "<true|false>");
     opts.optopt("", "use-channel", "使用通道 relay/p2p", "<use-channel>");
     opts.optopt("", "packet-loss", "丢包率", "<packet-loss>");
     opts.optopt("", "packet-delay", "延迟", "<packet-delay>");
     opts.optmulti("", "dns", "dns", "<dns>");
     opts.optmulti("", "mapping", "mapping", "<mapping>");
     opts.optopt("f", "", "配置文件", "<conf>");
+    opts.optopt("", "profile", "预设 mobile/server/gateway", "<profile>");
+    opts.optopt("", "data-dir", "保存状态和日志配置的目录", "<path>");
+    #[cfg(feature = "log")]
+    opts.optopt("", "log-level", "全局和按模块的日志级别", "<spec>");
+    #[cfg(feature = "log")]
+    opts.optopt("", "log-path", "日志文件，'-'表示输出到stderr", "<file|->");
+    opts.optopt("", "user", "配置完成后切换到的用户", "<user>");
+    opts.optopt("", "group", "配置完成后切换到的用户组", "<group>");
+    opts.optflag("", "no-drop-privileges", "不切换用户");
+    opts.optflag("", "report-usage", "在心跳中向服务端上报中继流量");
+    opts.optopt(
+        "",
+        "allow-server-actions",
+        "允许服务端请求执行的操作,逗号分隔",
+        "<resync,renat,rekey,collect-bundle,set-log-level>",
+    );
+    opts.optflag("", "require-encryption", "不允许对单个设备关闭加密");
+    opts.optflag("", "allow-peer-plaintext", "同意设备发起的关闭加密请求");
+    opts.optflag("", "no-flow-tracking", "不统计接收方向的流");
+    opts.optflag("", "notify-flows", "对端发起新连接时输出提示");
+    opts.optflag("", "no-fingerprint", "不公开设备指纹");
+    opts.optflag("", "no-tun-backpressure", "出口已满时继续读取网卡");
+    opts.optopt(
+        "",
+        "bring-up-relay",
+        "和新对端建立直连期间最多中继的包数",
+        "<N>",
+    );
+    opts.optopt("", "bring-up-timeout", "和新对端建立直连的超时时间", "<ms>");
+    opts.optopt("", "route-timeout", "路由没有收到数据多久后删除", "<s>");
+    opts.optopt(
+        "",
+        "register-timeout",
+        "启动后多久没有注册成功就退出",
+        "<s>",
+    );
+    opts.optopt("", "punch-window", "对称nat端口预测扫描的范围", "<N>");
+    opts.optopt(
+        "",
+        "cookie-threshold",
+        "每秒未验证的直连请求超过N个时要求对端回显cookie",
+        "<N>",
+    );
+    opts.optflag("", "snat-local", "本机发出的包源地址不是虚拟ip时改成虚拟ip");
+    opts.optopt(
+        "",
+        "tun-checksum",
+        "改写过的入站包写入网卡前的校验和处理",
+        "<compute|zero|auto>",
+    );
+    opts.optflag("", "mdns", "在局域网内公告虚拟ip并发现其他设备");
+    opts.optflag("", "reorder", "路径切换期间按序号重排收到的数据包");
+    opts.optopt(
+        "",
+        "inbound-limit",
+        "每个对端的接收速率上限",
+        "<pps>,<mbps>",
+    );
+    opts.optflag("", "auto-block-floods", "自动屏蔽持续超过接收限速的对端");
+    opts.optflag("", "no-broadcast", "不转发广播和组播");
+    opts.optflag("", "compact-encoding", "设备列表和打洞信息使用紧凑编码");
+    opts.optflag("", "upnp", "通过UPnP或NAT-PMP在路由器上映射udp端口");
+    opts.optflag("", "strict-inbound", "只和invite或批准过连接请求的对端直连");
+    opts.optopt("", "policy", "按分组设置对端策略的yaml/toml文件", "<file>");
+    opts.optmulti(
+        "",
+        "dns-route",
+        "指定域名通过虚拟网络转发给对端的dns服务",
+        "<domain>=<ip>",
+    );
+    opts.optopt("", "dns-listen", "本地dns服务的监听地址", "<addr>");
+    opts.optopt("", "health-listen", "存活和就绪检查的http地址", "<addr>");
+    opts.optflagopt(
+        "",
+        "rtt-history",
+        "每分钟记录对端的延迟和路径类型",
+        "<true|false>",
+    );
+    opts.optflag("", "safe-mode", "关闭可选功能,使用保守的设置启动");
+    opts.optflag("", "no-safe-mode", "连续崩溃后不自动进入安全模式");
+    opts.optopt(
+        "",
+        "share-anonymous-stats",
+        "每天上报一次匿名的打洞统计",
+        "<url>",
+    );
+    opts.optopt(
+        "",
+        "probe-budget",
+        "心跳、打洞等后台探测的总流量上限",
+        "<kbps|metered|off>",
+    );
+    opts.optopt("", "socket-limit", "打洞等辅助socket的数量上限", "<N>");
+    opts.optopt("", "max-devices", "接受服务端下发的设备数上限", "<N>");
+    opts.optopt(
+        "",
+        "peer-retention",
+        "超过这么久没有出现的对端清除保存的状态",
+        "<duration>",
+    );
+    opts.optflag("", "ephemeral-network", "临时网络,缩短对端状态的保留时间");
+    opts.optmulti(
+        "",
+        "standby-server",
+        "只用于打洞协商的备用服务器",
+        "<server>",
+    );
+    opts.optopt(
+        "",
+        "shared-rate-limit",
+        "和本机其他进程共享的总带宽",
+        "<mbps>",
+    );
+    opts.optopt(
+        "",
+        "history",
+        "查看虚拟ip或设备指纹的分配历史，或对端的延迟历史",
+        "<ip|fingerprint|rtt ...|export ...>",
+    );
     //"后台运行时,查看其他设备列表"
     opts.optflag("", "list", "后台运行时,查看其他设备列表");
     opts.optflag("", "all", "后台运行时,查看其他设备完整信息");
     opts.optflag("", "info", "后台运行时,查看当前设备信息");
+    opts.optflag("", "json", "和'--list'或'--info'一起使用,输出一行json");
     opts.optflag("", "route", "后台运行时,查看数据转发路径");
+    opts.optflag("", "connections", "后台运行时,查看对端发起的连接");
+    opts.optflag("", "health", "后台运行时,查看存活和就绪状态");
+    opts.optflag("", "dns-info", "后台运行时,查看dns转发规则和缓存");
     opts.optflag("", "stop", "停止后台运行");
+    opts.optopt(
+        "",
+        "control",
+        "后台运行时,通过本地控制通道查询,输出json",
+        "<list|status>",
+    );
+    opts.optflag("", "restart", "后台运行时,热重启(linux)");
+    opts.optflag("", "warm-restart", "由热重启拉起,内部使用");
+    opts.optflag(
+        "",
+        "takeover",
+        "接管同一数据目录下正在运行的进程,用于不中断升级(linux)",
+    );
+    opts.optopt("", "block", "后台运行时,屏蔽设备", "<ip|name>");
+    opts.optopt("", "unblock", "后台运行时,解除屏蔽", "<ip|name>");
+    opts.optopt(
+        "",
+        "forget",
+        "后台运行时,清除设备保存的所有状态",
+        "<ip|name>",
+    );
+    opts.optopt(
+        "",
+        "stats",
+        "后台运行时,查看统计信息",
+        "<drops|metrics|peers|probes|sockets|threads>",
+    );
+    opts.optopt("", "punch-peer", "后台运行时,手动对设备打洞", "<ip|name>");
+    opts.optopt(
+        "",
+        "invite",
+        "后台运行时,允许和设备直连并发起打洞",
+        "\"<ip|name> [duration]\"",
+    );
+    opts.optopt(
+        "",
+        "knock",
+        "后台运行时,请求严格入站的设备发起直连",
+        "\"<ip|name> [duration]\"",
+    );
+    opts.optflag("", "requests", "后台运行时,查看等待批准的连接请求");
+    opts.optopt(
+        "",
+        "accept",
+        "后台运行时,批准连接请求",
+        "\"<id> [duration]\"",
+    );
+    opts.optopt(
+        "",
+        "diary",
+        "后台运行时,查看和设备之间的连接日记",
+        "<ip|name>",
+    );
+    opts.optopt(
+        "",
+        "events",
+        "后台运行时,按时间、设备和类型查询所有设备的事件历史",
+        "\"[--since <10m>] [--peer <ip|name>] [--type <type>] [--json]\"",
+    );
+    opts.optopt("", "event-history", "内存中保留的事件数", "<N>");
+    opts.optopt("", "estimate", "后台运行时,估计能否和设备直连", "<ip|name>");
+    opts.optopt("", "bandwidth", "后台运行时,测量到设备的带宽", "<ip|name>");
+    opts.optflag(
+        "",
+        "include-relay",
+        "配合'--bandwidth'使用,没有直连时经过中继测量",
+    );
+    opts.optflag("", "no-bandwidth-probe", "拒绝对端发起的带宽测量");
+    opts.optopt(
+        "",
+        "ping",
+        "后台运行时,用虚拟网络协议ping设备",
+        "\"<ip|name> [count]\"",
+    );
+    opts.optflag("", "diary-log", "把对端的连接日记同时写入debug日志");
+    opts.optopt(
+        "",
+        "limit",
+        "后台运行时,修改设备的接收限速",
+        "inbound,<ip|name>,<<pps>,<mbps>|default>",
+    );
+    opts.optopt(
+        "",
+        "feature",
+        "后台运行时,修改和设备之间的加密",
+        "<ip|name>,encrypt,<on|off>",
+    );
+    opts.optopt(
+        "",
+        "prefer",
+        "后台运行时,修改设备的路径偏好",
+        "\"<ip|name> <p2p|relay|auto> [weights]\"",
+    );
+    opts.optopt(
+        "",
+        "subsystem",
+        "后台运行时,查看或单独启停子系统",
+        "\"<list|start <name>|stop <name> [--cascade]>\"",
+    );
+    opts.optopt(
+        "",
+        "path-weights",
+        "路径评分的权重",
+        "rtt=1.0,loss=50,jitter=2,bandwidth=0.1",
+    );
+    opts.optflag("", "explain", "配合'--stats'使用,显示说明");
+    opts.optopt("", "config", "toml配置文件", "<path>");
+    opts.optflag("", "show-config", "后台运行时,查看生效的配置及来源");
+    opts.optflag("", "gen-config", "输出带注释的配置文件模板");
+    opts.optflagopt("", "debug-bundle", "后台运行时,导出诊断包", "<path>");
+    opts.optflagopt("", "selftest", "后台运行时,端到端自检", "<ip|name>");
+    opts.optflag("", "no-redact", "配合'--debug-bundle'使用,不脱敏");
+    opts.optopt(
+        "",
+        "telemetry",
+        "后台运行时,查看或关闭匿名统计",
+        "<show|off>",
+    );
+    opts.optopt(
+        "",
+        "policy-ctl",
+        "后台运行时,查看、解释或重新加载策略",
+        "<show|explain <ip|name>|reload>",
+    );
     opts.optflag("h", "help", "帮助");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
-            print_usage(&program, opts);
-            println!("{}", f.to_string());
-            return;
+            print_usage(&program, &opts);
+            exit::config_error(f.to_string());
         }
     };
     if matches.opt_present("h") || args.len() == 1 {
-        print_usage(&program, opts);
+        print_usage(&program, &opts);
+        return;
+    }
+    if matches.opt_present("gen-config") {
+        print!("{}", settings::TEMPLATE);
         return;
     }
-    if !root_check::is_app_elevated() {
+    data_dir::init(matches.opt_str("data-dir"));
+    let settings = match Settings::from_args(&matches) {
+        Ok(settings) => settings,
+        Err(e) => exit::config_error(e),
+    };
+    #[cfg(feature = "log")]
+    let log_summary = init_log(settings.log_level.clone(), settings.log_path.clone());
+    if let Some(key) = matches.opt_str("history") {
+        // 直接读取本地记录，不需要后台运行
+        println!("{}", history(&key));
+        return;
+    }
+    // 使用预先创建的网卡或者不创建网卡时不需要root权限
+    let no_create_tun = matches.opt_present("existing-tun")
+        || matches.opt_present("tun-fd")
+        || matches.opt_present("observer");
+    if !no_create_tun && !root_check::is_app_elevated() {
         println!("Please run it with administrator or root privileges");
         #[cfg(any(target_os = "linux", target_os = "macos"))]
         sudo::escalate_if_needed().unwrap();
-        return;
+        exit::exit(
+            ExitReason::NetworkSetup,
+            "administrator or root privileges required",
+        );
     }
     #[cfg(feature = "command")]
-    if matches.opt_present("list") {
+    if matches.opt_present("json") && (matches.opt_present("list") || matches.opt_present("info")) {
+        let cmd = if matches.opt_present("list") {
+            "list json"
+        } else {
+            "status json"
+        };
+        command::command(command::CommandEnum::Json(cmd.to_string()));
+        return;
+    } else if matches.opt_present("list") {
         command::command(command::CommandEnum::List);
         return;
     } else if matches.opt_present("info") {
         command::command(command::CommandEnum::Info);
         return;
+    } else if matches.opt_present("show-config") {
+        command::command(command::CommandEnum::Config);
+        return;
     } else if matches.opt_present("stop") {
         command::command(command::CommandEnum::Stop);
         return;
+    } else if let Some(cmd) = matches.opt_str("control") {
+        command::control::command(&cmd);
+        return;
+    } else if matches.opt_present("restart") {
+        command::command(command::CommandEnum::Restart);
+        return;
     } else if matches.opt_present("route") {
         command::command(command::CommandEnum::Route);
         return;
     } else if matches.opt_present("all") {
         command::command(command::CommandEnum::All);
         return;
-    }
-    let conf = matches.opt_str("f");
-    let (config, cmd) = if conf.is_some() {
-        match config::read_config(&conf.unwrap()) {
-            Ok(c) => c,
-            Err(e) => {
-                println!("conf err {}", e);
-                return;
-            }
-        }
-    } else {
-        if !matches.opt_present("k") {
-            print_usage(&program, opts);
-            println!("parameter -k not found .");
-            return;
-        }
-        #[cfg(target_os = "windows")]
-        let tap = matches.opt_present("a");
-        let device_name = matches.opt_str("nic");
-        let token: String = matches.opt_get("k").unwrap().unwrap();
-        let device_id = matches.opt_get_default("d", String::new()).unwrap();
-        let device_id = if device_id.is_empty() {
-            config::get_device_id()
+    } else if matches.opt_present("connections") {
+        command::command(command::CommandEnum::Connections);
+        return;
+    } else if matches.opt_present("health") {
+        command::command(command::CommandEnum::Health);
+        return;
+    } else if matches.opt_present("dns-info") {
+        command::command(command::CommandEnum::Dns);
+        return;
+    } else if let Some(target) = matches.opt_str("block") {
+        command::command(command::CommandEnum::Block(target));
+        return;
+    } else if let Some(target) = matches.opt_str("unblock") {
+        command::command(command::CommandEnum::Unblock(target));
+        return;
+    } else if let Some(target) = matches.opt_str("forget") {
+        command::command(command::CommandEnum::Forget(target));
+        return;
+    } else if let Some(args) = matches.opt_str("limit") {
+        command::command(command::CommandEnum::Limit(args.replace(',', " ")));
+        return;
+    } else if let Some(target) = matches.opt_str("punch-peer") {
+        command::command(command::CommandEnum::Punch(target));
+        return;
+    } else if let Some(args) = matches.opt_str("invite") {
+        command::command(command::CommandEnum::Invite(args));
+        return;
+    } else if let Some(args) = matches.opt_str("knock") {
+        command::command(command::CommandEnum::Knock(args));
+        return;
+    } else if matches.opt_present("requests") {
+        command::command(command::CommandEnum::Requests);
+        return;
+    } else if let Some(args) = matches.opt_str("accept") {
+        command::command(command::CommandEnum::Accept(args));
+        return;
+    } else if let Some(target) = matches.opt_str("diary") {
+        command::command(command::CommandEnum::Diary(target));
+        return;
+    } else if let Some(args) = matches.opt_str("events") {
+        command::events::command(&args);
+        return;
+    } else if let Some(target) = matches.opt_str("estimate") {
+        command::command(command::CommandEnum::Estimate(target));
+        return;
+    } else if let Some(target) = matches.opt_str("bandwidth") {
+        let args = if matches.opt_present("include-relay") {
+            format!("{} --include-relay", target)
         } else {
-            device_id
-        };
-        if device_id.is_empty() {
-            print_usage(&program, opts);
-            println!("parameter -d not found .");
-            return;
-        }
-        let name = matches
-            .opt_get_default("n", os_info::get().to_string())
-            .unwrap();
-        let server_address_str = matches
-            .opt_get_default("s", "nat1.wherewego.top:29872".to_string())
-            .unwrap();
-
-        let mut stun_server = matches.opt_strs("e");
-        if stun_server.is_empty() {
-            stun_server.push("stun1.l.google.com:19302".to_string());
-            stun_server.push("stun2.l.google.com:19302".to_string());
-            stun_server.push("stun.miwifi.com:3478".to_string());
-        }
-        let dns = matches.opt_strs("dns");
-        let in_ip = matches.opt_strs("i");
-        let in_ip = match ips_parse(&in_ip) {
-            Ok(in_ip) => in_ip,
-            Err(e) => {
-                print_usage(&program, opts);
-                println!();
-                println!("-i: {:?} {}", in_ip, e);
-                println!("example: -i 192.168.0.0/24,10.26.0.3");
-                return;
-            }
-        };
-        let out_ip = matches.opt_strs("o");
-        let out_ip = match out_ips_parse(&out_ip) {
-            Ok(out_ip) => out_ip,
-            Err(e) => {
-                print_usage(&program, opts);
-                println!();
-                println!("-o: {:?} {}", out_ip, e);
-                println!("example: -o 0.0.0.0/0");
-                return;
-            }
+            target
         };
-        let password: Option<String> = matches.opt_get("w").unwrap();
-        let server_encrypt = matches.opt_present("W");
-        #[cfg(not(feature = "server_encrypt"))]
-        {
-            if server_encrypt {
-                println!("Server encryption not supported");
-                return;
-            }
-        }
-        let mtu: Option<String> = matches.opt_get("u").unwrap();
-        let mtu = if let Some(mtu) = mtu {
-            match u32::from_str(&mtu) {
-                Ok(mtu) => Some(mtu),
-                Err(e) => {
-                    print_usage(&program, opts);
-                    println!();
-                    println!("'-u {}' {}", mtu, e);
-                    return;
-                }
-            }
+        command::command(command::CommandEnum::Bandwidth(args));
+        return;
+    } else if let Some(args) = matches.opt_str("ping") {
+        command::command(command::CommandEnum::Ping(args));
+        return;
+    } else if let Some(args) = matches.opt_str("feature") {
+        command::command(command::CommandEnum::Feature(args.replace(',', " ")));
+        return;
+    } else if let Some(args) = matches.opt_str("prefer") {
+        command::command(command::CommandEnum::Prefer(args));
+        return;
+    } else if let Some(args) = matches.opt_str("subsystem") {
+        command::command(command::CommandEnum::Subsystem(args));
+        return;
+    } else if let Some(args) = matches.opt_str("telemetry") {
+        command::command(command::CommandEnum::Telemetry(args));
+        return;
+    } else if let Some(args) = matches.opt_str("policy-ctl") {
+        let args = if args.trim() == "show" {
+            String::new()
         } else {
-            None
+            args
         };
-        let virtual_ip: Option<String> = matches.opt_get("ip").unwrap();
-        let virtual_ip =
-            virtual_ip.map(|v| Ipv4Addr::from_str(&v).expect(&format!("'--ip {}' error", v)));
-        if let Some(virtual_ip) = virtual_ip {
-            if virtual_ip.is_unspecified() || virtual_ip.is_broadcast() || virtual_ip.is_multicast()
-            {
-                println!("'--ip {}' invalid", virtual_ip);
-                return;
-            }
-        }
-        let tcp_channel = matches.opt_present("tcp");
-        let relay = matches.opt_present("relay");
-
-        let parallel = matches.opt_get::<usize>("par").unwrap().unwrap_or(1);
-        if parallel == 0 {
-            println!("'--par {}' invalid", parallel);
-            return;
+        command::command(command::CommandEnum::Policy(args));
+        return;
+    } else if matches.opt_present("selftest") {
+        let target = matches.opt_str("selftest").unwrap_or_default();
+        if !command::selftest(&target) {
+            exit::exit(ExitReason::Runtime, format!("selftest '{}' failed", target));
         }
-
-        let cipher_model = match matches.opt_get::<CipherModel>("model") {
-            Ok(model) => {
-                #[cfg(not(any(
-                    feature = "aes_gcm",
-                    feature = "server_encrypt",
-                    feature = "aes_cbc",
-                    feature = "aes_ecb",
-                    feature = "sm4_cbc"
-                )))]
-                {
-                    if password.is_some() && model.is_none() {
-                        println!("Encryption not supported");
-                        return;
-                    }
-                }
-                #[cfg(not(any(feature = "aes_gcm", feature = "server_encrypt")))]
-                {
-                    if password.is_some() && model.is_none() {
-                        println!("'--model ' undefined");
-                        return;
-                    }
-                    model.unwrap_or(CipherModel::None)
-                }
-                #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
-                model.unwrap_or(CipherModel::AesGcm)
-            }
-            Err(e) => {
-                println!("'--model ' invalid,{}", e);
-                return;
-            }
-        };
-
-        let finger = matches.opt_present("finger");
-        let punch_model = matches
-            .opt_get::<PunchModel>("punch")
-            .unwrap()
-            .unwrap_or(PunchModel::All);
-        let use_channel_type = matches
-            .opt_get::<UseChannelType>("use-channel")
-            .unwrap()
-            .unwrap_or_else(|| {
-                if relay {
-                    UseChannelType::Relay
-                } else {
-                    UseChannelType::All
-                }
-            });
-
-        let ports = matches
-            .opt_get::<String>("ports")
-            .unwrap_or(None)
-            .map(|v| v.split(",").map(|x| x.parse().unwrap_or(0)).collect());
-
-        let cmd = matches.opt_present("cmd");
-        #[cfg(feature = "ip_proxy")]
-        let no_proxy = matches.opt_present("no-proxy");
-        let first_latency = matches.opt_present("first-latency");
-        let packet_loss = matches
-            .opt_get::<f64>("packet-loss")
-            .expect("--packet-loss");
-        let packet_delay = matches
-            .opt_get::<u32>("packet-delay")
-            .expect("--packet-delay")
-            .unwrap_or(0);
-        #[cfg(feature = "port_mapping")]
-        let port_mapping_list = matches.opt_strs("mapping");
-        let config = match Config::new(
-            #[cfg(target_os = "windows")]
-            tap,
-            token,
-            device_id,
-            name,
-            server_address_str,
-            dns,
-            stun_server,
-            in_ip,
-            out_ip,
-            password,
-            mtu,
-            tcp_channel,
-            virtual_ip,
-            #[cfg(feature = "ip_proxy")]
-            no_proxy,
-            server_encrypt,
-            parallel,
-            cipher_model,
-            finger,
-            punch_model,
-            ports,
-            first_latency,
-            device_name,
-            use_channel_type,
-            packet_loss,
-            packet_delay,
-            #[cfg(feature = "port_mapping")]
-            port_mapping_list,
-        ) {
-            Ok(config) => config,
-            Err(e) => {
-                println!("config error: {}", e);
-                return;
+        return;
+    } else if matches.opt_present("debug-bundle") {
+        let path = matches.opt_str("debug-bundle").unwrap_or_default();
+        command::command(command::CommandEnum::DebugBundle(
+            path,
+            !matches.opt_present("no-redact"),
+        ));
+        return;
+    } else if let Some(stats) = matches.opt_str("stats") {
+        match stats.as_str() {
+            "drops" => {
+                command::command(command::CommandEnum::Drops(matches.opt_present("explain")))
             }
-        };
-        (config, cmd)
-    };
+            "metrics" => command::command(command::CommandEnum::Metrics),
+            "peers" => command::command(command::CommandEnum::Peers),
+            "probes" => command::command(command::CommandEnum::Probes),
+            "sockets" => command::command(command::CommandEnum::Sockets),
+            "threads" => command::command(command::CommandEnum::Threads),
+            _ => println!(
+                "'--stats {}' invalid, available: drops,metrics,peers,probes,sockets,threads",
+                stats
+            ),
+        }
+        return;
+    }
     println!("version {}", vnt::VNT_VERSION);
     println!("Serial:{}", generated_serial_number::SERIAL_NUMBER);
     log::info!(
@@ -340,24 +654,101 @@ fn main() {
         vnt::VNT_VERSION,
         generated_serial_number::SERIAL_NUMBER
     );
-    main0(config, cmd);
-    std::process::exit(0);
+    let config = config::Config::resolve(&program, &opts, &matches, settings);
+    #[cfg(feature = "log")]
+    println!("{}", log_summary);
+    main0(config);
+    exit::stopped();
+}
+
+/// 延迟历史的子命令，其他按虚拟ip或指纹查询分配历史
+fn history(args: &str) -> String {
+    match rtt_history::command(args) {
+        Some(out) => out,
+        None => seen_devices::history(args),
+    }
 }
 
 mod callback;
 
-fn main0(config: Config, _show_cmd: bool) {
+fn main0(config: config::Config) {
+    state::start_flush();
     #[cfg(feature = "port_mapping")]
-    for (is_tcp, addr, dest) in config.port_mapping_list.iter() {
+    for (is_tcp, addr, dest) in config.vnt.port_mapping_list.iter() {
         if *is_tcp {
             println!("TCP port mapping {}->{}", addr, dest)
         } else {
             println!("UDP port mapping {}->{}", addr, dest)
         }
     }
-    let vnt_util = Vnt::new(config, callback::VntHandler {}).unwrap();
+    let rate_limit = config.vnt.rate_limit;
+    let vnt_util = match Vnt::new(
+        config.vnt,
+        callback::VntHandler::new(config.drop_user, config.retention.seen_history),
+    ) {
+        Ok(vnt) => vnt,
+        Err(e) => {
+            // 退出时记录日志
+            println!("{}", ui::red(format!("start failed: {:?}", e)));
+            exit::exit(ExitReason::from_start_error(&e), format!("{:?}", e));
+        }
+    };
+    if let Some(addr) = config.health_listen {
+        let switch = Switch::new();
+        let running = match health_http::start(addr, vnt_util.health(), &switch) {
+            Ok(local_addr) => {
+                ui::info(format!(
+                    "Health check listening on http://{}/livez and /readyz",
+                    local_addr
+                ));
+                true
+            }
+            Err(e) => {
+                ui::error(format!("'--health-listen {}' failed: {:?}", addr, e));
+                false
+            }
+        };
+        let (health, switch_) = (vnt_util.health(), switch.clone());
+        let subsystem = Subsystem::new(
+            "health-http",
+            move || health_http::start(addr, health.clone(), &switch_).map(|_| ()),
+            move || health_http::stop(&switch, addr),
+            move || Probe::new(true, addr.to_string()),
+        );
+        vnt_util.subsystems().register(if running {
+            subsystem
+        } else {
+            subsystem.stopped()
+        });
+    }
+    register_wait::watch(vnt_util.clone(), config.register_timeout);
+    if config.rtt_history {
+        rtt_history::start(vnt_util.clone());
+    }
+    retention::start(vnt_util.clone(), config.retention);
+    policy::start(vnt_util.clone());
+    if let Some(url) = config.telemetry {
+        telemetry::start(vnt_util.clone(), url);
+    }
+    #[cfg(target_os = "linux")]
+    if vnt_util.config().takeover {
+        takeover::start(&vnt_util);
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    {
+        let vnt = vnt_util.clone();
+        if let Err(e) = exit::handle_signals(move || vnt.stop()) {
+            log::warn!("signal {:?}", e);
+        }
+    }
     #[cfg(feature = "command")]
     {
+        command::load_block_list(&vnt_util);
+        command::load_path_preferences(&vnt_util);
+        command::load_subsystem_overrides(&vnt_util);
+        if rate_limit > 0 {
+            shared_rate::start(vnt_util.clone(), rate_limit);
+        }
         let vnt_c = vnt_util.clone();
         std::thread::Builder::new()
             .name("CommandServer".into())
@@ -367,28 +758,67 @@ fn main0(config: Config, _show_cmd: bool) {
                 }
             })
             .expect("CommandServer");
-        if _show_cmd {
-            let mut cmd = String::new();
-            loop {
-                cmd.clear();
-                println!("======== input:list,info,route,all,stop ========");
-                match io::stdin().read_line(&mut cmd) {
-                    Ok(len) => {
-                        if !command(&cmd[..len], &vnt_util) {
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        println!("input err:{}", e);
-                        break;
-                    }
+        let vnt_c = vnt_util.clone();
+        std::thread::Builder::new()
+            .name("ControlServer".into())
+            .spawn(move || {
+                if let Err(e) = command::control::start(vnt_c) {
+                    log::warn!("控制通道:{:?}", e);
                 }
-            }
+            })
+            .expect("ControlServer");
+        if config.cmd {
+            console_loop(&vnt_util);
         }
     }
-
+    if !config.cmd {
+        daemon::start(vnt_util.clone());
+    }
     vnt_util.wait()
 }
+/// 读取控制台输入直到exit或者输入结束
+#[cfg(feature = "command")]
+fn console_loop(vnt: &Vnt) {
+    let mut cmd = String::new();
+    loop {
+        cmd.clear();
+        println!(
+            "======== input:list,list json,status json,info,route,all,connections,health,dns,history,block,unblock,forget,punch,invite,knock,requests,accept,diary,events [--since 10m] [--peer <ip|name>] [--type <type>] [--json],estimate,bandwidth,ping,feature,limit,subsystem,loglevel,stats,stats drops,stats metrics,stats peers,stats probes,stats sockets,stats threads,debug-bundle,telemetry [show|off],policy [explain <ip|name>|reload],quiet on|off,restart,stop,exit; '> file' or '>> file' saves the output ========"
+        );
+        match io::stdin().read_line(&mut cmd) {
+            Ok(len) => {
+                if !command_line(&cmd[..len], vnt) {
+                    break;
+                }
+            }
+            Err(e) => {
+                println!("input err:{}", e);
+                break;
+            }
+        }
+    }
+}
+/// 执行一行控制台输入，'> file'覆盖写入文件，'>> file'追加
+#[cfg(feature = "command")]
+fn command_line(line: &str, vnt: &Vnt) -> bool {
+    let (cmd, redirect) = match console_out::redirect::parse(line) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}\n", e);
+            return true;
+        }
+    };
+    let redirect = match redirect {
+        Some(redirect) => redirect,
+        None => return command(cmd, vnt),
+    };
+    let mut running = true;
+    let out = console_out::redirect::capture(|| running = command(cmd, vnt));
+    if let Err(e) = console_out::redirect::save(&redirect, &out) {
+        println!("cannot write '{}': {}\n", redirect.target, e);
+    }
+    running
+}
 #[cfg(feature = "command")]
 fn command(cmd: &str, vnt: &Vnt) -> bool {
     if cmd.is_empty() {
@@ -399,10 +829,18 @@ fn command(cmd: &str, vnt: &Vnt) -> bool {
             let list = command::command_list(&vnt);
             console_out::console_device_list(list);
         }
+        cmd @ ("list json" | "status json") => {
+            if let Some(text) = command::control::json_text(cmd, &vnt) {
+                outln!("{}", text);
+            }
+        }
         "info" => {
             let info = command::command_info(&vnt);
             console_out::console_info(info);
         }
+        "info --config" => {
+            console_out::console_config(config::profile::effective());
+        }
         "route" => {
             let route = command::command_route(&vnt);
             console_out::console_route_table(route);
@@ -411,17 +849,139 @@ fn command(cmd: &str, vnt: &Vnt) -> bool {
             let list = command::command_list(&vnt);
             console_out::console_device_list_all(list);
         }
-        "stop" => {
+        "connections" => {
+            let list = command::command_connections(&vnt);
+            console_out::console_connections(list);
+        }
+        "loglevel" => {
+            out!("{}", loglevel_command(""));
+        }
+        "health" => {
+            let status = command::command_health(&vnt);
+            console_out::console_health(status);
+        }
+        "requests" => {
+            out!("{}", command::command_requests(&vnt));
+        }
+        "dns" => {
+            let status = command::command_dns(&vnt);
+            console_out::console_dns(status);
+        }
+        "stats drops" => {
+            let list = command::command_drops(&vnt);
+            console_out::console_drops(list, false);
+        }
+        "stats drops --explain" => {
+            let list = command::command_drops(&vnt);
+            console_out::console_drops(list, true);
+        }
+        "stats metrics" => {
+            let list = command::command_metrics(&vnt);
+            console_out::console_metrics(list);
+        }
+        "stats" | "stats peers" => {
+            let list = command::command_peer_stats(&vnt);
+            console_out::console_peer_stats(list);
+        }
+        "stats probes" => {
+            let list = command::command_probes(&vnt);
+            console_out::console_probes(list);
+        }
+        "stats sockets" => {
+            let list = command::command_sockets(&vnt);
+            console_out::console_sockets(list);
+        }
+        "stats threads" => {
+            let list = command::command_threads(&vnt);
+            console_out::console_threads(list);
+        }
+        "quiet" => {
+            let state = if ui::quiet() { "on" } else { "off" };
+            outln!("quiet {}", state);
+        }
+        mode @ ("quiet on" | "quiet off") => {
+            ui::set_quiet(mode == "quiet on");
+            outln!("{}", mode);
+        }
+        "stop" | "exit" => {
+            exit::set_reason(ExitReason::Clean, "stop command");
             let _ = vnt.stop();
             return false;
         }
-        _ => {}
+        "restart" => match warm_restart::prepare(vnt) {
+            Ok(_) => {
+                let e = warm_restart::exec();
+                outln!("restart failed: {:?}", e);
+            }
+            Err(e) => outln!("restart failed: {}", e),
+        },
+        _ => {
+            // 设备名称区分大小写，这里使用原始输入
+            let cmd = cmd.trim();
+            if let Some(target) = cmd.strip_prefix("block ") {
+                outln!("{}", command::command_block(&vnt, target, true));
+            } else if let Some(target) = cmd.strip_prefix("unblock ") {
+                outln!("{}", command::command_block(&vnt, target, false));
+            } else if let Some(target) = cmd.strip_prefix("forget ") {
+                outln!("{}", command::command_forget(&vnt, target));
+            } else if let Some(target) = cmd.strip_prefix("punch ") {
+                outln!("{}", command::command_punch(&vnt, target));
+            } else if let Some(args) = cmd.strip_prefix("invite ") {
+                outln!("{}", command::command_invite(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("knock ") {
+                outln!("{}", command::command_knock(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("accept ") {
+                outln!("{}", command::command_accept(&vnt, args));
+            } else if let Some(target) = cmd.strip_prefix("diary ") {
+                out!("{}", command::command_diary(&vnt, target));
+            } else if cmd == "events" || cmd.starts_with("events ") {
+                out!(
+                    "{}",
+                    command::events::command_events(&vnt, &cmd["events".len()..])
+                );
+            } else if let Some(target) = cmd.strip_prefix("estimate ") {
+                out!("{}", command::command_estimate(&vnt, target));
+            } else if let Some(args) = cmd.strip_prefix("loglevel ") {
+                out!("{}", loglevel_command(args));
+            } else if let Some(args) = cmd.strip_prefix("bandwidth ") {
+                outln!("{}", command::command_bandwidth(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("ping ") {
+                outln!("{}", command::command_ping(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("feature ") {
+                outln!("{}", command::command_feature(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("limit ") {
+                outln!("{}", command::command_limit(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("prefer") {
+                outln!("{}", command::command_prefer(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("subsystem") {
+                outln!("{}", command::command_subsystem(&vnt, args));
+            } else if let Some(key) = cmd.strip_prefix("history ") {
+                outln!("{}", history(key));
+            } else if let Some(args) = cmd.strip_prefix("telemetry") {
+                out!("{}", telemetry::command(&vnt, args));
+            } else if let Some(args) = cmd.strip_prefix("policy") {
+                outln!("{}", policy::command(&vnt, args));
+            } else if let Some(target) = cmd.strip_prefix("selftest") {
+                let result = command::command_selftest(&vnt, target);
+                console_out::console_selftest(&result);
+            } else if let Some(args) = cmd.strip_prefix("debug-bundle") {
+                let (redact, path) = command::debug_bundle::parse_args(args);
+                match command::debug_bundle::absolute_path(path) {
+                    Ok(path) => {
+                        if command::debug_bundle::confirm(&path, redact) {
+                            outln!("{}", command::command_debug_bundle(&vnt, &path, redact));
+                        }
+                    }
+                    Err(e) => outln!("error {:?}", e),
+                }
+            }
+        }
     }
-    println!();
+    outln!();
     return true;
 }
 
-fn print_usage(program: &str, _opts: Options) {
+fn print_usage(program: &str, _opts: &Options) {
     println!("Usage: {} [options]", program);
     println!("version:{}", vnt::VNT_VERSION);
     println!("Serial:{}", generated_serial_number::SERIAL_NUMBER);
@@ -430,8 +990,8 @@ fn print_usage(program: &str, _opts: Options) {
         "  -k <token>          {}",
         green("使用相同的token,就能组建一个局域网络".to_string())
     );
-    println!("  -n <name>           给设备一个名字,便于区分不同设备,默认使用系统版本");
-    println!("  -d <id>             设备唯一标识符,不使用--ip参数时,服务端凭此参数分配虚拟ip,注意不能重复");
+    println!("  -n, --name <name>   给设备一个名字,便于区分不同设备,默认使用主机名;'--list'中最多显示64字节,重名的设备带'*'标记");
+    println!("  -d, --device-id <id> 设备唯一标识符,不使用--ip参数时,服务端凭此参数分配虚拟ip,注意不能重复;默认使用机器标识,取不到时生成一个保存在数据目录的device-id文件中,之后重复使用;克隆的虚拟机机器标识相同,需要分别指定;'--info'中显示实际使用的标识和来源");
     println!("  -s <server>         注册和中继服务器地址,以'TXT:'开头表示解析TXT记录");
     println!("  -e <stun-server>    stun服务器,用于探测NAT类型,可使用多个地址,如-e stun1.l.google.com -e stun2.l.google.com");
     #[cfg(target_os = "windows")]
@@ -467,16 +1027,25 @@ fn print_usage(program: &str, _opts: Options) {
     enums.push_str("/sm4_cbc");
     if !enums.is_empty() {
         println!("  -w <password>       使用该密码生成的密钥对客户端数据进行加密,并且服务端无法解密,使用相同密码的客户端才能通信");
+        println!("  --key <password>    同'-w';密钥由密码和token派生,数据包带加密标志,和未加密或密码不同的对端之间的包会被丢弃并计入'--stats drops'的decrypt_failed");
     }
     #[cfg(feature = "server_encrypt")]
     println!("  -W                  加密当前客户端和服务端通信的数据,请留意服务端指纹是否正确");
-    println!("  -u <mtu>            自定义mtu(不加密默认为1450，加密默认为1410)");
+    println!("  -u, --mtu <mtu>     自定义mtu(不加密默认为1450，加密默认为1410),取值576~9000;超过mtu的网卡数据包会被丢弃并计入'--stats drops'的oversize");
     #[cfg(feature = "file_config")]
     println!("  -f <conf_file>      读取配置文件中的配置");
+    println!("  --config <path>     读取toml配置文件中的token、服务器地址、设备名称、端口、日志级别和可由预设调整的值,命令行参数优先;避免token出现在进程列表和shell历史中");
+    println!("  --gen-config        输出带注释的toml配置文件模板,如 vnt-cli --gen-config > /etc/vnt.toml");
+    println!("  --profile <profile> 预设 mobile/server/gateway,mobile:单线程单端口,server:多线程多端口优先低延迟,gateway:在server的基础上开启内置代理;mobile另外限制后台探测(--probe-budget metered)、不做端口预测打洞(--punch-window 0)、少占socket和内存(--socket-limit 16,--event-history 1000),server和gateway另外缩短路径切换的时间(--route-timeout 6,--tcp-fallback 3)并记录延迟历史(--rtt-history);按 默认值→预设→配置文件→命令行 的顺序覆盖,'--show-config'查看每个值的来源");
+    println!("  --data-dir <path>   保存设备标识等状态的目录,也可使用环境变量VNT_HOME,默认依次尝试程序目录下已有的env、系统状态目录、~/.vnt");
 
     println!("  --tcp               和服务端使用tcp通信,默认使用udp,遇到udp qos时可指定使用tcp");
-    println!("  --ip <ip>           指定虚拟ip,指定的ip不能和其他设备重复,必须有效并且在服务端所属网段下,默认情况由服务端分配");
-    println!("  --par <parallel>    任务并行度(必须为正整数),默认值为1");
+    println!("  --tcp-fallback <N>  udp连续N次连接服务器失败后自动改用tcp,tcp也连续失败时换回udp,默认6(约1分钟),0表示不切换;切换后注册、心跳和中继数据都走tcp,打洞仍然只用udp,list中经服务器中继的对端显示为relay(tcp)");
+    println!("  --ip <ip>           指定虚拟ip,指定的ip不能和其他设备重复,必须有效并且在服务端所属网段下,默认情况由服务端分配;被占用(ip already taken)或不在网段内(ip out of range)时停止运行");
+    println!("  --ip-fallback       配合'--ip'使用,指定的ip被占用或不在网段内时改为由服务端分配,不停止运行");
+    println!("  --par <parallel>    任务并行度,取值1~64,默认值为1");
+    println!("  --pin-workers <spec> 工作线程绑定的核心,如udp=2,tun=3,crypto=4-5;udp:接收udp,tcp:接收tcp,tun:读取网卡,crypto:'--par'大于1时的加解密线程,同种线程有多个时轮流分配列出的核心;核心必须允许本进程使用,不支持绑定的平台上忽略并提示,实际位置见'--stats threads'");
+    println!("  --worker-priority <p> 工作线程的优先级,normal/high,默认normal;high需要权限(linux上为CAP_SYS_NICE),没有权限时保持默认并提示");
     if !enums.is_empty() {
         println!(
             "  --model <model>     加密模式(默认aes_gcm),可选值{}",
@@ -488,18 +1057,96 @@ fn print_usage(program: &str, _opts: Options) {
     }
     println!("  --punch <punch>     取值ipv4/ipv6/all,ipv4表示仅使用ipv4打洞");
     println!("  --ports <port,port> 取值0~65535,指定本地监听的一组端口,默认监听两个随机端口,使用过多端口会增加网络负担");
+    println!(
+        "  --port <port>       只监听这一个udp端口,端口被占用时启动失败,不能和'--ports'同时使用"
+    );
+    println!("  --bind <ip>         监听的本地ip,多网卡时选择使用的网卡,默认监听所有地址");
     #[cfg(feature = "command")]
     println!("  --cmd               开启交互式命令,使用此参数开启控制台输入");
+    println!("  --daemon            非交互模式运行,不读取控制台输入,在日志中记录连接状态的变化,适合systemd或者后台服务");
+    println!("                      标准输入不是终端时自动使用此模式,'--nic-only'作用相同");
     #[cfg(feature = "ip_proxy")]
-    println!("  --no-proxy          关闭内置代理,如需点对网则需要配置网卡NAT转发");
-    println!("  --first-latency     优先低延迟的通道,默认情况优先使用p2p通道");
+    println!("  --no-proxy[=false]  关闭内置代理,如需点对网则需要配置网卡NAT转发");
+    println!("  --first-latency[=false] 优先低延迟的通道,默认情况优先使用p2p通道;'=false'关闭预设或配置文件打开的值");
     println!("  --use-channel <p2p> 使用通道 relay/p2p/all,默认两者都使用");
     println!("  --nic <tun0>        指定虚拟网卡名称");
-    println!("  --packet-loss <0>   模拟丢包,取值0~1之间的小数,程序会按设定的概率主动丢包,可用于模拟弱网");
+    #[cfg(target_os = "linux")]
+    {
+        println!("  --existing-tun <name> 使用管理员预先创建的tun网卡(ip tuntap add mode tun user <user> name <name>),不需要root权限,不会安装路由,需要的配置命令会打印出来");
+        println!("  --tun-fd <fd>       使用父进程传入的tun描述符,其他同'--existing-tun'");
+    }
+    println!("  --observer          观察者模式,不创建网卡,不打洞,不转发数据,只同步设备列表、延迟和事件,用于监控,不需要root权限");
+    println!("  --packet-loss <0>   模拟丢包,取值0~1之间的小数或百分比,程序会按设定的概率主动丢包,可用于模拟弱网");
     println!(
-        "  --packet-delay <0>  模拟延迟,整数,单位毫秒(ms),程序会按设定的值延迟发包,可用于模拟弱网"
+        "  --packet-delay <0>  模拟延迟,不带单位时为毫秒,最大10s,程序会按设定的值延迟发包,可用于模拟弱网"
     );
     println!("  --dns <host:port>   DNS服务器地址,可使用多个dns,不指定时使用系统解析");
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        println!("  --user <user>       以root运行时,完成虚拟网卡和路由配置后切换到该用户运行,默认为nobody,用户不存在时启动失败,之后虚拟ip变化需要重启程序");
+        println!("  --group <group>     指定切换的用户组,默认使用用户的主组");
+        println!("  --no-drop-privileges 不切换用户,始终以root运行");
+    }
+    println!("  --require-encryption 配合'-w'使用,拒绝任何对单个设备关闭加密的请求");
+    println!("  --allow-peer-plaintext 同意设备发起的关闭加密请求,默认拒绝,防止数据被降级成明文");
+    println!("  --no-flow-tracking  不统计对端发起的连接,'--connections'将没有数据");
+    println!(
+        "  --notify-flows      对端发起新连接时输出提示,如 new inbound flow from 10.26.0.9 tcp/22"
+    );
+    println!("  --no-fingerprint    不公开设备指纹,指纹由设备id和token计算,不同组网之间无法关联");
+    println!("  --no-bandwidth-probe 拒绝对端发起的带宽测量,对端的'bandwidth'命令会提示被拒绝");
+    println!("  --history <ip|fingerprint> 查看虚拟ip或设备指纹的分配历史,记录在数据目录的seen_devices.log");
+    println!("  --history \"rtt <ip> [30m|24h|7d]\" 查看对端的延迟统计、趋势图和按小时(UTC)的平均延迟,默认24h");
+    println!("  --history \"export <ip> --csv <file>\" 把对端保存的延迟历史导出为csv");
+    println!("  --safe-mode         安全模式,关闭mDNS、本地dns服务、健康检查接口、流统计、重排、带宽测量、延迟历史和匿名统计,并行度设为1,不绑定核心;关闭和调整的项会输出并写入日志");
+    println!("  --no-safe-mode      启动后10秒内连续崩溃3次时不自动进入安全模式;自动进入的安全模式稳定运行60秒后清零计数,下次按正常模式启动");
+    println!("  --rtt-history[=false] 每分钟记录一次对端当前路由的延迟和路径类型,保存在数据目录的rtt_history下,每个对端最多7天,不额外发送探测包");
+    println!("  --share-anonymous-stats <url> 每天向url(必须是https)上报一次匿名的打洞统计:各nat组合的成功率(加噪声后取整到10%)、打洞次数的数量级、成功用时的分布、系统、架构和版本,不含ip、名称、token和精确的设备数;需要编译时开启telemetry特性(默认不开启);第一次开启时显示上报内容,'telemetry show'查看,'telemetry show > file'导出后可手动提交,'telemetry off'关闭;设置环境变量VNT_NO_TELEMETRY时始终不上报");
+    println!("  --no-tun-backpressure 出口已满时继续读取网卡并丢包,默认暂停读取1~5ms,让系统tcp拥塞控制降速");
+    println!("  --path-weights <weights> 路径评分的权重,如rtt=1.0,loss=50,jitter=2,bandwidth=0.1,分数=rtt*延迟(ms)+loss*丢包率(%)+jitter*抖动(ms)-bandwidth*带宽(Mbps),越低越好;没写的项rtt为1其余为0,每项0~10000;不设置时按延迟选路,'route'中显示各路径的分数");
+    println!("  --bring-up-relay <N> 和新对端建立直连期间最多经服务器中继N个数据包,多余的丢弃,让出链路给打洞,默认0不限制");
+    println!(
+        "  --bring-up-timeout <ms> 和新对端建立直连的超时时间,超时后当作中继对端不再限制,默认10000,取值500ms~10m"
+    );
+    println!("  --route-timeout <s> 路由超过这么久没有收到数据就删除,直连删除后改走服务器中继,默认10,取值6s~10m");
+    println!("  --register-timeout <s> 启动时解析服务器地址失败按1s、2s、4s...最多60s的间隔重试,超过这么久仍没有注册成功就以退出码7退出,默认0一直重试,取值5s~7d;token错误立即以退出码3退出");
+    println!("  --punch-window <N>  对端是对称nat且本机是锥形nat时,在服务端观察到的对端端口上下各N个端口内分批发送打洞包,收到回应后停止,默认64,0表示只打观察到的端口");
+    println!("  --cookie-threshold <N> 每秒从未知直连路径收到的ping超过N个时,只回复由来源地址计算的cookie,对端带回cookie后才建立路由,防止伪造来源占满路由表;不支持的旧版本对端在此期间改走中继,默认64,0表示不检查");
+    #[cfg(feature = "command")]
+    println!("  --shared-rate-limit <mbps> 本机多个vnt进程共享的总带宽,不带单位时为mbps,最小64kbps,启动最早的进程负责分配,分配者退出时其他进程继续使用已分到的带宽");
+    println!("  --snat-local        本机服务绑定在物理网卡地址上时,把发往虚拟网络的包的源地址改成虚拟ip,默认丢弃这类包并提示");
+    println!("  --tun-checksum <mode> 地址转换、mss钳制、ip代理改写过的入站包写入网卡前如何处理tcp/udp校验和:compute重新计算,zero置0(用于网卡开启了校验和卸载的平台),auto(默认)在网卡就绪后注入测试包探测,'--info'中显示实际使用的方式");
+    println!("  --mdns              通过mDNS在局域网内公告虚拟ip,发现同一组网的设备后直接打洞,不依赖服务器交换地址,公告中不包含token");
+    println!("  --reorder           路径切换后的短时间内给发出的包带上序号,收到乱序的包时最多暂存8个/10ms按序写入网卡,两端都开启才生效");
+    println!("  --inbound-limit <pps>,<mbps> 每个对端发来的数据包的速率上限,超出的丢弃并提示,默认20000,200,0表示不限制,不影响心跳和打洞");
+    println!(
+        "  --auto-block-floods 对端连续10秒超过接收限速时自动屏蔽5分钟,期间丢弃它发来的数据包"
+    );
+    println!("  --no-broadcast      不转发广播和组播,发往255.255.255.255、网段广播地址和网络地址的包直接丢弃并计入丢包统计");
+    println!("  --compact-encoding  向服务端和对端声明支持紧凑编码,设备列表和打洞回复改用差值和varint压缩的格式,设备多或链路带宽小时减少控制流量,对方不支持时仍使用protobuf");
+    println!("  --upnp              启动时通过UPnP(失败时用NAT-PMP)在路由器上映射主udp端口,映射的外部地址发给对端用于直连,心跳中续期,退出时删除;映射失败只记录日志,'--info'中显示映射状态");
+    println!("  --strict-inbound    严格入站,不响应对端发起的打洞和未知路径上的ping,对端可用'knock <ip>'经服务器请求直连,本地用'requests'查看、'accept <id> [时长]'批准,或用'invite <ip> [时长]'主动允许,之后由本机发起打洞;许可默认1小时、最长24小时,到期后断开直连回到中继");
+    println!("  --policy <file>     按分组设置对端策略的toml文件(扩展名为.yaml/.yml时按yaml解析):[default]、[[group]](name、members为ip、网段、name:<通配符>、fp:<指纹前缀>)和[peer.\"<ip>\"]三级,可设置relay_only、rate_mbps、require_e2e、stealth_exception(严格入站时保持直连许可)和inbound规则如\"allow tcp/22 on servers\"(on表示本机属于该分组时生效);单项按 对端>分组(按文件顺序)>default 取值,入站规则按同样的顺序第一条匹配的生效,都不匹配时允许;设备列表变化时重新分组,'policy explain <ip|name>'查看对端的每项设置来自哪一级,'policy reload'重新加载");
+    println!("  --dns-route <domain>=<ip> 该域名及子域名的查询通过虚拟网络转发给对端的dns服务,可多次指定,最长后缀优先,对端不可达时返回SERVFAIL,如 corp.example=10.26.0.2");
+    println!("  --dns-listen <addr> 本地dns服务的监听地址,配置'--dns-route'时默认127.0.0.1:53,<设备名>.vnt解析成设备的虚拟ip,其他域名转发给'--dns'或公共dns,应答按ttl缓存");
+    println!("  --health-listen <addr> 在该地址提供http接口/livez和/readyz,通过返回200,否则返回503及失败的检查项,用于容器编排的存活和就绪探测");
+    println!("  --probe-budget <kbps|metered|off> 心跳、打洞、地址探测等后台流量的总上限,默认64kbps,metered为8kbps,off不限制;超出时先跳过多余路径的延迟探测和中继探测,保持在线的心跳总是发送");
+    #[cfg(target_os = "linux")]
+    println!("  --takeover          允许升级时由新进程接管,新旧进程都要指定;旧进程的网卡和路由交给新进程,主端口通过SO_REUSEPORT共享,之后3秒内旧进程收到的包转发给新进程再退出");
+    #[cfg(feature = "log")]
+    println!("  --log-level <spec>  日志级别,如'info,punch=trace,udp_channel=warn',不带'='的是全局级别,其他是按模块覆盖,模块可以写完整路径如vnt::channel::punch或路径中的一段;运行中可以用'loglevel'命令查看和修改");
+    #[cfg(feature = "log")]
+    println!("  --log-path <file|-> 日志写入指定文件,达到10MB时滚动,保留5个历史文件(file.1最新);'-'表示输出到stderr,适合systemd/journald;优先于log4rs.yaml,目录无法创建时改为stderr。启动时会打印一次实际使用的日志级别和输出位置");
+    println!("  --diary-log         把每个对端的连接日记(打洞、路径切换、路由淘汰等)同时以debug级别写入日志,默认只保存在内存中,可以通过'--diary'查看");
+    println!("  --socket-limit <N>  打洞、nat探测等辅助socket的数量上限,默认64,0表示不限制;对称网络打洞会少开一些端口,而不是失败");
+    println!("  --peer-retention <duration> 超过这么久没有出现在设备列表中的对端,清除数据目录下保存的延迟历史、分配历史和屏蔽,默认30d,不带单位时按小时;'clean --purge-peers'在不运行时清除");
+    println!("  --ephemeral-network 临时网络(CI、教室等频繁创建设备的场景),对端状态默认只保留6h,不记录设备分配历史");
+    println!("  --event-history <N> 内存中按时间顺序保留的所有设备的事件数,默认5k,0表示不保留,可以通过'--events'查询");
+    println!("  --max-devices <N>   接受服务端下发的设备数上限,默认10k,超过的部分丢弃并告警一次;防止异常的服务端让客户端耗尽内存");
+    println!("  --standby-server <server> 备用服务器,最多指定2个,以相同的虚拟ip注册后只用于打洞协商,对端最近的服务器离自己也近时通过它协商,不转发数据;服务端加密时不使用");
+    #[cfg(feature = "server_encrypt")]
+    println!("  --report-usage      配合'-W'使用,在心跳中向服务端上报经过服务器中继的累计流量,只有总量,不包含对端信息");
+    println!("  --allow-server-actions <list> 配合'-W'使用,允许服务端请求执行的操作,逗号分隔,默认全部拒绝:resync(重新拉取设备列表) renat(重新探测nat) rekey(重新握手更换密钥) collect-bundle(在本地生成脱敏的诊断包,不上传) set-log-level(参数同'loglevel'命令);同一操作60秒内最多执行一次,执行和拒绝都会记录日志并回应服务端");
     #[cfg(feature = "port_mapping")]
     println!("  --mapping <mapping> 端口映射,例如 --mapping udp:0.0.0.0:80->10.26.0.10:80 --mapping tcp:0.0.0.0:80->10.26.0.10:80");
 
@@ -510,31 +1157,206 @@ fn print_usage(program: &str, _opts: Options) {
             "  --list              {}",
             yellow("后台运行时,查看其他设备列表".to_string())
         );
+        println!(
+            "  --json              {}",
+            yellow("和'--list'或'--info'一起使用,输出一行json,不带颜色".to_string())
+        );
+        println!(
+            "  --control <cmd>     {}",
+            yellow("后台运行时,通过本地控制通道查询list或status,输出json".to_string())
+        );
+        println!(
+            "                      {}",
+            yellow("控制通道在linux和macos上是数据目录下的control.sock,windows上是127.0.0.1:39272,每行一个请求如{\"cmd\":\"list\"},返回一行json".to_string())
+        );
         println!(
             "  --all               {}",
-            yellow("后台运行时,查看其他设备完整信息".to_string())
+            yellow("后台运行时,查看其他设备完整信息,包括设备指纹".to_string())
         );
         println!(
             "  --info              {}",
             yellow("后台运行时,查看当前设备信息".to_string())
         );
+        println!(
+            "  --show-config       {}",
+            yellow("后台运行时,查看生效的配置及来源".to_string())
+        );
         println!(
             "  --route             {}",
             yellow("后台运行时,查看数据转发路径".to_string())
         );
+        println!(
+            "  --connections       {}",
+            yellow("后台运行时,查看对端发起的连接,按近期流量排序,只记录地址和端口".to_string())
+        );
+        println!(
+            "  --health            {}",
+            yellow(
+                "后台运行时,查看存活(处理线程是否卡住)和就绪(注册、网卡、数据通道)状态".to_string()
+            )
+        );
+        println!(
+            "  --dns-info          {}",
+            yellow("后台运行时,查看dns转发规则、缓存数量和命中率".to_string())
+        );
         println!(
             "  --stop              {}",
             yellow("停止后台运行".to_string())
         );
+        println!(
+            "  --restart           {}",
+            yellow("后台运行时,热重启(仅linux),保留网卡、直连路由和服务端会话,可用于原地升级;需要和后台进程相同的用户执行".to_string())
+        );
+        println!(
+            "  --block <ip|name>   {}",
+            yellow("后台运行时,屏蔽设备,屏蔽列表会保存,重启后依然生效".to_string())
+        );
+        println!(
+            "  --unblock <ip|name> {}",
+            yellow("后台运行时,解除屏蔽设备".to_string())
+        );
+        println!(
+            "  --forget <ip|name>  {}",
+            yellow("后台运行时,立即清除设备的延迟历史、分配历史、屏蔽和连接日记".to_string())
+        );
+        println!(
+            "  --punch-peer <ip>   {}",
+            yellow("后台运行时,手动对设备打洞,无视无法直连的判定".to_string())
+        );
+        println!(
+            "  --invite \"<ip|name> [duration]\" {}",
+            yellow("后台运行时,配合'--strict-inbound'使用,允许和设备直连并由本机发起打洞,时长不带单位时是分钟".to_string())
+        );
+        println!(
+            "  --knock \"<ip|name> [duration]\" {}",
+            yellow("后台运行时,经服务器请求严格入站的设备发起直连,对方批准后由它打洞".to_string())
+        );
+        println!(
+            "  --requests          {}",
+            yellow(
+                "后台运行时,查看等待批准的连接请求和生效中的直连许可,请求5分钟内没有批准则丢弃"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --accept \"<id> [duration]\" {}",
+            yellow("后台运行时,批准连接请求,不指定时长时使用对方请求的时长".to_string())
+        );
+        println!(
+            "  --diary <ip|name>   {}",
+            yellow(
+                "后台运行时,查看和设备之间最近200条连接事件(握手、打洞、路径切换、路由淘汰),诊断包中也包含".to_string()
+            )
+        );
+        println!(
+            "  --events \"[--since <10m>] [--peer <ip|name>] [--type <type>] [--json]\" {}",
+            yellow("后台运行时,查询所有设备的事件历史,条件可以组合,--type如PathSwitch、PunchFail,--json输出一行json".to_string())
+        );
+        println!(
+            "  --estimate <ip|name> {}",
+            yellow(
+                "后台运行时,根据双方的nat类型、ipv6、仅中继等策略和同类nat组合的打洞历史,估计能否直连并列出依据;'--route'中的中继路由也会给出这个结论".to_string()
+            )
+        );
+        println!(
+            "  --bandwidth <ip|name> {}",
+            yellow(
+                "后台运行时,分几轮发送连续的探测包测量到设备的带宽,总流量不超过2MB,需要对端同意;结果记录在'--route'的路由上,延迟相同时优先使用带宽大的路径.没有直连时需要加'--include-relay',会占用服务器带宽"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --ping \"<ip|name> [count]\" {}",
+            yellow(
+                "后台运行时,用vnt协议的心跳包ping设备,默认4次,最多100次,每个包等待2秒;有直连时走直连,否则经过服务器中继,每个回复标明p2p或relayed,不依赖对端的icmp"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --feature <ip|name>,encrypt,<on|off> {}",
+            yellow(
+                "后台运行时,修改和设备之间的加密,需要对端同意(对端需要使用'--allow-peer-plaintext'),对端拒绝时保持原来的设置"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --limit inbound,<ip|name>,<pps>,<mbps> {}",
+            yellow(
+                "后台运行时,单独设置设备的接收限速,'default'恢复默认并解除自动屏蔽,只写'inbound'查看当前设置"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --prefer \"<ip|name> <p2p|relay|auto> [weights]\" {}",
+            yellow(
+                "后台运行时,设置设备的路径偏好:p2p只走直连,relay总是中继,auto按分数选择,可带单独的权重;'auto'不带权重时取消设置,保存在数据目录,重启后保留"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --subsystem \"<list|start <name>|stop <name> [--cascade]>\" {}",
+            yellow(
+                "后台运行时,查看子系统的状态、运行时间和资源占用,或者单独启停dns、mdns、flows、flow-notify、bandwidth-probe、health-http;被其他子系统依赖时需要--cascade一起停止,核心组件不能停止;设置保存在数据目录,重启后保留"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --telemetry <show|off> {}",
+            yellow("后台运行时,查看将要上报的匿名统计或者关闭上报".to_string())
+        );
+        println!(
+            "  --policy-ctl <show|explain <ip|name>|reload> {}",
+            yellow("后台运行时,查看各分组的成员、说明对端的每项设置来自哪一级,或者重新加载'--policy'文件,文件有错误时保留原来的策略".to_string())
+        );
+        println!(
+            "  --selftest [peer]   {}",
+            yellow("后台运行时,检查本机回环、服务器回显、加解密和路由表,指定对端时再检查到对端的回显和路径,有失败项时退出码为1".to_string())
+        );
+        println!(
+            "  --debug-bundle [path] {}",
+            yellow("后台运行时,导出诊断包,默认脱敏,'--no-redact'保留原始内容(只能在交互控制台中使用),不覆盖已有的文件".to_string())
+        );
+        println!(
+            "  --stats drops       {}",
+            yellow("后台运行时,按原因查看丢包统计,加上'--explain'显示可能的原因".to_string())
+        );
+        println!(
+            "  --stats metrics     {}",
+            yellow("后台运行时,查看所有统计指标(流量、丢包、中继、服务器延迟)".to_string())
+        );
+        println!(
+            "  --stats peers       {}",
+            yellow("后台运行时,按对端查看收发的流量、包数、中继占比和当前速率".to_string())
+        );
+        println!(
+            "  --stats probes      {}",
+            yellow("后台运行时,按种类查看后台探测的发送量和因超出预算跳过的次数".to_string())
+        );
+        println!(
+            "  --stats sockets     {}",
+            yellow(
+                "后台运行时,查看打洞和nat探测打开的辅助socket的用途、端口、存活时间和流量"
+                    .to_string()
+            )
+        );
+        println!(
+            "  --stats threads     {}",
+            yellow("后台运行时,查看工作线程的名称、线程id、绑定的核心和优先级".to_string())
+        );
     }
+    println!("  数值参数可以带单位,如时长500ms/30s/5m,大小1410/10KB/1MiB,速率64kbps/20mbps/10MB/s,数量20k,不带单位时按各参数说明中的单位;超出范围时取边界值并提示,所有错误一起列出");
+    #[cfg(feature = "loadtest")]
+    println!(
+        "  loadtest ...        模拟大量客户端压测自己的服务器,'{} loadtest -h'查看参数",
+        program
+    );
+    println!(
+        "  clean ...           列出或撤销异常退出后残留的路由修改,'{} clean -h'查看参数",
+        program
+    );
+    println!(
+        "  setup ...           首次使用的配置向导,生成配置文件并可安装系统服务,'{} setup -h'查看参数",
+        program
+    );
     println!("  -h, --help          帮助");
 }
-
-fn green(str: String) -> impl std::fmt::Display {
-    style(str).green()
-}
-
-#[cfg(feature = "command")]
-fn yellow(str: String) -> impl std::fmt::Display {
-    style(str).yellow()
-}
@@ -0,0 +1,1167 @@
+//! 策略文件的解析、按对端解析生效值和编译，只依赖策略和设备列表，不接触运行时
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const RULE_USAGE: &str = "<allow|deny> <all|tcp|udp|icmp>[/<port>[-<port>]] [on <group>]";
+
+/// 设备列表中的一项，本机也用这个结构匹配分组
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Member {
+    pub ip: Ipv4Addr,
+    pub name: String,
+    /// 对端没有公开指纹时为空
+    pub fingerprint: String,
+}
+
+impl Member {
+    pub fn new(ip: Ipv4Addr, name: &str, fingerprint: &str) -> Self {
+        Self {
+            ip,
+            name: name.to_string(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+}
+
+/// 分组成员的匹配方式
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Matcher {
+    Ip(Ipv4Addr),
+    Cidr(Ipv4Addr, u8),
+    /// 设备名称，支持'*'和'?'
+    Name(String),
+    /// 指纹前缀，不区分大小写
+    Fingerprint(String),
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "member '{}' invalid, expected ip, cidr, name:<glob> or fp:<prefix>",
+                s
+            )
+        };
+        let s = s.trim();
+        if let Some(name) = s.strip_prefix("name:") {
+            if name.is_empty() {
+                return Err(err());
+            }
+            return Ok(Matcher::Name(name.to_string()));
+        }
+        if let Some(fp) = s.strip_prefix("fp:") {
+            if fp.is_empty() || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(err());
+            }
+            return Ok(Matcher::Fingerprint(fp.to_ascii_lowercase()));
+        }
+        match s.split_once('/') {
+            Some((ip, prefix)) => {
+                let ip = Ipv4Addr::from_str(ip).map_err(|_| err())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| err())?;
+                if prefix > 32 {
+                    return Err(err());
+                }
+                Ok(Matcher::Cidr(
+                    Ipv4Addr::from(u32::from(ip) & mask(prefix)),
+                    prefix,
+                ))
+            }
+            None => Ipv4Addr::from_str(s).map(Matcher::Ip).map_err(|_| err()),
+        }
+    }
+}
+
+impl Display for Matcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Matcher::Ip(ip) => write!(f, "{}", ip),
+            Matcher::Cidr(ip, prefix) => write!(f, "{}/{}", ip, prefix),
+            Matcher::Name(name) => write!(f, "name:{}", name),
+            Matcher::Fingerprint(fp) => write!(f, "fp:{}", fp),
+        }
+    }
+}
+
+impl Matcher {
+    pub fn matches(&self, member: &Member) -> bool {
+        match self {
+            Matcher::Ip(ip) => member.ip == *ip,
+            Matcher::Cidr(ip, prefix) => u32::from(member.ip) & mask(*prefix) == u32::from(*ip),
+            Matcher::Name(pattern) => glob(pattern.as_bytes(), member.name.as_bytes()),
+            Matcher::Fingerprint(fp) => {
+                !member.fingerprint.is_empty()
+                    && member.fingerprint.to_ascii_lowercase().starts_with(fp)
+            }
+        }
+    }
+}
+
+fn mask(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix)
+    }
+}
+
+/// '*'匹配任意个字符，'?'匹配一个字符
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // 最近一个'*'的位置和它当时对应的文本位置
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Proto {
+    All,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Proto {
+    fn name(&self) -> &'static str {
+        match self {
+            Proto::All => "all",
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+            Proto::Icmp => "icmp",
+        }
+    }
+    fn matches(&self, protocol: u8) -> bool {
+        match self {
+            Proto::All => true,
+            Proto::Tcp => protocol == TCP,
+            Proto::Udp => protocol == UDP,
+            Proto::Icmp => protocol == ICMP,
+        }
+    }
+}
+
+/// 入站规则，如"allow tcp/22 on servers"，on表示本机属于这个分组时才生效
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AclRule {
+    pub action: Action,
+    pub proto: Proto,
+    pub ports: Option<(u16, u16)>,
+    pub on: Option<String>,
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("rule '{}' invalid, expected '{}'", s, RULE_USAGE);
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        let (action, target, on) = match tokens[..] {
+            [action, target] => (action, target, None),
+            [action, target, "on", group] => (action, target, Some(group.to_string())),
+            _ => return Err(err()),
+        };
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(err()),
+        };
+        let (proto, ports) = match target.split_once('/') {
+            Some((proto, ports)) => (proto, Some(ports)),
+            None => (target, None),
+        };
+        let proto = match proto {
+            "all" => Proto::All,
+            "tcp" => Proto::Tcp,
+            "udp" => Proto::Udp,
+            "icmp" => Proto::Icmp,
+            _ => return Err(err()),
+        };
+        let ports = match ports {
+            None => None,
+            Some(_) if !matches!(proto, Proto::Tcp | Proto::Udp) => return Err(err()),
+            Some(ports) => {
+                let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+                match (start.parse::<u16>(), end.parse::<u16>()) {
+                    (Ok(start), Ok(end)) if start <= end => Some((start, end)),
+                    _ => return Err(err()),
+                }
+            }
+        };
+        Ok(AclRule {
+            action,
+            proto,
+            ports,
+            on,
+        })
+    }
+}
+
+impl Display for AclRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        write!(f, "{} {}", action, self.proto.name())?;
+        match self.ports {
+            Some((start, end)) if start == end => write!(f, "/{}", start)?,
+            Some((start, end)) => write!(f, "/{}-{}", start, end)?,
+            None => {}
+        }
+        if let Some(on) = &self.on {
+            write!(f, " on {}", on)?;
+        }
+        Ok(())
+    }
+}
+
+impl AclRule {
+    /// 规则限定了端口时，没有端口的包(分片的后续部分)不匹配
+    pub fn matches(&self, protocol: u8, port: Option<u16>) -> bool {
+        if !self.proto.matches(protocol) {
+            return false;
+        }
+        match (self.ports, port) {
+            (None, _) => true,
+            (Some((start, end)), Some(port)) => start <= port && port <= end,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// 一级设置，没有写的项由下一级决定
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    pub relay_only: Option<bool>,
+    /// 接收限速，0表示不限速
+    pub rate_mbps: Option<f64>,
+    pub require_e2e: Option<bool>,
+    /// 严格入站时仍然允许直连
+    pub stealth_exception: Option<bool>,
+    pub inbound: Vec<AclRule>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<Matcher>,
+    pub rules: Rules,
+}
+
+impl Group {
+    pub fn contains(&self, member: &Member) -> bool {
+        self.members.iter().any(|m| m.matches(member))
+    }
+}
+
+/// 文件中的一级，default和peer不能有name和members
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ScopeFile {
+    name: Option<String>,
+    members: Option<Vec<String>>,
+    relay_only: Option<bool>,
+    rate_mbps: Option<f64>,
+    require_e2e: Option<bool>,
+    stealth_exception: Option<bool>,
+    #[serde(default)]
+    inbound: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    default: ScopeFile,
+    #[serde(default)]
+    group: Vec<ScopeFile>,
+    #[serde(default)]
+    peer: BTreeMap<String, ScopeFile>,
+}
+
+/// 设置来自哪一级
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    Peer,
+    Group(String),
+    Default,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Peer => f.write_str("peer"),
+            Source::Group(name) => write!(f, "group {}", name),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// 加载后的策略，优先级 peer > group(按文件中的顺序) > default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+    pub default: Rules,
+    pub groups: Vec<Group>,
+    pub peers: BTreeMap<Ipv4Addr, Rules>,
+}
+
+impl Policy {
+    /// 按扩展名选择yaml或toml
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{} {}", path.display(), e))?;
+        let yaml = matches!(
+            path.extension().and_then(|v| v.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        Self::parse(&text, yaml).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    pub fn parse(text: &str, yaml: bool) -> Result<Self, String> {
+        let file: PolicyFile = if yaml {
+            serde_yaml::from_str(text).map_err(|e| e.to_string())?
+        } else {
+            toml::from_str(text).map_err(|e: toml::de::Error| e.to_string().trim().to_string())?
+        };
+        let mut policy = Policy {
+            default: scope_rules("default", file.default)?,
+            ..Default::default()
+        };
+        for (index, mut scope) in file.group.into_iter().enumerate() {
+            let name = match scope.name.take() {
+                Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+                _ => return Err(format!("group #{} has no name", index + 1)),
+            };
+            if policy.groups.iter().any(|g| g.name == name) {
+                return Err(format!("group '{}' defined twice", name));
+            }
+            let members = match scope.members.take() {
+                Some(members) if !members.is_empty() => members
+                    .iter()
+                    .map(|v| v.parse())
+                    .collect::<Result<Vec<Matcher>, String>>()
+                    .map_err(|e| format!("group '{}' {}", name, e))?,
+                _ => return Err(format!("group '{}' has no members", name)),
+            };
+            let rules = scope_rules(&format!("group '{}'", name), scope)?;
+            policy.groups.push(Group {
+                name,
+                members,
+                rules,
+            });
+        }
+        for (ip, scope) in file.peer {
+            let parsed = Ipv4Addr::from_str(ip.trim())
+                .map_err(|_| format!("peer '{}' is not an ipv4 address", ip))?;
+            let rules = scope_rules(&format!("peer '{}'", ip), scope)?;
+            policy.peers.insert(parsed, rules);
+        }
+        // on引用的分组必须存在
+        let scopes = std::iter::once(&policy.default)
+            .chain(policy.groups.iter().map(|g| &g.rules))
+            .chain(policy.peers.values());
+        for rules in scopes {
+            for rule in &rules.inbound {
+                if let Some(on) = &rule.on {
+                    if !policy.groups.iter().any(|g| &g.name == on) {
+                        return Err(format!("rule '{}' refers to unknown group '{}'", rule, on));
+                    }
+                }
+            }
+        }
+        Ok(policy)
+    }
+    /// 对端所在的分组，按文件中的顺序
+    pub fn groups_of(&self, member: &Member) -> Vec<&Group> {
+        self.groups.iter().filter(|g| g.contains(member)).collect()
+    }
+    /// 对端适用的各级设置，越具体越靠前
+    fn scopes(&self, member: &Member) -> Vec<(&Rules, Source)> {
+        let mut scopes = Vec::new();
+        if let Some(rules) = self.peers.get(&member.ip) {
+            scopes.push((rules, Source::Peer));
+        }
+        for group in self.groups_of(member) {
+            scopes.push((&group.rules, Source::Group(group.name.clone())));
+        }
+        scopes.push((&self.default, Source::Default));
+        scopes
+    }
+    /// local是本机，决定带on的规则是否生效
+    pub fn resolve(&self, local: &Member, peer: &Member) -> Resolved {
+        let scopes = self.scopes(peer);
+        let local_groups: Vec<&str> = self
+            .groups_of(local)
+            .iter()
+            .map(|g| g.name.as_str())
+            .collect();
+        let mut inbound = Vec::new();
+        let mut skipped = Vec::new();
+        for (rules, source) in &scopes {
+            for rule in &rules.inbound {
+                match &rule.on {
+                    Some(on) if !local_groups.contains(&on.as_str()) => {
+                        skipped.push((rule.clone(), source.clone()))
+                    }
+                    _ => inbound.push((rule.clone(), source.clone())),
+                }
+            }
+        }
+        Resolved {
+            groups: self
+                .groups_of(peer)
+                .iter()
+                .map(|g| g.name.clone())
+                .collect(),
+            relay_only: setting(&scopes, |r| r.relay_only),
+            rate_mbps: setting(&scopes, |r| r.rate_mbps),
+            require_e2e: setting(&scopes, |r| r.require_e2e),
+            stealth_exception: setting(&scopes, |r| r.stealth_exception),
+            inbound,
+            skipped,
+        }
+    }
+    fn rules_for_unknown(&self, local: &Member) -> Vec<AclRule> {
+        let local_groups = self.groups_of(local);
+        self.default
+            .inbound
+            .iter()
+            .filter(|r| match &r.on {
+                Some(on) => local_groups.iter().any(|g| &g.name == on),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+fn scope_rules(scope: &str, file: ScopeFile) -> Result<Rules, String> {
+    if file.name.is_some() || file.members.is_some() {
+        return Err(format!("{} cannot have 'name' or 'members'", scope));
+    }
+    if let Some(rate) = file.rate_mbps {
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("{} 'rate_mbps' {} is invalid", scope, rate));
+        }
+    }
+    let inbound = file
+        .inbound
+        .iter()
+        .map(|v| v.parse())
+        .collect::<Result<Vec<AclRule>, String>>()
+        .map_err(|e| format!("{} {}", scope, e))?;
+    Ok(Rules {
+        relay_only: file.relay_only,
+        rate_mbps: file.rate_mbps,
+        require_e2e: file.require_e2e,
+        stealth_exception: file.stealth_exception,
+        inbound,
+    })
+}
+
+fn setting<T: Copy>(scopes: &[(&Rules, Source)], get: impl Fn(&Rules) -> Option<T>) -> Setting<T> {
+    let mut found = scopes
+        .iter()
+        .filter_map(|(rules, source)| get(rules).map(|v| (v, source.clone())));
+    Setting {
+        value: found.next(),
+        overridden: found.collect(),
+    }
+}
+
+/// 一项设置的生效值和被覆盖的值
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting<T> {
+    pub value: Option<(T, Source)>,
+    pub overridden: Vec<(T, Source)>,
+}
+
+impl<T: Copy> Setting<T> {
+    pub fn get(&self) -> Option<T> {
+        self.value.as_ref().map(|(v, _)| *v)
+    }
+}
+
+/// 一个对端的解析结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolved {
+    pub groups: Vec<String>,
+    pub relay_only: Setting<bool>,
+    pub rate_mbps: Setting<f64>,
+    pub require_e2e: Setting<bool>,
+    pub stealth_exception: Setting<bool>,
+    /// 按优先级排列，第一条匹配的规则生效，都不匹配时允许
+    pub inbound: Vec<(AclRule, Source)>,
+    /// 本机不在on指定的分组中，没有生效的规则
+    pub skipped: Vec<(AclRule, Source)>,
+}
+
+impl Resolved {
+    pub fn actions(&self) -> Actions {
+        Actions {
+            relay_only: self.relay_only.get().unwrap_or(false),
+            rate_mbps: self.rate_mbps.get().filter(|v| *v > 0.0),
+            require_e2e: self.require_e2e.get().unwrap_or(false),
+            stealth_exception: self.stealth_exception.get().unwrap_or(false),
+        }
+    }
+}
+
+/// 需要通过已有功能设置到对端上的值，都为默认值时不需要设置
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Actions {
+    pub relay_only: bool,
+    pub rate_mbps: Option<f64>,
+    pub require_e2e: bool,
+    pub stealth_exception: bool,
+}
+
+/// 编译后的入站规则，按来源ip查找
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    peers: HashMap<Ipv4Addr, Vec<AclRule>>,
+    /// 不在设备列表中的来源(如'-o'后面的网段)只使用default的规则
+    default: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.default.is_empty()
+    }
+    pub fn allows(&self, source: &Ipv4Addr, protocol: u8, port: Option<u16>) -> bool {
+        let rules = self.peers.get(source).unwrap_or(&self.default);
+        !matches!(
+            rules.iter().find(|r| r.matches(protocol, port)),
+            Some(r) if r.action == Action::Deny
+        )
+    }
+}
+
+/// 编译结果，只包含和默认值不同的对端
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Compiled {
+    pub peers: BTreeMap<Ipv4Addr, Actions>,
+    pub acl: Acl,
+}
+
+/// 按设备列表编译策略，文件中单独列出的对端不在列表中时也会编译
+pub fn compile(policy: &Policy, local: &Member, directory: &[Member]) -> Compiled {
+    let mut compiled = Compiled::default();
+    compiled.acl.default = policy.rules_for_unknown(local);
+    let extra = policy
+        .peers
+        .keys()
+        .filter(|ip| !directory.iter().any(|m| m.ip == **ip))
+        .map(|ip| Member::new(*ip, "", ""));
+    let members: Vec<Member> = directory.iter().cloned().chain(extra).collect();
+    for member in &members {
+        if member.ip == local.ip {
+            continue;
+        }
+        let resolved = policy.resolve(local, member);
+        let actions = resolved.actions();
+        if actions != Actions::default() {
+            compiled.peers.insert(member.ip, actions);
+        }
+        let rules: Vec<AclRule> = resolved.inbound.into_iter().map(|(r, _)| r).collect();
+        if rules != compiled.acl.default {
+            compiled.acl.peers.insert(member.ip, rules);
+        }
+    }
+    compiled
+}
+
+fn format_bool(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn format_rate(value: f64) -> String {
+    if value > 0.0 {
+        format!("{} Mbps", value)
+    } else {
+        "unlimited".to_string()
+    }
+}
+
+fn explain_setting<T: Copy>(
+    out: &mut String,
+    name: &str,
+    setting: &Setting<T>,
+    unset: &str,
+    format: impl Fn(T) -> String,
+) {
+    match &setting.value {
+        Some((value, source)) => {
+            out.push_str(&format!("{}: {} ({})", name, format(*value), source))
+        }
+        None => out.push_str(&format!("{}: {} (unset)", name, unset)),
+    }
+    for (value, source) in &setting.overridden {
+        out.push_str(&format!(", overrides {} ({})", format(*value), source));
+    }
+    out.push('\n');
+}
+
+/// 'policy explain'，说明对端的每一项设置来自哪一级
+pub fn explain(policy: &Policy, local: &Member, directory: &[Member], target: Ipv4Addr) -> String {
+    let mut out = String::new();
+    let member = match directory.iter().find(|m| m.ip == target) {
+        Some(member) => {
+            if member.name.is_empty() {
+                out.push_str(&format!("peer: {}\n", target));
+            } else {
+                out.push_str(&format!("peer: {} ({})\n", target, member.name));
+            }
+            member.clone()
+        }
+        None => {
+            out.push_str(&format!("peer: {} (not in directory)\n", target));
+            Member::new(target, "", "")
+        }
+    };
+    if target == local.ip {
+        out.push_str("this device, policy does not apply\n");
+        return out;
+    }
+    let resolved = policy.resolve(local, &member);
+    if resolved.groups.is_empty() {
+        out.push_str("groups: none\n");
+    } else {
+        out.push_str(&format!("groups: {}\n", resolved.groups.join(", ")));
+    }
+    let bool_text = |v: bool| format_bool(v).to_string();
+    explain_setting(
+        &mut out,
+        "relay_only",
+        &resolved.relay_only,
+        "off",
+        bool_text,
+    );
+    explain_setting(
+        &mut out,
+        "rate",
+        &resolved.rate_mbps,
+        "unlimited",
+        format_rate,
+    );
+    explain_setting(
+        &mut out,
+        "require_e2e",
+        &resolved.require_e2e,
+        "off",
+        bool_text,
+    );
+    explain_setting(
+        &mut out,
+        "stealth_exception",
+        &resolved.stealth_exception,
+        "off",
+        bool_text,
+    );
+    out.push_str("inbound (first match wins):\n");
+    for (index, (rule, source)) in resolved.inbound.iter().enumerate() {
+        out.push_str(&format!("  {}. {} ({})\n", index + 1, rule, source));
+    }
+    out.push_str("  *. allow all (implicit)\n");
+    for (rule, source) in &resolved.skipped {
+        let on = rule.on.as_deref().unwrap_or_default();
+        out.push_str(&format!(
+            "not applied: {} ({}), this device is not in group '{}'\n",
+            rule, source, on
+        ));
+    }
+    out
+}
+
+/// 'policy'，列出各分组当前的成员
+pub fn summary(policy: &Policy, local: &Member, directory: &[Member]) -> String {
+    let mut out = String::new();
+    let local_groups: Vec<&str> = policy
+        .groups_of(local)
+        .iter()
+        .map(|g| g.name.as_str())
+        .collect();
+    if local_groups.is_empty() {
+        out.push_str("this device: no group\n");
+    } else {
+        out.push_str(&format!("this device: {}\n", local_groups.join(", ")));
+    }
+    for group in &policy.groups {
+        let members: Vec<String> = directory
+            .iter()
+            .filter(|m| m.ip != local.ip && group.contains(m))
+            .map(|m| m.ip.to_string())
+            .collect();
+        let members = if members.is_empty() {
+            "-".to_string()
+        } else {
+            members.join(", ")
+        };
+        out.push_str(&format!("group {}: {}\n", group.name, members));
+    }
+    for ip in policy.peers.keys() {
+        out.push_str(&format!("peer {}\n", ip));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::policy::compile::{
+        compile, explain, glob, summary, AclRule, Actions, Matcher, Member, Policy, Source,
+    };
+
+    const POLICY: &str = r#"
+[default]
+inbound = ["deny tcp/22 on servers"]
+
+[[group]]
+name = "admins"
+members = ["10.26.0.2", "name:admin-*"]
+inbound = ["allow tcp/22 on servers"]
+
+[[group]]
+name = "servers"
+members = ["10.26.0.16/28", "name:srv-?"]
+require_e2e = true
+
+[[group]]
+name = "guests"
+members = ["name:guest-*", "fp:ABCD"]
+relay_only = true
+rate_mbps = 5
+inbound = ["deny all"]
+
+[peer."10.26.0.9"]
+rate_mbps = 0
+stealth_exception = true
+"#;
+
+    fn ip(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 26, 0, last)
+    }
+
+    fn directory() -> Vec<Member> {
+        vec![
+            Member::new(ip(2), "laptop", ""),
+            Member::new(ip(3), "admin-b", ""),
+            Member::new(ip(5), "srv-a", ""),
+            Member::new(ip(9), "guest-9", ""),
+            Member::new(ip(17), "db", ""),
+            Member::new(ip(50), "phone", "abcdef01"),
+            Member::new(ip(40), "other", ""),
+        ]
+    }
+
+    fn policy() -> Policy {
+        Policy::parse(POLICY, false).unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        let cases = [
+            ("srv-*", "srv-a", true),
+            ("srv-*", "srv-", true),
+            ("srv-*", "web-a", false),
+            ("srv-?", "srv-ab", false),
+            ("*-db-*", "eu-db-1", true),
+            ("*", "", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("exact", "exact", true),
+            ("exact", "exactly", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_matcher() {
+        let member = Member::new(ip(18), "srv-b", "ABCDEF");
+        let cases = [
+            ("10.26.0.18", true),
+            ("10.26.0.19", false),
+            ("10.26.0.16/28", true),
+            ("10.26.0.0/28", false),
+            ("0.0.0.0/0", true),
+            ("name:srv-*", true),
+            ("name:web-*", false),
+            ("fp:abc", true),
+            ("fp:ABCDEF", true),
+            ("fp:abd", false),
+        ];
+        for (text, expected) in cases {
+            let matcher: Matcher = text.parse().unwrap();
+            assert_eq!(matcher.matches(&member), expected, "{}", text);
+        }
+        // cidr取网络地址
+        assert_eq!(
+            "10.26.0.18/28".parse::<Matcher>().unwrap().to_string(),
+            "10.26.0.16/28"
+        );
+        // 对端没有公开指纹时不匹配
+        assert!(!"fp:ab"
+            .parse::<Matcher>()
+            .unwrap()
+            .matches(&Member::new(ip(18), "x", "")));
+        for bad in [
+            "",
+            "name:",
+            "fp:",
+            "fp:xyz",
+            "10.26.0.0/33",
+            "10.26.0",
+            "srv-*",
+        ] {
+            assert!(bad.parse::<Matcher>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_rule() {
+        let cases = [
+            ("allow tcp/22", Some("allow tcp/22")),
+            ("deny  udp/53-54", Some("deny udp/53-54")),
+            ("deny all on servers", Some("deny all on servers")),
+            ("allow icmp", Some("allow icmp")),
+            ("allow tcp/22-22", Some("allow tcp/22")),
+            ("allow icmp/1", None),
+            ("allow all/80", None),
+            ("allow tcp/80-22", None),
+            ("allow tcp/70000", None),
+            ("permit tcp/22", None),
+            ("allow sctp", None),
+            ("allow tcp/22 to servers", None),
+            ("allow", None),
+        ];
+        for (text, expected) in cases {
+            let parsed = text.parse::<AclRule>().map(|r| r.to_string()).ok();
+            assert_eq!(parsed.as_deref(), expected, "{}", text);
+        }
+        let rule: AclRule = "allow tcp/20-22".parse().unwrap();
+        let cases = [
+            (6, Some(21), true),
+            (6, Some(23), false),
+            (17, Some(21), false),
+            // 分片的后续部分没有端口
+            (6, None, false),
+        ];
+        for (protocol, port, expected) in cases {
+            assert_eq!(
+                rule.matches(protocol, port),
+                expected,
+                "{} {:?}",
+                protocol,
+                port
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let cases = [
+            ("[[group]]\nmembers = [\"10.26.0.2\"]", "group #1 has no name"),
+            ("[[group]]\nname = \"a\"", "group 'a' has no members"),
+            (
+                "[[group]]\nname = \"a\"\nmembers = [\"x\"]",
+                "group 'a' member 'x' invalid",
+            ),
+            (
+                "[[group]]\nname = \"a\"\nmembers = [\"10.26.0.2\"]\n[[group]]\nname = \"a\"\nmembers = [\"10.26.0.3\"]",
+                "group 'a' defined twice",
+            ),
+            ("[default]\nname = \"a\"", "default cannot have 'name'"),
+            ("[peer.\"10.26.0.2\"]\nmembers = []", "peer '10.26.0.2' cannot have"),
+            ("[peer.\"srv\"]\nrelay_only = true", "peer 'srv' is not an ipv4 address"),
+            ("[default]\nrate_mbps = -1.0", "default 'rate_mbps' -1 is invalid"),
+            ("[default]\ninbound = [\"allow tcp/x\"]", "default rule 'allow tcp/x' invalid"),
+            (
+                "[default]\ninbound = [\"deny all on nobody\"]",
+                "refers to unknown group 'nobody'",
+            ),
+            ("[default]\nrelay = true", "unknown field `relay`"),
+            ("[defaults]\nrelay_only = true", "unknown field `defaults`"),
+        ];
+        for (text, expected) in cases {
+            let err = Policy::parse(text, false).unwrap_err();
+            assert!(err.contains(expected), "{:?} => {}", text, err);
+        }
+        assert_eq!(Policy::parse("", false), Ok(Policy::default()));
+    }
+
+    #[test]
+    fn test_yaml() {
+        let yaml = r#"
+default:
+  inbound: ["deny tcp/22 on servers"]
+group:
+  - name: admins
+    members: ["10.26.0.2", "name:admin-*"]
+    inbound: ["allow tcp/22 on servers"]
+  - name: servers
+    members: ["10.26.0.16/28", "name:srv-?"]
+    require_e2e: true
+  - name: guests
+    members: ["name:guest-*", "fp:ABCD"]
+    relay_only: true
+    rate_mbps: 5
+    inbound: ["deny all"]
+peer:
+  "10.26.0.9":
+    rate_mbps: 0
+    stealth_exception: true
+"#;
+        assert_eq!(Policy::parse(yaml, true), Ok(policy()));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let policy = policy();
+        let local = Member::new(ip(5), "srv-a", "");
+        let directory = directory();
+        // (对端, 分组, relay_only, rate, require_e2e, stealth, rate的来源)
+        let cases = [
+            (2, vec!["admins"], false, None, false, false, None),
+            (3, vec!["admins"], false, None, false, false, None),
+            (17, vec!["servers"], false, None, true, false, None),
+            (
+                50,
+                vec!["guests"],
+                true,
+                Some(5.0),
+                false,
+                false,
+                Some(Source::Group("guests".into())),
+            ),
+            // 单独设置的0覆盖分组的限速
+            (
+                9,
+                vec!["guests"],
+                true,
+                None,
+                false,
+                true,
+                Some(Source::Peer),
+            ),
+            (40, vec![], false, None, false, false, None),
+        ];
+        for (last, groups, relay_only, rate, e2e, stealth, rate_source) in cases {
+            let member = directory.iter().find(|m| m.ip == ip(last)).unwrap();
+            let resolved = policy.resolve(&local, member);
+            assert_eq!(resolved.groups, groups, "{}", last);
+            assert_eq!(
+                resolved.actions(),
+                Actions {
+                    relay_only,
+                    rate_mbps: rate,
+                    require_e2e: e2e,
+                    stealth_exception: stealth,
+                },
+                "{}",
+                last
+            );
+            assert_eq!(
+                resolved.rate_mbps.value.map(|(_, s)| s),
+                rate_source,
+                "{}",
+                last
+            );
+        }
+        // 属于多个分组时按文件中的顺序
+        let both = Member::new(ip(18), "admin-x", "");
+        let resolved = policy.resolve(&local, &both);
+        assert_eq!(resolved.groups, vec!["admins", "servers"]);
+        assert_eq!(
+            resolved
+                .inbound
+                .iter()
+                .map(|(r, s)| format!("{} ({})", r, s))
+                .collect::<Vec<_>>(),
+            vec![
+                "allow tcp/22 on servers (group admins)",
+                "deny tcp/22 on servers (default)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let policy = policy();
+        let directory = directory();
+        // (本机, 来源, 协议, 端口, 是否允许)
+        let cases = [
+            // 本机是服务器，只有管理员可以访问22端口
+            (5, 2, 6, Some(22), true),
+            (5, 3, 6, Some(22), true),
+            (5, 17, 6, Some(22), false),
+            (5, 40, 6, Some(22), false),
+            (5, 40, 6, Some(80), true),
+            (5, 40, 17, Some(22), true),
+            // 访客拒绝所有入站
+            (5, 50, 1, None, false),
+            (5, 9, 6, Some(80), false),
+            // 不在设备列表中的来源使用default
+            (5, 200, 6, Some(22), false),
+            // 本机不是服务器，带on的规则都不生效
+            (40, 17, 6, Some(22), true),
+            (40, 200, 6, Some(22), true),
+            (40, 50, 17, Some(53), false),
+        ];
+        for (local, source, protocol, port, expected) in cases {
+            let local = directory.iter().find(|m| m.ip == ip(local)).unwrap();
+            let compiled = compile(&policy, local, &directory);
+            assert_eq!(
+                compiled.acl.allows(&ip(source), protocol, port),
+                expected,
+                "{} <- {} {} {:?}",
+                local.ip,
+                source,
+                protocol,
+                port
+            );
+        }
+        let local = Member::new(ip(5), "srv-a", "");
+        let compiled = compile(&policy, &local, &directory);
+        // 只有和默认值不同的对端，不包含本机
+        assert_eq!(
+            compiled.peers.keys().copied().collect::<Vec<_>>(),
+            vec![ip(9), ip(17), ip(50)]
+        );
+        // 文件中单独列出但不在列表中的对端
+        let compiled = compile(&policy, &local, &[]);
+        assert_eq!(
+            compiled.peers.get(&ip(9)),
+            Some(&Actions {
+                stealth_exception: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            compile(&Policy::default(), &local, &directory),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let policy = policy();
+        let local = Member::new(ip(5), "srv-a", "");
+        let directory = directory();
+        let cases = [
+            (
+                9,
+                "peer: 10.26.0.9 (guest-9)\n\
+                 groups: guests\n\
+                 relay_only: on (group guests)\n\
+                 rate: unlimited (peer), overrides 5 Mbps (group guests)\n\
+                 require_e2e: off (unset)\n\
+                 stealth_exception: on (peer)\n\
+                 inbound (first match wins):\n  \
+                 1. deny all (group guests)\n  \
+                 2. deny tcp/22 on servers (default)\n  \
+                 *. allow all (implicit)\n",
+            ),
+            (
+                2,
+                "peer: 10.26.0.2 (laptop)\n\
+                 groups: admins\n\
+                 relay_only: off (unset)\n\
+                 rate: unlimited (unset)\n\
+                 require_e2e: off (unset)\n\
+                 stealth_exception: off (unset)\n\
+                 inbound (first match wins):\n  \
+                 1. allow tcp/22 on servers (group admins)\n  \
+                 2. deny tcp/22 on servers (default)\n  \
+                 *. allow all (implicit)\n",
+            ),
+            (
+                99,
+                "peer: 10.26.0.99 (not in directory)\n\
+                 groups: none\n\
+                 relay_only: off (unset)\n\
+                 rate: unlimited (unset)\n\
+                 require_e2e: off (unset)\n\
+                 stealth_exception: off (unset)\n\
+                 inbound (first match wins):\n  \
+                 1. deny tcp/22 on servers (default)\n  \
+                 *. allow all (implicit)\n",
+            ),
+            (
+                5,
+                "peer: 10.26.0.5 (srv-a)\nthis device, policy does not apply\n",
+            ),
+        ];
+        for (last, expected) in cases {
+            assert_eq!(explain(&policy, &local, &directory, ip(last)), expected);
+        }
+        // 本机不是服务器时说明规则没有生效的原因
+        let local = Member::new(ip(40), "other", "");
+        let text = explain(&policy, &local, &directory, ip(17));
+        assert!(
+            text.contains("require_e2e: on (group servers)\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with(
+            "  *. allow all (implicit)\n\
+             not applied: deny tcp/22 on servers (default), this device is not in group 'servers'\n"
+        ));
+    }
+
+    #[test]
+    fn test_summary() {
+        let local = Member::new(ip(5), "srv-a", "");
+        assert_eq!(
+            summary(&policy(), &local, &directory()),
+            "this device: servers\n\
+             group admins: 10.26.0.2, 10.26.0.3\n\
+             group servers: 10.26.0.17\n\
+             group guests: 10.26.0.9, 10.26.0.50\n\
+             peer 10.26.0.9\n"
+        );
+    }
+}
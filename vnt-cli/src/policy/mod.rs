@@ -0,0 +1,265 @@
+//! '--policy'，按策略文件给对端分组，设置中继、接收限速、加密要求、直连许可和入站规则
+//!
+//! 服务端的设备列表没有分组标签，分组成员由文件中的ip、网段、名称和指纹匹配，
+//! 设备列表变化时重新解析。各项设置通过已有的接口生效，只记录策略设置过的值，
+//! 策略变化时恢复成默认值，手动设置在下一次变化前不会被覆盖
+
+mod compile;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+
+use vnt::channel::inbound_limit::Limit;
+use vnt::channel::path_score::{PeerPreference, Preference};
+use vnt::channel::reverse::MAX_DURATION;
+use vnt::core::{Config, Vnt};
+use vnt::handle::packet_hook::Verdict;
+use vnt::util::fingerprint::device_fingerprint;
+
+use compile::{compile, explain, summary, Acl, Actions, Member, Policy};
+
+const INTERVAL: Duration = Duration::from_secs(10);
+/// 直连许可剩余时间少于这个值时续期
+const RENEW: Duration = Duration::from_secs(3600);
+
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+
+struct Engine {
+    path: PathBuf,
+    policy: RwLock<Policy>,
+    // 和入站钩子共用
+    acl: Arc<RwLock<Acl>>,
+    denied: Arc<AtomicU64>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // 上次解析时的本机和设备列表，变化时重新解析
+    local: Option<Member>,
+    directory: Vec<Member>,
+    // 策略设置过的值
+    applied: BTreeMap<Ipv4Addr, Actions>,
+}
+
+/// 加载策略文件并注册入站钩子，需要在创建Vnt之前调用，返回启动时显示的信息
+pub fn install(config: &mut Config, path: &str) -> Result<String, String> {
+    let path = PathBuf::from(path);
+    let policy = Policy::load(&path)?;
+    let text = format!(
+        "Policy: {}, {} groups, {} peers",
+        path.display(),
+        policy.groups.len(),
+        policy.peers.len()
+    );
+    let acl: Arc<RwLock<Acl>> = Arc::new(RwLock::new(Acl::default()));
+    let denied = Arc::new(AtomicU64::new(0));
+    {
+        let (acl, denied) = (acl.clone(), denied.clone());
+        config.packet_hooks.on_inbound(move |packet| {
+            let acl = acl.read().unwrap();
+            if acl.is_empty()
+                || acl.allows(
+                    &packet.source(),
+                    packet.protocol(),
+                    packet.destination_port(),
+                )
+            {
+                Verdict::Accept
+            } else {
+                denied.fetch_add(1, Ordering::Relaxed);
+                Verdict::Drop
+            }
+        });
+    }
+    let engine = Engine {
+        path,
+        policy: RwLock::new(policy),
+        acl,
+        denied,
+        state: Mutex::new(State::default()),
+    };
+    if ENGINE.set(engine).is_err() {
+        return Err("policy already loaded".to_string());
+    }
+    Ok(text)
+}
+
+/// 按设备列表的变化重新解析，没有'--policy'时不做任何事
+pub fn start(vnt: Vnt) {
+    let engine = match ENGINE.get() {
+        Some(engine) => engine,
+        None => return,
+    };
+    let result = std::thread::Builder::new()
+        .name("Policy".into())
+        .spawn(move || {
+            while !vnt.is_stopped() {
+                refresh(&vnt, engine, false);
+                std::thread::sleep(INTERVAL);
+            }
+        });
+    if let Err(e) = result {
+        log::warn!("启动策略线程失败 {:?}", e);
+    }
+}
+
+/// 'policy'、'policy explain <ip|name>'、'policy reload'
+pub fn command(vnt: &Vnt, args: &str) -> String {
+    let engine = match ENGINE.get() {
+        Some(engine) => engine,
+        None => return "policy is not loaded, start with '--policy <file>'".to_string(),
+    };
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args[..] {
+        [] => {
+            let (local, directory) = snapshot(vnt);
+            format!(
+                "policy: {}\n{}inbound denied: {}",
+                engine.path.display(),
+                summary(&engine.policy.read().unwrap(), &local, &directory),
+                engine.denied.load(Ordering::Relaxed)
+            )
+        }
+        ["explain", target] => {
+            let (local, directory) = snapshot(vnt);
+            let ip = match Ipv4Addr::from_str(target) {
+                Ok(ip) => ip,
+                Err(_) => {
+                    let list: Vec<Ipv4Addr> = directory
+                        .iter()
+                        .filter(|m| m.name == target)
+                        .map(|m| m.ip)
+                        .collect();
+                    match list[..] {
+                        [ip] => ip,
+                        [] => return format!("device '{}' not found", target),
+                        _ => return format!("multiple devices named '{}', use ip instead", target),
+                    }
+                }
+            };
+            explain(&engine.policy.read().unwrap(), &local, &directory, ip)
+        }
+        ["reload"] => match reload(vnt, engine) {
+            Ok(text) => text,
+            Err(e) => format!("reload failed, keeping the previous policy: {}", e),
+        },
+        _ => "usage: policy [explain <ip|name>|reload]".to_string(),
+    }
+}
+
+fn reload(vnt: &Vnt, engine: &Engine) -> Result<String, String> {
+    let policy = Policy::load(&engine.path)?;
+    let text = format!(
+        "reloaded {}, {} groups, {} peers",
+        engine.path.display(),
+        policy.groups.len(),
+        policy.peers.len()
+    );
+    *engine.policy.write().unwrap() = policy;
+    log::info!("重新加载策略 {:?}", engine.path);
+    refresh(vnt, engine, true);
+    Ok(text)
+}
+
+/// 本机和设备列表，按ip排序
+fn snapshot(vnt: &Vnt) -> (Member, Vec<Member>) {
+    let config = vnt.config();
+    let local = Member::new(
+        vnt.current_device().virtual_ip,
+        &config.name,
+        &device_fingerprint(&config.token, &config.device_id),
+    );
+    let mut directory: Vec<Member> = vnt
+        .device_list()
+        .iter()
+        .map(|v| Member::new(v.virtual_ip, &v.name, &v.fingerprint))
+        .collect();
+    directory.sort();
+    (local, directory)
+}
+
+/// 本机或设备列表变化时重新编译，force为true时总是重新编译
+fn refresh(vnt: &Vnt, engine: &Engine, force: bool) {
+    let (local, directory) = snapshot(vnt);
+    // 还没有注册成功
+    if local.ip.is_unspecified() {
+        return;
+    }
+    let mut state = engine.state.lock().unwrap();
+    if force || state.local.as_ref() != Some(&local) || state.directory != directory {
+        let compiled = compile(&engine.policy.read().unwrap(), &local, &directory);
+        *engine.acl.write().unwrap() = compiled.acl;
+        apply(vnt, &state.applied, &compiled.peers);
+        state.applied = compiled.peers;
+        state.local = Some(local);
+        state.directory = directory;
+    }
+    renew(vnt, &state.applied);
+}
+
+/// 只修改和上次不同的项
+fn apply(vnt: &Vnt, old: &BTreeMap<Ipv4Addr, Actions>, new: &BTreeMap<Ipv4Addr, Actions>) {
+    let default = Actions::default();
+    let peers: BTreeSet<&Ipv4Addr> = old.keys().chain(new.keys()).collect();
+    for ip in peers {
+        let ip = *ip;
+        let before = old.get(&ip).unwrap_or(&default);
+        let after = new.get(&ip).unwrap_or(&default);
+        if before == after {
+            continue;
+        }
+        if before.relay_only != after.relay_only {
+            let preference = after.relay_only.then_some(PeerPreference {
+                preference: Preference::Relay,
+                weights: None,
+            });
+            vnt.set_path_preference(ip, preference);
+        }
+        if before.rate_mbps != after.rate_mbps {
+            let limit = after
+                .rate_mbps
+                .map(|mbps| Limit::new(vnt.inbound_limit().pps, (mbps * 1_000_000.0 / 8.0) as u64));
+            vnt.set_inbound_limit(ip, limit);
+        }
+        if before.require_e2e != after.require_e2e {
+            if after.require_e2e && !vnt.client_encrypt() {
+                log::warn!("策略要求和{}之间加密，但没有设置'-w'，不会生效", ip);
+            }
+            vnt.set_peer_encryption_required(ip, after.require_e2e);
+        }
+        if before.stealth_exception != after.stealth_exception {
+            if after.stealth_exception {
+                vnt.allow_peer(ip, MAX_DURATION);
+            } else {
+                vnt.revoke_peer(ip);
+            }
+        }
+        log::info!("策略 {} {:?}", ip, after);
+    }
+}
+
+/// 直连许可最长24小时，快到期时续期
+fn renew(vnt: &Vnt, applied: &BTreeMap<Ipv4Addr, Actions>) {
+    if !vnt.strict_inbound() {
+        return;
+    }
+    let allowances = vnt.allowances();
+    for (ip, actions) in applied {
+        if !actions.stealth_exception {
+            continue;
+        }
+        let remaining = allowances
+            .iter()
+            .find(|v| v.peer == *ip)
+            .map_or(Duration::ZERO, |v| v.remaining);
+        if remaining < RENEW {
+            vnt.allow_peer(*ip, MAX_DURATION);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+
+use console::style;
+use vnt::core::{StartError, Vnt};
+
+use crate::config::numeric::Seconds;
+use crate::exit::{self, ExitReason};
+
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// 第attempt次失败后等待的时间，1s起翻倍，最多60s
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.saturating_sub(1).min(6)).min(MAX_DELAY)
+}
+
+/// 下一次重试前等待的时间，不超过剩余时间，None表示已经超时；timeout为0时不限制
+fn next_delay(attempt: u32, elapsed: Duration, timeout: Duration) -> Option<Duration> {
+    let delay = backoff(attempt);
+    if timeout.is_zero() {
+        return Some(delay);
+    }
+    match timeout.checked_sub(elapsed) {
+        Some(left) if !left.is_zero() => Some(delay.min(left)),
+        _ => None,
+    }
+}
+
+/// 解析服务器地址失败(StartError)时按指数退避重试，开机时网络还没有就绪也能启动；
+/// 其他错误和超过timeout后返回最后一次的错误
+pub fn retry<T>(timeout: Duration, mut f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let e = match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        if !is_unreachable(&e) {
+            return Err(e);
+        }
+        let delay = match next_delay(attempt, start.elapsed(), timeout) {
+            Some(delay) => delay,
+            None => return Err(e),
+        };
+        log::warn!(
+            "第{}次解析服务器地址失败 {:#}，{:?}后重试",
+            attempt,
+            e,
+            delay
+        );
+        println!(
+            "registering... attempt {} failed: {:#}, next retry in {}",
+            attempt,
+            e,
+            Seconds(delay)
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+pub fn is_unreachable(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref::<StartError>().is_some())
+}
+
+/// 重试后仍然连不上服务器
+pub fn unreachable(e: anyhow::Error) -> ! {
+    let message = format!("{:#}", e);
+    println!("{}", style(format!("start failed: {}", message)).red());
+    exit::exit(ExitReason::Unreachable, message)
+}
+
+/// 超过timeout还没有注册成功时停止，退出码区别于token错误，token错误由回调立即退出
+pub fn watch(vnt: Vnt, timeout: Duration) {
+    if timeout.is_zero() {
+        return;
+    }
+    let rs = std::thread::Builder::new()
+        .name("registerWatch".into())
+        .spawn(move || {
+            let start = Instant::now();
+            loop {
+                if vnt.is_stopped() || !vnt.current_device().virtual_ip.is_unspecified() {
+                    return;
+                }
+                let left = timeout.saturating_sub(start.elapsed());
+                if left.is_zero() {
+                    break;
+                }
+                std::thread::sleep(left.min(Duration::from_secs(1)));
+            }
+            let message = format!(
+                "not registered within '--register-timeout {}'",
+                Seconds(timeout)
+            );
+            println!("{}", style(&message).red());
+            exit::set_reason(ExitReason::Unreachable, message);
+            vnt.stop();
+        });
+    if let Err(e) = rs {
+        log::warn!("registerWatch {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use vnt::core::StartError;
+
+    use super::{backoff, is_unreachable, next_delay, retry};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(7), Duration::from_secs(60));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(60));
+
+        let secs = Duration::from_secs;
+        // 不限制时一直重试
+        assert_eq!(next_delay(30, secs(3600), Duration::ZERO), Some(secs(60)));
+        // 最后一次等待不超过剩余时间
+        assert_eq!(next_delay(4, secs(25), secs(30)), Some(secs(5)));
+        assert_eq!(next_delay(1, secs(30), secs(30)), None);
+        assert_eq!(next_delay(1, secs(31), secs(30)), None);
+    }
+
+    #[test]
+    fn test_retry() {
+        let resolve = || StartError::Resolve {
+            server: "vnt.example:29872".to_string(),
+            cause: "DNS query failed".to_string(),
+        };
+        // 解析失败后恢复
+        let mut count = 0;
+        let rs = retry(Duration::from_secs(60), || {
+            count += 1;
+            if count < 2 {
+                Err(resolve().into())
+            } else {
+                Ok(count)
+            }
+        });
+        assert_eq!(rs.unwrap(), 2);
+
+        // 其他错误不重试
+        let mut count = 0;
+        let rs: anyhow::Result<()> = retry(Duration::from_secs(60), || {
+            count += 1;
+            Err(anyhow::anyhow!("token too long"))
+        });
+        assert!(!is_unreachable(&rs.unwrap_err()));
+        assert_eq!(count, 1);
+
+        // 超时后返回最后一次的错误，加了context也能识别
+        let e = retry::<()>(Duration::from_millis(1), || {
+            std::thread::sleep(Duration::from_millis(2));
+            Err(anyhow::Error::from(resolve()).context("conf err"))
+        })
+        .unwrap_err();
+        assert!(is_unreachable(&e));
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use vnt::channel::block_list::BlockList;
+use vnt::core::Vnt;
+use vnt::util::state_store::{self, StateFile};
+
+use crate::state::{BLOCK_LIST_FILE, STORE};
+use crate::{rtt_history, seen_devices};
+
+/// 对端最后一次出现在设备列表中的时间
+pub const FILE_NAME: &str = "last_seen";
+/// 超过这么久没有出现在设备列表中的对端，清除保存的所有状态
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 86400);
+/// '--ephemeral-network'的保留时间
+pub const EPHEMERAL_RETENTION: Duration = Duration::from_secs(6 * 3600);
+const TICK: Duration = Duration::from_secs(10 * 60);
+
+// 清除过的对端，只追加。持有对端缓存的模块记录读到的位置，据此丢弃缓存，避免把清除的状态写回
+static PURGED: Mutex<Vec<Ipv4Addr>> = Mutex::new(Vec::new());
+
+/// cursor之后清除的对端，并把cursor移到最后
+pub fn purged_since(cursor: &mut usize) -> Vec<Ipv4Addr> {
+    let purged = PURGED.lock().unwrap_or_else(|e| e.into_inner());
+    let list = purged
+        .get(*cursor..)
+        .map(|v| v.to_vec())
+        .unwrap_or_default();
+    *cursor = purged.len();
+    list
+}
+
+/// 对端状态的保留策略
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Retention {
+    pub period: Duration,
+    /// 记录虚拟ip对应的设备历史
+    pub seen_history: bool,
+}
+
+impl Retention {
+    /// 临时网络中设备频繁创建和销毁，缩短保留时间并且不记录设备历史
+    pub fn new(period: Option<Duration>, ephemeral: bool) -> Self {
+        let default = if ephemeral {
+            EPHEMERAL_RETENTION
+        } else {
+            DEFAULT_RETENTION
+        };
+        Self {
+            period: period.unwrap_or(default),
+            seen_history: !ephemeral,
+        }
+    }
+}
+
+/// 每个对端最后出现的时间(unix秒)，每行: ip 时间
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LastSeen(pub BTreeMap<Ipv4Addr, u64>);
+
+impl StateFile for LastSeen {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for (ip, time) in &self.0 {
+            text.push_str(&format!("{} {}\n", ip, time));
+        }
+        text.into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(body).ok()?;
+        let mut map = BTreeMap::new();
+        for line in text.lines().filter(|v| !v.trim().is_empty()) {
+            let (ip, time) = line.trim().split_once(' ')?;
+            map.insert(Ipv4Addr::from_str(ip).ok()?, time.parse().ok()?);
+        }
+        Some(LastSeen(map))
+    }
+}
+
+/// 保存了状态的对端，新增按对端保存的文件时要同时加在这里和purge中
+fn stored_peers(dir: &Path) -> BTreeSet<Ipv4Addr> {
+    let mut peers = BTreeSet::new();
+    peers.extend(rtt_history::stored_peers(&dir.join(rtt_history::DIR_NAME)));
+    if let Ok(list) = seen_devices::read(&dir.join(seen_devices::FILE_NAME)) {
+        peers.extend(list.into_iter().map(|v| v.virtual_ip));
+    }
+    if let Ok(list) = BlockList::load(dir.join(BLOCK_LIST_FILE)) {
+        peers.extend(list.into_iter().map(|(ip, _)| ip));
+    }
+    peers
+}
+
+/// 超过保留时间没有出现的对端和最后出现的时间
+pub fn expired(last_seen: &LastSeen, now: u64, period: Duration) -> Vec<(Ipv4Addr, u64)> {
+    last_seen
+        .0
+        .iter()
+        .filter(|(_, time)| now.saturating_sub(**time) > period.as_secs())
+        .map(|(ip, time)| (*ip, *time))
+        .collect()
+}
+
+/// 记录当前设备列表中的对端，返回超过保留时间的对端，由调用方清除
+pub fn tick(
+    dir: &Path,
+    seen: &[Ipv4Addr],
+    now: u64,
+    period: Duration,
+) -> io::Result<Vec<Ipv4Addr>> {
+    let path = dir.join(FILE_NAME);
+    let mut last_seen = state_store::load_or_default::<LastSeen>(&path);
+    for ip in seen {
+        last_seen.0.insert(*ip, now);
+    }
+    // 没有记录时间的状态(例如加入保留策略之前保存的)从现在开始计算
+    for ip in stored_peers(dir) {
+        last_seen.0.entry(ip).or_insert(now);
+    }
+    state_store::save(&path, &last_seen)?;
+    Ok(expired(&last_seen, now, period)
+        .into_iter()
+        .map(|(ip, _)| ip)
+        .collect())
+}
+
+/// 清除对端保存在数据目录下的所有状态，返回清除了哪些
+pub fn purge(dir: &Path, ips: &[Ipv4Addr]) -> io::Result<Vec<&'static str>> {
+    let mut removed = Vec::new();
+    if ips.is_empty() {
+        return Ok(removed);
+    }
+    // 先通知持有缓存的模块，之后不会再写回
+    PURGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend_from_slice(ips);
+    let rtt_dir = dir.join(rtt_history::DIR_NAME);
+    let mut rtt = false;
+    for ip in ips {
+        rtt |= STORE.discard(&rtt_history::ring_path(&rtt_dir, *ip))?;
+    }
+    if rtt {
+        removed.push("rtt history");
+    }
+    if seen_devices::remove(&dir.join(seen_devices::FILE_NAME), ips)? > 0 {
+        removed.push("device history");
+    }
+    let block_path = dir.join(BLOCK_LIST_FILE);
+    let block_list = state_store::load_or_default::<BlockList>(&block_path);
+    if ips.iter().fold(false, |v, ip| block_list.unblock(ip) | v) {
+        // 暂存的写入可能还包含这些对端
+        STORE.discard(&block_path)?;
+        block_list.save(&block_path)?;
+        removed.push("block list");
+    }
+    let path = dir.join(FILE_NAME);
+    let mut last_seen = state_store::load_or_default::<LastSeen>(&path);
+    if ips
+        .iter()
+        .fold(false, |v, ip| last_seen.0.remove(ip).is_some() | v)
+    {
+        state_store::save(&path, &last_seen)?;
+    }
+    Ok(removed)
+}
+
+/// forget命令，立即清除一个对端的所有状态
+pub fn forget(vnt: &Vnt, ip: Ipv4Addr) -> String {
+    let dir = match crate::app_home() {
+        Ok(dir) => dir,
+        Err(e) => return format!("data directory unavailable: {}", e),
+    };
+    vnt.forget_peer(&ip);
+    match purge(&dir, &[ip]) {
+        Ok(removed) if removed.is_empty() => format!("forgot {}, nothing was saved", ip),
+        Ok(removed) => format!("forgot {}: {}", ip, removed.join(", ")),
+        Err(e) => format!("forget {} failed: {}", ip, e),
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+/// 定时记录设备列表中的对端，清除超过保留时间的对端
+pub fn start(vnt: Vnt, retention: Retention) {
+    let dir = match crate::app_home() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::warn!("数据目录不可写，不清理过期对端 {:?}", e);
+            return;
+        }
+    };
+    let result = std::thread::Builder::new()
+        .name("Retention".into())
+        .spawn(move || {
+            // 刚启动时还没有设备列表，等一个周期再记录
+            std::thread::sleep(TICK);
+            while !vnt.is_stopped() {
+                let seen: Vec<Ipv4Addr> = vnt.device_list().iter().map(|v| v.virtual_ip).collect();
+                match tick(&dir, &seen, now_secs(), retention.period) {
+                    Ok(expired) => {
+                        for ip in &expired {
+                            vnt.forget_peer(ip);
+                        }
+                        match purge(&dir, &expired) {
+                            Ok(_) => {
+                                for ip in &expired {
+                                    log::info!(
+                                        "对端{}超过{:?}未出现，已清除保存的状态",
+                                        ip,
+                                        retention.period
+                                    );
+                                }
+                            }
+                            Err(e) => log::warn!("清除过期对端失败 {:?}", e),
+                        }
+                    }
+                    Err(e) => log::warn!("记录对端出现时间失败 {:?}", e),
+                }
+                std::thread::sleep(TICK);
+            }
+        });
+    if let Err(e) = result {
+        log::warn!("过期对端清理启动失败 {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::net::Ipv4Addr;
+
+    use vnt::channel::block_list::BlockList;
+    use vnt::util::state_store;
+
+    use super::{
+        purge, purged_since, stored_peers, tick, LastSeen, DEFAULT_RETENTION, EPHEMERAL_RETENTION,
+        FILE_NAME,
+    };
+    use crate::rtt_history::{self, Ring};
+    use crate::seen_devices::{self, SeenDevices};
+    use crate::state::BLOCK_LIST_FILE;
+
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn test_purge_aged_peers() {
+        let dir = std::env::temp_dir().join(format!("vnt-retention-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rtt_dir = dir.join(rtt_history::DIR_NAME);
+        std::fs::create_dir_all(&rtt_dir).unwrap();
+        let old = Ipv4Addr::new(10, 99, 0, 2);
+        let recent = Ipv4Addr::new(10, 99, 0, 3);
+        // 没有记录出现时间的旧状态
+        let unknown = Ipv4Addr::new(10, 99, 0, 4);
+        let now = 100 * DAY;
+        for ip in [old, recent, unknown] {
+            state_store::save(&rtt_history::ring_path(&rtt_dir, ip), &Ring::new(4)).unwrap();
+        }
+        let seen = SeenDevices::open(Some(dir.join(seen_devices::FILE_NAME)));
+        let list: Vec<_> = [old, recent, unknown]
+            .iter()
+            .map(|ip| (*ip, String::new(), ip.to_string()))
+            .collect();
+        assert_eq!(seen.update(now, &list).len(), 3);
+        let block_list = BlockList::new();
+        block_list.block(old);
+        block_list.block(recent);
+        block_list.save(dir.join(BLOCK_LIST_FILE)).unwrap();
+        let mut last_seen = LastSeen::default();
+        last_seen.0.insert(old, now - 31 * DAY);
+        last_seen.0.insert(recent, now - DAY);
+        state_store::save(&dir.join(FILE_NAME), &last_seen).unwrap();
+
+        let mut cursor = 0;
+        purged_since(&mut cursor);
+        let expired = tick(&dir, &[], now, DEFAULT_RETENTION).unwrap();
+        assert_eq!(expired, vec![old]);
+        assert_eq!(
+            purge(&dir, &expired).unwrap(),
+            vec!["rtt history", "device history", "block list"]
+        );
+        assert!(purged_since(&mut cursor).contains(&old));
+        // 只清除过期的对端，其他对端的状态都保留
+        assert_eq!(stored_peers(&dir), BTreeSet::from([recent, unknown]));
+        assert!(!rtt_history::ring_path(&rtt_dir, old).exists());
+        assert!(rtt_history::ring_path(&rtt_dir, recent).exists());
+        assert_eq!(
+            BlockList::load(dir.join(BLOCK_LIST_FILE)).unwrap(),
+            vec![(recent, String::new())]
+        );
+        let last_seen: LastSeen = state_store::load(&dir.join(FILE_NAME)).unwrap();
+        assert_eq!(
+            last_seen.0.keys().copied().collect::<Vec<_>>(),
+            [recent, unknown]
+        );
+        assert_eq!(last_seen.0[&unknown], now);
+        // 已经清除的不重复清除
+        assert!(tick(&dir, &[], now, DEFAULT_RETENTION).unwrap().is_empty());
+
+        // 临时网络保留时间更短，仍在设备列表中的对端不清除
+        let later = now + DAY;
+        let expired = tick(&dir, &[unknown], later, EPHEMERAL_RETENTION).unwrap();
+        assert_eq!(expired, vec![recent]);
+        purge(&dir, &expired).unwrap();
+        assert_eq!(stored_peers(&dir), BTreeSet::from([unknown]));
+        assert!(BlockList::load(dir.join(BLOCK_LIST_FILE))
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
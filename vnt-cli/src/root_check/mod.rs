@@ -8,4 +8,4 @@ pub use windows::is_app_elevated;
 mod unix;
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
-pub use unix::is_app_elevated;
+pub use unix::{drop_privileges, effective_uid, is_app_elevated, lookup_user, DEFAULT_USER};
@@ -1,3 +1,228 @@
+use std::ffi::CString;
+use std::io;
+
 pub fn is_app_elevated() -> bool {
     sudo::RunningAs::Root == sudo::check()
 }
+
+pub fn effective_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+/// 以root运行且没有指定'--user'时切换到的用户
+pub const DEFAULT_USER: &str = "nobody";
+
+/// 查找用户和组，返回uid和gid，不允许切换到root
+pub fn lookup_user(user: &str, group: Option<&str>) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_user = CString::new(user)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+    let (uid, mut gid) = unsafe {
+        let passwd = libc::getpwnam(c_user.as_ptr());
+        if passwd.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("user '{}' not found", user),
+            ));
+        }
+        ((*passwd).pw_uid, (*passwd).pw_gid)
+    };
+    if let Some(group) = group {
+        let c_group = CString::new(group)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        gid = unsafe {
+            let g = libc::getgrnam(c_group.as_ptr());
+            if g.is_null() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("group '{}' not found", group),
+                ));
+            }
+            (*g).gr_gid
+        };
+    }
+    if uid == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("user '{}' is root", user),
+        ));
+    }
+    Ok((uid, gid))
+}
+
+/// 切换到非特权用户，已打开的文件描述符(虚拟网卡、socket)不受影响
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<()> {
+    let (uid, gid) = lookup_user(user, group)?;
+    unsafe {
+        // 顺序不能变，先清除附加组和切换组，最后切换用户
+        if libc::setgroups(1, &gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // 确认无法恢复root权限
+        if libc::setuid(0) == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "privileges can still be regained",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    const CHILD_ENV: &str = "VNT_DROP_PRIVILEGES_DIR";
+
+    /// 以root运行时，在子进程中切换到nobody，确认不能再恢复root，也不能写root独占的目录
+    #[test]
+    fn test_drop_privileges() {
+        if super::effective_uid() != 0 {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("vnt-drop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let success = run_child("root_check::unix::tests::drop_privileges_child", &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(success);
+    }
+
+    /// 切换用户影响整个进程，只能在子进程中执行
+    fn run_child(test: &str, env: impl AsRef<std::ffi::OsStr>) -> bool {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--ignored", "--test-threads=1"])
+            .env(CHILD_ENV, env)
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[test]
+    #[ignore]
+    fn drop_privileges_child() {
+        let dir = match std::env::var_os(CHILD_ENV) {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => return,
+        };
+        super::drop_privileges("nobody", None).unwrap();
+        unsafe {
+            assert_ne!(libc::getuid(), 0);
+            assert_ne!(libc::geteuid(), 0);
+            assert_ne!(libc::getegid(), 0);
+            assert_ne!(libc::setuid(0), 0);
+            assert_ne!(libc::seteuid(0), 0);
+        }
+        let err = std::fs::write(dir.join("x"), b"x").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    /// 切换用户后不能再创建虚拟网卡，但已打开的网卡仍能收发数据
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_drop_privileges_tun() {
+        if super::effective_uid() != 0 || !std::path::Path::new("/dev/net/tun").exists() {
+            return;
+        }
+        assert!(run_child(
+            "root_check::unix::tests::drop_privileges_tun_child",
+            "1"
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn drop_privileges_tun_child() {
+        use std::io::{Read, Write};
+        use std::net::{Ipv4Addr, UdpSocket};
+        use std::time::Duration;
+
+        if std::env::var_os(CHILD_ENV).is_none() {
+            return;
+        }
+        let local = Ipv4Addr::new(10, 26, 254, 1);
+        let peer = Ipv4Addr::new(10, 26, 254, 2);
+        let (mut tun, name) = open_tun().unwrap();
+        ip(&["addr", "add", "10.26.254.1/24", "dev", &name]);
+        ip(&["link", "set", &name, "up"]);
+        super::drop_privileges("nobody", None).unwrap();
+
+        let err = open_tun().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // 发往对端的包从网卡读出，交换地址和端口后写回，校验和不变
+        let socket = UdpSocket::bind((local, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket.send_to(b"vnt", (peer, 9)).unwrap();
+        let mut buf = [0u8; 2048];
+        let len = loop {
+            assert!(readable(&tun), "no packet from tun");
+            let len = tun.read(&mut buf).unwrap();
+            // 忽略系统发出的ipv6等其他包
+            if len >= 28 && buf[0] >> 4 == 4 && buf[9] == 17 && buf[16..20] == peer.octets() {
+                break len;
+            }
+        };
+        let ihl = ((buf[0] & 0x0F) * 4) as usize;
+        let (src, dst) = buf[12..20].split_at_mut(4);
+        src.swap_with_slice(dst);
+        let (src_port, dst_port) = buf[ihl..ihl + 4].split_at_mut(2);
+        src_port.swap_with_slice(dst_port);
+        assert_eq!(tun.write(&buf[..len]).unwrap(), len);
+
+        let mut reply = [0u8; 16];
+        let (n, from) = socket.recv_from(&mut reply).unwrap();
+        assert_eq!(&reply[..n], b"vnt");
+        assert_eq!(from, std::net::SocketAddr::from((peer, 9)));
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_tun() -> std::io::Result<(std::fs::File, String)> {
+        use std::os::fd::AsRawFd;
+        const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        unsafe {
+            let mut req: libc::ifreq = std::mem::zeroed();
+            req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+            if libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let name = std::ffi::CStr::from_ptr(req.ifr_name.as_ptr())
+                .to_string_lossy()
+                .to_string();
+            Ok((file, name))
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn ip(args: &[&str]) {
+        let status = std::process::Command::new("ip")
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "ip {:?}", args);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn readable(file: &std::fs::File) -> bool {
+        use std::os::fd::AsRawFd;
+        let mut fd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fd, 1, 2000) > 0 }
+    }
+}
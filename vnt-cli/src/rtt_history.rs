@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use vnt::core::Vnt;
+use vnt::util::state_store::{self, StateFile};
+
+pub const DIR_NAME: &str = "rtt_history";
+/// 每分钟一个采样，保存7天
+pub const CAPACITY: usize = 7 * 24 * 60;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// 每个采样在文件中占的字节数
+const SLOT_SIZE: usize = 8;
+/// 延迟未知，例如刚建立的路由还没有测出延迟
+const RTT_UNKNOWN: u16 = u16::MAX;
+/// 路由表中还没有测出延迟的路由使用的值
+const RTT_UNKNOWN_ROUTE: u16 = 9999;
+/// 丢包率未知，目前没有按对端统计丢包
+const LOSS_UNKNOWN: u8 = u8::MAX;
+/// 趋势图的最大宽度
+const SPARK_WIDTH: usize = 60;
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PathType {
+    /// 离线
+    None,
+    P2p,
+    Relay,
+}
+
+impl PathType {
+    fn name(&self) -> &'static str {
+        match self {
+            PathType::None => "offline",
+            PathType::P2p => "p2p",
+            PathType::Relay => "relay",
+        }
+    }
+}
+
+impl From<u8> for PathType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PathType::P2p,
+            2 => PathType::Relay,
+            _ => PathType::None,
+        }
+    }
+}
+
+impl From<PathType> for u8 {
+    fn from(value: PathType) -> Self {
+        match value {
+            PathType::None => 0,
+            PathType::P2p => 1,
+            PathType::Relay => 2,
+        }
+    }
+}
+
+/// 一分钟的采样
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sample {
+    /// unix时间的分钟数，0表示空位
+    pub minute: u32,
+    /// 毫秒
+    pub rtt: u16,
+    /// 百分比
+    pub loss: u8,
+    pub path: PathType,
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        minute: 0,
+        rtt: RTT_UNKNOWN,
+        loss: LOSS_UNKNOWN,
+        path: PathType::None,
+    };
+    fn rtt(&self) -> Option<u16> {
+        if self.rtt == RTT_UNKNOWN {
+            None
+        } else {
+            Some(self.rtt)
+        }
+    }
+    fn loss(&self) -> Option<u8> {
+        if self.loss == LOSS_UNKNOWN {
+            None
+        } else {
+            Some(self.loss)
+        }
+    }
+}
+
+/// 一个对端的采样环
+///
+/// 文件大小固定，第n分钟的采样保存在n%容量的位置上，写满后自然覆盖最旧的，
+/// 读取时比较位置上记录的分钟数，跳过被覆盖或者没有采样的
+///
+/// 格式: 容量(u32) 后面每个采样8字节，分钟数(u32) 延迟(u16) 丢包率(u8) 路径(u8)，都是小端
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ring {
+    slots: Vec<Sample>,
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+impl Ring {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: vec![Sample::EMPTY; capacity.max(1)],
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+    pub fn record(&mut self, sample: Sample) {
+        let index = sample.minute as usize % self.slots.len();
+        self.slots[index] = sample;
+    }
+    /// [from, to]之间的采样，按时间顺序，超出容量的部分已经被覆盖
+    pub fn range(&self, from: u32, to: u32) -> Vec<Sample> {
+        let from = from.max(to.saturating_sub(self.slots.len() as u32 - 1));
+        let mut list = Vec::new();
+        for minute in from..=to {
+            let sample = self.slots[minute as usize % self.slots.len()];
+            if sample.minute == minute && minute != 0 {
+                list.push(sample);
+            }
+        }
+        list
+    }
+}
+
+impl StateFile for Ring {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + self.slots.len() * SLOT_SIZE);
+        data.extend_from_slice(&(self.slots.len() as u32).to_le_bytes());
+        for sample in &self.slots {
+            data.extend_from_slice(&sample.minute.to_le_bytes());
+            data.extend_from_slice(&sample.rtt.to_le_bytes());
+            data.push(sample.loss);
+            data.push(sample.path.into());
+        }
+        data
+    }
+    fn decode(version: u32, body: &[u8]) -> Option<Self> {
+        if version != 1 || body.len() < 4 {
+            return None;
+        }
+        let capacity = u32::from_le_bytes(body[..4].try_into().ok()?) as usize;
+        let body = &body[4..];
+        if capacity == 0 || body.len() != capacity * SLOT_SIZE {
+            return None;
+        }
+        let slots = body
+            .chunks_exact(SLOT_SIZE)
+            .map(|v| Sample {
+                minute: u32::from_le_bytes([v[0], v[1], v[2], v[3]]),
+                rtt: u16::from_le_bytes([v[4], v[5]]),
+                loss: v[6],
+                path: PathType::from(v[7]),
+            })
+            .collect();
+        Some(Self { slots })
+    }
+}
+
+fn now_minute() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| (v.as_secs() / 60) as u32)
+        .unwrap_or(0)
+}
+
+/// 数据目录下的采样目录
+pub fn dir() -> Option<PathBuf> {
+    crate::app_home().ok().map(|v| v.join(DIR_NAME))
+}
+
+pub fn ring_path(dir: &Path, ip: Ipv4Addr) -> PathBuf {
+    dir.join(format!("{}.ring", ip))
+}
+
+/// 目录下有采样文件的对端
+pub fn stored_peers(dir: &Path) -> Vec<Ipv4Addr> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            Ipv4Addr::from_str(name.to_str()?.strip_suffix(".ring")?).ok()
+        })
+        .collect()
+}
+
+/// 每分钟记录一次在线对端当前路由的延迟和路径类型
+///
+/// 只读取路由表中已有的延迟，不额外发送探测包
+pub fn start(vnt: Vnt) {
+    let dir = match dir() {
+        Some(dir) => dir,
+        None => {
+            log::warn!("数据目录不可写，不记录延迟历史");
+            return;
+        }
+    };
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log::warn!("创建延迟历史目录失败 {:?} {:?}", dir, e);
+        return;
+    }
+    let result = std::thread::Builder::new()
+        .name("RttHistory".into())
+        .spawn(move || {
+            let mut rings: HashMap<Ipv4Addr, Ring> = HashMap::new();
+            let mut purged = 0;
+            while !vnt.is_stopped() {
+                std::thread::sleep(SAMPLE_INTERVAL);
+                // 清除过的对端丢弃缓存，不把旧采样写回
+                for ip in crate::retention::purged_since(&mut purged) {
+                    rings.remove(&ip);
+                }
+                let minute = now_minute();
+                for peer in vnt.device_list() {
+                    let ip = peer.virtual_ip;
+                    if peer.observer || (!peer.status.is_online() && !rings.contains_key(&ip)) {
+                        continue;
+                    }
+                    let sample = if !peer.status.is_online() {
+                        // 本次运行中出现过的对端离线时也记录，和没有采样区分
+                        Sample {
+                            minute,
+                            ..Sample::EMPTY
+                        }
+                    } else {
+                        match vnt.route(&ip) {
+                            Some(route) => Sample {
+                                minute,
+                                rtt: u16::try_from(route.rt)
+                                    .ok()
+                                    .filter(|v| *v < RTT_UNKNOWN_ROUTE)
+                                    .unwrap_or(RTT_UNKNOWN),
+                                loss: LOSS_UNKNOWN,
+                                path: if route.is_p2p() {
+                                    PathType::P2p
+                                } else {
+                                    PathType::Relay
+                                },
+                            },
+                            // 没有路由时经过服务器转发
+                            None => Sample {
+                                minute,
+                                path: PathType::Relay,
+                                ..Sample::EMPTY
+                            },
+                        }
+                    };
+                    let path = ring_path(&dir, ip);
+                    let ring = rings
+                        .entry(ip)
+                        .or_insert_with(|| state_store::load_or_default(&path));
+                    ring.record(sample);
+                    crate::state::STORE.stage(path, ring);
+                }
+            }
+        });
+    if let Err(e) = result {
+        log::warn!("延迟历史启动失败 {:?}", e);
+    }
+}
+
+/// 解析时长，如30m、24h、7d，不带单位时按小时
+fn parse_duration(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let (num, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "h"),
+    };
+    let num: u32 = num.parse().ok()?;
+    let minutes = match unit {
+        "m" => num,
+        "h" => num.checked_mul(60)?,
+        "d" => num.checked_mul(24 * 60)?,
+        _ => return None,
+    };
+    if minutes == 0 {
+        None
+    } else {
+        Some(minutes)
+    }
+}
+
+fn sparkline(values: &[Option<u32>]) -> String {
+    let max = values.iter().flatten().max().copied().unwrap_or(0);
+    let min = values.iter().flatten().min().copied().unwrap_or(0);
+    values
+        .iter()
+        .map(|v| match v {
+            Some(v) if max > min => {
+                SPARK_CHARS[((v - min) as usize * (SPARK_CHARS.len() - 1)) / (max - min) as usize]
+            }
+            Some(_) => SPARK_CHARS[0],
+            None => ' ',
+        })
+        .collect()
+}
+
+fn average(list: &[u32]) -> Option<u32> {
+    if list.is_empty() {
+        None
+    } else {
+        Some((list.iter().map(|v| *v as u64).sum::<u64>() / list.len() as u64) as u32)
+    }
+}
+
+/// 最近minutes分钟的统计和趋势图
+pub fn summary(ip: Ipv4Addr, ring: &Ring, now: u32, minutes: u32) -> String {
+    let minutes = minutes.min(ring.capacity() as u32);
+    let from = now.saturating_sub(minutes - 1);
+    let samples = ring.range(from, now);
+    let mut out = format!(
+        "{} last {}, {}/{} minutes sampled\n",
+        ip,
+        format_minutes(minutes),
+        samples.len(),
+        minutes
+    );
+    if samples.is_empty() {
+        return out;
+    }
+    let mut rtt: Vec<u32> = samples
+        .iter()
+        .filter_map(|v| v.rtt())
+        .map(u32::from)
+        .collect();
+    rtt.sort_unstable();
+    if let Some(avg) = average(&rtt) {
+        out.push_str(&format!(
+            "rtt(ms)  min {}  avg {}  p95 {}  max {}\n",
+            rtt[0],
+            avg,
+            rtt[(rtt.len() - 1) * 95 / 100],
+            rtt[rtt.len() - 1]
+        ));
+    }
+    let loss: Vec<u32> = samples
+        .iter()
+        .filter_map(|v| v.loss())
+        .map(u32::from)
+        .collect();
+    match average(&loss) {
+        Some(avg) => out.push_str(&format!("loss     avg {}%\n", avg)),
+        None => out.push_str("loss     -\n"),
+    }
+    let percent =
+        |path: PathType| samples.iter().filter(|v| v.path == path).count() * 100 / samples.len();
+    out.push_str(&format!(
+        "path     p2p {}%  relay {}%  offline {}%\n",
+        percent(PathType::P2p),
+        percent(PathType::Relay),
+        percent(PathType::None)
+    ));
+    // 每列是一段时间的平均延迟，空白表示没有数据
+    let width = (minutes as usize).min(SPARK_WIDTH);
+    let step = (minutes as usize).div_ceil(width);
+    let mut columns = vec![Vec::new(); width];
+    let mut hours = vec![Vec::new(); 24];
+    for sample in &samples {
+        if let Some(rtt) = sample.rtt() {
+            columns[(sample.minute - from) as usize / step].push(rtt as u32);
+            hours[(sample.minute / 60 % 24) as usize].push(rtt as u32);
+        }
+    }
+    let columns: Vec<Option<u32>> = columns.iter().map(|v| average(v)).collect();
+    out.push_str(&format!(
+        "trend    {}  ({} per column)\n",
+        sparkline(&columns),
+        format_minutes(step as u32)
+    ));
+    let hours: Vec<Option<u32>> = hours.iter().map(|v| average(v)).collect();
+    out.push_str(&format!("by hour  {}  (UTC 00-23)\n", sparkline(&hours)));
+    out
+}
+
+fn format_minutes(minutes: u32) -> String {
+    if minutes % (24 * 60) == 0 {
+        format!("{}d", minutes / (24 * 60))
+    } else if minutes % 60 == 0 {
+        format!("{}h", minutes / 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// 导出所有保存的采样，时间为UTC
+pub fn export_csv<W: Write>(ring: &Ring, now: u32, out: &mut W) -> io::Result<usize> {
+    writeln!(out, "time_utc,rtt_ms,loss_pct,path")?;
+    let samples = ring.range(0, now);
+    for sample in &samples {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            crate::seen_devices::utc_time(sample.minute as u64 * 60),
+            sample.rtt().map_or(String::new(), |v| v.to_string()),
+            sample.loss().map_or(String::new(), |v| v.to_string()),
+            sample.path.name()
+        )?;
+    }
+    Ok(samples.len())
+}
+
+fn load(ip: Ipv4Addr) -> Result<Ring, String> {
+    let dir = dir().ok_or("data directory unavailable, no rtt history".to_string())?;
+    state_store::load::<Ring>(&ring_path(&dir, ip)).ok_or(format!(
+        "No rtt history for {}, start with '--rtt-history'",
+        ip
+    ))
+}
+
+/// history rtt <peer> [duration] 和 history export <peer> --csv <file>，
+/// 不是这两个子命令时返回None
+pub fn command(args: &str) -> Option<String> {
+    let mut split = args.split_whitespace();
+    let sub = split.next()?;
+    if sub != "rtt" && sub != "export" {
+        return None;
+    }
+    let ip = match split.next().map(Ipv4Addr::from_str) {
+        Some(Ok(ip)) => ip,
+        _ => return Some(format!("usage: history {} <peer virtual ip> ...", sub)),
+    };
+    let ring = match load(ip) {
+        Ok(ring) => ring,
+        Err(e) => return Some(e),
+    };
+    let now = now_minute();
+    if sub == "rtt" {
+        let minutes = match split.next() {
+            Some(text) => match parse_duration(text) {
+                Some(v) => v,
+                None => return Some(format!("invalid duration '{}', e.g. 30m 24h 7d", text)),
+            },
+            None => 24 * 60,
+        };
+        return Some(summary(ip, &ring, now, minutes));
+    }
+    let path = match (split.next(), split.next()) {
+        (Some("--csv"), Some(path)) => path,
+        _ => return Some("usage: history export <peer> --csv <file>".to_string()),
+    };
+    let result = std::fs::File::create(path).and_then(|mut file| {
+        let count = export_csv(&ring, now, &mut file)?;
+        file.flush()?;
+        Ok(count)
+    });
+    Some(match result {
+        Ok(count) => format!("exported {} samples to {}", count, path),
+        Err(e) => format!("export {} failed: {}", path, e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use vnt::util::state_store::StateFile;
+
+    use super::{export_csv, parse_duration, summary, PathType, Ring, Sample};
+
+    fn sample(minute: u32, rtt: u16, path: PathType) -> Sample {
+        Sample {
+            minute,
+            rtt,
+            loss: 0,
+            path,
+        }
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut ring = Ring::new(10);
+        let start = 28_000_000;
+        for i in 0..25 {
+            ring.record(sample(start + i, i as u16, PathType::P2p));
+        }
+        // 只剩最后10分钟
+        let list = ring.range(start, start + 24);
+        assert_eq!(list.len(), 10);
+        assert_eq!(list[0].minute, start + 15);
+        assert_eq!(list[9].rtt, 24);
+        assert!(ring.range(start, start + 14).is_empty());
+        // 中间缺失的分钟跳过
+        ring.record(sample(start + 30, 30, PathType::Relay));
+        let list = ring.range(start + 21, start + 30);
+        assert_eq!(
+            list.iter().map(|v| v.minute - start).collect::<Vec<_>>(),
+            vec![21, 22, 23, 24, 30]
+        );
+        // 编码后大小固定，可以还原
+        let data = ring.encode();
+        assert_eq!(data.len(), 4 + 10 * 8);
+        assert_eq!(Ring::decode(1, &data), Some(ring.clone()));
+        assert_eq!(Ring::decode(1, &data[..data.len() - 1]), None);
+        assert_eq!(Ring::decode(2, &data), None);
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let mut ring = Ring::new(60);
+        // 2023-11-14 22:13
+        let start = 1700000000 / 60;
+        ring.record(sample(start, 12, PathType::P2p));
+        ring.record(sample(start + 1, u16::MAX, PathType::None));
+        ring.record(Sample {
+            loss: u8::MAX,
+            ..sample(start + 3, 85, PathType::Relay)
+        });
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&ring, start + 5, &mut out).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "time_utc,rtt_ms,loss_pct,path\n\
+             2023-11-14 22:13:00,12,0,p2p\n\
+             2023-11-14 22:14:00,,0,offline\n\
+             2023-11-14 22:16:00,85,,relay\n"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(parse_duration("30m"), Some(30));
+        assert_eq!(parse_duration("24"), Some(24 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("1w"), None);
+        let mut ring = Ring::new(super::CAPACITY);
+        let start = 1700000000 / 60 / 1440 * 1440;
+        for i in 0..120 {
+            // 第二个小时延迟变高
+            let rtt = if i < 60 { 10 } else { 80 };
+            ring.record(sample(start + i, rtt, PathType::P2p));
+        }
+        let text = summary(Ipv4Addr::new(10, 26, 0, 2), &ring, start + 119, 120);
+        assert!(text.contains("last 2h, 120/120 minutes sampled"));
+        assert!(text.contains("min 10  avg 45  p95 80  max 80"));
+        assert!(text.contains("p2p 100%"));
+        let trend = format!(
+            "trend    {}{}  (2m per column)",
+            "▁".repeat(30),
+            "█".repeat(30)
+        );
+        assert!(text.contains(&trend));
+        let by_hour = format!("by hour  ▁█{}  (UTC 00-23)", " ".repeat(22));
+        assert!(text.contains(&by_hour));
+    }
+}
@@ -0,0 +1,308 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use vnt::core::Config;
+use vnt::handle::dns::DnsRoute;
+use vnt::util::state_store::{self, StateFile};
+use vnt::util::workers::{PinSpec, Priority};
+
+/// 启动后这么久之内崩溃才计数
+const CRASH_WINDOW_SECS: u64 = 10;
+/// 连续崩溃这么多次后自动进入安全模式
+pub const CRASH_LIMIT: u32 = 3;
+/// 运行超过这个时间后清零计数，下次按正常模式启动
+pub const SURVIVE: Duration = Duration::from_secs(60);
+const COUNTER_FILE: &str = "crash_loop";
+/// 这些退出原因才算崩溃，参数错误等换成安全模式也无法恢复
+const CRASH_REASONS: [&str; 2] = ["crashed", "runtime_failure"];
+
+/// 连续启动后很快崩溃的次数，保存在数据目录下
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CrashCounter {
+    pub count: u32,
+    /// 已经计入的最后一次退出的时间戳，被强制结束时不会写退出文件，同一次退出只计一次
+    pub last_exit: u64,
+}
+
+impl StateFile for CrashCounter {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        format!("{} {}", self.count, self.last_exit).into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(body).ok()?;
+        let mut items = text.split_whitespace();
+        let count = items.next()?.parse().ok()?;
+        let last_exit = items.next()?.parse().ok()?;
+        Some(CrashCounter { count, last_exit })
+    }
+}
+
+/// last_exit.json中用到的字段
+#[derive(Debug, Eq, PartialEq)]
+struct LastExit {
+    reason: String,
+    timestamp: u64,
+    uptime_secs: u64,
+}
+
+fn read_last_exit(dir: &Path) -> Option<LastExit> {
+    let text = std::fs::read_to_string(dir.join("last_exit.json")).ok()?;
+    let value: serde_json::Value = serde_json::from_str(&text).ok()?;
+    Some(LastExit {
+        reason: value.get("reason")?.as_str()?.to_string(),
+        timestamp: value.get("timestamp")?.as_u64()?,
+        uptime_secs: value.get("uptime_secs")?.as_u64()?,
+    })
+}
+
+impl CrashCounter {
+    /// 根据上一次的退出更新计数，没有新的退出记录时保持不变
+    fn update(self, last: Option<&LastExit>) -> CrashCounter {
+        let last = match last {
+            Some(last) if last.timestamp != self.last_exit => last,
+            _ => return self,
+        };
+        let crashed =
+            CRASH_REASONS.contains(&last.reason.as_str()) && last.uptime_secs < CRASH_WINDOW_SECS;
+        CrashCounter {
+            count: if crashed { self.count + 1 } else { 0 },
+            last_exit: last.timestamp,
+        }
+    }
+    pub fn triggered(&self) -> bool {
+        self.count >= CRASH_LIMIT
+    }
+}
+
+/// 启动时调用，读取上一次的退出原因并更新计数文件
+pub fn check(dir: &Path) -> CrashCounter {
+    let path = dir.join(COUNTER_FILE);
+    let counter = state_store::load_or_default::<CrashCounter>(&path);
+    let updated = counter.update(read_last_exit(dir).as_ref());
+    if updated != counter {
+        if let Err(e) = state_store::save(&path, &updated) {
+            log::warn!("保存崩溃计数失败 {:?}", e);
+        }
+    }
+    updated
+}
+
+/// 运行超过SURVIVE后清零计数
+pub fn reset_later(dir: &Path) {
+    let path = dir.join(COUNTER_FILE);
+    let result = std::thread::Builder::new()
+        .name("SafeModeReset".into())
+        .spawn(move || {
+            std::thread::sleep(SURVIVE);
+            let counter = state_store::load_or_default::<CrashCounter>(&path);
+            if counter.count == 0 {
+                return;
+            }
+            let reset = CrashCounter {
+                count: 0,
+                last_exit: counter.last_exit,
+            };
+            match state_store::save(&path, &reset) {
+                Ok(_) => log::info!("已稳定运行{:?}，崩溃计数清零", SURVIVE),
+                Err(e) => log::warn!("崩溃计数清零失败 {:?}", e),
+            }
+        });
+    if let Err(e) = result {
+        log::warn!("崩溃计数清零线程启动失败 {:?}", e);
+    }
+}
+
+/// 安全模式会关闭或调整的设置
+pub struct Features {
+    pub mdns: bool,
+    pub dns_listen: Option<SocketAddr>,
+    pub dns_routes: Vec<DnsRoute>,
+    pub flow_tracking: bool,
+    pub notify_flows: bool,
+    pub reorder: bool,
+    pub bandwidth_probe: bool,
+    pub parallel: usize,
+    pub pin_workers: PinSpec,
+    pub worker_priority: Priority,
+    pub health_listen: Option<SocketAddr>,
+    pub rtt_history: bool,
+    pub telemetry: Option<String>,
+}
+
+impl Features {
+    /// 从配置中取出，调整后用store放回
+    pub fn take(
+        config: &mut Config,
+        health_listen: Option<SocketAddr>,
+        rtt_history: bool,
+        telemetry: Option<String>,
+    ) -> Features {
+        Features {
+            mdns: config.mdns,
+            dns_listen: config.dns_listen,
+            dns_routes: std::mem::take(&mut config.dns_routes),
+            flow_tracking: config.flow_tracking,
+            notify_flows: config.notify_flows,
+            reorder: config.reorder,
+            bandwidth_probe: config.bandwidth_probe,
+            parallel: config.parallel,
+            pin_workers: std::mem::take(&mut config.pin_workers),
+            worker_priority: config.worker_priority,
+            health_listen,
+            rtt_history,
+            telemetry,
+        }
+    }
+    /// 放回配置，返回health_listen、rtt_history、telemetry
+    pub fn store(self, config: &mut Config) -> (Option<SocketAddr>, bool, Option<String>) {
+        config.mdns = self.mdns;
+        config.dns_listen = self.dns_listen;
+        config.dns_routes = self.dns_routes;
+        config.flow_tracking = self.flow_tracking;
+        config.notify_flows = self.notify_flows;
+        config.reorder = self.reorder;
+        config.bandwidth_probe = self.bandwidth_probe;
+        config.parallel = self.parallel;
+        config.pin_workers = self.pin_workers;
+        config.worker_priority = self.worker_priority;
+        (self.health_listen, self.rtt_history, self.telemetry)
+    }
+    /// 关闭可选功能并使用保守的设置，返回实际改动的项
+    pub fn safe(&mut self) -> Vec<String> {
+        let mut changed = Vec::new();
+        if self.mdns {
+            self.mdns = false;
+            changed.push("mdns".to_string());
+        }
+        if self.dns_listen.is_some() || !self.dns_routes.is_empty() {
+            self.dns_listen = None;
+            self.dns_routes.clear();
+            changed.push("dns responder".to_string());
+        }
+        if self.health_listen.take().is_some() {
+            changed.push("health listener".to_string());
+        }
+        if self.flow_tracking {
+            self.flow_tracking = false;
+            self.notify_flows = false;
+            changed.push("flow tracking".to_string());
+        }
+        if self.reorder {
+            // 重排需要为每个对端暂存数据包
+            self.reorder = false;
+            changed.push("reorder".to_string());
+        }
+        if self.bandwidth_probe {
+            self.bandwidth_probe = false;
+            changed.push("bandwidth probe".to_string());
+        }
+        if self.rtt_history {
+            self.rtt_history = false;
+            changed.push("rtt history".to_string());
+        }
+        if self.telemetry.take().is_some() {
+            changed.push("anonymous stats".to_string());
+        }
+        if self.parallel != 1 {
+            changed.push(format!("parallel {} -> 1", self.parallel));
+            self.parallel = 1;
+        }
+        if !self.pin_workers.is_empty() {
+            self.pin_workers = PinSpec::default();
+            changed.push("worker pinning".to_string());
+        }
+        if self.worker_priority != Priority::Normal {
+            self.worker_priority = Priority::Normal;
+            changed.push("worker priority".to_string());
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vnt::util::state_store;
+    use vnt::util::workers::{PinSpec, Priority};
+
+    use super::{check, CrashCounter, Features, COUNTER_FILE, CRASH_LIMIT};
+
+    fn write_exit(dir: &std::path::Path, reason: &str, timestamp: u64, uptime: u64) {
+        let text = format!(
+            "{{\"reason\": \"{}\", \"code\": 6, \"message\": \"\", \"timestamp\": {}, \"uptime_secs\": {}}}",
+            reason, timestamp, uptime
+        );
+        std::fs::write(dir.join("last_exit.json"), text).unwrap();
+    }
+
+    #[test]
+    fn test_crash_loop() {
+        let dir = std::env::temp_dir().join(format!("vnt-safe-mode-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(check(&dir), CrashCounter::default());
+        for i in 1..=CRASH_LIMIT as u64 {
+            write_exit(&dir, "crashed", 100 + i, 2);
+            let counter = check(&dir);
+            assert_eq!(counter.count, i as u32);
+            // 没有新的退出记录(被强制结束)时不重复计数
+            assert_eq!(check(&dir), counter);
+        }
+        assert!(check(&dir).triggered());
+        // 安全模式下稳定运行后清零，下次按正常模式启动
+        let saved: CrashCounter = state_store::load(&dir.join(COUNTER_FILE)).unwrap();
+        state_store::save(
+            &dir.join(COUNTER_FILE),
+            &CrashCounter {
+                count: 0,
+                last_exit: saved.last_exit,
+            },
+        )
+        .unwrap();
+        assert!(!check(&dir).triggered());
+        // 运行很久之后的崩溃和其他退出原因不计数
+        write_exit(&dir, "crashed", 200, 2);
+        assert_eq!(check(&dir).count, 1);
+        write_exit(&dir, "crashed", 201, 3600);
+        assert_eq!(check(&dir).count, 0);
+        write_exit(&dir, "crashed", 202, 2);
+        assert_eq!(check(&dir).count, 1);
+        write_exit(&dir, "config_error", 203, 0);
+        assert_eq!(check(&dir).count, 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_safe_features() {
+        let mut features = Features {
+            mdns: true,
+            dns_listen: Some("127.0.0.1:53".parse().unwrap()),
+            dns_routes: vec!["corp.example=10.26.0.2".parse().unwrap()],
+            flow_tracking: true,
+            notify_flows: true,
+            reorder: true,
+            bandwidth_probe: true,
+            parallel: 4,
+            pin_workers: "udp=0".parse::<PinSpec>().unwrap(),
+            worker_priority: Priority::High,
+            health_listen: Some("127.0.0.1:8080".parse().unwrap()),
+            rtt_history: true,
+            telemetry: Some("https://example.com".to_string()),
+        };
+        let changed = features.safe();
+        assert_eq!(changed.len(), 11);
+        assert!(changed.contains(&"parallel 4 -> 1".to_string()));
+        assert!(!features.mdns);
+        assert!(features.dns_listen.is_none() && features.dns_routes.is_empty());
+        assert!(features.health_listen.is_none());
+        assert!(!features.flow_tracking && !features.notify_flows);
+        assert!(!features.reorder && !features.bandwidth_probe);
+        assert!(!features.rtt_history && features.telemetry.is_none());
+        assert_eq!(features.parallel, 1);
+        assert!(features.pin_workers.is_empty());
+        assert_eq!(features.worker_priority, Priority::Normal);
+        // 已经是保守设置时没有改动
+        assert!(features.safe().is_empty());
+    }
+}
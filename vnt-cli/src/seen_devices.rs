@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+pub const FILE_NAME: &str = "seen_devices.log";
+
+/// 一条分配记录，格式: 时间(unix秒)\t虚拟ip\t指纹\t名称
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SeenRecord {
+    pub time: u64,
+    pub virtual_ip: Ipv4Addr,
+    pub fingerprint: String,
+    pub name: String,
+}
+
+/// 名称中的制表符和换行会破坏格式
+fn clean_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+impl SeenRecord {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.time,
+            self.virtual_ip,
+            self.fingerprint,
+            clean_name(&self.name)
+        )
+    }
+    fn parse(line: &str) -> Option<Self> {
+        let mut split = line.trim_end_matches(['\r', '\n']).splitn(4, '\t');
+        let time = split.next()?.parse().ok()?;
+        let virtual_ip = Ipv4Addr::from_str(split.next()?).ok()?;
+        let fingerprint = split.next()?.to_string();
+        let name = split.next()?.to_string();
+        Some(Self {
+            time,
+            virtual_ip,
+            fingerprint,
+            name,
+        })
+    }
+    /// 按虚拟ip或指纹查询，指纹可以只给前缀
+    pub fn matches(&self, key: &str) -> bool {
+        match Ipv4Addr::from_str(key) {
+            Ok(ip) => self.virtual_ip == ip,
+            Err(_) => {
+                let key = key.to_lowercase();
+                !self.fingerprint.is_empty() && key.len() >= 4 && self.fingerprint.starts_with(&key)
+            }
+        }
+    }
+}
+
+impl Display for SeenRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fingerprint = if self.fingerprint.is_empty() {
+            "-"
+        } else {
+            &self.fingerprint
+        };
+        write!(
+            f,
+            "{}  {:<15}  {:<16}  {}",
+            utc_time(self.time),
+            self.virtual_ip,
+            fingerprint,
+            self.name
+        )
+    }
+}
+
+/// 虚拟ip对应的设备历史，只追加不修改
+///
+/// 虚拟ip对应的设备(指纹或名称)变化时追加一行，重启后从文件恢复最后的状态，不会重复记录
+pub struct SeenDevices {
+    path: Option<PathBuf>,
+    last: Mutex<HashMap<Ipv4Addr, (String, String)>>,
+    // 已经处理过的清除记录
+    purged: Mutex<usize>,
+}
+
+impl SeenDevices {
+    /// 数据目录不可写时只在内存中比较，不保存
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let mut last = HashMap::new();
+        if let Some(path) = &path {
+            match read(path) {
+                Ok(list) => {
+                    for record in list {
+                        last.insert(record.virtual_ip, (record.fingerprint, record.name));
+                    }
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        log::warn!("读取设备历史失败 {:?} {:?}", path, e);
+                    }
+                }
+            }
+        }
+        Self {
+            path,
+            last: Mutex::new(last),
+            purged: Mutex::new(0),
+        }
+    }
+    /// 收到新的设备列表时调用，返回新增的记录
+    pub fn update(&self, time: u64, list: &[(Ipv4Addr, String, String)]) -> Vec<SeenRecord> {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        // 清除过的对端再出现时当作新设备记录
+        let mut purged = self.purged.lock().unwrap_or_else(|e| e.into_inner());
+        for ip in crate::retention::purged_since(&mut purged) {
+            last.remove(&ip);
+        }
+        drop(purged);
+        let mut records = Vec::new();
+        for (virtual_ip, fingerprint, name) in list {
+            // 和从文件恢复的名称比较
+            let current = (fingerprint.clone(), clean_name(name));
+            if last.get(virtual_ip) == Some(&current) {
+                continue;
+            }
+            last.insert(*virtual_ip, current);
+            records.push(SeenRecord {
+                time,
+                virtual_ip: *virtual_ip,
+                fingerprint: fingerprint.clone(),
+                name: name.clone(),
+            });
+        }
+        drop(last);
+        if let (Some(path), false) = (&self.path, records.is_empty()) {
+            if let Err(e) = append(path, &records) {
+                log::warn!("保存设备历史失败 {:?} {:?}", path, e);
+            }
+        }
+        records
+    }
+}
+
+/// 数据目录下的历史文件
+pub fn path() -> Option<PathBuf> {
+    crate::app_home().ok().map(|v| v.join(FILE_NAME))
+}
+
+fn append(path: &Path, records: &[SeenRecord]) -> io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let text: String = records.iter().map(|v| v.to_line()).collect();
+    file.write_all(text.as_bytes())
+}
+
+pub fn read(path: &Path) -> io::Result<Vec<SeenRecord>> {
+    let file = std::fs::File::open(path)?;
+    let mut list = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // 写入一半的行直接跳过
+        if let Some(record) = SeenRecord::parse(&line?) {
+            list.push(record);
+        }
+    }
+    Ok(list)
+}
+
+/// 删除这些虚拟ip的记录，返回删除的行数
+pub fn remove(path: &Path, ips: &[Ipv4Addr]) -> io::Result<usize> {
+    let list = match read(path) {
+        Ok(list) => list,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let (removed, kept): (Vec<_>, Vec<_>) =
+        list.into_iter().partition(|v| ips.contains(&v.virtual_ip));
+    if !removed.is_empty() {
+        let text: String = kept.iter().map(|v| v.to_line()).collect();
+        vnt::util::state_store::atomic_write(path, text.as_bytes())?;
+    }
+    Ok(removed.len())
+}
+
+/// 查询虚拟ip或指纹的历史，按时间顺序
+pub fn query(path: &Path, key: &str) -> io::Result<Vec<SeenRecord>> {
+    let key = key.trim();
+    let mut list = read(path)?;
+    list.retain(|v| v.matches(key));
+    Ok(list)
+}
+
+/// history命令的结果
+pub fn history(key: &str) -> String {
+    let path = match path() {
+        Some(path) => path,
+        None => return "data directory unavailable, no device history".to_string(),
+    };
+    match query(&path, key) {
+        Ok(list) if list.is_empty() => format!("No history for {}", key),
+        Ok(list) => list
+            .iter()
+            .map(|record| record.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => format!("No history for {}", key),
+        Err(e) => format!("read {} failed: {}", path.display(), e),
+    }
+}
+
+/// unix秒转换成UTC时间
+pub fn utc_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Howard Hinnant的civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{query, utc_time, SeenDevices};
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("vnt-seen-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ip2 = Ipv4Addr::new(10, 26, 0, 2);
+        let ip3 = Ipv4Addr::new(10, 26, 0, 3);
+        let seen = SeenDevices::open(Some(path.clone()));
+        let list = vec![
+            (ip2, "a1b2c3d4e5f60718".to_string(), "pc".to_string()),
+            (ip3, "".to_string(), "phone".to_string()),
+        ];
+        assert_eq!(seen.update(100, &list).len(), 2);
+        // 没有变化不记录
+        assert!(seen.update(200, &list).is_empty());
+        // 地址重新分配给另一台设备
+        let list = vec![(ip2, "99aa88bb77cc66dd".to_string(), "laptop\tx".to_string())];
+        assert_eq!(seen.update(300, &list).len(), 1);
+        // 重启后从文件恢复，不重复记录
+        let seen = SeenDevices::open(Some(path.clone()));
+        assert!(seen.update(400, &list).is_empty());
+
+        let history = query(&path, "10.26.0.2").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].name, "pc");
+        assert_eq!(history[1].name, "laptop x");
+        assert_eq!(history[1].time, 300);
+        let history = query(&path, "A1B2").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].virtual_ip, ip2);
+        // 过短的前缀和空指纹不匹配
+        assert!(query(&path, "a1").unwrap().is_empty());
+        assert!(query(&path, "").unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_utc_time() {
+        assert_eq!(utc_time(0), "1970-01-01 00:00:00");
+        assert_eq!(utc_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(utc_time(1700000000), "2023-11-14 22:13:20");
+    }
+}
@@ -0,0 +1,665 @@
+use std::io::{self, IsTerminal};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use getopts::Options;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::config::settings::Settings;
+use crate::exit;
+use crate::{config, data_dir, root_check, DEFAULT_SERVER};
+
+/// 向导写入数据目录的配置文件
+pub const CONFIG_NAME: &str = "vnt.toml";
+
+/// 向导的输入输出，交互时使用终端，'--accept-defaults'时全部取默认值，测试中按脚本回答
+pub trait Prompt {
+    fn say(&mut self, line: &str);
+    /// 直接回车时返回default
+    fn input(&mut self, question: &str, default: &str) -> io::Result<String>;
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool>;
+}
+
+pub struct TermPrompt {
+    term: console::Term,
+}
+
+impl Prompt for TermPrompt {
+    fn say(&mut self, line: &str) {
+        let _ = self.term.write_line(line);
+    }
+    fn input(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if default.is_empty() {
+            self.term.write_str(&format!("{}: ", question))?;
+        } else {
+            self.term
+                .write_str(&format!("{} [{}]: ", question, default))?;
+        }
+        let line = self.term.read_line()?;
+        let line = line.trim();
+        Ok(if line.is_empty() { default } else { line }.to_string())
+    }
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            self.term.write_str(&format!("{} [{}]: ", question, hint))?;
+            match self.term.read_line()?.trim().to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.term.write_line("please answer y or n")?,
+            }
+        }
+    }
+}
+
+/// '--accept-defaults'，不读取输入
+pub struct Defaults;
+
+impl Prompt for Defaults {
+    fn say(&mut self, line: &str) {
+        println!("{}", line);
+    }
+    fn input(&mut self, _question: &str, default: &str) -> io::Result<String> {
+        Ok(default.to_string())
+    }
+    fn confirm(&mut self, _question: &str, default: bool) -> io::Result<bool> {
+        Ok(default)
+    }
+}
+
+/// 命令行上给出的回答，给出的项不再询问
+#[derive(Clone, Debug, Default)]
+pub struct Answers {
+    pub token: Option<String>,
+    pub name: Option<String>,
+    pub server: Option<String>,
+    /// '--overwrite'，已有配置文件时直接覆盖
+    pub overwrite: bool,
+    /// '--install-service'/'--no-service'
+    pub service: Option<bool>,
+}
+
+/// 向导运行的环境
+pub struct Env {
+    pub dir: PathBuf,
+    pub writable: bool,
+    pub elevated: bool,
+    /// 服务中启动的程序
+    pub exe: PathBuf,
+    pub install: fn(&ServiceFile) -> io::Result<()>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Outcome {
+    pub config: PathBuf,
+    pub token: String,
+    pub server: String,
+    pub service_started: bool,
+}
+
+/// 'setup'子命令，首次使用的配置向导
+pub fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("k", "token", "组网标识", "<token>");
+    opts.optopt("n", "name", "设备名称", "<name>");
+    opts.optopt("s", "server", "服务器地址", "<server>");
+    opts.optflag("", "accept-defaults", "不询问，全部使用默认值");
+    opts.optflag("", "overwrite", "覆盖已有的配置文件");
+    opts.optflag("", "install-service", "安装系统服务");
+    opts.optflag("", "no-service", "不安装系统服务");
+    opts.optopt("", "data-dir", "数据目录", "<path>");
+    opts.optflag("h", "help", "帮助");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(program);
+            exit::config_error(f.to_string());
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(program);
+        return;
+    }
+    if matches.opt_present("install-service") && matches.opt_present("no-service") {
+        exit::config_error("'--install-service' conflicts with '--no-service'");
+    }
+    let answers = Answers {
+        token: matches.opt_str("k"),
+        name: matches.opt_str("n"),
+        server: matches.opt_str("s"),
+        overwrite: matches.opt_present("overwrite"),
+        service: if matches.opt_present("install-service") {
+            Some(true)
+        } else if matches.opt_present("no-service") {
+            Some(false)
+        } else {
+            None
+        },
+    };
+    let dir = data_dir::init(matches.opt_str("data-dir"));
+    let env = Env {
+        dir: dir.path.clone(),
+        writable: dir.writable,
+        elevated: root_check::is_app_elevated(),
+        exe: std::env::current_exe().unwrap_or_else(|_| PathBuf::from(program)),
+        install,
+    };
+    let rs = if matches.opt_present("accept-defaults") {
+        run(&mut Defaults, &env, answers)
+    } else {
+        if !io::stdin().is_terminal() {
+            exit::config_error("stdin is not a terminal, use 'setup --accept-defaults'");
+        }
+        let mut prompt = TermPrompt {
+            term: console::Term::stdout(),
+        };
+        run(&mut prompt, &env, answers)
+    };
+    if let Err(e) = rs {
+        exit::config_error(e);
+    }
+}
+
+pub fn run(prompt: &mut dyn Prompt, env: &Env, answers: Answers) -> Result<Outcome, String> {
+    let path = env.dir.join(CONFIG_NAME);
+    prompt.say(&format!("vnt setup, data dir: {}", env.dir.display()));
+    let mut write = true;
+    if path.exists() {
+        prompt.say(&format!("found existing config {}", path.display()));
+        write = answers.overwrite
+            || prompt
+                .confirm("overwrite it?", false)
+                .map_err(|e| e.to_string())?;
+    }
+    let (token, name, server) = if write {
+        let token = match answers.token {
+            Some(token) => token,
+            None => prompt
+                .input(
+                    "token, shared by all devices of the network (enter keeps the generated one)",
+                    &generate_token(),
+                )
+                .map_err(|e| e.to_string())?,
+        };
+        let token = token.trim().to_string();
+        if token.is_empty() {
+            return Err("token is empty".to_string());
+        }
+        let default_name = config::hostname().unwrap_or_default();
+        let name = match answers.name {
+            Some(name) => name,
+            None => prompt
+                .input("device name", &default_name)
+                .map_err(|e| e.to_string())?,
+        };
+        let server = match answers.server {
+            Some(server) => server,
+            None => prompt
+                .input("server", DEFAULT_SERVER)
+                .map_err(|e| e.to_string())?,
+        };
+        (token, name.trim().to_string(), server.trim().to_string())
+    } else {
+        let settings = Settings::load(&path.to_string_lossy())?;
+        let token = settings
+            .token
+            .ok_or_else(|| format!("{}: 'token' is missing", path.display()))?;
+        prompt.say("keeping the existing config");
+        (
+            token,
+            String::new(),
+            settings
+                .server
+                .unwrap_or_else(|| DEFAULT_SERVER.to_string()),
+        )
+    };
+    for check in doctor(env, &server) {
+        match check.problem {
+            None => prompt.say(&format!("[ok] {}", check.name)),
+            Some(hint) => prompt.say(&format!("[!!] {}: {}", check.name, hint)),
+        }
+    }
+
+    if write {
+        if !env.writable {
+            return Err(format!("data dir {} is not writable", env.dir.display()));
+        }
+        write_config(&path, &config_text(&token, &name, &server))
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+        prompt.say(&format!("config written to {}", path.display()));
+    }
+
+    let mut service_started = false;
+    if let Some(file) = service_file(&env.exe, &path, &env.dir) {
+        let install = match answers.service {
+            Some(install) => install,
+            None => prompt
+                .confirm(
+                    "install vnt as a system service that starts on boot?",
+                    false,
+                )
+                .map_err(|e| e.to_string())?,
+        };
+        if install {
+            if !env.elevated {
+                prompt.say("installing a service needs administrator privileges, run setup again as administrator/root");
+            } else {
+                match (env.install)(&file) {
+                    Ok(_) => service_started = true,
+                    Err(e) => prompt.say(&format!("failed to install service: {}", e)),
+                }
+            }
+        }
+    }
+
+    if service_started {
+        prompt.say("vnt service installed and started");
+    } else {
+        prompt.say("start vnt with:");
+        prompt.say(&format!(
+            "  {} --config {}",
+            quote(&env.exe.to_string_lossy()),
+            quote(&path.to_string_lossy())
+        ));
+    }
+    prompt.say("on other machines, join the same network with:");
+    prompt.say(&format!("  {}", invite(&token, &server)));
+    Ok(Outcome {
+        config: path,
+        token,
+        server,
+        service_started,
+    })
+}
+
+/// 其他设备加入同一个网络的命令
+pub fn invite(token: &str, server: &str) -> String {
+    format!(
+        "vnt-cli setup --accept-defaults --token {} --server {}",
+        quote(token),
+        quote(server)
+    )
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+/// 和'--gen-config'模板相同的格式，能被'--config'加载
+pub fn config_text(token: &str, name: &str, server: &str) -> String {
+    let value = |v: &str| toml::Value::String(v.to_string()).to_string();
+    let mut text = String::from("# 由'vnt-cli setup'生成，使用'vnt-cli --config <path>'加载\n");
+    text.push_str(&format!("token = {}\n", value(token)));
+    if !server.is_empty() {
+        text.push_str(&format!("server = {}\n", value(server)));
+    }
+    if !name.is_empty() {
+        text.push_str(&format!("name = {}\n", value(name)));
+    }
+    text
+}
+
+fn write_config(path: &Path, text: &str) -> io::Result<()> {
+    std::fs::write(path, text)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+pub struct Check {
+    pub name: &'static str,
+    /// 有问题时的说明
+    pub problem: Option<String>,
+}
+
+/// 启动前的检查，只提示不阻止
+pub fn doctor(env: &Env, server: &str) -> Vec<Check> {
+    let mut checks = Vec::new();
+    checks.push(Check {
+        name: "data dir writable",
+        problem: (!env.writable).then(|| {
+            "device id and state will not survive restarts, use '--data-dir' or VNT_HOME"
+                .to_string()
+        }),
+    });
+    checks.push(Check {
+        name: "administrator privileges",
+        problem: (!env.elevated).then(|| {
+            "creating the virtual nic needs root/administrator, or start vnt with '-a'".to_string()
+        }),
+    });
+    #[cfg(target_os = "linux")]
+    checks.push(Check {
+        name: "tun device",
+        problem: (!Path::new("/dev/net/tun").exists()).then(|| {
+            "/dev/net/tun is missing, load the tun module (modprobe tun) or pass the device into the container".to_string()
+        }),
+    });
+    checks.push(Check {
+        name: "server address",
+        problem: match server.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(_)) => None,
+            Ok(None) => Some(format!("{} resolved to no address", server)),
+            Err(e) => Some(format!(
+                "cannot resolve {}: {}, check the address and DNS",
+                server, e
+            )),
+        },
+    });
+    checks
+}
+
+/// 系统服务的定义，content为空时只执行命令
+#[derive(Debug)]
+pub struct ServiceFile {
+    pub path: PathBuf,
+    pub content: String,
+    /// 写入后依次执行的命令
+    pub commands: Vec<Vec<String>>,
+}
+
+fn service_args(exe: &Path, config: &Path, dir: &Path) -> Vec<String> {
+    vec![
+        exe.to_string_lossy().to_string(),
+        "--config".to_string(),
+        config.to_string_lossy().to_string(),
+        "--data-dir".to_string(),
+        dir.to_string_lossy().to_string(),
+        "--daemon".to_string(),
+    ]
+}
+
+#[cfg(target_os = "linux")]
+pub fn service_file(exe: &Path, config: &Path, dir: &Path) -> Option<ServiceFile> {
+    Some(ServiceFile {
+        path: PathBuf::from("/etc/systemd/system/vnt.service"),
+        content: systemd_unit(&service_args(exe, config, dir)),
+        commands: vec![
+            vec!["systemctl".into(), "daemon-reload".into()],
+            vec![
+                "systemctl".into(),
+                "enable".into(),
+                "--now".into(),
+                "vnt.service".into(),
+            ],
+        ],
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub fn service_file(exe: &Path, config: &Path, dir: &Path) -> Option<ServiceFile> {
+    let path = PathBuf::from("/Library/LaunchDaemons/top.wherewego.vnt.plist");
+    Some(ServiceFile {
+        content: launchd_plist(&service_args(exe, config, dir)),
+        commands: vec![vec![
+            "launchctl".into(),
+            "load".into(),
+            "-w".into(),
+            path.to_string_lossy().to_string(),
+        ]],
+        path,
+    })
+}
+
+/// vnt-cli没有实现windows服务控制协议，使用开机运行的计划任务代替服务
+#[cfg(target_os = "windows")]
+pub fn service_file(exe: &Path, config: &Path, dir: &Path) -> Option<ServiceFile> {
+    let run = service_args(exe, config, dir)
+        .iter()
+        .map(|v| quote(v))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(ServiceFile {
+        path: PathBuf::new(),
+        content: String::new(),
+        commands: vec![
+            vec![
+                "schtasks".into(),
+                "/Create".into(),
+                "/TN".into(),
+                "vnt".into(),
+                "/TR".into(),
+                run,
+                "/SC".into(),
+                "ONSTART".into(),
+                "/RU".into(),
+                "SYSTEM".into(),
+                "/F".into(),
+            ],
+            vec!["schtasks".into(), "/Run".into(), "/TN".into(), "vnt".into()],
+        ],
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub fn service_file(_exe: &Path, _config: &Path, _dir: &Path) -> Option<ServiceFile> {
+    None
+}
+
+#[allow(dead_code)]
+fn systemd_unit(args: &[String]) -> String {
+    let exec = args.iter().map(|v| quote(v)).collect::<Vec<_>>().join(" ");
+    format!(
+        "[Unit]\nDescription=vnt\nAfter=network-online.target\nWants=network-online.target\n\n\
+         [Service]\nExecStart={}\nRestart=on-failure\nRestartSec=5\n\n\
+         [Install]\nWantedBy=multi-user.target\n",
+        exec
+    )
+}
+
+#[allow(dead_code)]
+fn launchd_plist(args: &[String]) -> String {
+    let escape = |v: &str| {
+        v.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let mut text = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n\
+         \t<key>Label</key>\n\t<string>top.wherewego.vnt</string>\n\
+         \t<key>ProgramArguments</key>\n\t<array>\n",
+    );
+    for arg in args {
+        text.push_str(&format!("\t\t<string>{}</string>\n", escape(arg)));
+    }
+    text.push_str(
+        "\t</array>\n\t<key>RunAtLoad</key>\n\t<true/>\n\
+         \t<key>KeepAlive</key>\n\t<true/>\n</dict>\n</plist>\n",
+    );
+    text
+}
+
+fn install(file: &ServiceFile) -> io::Result<()> {
+    if !file.content.is_empty() {
+        std::fs::write(&file.path, &file.content)?;
+    }
+    for command in &file.commands {
+        let status = std::process::Command::new(&command[0])
+            .args(&command[1..])
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("'{}' exited with {}", command.join(" "), status),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 包含空白或引号时加上双引号
+fn quote(v: &str) -> String {
+    if v.is_empty() || v.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        v.to_string()
+    }
+}
+
+fn print_usage(program: &str) {
+    println!(
+        "Usage: {} setup [--accept-defaults] [-k <token>] [-n <name>] [-s <server>]",
+        program
+    );
+    println!();
+    println!("首次使用的配置向导,检查运行环境,在数据目录中生成配置文件,可选安装开机启动的系统服务");
+    println!("Options:");
+    println!("  -k, --token <token> 组网标识,不指定时询问,默认随机生成");
+    println!("  -n, --name <name>   设备名称,默认使用主机名");
+    println!("  -s, --server <server> 服务器地址,默认{}", DEFAULT_SERVER);
+    println!("  --accept-defaults   不询问,未指定的项使用默认值,适合脚本");
+    println!("  --overwrite         覆盖数据目录中已有的配置文件,否则保留已有配置");
+    println!("  --install-service   安装系统服务(systemd/launchd/windows计划任务),需要管理员权限");
+    println!("  --no-service        不安装系统服务");
+    println!("  --data-dir <path>   数据目录,和启动vnt时相同");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::path::PathBuf;
+
+    use crate::config::settings::Settings;
+    use crate::setup::{config_text, run, Answers, Env, Prompt, ServiceFile, CONFIG_NAME};
+
+    /// 按顺序给出回答，记录输出
+    struct Scripted {
+        answers: VecDeque<String>,
+        lines: Vec<String>,
+    }
+
+    impl Prompt for Scripted {
+        fn say(&mut self, line: &str) {
+            self.lines.push(line.to_string());
+        }
+        fn input(&mut self, question: &str, default: &str) -> io::Result<String> {
+            self.lines.push(format!("? {}", question));
+            let answer = self.answers.pop_front().unwrap_or_default();
+            Ok(if answer.is_empty() {
+                default.to_string()
+            } else {
+                answer
+            })
+        }
+        fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+            self.lines.push(format!("? {}", question));
+            Ok(match self.answers.pop_front().as_deref() {
+                Some("y") => true,
+                Some("n") => false,
+                _ => default,
+            })
+        }
+    }
+
+    fn scripted(answers: &[&str]) -> Scripted {
+        Scripted {
+            answers: answers.iter().map(|v| v.to_string()).collect(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn installed(_file: &ServiceFile) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn env_in(name: &str) -> Env {
+        let dir = std::env::temp_dir().join(format!("vnt-setup-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Env {
+            dir,
+            writable: true,
+            elevated: true,
+            exe: PathBuf::from("/usr/bin/vnt-cli"),
+            install: installed,
+        }
+    }
+
+    #[test]
+    fn test_fresh() {
+        let env = env_in("fresh");
+        // token、名称、服务器、不安装服务
+        let mut prompt = scripted(&["abc", "pc", "127.0.0.1:29872", "n"]);
+        let outcome = run(&mut prompt, &env, Answers::default()).unwrap();
+        assert_eq!(outcome.token, "abc");
+        assert!(!outcome.service_started);
+        let settings = Settings::load(&outcome.config.to_string_lossy()).unwrap();
+        assert_eq!(settings.name.as_deref(), Some("pc"));
+        assert_eq!(settings.server.as_deref(), Some("127.0.0.1:29872"));
+        assert!(prompt.lines.iter().any(|v| v == "[ok] server address"));
+        assert!(prompt
+            .lines
+            .iter()
+            .any(|v| v.contains("--accept-defaults --token abc --server 127.0.0.1:29872")));
+        let _ = std::fs::remove_dir_all(&env.dir);
+
+        // 命令行给出全部回答时不再询问
+        let env = env_in("defaults");
+        let answers = Answers {
+            token: Some("xyz".to_string()),
+            name: Some("pc2".to_string()),
+            server: Some("127.0.0.1:1".to_string()),
+            service: Some(false),
+            ..Default::default()
+        };
+        let mut prompt = scripted(&[]);
+        let outcome = run(&mut prompt, &env, answers).unwrap();
+        assert_eq!(outcome.token, "xyz");
+        assert!(prompt.lines.iter().all(|v| !v.starts_with("? ")));
+        let _ = std::fs::remove_dir_all(&env.dir);
+    }
+
+    #[test]
+    fn test_existing() {
+        let env = env_in("existing");
+        let path = env.dir.join(CONFIG_NAME);
+        let text = config_text("old", "", "127.0.0.1:29872");
+        std::fs::write(&path, &text).unwrap();
+
+        // 拒绝覆盖时保留原有配置，安装服务
+        let mut prompt = scripted(&["n", "y"]);
+        let outcome = run(&mut prompt, &env, Answers::default()).unwrap();
+        assert_eq!(outcome.token, "old");
+        assert!(outcome.service_started);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+
+        // 默认不覆盖
+        let answers = Answers {
+            token: Some("new".to_string()),
+            service: Some(false),
+            ..Default::default()
+        };
+        let outcome = run(&mut scripted(&[]), &env, answers.clone()).unwrap();
+        assert_eq!(outcome.token, "old");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+
+        // 确认后覆盖
+        let outcome = run(
+            &mut scripted(&[]),
+            &env,
+            Answers {
+                overwrite: true,
+                server: Some("127.0.0.1:2".to_string()),
+                name: Some(String::new()),
+                ..answers
+            },
+        )
+        .unwrap();
+        assert_eq!(outcome.token, "new");
+        let settings = Settings::load(&path.to_string_lossy()).unwrap();
+        assert_eq!(settings.token.as_deref(), Some("new"));
+        let _ = std::fs::remove_dir_all(&env.dir);
+    }
+}
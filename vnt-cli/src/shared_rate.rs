@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use vnt::core::Vnt;
+
+/// 后台命令中的租约请求，格式: shared-rate lease <id>，应答: grant <字节/秒> <有效期ms>
+pub const LEASE_COMMAND: &str = "shared-rate lease ";
+/// 租约有效期，持有者每秒续租一次
+const LEASE_TTL: Duration = Duration::from_secs(3);
+const RENEW_INTERVAL: Duration = Duration::from_secs(1);
+/// 等待协调者应答的时间
+const REPLY_TIMEOUT: Duration = Duration::from_millis(300);
+/// 超过这个时间没有刷新的登记文件属于已经退出的进程
+const STALE: Duration = Duration::from_secs(10);
+
+/// 同一台机器上的多个进程共享一个总带宽
+///
+/// 每个进程在共享目录下登记自己的后台命令端口，启动最早的进程作为协调者，
+/// 其他进程每秒通过后台命令向协调者续租，协调者把总带宽平分给租约未过期的进程。
+/// 协调者无响应时依次尝试下一个进程，轮到自己就由自己协调。
+/// 选举期间继续使用上一次分到的速率，不会停止转发
+pub struct SharedRate {
+    dir: PathBuf,
+    id: u32,
+    // 启动时间，unix毫秒
+    start: u64,
+    // 总带宽，字节/秒
+    budget: u64,
+    port: Mutex<Option<u16>>,
+    // 当前使用的速率
+    rate: AtomicU64,
+    // 作为协调者时发出的租约
+    leases: Mutex<HashMap<u32, Instant>>,
+}
+
+impl SharedRate {
+    pub fn new(dir: PathBuf, id: u32, start: u64, budget: u64) -> Self {
+        Self {
+            dir,
+            id,
+            start,
+            budget,
+            port: Mutex::new(None),
+            rate: AtomicU64::new(budget),
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+    fn file(&self) -> PathBuf {
+        self.dir.join(format!("{}.peer", self.id))
+    }
+    /// 后台命令端口确定后登记，之后才能参与选举
+    pub fn register(&self, port: u16) -> io::Result<()> {
+        *self.port.lock().unwrap_or_else(|e| e.into_inner()) = Some(port);
+        self.refresh()
+    }
+    pub fn unregister(&self) {
+        let _ = std::fs::remove_file(self.file());
+    }
+    /// 重写登记文件，修改时间用来判断进程是否还在
+    fn refresh(&self) -> io::Result<()> {
+        let port = *self.port.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(port) = port {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.file(), format!("{} {}", self.start, port))?;
+        }
+        Ok(())
+    }
+    /// 登记的进程，按启动时间排序，第一个是协调者
+    fn peers(&self) -> Vec<(u64, u32, u16)> {
+        let mut list = Vec::new();
+        let dir = match std::fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(_) => return list,
+        };
+        for entry in dir.flatten() {
+            let path = entry.path();
+            let id = match path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.strip_suffix(".peer"))
+                .and_then(|v| v.parse::<u32>().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let stale = entry
+                .metadata()
+                .and_then(|v| v.modified())
+                .map(|v| v.elapsed().unwrap_or_default() > STALE)
+                .unwrap_or(true);
+            if stale && id != self.id {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap_or_default();
+            let mut split = text.split_whitespace();
+            if let (Some(Ok(start)), Some(Ok(port))) = (
+                split.next().map(|v| v.parse::<u64>()),
+                split.next().map(|v| v.parse::<u16>()),
+            ) {
+                list.push((start, id, port));
+            }
+        }
+        list.sort();
+        list
+    }
+    /// 作为协调者发出租约，总带宽平分给租约未过期的进程，协调者自己总是算一份
+    fn grant(&self, from: u32, now: Instant) -> u64 {
+        let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        leases.retain(|_, expire| *expire > now);
+        leases.insert(from, now + LEASE_TTL);
+        let count = leases.len() + usize::from(!leases.contains_key(&self.id));
+        (self.budget / count as u64).max(1)
+    }
+    /// 处理其他进程的租约请求
+    pub fn handle(&self, args: &str) -> String {
+        match args.trim().parse::<u32>() {
+            Ok(from) => format!(
+                "grant {} {}",
+                self.grant(from, Instant::now()),
+                LEASE_TTL.as_millis()
+            ),
+            Err(_) => format!("error invalid lease request '{}'", args),
+        }
+    }
+    /// 续租一次，返回应该使用的速率
+    pub fn tick(&self) -> u64 {
+        if let Err(e) = self.refresh() {
+            log::warn!("共享限速登记失败 {:?} {:?}", self.dir, e);
+        }
+        for (_, id, port) in self.peers() {
+            if id == self.id {
+                let rate = self.grant(self.id, Instant::now());
+                self.rate.store(rate, Ordering::Relaxed);
+                return rate;
+            }
+            match request(port, self.id) {
+                Ok(rate) => {
+                    self.rate.store(rate, Ordering::Relaxed);
+                    return rate;
+                }
+                Err(e) => {
+                    log::debug!("共享限速协调者{}无响应 {:?}", id, e);
+                }
+            }
+        }
+        // 没有登记成功，沿用上一次的速率
+        self.rate.load(Ordering::Relaxed)
+    }
+}
+
+fn request(port: u16, id: u32) -> io::Result<u64> {
+    let udp = UdpSocket::bind("127.0.0.1:0")?;
+    udp.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    udp.connect(("127.0.0.1", port))?;
+    udp.send(format!("{}{}", LEASE_COMMAND, id).as_bytes())?;
+    let mut buf = [0u8; 64];
+    let len = udp.recv(&mut buf)?;
+    let reply = String::from_utf8_lossy(&buf[..len]);
+    let mut split = reply.split_whitespace();
+    match (split.next(), split.next().map(|v| v.parse::<u64>())) {
+        (Some("grant"), Some(Ok(rate))) if rate > 0 => Ok(rate),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("lease reply '{}'", reply),
+        )),
+    }
+}
+
+static SHARED: OnceLock<Arc<SharedRate>> = OnceLock::new();
+
+/// 共享目录，同一台机器上的进程都能访问
+fn shared_dir() -> PathBuf {
+    std::env::temp_dir().join("vnt-shared-rate")
+}
+
+/// 启动共享限速，budget为总带宽(字节/秒)
+pub fn start(vnt: Vnt, budget: u64) {
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or(0);
+    let shared = Arc::new(SharedRate::new(
+        shared_dir(),
+        std::process::id(),
+        start,
+        budget,
+    ));
+    if SHARED.set(shared.clone()).is_err() {
+        return;
+    }
+    let result = std::thread::Builder::new()
+        .name("SharedRate".into())
+        .spawn(move || {
+            while !vnt.is_stopped() {
+                let rate = shared.tick();
+                vnt.set_rate_limit(rate);
+                std::thread::sleep(RENEW_INTERVAL);
+            }
+            shared.unregister();
+        });
+    if let Err(e) = result {
+        log::warn!("共享限速启动失败，只使用本地限速 {:?}", e);
+    }
+}
+
+/// 后台命令端口确定后调用
+pub fn register(port: u16) {
+    if let Some(shared) = SHARED.get() {
+        if let Err(e) = shared.register(port) {
+            log::warn!("共享限速登记失败，只使用本地限速 {:?}", e);
+        }
+    }
+}
+
+/// 后台命令收到的租约请求
+pub fn handle_lease(args: &str) -> String {
+    match SHARED.get() {
+        Some(shared) => shared.handle(args),
+        None => "error shared rate limit disabled".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use vnt::channel::rate_limit::RateLimit;
+
+    use super::{SharedRate, LEASE_COMMAND};
+
+    /// 模拟后台命令服务，只处理租约请求
+    fn serve(shared: Arc<SharedRate>) -> (u16, Arc<AtomicBool>) {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let port = udp.local_addr().unwrap().port();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_c = stop.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 256];
+            while !stop_c.load(Ordering::Relaxed) {
+                if let Ok((len, addr)) = udp.recv_from(&mut buf) {
+                    let cmd = std::str::from_utf8(&buf[..len]).unwrap();
+                    let args = cmd.strip_prefix(LEASE_COMMAND).unwrap();
+                    let _ = udp.send_to(shared.handle(args).as_bytes(), addr);
+                }
+            }
+        });
+        (port, stop)
+    }
+
+    #[test]
+    fn test_shared_cap() {
+        let dir = std::env::temp_dir().join(format!("vnt-shared-rate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let budget = 1_000_000;
+        let a = Arc::new(SharedRate::new(dir.clone(), 1, 100, budget));
+        let b = Arc::new(SharedRate::new(dir.clone(), 2, 200, budget));
+        let (port_a, stop_a) = serve(a.clone());
+        let (port_b, stop_b) = serve(b.clone());
+        a.register(port_a).unwrap();
+        b.register(port_b).unwrap();
+        // a启动较早，作为协调者
+        let rate_b = b.tick();
+        let rate_a = a.tick();
+        assert_eq!((rate_a, rate_b), (budget / 2, budget / 2));
+
+        let limit_a = RateLimit::new();
+        let limit_b = RateLimit::new();
+        limit_a.set_rate(rate_a);
+        limit_b.set_rate(rate_b);
+        let start = Instant::now();
+        let mut sent = 0u64;
+        for ms in 0..5000 {
+            let now = start + Duration::from_millis(ms);
+            for limit in [&limit_a, &limit_b] {
+                while limit.take_at(1400, now) {
+                    sent += 1400;
+                }
+            }
+        }
+        // 两个进程各自全速发送，合计不超过总带宽加上各自的突发
+        assert!(sent <= budget * 5 + budget / 10, "{}", sent);
+        assert!(sent >= budget * 5 * 95 / 100, "{}", sent);
+
+        // 协调者退出后b接管，期间一直有可用的速率
+        stop_a.store(true, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(b.tick(), budget);
+        stop_b.store(true, Ordering::Relaxed);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use vnt::util::state_store::StateStore;
+
+/// 暂存的状态每隔这么久统一写入一次，退出时也会写入
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 数据目录下的状态文件统一从这里写入
+pub static STORE: StateStore = StateStore::new();
+
+/// 数据目录下的屏蔽列表
+pub const BLOCK_LIST_FILE: &str = "blocked-peers";
+
+/// 数据目录下的对端路径偏好
+pub const PATH_PREFERENCES_FILE: &str = "path-preferences";
+
+/// 数据目录下的子系统启停设置
+pub const SUBSYSTEMS_FILE: &str = "subsystems";
+
+pub fn start_flush() {
+    let result = std::thread::Builder::new()
+        .name("StateFlush".into())
+        .spawn(|| loop {
+            std::thread::sleep(FLUSH_INTERVAL);
+            STORE.flush();
+        });
+    if let Err(e) = result {
+        log::warn!("状态文件定时写入启动失败 {:?}", e);
+    }
+}
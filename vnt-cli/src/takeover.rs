@@ -0,0 +1,274 @@
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use vnt::core::{Config, Vnt, WarmState};
+
+/// 数据目录下的控制连接
+const SOCKET_NAME: &str = "takeover.sock";
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// 状态的最大长度，路由很多时也远小于这个值
+const MAX_STATE_LEN: usize = 1024 * 1024;
+
+/// 已经拿到状态，等待新进程启动后通知旧进程转发
+static PENDING: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+fn socket_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(SOCKET_NAME))
+}
+
+/// 向同一数据目录下正在运行的进程请求接管，没有可接管的进程时返回None
+///
+/// 拿到的状态和热重启相同，网卡描述符通过SCM_RIGHTS传递，
+/// 新进程用SO_REUSEPORT绑定同样的端口，不需要重新打洞
+pub fn request(config: &Config) -> anyhow::Result<Option<WarmState>> {
+    let stream = match UnixStream::connect(socket_path()?) {
+        Ok(stream) => stream,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None);
+        }
+        Err(e) => Err(e)?,
+    };
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    (&stream).write_all(b"takeover\n")?;
+    let mut buf = [0u8; 256];
+    let (len, fd) = recv_with_fd(&stream, &mut buf)?;
+    // 描述符先接收，后面的读取失败也不会泄漏到旧进程
+    let mut reader = BufReader::new(Cursor::new(buf[..len].to_vec()).chain(&stream));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let len = match line.trim().strip_prefix("ok ") {
+        Some(len) => len.parse::<usize>()?,
+        None => Err(anyhow::anyhow!("{}", line.trim()))?,
+    };
+    if len > MAX_STATE_LEN {
+        Err(anyhow::anyhow!("state too large {}", len))?;
+    }
+    let mut text = vec![0u8; len];
+    reader.read_exact(&mut text)?;
+    let text = String::from_utf8(text)?;
+    let mut state = WarmState::decode(&text, &config.token, config.password.as_deref())?;
+    state.tun_fd = fd;
+    log::info!(
+        "接管,收到状态 ports={:?},routes={},tun_fd={:?}",
+        state.ports,
+        state.routes.len(),
+        state.tun_fd
+    );
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(stream);
+    Ok(Some(state))
+}
+
+/// 新进程启动后通知旧进程转发并退出，然后等待下一次接管
+pub fn start(vnt: &Vnt) {
+    let pending = PENDING.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(stream) = pending {
+        match release(stream, vnt) {
+            Ok(()) => println!(
+                "Took over the previous instance, it exits in {:?}",
+                vnt::channel::handover::HANDOVER_WINDOW
+            ),
+            Err(e) => println!("takeover release failed: {:?}", e),
+        }
+    }
+    if let Err(e) = serve(vnt.clone()) {
+        log::warn!("接管监听失败 {:?}", e);
+    }
+}
+
+fn release(stream: UnixStream, vnt: &Vnt) -> anyhow::Result<()> {
+    let (addr, token) = vnt
+        .handover_endpoint()
+        .ok_or(anyhow::anyhow!("handover not enabled"))?;
+    (&stream).write_all(format!("release {} {}\n", addr, token).as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    if line.trim() != "ok" {
+        Err(anyhow::anyhow!("{}", line.trim()))?;
+    }
+    Ok(())
+}
+
+fn serve(vnt: Vnt) -> io::Result<()> {
+    let path = socket_path()?;
+    // 上一个进程留下的，或者正在退出的旧进程的
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    std::thread::Builder::new()
+        .name("takeover".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if vnt.is_stopped() {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("接管连接 {:?}", e);
+                        continue;
+                    }
+                };
+                match hand_over(&stream, &vnt) {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => log::warn!("接管失败 {:?}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// 交给新进程，成功时返回true
+fn hand_over(stream: &UnixStream, vnt: &Vnt) -> anyhow::Result<bool> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim() != "takeover" {
+        Err(anyhow::anyhow!("unknown request {:?}", line.trim()))?;
+    }
+    let state = match vnt.warm_state() {
+        Ok(state) => state,
+        Err(e) => {
+            let mut writer = stream;
+            writer.write_all(format!("error {}\n", e).as_bytes())?;
+            return Ok(false);
+        }
+    };
+    let config = vnt.config();
+    let text = state.encode(&config.token, config.password.as_deref());
+    send_with_fd(
+        stream,
+        format!("ok {}\n", text.len()).as_bytes(),
+        state.tun_fd,
+    )?;
+    let mut writer = stream;
+    writer.write_all(text.as_bytes())?;
+    line.clear();
+    reader.read_line(&mut line)?;
+    let mut split = line.split_whitespace();
+    let (addr, token) = match (split.next(), split.next(), split.next()) {
+        (Some("release"), Some(addr), Some(token)) => {
+            (addr.parse::<SocketAddr>()?, token.parse::<u64>()?)
+        }
+        // 新进程启动失败，继续运行
+        _ => Err(anyhow::anyhow!("takeover aborted {:?}", line.trim()))?,
+    };
+    vnt.hand_over(addr, token)?;
+    writer.write_all(b"ok\n")?;
+    Ok(true)
+}
+
+fn send_with_fd(stream: &UnixStream, data: &[u8], fd: Option<RawFd>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // 按cmsghdr对齐
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        unsafe {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+    let len = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut writer = stream;
+    writer.write_all(&data[len as usize..])
+}
+
+fn recv_with_fd(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // 不设置CLOEXEC，之后热重启时还要继承给新的可执行文件
+    let len = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                fd = Some(std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const RawFd
+                ));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((len as usize, fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+
+    use super::{recv_with_fd, send_with_fd};
+
+    #[test]
+    fn test_pass_fd() {
+        let path = std::env::temp_dir().join(format!("vnt-takeover-{}", std::process::id()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"tun").unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        send_with_fd(&a, b"ok 3\n", Some(file.as_raw_fd())).unwrap();
+        send_with_fd(&a, b"abc", None).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, fd) = recv_with_fd(&b, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ok 3\n");
+        // 收到的是同一个文件的新描述符
+        let fd = fd.unwrap();
+        assert_ne!(fd, file.as_raw_fd());
+        let mut received = unsafe { std::fs::File::from_raw_fd(fd) };
+        received.seek(SeekFrom::Start(0)).unwrap();
+        let mut text = String::new();
+        received.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "tun");
+        let (len, fd) = recv_with_fd(&b, &mut buf).unwrap();
+        assert_eq!((&buf[..len], fd), (&b"abc"[..], None));
+        let _ = std::fs::remove_file(&path);
+    }
+}
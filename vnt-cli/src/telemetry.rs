@@ -0,0 +1,408 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use vnt::channel::p2p_estimate::{PairRecord, PUNCH_TIME_BUCKETS};
+use vnt::channel::punch::NatType;
+use vnt::channel::punch_sync::SKEW_BUCKETS;
+use vnt::core::Vnt;
+
+/// 设置了这个环境变量时不上报，优先于命令行参数
+pub const OFF_ENV: &str = "VNT_NO_TELEMETRY";
+const SCHEMA: u32 = 2;
+/// 次数少于这个数的nat组合不上报，避免少见的组合暴露网络结构
+const MIN_ATTEMPTS: u32 = 10;
+/// 成功率先加上这个范围内的随机数，再取整到10%
+const NOISE_PERCENT: i32 = 5;
+/// 启动一段时间后第一次上报，之后每天一次
+const FIRST_DELAY: Duration = Duration::from_secs(3600);
+const INTERVAL: Duration = Duration::from_secs(24 * 3600);
+const RETRIES: u32 = 3;
+const CONSENT_FILE: &str = "telemetry-consent";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 上报的内容，只有聚合后的数据，没有地址、名称、token和精确的设备数
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Payload {
+    pub schema: u32,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub nat_pairs: Vec<NatPair>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NatPair {
+    pub local: String,
+    pub remote: String,
+    // 打洞次数的数量级，如"10-99"
+    pub attempts: String,
+    pub success_percent: u32,
+    // 成功用时各档所占的比例
+    pub timings: Vec<TimingShare>,
+    // 双方开始打洞的时间差各档所占的比例
+    pub skews: Vec<TimingShare>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TimingShare {
+    // 不超过这个时长，如"500ms"，最后一档为"longer"
+    pub within: String,
+    pub percent: u32,
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// 关闭后上报线程退出，不再有待执行的上报
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+fn off_by_env() -> bool {
+    std::env::var_os(OFF_ENV).is_some()
+}
+
+/// 只允许https
+pub fn check_url(url: &str) -> Result<(), String> {
+    // 默认不编译上报功能，需要'--features telemetry'
+    if cfg!(not(feature = "telemetry")) {
+        return Err("built without the telemetry feature".to_string());
+    }
+    check_https(url)
+}
+
+fn check_https(url: &str) -> Result<(), String> {
+    match url.strip_prefix("https://") {
+        Some(rest) if !rest.is_empty() => Ok(()),
+        _ => Err(format!("'{}' must be an https url", url)),
+    }
+}
+
+/// 按nat组合聚合，noise返回加到成功率上的随机数
+pub fn payload(records: Vec<PairRecord>, mut noise: impl FnMut() -> i32) -> Payload {
+    let mut nat_pairs: Vec<NatPair> = records
+        .into_iter()
+        .filter(|record| record.stats.attempts >= MIN_ATTEMPTS)
+        .map(|record| {
+            let stats = record.stats;
+            let percent = (stats.successes.min(stats.attempts) * 100 / stats.attempts) as i32;
+            NatPair {
+                local: nat_name(record.local).to_string(),
+                remote: nat_name(record.remote).to_string(),
+                attempts: magnitude(stats.attempts).to_string(),
+                success_percent: round10(percent + noise()),
+                timings: timing_shares(&record.timings, PUNCH_TIME_BUCKETS),
+                skews: timing_shares(&record.skews, SKEW_BUCKETS),
+            }
+        })
+        .collect();
+    nat_pairs.sort_by(|a, b| (&a.local, &a.remote).cmp(&(&b.local, &b.remote)));
+    Payload {
+        schema: SCHEMA,
+        version: vnt::VNT_VERSION.to_string(),
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        nat_pairs,
+    }
+}
+
+fn nat_name(nat_type: NatType) -> &'static str {
+    match nat_type {
+        NatType::Cone => "cone",
+        NatType::Symmetric => "symmetric",
+    }
+}
+
+fn magnitude(count: u32) -> &'static str {
+    match count {
+        0..=9 => "1-9",
+        10..=99 => "10-99",
+        100..=999 => "100-999",
+        _ => "1000+",
+    }
+}
+
+fn round10(percent: i32) -> u32 {
+    ((percent.clamp(0, 100) + 5) / 10 * 10) as u32
+}
+
+fn timing_shares(timings: &[u32], buckets: &[u64]) -> Vec<TimingShare> {
+    let total: u32 = timings.iter().sum();
+    if total == 0 {
+        return Vec::new();
+    }
+    timings
+        .iter()
+        .enumerate()
+        .map(|(index, count)| TimingShare {
+            within: match buckets.get(index) {
+                Some(ms) => format!("{}ms", ms),
+                None => "longer".to_string(),
+            },
+            percent: round10((*count as u64 * 100 / total as u64) as i32),
+        })
+        .collect()
+}
+
+/// 当前会上报的内容
+pub fn render(vnt: &Vnt) -> String {
+    let mut rng = rand::thread_rng();
+    let payload = payload(vnt.punch_records(), || {
+        rng.gen_range(-NOISE_PERCENT..=NOISE_PERCENT)
+    });
+    serde_yaml::to_string(&payload).unwrap_or_else(|e| format!("error {:?}", e))
+}
+
+/// 'telemetry [show|off]'
+pub fn command(vnt: &Vnt, args: &str) -> String {
+    match args.trim() {
+        "" => format!("telemetry {}", if enabled() { "on" } else { "off" }),
+        "show" => render(vnt),
+        "off" => {
+            disable();
+            "telemetry off".to_string()
+        }
+        args => format!("'telemetry {}' invalid, available: show,off", args),
+    }
+}
+
+/// 开启匿名统计，第一次开启时展示上报的内容
+pub fn start(vnt: Vnt, url: String) {
+    if off_by_env() {
+        println!("{} is set, anonymous stats are not shared", OFF_ENV);
+        return;
+    }
+    show_on_first_enable(&vnt, &url);
+    ENABLED.store(true, Ordering::Relaxed);
+    let stopped = {
+        let vnt = vnt.clone();
+        move || vnt.is_stopped()
+    };
+    if let Err(e) = schedule(FIRST_DELAY, INTERVAL, stopped, move || {
+        submit(&url, &render(&vnt))
+    }) {
+        log::warn!("匿名统计启动失败 {:?}", e);
+        disable();
+    }
+}
+
+fn show_on_first_enable(vnt: &Vnt, url: &str) {
+    let path = match crate::app_home() {
+        Ok(home) => home.join(CONSENT_FILE),
+        Err(_) => return,
+    };
+    if path.exists() {
+        return;
+    }
+    println!(
+        "Anonymous punch statistics will be sent to {} once a day, 'telemetry off' stops it, {} disables it entirely. Current payload ('telemetry show' prints it again):",
+        url, OFF_ENV
+    );
+    print!("{}", render(vnt));
+    if let Err(e) = std::fs::write(&path, url) {
+        log::warn!("{:?} {:?}", path, e);
+    }
+}
+
+/// 按间隔执行f，关闭或者停止后线程退出
+fn schedule<S, F>(
+    first: Duration,
+    interval: Duration,
+    stopped: S,
+    mut f: F,
+) -> io::Result<JoinHandle<()>>
+where
+    S: Fn() -> bool + Send + 'static,
+    F: FnMut() + Send + 'static,
+{
+    let tick = interval.min(Duration::from_secs(1));
+    std::thread::Builder::new()
+        .name("telemetry".into())
+        .spawn(move || {
+            let mut next = Instant::now() + first;
+            while enabled() && !stopped() {
+                if Instant::now() >= next {
+                    f();
+                    next = Instant::now() + interval;
+                }
+                std::thread::sleep(tick);
+            }
+        })
+}
+
+fn submit(url: &str, body: &str) {
+    for attempt in 0..RETRIES {
+        if !enabled() {
+            return;
+        }
+        match post(url, body) {
+            Ok(_) => {
+                log::info!("匿名统计已上报 {}", url);
+                return;
+            }
+            Err(e) => {
+                log::warn!("匿名统计上报失败 第{}次 {} {:?}", attempt + 1, url, e);
+                std::thread::sleep(Duration::from_secs(10 << attempt));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "telemetry")]
+fn post(url: &str, body: &str) -> io::Result<()> {
+    ureq::post(url)
+        .timeout(Duration::from_secs(10))
+        .set("Content-Type", "application/yaml")
+        .send_string(body)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(not(feature = "telemetry"))]
+fn post(_url: &str, _body: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "built without the telemetry feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_yaml::Value;
+    use vnt::channel::p2p_estimate::{PairRecord, PairStats};
+    use vnt::channel::punch::NatType;
+
+    use super::{check_https, disable, payload, schedule, ENABLED};
+
+    fn record(local: NatType, remote: NatType, attempts: u32, successes: u32) -> PairRecord {
+        PairRecord {
+            local,
+            remote,
+            stats: PairStats {
+                attempts,
+                successes,
+            },
+            timings: vec![successes, 0, 0, 0, 0, 0],
+            skews: vec![0, successes, 0, 0, 0, 0, 0],
+        }
+    }
+
+    /// 所有字段名，包括嵌套的
+    fn keys(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Mapping(map) => {
+                for (k, v) in map {
+                    out.push(k.as_str().unwrap().to_string());
+                    keys(v, out);
+                }
+            }
+            Value::Sequence(list) => list.iter().for_each(|v| keys(v, out)),
+            _ => {}
+        }
+    }
+
+    fn strings(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::String(v) => out.push(v.clone()),
+            Value::Mapping(map) => map.values().for_each(|v| strings(v, out)),
+            Value::Sequence(list) => list.iter().for_each(|v| strings(v, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_payload_schema() {
+        let records = vec![
+            record(NatType::Symmetric, NatType::Cone, 1234, 617),
+            record(NatType::Cone, NatType::Cone, 57, 50),
+            // 样本太少，不上报
+            record(NatType::Cone, NatType::Symmetric, 3, 1),
+        ];
+        let payload = payload(records, || 3);
+        assert_eq!(payload.nat_pairs.len(), 2);
+        assert_eq!(payload.nat_pairs[0].local, "cone");
+        assert_eq!(payload.nat_pairs[0].attempts, "10-99");
+        // 87% + 3 取整
+        assert_eq!(payload.nat_pairs[0].success_percent, 90);
+        assert_eq!(payload.nat_pairs[1].attempts, "1000+");
+        assert_eq!(payload.nat_pairs[1].timings[0].percent, 100);
+        assert_eq!(payload.nat_pairs[1].skews[1].within, "50ms");
+        assert_eq!(payload.nat_pairs[1].skews[1].percent, 100);
+
+        let value = serde_yaml::to_value(&payload).unwrap();
+        let mut names = Vec::new();
+        keys(&value, &mut names);
+        names.sort();
+        names.dedup();
+        let allowed = [
+            "arch",
+            "attempts",
+            "local",
+            "nat_pairs",
+            "os",
+            "percent",
+            "remote",
+            "schema",
+            "skews",
+            "success_percent",
+            "timings",
+            "version",
+            "within",
+        ];
+        assert_eq!(names, allowed);
+        // 没有能放下地址的字段
+        let mut values = Vec::new();
+        strings(&value, &mut values);
+        for v in values {
+            assert!(v.len() <= 16, "{}", v);
+            assert!(v.parse::<IpAddr>().is_err(), "{}", v);
+            assert!(v.parse::<SocketAddr>().is_err(), "{}", v);
+            assert!(!v.contains('.') || v == vnt::VNT_VERSION, "{}", v);
+        }
+        assert!(check_https("https://stats.example/vnt").is_ok());
+        assert!(check_https("http://stats.example/vnt").is_err());
+        assert!(check_https("https://").is_err());
+        assert_eq!(
+            super::check_url("https://stats.example/vnt").is_ok(),
+            cfg!(feature = "telemetry")
+        );
+    }
+
+    #[test]
+    fn test_disable() {
+        ENABLED.store(true, Ordering::Relaxed);
+        let count = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let count = count.clone();
+            schedule(
+                Duration::ZERO,
+                Duration::from_millis(5),
+                || false,
+                move || {
+                    count.fetch_add(1, Ordering::Relaxed);
+                },
+            )
+            .unwrap()
+        };
+        while count.load(Ordering::Relaxed) < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        disable();
+        // 关闭后线程退出，之后不再执行
+        handle.join().unwrap();
+        let after = count.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::Relaxed), after);
+    }
+}
@@ -0,0 +1,67 @@
+//! 控制台的状态通知和启动信息
+//!
+//! 颜色只在这里加，日志用同一段文字的无样式版本，同一件事只调用一次，
+//! 不再分别写日志和控制台。vnt中已经记录过日志的回调只输出到控制台
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use console::style;
+
+// 控制台的quiet开关，打开时不输出异步的状态通知，命令输出和错误不受影响
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn green(str: String) -> impl Display {
+    style(str).green()
+}
+
+pub fn yellow(str: String) -> impl Display {
+    style(str).yellow()
+}
+
+pub fn red(str: String) -> impl Display {
+    style(str).red()
+}
+
+/// 输出一行状态通知，不写日志
+pub fn notify(line: impl Display) {
+    if !quiet() {
+        println!("{}", line);
+    }
+}
+
+/// 写info日志并输出，不受quiet影响
+pub fn info(msg: impl Display) {
+    let msg = msg.to_string();
+    log::info!("{}", msg);
+    println!("{}", msg);
+}
+
+/// 写info日志并输出青色的状态通知
+pub fn event(msg: impl Display) {
+    let msg = msg.to_string();
+    log::info!("{}", msg);
+    notify(style(msg).cyan());
+}
+
+/// 写warn日志并输出黄色的提示，不受quiet影响
+pub fn warn(msg: impl Display) {
+    let msg = msg.to_string();
+    log::warn!("{}", msg);
+    println!("{}", yellow(msg));
+}
+
+/// 写error日志并输出红色的错误，不受quiet影响
+pub fn error(msg: impl Display) {
+    let msg = msg.to_string();
+    log::error!("{}", msg);
+    println!("{}", red(msg));
+}
@@ -0,0 +1,164 @@
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use vnt::core::{Config, Vnt, WarmState};
+use vnt::util::state_store::{self, StateFile};
+
+/// 编码后的热重启状态，版本0是没有文件头的旧文件，不迁移，按冷启动处理
+struct WarmFile(String);
+
+impl StateFile for WarmFile {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+    fn decode(version: u32, body: &[u8]) -> Option<Self> {
+        if version == 0 {
+            return None;
+        }
+        String::from_utf8(body.to_vec()).ok().map(WarmFile)
+    }
+}
+
+/// 启动参数，表示由热重启拉起
+pub const WARM_RESTART_ARG: &str = "--warm-restart";
+
+fn state_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join("warm-state"))
+}
+
+fn token_path() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join("restart-token"))
+}
+
+/// 生成热重启的口令，命令端口没有认证，只有能读取数据目录的用户才能发起热重启
+pub fn create_token() -> io::Result<()> {
+    let token: String = (0..16)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect();
+    write_private(&token_path()?, token.as_bytes())
+}
+
+pub fn read_token() -> io::Result<String> {
+    Ok(std::fs::read_to_string(token_path()?)?.trim().to_string())
+}
+
+/// 校验命令端口收到的口令
+pub fn check_token(token: &str) -> bool {
+    match read_token() {
+        Ok(expected) => !expected.is_empty() && equal(expected.as_bytes(), token.trim().as_bytes()),
+        Err(e) => {
+            log::warn!("读取热重启口令失败 {:?}", e);
+            false
+        }
+    }
+}
+
+/// 比较时间和内容无关
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 只有当前用户可以读写，先写临时文件再改名
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)
+}
+
+/// 保存状态，之后调用exec替换当前进程
+pub fn prepare(vnt: &Vnt) -> anyhow::Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = vnt;
+        Err(anyhow::anyhow!("warm restart is only supported on linux"))
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if crate::root_check::effective_uid() != 0 {
+            // 新进程需要重新配置网卡
+            Err(anyhow::anyhow!(
+                "warm restart is unavailable after dropping privileges"
+            ))?;
+        }
+        let state = vnt.warm_state()?;
+        let config = vnt.config();
+        let text = state.encode(&config.token, config.password.as_deref());
+        // 包含服务端会话的密钥
+        write_private(&state_path()?, &state_store::encode(&WarmFile(text)))?;
+        log::info!(
+            "热重启,保存状态 ports={:?},routes={},session={}",
+            state.ports,
+            state.routes.len(),
+            state.session.is_some()
+        );
+        Ok(())
+    }
+}
+
+/// 用新的可执行文件替换当前进程，tun描述符会被继承，成功时不会返回
+pub fn exec() -> io::Error {
+    #[cfg(not(target_os = "linux"))]
+    {
+        io::Error::from(io::ErrorKind::Unsupported)
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::CommandExt;
+        let exe = match std::env::current_exe() {
+            Ok(exe) => exe,
+            Err(e) => return e,
+        };
+        let mut args: Vec<String> = std::env::args()
+            .skip(1)
+            .filter(|v| v != WARM_RESTART_ARG)
+            .collect();
+        args.push(WARM_RESTART_ARG.to_string());
+        let e = std::process::Command::new(exe).args(args).exec();
+        // 没有替换成功，删除状态文件避免下次误用
+        if let Ok(path) = state_path() {
+            let _ = std::fs::remove_file(path);
+        }
+        e
+    }
+}
+
+/// 读取上一个进程保存的状态，校验不通过时返回None，按冷启动处理
+pub fn load(config: &Config) -> Option<WarmState> {
+    let path = match state_path() {
+        Ok(path) => path,
+        Err(e) => {
+            log::warn!("热重启状态文件 {:?}", e);
+            return None;
+        }
+    };
+    let text = state_store::load::<WarmFile>(&path);
+    // 只能使用一次
+    let _ = std::fs::remove_file(&path);
+    let text = text?.0;
+    let state = WarmState::decode(&text, &config.token, config.password.as_deref());
+    match state {
+        Ok(state) => {
+            if state.elapsed() > WarmState::MAX_AGE {
+                log::warn!("热重启状态已过期 {:?}", state.elapsed());
+                return None;
+            }
+            Some(state)
+        }
+        Err(e) => {
+            log::warn!("热重启状态无效,冷启动 {:?}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! 运行vnt-cli，检查失败时的退出码和last_exit.json
+//!
+//! 使用观察者模式，不创建网卡，不需要root权限
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use vnt::protocol::{error_packet, NetPacket, Protocol, MAX_TTL};
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("vnt-exit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(data_dir: &Path, args: &[&str]) -> ExitStatus {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vnt-cli"))
+        .args(["--observer", "--data-dir"])
+        .arg(data_dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if start.elapsed() > Duration::from_secs(30) {
+            let _ = child.kill();
+            panic!("vnt-cli did not exit");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn last_exit(data_dir: &Path) -> serde_json::Value {
+    let text = std::fs::read_to_string(data_dir.join("last_exit.json")).unwrap();
+    serde_json::from_str(&text).unwrap()
+}
+
+/// 对收到的任何包都回复token错误
+fn reject_server(stop: Arc<AtomicBool>) -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let mut packet = NetPacket::new(vec![0u8; 12]).unwrap();
+    packet.set_default_version();
+    packet.set_gateway_flag(true);
+    packet.set_protocol(Protocol::Error);
+    packet.set_transport_protocol_into(error_packet::Protocol::TokenError);
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(Ipv4Addr::new(10, 26, 0, 1));
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 65536];
+        while !stop.load(Ordering::Relaxed) {
+            if let Ok((_, addr)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(packet.buffer(), addr);
+            }
+        }
+    });
+    port
+}
+
+#[test]
+fn test_auth_rejected() {
+    let dir = TempDir::new("auth");
+    let stop = Arc::new(AtomicBool::new(false));
+    let server = format!("127.0.0.1:{}", reject_server(stop.clone()));
+    let status = run(&dir.0, &["-k", "exit-code-test", "-s", &server]);
+    stop.store(true, Ordering::Relaxed);
+    assert_eq!(status.code(), Some(3));
+    let json = last_exit(&dir.0);
+    assert_eq!(json["reason"], "auth_rejected");
+    assert_eq!(json["code"], 3);
+    assert!(json["timestamp"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_port_in_use() {
+    let dir = TempDir::new("port");
+    let busy = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let port = busy.local_addr().unwrap().port().to_string();
+    let status = run(
+        &dir.0,
+        &[
+            "-k",
+            "exit-code-test",
+            "-s",
+            "127.0.0.1:9",
+            "--ports",
+            &port,
+        ],
+    );
+    assert_eq!(status.code(), Some(4));
+    let json = last_exit(&dir.0);
+    assert_eq!(json["reason"], "network_setup_failure");
+    assert_eq!(json["code"], 4);
+    assert!(json["message"].as_str().unwrap().contains(&port));
+}
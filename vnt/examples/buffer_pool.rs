@@ -0,0 +1,81 @@
+//! 多线程模式下读取网卡到处理线程的交接开销
+//!
+//! ```text
+//! cargo run -p vnt --release --example buffer_pool -- 4 2000000
+//! ```
+//! 参数是处理线程数和包数，分别测量每个包新分配缓冲区和复用缓冲区时的吞吐
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vnt::util::buffer_pool::buffer_pool;
+use vnt::util::metrics::Registry;
+
+const BUFFER_SIZE: usize = 1024 * 16;
+const PACKET_LEN: usize = 1400;
+
+fn run(workers: usize, packets: usize, reuse: bool) -> (Duration, u64) {
+    let registry = Registry::new();
+    let alloc = registry.counter("buffer_alloc", &[]);
+    let (buffers, recycler) = buffer_pool(workers * 17 + 1, BUFFER_SIZE, alloc.clone());
+    let mut senders = Vec::with_capacity(workers);
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let (sender, receiver) = sync_channel::<(Vec<u8>, usize)>(16);
+        let recycler = recycler.clone();
+        senders.push(sender);
+        handles.push(thread::spawn(move || {
+            let mut sum = 0u64;
+            while let Ok((buf, len)) = receiver.recv() {
+                // 模拟处理线程读取包头
+                sum = sum.wrapping_add(buf[12] as u64 + len as u64);
+                if reuse {
+                    recycler.put(buf);
+                }
+            }
+            sum
+        }));
+    }
+    let start = Instant::now();
+    for i in 0..packets {
+        let mut buf = if reuse {
+            buffers.get()
+        } else {
+            alloc.inc();
+            vec![0; BUFFER_SIZE]
+        };
+        buf[..12].fill(0);
+        buf[12] = i as u8;
+        if senders[i % workers].send((buf, 12 + PACKET_LEN)).is_err() {
+            break;
+        }
+    }
+    drop(senders);
+    for handle in handles {
+        let _ = handle.join();
+    }
+    (start.elapsed(), alloc.get())
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let workers: usize = args.next().and_then(|v| v.parse().ok()).unwrap_or(4);
+    let packets: usize = args
+        .next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2_000_000);
+    for (name, reuse) in [("alloc", false), ("pool", true)] {
+        let (elapsed, alloc) = run(workers.max(1), packets, reuse);
+        let secs = elapsed.as_secs_f64();
+        println!(
+            "{:<6} {} packets in {:.2}s, {:.0} packets/s, {:.0} Mbit/s at {}B, {} allocations",
+            name,
+            packets,
+            secs,
+            packets as f64 / secs,
+            (packets * PACKET_LEN * 8) as f64 / secs / 1_000_000.0,
+            PACKET_LEN,
+            alloc
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! 生成模糊测试的种子语料
+//!
+//! ```text
+//! cargo run -p vnt --example fuzz_corpus --features fuzz -- vnt/fuzz/corpus
+//! cd vnt && cargo +nightly fuzz run dispatch
+//! ```
+//! 每个目标一个目录，和cargo-fuzz默认的语料目录一致
+use std::path::PathBuf;
+
+use vnt::util::fuzz::TARGETS;
+
+fn main() {
+    let dir = PathBuf::from(
+        std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| "fuzz/corpus".to_string()),
+    );
+    for target in TARGETS {
+        let target_dir = dir.join(target.name);
+        std::fs::create_dir_all(&target_dir).expect("create corpus dir");
+        let seeds = (target.seeds)();
+        for (i, seed) in seeds.iter().enumerate() {
+            std::fs::write(target_dir.join(format!("seed-{}", i)), seed).expect("write seed");
+        }
+        println!("{}: {} seeds", target.name, seeds.len());
+    }
+}
@@ -0,0 +1,55 @@
+//! 使用数据包钩子实现简单的端口转发
+//!
+//! 发往 10.26.0.3:80 的tcp/udp包改为发往 10.26.0.3:8080，回包的源端口改回80，
+//! 对本机的应用来说和直接访问80端口一样。
+//!
+//! 实际使用时把钩子设置到配置中再启动:
+//! ```ignore
+//! config.packet_hooks = port_forward_hooks(target, 80, 8080);
+//! let vnt = vnt::core::Vnt::new(config, callback)?;
+//! ```
+use std::net::Ipv4Addr;
+
+use vnt::handle::packet_hook::{PacketHooks, Verdict};
+
+fn port_forward_hooks(target: Ipv4Addr, from: u16, to: u16) -> PacketHooks {
+    let mut hooks = PacketHooks::new();
+    hooks.on_outbound(move |view| {
+        if view.destination() == target && view.destination_port() == Some(from) {
+            view.set_destination_port(to);
+            Verdict::Modify
+        } else {
+            Verdict::Accept
+        }
+    });
+    hooks.on_inbound(move |view| {
+        if view.source() == target && view.source_port() == Some(to) {
+            view.set_source_port(from);
+            Verdict::Modify
+        } else {
+            Verdict::Accept
+        }
+    });
+    hooks
+}
+
+fn main() {
+    let target = Ipv4Addr::new(10, 26, 0, 3);
+    let hooks = port_forward_hooks(target, 80, 8080);
+    // 构造一个本机发往 10.26.0.3:80 的udp包
+    let mut packet = vec![0u8; 28];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&28u16.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&[10, 26, 0, 2]);
+    packet[16..20].copy_from_slice(&target.octets());
+    packet[20..22].copy_from_slice(&40000u16.to_be_bytes());
+    packet[22..24].copy_from_slice(&80u16.to_be_bytes());
+    packet[24..26].copy_from_slice(&8u16.to_be_bytes());
+    assert!(hooks.run_outbound(&mut packet));
+    println!(
+        "destination port: {}",
+        u16::from_be_bytes([packet[22], packet[23]])
+    );
+}
@@ -0,0 +1,59 @@
+//! 回放抓包文件
+//!
+//! ```text
+//! cargo run -p vnt --example replay --features replay -- <file> [--no-timing] [--ip 10.26.0.2/24] [-w <password> [--model aes_gcm] [--token <token>]]
+//! ```
+//! 输出的摘要可以和其他版本的输出做diff
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use vnt::cipher::{Cipher, CipherModel};
+use vnt::handle::replay::{replay, ReplayConfig};
+use vnt::util::capture::CaptureReader;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut file = None;
+    let mut timing = true;
+    let mut ip = "10.26.0.2/24".to_string();
+    let mut password = None;
+    let mut model = "aes_gcm".to_string();
+    let mut token = None;
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--no-timing" => timing = false,
+            "--ip" => ip = iter.next().expect("--ip <ip/prefix>"),
+            "-w" => password = iter.next(),
+            "--model" => model = iter.next().expect("--model <model>"),
+            "--token" => token = iter.next(),
+            _ => file = Some(arg),
+        }
+    }
+    let file = file.expect("usage: replay <file> [--no-timing]");
+    let (ip, prefix) = ip.split_once('/').unwrap_or((&ip, "24"));
+    let virtual_ip = Ipv4Addr::from_str(ip).expect("ip");
+    let prefix: u32 = prefix.parse().expect("prefix");
+    let virtual_netmask = Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+    let network = u32::from(virtual_ip) & u32::from(virtual_netmask);
+    let config = ReplayConfig {
+        virtual_ip,
+        virtual_netmask,
+        virtual_gateway: Ipv4Addr::from(network + 1),
+        client_cipher: Cipher::new_password(
+            CipherModel::from_str(&model).expect("model"),
+            password,
+            token,
+        ),
+        timing,
+    };
+    let reader = CaptureReader::new(std::io::BufReader::new(
+        std::fs::File::open(file).expect("open"),
+    ))
+    .expect("read");
+    let summary = replay(reader, &config).expect("replay");
+    print!("{}", summary);
+    if !summary.violations.is_empty() {
+        std::process::exit(1);
+    }
+}
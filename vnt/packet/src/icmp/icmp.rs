@@ -100,7 +100,7 @@ impl<B: AsRef<[u8]>> IcmpPacket<B> {
                 Ok(d) => Description::Ip(d),
                 Err(_) => Description::Other(self.payload()),
             },
-            Kind::TimestampRequest | Kind::TimestampReply => {
+            Kind::TimestampRequest | Kind::TimestampReply if self.payload().len() >= 12 => {
                 let mut buffer = Cursor::new(self.payload());
 
                 Description::Timestamp(
@@ -280,10 +280,10 @@ impl<B: AsRef<[u8]>> IgmpV3QueryPacket<B> {
         }
     }
     pub fn source_address(&self, index: u16) -> Option<Ipv4Addr> {
-        if self.source_number() >= index {
+        if index >= self.source_number() {
             None
         } else {
-            let start = (12 + index * 4) as usize;
+            let start = 12 + index as usize * 4;
             let end = start + 4;
             let buf = self.buffer.as_ref();
             let len = buf.len();
@@ -425,10 +425,10 @@ impl<B: AsRef<[u8]>> IgmpV3RecordPacket<B> {
         }
     }
     pub fn source_address(&self, index: u16) -> Option<Ipv4Addr> {
-        if self.source_number() >= index {
+        if index >= self.source_number() {
             None
         } else {
-            let start = (8 + index * 4) as usize;
+            let start = 8 + index as usize * 4;
             let end = start + 4;
             if end > self.buffer.as_ref().len() {
                 return None;
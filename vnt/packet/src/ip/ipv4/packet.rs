@@ -44,7 +44,10 @@ impl<B: AsRef<[u8]>> IpV4Packet<B> {
             Err(io::Error::new(io::ErrorKind::InvalidData, "not ipv4"))?;
         }
         let packet = Self::unchecked(buffer);
-        if packet.buffer.as_ref().len() < packet.header_len() as usize * 4 {
+        // 头部长度小于5时固定部分会和负载重叠
+        if packet.header_len() < 5
+            || packet.buffer.as_ref().len() < packet.header_len() as usize * 4
+        {
             Err(io::Error::new(io::ErrorKind::InvalidData, "head_len err"))?;
         }
         Ok(packet)
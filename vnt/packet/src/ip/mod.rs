@@ -9,8 +9,8 @@ pub enum IpPacket<B> {
 
 impl<B: AsRef<[u8]>> IpPacket<B> {
     pub fn new(buffer: B) -> io::Result<Self> {
-        match buffer.as_ref()[0] >> 4 {
-            4 => Ok(IpPacket::V4(IpV4Packet::new(buffer)?)),
+        match buffer.as_ref().first().map(|v| v >> 4) {
+            Some(4) => Ok(IpPacket::V4(IpV4Packet::new(buffer)?)),
             _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
         }
     }
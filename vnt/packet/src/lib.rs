@@ -78,7 +78,8 @@ pub fn cal_checksum(buffer: &[u8]) -> u16 {
     }
     if length & 1 == 1 {
         //奇数,说明还有一位,不足的补0
-        sum += u32c(buffer.read_u8().unwrap(), 0);
+        //最后读取u16失败时cursor已经移到末尾,直接取最后一个字节
+        sum += u32c(buffer.get_ref()[length - 1], 0);
     }
     while sum >> 16 != 0 {
         sum = (sum & 0xffff) + (sum >> 16);
@@ -121,7 +122,7 @@ pub fn ipv4_cal_checksum(
     }
     if length & 1 == 1 {
         //奇数,说明还有一位
-        sum += u32c(buffer.read_u8().unwrap(), 0);
+        sum += u32c(buffer.get_ref()[length - 1], 0);
     }
     while sum >> 16 != 0 {
         sum = (sum & 0xffff) + (sum >> 16);
@@ -143,4 +144,16 @@ mod tests {
         let sum = cal_checksum(&[255, 255]);
         println!("{:?}", sum);
     }
+
+    #[test]
+    fn test_odd_length() {
+        assert_eq!(cal_checksum(&[0x12, 0x34, 0x56]), !0x6834);
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let even = ipv4_cal_checksum(&[0x12, 0x34, 0x56, 0], &ip, &ip, 17);
+        // 长度参与计算，补0后只差长度
+        assert_eq!(
+            ipv4_cal_checksum(&[0x12, 0x34, 0x56], &ip, &ip, 17),
+            even + 1
+        );
+    }
 }
@@ -69,7 +69,9 @@ impl<B: AsRef<[u8]>> TcpPacket<B> {
             Err(io::Error::from(io::ErrorKind::InvalidData))?;
         }
 
-        if packet.buffer.as_ref().len() < packet.data_offset() as usize * 4 {
+        if packet.data_offset() < 5
+            || packet.buffer.as_ref().len() < packet.data_offset() as usize * 4
+        {
             Err(io::Error::from(io::ErrorKind::InvalidData))?;
         }
 
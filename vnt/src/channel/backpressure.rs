@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 连续多少次发送都因为出口已满失败才暂停读取网卡
+const THRESHOLD: u32 = 8;
+const MIN_PAUSE: Duration = Duration::from_millis(1);
+const MAX_PAUSE: Duration = Duration::from_millis(5);
+
+/// 网卡读取的背压
+///
+/// 出口已满时继续读取网卡只能丢包，暂停读取能让网卡队列积压，由系统的tcp拥塞控制让本地程序降速。
+/// 任何一次发送成功都会清零，只有一个对端拥塞而其他对端还能发送时不会暂停
+pub struct Backpressure {
+    enabled: AtomicBool,
+    // 连续因出口已满而失败的发送次数
+    streak: AtomicU32,
+    pauses: Counter,
+    pause_us: Counter,
+}
+
+impl Backpressure {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+            pauses: registry.counter("tun_backpressure_pauses", &[]),
+            pause_us: registry.counter("tun_backpressure_pause_us", &[]),
+        }
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+    /// 数据包已经发出(直连或中继)
+    #[inline]
+    pub fn sent(&self) {
+        // 先读再写，避免多个线程反复写同一个缓存行
+        if self.streak.load(Ordering::Relaxed) != 0 {
+            self.streak.store(0, Ordering::Relaxed);
+        }
+    }
+    /// 直连和中继都因为出口已满发送失败
+    #[inline]
+    pub fn blocked(&self) {
+        self.streak.fetch_add(1, Ordering::Relaxed);
+    }
+    /// 读取网卡前调用，返回需要暂停的时间
+    pub fn pause(&self) -> Option<Duration> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let streak = self.streak.load(Ordering::Relaxed);
+        if streak < THRESHOLD {
+            return None;
+        }
+        // 持续拥塞时逐渐加长，但不超过上限，暂停结束后总会再读一个包试探
+        let pause = (MIN_PAUSE * (streak / THRESHOLD)).min(MAX_PAUSE);
+        self.pauses.inc();
+        self.pause_us.add(pause.as_micros() as u64);
+        Some(pause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::util::metrics::Registry;
+
+    use super::{Backpressure, MAX_PAUSE, MIN_PAUSE, THRESHOLD};
+
+    #[test]
+    fn test_global_exhausted() {
+        let registry = Registry::new();
+        let backpressure = Backpressure::new(&registry);
+        for _ in 0..THRESHOLD - 1 {
+            backpressure.blocked();
+        }
+        assert_eq!(backpressure.pause(), None);
+        backpressure.blocked();
+        assert_eq!(backpressure.pause(), Some(MIN_PAUSE));
+        for _ in 0..THRESHOLD * 20 {
+            backpressure.blocked();
+        }
+        assert_eq!(backpressure.pause(), Some(MAX_PAUSE));
+        // 出口恢复后立即停止暂停
+        backpressure.sent();
+        assert_eq!(backpressure.pause(), None);
+        assert_eq!(registry.counter("tun_backpressure_pauses", &[]).get(), 2);
+        assert_eq!(
+            registry.counter("tun_backpressure_pause_us", &[]).get(),
+            (MIN_PAUSE + MAX_PAUSE).as_micros() as u64
+        );
+    }
+
+    #[test]
+    fn test_single_peer_congested() {
+        let backpressure = Backpressure::new(&Registry::new());
+        // 一个对端一直失败，其他对端正常发送
+        for _ in 0..THRESHOLD * 10 {
+            backpressure.blocked();
+            backpressure.blocked();
+            backpressure.sent();
+            assert_eq!(backpressure.pause(), None);
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        let backpressure = Backpressure::new(&Registry::new());
+        backpressure.set_enabled(false);
+        for _ in 0..THRESHOLD * 2 {
+            backpressure.blocked();
+        }
+        assert_eq!(backpressure.pause(), None);
+        backpressure.set_enabled(true);
+        assert_eq!(backpressure.pause(), Some(Duration::from_millis(2)));
+    }
+}
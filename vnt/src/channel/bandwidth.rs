@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 一次测量的总流量上限，发送端和接收端都检查
+pub const MAX_VOLUME: usize = 2 * 1024 * 1024;
+/// 最多的轮数，回复需要放在一个包里
+pub const MAX_BURSTS: usize = 8;
+/// 同时进行的测量数量上限，来源地址可以伪造
+const MAX_SESSIONS: usize = 4;
+/// 接收端保留测量记录的时间，结果请求可能重传
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// 一轮至少收到这么多包才参与估计
+const MIN_PACKETS: u16 = 3;
+/// ip、udp和vnt协议头
+const OVERHEAD: usize = 40;
+/// 置信区间使用的z值，约95%
+const Z: f64 = 1.96;
+
+/// 接收端一轮的统计
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BurstSample {
+    pub packets: u16,
+    /// 第一个包到最后一个包的间隔，微秒
+    pub span_us: u32,
+    /// 第一个包之后收到的字节数
+    pub bytes: u32,
+}
+
+impl BurstSample {
+    /// 按包间隔估计的瓶颈速率，kbps，包太少或者间隔为0时无法估计
+    pub fn kbps(&self) -> Option<f64> {
+        if self.packets < MIN_PACKETS || self.span_us == 0 {
+            return None;
+        }
+        Some(self.bytes as f64 * 8000.0 / self.span_us as f64)
+    }
+}
+
+/// 带宽估计，kbps
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BandwidthEstimate {
+    pub kbps: u32,
+    /// 置信区间，只有一轮有效时和估计值相同
+    pub low_kbps: u32,
+    pub high_kbps: u32,
+    /// 参与估计的轮数
+    pub bursts: usize,
+}
+
+impl Display for BandwidthEstimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}~{}, {} bursts)",
+            format_kbps(self.kbps),
+            format_kbps(self.low_kbps),
+            format_kbps(self.high_kbps),
+            self.bursts
+        )
+    }
+}
+
+pub fn format_kbps(kbps: u32) -> String {
+    if kbps >= 1_000_000 {
+        format!("{:.2}Gbps", kbps as f64 / 1_000_000.0)
+    } else if kbps >= 1000 {
+        format!("{:.1}Mbps", kbps as f64 / 1000.0)
+    } else {
+        format!("{}kbps", kbps)
+    }
+}
+
+/// 每轮速率的中位数作为估计值，受其他流量干扰的轮次影响较小；
+/// 区间为均值的95%置信区间，并且包含估计值
+pub fn estimate(samples: &[BurstSample]) -> Option<BandwidthEstimate> {
+    let mut rates: Vec<f64> = samples.iter().filter_map(|v| v.kbps()).collect();
+    if rates.is_empty() {
+        return None;
+    }
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = rates.len();
+    let median = if n % 2 == 1 {
+        rates[n / 2]
+    } else {
+        (rates[n / 2 - 1] + rates[n / 2]) / 2.0
+    };
+    let mean = rates.iter().sum::<f64>() / n as f64;
+    let half = if n > 1 {
+        let variance = rates.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        Z * variance.sqrt() / (n as f64).sqrt()
+    } else {
+        0.0
+    };
+    let low = (mean - half).min(median).max(0.0);
+    let high = (mean + half).max(median);
+    Some(BandwidthEstimate {
+        kbps: median.round() as u32,
+        low_kbps: low.round() as u32,
+        high_kbps: high.round() as u32,
+        bursts: n,
+    })
+}
+
+/// 一次测量的总流量
+pub fn volume(bursts: usize, burst_len: usize, size: usize) -> usize {
+    bursts * burst_len * (size + OVERHEAD)
+}
+
+/// 对端的回复，同意测量时samples为空，结果回复中每轮一项
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BandwidthReply {
+    pub consent: bool,
+    pub samples: Vec<BurstSample>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Arrival {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    packets: u16,
+    bytes: u32,
+}
+
+impl Arrival {
+    fn sample(&self) -> BurstSample {
+        let span = match (self.first, self.last) {
+            (Some(first), Some(last)) => last.saturating_duration_since(first),
+            _ => Duration::ZERO,
+        };
+        BurstSample {
+            packets: self.packets,
+            span_us: span.as_micros().min(u32::MAX as u128) as u32,
+            bytes: self.bytes,
+        }
+    }
+}
+
+struct Session {
+    arrivals: Vec<Arrival>,
+    start: Instant,
+}
+
+/// 带宽测量的两端状态
+///
+/// 接收端记录每轮探测包的到达时间，发起端等待对端的回复
+pub struct BandwidthProbes {
+    // 是否同意对端发起的测量
+    allow: AtomicBool,
+    sessions: Mutex<HashMap<(Ipv4Addr, u32), Session>>,
+    waiting: Mutex<HashMap<u32, SyncSender<BandwidthReply>>>,
+}
+
+impl Default for BandwidthProbes {
+    fn default() -> Self {
+        Self {
+            allow: AtomicBool::new(true),
+            sessions: Default::default(),
+            waiting: Default::default(),
+        }
+    }
+}
+
+impl BandwidthProbes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_allow(&self, allow: bool) {
+        self.allow.store(allow, Ordering::Relaxed);
+    }
+    pub fn allow(&self) -> bool {
+        self.allow.load(Ordering::Relaxed)
+    }
+    /// 对端请求开始测量，返回是否同意，重传的请求同样同意
+    pub fn start(&self, ip: Ipv4Addr, id: u32, bursts: u8, burst_len: u8, size: u16) -> bool {
+        if !self.allow()
+            || bursts == 0
+            || bursts as usize > MAX_BURSTS
+            || burst_len < 2
+            || volume(bursts as usize, burst_len as usize, size as usize) > MAX_VOLUME
+        {
+            return false;
+        }
+        let mut sessions = self.sessions.lock();
+        if sessions.contains_key(&(ip, id)) {
+            return true;
+        }
+        sessions.retain(|_, v| v.start.elapsed() < SESSION_TIMEOUT);
+        if sessions.len() >= MAX_SESSIONS {
+            log::warn!("带宽测量过多，拒绝{}", ip);
+            return false;
+        }
+        log::info!("同意{}的带宽测量 id={},{}x{}", ip, id, bursts, burst_len);
+        sessions.insert(
+            (ip, id),
+            Session {
+                arrivals: vec![Arrival::default(); bursts as usize],
+                start: Instant::now(),
+            },
+        );
+        true
+    }
+    pub fn arrive(&self, ip: Ipv4Addr, id: u32, burst: u8, len: usize) {
+        self.arrive_at(ip, id, burst, len, Instant::now())
+    }
+    fn arrive_at(&self, ip: Ipv4Addr, id: u32, burst: u8, len: usize, now: Instant) {
+        let mut sessions = self.sessions.lock();
+        let arrival = match sessions
+            .get_mut(&(ip, id))
+            .and_then(|v| v.arrivals.get_mut(burst as usize))
+        {
+            Some(arrival) => arrival,
+            None => return,
+        };
+        arrival.packets = arrival.packets.saturating_add(1);
+        if arrival.first.is_none() {
+            arrival.first = Some(now);
+        } else {
+            arrival.bytes = arrival.bytes.saturating_add(len as u32);
+        }
+        arrival.last = Some(now);
+    }
+    /// 每轮的接收情况，没有这次测量时返回None
+    pub fn finish(&self, ip: Ipv4Addr, id: u32) -> Option<Vec<BurstSample>> {
+        self.sessions
+            .lock()
+            .get(&(ip, id))
+            .map(|v| v.arrivals.iter().map(|v| v.sample()).collect())
+    }
+    /// 发起测量前调用，结束后需要cancel
+    pub fn expect(&self, id: u32) -> Receiver<BandwidthReply> {
+        let (sender, receiver) = sync_channel(4);
+        self.waiting.lock().insert(id, sender);
+        receiver
+    }
+    pub fn cancel(&self, id: u32) {
+        self.waiting.lock().remove(&id);
+    }
+    pub fn deliver(&self, id: u32, reply: BandwidthReply) {
+        if let Some(sender) = self.waiting.lock().get(&id) {
+            let _ = sender.try_send(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use crate::channel::bandwidth::{estimate, BandwidthProbes, BurstSample, MAX_BURSTS};
+
+    const SIZE: usize = 1200;
+
+    /// 按瓶颈速率均匀到达，extra_us是每个包额外的排队延迟
+    fn burst(probes: &BandwidthProbes, burst: u8, kbps: u64, len: usize, extra_us: &[u64]) {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let gap_us = SIZE as u64 * 8000 / kbps;
+        let base = Instant::now();
+        let mut offset = 0;
+        for i in 0..len {
+            offset += gap_us + extra_us.get(i).copied().unwrap_or(0);
+            let now = base + Duration::from_micros(offset);
+            probes.arrive_at(ip, 1, burst, SIZE, now);
+        }
+    }
+
+    fn assert_near(actual: u32, expect: u32) {
+        let diff = (actual as f64 - expect as f64).abs();
+        assert!(diff <= expect as f64 * 0.01, "{} != {}", actual, expect);
+    }
+
+    #[test]
+    fn test_known_bottleneck() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        for kbps in [2_000, 50_000, 800_000] {
+            let probes = BandwidthProbes::new();
+            assert!(probes.start(ip, 1, 5, 40, SIZE as u16));
+            for i in 0..5 {
+                burst(&probes, i, kbps, 40, &[]);
+            }
+            let samples = probes.finish(ip, 1).unwrap();
+            assert_eq!(samples.len(), 5);
+            assert!(samples.iter().all(|v| v.packets == 40));
+            let estimate = estimate(&samples).unwrap();
+            assert_near(estimate.kbps, kbps as u32);
+            assert!(estimate.low_kbps <= estimate.kbps && estimate.kbps <= estimate.high_kbps);
+            assert_eq!(estimate.bursts, 5);
+        }
+    }
+
+    /// 其他流量插入时那一轮的速率偏低，中位数不受影响，区间变宽
+    #[test]
+    fn test_cross_traffic() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let probes = BandwidthProbes::new();
+        assert!(probes.start(ip, 1, 5, 40, SIZE as u16));
+        for i in 0..4 {
+            burst(&probes, i, 20_000, 40, &[]);
+        }
+        burst(&probes, 4, 20_000, 40, &[2000; 40]);
+        let estimate = estimate(&probes.finish(ip, 1).unwrap()).unwrap();
+        assert_near(estimate.kbps, 20_000);
+        assert!(estimate.low_kbps < 20_000 && estimate.high_kbps >= 20_000);
+    }
+
+    #[test]
+    fn test_estimate_samples() {
+        assert_eq!(estimate(&[]), None);
+        // 包太少或者间隔为0
+        let few = BurstSample {
+            packets: 2,
+            span_us: 100,
+            bytes: 1200,
+        };
+        let zero = BurstSample {
+            packets: 10,
+            span_us: 0,
+            bytes: 12000,
+        };
+        assert_eq!(estimate(&[few, zero]), None);
+        // 10个包，第一个之后9x1000字节用了900微秒，80Mbps
+        let one = BurstSample {
+            packets: 10,
+            span_us: 900,
+            bytes: 9000,
+        };
+        let estimate = estimate(&[one, few]).unwrap();
+        assert_eq!(
+            (estimate.kbps, estimate.low_kbps, estimate.high_kbps),
+            (80_000, 80_000, 80_000)
+        );
+        assert_eq!(
+            estimate.to_string(),
+            "80.0Mbps (80.0Mbps~80.0Mbps, 1 bursts)"
+        );
+    }
+
+    #[test]
+    fn test_consent() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let probes = BandwidthProbes::new();
+        // 超过总流量或者轮数
+        assert!(!probes.start(ip, 1, 8, 255, 1400));
+        assert!(!probes.start(ip, 1, MAX_BURSTS as u8 + 1, 10, 1400));
+        assert!(!probes.start(ip, 1, 4, 1, 1400));
+        for id in 0..4 {
+            assert!(probes.start(ip, id, 4, 40, 1400));
+        }
+        // 重传的请求
+        assert!(probes.start(ip, 0, 4, 40, 1400));
+        assert!(!probes.start(ip, 4, 4, 40, 1400));
+        assert_eq!(probes.finish(ip, 4), None);
+        // 不属于这次测量的包
+        probes.arrive(ip, 0, 9, SIZE);
+        probes.arrive(Ipv4Addr::new(10, 26, 0, 3), 0, 0, SIZE);
+        assert!(probes.finish(ip, 0).unwrap().iter().all(|v| v.packets == 0));
+        let probes = BandwidthProbes::new();
+        probes.set_allow(false);
+        assert!(!probes.start(ip, 1, 4, 40, 1400));
+    }
+}
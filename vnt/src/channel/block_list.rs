@@ -0,0 +1,245 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::util::state_store::{self, StateFile};
+
+#[derive(Default)]
+struct Inner {
+    /// 生效的屏蔽，收发数据时查询
+    ips: HashSet<Ipv4Addr>,
+    /// 屏蔽时对端公开的指纹和当时的ip，虚拟ip重新分配后按指纹找到同一台设备
+    devices: HashMap<String, Ipv4Addr>,
+}
+
+/// 本地屏蔽的对端，收发数据和打洞都会跳过这些设备
+#[derive(Clone, Default)]
+pub struct BlockList {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl BlockList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[inline]
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let guard = self.inner.read();
+        !guard.ips.is_empty() && guard.ips.contains(ip)
+    }
+    /// 添加屏蔽，原先不存在时返回true
+    pub fn block(&self, ip: Ipv4Addr) -> bool {
+        self.inner.write().ips.insert(ip)
+    }
+    /// 添加屏蔽并记录对端的指纹，指纹为空时和block相同
+    pub fn block_device(&self, ip: Ipv4Addr, fingerprint: &str) -> bool {
+        let mut guard = self.inner.write();
+        if !fingerprint.is_empty() {
+            guard.devices.insert(fingerprint.to_string(), ip);
+        }
+        guard.ips.insert(ip)
+    }
+    /// 解除屏蔽，原先存在时返回true
+    pub fn unblock(&self, ip: &Ipv4Addr) -> bool {
+        let mut guard = self.inner.write();
+        guard.devices.retain(|_, v| v != ip);
+        guard.ips.remove(ip)
+    }
+    pub fn len(&self) -> usize {
+        self.inner.read().ips.len()
+    }
+    pub fn list(&self) -> Vec<Ipv4Addr> {
+        let mut list: Vec<Ipv4Addr> = self.inner.read().ips.iter().copied().collect();
+        list.sort();
+        list
+    }
+    /// 保存的内容，ip和屏蔽时的指纹，没有指纹时为空
+    pub fn entries(&self) -> Vec<(Ipv4Addr, String)> {
+        let guard = self.inner.read();
+        let mut list: Vec<(Ipv4Addr, String)> = guard
+            .devices
+            .iter()
+            .map(|(fingerprint, ip)| (*ip, fingerprint.clone()))
+            .collect();
+        for ip in guard.ips.iter() {
+            if !guard.devices.values().any(|v| v == ip) {
+                list.push((*ip, String::new()));
+            }
+        }
+        list.sort();
+        list
+    }
+    /// 设备列表更新后按指纹调整屏蔽的ip：
+    /// 屏蔽的设备换了ip时屏蔽新的ip，原来的ip分给了其他公开指纹的设备时不再屏蔽。
+    /// 对端没有公开指纹时无法区分，保持按ip屏蔽。返回新屏蔽的ip
+    pub fn follow<'a>(&self, peers: impl Iterator<Item = (Ipv4Addr, &'a str)>) -> Vec<Ipv4Addr> {
+        let peers: HashMap<Ipv4Addr, &str> = peers.collect();
+        let mut guard = self.inner.write();
+        if guard.devices.is_empty() {
+            return Vec::new();
+        }
+        let Inner { ips, devices } = &mut *guard;
+        let mut blocked = Vec::new();
+        for (fingerprint, ip) in devices.iter_mut() {
+            let current = peers
+                .iter()
+                .find(|(_, v)| **v == fingerprint.as_str())
+                .map(|(ip, _)| *ip);
+            match current {
+                Some(current) if current != *ip => {
+                    ips.remove(ip);
+                    if ips.insert(current) {
+                        blocked.push(current);
+                    }
+                    *ip = current;
+                }
+                Some(_) => {
+                    if ips.insert(*ip) {
+                        blocked.push(*ip);
+                    }
+                }
+                None => {
+                    if let Some(other) = peers.get(ip) {
+                        if !other.is_empty() {
+                            // ip已经分给了其他设备，等原来的设备重新出现
+                            ips.remove(ip);
+                        }
+                    }
+                }
+            }
+        }
+        blocked
+    }
+    /// 从文件加载，文件不存在或损坏时返回空列表
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<(Ipv4Addr, String)>> {
+        Ok(state_store::load_or_default::<BlockList>(path.as_ref()).entries())
+    }
+    /// 保存到文件，先写临时文件再改名
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        state_store::save(path.as_ref(), self)
+    }
+}
+
+/// 每行一个ip，后面跟着屏蔽时的指纹；版本0是没有文件头的旧文件，版本0和1只有ip
+impl StateFile for BlockList {
+    const VERSION: u32 = 2;
+    fn encode(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for (ip, fingerprint) in self.entries() {
+            text.push_str(&ip.to_string());
+            if !fingerprint.is_empty() {
+                text.push(' ');
+                text.push_str(&fingerprint);
+            }
+            text.push('\n');
+        }
+        text.into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(body).ok()?;
+        let block_list = BlockList::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (ip, fingerprint) = match line.split_once(char::is_whitespace) {
+                Some((ip, fingerprint)) => (ip, fingerprint.trim()),
+                None => (line, ""),
+            };
+            match Ipv4Addr::from_str(ip) {
+                Ok(ip) => {
+                    block_list.block_device(ip, fingerprint);
+                }
+                Err(e) => {
+                    log::warn!("屏蔽列表格式错误 {:?},{:?}", line, e);
+                }
+            }
+        }
+        Some(block_list)
+    }
+}
+
+#[test]
+fn test_block_list() {
+    let block_list = BlockList::new();
+    let ip = Ipv4Addr::new(10, 26, 0, 3);
+    assert!(!block_list.contains(&ip));
+    assert!(block_list.block(ip));
+    assert!(!block_list.block(ip));
+    assert!(block_list.contains(&ip));
+    assert!(block_list.unblock(&ip));
+    assert!(!block_list.contains(&ip));
+    assert!(!block_list.unblock(&ip));
+}
+
+#[test]
+fn test_block_list_persist() {
+    let path = std::env::temp_dir().join(format!("vnt-blocked-peers-{}", std::process::id()));
+    let block_list = BlockList::new();
+    block_list.block(Ipv4Addr::new(10, 26, 0, 4));
+    block_list.block_device(Ipv4Addr::new(10, 26, 0, 3), "0a1b2c3d4e5f6071");
+    block_list.save(&path).unwrap();
+    // 覆盖已有的文件，不留下临时文件
+    block_list.save(&path).unwrap();
+    let dir = path.parent().unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(!std::fs::read_dir(dir).unwrap().any(|v| {
+        let v = v.unwrap().file_name();
+        let v = v.to_string_lossy();
+        v.starts_with(name) && v.ends_with(".tmp")
+    }));
+    let list = BlockList::load(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(
+        list,
+        vec![
+            (Ipv4Addr::new(10, 26, 0, 3), "0a1b2c3d4e5f6071".to_string()),
+            (Ipv4Addr::new(10, 26, 0, 4), String::new())
+        ]
+    );
+    assert!(BlockList::load(&path).unwrap().is_empty());
+    // 只有ip的旧版本
+    let old = state_store::decode::<BlockList>(b"10.26.0.3\n10.26.0.4\n").unwrap();
+    assert_eq!(
+        old.list(),
+        vec![Ipv4Addr::new(10, 26, 0, 3), Ipv4Addr::new(10, 26, 0, 4)]
+    );
+}
+
+#[test]
+fn test_block_list_follow() {
+    let block_list = BlockList::new();
+    let old = Ipv4Addr::new(10, 26, 0, 3);
+    let new = Ipv4Addr::new(10, 26, 0, 9);
+    let anonymous = Ipv4Addr::new(10, 26, 0, 4);
+    block_list.block_device(old, "aaaa");
+    block_list.block(anonymous);
+    // 同一台设备还在原来的ip
+    assert!(block_list
+        .follow([(old, "aaaa"), (anonymous, "")].into_iter())
+        .is_empty());
+    assert!(block_list.contains(&old));
+    // 设备换了ip，原来的ip分给了其他设备
+    assert_eq!(
+        block_list.follow([(old, "bbbb"), (new, "aaaa"), (anonymous, "cccc")].into_iter()),
+        vec![new]
+    );
+    assert!(!block_list.contains(&old));
+    assert!(block_list.contains(&new));
+    // 没有指纹的屏蔽只按ip
+    assert!(block_list.contains(&anonymous));
+    // 设备不在列表中，它的ip分给了其他设备
+    assert!(block_list.follow([(new, "dddd")].into_iter()).is_empty());
+    assert!(!block_list.contains(&new));
+    // 重新出现时继续屏蔽
+    assert_eq!(block_list.follow([(old, "aaaa")].into_iter()), vec![old]);
+    assert!(block_list.contains(&old));
+    assert!(block_list.unblock(&old));
+    assert_eq!(block_list.entries(), vec![(anonymous, String::new())]);
+}
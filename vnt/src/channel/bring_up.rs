@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 建立过程中最多中继的数据包数量，0表示不限制
+///
+/// 默认不限制，大流量的应用在建立完成前也需要中继，是否限制由用户决定
+pub const DEFAULT_MAX_RELAY: u32 = 0;
+/// 超过这个时间还没有验证直连就放弃，之后只使用中继
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// 记录的对端数量上限，避免伪造来源导致内存增长
+const PEER_LIMIT: usize = 1024;
+
+/// 新对端的建立阶段
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BringUpState {
+    /// 通过服务器交换打洞信息
+    Handshake,
+    /// 已交换打洞信息，等待对端的打洞响应
+    Punch,
+    /// 已有直连地址，等待直连路径上的第一次延迟测量
+    Validate,
+    /// 直连已验证
+    Up,
+    /// 超时，使用中继
+    Relay,
+}
+
+impl BringUpState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BringUpState::Handshake => "handshake",
+            BringUpState::Punch => "punch",
+            BringUpState::Validate => "validate",
+            BringUpState::Up => "up",
+            BringUpState::Relay => "relay",
+        }
+    }
+    fn finished(&self) -> bool {
+        matches!(self, BringUpState::Up | BringUpState::Relay)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum Event {
+    Begin,
+    Handshake,
+    Punch,
+    Validate,
+}
+
+struct Session {
+    start: Instant,
+    // 各阶段完成的时间
+    handshake: Option<Instant>,
+    punch: Option<Instant>,
+    validate: Option<Instant>,
+    // 确定无法直连的时间
+    give_up: Option<Instant>,
+    // 建立过程中中继和丢弃的数据包
+    relayed: u32,
+    dropped: u32,
+    // 已经统计过结果
+    counted: bool,
+}
+
+impl Session {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            handshake: None,
+            punch: None,
+            validate: None,
+            give_up: None,
+            relayed: 0,
+            dropped: 0,
+            counted: false,
+        }
+    }
+    fn state(&self, now: Instant, timeout: Duration) -> BringUpState {
+        if self.validate.is_some() {
+            BringUpState::Up
+        } else if self.give_up.is_some() || now.saturating_duration_since(self.start) >= timeout {
+            BringUpState::Relay
+        } else if self.punch.is_some() {
+            BringUpState::Validate
+        } else if self.handshake.is_some() {
+            BringUpState::Punch
+        } else {
+            BringUpState::Handshake
+        }
+    }
+}
+
+/// 一个对端的建立记录
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BringUpInfo {
+    pub state: BringUpState,
+    /// 完成时是总耗时，进行中是已经过的时间
+    pub elapsed: Duration,
+    pub handshake: Option<Duration>,
+    pub punch: Option<Duration>,
+    pub validate: Option<Duration>,
+    pub relayed: u32,
+    pub dropped: u32,
+}
+
+impl Display for BringUpInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.state {
+            BringUpState::Up => write!(f, "brought up in {}", millis(self.elapsed))?,
+            BringUpState::Relay => write!(f, "relay after {}", millis(self.elapsed))?,
+            state => write!(f, "{} {}", state.name(), millis(self.elapsed))?,
+        }
+        let phases: Vec<String> = [
+            ("handshake", self.handshake),
+            ("punch", self.punch),
+            ("validated", self.validate),
+        ]
+        .iter()
+        .filter_map(|(name, v)| v.map(|v| format!("{} {}", name, millis(v))))
+        .collect();
+        if !phases.is_empty() {
+            write!(f, " ({})", phases.join(", "))?;
+        }
+        if self.dropped > 0 {
+            write!(f, " {} dropped", self.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+fn millis(v: Duration) -> String {
+    format!("{}ms", v.as_millis())
+}
+
+/// 新对端建立过程的顺序和节奏
+///
+/// 第一次向对端中继数据或者发起打洞协商时开始，依次经过:
+/// 1. handshake: 通过服务器交换打洞信息(PunchInfo)
+/// 2. punch: 收到对端的打洞响应，得到直连地址
+/// 3. validated: 直连路径上第一次ping/pong，测得延迟
+///
+/// 打洞和协商消息不经过这里，不受任何限制；建立过程中最多中继max_relay个数据包，
+/// 多余的丢弃，避免应用层(例如tcp syn重传)的突发流量挤占服务器链路，拖慢协商和打洞。
+/// 超时还没有完成则当作中继对端，不再限制
+pub struct BringUp {
+    max_relay: AtomicU32,
+    timeout_ms: AtomicU64,
+    sessions: Mutex<HashMap<Ipv4Addr, Session>>,
+    completed: Counter,
+    timed_out: Counter,
+}
+
+impl BringUp {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            max_relay: AtomicU32::new(DEFAULT_MAX_RELAY),
+            timeout_ms: AtomicU64::new(DEFAULT_TIMEOUT.as_millis() as u64),
+            sessions: Mutex::new(HashMap::with_capacity(16)),
+            completed: registry.counter("bring_up", &[("result", "up")]),
+            timed_out: registry.counter("bring_up", &[("result", "relay")]),
+        }
+    }
+    pub fn set_limits(&self, max_relay: u32, timeout: Duration) {
+        self.max_relay.store(max_relay, Ordering::Relaxed);
+        self.timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.load(Ordering::Relaxed))
+    }
+    /// 中继数据包前调用，返回false表示建立过程中中继的包已达到上限，应当丢弃
+    pub fn relay(&self, peer: Ipv4Addr) -> bool {
+        self.relay_at(peer, Instant::now())
+    }
+    fn relay_at(&self, peer: Ipv4Addr, now: Instant) -> bool {
+        let timeout = self.timeout();
+        let max_relay = self.max_relay.load(Ordering::Relaxed);
+        let mut guard = self.sessions.lock();
+        if guard.len() >= PEER_LIMIT && !guard.contains_key(&peer) {
+            return true;
+        }
+        let session = guard.entry(peer).or_insert_with(|| Session::new(now));
+        match session.state(now, timeout) {
+            BringUpState::Up => true,
+            BringUpState::Relay => {
+                if !session.counted {
+                    session.counted = true;
+                    self.timed_out.inc();
+                }
+                true
+            }
+            _ => {
+                if max_relay > 0 && session.relayed >= max_relay {
+                    session.dropped += 1;
+                    false
+                } else {
+                    session.relayed += 1;
+                    true
+                }
+            }
+        }
+    }
+    /// 向对端发起打洞协商
+    pub fn begin(&self, peer: Ipv4Addr) {
+        self.event(peer, Event::Begin, Instant::now());
+    }
+    /// 收到对端的打洞信息
+    pub fn handshake(&self, peer: Ipv4Addr) {
+        self.event(peer, Event::Handshake, Instant::now());
+    }
+    /// 收到对端的打洞响应
+    pub fn punched(&self, peer: Ipv4Addr) {
+        self.event(peer, Event::Punch, Instant::now());
+    }
+    /// 直连路径上测得延迟，本次协商因此完成时返回true
+    pub fn validated(&self, peer: Ipv4Addr) -> bool {
+        self.event(peer, Event::Validate, Instant::now())
+    }
+    /// 确定无法直连(对端只使用中继、打洞多次失败)，不用等待超时，本次协商因此结束时返回true
+    pub fn give_up(&self, peer: Ipv4Addr) -> bool {
+        self.give_up_at(peer, Instant::now())
+    }
+    fn give_up_at(&self, peer: Ipv4Addr, now: Instant) -> bool {
+        let timeout = self.timeout();
+        if let Some(session) = self.sessions.lock().get_mut(&peer) {
+            if !session.state(now, timeout).finished() {
+                session.give_up = Some(now);
+                session.counted = true;
+                self.timed_out.inc();
+                return true;
+            }
+        }
+        false
+    }
+    fn event(&self, peer: Ipv4Addr, event: Event, now: Instant) -> bool {
+        let timeout = self.timeout();
+        let mut guard = self.sessions.lock();
+        if !guard.contains_key(&peer) {
+            // 没有经过协商的直连(例如心跳)不算新对端
+            if event > Event::Handshake || guard.len() >= PEER_LIMIT {
+                return false;
+            }
+        }
+        let session = guard.entry(peer).or_insert_with(|| Session::new(now));
+        if session.state(now, timeout).finished() {
+            return false;
+        }
+        // 对端发起的打洞可能跳过前面的阶段
+        if event >= Event::Handshake {
+            session.handshake.get_or_insert(now);
+        }
+        if event >= Event::Punch {
+            session.punch.get_or_insert(now);
+        }
+        if event >= Event::Validate {
+            session.validate = Some(now);
+            session.counted = true;
+            self.completed.inc();
+            return true;
+        }
+        false
+    }
+    /// 对端离线后删除记录，重新上线时重新开始
+    pub fn retain_peers(&self, online: &[Ipv4Addr]) {
+        self.sessions.lock().retain(|ip, _| online.contains(ip));
+    }
+    pub fn get(&self, peer: &Ipv4Addr) -> Option<BringUpInfo> {
+        self.get_at(peer, Instant::now())
+    }
+    fn get_at(&self, peer: &Ipv4Addr, now: Instant) -> Option<BringUpInfo> {
+        let timeout = self.timeout();
+        let guard = self.sessions.lock();
+        let session = guard.get(peer)?;
+        let state = session.state(now, timeout);
+        let elapsed = match state {
+            BringUpState::Up => session.validate.unwrap() - session.start,
+            BringUpState::Relay => session
+                .give_up
+                .map_or(timeout, |v| v.saturating_duration_since(session.start)),
+            _ => now.saturating_duration_since(session.start),
+        };
+        // 各阶段的耗时是和上一个阶段之间的间隔
+        let mut last = session.start;
+        let mut phase = |v: Option<Instant>| {
+            v.map(|v| {
+                let d = v.saturating_duration_since(last);
+                last = v;
+                d
+            })
+        };
+        let handshake = phase(session.handshake);
+        let punch = phase(session.punch);
+        let validate = phase(session.validate);
+        Some(BringUpInfo {
+            state,
+            elapsed,
+            handshake,
+            punch,
+            validate,
+            relayed: session.relayed,
+            dropped: session.dropped,
+        })
+    }
+    pub fn list(&self) -> Vec<(Ipv4Addr, BringUpInfo)> {
+        let now = Instant::now();
+        let peers: Vec<Ipv4Addr> = self.sessions.lock().keys().cloned().collect();
+        let mut list: Vec<(Ipv4Addr, BringUpInfo)> = peers
+            .into_iter()
+            .filter_map(|ip| self.get_at(&ip, now).map(|v| (ip, v)))
+            .collect();
+        list.sort_by_key(|(ip, _)| *ip);
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::util::metrics::Registry;
+
+    use super::{BringUp, BringUpState, Event};
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    #[test]
+    fn test_phases() {
+        let registry = Registry::new();
+        let bring_up = BringUp::new(&registry);
+        bring_up.set_limits(3, ms(5000));
+        let start = Instant::now();
+        assert!(bring_up.relay_at(PEER, start));
+        bring_up.event(PEER, Event::Handshake, start + ms(180));
+        bring_up.event(PEER, Event::Punch, start + ms(600));
+        // 建立过程中只中继3个包
+        assert!(bring_up.relay_at(PEER, start + ms(601)));
+        assert!(bring_up.relay_at(PEER, start + ms(602)));
+        assert!(!bring_up.relay_at(PEER, start + ms(603)));
+        let info = bring_up.get_at(&PEER, start + ms(610)).unwrap();
+        assert_eq!(info.state, BringUpState::Validate);
+        assert_eq!(
+            info.to_string(),
+            "validate 610ms (handshake 180ms, punch 420ms) 1 dropped"
+        );
+        assert!(bring_up.event(PEER, Event::Validate, start + ms(640)));
+        // 建立完成后不再限制，后续的心跳不改变记录
+        assert!(bring_up.relay_at(PEER, start + ms(700)));
+        assert!(!bring_up.event(PEER, Event::Validate, start + ms(900)));
+        let info = bring_up.get_at(&PEER, start + ms(2000)).unwrap();
+        assert_eq!(info.state, BringUpState::Up);
+        assert_eq!(
+            info.to_string(),
+            "brought up in 640ms (handshake 180ms, punch 420ms, validated 40ms) 1 dropped"
+        );
+        assert_eq!(registry.counter("bring_up", &[("result", "up")]).get(), 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let registry = Registry::new();
+        let bring_up = BringUp::new(&registry);
+        bring_up.set_limits(1, ms(1000));
+        let start = Instant::now();
+        bring_up.event(PEER, Event::Begin, start);
+        assert!(bring_up.relay_at(PEER, start + ms(10)));
+        assert!(!bring_up.relay_at(PEER, start + ms(20)));
+        // 超时后当作中继对端，不再丢包
+        assert!(bring_up.relay_at(PEER, start + ms(1000)));
+        assert!(bring_up.relay_at(PEER, start + ms(1001)));
+        bring_up.event(PEER, Event::Validate, start + ms(1500));
+        let info = bring_up.get_at(&PEER, start + ms(2000)).unwrap();
+        assert_eq!(info.state, BringUpState::Relay);
+        assert_eq!(info.to_string(), "relay after 1000ms 1 dropped");
+        assert_eq!(
+            registry.counter("bring_up", &[("result", "relay")]).get(),
+            1
+        );
+        // 没有经过协商的直连不产生记录
+        let other = Ipv4Addr::new(10, 26, 0, 4);
+        bring_up.event(other, Event::Validate, start);
+        assert!(bring_up.get_at(&other, start).is_none());
+        // 确定无法直连时不用等待超时
+        bring_up.event(other, Event::Handshake, start);
+        assert!(bring_up.give_up_at(other, start + ms(300)));
+        assert!(bring_up.relay_at(other, start + ms(301)));
+        assert!(bring_up.relay_at(other, start + ms(302)));
+        let info = bring_up.get_at(&other, start + ms(2000)).unwrap();
+        assert_eq!(info.to_string(), "relay after 300ms (handshake 0ms)");
+        assert_eq!(
+            registry.counter("bring_up", &[("result", "relay")]).get(),
+            2
+        );
+        // 离线后重新开始
+        bring_up.retain_peers(&[other]);
+        assert!(bring_up.get_at(&PEER, start).is_none());
+    }
+
+    /// 模拟服务器延迟抖动和控制消息丢失，建立时间的分布不应变差
+    #[test]
+    fn test_chaos_distribution() {
+        let bring_up = BringUp::new(&Registry::new());
+        bring_up.set_limits(64, ms(3000));
+        let mut rng = StdRng::seed_from_u64(231);
+        let start = Instant::now();
+        let mut times = Vec::new();
+        let mut relay_only = 0;
+        for i in 0..500u32 {
+            let peer = Ipv4Addr::from(0x0A1A_0000 + i + 2);
+            let mut now = start;
+            bring_up.event(peer, Event::Begin, now);
+            // 协商经过服务器往返，20%的概率丢失，500ms后重试
+            let mut retries = 0;
+            while retries < 3 && rng.gen_ratio(1, 5) {
+                retries += 1;
+                now += ms(500);
+            }
+            now += ms(rng.gen_range(20..200));
+            bring_up.event(peer, Event::Handshake, now);
+            // 同时到达的突发流量
+            let mut dropped = 0;
+            for _ in 0..200 {
+                if !bring_up.relay_at(peer, now) {
+                    dropped += 1;
+                }
+            }
+            assert_eq!(dropped, 200 - 64);
+            if rng.gen_ratio(1, 10) {
+                // 无法直连
+                relay_only += 1;
+                now += ms(3000);
+                assert!(bring_up.relay_at(peer, now));
+                let info = bring_up.get_at(&peer, now).unwrap();
+                assert_eq!(info.state, BringUpState::Relay);
+                continue;
+            }
+            now += ms(rng.gen_range(10..400));
+            bring_up.event(peer, Event::Punch, now);
+            now += ms(rng.gen_range(5..100));
+            bring_up.event(peer, Event::Validate, now);
+            let info = bring_up.get_at(&peer, now).unwrap();
+            assert_eq!(info.state, BringUpState::Up);
+            // 各阶段之和等于总耗时
+            assert_eq!(
+                info.handshake.unwrap() + info.punch.unwrap() + info.validate.unwrap(),
+                info.elapsed
+            );
+            times.push(info.elapsed);
+        }
+        times.sort();
+        let p50 = times[times.len() / 2];
+        let p95 = times[times.len() * 95 / 100];
+        assert!(p50 < ms(800), "p50 {:?}", p50);
+        assert!(p95 < ms(2000), "p95 {:?}", p95);
+        assert!(relay_only < 100, "relay {}", relay_only);
+    }
+}
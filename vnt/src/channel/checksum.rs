@@ -0,0 +1,340 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::util::metrics::{Counter, Registry};
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// 改写过端口或地址的包写入虚拟网卡前如何处理传输层校验和
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ChecksumMode {
+    /// 重新计算完整的校验和
+    Compute,
+    /// 校验和置0，用于不校验网卡注入的包、但是会被错误的校验和卡住的平台
+    Zero,
+    /// 网卡就绪后用回环探测决定
+    Auto,
+}
+
+impl ChecksumMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumMode::Compute => "compute",
+            ChecksumMode::Zero => "zero",
+            ChecksumMode::Auto => "auto",
+        }
+    }
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ChecksumMode::Zero,
+            2 => ChecksumMode::Auto,
+            _ => ChecksumMode::Compute,
+        }
+    }
+    fn to_u8(self) -> u8 {
+        match self {
+            ChecksumMode::Compute => 0,
+            ChecksumMode::Zero => 1,
+            ChecksumMode::Auto => 2,
+        }
+    }
+}
+
+impl FromStr for ChecksumMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "compute" => Ok(ChecksumMode::Compute),
+            "zero" => Ok(ChecksumMode::Zero),
+            "auto" => Ok(ChecksumMode::Auto),
+            _ => Err(format!("not match '{}', enum: compute/zero/auto", s)),
+        }
+    }
+}
+
+impl Default for ChecksumMode {
+    fn default() -> Self {
+        ChecksumMode::Auto
+    }
+}
+
+/// 入站方向改写过传输层头部的包(地址转换、mss修改、端口转发、钩子)的校验和策略
+///
+/// 改写时已经增量更新了校验和，但是部分平台的网卡驱动开启了校验和卸载，
+/// 收到的包本身带的就是不完整的校验和，增量更新后写入网卡会被协议栈丢弃。
+/// 出站的包发给对端，不知道对端的情况，始终保持增量更新的结果
+pub struct ChecksumPolicy {
+    // 配置的模式
+    configured: AtomicU8,
+    // 实际使用的模式，auto探测前按compute处理
+    effective: AtomicU8,
+    computed: Counter,
+    zeroed: Counter,
+}
+
+impl ChecksumPolicy {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            configured: AtomicU8::new(ChecksumMode::Auto.to_u8()),
+            effective: AtomicU8::new(ChecksumMode::Compute.to_u8()),
+            computed: registry.counter("checksum_adjusted", &[("policy", "compute")]),
+            zeroed: registry.counter("checksum_adjusted", &[("policy", "zero")]),
+        }
+    }
+    pub fn set_mode(&self, mode: ChecksumMode) {
+        self.configured.store(mode.to_u8(), Ordering::Relaxed);
+        let effective = if mode == ChecksumMode::Auto {
+            ChecksumMode::Compute
+        } else {
+            mode
+        };
+        self.effective.store(effective.to_u8(), Ordering::Relaxed);
+    }
+    pub fn mode(&self) -> ChecksumMode {
+        ChecksumMode::from_u8(self.configured.load(Ordering::Relaxed))
+    }
+    /// 当前生效的模式，不会是auto
+    pub fn effective(&self) -> ChecksumMode {
+        ChecksumMode::from_u8(self.effective.load(Ordering::Relaxed))
+    }
+    /// auto模式下记录探测结果，指定了模式时忽略
+    pub fn resolve(&self, mode: ChecksumMode) {
+        if self.mode() == ChecksumMode::Auto && mode != ChecksumMode::Auto {
+            self.effective.store(mode.to_u8(), Ordering::Relaxed);
+        }
+    }
+    /// 处理改写过的入站包，返回是否处理了校验和
+    pub fn apply(&self, ipv4: &mut [u8]) -> bool {
+        let mode = self.effective();
+        if !apply_mode(mode, ipv4) {
+            return false;
+        }
+        match mode {
+            ChecksumMode::Zero => self.zeroed.inc(),
+            _ => self.computed.inc(),
+        }
+        true
+    }
+}
+
+/// 按模式处理tcp/udp的校验和，返回是否处理了
+///
+/// compute只处理没有分片的包，分片的包无法计算完整的校验和，保留增量更新的结果；
+/// udp校验和为0表示没有使用校验和，保持为0
+pub(crate) fn apply_mode(mode: ChecksumMode, ipv4: &mut [u8]) -> bool {
+    if ipv4.len() < 20 || ipv4[0] >> 4 != 4 {
+        return false;
+    }
+    let ihl = (ipv4[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([ipv4[2], ipv4[3]]) as usize;
+    if ihl < 20 || total_len > ipv4.len() || total_len < ihl {
+        return false;
+    }
+    let flags = u16::from_be_bytes([ipv4[6], ipv4[7]]);
+    let first_fragment = flags & 0x1fff == 0;
+    let more_fragments = flags & 0x2000 != 0;
+    if !first_fragment {
+        return false;
+    }
+    let protocol = ipv4[9];
+    let pos = match protocol {
+        TCP if total_len >= ihl + 20 => ihl + 16,
+        UDP if total_len >= ihl + 8 => ihl + 6,
+        _ => return false,
+    };
+    match mode {
+        ChecksumMode::Zero => {
+            ipv4[pos..pos + 2].copy_from_slice(&[0, 0]);
+            true
+        }
+        _ => {
+            if more_fragments || (protocol == UDP && ipv4[pos..pos + 2] == [0, 0]) {
+                return false;
+            }
+            ipv4[pos..pos + 2].copy_from_slice(&[0, 0]);
+            let mut checksum = l4_checksum(&ipv4[..total_len], ihl);
+            if protocol == UDP && checksum == 0 {
+                // 计算结果为0时udp使用全1
+                checksum = 0xffff;
+            }
+            ipv4[pos..pos + 2].copy_from_slice(&checksum.to_be_bytes());
+            true
+        }
+    }
+}
+
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+/// 带伪首部的传输层校验和，ipv4是完整的包
+fn l4_checksum(ipv4: &[u8], ihl: usize) -> u16 {
+    let l4 = &ipv4[ihl..];
+    let mut acc = sum(&ipv4[12..20], ipv4[9] as u32 + l4.len() as u32);
+    acc = sum(l4, acc);
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::channel::mtu_guard::update_checksum;
+    use crate::util::metrics::Registry;
+
+    use super::{apply_mode, l4_checksum, ChecksumMode, ChecksumPolicy, TCP, UDP};
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+    const DEST: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+
+    /// options是tcp选项，udp时忽略
+    fn packet(protocol: u8, options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let head = if protocol == TCP {
+            20 + options.len()
+        } else {
+            8
+        };
+        let total_len = 20 + head + payload.len();
+        let mut buf = vec![0u8; total_len];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        buf[8] = 64;
+        buf[9] = protocol;
+        buf[12..16].copy_from_slice(&SRC.octets());
+        buf[16..20].copy_from_slice(&DEST.octets());
+        let l4 = &mut buf[20..];
+        l4[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        l4[2..4].copy_from_slice(&22u16.to_be_bytes());
+        if protocol == TCP {
+            l4[12] = ((head / 4) as u8) << 4;
+            l4[20..head].copy_from_slice(options);
+        } else {
+            l4[4..6].copy_from_slice(&((head + payload.len()) as u16).to_be_bytes());
+            // 不为0时才会计算
+            l4[6..8].copy_from_slice(&[0xff, 0xff]);
+        }
+        l4[head..].copy_from_slice(payload);
+        assert!(apply_mode(ChecksumMode::Compute, &mut buf));
+        buf
+    }
+
+    fn checksum(buf: &[u8]) -> u16 {
+        let pos = if buf[9] == TCP { 36 } else { 26 };
+        u16::from_be_bytes([buf[pos], buf[pos + 1]])
+    }
+
+    /// 校验和字段参与计算时结果为0
+    fn valid(buf: &[u8]) -> bool {
+        let mut copy = buf.to_vec();
+        let pos = if buf[9] == TCP { 36 } else { 26 };
+        copy[pos..pos + 2].copy_from_slice(&[0, 0]);
+        let expect = l4_checksum(&copy, 20);
+        let actual = checksum(buf);
+        // 反码运算中0和0xffff等价
+        expect == actual || (expect == 0 && actual == 0xffff)
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        // 没有选项，奇数长度的负载
+        let buf = packet(TCP, &[], b"checksum");
+        assert!(valid(&buf));
+        let buf = packet(TCP, &[], b"odd");
+        assert!(valid(&buf));
+        // mss选项，修改mss后增量更新和完整计算的结果一致
+        let mut buf = packet(TCP, &[2, 4, 0x05, 0xb4, 1, 1, 4, 2], b"");
+        assert!(valid(&buf));
+        buf[42..44].copy_from_slice(&1360u16.to_be_bytes());
+        let incremental = update_checksum(checksum(&buf), 1460, 1360);
+        assert!(apply_mode(ChecksumMode::Compute, &mut buf));
+        assert_eq!(checksum(&buf), incremental);
+        // 修改端口
+        buf[22..24].copy_from_slice(&2222u16.to_be_bytes());
+        let incremental = update_checksum(checksum(&buf), 22, 2222);
+        assert!(apply_mode(ChecksumMode::Compute, &mut buf));
+        assert_eq!(checksum(&buf), incremental);
+        assert!(valid(&buf));
+    }
+
+    #[test]
+    fn test_udp() {
+        let mut buf = packet(UDP, &[], b"dns query");
+        assert!(valid(&buf));
+        buf[22..24].copy_from_slice(&5353u16.to_be_bytes());
+        let incremental = update_checksum(checksum(&buf), 22, 5353);
+        assert!(apply_mode(ChecksumMode::Compute, &mut buf));
+        assert_eq!(checksum(&buf), incremental);
+        // 0表示没有校验和，保持为0
+        buf[26..28].copy_from_slice(&[0, 0]);
+        let original = buf.clone();
+        assert!(!apply_mode(ChecksumMode::Compute, &mut buf));
+        assert_eq!(buf, original);
+        // 计算结果为0时写入0xffff，最后两个字节补齐到反码和为0xffff
+        let mut buf = packet(UDP, &[], &[0x5a, 0xa5, 0, 0]);
+        let len = buf.len();
+        let pad = checksum(&buf);
+        buf[len - 2..].copy_from_slice(&pad.to_be_bytes());
+        assert!(apply_mode(ChecksumMode::Compute, &mut buf));
+        assert_eq!(checksum(&buf), 0xffff);
+        assert!(valid(&buf));
+    }
+
+    #[test]
+    fn test_zero() {
+        let mut tcp = packet(TCP, &[], b"zero");
+        assert!(apply_mode(ChecksumMode::Zero, &mut tcp));
+        assert_eq!(checksum(&tcp), 0);
+        let mut udp = packet(UDP, &[], b"zero");
+        assert!(apply_mode(ChecksumMode::Zero, &mut udp));
+        assert_eq!(checksum(&udp), 0);
+        // 非首个分片没有传输层头部
+        let mut fragment = packet(UDP, &[], b"fragment");
+        fragment[6..8].copy_from_slice(&0x0010u16.to_be_bytes());
+        let original = fragment.clone();
+        assert!(!apply_mode(ChecksumMode::Zero, &mut fragment));
+        assert_eq!(fragment, original);
+        // icmp和截断的包不处理
+        let mut icmp = packet(UDP, &[], b"icmp");
+        icmp[9] = 1;
+        assert!(!apply_mode(ChecksumMode::Zero, &mut icmp));
+        assert!(!apply_mode(ChecksumMode::Zero, &mut tcp[..30]));
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!("ZERO".parse::<ChecksumMode>(), Ok(ChecksumMode::Zero));
+        assert!("none".parse::<ChecksumMode>().is_err());
+        let registry = Registry::new();
+        let policy = ChecksumPolicy::new(&registry);
+        policy.set_mode(ChecksumMode::Auto);
+        assert_eq!(policy.effective(), ChecksumMode::Compute);
+        policy.resolve(ChecksumMode::Zero);
+        assert_eq!(policy.mode(), ChecksumMode::Auto);
+        assert_eq!(policy.effective(), ChecksumMode::Zero);
+        let mut buf = packet(UDP, &[], b"policy");
+        assert!(policy.apply(&mut buf));
+        assert_eq!(checksum(&buf), 0);
+        // udp没有校验和，compute不处理
+        policy.set_mode(ChecksumMode::Compute);
+        policy.resolve(ChecksumMode::Zero);
+        assert_eq!(policy.effective(), ChecksumMode::Compute);
+        assert!(!policy.apply(&mut buf));
+        let mut buf = packet(TCP, &[], b"policy");
+        assert!(policy.apply(&mut buf));
+        let zeroed = registry.counter("checksum_adjusted", &[("policy", "zero")]);
+        let computed = registry.counter("checksum_adjusted", &[("policy", "compute")]);
+        assert_eq!((zeroed.get(), computed.get()), (1, 1));
+    }
+}
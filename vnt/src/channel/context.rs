@@ -7,12 +7,69 @@ use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use crossbeam_utils::atomic::AtomicCell;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::Rng;
 
+use crate::channel::backpressure::Backpressure;
+use crate::channel::bandwidth::BandwidthProbes;
+use crate::channel::block_list::BlockList;
+use crate::channel::bring_up::BringUp;
+use crate::channel::checksum::ChecksumPolicy;
+use crate::channel::cookie::CookieGuard;
+use crate::channel::diary::{Diary, DiaryEvent, EvictReason, PathInfo};
+use crate::channel::drop_reason::{DropReason, DropStats};
+use crate::channel::handover::Handover;
+use crate::channel::icmp_error::{IcmpErrors, IcmpKind};
+use crate::channel::inbound_limit::InboundLimit;
+use crate::channel::lan_peers::LanPeers;
+use crate::channel::mtu_guard::MtuGuard;
+use crate::channel::p2p_estimate::PunchHistory;
+use crate::channel::path_score::{PathChoice, PathScores, PathWeights, PeerPreference, Preference};
+use crate::channel::peer_feature::PeerFeatures;
+use crate::channel::peer_stats::PeerStats;
+use crate::channel::ping::EchoWaiters;
+use crate::channel::probe_budget::ProbeBudget;
 use crate::channel::punch::NatType;
+use crate::channel::punch_sync::PunchSync;
+use crate::channel::rate_limit::RateLimit;
+use crate::channel::relay_stats::RelayStats;
+use crate::channel::rendezvous::Rendezvous;
+use crate::channel::reorder::Reorder;
+use crate::channel::reverse::ReverseConnect;
+use crate::channel::route_cache::RouteCache;
+use crate::channel::route_health::RouteHealth;
+use crate::channel::self_probe::SelfProbe;
 use crate::channel::sender::{AcceptSocketSender, ChannelSender, PacketSender};
+use crate::channel::socket_pool::{PooledSocket, SocketPool, SocketPurpose};
+use crate::channel::source_policy::SourcePolicy;
+use crate::channel::transport::ServerTransport;
 use crate::channel::{Route, RouteKey, UseChannelType, DEFAULT_RT};
+use crate::handle::packet_hook::PacketHooks;
+use crate::handle::server_action::ServerActions;
+use crate::handle::PeerDeviceInfo;
+use crate::protocol::{compat, Protocol, HEAD_LEN};
+use crate::tun_tap_device::intent_log::IntentLog;
+use crate::util::health::Health;
+use crate::util::metrics::{Gauge, Histogram, Registry};
+use crate::util::serial::Anomaly;
+use crate::util::workers::Workers;
+
+/// 待发送的包
+enum Outbound<'a> {
+    Packet(&'a [u8]),
+    /// |EXT_HEAD_LEN字节预留|V1格式的包|，发往支持V2的对端时原地编码
+    Reserved(&'a mut [u8]),
+}
+
+impl Outbound<'_> {
+    /// V1格式的包
+    fn v1(&self) -> &[u8] {
+        match self {
+            Outbound::Packet(buf) => buf,
+            Outbound::Reserved(buf) => &buf[compat::EXT_HEAD_LEN..],
+        }
+    }
+}
 
 /// 传输通道上下文，持有udp socket、tcp socket和路由信息
 #[derive(Clone)]
@@ -29,6 +86,7 @@ impl ChannelContext {
         packet_loss_rate: Option<f64>,
         packet_delay: u32,
         use_ipv6: bool,
+        packet_hooks: PacketHooks,
     ) -> Self {
         let channel_num = main_udp_socket.len();
         assert_ne!(channel_num, 0, "not channel");
@@ -42,17 +100,69 @@ impl ChannelContext {
                 }
             })
             .unwrap_or(0);
+        let metrics = Registry::new();
+        let diary = Diary::new();
         let inner = ContextInner {
             main_udp_socket,
             sub_udp_socket: RwLock::new(Vec::with_capacity(64)),
             tcp_map: RwLock::new(HashMap::with_capacity(64)),
-            route_table: RouteTable::new(use_channel_type, first_latency, channel_num),
-            is_tcp,
+            route_table: RouteTable::new(
+                use_channel_type,
+                first_latency,
+                channel_num,
+                diary.clone(),
+            ),
+            transport: ServerTransport::new(is_tcp, &metrics),
             state: AtomicBool::new(true),
             packet_loss_rate,
             packet_delay,
             main_index: AtomicUsize::new(0),
             use_ipv6,
+            block_list: BlockList::new(),
+            peer_features: PeerFeatures::new(),
+            route_conflict: AtomicBool::new(false),
+            relay_passthrough: AtomicBool::new(false),
+            compact_encoding: AtomicBool::new(false),
+            broadcast: AtomicBool::new(true),
+            mtu_guard: MtuGuard::new(),
+            backpressure: Backpressure::new(&metrics),
+            bring_up: BringUp::new(&metrics),
+            source_policy: SourcePolicy::new(&metrics),
+            checksum: ChecksumPolicy::new(&metrics),
+            rate_limit: RateLimit::new(),
+            inbound_limit: InboundLimit::new(),
+            lan_peers: LanPeers::new(),
+            reorder: Reorder::new(&metrics),
+            probe_budget: ProbeBudget::new(&metrics),
+            socket_pool: SocketPool::new(),
+            self_probe: SelfProbe::new(),
+            bandwidth: BandwidthProbes::new(),
+            ping: EchoWaiters::new(),
+            handover: Handover::new(),
+            icmp_errors: IcmpErrors::new(&metrics),
+            diary,
+            punch_history: PunchHistory::new(),
+            punch_sync: PunchSync::new(&metrics),
+            route_health: RouteHealth::new(&metrics),
+            cookie_guard: CookieGuard::new(&metrics),
+            server_actions: ServerActions::new(&metrics),
+            rendezvous: Rendezvous::new(&metrics),
+            reverse: ReverseConnect::new(),
+            intent_log: IntentLog::new(),
+            tun_routes: Mutex::new(Vec::new()),
+            workers: Workers::new(),
+            health: Health::new(),
+            packet_hooks,
+            drop_stats: DropStats::new(&metrics),
+            relay_stats: RelayStats::new(&metrics),
+            peer_stats: PeerStats::new(),
+            server_rt: metrics.gauge("server_rt_ms", &[]),
+            server_rt_histogram: metrics.histogram("server_rt_histogram_ms", &[], RT_BUCKETS),
+            stale_pong: Anomaly::new(
+                "pong的时间戳无法计算延迟",
+                metrics.counter("arith_anomaly", &[("kind", "stale_pong")]),
+            ),
+            metrics,
         };
         Self {
             inner: Arc::new(inner),
@@ -74,18 +184,20 @@ impl Deref for ChannelContext {
 /// 对称网络增加的udp socket数目，有助于增加打洞成功率
 pub const SYMMETRIC_CHANNEL_NUM: usize = 100;
 const PACKET_LOSS_RATE_DENOMINATOR: u32 = 100_0000;
+/// 服务器延迟的分桶，单位毫秒
+const RT_BUCKETS: &[u64] = &[10, 20, 50, 100, 200, 500, 1000];
 
 pub struct ContextInner {
     // 核心udp socket
     pub(crate) main_udp_socket: Vec<UdpSocket>,
     // 对称网络增加的udp socket
-    sub_udp_socket: RwLock<Vec<UdpSocket>>,
+    sub_udp_socket: RwLock<Vec<PooledSocket>>,
     // tcp数据发送器
     pub(crate) tcp_map: RwLock<HashMap<SocketAddr, PacketSender>>,
     // 路由信息
     pub route_table: RouteTable,
-    // 是否使用tcp连接服务器
-    is_tcp: bool,
+    // 连接服务器使用的协议，udp不通时切换到tcp
+    pub transport: ServerTransport,
     //状态
     state: AtomicBool,
     //控制丢包率，取值v=[0,100_0000] 丢包率r=v/100_0000
@@ -94,6 +206,89 @@ pub struct ContextInner {
     packet_delay: u32,
     main_index: AtomicUsize,
     use_ipv6: bool,
+    // 本地屏蔽的对端
+    pub block_list: BlockList,
+    // 按对端手动调整的压缩/加密
+    pub peer_features: PeerFeatures,
+    // 到服务器或直连对端的路由经过虚拟网卡且无法修复，暂停转发外部路由
+    pub route_conflict: AtomicBool,
+    // 服务端支持只认证不加密的中继包，握手时协商
+    pub relay_passthrough: AtomicBool,
+    // 本地启用紧凑编码，握手和打洞时向服务端和对端声明
+    pub compact_encoding: AtomicBool,
+    // 是否转发广播，关闭时丢弃发往广播地址和网络地址的包
+    pub broadcast: AtomicBool,
+    // 路径mtu黑洞检测和mss钳制
+    pub mtu_guard: MtuGuard,
+    // 出口已满时暂停读取网卡
+    pub backpressure: Backpressure,
+    // 新对端的建立过程
+    pub bring_up: BringUp,
+    // 本机发出的包的源地址检查
+    pub source_policy: SourcePolicy,
+    // 入站改写过的包写入网卡前的校验和处理
+    pub checksum: ChecksumPolicy,
+    // 发往虚拟网络的流量限速
+    pub rate_limit: RateLimit,
+    // 按来源对端限制接收速率
+    pub inbound_limit: InboundLimit,
+    // 局域网发现的对端地址
+    pub lan_peers: LanPeers,
+    // 路径切换期间的接收端重排
+    pub reorder: Reorder,
+    // 后台探测流量的总预算
+    pub probe_budget: ProbeBudget,
+    // 打洞、nat探测使用的辅助socket
+    pub socket_pool: SocketPool,
+    // 自检包的接收端
+    pub self_probe: SelfProbe,
+    // 带宽测量的两端状态
+    pub bandwidth: BandwidthProbes,
+    // ping命令等待的回复
+    pub ping: EchoWaiters,
+    // 进程接管期间转发收到的数据
+    pub handover: Handover,
+    // 外层udp socket收到的icmp错误
+    pub icmp_errors: IcmpErrors,
+    // 对端事件日记，用于排查连接问题
+    pub diary: Diary,
+    // 按nat组合统计的打洞结果
+    pub punch_history: PunchHistory,
+    // 按服务器时钟约定双方同时打洞
+    pub punch_sync: PunchSync,
+    // 直连路径断开时改用中继，恢复后切回
+    pub route_health: RouteHealth,
+    // 负载高时要求未知路径上的对端回显cookie
+    pub cookie_guard: CookieGuard,
+    // 服务端请求执行的操作，默认全部拒绝
+    pub server_actions: ServerActions,
+    // 打洞协商使用的服务器
+    pub rendezvous: Rendezvous,
+    // 严格入站时的直连许可和等待批准的连接请求
+    pub reverse: ReverseConnect,
+    // 修改系统路由前写入的日志
+    pub intent_log: IntentLog,
+    // 本次添加到网卡的路由，停止时删除
+    pub tun_routes: Mutex<Vec<(Ipv4Addr, Ipv4Addr)>>,
+    // 工作线程的核心绑定和优先级
+    pub workers: Workers,
+    // 存活和就绪检查
+    pub health: Health,
+    // 调用方注册的数据包钩子
+    pub packet_hooks: PacketHooks,
+    // 丢包统计
+    pub drop_stats: DropStats,
+    // 经过服务器中继的流量
+    pub relay_stats: RelayStats,
+    // 按对端统计的流量，区分直连和中继
+    pub peer_stats: PeerStats,
+    // 和服务器之间的延迟
+    pub server_rt: Gauge,
+    pub server_rt_histogram: Histogram,
+    // 过期或者时间戳在未来的pong
+    pub stale_pong: Anomaly,
+    // 统计指标，stats命令从这里读取
+    pub metrics: Registry,
 }
 
 impl ContextInner {
@@ -111,7 +306,7 @@ impl ContextInner {
         self.sub_udp_socket.read().is_empty()
     }
     pub fn is_main_tcp(&self) -> bool {
-        self.is_tcp
+        self.transport.is_tcp()
     }
     pub fn is_udp_main(&self, route_key: &RouteKey) -> bool {
         !route_key.is_tcp() && route_key.index < self.main_udp_socket.len()
@@ -131,14 +326,18 @@ impl ContextInner {
                 if !write_guard.is_empty() {
                     return Ok(());
                 }
-                let mut vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
-                for _ in 0..SYMMETRIC_CHANNEL_NUM {
-                    let udp = UdpSocket::bind("0.0.0.0:0")?;
+                // 超过socket上限时使用更少的端口
+                let vec = self
+                    .socket_pool
+                    .acquire_many(SocketPurpose::SymmetricPunch, SYMMETRIC_CHANNEL_NUM);
+                if vec.is_empty() {
+                    return Ok(());
+                }
+                for udp in vec.iter() {
                     //副通道使用异步io
                     udp.set_nonblocking(true)?;
-                    vec.push(udp);
                 }
-                let mut mio_vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
+                let mut mio_vec = Vec::with_capacity(vec.len());
                 for udp in vec.iter() {
                     let udp_socket = mio::net::UdpSocket::from_std(udp.try_clone()?);
                     mio_vec.push(udp_socket);
@@ -168,6 +367,13 @@ impl ContextInner {
         }
         Ok(ports)
     }
+    /// 核心udp监听的本地地址
+    pub fn main_local_udp_addr(&self) -> Vec<SocketAddr> {
+        self.main_udp_socket
+            .iter()
+            .filter_map(|udp| udp.local_addr().ok())
+            .collect()
+    }
     pub fn send_tcp(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
         if let Some(tcp) = self.tcp_map.read().get(&addr) {
             tcp.try_send(buf)
@@ -176,6 +382,11 @@ impl ContextInner {
         }
     }
     pub fn send_main_udp(&self, index: usize, buf: &[u8], mut addr: SocketAddr) -> io::Result<()> {
+        let dest = addr;
+        if let Some(e) = self.icmp_errors.injected(&dest) {
+            self.send_main_udp_error(index, dest, &e);
+            return Err(e);
+        }
         if self.use_ipv6 {
             //如果是v4地址则需要转换成v6
             if let SocketAddr::V4(ipv4) = addr {
@@ -187,12 +398,25 @@ impl ContextInner {
                 ));
             }
         }
-        self.main_udp_socket[index].send_to(buf, addr)?;
+        if let Err(e) = self.main_udp_socket[index].send_to(buf, addr) {
+            // linux上socket挂起的错误可能是之前发往其他地址的包触发的，以错误队列为准
+            if !cfg!(target_os = "linux") {
+                self.send_main_udp_error(index, dest, &e);
+            }
+            return Err(e);
+        }
         Ok(())
     }
+    /// 发送返回的不可达错误，对应到目的地址
+    fn send_main_udp_error(&self, index: usize, dest: SocketAddr, e: &io::Error) {
+        if let Some(kind) = IcmpKind::from_io(e) {
+            self.icmp_errors
+                .report(Some(RouteKey::new(false, index, dest)), kind);
+        }
+    }
     /// 将数据发送到默认通道，一般发往服务器才用此方法
     pub fn send_default(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
-        if self.is_tcp {
+        if self.is_main_tcp() {
             //服务端地址只在重连时检测变化
             self.send_tcp(buf, addr)
         } else {
@@ -228,33 +452,150 @@ impl ContextInner {
         id: &Ipv4Addr,
         server_addr: SocketAddr,
         send_default: bool,
+    ) -> io::Result<()> {
+        self.send_ipv4_by_id_(Outbound::Packet(buf), id, server_addr, send_default, None)
+    }
+    /// 发送网络数据，优先从路由缓存中查找路由
+    ///
+    /// buf的结构为|EXT_HEAD_LEN字节预留|V1格式的包|，发往支持V2的对端时原地编码
+    pub fn send_ipv4_by_id_cache(
+        &self,
+        buf: &mut [u8],
+        id: &Ipv4Addr,
+        server_addr: SocketAddr,
+        send_default: bool,
+        route_cache: &mut RouteCache,
+    ) -> io::Result<()> {
+        if buf.len() < compat::EXT_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no reserved head",
+            ));
+        }
+        self.send_ipv4_by_id_(
+            Outbound::Reserved(buf),
+            id,
+            server_addr,
+            send_default,
+            Some(route_cache),
+        )
+    }
+    fn send_ipv4_by_id_(
+        &self,
+        mut buf: Outbound,
+        id: &Ipv4Addr,
+        server_addr: SocketAddr,
+        send_default: bool,
+        route_cache: Option<&mut RouteCache>,
     ) -> io::Result<()> {
         if self.packet_loss_rate > 0 {
             if rand::thread_rng().gen_ratio(self.packet_loss_rate, PACKET_LOSS_RATE_DENOMINATOR) {
+                self.drop_stats.add_peer(DropReason::Simulated, *id);
                 return Ok(());
             }
         }
         if self.packet_delay > 0 {
             thread::sleep(Duration::from_millis(self.packet_delay as _));
         }
+        // 用户设置的路径偏好，没有设置时为Default
+        let choice = self.route_table.path_scores.choice(id);
+        let rs = match choice {
+            PathChoice::Relay => Err(io::Error::from(io::ErrorKind::NotFound)),
+            PathChoice::P2p if self.route_table.route_one_p2p(id).is_none() => {
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            }
+            _ => self.send_by_id_(&mut buf, id, route_cache),
+        };
+        let buf = buf.v1();
         //优先发到直连到地址
-        if let Err(e) = self.send_by_id(buf, id) {
+        if let Err(e) = rs {
             if e.kind() != io::ErrorKind::NotFound {
                 log::warn!("{}:{:?}", id, e);
             }
-            if !self.route_table.use_channel_type.is_only_p2p() && send_default {
+            if self.route_table.use_channel_type.is_only_p2p() || choice == PathChoice::P2p {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.backpressure.blocked();
+                }
+                self.drop_stats.add_peer(DropReason::NoRoute, *id);
+            } else if !send_default {
+                self.drop_stats.add_peer(DropReason::Offline, *id);
+            } else if !self.route_table.use_channel_type.is_only_relay()
+                && choice != PathChoice::Relay
+                && !self.bring_up.relay(*id)
+            {
+                // 建立过程中中继的包已达到上限，让出服务器链路给协商和打洞
+                self.drop_stats.add_peer(DropReason::BringUp, *id);
+            } else {
                 //符合条件再发到服务器转发
-                self.send_default(buf, server_addr)?;
+                if let Err(e) = self.send_default(buf, server_addr) {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        self.backpressure.blocked();
+                    }
+                    return Err(e);
+                }
+                self.backpressure.sent();
+                self.relay_stats.add_tx(buf.len());
+                self.peer_stats.tx(*id, buf.len(), false);
+                self.reorder.relayed(*id);
             }
+        } else {
+            self.backpressure.sent();
+            self.peer_stats.tx(*id, buf.len(), true);
         }
         Ok(())
     }
     /// 将数据发到指定id
     pub fn send_by_id(&self, buf: &[u8], id: &Ipv4Addr) -> io::Result<()> {
+        self.send_by_id_(&mut Outbound::Packet(buf), id, None)
+    }
+    fn send_by_id_(
+        &self,
+        buf: &mut Outbound,
+        id: &Ipv4Addr,
+        mut route_cache: Option<&mut RouteCache>,
+    ) -> io::Result<()> {
         let mut c = 0;
+        // 只有发往网卡的数据参与重排，重试时沿用同一个序号
+        let ip_turn = {
+            let v1 = buf.v1();
+            v1.len() > HEAD_LEN && Protocol::from(v1[1]) == Protocol::IpTurn
+        };
+        let mut seq = None;
         loop {
-            let route = self.route_table.get_route_by_id(c, id)?;
-            return if let Err(e) = self.send_by_key(buf, route.route_key()) {
+            let route = match route_cache.as_mut() {
+                Some(route_cache) if c == 0 => route_cache.get(&self.route_table, id)?,
+                _ => self.route_table.get_route_by_id(c, id)?,
+            };
+            if ip_turn && c == 0 {
+                seq = self.reorder.outbound(*id, route.route_key());
+            }
+            let rs = if route.is_wire_v2() {
+                // 对端支持才使用新的协议头
+                match buf {
+                    Outbound::Reserved(buf) => {
+                        compat::encode_v2_in_place(buf, 0)?;
+                        if let Some(seq) = seq {
+                            compat::set_sequence(buf, seq);
+                        }
+                        let rs = self.send_by_key(buf, route.route_key());
+                        // 重试和经过服务器中继时还要用V1格式
+                        compat::restore_v1_in_place(buf);
+                        rs
+                    }
+                    Outbound::Packet(buf) => {
+                        // 没有预留空间的不在热路径上，复制一次
+                        let mut out = vec![0u8; buf.len() + compat::EXT_HEAD_LEN];
+                        let len = compat::encode_v2(buf, 0, &mut out)?;
+                        if let Some(seq) = seq {
+                            compat::set_sequence(&mut out[..len], seq);
+                        }
+                        self.send_by_key(&out[..len], route.route_key())
+                    }
+                }
+            } else {
+                self.send_by_key(buf.v1(), route.route_key())
+            };
+            return if let Err(e) = rs {
                 //降低发送速率
                 if e.kind() == io::ErrorKind::WouldBlock {
                     c += 1;
@@ -290,8 +631,59 @@ impl ContextInner {
             Ok(())
         }
     }
-    pub fn remove_route(&self, ip: &Ipv4Addr, route_key: RouteKey) {
-        self.route_table.remove_route(ip, route_key)
+    pub fn remove_route(&self, ip: &Ipv4Addr, route_key: RouteKey, reason: EvictReason) {
+        self.route_table.remove_route(ip, route_key, reason)
+    }
+    /// 屏蔽对端，并断开已有的直连路由，对端公开了指纹时虚拟ip改变后继续屏蔽
+    pub fn block_peer(&self, ip: Ipv4Addr, fingerprint: &str) -> bool {
+        let rs = self.block_list.block_device(ip, fingerprint);
+        if rs {
+            self.diary.record(ip, DiaryEvent::Blocked);
+        }
+        self.route_table.remove_ip(&ip, EvictReason::Blocked);
+        rs
+    }
+    /// 设备列表更新后，屏蔽的设备换了ip时屏蔽新的ip
+    pub fn follow_blocked(&self, peers: &[PeerDeviceInfo]) {
+        let blocked = self
+            .block_list
+            .follow(peers.iter().map(|v| (v.virtual_ip, v.fingerprint.as_str())));
+        for ip in blocked {
+            log::info!("屏蔽的设备使用了新的ip {}", ip);
+            self.diary.record(ip, DiaryEvent::Blocked);
+            self.route_table.remove_ip(&ip, EvictReason::Blocked);
+        }
+    }
+    pub fn unblock_peer(&self, ip: &Ipv4Addr) -> bool {
+        let rs = self.block_list.unblock(ip);
+        if rs {
+            self.diary.record(*ip, DiaryEvent::Unblocked);
+        }
+        rs
+    }
+    /// 严格入站时允许和对端直连，返回实际的时长
+    pub fn allow_peer(&self, ip: Ipv4Addr, duration: Duration) -> Duration {
+        let duration = self.reverse.grant(ip, duration, Instant::now());
+        self.diary.record(ip, DiaryEvent::Allowed { duration });
+        duration
+    }
+    /// 撤销直连许可，严格入站时同时删除直连路由
+    pub fn revoke_peer(&self, ip: Ipv4Addr) -> bool {
+        if !self.reverse.revoke(&ip) {
+            return false;
+        }
+        self.diary.record(ip, DiaryEvent::AllowanceExpired);
+        if self.reverse.strict() {
+            self.route_table.remove_ip(&ip, EvictReason::Expired);
+        }
+        true
+    }
+    /// 删除到期的直连许可和直连路由，对端的流量回到服务器中继
+    pub fn expire_allowances(&self, now: Instant) {
+        for ip in self.reverse.expire(now) {
+            self.diary.record(ip, DiaryEvent::AllowanceExpired);
+            self.route_table.remove_ip(&ip, EvictReason::Expired);
+        }
     }
 }
 
@@ -301,21 +693,80 @@ pub struct RouteTable {
     first_latency: bool,
     channel_num: usize,
     use_channel_type: UseChannelType,
+    // 路由版本号，路由有变化时增加，用于使路由缓存失效
+    generation: AtomicUsize,
+    // 记录路径切换和路由淘汰
+    diary: Diary,
+    // 用户设置的路径权重和偏好
+    pub path_scores: PathScores,
 }
 
 impl RouteTable {
-    fn new(use_channel_type: UseChannelType, first_latency: bool, channel_num: usize) -> Self {
+    pub(crate) fn new(
+        use_channel_type: UseChannelType,
+        first_latency: bool,
+        channel_num: usize,
+        diary: Diary,
+    ) -> Self {
         Self {
             route_table: RwLock::new(HashMap::with_capacity(64)),
             use_channel_type,
             first_latency,
             channel_num,
+            generation: AtomicUsize::new(0),
+            diary,
+            path_scores: PathScores::new(),
         }
     }
 }
 
 impl RouteTable {
-    fn get_route_by_id(&self, index: usize, id: &Ipv4Addr) -> io::Result<Route> {
+    #[inline]
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+    /// 使路由缓存失效，需要在持有写锁时调用
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+    /// 记录被淘汰的路由，首选路由的地址或类型变化时记录路径切换，只是延迟变化不记录
+    fn record_(
+        &self,
+        id: Ipv4Addr,
+        before: Option<PathInfo>,
+        after: Option<PathInfo>,
+        evicted: Vec<Route>,
+        reason: EvictReason,
+    ) {
+        for route in evicted {
+            self.diary.record(
+                id,
+                DiaryEvent::Evicted {
+                    addr: route.addr,
+                    reason,
+                },
+            );
+        }
+        let switched = match (&before, &after) {
+            (Some(before), Some(after)) => {
+                before.addr != after.addr
+                    || before.is_tcp != after.is_tcp
+                    || before.p2p != after.p2p
+            }
+            (None, None) => false,
+            _ => true,
+        };
+        if switched {
+            self.diary.record(
+                id,
+                DiaryEvent::PathSwitch {
+                    from: before,
+                    to: after,
+                },
+            );
+        }
+    }
+    pub(crate) fn get_route_by_id(&self, index: usize, id: &Ipv4Addr) -> io::Result<Route> {
         if let Some((_count, v)) = self.route_table.read().get(id) {
             if self.first_latency {
                 if let Some((route, _)) = v.first() {
@@ -366,21 +817,38 @@ impl RouteTable {
             }
         }
         let mut route_table = self.route_table.write();
+        self.invalidate();
         let (_, list) = route_table
             .entry(id)
             .or_insert_with(|| (AtomicUsize::new(0), Vec::with_capacity(4)));
+        let before = list.first().map(|(route, _)| PathInfo::from(route));
+        let (replaced, truncated) = self.insert_(&id, list, route, only_if_absent);
+        let after = list.first().map(|(route, _)| PathInfo::from(route));
+        self.record_(id, None, None, replaced, EvictReason::Replaced);
+        self.record_(id, before, after, truncated, EvictReason::Truncated);
+    }
+    /// 返回被直连替换和超过数量被淘汰的路由
+    fn insert_(
+        &self,
+        id: &Ipv4Addr,
+        list: &mut Vec<(Route, AtomicCell<Instant>)>,
+        route: Route,
+        only_if_absent: bool,
+    ) -> (Vec<Route>, Vec<Route>) {
+        let key = route.route_key();
         let mut exist = false;
         for (x, time) in list.iter_mut() {
             if x.metric < route.metric && !self.first_latency {
                 //非优先延迟的情况下 不能比当前的路径更长
-                return;
+                return (Vec::new(), Vec::new());
             }
             if x.route_key() == key {
                 if only_if_absent {
-                    return;
+                    return (Vec::new(), Vec::new());
                 }
                 x.metric = route.metric;
                 x.rt = route.rt;
+                x.wire_version = route.wire_version;
                 exist = true;
                 time.store(Instant::now());
                 break;
@@ -388,11 +856,11 @@ impl RouteTable {
         }
         if exist {
             // 这个排序还有待优化，因为后加入的大概率排最后，被直接淘汰的概率也大，可能导致更好的通道被移除了
-            list.sort_by_key(|(k, _)| k.rt);
+            self.sort_(id, list);
             //如果延迟都稳定了，则去除多余通道
             for (route, _) in list.iter() {
                 if route.rt == DEFAULT_RT {
-                    return;
+                    return (Vec::new(), Vec::new());
                 }
             }
             //延迟优先模式需要更多的通道探测延迟最低的路线
@@ -401,25 +869,68 @@ impl RouteTable {
             } else {
                 self.channel_num
             };
-            self.truncate_(list, limit_len);
+            (Vec::new(), self.truncate_(list, limit_len))
         } else {
+            let mut replaced = Vec::new();
             if !self.first_latency {
                 if route.is_p2p() {
                     //非优先延迟的情况下 添加了直连的则排除非直连的
+                    replaced = list
+                        .iter()
+                        .filter(|(k, _)| !k.is_p2p())
+                        .map(|(k, _)| *k)
+                        .collect();
                     list.retain(|(k, _)| k.is_p2p());
                 }
             };
             //增加路由表容量，避免波动
             let limit_len = self.channel_num * 2;
-            list.sort_by_key(|(k, _)| k.rt);
-            self.truncate_(list, limit_len);
+            self.sort_(id, list);
+            let truncated = self.truncate_(list, limit_len);
             list.push((route, AtomicCell::new(Instant::now())));
+            (replaced, truncated)
         }
     }
-    fn truncate_(&self, list: &mut Vec<(Route, AtomicCell<Instant>)>, len: usize) {
-        if list.len() <= len {
+    /// 没有路径覆盖设置时先看延迟再看带宽；设置了权重时按分数，偏好直连时直连在前
+    fn sort_(&self, id: &Ipv4Addr, list: &mut [(Route, AtomicCell<Instant>)]) {
+        if !self.path_scores.active() {
+            list.sort_by_key(|(k, _)| k.order());
             return;
         }
+        let p2p_first = self.path_scores.preference(id) == Preference::P2p;
+        let weights = self.path_scores.effective(id);
+        let score =
+            |route: &Route| weights.map_or(0.0, |w| w.score(&self.path_scores.sample(*id, route)));
+        list.sort_by(|(a, _), (b, _)| {
+            (p2p_first && !a.is_p2p())
+                .cmp(&(p2p_first && !b.is_p2p()))
+                .then_with(|| score(a).total_cmp(&score(b)))
+                .then_with(|| a.order().cmp(&b.order()))
+        });
+    }
+    /// 修改全局路径权重后重新排序所有路由
+    pub fn set_path_weights(&self, weights: Option<PathWeights>) {
+        let mut write_guard = self.route_table.write();
+        self.path_scores.set_weights(weights);
+        self.invalidate();
+        for (id, (_, routes)) in write_guard.iter_mut() {
+            self.sort_(id, routes);
+        }
+    }
+    /// 修改对端的路径偏好，None表示取消覆盖
+    pub fn set_path_preference(&self, id: Ipv4Addr, preference: Option<PeerPreference>) {
+        let mut write_guard = self.route_table.write();
+        self.path_scores.set_peer(id, preference);
+        self.invalidate();
+        if let Some((_, routes)) = write_guard.get_mut(&id) {
+            self.sort_(&id, routes);
+        }
+    }
+    /// 返回被淘汰的路由
+    fn truncate_(&self, list: &mut Vec<(Route, AtomicCell<Instant>)>, len: usize) -> Vec<Route> {
+        if list.len() <= len {
+            return Vec::new();
+        }
         if self.first_latency {
             //找到第一个p2p通道
             if let Some(index) =
@@ -430,13 +941,13 @@ impl RouteTable {
                 if index >= len {
                     //保留第一个p2p通道
                     let route = list.remove(index);
-                    list.truncate(len - 1);
+                    let evicted = list.drain(len - 1..).map(|(k, _)| k).collect();
                     list.push(route);
-                    return;
+                    return evicted;
                 }
             }
         }
-        list.truncate(len);
+        list.drain(len..).map(|(k, _)| k).collect()
     }
     pub fn route(&self, id: &Ipv4Addr) -> Option<Vec<Route>> {
         if let Some((_, v)) = self.route_table.read().get(id) {
@@ -445,6 +956,13 @@ impl RouteTable {
             None
         }
     }
+    /// 是否已经有这条路径
+    pub fn has_route(&self, id: &Ipv4Addr, route_key: &RouteKey) -> bool {
+        match self.route_table.read().get(id) {
+            Some((_, v)) => v.iter().any(|(route, _)| route.route_key() == *route_key),
+            None => false,
+        }
+    }
     pub fn route_one(&self, id: &Ipv4Addr) -> Option<Route> {
         if let Some((_, v)) = self.route_table.read().get(id) {
             v.first().map(|(i, _)| *i)
@@ -508,6 +1026,13 @@ impl RouteTable {
         }
         list
     }
+    /// 直连的对端中仍使用旧协议头的数量，网关不计入
+    pub fn legacy_peer_num(&self, is_gateway: impl Fn(&Ipv4Addr) -> bool) -> usize {
+        self.route_table_p2p()
+            .iter()
+            .filter(|(ip, route)| !is_gateway(ip) && route.wire_version.is_legacy())
+            .count()
+    }
     pub fn route_table_one(&self) -> Vec<(Ipv4Addr, Route)> {
         let mut list = Vec::with_capacity(8);
         let table = self.route_table.read();
@@ -518,15 +1043,82 @@ impl RouteTable {
         }
         list
     }
-    pub fn remove_route(&self, id: &Ipv4Addr, route_key: RouteKey) {
+    pub fn remove_route(&self, id: &Ipv4Addr, route_key: RouteKey, reason: EvictReason) {
         let mut write_guard = self.route_table.write();
+        self.invalidate();
         if let Some((_, routes)) = write_guard.get_mut(id) {
+            let before = routes.first().map(|(route, _)| PathInfo::from(route));
+            let evicted = routes
+                .iter()
+                .filter(|(x, _)| x.route_key() == route_key)
+                .map(|(x, _)| *x)
+                .collect();
             routes.retain(|(x, _)| x.route_key() != route_key);
+            let after = routes.first().map(|(route, _)| PathInfo::from(route));
+            self.record_(*id, before, after, evicted, reason);
             if routes.is_empty() {
                 write_guard.remove(id);
             }
         }
     }
+    /// 收到icmp不可达的直连路由，延迟置为默认值排到最后，不再优先使用，收到心跳回应后恢复。
+    /// 返回是否有变化，已经是默认延迟的不重复处理
+    pub fn mark_suspect(&self, id: &Ipv4Addr, route_key: &RouteKey) -> bool {
+        let mut write_guard = self.route_table.write();
+        if let Some((_, routes)) = write_guard.get_mut(id) {
+            let before = routes.first().map(|(route, _)| PathInfo::from(route));
+            let mut changed = false;
+            for (route, _) in routes.iter_mut() {
+                if &route.route_key() == route_key && route.is_p2p() && route.rt != DEFAULT_RT {
+                    route.rt = DEFAULT_RT;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return false;
+            }
+            self.invalidate();
+            self.sort_(id, routes);
+            let after = routes.first().map(|(route, _)| PathInfo::from(route));
+            self.record_(*id, before, after, Vec::new(), EvictReason::Replaced);
+            return true;
+        }
+        false
+    }
+    /// 记录路径测得的带宽，延迟相同时优先使用带宽大的路径
+    pub fn set_bandwidth(&self, id: &Ipv4Addr, route_key: &RouteKey, kbps: u32) -> bool {
+        let mut write_guard = self.route_table.write();
+        if let Some((_, routes)) = write_guard.get_mut(id) {
+            let before = routes.first().map(|(route, _)| PathInfo::from(route));
+            let route = match routes.iter_mut().find(|(v, _)| &v.route_key() == route_key) {
+                Some((route, _)) => route,
+                None => return false,
+            };
+            route.bandwidth = kbps;
+            self.invalidate();
+            self.sort_(id, routes);
+            let after = routes.first().map(|(route, _)| PathInfo::from(route));
+            self.record_(*id, before, after, Vec::new(), EvictReason::Replaced);
+            return true;
+        }
+        false
+    }
+    pub fn remove_ip(&self, id: &Ipv4Addr, reason: EvictReason) {
+        let mut write_guard = self.route_table.write();
+        self.invalidate();
+        if let Some((_, routes)) = write_guard.remove(id) {
+            let before = routes.first().map(|(route, _)| PathInfo::from(route));
+            let evicted = routes.into_iter().map(|(x, _)| x).collect();
+            self.record_(*id, before, None, evicted, reason);
+        }
+    }
+    /// 所有路由中最近一次收到数据的时刻
+    pub fn last_read_time(&self, id: &Ipv4Addr) -> Option<Instant> {
+        self.route_table
+            .read()
+            .get(id)
+            .and_then(|(_, routes)| routes.iter().map(|(_, time)| time.load()).max())
+    }
     /// 更新路由入栈包的时刻，长时间没有收到数据的路由将会被剔除
     pub fn update_read_time(&self, id: &Ipv4Addr, route_key: &RouteKey) {
         if let Some((_, routes)) = self.route_table.read().get(id) {
@@ -539,3 +1131,102 @@ impl RouteTable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    use crate::channel::route_cache::RouteCache;
+    use crate::channel::{loopback_context, Route};
+    use crate::protocol::compat::{self, WireVersion};
+    use crate::protocol::{ip_turn_packet, NetPacket, Protocol, HEAD_LEN, MAX_TTL};
+
+    const V1_PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+    const V2_PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 4);
+    const RELAY_PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 5);
+
+    fn peer() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn packet(destination: Ipv4Addr) -> Vec<u8> {
+        let payload = [7u8; 32];
+        let mut buf = vec![0u8; HEAD_LEN + payload.len()];
+        let mut packet = NetPacket::new(&mut buf[..]).unwrap();
+        packet.set_default_version();
+        packet.set_protocol(Protocol::IpTurn);
+        packet.set_transport_protocol_into(ip_turn_packet::Protocol::Ipv4);
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_source(Ipv4Addr::new(10, 26, 0, 2));
+        packet.set_destination(destination);
+        packet.payload_mut().copy_from_slice(&payload);
+        buf
+    }
+
+    /// 收到一个包，返回线上版本和解码后的V1格式
+    fn recv(socket: &UdpSocket) -> (WireVersion, Vec<u8>) {
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        let version = WireVersion::of(&buf[..len]).unwrap();
+        let len = compat::decode_in_place(&mut buf[..len]).unwrap();
+        (version, buf[..len].to_vec())
+    }
+
+    #[test]
+    fn test_send_wire_version() {
+        let context = loopback_context();
+        let (v1_socket, v1_addr) = peer();
+        let (v2_socket, v2_addr) = peer();
+        let (relay_socket, relay_addr) = peer();
+        context
+            .route_table
+            .add_route(V1_PEER, Route::new(false, 0, v1_addr, 1, 10));
+        context.route_table.add_route(
+            V2_PEER,
+            Route::new(false, 0, v2_addr, 1, 10).with_wire_version(WireVersion::V2),
+        );
+        // 中继路由即使标记了V2也要用V1，中间节点不一定支持
+        let mut relay = Route::new(false, 0, relay_addr, 2, 10);
+        relay.wire_version = WireVersion::V2;
+        context.route_table.add_route(RELAY_PEER, relay);
+
+        // 旧版本对端收到的是V1
+        let v1_packet = packet(V1_PEER);
+        context.send_by_id(&v1_packet, &V1_PEER).unwrap();
+        assert_eq!(recv(&v1_socket), (WireVersion::V1, v1_packet));
+
+        // 新版本对端收到的是V2，解码后和原始包一致
+        let v2_packet = packet(V2_PEER);
+        context.send_by_id(&v2_packet, &V2_PEER).unwrap();
+        assert_eq!(recv(&v2_socket), (WireVersion::V2, v2_packet.clone()));
+
+        // 预留了扩展头空间的发送路径，发送后要恢复成V1格式
+        let server_addr = relay_addr;
+        let mut route_cache = RouteCache::new();
+        let mut buf = vec![0u8; compat::EXT_HEAD_LEN + v2_packet.len()];
+        buf[compat::EXT_HEAD_LEN..].copy_from_slice(&v2_packet);
+        context
+            .send_ipv4_by_id_cache(&mut buf, &V2_PEER, server_addr, false, &mut route_cache)
+            .unwrap();
+        assert_eq!(recv(&v2_socket), (WireVersion::V2, v2_packet.clone()));
+        assert_eq!(&buf[compat::EXT_HEAD_LEN..], &v2_packet[..]);
+
+        let relay_packet = packet(RELAY_PEER);
+        let mut buf = vec![0u8; compat::EXT_HEAD_LEN + relay_packet.len()];
+        buf[compat::EXT_HEAD_LEN..].copy_from_slice(&relay_packet);
+        context
+            .send_ipv4_by_id_cache(&mut buf, &RELAY_PEER, server_addr, false, &mut route_cache)
+            .unwrap();
+        assert_eq!(recv(&relay_socket), (WireVersion::V1, relay_packet));
+
+        // 只有直连的V1对端算作旧版本，网关不计入
+        assert_eq!(context.route_table.legacy_peer_num(|_| false), 1);
+        assert_eq!(context.route_table.legacy_peer_num(|ip| ip == &V1_PEER), 0);
+    }
+}
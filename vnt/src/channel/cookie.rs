@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::protocol::control_packet::COOKIE_LEN;
+use crate::util::metrics::{Counter, Registry};
+
+/// 每秒未验证的直连请求超过这个数量时要求对端回显cookie
+pub const DEFAULT_THRESHOLD: u32 = 64;
+/// 密钥轮换间隔，上一个密钥生成的cookie在下一个间隔内仍然有效
+const ROTATE: Duration = Duration::from_secs(120);
+/// 统计请求速率的窗口
+const WINDOW: Duration = Duration::from_secs(1);
+/// 每个窗口最多回复阈值这么多倍的cookie，超过的直接丢弃，避免被用来反射流量
+const CHALLENGE_FACTOR: u32 = 4;
+
+/// 对未验证的直连请求的处理
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// 正常处理，建立路由
+    Accept,
+    /// 只回复cookie，不建立路由
+    Challenge([u8; COOKIE_LEN]),
+    /// 负载太高，连cookie也不回复
+    Drop,
+}
+
+struct State {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+    window: Instant,
+    // 当前窗口内未验证的请求数和回复的cookie数
+    count: u32,
+    challenged: u32,
+}
+
+/// 无状态的cookie，防止伪造来源的直连请求占满路由表
+///
+/// 对端之间没有非对称加密的握手，未知路径上收到的ping就会添加路由，这是唯一按请求分配状态的地方。
+/// 未验证的请求速率超过阈值后，只回复由本地密钥和来源地址计算出的cookie，
+/// 对端在新的ping中原样带回，证明能收到发往该地址的包之后才建立路由。
+/// 整个过程不按来源保存任何状态，占用的内存和请求数量无关；
+/// 服务端握手的rsa运算在加密线程池中执行，队列长度另有限制
+pub struct CookieGuard {
+    // 0表示不检查
+    threshold: AtomicU32,
+    state: Mutex<State>,
+    challenged: Counter,
+    verified: Counter,
+    dropped: Counter,
+}
+
+impl CookieGuard {
+    pub fn new(registry: &Registry) -> Self {
+        let now = Instant::now();
+        Self {
+            threshold: AtomicU32::new(DEFAULT_THRESHOLD),
+            state: Mutex::new(State {
+                secret: rand::random(),
+                previous: rand::random(),
+                rotated: now,
+                window: now,
+                count: 0,
+                challenged: 0,
+            }),
+            challenged: registry.counter("handshake_cookie", &[("result", "challenged")]),
+            verified: registry.counter("handshake_cookie", &[("result", "verified")]),
+            dropped: registry.counter("handshake_cookie", &[("result", "dropped")]),
+        }
+    }
+    pub fn set_threshold(&self, threshold: u32) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+    pub fn threshold(&self) -> u32 {
+        self.threshold.load(Ordering::Relaxed)
+    }
+    /// 从没有路由的直连路径收到ping时调用，cookie是ping中带回的值
+    pub fn check(&self, addr: SocketAddr, cookie: Option<[u8; COOKIE_LEN]>) -> Verdict {
+        self.check_at(addr, cookie, Instant::now())
+    }
+    fn check_at(
+        &self,
+        addr: SocketAddr,
+        cookie: Option<[u8; COOKIE_LEN]>,
+        now: Instant,
+    ) -> Verdict {
+        let threshold = self.threshold();
+        if threshold == 0 {
+            return Verdict::Accept;
+        }
+        let mut state = self.state.lock();
+        if now.saturating_duration_since(state.rotated) >= ROTATE {
+            state.previous = state.secret;
+            state.secret = rand::random();
+            state.rotated = now;
+        }
+        if now.saturating_duration_since(state.window) >= WINDOW {
+            state.window = now;
+            state.count = 0;
+            state.challenged = 0;
+        }
+        state.count = state.count.saturating_add(1);
+        if state.count <= threshold {
+            return Verdict::Accept;
+        }
+        if let Some(cookie) = cookie {
+            if cookie == make_cookie(&state.secret, addr)
+                || cookie == make_cookie(&state.previous, addr)
+            {
+                self.verified.inc();
+                return Verdict::Accept;
+            }
+        }
+        if state.challenged >= threshold.saturating_mul(CHALLENGE_FACTOR) {
+            self.dropped.inc();
+            return Verdict::Drop;
+        }
+        state.challenged += 1;
+        self.challenged.inc();
+        Verdict::Challenge(make_cookie(&state.secret, addr))
+    }
+}
+
+fn make_cookie(secret: &[u8; 16], addr: SocketAddr) -> [u8; COOKIE_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    match addr {
+        SocketAddr::V4(addr) => hasher.update(addr.ip().to_ipv6_mapped().octets()),
+        SocketAddr::V6(addr) => hasher.update(addr.ip().octets()),
+    }
+    hasher.update(addr.port().to_be_bytes());
+    let mut cookie = [0; COOKIE_LEN];
+    cookie.copy_from_slice(&hasher.finalize()[..COOKIE_LEN]);
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::channel::cookie::{CookieGuard, Verdict, CHALLENGE_FACTOR, ROTATE, WINDOW};
+    use crate::util::metrics::Registry;
+
+    fn addr(n: u32) -> SocketAddr {
+        SocketAddr::from(((n | 0x0a00_0000).to_be_bytes(), (n % 60000) as u16 + 1024))
+    }
+
+    /// 大量伪造来源的请求只有阈值内的被接受，回复的cookie数量有上限，正常的对端带回cookie后仍能建立直连
+    #[test]
+    fn test_flood() {
+        let guard = CookieGuard::new(&Registry::new());
+        guard.set_threshold(8);
+        let now = Instant::now();
+        let legit = SocketAddr::from(([192, 168, 1, 3], 29872));
+        let mut accepted = 0;
+        let mut challenged = 0;
+        let mut legit_cookie = None;
+        for n in 0..100_000 {
+            match guard.check_at(addr(n), None, now) {
+                Verdict::Accept => accepted += 1,
+                Verdict::Challenge(_) => challenged += 1,
+                Verdict::Drop => {}
+            }
+            if n == 10 {
+                match guard.check_at(legit, None, now) {
+                    Verdict::Challenge(cookie) => legit_cookie = Some(cookie),
+                    verdict => panic!("{:?}", verdict),
+                }
+            }
+        }
+        assert_eq!(accepted, 8);
+        assert_eq!(challenged, 8 * CHALLENGE_FACTOR - 1);
+        let cookie = legit_cookie.unwrap();
+        assert_eq!(guard.check_at(legit, Some(cookie), now), Verdict::Accept);
+        // 其他地址不能使用这个cookie
+        assert_eq!(guard.check_at(addr(1), Some(cookie), now), Verdict::Drop);
+        // 下一个窗口重新计数
+        let later = now + WINDOW;
+        for _ in 0..8 {
+            assert_eq!(guard.check_at(addr(1), None, later), Verdict::Accept);
+        }
+        assert!(matches!(
+            guard.check_at(addr(1), None, later),
+            Verdict::Challenge(_)
+        ));
+        guard.set_threshold(0);
+        assert_eq!(guard.check_at(addr(1), None, later), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let guard = CookieGuard::new(&Registry::new());
+        guard.set_threshold(1);
+        let legit = SocketAddr::from(([192, 168, 1, 3], 29872));
+        let now = Instant::now();
+        assert_eq!(guard.check_at(legit, None, now), Verdict::Accept);
+        let cookie = match guard.check_at(legit, None, now) {
+            Verdict::Challenge(cookie) => cookie,
+            verdict => panic!("{:?}", verdict),
+        };
+        // 轮换一次后仍然有效，两次后失效
+        let later = now + ROTATE;
+        assert_eq!(guard.check_at(legit, None, later), Verdict::Accept);
+        assert_eq!(guard.check_at(legit, Some(cookie), later), Verdict::Accept);
+        let much_later = later + ROTATE + Duration::from_secs(1);
+        assert_eq!(guard.check_at(legit, None, much_later), Verdict::Accept);
+        assert!(matches!(
+            guard.check_at(legit, Some(cookie), much_later),
+            Verdict::Challenge(_)
+        ));
+    }
+}
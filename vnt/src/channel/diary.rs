@@ -0,0 +1,586 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::channel::{Route, DEFAULT_RT};
+
+/// 每个对端保留的事件数
+pub const PEER_CAPACITY: usize = 200;
+/// 保留日记的对端数量上限，超过时丢弃最久没有新事件的对端
+pub const PEER_LIMIT: usize = 256;
+/// 所有对端的事件按时间顺序保留的条数
+pub const HISTORY_CAPACITY: usize = 5000;
+
+/// 路由被删除的原因
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EvictReason {
+    /// 长时间没有收到数据
+    Idle,
+    /// 休眠唤醒后清理直连路由
+    Resume,
+    /// 对端通知下线
+    Offline,
+    /// 对端通知地址变化
+    EndpointChange,
+    Blocked,
+    /// 超过通道数量被淘汰
+    Truncated,
+    /// 有了直连路由，淘汰中继路由
+    Replaced,
+    /// 连续多次探测没有回应
+    Unreachable,
+    /// 严格入站时直连许可到期
+    Expired,
+}
+
+impl EvictReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictReason::Idle => "idle",
+            EvictReason::Resume => "resume",
+            EvictReason::Offline => "offline",
+            EvictReason::EndpointChange => "endpoint_change",
+            EvictReason::Blocked => "blocked",
+            EvictReason::Truncated => "truncated",
+            EvictReason::Replaced => "replaced_by_p2p",
+            EvictReason::Unreachable => "unreachable",
+            EvictReason::Expired => "allowance_expired",
+        }
+    }
+}
+
+/// 路径切换前后的路由
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PathInfo {
+    pub addr: SocketAddr,
+    pub is_tcp: bool,
+    pub p2p: bool,
+    /// 还没有测得延迟时为None
+    pub rt: Option<i64>,
+}
+
+impl From<&Route> for PathInfo {
+    fn from(route: &Route) -> Self {
+        Self {
+            addr: route.addr,
+            is_tcp: route.is_tcp,
+            p2p: route.is_p2p(),
+            rt: (route.rt != DEFAULT_RT).then_some(route.rt),
+        }
+    }
+}
+
+impl Display for PathInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            if self.p2p { "p2p" } else { "relay" },
+            if self.is_tcp { "tcp" } else { "udp" },
+            self.addr
+        )?;
+        match self.rt {
+            Some(rt) => write!(f, " {}ms", rt),
+            None => write!(f, " ?ms"),
+        }
+    }
+}
+
+/// 对端生命周期中的重要事件
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DiaryEvent {
+    /// 通过服务器发起打洞协商
+    HandshakeStart,
+    /// 直连路径验证通过
+    HandshakeOk {
+        elapsed: Duration,
+    },
+    HandshakeFail {
+        reason: &'static str,
+    },
+    /// 按对方的nat类型选择的打洞方式
+    PunchAttempt {
+        strategy: &'static str,
+        tcp: bool,
+    },
+    PunchOk {
+        addr: SocketAddr,
+    },
+    /// 上一次打洞没有建立直连
+    PunchFail,
+    /// 按服务器时钟测得的双方开始打洞的时间差
+    PunchSkew {
+        skew: Duration,
+    },
+    /// 首选路由变化，None表示没有路由
+    PathSwitch {
+        from: Option<PathInfo>,
+        to: Option<PathInfo>,
+    },
+    Evicted {
+        addr: SocketAddr,
+        reason: EvictReason,
+    },
+    EndpointChange,
+    /// 发往直连地址的包收到icmp不可达
+    Unreachable {
+        addr: SocketAddr,
+        kind: &'static str,
+    },
+    Blocked,
+    Unblocked,
+    /// 严格入站时允许直连
+    Allowed {
+        duration: Duration,
+    },
+    AllowanceExpired,
+}
+
+impl DiaryEvent {
+    /// 'events --type'可以使用的类型名
+    pub const KINDS: [&'static str; 15] = [
+        "HandshakeStart",
+        "HandshakeOk",
+        "HandshakeFail",
+        "PunchAttempt",
+        "PunchOk",
+        "PunchFail",
+        "PunchSkew",
+        "PathSwitch",
+        "Evicted",
+        "EndpointChange",
+        "Unreachable",
+        "Blocked",
+        "Unblocked",
+        "Allowed",
+        "AllowanceExpired",
+    ];
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DiaryEvent::HandshakeStart => "HandshakeStart",
+            DiaryEvent::HandshakeOk { .. } => "HandshakeOk",
+            DiaryEvent::HandshakeFail { .. } => "HandshakeFail",
+            DiaryEvent::PunchAttempt { .. } => "PunchAttempt",
+            DiaryEvent::PunchOk { .. } => "PunchOk",
+            DiaryEvent::PunchFail => "PunchFail",
+            DiaryEvent::PunchSkew { .. } => "PunchSkew",
+            DiaryEvent::PathSwitch { .. } => "PathSwitch",
+            DiaryEvent::Evicted { .. } => "Evicted",
+            DiaryEvent::EndpointChange => "EndpointChange",
+            DiaryEvent::Unreachable { .. } => "Unreachable",
+            DiaryEvent::Blocked => "Blocked",
+            DiaryEvent::Unblocked => "Unblocked",
+            DiaryEvent::Allowed { .. } => "Allowed",
+            DiaryEvent::AllowanceExpired => "AllowanceExpired",
+        }
+    }
+    /// 不区分大小写，返回KINDS中的名称
+    pub fn parse_kind(text: &str) -> Option<&'static str> {
+        Self::KINDS
+            .iter()
+            .find(|v| v.eq_ignore_ascii_case(text))
+            .copied()
+    }
+}
+
+impl Display for DiaryEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fn path(path: &Option<PathInfo>) -> String {
+            path.map_or_else(|| "none".to_string(), |v| v.to_string())
+        }
+        match self {
+            DiaryEvent::HandshakeStart => write!(f, "handshake start"),
+            DiaryEvent::HandshakeOk { elapsed } => {
+                write!(f, "handshake ok in {}ms", elapsed.as_millis())
+            }
+            DiaryEvent::HandshakeFail { reason } => write!(f, "handshake failed: {}", reason),
+            DiaryEvent::PunchAttempt { strategy, tcp } => {
+                write!(f, "punch attempt strategy={}", strategy)?;
+                if *tcp {
+                    write!(f, " +tcp")?;
+                }
+                Ok(())
+            }
+            DiaryEvent::PunchOk { addr } => write!(f, "punch ok via {}", addr),
+            DiaryEvent::PunchFail => write!(f, "punch failed, no direct path"),
+            DiaryEvent::PunchSkew { skew } => {
+                write!(f, "punch skew {}ms from peer", skew.as_millis())
+            }
+            DiaryEvent::PathSwitch { from, to } => {
+                write!(f, "path switch {} -> {}", path(from), path(to))
+            }
+            DiaryEvent::Evicted { addr, reason } => {
+                write!(f, "route {} evicted: {}", addr, reason.name())
+            }
+            DiaryEvent::EndpointChange => write!(f, "peer endpoint changed"),
+            DiaryEvent::Unreachable { addr, kind } => write!(f, "{} from {}", kind, addr),
+            DiaryEvent::Blocked => write!(f, "blocked"),
+            DiaryEvent::Unblocked => write!(f, "unblocked"),
+            DiaryEvent::Allowed { duration } => {
+                write!(f, "direct allowed for {}s", duration.as_secs())
+            }
+            DiaryEvent::AllowanceExpired => write!(f, "direct allowance expired"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiaryEntry {
+    /// unix时间，毫秒
+    pub time: u64,
+    pub event: DiaryEvent,
+}
+
+/// 事件历史中的一条，包含所有对端
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// unix时间，毫秒
+    pub time: u64,
+    pub peer: Ipv4Addr,
+    pub event: DiaryEvent,
+}
+
+/// 查询事件历史的条件，同时设置时都要满足
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    /// unix时间，毫秒，不早于这个时间
+    pub since: Option<u64>,
+    pub peer: Option<Ipv4Addr>,
+    /// DiaryEvent::KINDS中的名称
+    pub kind: Option<&'static str>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        !matches!(self.since, Some(since) if entry.time < since)
+            && !matches!(self.peer, Some(peer) if entry.peer != peer)
+            && !matches!(self.kind, Some(kind) if entry.event.kind() != kind)
+    }
+}
+
+struct Page {
+    // 最后一次写入的序号，用于淘汰对端
+    last: u64,
+    entries: VecDeque<DiaryEntry>,
+}
+
+/// 每个对端的事件日记，用于事后排查连接问题
+///
+/// 每个对端最多保留PEER_CAPACITY条，最多保留PEER_LIMIT个对端，只在内存中。
+/// 另外所有对端的事件按时间顺序保留在一个有上限的历史中，可以按时间、对端和类型查询。
+/// 开启mirror时同时以debug级别写入日志
+#[derive(Clone)]
+pub struct Diary {
+    inner: Arc<DiaryInner>,
+}
+
+struct DiaryInner {
+    capacity: usize,
+    peer_limit: usize,
+    mirror: AtomicBool,
+    seq: AtomicU64,
+    pages: Mutex<HashMap<Ipv4Addr, Page>>,
+    history_capacity: AtomicUsize,
+    history: Mutex<VecDeque<HistoryEntry>>,
+}
+
+impl Default for Diary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Diary {
+    pub fn new() -> Self {
+        Self::with_limits(PEER_CAPACITY, PEER_LIMIT)
+    }
+    fn with_limits(capacity: usize, peer_limit: usize) -> Self {
+        Self {
+            inner: Arc::new(DiaryInner {
+                capacity,
+                peer_limit,
+                mirror: AtomicBool::new(false),
+                seq: AtomicU64::new(0),
+                pages: Mutex::new(HashMap::with_capacity(16)),
+                history_capacity: AtomicUsize::new(HISTORY_CAPACITY),
+                history: Mutex::new(VecDeque::with_capacity(64)),
+            }),
+        }
+    }
+    /// 同时写入debug日志
+    pub fn set_mirror(&self, mirror: bool) {
+        self.inner.mirror.store(mirror, Ordering::Relaxed);
+    }
+    /// 事件历史保留的条数，0表示不保留
+    pub fn set_history_capacity(&self, capacity: usize) {
+        self.inner
+            .history_capacity
+            .store(capacity, Ordering::Relaxed);
+        let mut history = self.inner.history.lock();
+        while history.len() > capacity {
+            history.pop_front();
+        }
+        history.shrink_to(capacity);
+    }
+    pub fn record(&self, peer: Ipv4Addr, event: DiaryEvent) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_millis() as u64);
+        self.record_at(peer, event, time)
+    }
+    fn record_at(&self, peer: Ipv4Addr, event: DiaryEvent, time: u64) {
+        if self.inner.mirror.load(Ordering::Relaxed) {
+            log::debug!("diary {} {}", peer, event);
+        }
+        let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
+        let mut pages = self.inner.pages.lock();
+        if !pages.contains_key(&peer) && pages.len() >= self.inner.peer_limit {
+            let oldest = pages
+                .iter()
+                .min_by_key(|(_, page)| page.last)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                pages.remove(&oldest);
+            }
+        }
+        let page = pages.entry(peer).or_insert_with(|| Page {
+            last: seq,
+            entries: VecDeque::with_capacity(16),
+        });
+        if page.entries.len() >= self.inner.capacity {
+            page.entries.pop_front();
+        }
+        page.last = seq;
+        page.entries.push_back(DiaryEntry {
+            time,
+            event: event.clone(),
+        });
+        drop(pages);
+        let capacity = self.inner.history_capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut history = self.inner.history.lock();
+        while history.len() >= capacity {
+            history.pop_front();
+        }
+        history.push_back(HistoryEntry { time, peer, event });
+    }
+    /// 对端的事件，按时间顺序
+    pub fn entries(&self, peer: &Ipv4Addr) -> Vec<DiaryEntry> {
+        self.inner
+            .pages
+            .lock()
+            .get(peer)
+            .map(|page| page.entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    /// 删除对端的日记和历史中的事件
+    pub fn forget(&self, peer: &Ipv4Addr) -> bool {
+        self.inner.history.lock().retain(|v| v.peer != *peer);
+        self.inner.pages.lock().remove(peer).is_some()
+    }
+    /// 历史中满足条件的事件，按时间顺序
+    pub fn history(&self, filter: &EventFilter) -> Vec<HistoryEntry> {
+        self.inner
+            .history
+            .lock()
+            .iter()
+            .filter(|v| filter.matches(v))
+            .cloned()
+            .collect()
+    }
+    /// 有日记的对端
+    pub fn peers(&self) -> Vec<Ipv4Addr> {
+        let mut list: Vec<Ipv4Addr> = self.inner.pages.lock().keys().cloned().collect();
+        list.sort();
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::channel::context::RouteTable;
+    use crate::channel::diary::{Diary, DiaryEvent, EventFilter, EvictReason};
+    use crate::channel::{Route, UseChannelType};
+
+    #[test]
+    fn test_ring() {
+        let diary = Diary::with_limits(3, 2);
+        let a = Ipv4Addr::new(10, 26, 0, 2);
+        let b = Ipv4Addr::new(10, 26, 0, 3);
+        let c = Ipv4Addr::new(10, 26, 0, 4);
+        for time in 0..5 {
+            diary.record_at(a, DiaryEvent::HandshakeStart, time);
+        }
+        // 只保留最新的3条，按时间顺序
+        let times: Vec<u64> = diary.entries(&a).iter().map(|v| v.time).collect();
+        assert_eq!(times, vec![2, 3, 4]);
+        diary.record_at(b, DiaryEvent::Blocked, 5);
+        diary.record_at(a, DiaryEvent::Unblocked, 6);
+        // 超过对端上限时淘汰最久没有新事件的b
+        diary.record_at(c, DiaryEvent::PunchFail, 7);
+        assert_eq!(diary.peers(), vec![a, c]);
+        assert!(diary.entries(&b).is_empty());
+        assert_eq!(
+            diary.entries(&a).last().unwrap().event,
+            DiaryEvent::Unblocked
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let diary = Diary::new();
+        diary.set_history_capacity(4);
+        let a = Ipv4Addr::new(10, 26, 0, 2);
+        let b = Ipv4Addr::new(10, 26, 0, 3);
+        let addr = SocketAddr::from(([1, 2, 3, 4], 1000));
+        diary.record_at(a, DiaryEvent::HandshakeStart, 1);
+        diary.record_at(b, DiaryEvent::PunchFail, 2);
+        diary.record_at(a, DiaryEvent::PunchOk { addr }, 3);
+        diary.record_at(
+            b,
+            DiaryEvent::PathSwitch {
+                from: None,
+                to: None,
+            },
+            4,
+        );
+        diary.record_at(
+            a,
+            DiaryEvent::PathSwitch {
+                from: None,
+                to: None,
+            },
+            5,
+        );
+        // 超过上限时丢弃最早的，和每个对端的日记无关
+        let all = diary.history(&EventFilter::default());
+        assert_eq!(
+            all.iter().map(|v| v.time).collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+        assert_eq!(diary.entries(&a).len(), 3);
+
+        let times = |filter: EventFilter| -> Vec<u64> {
+            diary.history(&filter).iter().map(|v| v.time).collect()
+        };
+        let kind = DiaryEvent::parse_kind("pathswitch");
+        assert_eq!(kind, Some("PathSwitch"));
+        assert_eq!(
+            times(EventFilter {
+                kind,
+                ..Default::default()
+            }),
+            vec![4, 5]
+        );
+        assert_eq!(
+            times(EventFilter {
+                peer: Some(a),
+                kind,
+                ..Default::default()
+            }),
+            vec![5]
+        );
+        assert_eq!(
+            times(EventFilter {
+                since: Some(3),
+                peer: Some(a),
+                kind: None,
+            }),
+            vec![3, 5]
+        );
+        // 没有满足所有条件的事件
+        assert!(times(EventFilter {
+            since: Some(5),
+            peer: Some(b),
+            kind: None,
+        })
+        .is_empty());
+        assert!(times(EventFilter {
+            kind: DiaryEvent::parse_kind("Blocked"),
+            ..Default::default()
+        })
+        .is_empty());
+        assert_eq!(DiaryEvent::parse_kind("PathChanged"), None);
+
+        diary.forget(&b);
+        assert_eq!(times(EventFilter::default()), vec![3, 5]);
+        diary.set_history_capacity(1);
+        assert_eq!(times(EventFilter::default()), vec![5]);
+        diary.set_history_capacity(0);
+        diary.record_at(a, DiaryEvent::Blocked, 6);
+        assert!(times(EventFilter::default()).is_empty());
+        assert_eq!(diary.entries(&a).len(), 4);
+    }
+
+    fn route(port: u16, metric: u8, rt: i64) -> Route {
+        Route::new(false, 0, SocketAddr::from(([1, 2, 3, 4], port)), metric, rt)
+    }
+
+    fn events(diary: &Diary, ip: &Ipv4Addr, from: usize) -> Vec<String> {
+        diary.entries(ip)[from..]
+            .iter()
+            .map(|v| v.event.to_string())
+            .collect()
+    }
+
+    /// 路由表中每个改变路径的地方都要记录
+    #[test]
+    fn test_route_table() {
+        let diary = Diary::new();
+        let table = RouteTable::new(UseChannelType::All, false, 1, diary.clone());
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        table.add_route(ip, route(1000, 2, 40));
+        assert_eq!(
+            events(&diary, &ip, 0),
+            vec!["path switch none -> relay udp 1.2.3.4:1000 40ms"]
+        );
+        // 延迟更新不算切换
+        table.add_route(ip, route(1000, 2, 50));
+        assert_eq!(diary.entries(&ip).len(), 1);
+        // 直连替换中继
+        table.add_route(ip, route(2000, 1, 10));
+        assert_eq!(
+            events(&diary, &ip, 1),
+            vec![
+                "route 1.2.3.4:1000 evicted: replaced_by_p2p",
+                "path switch relay udp 1.2.3.4:1000 50ms -> p2p udp 1.2.3.4:2000 10ms"
+            ]
+        );
+        // 超过通道数量被淘汰
+        table.add_route(ip, route(3000, 1, 5));
+        table.add_route(ip, route(4000, 1, 20));
+        table.add_route(ip, route(5000, 1, 30));
+        let list = events(&diary, &ip, 3);
+        assert!(list.contains(&"route 1.2.3.4:4000 evicted: truncated".to_string()));
+        assert!(list.contains(
+            &"path switch p2p udp 1.2.3.4:2000 10ms -> p2p udp 1.2.3.4:3000 5ms".to_string()
+        ));
+        let len = diary.entries(&ip).len();
+        let first = table.route_one(&ip).unwrap();
+        table.remove_route(&ip, first.route_key(), EvictReason::Idle);
+        let list = events(&diary, &ip, len);
+        assert_eq!(list[0], "route 1.2.3.4:3000 evicted: idle");
+        assert!(list[1].starts_with("path switch p2p udp 1.2.3.4:3000 5ms -> "));
+        let len = diary.entries(&ip).len();
+        let remain = table.route(&ip).unwrap().len();
+        table.remove_ip(&ip, EvictReason::Offline);
+        let list = events(&diary, &ip, len);
+        assert_eq!(list.len(), remain + 1);
+        assert!(list[..remain]
+            .iter()
+            .all(|v| v.ends_with("evicted: offline")));
+        assert!(list[remain].ends_with("-> none"));
+        // 没有路由时不记录
+        table.remove_ip(&ip, EvictReason::Offline);
+        assert_eq!(diary.entries(&ip).len(), len + remain + 1);
+    }
+}
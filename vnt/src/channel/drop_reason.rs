@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+use parking_lot::RwLock;
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 丢包原因
+///
+/// | 原因 | 可能的原因 | 处理办法 |
+/// |------|-----------|---------|
+/// | Malformed | 数据包格式错误、版本不兼容、被截断 | 确认两端版本一致，检查中间网络是否修改数据 |
+/// | DecryptFailed | 两端密码或加密模式不一致 | 检查`-w`和`--model`参数 |
+/// | TtlExpired | 转发层数过多或者包在路上停留太久 | 检查是否存在转发环路 |
+/// | Blocked | 对端已被本地屏蔽 | 使用`unblock`解除屏蔽 |
+/// | NoRoute | 目标不在虚拟网段内且没有配置点对网，或仅p2p模式下没有直连 | 检查`-i/-o`参数和`--use-channel` |
+/// | Offline | 未连接服务器，无法中继 | 检查服务器地址和网络 |
+/// | Denied | 点对网目标不在允许的网段内 | 检查对端的`-o`参数 |
+/// | Loop | 会导致环路的包，例如发往自身监听端口的代理数据 | 一般无需处理，持续增长时检查路由配置 |
+/// | Simulated | `--packet-loss`模拟的丢包 | 去掉`--packet-loss`参数 |
+/// | Hook | 调用方注册的数据包钩子丢弃，或钩子panic | 检查钩子的逻辑 |
+/// | BringUp | 和新对端建立直连期间中继的包超过上限 | 短暂出现属于正常，持续增长时调大`--bring-up-relay` |
+/// | SourceAddress | 本机发出的包源地址不是虚拟ip，例如服务绑定在物理网卡上，对端无法回复 | 服务绑定虚拟ip、用`-o`允许该网段，或使用`--snat-local` |
+/// | RateLimited | 超过限速，多个进程共享带宽时为分到的份额 | 调大`--shared-rate-limit` |
+/// | InboundLimited | 对端发来的数据超过接收限速，或被自动屏蔽 | 检查对端，或调大`--inbound-limit` |
+/// | Reserved | 发往网段的广播地址或网络地址，但关闭了广播 | 去掉`--no-broadcast`参数 |
+/// | Oversize | 从网卡读到的包超过mtu，网卡的mtu没有设置成功 | 检查`--mtu`参数和网卡的实际mtu |
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum DropReason {
+    Malformed,
+    DecryptFailed,
+    TtlExpired,
+    Blocked,
+    NoRoute,
+    Offline,
+    Denied,
+    Loop,
+    Simulated,
+    Hook,
+    BringUp,
+    SourceAddress,
+    RateLimited,
+    InboundLimited,
+    Reserved,
+    Oversize,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 16] = [
+        DropReason::Malformed,
+        DropReason::DecryptFailed,
+        DropReason::TtlExpired,
+        DropReason::Blocked,
+        DropReason::NoRoute,
+        DropReason::Offline,
+        DropReason::Denied,
+        DropReason::Loop,
+        DropReason::Simulated,
+        DropReason::Hook,
+        DropReason::BringUp,
+        DropReason::SourceAddress,
+        DropReason::RateLimited,
+        DropReason::InboundLimited,
+        DropReason::Reserved,
+        DropReason::Oversize,
+    ];
+    const COUNT: usize = Self::ALL.len();
+    fn index(&self) -> usize {
+        match self {
+            DropReason::Malformed => 0,
+            DropReason::DecryptFailed => 1,
+            DropReason::TtlExpired => 2,
+            DropReason::Blocked => 3,
+            DropReason::NoRoute => 4,
+            DropReason::Offline => 5,
+            DropReason::Denied => 6,
+            DropReason::Loop => 7,
+            DropReason::Simulated => 8,
+            DropReason::Hook => 9,
+            DropReason::BringUp => 10,
+            DropReason::SourceAddress => 11,
+            DropReason::RateLimited => 12,
+            DropReason::InboundLimited => 13,
+            DropReason::Reserved => 14,
+            DropReason::Oversize => 15,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::Malformed => "malformed",
+            DropReason::DecryptFailed => "decrypt_failed",
+            DropReason::TtlExpired => "ttl_expired",
+            DropReason::Blocked => "blocked",
+            DropReason::NoRoute => "no_route",
+            DropReason::Offline => "offline",
+            DropReason::Denied => "denied",
+            DropReason::Loop => "loop",
+            DropReason::Simulated => "simulated",
+            DropReason::Hook => "hook",
+            DropReason::BringUp => "bring_up",
+            DropReason::SourceAddress => "source_address",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::InboundLimited => "inbound_limited",
+            DropReason::Reserved => "reserved_address",
+            DropReason::Oversize => "oversize",
+        }
+    }
+    /// 可能的原因和处理办法，和类型注释中的表格保持一致
+    pub fn explain(&self) -> &'static str {
+        match self {
+            DropReason::Malformed => {
+                "packet malformed or truncated, check both sides run compatible versions"
+            }
+            DropReason::DecryptFailed => {
+                "decryption failed, check '-w' password and '--model' on both sides"
+            }
+            DropReason::TtlExpired => "ttl expired, check for relay loops",
+            DropReason::Blocked => "peer blocked locally, use 'unblock' to restore",
+            DropReason::NoRoute => "no route to destination, check '-i'/'-o' and '--use-channel'",
+            DropReason::Offline => "not connected to server, relay unavailable",
+            DropReason::Denied => "destination not allowed by '-o' of this device",
+            DropReason::Loop => {
+                "packet would loop back, check route configuration if it keeps growing"
+            }
+            DropReason::Simulated => "dropped by '--packet-loss'",
+            DropReason::Hook => "dropped by a packet hook of the embedding application",
+            DropReason::BringUp => {
+                "relay limit reached while connecting to a new peer, raise '--bring-up-relay' if it keeps growing"
+            }
+            DropReason::SourceAddress => {
+                "source address is not the virtual ip, bind the service to the virtual ip or use '--snat-local'"
+            }
+            DropReason::RateLimited => "over the rate limit, raise '--shared-rate-limit'",
+            DropReason::InboundLimited => {
+                "peer exceeded the inbound rate limit or was auto-blocked, check the peer or raise '--inbound-limit'"
+            }
+            DropReason::Reserved => {
+                "sent to the broadcast or network address while broadcast is disabled, remove '--no-broadcast'"
+            }
+            DropReason::Oversize => {
+                "packet read from the tun is larger than the mtu, check '--mtu' and the mtu of the tun device"
+            }
+        }
+    }
+}
+
+/// 按原因记录的对端数量上限，避免伪造来源导致内存增长
+const PEER_LIMIT: usize = 1024;
+/// 对端按ip分到不同的分片，不同对端的丢包不争用同一把锁
+const PEER_SHARDS: usize = 16;
+
+type PeerShard = RwLock<HashMap<Ipv4Addr, [AtomicU64; DropReason::COUNT]>>;
+
+/// 丢包统计
+pub struct DropStats {
+    counters: [Counter; DropReason::COUNT],
+    /// 已记录的对端只取分片的读锁，计数用原子操作
+    peers: [CachePadded<PeerShard>; PEER_SHARDS],
+}
+
+/// 单个原因的丢包统计
+#[derive(Clone, Debug)]
+pub struct DropStat {
+    pub reason: DropReason,
+    pub count: u64,
+    pub peers: Vec<(Ipv4Addr, u64)>,
+}
+
+impl DropStats {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            counters: DropReason::ALL
+                .map(|reason| registry.counter("packet_drops", &[("reason", reason.name())])),
+            peers: Default::default(),
+        }
+    }
+    #[inline]
+    pub fn add(&self, reason: DropReason) {
+        self.counters[reason.index()].inc();
+    }
+    /// 已知对端时同时记录到对端
+    pub fn add_peer(&self, reason: DropReason, peer: Ipv4Addr) {
+        self.add(reason);
+        let shard = &self.peers[shard_index(peer)];
+        if let Some(v) = shard.read().get(&peer) {
+            v[reason.index()].fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut guard = shard.write();
+        if guard.len() >= PEER_LIMIT / PEER_SHARDS && !guard.contains_key(&peer) {
+            return;
+        }
+        guard.entry(peer).or_insert_with(Default::default)[reason.index()]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.counters[reason.index()].get()
+    }
+    pub fn snapshot(&self) -> Vec<DropStat> {
+        let mut all: Vec<(Ipv4Addr, [u64; DropReason::COUNT])> = Vec::new();
+        for shard in self.peers.iter() {
+            all.extend(
+                shard
+                    .read()
+                    .iter()
+                    .map(|(ip, v)| (*ip, std::array::from_fn(|i| v[i].load(Ordering::Relaxed)))),
+            );
+        }
+        DropReason::ALL
+            .iter()
+            .map(|reason| {
+                let mut peers: Vec<(Ipv4Addr, u64)> = all
+                    .iter()
+                    .map(|(ip, v)| (*ip, v[reason.index()]))
+                    .filter(|(_, count)| *count > 0)
+                    .collect();
+                peers.sort();
+                DropStat {
+                    reason: *reason,
+                    count: self.count(*reason),
+                    peers,
+                }
+            })
+            .collect()
+    }
+}
+
+/// 虚拟网段内的ip主要是最后一个字节不同
+fn shard_index(peer: Ipv4Addr) -> usize {
+    let v = u32::from(peer);
+    ((v ^ (v >> 8)) as usize) & (PEER_SHARDS - 1)
+}
+
+#[test]
+fn test_drop_stats() {
+    let stats = DropStats::new(&Registry::new());
+    let peer = Ipv4Addr::new(10, 26, 0, 3);
+    stats.add(DropReason::Malformed);
+    stats.add_peer(DropReason::Blocked, peer);
+    stats.add_peer(DropReason::Blocked, peer);
+    assert_eq!(stats.count(DropReason::Malformed), 1);
+    assert_eq!(stats.count(DropReason::Blocked), 2);
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.len(), DropReason::ALL.len());
+    let blocked = snapshot
+        .iter()
+        .find(|v| v.reason == DropReason::Blocked)
+        .unwrap();
+    assert_eq!(blocked.peers, vec![(peer, 2)]);
+    for (index, reason) in DropReason::ALL.iter().enumerate() {
+        assert_eq!(reason.index(), index);
+    }
+}
+
+#[test]
+fn test_drop_stats_shards() {
+    let stats = DropStats::new(&Registry::new());
+    let handles: Vec<_> = (0..4u8)
+        .map(|t| {
+            let stats = &stats;
+            move || {
+                for i in 0..=255u8 {
+                    stats.add_peer(DropReason::NoRoute, Ipv4Addr::new(10, 26, t, i));
+                }
+            }
+        })
+        .collect();
+    std::thread::scope(|s| {
+        for f in handles {
+            s.spawn(f);
+        }
+    });
+    assert_eq!(stats.count(DropReason::NoRoute), 1024);
+    let snapshot = stats.snapshot();
+    let no_route = snapshot
+        .iter()
+        .find(|v| v.reason == DropReason::NoRoute)
+        .unwrap();
+    // 每个分片都有上限，总数不超过PEER_LIMIT
+    assert!(no_route.peers.len() <= PEER_LIMIT);
+    assert!(no_route.peers.len() > PEER_LIMIT / 2);
+    assert!(no_route.peers.iter().all(|(_, count)| *count == 1));
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+
+/// 对端的一个地址和使用的传输协议
+///
+/// 路由、打洞候选地址和状态文件都用这个表示，以后增加的传输协议作为新的变体加入。
+/// 文本格式中udp地址和SocketAddr相同(兼容旧的状态文件)，其他协议带前缀，如"tcp://1.2.3.4:29872"
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[non_exhaustive]
+pub enum Endpoint {
+    UdpV4(SocketAddrV4),
+    UdpV6(SocketAddrV6),
+    Tcp(SocketAddr),
+}
+
+const TCP_PREFIX: &str = "tcp://";
+const UDP_PREFIX: &str = "udp://";
+
+impl Endpoint {
+    pub fn new(is_tcp: bool, addr: SocketAddr) -> Self {
+        if is_tcp {
+            return Endpoint::Tcp(addr);
+        }
+        Endpoint::udp(addr)
+    }
+    pub fn udp(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Endpoint::UdpV4(addr),
+            SocketAddr::V6(addr) => Endpoint::UdpV6(addr),
+        }
+    }
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Endpoint::UdpV4(addr) => SocketAddr::V4(*addr),
+            Endpoint::UdpV6(addr) => SocketAddr::V6(*addr),
+            Endpoint::Tcp(addr) => *addr,
+        }
+    }
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, Endpoint::Tcp(_))
+    }
+    pub fn is_ipv6(&self) -> bool {
+        self.addr().is_ipv6()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::UdpV4(addr) => write!(f, "{}", addr),
+            Endpoint::UdpV6(addr) => write!(f, "{}", addr),
+            Endpoint::Tcp(addr) => write!(f, "{}{}", TCP_PREFIX, addr),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (is_tcp, addr) = if let Some(addr) = s.strip_prefix(TCP_PREFIX) {
+            (true, addr)
+        } else if let Some(addr) = s.strip_prefix(UDP_PREFIX) {
+            (false, addr)
+        } else if s.contains("://") {
+            return Err(format!("unknown transport '{}'", s));
+        } else {
+            (false, s)
+        };
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Ok(Endpoint::new(is_tcp, addr)),
+            Err(_) => Err(format!("invalid endpoint '{}'", s)),
+        }
+    }
+}
+
+/// 到对端的一条路径的状态
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PathState {
+    /// 对端告知的候选地址，还没有验证
+    Candidate,
+    /// 收到过对端的回应，在路由表中
+    Active,
+}
+
+/// 到对端的一条路径，地址、本地使用的socket和质量
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PeerPath {
+    pub endpoint: Endpoint,
+    // 本地socket的下标，udp是主端口的下标，tcp连接没有意义
+    pub index: usize,
+    // 跳数，1是直连
+    pub metric: u8,
+    // 延迟，毫秒，还没有测出时是默认值
+    pub rt: i64,
+    pub state: PathState,
+}
+
+impl PeerPath {
+    pub fn candidate(endpoint: Endpoint, index: usize) -> Self {
+        Self {
+            endpoint,
+            index,
+            metric: 1,
+            rt: super::DEFAULT_RT,
+            state: PathState::Candidate,
+        }
+    }
+    pub fn is_p2p(&self) -> bool {
+        self.metric == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::channel::endpoint::{Endpoint, PathState, PeerPath};
+    use crate::channel::{Route, RouteKey};
+
+    #[test]
+    fn test_endpoint_text() {
+        for text in [
+            "192.168.1.3:40000",
+            "[2001:db8::1]:29872",
+            "tcp://1.2.3.4:29872",
+        ] {
+            let endpoint: Endpoint = text.parse().unwrap();
+            assert_eq!(endpoint.to_string(), text);
+        }
+        // 旧的状态文件里是SocketAddr
+        let addr = SocketAddr::from(([192, 168, 1, 3], 40000));
+        assert_eq!(
+            addr.to_string().parse::<Endpoint>(),
+            Ok(Endpoint::udp(addr))
+        );
+        assert_eq!("udp://192.168.1.3:40000".parse(), Ok(Endpoint::udp(addr)));
+        assert!("[::1]:1".parse::<Endpoint>().unwrap().is_ipv6());
+        assert!("quic://1.2.3.4:1".parse::<Endpoint>().is_err());
+        assert!("1.2.3.4".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_route_path() {
+        let addr = SocketAddr::from(([192, 168, 1, 3], 40000));
+        let route = Route::new(true, 2, addr, 1, 12);
+        let path = route.path();
+        assert_eq!(path.endpoint, Endpoint::Tcp(addr));
+        assert_eq!((path.index, path.metric, path.rt), (2, 1, 12));
+        assert_eq!(path.state, PathState::Active);
+        let back = Route::from_path(&path);
+        assert_eq!(back.route_key(), route.route_key());
+        assert_eq!((back.metric, back.rt), (1, 12));
+
+        let key = RouteKey::from_endpoint(Endpoint::udp(addr), 1);
+        assert!(!key.is_tcp());
+        assert_eq!(
+            key.endpoint(),
+            Endpoint::UdpV4("192.168.1.3:40000".parse().unwrap())
+        );
+        assert!(PeerPath::candidate(key.endpoint(), 1).is_p2p());
+    }
+}
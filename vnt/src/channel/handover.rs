@@ -0,0 +1,318 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::Rng;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::handler::RecvChannelHandler;
+use crate::channel::{RouteKey, BUFFER_SIZE};
+use crate::util::StopManager;
+
+/// 旧进程转发的持续时间，之后停止
+pub const HANDOVER_WINDOW: Duration = Duration::from_secs(3);
+const VERSION: u8 = 1;
+/// 版本(1) 令牌(8) 通道下标(1) 地址类型(1)，后面是ip、端口(2)和收到的数据
+const HEAD_LEN: usize = 11;
+
+/// 接管期间旧进程把主udp端口收到的数据转发给新进程
+///
+/// 新旧进程用SO_REUSEPORT绑定同一个端口，内核按四元组把包分给其中一个，
+/// 旧进程收到的包原样加上来源地址转发到新进程的本地端口，由新进程当作自己收到的处理。
+/// 令牌在接管时通过本地控制连接交换，避免其他本地进程伪造
+pub struct Handover {
+    active: AtomicBool,
+    target: Mutex<Option<(UdpSocket, SocketAddr, u64)>>,
+    forwarded: AtomicU64,
+}
+
+impl Default for Handover {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handover {
+    pub fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            target: Mutex::new(None),
+            forwarded: AtomicU64::new(0),
+        }
+    }
+    /// 开始转发到新进程
+    pub fn start(&self, target: SocketAddr, token: u64) -> io::Result<()> {
+        let socket = if target.is_ipv4() {
+            UdpSocket::bind("127.0.0.1:0")?
+        } else {
+            UdpSocket::bind("[::1]:0")?
+        };
+        self.target.lock().replace((socket, target, token));
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+    /// 转发成功返回true，调用方不再处理这个包
+    pub fn forward(&self, index: usize, addr: SocketAddr, data: &[u8]) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        let guard = self.target.lock();
+        let (socket, target, token) = match guard.as_ref() {
+            Some(v) => v,
+            None => return false,
+        };
+        let frame = encode(*token, index, addr, data);
+        match socket.send_to(&frame, *target) {
+            Ok(_) => {
+                self.forwarded.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                log::warn!("接管转发失败 {:?}", e);
+                false
+            }
+        }
+    }
+    /// 已经转发的包数
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+}
+
+fn encode(token: u64, index: usize, addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEAD_LEN + 18 + data.len());
+    frame.push(VERSION);
+    frame.extend_from_slice(&token.to_be_bytes());
+    frame.push(index as u8);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            frame.push(4);
+            frame.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            frame.push(6);
+            frame.extend_from_slice(&ip.octets());
+        }
+    }
+    frame.extend_from_slice(&addr.port().to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// 返回通道下标、来源地址和数据的起始位置，令牌不符时返回None
+fn decode(token: u64, frame: &[u8]) -> Option<(usize, SocketAddr, usize)> {
+    if frame.len() < HEAD_LEN || frame[0] != VERSION {
+        return None;
+    }
+    if u64::from_be_bytes(frame[1..9].try_into().ok()?) != token {
+        return None;
+    }
+    let index = frame[9] as usize;
+    let (ip, start) = match frame[10] {
+        4 if frame.len() >= HEAD_LEN + 6 => {
+            let octets: [u8; 4] = frame[HEAD_LEN..HEAD_LEN + 4].try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(octets)), HEAD_LEN + 4)
+        }
+        6 if frame.len() >= HEAD_LEN + 18 => {
+            let octets: [u8; 16] = frame[HEAD_LEN..HEAD_LEN + 16].try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(octets)), HEAD_LEN + 16)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([frame[start], frame[start + 1]]);
+    Some((index, SocketAddr::new(ip, port), start + 2))
+}
+
+/// 新进程接收旧进程转发的数据，返回本地地址和令牌
+pub fn listen<H>(
+    stop_manager: StopManager,
+    recv_handler: H,
+    context: ChannelContext,
+) -> io::Result<(SocketAddr, u64)>
+where
+    H: RecvChannelHandler,
+{
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    let addr = socket.local_addr()?;
+    let token: u64 = rand::thread_rng().gen();
+    let wake = socket.try_clone()?;
+    let worker = stop_manager.add_listener("handover".into(), move || {
+        // 发一个空包唤醒阻塞的接收
+        let _ = wake.send_to(&[], addr);
+    })?;
+    std::thread::Builder::new()
+        .name("handover".into())
+        .spawn(move || {
+            receive(socket, token, recv_handler, context);
+            worker.stop_all();
+        })?;
+    Ok((addr, token))
+}
+
+fn receive<H>(socket: UdpSocket, token: u64, mut recv_handler: H, context: ChannelContext)
+where
+    H: RecvChannelHandler,
+{
+    let mut buf = [0; BUFFER_SIZE + HEAD_LEN + 18];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("接管接收 {:?}", e);
+                return;
+            }
+        };
+        if context.is_stop() {
+            return;
+        }
+        if !from.ip().is_loopback() {
+            continue;
+        }
+        if let Some((index, addr, start)) = decode(token, &buf[..len]) {
+            if index < context.main_udp_socket.len() {
+                recv_handler.handle(
+                    &mut buf[start..len],
+                    RouteKey::new(false, index, addr),
+                    &context,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+
+    use super::{decode, encode, Handover};
+
+    #[test]
+    fn test_frame() {
+        for addr in ["1.2.3.4:5000", "[2001:db8::1]:443", "[::ffff:1.2.3.4]:5000"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let frame = encode(7, 1, addr, b"data");
+            let (index, from, start) = decode(7, &frame).unwrap();
+            assert_eq!((index, from), (1, addr));
+            assert_eq!(&frame[start..], b"data");
+            // 令牌不对或者被截断
+            assert!(decode(8, &frame).is_none());
+            assert!(decode(7, &frame[..13]).is_none());
+        }
+    }
+
+    /// 新旧两个socket用SO_REUSEPORT绑定同一个端口，发送方连续发包，
+    /// 中途旧的开始转发并关闭，新的要收到每一个包，中断不超过转发窗口
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_takeover() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        fn bind(port: u16) -> UdpSocket {
+            let socket =
+                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+            socket.set_reuse_port(true).unwrap();
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            socket.bind(&addr.into()).unwrap();
+            let socket: UdpSocket = socket.into();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(20)))
+                .unwrap();
+            socket
+        }
+        const TOTAL: u32 = 600;
+        let old = bind(0);
+        let port = old.local_addr().unwrap().port();
+        // (序号, 收到的时刻)
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handover = Arc::new(Handover::new());
+        let stop_old = Arc::new(AtomicBool::new(false));
+        let old_thread = {
+            let received = received.clone();
+            let handover = handover.clone();
+            let stop_old = stop_old.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                loop {
+                    let stop = stop_old.load(Ordering::Acquire);
+                    match old.recv_from(&mut buf) {
+                        Ok((len, addr)) => {
+                            if !handover.forward(0, addr, &buf[..len]) {
+                                let seq = u32::from_be_bytes(buf[..4].try_into().unwrap());
+                                received.lock().unwrap().push((seq, Instant::now()));
+                            }
+                        }
+                        // 停止前收完缓冲区里的
+                        Err(_) if stop => return,
+                        Err(_) => {}
+                    }
+                }
+            })
+        };
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let send = |from: u32, to: u32| {
+            for seq in from..to {
+                sender.send_to(&seq.to_be_bytes(), target).unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        send(0, TOTAL / 3);
+        // 新进程启动
+        let new = bind(port);
+        let forward = UdpSocket::bind("127.0.0.1:0").unwrap();
+        forward
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let token = 99;
+        let done = Arc::new(AtomicBool::new(false));
+        // 新进程分别读取自己的端口和转发过来的
+        let new_threads: Vec<_> = [(new, false), (forward.try_clone().unwrap(), true)]
+            .into_iter()
+            .map(|(socket, forwarded)| {
+                let received = received.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 128];
+                    while !done.load(Ordering::Acquire) {
+                        if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                            let start = if forwarded {
+                                decode(token, &buf[..len]).unwrap().2
+                            } else {
+                                0
+                            };
+                            let seq = u32::from_be_bytes(buf[start..start + 4].try_into().unwrap());
+                            received.lock().unwrap().push((seq, Instant::now()));
+                        }
+                    }
+                })
+            })
+            .collect();
+        handover
+            .start(forward.local_addr().unwrap(), token)
+            .unwrap();
+        send(TOTAL / 3, TOTAL * 2 / 3);
+        stop_old.store(true, Ordering::Release);
+        old_thread.join().unwrap();
+        send(TOTAL * 2 / 3, TOTAL);
+        std::thread::sleep(Duration::from_millis(100));
+        done.store(true, Ordering::Release);
+        for thread in new_threads {
+            thread.join().unwrap();
+        }
+
+        let mut list = received.lock().unwrap().clone();
+        list.sort_by_key(|(seq, _)| *seq);
+        list.dedup_by_key(|(seq, _)| *seq);
+        assert!(list.len() as u32 >= TOTAL - 2, "received {}", list.len());
+        list.sort_by_key(|(_, time)| *time);
+        let max_gap = list.windows(2).map(|v| v[1].1 - v[0].1).max().unwrap();
+        assert!(max_gap < super::HANDOVER_WINDOW, "gap {:?}", max_gap);
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
+use crate::util::metrics::{Counter, Registry};
+
+/// 不可达的地址在这段时间内不再作为打洞候选，下一轮打洞时重新尝试
+pub const UNREACHABLE_TTL: Duration = Duration::from_secs(5);
+/// 记录的不可达地址上限，对称网络打洞时可能一次收到很多
+const RECENT_LIMIT: usize = 1024;
+/// 等待处理的错误上限，处理不过来时只计数
+const QUEUE_LEN: usize = 256;
+
+/// 外层udp socket收到的icmp错误类型
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum IcmpKind {
+    PortUnreachable,
+    HostUnreachable,
+    NetUnreachable,
+}
+
+impl IcmpKind {
+    pub const ALL: [IcmpKind; 3] = [
+        IcmpKind::PortUnreachable,
+        IcmpKind::HostUnreachable,
+        IcmpKind::NetUnreachable,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            IcmpKind::PortUnreachable => "port_unreachable",
+            IcmpKind::HostUnreachable => "host_unreachable",
+            IcmpKind::NetUnreachable => "net_unreachable",
+        }
+    }
+    fn index(&self) -> usize {
+        match self {
+            IcmpKind::PortUnreachable => 0,
+            IcmpKind::HostUnreachable => 1,
+            IcmpKind::NetUnreachable => 2,
+        }
+    }
+    /// 发送或接收返回的错误，其他错误(如分片需要)不是不可达，返回None
+    pub fn from_io(e: &io::Error) -> Option<IcmpKind> {
+        e.raw_os_error().and_then(IcmpKind::from_errno)
+    }
+    #[cfg(unix)]
+    pub fn from_errno(errno: i32) -> Option<IcmpKind> {
+        match errno {
+            libc::ECONNREFUSED => Some(IcmpKind::PortUnreachable),
+            libc::EHOSTUNREACH => Some(IcmpKind::HostUnreachable),
+            libc::ENETUNREACH => Some(IcmpKind::NetUnreachable),
+            _ => None,
+        }
+    }
+    #[cfg(windows)]
+    pub fn from_errno(errno: i32) -> Option<IcmpKind> {
+        match errno {
+            // WSAECONNRESET，udp收到端口不可达时由recvfrom返回
+            10054 => Some(IcmpKind::PortUnreachable),
+            // WSAEHOSTUNREACH
+            10065 => Some(IcmpKind::HostUnreachable),
+            // WSAENETUNREACH
+            10051 => Some(IcmpKind::NetUnreachable),
+            _ => None,
+        }
+    }
+    /// 注入错误时模拟系统返回的错误
+    fn to_io(self) -> io::Error {
+        #[cfg(unix)]
+        let errno = match self {
+            IcmpKind::PortUnreachable => libc::ECONNREFUSED,
+            IcmpKind::HostUnreachable => libc::EHOSTUNREACH,
+            IcmpKind::NetUnreachable => libc::ENETUNREACH,
+        };
+        #[cfg(windows)]
+        let errno = match self {
+            IcmpKind::PortUnreachable => 10054,
+            IcmpKind::HostUnreachable => 10065,
+            IcmpKind::NetUnreachable => 10051,
+        };
+        io::Error::from_raw_os_error(errno)
+    }
+}
+
+impl Display for IcmpKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 能对应到目的地址的icmp错误
+#[derive(Copy, Clone, Debug)]
+pub struct IcmpError {
+    pub route_key: RouteKey,
+    pub kind: IcmpKind,
+}
+
+/// 外层udp socket的icmp错误
+///
+/// linux上开启IP_RECVERR从错误队列读取，能知道出错的目的地址；
+/// 其他平台只能从发送失败得到目的地址，接收时返回的错误(如windows的10054)只计数。
+/// 对应到目的地址的错误交给后台线程立即处理，不用等心跳超时
+pub struct IcmpErrors {
+    counters: [Counter; 3],
+    sender: SyncSender<IcmpError>,
+    receiver: Mutex<Option<Receiver<IcmpError>>>,
+    recent: Mutex<HashMap<SocketAddr, (IcmpKind, Instant)>>,
+    // 测试用，发往这些地址时模拟发送失败
+    injecting: AtomicBool,
+    injected: Mutex<HashMap<SocketAddr, IcmpKind>>,
+}
+
+impl IcmpErrors {
+    pub fn new(registry: &Registry) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        Self {
+            counters: IcmpKind::ALL
+                .map(|kind| registry.counter("icmp_errors", &[("kind", kind.name())])),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            recent: Mutex::new(HashMap::new()),
+            injecting: AtomicBool::new(false),
+            injected: Mutex::new(HashMap::new()),
+        }
+    }
+    /// 只能取一次，由处理线程持有
+    pub fn take_receiver(&self) -> Option<Receiver<IcmpError>> {
+        self.receiver.lock().take()
+    }
+    pub fn count(&self, kind: IcmpKind) -> u64 {
+        self.counters[kind.index()].get()
+    }
+    /// 记录一个icmp错误，route_key为None表示无法对应到目的地址
+    pub fn report(&self, route_key: Option<RouteKey>, kind: IcmpKind) {
+        self.report_at(route_key, kind, Instant::now())
+    }
+    pub fn report_at(&self, route_key: Option<RouteKey>, kind: IcmpKind, now: Instant) {
+        self.counters[kind.index()].inc();
+        let route_key = match route_key {
+            Some(route_key) => route_key,
+            None => return,
+        };
+        {
+            let mut recent = self.recent.lock();
+            if recent.len() >= RECENT_LIMIT {
+                recent
+                    .retain(|_, (_, time)| now.saturating_duration_since(*time) < UNREACHABLE_TTL);
+            }
+            if recent.len() < RECENT_LIMIT {
+                recent.insert(route_key.addr, (kind, now));
+            }
+        }
+        if self.sender.try_send(IcmpError { route_key, kind }).is_err() {
+            log::debug!("icmp错误处理不过来 {:?} {}", route_key, kind);
+        }
+    }
+    /// 最近是否收到过这个地址的不可达
+    pub fn is_unreachable(&self, addr: &SocketAddr) -> bool {
+        self.is_unreachable_at(addr, Instant::now())
+    }
+    pub fn is_unreachable_at(&self, addr: &SocketAddr, now: Instant) -> bool {
+        match self.recent.lock().get(addr) {
+            Some((_, time)) => now.saturating_duration_since(*time) < UNREACHABLE_TTL,
+            None => false,
+        }
+    }
+    /// 注入错误，之后发往这个地址的包都返回对应的发送错误，None表示取消
+    pub fn inject(&self, addr: SocketAddr, kind: Option<IcmpKind>) {
+        let mut injected = self.injected.lock();
+        match kind {
+            Some(kind) => {
+                injected.insert(addr, kind);
+            }
+            None => {
+                injected.remove(&addr);
+            }
+        }
+        self.injecting
+            .store(!injected.is_empty(), Ordering::Relaxed);
+    }
+    /// 发送前检查是否注入了错误
+    #[inline]
+    pub fn injected(&self, addr: &SocketAddr) -> Option<io::Error> {
+        if !self.injecting.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.injected.lock().get(addr).map(|kind| kind.to_io())
+    }
+}
+
+/// 开启后icmp错误会放入socket的错误队列
+#[cfg(target_os = "linux")]
+pub fn enable_recv_err(socket: &impl std::os::fd::AsRawFd, ipv6: bool) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    let on: libc::c_int = 1;
+    let set = |level: libc::c_int, name: libc::c_int| unsafe {
+        if libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        ) == 0
+        {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    if ipv6 {
+        set(libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?;
+        // 双栈socket发往v4映射地址的错误走ipv4的处理，失败不影响v6
+        let _ = set(libc::IPPROTO_IP, libc::IP_RECVERR);
+        Ok(())
+    } else {
+        set(libc::IPPROTO_IP, libc::IP_RECVERR)
+    }
+}
+
+/// linux/errqueue.h的sock_extended_err
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SockExtendedErr {
+    ee_errno: u32,
+    ee_origin: u8,
+    ee_type: u8,
+    ee_code: u8,
+    ee_pad: u8,
+    ee_info: u32,
+    ee_data: u32,
+}
+
+/// 读取错误队列里的全部错误，返回出错的目的地址
+#[cfg(target_os = "linux")]
+pub fn read_errqueue(socket: &impl std::os::fd::AsRawFd) -> Vec<(SocketAddr, IcmpKind)> {
+    let fd = socket.as_raw_fd();
+    let mut list = Vec::new();
+    loop {
+        // 错误队列里带着原始包，只需要地址和错误码
+        let mut buf = [0u8; 64];
+        let mut control = [0u64; 64];
+        let mut name: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let rs = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if rs < 0 {
+            // EAGAIN表示已经读完
+            break;
+        }
+        let addr = unsafe { socket2::SockAddr::new(name, msg.msg_namelen) }.as_socket();
+        let mut kind = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level == libc::IPPROTO_IP && ty == libc::IP_RECVERR)
+                || (level == libc::IPPROTO_IPV6 && ty == libc::IPV6_RECVERR)
+            {
+                let err = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const SockExtendedErr)
+                };
+                kind = IcmpKind::from_errno(err.ee_errno as i32);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        if let (Some(addr), Some(kind)) = (addr, kind) {
+            list.push((unmapped(addr), kind));
+        }
+    }
+    list
+}
+
+/// 双栈socket上的v4映射地址还原成v4，和路由里的地址一致
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        if let Some(ip) = v6.ip().to_ipv4_mapped() {
+            return SocketAddr::new(ip.into(), v6.port());
+        }
+    }
+    addr
+}
+
+/// 主udp socket接收返回错误时调用，是icmp错误则返回true
+#[cfg(target_os = "linux")]
+pub fn recv_error(
+    context: &ChannelContext,
+    index: usize,
+    socket: &mio::net::UdpSocket,
+    e: &io::Error,
+) -> bool {
+    // 错误队列里有目的地址，recv返回的错误本身不再单独计数
+    drain_errqueue(context, index, socket);
+    IcmpKind::from_io(e).is_some()
+}
+
+/// 主udp socket接收返回错误时调用，是icmp错误则返回true
+#[cfg(not(target_os = "linux"))]
+pub fn recv_error(
+    context: &ChannelContext,
+    _index: usize,
+    _socket: &mio::net::UdpSocket,
+    e: &io::Error,
+) -> bool {
+    match IcmpKind::from_io(e) {
+        Some(kind) => {
+            // 不知道是发往哪个地址的包触发的，只计数
+            context.icmp_errors.report(None, kind);
+            true
+        }
+        None => false,
+    }
+}
+
+/// 只有linux有错误队列
+#[cfg(not(target_os = "linux"))]
+pub fn drain_errqueue(_context: &ChannelContext, _index: usize, _socket: &mio::net::UdpSocket) {}
+
+/// 读取错误队列并上报
+#[cfg(target_os = "linux")]
+pub fn drain_errqueue(context: &ChannelContext, index: usize, socket: &mio::net::UdpSocket) {
+    for (addr, kind) in read_errqueue(socket) {
+        log::debug!("icmp错误 index={} {} {}", index, addr, kind);
+        context
+            .icmp_errors
+            .report(Some(RouteKey::new(false, index, addr)), kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use crate::channel::context::RouteTable;
+    use crate::channel::diary::{Diary, DiaryEvent};
+    use crate::channel::icmp_error::{IcmpErrors, IcmpKind, UNREACHABLE_TTL};
+    use crate::channel::{Route, RouteKey, UseChannelType};
+    use crate::util::metrics::Registry;
+
+    #[test]
+    fn test_kind() {
+        for kind in IcmpKind::ALL {
+            assert_eq!(IcmpKind::from_io(&kind.to_io()), Some(kind));
+        }
+        assert_eq!(
+            IcmpKind::from_io(&std::io::Error::from(std::io::ErrorKind::WouldBlock)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_inject() {
+        let errors = IcmpErrors::new(&Registry::new());
+        let receiver = errors.take_receiver().unwrap();
+        assert!(errors.take_receiver().is_none());
+        let addr: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:1000".parse().unwrap();
+        assert!(errors.injected(&addr).is_none());
+        errors.inject(addr, Some(IcmpKind::HostUnreachable));
+        let e = errors.injected(&addr).unwrap();
+        assert!(errors.injected(&other).is_none());
+        let kind = IcmpKind::from_io(&e).unwrap();
+        assert_eq!(kind, IcmpKind::HostUnreachable);
+
+        let now = Instant::now();
+        errors.report_at(Some(RouteKey::new(false, 0, addr)), kind, now);
+        errors.report_at(None, IcmpKind::PortUnreachable, now);
+        assert_eq!(errors.count(IcmpKind::HostUnreachable), 1);
+        assert_eq!(errors.count(IcmpKind::PortUnreachable), 1);
+        // 没有目的地址的只计数
+        let error = receiver.try_recv().unwrap();
+        assert_eq!(error.route_key.addr, addr);
+        assert!(receiver.try_recv().is_err());
+
+        assert!(errors.is_unreachable_at(&addr, now + Duration::from_secs(1)));
+        assert!(!errors.is_unreachable_at(&addr, now + UNREACHABLE_TTL));
+        assert!(!errors.is_unreachable_at(&other, now));
+
+        errors.inject(addr, None);
+        assert!(errors.injected(&addr).is_none());
+    }
+
+    #[test]
+    fn test_mark_suspect() {
+        let diary = Diary::new();
+        let route_table = RouteTable::new(UseChannelType::All, false, 2, diary.clone());
+        let id = Ipv4Addr::new(10, 26, 0, 3);
+        let a = Route::new(false, 0, SocketAddr::from(([192, 0, 2, 1], 1000)), 1, 10);
+        let b = Route::new(false, 1, SocketAddr::from(([192, 0, 2, 1], 2000)), 1, 20);
+        route_table.add_route(id, a);
+        route_table.add_route(id, b);
+        assert_eq!(
+            route_table.route_one(&id).unwrap().route_key(),
+            a.route_key()
+        );
+        assert!(route_table.mark_suspect(&id, &a.route_key()));
+        // 已经是可疑的不重复处理
+        assert!(!route_table.mark_suspect(&id, &a.route_key()));
+        assert_eq!(
+            route_table.route_one(&id).unwrap().route_key(),
+            b.route_key()
+        );
+        assert_eq!(
+            route_table.get_route_by_id(0, &id).unwrap().route_key(),
+            b.route_key()
+        );
+        assert!(diary
+            .entries(&id)
+            .iter()
+            .any(|v| matches!(v.event, DiaryEvent::PathSwitch { .. })));
+        // 心跳回应恢复延迟
+        route_table.add_route(id, a);
+        assert_eq!(
+            route_table.route_one(&id).unwrap().route_key(),
+            a.route_key()
+        );
+    }
+}
@@ -4,6 +4,9 @@ use std::time::Duration;
 use crate::channel::context::ChannelContext;
 use crate::channel::Route;
 
+/// 路由超过这个时间没有收到数据就删除，心跳间隔3秒，至少要能容忍丢失一次心跳
+pub const DEFAULT_READ_IDLE: Duration = Duration::from_secs(10);
+
 pub struct Idle {
     read_idle: Duration,
     context: ChannelContext,
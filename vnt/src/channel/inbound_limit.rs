@@ -0,0 +1,420 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::protocol::Protocol;
+
+/// 默认每个对端每秒最多2万个包
+pub const DEFAULT_PPS: u64 = 20_000;
+/// 默认每个对端最多200Mbps
+pub const DEFAULT_BPS: u64 = 25_000_000;
+/// 连续超限这么多秒后自动屏蔽
+const AUTO_BLOCK_AFTER: u32 = 10;
+/// 自动屏蔽的时长
+pub const AUTO_BLOCK_COOLDOWN: Duration = Duration::from_secs(300);
+/// 同一个对端超限的提示间隔
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(10);
+/// 记录的对端数量上限，来源地址可以伪造
+const PEER_LIMIT: usize = 4096;
+/// 超过这个时间没有数据的对端可以被清理
+const PEER_IDLE: Duration = Duration::from_secs(60);
+/// 记录满时每次最多检查的对端数量
+const EVICT_SCAN: usize = 8;
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// 接收速率上限，0表示不限制
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Limit {
+    // 包/秒
+    pub pps: u64,
+    // 字节/秒
+    pub bps: u64,
+}
+
+impl Limit {
+    pub const UNLIMITED: Limit = Limit { pps: 0, bps: 0 };
+    pub fn new(pps: u64, bps: u64) -> Self {
+        Self { pps, bps }
+    }
+}
+
+/// 限速的判断结果
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    /// 丢弃，需要提示超限
+    Exceeded(Limit),
+    /// 丢弃，刚被自动屏蔽
+    Blocked(Limit, Duration),
+}
+
+impl Verdict {
+    pub fn is_pass(&self) -> bool {
+        self == &Verdict::Pass
+    }
+}
+
+struct PeerState {
+    limit: Limit,
+    // 当前1秒窗口的开始时间和计数
+    window: Instant,
+    packets: u64,
+    bytes: u64,
+    exceeded: bool,
+    // 连续超限的窗口数
+    over: u32,
+    last_notify: Option<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+impl PeerState {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            window: now,
+            packets: 0,
+            bytes: 0,
+            exceeded: false,
+            over: 0,
+            last_notify: None,
+            blocked_until: None,
+        }
+    }
+}
+
+struct Peers {
+    map: HashMap<Ipv4Addr, Arc<Mutex<PeerState>>>,
+    // 按加入顺序轮转，记录满时从队首找空闲的对端淘汰
+    clock: VecDeque<Ipv4Addr>,
+}
+
+impl Peers {
+    /// 最多检查EVICT_SCAN个对端，淘汰一个空闲且未被屏蔽的，其余的移到队尾
+    fn evict_one(&mut self, now: Instant) -> bool {
+        for _ in 0..EVICT_SCAN.min(self.clock.len()) {
+            let ip = match self.clock.pop_front() {
+                Some(ip) => ip,
+                None => break,
+            };
+            let idle = match self.map.get(&ip) {
+                Some(state) => {
+                    let state = state.lock();
+                    state.blocked_until.is_none()
+                        && now.saturating_duration_since(state.window) >= PEER_IDLE
+                }
+                None => continue,
+            };
+            if idle {
+                self.map.remove(&ip);
+                return true;
+            }
+            self.clock.push_back(ip);
+        }
+        false
+    }
+}
+
+/// 按来源对端限制写入网卡的速率
+///
+/// 每个对端单独计数，只在第一次出现时获取写锁，之后每个包只锁自己的状态。
+/// 只限制数据包，控制包(心跳、打洞)不受影响
+pub struct InboundLimit {
+    // 默认限速
+    pps: AtomicU64,
+    bps: AtomicU64,
+    auto_block: AtomicBool,
+    overrides: RwLock<HashMap<Ipv4Addr, Limit>>,
+    peers: RwLock<Peers>,
+}
+
+impl InboundLimit {
+    pub fn new() -> Self {
+        Self {
+            pps: AtomicU64::new(DEFAULT_PPS),
+            bps: AtomicU64::new(DEFAULT_BPS),
+            auto_block: AtomicBool::new(false),
+            overrides: RwLock::new(HashMap::new()),
+            peers: RwLock::new(Peers {
+                map: HashMap::with_capacity(16),
+                clock: VecDeque::with_capacity(16),
+            }),
+        }
+    }
+    pub fn set_default(&self, limit: Limit) {
+        self.pps.store(limit.pps, Ordering::Relaxed);
+        self.bps.store(limit.bps, Ordering::Relaxed);
+        let overrides = self.overrides.read();
+        for (ip, state) in self.peers.read().map.iter() {
+            if !overrides.contains_key(ip) {
+                state.lock().limit = limit;
+            }
+        }
+    }
+    pub fn default_limit(&self) -> Limit {
+        Limit::new(
+            self.pps.load(Ordering::Relaxed),
+            self.bps.load(Ordering::Relaxed),
+        )
+    }
+    pub fn set_auto_block(&self, enabled: bool) {
+        self.auto_block.store(enabled, Ordering::Relaxed);
+    }
+    /// 单独设置对端的限速，None恢复默认
+    pub fn set_peer(&self, peer: Ipv4Addr, limit: Option<Limit>) {
+        let effective = match limit {
+            Some(limit) => {
+                self.overrides.write().insert(peer, limit);
+                limit
+            }
+            None => {
+                self.overrides.write().remove(&peer);
+                self.default_limit()
+            }
+        };
+        if let Some(state) = self.peers.read().map.get(&peer) {
+            let mut state = state.lock();
+            state.limit = effective;
+            // 手动调整后解除自动屏蔽
+            state.blocked_until = None;
+            state.over = 0;
+        }
+    }
+    /// 单独设置过的对端和被自动屏蔽的对端，(ip,限速,是否单独设置,剩余屏蔽时间)
+    pub fn list(&self) -> Vec<(Ipv4Addr, Limit, bool, Option<Duration>)> {
+        let now = Instant::now();
+        let overrides = self.overrides.read();
+        let mut list: Vec<_> = overrides
+            .iter()
+            .map(|(ip, limit)| (*ip, *limit, true, None))
+            .collect();
+        for (ip, state) in self.peers.read().map.iter() {
+            let state = state.lock();
+            let blocked = state
+                .blocked_until
+                .filter(|until| *until > now)
+                .map(|until| until - now);
+            if blocked.is_none() {
+                continue;
+            }
+            match list.iter_mut().find(|v| v.0 == *ip) {
+                Some(v) => v.3 = blocked,
+                None => list.push((*ip, state.limit, false, blocked)),
+            }
+        }
+        list.sort_by_key(|v| v.0);
+        list
+    }
+    #[inline]
+    pub fn check(&self, peer: Ipv4Addr, protocol: Protocol, len: usize) -> Verdict {
+        if protocol != Protocol::IpTurn {
+            return Verdict::Pass;
+        }
+        self.check_at(peer, len, Instant::now())
+    }
+    pub fn check_at(&self, peer: Ipv4Addr, len: usize, now: Instant) -> Verdict {
+        let state = self.peers.read().map.get(&peer).cloned();
+        let state = match state {
+            Some(state) => state,
+            None => self.insert(peer, now),
+        };
+        let mut state = state.lock();
+        if let Some(until) = state.blocked_until {
+            if now < until {
+                return Verdict::Drop;
+            }
+            state.blocked_until = None;
+            state.over = 0;
+        }
+        if now.saturating_duration_since(state.window) >= WINDOW {
+            if state.exceeded {
+                state.over += 1;
+            } else {
+                state.over = 0;
+            }
+            // 中间有整秒没有数据时也算未超限
+            if now.saturating_duration_since(state.window) >= WINDOW * 2 {
+                state.over = 0;
+            }
+            state.window = now;
+            state.packets = 0;
+            state.bytes = 0;
+            state.exceeded = false;
+        }
+        state.packets += 1;
+        state.bytes += len as u64;
+        let limit = state.limit;
+        if (limit.pps == 0 || state.packets <= limit.pps)
+            && (limit.bps == 0 || state.bytes <= limit.bps)
+        {
+            return Verdict::Pass;
+        }
+        if !state.exceeded {
+            state.exceeded = true;
+            if state.over + 1 >= AUTO_BLOCK_AFTER && self.auto_block.load(Ordering::Relaxed) {
+                state.blocked_until = Some(now + AUTO_BLOCK_COOLDOWN);
+                state.last_notify = Some(now);
+                return Verdict::Blocked(limit, AUTO_BLOCK_COOLDOWN);
+            }
+            let notify = match state.last_notify {
+                Some(last) => now.saturating_duration_since(last) >= NOTIFY_INTERVAL,
+                None => true,
+            };
+            if notify {
+                state.last_notify = Some(now);
+                return Verdict::Exceeded(limit);
+            }
+        }
+        Verdict::Drop
+    }
+    fn insert(&self, peer: Ipv4Addr, now: Instant) -> Arc<Mutex<PeerState>> {
+        let limit = self
+            .overrides
+            .read()
+            .get(&peer)
+            .copied()
+            .unwrap_or_else(|| self.default_limit());
+        let mut peers = self.peers.write();
+        if let Some(state) = peers.map.get(&peer) {
+            return state.clone();
+        }
+        let state = Arc::new(Mutex::new(PeerState::new(limit, now)));
+        if peers.map.len() < PEER_LIMIT || peers.evict_one(now) {
+            peers.map.insert(peer, state.clone());
+            peers.clock.push_back(peer);
+        } else if let Some(shared) = peers.map.get(&Ipv4Addr::UNSPECIFIED) {
+            // 记录满了，新来源共用一个计数
+            return shared.clone();
+        } else {
+            // 共用的计数不参与淘汰，可以超出上限一个
+            peers.map.insert(Ipv4Addr::UNSPECIFIED, state.clone());
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::{InboundLimit, Limit, Verdict, AUTO_BLOCK_COOLDOWN, PEER_IDLE, PEER_LIMIT};
+    use crate::protocol::Protocol;
+
+    #[test]
+    fn test_flood() {
+        let limit = InboundLimit::new();
+        limit.set_default(Limit::new(100, 0));
+        let flood = Ipv4Addr::new(10, 26, 0, 3);
+        let other = Ipv4Addr::new(10, 26, 0, 4);
+        let start = Instant::now();
+        let mut passed = 0;
+        let mut notified = 0;
+        // 5秒内每毫秒1个包，对端每10毫秒1个包
+        for ms in 0..5000 {
+            let now = start + Duration::from_millis(ms);
+            match limit.check_at(flood, 100, now) {
+                Verdict::Pass => passed += 1,
+                Verdict::Exceeded(v) => {
+                    assert_eq!(v, Limit::new(100, 0));
+                    notified += 1
+                }
+                Verdict::Drop => {}
+                Verdict::Blocked(..) => panic!("auto block disabled"),
+            }
+            if ms % 10 == 0 {
+                assert!(limit.check_at(other, 100, now).is_pass());
+            }
+        }
+        assert_eq!(passed, 500);
+        // 10秒内只提示一次
+        assert_eq!(notified, 1);
+        // 控制包不受限制
+        for _ in 0..1000 {
+            assert!(limit.check(flood, Protocol::Control, 100).is_pass());
+        }
+    }
+
+    #[test]
+    fn test_bytes_and_override() {
+        let limit = InboundLimit::new();
+        limit.set_default(Limit::new(0, 10_000));
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(limit.check_at(peer, 1000, now).is_pass());
+        }
+        assert!(!limit.check_at(peer, 1000, now).is_pass());
+        limit.set_peer(peer, Some(Limit::UNLIMITED));
+        assert!(limit.check_at(peer, 100_000, now).is_pass());
+        assert_eq!(limit.list().len(), 1);
+        limit.set_peer(peer, None);
+        assert!(!limit.check_at(peer, 100_000, now).is_pass());
+        assert!(limit.list().is_empty());
+    }
+
+    #[test]
+    fn test_auto_block() {
+        let limit = InboundLimit::new();
+        limit.set_default(Limit::new(10, 0));
+        limit.set_auto_block(true);
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        let start = Instant::now();
+        let mut blocked_at = None;
+        'outer: for sec in 0..20u64 {
+            for i in 0..20 {
+                let now = start + Duration::from_secs(sec) + Duration::from_millis(i);
+                if let Verdict::Blocked(_, cooldown) = limit.check_at(peer, 100, now) {
+                    assert_eq!(cooldown, AUTO_BLOCK_COOLDOWN);
+                    blocked_at = Some(sec);
+                    break 'outer;
+                }
+            }
+        }
+        assert_eq!(blocked_at, Some(9));
+        // 屏蔽期间全部丢弃
+        let now = start + Duration::from_secs(100);
+        assert_eq!(limit.check_at(peer, 100, now), Verdict::Drop);
+        assert!(limit.list()[0].3.is_some());
+        // 屏蔽结束后恢复
+        let now = start + Duration::from_secs(10) + AUTO_BLOCK_COOLDOWN;
+        assert!(limit.check_at(peer, 100, now).is_pass());
+    }
+
+    #[test]
+    fn test_evict() {
+        let limit = InboundLimit::new();
+        limit.set_default(Limit::new(1, 0));
+        let start = Instant::now();
+        for i in 0..PEER_LIMIT as u32 {
+            assert!(limit
+                .check_at(Ipv4Addr::from(0x0a00_0000 + i), 100, start)
+                .is_pass());
+        }
+        // 都不空闲时新来源共用一个计数
+        let now = start + Duration::from_secs(1);
+        assert!(limit
+            .check_at(Ipv4Addr::new(11, 0, 0, 1), 100, now)
+            .is_pass());
+        assert!(!limit
+            .check_at(Ipv4Addr::new(11, 0, 0, 2), 100, now)
+            .is_pass());
+        // 空闲后每个新来源淘汰一个空闲的对端，有自己的计数
+        let now = start + PEER_IDLE;
+        assert!(limit
+            .check_at(Ipv4Addr::new(12, 0, 0, 1), 100, now)
+            .is_pass());
+        assert!(limit
+            .check_at(Ipv4Addr::new(12, 0, 0, 2), 100, now)
+            .is_pass());
+        let peers = limit.peers.read();
+        assert!(peers.map.contains_key(&Ipv4Addr::new(12, 0, 0, 1)));
+        assert!(peers.map.contains_key(&Ipv4Addr::new(12, 0, 0, 2)));
+        assert_eq!(peers.map.len(), PEER_LIMIT + 1);
+        assert_eq!(peers.clock.len(), PEER_LIMIT);
+    }
+}
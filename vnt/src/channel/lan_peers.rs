@@ -0,0 +1,51 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// 局域网发现的地址多久没有再次确认就不再使用
+const EXPIRE: Duration = Duration::from_secs(600);
+const LIMIT: usize = 256;
+
+/// 局域网内发现并验证过的对端地址，打洞时优先尝试
+#[derive(Default)]
+pub struct LanPeers {
+    peers: Mutex<HashMap<Ipv4Addr, (SocketAddr, Instant)>>,
+}
+
+impl LanPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert(&self, virtual_ip: Ipv4Addr, addr: SocketAddr) {
+        let now = Instant::now();
+        let mut peers = self.peers.lock();
+        if peers.len() >= LIMIT && !peers.contains_key(&virtual_ip) {
+            peers.retain(|_, (_, time)| now.duration_since(*time) < EXPIRE);
+            if peers.len() >= LIMIT {
+                return;
+            }
+        }
+        peers.insert(virtual_ip, (addr, now));
+    }
+    pub fn get(&self, virtual_ip: &Ipv4Addr) -> Option<SocketAddr> {
+        let peers = self.peers.lock();
+        if peers.is_empty() {
+            return None;
+        }
+        peers
+            .get(virtual_ip)
+            .filter(|(_, time)| time.elapsed() < EXPIRE)
+            .map(|(addr, _)| *addr)
+    }
+    pub fn list(&self) -> Vec<(Ipv4Addr, SocketAddr)> {
+        let peers = self.peers.lock();
+        let mut list: Vec<(Ipv4Addr, SocketAddr)> = peers
+            .iter()
+            .filter(|(_, (_, time))| time.elapsed() < EXPIRE)
+            .map(|(ip, (addr, _))| (*ip, *addr))
+            .collect();
+        list.sort();
+        list
+    }
+}
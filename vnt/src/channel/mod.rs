@@ -1,21 +1,58 @@
 use anyhow::Context;
-use std::net::{SocketAddr, UdpSocket};
+use std::cmp::Reverse;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::str::FromStr;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::endpoint::{Endpoint, PathState, PeerPath};
 use crate::channel::handler::RecvChannelHandler;
 use crate::channel::sender::AcceptSocketSender;
 use crate::channel::tcp_channel::tcp_listen;
 use crate::channel::udp_channel::udp_listen;
+use crate::handle::packet_hook::PacketHooks;
+use crate::protocol::compat::WireVersion;
 use crate::util::StopManager;
 
+pub mod backpressure;
+pub mod bandwidth;
+pub mod block_list;
+pub mod bring_up;
+pub mod checksum;
 pub mod context;
+pub mod cookie;
+pub mod diary;
+pub mod drop_reason;
+pub mod endpoint;
 pub mod handler;
+pub mod handover;
+pub mod icmp_error;
 pub mod idle;
+pub mod inbound_limit;
+pub mod lan_peers;
+pub mod mtu_guard;
 pub mod notify;
+pub mod p2p_estimate;
+pub mod path_score;
+pub mod peer_feature;
+pub mod peer_stats;
+pub mod ping;
+pub mod probe_budget;
 pub mod punch;
+pub mod punch_sync;
+pub mod rate_limit;
+pub mod relay_stats;
+pub mod rendezvous;
+pub mod reorder;
+pub mod reverse;
+pub mod route_cache;
+pub mod route_health;
+pub mod self_probe;
 pub mod sender;
+pub mod socket_pool;
+pub mod source_policy;
 pub mod tcp_channel;
+pub mod transport;
 pub mod udp_channel;
 
 const BUFFER_SIZE: usize = 1024 * 16;
@@ -61,6 +98,10 @@ pub struct Route {
     pub addr: SocketAddr,
     pub metric: u8,
     pub rt: i64,
+    // 对端协议头版本，只对直连路由生效
+    pub wire_version: WireVersion,
+    // 测得的带宽，kbps，0表示没有测量过
+    pub bandwidth: u32,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -77,6 +118,8 @@ impl Route {
             addr,
             metric,
             rt,
+            wire_version: WireVersion::V1,
+            bandwidth: 0,
         }
     }
     pub fn from(route_key: RouteKey, metric: u8, rt: i64) -> Self {
@@ -86,6 +129,8 @@ impl Route {
             addr: route_key.addr,
             metric,
             rt,
+            wire_version: WireVersion::V1,
+            bandwidth: 0,
         }
     }
     pub fn from_default_rt(route_key: RouteKey, metric: u8) -> Self {
@@ -95,8 +140,16 @@ impl Route {
             addr: route_key.addr,
             metric,
             rt: DEFAULT_RT,
+            wire_version: WireVersion::V1,
+            bandwidth: 0,
         }
     }
+    pub fn with_wire_version(mut self, wire_version: WireVersion) -> Self {
+        if self.is_p2p() {
+            self.wire_version = wire_version;
+        }
+        self
+    }
     pub fn route_key(&self) -> RouteKey {
         RouteKey {
             is_tcp: self.is_tcp,
@@ -104,6 +157,29 @@ impl Route {
             addr: self.addr,
         }
     }
+    /// 路由表中的路由都是已验证的路径
+    pub fn path(&self) -> PeerPath {
+        PeerPath {
+            endpoint: Endpoint::new(self.is_tcp, self.addr),
+            index: self.index,
+            metric: self.metric,
+            rt: self.rt,
+            state: PathState::Active,
+        }
+    }
+    pub fn from_path(path: &PeerPath) -> Self {
+        Self::new(
+            path.endpoint.is_tcp(),
+            path.index,
+            path.endpoint.addr(),
+            path.metric,
+            path.rt,
+        )
+    }
+    /// 路径排序先看延迟，延迟相同时带宽大的在前
+    fn order(&self) -> (i64, Reverse<u32>) {
+        (self.rt, Reverse(self.bandwidth))
+    }
     pub fn sort_key(&self) -> RouteSortKey {
         RouteSortKey {
             metric: self.metric,
@@ -113,6 +189,14 @@ impl Route {
     pub fn is_p2p(&self) -> bool {
         self.metric == 1
     }
+    /// 测出的延迟，还没有测量时为None
+    pub fn measured_rt(&self) -> Option<i64> {
+        (self.rt >= 0 && self.rt != DEFAULT_RT).then_some(self.rt)
+    }
+    /// 是否使用带扩展头的协议
+    pub fn is_wire_v2(&self) -> bool {
+        self.is_p2p() && self.wire_version == WireVersion::V2
+    }
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -136,51 +220,94 @@ impl RouteKey {
     pub fn index(&self) -> usize {
         self.index
     }
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new(self.is_tcp, self.addr)
+    }
+    pub(crate) fn from_endpoint(endpoint: Endpoint, index: usize) -> Self {
+        Self::new(endpoint.is_tcp(), index, endpoint.addr())
+    }
+}
+
+/// 本地监听的地址，没有指定ip时监听v6+v4双栈，不支持ipv6时监听所有v4地址
+fn bind_address(bind_ip: Option<IpAddr>, use_ipv6: bool, port: u16) -> SocketAddr {
+    match bind_ip {
+        Some(ip) => SocketAddr::new(ip, port),
+        None if use_ipv6 => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+    }
+}
+
+/// 绑定失败时给出明确的原因，不自动换端口
+fn bind_error(protocol: &str, address: SocketAddr, e: io::Error) -> anyhow::Error {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => anyhow::anyhow!(
+            "{} port {} is already in use ({})",
+            protocol,
+            address.port(),
+            address
+        ),
+        io::ErrorKind::AddrNotAvailable => {
+            anyhow::anyhow!("bind address {} is not assigned to this host", address.ip())
+        }
+        _ => anyhow::anyhow!("{:?},{} bind failed: {}", e, protocol, address),
+    }
 }
 
 pub fn init_context(
     ports: Vec<u16>,
+    bind_ip: Option<IpAddr>,
     use_channel_type: UseChannelType,
     first_latency: bool,
     is_tcp: bool,
     packet_loss_rate: Option<f64>,
     packet_delay: u32,
+    packet_hooks: PacketHooks,
+    reuse_port: bool,
 ) -> anyhow::Result<(ChannelContext, mio::net::TcpListener)> {
     assert!(!ports.is_empty(), "not channel");
     let mut udps = Vec::with_capacity(ports.len());
-    //检查系统是否支持ipv6
-    let use_ipv6 = match socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None) {
-        Ok(_) => true,
-        Err(e) => {
-            log::warn!("{:?}", e);
-            false
-        }
+    let use_ipv6 = match bind_ip {
+        // 指定了本地ip时只使用对应的协议
+        Some(ip) => ip.is_ipv6(),
+        //检查系统是否支持ipv6
+        None => match socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None) {
+            Ok(_) => true,
+            Err(e) => {
+                log::warn!("{:?}", e);
+                false
+            }
+        },
+    };
+    let domain = if use_ipv6 {
+        socket2::Domain::IPV6
+    } else {
+        socket2::Domain::IPV4
     };
     for port in &ports {
-        //监听v6+v4双栈
-        let (socket, address) = if use_ipv6 {
-            let address: SocketAddr = format!("[::]:{}", port).parse().unwrap();
-            let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?;
+        let address = bind_address(bind_ip, use_ipv6, *port);
+        let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, None)?;
+        if use_ipv6 {
+            //监听v6+v4双栈
             socket
                 .set_only_v6(false)
                 .with_context(|| format!("set_only_v6 failed: {}", &address))?;
-            (socket, address)
-        } else {
-            let address: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
-            (
-                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
-                address,
-            )
-        };
+        }
         if let Err(e) = socket.set_send_buffer_size(2 * 1024 * 1024) {
             log::warn!("set_send_buffer_size {:?}", e);
         }
         if let Err(e) = socket.set_recv_buffer_size(2 * 1024 * 1024) {
             log::warn!("set_send_buffer_size {:?}", e);
         }
+        #[cfg(target_os = "linux")]
+        if reuse_port {
+            // 接管时新旧进程同时绑定这个端口
+            socket
+                .set_reuse_port(true)
+                .with_context(|| format!("set_reuse_port failed: {}", &address))?;
+        }
         socket
             .bind(&address.into())
-            .with_context(|| format!("bind failed: {}", &address))?;
+            .map_err(|e| bind_error("udp", address, e))?;
         let main_channel: UdpSocket = socket.into();
         udps.push(main_channel);
     }
@@ -192,38 +319,40 @@ pub fn init_context(
         packet_loss_rate,
         packet_delay,
         use_ipv6,
+        packet_hooks,
     );
+    if let Some(IpAddr::V4(ip)) = bind_ip {
+        // 打洞和nat探测的辅助端口也走同一个网卡
+        context.socket_pool.set_bind_ip(ip);
+    }
 
     let port = context.main_local_udp_port()?[0];
-    //监听v6+v4双栈，tcp通道使用异步io
-    let (socket, address) = if use_ipv6 {
-        let address: SocketAddr = format!("[::]:{}", port).parse().unwrap();
-        let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
+    //tcp通道使用异步io
+    let address = bind_address(bind_ip, use_ipv6, port);
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+    if use_ipv6 {
         socket
             .set_only_v6(false)
             .with_context(|| format!("set_only_v6 failed: {}", &address))?;
-        (socket, address)
-    } else {
-        let address: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
-        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
-        (socket, address)
-    };
+    }
 
+    #[cfg(target_os = "linux")]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
     if let Err(e) = socket.bind(&address.into()) {
         if ports[0] == 0 {
             //端口可能冲突，则使用任意端口
             log::warn!("监听tcp端口失败 {:?},重试一次", address);
-            let address: SocketAddr = if use_ipv6 {
-                format!("[::]:{}", 0).parse().unwrap()
-            } else {
-                format!("0.0.0.0:{}", port).parse().unwrap()
-            };
+            let address = bind_address(bind_ip, use_ipv6, 0);
             socket
                 .bind(&address.into())
-                .with_context(|| format!("bind failed: {}", &address))?;
+                .map_err(|e| bind_error("tcp", address, e))?;
         } else {
-            //手动指定的ip,直接报错
-            Err(anyhow::anyhow!("{:?},bind failed: {}", e, address))?;
+            //手动指定的端口,直接报错
+            Err(bind_error("tcp", address, e))?;
         }
     }
     socket.listen(128)?;
@@ -258,3 +387,67 @@ where
 
     Ok((udp_socket_sender, tcp_socket_sender))
 }
+
+/// 测试用的上下文，只监听本机的随机端口
+#[cfg(test)]
+pub(crate) fn loopback_context() -> context::ChannelContext {
+    let (context, _tcp) = init_context(
+        vec![0],
+        Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        UseChannelType::All,
+        false,
+        false,
+        None,
+        0,
+        crate::handle::packet_hook::PacketHooks::default(),
+        false,
+    )
+    .unwrap();
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+    use crate::channel::{bind_address, init_context, UseChannelType};
+    use crate::handle::packet_hook::PacketHooks;
+
+    fn init(port: u16, bind_ip: IpAddr) -> anyhow::Result<u16> {
+        let (context, _tcp) = init_context(
+            vec![port],
+            Some(bind_ip),
+            UseChannelType::All,
+            false,
+            false,
+            None,
+            0,
+            PacketHooks::default(),
+            false,
+        )?;
+        Ok(context.main_local_udp_port()?[0])
+    }
+
+    #[test]
+    fn test_bind_address() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(
+            bind_address(Some(ip), true, 29872),
+            SocketAddr::new(ip, 29872)
+        );
+        assert_eq!(bind_address(None, true, 1).to_string(), "[::]:1");
+        assert_eq!(bind_address(None, false, 1).to_string(), "0.0.0.0:1");
+    }
+
+    #[test]
+    fn test_port_in_use() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let used = UdpSocket::bind((loopback, 0)).unwrap();
+        let port = used.local_addr().unwrap().port();
+        // 指定的端口被占用时直接报错，不换成其他端口
+        let err = init(port, loopback).unwrap_err();
+        assert!(err.to_string().contains("already in use"), "{}", err);
+        drop(used);
+        assert_eq!(init(port, loopback).unwrap(), port);
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// ip总长度达到这个值的包算作大包
+const LARGE: u16 = 1200;
+/// 统计窗口，窗口结束时判断一次
+const WINDOW: Duration = Duration::from_secs(10);
+/// 窗口内大包重传次数的下限
+const MIN_LARGE_RETRANS: u32 = 4;
+/// 窗口内收到对端包的下限，对端没有响应时不判断
+const MIN_INBOUND: u32 = 8;
+/// 每个对端每个窗口最多记录的tcp段
+const SEEN_CAPACITY: usize = 512;
+const MAX_INCIDENTS: usize = 16;
+/// ip头和tcp头的长度
+const TCP_IP_HEAD: u16 = 40;
+const MIN_MSS: u16 = 536;
+
+const TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+
+/// 一次黑洞检测记录
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MtuIncident {
+    pub peer: Ipv4Addr,
+    /// 一直重传的最小的包长度
+    pub stalled_len: u16,
+    /// 窗口内送达的最大的包长度
+    pub delivered_len: u16,
+    /// 之后对这个对端的tcp连接使用的mss
+    pub mss: u16,
+    /// unix时间戳(毫秒)
+    pub time: u64,
+}
+
+impl Display for MtuIncident {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "path to {} drops packets of {} bytes while smaller packets get through, new tcp connections use mss {}",
+            self.peer, self.stalled_len, self.mss
+        )
+    }
+}
+
+/// 出站tcp段的标识，相同标识再次出现视为重传
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct SegmentKey {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    payload_len: u16,
+}
+
+struct Segment {
+    key: SegmentKey,
+    total_len: u16,
+    syn: bool,
+}
+
+fn parse_tcp(ipv4: &[u8]) -> Option<Segment> {
+    if ipv4.len() < 20 || ipv4[9] != TCP {
+        return None;
+    }
+    let ihl = (ipv4[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([ipv4[2], ipv4[3]]);
+    if ihl < 20 || total_len as usize > ipv4.len() || (total_len as usize) < ihl + 20 {
+        return None;
+    }
+    let tcp = &ipv4[ihl..total_len as usize];
+    let doff = (tcp[12] >> 4) as usize * 4;
+    if doff < 20 || doff > tcp.len() {
+        return None;
+    }
+    Some(Segment {
+        key: SegmentKey {
+            src_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+            dst_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            payload_len: (tcp.len() - doff) as u16,
+        },
+        total_len,
+        syn: tcp[13] & TCP_SYN != 0,
+    })
+}
+
+/// RFC 1624 增量更新校验和
+pub(crate) fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// 把syn包中大于mss的mss选项改小，返回是否修改
+fn clamp_mss(ipv4: &mut [u8], mss: u16) -> bool {
+    let ihl = (ipv4[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([ipv4[2], ipv4[3]]) as usize;
+    let tcp = &mut ipv4[ihl..total_len];
+    let doff = (tcp[12] >> 4) as usize * 4;
+    let mut i = 20;
+    while i < doff {
+        match tcp[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                if i + 1 >= doff {
+                    break;
+                }
+                let len = tcp[i + 1] as usize;
+                if len < 2 || i + len > doff {
+                    break;
+                }
+                if kind == 2 && len == 4 {
+                    let old = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if old <= mss {
+                        return false;
+                    }
+                    tcp[i + 2..i + 4].copy_from_slice(&mss.to_be_bytes());
+                    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                    let checksum = update_checksum(checksum, old, mss);
+                    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+                    return true;
+                }
+                i += len;
+            }
+        }
+    }
+    false
+}
+
+#[derive(Default)]
+struct PeerWindow {
+    start: Option<Instant>,
+    inbound: u32,
+    // 段 -> (ip总长度,发送次数)
+    seen: HashMap<SegmentKey, (u16, u32)>,
+}
+
+/// 黑洞的特征：对端一直有包回来，小包没有重传，但是一定长度以上的包反复重传
+///
+/// 只在窗口结束时根据整个窗口判断，丢包严重的链路小包也会重传，不会被误判
+#[derive(Default)]
+struct Detector {
+    peers: HashMap<Ipv4Addr, PeerWindow>,
+}
+
+impl Detector {
+    fn outbound(&mut self, peer: Ipv4Addr, key: SegmentKey, total_len: u16, now: Instant) {
+        if key.payload_len == 0 {
+            // 纯ack重复是正常的
+            return;
+        }
+        let window = self.peers.entry(peer).or_default();
+        window.start.get_or_insert(now);
+        if let Some((_, count)) = window.seen.get_mut(&key) {
+            *count += 1;
+        } else if window.seen.len() < SEEN_CAPACITY {
+            window.seen.insert(key, (total_len, 1));
+        }
+    }
+    fn inbound(&mut self, peer: Ipv4Addr, now: Instant) {
+        if let Some(window) = self.peers.get_mut(&peer) {
+            window.start.get_or_insert(now);
+            window.inbound += 1;
+        }
+    }
+    /// 判断结束的窗口，返回 (对端,一直重传的长度,送达的最大长度)
+    fn check(&mut self, now: Instant) -> Vec<(Ipv4Addr, u16, u16)> {
+        let mut list = Vec::new();
+        self.peers.retain(|peer, window| {
+            let start = match window.start {
+                Some(start) => start,
+                None => return false,
+            };
+            if now.saturating_duration_since(start) < WINDOW {
+                return true;
+            }
+            if let Some((stalled, delivered)) = evaluate(window) {
+                list.push((*peer, stalled, delivered));
+            }
+            // 每个窗口重新统计
+            false
+        });
+        list
+    }
+}
+
+fn evaluate(window: &PeerWindow) -> Option<(u16, u16)> {
+    if window.inbound < MIN_INBOUND {
+        return None;
+    }
+    let mut large_sent = 0u32;
+    let mut large_retrans = 0u32;
+    let mut small_sent = 0u32;
+    let mut small_retrans = 0u32;
+    let mut stalled = u16::MAX;
+    for (len, count) in window.seen.values() {
+        if *len >= LARGE {
+            large_sent += count;
+            large_retrans += count - 1;
+            if *count > 1 {
+                stalled = stalled.min(*len);
+            }
+        } else {
+            small_sent += count;
+            small_retrans += count - 1;
+        }
+    }
+    if large_retrans < MIN_LARGE_RETRANS || large_retrans * 2 < large_sent {
+        return None;
+    }
+    // 小包重传超过5%说明是链路本身丢包
+    if small_retrans * 20 > small_sent {
+        return None;
+    }
+    let delivered = window
+        .seen
+        .values()
+        .filter(|(len, count)| *count == 1 && *len < stalled)
+        .map(|(len, _)| *len)
+        .max()
+        .unwrap_or(0);
+    Some((stalled, delivered))
+}
+
+/// 对端的mss，黑洞时用送达的最大包推算，没有送达的大包时按一直重传的长度减小
+fn mss_for(stalled: u16, delivered: u16) -> u16 {
+    let len = if delivered >= LARGE {
+        delivered
+    } else {
+        stalled.saturating_sub(200)
+    };
+    len.saturating_sub(TCP_IP_HEAD).max(MIN_MSS)
+}
+
+#[derive(Default)]
+struct MtuGuardInner {
+    detector: Detector,
+    // 对端 -> mss
+    clamps: HashMap<Ipv4Addr, u16>,
+    incidents: VecDeque<MtuIncident>,
+}
+
+/// 路径mtu黑洞检测，检测到后对这个对端的tcp握手降低mss
+///
+/// 没有主动探测路径mtu，用窗口内送达的最大包推算，对已经建立的连接不生效
+#[derive(Default)]
+pub struct MtuGuard {
+    inner: Mutex<MtuGuardInner>,
+    // 虚拟网卡的mtu，0表示不检查
+    device_mtu: AtomicU32,
+    oversize_logged: AtomicBool,
+}
+
+impl MtuGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_device_mtu(&self, mtu: u32) {
+        self.device_mtu.store(mtu, Ordering::Relaxed);
+    }
+    pub fn device_mtu(&self) -> u32 {
+        self.device_mtu.load(Ordering::Relaxed)
+    }
+    /// 网卡读到的ip包超过mtu时返回true，由调用方丢弃，不发送超过路径mtu的udp包。
+    /// 第一次出现时告警，之后只计入丢包统计
+    pub fn oversize(&self, len: usize) -> bool {
+        let mtu = self.device_mtu();
+        if mtu == 0 || len <= mtu as usize {
+            return false;
+        }
+        if !self.oversize_logged.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "从网卡读到{}字节的包,超过mtu {},已丢弃,检查网卡的mtu是否设置成功",
+                len,
+                mtu
+            );
+        }
+        true
+    }
+    /// 网卡读到的发往对端的ip包
+    pub fn outbound(&self, peer: Ipv4Addr, ipv4: &mut [u8]) {
+        let segment = match parse_tcp(ipv4) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let mut guard = self.inner.lock();
+        if segment.syn {
+            if let Some(mss) = guard.clamps.get(&peer).copied() {
+                drop(guard);
+                clamp_mss(ipv4, mss);
+                return;
+            }
+        }
+        guard
+            .detector
+            .outbound(peer, segment.key, segment.total_len, Instant::now());
+    }
+    #[cfg(any(test, feature = "fuzz"))]
+    pub(crate) fn set_clamp(&self, peer: Ipv4Addr, mss: u16) {
+        self.inner.lock().clamps.insert(peer, mss);
+    }
+    /// 对端发来的准备写入网卡的ip包，返回是否修改了mss
+    pub fn inbound(&self, peer: Ipv4Addr, ipv4: &mut [u8]) -> bool {
+        let segment = parse_tcp(ipv4);
+        let mut guard = self.inner.lock();
+        guard.detector.inbound(peer, Instant::now());
+        if let Some(segment) = segment {
+            if segment.syn {
+                if let Some(mss) = guard.clamps.get(&peer).copied() {
+                    drop(guard);
+                    return clamp_mss(ipv4, mss);
+                }
+            }
+        }
+        false
+    }
+    /// 定时调用，返回新发现的黑洞
+    pub fn check(&self) -> Vec<MtuIncident> {
+        let mut guard = self.inner.lock();
+        let mut list = Vec::new();
+        for (peer, stalled, delivered) in guard.detector.check(Instant::now()) {
+            let mss = mss_for(stalled, delivered);
+            if guard.clamps.get(&peer).map_or(false, |v| *v <= mss) {
+                continue;
+            }
+            guard.clamps.insert(peer, mss);
+            let incident = MtuIncident {
+                peer,
+                stalled_len: stalled,
+                delivered_len: delivered,
+                mss,
+                time: crate::handle::now_time(),
+            };
+            if guard.incidents.len() >= MAX_INCIDENTS {
+                guard.incidents.pop_front();
+            }
+            guard.incidents.push_back(incident.clone());
+            list.push(incident);
+        }
+        list
+    }
+    pub fn incidents(&self) -> Vec<MtuIncident> {
+        self.inner.lock().incidents.iter().cloned().collect()
+    }
+    pub fn mss(&self, peer: &Ipv4Addr) -> Option<u16> {
+        self.inner.lock().clamps.get(peer).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        clamp_mss, evaluate, mss_for, parse_tcp, Detector, MtuGuard, PeerWindow, SegmentKey, WINDOW,
+    };
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    #[test]
+    fn test_oversize() {
+        let guard = MtuGuard::new();
+        // 没有设置时不检查
+        assert!(!guard.oversize(9000));
+        guard.set_device_mtu(1410);
+        assert!(!guard.oversize(1410));
+        assert!(guard.oversize(1411));
+        assert!(guard.oversize(1500));
+    }
+
+    fn key(seq: u32, payload_len: u16) -> SegmentKey {
+        SegmentKey {
+            src_port: 40000,
+            dst_port: 22,
+            seq,
+            payload_len,
+        }
+    }
+
+    /// 按(序号,长度,发送次数)构造一个窗口的流量
+    fn trace(segments: &[(u32, u16, u32)], inbound: u32) -> Detector {
+        let start = Instant::now();
+        let mut detector = Detector::default();
+        for (seq, len, count) in segments {
+            for _ in 0..*count {
+                detector.outbound(PEER, key(*seq, len - 40), *len, start);
+            }
+        }
+        for _ in 0..inbound {
+            detector.inbound(PEER, start);
+        }
+        detector
+    }
+
+    fn check(detector: &mut Detector) -> Vec<(Ipv4Addr, u16, u16)> {
+        detector.check(Instant::now() + WINDOW + Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_blackhole() {
+        // 小包正常，1400字节的包一直重传
+        let mut segments: Vec<(u32, u16, u32)> = (0..20).map(|i| (i * 100, 120, 1)).collect();
+        segments.push((10000, 1300, 1));
+        segments.push((20000, 1400, 5));
+        segments.push((30000, 1400, 4));
+        let mut detector = trace(&segments, 30);
+        assert_eq!(check(&mut detector), vec![(PEER, 1400, 1300)]);
+        // 窗口已重置
+        assert!(check(&mut detector).is_empty());
+        assert_eq!(mss_for(1400, 1300), 1260);
+        assert_eq!(mss_for(1400, 0), 1160);
+    }
+
+    #[test]
+    fn test_lossy_link() {
+        // 丢包严重的链路，小包也在重传
+        let mut segments: Vec<(u32, u16, u32)> = (0..20).map(|i| (i * 100, 120, 2)).collect();
+        segments.push((20000, 1400, 5));
+        let mut detector = trace(&segments, 30);
+        assert!(check(&mut detector).is_empty());
+    }
+
+    #[test]
+    fn test_not_enough_evidence() {
+        // 大包偶尔重传
+        let mut segments: Vec<(u32, u16, u32)> = (0..20).map(|i| (i * 2000, 1400, 1)).collect();
+        segments.push((100000, 1400, 3));
+        assert!(check(&mut trace(&segments, 30)).is_empty());
+        // 对端没有响应，可能是对端离线
+        let segments = vec![(20000, 1400, 5), (30000, 1400, 5)];
+        assert!(check(&mut trace(&segments, 2)).is_empty());
+        // 窗口还没结束
+        let mut detector = trace(&segments, 30);
+        assert!(detector.check(Instant::now()).is_empty());
+        assert!(evaluate(&PeerWindow::default()).is_none());
+    }
+
+    /// 带伪首部的tcp校验和
+    fn tcp_checksum(ipv4: &[u8]) -> u16 {
+        let tcp = &ipv4[20..];
+        let mut sum = 0u32;
+        let mut add = |buf: &[u8]| {
+            for chunk in buf.chunks(2) {
+                let v = if chunk.len() == 2 {
+                    u16::from_be_bytes([chunk[0], chunk[1]])
+                } else {
+                    u16::from_be_bytes([chunk[0], 0])
+                };
+                sum += v as u32;
+            }
+        };
+        add(&ipv4[12..20]);
+        add(&[0, 6]);
+        add(&(tcp.len() as u16).to_be_bytes());
+        let mut tcp = tcp.to_vec();
+        tcp[16] = 0;
+        tcp[17] = 0;
+        add(&tcp);
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        let mut packet = vec![0u8; 44];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&44u16.to_be_bytes());
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&[10, 26, 0, 2]);
+        packet[16..20].copy_from_slice(&PEER.octets());
+        let tcp = &mut packet[20..];
+        tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&22u16.to_be_bytes());
+        tcp[12] = 6 << 4;
+        tcp[13] = 0x02;
+        tcp[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+        let checksum = tcp_checksum(&packet);
+        packet[36..38].copy_from_slice(&checksum.to_be_bytes());
+        let segment = parse_tcp(&packet).unwrap();
+        assert!(segment.syn);
+        assert_eq!(segment.key.payload_len, 0);
+        assert!(clamp_mss(&mut packet, 1260));
+        assert_eq!(u16::from_be_bytes([packet[42], packet[43]]), 1260);
+        assert_eq!(
+            u16::from_be_bytes([packet[36], packet[37]]),
+            tcp_checksum(&packet)
+        );
+        // 已经比限制小
+        assert!(!clamp_mss(&mut packet, 1300));
+    }
+}
@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::punch::{NatInfo, NatType, PunchModel};
+use crate::channel::punch_sync::SKEW_BUCKETS;
+use crate::handle::negative_path::MAX_PUNCH_FAILURES;
+
+/// 历史记录少于这个数时不给出成功率
+const MIN_SAMPLES: u32 = 3;
+/// 等待结果的对端数量上限
+const PENDING_LIMIT: usize = 1024;
+/// 打洞成功用时的分档，单位毫秒，最后一档为更长的
+pub const PUNCH_TIME_BUCKETS: &[u64] = &[200, 500, 1000, 3000, 10000];
+
+/// 同一种nat组合的打洞次数和成功次数，每个对端在成功之前只算一次
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PairStats {
+    pub attempts: u32,
+    pub successes: u32,
+}
+
+impl PairStats {
+    /// 样本足够时的成功率，百分比
+    pub fn percent(&self) -> Option<u32> {
+        if self.attempts < MIN_SAMPLES {
+            return None;
+        }
+        Some(self.successes.min(self.attempts) * 100 / self.attempts)
+    }
+}
+
+/// 按(本机nat类型,对端nat类型)统计打洞的结果
+#[derive(Default)]
+pub struct PunchHistory {
+    inner: Mutex<HistoryInner>,
+}
+
+#[derive(Default)]
+struct HistoryInner {
+    pairs: HashMap<(NatType, NatType), PairStats>,
+    // 成功用时落在各档的次数
+    timings: HashMap<(NatType, NatType), Vec<u32>>,
+    // 双方开始打洞的时间差落在各档的次数
+    skews: HashMap<(NatType, NatType), Vec<u32>>,
+    pending: HashMap<Ipv4Addr, ((NatType, NatType), Instant)>,
+}
+
+/// 一种nat组合的统计，用于导出
+#[derive(Clone, Debug)]
+pub struct PairRecord {
+    pub local: NatType,
+    pub remote: NatType,
+    pub stats: PairStats,
+    // 长度为PUNCH_TIME_BUCKETS.len() + 1
+    pub timings: Vec<u32>,
+    // 长度为SKEW_BUCKETS.len() + 1
+    pub skews: Vec<u32>,
+}
+
+impl PunchHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 发起打洞，同一个对端等待结果期间不重复计数
+    pub fn attempt(&self, peer: Ipv4Addr, local: NatType, remote: NatType) {
+        let mut inner = self.inner.lock();
+        if inner.pending.contains_key(&peer) || inner.pending.len() >= PENDING_LIMIT {
+            return;
+        }
+        inner
+            .pending
+            .insert(peer, ((local, remote), Instant::now()));
+        inner.pairs.entry((local, remote)).or_default().attempts += 1;
+    }
+    /// 收到打洞响应
+    pub fn succeeded(&self, peer: &Ipv4Addr) {
+        let mut inner = self.inner.lock();
+        if let Some((pair, start)) = inner.pending.remove(peer) {
+            inner.pairs.entry(pair).or_default().successes += 1;
+            let timings = inner
+                .timings
+                .entry(pair)
+                .or_insert_with(|| vec![0; PUNCH_TIME_BUCKETS.len() + 1]);
+            timings[bucket(PUNCH_TIME_BUCKETS, start.elapsed())] += 1;
+        }
+    }
+    /// 双方开始打洞的时间差
+    pub fn skewed(&self, local: NatType, remote: NatType, skew: Duration) {
+        let mut inner = self.inner.lock();
+        let skews = inner
+            .skews
+            .entry((local, remote))
+            .or_insert_with(|| vec![0; SKEW_BUCKETS.len() + 1]);
+        skews[bucket(SKEW_BUCKETS, skew)] += 1;
+    }
+    pub fn get(&self, local: NatType, remote: NatType) -> Option<PairStats> {
+        self.inner.lock().pairs.get(&(local, remote)).copied()
+    }
+    /// 所有nat组合的统计
+    pub fn records(&self) -> Vec<PairRecord> {
+        let inner = self.inner.lock();
+        inner
+            .pairs
+            .iter()
+            .map(|(pair, stats)| PairRecord {
+                local: pair.0,
+                remote: pair.1,
+                stats: *stats,
+                timings: inner
+                    .timings
+                    .get(pair)
+                    .cloned()
+                    .unwrap_or_else(|| vec![0; PUNCH_TIME_BUCKETS.len() + 1]),
+                skews: inner
+                    .skews
+                    .get(pair)
+                    .cloned()
+                    .unwrap_or_else(|| vec![0; SKEW_BUCKETS.len() + 1]),
+            })
+            .collect()
+    }
+}
+
+fn bucket(buckets: &[u64], elapsed: Duration) -> usize {
+    let millis = elapsed.as_millis() as u64;
+    buckets
+        .iter()
+        .position(|v| millis <= *v)
+        .unwrap_or(buckets.len())
+}
+
+/// 一端的nat和策略
+#[derive(Clone, Debug, Default)]
+pub struct Side {
+    /// 还没有收到对端的nat信息时为None
+    pub nat_type: Option<NatType>,
+    pub public_ips: Vec<Ipv4Addr>,
+    pub ipv6: bool,
+    /// 本地地址在100.64.0.0/10，位于运营商nat之后
+    pub cgnat: bool,
+    pub relay_only: bool,
+}
+
+impl Side {
+    pub fn new(nat_info: Option<&NatInfo>, relay_only: bool) -> Self {
+        match nat_info {
+            Some(nat_info) => Self {
+                nat_type: Some(nat_info.nat_type),
+                public_ips: nat_info.public_ips.clone(),
+                ipv6: nat_info.ipv6().is_some(),
+                cgnat: matches!(nat_info.local_ipv4(), Some(ip) if is_cgnat(ip)),
+                relay_only,
+            },
+            None => Self {
+                relay_only,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn is_cgnat(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && (octets[1] & 0b1100_0000) == 64
+}
+
+/// 估计需要的全部信息
+#[derive(Clone, Debug)]
+pub struct EstimateInput {
+    pub local: Side,
+    pub peer: Side,
+    pub punch_model: PunchModel,
+    /// 局域网内发现过对端
+    pub lan: bool,
+    /// 连续打洞失败的次数
+    pub punch_failures: usize,
+    /// 这种nat组合的历史结果
+    pub history: Option<PairStats>,
+}
+
+/// 直连的方式
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Via {
+    Lan,
+    Ipv6,
+    Ipv4,
+    PortPrediction,
+    Hairpin,
+}
+
+impl Display for Via {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Via::Lan => f.write_str("via LAN"),
+            Via::Ipv6 => f.write_str("via IPv6"),
+            Via::Ipv4 => f.write_str("via IPv4"),
+            Via::PortPrediction => f.write_str("with port prediction"),
+            Via::Hairpin => f.write_str("via NAT hairpin"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Likely(Via),
+    /// 第二项为历史成功率
+    Possible(Via, Option<u32>),
+    Unlikely(Option<u32>),
+    Impossible(&'static str),
+    Unknown,
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Likely(via) => write!(f, "likely direct {}", via),
+            Verdict::Possible(via, None) => write!(f, "possible {}", via),
+            Verdict::Possible(via, Some(percent)) => {
+                write!(f, "possible {} (~{}% based on history)", via, percent)
+            }
+            Verdict::Unlikely(None) => f.write_str("unlikely"),
+            Verdict::Unlikely(Some(percent)) => {
+                write!(f, "unlikely (~{}% based on history)", percent)
+            }
+            Verdict::Impossible(reason) => write!(f, "impossible: {}", reason),
+            Verdict::Unknown => f.write_str("unknown: no NAT info from the peer yet"),
+        }
+    }
+}
+
+/// 结论和依据，依据按判断的顺序排列
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    pub verdict: Verdict,
+    pub reasons: Vec<String>,
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.verdict)?;
+        for reason in &self.reasons {
+            writeln!(f, "  - {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// 根据双方的nat、策略和历史结果估计能否直连，不访问网络
+pub fn estimate(input: &EstimateInput) -> Estimate {
+    let mut reasons = Vec::new();
+    let verdict = verdict(input, &mut reasons);
+    Estimate { verdict, reasons }
+}
+
+fn verdict(input: &EstimateInput, reasons: &mut Vec<String>) -> Verdict {
+    let (local, peer) = (&input.local, &input.peer);
+    match (local.relay_only, peer.relay_only) {
+        (true, true) => {
+            reasons.push("both peers only use the relay".to_string());
+            return Verdict::Impossible("both peers relay-only");
+        }
+        (true, false) => {
+            reasons.push("this device only uses the relay ('--use-channel relay')".to_string());
+            return Verdict::Impossible("this device is relay-only");
+        }
+        (false, true) => {
+            reasons.push("the peer announced that it only uses the relay".to_string());
+            return Verdict::Impossible("peer is relay-only");
+        }
+        (false, false) => {}
+    }
+    let (local_nat, peer_nat) = match (local.nat_type, peer.nat_type) {
+        (Some(local_nat), Some(peer_nat)) => (local_nat, peer_nat),
+        (None, _) => {
+            reasons.push("NAT type of this device is not detected yet".to_string());
+            return Verdict::Unknown;
+        }
+        (_, None) => {
+            reasons.push("the peer has not sent its NAT info, it may be offline".to_string());
+            return Verdict::Unknown;
+        }
+    };
+    reasons.push(format!(
+        "NAT types: local {:?}, peer {:?}",
+        local_nat, peer_nat
+    ));
+    let failed = input.punch_failures >= MAX_PUNCH_FAILURES;
+    if input.punch_failures > 0 {
+        reasons.push(format!(
+            "{} punch attempts failed in a row{}",
+            input.punch_failures,
+            if failed {
+                ", automatic punching stopped, use 'punch' to retry"
+            } else {
+                ""
+            }
+        ));
+    }
+    if input.lan {
+        reasons.push("the peer was discovered on the local network".to_string());
+        return Verdict::Likely(Via::Lan);
+    }
+    if local.ipv6 && peer.ipv6 {
+        if input.punch_model == PunchModel::IPv4 {
+            reasons.push("both sides have IPv6 but '--punch ipv4' disables it".to_string());
+        } else if !failed {
+            reasons.push("both sides have a global IPv6 address".to_string());
+            return Verdict::Likely(Via::Ipv6);
+        }
+    } else if input.punch_model == PunchModel::IPv6 {
+        reasons
+            .push("'--punch ipv6' but not both sides have IPv6, falling back to IPv4".to_string());
+    }
+    let percent = input.history.and_then(|v| v.percent());
+    if let Some(history) = input.history {
+        reasons.push(format!(
+            "history for {:?}-{:?}: {} of {} peers connected directly",
+            local_nat, peer_nat, history.successes, history.attempts
+        ));
+    }
+    for (side, name) in [(local, "this device"), (peer, "the peer")] {
+        if side.cgnat {
+            reasons.push(format!(
+                "{} is behind carrier-grade NAT (100.64.0.0/10), mappings may change",
+                name
+            ));
+        }
+    }
+    let cgnat = local.cgnat || peer.cgnat;
+    if local
+        .public_ips
+        .iter()
+        .any(|ip| peer.public_ips.contains(ip))
+    {
+        // 同一个出口，只有nat支持回环时才能通过公网地址直连
+        reasons.push(
+            "both sides share a public IP, direct needs NAT hairpin support which is not measured"
+                .to_string(),
+        );
+        return if failed {
+            Verdict::Unlikely(percent)
+        } else {
+            Verdict::Possible(Via::Hairpin, percent)
+        };
+    }
+    match (local_nat, peer_nat) {
+        (NatType::Cone, NatType::Cone) => {
+            reasons.push("both sides are cone NAT, the mapped ports can be reached".to_string());
+            if cgnat || failed || matches!(percent, Some(v) if v < 50) {
+                Verdict::Possible(Via::Ipv4, percent)
+            } else {
+                Verdict::Likely(Via::Ipv4)
+            }
+        }
+        (NatType::Symmetric, NatType::Symmetric) => {
+            reasons.push("both sides are symmetric NAT, ports can only be guessed".to_string());
+            Verdict::Unlikely(percent)
+        }
+        _ => {
+            reasons
+                .push("one side is symmetric NAT, the cone side has to guess its port".to_string());
+            if failed || percent == Some(0) {
+                Verdict::Unlikely(percent)
+            } else {
+                Verdict::Possible(Via::PortPrediction, percent)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::channel::p2p_estimate::{
+        estimate, EstimateInput, PairStats, PunchHistory, Side, Verdict, Via,
+    };
+    use crate::channel::punch::{NatType, PunchModel};
+
+    fn side(nat_type: NatType, ip: u8) -> Side {
+        Side {
+            nat_type: Some(nat_type),
+            public_ips: vec![Ipv4Addr::new(1, 1, 1, ip)],
+            ..Default::default()
+        }
+    }
+
+    fn input(local: Side, peer: Side) -> EstimateInput {
+        EstimateInput {
+            local,
+            peer,
+            punch_model: PunchModel::All,
+            lan: false,
+            punch_failures: 0,
+            history: None,
+        }
+    }
+
+    fn verdict(input: &EstimateInput) -> Verdict {
+        estimate(input).verdict
+    }
+
+    #[test]
+    fn test_matrix() {
+        use NatType::{Cone, Symmetric};
+        let history = |attempts, successes| {
+            Some(PairStats {
+                attempts,
+                successes,
+            })
+        };
+        // (本机, 对端, 历史, 连续失败次数, 结论)
+        let cases = [
+            (Cone, Cone, None, 0, Verdict::Likely(Via::Ipv4)),
+            (
+                Cone,
+                Cone,
+                history(10, 2),
+                0,
+                Verdict::Possible(Via::Ipv4, Some(20)),
+            ),
+            (Cone, Cone, None, 8, Verdict::Possible(Via::Ipv4, None)),
+            (
+                Cone,
+                Symmetric,
+                None,
+                0,
+                Verdict::Possible(Via::PortPrediction, None),
+            ),
+            (
+                Symmetric,
+                Cone,
+                history(5, 2),
+                0,
+                Verdict::Possible(Via::PortPrediction, Some(40)),
+            ),
+            // 样本不够时不给出成功率
+            (
+                Symmetric,
+                Cone,
+                history(2, 2),
+                0,
+                Verdict::Possible(Via::PortPrediction, None),
+            ),
+            (
+                Symmetric,
+                Cone,
+                history(6, 0),
+                0,
+                Verdict::Unlikely(Some(0)),
+            ),
+            (Symmetric, Cone, None, 8, Verdict::Unlikely(None)),
+            (Symmetric, Symmetric, None, 0, Verdict::Unlikely(None)),
+            (
+                Symmetric,
+                Symmetric,
+                history(4, 1),
+                0,
+                Verdict::Unlikely(Some(25)),
+            ),
+        ];
+        for (local, peer, history, failures, expect) in cases {
+            let mut input = input(side(local, 1), side(peer, 2));
+            input.history = history;
+            input.punch_failures = failures;
+            assert_eq!(
+                verdict(&input),
+                expect,
+                "{:?} {:?} {:?} {}",
+                local,
+                peer,
+                history,
+                failures
+            );
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut both = input(side(NatType::Cone, 1), side(NatType::Cone, 2));
+        both.local.relay_only = true;
+        both.peer.relay_only = true;
+        assert_eq!(
+            verdict(&both).to_string(),
+            "impossible: both peers relay-only"
+        );
+        both.local.relay_only = false;
+        assert_eq!(verdict(&both), Verdict::Impossible("peer is relay-only"));
+
+        let unknown = input(side(NatType::Cone, 1), Side::default());
+        assert_eq!(verdict(&unknown), Verdict::Unknown);
+
+        // ipv6优先于nat类型
+        let mut ipv6 = input(side(NatType::Symmetric, 1), side(NatType::Symmetric, 2));
+        ipv6.local.ipv6 = true;
+        ipv6.peer.ipv6 = true;
+        assert_eq!(verdict(&ipv6), Verdict::Likely(Via::Ipv6));
+        assert_eq!(verdict(&ipv6).to_string(), "likely direct via IPv6");
+        ipv6.punch_model = PunchModel::IPv4;
+        assert_eq!(verdict(&ipv6), Verdict::Unlikely(None));
+
+        let mut lan = input(side(NatType::Symmetric, 1), side(NatType::Symmetric, 1));
+        lan.lan = true;
+        assert_eq!(verdict(&lan), Verdict::Likely(Via::Lan));
+        lan.lan = false;
+        assert_eq!(verdict(&lan), Verdict::Possible(Via::Hairpin, None));
+
+        let mut cgnat = input(side(NatType::Cone, 1), side(NatType::Cone, 2));
+        cgnat.peer.cgnat = true;
+        let estimate = estimate(&cgnat);
+        assert_eq!(estimate.verdict, Verdict::Possible(Via::Ipv4, None));
+        assert!(estimate
+            .reasons
+            .iter()
+            .any(|v| v.contains("carrier-grade NAT")));
+        assert_eq!(
+            estimate.to_string().lines().count(),
+            estimate.reasons.len() + 1
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let history = PunchHistory::new();
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        history.attempt(peer, NatType::Cone, NatType::Symmetric);
+        // 等待结果期间不重复计数
+        history.attempt(peer, NatType::Cone, NatType::Symmetric);
+        history.succeeded(&peer);
+        history.succeeded(&peer);
+        history.attempt(peer, NatType::Cone, NatType::Symmetric);
+        let stats = history.get(NatType::Cone, NatType::Symmetric).unwrap();
+        assert_eq!((stats.attempts, stats.successes), (2, 1));
+        assert_eq!(stats.percent(), None);
+        assert_eq!(history.get(NatType::Cone, NatType::Cone), None);
+        let records = history.records();
+        assert_eq!(records.len(), 1);
+        // 刚发起就成功，落在第一档
+        assert_eq!(records[0].timings, vec![1, 0, 0, 0, 0, 0]);
+        assert_eq!(records[0].skews, vec![0; 7]);
+        history.skewed(NatType::Cone, NatType::Symmetric, Duration::from_millis(60));
+        history.skewed(NatType::Cone, NatType::Symmetric, Duration::from_secs(3));
+        assert_eq!(history.records()[0].skews, vec![0, 0, 1, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,592 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::channel::{Route, RouteKey};
+use crate::util::state_store::StateFile;
+
+/// 单项权重的上限，再大就只是放大了分数，没有意义
+pub const MAX_WEIGHT: f64 = 10_000.0;
+/// 记录的路径数量上限，避免伪造来源导致内存增长
+const PATH_LIMIT: usize = 4096;
+/// 丢包率和抖动的平滑系数
+const LOSS_ALPHA: f64 = 0.1;
+const JITTER_ALPHA: f64 = 0.125;
+
+/// 路径评分的权重，分数 = rtt*延迟(ms) + loss*丢包率(%) + jitter*抖动(ms) - bandwidth*带宽(Mbps)，越低越好
+///
+/// 默认只看延迟，和不设置权重时的排序一致
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PathWeights {
+    pub rtt: f64,
+    pub loss: f64,
+    pub jitter: f64,
+    pub bandwidth: f64,
+}
+
+impl Default for PathWeights {
+    fn default() -> Self {
+        Self {
+            rtt: 1.0,
+            loss: 0.0,
+            jitter: 0.0,
+            bandwidth: 0.0,
+        }
+    }
+}
+
+impl PathWeights {
+    pub fn score(&self, sample: &PathSample) -> f64 {
+        self.rtt * sample.rt as f64 + self.loss * sample.loss + self.jitter * sample.jitter
+            - self.bandwidth * sample.bandwidth as f64 / 1000.0
+    }
+}
+
+/// 解析'rtt=1.0,loss=50,jitter=2,bandwidth=0.1'，没有写的项使用默认值
+impl FromStr for PathWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = PathWeights::default();
+        let mut seen = Vec::with_capacity(4);
+        for item in s.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not <name>=<weight>", item))?;
+            let key = key.trim();
+            let value: f64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", value.trim()))?;
+            if !value.is_finite() || !(0.0..=MAX_WEIGHT).contains(&value) {
+                return Err(format!(
+                    "weight of '{}' must be between 0 and {}",
+                    key, MAX_WEIGHT
+                ));
+            }
+            if seen.contains(&key) {
+                return Err(format!("'{}' is set more than once", key));
+            }
+            seen.push(key);
+            match key {
+                "rtt" => weights.rtt = value,
+                "loss" => weights.loss = value,
+                "jitter" => weights.jitter = value,
+                "bandwidth" => weights.bandwidth = value,
+                _ => {
+                    return Err(format!(
+                        "unknown weight '{}', available: rtt,loss,jitter,bandwidth",
+                        key
+                    ))
+                }
+            }
+        }
+        if seen.is_empty() {
+            return Err("no weights given".to_string());
+        }
+        // 只看带宽时，没测过带宽的路径分数都一样
+        if weights.rtt == 0.0 && weights.loss == 0.0 && weights.jitter == 0.0 {
+            return Err("at least one of rtt,loss,jitter must be greater than 0".to_string());
+        }
+        Ok(weights)
+    }
+}
+
+impl Display for PathWeights {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rtt={},loss={},jitter={},bandwidth={}",
+            self.rtt, self.loss, self.jitter, self.bandwidth
+        )
+    }
+}
+
+/// 参与评分的路径指标
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PathSample {
+    /// 延迟，ms
+    pub rt: i64,
+    /// 丢包率，百分比
+    pub loss: f64,
+    /// 抖动，ms
+    pub jitter: f64,
+    /// 测得的带宽，kbps，0表示没有测量过
+    pub bandwidth: u32,
+}
+
+/// 对端的路径偏好
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Preference {
+    /// 按分数选择
+    Auto,
+    /// 只走直连，没有直连时丢弃
+    P2p,
+    /// 总是经过服务器中继
+    Relay,
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Preference::Auto),
+            "p2p" => Ok(Preference::P2p),
+            "relay" => Ok(Preference::Relay),
+            _ => Err(format!("not match '{}', enum: p2p/relay/auto", s)),
+        }
+    }
+}
+
+impl Display for Preference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Preference::Auto => "auto",
+            Preference::P2p => "p2p",
+            Preference::Relay => "relay",
+        })
+    }
+}
+
+/// 单个对端的覆盖设置
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PeerPreference {
+    pub preference: Preference,
+    /// 为None时使用全局权重
+    pub weights: Option<PathWeights>,
+}
+
+impl Display for PeerPreference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.weights {
+            Some(weights) => write!(f, "{} {}", self.preference, weights),
+            None => write!(f, "{}", self.preference),
+        }
+    }
+}
+
+/// 发送数据时的选择
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PathChoice {
+    /// 没有覆盖设置，按原来的方式选择
+    Default,
+    /// 只使用直连
+    P2p,
+    /// 直接经过服务器中继
+    Relay,
+}
+
+#[derive(Default)]
+struct PathStat {
+    last_rt: Option<i64>,
+    jitter: f64,
+    loss: f64,
+    // 已发出探测还没有回应
+    pending: bool,
+}
+
+/// 路径评分和用户的偏好覆盖
+///
+/// 没有设置'--path-weights'也没有对端覆盖时不参与选路，路由排序和原来完全一致。
+/// 心跳的探测和回应总是记录，用于计算丢包率和抖动，设置权重后立即生效
+#[derive(Default)]
+pub struct PathScores {
+    active: AtomicBool,
+    global: RwLock<Option<PathWeights>>,
+    peers: RwLock<HashMap<Ipv4Addr, PeerPreference>>,
+    stats: Mutex<HashMap<(Ipv4Addr, RouteKey), PathStat>>,
+    // 按分数中继比直连好的对端
+    relay_better: RwLock<HashSet<Ipv4Addr>>,
+}
+
+impl PathScores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn update_active(&self) {
+        let active = self.global.read().is_some() || !self.peers.read().is_empty();
+        self.active.store(active, Ordering::Relaxed);
+    }
+    #[inline]
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+    /// 设置全局权重，None表示恢复默认
+    pub fn set_weights(&self, weights: Option<PathWeights>) {
+        *self.global.write() = weights;
+        self.relay_better.write().clear();
+        self.update_active();
+    }
+    pub fn weights(&self) -> Option<PathWeights> {
+        *self.global.read()
+    }
+    /// 设置对端的偏好，None表示取消覆盖
+    pub fn set_peer(&self, peer: Ipv4Addr, preference: Option<PeerPreference>) {
+        match preference {
+            Some(preference) => {
+                self.peers.write().insert(peer, preference);
+            }
+            None => {
+                self.peers.write().remove(&peer);
+            }
+        }
+        self.relay_better.write().remove(&peer);
+        self.update_active();
+    }
+    pub fn peer(&self, peer: &Ipv4Addr) -> Option<PeerPreference> {
+        self.peers.read().get(peer).copied()
+    }
+    /// 所有对端的覆盖设置，按ip排序
+    pub fn peers(&self) -> Vec<(Ipv4Addr, PeerPreference)> {
+        let mut list: Vec<_> = self.peers.read().iter().map(|(k, v)| (*k, *v)).collect();
+        list.sort_by_key(|(ip, _)| *ip);
+        list
+    }
+    /// 对端生效的权重，没有任何覆盖设置时返回None
+    pub fn effective(&self, peer: &Ipv4Addr) -> Option<PathWeights> {
+        if !self.active() {
+            return None;
+        }
+        if let Some(weights) = self.peers.read().get(peer).and_then(|v| v.weights) {
+            return Some(weights);
+        }
+        *self.global.read()
+    }
+    /// 对端的偏好，没有覆盖时为Auto
+    pub fn preference(&self, peer: &Ipv4Addr) -> Preference {
+        if !self.active() {
+            return Preference::Auto;
+        }
+        self.peers
+            .read()
+            .get(peer)
+            .map_or(Preference::Auto, |v| v.preference)
+    }
+    /// 发送数据前调用，没有覆盖设置时只读一次原子变量
+    #[inline]
+    pub fn choice(&self, peer: &Ipv4Addr) -> PathChoice {
+        if !self.active() {
+            return PathChoice::Default;
+        }
+        match self.preference(peer) {
+            Preference::P2p => PathChoice::P2p,
+            Preference::Relay => PathChoice::Relay,
+            Preference::Auto => {
+                if self.relay_better.read().contains(peer) {
+                    PathChoice::Relay
+                } else {
+                    PathChoice::Default
+                }
+            }
+        }
+    }
+    /// 心跳发出探测，上一个探测还没有回应时记为丢失
+    pub fn probe(&self, peer: Ipv4Addr, key: RouteKey) {
+        let mut guard = self.stats.lock();
+        if guard.len() >= PATH_LIMIT && !guard.contains_key(&(peer, key)) {
+            return;
+        }
+        let stat = guard.entry((peer, key)).or_default();
+        let lost = if stat.pending { 100.0 } else { 0.0 };
+        stat.loss += (lost - stat.loss) * LOSS_ALPHA;
+        stat.pending = true;
+    }
+    /// 收到探测的回应
+    pub fn pong(&self, peer: Ipv4Addr, key: RouteKey, rt: i64) {
+        let mut guard = self.stats.lock();
+        if let Some(stat) = guard.get_mut(&(peer, key)) {
+            if stat.pending {
+                stat.loss -= stat.loss * LOSS_ALPHA;
+                stat.pending = false;
+            }
+            if let Some(last_rt) = stat.last_rt {
+                let delta = (rt - last_rt).abs() as f64;
+                stat.jitter += (delta - stat.jitter) * JITTER_ALPHA;
+            }
+            stat.last_rt = Some(rt);
+        }
+    }
+    pub fn sample(&self, peer: Ipv4Addr, route: &Route) -> PathSample {
+        let mut sample = PathSample {
+            rt: route.rt,
+            loss: 0.0,
+            jitter: 0.0,
+            bandwidth: route.bandwidth,
+        };
+        if let Some(stat) = self.stats.lock().get(&(peer, route.route_key())) {
+            sample.loss = stat.loss;
+            sample.jitter = stat.jitter;
+        }
+        sample
+    }
+    /// 经过服务器中继的路径，延迟按到服务器延迟的两倍估计
+    pub fn relay_sample(server_rt: i64) -> PathSample {
+        PathSample {
+            rt: server_rt * 2,
+            ..PathSample::default()
+        }
+    }
+    /// 路由的分数，没有覆盖设置时返回None
+    pub fn score(&self, peer: Ipv4Addr, route: &Route) -> Option<f64> {
+        let weights = self.effective(&peer)?;
+        Some(weights.score(&self.sample(peer, route)))
+    }
+    /// 直连路径测得延迟后调用，比较最好的直连和中继的分数
+    pub fn compare(&self, peer: Ipv4Addr, p2p: Option<&Route>, server_rt: i64) {
+        if self.preference(&peer) != Preference::Auto {
+            return;
+        }
+        let relay_better = match (self.effective(&peer), p2p) {
+            (Some(weights), Some(route)) if server_rt > 0 => {
+                let relay = weights.score(&Self::relay_sample(server_rt));
+                relay < weights.score(&self.sample(peer, route))
+            }
+            _ => false,
+        };
+        let mut guard = self.relay_better.write();
+        if relay_better {
+            if guard.insert(peer) {
+                log::info!("{} 按路径权重改用中继", peer);
+            }
+        } else if guard.remove(&peer) {
+            log::info!("{} 按路径权重切回直连", peer);
+        }
+    }
+    /// 清理已经下线的对端，覆盖设置保留
+    pub fn retain_peers(&self, online: &[Ipv4Addr]) {
+        self.stats.lock().retain(|(ip, _), _| online.contains(ip));
+        self.relay_better.write().retain(|ip| online.contains(ip));
+    }
+}
+
+/// 保存在数据目录下的对端覆盖设置，每行'<ip> <p2p|relay|auto> [权重]'
+#[derive(Default)]
+pub struct PathPreferences(pub Vec<(Ipv4Addr, PeerPreference)>);
+
+impl StateFile for PathPreferences {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for (ip, preference) in &self.0 {
+            text.push_str(&format!("{} {}\n", ip, preference));
+        }
+        text.into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(body).ok()?;
+        let mut list = Vec::new();
+        for line in text.lines().map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            match parse_line(line) {
+                Ok(v) => list.push(v),
+                Err(e) => log::warn!("路径偏好格式错误 {:?},{}", line, e),
+            }
+        }
+        Some(PathPreferences(list))
+    }
+}
+
+fn parse_line(line: &str) -> Result<(Ipv4Addr, PeerPreference), String> {
+    let mut parts = line.split_whitespace();
+    let ip = parts
+        .next()
+        .unwrap_or_default()
+        .parse::<Ipv4Addr>()
+        .map_err(|e| e.to_string())?;
+    let preference = parts.next().unwrap_or_default().parse::<Preference>()?;
+    let weights = parts.next().map(PathWeights::from_str).transpose()?;
+    Ok((
+        ip,
+        PeerPreference {
+            preference,
+            weights,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::util::state_store;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn sample(rt: i64, loss: f64, jitter: f64, bandwidth: u32) -> PathSample {
+        PathSample {
+            rt,
+            loss,
+            jitter,
+            bandwidth,
+        }
+    }
+
+    fn route(port: u16, rt: i64) -> Route {
+        Route::new(false, 0, SocketAddr::from(([192, 168, 1, 3], port)), 1, rt)
+    }
+
+    #[test]
+    fn test_parse_weights() {
+        let cases: &[(&str, Result<PathWeights, &str>)] = &[
+            (
+                "rtt=1.0,loss=50,jitter=2,bandwidth=0.1",
+                Ok(PathWeights {
+                    rtt: 1.0,
+                    loss: 50.0,
+                    jitter: 2.0,
+                    bandwidth: 0.1,
+                }),
+            ),
+            (
+                " loss = 10 ",
+                Ok(PathWeights {
+                    loss: 10.0,
+                    ..PathWeights::default()
+                }),
+            ),
+            ("", Err("no weights given")),
+            ("rtt", Err("'rtt' is not <name>=<weight>")),
+            ("rtt=fast", Err("'fast' is not a number")),
+            ("rtt=-1", Err("weight of 'rtt' must be between 0 and 10000")),
+            (
+                "rtt=NaN",
+                Err("weight of 'rtt' must be between 0 and 10000"),
+            ),
+            (
+                "rtt=inf",
+                Err("weight of 'rtt' must be between 0 and 10000"),
+            ),
+            ("rtt=1,rtt=2", Err("'rtt' is set more than once")),
+            (
+                "hops=1",
+                Err("unknown weight 'hops', available: rtt,loss,jitter,bandwidth"),
+            ),
+            (
+                "rtt=0,bandwidth=1",
+                Err("at least one of rtt,loss,jitter must be greater than 0"),
+            ),
+        ];
+        for (input, expect) in cases {
+            let rs = input.parse::<PathWeights>();
+            assert_eq!(rs, expect.map_err(|e| e.to_string()), "{}", input);
+        }
+        let weights: PathWeights = "rtt=1.0,loss=50,jitter=2,bandwidth=0.1".parse().unwrap();
+        assert_eq!(weights.to_string().parse::<PathWeights>(), Ok(weights));
+    }
+
+    #[test]
+    fn test_score() {
+        let custom: PathWeights = "rtt=1,loss=50,jitter=2,bandwidth=0.1".parse().unwrap();
+        // (权重, 路径a, 路径b, a是否比b好)
+        let wifi = sample(5, 4.0, 3.0, 0);
+        let relay = sample(25, 0.0, 0.0, 0);
+        let cases = [
+            // 默认只看延迟
+            (PathWeights::default(), wifi, relay, true),
+            (custom, wifi, relay, false),
+            (
+                custom,
+                sample(20, 0.0, 0.0, 0),
+                sample(20, 0.0, 0.0, 100_000),
+                false,
+            ),
+            (
+                custom,
+                sample(20, 0.0, 1.0, 0),
+                sample(20, 0.0, 5.0, 0),
+                true,
+            ),
+            (
+                custom,
+                sample(30, 0.0, 0.0, 0),
+                sample(20, 0.0, 6.0, 0),
+                true,
+            ),
+        ];
+        for (i, (weights, a, b, a_better)) in cases.iter().enumerate() {
+            assert_eq!(weights.score(a) < weights.score(b), *a_better, "case {}", i);
+        }
+        assert_eq!(custom.score(&wifi), 5.0 + 200.0 + 6.0);
+        assert_eq!(custom.score(&sample(10, 0.0, 0.0, 50_000)), 5.0);
+    }
+
+    #[test]
+    fn test_choice() {
+        let scores = PathScores::new();
+        let p2p = route(1, 5);
+        // 没有覆盖设置时不参与
+        assert!(!scores.active());
+        assert_eq!(scores.choice(&PEER), PathChoice::Default);
+        assert_eq!(scores.score(PEER, &p2p), None);
+        scores.compare(PEER, Some(&p2p), 12);
+        assert_eq!(scores.choice(&PEER), PathChoice::Default);
+
+        // 丢包的直连
+        for _ in 0..10 {
+            scores.probe(PEER, p2p.route_key());
+        }
+        scores.pong(PEER, p2p.route_key(), 5);
+        scores.set_weights(Some("rtt=1,loss=50".parse().unwrap()));
+        let sample = scores.sample(PEER, &p2p);
+        assert!(sample.loss > 50.0, "{:?}", sample);
+        scores.compare(PEER, Some(&p2p), 12);
+        assert_eq!(scores.choice(&PEER), PathChoice::Relay);
+        // 服务器延迟未知时不切换
+        scores.compare(PEER, Some(&p2p), -1);
+        assert_eq!(scores.choice(&PEER), PathChoice::Default);
+
+        let p2p_only = PeerPreference {
+            preference: Preference::P2p,
+            weights: None,
+        };
+        scores.set_peer(PEER, Some(p2p_only));
+        assert_eq!(scores.choice(&PEER), PathChoice::P2p);
+        scores.compare(PEER, Some(&p2p), 12);
+        assert_eq!(scores.choice(&PEER), PathChoice::P2p);
+        // 对端的权重优先于全局权重
+        let rtt_only = PeerPreference {
+            preference: Preference::Auto,
+            weights: Some("rtt=1".parse().unwrap()),
+        };
+        scores.set_peer(PEER, Some(rtt_only));
+        scores.compare(PEER, Some(&p2p), 12);
+        assert_eq!(scores.choice(&PEER), PathChoice::Default);
+        assert_eq!(scores.score(PEER, &p2p), Some(5.0));
+
+        scores.set_peer(PEER, None);
+        scores.set_weights(None);
+        assert!(!scores.active());
+    }
+
+    #[test]
+    fn test_persist() {
+        let list = PathPreferences(vec![
+            (
+                PEER,
+                PeerPreference {
+                    preference: Preference::Relay,
+                    weights: None,
+                },
+            ),
+            (
+                Ipv4Addr::new(10, 26, 0, 4),
+                PeerPreference {
+                    preference: Preference::Auto,
+                    weights: Some("rtt=1,loss=50,jitter=2,bandwidth=0.1".parse().unwrap()),
+                },
+            ),
+        ]);
+        let data = state_store::encode(&list);
+        let decoded = state_store::decode::<PathPreferences>(&data).unwrap();
+        assert_eq!(decoded.0, list.0);
+        // 格式错误的行跳过
+        let body = b"10.26.0.3 p2p\nbad line\n10.26.0.5 auto rtt=-1\n";
+        let decoded = PathPreferences::decode(1, body).unwrap();
+        assert_eq!(decoded.0.len(), 1);
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+/// 可以按对端单独调整的功能
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Feature {
+    Compress,
+    Encrypt,
+    Unknown(u8),
+}
+
+impl From<u8> for Feature {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Feature::Compress,
+            2 => Feature::Encrypt,
+            val => Feature::Unknown(val),
+        }
+    }
+}
+
+impl Into<u8> for Feature {
+    fn into(self) -> u8 {
+        match self {
+            Feature::Compress => 1,
+            Feature::Encrypt => 2,
+            Feature::Unknown(val) => val,
+        }
+    }
+}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "compress" => Ok(Feature::Compress),
+            "encrypt" => Ok(Feature::Encrypt),
+            _ => Err(format!("not match '{}', enum: encrypt", s)),
+        }
+    }
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Feature::Compress => f.write_str("compress"),
+            Feature::Encrypt => f.write_str("encrypt"),
+            Feature::Unknown(val) => write!(f, "unknown({})", val),
+        }
+    }
+}
+
+/// 手动设置的来源
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Origin {
+    /// 本机通过控制台设置
+    Local,
+    /// 对端发起，本机同意
+    Remote,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Pending {
+    seq: u32,
+    requested: bool,
+    // 发起请求前是否开启
+    previous: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Override {
+    // 当前生效的值
+    enabled: bool,
+    origin: Origin,
+    // 等待对端确认的请求
+    pending: Option<Pending>,
+}
+
+impl Override {
+    fn is_default(&self, feature: Feature) -> bool {
+        self.pending.is_none() && self.enabled == (feature == Feature::Encrypt)
+    }
+}
+
+/// 对端功能的手动设置
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FeatureOverride {
+    pub ip: Ipv4Addr,
+    pub feature: Feature,
+    pub enabled: bool,
+    pub origin: Origin,
+    pub pending: bool,
+}
+
+impl Display for FeatureOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        let origin = match (self.pending, self.origin) {
+            (true, _) => "pending",
+            (false, Origin::Local) => "manual",
+            (false, Origin::Remote) => "peer",
+        };
+        write!(f, "{}={}({})", self.feature, state, origin)
+    }
+}
+
+/// 按对端手动调整的功能
+///
+/// 只有双方确认后才会生效，之后一直保留，路由变化不会影响。
+/// 没有手动设置的对端使用全局配置，热点路径上只在有设置时才需要查表
+#[derive(Clone, Default)]
+pub struct PeerFeatures {
+    inner: Arc<RwLock<HashMap<(Ipv4Addr, Feature), Override>>>,
+    results: Arc<RwLock<HashMap<(Ipv4Addr, Feature), (u32, bool)>>>,
+    require_encryption: Arc<AtomicBool>,
+    // 是否同意对端发起的关闭加密，默认拒绝，防止被降级成明文
+    allow_remote_plaintext: Arc<AtomicBool>,
+    // 单独要求加密的对端
+    required: Arc<RwLock<HashSet<Ipv4Addr>>>,
+    next_seq: Arc<AtomicU32>,
+}
+
+impl PeerFeatures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 要求所有对端都加密时，不允许对任何对端关闭加密
+    pub fn set_require_encryption(&self, require: bool) {
+        self.require_encryption.store(require, Ordering::Relaxed);
+    }
+    pub fn require_encryption(&self) -> bool {
+        self.require_encryption.load(Ordering::Relaxed)
+    }
+    /// 同意对端发起的关闭加密请求，本机发起的不受影响
+    pub fn set_allow_remote_plaintext(&self, allow: bool) {
+        self.allow_remote_plaintext.store(allow, Ordering::Relaxed);
+    }
+    /// 要求和指定对端之间加密，已经关闭的加密立即恢复
+    pub fn set_required(&self, ip: Ipv4Addr, required: bool) {
+        if !required {
+            self.required.write().remove(&ip);
+            return;
+        }
+        self.required.write().insert(ip);
+        if self.inner.write().remove(&(ip, Feature::Encrypt)).is_some() {
+            log::info!("对端{}要求加密,恢复加密", ip);
+        }
+    }
+    pub fn is_required(&self, ip: &Ipv4Addr) -> bool {
+        self.require_encryption() || self.required.read().contains(ip)
+    }
+    fn policy(&self, ip: Ipv4Addr, feature: Feature, enabled: bool) -> io::Result<()> {
+        match feature {
+            Feature::Encrypt => {
+                if !enabled && self.is_required(&ip) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "encryption is required",
+                    ));
+                }
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} is not supported", feature),
+            )),
+        }
+    }
+    /// 发给该对端的数据是否不加密
+    #[inline]
+    pub fn is_plaintext(&self, ip: &Ipv4Addr) -> bool {
+        let guard = self.inner.read();
+        if guard.is_empty() {
+            return false;
+        }
+        matches!(guard.get(&(*ip, Feature::Encrypt)), Some(v) if !v.enabled)
+    }
+    /// 是否接受该对端未加密的数据，切换过程中两种都接受
+    #[inline]
+    pub fn accept_plaintext(&self, ip: &Ipv4Addr) -> bool {
+        let guard = self.inner.read();
+        if guard.is_empty() {
+            return false;
+        }
+        match guard.get(&(*ip, Feature::Encrypt)) {
+            Some(v) => match v.pending {
+                Some(p) => !p.requested || !p.previous,
+                None => !v.enabled,
+            },
+            None => false,
+        }
+    }
+    fn current(&self, ip: Ipv4Addr, feature: Feature) -> bool {
+        self.inner
+            .read()
+            .get(&(ip, feature))
+            .map_or(feature == Feature::Encrypt, |v| v.enabled)
+    }
+    /// 发起修改，返回请求序号，需要把请求发给对端
+    pub fn request(&self, ip: Ipv4Addr, feature: Feature, enabled: bool) -> io::Result<u32> {
+        self.policy(ip, feature, enabled)?;
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let previous = self.current(ip, feature);
+        self.inner.write().insert(
+            (ip, feature),
+            Override {
+                // 开启加密立即生效，对端任何时候都接受加密的数据
+                enabled: (feature == Feature::Encrypt && enabled) || previous,
+                origin: Origin::Local,
+                pending: Some(Pending {
+                    seq,
+                    requested: enabled,
+                    previous,
+                }),
+            },
+        );
+        Ok(seq)
+    }
+    /// 处理对端的请求，返回是否同意，同意时立即生效
+    pub fn handle_request(&self, ip: Ipv4Addr, feature: Feature, enabled: bool) -> bool {
+        if let Err(e) = self.policy(ip, feature, enabled) {
+            log::warn!("拒绝对端{}修改{}={},{}", ip, feature, enabled, e);
+            return false;
+        }
+        if feature == Feature::Encrypt
+            && !enabled
+            && !self.allow_remote_plaintext.load(Ordering::Relaxed)
+        {
+            log::warn!(
+                "拒绝对端{}关闭加密,需要本机使用'--allow-peer-plaintext'",
+                ip
+            );
+            return false;
+        }
+        let mut guard = self.inner.write();
+        if enabled == (feature == Feature::Encrypt) {
+            // 恢复默认
+            guard.remove(&(ip, feature));
+        } else {
+            guard.insert(
+                (ip, feature),
+                Override {
+                    enabled,
+                    origin: Origin::Remote,
+                    pending: None,
+                },
+            );
+        }
+        log::info!("对端{}修改{}={}", ip, feature, enabled);
+        true
+    }
+    /// 处理对端的回复，被拒绝时恢复原来的设置
+    pub fn handle_reply(&self, ip: Ipv4Addr, seq: u32, feature: Feature, accepted: bool) {
+        let mut guard = self.inner.write();
+        let entry = match guard.get_mut(&(ip, feature)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let pending = match entry.pending {
+            Some(pending) if pending.seq == seq => pending,
+            _ => return,
+        };
+        entry.pending = None;
+        entry.enabled = if accepted {
+            pending.requested
+        } else {
+            pending.previous
+        };
+        if entry.is_default(feature) {
+            guard.remove(&(ip, feature));
+        }
+        drop(guard);
+        if accepted {
+            log::info!("对端{}同意修改{},seq={}", ip, feature, seq);
+        } else {
+            log::warn!("对端{}拒绝修改{},seq={}", ip, feature, seq);
+        }
+        self.results.write().insert((ip, feature), (seq, accepted));
+    }
+    /// 请求的结果，还没有回复时返回None
+    pub fn result(&self, ip: Ipv4Addr, feature: Feature, seq: u32) -> Option<bool> {
+        match self.results.read().get(&(ip, feature)) {
+            Some((s, accepted)) if *s == seq => Some(*accepted),
+            _ => None,
+        }
+    }
+    /// 对端没有回复，恢复原来的设置
+    pub fn cancel(&self, ip: Ipv4Addr, feature: Feature, seq: u32) {
+        let mut guard = self.inner.write();
+        if let Some(entry) = guard.get_mut(&(ip, feature)) {
+            match entry.pending {
+                Some(pending) if pending.seq == seq => {
+                    entry.pending = None;
+                    entry.enabled = pending.previous;
+                    if entry.is_default(feature) {
+                        guard.remove(&(ip, feature));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    /// 只保留在线对端的设置
+    pub fn retain_peers(&self, online: &[Ipv4Addr]) {
+        let mut guard = self.inner.write();
+        if guard.is_empty() {
+            return;
+        }
+        guard.retain(|(ip, feature), _| {
+            let keep = online.contains(ip);
+            if !keep {
+                log::info!("对端{}已离线,清除{}设置", ip, feature);
+            }
+            keep
+        });
+    }
+    pub fn list(&self) -> Vec<FeatureOverride> {
+        let mut list: Vec<FeatureOverride> = self
+            .inner
+            .read()
+            .iter()
+            .map(|((ip, feature), v)| FeatureOverride {
+                ip: *ip,
+                feature: *feature,
+                enabled: v.pending.map_or(v.enabled, |p| p.requested),
+                origin: v.origin,
+                pending: v.pending.is_some(),
+            })
+            .collect();
+        list.sort_by_key(|v| (v.ip, Into::<u8>::into(v.feature)));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::channel::peer_feature::{Feature, Origin, PeerFeatures};
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    /// a向b请求修改，返回b是否同意
+    fn negotiate(a: &PeerFeatures, b: &PeerFeatures, feature: Feature, enabled: bool) -> bool {
+        let seq = a.request(B, feature, enabled).unwrap();
+        let accepted = b.handle_request(A, feature, enabled);
+        a.handle_reply(B, seq, feature, accepted);
+        assert_eq!(a.result(B, feature, seq), Some(accepted));
+        accepted
+    }
+
+    #[test]
+    fn test_accept() {
+        let a = PeerFeatures::new();
+        let b = PeerFeatures::new();
+        b.set_allow_remote_plaintext(true);
+        assert!(!a.is_plaintext(&B));
+        // 请求发出后还没有回复，继续加密，但已经接受对端的明文
+        let seq = a.request(B, Feature::Encrypt, false).unwrap();
+        assert!(!a.is_plaintext(&B));
+        assert!(a.accept_plaintext(&B));
+        assert!(b.handle_request(A, Feature::Encrypt, false));
+        assert!(b.is_plaintext(&A));
+        a.handle_reply(B, seq, Feature::Encrypt, true);
+        assert!(a.is_plaintext(&B));
+        let list = a.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].origin, Origin::Local);
+        assert_eq!(list[0].to_string(), "encrypt=off(manual)");
+        assert_eq!(b.list()[0].to_string(), "encrypt=off(peer)");
+        // 恢复加密
+        assert!(negotiate(&a, &b, Feature::Encrypt, true));
+        assert!(!a.is_plaintext(&B) && !a.accept_plaintext(&B));
+        assert!(!b.is_plaintext(&A) && !b.accept_plaintext(&A));
+        assert!(a.list().is_empty() && b.list().is_empty());
+    }
+
+    #[test]
+    fn test_refuse() {
+        let a = PeerFeatures::new();
+        let b = PeerFeatures::new();
+        // 默认拒绝对端关闭加密
+        assert!(!negotiate(&a, &b, Feature::Encrypt, false));
+        assert!(!a.accept_plaintext(&B));
+        b.set_allow_remote_plaintext(true);
+        b.set_require_encryption(true);
+        assert!(!negotiate(&a, &b, Feature::Encrypt, false));
+        // 被拒绝后保持原来的设置
+        assert!(!a.is_plaintext(&B));
+        assert!(!a.accept_plaintext(&B));
+        assert!(a.list().is_empty() && b.list().is_empty());
+        // 本机要求加密时不能发起
+        assert!(b.request(A, Feature::Encrypt, false).is_err());
+        // 不支持压缩
+        assert!(a.request(B, Feature::Compress, true).is_err());
+        assert!(!b.handle_request(A, Feature::Compress, true));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let a = PeerFeatures::new();
+        let seq = a.request(B, Feature::Encrypt, false).unwrap();
+        a.cancel(B, Feature::Encrypt, seq);
+        assert!(a.list().is_empty());
+        assert_eq!(a.result(B, Feature::Encrypt, seq), None);
+        // 过期的回复不生效
+        a.handle_reply(B, seq, Feature::Encrypt, true);
+        assert!(!a.is_plaintext(&B));
+    }
+
+    #[test]
+    fn test_required() {
+        let a = PeerFeatures::new();
+        let b = PeerFeatures::new();
+        a.set_allow_remote_plaintext(true);
+        b.set_allow_remote_plaintext(true);
+        assert!(negotiate(&a, &b, Feature::Encrypt, false));
+        assert!(a.is_plaintext(&B));
+        // 单独要求加密后立即恢复，也不再接受明文
+        a.set_required(B, true);
+        assert!(a.is_required(&B) && !a.is_required(&A));
+        assert!(!a.is_plaintext(&B) && !a.accept_plaintext(&B));
+        assert!(a.request(B, Feature::Encrypt, false).is_err());
+        assert!(!a.handle_request(B, Feature::Encrypt, false));
+        // 其他对端不受影响
+        assert!(a.request(A, Feature::Encrypt, false).is_ok());
+        a.set_required(B, false);
+        assert!(negotiate(&a, &b, Feature::Encrypt, false));
+    }
+}
@@ -0,0 +1,367 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 统计的对端数量上限，超过后新的对端不再统计，避免伪造来源导致内存增长
+const SLOTS: usize = 1024;
+/// 计算速率的采样间隔
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// 对端下线后槽位标记成这个值，查找时跳过，新的对端可以占用
+const TOMBSTONE: u32 = u32::MAX;
+
+#[derive(Default)]
+struct Slot {
+    // 对端虚拟ip，0表示空位，TOMBSTONE表示对端已下线
+    ip: AtomicU32,
+    tx_p2p: AtomicU64,
+    tx_relay: AtomicU64,
+    rx_p2p: AtomicU64,
+    rx_relay: AtomicU64,
+    tx_packets: AtomicU64,
+    rx_packets: AtomicU64,
+    // 上一个采样间隔的速率，字节/秒
+    tx_rate: AtomicU64,
+    rx_rate: AtomicU64,
+}
+
+impl Slot {
+    fn tx(&self) -> u64 {
+        self.tx_p2p.load(Ordering::Relaxed) + self.tx_relay.load(Ordering::Relaxed)
+    }
+    fn rx(&self) -> u64 {
+        self.rx_p2p.load(Ordering::Relaxed) + self.rx_relay.load(Ordering::Relaxed)
+    }
+}
+
+/// 按对端统计收发的字节数和包数，区分直连和服务器中继
+///
+/// 收发线程只做原子操作：按ip散列到固定的槽位，空位用cas占用，不加锁。
+/// 下线对端的槽位只标记不清空，不会打断后面的对端的查找
+pub struct PeerStats {
+    slots: Box<[Slot]>,
+    // 上次采样的时间和各槽位的(发送,接收)字节数，只有采样时使用
+    last: Mutex<(Option<Instant>, Vec<(u64, u64)>)>,
+}
+
+/// 一个对端的流量，或者所有对端的合计
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PeerTraffic {
+    pub peer: Ipv4Addr,
+    pub tx_p2p: u64,
+    pub tx_relay: u64,
+    pub rx_p2p: u64,
+    pub rx_relay: u64,
+    pub tx_packets: u64,
+    pub rx_packets: u64,
+    /// 上一个采样间隔的速率，字节/秒
+    pub tx_rate: u64,
+    pub rx_rate: u64,
+}
+
+impl PeerTraffic {
+    pub fn tx(&self) -> u64 {
+        self.tx_p2p + self.tx_relay
+    }
+    pub fn rx(&self) -> u64 {
+        self.rx_p2p + self.rx_relay
+    }
+    pub fn packets(&self) -> u64 {
+        self.tx_packets + self.rx_packets
+    }
+    /// 经过中继的字节数所占的比例，百分比
+    pub fn relay_percent(&self) -> u64 {
+        let total = self.tx() + self.rx();
+        if total == 0 {
+            return 0;
+        }
+        (self.tx_relay + self.rx_relay) * 100 / total
+    }
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerStats {
+    pub fn new() -> Self {
+        Self::with_slots(SLOTS)
+    }
+    fn with_slots(slots: usize) -> Self {
+        Self {
+            slots: (0..slots).map(|_| Slot::default()).collect(),
+            last: Mutex::new((None, vec![(0, 0); slots])),
+        }
+    }
+    /// 找到对端的槽位，没有时优先占用第一个下线对端的槽位，其次是空位，已满时返回None
+    fn slot(&self, peer: Ipv4Addr) -> Option<&Slot> {
+        let ip = u32::from(peer);
+        if ip == 0 || ip == TOMBSTONE {
+            return None;
+        }
+        let len = self.slots.len();
+        let start = (ip.wrapping_mul(0x9E37_79B1) >> 16) as usize % len;
+        loop {
+            let mut free = None;
+            for i in 0..len {
+                let slot = &self.slots[(start + i) % len];
+                match slot.ip.load(Ordering::Acquire) {
+                    v if v == ip => return Some(slot),
+                    0 => {
+                        free.get_or_insert((slot, 0));
+                        break;
+                    }
+                    TOMBSTONE => {
+                        free.get_or_insert((slot, TOMBSTONE));
+                    }
+                    _ => {}
+                }
+            }
+            let (slot, current) = free?;
+            match slot
+                .ip
+                .compare_exchange(current, ip, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Some(slot),
+                // 同时被其他线程占用，可能就是同一个对端
+                Err(v) if v == ip => return Some(slot),
+                // 被其他对端占用，重新查找
+                Err(_) => {}
+            }
+        }
+    }
+    /// 清除已下线对端的统计，长时间运行时槽位不会被来了又走的对端占满
+    pub fn retain_peers(&self, online: &[Ipv4Addr]) {
+        let mut last = self.last.lock();
+        for (slot, prev) in self.slots.iter().zip(last.1.iter_mut()) {
+            let ip = slot.ip.load(Ordering::Acquire);
+            if ip == 0 || ip == TOMBSTONE || online.contains(&Ipv4Addr::from(ip)) {
+                continue;
+            }
+            for v in [
+                &slot.tx_p2p,
+                &slot.tx_relay,
+                &slot.rx_p2p,
+                &slot.rx_relay,
+                &slot.tx_packets,
+                &slot.rx_packets,
+                &slot.tx_rate,
+                &slot.rx_rate,
+            ] {
+                v.store(0, Ordering::Relaxed);
+            }
+            *prev = (0, 0);
+            slot.ip.store(TOMBSTONE, Ordering::Release);
+        }
+    }
+    /// 发往对端的数据，p2p为false表示经过服务器中继
+    #[inline]
+    pub fn tx(&self, peer: Ipv4Addr, len: usize, p2p: bool) {
+        if let Some(slot) = self.slot(peer) {
+            let bytes = if p2p { &slot.tx_p2p } else { &slot.tx_relay };
+            bytes.fetch_add(len as u64, Ordering::Relaxed);
+            slot.tx_packets.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// 收到对端的数据
+    #[inline]
+    pub fn rx(&self, peer: Ipv4Addr, len: usize, p2p: bool) {
+        if let Some(slot) = self.slot(peer) {
+            let bytes = if p2p { &slot.rx_p2p } else { &slot.rx_relay };
+            bytes.fetch_add(len as u64, Ordering::Relaxed);
+            slot.rx_packets.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// 定时调用，用和上次采样之间的差值计算速率
+    pub fn sample(&self, now: Instant) {
+        let mut last = self.last.lock();
+        let elapsed = last.0.map(|v| now.saturating_duration_since(v));
+        for (slot, prev) in self.slots.iter().zip(last.1.iter_mut()) {
+            let ip = slot.ip.load(Ordering::Acquire);
+            if ip == 0 || ip == TOMBSTONE {
+                continue;
+            }
+            let current = (slot.tx(), slot.rx());
+            if let Some(elapsed) = elapsed.filter(|v| !v.is_zero()) {
+                let rate = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64()) as u64;
+                slot.tx_rate
+                    .store(rate(current.0.saturating_sub(prev.0)), Ordering::Relaxed);
+                slot.rx_rate
+                    .store(rate(current.1.saturating_sub(prev.1)), Ordering::Relaxed);
+            }
+            *prev = current;
+        }
+        last.0 = Some(now);
+    }
+    /// 所有有过流量的对端，按ip排序
+    pub fn snapshot(&self) -> Vec<PeerTraffic> {
+        let mut list: Vec<PeerTraffic> = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let ip = slot.ip.load(Ordering::Acquire);
+                if ip == 0 || ip == TOMBSTONE {
+                    return None;
+                }
+                Some(PeerTraffic {
+                    peer: Ipv4Addr::from(ip),
+                    tx_p2p: slot.tx_p2p.load(Ordering::Relaxed),
+                    tx_relay: slot.tx_relay.load(Ordering::Relaxed),
+                    rx_p2p: slot.rx_p2p.load(Ordering::Relaxed),
+                    rx_relay: slot.rx_relay.load(Ordering::Relaxed),
+                    tx_packets: slot.tx_packets.load(Ordering::Relaxed),
+                    rx_packets: slot.rx_packets.load(Ordering::Relaxed),
+                    tx_rate: slot.tx_rate.load(Ordering::Relaxed),
+                    rx_rate: slot.rx_rate.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        list.sort_by_key(|v| v.peer);
+        list
+    }
+    /// 所有对端的合计，peer为0.0.0.0
+    pub fn totals(&self) -> PeerTraffic {
+        let mut total = PeerTraffic {
+            peer: Ipv4Addr::UNSPECIFIED,
+            tx_p2p: 0,
+            tx_relay: 0,
+            rx_p2p: 0,
+            rx_relay: 0,
+            tx_packets: 0,
+            rx_packets: 0,
+            tx_rate: 0,
+            rx_rate: 0,
+        };
+        for v in self.snapshot() {
+            total.tx_p2p += v.tx_p2p;
+            total.tx_relay += v.tx_relay;
+            total.rx_p2p += v.rx_p2p;
+            total.rx_relay += v.rx_relay;
+            total.tx_packets += v.tx_packets;
+            total.rx_packets += v.rx_packets;
+            total.tx_rate += v.tx_rate;
+            total.rx_rate += v.rx_rate;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::PeerStats;
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 4);
+
+    #[test]
+    fn test_counters() {
+        let stats = PeerStats::new();
+        stats.tx(B, 1000, true);
+        stats.tx(A, 100, false);
+        stats.rx(A, 200, true);
+        stats.rx(A, 300, false);
+        // 不是对端
+        stats.tx(Ipv4Addr::UNSPECIFIED, 100, true);
+        let list = stats.snapshot();
+        assert_eq!(list.len(), 2);
+        let a = list[0];
+        assert_eq!(a.peer, A);
+        assert_eq!((a.tx(), a.rx(), a.packets()), (100, 500, 3));
+        assert_eq!((a.tx_relay, a.rx_p2p, a.rx_relay), (100, 200, 300));
+        assert_eq!(a.relay_percent(), 66);
+        assert_eq!(list[1].relay_percent(), 0);
+        let totals = stats.totals();
+        assert_eq!((totals.tx(), totals.rx(), totals.packets()), (1100, 500, 4));
+    }
+
+    #[test]
+    fn test_rate() {
+        let stats = PeerStats::new();
+        let start = Instant::now();
+        stats.rx(A, 1000, true);
+        // 第一次采样只记录基准
+        stats.sample(start);
+        assert_eq!(stats.snapshot()[0].rx_rate, 0);
+        stats.rx(A, 5000, true);
+        stats.tx(A, 2500, false);
+        stats.sample(start + Duration::from_secs(5));
+        let a = stats.snapshot()[0];
+        assert_eq!((a.rx_rate, a.tx_rate), (1000, 500));
+        // 没有新的流量时速率归零
+        stats.sample(start + Duration::from_secs(10));
+        assert_eq!(stats.totals().rx_rate, 0);
+    }
+
+    #[test]
+    fn test_full() {
+        let stats = PeerStats::with_slots(4);
+        for i in 1..=6u32 {
+            stats.rx(Ipv4Addr::from(0x0A1A_0000 + i), 10, true);
+        }
+        // 已满时只统计已有的对端
+        stats.rx(Ipv4Addr::from(0x0A1A_0001), 10, true);
+        let list = stats.snapshot();
+        assert_eq!(list.len(), 4);
+        assert_eq!(stats.totals().rx(), 50);
+    }
+
+    #[test]
+    fn test_retain_peers() {
+        let stats = PeerStats::with_slots(4);
+        let peers: Vec<Ipv4Addr> = (1..=4u32)
+            .map(|i| Ipv4Addr::from(0x0A1A_0000 + i))
+            .collect();
+        for peer in &peers {
+            stats.rx(*peer, 10, true);
+        }
+        let new = Ipv4Addr::from(0x0A1A_0010);
+        stats.rx(new, 10, true);
+        assert_eq!(stats.snapshot().len(), 4);
+        // 下线的对端让出槽位，留下的对端仍然能找到
+        stats.retain_peers(&peers[2..]);
+        assert_eq!(stats.snapshot().len(), 2);
+        stats.rx(peers[3], 10, true);
+        stats.rx(new, 10, true);
+        let list = stats.snapshot();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.iter().find(|v| v.peer == peers[3]).unwrap().rx(), 20);
+        assert_eq!(list.iter().find(|v| v.peer == new).unwrap().rx(), 10);
+        // 重新上线的对端从零开始统计，不会占用两个槽位
+        stats.rx(peers[0], 10, true);
+        stats.rx(peers[0], 10, true);
+        let list = stats.snapshot();
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.iter().find(|v| v.peer == peers[0]).unwrap().rx(), 20);
+        assert_eq!(stats.totals().rx(), 60);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let stats = Arc::new(PeerStats::with_slots(64));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let stats = stats.clone();
+                std::thread::spawn(move || {
+                    for i in 0..9600u32 {
+                        stats.tx(Ipv4Addr::from(0x0A1A_0000 + i % 32 + 1), 1, i % 2 == 0);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // 同时占用空位不会产生重复的槽位，也不丢失计数
+        let list = stats.snapshot();
+        assert_eq!(list.len(), 32);
+        assert!(list.iter().all(|v| v.tx_packets == 1200));
+        assert_eq!(stats.totals().tx(), 38_400);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 收到的回复
+#[derive(Copy, Clone, Debug)]
+pub struct EchoReply {
+    /// 回复是否经过直连到达
+    pub p2p: bool,
+    pub at: Instant,
+}
+
+/// ping命令等待中的回复
+///
+/// 序号在进程内递增，同时进行的多个ping和心跳互不影响
+#[derive(Default)]
+pub struct EchoWaiters {
+    next_seq: AtomicU32,
+    waiting: Mutex<HashMap<u32, SyncSender<EchoReply>>>,
+}
+
+impl EchoWaiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 发送前调用，结束后需要cancel
+    pub fn expect(&self) -> (u32, Receiver<EchoReply>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = sync_channel(1);
+        self.waiting.lock().insert(seq, sender);
+        (seq, receiver)
+    }
+    pub fn cancel(&self, seq: u32) {
+        self.waiting.lock().remove(&seq);
+    }
+    /// 返回是否有人在等待，超时后到达的回复直接丢弃
+    pub fn deliver(&self, seq: u32, p2p: bool) -> bool {
+        match self.waiting.lock().remove(&seq) {
+            Some(sender) => sender
+                .try_send(EchoReply {
+                    p2p,
+                    at: Instant::now(),
+                })
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// 一次ping的统计
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PingSummary {
+    pub sent: u32,
+    pub received: u32,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+impl PingSummary {
+    /// None表示超时
+    pub fn new(replies: &[Option<Duration>]) -> PingSummary {
+        let rtts: Vec<Duration> = replies.iter().filter_map(|v| *v).collect();
+        let received = rtts.len() as u32;
+        let mut summary = PingSummary {
+            sent: replies.len() as u32,
+            received,
+            ..Default::default()
+        };
+        if received > 0 {
+            summary.min = rtts.iter().min().copied().unwrap_or_default();
+            summary.max = rtts.iter().max().copied().unwrap_or_default();
+            summary.avg = rtts.iter().sum::<Duration>() / received;
+        }
+        summary
+    }
+    /// 丢包率，百分比
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.received) as f64 * 100.0 / self.sent as f64
+    }
+}
+
+impl Display for PingSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} sent, {} received, {:.0}% loss",
+            self.sent,
+            self.received,
+            self.loss()
+        )?;
+        if self.received > 0 {
+            write!(
+                f,
+                ", rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+                ms(self.min),
+                ms(self.avg),
+                ms(self.max)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::channel::ping::{EchoWaiters, PingSummary};
+
+    #[test]
+    fn test_echo_waiters() {
+        let waiters = EchoWaiters::new();
+        let (first, first_rx) = waiters.expect();
+        let (second, second_rx) = waiters.expect();
+        assert_ne!(first, second);
+        // 回复乱序到达时按序号匹配
+        assert!(waiters.deliver(second, false));
+        assert!(waiters.deliver(first, true));
+        assert!(first_rx.try_recv().unwrap().p2p);
+        assert!(!second_rx.try_recv().unwrap().p2p);
+        // 重复的回复和超时后的回复
+        assert!(!waiters.deliver(first, true));
+        let (third, _third_rx) = waiters.expect();
+        waiters.cancel(third);
+        assert!(!waiters.deliver(third, true));
+    }
+
+    #[test]
+    fn test_summary() {
+        let ms = Duration::from_millis;
+        let summary = PingSummary::new(&[Some(ms(10)), None, Some(ms(30)), Some(ms(20))]);
+        assert_eq!((summary.sent, summary.received), (4, 3));
+        assert_eq!(
+            (summary.min, summary.avg, summary.max),
+            (ms(10), ms(20), ms(30))
+        );
+        assert_eq!(
+            summary.to_string(),
+            "4 sent, 3 received, 25% loss, rtt min/avg/max = 10.00/20.00/30.00 ms"
+        );
+        assert_eq!(
+            PingSummary::new(&[None, None]).to_string(),
+            "2 sent, 0 received, 100% loss"
+        );
+    }
+}
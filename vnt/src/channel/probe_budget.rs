@@ -0,0 +1,286 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 默认预算，kbps。50个对端的心跳约20kbps，正常使用时不会触发
+pub const DEFAULT_KBPS: u64 = 64;
+/// 按流量计费的网络使用的预算，kbps
+pub const METERED_KBPS: u64 = 8;
+/// 桶容量为一个心跳周期的预算，心跳是集中发送的
+const BURST: Duration = Duration::from_secs(3);
+/// ip和udp头
+const OVERHEAD: usize = 28;
+
+/// 后台探测的优先级
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority {
+    /// 保持在线和正在使用的路径，总是发送
+    Critical,
+    /// 预算内发送
+    Normal,
+    /// 预算剩余一半以上才发送
+    Opportunistic,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Critical => "critical",
+            Priority::Normal => "normal",
+            Priority::Opportunistic => "opportunistic",
+        })
+    }
+}
+
+/// 后台探测的种类
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProbeKind {
+    /// 和服务器之间的心跳
+    ServerHeartbeat,
+    /// 对端最优直连路径上的心跳
+    PeerKeepalive,
+    /// 对端其他直连路径上的心跳，只用于刷新延迟
+    LatencyProbe,
+    /// 没有直连路径时经过服务器的心跳
+    RelayEcho,
+    /// 寻找可以帮忙转发的客户端
+    RelayProbe,
+    /// 公网地址探测
+    AddrRequest,
+    /// 打洞协商
+    Punch,
+}
+
+impl ProbeKind {
+    pub const ALL: [ProbeKind; 7] = [
+        ProbeKind::ServerHeartbeat,
+        ProbeKind::PeerKeepalive,
+        ProbeKind::LatencyProbe,
+        ProbeKind::RelayEcho,
+        ProbeKind::RelayProbe,
+        ProbeKind::AddrRequest,
+        ProbeKind::Punch,
+    ];
+    fn index(&self) -> usize {
+        match self {
+            ProbeKind::ServerHeartbeat => 0,
+            ProbeKind::PeerKeepalive => 1,
+            ProbeKind::LatencyProbe => 2,
+            ProbeKind::RelayEcho => 3,
+            ProbeKind::RelayProbe => 4,
+            ProbeKind::AddrRequest => 5,
+            ProbeKind::Punch => 6,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeKind::ServerHeartbeat => "server_heartbeat",
+            ProbeKind::PeerKeepalive => "peer_keepalive",
+            ProbeKind::LatencyProbe => "latency_probe",
+            ProbeKind::RelayEcho => "relay_echo",
+            ProbeKind::RelayProbe => "relay_probe",
+            ProbeKind::AddrRequest => "addr_request",
+            ProbeKind::Punch => "punch",
+        }
+    }
+    pub fn priority(&self) -> Priority {
+        match self {
+            ProbeKind::ServerHeartbeat | ProbeKind::PeerKeepalive => Priority::Critical,
+            ProbeKind::RelayEcho | ProbeKind::AddrRequest | ProbeKind::Punch => Priority::Normal,
+            ProbeKind::LatencyProbe | ProbeKind::RelayProbe => Priority::Opportunistic,
+        }
+    }
+}
+
+/// 单个种类的统计
+#[derive(Clone, Debug)]
+pub struct ProbeStat {
+    pub kind: ProbeKind,
+    pub sent: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+}
+
+struct KindStats {
+    sent: Counter,
+    bytes: Counter,
+    skipped: Counter,
+}
+
+/// 后台探测流量的总预算
+///
+/// 各个定时探测在发送前申请，按优先级决定是否发送：重要的探测总是发送但会消耗预算，
+/// 普通的探测在预算内发送，可有可无的探测只在预算充足时发送，被跳过的等下一轮再尝试
+pub struct ProbeBudget {
+    // 字节/秒，0表示不限制
+    rate: AtomicU64,
+    // 剩余预算，可以被重要的探测透支
+    state: Mutex<(i64, Instant)>,
+    stats: Vec<KindStats>,
+}
+
+impl ProbeBudget {
+    pub fn new(metrics: &Registry) -> Self {
+        let stats = ProbeKind::ALL
+            .iter()
+            .map(|kind| KindStats {
+                sent: metrics.counter("probe_sent", &[("kind", kind.name())]),
+                bytes: metrics.counter("probe_bytes", &[("kind", kind.name())]),
+                skipped: metrics.counter("probe_skipped", &[("kind", kind.name())]),
+            })
+            .collect();
+        Self {
+            rate: AtomicU64::new(DEFAULT_KBPS * 1000 / 8),
+            state: Mutex::new((0, Instant::now())),
+            stats,
+        }
+    }
+    /// kbps为0表示不限制
+    pub fn set_kbps(&self, kbps: u64) {
+        self.rate.store(kbps * 1000 / 8, Ordering::Relaxed);
+        let mut state = self.state.lock();
+        state.0 = self.capacity();
+    }
+    pub fn kbps(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed) * 8 / 1000
+    }
+    fn capacity(&self) -> i64 {
+        (self.rate.load(Ordering::Relaxed) as u128 * BURST.as_millis() / 1000) as i64
+    }
+    /// 申请发送一个len字节的探测包，返回false时跳过本次探测
+    pub fn allow(&self, kind: ProbeKind, len: usize) -> bool {
+        self.allow_at(kind, len, Instant::now())
+    }
+    pub fn allow_at(&self, kind: ProbeKind, len: usize, now: Instant) -> bool {
+        let len = len + OVERHEAD;
+        let stats = &self.stats[kind.index()];
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate != 0 {
+            let capacity = self.capacity();
+            let mut guard = self.state.lock();
+            let (tokens, last) = &mut *guard;
+            let elapsed = now.saturating_duration_since(*last);
+            let refill = (elapsed.as_micros() as u64).saturating_mul(rate) / 1_000_000;
+            if refill > 0 {
+                *tokens = tokens.saturating_add(refill as i64).min(capacity);
+                *last = now;
+            }
+            let cost = len as i64;
+            let allowed = match kind.priority() {
+                Priority::Critical => true,
+                Priority::Normal => *tokens >= cost,
+                Priority::Opportunistic => *tokens - cost >= capacity / 2,
+            };
+            if !allowed {
+                stats.skipped.inc();
+                return false;
+            }
+            // 最多透支一个桶，避免长时间压制其他探测
+            *tokens = (*tokens - cost).max(-capacity);
+        }
+        stats.sent.inc();
+        stats.bytes.add(len as u64);
+        true
+    }
+    pub fn snapshot(&self) -> Vec<ProbeStat> {
+        ProbeKind::ALL
+            .iter()
+            .map(|kind| {
+                let stats = &self.stats[kind.index()];
+                ProbeStat {
+                    kind: *kind,
+                    sent: stats.sent.get(),
+                    bytes: stats.bytes.get(),
+                    skipped: stats.skipped.get(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ProbeBudget, ProbeKind, BURST, OVERHEAD};
+    use crate::util::metrics::Registry;
+
+    fn stat(budget: &ProbeBudget, kind: ProbeKind) -> (u64, u64, u64) {
+        let s = budget
+            .snapshot()
+            .into_iter()
+            .find(|v| v.kind == kind)
+            .unwrap();
+        (s.sent, s.bytes, s.skipped)
+    }
+
+    #[test]
+    fn test_priority() {
+        let budget = ProbeBudget::new(&Registry::new());
+        budget.set_kbps(8);
+        let now = Instant::now();
+        // 桶容量3000字节
+        let len = 100 - OVERHEAD;
+        for _ in 0..15 {
+            assert!(budget.allow_at(ProbeKind::Punch, len, now));
+        }
+        // 剩余1500，可有可无的探测要保留一半
+        assert!(!budget.allow_at(ProbeKind::LatencyProbe, len, now));
+        assert!(budget.allow_at(ProbeKind::Punch, len, now));
+        // 预算用完后普通探测跳过，重要的探测仍然发送
+        for _ in 0..14 {
+            budget.allow_at(ProbeKind::Punch, len, now);
+        }
+        assert!(!budget.allow_at(ProbeKind::AddrRequest, len, now));
+        assert!(budget.allow_at(ProbeKind::ServerHeartbeat, len, now));
+        assert!(budget.allow_at(ProbeKind::PeerKeepalive, len, now));
+        assert_eq!(stat(&budget, ProbeKind::LatencyProbe), (0, 0, 1));
+        assert_eq!(stat(&budget, ProbeKind::ServerHeartbeat), (1, 100, 0));
+        // 透支的部分恢复之后才发送普通探测
+        assert!(!budget.allow_at(ProbeKind::Punch, len, now + Duration::from_millis(100)));
+        assert!(budget.allow_at(ProbeKind::Punch, len, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_budget_under_demand() {
+        let budget = ProbeBudget::new(&Registry::new());
+        budget.set_kbps(16);
+        let rate = 16 * 1000 / 8;
+        let start = Instant::now();
+        let secs = 60;
+        // 每100ms: 重要的探测200字节(10%预算)，普通和可有可无的各需要2倍预算
+        for tick in 0..secs * 10 {
+            let now = start + Duration::from_millis(tick * 100);
+            budget.allow_at(ProbeKind::ServerHeartbeat, 200 - OVERHEAD, now);
+            for _ in 0..4 {
+                budget.allow_at(ProbeKind::Punch, 100 - OVERHEAD, now);
+                budget.allow_at(ProbeKind::RelayProbe, 100 - OVERHEAD, now);
+            }
+        }
+        let total: u64 = budget.snapshot().iter().map(|v| v.bytes).sum();
+        // 总量不超过预算加一个桶
+        let limit = rate * secs + rate * BURST.as_secs();
+        assert!(total <= limit, "{} > {}", total, limit);
+        assert!(total >= rate * secs * 9 / 10, "{}", total);
+        // 重要的探测全部发送，普通的优先于可有可无的
+        assert_eq!(stat(&budget, ProbeKind::ServerHeartbeat).2, 0);
+        let punch = stat(&budget, ProbeKind::Punch);
+        let relay = stat(&budget, ProbeKind::RelayProbe);
+        assert!(punch.0 > relay.0, "{:?} {:?}", punch, relay);
+        assert!(relay.2 > 0);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let budget = ProbeBudget::new(&Registry::new());
+        budget.set_kbps(0);
+        let now = Instant::now();
+        for _ in 0..10000 {
+            assert!(budget.allow_at(ProbeKind::RelayProbe, 1000, now));
+        }
+    }
+}
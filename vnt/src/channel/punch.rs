@@ -9,10 +9,19 @@ use rand::prelude::SliceRandom;
 use rand::Rng;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::diary::DiaryEvent;
+use crate::channel::endpoint::{Endpoint, PeerPath};
 use crate::channel::sender::AcceptSocketSender;
 use crate::external_route::ExternalRoute;
 use crate::nat::NatTest;
 
+/// 对端是对称nat时，在观察到的端口上下扫描的默认范围
+pub const DEFAULT_PORT_WINDOW: u16 = 64;
+/// 扫描时每批发送的探测包数，每批之后检查是否已经打通
+const SWEEP_BURST: usize = 8;
+/// 两批探测包之间的间隔，避免触发运营商或nat设备的限速
+const SWEEP_PAUSE: Duration = Duration::from_millis(20);
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PunchModel {
     IPv4,
@@ -57,6 +66,67 @@ pub enum NatType {
     Cone,
 }
 
+/// 用于显示和注册时上报的nat分类，打洞只区分锥形和对称
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NatClass {
+    /// 还没有探测出公网地址
+    Unknown,
+    /// 公网地址就是本机地址，没有nat
+    OpenInternet,
+    Cone,
+    Symmetric,
+}
+
+/// 向对端公网端口打洞的方式，由双方的nat类型决定
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PortStrategy {
+    /// 只使用服务端观察到的端口
+    Exact,
+    /// 在观察到的端口附近扫描，预测对称nat新分配的端口
+    Sweep,
+}
+
+impl PortStrategy {
+    /// 只有自己是锥形、对端是对称时扫描才有意义，自己也是对称时对端猜中了也通信不了
+    pub fn new(local: NatType, remote: NatType, window: u16) -> Self {
+        match (local, remote) {
+            (NatType::Cone, NatType::Symmetric) if window > 0 => PortStrategy::Sweep,
+            _ => PortStrategy::Exact,
+        }
+    }
+}
+
+/// 观察到的端口附近的端口，由近到远排列，同样距离时先向上，对称nat通常递增分配端口
+pub fn sweep_ports(port: u16, window: u16) -> Vec<u16> {
+    let mut ports = Vec::with_capacity(window as usize * 2 + 1);
+    if port != 0 {
+        ports.push(port);
+    }
+    for distance in 1..=window {
+        if let Some(up) = port.checked_add(distance) {
+            ports.push(up);
+        }
+        if let Some(down) = port.checked_sub(distance) {
+            if down != 0 {
+                ports.push(down);
+            }
+        }
+    }
+    ports
+}
+
+impl NatClass {
+    /// 对直连可能性的说明
+    pub fn hint(&self) -> &'static str {
+        match self {
+            NatClass::Unknown => "detection pending",
+            NatClass::OpenInternet => "no NAT, peers can connect directly",
+            NatClass::Cone => "direct connections possible with most peers",
+            NatClass::Symmetric => "direct connections to other symmetric peers unlikely",
+        }
+    }
+}
+
 impl NatInfo {
     pub fn new(
         mut public_ips: Vec<Ipv4Addr>,
@@ -123,6 +193,27 @@ impl NatInfo {
     pub fn local_ipv4(&self) -> Option<Ipv4Addr> {
         self.local_ipv4
     }
+    /// 路由器上的端口映射对任何来源开放，主端口改用映射的外部端口并按锥形打洞
+    pub fn apply_mapping(&mut self, external: SocketAddrV4) {
+        self.public_ips.retain(|ip| ip != external.ip());
+        self.public_ips.insert(0, *external.ip());
+        if let Some(port) = self.public_ports.get_mut(0) {
+            *port = external.port();
+        }
+        self.nat_type = NatType::Cone;
+    }
+    pub fn nat_class(&self) -> NatClass {
+        if self.public_ips.is_empty() {
+            return NatClass::Unknown;
+        }
+        match self.nat_type {
+            NatType::Symmetric => NatClass::Symmetric,
+            NatType::Cone => match self.local_ipv4 {
+                Some(ip) if self.public_ips.contains(&ip) => NatClass::OpenInternet,
+                _ => NatClass::Cone,
+            },
+        }
+    }
     pub fn ipv6(&self) -> Option<Ipv6Addr> {
         self.ipv6
     }
@@ -177,6 +268,41 @@ impl NatInfo {
             None
         }
     }
+    /// 本地的tcp地址，ipv6在前
+    pub fn local_tcp_endpoints(&self) -> Vec<Endpoint> {
+        self.local_tcp_ipv6addr()
+            .into_iter()
+            .chain(self.local_tcp_ipv4addr())
+            .map(Endpoint::Tcp)
+            .collect()
+    }
+    /// 对端的所有候选路径，本地地址在前，index是本地主端口的下标
+    pub fn candidates(&self, channel_num: usize) -> Vec<PeerPath> {
+        let mut list: Vec<PeerPath> = self
+            .local_tcp_endpoints()
+            .into_iter()
+            .map(|endpoint| PeerPath::candidate(endpoint, 0))
+            .collect();
+        for index in 0..channel_num {
+            for addr in self
+                .local_udp_ipv4addr(index)
+                .into_iter()
+                .chain(self.local_udp_ipv6addr(index))
+            {
+                list.push(PeerPath::candidate(Endpoint::udp(addr), index));
+            }
+        }
+        for (index, port) in self.public_ports.iter().enumerate().take(channel_num) {
+            if *port == 0 {
+                continue;
+            }
+            for ip in &self.public_ips {
+                let addr = SocketAddr::V4(SocketAddrV4::new(*ip, *port));
+                list.push(PeerPath::candidate(Endpoint::udp(addr), index));
+            }
+        }
+        list
+    }
 }
 
 #[derive(Clone)]
@@ -189,6 +315,8 @@ pub struct Punch {
     tcp_socket_sender: AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
     external_route: ExternalRoute,
     nat_test: NatTest,
+    // 端口预测扫描的范围，0表示不扫描
+    port_window: u16,
 }
 
 impl Punch {
@@ -199,6 +327,7 @@ impl Punch {
         tcp_socket_sender: AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
         external_route: ExternalRoute,
         nat_test: NatTest,
+        port_window: u16,
     ) -> Self {
         let mut port_vec: Vec<u16> = (1..65535).collect();
         port_vec.push(65535);
@@ -213,6 +342,7 @@ impl Punch {
             tcp_socket_sender,
             external_route,
             nat_test,
+            port_window,
         }
     }
 }
@@ -250,6 +380,28 @@ impl Punch {
             log::info!("已打洞成功,无需打洞:{:?}", id);
             return Ok(());
         }
+        let strategy = match nat_info.nat_type {
+            NatType::Symmetric => "symmetric",
+            NatType::Cone if self.context.is_cone() => "cone",
+            NatType::Cone => "cone_from_symmetric",
+        };
+        let local_nat_type = self.nat_test.nat_info().nat_type;
+        self.context
+            .punch_history
+            .attempt(id, local_nat_type, nat_info.nat_type);
+        self.context.diary.record(
+            id,
+            DiaryEvent::PunchAttempt {
+                strategy,
+                tcp: punch_tcp && self.is_tcp && nat_info.tcp_port != 0,
+            },
+        );
+        if let Some(addr) = self.context.lan_peers.get(&id) {
+            // 局域网发现并验证过的地址，先尝试
+            if let Err(e) = self.context.send_main_udp(0, buf, addr) {
+                log::debug!("局域网打洞失败 {} {} {:?}", id, addr, e);
+            }
+        }
         nat_info
             .public_ips
             .retain(|ip| self.external_route.route(&ip).is_none());
@@ -265,16 +417,8 @@ impl Punch {
         });
         if punch_tcp && self.is_tcp && nat_info.tcp_port != 0 {
             //向tcp发起连接
-            if let Some(ipv6_addr) = nat_info.local_tcp_ipv6addr() {
-                if self.connect_tcp(buf, ipv6_addr) {
-                    // return Ok(());
-                }
-            }
-            //向tcp发起连接
-            if let Some(ipv4_addr) = nat_info.local_tcp_ipv4addr() {
-                if self.connect_tcp(buf, ipv4_addr) {
-                    // return Ok(());
-                }
+            for endpoint in nat_info.local_tcp_endpoints() {
+                self.connect_tcp(buf, endpoint.addr());
             }
             if nat_info.nat_type == NatType::Cone && nat_info.public_ips.len() == 1 {
                 let addr =
@@ -287,7 +431,9 @@ impl Punch {
         let channel_num = self.context.channel_num();
         for index in 0..channel_num {
             if let Some(ipv4_addr) = nat_info.local_udp_ipv4addr(index) {
-                if !self.nat_test.is_local_address(false, ipv4_addr) {
+                if !self.nat_test.is_local_address(false, ipv4_addr)
+                    && !self.context.icmp_errors.is_unreachable(&ipv4_addr)
+                {
                     let _ = self.context.send_main_udp(index, buf, ipv4_addr);
                 }
             }
@@ -318,7 +464,29 @@ impl Punch {
                 //全局最多发送max_k2个包
                 let max_k2 = rand::thread_rng().gen_range(600..800);
                 let port = nat_info.public_ports.get(0).map(|e| *e).unwrap_or(0);
-                if nat_info.public_port_range < max_k1 * 3 {
+                let strategy =
+                    PortStrategy::new(local_nat_type, nat_info.nat_type, self.port_window);
+                if strategy == PortStrategy::Sweep && port != 0 {
+                    let ports = sweep_ports(port, self.port_window);
+                    let (probes, done) = self.sweep(id, &ports, buf, &nat_info.public_ips)?;
+                    if done {
+                        log::info!(
+                            "端口预测打洞成功,目标:{},端口:{},范围:±{},探测包:{}",
+                            id,
+                            port,
+                            self.port_window,
+                            probes
+                        );
+                        return Ok(());
+                    }
+                    log::info!(
+                        "端口预测打洞未收到回应,目标:{},端口:{},范围:±{},探测包:{}",
+                        id,
+                        port,
+                        self.port_window,
+                        probes
+                    );
+                } else if nat_info.public_port_range < max_k1 * 3 {
                     //端口变化不大时，在预测的范围内随机发送
                     let min_port = if port > nat_info.public_port_range {
                         port - nat_info.public_port_range
@@ -363,6 +531,10 @@ impl Punch {
                             continue;
                         }
                         let addr = SocketAddr::V4(SocketAddrV4::new(*ip, port));
+                        if self.context.icmp_errors.is_unreachable(&addr) {
+                            // 刚收到过不可达，这一轮不再尝试
+                            continue;
+                        }
                         if is_cone {
                             self.context.send_main_udp(index, buf, addr)?;
                         } else {
@@ -381,6 +553,35 @@ impl Punch {
         Ok(())
     }
 
+    /// 成批向端口发送探测包，每批之间检查是否新增了直连路由，返回发送的包数和是否打通
+    fn sweep(
+        &self,
+        id: Ipv4Addr,
+        ports: &[u16],
+        buf: &[u8],
+        ips: &[Ipv4Addr],
+    ) -> io::Result<(usize, bool)> {
+        let p2p_num = self.context.route_table.p2p_num(&id);
+        let mut probes = 0;
+        for chunk in ports.chunks(SWEEP_BURST) {
+            if self.context.route_table.p2p_num(&id) > p2p_num {
+                return Ok((probes, true));
+            }
+            for port in chunk {
+                for pub_ip in ips {
+                    let addr = SocketAddr::V4(SocketAddrV4::new(*pub_ip, *port));
+                    if self.context.icmp_errors.is_unreachable(&addr) {
+                        continue;
+                    }
+                    self.context.send_main_udp(0, buf, addr)?;
+                    probes += 1;
+                }
+            }
+            thread::sleep(SWEEP_PAUSE);
+        }
+        Ok((probes, self.context.route_table.p2p_num(&id) > p2p_num))
+    }
+
     fn punch_symmetric(
         &self,
         ports: &[u16],
@@ -396,6 +597,9 @@ impl Punch {
                     return Ok(index);
                 }
                 let addr = SocketAddr::V4(SocketAddrV4::new(*pub_ip, *port));
+                if self.context.icmp_errors.is_unreachable(&addr) {
+                    continue;
+                }
                 self.context.send_main_udp(0, buf, addr)?;
                 thread::sleep(Duration::from_millis(2));
             }
@@ -403,3 +607,118 @@ impl Punch {
         Ok(ports.len())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::channel::endpoint::{Endpoint, PathState};
+    use crate::channel::punch::{sweep_ports, NatClass, NatInfo, NatType, PortStrategy};
+
+    #[test]
+    fn test_candidates() {
+        let nat_info = NatInfo::new(
+            vec![Ipv4Addr::new(1, 2, 3, 4)],
+            vec![40001, 0],
+            0,
+            Some(Ipv4Addr::new(192, 168, 1, 3)),
+            Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            vec![30001, 30002],
+            30003,
+            NatType::Cone,
+        );
+        let list: Vec<String> = nat_info
+            .candidates(2)
+            .iter()
+            .map(|v| format!("{}#{}", v.endpoint, v.index))
+            .collect();
+        assert_eq!(
+            list,
+            vec![
+                "tcp://[2001:db8::1]:30003#0",
+                "tcp://192.168.1.3:30003#0",
+                "192.168.1.3:30001#0",
+                "[2001:db8::1]:30001#0",
+                "192.168.1.3:30002#1",
+                "[2001:db8::1]:30002#1",
+                "1.2.3.4:40001#0",
+            ]
+        );
+        assert!(nat_info
+            .candidates(1)
+            .iter()
+            .all(|v| v.state == PathState::Candidate && v.is_p2p()));
+        assert_eq!(
+            nat_info.local_tcp_endpoints()[1],
+            Endpoint::Tcp("192.168.1.3:30003".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_sweep_ports() {
+        assert_eq!(sweep_ports(1000, 2), vec![1000, 1001, 999, 1002, 998]);
+        assert_eq!(sweep_ports(1000, 0), vec![1000]);
+        // 不超出端口范围，不包含0
+        assert_eq!(sweep_ports(65534, 2), vec![65534, 65535, 65533, 65532]);
+        assert_eq!(sweep_ports(1, 2), vec![1, 2, 3]);
+        assert_eq!(sweep_ports(100, 64).len(), 129);
+        assert_eq!(
+            PortStrategy::new(NatType::Cone, NatType::Symmetric, 64),
+            PortStrategy::Sweep
+        );
+        assert_eq!(
+            PortStrategy::new(NatType::Cone, NatType::Symmetric, 0),
+            PortStrategy::Exact
+        );
+        assert_eq!(
+            PortStrategy::new(NatType::Symmetric, NatType::Symmetric, 64),
+            PortStrategy::Exact
+        );
+        assert_eq!(
+            PortStrategy::new(NatType::Symmetric, NatType::Cone, 64),
+            PortStrategy::Exact
+        );
+    }
+
+    #[test]
+    fn test_nat_class() {
+        let public = Ipv4Addr::new(1, 2, 3, 4);
+        let lan = Ipv4Addr::new(192, 168, 1, 3);
+        let cases = [
+            (vec![], Some(lan), NatType::Cone, NatClass::Unknown),
+            (vec![public], Some(lan), NatType::Cone, NatClass::Cone),
+            (
+                vec![public],
+                Some(public),
+                NatType::Cone,
+                NatClass::OpenInternet,
+            ),
+            (
+                vec![public],
+                Some(lan),
+                NatType::Symmetric,
+                NatClass::Symmetric,
+            ),
+            // 多个公网ip按对称处理
+            (
+                vec![public, Ipv4Addr::new(1, 2, 3, 5)],
+                Some(public),
+                NatType::Cone,
+                NatClass::Symmetric,
+            ),
+        ];
+        for (public_ips, local_ipv4, nat_type, expect) in cases {
+            let nat_info = NatInfo::new(
+                public_ips,
+                vec![0],
+                0,
+                local_ipv4,
+                None,
+                vec![0],
+                0,
+                nat_type,
+            );
+            assert_eq!(nat_info.nat_class(), expect, "{:?}", nat_info);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::util::metrics::{Histogram, Registry};
+
+/// 双方开始打洞的时间差的分档，单位毫秒
+pub const SKEW_BUCKETS: &[u64] = &[20, 50, 100, 200, 500, 1000];
+/// 打洞时刻至少在这么久之后，给协商消息留出转发的时间
+const MIN_LEAD_MS: u64 = 100;
+/// 在双方延迟之和上再加的余量
+const LEAD_MARGIN_MS: u64 = 30;
+/// 等待不超过这么久，时钟估计出错时也不会拖住打洞线程
+const MAX_WAIT_MS: u64 = 2000;
+/// 延迟超过平滑值两倍再加这么多的样本，单向延迟多半不对称，不用于估计偏移
+const OUTLIER_MS: f64 = 20.0;
+/// 偏移突变超过这么多时认为本地时钟被调整，重新估计
+const CLOCK_JUMP_MS: f64 = 1000.0;
+/// 双方开始时刻相差超过这么多时不是同一轮打洞
+const ROUND_MS: u64 = 10_000;
+/// 记录开始时刻的对端数量上限
+const PEER_LIMIT: usize = 1024;
+
+/// 估计服务器时钟，让打洞双方在同一时刻开始
+///
+/// 服务器在pong中带上发送时的时间，按延迟的一半折算成和本地时钟的偏移，平滑后使用。
+/// 协商消息经过服务器转发时加密，服务器无法填写时刻，由响应方按服务器时钟选择打洞时刻，
+/// 放在回复中，双方各自换算成本地时刻等待。
+/// 打洞包中带上开始时刻，收到对端的打洞包时得到双方实际的时间差
+pub struct PunchSync {
+    clock: Mutex<Clock>,
+    rounds: Mutex<HashMap<Ipv4Addr, Round>>,
+    skew: Histogram,
+}
+
+#[derive(Default)]
+struct Clock {
+    // 服务器时间减去本地时间，毫秒
+    offset: f64,
+    rtt: f64,
+    samples: u32,
+}
+
+/// 一轮打洞双方的开始时刻，服务器时间，0表示还不知道
+#[derive(Copy, Clone, Default)]
+struct Round {
+    own: u64,
+    peer: u64,
+    recorded: bool,
+}
+
+impl Round {
+    /// 双方都有时返回时间差，每轮只返回一次
+    fn skew(&mut self) -> Option<Duration> {
+        if self.recorded || self.own == 0 || self.peer == 0 {
+            return None;
+        }
+        self.recorded = true;
+        Some(Duration::from_millis(self.own.abs_diff(self.peer)))
+    }
+}
+
+impl PunchSync {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            clock: Mutex::new(Clock::default()),
+            rounds: Mutex::new(HashMap::new()),
+            skew: registry.histogram("punch_skew_ms", &[], SKEW_BUCKETS),
+        }
+    }
+    /// 收到带有服务器时间的pong，server_ms为服务器发出pong的时刻
+    pub fn sample(&self, server_ms: u64, rtt: u32) {
+        self.sample_at(server_ms, rtt, crate::handle::now_time())
+    }
+    fn sample_at(&self, server_ms: u64, rtt: u32, local_ms: u64) {
+        if server_ms == 0 {
+            return;
+        }
+        let rtt = rtt as f64;
+        let offset = server_ms as f64 + rtt / 2.0 - local_ms as f64;
+        let mut clock = self.clock.lock();
+        if clock.samples == 0 || (offset - clock.offset).abs() > CLOCK_JUMP_MS {
+            *clock = Clock {
+                offset,
+                rtt,
+                samples: 1,
+            };
+            return;
+        }
+        let outlier = rtt > clock.rtt * 2.0 + OUTLIER_MS;
+        clock.samples = clock.samples.saturating_add(1);
+        // 刚开始时取平均，之后按1/8平滑
+        let weight = 1.0 / clock.samples.min(8) as f64;
+        clock.rtt += (rtt - clock.rtt) * weight;
+        if !outlier {
+            clock.offset += (offset - clock.offset) * weight;
+        }
+    }
+    /// 平滑后到服务器的延迟，没有估计时为0
+    pub fn rtt(&self) -> u32 {
+        let clock = self.clock.lock();
+        if clock.samples == 0 {
+            return 0;
+        }
+        (clock.rtt.round() as u32).max(1)
+    }
+    fn now_server_at(&self, local_ms: u64) -> Option<u64> {
+        let clock = self.clock.lock();
+        if clock.samples == 0 {
+            return None;
+        }
+        Some((local_ms as f64 + clock.offset).max(1.0) as u64)
+    }
+    /// 响应方选择打洞时刻，peer_rtt为发起方到服务器的延迟，双方任意一方没有估计时返回None
+    pub fn plan(&self, peer_rtt: u32) -> Option<u64> {
+        self.plan_at(peer_rtt, crate::handle::now_time())
+    }
+    fn plan_at(&self, peer_rtt: u32, local_ms: u64) -> Option<u64> {
+        let rtt = self.rtt();
+        if peer_rtt == 0 || rtt == 0 {
+            return None;
+        }
+        let now = self.now_server_at(local_ms)?;
+        // 回复经过服务器转发，单向延迟不会超过双方的往返延迟之和
+        let lead = (rtt as u64 + peer_rtt as u64 + LEAD_MARGIN_MS).clamp(MIN_LEAD_MS, MAX_WAIT_MS);
+        Some(now + lead)
+    }
+    /// 距离打洞时刻还要等待多久，已经过了或者远得不合理时立即开始
+    pub fn wait(&self, fire_at: u64) -> Duration {
+        self.wait_at(fire_at, crate::handle::now_time())
+    }
+    fn wait_at(&self, fire_at: u64, local_ms: u64) -> Duration {
+        let now = match self.now_server_at(local_ms) {
+            Some(now) => now,
+            None => return Duration::ZERO,
+        };
+        match fire_at.checked_sub(now) {
+            Some(wait) if wait <= MAX_WAIT_MS => Duration::from_millis(wait),
+            _ => Duration::ZERO,
+        }
+    }
+    /// 开始向对端打洞，返回服务器时间表示的开始时刻，放进打洞包里，没有估计时为0。
+    /// 对端的打洞包先到时同时返回双方的时间差
+    pub fn fire(&self, peer: Ipv4Addr) -> (u64, Option<Duration>) {
+        self.fire_at(peer, crate::handle::now_time())
+    }
+    fn fire_at(&self, peer: Ipv4Addr, local_ms: u64) -> (u64, Option<Duration>) {
+        let now = match self.now_server_at(local_ms) {
+            Some(now) => now,
+            None => return (0, None),
+        };
+        let mut rounds = self.rounds.lock();
+        if let Some(round) = rounds.get_mut(&peer) {
+            if round.own == 0 && !round.recorded && round.peer.abs_diff(now) < ROUND_MS {
+                round.own = now;
+                let skew = round.skew();
+                self.observe(skew);
+                return (now, skew);
+            }
+        } else if rounds.len() >= PEER_LIMIT {
+            return (now, None);
+        }
+        rounds.insert(
+            peer,
+            Round {
+                own: now,
+                ..Default::default()
+            },
+        );
+        (now, None)
+    }
+    /// 收到对端打洞包中的开始时刻，自己也已经开始时返回双方的时间差
+    pub fn peer_fired(&self, peer: Ipv4Addr, fired: u64) -> Option<Duration> {
+        if fired == 0 {
+            return None;
+        }
+        let mut rounds = self.rounds.lock();
+        if let Some(round) = rounds.get_mut(&peer) {
+            if round.peer == fired {
+                // 同一轮的其他打洞包
+                return None;
+            }
+            if round.peer == 0 && !round.recorded && round.own.abs_diff(fired) < ROUND_MS {
+                round.peer = fired;
+                let skew = round.skew();
+                self.observe(skew);
+                return skew;
+            }
+        } else if rounds.len() >= PEER_LIMIT {
+            return None;
+        }
+        rounds.insert(
+            peer,
+            Round {
+                peer: fired,
+                ..Default::default()
+            },
+        );
+        None
+    }
+    fn observe(&self, skew: Option<Duration>) {
+        if let Some(skew) = skew {
+            self.skew.observe(skew.as_millis() as u64);
+        }
+    }
+    /// 清除离线对端的记录
+    pub fn retain_peers(&self, peers: &[Ipv4Addr]) {
+        self.rounds.lock().retain(|ip, _| peers.contains(ip));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::util::metrics::Registry;
+
+    use super::{PunchSync, MAX_WAIT_MS};
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    #[test]
+    fn test_clock() {
+        let sync = PunchSync::new(&Registry::new());
+        assert_eq!(sync.rtt(), 0);
+        assert_eq!(sync.plan_at(40, 1000), None);
+        assert_eq!(sync.wait_at(5000, 1000), Duration::ZERO);
+        assert_eq!(sync.fire_at(PEER, 1000), (0, None));
+        // 服务器时钟比本地快10秒
+        sync.sample_at(11_000, 40, 1_020);
+        assert_eq!(sync.rtt(), 40);
+        assert_eq!(sync.now_server_at(2_000), Some(12_000));
+        // 排队造成的大延迟不影响偏移
+        sync.sample_at(21_000, 400, 11_020);
+        assert_eq!(sync.now_server_at(2_000), Some(12_000));
+        // 对端还不支持时不协调
+        assert_eq!(sync.plan_at(0, 2_000), None);
+        let fire_at = sync.plan_at(60, 2_000).unwrap();
+        assert!(fire_at >= 12_100, "{}", fire_at);
+        assert_eq!(
+            sync.wait_at(fire_at, 2_000),
+            Duration::from_millis(fire_at - 12_000)
+        );
+        // 已经过了或者太远都立即开始
+        assert_eq!(sync.wait_at(11_000, 2_000), Duration::ZERO);
+        assert_eq!(
+            sync.wait_at(12_000 + MAX_WAIT_MS + 1, 2_000),
+            Duration::ZERO
+        );
+        // 本地时钟被调整
+        sync.sample_at(31_000, 40, 100_020);
+        assert_eq!(sync.now_server_at(100_020), Some(31_020));
+    }
+
+    #[test]
+    fn test_skew() {
+        let registry = Registry::new();
+        let sync = PunchSync::new(&registry);
+        sync.sample_at(10_000, 20, 10_010);
+        assert_eq!(sync.fire_at(PEER, 20_000), (20_000, None));
+        assert_eq!(sync.peer_fired(PEER, 0), None);
+        assert_eq!(
+            sync.peer_fired(PEER, 20_030),
+            Some(Duration::from_millis(30))
+        );
+        // 同一轮的其他包不重复记录
+        assert_eq!(sync.peer_fired(PEER, 20_030), None);
+        // 对端的包先到
+        assert_eq!(sync.peer_fired(PEER, 29_950), None);
+        assert_eq!(
+            sync.fire_at(PEER, 30_000),
+            (30_000, Some(Duration::from_millis(50)))
+        );
+        // 太久之前的记录不是同一轮
+        assert_eq!(sync.peer_fired(PEER, 90_000), None);
+        assert_eq!(sync.fire_at(PEER, 200_000), (200_000, None));
+        let snapshot = registry
+            .histogram("punch_skew_ms", &[], super::SKEW_BUCKETS)
+            .snapshot();
+        assert_eq!((snapshot.count, snapshot.sum), (2, 80));
+        sync.retain_peers(&[]);
+        assert_eq!(sync.peer_fired(PEER, 200_010), None);
+    }
+
+    /// 一端的时钟和到服务器的延迟
+    struct Client {
+        sync: PunchSync,
+        // 本地时钟减去真实时间
+        clock: i64,
+        up: u64,
+        down: u64,
+    }
+
+    impl Client {
+        fn new(clock: i64, up: u64, down: u64) -> Self {
+            Self {
+                sync: PunchSync::new(&Registry::new()),
+                clock,
+                up,
+                down,
+            }
+        }
+        fn local(&self, t: u64) -> u64 {
+            (t as i64 + self.clock) as u64
+        }
+        fn up(&self, rng: &mut StdRng) -> u64 {
+            self.up + jitter(rng)
+        }
+        fn down(&self, rng: &mut StdRng) -> u64 {
+            self.down + jitter(rng)
+        }
+        /// 心跳，服务器时钟就是真实时间
+        fn heartbeat(&self, t: u64, rng: &mut StdRng) {
+            let server = t + self.up(rng);
+            let recv = server + self.down(rng);
+            self.sync
+                .sample_at(server, (recv - t) as u32, self.local(recv));
+        }
+    }
+
+    /// 抖动，偶尔有排队造成的长延迟
+    fn jitter(rng: &mut StdRng) -> u64 {
+        if rng.gen_ratio(1, 20) {
+            rng.gen_range(100..300)
+        } else {
+            rng.gen_range(0..15)
+        }
+    }
+
+    /// 双方的时钟不同，上下行延迟不对称，经过服务器协商后开始打洞的时间差
+    #[test]
+    fn test_chaos_skew() {
+        let mut rng = StdRng::seed_from_u64(265);
+        let a = Client::new(37_000, 15, 60);
+        let b = Client::new(-12_345, 80, 20);
+        let mut t = 1_000_000;
+        for _ in 0..20 {
+            a.heartbeat(t, &mut rng);
+            b.heartbeat(t + 3, &mut rng);
+            t += 5_000;
+        }
+        let mut coordinated = Vec::new();
+        let mut baseline = Vec::new();
+        let mut errors = Vec::new();
+        for i in 0..300u32 {
+            let peer_a = Ipv4Addr::from(0x0A1A_0000 + i);
+            let peer_b = Ipv4Addr::from(0x0A1B_0000 + i);
+            // a发起协商，经过服务器到达b
+            let at_b = t + a.up(&mut rng) + b.down(&mut rng);
+            let fire_at = b.sync.plan_at(a.sync.rtt(), b.local(at_b)).unwrap();
+            let fired_b = at_b + b.sync.wait_at(fire_at, b.local(at_b)).as_millis() as u64;
+            // b的回复经过服务器到达a
+            let at_a = at_b + b.up(&mut rng) + a.down(&mut rng);
+            let fired_a = at_a + a.sync.wait_at(fire_at, a.local(at_a)).as_millis() as u64;
+            let skew = fired_a.abs_diff(fired_b);
+            coordinated.push(skew);
+            baseline.push(at_a.abs_diff(at_b));
+            // 打洞包带上各自的开始时刻，双方测得相同的时间差
+            let (stamp_a, _) = a.sync.fire_at(peer_b, a.local(fired_a));
+            let (stamp_b, _) = b.sync.fire_at(peer_a, b.local(fired_b));
+            let measured = a.sync.peer_fired(peer_b, stamp_b).unwrap();
+            assert_eq!(b.sync.peer_fired(peer_a, stamp_a), Some(measured));
+            errors.push((measured.as_millis() as u64).abs_diff(skew));
+            a.heartbeat(t + 2_000, &mut rng);
+            b.heartbeat(t + 2_500, &mut rng);
+            t += 5_000;
+        }
+        coordinated.sort();
+        baseline.sort();
+        errors.sort();
+        let p50 = coordinated[coordinated.len() / 2];
+        let p90 = coordinated[coordinated.len() * 9 / 10];
+        assert!(p50 < 80, "p50 {}", p50);
+        // 回复遇到排队的长延迟时晚于约定的时刻到达，只能立即开始
+        assert!(p90 < 100, "p90 {}", p90);
+        // 不协调时相差一次服务器转发的单向延迟
+        assert!(baseline[baseline.len() / 2] > p50 * 2, "{:?}", baseline);
+        // 测得的时间差只受双方偏移估计误差的影响
+        assert!(errors[errors.len() * 9 / 10] < 100, "{:?}", errors);
+    }
+}
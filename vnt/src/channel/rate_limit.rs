@@ -0,0 +1,81 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// 桶容量为0.1秒的流量，速率很低时至少能放下几个完整的包
+const MIN_BURST: u64 = 16 * 1024;
+
+/// 发往虚拟网络的流量的令牌桶限速，速率为0表示不限制
+///
+/// 速率可以在运行中随时修改，例如多个进程共享带宽时按租约调整
+pub struct RateLimit {
+    // 字节/秒
+    rate: AtomicU64,
+    // 剩余令牌，上次补充的时间
+    state: Mutex<(u64, Instant)>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self {
+            rate: AtomicU64::new(0),
+            state: Mutex::new((MIN_BURST, Instant::now())),
+        }
+    }
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        self.rate.store(bytes_per_sec, Ordering::Relaxed);
+    }
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+    /// 取出len字节的令牌，不够时返回false
+    #[inline]
+    pub fn take(&self, len: usize) -> bool {
+        if self.rate() == 0 {
+            return true;
+        }
+        self.take_at(len, Instant::now())
+    }
+    pub fn take_at(&self, len: usize, now: Instant) -> bool {
+        let rate = self.rate();
+        if rate == 0 {
+            return true;
+        }
+        let burst = (rate / 10).max(MIN_BURST);
+        let mut guard = self.state.lock();
+        let (tokens, last) = &mut *guard;
+        let elapsed = now.saturating_duration_since(*last);
+        let refill = (elapsed.as_micros() as u64).saturating_mul(rate) / 1_000_000;
+        if refill > 0 {
+            *tokens = tokens.saturating_add(refill).min(burst);
+            *last = now;
+        }
+        if *tokens < len as u64 {
+            return false;
+        }
+        *tokens -= len as u64;
+        true
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    use std::time::Duration;
+    let limit = RateLimit::new();
+    let start = Instant::now();
+    // 不限速
+    for _ in 0..1000 {
+        assert!(limit.take_at(1500, start));
+    }
+    limit.set_rate(1_000_000);
+    let mut sent = 0;
+    for ms in 0..2000 {
+        let now = start + Duration::from_millis(ms);
+        while limit.take_at(1000, now) {
+            sent += 1000;
+        }
+    }
+    // 两秒加上一个桶的突发
+    assert!(sent <= 2_000_000 + 100_000, "{}", sent);
+    assert!(sent >= 1_900_000, "{}", sent);
+}
@@ -0,0 +1,28 @@
+use crate::util::metrics::{Counter, Registry};
+
+/// 经过服务器中继的流量，只统计总量，不区分对端
+pub struct RelayStats {
+    tx: Counter,
+    rx: Counter,
+}
+
+impl RelayStats {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            tx: registry.counter("relay_bytes", &[("direction", "tx")]),
+            rx: registry.counter("relay_bytes", &[("direction", "rx")]),
+        }
+    }
+    pub fn add_tx(&self, len: usize) {
+        self.tx.add(len as u64);
+    }
+    pub fn add_rx(&self, len: usize) {
+        self.rx.add(len as u64);
+    }
+    pub fn tx(&self) -> u64 {
+        self.tx.get()
+    }
+    pub fn rx(&self) -> u64 {
+        self.rx.get()
+    }
+}
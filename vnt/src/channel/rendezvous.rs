@@ -0,0 +1,227 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use parking_lot::RwLock;
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 最多维持的备用服务器数
+pub const MAX_STANDBY: usize = 2;
+const NO_RTT: u32 = u32::MAX;
+/// 对端推荐的服务器延迟不超过主服务器的5/4加上这个值时，通过它协商
+const CLOSE_SLACK_MS: u32 = 5;
+
+/// 一个备用服务器的状态
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StandbyServer {
+    pub addr: SocketAddr,
+    /// 平滑后的延迟，毫秒
+    pub rtt: Option<u32>,
+    /// 已经以相同的虚拟ip注册，可以通过它转发打洞协商
+    pub registered: bool,
+}
+
+/// 打洞协商使用的服务器
+///
+/// 心跳中上报到每个服务器的延迟，服务端据此在设备列表中给出每个设备最近的服务器。
+/// 和对端协商打洞时，如果对端最近的服务器也离自己足够近，并且在那里有备用注册，就通过它协商，
+/// 否则使用主服务器。备用服务器只用于协商，不转发数据
+pub struct Rendezvous {
+    standby: RwLock<Vec<StandbyServer>>,
+    primary_rtt: AtomicU32,
+    via_primary: Counter,
+    via_standby: Counter,
+}
+
+impl Rendezvous {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            standby: RwLock::new(Vec::new()),
+            primary_rtt: AtomicU32::new(NO_RTT),
+            via_primary: registry.counter("punch_coordination", &[("via", "primary")]),
+            via_standby: registry.counter("punch_coordination", &[("via", "standby")]),
+        }
+    }
+    /// 超过上限的部分忽略
+    pub fn set_standby(&self, list: &[SocketAddr]) {
+        *self.standby.write() = list
+            .iter()
+            .take(MAX_STANDBY)
+            .map(|addr| StandbyServer {
+                addr: *addr,
+                rtt: None,
+                registered: false,
+            })
+            .collect();
+    }
+    pub fn standby(&self) -> Vec<StandbyServer> {
+        self.standby.read().clone()
+    }
+    pub fn has_standby(&self) -> bool {
+        !self.standby.read().is_empty()
+    }
+    pub fn is_standby(&self, addr: &SocketAddr) -> bool {
+        self.standby.read().iter().any(|v| v.addr == *addr)
+    }
+    pub fn set_registered(&self, addr: &SocketAddr, registered: bool) {
+        if let Some(v) = self.standby.write().iter_mut().find(|v| v.addr == *addr) {
+            v.registered = registered;
+            if !registered {
+                v.rtt = None;
+            }
+        }
+    }
+    /// 记录到服务器的延迟，不是备用服务器的算作主服务器
+    pub fn record_rtt(&self, addr: &SocketAddr, rtt: u32) {
+        if let Some(v) = self.standby.write().iter_mut().find(|v| v.addr == *addr) {
+            v.rtt = Some(smooth(v.rtt, rtt));
+            return;
+        }
+        let old = self.primary_rtt.load(Ordering::Relaxed);
+        let old = if old == NO_RTT { None } else { Some(old) };
+        self.primary_rtt.store(smooth(old, rtt), Ordering::Relaxed);
+    }
+    pub fn primary_rtt(&self) -> Option<u32> {
+        match self.primary_rtt.load(Ordering::Relaxed) {
+            NO_RTT => None,
+            rtt => Some(rtt),
+        }
+    }
+    /// 心跳中上报的延迟，主服务器在前
+    pub fn report(&self, primary: SocketAddr) -> Vec<(SocketAddr, u32)> {
+        let mut list = Vec::with_capacity(MAX_STANDBY + 1);
+        if let Some(rtt) = self.primary_rtt() {
+            list.push((primary, rtt));
+        }
+        for v in self.standby.read().iter() {
+            if let Some(rtt) = v.rtt {
+                list.push((v.addr, rtt));
+            }
+        }
+        list
+    }
+    /// 和对端协商打洞使用的服务器，hint为设备列表中对端最近的服务器
+    pub fn choose(&self, primary: SocketAddr, hint: Option<SocketAddr>) -> SocketAddr {
+        let addr = choose(primary, self.primary_rtt(), &self.standby.read(), hint);
+        if addr == primary {
+            self.via_primary.inc();
+        } else {
+            self.via_standby.inc();
+        }
+        addr
+    }
+}
+
+fn smooth(old: Option<u32>, rtt: u32) -> u32 {
+    match old {
+        Some(old) => ((old as u64 * 3 + rtt as u64) / 4) as u32,
+        None => rtt,
+    }
+}
+
+/// 对端最近的服务器有备用注册，并且自己到它的延迟不比主服务器明显更高时使用它
+fn choose(
+    primary: SocketAddr,
+    primary_rtt: Option<u32>,
+    standby: &[StandbyServer],
+    hint: Option<SocketAddr>,
+) -> SocketAddr {
+    let hint = match hint {
+        Some(hint) if hint != primary => hint,
+        _ => return primary,
+    };
+    let server = match standby.iter().find(|v| v.addr == hint && v.registered) {
+        Some(server) => server,
+        None => return primary,
+    };
+    match (server.rtt, primary_rtt) {
+        (Some(rtt), Some(primary_rtt))
+            if rtt as u64 * 4 <= primary_rtt as u64 * 5 + CLOSE_SLACK_MS as u64 * 4 =>
+        {
+            server.addr
+        }
+        // 没有测到主服务器的延迟时，只要备用服务器是通的就可以
+        (Some(_), None) => server.addr,
+        _ => primary,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::channel::rendezvous::{choose, Rendezvous, StandbyServer};
+    use crate::util::metrics::Registry;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_choose() {
+        let primary = addr("1.1.1.1:29872");
+        let near = addr("2.2.2.2:29872");
+        let standby = [StandbyServer {
+            addr: near,
+            rtt: Some(12),
+            registered: true,
+        }];
+        assert_eq!(choose(primary, Some(150), &standby, None), primary);
+        assert_eq!(choose(primary, Some(150), &standby, Some(primary)), primary);
+        assert_eq!(choose(primary, Some(150), &standby, Some(near)), near);
+        // 对端最近的服务器离自己太远
+        assert_eq!(choose(primary, Some(5), &standby, Some(near)), primary);
+        // 5ms以内的差距可以接受
+        assert_eq!(choose(primary, Some(10), &standby, Some(near)), near);
+        // 没有备用注册的服务器不能转发协商
+        let unregistered = [StandbyServer {
+            registered: false,
+            ..standby[0]
+        }];
+        assert_eq!(
+            choose(primary, Some(150), &unregistered, Some(near)),
+            primary
+        );
+        assert_eq!(
+            choose(primary, Some(150), &standby, Some(addr("3.3.3.3:29872"))),
+            primary
+        );
+    }
+
+    /// 模拟两个同城的对端和一个远端的主服务器，比较协商一次的往返时间
+    #[test]
+    fn test_coordination_latency() {
+        let registry = Registry::new();
+        let primary = addr("1.1.1.1:29872");
+        let near = addr("2.2.2.2:29872");
+        let far = addr("3.3.3.3:29872");
+        // 到各个服务器的往返延迟
+        let rtt = |server: SocketAddr| match server {
+            s if s == primary => 160,
+            s if s == near => 10,
+            _ => 90,
+        };
+        let a = Rendezvous::new(&registry);
+        a.set_standby(&[near, far, addr("4.4.4.4:29872")]);
+        assert_eq!(a.standby().len(), 2);
+        for server in [primary, near, far] {
+            a.record_rtt(&server, rtt(server));
+        }
+        a.set_registered(&near, true);
+        a.set_registered(&far, true);
+        assert_eq!(
+            a.report(primary),
+            vec![(primary, 160), (near, 10), (far, 90)]
+        );
+        // 对端B同样离near最近，协商请求经服务器转发到B再把回应转发回来
+        let via = a.choose(primary, Some(near));
+        assert_eq!(via, near);
+        let before = rtt(primary) * 2;
+        let after = rtt(via) * 2;
+        assert!(after * 10 < before, "{} {}", after, before);
+        // 备用注册失效后回到主服务器
+        a.set_registered(&near, false);
+        assert_eq!(a.choose(primary, Some(near)), primary);
+        assert_eq!(a.report(primary), vec![(primary, 160), (far, 90)]);
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::RouteKey;
+use crate::util::metrics::{Counter, Registry};
+use crate::util::serial::Serial;
+
+/// 切换路径后发送方给数据包带上序号的时间
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(200);
+/// 每个对端最多暂存的包数
+pub const DEFAULT_MAX_PACKETS: usize = 8;
+/// 暂存的包最多等待的时间
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(10);
+/// 比期望序号落后太多时认为对端开始了新的切换
+const RESYNC: i64 = 64;
+const PEER_LIMIT: usize = 1024;
+
+struct SendState {
+    // None表示上一个包经过服务器中继
+    last: Option<RouteKey>,
+    until: Option<Instant>,
+    seq: u16,
+    used: Instant,
+}
+
+struct RecvState {
+    next: u16,
+    last: Instant,
+    // 序号，到达时间，数据
+    held: Vec<(u16, Instant, Vec<u8>)>,
+}
+
+/// 路径切换期间的接收端重排
+///
+/// 发送方发现到某个对端的路径变化后，在DEFAULT_WINDOW内给直连路径上的ip数据包带上序号(扩展头)。
+/// 接收方只在收到带序号的包时才可能暂存：乱序的包最多暂存DEFAULT_MAX_PACKETS个、DEFAULT_MAX_DELAY，
+/// 按序号写入网卡，超出限制立即全部写入。不带序号的包从不等待。
+/// 中继路径不使用扩展头，经过服务器的包直接写入。两端都开启才生效
+pub struct Reorder {
+    enabled: AtomicBool,
+    send: Mutex<HashMap<Ipv4Addr, SendState>>,
+    recv: Mutex<HashMap<Ipv4Addr, RecvState>>,
+    // 所有对端暂存的包数，为0时跳过加锁
+    held: AtomicUsize,
+    // 暂存过的包
+    held_total: Counter,
+    // 确实调整了顺序的包
+    reordered: Counter,
+    // 等待超时或者超出数量而跳过了缺失序号
+    flushed: Counter,
+}
+
+impl Reorder {
+    pub fn new(metrics: &Registry) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            send: Mutex::new(HashMap::new()),
+            recv: Mutex::new(HashMap::new()),
+            held: AtomicUsize::new(0),
+            held_total: metrics.counter("reorder_held", &[]),
+            reordered: metrics.counter("reorder_reordered", &[]),
+            flushed: metrics.counter("reorder_flushed", &[]),
+        }
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    /// 经过服务器中继发出
+    pub fn relayed(&self, peer: Ipv4Addr) {
+        if self.is_enabled() {
+            self.switch(peer, None, Instant::now());
+        }
+    }
+    /// 通过直连路径发出，处于切换窗口内时返回序号
+    pub fn outbound(&self, peer: Ipv4Addr, route_key: RouteKey) -> Option<u16> {
+        if !self.is_enabled() {
+            return None;
+        }
+        self.switch(peer, Some(route_key), Instant::now())
+    }
+    fn switch(&self, peer: Ipv4Addr, route: Option<RouteKey>, now: Instant) -> Option<u16> {
+        let mut send = self.send.lock();
+        if send.len() >= PEER_LIMIT && !send.contains_key(&peer) {
+            send.retain(|_, v| now.duration_since(v.used) < DEFAULT_WINDOW * 100);
+            if send.len() >= PEER_LIMIT {
+                return None;
+            }
+        }
+        let state = send.entry(peer).or_insert(SendState {
+            last: route,
+            until: None,
+            seq: 0,
+            used: now,
+        });
+        state.used = now;
+        let active = state.until.map_or(false, |v| v > now);
+        if state.last != route {
+            state.last = route;
+            if !active {
+                // 新的切换，序号从0开始
+                state.seq = 0;
+            }
+            state.until = Some(now + DEFAULT_WINDOW);
+        } else if !active {
+            state.until = None;
+            return None;
+        }
+        route?;
+        let seq = state.seq;
+        state.seq = state.seq.wrapping_add(1);
+        Some(seq)
+    }
+    /// 收到发给网卡的包，seq为扩展头中的序号，relayed表示经过服务器中继
+    pub fn inbound<W: FnMut(&[u8])>(
+        &self,
+        peer: Ipv4Addr,
+        seq: Option<u16>,
+        relayed: bool,
+        data: &[u8],
+        write: W,
+    ) {
+        self.inbound_at(peer, seq, relayed, data, Instant::now(), write)
+    }
+    pub fn inbound_at<W: FnMut(&[u8])>(
+        &self,
+        peer: Ipv4Addr,
+        seq: Option<u16>,
+        relayed: bool,
+        data: &[u8],
+        now: Instant,
+        mut write: W,
+    ) {
+        let seq = match seq {
+            Some(seq) if self.is_enabled() => seq,
+            _ => {
+                if !relayed && self.held.load(Ordering::Relaxed) > 0 {
+                    // 直连路径上不带序号，说明切换窗口已经结束
+                    if let Some(state) = self.recv.lock().remove(&peer) {
+                        self.release(state.next, state.held, &mut write);
+                    }
+                }
+                write(data);
+                return;
+            }
+        };
+        let mut recv = self.recv.lock();
+        if recv.len() >= PEER_LIMIT && !recv.contains_key(&peer) {
+            drop(recv);
+            write(data);
+            return;
+        }
+        let state = recv.entry(peer).or_insert(RecvState {
+            next: 0,
+            last: now,
+            held: Vec::new(),
+        });
+        if now.duration_since(state.last) >= DEFAULT_WINDOW {
+            // 上一次切换早已结束
+            let held = std::mem::take(&mut state.held);
+            self.release(state.next, held, &mut write);
+            state.next = 0;
+        }
+        state.last = now;
+        // 相差正好一半时无法判断先后，当作落后很多重新同步
+        let distance = seq.serial_diff(state.next).unwrap_or(i64::MIN);
+        if distance < 0 {
+            if distance < -RESYNC {
+                let held = std::mem::take(&mut state.held);
+                self.release(state.next, held, &mut write);
+                state.next = seq.wrapping_add(1);
+            }
+            // 迟到的包无法再调整，直接写入
+            write(data);
+            return;
+        }
+        if distance > 0 {
+            self.held_total.inc();
+            self.held.fetch_add(1, Ordering::Relaxed);
+            state.held.push((seq, now, data.to_vec()));
+            if state.held.len() > DEFAULT_MAX_PACKETS {
+                self.flushed.inc();
+                let held = std::mem::take(&mut state.held);
+                state.next = self.release(state.next, held, &mut write);
+            }
+            return;
+        }
+        write(data);
+        state.next = seq.wrapping_add(1);
+        // 缺失的包到了，依次写入后面已经到达的包
+        while let Some(pos) = state.held.iter().position(|(v, _, _)| *v == state.next) {
+            let (_, _, data) = state.held.swap_remove(pos);
+            self.held.fetch_sub(1, Ordering::Relaxed);
+            self.reordered.inc();
+            write(&data);
+            state.next = state.next.wrapping_add(1);
+        }
+    }
+    /// 定时调用，写入等待超时的包
+    pub fn expire<W: FnMut(&[u8])>(&self, now: Instant, mut write: W) {
+        if self.held.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut recv = self.recv.lock();
+        for state in recv.values_mut() {
+            if state
+                .held
+                .iter()
+                .any(|(_, time, _)| now.duration_since(*time) >= DEFAULT_MAX_DELAY)
+            {
+                self.flushed.inc();
+                let held = std::mem::take(&mut state.held);
+                state.next = self.release(state.next, held, &mut write);
+            }
+        }
+        recv.retain(|_, v| !v.held.is_empty() || now.duration_since(v.last) < DEFAULT_WINDOW);
+    }
+    /// 按序号写入所有暂存的包，返回下一个期望的序号
+    fn release<W: FnMut(&[u8])>(
+        &self,
+        next: u16,
+        mut held: Vec<(u16, Instant, Vec<u8>)>,
+        write: &mut W,
+    ) -> u16 {
+        // 暂存的包序号都在next之后，按和next的距离排序，序号回绕时也正确
+        held.sort_by_key(|(v, _, _)| v.wrapping_sub(next));
+        self.held.fetch_sub(held.len(), Ordering::Relaxed);
+        let mut next = next;
+        for (seq, _, data) in held {
+            self.reordered.inc();
+            write(&data);
+            next = seq.wrapping_add(1);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use super::{Reorder, DEFAULT_MAX_DELAY, DEFAULT_MAX_PACKETS};
+    use crate::channel::RouteKey;
+    use crate::util::metrics::Registry;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn key(port: u16) -> RouteKey {
+        RouteKey::new(false, 0, SocketAddr::from(([192, 168, 1, 3], port)))
+    }
+
+    /// 模拟网卡，记录写入的序号和时间
+    struct Tun {
+        written: Vec<(u16, Instant)>,
+    }
+
+    #[test]
+    fn test_sender_window() {
+        let reorder = Reorder::new(&Registry::new());
+        reorder.set_enabled(true);
+        assert_eq!(reorder.outbound(PEER, key(1)), None);
+        assert_eq!(reorder.outbound(PEER, key(1)), None);
+        // 中继切换到直连
+        reorder.relayed(PEER);
+        assert_eq!(reorder.outbound(PEER, key(1)), Some(0));
+        assert_eq!(reorder.outbound(PEER, key(1)), Some(1));
+        // 窗口内再次切换，序号继续
+        assert_eq!(reorder.outbound(PEER, key(2)), Some(2));
+        reorder.set_enabled(false);
+        assert_eq!(reorder.outbound(PEER, key(1)), None);
+    }
+
+    #[test]
+    fn test_two_paths() {
+        let reorder = Reorder::new(&Registry::new());
+        reorder.set_enabled(true);
+        let start = Instant::now();
+        let mut tun = Tun {
+            written: Vec::new(),
+        };
+        // 两条路径交替发送，慢路径延迟3ms，快路径1ms
+        let mut arrivals = Vec::new();
+        for seq in 0u16..200 {
+            let sent = start + Duration::from_micros(seq as u64 * 500);
+            let delay = if seq % 2 == 0 { 3000 } else { 1000 };
+            arrivals.push((sent + Duration::from_micros(delay), seq));
+        }
+        arrivals.sort();
+        for (now, seq) in &arrivals {
+            reorder.expire(*now, |data| {
+                tun.written
+                    .push((u16::from_be_bytes([data[0], data[1]]), *now))
+            });
+            reorder.inbound_at(PEER, Some(*seq), false, &seq.to_be_bytes(), *now, |data| {
+                tun.written
+                    .push((u16::from_be_bytes([data[0], data[1]]), *now))
+            });
+        }
+        let seqs: Vec<u16> = tun.written.iter().map(|(v, _)| *v).collect();
+        assert_eq!(seqs, (0..200).collect::<Vec<u16>>());
+        // 只有暂存的包会晚写入，且不超过等待上限
+        for (seq, written) in &tun.written {
+            let arrived = arrivals.iter().find(|(_, v)| v == seq).unwrap().0;
+            assert!(written.duration_since(arrived) <= DEFAULT_MAX_DELAY);
+        }
+        assert!(reorder.reordered.get() > 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let reorder = Reorder::new(&Registry::new());
+        reorder.set_enabled(true);
+        let start = Instant::now();
+        let mut written = Vec::new();
+        // 序号0丢失
+        for seq in 1..=DEFAULT_MAX_PACKETS as u16 {
+            reorder.inbound_at(PEER, Some(seq), false, &seq.to_be_bytes(), start, |v| {
+                written.push(v.to_vec())
+            });
+        }
+        assert!(written.is_empty());
+        // 超过数量上限立即全部写入
+        let seq = DEFAULT_MAX_PACKETS as u16 + 1;
+        reorder.inbound_at(PEER, Some(seq), false, &seq.to_be_bytes(), start, |v| {
+            written.push(v.to_vec())
+        });
+        assert_eq!(written.len(), DEFAULT_MAX_PACKETS + 1);
+        assert_eq!(reorder.flushed.get(), 1);
+
+        // 等待超时
+        written.clear();
+        reorder.inbound_at(PEER, Some(20), false, &[20], start, |v| {
+            written.push(v.to_vec())
+        });
+        reorder.expire(start + DEFAULT_MAX_DELAY / 2, |v| written.push(v.to_vec()));
+        assert!(written.is_empty());
+        reorder.expire(start + DEFAULT_MAX_DELAY, |v| written.push(v.to_vec()));
+        assert_eq!(written, vec![vec![20]]);
+
+        // 不带序号的包从不等待，直连路径上出现时写入暂存的包
+        written.clear();
+        reorder.inbound_at(PEER, Some(30), false, &[30], start, |v| {
+            written.push(v.to_vec())
+        });
+        reorder.inbound_at(PEER, None, true, &[1], start, |v| written.push(v.to_vec()));
+        assert_eq!(written, vec![vec![1]]);
+        reorder.inbound_at(PEER, None, false, &[2], start, |v| written.push(v.to_vec()));
+        assert_eq!(written, vec![vec![1], vec![30], vec![2]]);
+        assert_eq!(reorder.held.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 不指定时长时直连许可的有效期
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(3600);
+/// 直连许可的最长有效期
+pub const MAX_DURATION: Duration = Duration::from_secs(86400);
+/// 没有被批准的请求保留这么久后丢弃，请求方不会收到任何回复
+pub const PENDING_TTL: Duration = Duration::from_secs(300);
+/// 等待批准的请求数量上限，来源只能是同一个网络中的设备
+const PENDING_LIMIT: usize = 32;
+/// 同一个对端两次请求的最小间隔
+const INTENT_INTERVAL: Duration = Duration::from_secs(10);
+/// 每分钟最多接受的请求数
+const INTENT_PER_MINUTE: u32 = 20;
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// 收到连接请求后的处理
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Intent {
+    /// 没有开启严格入站，直接同意
+    Approve,
+    /// 等待本地批准，'requests'中显示的编号
+    Queued(u32),
+    /// 同一个对端的请求已经在等待批准
+    Duplicate,
+    /// 超过速率或数量限制，丢弃
+    Limited,
+}
+
+/// 等待批准的连接请求
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingRequest {
+    pub id: u32,
+    pub peer: Ipv4Addr,
+    /// 请求方希望的时长
+    pub duration: Duration,
+    /// 收到后经过的时间
+    pub age: Duration,
+}
+
+/// 生效中的直连许可
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Allowance {
+    pub peer: Ipv4Addr,
+    pub remaining: Duration,
+}
+
+struct Pending {
+    id: u32,
+    peer: Ipv4Addr,
+    nonce: u32,
+    duration: Duration,
+    received: Instant,
+}
+
+struct State {
+    // 对端和许可的到期时间
+    allowances: HashMap<Ipv4Addr, Instant>,
+    pending: Vec<Pending>,
+    // 对端最近一次请求的时间
+    last_intent: HashMap<Ipv4Addr, Instant>,
+    window: Option<Instant>,
+    count: u32,
+    next_id: u32,
+    // 本机发出的请求，批准时核对nonce
+    outgoing: HashMap<Ipv4Addr, (u32, Instant)>,
+}
+
+/// 严格入站模式下的反向连接
+///
+/// 开启严格入站后不响应对端发起的打洞，也不接受未知路径上的ping，只和有许可的对端建立直连。
+/// 许可来自本地的invite命令，或者批准对端经服务器中继发来的连接请求，之后由本机向对端发起打洞，
+/// 到期后删除许可和直连路由，对端的流量回到服务器中继
+pub struct ReverseConnect {
+    strict: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl Default for ReverseConnect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReverseConnect {
+    pub fn new() -> Self {
+        Self {
+            strict: AtomicBool::new(false),
+            state: Mutex::new(State {
+                allowances: HashMap::new(),
+                pending: Vec::new(),
+                last_intent: HashMap::new(),
+                window: None,
+                count: 0,
+                next_id: 1,
+                outgoing: HashMap::new(),
+            }),
+        }
+    }
+    pub fn set_strict(&self, strict: bool) {
+        self.strict.store(strict, Ordering::Relaxed);
+    }
+    pub fn strict(&self) -> bool {
+        self.strict.load(Ordering::Relaxed)
+    }
+    /// 是否可以和对端建立直连，没有开启严格入站时总是可以
+    #[inline]
+    pub fn allowed(&self, peer: &Ipv4Addr) -> bool {
+        !self.strict() || self.allowed_at(peer, Instant::now())
+    }
+    fn allowed_at(&self, peer: &Ipv4Addr, now: Instant) -> bool {
+        self.state
+            .lock()
+            .allowances
+            .get(peer)
+            .map_or(false, |expire| now < *expire)
+    }
+    /// 允许和对端直连，已有许可时按新的时长重新计算
+    pub fn grant(&self, peer: Ipv4Addr, duration: Duration, now: Instant) -> Duration {
+        let duration = clamp(duration);
+        let mut state = self.state.lock();
+        state.allowances.insert(peer, now + duration);
+        state.pending.retain(|v| v.peer != peer);
+        duration
+    }
+    /// 收到对端经服务器中继发来的连接请求
+    pub fn intent(&self, peer: Ipv4Addr, nonce: u32, duration: Duration, now: Instant) -> Intent {
+        let mut state = self.state.lock();
+        if let Some(last) = state.last_intent.get(&peer) {
+            if now.saturating_duration_since(*last) < INTENT_INTERVAL {
+                return Intent::Limited;
+            }
+        }
+        match state.window {
+            Some(window) if now.saturating_duration_since(window) < WINDOW => {
+                if state.count >= INTENT_PER_MINUTE {
+                    return Intent::Limited;
+                }
+            }
+            _ => {
+                state.window = Some(now);
+                state.count = 0;
+            }
+        }
+        state.count += 1;
+        state.last_intent.insert(peer, now);
+        if !self.strict() {
+            return Intent::Approve;
+        }
+        prune(&mut state, now);
+        if let Some(pending) = state.pending.iter_mut().find(|v| v.peer == peer) {
+            // 对端重发时使用新的nonce
+            pending.nonce = nonce;
+            pending.duration = clamp(duration);
+            return Intent::Duplicate;
+        }
+        if state.pending.len() >= PENDING_LIMIT {
+            return Intent::Limited;
+        }
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1).max(1);
+        state.pending.push(Pending {
+            id,
+            peer,
+            nonce,
+            duration: clamp(duration),
+            received: now,
+        });
+        Intent::Queued(id)
+    }
+    /// 等待批准的请求，按收到的顺序
+    pub fn pending(&self, now: Instant) -> Vec<PendingRequest> {
+        let mut state = self.state.lock();
+        prune(&mut state, now);
+        state
+            .pending
+            .iter()
+            .map(|v| PendingRequest {
+                id: v.id,
+                peer: v.peer,
+                duration: v.duration,
+                age: now.saturating_duration_since(v.received),
+            })
+            .collect()
+    }
+    /// 批准请求，返回对端、请求中的nonce和许可的时长；duration为None时使用请求的时长
+    pub fn accept(
+        &self,
+        id: u32,
+        duration: Option<Duration>,
+        now: Instant,
+    ) -> Option<(Ipv4Addr, u32, Duration)> {
+        let mut state = self.state.lock();
+        prune(&mut state, now);
+        let index = state.pending.iter().position(|v| v.id == id)?;
+        let pending = state.pending.remove(index);
+        let duration = clamp(duration.unwrap_or(pending.duration));
+        state.allowances.insert(pending.peer, now + duration);
+        Some((pending.peer, pending.nonce, duration))
+    }
+    /// 删除到期的许可，返回需要断开直连的对端
+    pub fn expire(&self, now: Instant) -> Vec<Ipv4Addr> {
+        let mut state = self.state.lock();
+        prune(&mut state, now);
+        let mut expired = Vec::new();
+        state.allowances.retain(|peer, expire| {
+            if now < *expire {
+                true
+            } else {
+                expired.push(*peer);
+                false
+            }
+        });
+        expired.sort();
+        expired
+    }
+    /// 收回许可，返回之前是否有许可
+    pub fn revoke(&self, peer: &Ipv4Addr) -> bool {
+        self.state.lock().allowances.remove(peer).is_some()
+    }
+    pub fn allowances(&self, now: Instant) -> Vec<Allowance> {
+        let state = self.state.lock();
+        let mut list: Vec<Allowance> = state
+            .allowances
+            .iter()
+            .filter(|(_, expire)| now < **expire)
+            .map(|(peer, expire)| Allowance {
+                peer: *peer,
+                remaining: expire.saturating_duration_since(now),
+            })
+            .collect();
+        list.sort_by_key(|v| v.peer);
+        list
+    }
+    /// 向对端发出请求前记录nonce
+    pub fn request(&self, peer: Ipv4Addr, nonce: u32, now: Instant) {
+        let mut state = self.state.lock();
+        prune(&mut state, now);
+        state.outgoing.insert(peer, (nonce, now));
+    }
+    /// 收到对端的批准，nonce和发出的请求一致时返回true
+    pub fn approved(&self, peer: &Ipv4Addr, nonce: u32, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        prune(&mut state, now);
+        match state.outgoing.get(peer) {
+            Some((expect, _)) if *expect == nonce => {
+                state.outgoing.remove(peer);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn clamp(duration: Duration) -> Duration {
+    if duration.is_zero() {
+        DEFAULT_DURATION
+    } else {
+        duration.min(MAX_DURATION)
+    }
+}
+
+/// 清理超时没有批准的请求和过期的限速记录
+fn prune(state: &mut State, now: Instant) {
+    state
+        .pending
+        .retain(|v| now.saturating_duration_since(v.received) < PENDING_TTL);
+    state
+        .outgoing
+        .retain(|_, (_, sent)| now.saturating_duration_since(*sent) < PENDING_TTL);
+    state
+        .last_intent
+        .retain(|_, last| now.saturating_duration_since(*last) < INTENT_INTERVAL);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::{Intent, ReverseConnect, DEFAULT_DURATION, PENDING_TTL};
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    #[test]
+    fn test_approve_and_expire() {
+        let reverse = ReverseConnect::new();
+        let now = Instant::now();
+        // 没有开启严格入站时不需要许可
+        assert!(reverse.allowed(&PEER));
+        assert_eq!(
+            reverse.intent(PEER, 7, Duration::ZERO, now),
+            Intent::Approve
+        );
+
+        reverse.set_strict(true);
+        assert!(!reverse.allowed(&PEER));
+        let now = now + Duration::from_secs(11);
+        let id = match reverse.intent(PEER, 8, Duration::from_secs(60), now) {
+            Intent::Queued(id) => id,
+            v => panic!("{:?}", v),
+        };
+        // 重发的请求更新nonce
+        let now = now + Duration::from_secs(11);
+        assert_eq!(
+            reverse.intent(PEER, 9, Duration::from_secs(60), now),
+            Intent::Duplicate
+        );
+        let pending = reverse.pending(now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].peer, PEER);
+
+        let (peer, nonce, duration) = reverse.accept(id, None, now).unwrap();
+        assert_eq!((peer, nonce, duration), (PEER, 9, Duration::from_secs(60)));
+        assert!(reverse.pending(now).is_empty());
+        assert!(reverse.allowed_at(&PEER, now + Duration::from_secs(59)));
+        assert!(reverse.expire(now + Duration::from_secs(59)).is_empty());
+
+        // 请求方核对批准中的nonce
+        let requester = ReverseConnect::new();
+        requester.request(PEER, 9, now);
+        assert!(requester.approved(&PEER, 9, now));
+        assert!(!requester.approved(&PEER, 9, now));
+
+        // 到期后删除许可
+        let later = now + Duration::from_secs(60);
+        assert!(!reverse.allowed_at(&PEER, later));
+        assert_eq!(reverse.expire(later), vec![PEER]);
+        assert!(reverse.expire(later).is_empty());
+        assert!(reverse.allowances(later).is_empty());
+
+        // invite不需要请求，时长为0时使用默认值
+        assert_eq!(reverse.grant(PEER, Duration::ZERO, later), DEFAULT_DURATION);
+        assert_eq!(reverse.allowances(later)[0].remaining, DEFAULT_DURATION);
+        assert!(reverse.revoke(&PEER));
+        assert!(!reverse.allowed(&PEER));
+    }
+
+    #[test]
+    fn test_denied() {
+        let reverse = ReverseConnect::new();
+        reverse.set_strict(true);
+        let now = Instant::now();
+        let id = match reverse.intent(PEER, 1, Duration::ZERO, now) {
+            Intent::Queued(id) => id,
+            v => panic!("{:?}", v),
+        };
+        // 请求方发出请求，一直没有批准
+        let requester = ReverseConnect::new();
+        requester.request(PEER, 1, now);
+        // 速率限制
+        assert_eq!(
+            reverse.intent(PEER, 1, Duration::ZERO, now + Duration::from_secs(1)),
+            Intent::Limited
+        );
+        // 超时后请求被丢弃，不能再批准，也不会产生许可
+        let later = now + PENDING_TTL;
+        assert!(reverse.pending(later).is_empty());
+        assert_eq!(reverse.accept(id, None, later), None);
+        assert!(!reverse.allowed_at(&PEER, later));
+        assert!(reverse.expire(later).is_empty());
+        // 请求方不接受超时后或者nonce不对的批准
+        assert!(!requester.approved(&PEER, 2, now));
+        assert!(!requester.approved(&PEER, 1, later));
+
+        // 数量和速率上限
+        let mut queued = 0;
+        for i in 0..40u8 {
+            if let Intent::Queued(_) =
+                reverse.intent(Ipv4Addr::new(10, 26, 1, i), 1, Duration::ZERO, later)
+            {
+                queued += 1;
+            }
+        }
+        assert_eq!(queued, 20);
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use crate::channel::context::RouteTable;
+use crate::channel::Route;
+
+/// 缓存条目数，必须是2的幂
+const CACHE_SIZE: usize = 8;
+
+/// 单个发送线程持有的路由缓存，按目的ip直接映射
+///
+/// 路由表每次变化都会增加版本号，版本号不一致时整体失效，不会使用过时的路由
+pub struct RouteCache {
+    generation: usize,
+    entries: [Option<(Ipv4Addr, Route)>; CACHE_SIZE],
+}
+
+impl RouteCache {
+    pub fn new() -> Self {
+        Self {
+            generation: 0,
+            entries: [None; CACHE_SIZE],
+        }
+    }
+    #[inline]
+    pub fn get(&mut self, route_table: &RouteTable, id: &Ipv4Addr) -> io::Result<Route> {
+        // 先读版本号再查路由，查询期间路由表有变化时下次会失效
+        let generation = route_table.generation();
+        if generation != self.generation {
+            self.entries = [None; CACHE_SIZE];
+            self.generation = generation;
+        }
+        let slot = u32::from(*id) as usize & (CACHE_SIZE - 1);
+        if let Some((ip, route)) = &self.entries[slot] {
+            if ip == id {
+                return Ok(*route);
+            }
+        }
+        let route = route_table.get_route_by_id(0, id)?;
+        self.entries[slot] = Some((*id, route));
+        Ok(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::channel::context::RouteTable;
+    use crate::channel::diary::{Diary, EvictReason};
+    use crate::channel::route_cache::RouteCache;
+    use crate::channel::{Route, UseChannelType};
+
+    fn route(port: u16) -> Route {
+        Route::new(false, 0, SocketAddr::from(([192, 168, 1, 2], port)), 1, 10)
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let route_table = RouteTable::new(UseChannelType::All, false, 1, Diary::new());
+        let mut cache = RouteCache::new();
+        let id = Ipv4Addr::new(10, 26, 0, 3);
+        assert!(cache.get(&route_table, &id).is_err());
+        route_table.add_route(id, route(1000));
+        assert_eq!(cache.get(&route_table, &id).unwrap().addr.port(), 1000);
+        route_table.remove_ip(&id, EvictReason::Offline);
+        assert!(cache.get(&route_table, &id).is_err());
+        route_table.add_route(id, route(2000));
+        assert_eq!(cache.get(&route_table, &id).unwrap().addr.port(), 2000);
+    }
+
+    #[test]
+    fn test_concurrent_change() {
+        let route_table = Arc::new(RouteTable::new(UseChannelType::All, false, 1, Diary::new()));
+        let id = Ipv4Addr::new(10, 26, 0, 3);
+        let writer = {
+            let route_table = route_table.clone();
+            thread::spawn(move || {
+                for port in 1..=1000u16 {
+                    route_table.remove_ip(&id, EvictReason::Offline);
+                    route_table.add_route(id, route(port));
+                }
+            })
+        };
+        let mut cache = RouteCache::new();
+        while !writer.is_finished() {
+            let _ = cache.get(&route_table, &id);
+        }
+        writer.join().unwrap();
+        // 路由变化结束后，缓存必须是最新的路由
+        assert_eq!(cache.get(&route_table, &id).unwrap().addr.port(), 1000);
+    }
+
+    /// 持续发往同一个对端时，缓存和直接查路由表的开销
+    ///
+    /// ```text
+    /// cargo test -p vnt --release bench_steady_flow -- --ignored --nocapture
+    /// ```
+    #[test]
+    #[ignore]
+    fn bench_steady_flow() {
+        const LOOKUPS: u32 = 10_000_000;
+        let route_table = RouteTable::new(UseChannelType::All, true, 1, Diary::new());
+        for i in 0..=255u8 {
+            route_table.add_route(Ipv4Addr::new(10, 26, 1, i), route(1000 + i as u16));
+        }
+        let id = Ipv4Addr::new(10, 26, 1, 3);
+        let start = Instant::now();
+        let mut sum = 0u64;
+        for _ in 0..LOOKUPS {
+            sum += route_table.get_route_by_id(0, &id).unwrap().addr.port() as u64;
+        }
+        let table = start.elapsed();
+        let mut cache = RouteCache::new();
+        let start = Instant::now();
+        for _ in 0..LOOKUPS {
+            sum += cache.get(&route_table, &id).unwrap().addr.port() as u64;
+        }
+        let cached = start.elapsed();
+        assert_eq!(sum, 1003 * 2 * LOOKUPS as u64);
+        println!(
+            "route table {:.1}ns/lookup, cache {:.1}ns/lookup",
+            table.as_nanos() as f64 / LOOKUPS as f64,
+            cached.as_nanos() as f64 / LOOKUPS as f64
+        );
+    }
+}
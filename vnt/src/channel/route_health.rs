@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::RouteKey;
+use crate::util::metrics::{Counter, Registry};
+
+/// 正在使用的直连路径连续这么多次探测没有回应，认为已经断开，改用中继
+pub const DEAD_AFTER: u32 = 3;
+/// 切回直连后在list中显示切换的时间
+const RECOVERED_SHOW: Duration = Duration::from_secs(30);
+/// 记录的对端数量上限，避免伪造来源导致内存增长
+const PEER_LIMIT: usize = 1024;
+
+/// 直连和中继之间的切换
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PathTransition {
+    /// 直连断开，使用中继，后台重新打洞
+    Down { since: Instant },
+    /// 重新打洞成功，已切回直连
+    Up { since: Instant },
+}
+
+impl Display for PathTransition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathTransition::Down { .. } => f.write_str("p2p->relay, reconnecting"),
+            PathTransition::Up { .. } => f.write_str("relay->p2p"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    // 正在探测的直连路径和连续没有回应的次数
+    key: Option<RouteKey>,
+    missed: u32,
+    transition: Option<PathTransition>,
+}
+
+/// 借用心跳检查正在使用的直连路径
+///
+/// 心跳每次发出探测计数加一，收到回应清零，连续DEAD_AFTER次没有回应时由调用方删除路由，
+/// 没有其他直连路径时改用服务器中继，定时打洞会在后台重新建立直连。
+/// 重新建立的直连延迟低于服务器延迟的两倍时才切回，否则继续使用中继
+pub struct RouteHealth {
+    peers: Mutex<HashMap<Ipv4Addr, Peer>>,
+    down: Counter,
+    up: Counter,
+}
+
+impl RouteHealth {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+            down: registry.counter("route_fallback", &[("to", "relay")]),
+            up: registry.counter("route_fallback", &[("to", "p2p")]),
+        }
+    }
+    /// 心跳通过正在使用的直连路径发出探测前调用，返回true表示路径已断开，不用再发
+    pub fn probe(&self, peer: Ipv4Addr, key: RouteKey) -> bool {
+        let mut guard = self.peers.lock();
+        if guard.len() >= PEER_LIMIT && !guard.contains_key(&peer) {
+            return false;
+        }
+        let state = guard.entry(peer).or_default();
+        if state.key != Some(key) {
+            // 换了路径，重新计数
+            state.key = Some(key);
+            state.missed = 0;
+        }
+        if state.missed >= DEAD_AFTER {
+            state.key = None;
+            state.missed = 0;
+            return true;
+        }
+        state.missed += 1;
+        false
+    }
+    /// 直连路径收到探测的回应
+    pub fn ack(&self, peer: Ipv4Addr, key: RouteKey) {
+        if let Some(state) = self.peers.lock().get_mut(&peer) {
+            if state.key == Some(key) {
+                state.missed = 0;
+            }
+        }
+    }
+    /// 直连断开后已经没有其他直连路径，数据改走中继
+    pub fn fell_back(&self, peer: Ipv4Addr) {
+        self.fell_back_at(peer, Instant::now())
+    }
+    fn fell_back_at(&self, peer: Ipv4Addr, now: Instant) {
+        let mut guard = self.peers.lock();
+        if guard.len() >= PEER_LIMIT && !guard.contains_key(&peer) {
+            return;
+        }
+        let state = guard.entry(peer).or_default();
+        if !matches!(state.transition, Some(PathTransition::Down { .. })) {
+            state.transition = Some(PathTransition::Down { since: now });
+            self.down.inc();
+        }
+    }
+    /// 直连路径测得延迟rt后调用，返回是否使用这条路径。
+    /// 断开过的对端只有延迟低于经过服务器的延迟(server_rt的两倍)时才切回，server_rt未知时直接切回
+    pub fn accept(&self, peer: Ipv4Addr, rt: i64, server_rt: i64) -> bool {
+        self.accept_at(peer, rt, server_rt, Instant::now())
+    }
+    fn accept_at(&self, peer: Ipv4Addr, rt: i64, server_rt: i64, now: Instant) -> bool {
+        let mut guard = self.peers.lock();
+        let state = match guard.get_mut(&peer) {
+            Some(state) => state,
+            None => return true,
+        };
+        if !matches!(state.transition, Some(PathTransition::Down { .. })) {
+            return true;
+        }
+        if server_rt > 0 && rt >= server_rt * 2 {
+            return false;
+        }
+        state.transition = Some(PathTransition::Up { since: now });
+        self.up.inc();
+        true
+    }
+    /// 当前或最近的切换，用于list显示
+    pub fn transition(&self, peer: &Ipv4Addr) -> Option<PathTransition> {
+        self.transition_at(peer, Instant::now())
+    }
+    fn transition_at(&self, peer: &Ipv4Addr, now: Instant) -> Option<PathTransition> {
+        let transition = self.peers.lock().get(peer)?.transition?;
+        match transition {
+            PathTransition::Up { since }
+                if now.saturating_duration_since(since) >= RECOVERED_SHOW =>
+            {
+                None
+            }
+            transition => Some(transition),
+        }
+    }
+    /// 清理已经下线的对端
+    pub fn retain_peers(&self, online: &[Ipv4Addr]) {
+        self.peers.lock().retain(|ip, _| online.contains(ip));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use crate::channel::route_health::{PathTransition, RouteHealth, DEAD_AFTER};
+    use crate::channel::RouteKey;
+    use crate::util::metrics::Registry;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn key(port: u16) -> RouteKey {
+        RouteKey::new(false, 0, SocketAddr::from(([192, 168, 1, 3], port)))
+    }
+
+    #[test]
+    fn test_dead_after_missed() {
+        let health = RouteHealth::new(&Registry::new());
+        // 有回应时一直正常
+        for _ in 0..10 {
+            assert!(!health.probe(PEER, key(1)));
+            health.ack(PEER, key(1));
+        }
+        // 其他路径的回应不算
+        for _ in 0..DEAD_AFTER {
+            assert!(!health.probe(PEER, key(1)));
+            health.ack(PEER, key(2));
+        }
+        assert!(health.probe(PEER, key(1)));
+        // 换了路径重新计数
+        for _ in 0..DEAD_AFTER {
+            assert!(!health.probe(PEER, key(2)));
+        }
+        assert!(!health.probe(PEER, key(3)));
+    }
+
+    #[test]
+    fn test_switch_back() {
+        let health = RouteHealth::new(&Registry::new());
+        let now = Instant::now();
+        // 没有断开过的直接使用
+        assert!(health.accept_at(PEER, 500, 40, now));
+        assert_eq!(health.transition_at(&PEER, now), None);
+
+        health.fell_back_at(PEER, now);
+        let down = Some(PathTransition::Down { since: now });
+        assert_eq!(health.transition_at(&PEER, now), down);
+        assert_eq!(down.unwrap().to_string(), "p2p->relay, reconnecting");
+        // 重新建立的直连还不如中继
+        assert!(!health.accept_at(PEER, 80, 40, now));
+        assert_eq!(health.transition_at(&PEER, now), down);
+
+        let later = now + Duration::from_secs(20);
+        assert!(health.accept_at(PEER, 79, 40, later));
+        let up = health.transition_at(&PEER, later).unwrap();
+        assert_eq!(up.to_string(), "relay->p2p");
+        assert_eq!(
+            health.transition_at(&PEER, later + Duration::from_secs(30)),
+            None
+        );
+        // 服务器延迟未知时直接切回
+        health.fell_back_at(PEER, later);
+        assert!(health.accept_at(PEER, 500, -1, later));
+
+        health.retain_peers(&[]);
+        assert_eq!(health.transition_at(&PEER, later), None);
+    }
+}
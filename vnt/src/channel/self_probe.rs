@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::handle::packet_hook::PacketView;
+
+/// 自检包负载开头的标记，后面是8字节的编号，剩余部分由编号生成，用于检查是否被修改
+pub const MAGIC: [u8; 8] = *b"vnt-self";
+/// 自检udp包使用的端口
+pub const PROBE_PORT: u16 = 9;
+const HEAD_LEN: usize = MAGIC.len() + 8;
+const UDP: u8 = 17;
+const ICMP: u8 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// 自检包的接收端
+///
+/// 接收路径在写入虚拟网卡之前调用`deliver`，等待中的自检包交给发起方，不写入网卡。
+/// 没有等待的自检时只有一次原子读取。集成测试也通过这里注入和接收数据包
+#[derive(Default)]
+pub struct SelfProbe {
+    waiting: AtomicUsize,
+    pending: Mutex<HashMap<u64, SyncSender<bool>>>,
+}
+
+/// 等待一个自检包，drop时取消
+pub struct Expect<'a> {
+    probe: &'a SelfProbe,
+    token: u64,
+    receiver: Receiver<bool>,
+    start: Instant,
+}
+
+impl Expect<'_> {
+    /// 收到时返回往返时间和负载是否完整，超时返回None
+    pub fn wait(&self, timeout: Duration) -> Option<(Duration, bool)> {
+        self.receiver
+            .recv_timeout(timeout)
+            .ok()
+            .map(|intact| (self.start.elapsed(), intact))
+    }
+}
+
+impl Drop for Expect<'_> {
+    fn drop(&mut self) {
+        if self.probe.pending.lock().remove(&self.token).is_some() {
+            self.probe.waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl SelfProbe {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 发送自检包之前调用
+    pub fn expect(&self, token: u64) -> Expect<'_> {
+        let (sender, receiver) = sync_channel(1);
+        if self.pending.lock().insert(token, sender).is_none() {
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+        }
+        Expect {
+            probe: self,
+            token,
+            receiver,
+            start: Instant::now(),
+        }
+    }
+    /// 是等待中的自检包时返回true，调用方不再写入网卡
+    pub fn deliver(&self, ipv4: &[u8]) -> bool {
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let (token, intact) = match parse(ipv4) {
+            Some(v) => v,
+            None => return false,
+        };
+        match self.pending.lock().get(&token) {
+            Some(sender) => {
+                let _ = sender.try_send(intact);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 自检包的负载，len不小于16
+pub fn payload(token: u64, len: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(len.max(HEAD_LEN));
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&token.to_be_bytes());
+    for i in HEAD_LEN..len {
+        buf.push(pattern(token, i));
+    }
+    buf
+}
+
+fn pattern(token: u64, i: usize) -> u8 {
+    (token as u8).wrapping_add(i as u8)
+}
+
+/// 负载中的编号和是否完整
+fn check_payload(payload: &[u8]) -> Option<(u64, bool)> {
+    if payload.len() < HEAD_LEN || payload[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let token = u64::from_be_bytes(payload[MAGIC.len()..HEAD_LEN].try_into().unwrap());
+    let intact = payload
+        .iter()
+        .enumerate()
+        .skip(HEAD_LEN)
+        .all(|(i, v)| *v == pattern(token, i));
+    Some((token, intact))
+}
+
+/// 发到自检端口的udp包或者icmp回应
+fn parse(ipv4: &[u8]) -> Option<(u64, bool)> {
+    if ipv4.len() < 20 || ipv4[0] >> 4 != 4 {
+        return None;
+    }
+    let head_len = ((ipv4[0] & 0x0F) as usize) * 4;
+    let transport = ipv4.get(head_len..)?;
+    if transport.len() < 8 {
+        return None;
+    }
+    match ipv4[9] {
+        UDP if u16::from_be_bytes([transport[2], transport[3]]) == PROBE_PORT => {
+            check_payload(&transport[8..])
+        }
+        ICMP if transport[0] == ICMP_ECHO_REPLY => check_payload(&transport[8..]),
+        _ => None,
+    }
+}
+
+pub(crate) fn ipv4(
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    protocol: u8,
+    transport: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let total_len = 20 + transport.len();
+    if total_len > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "packet too long",
+        ));
+    }
+    let mut buf = Vec::with_capacity(total_len);
+    buf.extend_from_slice(&[0x45, 0]);
+    buf.extend_from_slice(&(total_len as u16).to_be_bytes());
+    // 不分片
+    buf.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+    buf.extend_from_slice(&src.octets());
+    buf.extend_from_slice(&dest.octets());
+    buf.extend_from_slice(&transport);
+    PacketView::new(&mut buf)?.fix_checksums();
+    Ok(buf)
+}
+
+/// 发往自检端口的udp包
+pub fn udp_packet(src: Ipv4Addr, dest: Ipv4Addr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_len = 8 + payload.len();
+    let mut transport = Vec::with_capacity(udp_len);
+    transport.extend_from_slice(&PROBE_PORT.to_be_bytes());
+    transport.extend_from_slice(&PROBE_PORT.to_be_bytes());
+    transport.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    // 校验和不为0时才会重新计算
+    transport.extend_from_slice(&[0xFF, 0xFF]);
+    transport.extend_from_slice(payload);
+    ipv4(src, dest, UDP, transport)
+}
+
+/// icmp回显请求，对端客户端和服务器会原样带回负载
+pub fn icmp_echo(src: Ipv4Addr, dest: Ipv4Addr, seq: u16, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut transport = Vec::with_capacity(8 + payload.len());
+    transport.extend_from_slice(&[ICMP_ECHO_REQUEST, 0, 0, 0]);
+    transport.extend_from_slice(&(std::process::id() as u16).to_be_bytes());
+    transport.extend_from_slice(&seq.to_be_bytes());
+    transport.extend_from_slice(payload);
+    ipv4(src, dest, ICMP, transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::channel::self_probe::{icmp_echo, payload, udp_packet, SelfProbe};
+
+    #[test]
+    fn test_deliver() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let probe = SelfProbe::new();
+        let packet = udp_packet(ip, ip, &payload(7, 100)).unwrap();
+        // 没有等待的自检时按普通数据处理
+        assert!(!probe.deliver(&packet));
+        let expect = probe.expect(7);
+        assert!(!probe.deliver(&udp_packet(ip, ip, &payload(8, 100)).unwrap()));
+        assert!(probe.deliver(&packet));
+        assert!(expect.wait(Duration::from_secs(1)).unwrap().1);
+        drop(expect);
+        assert!(!probe.deliver(&packet));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let probe = SelfProbe::new();
+        let expect = probe.expect(3);
+        let mut packet = icmp_echo(ip, ip, 1, &payload(3, 1000)).unwrap();
+        // 请求不是回应
+        assert!(!probe.deliver(&packet));
+        packet[20] = 0;
+        let len = packet.len();
+        packet[len - 1] ^= 0xFF;
+        assert!(probe.deliver(&packet));
+        assert_eq!(
+            expect.wait(Duration::from_secs(1)).map(|v| v.1),
+            Some(false)
+        );
+        assert!(expect.wait(Duration::from_millis(10)).is_none());
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 辅助socket的默认数量上限
+pub const DEFAULT_LIMIT: usize = 64;
+/// 批量申请时给短时使用的socket留出的数量
+const RESERVED: usize = 8;
+/// 检查泄漏的间隔
+pub const AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 辅助socket的用途
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SocketPurpose {
+    /// 对称网络打洞增加的端口，nat类型变回锥形时关闭
+    SymmetricPunch,
+    /// nat类型探测
+    Stun,
+}
+
+impl SocketPurpose {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SocketPurpose::SymmetricPunch => "symmetric_punch",
+            SocketPurpose::Stun => "stun",
+        }
+    }
+    /// 超过这个时间还没关闭就认为泄漏，None表示不限制
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        match self {
+            SocketPurpose::SymmetricPunch => None,
+            SocketPurpose::Stun => Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl fmt::Display for SocketPurpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+struct Entry {
+    purpose: SocketPurpose,
+    local_port: u16,
+    created: Instant,
+    bytes: Arc<AtomicU64>,
+}
+
+/// 一个socket的统计
+#[derive(Clone, Debug)]
+pub struct SocketStat {
+    pub purpose: SocketPurpose,
+    pub local_port: u16,
+    pub age: Duration,
+    /// 收发的字节数
+    pub bytes: u64,
+}
+
+/// 打洞、nat探测等临时创建的udp socket
+///
+/// 所有辅助socket都通过这里创建并计数，超过上限时申请失败，由调用方减少端口数量。
+/// 返回的PooledSocket在drop时关闭并从计数中移除
+#[derive(Clone)]
+pub struct SocketPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    limit: AtomicUsize,
+    // 绑定的本地ip，默认是0.0.0.0
+    bind_ip: AtomicU32,
+    next_id: AtomicU64,
+    sockets: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Default for SocketPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SocketPool {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                limit: AtomicUsize::new(DEFAULT_LIMIT),
+                bind_ip: AtomicU32::new(0),
+                next_id: AtomicU64::new(0),
+                sockets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+    pub fn set_limit(&self, limit: usize) {
+        self.inner.limit.store(limit, Ordering::Relaxed);
+    }
+    /// 多网卡时和主端口使用同一个网卡
+    pub fn set_bind_ip(&self, ip: Ipv4Addr) {
+        self.inner.bind_ip.store(ip.into(), Ordering::Relaxed);
+    }
+    fn bind_ip(&self) -> Ipv4Addr {
+        self.inner.bind_ip.load(Ordering::Relaxed).into()
+    }
+    pub fn limit(&self) -> usize {
+        self.inner.limit.load(Ordering::Relaxed)
+    }
+    /// 当前打开的数量
+    pub fn in_use(&self) -> usize {
+        self.inner.sockets.lock().len()
+    }
+    /// 创建一个ipv4的udp socket，超过上限时返回错误
+    pub fn acquire(&self, purpose: SocketPurpose) -> io::Result<PooledSocket> {
+        self.acquire_with(purpose, 0, || UdpSocket::bind((self.bind_ip(), 0)))
+    }
+    /// 尽量创建want个，给其他用途留出一部分，返回实际创建的
+    pub fn acquire_many(&self, purpose: SocketPurpose, want: usize) -> Vec<PooledSocket> {
+        let mut list = Vec::with_capacity(want);
+        for _ in 0..want {
+            match self.acquire_with(purpose, RESERVED, || UdpSocket::bind((self.bind_ip(), 0))) {
+                Ok(socket) => list.push(socket),
+                Err(e) => {
+                    log::info!("{}端口数量受限 {}/{} {:?}", purpose, list.len(), want, e);
+                    break;
+                }
+            }
+        }
+        list
+    }
+    fn acquire_with(
+        &self,
+        purpose: SocketPurpose,
+        reserved: usize,
+        bind: impl FnOnce() -> io::Result<UdpSocket>,
+    ) -> io::Result<PooledSocket> {
+        let mut sockets = self.inner.sockets.lock();
+        let limit = self.limit();
+        if limit != 0 && sockets.len() + reserved >= limit {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("socket limit {} reached", limit),
+            ));
+        }
+        let socket = bind()?;
+        let local_port = socket.local_addr().map(|v| v.port()).unwrap_or(0);
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let bytes = Arc::new(AtomicU64::new(0));
+        sockets.insert(
+            id,
+            Entry {
+                purpose,
+                local_port,
+                created: Instant::now(),
+                bytes: bytes.clone(),
+            },
+        );
+        Ok(PooledSocket {
+            socket,
+            id,
+            bytes,
+            pool: self.inner.clone(),
+        })
+    }
+    pub fn stats(&self) -> Vec<SocketStat> {
+        self.stats_at(Instant::now())
+    }
+    fn stats_at(&self, now: Instant) -> Vec<SocketStat> {
+        let mut list: Vec<SocketStat> = self
+            .inner
+            .sockets
+            .lock()
+            .values()
+            .map(|entry| SocketStat {
+                purpose: entry.purpose,
+                local_port: entry.local_port,
+                age: now.saturating_duration_since(entry.created),
+                bytes: entry.bytes.load(Ordering::Relaxed),
+            })
+            .collect();
+        list.sort_by(|a, b| b.age.cmp(&a.age));
+        list
+    }
+    /// 超过用途最长存活时间还没关闭的socket
+    pub fn audit(&self) -> Vec<SocketStat> {
+        self.audit_at(Instant::now())
+    }
+    fn audit_at(&self, now: Instant) -> Vec<SocketStat> {
+        self.stats_at(now)
+            .into_iter()
+            .filter(|stat| match stat.purpose.max_lifetime() {
+                Some(max) => stat.age > max,
+                None => false,
+            })
+            .collect()
+    }
+}
+
+/// 从SocketPool申请的socket，drop时关闭
+pub struct PooledSocket {
+    socket: UdpSocket,
+    id: u64,
+    bytes: Arc<AtomicU64>,
+    pool: Arc<PoolInner>,
+}
+
+impl PooledSocket {
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.socket.send(buf)?;
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let len = self.socket.send_to(buf, addr)?;
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, addr) = self.socket.recv_from(buf)?;
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Ok((len, addr))
+    }
+}
+
+impl Deref for PooledSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket
+    }
+}
+
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        self.pool.sockets.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::{SocketPool, SocketPurpose};
+
+    /// 模拟一个打洞策略，申请端口后中途超时返回
+    fn strategy(pool: &SocketPool, ports: usize) -> io::Result<usize> {
+        let sockets = pool.acquire_many(SocketPurpose::SymmetricPunch, ports);
+        let stun = pool.acquire(SocketPurpose::Stun)?;
+        stun.set_read_timeout(Some(Duration::from_millis(10)))?;
+        let mut buf = [0u8; 16];
+        stun.recv_from(&mut buf)?;
+        Ok(sockets.len())
+    }
+
+    #[test]
+    fn test_cap() {
+        let pool = SocketPool::new();
+        pool.set_limit(16);
+        let list = pool.acquire_many(SocketPurpose::SymmetricPunch, 100);
+        // 留出一部分给其他用途
+        assert_eq!(list.len(), 8);
+        let mut stun = Vec::new();
+        for _ in 0..8 {
+            stun.push(pool.acquire(SocketPurpose::Stun).unwrap());
+        }
+        assert!(pool.acquire(SocketPurpose::Stun).is_err());
+        assert!(pool
+            .acquire_many(SocketPurpose::SymmetricPunch, 4)
+            .is_empty());
+        assert_eq!(pool.in_use(), 16);
+        drop(stun);
+        assert_eq!(pool.in_use(), 8);
+        assert!(pool.acquire(SocketPurpose::Stun).is_ok());
+    }
+
+    #[test]
+    fn test_bind_ip() {
+        let pool = SocketPool::new();
+        pool.set_bind_ip(Ipv4Addr::LOCALHOST);
+        let socket = pool.acquire(SocketPurpose::Stun).unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+    }
+
+    #[test]
+    fn test_raii_timeout() {
+        let pool = SocketPool::new();
+        pool.set_limit(12);
+        for _ in 0..3 {
+            let err = strategy(&pool, 10).unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ));
+            // 超时返回后全部关闭
+            assert_eq!(pool.in_use(), 0);
+        }
+        let kept = pool.acquire_many(SocketPurpose::SymmetricPunch, 2);
+        let stun = pool.acquire(SocketPurpose::Stun).unwrap();
+        stun.send_to(&[0u8; 10], kept[0].local_addr().unwrap())
+            .unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.len(), 3);
+        assert!(stats
+            .iter()
+            .any(|v| v.purpose == SocketPurpose::Stun && v.bytes == 10));
+        // 只有超过最长存活时间的stun socket被认为泄漏
+        let leaked = pool.audit_at(Instant::now() + Duration::from_secs(60));
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].purpose, SocketPurpose::Stun);
+        assert!(pool.audit().is_empty());
+    }
+}
@@ -0,0 +1,339 @@
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::channel::mtu_guard::update_checksum;
+use crate::external_route::AllowExternalRoute;
+use crate::util::metrics::{Counter, Registry};
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMP: u8 = 1;
+/// 地址转换记录的上限和空闲超时
+const NAT_LIMIT: usize = 1024;
+const NAT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 网卡读到的包的处理结果
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SourceCheck {
+    /// 源地址是虚拟ip或者允许的网段
+    Pass,
+    /// 源地址已改成虚拟ip
+    Rewritten,
+    /// 对端无法回复的源地址
+    Drop,
+}
+
+// 对端，协议，本地端口，对端端口
+type NatKey = (Ipv4Addr, u8, u16, u16);
+
+/// 本机发往虚拟网络的包的源地址策略
+///
+/// 本机的服务绑定在物理网卡地址上时，发往虚拟网络的包会带着物理网卡的地址进入网卡，
+/// 对端回复时按自己的路由发到它本地的网络，连接无法建立。
+/// 源地址不是虚拟ip、也不在`-o`允许的网段内时默认丢弃并提示一次，
+/// 开启`--snat-local`后改成虚拟ip，并记录下来把回复的目的地址改回去
+pub struct SourcePolicy {
+    snat: AtomicBool,
+    allowed: RwLock<AllowExternalRoute>,
+    hinted: AtomicBool,
+    nat: Mutex<HashMap<NatKey, (Ipv4Addr, Instant)>>,
+    rewrites: Counter,
+}
+
+impl SourcePolicy {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            snat: AtomicBool::new(false),
+            allowed: RwLock::new(AllowExternalRoute::new(Vec::new())),
+            hinted: AtomicBool::new(false),
+            nat: Mutex::new(HashMap::new()),
+            rewrites: registry.counter("source_snat_rewrites", &[]),
+        }
+    }
+    /// allowed 本机代理的网段(`-o`)，snat 是否改写源地址
+    pub fn set_policy(&self, allowed: AllowExternalRoute, snat: bool) {
+        *self.allowed.write() = allowed;
+        self.snat.store(snat, Ordering::Relaxed);
+    }
+    /// 检查网卡读到的ipv4包，格式错误的包交给后面的流程处理
+    pub fn outbound(&self, ipv4: &mut [u8], virtual_ip: Ipv4Addr) -> SourceCheck {
+        let ihl = match header_len(ipv4) {
+            Some(ihl) => ihl,
+            None => return SourceCheck::Pass,
+        };
+        let src = Ipv4Addr::new(ipv4[12], ipv4[13], ipv4[14], ipv4[15]);
+        if src == virtual_ip || virtual_ip.is_unspecified() || self.allowed.read().allow(&src) {
+            return SourceCheck::Pass;
+        }
+        if !self.snat.load(Ordering::Relaxed) {
+            if !self.hinted.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "丢弃源地址为{}的包，对端无法回复。服务应绑定虚拟ip{}，或者用'-o'允许该网段，或者使用'--snat-local'",
+                    src,
+                    virtual_ip
+                );
+            }
+            return SourceCheck::Drop;
+        }
+        let dest = Ipv4Addr::new(ipv4[16], ipv4[17], ipv4[18], ipv4[19]);
+        if let Some((protocol, local, remote)) = ports(ipv4, ihl, false) {
+            let mut nat = self.nat.lock();
+            let now = Instant::now();
+            if nat.len() >= NAT_LIMIT {
+                nat.retain(|_, (_, time)| now.duration_since(*time) < NAT_TIMEOUT);
+            }
+            // 满了也照样改写，只是回复无法还原
+            if nat.len() < NAT_LIMIT || nat.contains_key(&(dest, protocol, local, remote)) {
+                nat.insert((dest, protocol, local, remote), (src, now));
+            }
+        }
+        rewrite(ipv4, ihl, 12, virtual_ip);
+        self.rewrites.inc();
+        SourceCheck::Rewritten
+    }
+    /// 对端发来的包，目的地址是改写过的连接时改回原来的地址
+    ///
+    /// 只能通过端口识别连接，非首个分片无法还原。返回是否改写了
+    pub fn inbound(&self, ipv4: &mut [u8]) -> bool {
+        if !self.snat.load(Ordering::Relaxed) {
+            return false;
+        }
+        let (ihl, (protocol, local, remote)) =
+            match header_len(ipv4).and_then(|ihl| Some((ihl, ports(ipv4, ihl, true)?))) {
+                Some(v) => v,
+                None => return false,
+            };
+        let peer = Ipv4Addr::new(ipv4[12], ipv4[13], ipv4[14], ipv4[15]);
+        let original = {
+            let mut nat = self.nat.lock();
+            if nat.is_empty() {
+                return false;
+            }
+            match nat.get_mut(&(peer, protocol, local, remote)) {
+                Some((original, time)) => {
+                    *time = Instant::now();
+                    *original
+                }
+                None => return false,
+            }
+        };
+        rewrite(ipv4, ihl, 16, original);
+        true
+    }
+}
+
+/// 校验ipv4头，返回头部长度
+fn header_len(ipv4: &[u8]) -> Option<usize> {
+    if ipv4.len() < 20 || ipv4[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = (ipv4[0] & 0x0f) as usize * 4;
+    if ihl < 20 || ipv4.len() < ihl {
+        return None;
+    }
+    Some(ihl)
+}
+
+/// 识别连接用的协议和端口(本地，对端)，icmp使用echo的标识符
+fn ports(ipv4: &[u8], ihl: usize, inbound: bool) -> Option<(u8, u16, u16)> {
+    let offset = u16::from_be_bytes([ipv4[6], ipv4[7]]) & 0x1fff;
+    if offset != 0 {
+        return None;
+    }
+    let protocol = ipv4[9];
+    let l4 = &ipv4[ihl..];
+    match protocol {
+        TCP | UDP if l4.len() >= 8 => {
+            let src = u16::from_be_bytes([l4[0], l4[1]]);
+            let dst = u16::from_be_bytes([l4[2], l4[3]]);
+            if inbound {
+                Some((protocol, dst, src))
+            } else {
+                Some((protocol, src, dst))
+            }
+        }
+        // 出方向只记录echo请求，入方向只还原echo应答
+        ICMP if l4.len() >= 8 && l4[0] == if inbound { 0 } else { 8 } => {
+            Some((protocol, u16::from_be_bytes([l4[4], l4[5]]), 0))
+        }
+        _ => None,
+    }
+}
+
+/// 修改源地址(pos=12)或目的地址(pos=16)，增量更新ip和tcp/udp校验和
+fn rewrite(ipv4: &mut [u8], ihl: usize, pos: usize, new: Ipv4Addr) {
+    let old: [u8; 4] = ipv4[pos..pos + 4].try_into().unwrap();
+    let new = new.octets();
+    ipv4[pos..pos + 4].copy_from_slice(&new);
+    fix_checksum(&mut ipv4[10..12], &old, &new);
+    let offset = u16::from_be_bytes([ipv4[6], ipv4[7]]) & 0x1fff;
+    if offset != 0 {
+        // 伪首部的校验和在首个分片里，增量更新对整个报文都成立
+        return;
+    }
+    let protocol = ipv4[9];
+    let l4 = &mut ipv4[ihl..];
+    match protocol {
+        TCP if l4.len() >= 18 => fix_checksum(&mut l4[16..18], &old, &new),
+        UDP if l4.len() >= 8 => {
+            // 0表示没有校验和
+            if l4[6..8] != [0, 0] {
+                fix_checksum(&mut l4[6..8], &old, &new);
+                if l4[6..8] == [0, 0] {
+                    l4[6..8].copy_from_slice(&[0xff, 0xff]);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn fix_checksum(field: &mut [u8], old: &[u8; 4], new: &[u8; 4]) {
+    let mut checksum = u16::from_be_bytes([field[0], field[1]]);
+    for i in [0, 2] {
+        checksum = update_checksum(
+            checksum,
+            u16::from_be_bytes([old[i], old[i + 1]]),
+            u16::from_be_bytes([new[i], new[i + 1]]),
+        );
+    }
+    field.copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::external_route::AllowExternalRoute;
+    use crate::util::metrics::Registry;
+
+    use super::{SourceCheck, SourcePolicy, TCP, UDP};
+
+    const VIRTUAL_IP: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const PHYSICAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 5);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn sum(data: &[u8], mut acc: u32) -> u32 {
+        for chunk in data.chunks(2) {
+            let word = if chunk.len() == 2 {
+                u16::from_be_bytes([chunk[0], chunk[1]])
+            } else {
+                u16::from_be_bytes([chunk[0], 0])
+            };
+            acc += word as u32;
+        }
+        acc
+    }
+
+    fn fold(mut acc: u32) -> u16 {
+        while acc >> 16 != 0 {
+            acc = (acc & 0xffff) + (acc >> 16);
+        }
+        !(acc as u16)
+    }
+
+    /// 完整计算ip和传输层的校验和
+    fn packet(protocol: u8, src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16) -> Vec<u8> {
+        let payload = b"split horizon";
+        let l4_len = if protocol == TCP { 20 } else { 8 } + payload.len();
+        let total_len = 20 + l4_len;
+        let mut buf = vec![0u8; total_len];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        buf[8] = 64;
+        buf[9] = protocol;
+        buf[12..16].copy_from_slice(&src.octets());
+        buf[16..20].copy_from_slice(&dst.octets());
+        let checksum = fold(sum(&buf[..20], 0));
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        let l4 = &mut buf[20..];
+        l4[0..2].copy_from_slice(&sport.to_be_bytes());
+        l4[2..4].copy_from_slice(&dport.to_be_bytes());
+        if protocol == TCP {
+            l4[12] = 5 << 4;
+            l4[20..].copy_from_slice(payload);
+        } else {
+            l4[4..6].copy_from_slice(&(l4_len as u16).to_be_bytes());
+            l4[8..].copy_from_slice(payload);
+        }
+        let checksum = l4_checksum(&buf);
+        let pos = if protocol == TCP { 36 } else { 26 };
+        buf[pos..pos + 2].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    fn l4_checksum(buf: &[u8]) -> u16 {
+        let mut acc = sum(&buf[12..20], 0);
+        acc += buf[9] as u32 + (buf.len() - 20) as u32;
+        fold(sum(&buf[20..], acc))
+    }
+
+    fn checksum_valid(buf: &[u8]) -> bool {
+        fold(sum(&buf[..20], 0)) == 0
+            && fold(sum(
+                &buf[20..],
+                sum(&buf[12..20], buf[9] as u32 + (buf.len() - 20) as u32),
+            )) == 0
+    }
+
+    #[test]
+    fn test_detect() {
+        let policy = SourcePolicy::new(&Registry::new());
+        policy.set_policy(
+            AllowExternalRoute::new(vec![(
+                u32::from(Ipv4Addr::new(192, 168, 2, 0)),
+                u32::from(Ipv4Addr::new(255, 255, 255, 0)),
+            )]),
+            false,
+        );
+        let mut buf = packet(TCP, VIRTUAL_IP, PEER, 40000, 22);
+        assert_eq!(policy.outbound(&mut buf, VIRTUAL_IP), SourceCheck::Pass);
+        // -o允许的网段是本机代理的设备
+        let mut buf = packet(TCP, Ipv4Addr::new(192, 168, 2, 9), PEER, 40000, 22);
+        assert_eq!(policy.outbound(&mut buf, VIRTUAL_IP), SourceCheck::Pass);
+        let mut buf = packet(UDP, PHYSICAL_IP, PEER, 40000, 53);
+        let original = buf.clone();
+        assert_eq!(policy.outbound(&mut buf, VIRTUAL_IP), SourceCheck::Drop);
+        assert_eq!(buf, original);
+        // 截断的包留给后面的流程处理
+        assert_eq!(
+            policy.outbound(&mut buf[..10], VIRTUAL_IP),
+            SourceCheck::Pass
+        );
+    }
+
+    #[test]
+    fn test_snat() {
+        let registry = Registry::new();
+        let policy = SourcePolicy::new(&registry);
+        policy.set_policy(AllowExternalRoute::new(Vec::new()), true);
+        for protocol in [TCP, UDP] {
+            let mut buf = packet(protocol, PHYSICAL_IP, PEER, 40000, 8080);
+            assert_eq!(
+                policy.outbound(&mut buf, VIRTUAL_IP),
+                SourceCheck::Rewritten
+            );
+            assert_eq!(buf, packet(protocol, VIRTUAL_IP, PEER, 40000, 8080));
+            assert!(checksum_valid(&buf));
+            // 回复的目的地址改回物理网卡地址
+            let mut reply = packet(protocol, PEER, VIRTUAL_IP, 8080, 40000);
+            assert!(policy.inbound(&mut reply));
+            assert_eq!(reply, packet(protocol, PEER, PHYSICAL_IP, 8080, 40000));
+            assert!(checksum_valid(&reply));
+            // 其他连接不受影响
+            let mut other = packet(protocol, PEER, VIRTUAL_IP, 8080, 40001);
+            let original = other.clone();
+            assert!(!policy.inbound(&mut other));
+            assert_eq!(other, original);
+        }
+        // 没有校验和的udp包保持为0
+        let mut buf = packet(UDP, PHYSICAL_IP, PEER, 40002, 8080);
+        buf[26..28].copy_from_slice(&[0, 0]);
+        policy.outbound(&mut buf, VIRTUAL_IP);
+        assert_eq!(buf[26..28], [0, 0]);
+        assert_eq!(registry.counter("source_snat_rewrites", &[]).get(), 3);
+    }
+}
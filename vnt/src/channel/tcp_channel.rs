@@ -20,6 +20,7 @@ use crate::channel::handler::RecvChannelHandler;
 use crate::channel::notify::{AcceptNotify, WritableNotify};
 use crate::channel::sender::{AcceptSocketSender, PacketSender};
 use crate::channel::{RouteKey, BUFFER_SIZE};
+use crate::util::workers::Worker;
 use crate::util::StopManager;
 
 const SERVER: Token = Token(0);
@@ -79,6 +80,7 @@ fn tcp_listen0<H>(
 where
     H: RecvChannelHandler,
 {
+    context.workers.enter(Worker::Tcp);
     let (tcp_sender, tcp_receiver) = sync_channel(64);
     let write_waker = init_writable_handler(tcp_receiver, stop_manager.clone(), context.clone())?;
     poll.registry()
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::util::metrics::{Counter, Registry};
+
+/// 默认连续失败这么多次后切换协议，重连间隔1、2、4...秒，约1分钟
+pub const DEFAULT_FALLBACK: usize = 6;
+
+/// 连接服务器使用的传输协议
+///
+/// udp被完全过滤时一直重试注册也不会成功，连续失败一定次数后改用tcp连接服务器，
+/// 注册、心跳和中继数据都走tcp连接，打洞仍然只用udp。tcp也连续失败时换回udp。
+/// 指定了'--tcp'时固定使用tcp，不切换
+pub struct ServerTransport {
+    is_tcp: AtomicBool,
+    fixed: bool,
+    threshold: AtomicUsize,
+    failures: AtomicUsize,
+    to_tcp: Counter,
+    to_udp: Counter,
+}
+
+impl ServerTransport {
+    pub fn new(is_tcp: bool, registry: &Registry) -> Self {
+        Self {
+            is_tcp: AtomicBool::new(is_tcp),
+            fixed: is_tcp,
+            threshold: AtomicUsize::new(DEFAULT_FALLBACK),
+            failures: AtomicUsize::new(0),
+            to_tcp: registry.counter("server_transport_switch", &[("to", "tcp")]),
+            to_udp: registry.counter("server_transport_switch", &[("to", "udp")]),
+        }
+    }
+    /// 0表示不切换
+    pub fn set_fallback(&self, threshold: usize) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+    pub fn is_tcp(&self) -> bool {
+        self.is_tcp.load(Ordering::Relaxed)
+    }
+    /// 已连接到服务器
+    pub fn connected(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+    /// 连接服务器前调用，上一次尝试没有成功。返回是否切换了协议
+    pub fn attempt(&self) -> bool {
+        let threshold = self.threshold.load(Ordering::Relaxed);
+        if self.fixed || threshold == 0 {
+            return false;
+        }
+        if self.failures.fetch_add(1, Ordering::Relaxed) < threshold {
+            return false;
+        }
+        self.failures.store(1, Ordering::Relaxed);
+        let is_tcp = !self.is_tcp.fetch_xor(true, Ordering::Relaxed);
+        if is_tcp {
+            self.to_tcp.inc();
+            log::warn!("udp连续{}次连接服务器失败,改用tcp", threshold);
+        } else {
+            self.to_udp.inc();
+            log::warn!("tcp连续{}次连接服务器失败,换回udp", threshold);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::transport::ServerTransport;
+    use crate::util::metrics::Registry;
+
+    #[test]
+    fn test_fallback() {
+        let registry = Registry::new();
+        let transport = ServerTransport::new(false, &registry);
+        transport.set_fallback(3);
+        // 第一次尝试之前没有失败
+        for _ in 0..3 {
+            assert!(!transport.attempt());
+        }
+        assert!(!transport.is_tcp());
+        assert!(transport.attempt());
+        assert!(transport.is_tcp());
+        // tcp上的第一次尝试已经算入
+        assert!(!transport.attempt());
+        assert!(!transport.attempt());
+        transport.connected();
+        for _ in 0..3 {
+            assert!(!transport.attempt());
+        }
+        assert!(transport.is_tcp());
+        assert!(transport.attempt());
+        assert!(!transport.is_tcp());
+
+        // 指定了tcp或者关闭切换时不变
+        let fixed = ServerTransport::new(true, &registry);
+        fixed.set_fallback(1);
+        let off = ServerTransport::new(false, &registry);
+        off.set_fallback(0);
+        for _ in 0..10 {
+            assert!(!fixed.attempt());
+            assert!(!off.attempt());
+        }
+        assert!(fixed.is_tcp());
+        assert!(!off.is_tcp());
+    }
+}
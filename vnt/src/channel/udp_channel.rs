@@ -8,9 +8,12 @@ use mio::{Events, Interest, Poll, Token, Waker};
 
 use crate::channel::context::ChannelContext;
 use crate::channel::handler::RecvChannelHandler;
+use crate::channel::icmp_error;
 use crate::channel::notify::AcceptNotify;
 use crate::channel::sender::AcceptSocketSender;
 use crate::channel::{RouteKey, BUFFER_SIZE};
+use crate::util::health::{WATCHDOG_INTERVAL, WATCHDOG_STALE};
+use crate::util::workers::Worker;
 use crate::util::StopManager;
 
 pub fn udp_listen<H>(
@@ -71,8 +74,11 @@ where
     let mut events = Events::with_capacity(1024);
     let mut buf = [0; BUFFER_SIZE];
     let mut read_map: HashMap<Token, UdpSocket> = HashMap::with_capacity(32);
+    let watchdog = context.health.watchdog("sub_udp", WATCHDOG_STALE);
+    context.workers.enter(Worker::Udp);
     loop {
-        poll.poll(&mut events, None)?;
+        poll.poll(&mut events, Some(WATCHDOG_INTERVAL))?;
+        watchdog.beat();
         for event in events.iter() {
             match event.token() {
                 NOTIFY => {
@@ -239,12 +245,18 @@ pub fn main_udp_listen0<H>(
 where
     H: RecvChannelHandler,
 {
+    context.workers.enter(Worker::Udp);
     let mut buf = [0; BUFFER_SIZE];
     let mut udps = Vec::with_capacity(context.main_udp_socket.len());
 
     for (index, udp) in context.main_udp_socket.iter().enumerate() {
         let udp_socket = udp.try_clone()?;
         udp_socket.set_nonblocking(true)?;
+        #[cfg(target_os = "linux")]
+        if let Err(e) = icmp_error::enable_recv_err(&udp_socket, udp_socket.local_addr()?.is_ipv6())
+        {
+            log::warn!("开启IP_RECVERR失败 index={} {:?}", index, e);
+        }
         let mut mio_udp = UdpSocket::from_std(udp_socket);
         poll.registry()
             .register(&mut mio_udp, Token(index + 1), Interest::READABLE)?;
@@ -252,8 +264,10 @@ where
     }
 
     let mut events = Events::with_capacity(udps.len());
+    let watchdog = context.health.watchdog("main_udp", WATCHDOG_STALE);
     loop {
-        poll.poll(&mut events, None)?;
+        poll.poll(&mut events, Some(WATCHDOG_INTERVAL))?;
+        watchdog.beat();
         for x in events.iter() {
             let index = match x.token() {
                 NOTIFY => return Ok(()),
@@ -268,6 +282,10 @@ where
             loop {
                 match udp.recv_from(&mut buf) {
                     Ok((len, addr)) => {
+                        if context.handover.forward(index, addr, &buf[..len]) {
+                            // 已经交给接管的新进程
+                            continue;
+                        }
                         recv_handler.handle(
                             &mut buf[..len],
                             RouteKey::new(false, index, addr),
@@ -278,10 +296,16 @@ where
                         if e.kind() == io::ErrorKind::WouldBlock {
                             break;
                         }
-                        log::error!("main_udp_listen_{}={:?}", index, e);
+                        if !icmp_error::recv_error(&context, index, udp, &e) {
+                            log::error!("main_udp_listen_{}={:?}", index, e);
+                        }
                     }
                 }
             }
+            if x.is_error() {
+                // 错误队列里还有没读的icmp错误
+                icmp_error::drain_errqueue(&context, index, udp);
+            }
         }
     }
 }
@@ -48,6 +48,9 @@ impl AesGcmCipher {
             log::error!("数据异常,长度小于{}", AES_GCM_ENCRYPTION_RESERVED);
             return Err(io::Error::new(io::ErrorKind::Other, "data err"));
         }
+        if net_packet.is_auth_only() {
+            return self.verify_ipv4(net_packet);
+        }
         let mut nonce_raw = [0; 12];
         nonce_raw[0..4].copy_from_slice(&net_packet.source().octets());
         nonce_raw[4..8].copy_from_slice(&net_packet.destination().octets());
@@ -129,4 +132,130 @@ impl AesGcmCipher {
             )),
         };
     }
+    /// 只认证不加密，载荷原样发送
+    ///
+    /// 协议头和载荷都参与认证，接收方能确认数据来自持有密钥的一方且没有被修改。
+    /// 仍然设置加密标志，不认识只认证标志的接收方会解密失败，不会把数据当作明文处理
+    pub fn authenticate_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        if net_packet.reserve() < AES_GCM_ENCRYPTION_RESERVED {
+            return Err(io::Error::new(io::ErrorKind::Other, "too short"));
+        }
+        // 标志也要参与认证，防止被改成普通加密包
+        net_packet.set_encrypt_flag(true);
+        net_packet.set_auth_only_flag(true);
+        let nonce_raw = nonce_raw(net_packet);
+        let nonce: &GenericArray<u8, U12> = Nonce::from_slice(&nonce_raw);
+        let data_len = net_packet.data_len() + AES_GCM_ENCRYPTION_RESERVED;
+        net_packet.set_data_len(data_len)?;
+        let exist_finger = self.finger.is_some();
+        SecretBody::new(net_packet.payload_mut(), exist_finger)?
+            .set_random(rand::thread_rng().next_u32());
+        let aad =
+            SecretBody::new(net_packet.payload(), exist_finger)?.auth_only_aad(net_packet.head());
+        let rs = match &self.cipher {
+            AesGcmEnum::AES128GCM(aes_gcm) => {
+                aes_gcm.encrypt_in_place_detached(nonce, &aad, &mut [])
+            }
+            AesGcmEnum::AES256GCM(aes_gcm) => {
+                aes_gcm.encrypt_in_place_detached(nonce, &aad, &mut [])
+            }
+        };
+        match rs {
+            Ok(tag) => {
+                let mut secret_body = SecretBody::new(net_packet.payload_mut(), exist_finger)?;
+                secret_body.set_tag(tag.as_slice())?;
+                if let Some(finger) = &self.finger {
+                    let finger = finger.calculate_finger(&nonce_raw, secret_body.en_body());
+                    secret_body.set_finger(&finger)?;
+                }
+                Ok(())
+            }
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("认证失败:{}", e),
+            )),
+        }
+    }
+    fn verify_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        let nonce_raw = nonce_raw(net_packet);
+        let nonce: &GenericArray<u8, U12> = Nonce::from_slice(&nonce_raw);
+        let secret_body = SecretBody::new(net_packet.payload(), self.finger.is_some())?;
+        if let Some(finger) = &self.finger {
+            let finger = finger.calculate_finger(&nonce_raw, secret_body.en_body());
+            if &finger != secret_body.finger() {
+                return Err(io::Error::new(io::ErrorKind::Other, "finger err"));
+            }
+        }
+        let tag: GenericArray<u8, U16> = Tag::clone_from_slice(secret_body.tag());
+        let aad = secret_body.auth_only_aad(net_packet.head());
+        let rs = match &self.cipher {
+            AesGcmEnum::AES128GCM(aes_gcm) => {
+                aes_gcm.decrypt_in_place_detached(nonce, &aad, &mut [], &tag)
+            }
+            AesGcmEnum::AES256GCM(aes_gcm) => {
+                aes_gcm.decrypt_in_place_detached(nonce, &aad, &mut [], &tag)
+            }
+        };
+        if let Err(e) = rs {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("认证失败:{}", e),
+            ));
+        }
+        net_packet.set_encrypt_flag(false);
+        net_packet.set_auth_only_flag(false);
+        net_packet.set_data_len(net_packet.data_len() - AES_GCM_ENCRYPTION_RESERVED)?;
+        Ok(())
+    }
+}
+
+fn nonce_raw<B: AsRef<[u8]>>(net_packet: &NetPacket<B>) -> [u8; 12] {
+    let mut nonce_raw = [0; 12];
+    nonce_raw[0..4].copy_from_slice(&net_packet.source().octets());
+    nonce_raw[4..8].copy_from_slice(&net_packet.destination().octets());
+    nonce_raw[8] = net_packet.protocol().into();
+    nonce_raw[9] = net_packet.transport_protocol();
+    nonce_raw[10] = net_packet.is_gateway() as u8;
+    nonce_raw[11] = net_packet.source_ttl();
+    nonce_raw
+}
+
+#[test]
+fn test_auth_only() {
+    let cipher = AesGcmCipher::new_256([7; 32], Some(Finger::new("123")));
+    let mut buf = [0u8; 100];
+    buf[12..40].copy_from_slice(&[0xAB; 28]);
+    let mut p = NetPacket::new_encrypt(buf).unwrap();
+    p.set_default_version();
+    p.first_set_ttl(15);
+    let src = p.buffer().to_vec();
+    cipher.authenticate_ipv4(&mut p).unwrap();
+    // 载荷没有再加密一次
+    assert_eq!(&p.payload()[..src.len() - 12], &src[12..]);
+    assert!(p.is_encrypt());
+    assert!(p.is_auth_only());
+    // 转发修改ttl不影响认证
+    p.incr_ttl();
+    let tampered = p;
+    cipher.decrypt_ipv4(&mut p).unwrap();
+    assert!(!p.is_encrypt());
+    assert!(!p.is_auth_only());
+    assert_eq!(&p.buffer()[12..], &src[12..]);
+    // 修改载荷或协议头都无法通过认证
+    let mut t = tampered;
+    t.buffer_mut()[20] ^= 1;
+    assert!(cipher.decrypt_ipv4(&mut t).is_err());
+    let mut t = tampered;
+    t.set_source(std::net::Ipv4Addr::new(10, 0, 0, 9));
+    assert!(cipher.decrypt_ipv4(&mut t).is_err());
+    // 去掉只认证标志，当作普通加密包解密也会失败
+    let mut t = tampered;
+    t.set_auth_only_flag(false);
+    assert!(cipher.decrypt_ipv4(&mut t).is_err());
 }
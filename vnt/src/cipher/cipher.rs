@@ -290,6 +290,17 @@ impl Cipher {
             Cipher::None => Ok(()),
         }
     }
+    /// 只认证不加密，只有aes_gcm支持，其他方式仍然完整加密
+    pub fn authenticate_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+        if let Cipher::AesGcm((aes_gcm, _)) = self {
+            return aes_gcm.authenticate_ipv4(net_packet);
+        }
+        self.encrypt_ipv4(net_packet)
+    }
     #[cfg(not(any(
         feature = "aes_gcm",
         feature = "server_encrypt",
@@ -62,6 +62,9 @@ impl AesGcmCipher {
             log::error!("数据异常,长度小于{}", AES_GCM_ENCRYPTION_RESERVED);
             return Err(io::Error::new(io::ErrorKind::Other, "data err"));
         }
+        if net_packet.is_auth_only() {
+            return self.verify_ipv4(net_packet);
+        }
         let mut nonce_raw = [0; 12];
         nonce_raw[0..4].copy_from_slice(&net_packet.source().octets());
         nonce_raw[4..8].copy_from_slice(&net_packet.destination().octets());
@@ -147,4 +150,93 @@ impl AesGcmCipher {
             )),
         };
     }
+    /// 只认证不加密，载荷原样发送，见aes_gcm_cipher
+    pub fn authenticate_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        if net_packet.reserve() < AES_GCM_ENCRYPTION_RESERVED {
+            return Err(io::Error::new(io::ErrorKind::Other, "too short"));
+        }
+        // 标志也要参与认证，防止被改成普通加密包
+        net_packet.set_encrypt_flag(true);
+        net_packet.set_auth_only_flag(true);
+        let nonce_raw = nonce_raw(net_packet);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_raw);
+        let data_len = net_packet.data_len() + AES_GCM_ENCRYPTION_RESERVED;
+        net_packet.set_data_len(data_len)?;
+        let exist_finger = self.finger.is_some();
+        SecretBody::new(net_packet.payload_mut(), exist_finger)?
+            .set_random(rand::thread_rng().next_u32());
+        let aad =
+            SecretBody::new(net_packet.payload(), exist_finger)?.auth_only_aad(net_packet.head());
+        let aad = aead::Aad::from(aad.as_slice());
+        let rs = match &self.cipher {
+            AesGcmEnum::AesGCM128(cipher, _) => {
+                cipher.seal_in_place_separate_tag(nonce, aad, &mut [])
+            }
+            AesGcmEnum::AesGCM256(cipher, _) => {
+                cipher.seal_in_place_separate_tag(nonce, aad, &mut [])
+            }
+        };
+        match rs {
+            Ok(tag) => {
+                let mut secret_body = SecretBody::new(net_packet.payload_mut(), exist_finger)?;
+                secret_body.set_tag(tag.as_ref())?;
+                if let Some(finger) = &self.finger {
+                    let finger = finger.calculate_finger(&nonce_raw, secret_body.en_body());
+                    secret_body.set_finger(&finger)?;
+                }
+                Ok(())
+            }
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("认证失败:{}", e),
+            )),
+        }
+    }
+    fn verify_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        let nonce_raw = nonce_raw(net_packet);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce_raw);
+        let secret_body = SecretBody::new(net_packet.payload(), self.finger.is_some())?;
+        if let Some(finger) = &self.finger {
+            let finger = finger.calculate_finger(&nonce_raw, secret_body.en_body());
+            if &finger != secret_body.finger() {
+                return Err(io::Error::new(io::ErrorKind::Other, "ring aes finger err"));
+            }
+        }
+        // 没有密文，只有tag
+        let mut tag = [0u8; 16];
+        tag.copy_from_slice(secret_body.tag());
+        let aad = secret_body.auth_only_aad(net_packet.head());
+        let aad = aead::Aad::from(aad.as_slice());
+        let rs = match &self.cipher {
+            AesGcmEnum::AesGCM128(cipher, _) => cipher.open_in_place(nonce, aad, &mut tag),
+            AesGcmEnum::AesGCM256(cipher, _) => cipher.open_in_place(nonce, aad, &mut tag),
+        };
+        if let Err(e) = rs {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("认证失败:{}", e),
+            ));
+        }
+        net_packet.set_encrypt_flag(false);
+        net_packet.set_auth_only_flag(false);
+        net_packet.set_data_len(net_packet.data_len() - AES_GCM_ENCRYPTION_RESERVED)?;
+        Ok(())
+    }
+}
+
+fn nonce_raw<B: AsRef<[u8]>>(net_packet: &NetPacket<B>) -> [u8; 12] {
+    let mut nonce_raw = [0; 12];
+    nonce_raw[0..4].copy_from_slice(&net_packet.source().octets());
+    nonce_raw[4..8].copy_from_slice(&net_packet.destination().octets());
+    nonce_raw[8] = net_packet.protocol().into();
+    nonce_raw[9] = net_packet.transport_protocol();
+    nonce_raw[10] = net_packet.is_gateway() as u8;
+    nonce_raw[11] = net_packet.source_ttl();
+    nonce_raw
 }
@@ -41,6 +41,16 @@ impl RsaCipher {
     pub fn public_key(&self) -> io::Result<&RsaPublicKey> {
         return Ok(&self.inner.public_key);
     }
+    /// 公钥的der编码，热重启时交给新进程
+    pub fn der(&self) -> io::Result<Vec<u8>> {
+        match self.inner.public_key.to_public_key_der() {
+            Ok(der) => Ok(der.as_bytes().to_vec()),
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("to_public_key_der error {}", e),
+            )),
+        }
+    }
 }
 pub fn finger(public_key: &RsaPublicKey) -> io::Result<String> {
     match public_key.to_public_key_der() {
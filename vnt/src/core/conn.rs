@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::{Mutex, RwLock};
@@ -9,25 +10,67 @@ use rand::Rng;
 #[cfg(not(target_os = "android"))]
 use tun::device::IFace;
 
+use crate::channel::bring_up::BringUpInfo;
+use crate::channel::checksum::ChecksumMode;
 use crate::channel::context::ChannelContext;
+use crate::channel::diary::{DiaryEntry, DiaryEvent, EventFilter, HistoryEntry};
+use crate::channel::drop_reason::DropStat;
+use crate::channel::handover::{self, HANDOVER_WINDOW};
 use crate::channel::idle::Idle;
+use crate::channel::inbound_limit::Limit;
+use crate::channel::mtu_guard::MtuIncident;
+use crate::channel::p2p_estimate::{self, Estimate, EstimateInput, PairRecord, Side};
+use crate::channel::path_score::{PathChoice, PathScores, PathWeights, PeerPreference};
+use crate::channel::peer_feature::{Feature, FeatureOverride};
+use crate::channel::peer_stats::PeerTraffic;
+use crate::channel::probe_budget::ProbeStat;
 use crate::channel::punch::{NatInfo, Punch};
-use crate::channel::{init_channel, init_context, Route, RouteKey};
+use crate::channel::reverse::{Allowance, PendingRequest};
+use crate::channel::route_health::PathTransition;
+use crate::channel::socket_pool::SocketStat;
+use crate::channel::{init_channel, init_context, Route, RouteKey, UseChannelType};
 use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
-use crate::core::Config;
+use crate::core::{Config, SessionTicket, WarmState};
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
+use crate::handle::bandwidth::{BandwidthReport, BandwidthTest};
+use crate::handle::critical_notice::{CriticalNotice, NoticeKind, NoticeOutcome};
+#[cfg(feature = "server_encrypt")]
+use crate::handle::crypto_pool::{
+    CryptoPool, CRYPTO_QUEUE_CAPACITY, CRYPTO_TIMEOUT, CRYPTO_WORKERS,
+};
+use crate::handle::directory::{self, Directory};
+use crate::handle::dns::{self, DnsServer, DnsStats};
+use crate::handle::flow_table::{FlowInfo, FlowTable, FLOW_CAPACITY};
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchReceiver;
+use crate::handle::negative_path::{NegativePathCache, NoDirectEvidence};
+use crate::handle::notice::NoticeHolder;
+use crate::handle::ping::{EchoTest, PingReport, MAX_COUNT};
 use crate::handle::recv_data::RecvDataHandler;
-use crate::handle::{maintain, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::handle::reverse::send_reverse;
+use crate::handle::selftest::{SelfTest, SelfTestReport};
+use crate::handle::server_action::Action;
+use crate::handle::{
+    maintain, registrar, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo,
+};
+use crate::nat::upnp::{Mapper, Mapping};
 use crate::nat::NatTest;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{FeaturePacket, FEATURE_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+use crate::tun_tap_device::route_guard::RouteGuard;
 use crate::tun_tap_device::tun_create_helper::{DeviceAdapter, TunDeviceHelper};
-use crate::util::{
-    Scheduler, SingleU64Adder, StopManager, U64Adder, WatchSingleU64Adder, WatchU64Adder,
-};
-use crate::{nat, VntCallback};
+#[cfg(not(target_os = "android"))]
+use crate::tun_tap_device::tun_setup;
+use crate::util::fingerprint::device_fingerprint;
+use crate::util::health::{Health, ReadyCheck};
+use crate::util::metrics::{Counter, Sample};
+use crate::util::subsystem::{Probe, Subsystem, Subsystems, Switch};
+use crate::util::workers::Placement;
+use crate::util::{Scheduler, StopManager};
+use crate::{nat, NoticeInfo, VntCallback};
 #[cfg(not(target_os = "android"))]
 use crate::{tun_tap_device, DeviceInfo};
 
@@ -40,25 +83,68 @@ pub struct Vnt {
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     context: ChannelContext,
     peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
-    down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
+    down_count_watcher: Counter,
+    up_count_watcher: Counter,
     client_secret_hash: Option<[u8; 16]>,
+    notice: NoticeHolder,
+    client_cipher: Cipher,
+    server_cipher: Cipher,
+    #[cfg(feature = "server_encrypt")]
+    rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+    negative_path: NegativePathCache,
+    critical_notice: CriticalNotice,
+    flow_table: FlowTable,
+    #[cfg(not(target_os = "android"))]
+    device_adapter: DeviceAdapter,
+    route_guard: Option<RouteGuard>,
+    tun_fd: Option<i32>,
+    dns: Option<DnsServer>,
+    handover: Option<(SocketAddr, u64)>,
+    subsystems: Subsystems,
+    upnp: Option<Mapper>,
 }
 
 impl Vnt {
-    pub fn new<Call: VntCallback>(config: Config, callback: Call) -> anyhow::Result<Self> {
+    pub fn new<Call: VntCallback>(mut config: Config, callback: Call) -> anyhow::Result<Self> {
+        if config.observer {
+            // 观察者不打洞，对端的延迟通过服务器中继测量
+            config.use_channel_type = UseChannelType::Relay;
+        }
+        if config.takeover && !cfg!(target_os = "linux") {
+            Err(anyhow::anyhow!("takeover is only supported on linux"))?;
+        }
         log::info!("config:{:?}", config);
         //服务端非对称加密
         #[cfg(feature = "server_encrypt")]
         let rsa_cipher: Arc<Mutex<Option<RsaCipher>>> = Arc::new(Mutex::new(None));
+        // 热重启时沿用上一个进程和服务端的会话
+        let session = config
+            .warm_state
+            .as_ref()
+            .and_then(|v| v.session.clone())
+            .filter(|v| v.resumable(config.server_encrypt));
         //服务端对称加密
         let server_cipher: Cipher = if config.server_encrypt {
-            let mut key = [0u8; 32];
-            rand::thread_rng().fill(&mut key);
-            Cipher::new_key(key, config.token.clone())?
+            match session
+                .as_ref()
+                .and_then(|v| <[u8; 32]>::try_from(v.server_key.as_slice()).ok())
+            {
+                Some(key) => Cipher::new_key(key, config.token.clone())?,
+                None => {
+                    let mut key = [0u8; 32];
+                    rand::thread_rng().fill(&mut key);
+                    Cipher::new_key(key, config.token.clone())?
+                }
+            }
         } else {
             Cipher::None
         };
+        #[cfg(feature = "server_encrypt")]
+        if let Some(session) = &session {
+            if let Ok(cipher) = RsaCipher::new(&session.server_public_key) {
+                rsa_cipher.lock().replace(cipher);
+            }
+        }
         let finger = if config.finger {
             Some(config.token.clone())
         } else {
@@ -84,6 +170,14 @@ impl Vnt {
             config.device_id.clone(),
             config.server_address_str.clone(),
             config.name_servers.clone(),
+            config.existing_tun.is_some(),
+            config.observer,
+            if config.fingerprint {
+                device_fingerprint(&config.token, &config.device_id)
+            } else {
+                String::new()
+            },
+            config.ip_fallback,
         );
         // 服务停止管理器
         let stop_manager = {
@@ -102,6 +196,12 @@ impl Vnt {
                 v.clone()
             }
         });
+        if let Some(state) = &config.warm_state {
+            // 热重启沿用原来的端口，nat映射保持不变，对端不需要重新打洞
+            if !state.ports.is_empty() {
+                ports = state.ports.clone();
+            }
+        }
         if config.use_channel_type.is_only_relay() {
             //中继模式下只监听一个端口就够了
             ports.truncate(1);
@@ -109,15 +209,115 @@ impl Vnt {
         //通道上下文
         let (context, tcp_listener) = init_context(
             ports,
+            config.bind_ip,
             config.use_channel_type,
             config.first_latency,
             config.tcp,
             config.packet_loss_rate,
             config.packet_delay,
+            config.packet_hooks.clone(),
+            config.takeover,
         )?;
-        let local_ipv4 = nat::local_ipv4();
-        let local_ipv6 = nat::local_ipv6();
+        context
+            .peer_features
+            .set_require_encryption(config.require_encryption);
+        context
+            .peer_features
+            .set_allow_remote_plaintext(config.allow_peer_plaintext);
+        context.backpressure.set_enabled(config.tun_backpressure);
+        context
+            .bring_up
+            .set_limits(config.bring_up_relay, config.bring_up_timeout);
+        context.rate_limit.set_rate(config.rate_limit);
+        context.bandwidth.set_allow(config.bandwidth_probe);
+        context.cookie_guard.set_threshold(config.cookie_threshold);
+        if let Some(weights) = config.path_weights {
+            log::info!("路径权重 {}", weights);
+            context.route_table.set_path_weights(Some(weights));
+        }
+        if cfg!(not(target_os = "android")) || config.mtu.is_some() {
+            // android上网卡由应用创建，没有指定mtu时不检查
+            context.mtu_guard.set_device_mtu(config.device_mtu());
+        }
+        context.reorder.set_enabled(config.reorder);
+        context.probe_budget.set_kbps(config.probe_budget);
+        context
+            .inbound_limit
+            .set_default(Limit::new(config.inbound_limit.0, config.inbound_limit.1));
+        context
+            .inbound_limit
+            .set_auto_block(config.auto_block_floods);
+        context.socket_pool.set_limit(config.socket_limit);
+        context.broadcast.store(config.broadcast, Ordering::Relaxed);
+        context
+            .compact_encoding
+            .store(config.compact_encoding, Ordering::Relaxed);
+        context.reverse.set_strict(config.strict_inbound);
+        context.diary.set_mirror(config.diary_log);
+        context.diary.set_history_capacity(config.event_history);
+        context.transport.set_fallback(config.tcp_fallback);
+        context
+            .workers
+            .configure(config.pin_workers.clone(), config.worker_priority);
+        if let Some(path) = &config.intent_log {
+            // 创建网卡和添加路由之前处理上次异常退出留下的修改
+            tun_tap_device::intent_log::recover(&context.intent_log, path);
+        }
+        if !config.server_actions.is_empty() {
+            let names: Vec<&str> = config.server_actions.iter().map(|v| v.name()).collect();
+            log::warn!("允许服务端请求执行的操作: {}", names.join(","));
+            context.server_actions.set_allowed(&config.server_actions);
+        }
+        if !config.standby_servers.is_empty() {
+            if config.server_encrypt {
+                // 备用服务器上的注册不加密
+                log::warn!("服务端加密时不使用备用服务器");
+            } else {
+                context.rendezvous.set_standby(&config.standby_servers);
+            }
+        }
+        context.source_policy.set_policy(
+            AllowExternalRoute::new(config.out_ips.clone()),
+            config.snat_local,
+        );
+        context.checksum.set_mode(config.tun_checksum);
+        if let Some(state) = &config.warm_state {
+            // 注册完成前先使用上一个进程的ip和直连路由转发数据
+            let mut device_info = current_device.load();
+            device_info.update(
+                state.virtual_ip,
+                state.virtual_netmask,
+                state.virtual_gateway,
+            );
+            for (ip, path) in &state.routes {
+                // tcp连接无法继承
+                if !path.endpoint.is_tcp() && path.index < context.channel_num() {
+                    context.route_table.add_route(*ip, Route::from_path(path));
+                }
+            }
+            if let Some(session) = &session {
+                // 到服务端的路由也沿用了，直接继续心跳，不重新握手和注册
+                if context
+                    .route_table
+                    .route_one(&state.virtual_gateway)
+                    .is_some()
+                {
+                    device_info.connect_server = session.server;
+                    device_info.status = ConnectStatus::Connected;
+                    context.health.ready(ReadyCheck::Registered);
+                    log::info!("热重启沿用服务端会话 {}", session.server);
+                }
+            }
+            current_device.store(device_info);
+        }
+        // 绑定了本地ip时上报这个地址，和实际收发数据的网卡一致
+        let (local_ipv4, local_ipv6) = match config.bind_ip {
+            Some(IpAddr::V4(ip)) => (Some(ip), None),
+            Some(IpAddr::V6(ip)) => (None, Some(ip)),
+            None => (nat::local_ipv4(), nat::local_ipv6()),
+        };
         let udp_ports = context.main_local_udp_port()?;
+        let main_udp_port = udp_ports[0];
         let tcp_port = tcp_listener.local_addr()?.port();
         //nat检测工具
         let nat_test = NatTest::new(
@@ -129,14 +329,58 @@ impl Vnt {
             tcp_port,
         );
 
-        // pc上先创建虚拟网卡
+        // 创建网卡前记录到服务器的出口，路由回环时通过它添加主机路由
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        let original_hop = match config.server_address {
+            std::net::SocketAddr::V4(addr) => tun_tap_device::route_guard::query(*addr.ip())
+                .unwrap_or_else(|e| {
+                    log::warn!("查询到服务器的路由失败 {:?}", e);
+                    None
+                }),
+            std::net::SocketAddr::V6(_) => None,
+        };
+        // NAT-PMP发往到服务器的出口网关
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        let gateway = original_hop.as_ref().and_then(|hop| hop.gateway);
+        #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+        let gateway = None;
+        // pc上先创建虚拟网卡，观察者不创建
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-        let device = {
+        let device = if config.observer {
+            log::info!("观察者模式,不创建网卡");
+            None
+        } else {
             let device = tun_tap_device::create_device(&config)?;
             let tun_info = DeviceInfo::new(device.name()?, device.version()?);
             callback.create_tun(tun_info);
-            device
+            Some(device)
         };
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        let route_guard = match &device {
+            Some(device) => Some(RouteGuard::new(
+                device.name()?,
+                original_hop,
+                context.intent_log.clone(),
+            )),
+            None => None,
+        };
+        #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+        let route_guard: Option<RouteGuard> = None;
+        let upnp = if config.upnp && !config.use_channel_type.is_only_relay() {
+            // 映射需要几秒，不阻塞启动，成功之前按原来的方式打洞
+            let upnp = Mapper::new(main_udp_port, gateway, nat_test.clone());
+            let mapper = upnp.clone();
+            std::thread::Builder::new()
+                .name("upnp".into())
+                .spawn(move || mapper.map())?;
+            Some(upnp)
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let tun_fd = device.as_ref().map(|device| device.as_tun_fd().0);
+        #[cfg(not(target_os = "linux"))]
+        let tun_fd = None;
         // 定时器
         let scheduler = Scheduler::new(stop_manager.clone())?;
         let external_route = ExternalRoute::new(config.in_ips.clone());
@@ -156,15 +400,43 @@ impl Vnt {
         let (punch_sender, punch_receiver) = maintain::punch_channel();
         let peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>> =
             Arc::new(RwLock::new(HashMap::with_capacity(16)));
-        let down_counter =
-            U64Adder::with_capacity(config.ports.as_ref().map(|v| v.len()).unwrap_or_default() + 8);
-        let down_count_watcher = down_counter.watch();
+        let down_counter = context
+            .metrics
+            .counter("traffic_bytes", &[("direction", "down")]);
+        let down_count_watcher = down_counter.clone();
         let handshake = Handshake::new(
             #[cfg(feature = "server_encrypt")]
             rsa_cipher.clone(),
         );
-        let up_counter = SingleU64Adder::new();
-        let up_count_watcher = up_counter.watch();
+        let up_counter = context
+            .metrics
+            .counter("traffic_bytes", &[("direction", "up")]);
+        let up_count_watcher = up_counter.clone();
+        //服务端公告
+        let notice = NoticeHolder::new();
+        //无法直连的对端
+        let negative_path = NegativePathCache::new();
+        //需要对端确认的通知
+        let critical_notice = CriticalNotice::new();
+        //接收方向的流统计
+        let flow_table = {
+            // 运行中可以通过flow-notify子系统打开
+            let callback = callback.clone();
+            let notify: Arc<dyn Fn(FlowInfo) + Send + Sync> =
+                Arc::new(move |info| callback.new_flow(info));
+            let flow_table = FlowTable::new(config.flow_tracking, FLOW_CAPACITY, Some(notify));
+            flow_table.set_notify(config.flow_tracking && config.notify_flows);
+            flow_table
+        };
+        //服务端下发的设备列表，在单独的线程中应用
+        let directory = Directory::new(config.max_devices, &context.metrics);
+        directory::start(
+            directory.clone(),
+            context.clone(),
+            current_device.clone(),
+            device_list.clone(),
+            callback.clone(),
+        );
         let tun_helper = TunDeviceHelper::new(
             stop_manager.clone(),
             context.clone(),
@@ -178,6 +450,15 @@ impl Vnt {
             up_counter,
             device_list.clone(),
         );
+        //rsa握手在单独的线程中执行
+        #[cfg(feature = "server_encrypt")]
+        let crypto_pool = CryptoPool::new(
+            &stop_manager,
+            &context.metrics,
+            CRYPTO_WORKERS.min(config.parallel.max(1)),
+            CRYPTO_QUEUE_CAPACITY,
+            CRYPTO_TIMEOUT,
+        )?;
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         let device_adapter = DeviceAdapter::new(device.clone());
         #[cfg(target_os = "android")]
@@ -185,11 +466,13 @@ impl Vnt {
 
         let handler = RecvDataHandler::new(
             #[cfg(feature = "server_encrypt")]
-            rsa_cipher,
+            rsa_cipher.clone(),
+            #[cfg(feature = "server_encrypt")]
+            crypto_pool,
             server_cipher.clone(),
             client_cipher.clone(),
             current_device.clone(),
-            device_adapter,
+            device_adapter.clone(),
             device_list.clone(),
             config_info.clone(),
             nat_test.clone(),
@@ -202,8 +485,22 @@ impl Vnt {
             proxy_map.clone(),
             down_counter,
             handshake.clone(),
+            notice.clone(),
+            negative_path.clone(),
+            critical_notice.clone(),
+            flow_table.clone(),
+            directory,
         );
 
+        let handover = if config.takeover {
+            Some(handover::listen(
+                stop_manager.clone(),
+                handler.clone(),
+                context.clone(),
+            )?)
+        } else {
+            None
+        };
         //初始化网络数据通道
         let (udp_socket_sender, tcp_socket_sender) =
             init_channel(tcp_listener, context.clone(), stop_manager.clone(), handler)?;
@@ -215,10 +512,210 @@ impl Vnt {
             tcp_socket_sender.clone(),
             external_route.clone(),
             nat_test.clone(),
+            config.punch_port_window,
         );
 
         #[cfg(not(target_os = "android"))]
-        tun_helper.start(device)?;
+        if let Some(device) = device {
+            tun_helper.start(device)?;
+        }
+        if let Some(state) = &config.warm_state {
+            log::info!("热重启完成,数据面中断{:?}", state.elapsed());
+            // 网卡沿用上一个进程的地址，注册时地址不变不会再设置
+            context.health.ready(ReadyCheck::Tun);
+        }
+        if config.observer {
+            context.health.ready(ReadyCheck::Tun);
+        }
+        //可以单独启停的子系统
+        let subsystems = Subsystems::new();
+        {
+            let callback = callback.clone();
+            subsystems.set_listener(move |info| callback.subsystem(info));
+        }
+        for (name, check) in [
+            ("registration", ReadyCheck::Registered),
+            ("tun", ReadyCheck::Tun),
+            ("data-path", ReadyCheck::DataPath),
+        ] {
+            let health = context.health.clone();
+            subsystems.register(Subsystem::core(name, move || {
+                match health
+                    .readyz()
+                    .failed
+                    .into_iter()
+                    .find(|(v, _)| v == check.name())
+                {
+                    Some((_, reason)) => Probe::new(false, reason),
+                    None => Probe::new(true, ""),
+                }
+            }));
+        }
+        {
+            let (on, off, probe) = (flow_table.clone(), flow_table.clone(), flow_table.clone());
+            let flows = Subsystem::new(
+                "flows",
+                move || {
+                    on.set_enabled(true);
+                    Ok(())
+                },
+                move || off.set_enabled(false),
+                move || Probe::new(true, format!("{} flows", probe.len())),
+            );
+            subsystems.register(if config.flow_tracking {
+                flows
+            } else {
+                flows.stopped()
+            });
+            let (on, off) = (flow_table.clone(), flow_table.clone());
+            let notify = Subsystem::new(
+                "flow-notify",
+                move || {
+                    on.set_notify(true);
+                    Ok(())
+                },
+                move || off.set_notify(false),
+                || Probe::new(true, ""),
+            )
+            .depends_on("flows");
+            subsystems.register(if flow_table.is_notify() {
+                notify
+            } else {
+                notify.stopped()
+            });
+        }
+        {
+            let (on, off) = (context.clone(), context.clone());
+            let probe = Subsystem::new(
+                "bandwidth-probe",
+                move || {
+                    on.bandwidth.set_allow(true);
+                    Ok(())
+                },
+                move || off.bandwidth.set_allow(false),
+                || Probe::new(true, ""),
+            );
+            subsystems.register(if config.bandwidth_probe {
+                probe
+            } else {
+                probe.stopped()
+            });
+        }
+        if !config.use_channel_type.is_only_relay() {
+            let switch = Switch::new();
+            let start_mdns = {
+                let switch = switch.clone();
+                let stop_manager = stop_manager.clone();
+                let context = context.clone();
+                let current_device = current_device.clone();
+                let client_cipher = client_cipher.clone();
+                let token = config.token.clone();
+                let password = config.password.clone();
+                let name = config.name.clone();
+                move || {
+                    crate::handle::mdns::start(
+                        stop_manager.clone(),
+                        context.clone(),
+                        current_device.clone(),
+                        client_cipher.clone(),
+                        &token,
+                        password.as_deref(),
+                        &name,
+                        &switch,
+                    )
+                }
+            };
+            let running = config.mdns
+                && match start_mdns() {
+                    Ok(_) => true,
+                    Err(e) => {
+                        log::warn!("局域网发现启动失败 {:?}", e);
+                        false
+                    }
+                };
+            let lan_peers = context.clone();
+            let mdns = Subsystem::new(
+                "mdns",
+                start_mdns,
+                move || switch.stop(),
+                move || {
+                    Probe::new(
+                        true,
+                        format!("{} lan peers", lan_peers.lan_peers.list().len()),
+                    )
+                },
+            );
+            subsystems.register(if running { mdns } else { mdns.stopped() });
+        }
+        let dns = match config.dns_listen {
+            Some(listen) => {
+                let upstream = config
+                    .name_servers
+                    .iter()
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+                let server = DnsServer::new(
+                    dns::ZONE,
+                    config.dns_routes.clone(),
+                    upstream,
+                    dns::DeviceNames {
+                        name: config.name.clone(),
+                        current_device: current_device.clone(),
+                        device_list: device_list.clone(),
+                    },
+                );
+                let switch = Switch::new();
+                let listener = dns::Listener::new(listen);
+                let running =
+                    match dns::start(stop_manager.clone(), server.clone(), &listener, &switch) {
+                        Ok(addr) => {
+                            log::info!("dns服务 {} 规则 {:?}", addr, config.dns_routes);
+                            true
+                        }
+                        // 端口被占用时不影响组网，dns命令中显示未监听
+                        Err(e) => {
+                            log::warn!("dns服务启动失败 {} {:?}", listen, e);
+                            false
+                        }
+                    };
+                let (stop_manager, start_server, probe_server) =
+                    (stop_manager.clone(), server.clone(), server.clone());
+                let switch_ = switch.clone();
+                let subsystem = Subsystem::new(
+                    "dns",
+                    move || {
+                        dns::start(
+                            stop_manager.clone(),
+                            start_server.clone(),
+                            &listener,
+                            &switch_,
+                        )
+                        .map(|_| ())
+                    },
+                    move || switch.stop(),
+                    move || {
+                        let stats = probe_server.stats();
+                        match stats.listen {
+                            Some(addr) => Probe::new(
+                                true,
+                                format!(
+                                    "{} cache {}/{}",
+                                    addr, stats.cache_size, stats.cache_limit
+                                ),
+                            ),
+                            None => Probe::new(false, "not listening"),
+                        }
+                    },
+                );
+                subsystems.register(if running {
+                    subsystem
+                } else {
+                    subsystem.stopped()
+                });
+                Some(server)
+            }
+            None => None,
+        };
 
         maintain::idle_gateway(
             &scheduler,
@@ -228,6 +725,7 @@ impl Vnt {
             tcp_socket_sender.clone(),
             callback.clone(),
             0,
+            0,
             handshake,
         );
         {
@@ -238,15 +736,79 @@ impl Vnt {
             let up_count_watcher = up_count_watcher.clone();
             let config_info = config_info.clone();
             let current_device = current_device.clone();
-            if !config.use_channel_type.is_only_relay() {
+            let notice = notice.clone();
+            let negative_path = negative_path.clone();
+            let client_cipher = client_cipher.clone();
+            let server_cipher = server_cipher.clone();
+            let critical_notice = critical_notice.clone();
+            let udp_socket_sender = if !config.use_channel_type.is_only_relay() {
                 // 定时nat探测
                 maintain::retrieve_nat_type(
                     &scheduler,
                     context.clone(),
                     nat_test.clone(),
-                    udp_socket_sender,
+                    udp_socket_sender.clone(),
+                    device_list.clone(),
+                    critical_notice.clone(),
                 );
+                Some(udp_socket_sender)
+            } else {
+                None
+            };
+            let report_usage = config.report_usage;
+            if report_usage {
+                log::info!("已开启中继流量上报,只上报经过服务器中继的累计总量,不包含对端信息");
+            }
+            if let Some(route_guard) = route_guard.clone() {
+                // 路由回环检测
+                maintain::route_guard(
+                    &scheduler,
+                    context.clone(),
+                    current_device.clone(),
+                    route_guard,
+                    callback.clone(),
+                );
+            }
+            if !config.observer {
+                // 路径mtu黑洞检测
+                maintain::mtu_blackhole(&scheduler, context.clone(), callback.clone());
+            }
+            // 存活和就绪检查
+            maintain::health_check(&scheduler, context.clone(), callback.clone());
+            // 辅助socket泄漏检查
+            maintain::socket_audit(&scheduler, context.clone());
+            // 对端流量速率
+            maintain::peer_stats_sample(&scheduler, context.clone());
+            maintain::reverse_expire(&scheduler, context.clone());
+            if config.reorder {
+                // 路径切换期间的重排
+                maintain::reorder_flush(&scheduler, context.clone(), device_adapter.clone());
             }
+            let resume_context = maintain::ResumeContext {
+                context: context.clone(),
+                current_device_info: current_device.clone(),
+                device_list: device_list.clone(),
+                client_cipher: client_cipher.clone(),
+                server_cipher: server_cipher.clone(),
+                report_usage,
+                negative_path: negative_path.clone(),
+                nat_test: nat_test.clone(),
+                udp_socket_sender,
+                critical_notice: critical_notice.clone(),
+            };
+            if !config.server_actions.is_empty() {
+                // 执行服务端请求的操作
+                maintain::server_action(&scheduler, resume_context.clone(), callback.clone());
+            }
+            // 休眠唤醒检测
+            maintain::resume_check(
+                &scheduler,
+                maintain::resume_detector(),
+                resume_context,
+                callback.clone(),
+            );
+            let route_idle_timeout = config.route_idle_timeout;
+            let upnp = upnp.clone();
             //延迟启动
             scheduler.timeout(Duration::from_secs(3), move |scheduler| {
                 start(
@@ -263,6 +825,12 @@ impl Vnt {
                     callback,
                     down_count_watcher,
                     up_count_watcher,
+                    notice,
+                    negative_path,
+                    report_usage,
+                    critical_notice,
+                    route_idle_timeout,
+                    upnp,
                 );
             });
         }
@@ -278,6 +846,22 @@ impl Vnt {
             down_count_watcher,
             up_count_watcher,
             client_secret_hash: config_info.client_secret_hash,
+            notice,
+            client_cipher,
+            server_cipher,
+            #[cfg(feature = "server_encrypt")]
+            rsa_cipher,
+            negative_path,
+            critical_notice,
+            flow_table,
+            #[cfg(not(target_os = "android"))]
+            device_adapter,
+            route_guard,
+            tun_fd,
+            dns,
+            handover,
+            subsystems,
+            upnp,
         })
     }
 }
@@ -294,8 +878,14 @@ pub fn start<Call: VntCallback>(
     config_info: BaseConfigInfo,
     punch: Punch,
     callback: Call,
-    down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
+    down_count_watcher: Counter,
+    up_count_watcher: Counter,
+    notice: NoticeHolder,
+    negative_path: NegativePathCache,
+    report_usage: bool,
+    critical_notice: CriticalNotice,
+    route_idle_timeout: Duration,
+    upnp: Option<Mapper>,
 ) {
     // 定时心跳
     maintain::heartbeat(
@@ -305,9 +895,11 @@ pub fn start<Call: VntCallback>(
         device_list.clone(),
         client_cipher.clone(),
         server_cipher.clone(),
+        report_usage,
+        upnp,
     );
     // 路由空闲检测逻辑
-    let idle = Idle::new(Duration::from_secs(10), context.clone());
+    let idle = Idle::new(route_idle_timeout, context.clone());
     // 定时空闲检查
     maintain::idle_route(
         &scheduler,
@@ -316,6 +908,12 @@ pub fn start<Call: VntCallback>(
         current_device.clone(),
         callback,
     );
+    // icmp错误处理
+    maintain::icmp_error(
+        context.clone(),
+        current_device.clone(),
+        client_cipher.clone(),
+    );
     // 定时客户端中继检测
     if !context.use_channel_type().is_only_p2p() {
         maintain::client_relay(
@@ -347,8 +945,31 @@ pub fn start<Call: VntCallback>(
             client_cipher.clone(),
             punch_receiver,
             punch,
+            notice,
+            negative_path,
         );
+        if context.rendezvous.has_standby() {
+            // 备用服务器上的注册
+            maintain::standby(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                device_list.clone(),
+                config_info.clone(),
+                nat_test.clone(),
+                0,
+            );
+        }
     }
+    // 通知重传
+    maintain::critical_notice(
+        scheduler,
+        context.clone(),
+        current_device.clone(),
+        device_list.clone(),
+        client_cipher.clone(),
+        critical_notice,
+    );
     maintain::up_status(
         scheduler,
         context.clone(),
@@ -365,6 +986,125 @@ impl Vnt {
     pub fn server_encrypt(&self) -> bool {
         self.config.server_encrypt
     }
+    /// 当前是否通过tcp连接服务器，udp不通时会自动切换
+    pub fn is_main_tcp(&self) -> bool {
+        self.context.is_main_tcp()
+    }
+    /// 所有统计指标的快照
+    pub fn metrics(&self) -> Vec<Sample> {
+        self.context.metrics.snapshot()
+    }
+    /// 是否在服务端心跳中上报中继流量
+    pub fn report_usage(&self) -> bool {
+        self.config.report_usage
+    }
+    /// 经过服务器中继的累计流量(发送,接收)
+    /// 本机的端到端自检，指定对端时再检查到对端的回显和路径，会阻塞几秒
+    pub fn selftest(&self, peer: Option<Ipv4Addr>) -> SelfTestReport {
+        let device_list = self.device_list.lock().1.clone();
+        SelfTest {
+            context: &self.context,
+            current_device: self.current_device.load(),
+            client_cipher: &self.client_cipher,
+            server_cipher: &self.server_cipher,
+            device_list,
+            mtu: self.config.device_mtu(),
+            device: &self.device_adapter,
+        }
+        .run(peer)
+    }
+    /// 测量到对端的带宽，结果记录在路由上。需要对端同意，没有直连时需要include_relay
+    pub fn bandwidth(&self, ip: Ipv4Addr, include_relay: bool) -> anyhow::Result<BandwidthReport> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot measure {}", ip))?;
+        }
+        let report = BandwidthTest {
+            context: &self.context,
+            current_device,
+            client_cipher: &self.client_cipher,
+            mtu: self.config.device_mtu(),
+        }
+        .run(ip, include_relay)?;
+        Ok(report)
+    }
+    /// 用虚拟网络协议ping对端，有直连时走直连，否则经过服务器中继
+    pub fn ping(&self, ip: Ipv4Addr, count: u32) -> anyhow::Result<PingReport> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot ping {}", ip))?;
+        }
+        if count == 0 || count > MAX_COUNT {
+            Err(anyhow::anyhow!("count must be 1..={}", MAX_COUNT))?;
+        }
+        let report = EchoTest {
+            context: &self.context,
+            current_device,
+            client_cipher: &self.client_cipher,
+        }
+        .run(ip, count)?;
+        Ok(report)
+    }
+    /// 虚拟网卡的mtu，超过的包读取后丢弃，0表示不检查
+    pub fn device_mtu(&self) -> u32 {
+        self.context.mtu_guard.device_mtu()
+    }
+    /// 主端口实际绑定的本地地址
+    pub fn local_bind(&self) -> Vec<SocketAddr> {
+        self.context.main_local_udp_addr()
+    }
+    pub fn relay_usage(&self) -> (u64, u64) {
+        (self.context.relay_stats.tx(), self.context.relay_stats.rx())
+    }
+    /// 直连断开改用中继，或者刚切回直连
+    pub fn path_transition(&self, ip: &Ipv4Addr) -> Option<PathTransition> {
+        self.context.route_health.transition(ip)
+    }
+    /// 按对端统计的流量，按ip排序
+    pub fn peer_stats(&self) -> Vec<PeerTraffic> {
+        self.context.peer_stats.snapshot()
+    }
+    /// 所有对端流量的合计
+    pub fn peer_totals(&self) -> PeerTraffic {
+        self.context.peer_stats.totals()
+    }
+    /// 全局路径权重，None表示按延迟选路
+    pub fn path_weights(&self) -> Option<PathWeights> {
+        self.context.route_table.path_scores.weights()
+    }
+    /// 对端的路径偏好，None表示取消覆盖
+    pub fn set_path_preference(&self, ip: Ipv4Addr, preference: Option<PeerPreference>) {
+        self.context.route_table.set_path_preference(ip, preference)
+    }
+    /// 所有对端的路径偏好覆盖
+    pub fn path_preferences(&self) -> Vec<(Ipv4Addr, PeerPreference)> {
+        self.context.route_table.path_scores.peers()
+    }
+    /// 对端生效的路径权重，没有任何覆盖设置时返回None
+    pub fn effective_path_weights(&self, ip: &Ipv4Addr) -> Option<PathWeights> {
+        self.context.route_table.path_scores.effective(ip)
+    }
+    /// 路由的分数，越低越好，没有任何覆盖设置时返回None
+    pub fn path_score(&self, ip: Ipv4Addr, route: &Route) -> Option<f64> {
+        self.context.route_table.path_scores.score(ip, route)
+    }
+    /// 经过服务器中继的分数，服务器延迟未知时返回None
+    pub fn relay_score(&self, ip: &Ipv4Addr) -> Option<f64> {
+        let weights = self.context.route_table.path_scores.effective(ip)?;
+        let server_rt = self.context.server_rt.get();
+        if server_rt <= 0 {
+            return None;
+        }
+        Some(weights.score(&PathScores::relay_sample(server_rt)))
+    }
+    /// 发送数据时对这个对端的选择
+    pub fn path_choice(&self, ip: &Ipv4Addr) -> PathChoice {
+        self.context.route_table.path_scores.choice(ip)
+    }
+    /// 允许服务端请求执行的操作
+    pub fn allowed_server_actions(&self) -> Vec<Action> {
+        self.context.server_actions.allowed()
+    }
     pub fn client_encrypt(&self) -> bool {
         self.config.password.is_some()
     }
@@ -395,12 +1135,418 @@ impl Vnt {
     pub fn is_gateway(&self, ip: &Ipv4Addr) -> bool {
         self.current_device.load().is_gateway(ip)
     }
+    /// 距离最近一次从对端的路由收到数据过了多久，没有路由时为None
+    pub fn peer_last_seen(&self, ip: &Ipv4Addr) -> Option<Duration> {
+        self.context
+            .route_table
+            .last_read_time(ip)
+            .map(|time| time.elapsed())
+    }
     pub fn route_key(&self, route_key: &RouteKey) -> Option<Ipv4Addr> {
         self.context.route_table.route_to_id(route_key)
     }
     pub fn route_table(&self) -> Vec<(Ipv4Addr, Vec<Route>)> {
         self.context.route_table.route_table()
     }
+    /// 直连的对端中仍使用旧协议头的数量，为0时才能考虑移除旧协议
+    pub fn legacy_peer_num(&self) -> usize {
+        let current_device = self.current_device.load();
+        self.context
+            .route_table
+            .legacy_peer_num(|ip| current_device.is_gateway(ip))
+    }
+    /// 屏蔽对端，不能屏蔽网关和自己
+    pub fn block_peer(&self, ip: Ipv4Addr) -> anyhow::Result<bool> {
+        let fingerprint = self
+            .device_list
+            .lock()
+            .1
+            .iter()
+            .find(|v| v.virtual_ip == ip)
+            .map(|v| v.fingerprint.clone())
+            .unwrap_or_default();
+        self.block_device(ip, &fingerprint)
+    }
+    /// 按ip屏蔽并记录对端的指纹，恢复保存的屏蔽时使用
+    pub fn block_device(&self, ip: Ipv4Addr, fingerprint: &str) -> anyhow::Result<bool> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot block {}", ip))?;
+        }
+        Ok(self.context.block_peer(ip, fingerprint))
+    }
+    pub fn unblock_peer(&self, ip: &Ipv4Addr) -> bool {
+        self.context.unblock_peer(ip)
+    }
+    pub fn block_list(&self) -> Vec<Ipv4Addr> {
+        self.context.block_list.list()
+    }
+    /// 屏蔽的ip和屏蔽时对端的指纹，保存时使用
+    pub fn blocked_devices(&self) -> Vec<(Ipv4Addr, String)> {
+        self.context.block_list.entries()
+    }
+    /// 手动对指定设备打洞，会清除无法直连的判定
+    pub fn punch(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot punch {}", ip))?;
+        }
+        if self.context.use_channel_type().is_only_relay() {
+            Err(anyhow::anyhow!("p2p disabled"))?;
+        }
+        maintain::punch_now(
+            &self.context,
+            &self.nat_test,
+            &current_device,
+            &self.client_cipher,
+            &self.negative_path,
+            ip,
+        )?;
+        Ok(())
+    }
+    /// 入站改写过的包实际使用的校验和处理，auto模式在网卡就绪后探测
+    pub fn tun_checksum(&self) -> ChecksumMode {
+        self.context.checksum.effective()
+    }
+    pub fn strict_inbound(&self) -> bool {
+        self.context.reverse.strict()
+    }
+    /// 允许和对端直连并立即发起打洞，duration为0时使用默认时长，返回实际的时长
+    pub fn invite(&self, ip: Ipv4Addr, duration: Duration) -> anyhow::Result<Duration> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot invite {}", ip))?;
+        }
+        if self.context.use_channel_type().is_only_relay() {
+            Err(anyhow::anyhow!("p2p disabled"))?;
+        }
+        let duration = self.context.allow_peer(ip, duration);
+        maintain::punch_now(
+            &self.context,
+            &self.nat_test,
+            &current_device,
+            &self.client_cipher,
+            &self.negative_path,
+            ip,
+        )?;
+        Ok(duration)
+    }
+    /// 允许和对端直连但不主动打洞，duration为0时使用默认时长，返回实际的时长
+    pub fn allow_peer(&self, ip: Ipv4Addr, duration: Duration) -> Duration {
+        self.context.allow_peer(ip, duration)
+    }
+    /// 撤销直连许可，没有许可时返回false
+    pub fn revoke_peer(&self, ip: Ipv4Addr) -> bool {
+        self.context.revoke_peer(ip)
+    }
+    /// 经服务器请求严格入站的对端发起直连，对端批准后由它打洞
+    pub fn knock(&self, ip: Ipv4Addr, duration: Duration) -> anyhow::Result<()> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot knock {}", ip))?;
+        }
+        if self.context.use_channel_type().is_only_relay() {
+            Err(anyhow::anyhow!("p2p disabled"))?;
+        }
+        let nonce = rand::thread_rng().gen();
+        self.context.reverse.request(ip, nonce, Instant::now());
+        send_reverse(
+            &self.context,
+            &current_device,
+            &self.client_cipher,
+            control_packet::Protocol::ConnectIntent,
+            ip,
+            nonce,
+            duration,
+        )?;
+        Ok(())
+    }
+    /// 等待批准的连接请求
+    pub fn connect_requests(&self) -> Vec<PendingRequest> {
+        self.context.reverse.pending(Instant::now())
+    }
+    /// 批准连接请求，回复对端后发起打洞；duration为None时使用请求的时长
+    pub fn accept_request(
+        &self,
+        id: u32,
+        duration: Option<Duration>,
+    ) -> anyhow::Result<(Ipv4Addr, Duration)> {
+        let (ip, nonce, duration) = match self.context.reverse.accept(id, duration, Instant::now())
+        {
+            Some(v) => v,
+            None => Err(anyhow::anyhow!("no pending request {}", id))?,
+        };
+        self.context
+            .diary
+            .record(ip, DiaryEvent::Allowed { duration });
+        let current_device = self.current_device.load();
+        send_reverse(
+            &self.context,
+            &current_device,
+            &self.client_cipher,
+            control_packet::Protocol::ConnectApprove,
+            ip,
+            nonce,
+            duration,
+        )?;
+        maintain::punch_now(
+            &self.context,
+            &self.nat_test,
+            &current_device,
+            &self.client_cipher,
+            &self.negative_path,
+            ip,
+        )?;
+        Ok((ip, duration))
+    }
+    /// 生效中的直连许可
+    pub fn allowances(&self) -> Vec<Allowance> {
+        self.context.reverse.allowances(Instant::now())
+    }
+    /// 手动修改和指定设备之间的加密，对端同意后对之后的数据生效
+    ///
+    /// 返回对端是否同意，没有回复时保持原来的设置
+    pub fn set_peer_feature(
+        &self,
+        ip: Ipv4Addr,
+        feature: Feature,
+        enabled: bool,
+    ) -> anyhow::Result<bool> {
+        let current_device = self.current_device.load();
+        if current_device.is_gateway(&ip) || current_device.virtual_ip == ip {
+            Err(anyhow::anyhow!("cannot change {}", ip))?;
+        }
+        if feature == Feature::Encrypt && !self.client_encrypt() {
+            Err(anyhow::anyhow!("encryption is not configured"))?;
+        }
+        let features = &self.context.peer_features;
+        let seq = features.request(ip, feature, enabled)?;
+        let mut packet = NetPacket::new_encrypt([0; 12 + FEATURE_LEN + ENCRYPTION_RESERVED])?;
+        packet.set_default_version();
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::FeatureRequest.into());
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_source(current_device.virtual_ip);
+        packet.set_destination(ip);
+        let mut feature_packet = FeaturePacket::new(packet.payload_mut())?;
+        feature_packet.set_seq(seq);
+        feature_packet.set_feature(feature.into());
+        feature_packet.set_enabled(enabled);
+        self.client_cipher.encrypt_ipv4(&mut packet)?;
+        for _ in 0..3 {
+            if let Err(e) = self.context.send_ipv4_by_id(
+                packet.buffer(),
+                &ip,
+                current_device.connect_server,
+                current_device.status.online(),
+            ) {
+                log::warn!("{}:{:?}", ip, e);
+            }
+            for _ in 0..50 {
+                if let Some(accepted) = features.result(ip, feature, seq) {
+                    return Ok(accepted);
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+        features.cancel(ip, feature, seq);
+        Err(anyhow::anyhow!("{} did not respond", ip))
+    }
+    /// 接收方向的活跃流，按近期流量排序，关闭流统计时为空
+    pub fn connections(&self) -> Vec<FlowInfo> {
+        self.flow_table.list()
+    }
+    pub fn flow_tracking(&self) -> bool {
+        self.flow_table.is_enabled()
+    }
+    /// 单独要求和对端之间加密，不允许任何一方关闭
+    pub fn set_peer_encryption_required(&self, ip: Ipv4Addr, required: bool) {
+        self.context.peer_features.set_required(ip, required)
+    }
+    /// 手动调整过的对端功能
+    pub fn peer_features(&self) -> Vec<FeatureOverride> {
+        self.context.peer_features.list()
+    }
+    /// 热重启时交给新进程的状态，需要在注册成功后调用
+    pub fn warm_state(&self) -> anyhow::Result<WarmState> {
+        let current_device = self.current_device.load();
+        if current_device.status.offline() {
+            Err(anyhow::anyhow!("not connected"))?;
+        }
+        let mut routes = Vec::new();
+        for (ip, list) in self.context.route_table.route_table() {
+            for route in list {
+                // tcp连接无法交给新进程
+                if !route.is_tcp && route.metric == 1 {
+                    routes.push((ip, route.path()));
+                }
+            }
+        }
+        // tcp连接无法交给新进程，需要重新注册
+        let session = if self.context.is_main_tcp() {
+            None
+        } else {
+            #[cfg(feature = "server_encrypt")]
+            let server_public_key = match self.rsa_cipher.lock().as_ref() {
+                Some(cipher) => cipher.der()?,
+                None => Vec::new(),
+            };
+            #[cfg(not(feature = "server_encrypt"))]
+            let server_public_key = Vec::new();
+            Some(SessionTicket {
+                server: current_device.connect_server,
+                server_key: self.server_cipher.key().map_or(Vec::new(), |v| v.to_vec()),
+                server_public_key,
+            })
+        };
+        Ok(WarmState {
+            saved_at: WarmState::now_millis(),
+            ports: self.context.main_local_udp_port()?,
+            virtual_ip: current_device.virtual_ip,
+            virtual_netmask: current_device.virtual_netmask,
+            virtual_gateway: current_device.virtual_gateway,
+            routes,
+            tun_fd: self.tun_fd,
+            session,
+        })
+    }
+    /// 接收旧进程转发数据的本地地址和令牌，开启接管时才有
+    pub fn handover_endpoint(&self) -> Option<(SocketAddr, u64)> {
+        self.handover
+    }
+    /// 交给接管的新进程，转发收到的数据，一段时间后停止
+    ///
+    /// 不通知对端下线，也不恢复路由，网卡和路由由新进程继续使用
+    pub fn hand_over(&self, target: SocketAddr, token: u64) -> anyhow::Result<()> {
+        self.context.handover.start(target, token)?;
+        log::info!("交给新进程 {}", target);
+        let stop_manager = self.stop_manager.clone();
+        let context = self.context.clone();
+        std::thread::Builder::new()
+            .name("handover".into())
+            .spawn(move || {
+                std::thread::sleep(HANDOVER_WINDOW);
+                log::info!("接管完成,转发{}个包", context.handover.forwarded());
+                stop_manager.stop();
+            })?;
+        Ok(())
+    }
+    /// 估计和对端能否直连，依据双方的nat、策略和同类nat组合的历史结果
+    pub fn estimate(&self, ip: &Ipv4Addr) -> Estimate {
+        let local = self.nat_info();
+        let peer = self.peer_nat_info(ip);
+        let evidence = self.negative_path.check(ip);
+        let punch_failures = match evidence {
+            Some(NoDirectEvidence::PunchFailures(count)) => count,
+            _ => 0,
+        };
+        let history = peer.as_ref().and_then(|peer| {
+            self.context
+                .punch_history
+                .get(local.nat_type, peer.nat_type)
+        });
+        p2p_estimate::estimate(&EstimateInput {
+            local: Side::new(
+                Some(&local),
+                self.context.use_channel_type().is_only_relay(),
+            ),
+            peer: Side::new(peer.as_ref(), evidence == Some(NoDirectEvidence::RelayOnly)),
+            punch_model: self.config.punch_model,
+            lan: self.context.lan_peers.get(ip).is_some(),
+            punch_failures,
+            history,
+        })
+    }
+    /// 按nat组合统计的打洞结果
+    pub fn punch_records(&self) -> Vec<PairRecord> {
+        self.context.punch_history.records()
+    }
+    /// 判定为无法直连的设备及依据
+    pub fn no_direct_list(&self) -> Vec<(Ipv4Addr, NoDirectEvidence)> {
+        self.negative_path.list()
+    }
+    /// 按原因统计的丢包数
+    pub fn drop_stats(&self) -> Vec<DropStat> {
+        self.context.drop_stats.snapshot()
+    }
+    /// 单独设置对端的接收限速，None恢复默认
+    pub fn set_inbound_limit(&self, peer: Ipv4Addr, limit: Option<Limit>) {
+        self.context.inbound_limit.set_peer(peer, limit);
+    }
+    /// 默认的接收限速
+    pub fn inbound_limit(&self) -> Limit {
+        self.context.inbound_limit.default_limit()
+    }
+    /// 单独设置过接收限速或被自动屏蔽的对端
+    pub fn inbound_limits(&self) -> Vec<(Ipv4Addr, Limit, bool, Option<Duration>)> {
+        self.context.inbound_limit.list()
+    }
+    /// 存活和就绪状态
+    pub fn health(&self) -> Health {
+        self.context.health.clone()
+    }
+    /// 各类后台探测的发送和跳过次数
+    pub fn probe_stats(&self) -> Vec<ProbeStat> {
+        self.context.probe_budget.snapshot()
+    }
+    /// 可以单独启停的子系统，应用自己的组件也可以注册
+    pub fn subsystems(&self) -> &Subsystems {
+        &self.subsystems
+    }
+    /// 路由器上生效的端口映射，没有开启或者映射失败时返回None
+    pub fn upnp(&self) -> Option<Mapping> {
+        self.upnp.as_ref().and_then(|upnp| upnp.mapping())
+    }
+    /// dns转发规则和缓存的统计，没有启动dns服务时返回None
+    pub fn dns_stats(&self) -> Option<DnsStats> {
+        self.dns.as_ref().map(|dns| dns.stats())
+    }
+    /// 打洞、nat探测使用的辅助socket
+    pub fn socket_stats(&self) -> Vec<SocketStat> {
+        self.context.socket_pool.stats()
+    }
+    /// 辅助socket的数量上限，0表示不限制
+    /// 工作线程的核心和优先级
+    pub fn thread_placements(&self) -> Vec<Placement> {
+        self.context.workers.placements()
+    }
+    pub fn socket_limit(&self) -> usize {
+        self.context.socket_pool.limit()
+    }
+    /// 后台探测的预算，kbps，0表示不限制
+    pub fn probe_budget(&self) -> u64 {
+        self.context.probe_budget.kbps()
+    }
+    /// 最近的路径mtu黑洞记录
+    pub fn mtu_incidents(&self) -> Vec<MtuIncident> {
+        self.context.mtu_guard.incidents()
+    }
+    /// 新对端的建立过程和各阶段耗时
+    pub fn bring_up_list(&self) -> Vec<(Ipv4Addr, BringUpInfo)> {
+        self.context.bring_up.list()
+    }
+    /// 对端的连接日记，按时间顺序
+    pub fn diary(&self, ip: &Ipv4Addr) -> Vec<DiaryEntry> {
+        self.context.diary.entries(ip)
+    }
+    /// 有日记的对端
+    pub fn diary_peers(&self) -> Vec<Ipv4Addr> {
+        self.context.diary.peers()
+    }
+    /// 所有对端的事件历史中满足条件的事件，按时间顺序
+    pub fn events(&self, filter: &EventFilter) -> Vec<HistoryEntry> {
+        self.context.diary.history(filter)
+    }
+    /// 清除对端在内存中留下的日记和屏蔽，对端不再使用时调用，在线的对端会重新建立连接状态
+    pub fn forget_peer(&self, ip: &Ipv4Addr) {
+        // 解除屏蔽会写日记，先解除再删日记
+        self.context.unblock_peer(ip);
+        self.context.diary.forget(ip);
+    }
+    /// 限制发往虚拟网络的速率，单位字节/秒，0表示不限制
+    pub fn set_rate_limit(&self, bytes_per_sec: u64) {
+        self.context.rate_limit.set_rate(bytes_per_sec);
+    }
     pub fn up_stream(&self) -> u64 {
         self.up_count_watcher.get()
     }
@@ -408,15 +1554,103 @@ impl Vnt {
         self.down_count_watcher.get()
     }
     pub fn stop(&self) {
+        self.notify_offline();
+        self.deregister();
+        if let Some(route_guard) = &self.route_guard {
+            route_guard.release();
+        }
+        if let Some(upnp) = &self.upnp {
+            upnp.release();
+        }
+        self.remove_tun_routes();
+        // 停止收发线程，windows上同时关闭网卡
         self.stop_manager.stop()
     }
+    /// 通知服务端注销，其他设备在下一个纪元就能看到下线，不等待回应
+    fn deregister(&self) {
+        let current_device = self.current_device.load();
+        if self.context.is_stop() || !current_device.status.online() {
+            return;
+        }
+        let rs = registrar::deregistration_packet(&self.server_cipher, current_device.virtual_ip)
+            .and_then(|packet| {
+                self.context
+                    .send_default(packet.buffer(), current_device.connect_server)
+            });
+        if let Err(e) = rs {
+            log::warn!("注销 {:?}", e);
+        }
+    }
+    /// 删除本次添加到网卡的路由，重复调用时不再处理
+    fn remove_tun_routes(&self) {
+        #[cfg(not(target_os = "android"))]
+        if let Some(device) = self.device_adapter.tun() {
+            let routes = std::mem::take(&mut *self.context.tun_routes.lock());
+            if routes.is_empty() {
+                return;
+            }
+            let failed = tun_setup::teardown(&**device, &routes, &self.context.intent_log);
+            log::info!("删除路由 {}/{}", routes.len() - failed, routes.len());
+        }
+    }
+    /// 停止前通知在线的对端，等待确认直到截止时间，未完成的通知被取消
+    fn notify_offline(&self) {
+        let current_device = self.current_device.load();
+        if self.context.is_stop() || !current_device.status.online() {
+            return;
+        }
+        let peers = maintain::online_peers(&self.device_list);
+        if peers.is_empty() {
+            return;
+        }
+        let kind = NoticeKind::Offline;
+        let id = match self.critical_notice.send(kind, peers) {
+            Ok(id) => id,
+            Err(e) => {
+                log::warn!("下线通知 {:?}", e);
+                return;
+            }
+        };
+        let all_done = self.critical_notice.flush(
+            kind.deadline(),
+            |ip| maintain::is_online(&self.device_list, ip),
+            |id, kind, ip| {
+                maintain::send_notice(
+                    &self.context,
+                    &current_device,
+                    &self.client_cipher,
+                    id,
+                    kind,
+                    ip,
+                )
+            },
+        );
+        if !all_done {
+            self.critical_notice.cancel_all();
+        }
+        if let Some(result) = self.critical_notice.outcome(id) {
+            let delivered = result
+                .outcomes
+                .iter()
+                .filter(|(_, outcome)| *outcome == NoticeOutcome::Delivered)
+                .count();
+            log::info!("下线通知完成 {}/{}", delivered, result.outcomes.len());
+        }
+    }
     pub fn wait(&self) {
         self.stop_manager.wait()
     }
+    pub fn is_stopped(&self) -> bool {
+        self.stop_manager.is_stop()
+    }
     pub fn wait_timeout(&self, dur: Duration) -> bool {
         self.stop_manager.wait_timeout(dur)
     }
     pub fn config(&self) -> &Config {
         &self.config
     }
+    /// 最近一次收到的服务端公告
+    pub fn notice(&self) -> Option<NoticeInfo> {
+        self.notice.get()
+    }
 }
@@ -1,15 +1,41 @@
 use anyhow::anyhow;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub use conn::Vnt;
+pub use warm_restart::{SessionTicket, WarmState};
 
+use crate::channel::checksum::ChecksumMode;
 use crate::channel::punch::PunchModel;
 use crate::channel::UseChannelType;
 use crate::cipher::CipherModel;
+use crate::handle::packet_hook::PacketHooks;
+use crate::tun_tap_device::existing_tun::ExistingTun;
+use crate::util::workers::{PinSpec, Priority};
 use crate::util::{address_choose, dns_query_all};
 
 mod conn;
+mod warm_restart;
+
+/// 启动时的错误，调用方据此区分可以重试的情况，不需要匹配错误信息
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    /// 解析服务器地址失败，开机时网络还没有就绪、dns短暂不可用时都会出现，可以重试
+    #[error("resolve server address {server} failed: {cause}")]
+    Resolve { server: String, cause: String },
+}
+
+/// 解析服务器地址，多个地址时按历史选择
+pub fn resolve_server(server: &str, name_servers: &[String]) -> Result<SocketAddr, StartError> {
+    dns_query_all(server, name_servers.to_vec())
+        .and_then(address_choose)
+        .map_err(|e| StartError::Resolve {
+            server: server.to_string(),
+            cause: format!("{:#}", e),
+        })
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -46,6 +72,96 @@ pub struct Config {
     // 端口映射
     #[cfg(feature = "port_mapping")]
     pub port_mapping_list: Vec<(bool, SocketAddr, String)>,
+    // 热重启时上一个进程留下的状态
+    pub warm_state: Option<WarmState>,
+    // 在服务端心跳中上报中继流量
+    pub report_usage: bool,
+    // 使用预先创建的tun网卡，不创建网卡也不安装路由
+    pub existing_tun: Option<ExistingTun>,
+    // 不允许对单个对端关闭加密
+    pub require_encryption: bool,
+    // 同意对端发起的关闭加密，默认拒绝
+    pub allow_peer_plaintext: bool,
+    // 统计接收方向的流，关闭可以减少开销
+    pub flow_tracking: bool,
+    // 首次收到对端发起的流时回调
+    pub notify_flows: bool,
+    // 数据包钩子，只能通过库接口设置
+    pub packet_hooks: PacketHooks,
+    // 观察者模式，只同步设备列表和延迟，不创建网卡，不打洞，不转发数据
+    pub observer: bool,
+    // 注册时公开设备指纹，关闭时发送空值
+    pub fingerprint: bool,
+    // 出口已满时暂停读取网卡，关闭则继续读取并丢包
+    pub tun_backpressure: bool,
+    // 和新对端建立直连期间最多中继的数据包数量，0表示不限制
+    pub bring_up_relay: u32,
+    // 超过这个时间还没有建立直连就当作中继对端
+    pub bring_up_timeout: Duration,
+    // 本机发出的包源地址不是虚拟ip时改成虚拟ip，关闭则丢弃
+    pub snat_local: bool,
+    // 入站改写过的包写入网卡前的校验和处理，auto在网卡就绪后探测
+    pub tun_checksum: ChecksumMode,
+    // 发往虚拟网络的流量限速，字节/秒，0表示不限制
+    pub rate_limit: u64,
+    // 在局域网内公告虚拟ip并发现其他设备
+    pub mdns: bool,
+    // 路径切换期间给数据包带上序号，接收端按序号重排
+    pub reorder: bool,
+    // 心跳、打洞等后台探测的总流量上限，kbps，0表示不限制
+    pub probe_budget: u64,
+    // 每个对端的接收速率上限，包/秒和字节/秒，0表示不限制
+    pub inbound_limit: (u64, u64),
+    // 持续超过接收限速的对端自动屏蔽一段时间
+    pub auto_block_floods: bool,
+    // 本地dns服务的监听地址，None表示不启动
+    pub dns_listen: Option<SocketAddr>,
+    // 按域名后缀转发到对端dns服务的规则
+    pub dns_routes: Vec<crate::handle::dns::DnsRoute>,
+    // 打洞、nat探测等辅助socket的数量上限，0表示不限制
+    pub socket_limit: usize,
+    // 转发广播和组播，关闭时丢弃发往网段广播地址和网络地址的包
+    pub broadcast: bool,
+    // 允许新进程接管，主端口设置SO_REUSEPORT并接收旧进程转发的数据，只支持linux
+    pub takeover: bool,
+    // 把对端日记同时以debug级别写入日志
+    pub diary_log: bool,
+    // 内存中保留的所有对端的事件数，0表示不保留
+    pub event_history: usize,
+    // 接受服务端下发的设备数上限，超过的部分丢弃
+    pub max_devices: usize,
+    // 修改系统路由前写入的日志，异常退出后下次启动时据此清理，None时不记录
+    pub intent_log: Option<PathBuf>,
+    // 只用于打洞协商的备用服务器，最多2个，服务端加密时不使用
+    pub standby_servers: Vec<SocketAddr>,
+    // udp连续这么多次连接服务器失败后改用tcp，tcp也连续失败时换回udp，0表示不切换
+    pub tcp_fallback: usize,
+    // 工作线程绑定的核心，为空时不绑定
+    pub pin_workers: PinSpec,
+    // 工作线程的优先级
+    pub worker_priority: Priority,
+    // '--ip'被占用或者不在网段内时改为由服务端分配，不开启时停止运行
+    pub ip_fallback: bool,
+    // 主端口绑定的本地ip，多网卡时选择出口，为空时监听所有地址
+    pub bind_ip: Option<IpAddr>,
+    // 同意对端发起的带宽测量
+    pub bandwidth_probe: bool,
+    // 允许服务端请求执行的操作，为空时全部拒绝
+    pub server_actions: Vec<crate::handle::server_action::Action>,
+    // 路径评分的权重，None时按延迟选路
+    pub path_weights: Option<crate::channel::path_score::PathWeights>,
+    // 路由超过这个时间没有收到数据就删除，直连删除后改用中继
+    pub route_idle_timeout: Duration,
+    // 每秒未验证的直连请求超过这个数量时要求对端回显cookie，0表示不检查
+    pub cookie_threshold: u32,
+    // 对称nat端口预测扫描的范围，0表示只打观察到的端口
+    pub punch_port_window: u16,
+    // 声明支持紧凑编码的设备列表和打洞信息，对方不支持时仍使用protobuf
+    pub compact_encoding: bool,
+    // 启动时通过UPnP或NAT-PMP在路由器上映射主udp端口
+    pub upnp: bool,
+    // 严格入站，只和invite或批准过连接请求的对端直连
+    pub strict_inbound: bool,
 }
 
 impl Config {
@@ -99,8 +215,7 @@ impl Config {
         if name.is_empty() || name.len() > 128 {
             return Err(anyhow!("name too long"));
         }
-        let server_address =
-            address_choose(dns_query_all(&server_address_str, name_servers.clone())?)?;
+        let server_address = resolve_server(&server_address_str, &name_servers)?;
         #[cfg(feature = "port_mapping")]
         let port_mapping_list = crate::port_mapping::convert(port_mapping_list)?;
 
@@ -140,10 +255,81 @@ impl Config {
             packet_delay,
             #[cfg(feature = "port_mapping")]
             port_mapping_list,
+            warm_state: None,
+            report_usage: false,
+            existing_tun: None,
+            require_encryption: false,
+            allow_peer_plaintext: false,
+            flow_tracking: true,
+            notify_flows: false,
+            packet_hooks: PacketHooks::default(),
+            observer: false,
+            fingerprint: true,
+            tun_backpressure: true,
+            bring_up_relay: crate::channel::bring_up::DEFAULT_MAX_RELAY,
+            bring_up_timeout: crate::channel::bring_up::DEFAULT_TIMEOUT,
+            route_idle_timeout: crate::channel::idle::DEFAULT_READ_IDLE,
+            cookie_threshold: crate::channel::cookie::DEFAULT_THRESHOLD,
+            punch_port_window: crate::channel::punch::DEFAULT_PORT_WINDOW,
+            compact_encoding: false,
+            upnp: false,
+            strict_inbound: false,
+            snat_local: false,
+            tun_checksum: ChecksumMode::Auto,
+            rate_limit: 0,
+            mdns: false,
+            reorder: false,
+            probe_budget: crate::channel::probe_budget::DEFAULT_KBPS,
+            inbound_limit: (
+                crate::channel::inbound_limit::DEFAULT_PPS,
+                crate::channel::inbound_limit::DEFAULT_BPS,
+            ),
+            auto_block_floods: false,
+            dns_listen: None,
+            dns_routes: Vec::new(),
+            socket_limit: crate::channel::socket_pool::DEFAULT_LIMIT,
+            broadcast: true,
+            takeover: false,
+            diary_log: false,
+            event_history: crate::channel::diary::HISTORY_CAPACITY,
+            max_devices: crate::handle::directory::DEFAULT_MAX_DEVICES,
+            intent_log: None,
+            standby_servers: Vec::new(),
+            tcp_fallback: crate::channel::transport::DEFAULT_FALLBACK,
+            pin_workers: PinSpec::default(),
+            worker_priority: Priority::Normal,
+            ip_fallback: false,
+            bind_ip: None,
+            bandwidth_probe: true,
+            server_actions: Vec::new(),
+            path_weights: None,
         })
     }
 }
 impl Config {
+    /// 虚拟网卡的mtu，没有指定时加密需要多预留一些
+    pub fn device_mtu(&self) -> u32 {
+        self.mtu
+            .unwrap_or(if self.password.is_none() { 1450 } else { 1410 })
+    }
+    /// 解析备用服务器地址，和主服务器相同的忽略
+    pub fn set_standby_servers(&mut self, servers: &[String]) -> anyhow::Result<()> {
+        if servers.len() > crate::channel::rendezvous::MAX_STANDBY {
+            return Err(anyhow!(
+                "at most {} standby servers",
+                crate::channel::rendezvous::MAX_STANDBY
+            ));
+        }
+        let mut list = Vec::with_capacity(servers.len());
+        for server in servers {
+            let addr = address_choose(dns_query_all(server, self.name_servers.clone())?)?;
+            if addr != self.server_address && !list.contains(&addr) {
+                list.push(addr);
+            }
+        }
+        self.standby_servers = list;
+        Ok(())
+    }
     #[cfg(any(
         feature = "aes_gcm",
         feature = "server_encrypt",
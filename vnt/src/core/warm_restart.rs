@@ -0,0 +1,315 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::Digest;
+
+use crate::channel::endpoint::{Endpoint, PathState, PeerPath};
+
+const STATE_VERSION: u32 = 1;
+
+/// 热重启时交给新进程的状态
+///
+/// 只保存能在新进程中直接复用的内容：监听端口(保持nat映射不变)、虚拟ip、
+/// udp直连路由、tun描述符和服务端会话。用token和密码派生的key做完整性校验，
+/// 防止被篡改或者被不同组网的进程误用；会话中的密钥用同一个key掩码后保存
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WarmState {
+    // 保存时间，unix时间戳毫秒
+    pub saved_at: u64,
+    pub ports: Vec<u16>,
+    pub virtual_ip: Ipv4Addr,
+    pub virtual_netmask: Ipv4Addr,
+    pub virtual_gateway: Ipv4Addr,
+    // (目标ip,直连路径)
+    pub routes: Vec<(Ipv4Addr, PeerPath)>,
+    // 继承给新进程的tun描述符，只在linux上使用
+    pub tun_fd: Option<i32>,
+    pub session: Option<SessionTicket>,
+}
+
+/// 和服务端的会话，新进程沿用后不需要重新握手和注册
+///
+/// 只在通过udp连接服务端时保存，端口不变时服务端看到的还是同一个客户端，
+/// 新进程直接继续心跳。服务端不认可时心跳超时，按正常流程重连
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionTicket {
+    pub server: SocketAddr,
+    // 和服务端协商的对称密钥，没有开启服务端加密时为空
+    pub server_key: Vec<u8>,
+    // 服务端公钥(der)，服务端丢失密钥时用来重新上传
+    pub server_public_key: Vec<u8>,
+}
+
+impl SessionTicket {
+    /// 和当前配置的服务端加密一致时才能沿用
+    pub fn resumable(&self, server_encrypt: bool) -> bool {
+        self.server_key.is_empty() != server_encrypt
+    }
+}
+
+impl WarmState {
+    /// 状态文件的有效期，超过这个时间对端已经感知到中断，按冷启动处理
+    pub const MAX_AGE: Duration = Duration::from_secs(10);
+    pub fn now_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_millis() as u64)
+            .unwrap_or(0)
+    }
+    /// 从保存到现在经过的时间
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(Self::now_millis().saturating_sub(self.saved_at))
+    }
+    pub fn encode(&self, token: &str, password: Option<&str>) -> String {
+        let mut body = String::new();
+        body.push_str(&format!("version={}\n", STATE_VERSION));
+        body.push_str(&format!("saved_at={}\n", self.saved_at));
+        let ports: Vec<String> = self.ports.iter().map(|v| v.to_string()).collect();
+        body.push_str(&format!("ports={}\n", ports.join(",")));
+        body.push_str(&format!("virtual_ip={}\n", self.virtual_ip));
+        body.push_str(&format!("virtual_netmask={}\n", self.virtual_netmask));
+        body.push_str(&format!("virtual_gateway={}\n", self.virtual_gateway));
+        if let Some(fd) = self.tun_fd {
+            body.push_str(&format!("tun_fd={}\n", fd));
+        }
+        if let Some(session) = &self.session {
+            let pad = pad(token, password, self.saved_at);
+            body.push_str(&format!(
+                "session={},{},{}\n",
+                session.server,
+                to_hex(&mask(&session.server_key, &pad)),
+                to_hex(&session.server_public_key)
+            ));
+        }
+        for (ip, path) in &self.routes {
+            body.push_str(&format!(
+                "route={},{},{},{},{}\n",
+                ip, path.endpoint, path.index, path.metric, path.rt
+            ));
+        }
+        let mac = mac(&secret(token, password), &body);
+        body.push_str(&format!("mac={}\n", mac));
+        body
+    }
+    pub fn decode(text: &str, token: &str, password: Option<&str>) -> io::Result<Self> {
+        let index = text
+            .rfind("mac=")
+            .ok_or_else(|| invalid("mac not found".to_string()))?;
+        let (body, mac_line) = text.split_at(index);
+        if mac_line.trim_end()["mac=".len()..] != mac(&secret(token, password), body) {
+            return Err(invalid("mac mismatch".to_string()));
+        }
+        let mut state = WarmState {
+            saved_at: 0,
+            ports: vec![],
+            virtual_ip: Ipv4Addr::UNSPECIFIED,
+            virtual_netmask: Ipv4Addr::UNSPECIFIED,
+            virtual_gateway: Ipv4Addr::UNSPECIFIED,
+            routes: vec![],
+            tun_fd: None,
+            session: None,
+        };
+        let mut version = 0;
+        for line in body.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("line {:?}", line)))?;
+            match key {
+                "version" => version = parse(value)?,
+                "saved_at" => state.saved_at = parse(value)?,
+                "ports" => {
+                    for port in value.split(',').filter(|v| !v.is_empty()) {
+                        state.ports.push(parse(port)?);
+                    }
+                }
+                "virtual_ip" => state.virtual_ip = parse(value)?,
+                "virtual_netmask" => state.virtual_netmask = parse(value)?,
+                "virtual_gateway" => state.virtual_gateway = parse(value)?,
+                "tun_fd" => state.tun_fd = Some(parse(value)?),
+                "session" => {
+                    let v: Vec<&str> = value.split(',').collect();
+                    if v.len() != 3 {
+                        return Err(invalid(format!("session {:?}", value)));
+                    }
+                    state.session = Some(SessionTicket {
+                        server: parse(v[0])?,
+                        server_key: from_hex(v[1])?,
+                        server_public_key: from_hex(v[2])?,
+                    });
+                }
+                "route" => {
+                    let v: Vec<&str> = value.split(',').collect();
+                    if v.len() != 5 {
+                        return Err(invalid(format!("route {:?}", value)));
+                    }
+                    let endpoint: Endpoint = parse(v[1])?;
+                    state.routes.push((
+                        parse(v[0])?,
+                        PeerPath {
+                            endpoint,
+                            index: parse(v[2])?,
+                            metric: parse(v[3])?,
+                            rt: parse(v[4])?,
+                            state: PathState::Active,
+                        },
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if version != STATE_VERSION {
+            return Err(invalid(format!("version {}", version)));
+        }
+        if let Some(session) = &mut state.session {
+            // 掩码用到保存时间，所有行解析完之后再还原
+            let pad = pad(token, password, state.saved_at);
+            if session.server_key.len() > pad.len() {
+                return Err(invalid("session key too long".to_string()));
+            }
+            session.server_key = mask(&session.server_key, &pad);
+        }
+        Ok(state)
+    }
+}
+
+fn secret(token: &str, password: Option<&str>) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(b"vnt-warm-restart");
+    hasher.update(token.as_bytes());
+    hasher.update(password.unwrap_or("").as_bytes());
+    hasher.finalize().into()
+}
+
+/// 会话密钥的掩码，每次保存都不同
+fn pad(token: &str, password: Option<&str>, saved_at: u64) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(secret(token, password));
+    hasher.update(b"session");
+    hasher.update(saved_at.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn mask(data: &[u8], pad: &[u8; 32]) -> Vec<u8> {
+    data.iter().zip(pad.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|v| {
+            std::str::from_utf8(v)
+                .ok()
+                .filter(|v| v.len() == 2)
+                .and_then(|v| u8::from_str_radix(v, 16).ok())
+                .ok_or_else(|| invalid(format!("hex {:?}", text)))
+        })
+        .collect()
+}
+
+fn mac(key: &[u8; 32], body: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(key);
+    hasher.update(body.as_bytes());
+    let inner: [u8; 32] = hasher.finalize().into();
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(key);
+    hasher.update(inner);
+    let hash: [u8; 32] = hasher.finalize().into();
+    hash.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse<T: FromStr>(value: &str) -> io::Result<T> {
+    T::from_str(value).map_err(|_| invalid(format!("value {:?}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::channel::endpoint::{Endpoint, PathState, PeerPath};
+    use crate::core::{SessionTicket, WarmState};
+
+    fn state() -> WarmState {
+        WarmState {
+            saved_at: WarmState::now_millis(),
+            ports: vec![30001, 30002],
+            virtual_ip: Ipv4Addr::new(10, 26, 0, 2),
+            virtual_netmask: Ipv4Addr::new(255, 255, 255, 0),
+            virtual_gateway: Ipv4Addr::new(10, 26, 0, 1),
+            routes: vec![(
+                Ipv4Addr::new(10, 26, 0, 3),
+                PeerPath {
+                    endpoint: Endpoint::udp(SocketAddr::from(([192, 168, 1, 3], 40000))),
+                    index: 1,
+                    metric: 1,
+                    rt: 12,
+                    state: PathState::Active,
+                },
+            )],
+            tun_fd: Some(7),
+            session: Some(SessionTicket {
+                server: SocketAddr::from(([1, 2, 3, 4], 29872)),
+                server_key: (0..32).collect(),
+                server_public_key: vec![0x30, 0x82, 0x01],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let state = state();
+        let text = state.encode("token", Some("password"));
+        assert_eq!(
+            WarmState::decode(&text, "token", Some("password")).unwrap(),
+            state
+        );
+        // 不同的组网或者密码
+        assert!(WarmState::decode(&text, "token2", Some("password")).is_err());
+        assert!(WarmState::decode(&text, "token", None).is_err());
+        // 被修改过
+        let tampered = text.replace("tun_fd=7", "tun_fd=8");
+        assert!(WarmState::decode(&tampered, "token", Some("password")).is_err());
+        assert!(WarmState::decode("", "token", None).is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let state = state();
+        let text = state.encode("token", Some("password"));
+        // 密钥不以明文保存
+        let key: String = (0..32u8).map(|v| format!("{:02x}", v)).collect();
+        assert!(!text.contains(&key));
+        assert!(text.contains("session=1.2.3.4:29872,"));
+        let session = WarmState::decode(&text, "token", Some("password"))
+            .unwrap()
+            .session
+            .unwrap();
+        assert_eq!(session.server_key, (0..32).collect::<Vec<u8>>());
+        assert!(session.resumable(true) && !session.resumable(false));
+        // 没有会话的旧文件
+        let mut old = state.clone();
+        old.session = None;
+        let text = old.encode("token", None);
+        assert_eq!(
+            WarmState::decode(&text, "token", None).unwrap().session,
+            None
+        );
+    }
+
+    /// 路由的格式和改成PeerPath之前相同
+    #[test]
+    fn test_route_line() {
+        let text = state().encode("token", None);
+        assert!(text.contains("route=10.26.0.3,192.168.1.3:40000,1,1,12\n"));
+    }
+}
@@ -0,0 +1,342 @@
+//! 到对端的带宽测量
+//!
+//! 先请求对端同意，再分几轮连续发送填充的探测包，对端记录每轮的到达时间并返回汇总，
+//! 按每轮第一个包之后的字节数和到达间隔估计瓶颈带宽。总流量不超过MAX_VOLUME，
+//! 经过中继会占用服务器或中继客户端的带宽，需要明确指定
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::channel::bandwidth::{
+    self, BandwidthEstimate, BandwidthReply, BurstSample, MAX_BURSTS, MAX_VOLUME,
+};
+use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{
+    BandwidthProbePacket, BandwidthReplyPacket, BandwidthRequestPacket, BANDWIDTH_BURST_LEN,
+    BANDWIDTH_PROBE_LEN, BANDWIDTH_REPLY_LEN, BANDWIDTH_REQUEST_LEN,
+};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 轮数和每轮的包数，mtu较大时减少每轮的包数
+const BURSTS: u8 = 6;
+const BURST_LEN: u8 = 48;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const ATTEMPTS: usize = 3;
+/// 两轮之间的间隔，让瓶颈处的队列排空
+const BURST_GAP: Duration = Duration::from_millis(100);
+/// 最后一轮之后等待在途的包
+const DRAIN: Duration = Duration::from_millis(300);
+
+/// 一次测量的结果
+#[derive(Clone, Debug)]
+pub struct BandwidthReport {
+    pub peer: Ipv4Addr,
+    /// 测量使用的路径
+    pub path: String,
+    pub p2p: bool,
+    pub sent: u32,
+    pub received: u32,
+    pub estimate: BandwidthEstimate,
+    pub elapsed: Duration,
+}
+
+fn other(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn new_packet(
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    protocol: control_packet::Protocol,
+    payload_len: usize,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut packet = NetPacket::new_encrypt(vec![0; 12 + payload_len + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(protocol.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(src);
+    packet.set_destination(dest);
+    Ok(packet)
+}
+
+/// 接收端的回复，结果回复中每轮一项
+pub(crate) fn reply_packet(
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    id: u32,
+    consent: bool,
+    samples: &[BurstSample],
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let samples = &samples[..samples.len().min(MAX_BURSTS)];
+    let mut packet = new_packet(
+        src,
+        dest,
+        control_packet::Protocol::BandwidthReply,
+        BANDWIDTH_REPLY_LEN + samples.len() * BANDWIDTH_BURST_LEN,
+    )?;
+    let mut reply = BandwidthReplyPacket::new(packet.payload_mut())?;
+    reply.set_id(id);
+    reply.set_consent(consent);
+    reply.set_count(samples.len() as u8);
+    for (i, sample) in samples.iter().enumerate() {
+        reply.set_burst(i, sample.packets, sample.span_us, sample.bytes);
+    }
+    Ok(packet)
+}
+
+/// 解析对端的回复
+pub(crate) fn parse_reply<B: AsRef<[u8]>>(reply: &BandwidthReplyPacket<B>) -> BandwidthReply {
+    let samples = (0..reply.count() as usize)
+        .map(|i| {
+            let (packets, span_us, bytes) = reply.burst(i);
+            BurstSample {
+                packets,
+                span_us,
+                bytes,
+            }
+        })
+        .collect();
+    BandwidthReply {
+        consent: reply.consent(),
+        samples,
+    }
+}
+
+pub(crate) struct BandwidthTest<'a> {
+    pub context: &'a ChannelContext,
+    pub current_device: CurrentDeviceInfo,
+    pub client_cipher: &'a Cipher,
+    pub mtu: u32,
+}
+
+impl BandwidthTest<'_> {
+    /// 默认只测量直连路径，include_relay为true时也可以经过中继测量
+    pub fn run(&self, peer: Ipv4Addr, include_relay: bool) -> io::Result<BandwidthReport> {
+        let start = Instant::now();
+        if self.current_device.virtual_ip.is_unspecified() {
+            return Err(other("no virtual ip yet".to_string()));
+        }
+        let route = self.context.route_table.route_one(&peer);
+        let p2p = route.map_or(false, |v| v.is_p2p());
+        if !p2p && !include_relay {
+            return Err(other(format!(
+                "{} has no p2p path, measuring through a relay loads the server, use --include-relay",
+                peer
+            )));
+        }
+        let path = match route {
+            Some(route) => {
+                let kind = if p2p { "p2p" } else { "relay" };
+                let transport = if route.is_tcp { "tcp" } else { "udp" };
+                format!("{} {} {}", kind, transport, route.addr)
+            }
+            None => {
+                if !self.current_device.status.online() {
+                    return Err(other(
+                        "no route and not connected to the server".to_string(),
+                    ));
+                }
+                "relay via server".to_string()
+            }
+        };
+        let route_key = route.map(|v| v.route_key());
+        let size = (self.mtu as usize).clamp(BANDWIDTH_PROBE_LEN, u16::MAX as usize);
+        let burst_len = (MAX_VOLUME / bandwidth::volume(BURSTS as usize, 1, size))
+            .min(BURST_LEN as usize) as u8;
+        let id: u32 = rand::random();
+        let receiver = self.context.bandwidth.expect(id);
+        let rs = self.measure(peer, route_key, id, &receiver, burst_len, size);
+        self.context.bandwidth.cancel(id);
+        let (sent, samples) = rs?;
+        let received: u32 = samples.iter().map(|v| v.packets as u32).sum();
+        let estimate = match bandwidth::estimate(&samples) {
+            Some(estimate) => estimate,
+            None => {
+                return Err(other(format!(
+                    "{} of {} probe packets arrived, not enough to estimate",
+                    received, sent
+                )))
+            }
+        };
+        if let Some(route_key) = route_key {
+            self.context
+                .route_table
+                .set_bandwidth(&peer, &route_key, estimate.kbps);
+        }
+        log::info!("到{}的带宽 {},路径 {}", peer, estimate, path);
+        Ok(BandwidthReport {
+            peer,
+            path,
+            p2p,
+            sent,
+            received,
+            estimate,
+            elapsed: start.elapsed(),
+        })
+    }
+    fn measure(
+        &self,
+        peer: Ipv4Addr,
+        route_key: Option<RouteKey>,
+        id: u32,
+        receiver: &Receiver<BandwidthReply>,
+        burst_len: u8,
+        size: usize,
+    ) -> io::Result<(u32, Vec<BurstSample>)> {
+        let request = self.request(peer, id, false, burst_len, size)?;
+        let reply = self.exchange(&request, route_key, peer, receiver, false)?;
+        if !reply.consent {
+            return Err(other(format!("{} refused the bandwidth probe", peer)));
+        }
+        let mut sent = 0;
+        for burst in 0..BURSTS {
+            if burst > 0 {
+                std::thread::sleep(BURST_GAP);
+            }
+            // 先加密好一轮的包，发送时不间断
+            let mut packets = Vec::with_capacity(burst_len as usize);
+            for index in 0..burst_len {
+                packets.push(self.probe(peer, id, burst, index, size)?);
+            }
+            for packet in &packets {
+                match self.send(packet, route_key, peer) {
+                    Ok(_) => sent += 1,
+                    Err(e) => log::warn!("带宽探测包发送失败 {}:{:?}", peer, e),
+                }
+            }
+        }
+        std::thread::sleep(DRAIN);
+        let request = self.request(peer, id, true, burst_len, size)?;
+        let reply = self.exchange(&request, route_key, peer, receiver, true)?;
+        if !reply.consent {
+            return Err(other(format!("{} lost the probe session", peer)));
+        }
+        Ok((sent, reply.samples))
+    }
+    fn request(
+        &self,
+        peer: Ipv4Addr,
+        id: u32,
+        finish: bool,
+        burst_len: u8,
+        size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut packet = new_packet(
+            self.current_device.virtual_ip,
+            peer,
+            control_packet::Protocol::BandwidthRequest,
+            BANDWIDTH_REQUEST_LEN,
+        )?;
+        let mut request = BandwidthRequestPacket::new(packet.payload_mut())?;
+        request.set_id(id);
+        request.set_finish(finish);
+        request.set_bursts(BURSTS);
+        request.set_burst_len(burst_len);
+        request.set_size(size as u16);
+        self.client_cipher.encrypt_ipv4(&mut packet)?;
+        Ok(packet.buffer().to_vec())
+    }
+    fn probe(
+        &self,
+        peer: Ipv4Addr,
+        id: u32,
+        burst: u8,
+        index: u8,
+        size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut packet = new_packet(
+            self.current_device.virtual_ip,
+            peer,
+            control_packet::Protocol::BandwidthProbe,
+            size,
+        )?;
+        let mut probe = BandwidthProbePacket::new(packet.payload_mut())?;
+        probe.set_id(id);
+        probe.set_burst(burst);
+        probe.set_index(index);
+        self.client_cipher.encrypt_ipv4(&mut packet)?;
+        Ok(packet.buffer().to_vec())
+    }
+    /// 测量期间一直使用同一条路径
+    fn send(&self, buf: &[u8], route_key: Option<RouteKey>, peer: Ipv4Addr) -> io::Result<()> {
+        match route_key {
+            Some(route_key) => self.context.send_by_key(buf, route_key),
+            None => self.context.send_ipv4_by_id(
+                buf,
+                &peer,
+                self.current_device.connect_server,
+                self.current_device.status.online(),
+            ),
+        }
+    }
+    /// 发送请求并等待回复，等待结果时忽略重传的开始请求的回复
+    fn exchange(
+        &self,
+        request: &[u8],
+        route_key: Option<RouteKey>,
+        peer: Ipv4Addr,
+        receiver: &Receiver<BandwidthReply>,
+        want_result: bool,
+    ) -> io::Result<BandwidthReply> {
+        for _ in 0..ATTEMPTS {
+            if let Err(e) = self.send(request, route_key, peer) {
+                log::warn!("带宽测量请求发送失败 {}:{:?}", peer, e);
+            }
+            let deadline = Instant::now() + REPLY_TIMEOUT;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                match receiver.recv_timeout(timeout) {
+                    Ok(reply) => {
+                        if want_result && reply.consent && reply.samples.is_empty() {
+                            continue;
+                        }
+                        return Ok(reply);
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        Err(other(format!(
+            "{} did not respond, the peer may not support bandwidth probes",
+            peer
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::channel::bandwidth::BurstSample;
+    use crate::handle::bandwidth::{parse_reply, reply_packet};
+    use crate::protocol::control_packet::BandwidthReplyPacket;
+
+    #[test]
+    fn test_reply_packet() {
+        let src = Ipv4Addr::new(10, 26, 0, 2);
+        let dest = Ipv4Addr::new(10, 26, 0, 3);
+        let samples: Vec<BurstSample> = (0..10)
+            .map(|i| BurstSample {
+                packets: 40 + i,
+                span_us: 1000 * i as u32,
+                bytes: u32::MAX - i as u32,
+            })
+            .collect();
+        let packet = reply_packet(src, dest, 7, true, &samples).unwrap();
+        let reply = parse_reply(&BandwidthReplyPacket::new(packet.payload()).unwrap());
+        assert!(reply.consent);
+        // 超过的轮数不放进回复
+        assert_eq!(reply.samples, samples[..8]);
+        let packet = reply_packet(src, dest, 7, false, &[]).unwrap();
+        let reply = parse_reply(&BandwidthReplyPacket::new(packet.payload()).unwrap());
+        assert!(!reply.consent && reply.samples.is_empty());
+        // 长度和轮数不符
+        assert!(BandwidthReplyPacket::new(&[0, 0, 0, 7, 1, 1, 0, 0][..]).is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use crate::handle::flow_table::FlowInfo;
+use crate::handle::server_action::Action;
 use crate::handle::PeerDeviceStatus;
+use crate::util::health::HealthReport;
 #[cfg(feature = "server_encrypt")]
 use rsa::RsaPublicKey;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[derive(Debug)]
@@ -172,6 +176,10 @@ pub enum ErrorType {
     IpAlreadyExists,
     InvalidIp,
     LocalIpExists,
+    // 路由回环或嵌套在其他vpn中
+    RouteWarning,
+    // 路径mtu黑洞，已降低mss
+    PathMtu,
     Unknown,
 }
 
@@ -184,6 +192,8 @@ impl Into<u8> for ErrorType {
             ErrorType::IpAlreadyExists => 4,
             ErrorType::InvalidIp => 5,
             ErrorType::LocalIpExists => 6,
+            ErrorType::RouteWarning => 7,
+            ErrorType::PathMtu => 8,
             ErrorType::Unknown => 255,
         }
     }
@@ -239,6 +249,8 @@ pub struct PeerClientInfo {
     pub name: String,
     pub status: PeerDeviceStatus,
     pub client_secret: bool,
+    // 设备指纹，对端没有公开时为空
+    pub fingerprint: String,
 }
 
 impl PeerClientInfo {
@@ -247,12 +259,14 @@ impl PeerClientInfo {
         name: String,
         status: PeerDeviceStatus,
         client_secret: bool,
+        fingerprint: String,
     ) -> Self {
         Self {
             virtual_ip,
             name,
             status,
             client_secret,
+            fingerprint,
         }
     }
 }
@@ -260,12 +274,189 @@ impl PeerClientInfo {
 impl Display for PeerClientInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
-            "ip={} ,name={} ,status={:?}, client_secret={}",
-            self.virtual_ip, self.name, self.status, self.client_secret
+            "ip={} ,name={} ,status={:?}, client_secret={}, fingerprint={}",
+            self.virtual_ip, self.name, self.status, self.client_secret, self.fingerprint
         ))
     }
 }
 
+/// 系统休眠唤醒
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResumeInfo {
+    pub slept: Duration,
+    // 休眠时间较长，需要重新注册
+    pub reconnect: bool,
+}
+
+impl ResumeInfo {
+    pub fn new(slept: Duration, reconnect: bool) -> Self {
+        Self { slept, reconnect }
+    }
+}
+
+impl Display for ResumeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "resumed after {}s sleep", self.slept.as_secs())?;
+        if self.reconnect {
+            f.write_str(", reconnecting")?;
+        }
+        Ok(())
+    }
+}
+
+/// 对端发来的数据超过接收限速
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FloodInfo {
+    pub peer: Ipv4Addr,
+    // 包/秒，字节/秒，0表示不限制
+    pub pps: u64,
+    pub bps: u64,
+    // 自动屏蔽的时长
+    pub blocked: Option<Duration>,
+}
+
+impl FloodInfo {
+    pub fn new(peer: Ipv4Addr, pps: u64, bps: u64, blocked: Option<Duration>) -> Self {
+        Self {
+            peer,
+            pps,
+            bps,
+            blocked,
+        }
+    }
+}
+
+impl Display for FloodInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer {} exceeding inbound limit (", self.peer)?;
+        if self.pps > 0 {
+            write!(f, "{} pkt/s", self.pps)?;
+        }
+        if self.bps > 0 {
+            if self.pps > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} B/s", self.bps)?;
+        }
+        f.write_str(")")?;
+        if let Some(blocked) = self.blocked {
+            write!(f, ", blocked for {}s", blocked.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// 存活或就绪状态变化
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthInfo {
+    // true为存活检查，false为就绪检查
+    pub live: bool,
+    pub report: HealthReport,
+}
+
+impl HealthInfo {
+    pub fn new(live: bool, report: HealthReport) -> Self {
+        Self { live, report }
+    }
+}
+
+impl Display for HealthInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.live { "liveness" } else { "readiness" };
+        if self.report.ok() {
+            write!(f, "{} ok", kind)
+        } else {
+            write!(f, "{} failed: {}", kind, self.report)
+        }
+    }
+}
+
+/// 服务端运营者发布的公告，内容已去除控制字符
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NoticeInfo {
+    pub message: String,
+    // 计划维护时间，unix时间戳(秒)，0表示没有维护计划
+    pub maintenance_at: i64,
+}
+
+impl NoticeInfo {
+    pub fn new(message: String, maintenance_at: i64) -> Self {
+        Self {
+            message,
+            maintenance_at,
+        }
+    }
+}
+
+impl Display for NoticeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.maintenance_at > 0 {
+            f.write_str(&format!(
+                "{} ,maintenance_at={}",
+                self.message, self.maintenance_at
+            ))
+        } else {
+            f.write_str(&self.message)
+        }
+    }
+}
+
+/// 服务端请求执行、需要由应用完成的操作，已经过允许列表和频率检查
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerActionInfo {
+    pub id: u32,
+    pub action: Action,
+    // 已去除控制字符
+    pub arg: String,
+}
+
+impl ServerActionInfo {
+    pub fn new(id: u32, action: Action, arg: String) -> Self {
+        Self { id, action, arg }
+    }
+}
+
+impl Display for ServerActionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "id={} action={} arg={:?}",
+            self.id, self.action, self.arg
+        )
+    }
+}
+
+/// 子系统启动或停止
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubsystemInfo {
+    pub name: String,
+    pub running: bool,
+    // 触发的原因，如命令、级联停止、恢复保存的设置
+    pub reason: String,
+}
+
+impl SubsystemInfo {
+    pub fn new(name: &str, running: bool, reason: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            running,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Display for SubsystemInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subsystem {} {} ({})",
+            self.name,
+            if self.running { "started" } else { "stopped" },
+            self.reason
+        )
+    }
+}
+
 pub trait VntCallback: Clone + Send + Sync + 'static {
     /// 启动成功
     fn success(&self) {}
@@ -288,6 +479,22 @@ pub trait VntCallback: Clone + Send + Sync + 'static {
         0
     }
     fn peer_client_list(&self, _info: Vec<PeerClientInfo>) {}
+    /// 服务端公告，内容变化时才会回调
+    fn notice(&self, _info: NoticeInfo) {}
+    /// 系统休眠唤醒后回调，此时已经开始恢复连接
+    fn resumed(&self, _info: ResumeInfo) {}
+    /// 对端超过接收限速，同一个对端10秒内最多回调一次
+    fn inbound_flood(&self, _info: FloodInfo) {}
+    /// 存活或就绪状态变化
+    fn health(&self, _info: HealthInfo) {}
+    /// 首次收到对端发起的流，需要开启流通知
+    fn new_flow(&self, _info: FlowInfo) {}
+    /// 服务端请求的collect-bundle、set-log-level，在维护任务中调用，返回结果说明
+    fn server_action(&self, _info: ServerActionInfo) -> Result<String, String> {
+        Err("not supported by this client".to_string())
+    }
+    /// 子系统启动或停止
+    fn subsystem(&self, _info: SubsystemInfo) {}
     /// 异常信息
     fn error(&self, _info: ErrorInfo) {}
     /// 服务停止
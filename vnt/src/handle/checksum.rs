@@ -0,0 +1,99 @@
+//! 虚拟网卡的校验和探测
+//!
+//! 构造一个来自网关的udp包，像端口转发一样改写目的端口并增量更新校验和，
+//! 再按校验和策略处理后写入网卡，本机绑定在虚拟ip上的socket收到相同的负载就说明协议栈接受了这个包
+
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::channel::checksum::{apply_mode, ChecksumMode};
+use crate::channel::context::ChannelContext;
+use crate::channel::mtu_guard::update_checksum;
+use crate::channel::self_probe::{ipv4, payload};
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+
+const UDP: u8 = 17;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_LEN: usize = 64;
+/// 网卡刚配置地址时可能还不能绑定
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// 按指定的模式注入一个改写过的包，确认本机协议栈能收到
+pub(crate) fn probe(
+    device: &DeviceAdapter,
+    virtual_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    mode: ChecksumMode,
+) -> io::Result<Duration> {
+    let socket = UdpSocket::bind((virtual_ip, 0))?;
+    socket.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let port = socket.local_addr()?.port();
+    let token: u64 = rand::random();
+    let data = payload(token, PROBE_LEN);
+    // 先发往另一个端口，再改写成socket的端口
+    let wrong_port = port ^ 1;
+    let udp_len = 8 + data.len();
+    let mut transport = Vec::with_capacity(udp_len);
+    transport.extend_from_slice(&port.to_be_bytes());
+    transport.extend_from_slice(&wrong_port.to_be_bytes());
+    transport.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    // 校验和不为0时才会计算
+    transport.extend_from_slice(&[0xFF, 0xFF]);
+    transport.extend_from_slice(&data);
+    let mut packet = ipv4(gateway, virtual_ip, UDP, transport)?;
+    packet[22..24].copy_from_slice(&port.to_be_bytes());
+    let checksum = update_checksum(
+        u16::from_be_bytes([packet[26], packet[27]]),
+        wrong_port,
+        port,
+    );
+    packet[26..28].copy_from_slice(&checksum.to_be_bytes());
+    apply_mode(mode, &mut packet);
+    let start = Instant::now();
+    device.write(&packet)?;
+    let mut buf = [0u8; PROBE_LEN + 1];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf)?;
+        if buf[..len] == data[..] {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+/// auto模式下网卡就绪后探测一次，计算后的校验和不能通过而置0可以通过时改用zero
+pub(crate) fn detect(
+    context: &ChannelContext,
+    device: &DeviceAdapter,
+    virtual_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+) {
+    if context.checksum.mode() != ChecksumMode::Auto || !device.attached() {
+        return;
+    }
+    let context = context.clone();
+    let device = device.clone();
+    let rs = std::thread::Builder::new()
+        .name("checksumProbe".into())
+        .spawn(move || {
+            std::thread::sleep(SETTLE);
+            match probe(&device, virtual_ip, gateway, ChecksumMode::Compute) {
+                Ok(_) => {
+                    context.checksum.resolve(ChecksumMode::Compute);
+                    log::info!("网卡校验和探测通过，使用compute");
+                }
+                Err(e) => match probe(&device, virtual_ip, gateway, ChecksumMode::Zero) {
+                    Ok(_) => {
+                        context.checksum.resolve(ChecksumMode::Zero);
+                        log::warn!("改写的包校验和计算后无法送达({:?})，改为置0", e);
+                    }
+                    Err(e2) => {
+                        log::warn!("网卡校验和探测失败 compute={:?} zero={:?}", e, e2);
+                    }
+                },
+            }
+        });
+    if let Err(e) = rs {
+        log::warn!("启动校验和探测失败 {:?}", e);
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 同时未完成的通知上限，超出时拒绝发送新的通知
+const MAX_OUTSTANDING: usize = 8;
+/// 保留最近完成的通知结果数
+const MAX_FINISHED: usize = 16;
+/// 接收方记住最近收到的通知数，用于丢弃重传的副本
+const MAX_SEEN: usize = 64;
+/// 首次重传间隔，之后翻倍
+const RETRY_MIN: Duration = Duration::from_millis(200);
+const RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// 需要对端确认的通知类型
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NoticeKind {
+    /// 即将下线，对端可以立即清除到本机的直连路由
+    Offline,
+    /// 本机的地址发生变化，对端的直连路由可能已经失效
+    EndpointChange,
+    Unknown(u8),
+}
+
+impl From<u8> for NoticeKind {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => NoticeKind::Offline,
+            2 => NoticeKind::EndpointChange,
+            val => NoticeKind::Unknown(val),
+        }
+    }
+}
+
+impl Into<u8> for NoticeKind {
+    fn into(self) -> u8 {
+        match self {
+            NoticeKind::Offline => 1,
+            NoticeKind::EndpointChange => 2,
+            NoticeKind::Unknown(val) => val,
+        }
+    }
+}
+
+impl NoticeKind {
+    /// 超过这个时间还没有确认就放弃
+    pub fn deadline(&self) -> Duration {
+        match self {
+            // 下线时不能等太久
+            NoticeKind::Offline => Duration::from_secs(2),
+            _ => Duration::from_secs(10),
+        }
+    }
+}
+
+/// 每个接收方的投递结果
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NoticeOutcome {
+    /// 收到了确认
+    Delivered,
+    /// 截止时间内没有收到确认
+    Expired,
+    /// 接收方不在设备列表中或者已离线
+    UnknownRecipient,
+    /// 停止时被取消
+    Cancelled,
+}
+
+impl Display for NoticeOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoticeOutcome::Delivered => f.write_str("delivered"),
+            NoticeOutcome::Expired => f.write_str("expired"),
+            NoticeOutcome::UnknownRecipient => f.write_str("recipient unknown"),
+            NoticeOutcome::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
+
+struct Recipient {
+    ip: Ipv4Addr,
+    sends: u32,
+    next_send: Instant,
+    outcome: Option<NoticeOutcome>,
+}
+
+struct Notice {
+    id: u32,
+    kind: NoticeKind,
+    deadline: Instant,
+    recipients: Vec<Recipient>,
+}
+
+impl Notice {
+    fn is_finished(&self) -> bool {
+        self.recipients.iter().all(|v| v.outcome.is_some())
+    }
+}
+
+/// 已完成的通知及各接收方的结果
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NoticeResult {
+    pub id: u32,
+    pub kind: NoticeKind,
+    pub outcomes: Vec<(Ipv4Addr, NoticeOutcome)>,
+}
+
+struct Inner {
+    next_id: u32,
+    outstanding: Vec<Notice>,
+    finished: VecDeque<NoticeResult>,
+    seen: VecDeque<(Ipv4Addr, u32)>,
+}
+
+/// 需要确认的一次性通知
+///
+/// 对每个接收方重传直到收到确认或者超过截止时间，记录每个接收方的结果。
+/// 发送由调用方通过tick驱动，这里只维护状态，不直接访问网络
+#[derive(Clone)]
+pub struct CriticalNotice {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for CriticalNotice {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                // 随机起始，避免对端重启后id和之前的重复被当成重传丢弃
+                next_id: rand::random(),
+                outstanding: Vec::with_capacity(MAX_OUTSTANDING),
+                finished: VecDeque::with_capacity(MAX_FINISHED),
+                seen: VecDeque::with_capacity(MAX_SEEN),
+            })),
+        }
+    }
+}
+
+impl CriticalNotice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 登记一条通知，返回通知id，在下一次tick时发出
+    pub fn send(&self, kind: NoticeKind, recipients: Vec<Ipv4Addr>) -> io::Result<u32> {
+        self.send_at(kind, recipients, Instant::now())
+    }
+    fn send_at(
+        &self,
+        kind: NoticeKind,
+        mut recipients: Vec<Ipv4Addr>,
+        now: Instant,
+    ) -> io::Result<u32> {
+        let mut guard = self.inner.lock();
+        if guard.outstanding.len() >= MAX_OUTSTANDING {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("too many outstanding notices {}", guard.outstanding.len()),
+            ));
+        }
+        recipients.sort();
+        recipients.dedup();
+        let id = guard.next_id;
+        guard.next_id = guard.next_id.wrapping_add(1);
+        guard.outstanding.push(Notice {
+            id,
+            kind,
+            deadline: now + kind.deadline(),
+            recipients: recipients
+                .into_iter()
+                .map(|ip| Recipient {
+                    ip,
+                    sends: 0,
+                    next_send: now,
+                    outcome: None,
+                })
+                .collect(),
+        });
+        Ok(id)
+    }
+    /// 收到对端的确认
+    pub fn ack(&self, id: u32, from: Ipv4Addr) -> bool {
+        let mut guard = self.inner.lock();
+        let recipient = guard
+            .outstanding
+            .iter_mut()
+            .filter(|v| v.id == id)
+            .flat_map(|v| v.recipients.iter_mut())
+            .find(|v| v.ip == from && v.outcome.is_none());
+        if let Some(recipient) = recipient {
+            recipient.outcome.replace(NoticeOutcome::Delivered);
+            finish(&mut guard);
+            return true;
+        }
+        false
+    }
+    /// 重传未确认的通知，并处理超时的接收方
+    ///
+    /// is_known判断接收方是否存在，send负责实际发送，发送时不持有锁
+    pub fn tick(
+        &self,
+        now: Instant,
+        is_known: impl Fn(&Ipv4Addr) -> bool,
+        mut send: impl FnMut(u32, NoticeKind, &Ipv4Addr) -> io::Result<()>,
+    ) {
+        let mut list = Vec::new();
+        {
+            let mut guard = self.inner.lock();
+            for notice in guard.outstanding.iter_mut() {
+                for recipient in notice.recipients.iter_mut() {
+                    if recipient.outcome.is_some() {
+                        continue;
+                    }
+                    if !is_known(&recipient.ip) {
+                        recipient.outcome.replace(NoticeOutcome::UnknownRecipient);
+                    } else if now >= notice.deadline {
+                        recipient.outcome.replace(NoticeOutcome::Expired);
+                    } else if now >= recipient.next_send {
+                        let backoff = RETRY_MIN * 2u32.pow(recipient.sends.min(8));
+                        recipient.sends += 1;
+                        recipient.next_send = now + backoff.min(RETRY_MAX);
+                        list.push((notice.id, notice.kind, recipient.ip));
+                    }
+                }
+            }
+            finish(&mut guard);
+        }
+        for (id, kind, ip) in list {
+            if let Err(e) = send(id, kind, &ip) {
+                log::warn!("发送通知失败 id={},{:?},dest={},{:?}", id, kind, ip, e);
+            }
+        }
+    }
+    /// 没有未完成的通知
+    pub fn is_idle(&self) -> bool {
+        self.inner.lock().outstanding.is_empty()
+    }
+    /// 已完成通知的结果，未完成或者已经被淘汰时返回None
+    pub fn outcome(&self, id: u32) -> Option<NoticeResult> {
+        self.inner
+            .lock()
+            .finished
+            .iter()
+            .find(|v| v.id == id)
+            .cloned()
+    }
+    /// 取消所有未完成的通知，停止时调用
+    pub fn cancel_all(&self) {
+        let mut guard = self.inner.lock();
+        for notice in guard.outstanding.iter_mut() {
+            for recipient in notice.recipients.iter_mut() {
+                if recipient.outcome.is_none() {
+                    recipient.outcome.replace(NoticeOutcome::Cancelled);
+                }
+            }
+        }
+        finish(&mut guard);
+    }
+    /// 驱动重传直到所有通知完成或者超时，返回是否全部完成
+    pub fn flush(
+        &self,
+        timeout: Duration,
+        is_known: impl Fn(&Ipv4Addr) -> bool,
+        mut send: impl FnMut(u32, NoticeKind, &Ipv4Addr) -> io::Result<()>,
+    ) -> bool {
+        let start = Instant::now();
+        loop {
+            self.tick(Instant::now(), &is_known, &mut send);
+            if self.is_idle() {
+                return true;
+            }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    /// 接收方调用，重传的副本返回false
+    pub fn first_seen(&self, from: Ipv4Addr, id: u32) -> bool {
+        let mut guard = self.inner.lock();
+        if guard.seen.contains(&(from, id)) {
+            return false;
+        }
+        if guard.seen.len() >= MAX_SEEN {
+            guard.seen.pop_front();
+        }
+        guard.seen.push_back((from, id));
+        true
+    }
+}
+
+fn finish(inner: &mut Inner) {
+    let mut index = 0;
+    while index < inner.outstanding.len() {
+        if !inner.outstanding[index].is_finished() {
+            index += 1;
+            continue;
+        }
+        let notice = inner.outstanding.remove(index);
+        let result = NoticeResult {
+            id: notice.id,
+            kind: notice.kind,
+            outcomes: notice
+                .recipients
+                .iter()
+                .map(|v| (v.ip, v.outcome.unwrap_or(NoticeOutcome::Cancelled)))
+                .collect(),
+        };
+        for (ip, outcome) in &result.outcomes {
+            if *outcome == NoticeOutcome::Delivered {
+                log::info!(
+                    "通知 id={},{:?},dest={},{}",
+                    result.id,
+                    result.kind,
+                    ip,
+                    outcome
+                );
+            } else {
+                log::warn!(
+                    "通知 id={},{:?},dest={},{}",
+                    result.id,
+                    result.kind,
+                    ip,
+                    outcome
+                );
+            }
+        }
+        if inner.finished.len() >= MAX_FINISHED {
+            inner.finished.pop_front();
+        }
+        inner.finished.push_back(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use crate::handle::critical_notice::{
+        CriticalNotice, NoticeKind, NoticeOutcome, MAX_OUTSTANDING,
+    };
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 4);
+    const C: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 5);
+
+    /// 模拟丢掉前几次发送，没被丢掉的立即得到确认
+    fn run(
+        notices: &CriticalNotice,
+        drop_first: usize,
+        ack: bool,
+        start: Instant,
+        end: Duration,
+    ) -> usize {
+        let mut sends = 0;
+        let mut now = start;
+        while now <= start + end {
+            let mut delivered = Vec::new();
+            notices.tick(
+                now,
+                |ip| *ip != C,
+                |id, _, ip| {
+                    sends += 1;
+                    if sends > drop_first && ack {
+                        delivered.push((id, *ip));
+                    }
+                    Ok(())
+                },
+            );
+            for (id, ip) in delivered {
+                notices.ack(id, ip);
+            }
+            now += Duration::from_millis(100);
+        }
+        sends
+    }
+
+    #[test]
+    fn test_delivered_after_drops() {
+        let notices = CriticalNotice::new();
+        let now = Instant::now();
+        let id = notices
+            .send_at(NoticeKind::Offline, vec![A, A], now)
+            .unwrap();
+        // 前两次发送丢失，第三次重传送达
+        let sends = run(&notices, 2, true, now, Duration::from_secs(3));
+        assert_eq!(sends, 3);
+        assert!(notices.is_idle());
+        let result = notices.outcome(id).unwrap();
+        assert_eq!(result.outcomes, vec![(A, NoticeOutcome::Delivered)]);
+        // 重复的确认不再生效
+        assert!(!notices.ack(id, A));
+    }
+
+    #[test]
+    fn test_expired_and_unknown() {
+        let notices = CriticalNotice::new();
+        let now = Instant::now();
+        let id = notices
+            .send_at(NoticeKind::EndpointChange, vec![B, C], now)
+            .unwrap();
+        run(&notices, usize::MAX, true, now, Duration::from_secs(5));
+        // 还没到截止时间
+        assert!(notices.outcome(id).is_none());
+        run(&notices, usize::MAX, false, now, Duration::from_secs(11));
+        let result = notices.outcome(id).unwrap();
+        assert_eq!(
+            result.outcomes,
+            vec![
+                (B, NoticeOutcome::Expired),
+                (C, NoticeOutcome::UnknownRecipient)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bounded_and_cancel() {
+        let notices = CriticalNotice::new();
+        let now = Instant::now();
+        let ids: Vec<u32> = (0..MAX_OUTSTANDING)
+            .map(|_| notices.send_at(NoticeKind::Offline, vec![A], now).unwrap())
+            .collect();
+        assert!(notices.send_at(NoticeKind::Offline, vec![A], now).is_err());
+        notices.cancel_all();
+        assert!(notices.is_idle());
+        for id in ids {
+            assert_eq!(
+                notices.outcome(id).unwrap().outcomes,
+                vec![(A, NoticeOutcome::Cancelled)]
+            );
+        }
+        assert!(notices.send_at(NoticeKind::Offline, vec![A], now).is_ok());
+    }
+
+    #[test]
+    fn test_first_seen() {
+        let notices = CriticalNotice::new();
+        assert!(notices.first_seen(A, 1));
+        assert!(!notices.first_seen(A, 1));
+        assert!(notices.first_seen(B, 1));
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::util::metrics::{Counter, Gauge, Registry};
+use crate::util::StopManager;
+
+/// 低端设备上也要留出核心给数据线程
+pub const CRYPTO_WORKERS: usize = 2;
+pub const CRYPTO_QUEUE_CAPACITY: usize = 64;
+/// 握手重试间隔是秒级的，排队太久的结果已经没有意义
+pub const CRYPTO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 提交任务被拒绝的原因
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rejected {
+    /// 同一个对端已经有任务在排队或执行
+    InFlight,
+    /// 队列已满
+    Full,
+    Stopped,
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Job {
+    key: SocketAddr,
+    deadline: Instant,
+    task: Task,
+    expire: Task,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    // 排队和执行中的对端
+    keys: HashSet<SocketAddr>,
+    stopped: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    cond: Condvar,
+    capacity: usize,
+    timeout: Duration,
+    depth: Gauge,
+    in_flight: Counter,
+    full: Counter,
+    expired: Counter,
+}
+
+/// 执行耗时的非对称加密操作(rsa握手、上传密钥)
+///
+/// 和收发数据的线程分开，全局并发数等于线程数，每个对端同时只有一个任务，
+/// 多出来的任务排队，超过期限还没开始执行的任务直接失败，等待下一次重试，不会越积越多。
+/// 每个数据包的对称加解密仍然在数据线程中执行
+#[derive(Clone)]
+pub struct CryptoPool {
+    inner: Arc<Inner>,
+}
+
+impl CryptoPool {
+    pub fn new(
+        stop_manager: &StopManager,
+        registry: &Registry,
+        workers: usize,
+        capacity: usize,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let pool = Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::with_capacity(capacity),
+                    keys: HashSet::with_capacity(capacity),
+                    stopped: false,
+                }),
+                cond: Condvar::new(),
+                capacity,
+                timeout,
+                depth: registry.gauge("crypto_queue_depth", &[]),
+                in_flight: registry.counter("crypto_rejected", &[("reason", "in_flight")]),
+                full: registry.counter("crypto_rejected", &[("reason", "full")]),
+                expired: registry.counter("crypto_rejected", &[("reason", "expired")]),
+            }),
+        };
+        for index in 0..workers.max(1) {
+            let worker = {
+                let pool = pool.clone();
+                stop_manager.add_listener(format!("cryptoPool-{}", index), move || pool.stop())?
+            };
+            let inner = pool.inner.clone();
+            thread::Builder::new()
+                .name(format!("cryptoPool-{}", index))
+                .spawn(move || {
+                    run(&inner);
+                    drop(worker);
+                })?;
+        }
+        Ok(pool)
+    }
+    /// 提交任务，不会阻塞调用线程
+    ///
+    /// 超过期限还没开始执行时调用expire代替task
+    pub fn submit<T, E>(&self, key: SocketAddr, task: T, expire: E) -> Result<(), Rejected>
+    where
+        T: FnOnce() + Send + 'static,
+        E: FnOnce() + Send + 'static,
+    {
+        let inner = &self.inner;
+        let mut state = inner.state.lock();
+        if state.stopped {
+            return Err(Rejected::Stopped);
+        }
+        if state.keys.contains(&key) {
+            inner.in_flight.inc();
+            return Err(Rejected::InFlight);
+        }
+        if state.queue.len() >= inner.capacity {
+            inner.full.inc();
+            return Err(Rejected::Full);
+        }
+        state.keys.insert(key);
+        state.queue.push_back(Job {
+            key,
+            deadline: Instant::now() + inner.timeout,
+            task: Box::new(task),
+            expire: Box::new(expire),
+        });
+        inner.depth.add(1);
+        drop(state);
+        inner.cond.notify_one();
+        Ok(())
+    }
+    pub fn queue_depth(&self) -> usize {
+        self.inner.state.lock().queue.len()
+    }
+    pub fn stop(&self) {
+        let mut state = self.inner.state.lock();
+        state.stopped = true;
+        self.inner.depth.add(-(state.queue.len() as i64));
+        state.queue.clear();
+        drop(state);
+        self.inner.cond.notify_all();
+    }
+}
+
+fn run(inner: &Inner) {
+    loop {
+        let job = {
+            let mut state = inner.state.lock();
+            loop {
+                if state.stopped {
+                    return;
+                }
+                if let Some(job) = state.queue.pop_front() {
+                    break job;
+                }
+                inner.cond.wait(&mut state);
+            }
+        };
+        inner.depth.add(-1);
+        let Job {
+            key,
+            deadline,
+            task,
+            expire,
+        } = job;
+        if deadline < Instant::now() {
+            inner.expired.inc();
+            expire();
+        } else {
+            task();
+        }
+        inner.state.lock().keys.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::util::metrics::Registry;
+    use crate::util::StopManager;
+
+    use super::{CryptoPool, Rejected};
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 29872))
+    }
+
+    /// 大量假对端同时握手时，数据线程提交任务不被阻塞，排队数量有上限
+    #[test]
+    fn test_handshake_storm() {
+        let stop_manager = StopManager::new(|| {});
+        let registry = Registry::new();
+        let pool =
+            CryptoPool::new(&stop_manager, &registry, 2, 32, Duration::from_millis(200)).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        let expired = Arc::new(AtomicUsize::new(0));
+        let mut accepted = 0;
+        let mut full = 0;
+        let mut max_submit = Duration::ZERO;
+        for i in 0..500 {
+            let done = done.clone();
+            let expired = expired.clone();
+            let start = Instant::now();
+            let rs = pool.submit(
+                addr(i),
+                move || {
+                    // 模拟rsa运算
+                    std::thread::sleep(Duration::from_millis(20));
+                    done.fetch_add(1, Ordering::Relaxed);
+                },
+                move || {
+                    expired.fetch_add(1, Ordering::Relaxed);
+                },
+            );
+            max_submit = max_submit.max(start.elapsed());
+            match rs {
+                Ok(_) => accepted += 1,
+                Err(Rejected::Full) => full += 1,
+                Err(e) => panic!("{:?}", e),
+            }
+            assert!(pool.queue_depth() <= 32);
+        }
+        // 数据线程的延迟不受排队任务影响
+        assert!(max_submit < Duration::from_millis(50), "{:?}", max_submit);
+        assert!(full > 0);
+        std::thread::sleep(Duration::from_millis(1500));
+        assert_eq!(pool.queue_depth(), 0);
+        let done = done.load(Ordering::Relaxed);
+        let expired = expired.load(Ordering::Relaxed);
+        assert_eq!(done + expired, accepted);
+        // 超过期限的任务直接失败，没有全部执行
+        assert!(expired > 0);
+        assert_eq!(
+            registry
+                .counter("crypto_rejected", &[("reason", "full")])
+                .get(),
+            full as u64
+        );
+        stop_manager.stop();
+    }
+
+    #[test]
+    fn test_one_per_peer() {
+        let stop_manager = StopManager::new(|| {});
+        let pool = CryptoPool::new(
+            &stop_manager,
+            &Registry::new(),
+            1,
+            8,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        pool.submit(
+            addr(1),
+            move || {
+                let _ = receiver.recv();
+            },
+            || {},
+        )
+        .unwrap();
+        assert_eq!(pool.submit(addr(1), || {}, || {}), Err(Rejected::InFlight));
+        assert!(pool.submit(addr(2), || {}, || {}).is_ok());
+        drop(sender);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(pool.submit(addr(1), || {}, || {}).is_ok());
+        stop_manager.stop();
+        assert_eq!(pool.submit(addr(3), || {}, || {}), Err(Rejected::Stopped));
+    }
+}
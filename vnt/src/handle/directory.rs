@@ -0,0 +1,451 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::{Condvar, Mutex};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::diary::EvictReason;
+use crate::handle::callback::{ErrorInfo, ErrorType, VntCallback};
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::proto::message::DeviceInfo;
+use crate::util::metrics::{Counter, Registry};
+use crate::util::sanitize::{self, FINGERPRINT_MAX_LEN};
+use crate::util::serial::{serial_lt, Anomaly};
+use crate::PeerClientInfo;
+
+/// 默认接受的设备数上限
+pub const DEFAULT_MAX_DEVICES: usize = 10_000;
+/// 每转换这么多条让出一次cpu
+const CHUNK: usize = 256;
+const NOT_REPORTED: u32 = u32::MAX;
+
+/// 服务端下发的设备列表
+///
+/// 接收线程只把解析好的列表放进来就返回，由单独的线程转换和应用，只在替换时短暂持有设备列表的锁，
+/// 还没来得及应用的旧列表直接被新列表覆盖。超过上限的部分丢弃，异常的消息计数，同一个纪元只告警一次
+///
+/// 纪元是16位的，按RFC 1982比较先后，65535之后回绕到0仍然是更新的纪元。比当前纪元旧的列表是乱序到达的，
+/// 直接丢弃，本地的纪元只增不减。掉线和重新注册时纪元归零，之后接受服务端的任意纪元(服务端可能重启过)
+#[derive(Clone)]
+pub struct Directory {
+    inner: Arc<DirectoryInner>,
+}
+
+struct DirectoryInner {
+    max_devices: usize,
+    pending: Mutex<Option<(u16, Vec<DeviceInfo>)>>,
+    cond: Condvar,
+    oversized: Counter,
+    malformed: Counter,
+    superseded: Counter,
+    // 比当前纪元旧的列表
+    stale: Anomaly,
+    // 已经告警过的纪元
+    reported: AtomicU32,
+}
+
+impl Directory {
+    pub fn new(max_devices: usize, registry: &Registry) -> Self {
+        Self {
+            inner: Arc::new(DirectoryInner {
+                max_devices,
+                pending: Mutex::new(None),
+                cond: Condvar::new(),
+                oversized: registry.counter("directory_rejected", &[("reason", "oversized")]),
+                malformed: registry.counter("directory_rejected", &[("reason", "malformed")]),
+                superseded: registry.counter("directory_superseded", &[]),
+                stale: Anomaly::new(
+                    "收到旧纪元的设备列表",
+                    registry.counter("directory_rejected", &[("reason", "stale")]),
+                ),
+                reported: AtomicU32::new(NOT_REPORTED),
+            }),
+        }
+    }
+    /// 接收线程调用，不等待应用
+    pub fn submit(&self, epoch: u16, list: Vec<DeviceInfo>) {
+        let old = {
+            let mut pending = self.inner.pending.lock();
+            if let Some((pending_epoch, _)) = pending.as_ref() {
+                if serial_lt(epoch, *pending_epoch) {
+                    let pending_epoch = *pending_epoch;
+                    drop(pending);
+                    self.inner
+                        .stale
+                        .record(format_args!("epoch={} pending={}", epoch, pending_epoch));
+                    return;
+                }
+            }
+            pending.replace((epoch, list))
+        };
+        self.inner.cond.notify_one();
+        if old.is_some() {
+            self.inner.superseded.inc();
+        }
+    }
+    /// 掉线时丢弃还没应用的列表，避免旧的纪元覆盖掉线后的状态
+    pub fn clear(&self) {
+        let old = self.inner.pending.lock().take();
+        drop(old);
+    }
+    /// 无法解析的设备列表
+    pub fn malformed(&self, epoch: u16, reason: &str) {
+        self.inner.malformed.inc();
+        if self.first_report(epoch) {
+            log::warn!("服务端下发的设备列表无法解析 epoch={} {}", epoch, reason);
+        }
+    }
+    fn take(&self, timeout: Duration) -> Option<(u16, Vec<DeviceInfo>)> {
+        let mut pending = self.inner.pending.lock();
+        if pending.is_none() {
+            self.inner.cond.wait_for(&mut pending, timeout);
+        }
+        pending.take()
+    }
+    /// 同一个纪元只告警一次
+    fn first_report(&self, epoch: u16) -> bool {
+        self.inner.reported.swap(epoch as u32, Ordering::Relaxed) != epoch as u32
+    }
+    /// 截断超过上限的部分，返回原来的数量
+    fn limit(&self, list: &mut Vec<DeviceInfo>) -> Option<usize> {
+        let len = list.len();
+        if len <= self.inner.max_devices {
+            return None;
+        }
+        list.truncate(self.inner.max_devices);
+        self.inner.oversized.inc();
+        Some(len)
+    }
+    /// 分批转换，批之间让出cpu，丢弃地址为空和重复的条目
+    fn convert(&self, epoch: u16, list: Vec<DeviceInfo>) -> Vec<PeerDeviceInfo> {
+        let mut seen = HashSet::with_capacity(list.len());
+        let mut out = Vec::with_capacity(list.len());
+        let mut invalid = 0;
+        for (index, info) in list.into_iter().enumerate() {
+            if index != 0 && index % CHUNK == 0 {
+                thread::yield_now();
+            }
+            let ip = Ipv4Addr::from(info.virtual_ip);
+            if ip.is_unspecified() || !seen.insert(ip) {
+                invalid += 1;
+                continue;
+            }
+            out.push(peer_device_info(info));
+        }
+        if invalid != 0 {
+            self.inner.malformed.inc();
+            if self.first_report(epoch) {
+                log::warn!("设备列表中有{}个无效或重复的条目 epoch={}", invalid, epoch);
+            }
+        }
+        out
+    }
+}
+
+/// 在单独的线程中应用设备列表，接收线程不会因此阻塞
+pub fn start<Call: VntCallback>(
+    directory: Directory,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    callback: Call,
+) {
+    thread::Builder::new()
+        .name("directory".into())
+        .spawn(move || {
+            while !context.is_stop() {
+                if let Some((epoch, list)) = directory.take(Duration::from_secs(1)) {
+                    apply(
+                        &directory,
+                        &context,
+                        &current_device,
+                        &device_list,
+                        &callback,
+                        epoch,
+                        list,
+                    );
+                }
+            }
+        })
+        .expect("directory");
+}
+
+fn apply<Call: VntCallback>(
+    directory: &Directory,
+    context: &ChannelContext,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    callback: &Call,
+    epoch: u16,
+    mut list: Vec<DeviceInfo>,
+) {
+    if let Some(len) = directory.limit(&mut list) {
+        if directory.first_report(epoch) {
+            let max = directory.inner.max_devices;
+            log::warn!(
+                "服务端下发了{}个设备,超过上限{},只使用前{}个",
+                len,
+                max,
+                max
+            );
+            callback.error(ErrorInfo::new_msg(
+                ErrorType::Unknown,
+                format!(
+                    "the server sent {} devices, only the first {} are used, see '--max-devices'",
+                    len, max
+                ),
+            ));
+        }
+    }
+    let ip_list = directory.convert(epoch, list);
+    if let Err(current) = replace(device_list, epoch, &ip_list) {
+        directory
+            .inner
+            .stale
+            .record(format_args!("epoch={} current={}", epoch, current));
+        return;
+    }
+    context.follow_blocked(&ip_list);
+    // 对端下线后不再保留手动设置，重新上线时使用默认设置
+    let online: Vec<Ipv4Addr> = ip_list
+        .iter()
+        .filter(|v| v.status.is_online())
+        .map(|v| v.virtual_ip)
+        .collect();
+    context.peer_features.retain_peers(&online);
+    context.bring_up.retain_peers(&online);
+    context.punch_sync.retain_peers(&online);
+    context.route_health.retain_peers(&online);
+    context.route_table.path_scores.retain_peers(&online);
+    context.peer_stats.retain_peers(&online);
+    // 服务端重启后重新注册，列表里已经没有或者下线的设备不再保留路由
+    let gateway = current_device.load().virtual_gateway;
+    let routes: Vec<Ipv4Addr> = context
+        .route_table
+        .route_table()
+        .into_iter()
+        .map(|(ip, _)| ip)
+        .collect();
+    for ip in stale_routes(&routes, &ip_list, gateway) {
+        log::info!("设备列表中已没有{},删除路由", ip);
+        context.route_table.remove_ip(&ip, EvictReason::Offline);
+    }
+    callback.peer_client_list(
+        ip_list
+            .into_iter()
+            .map(|v| {
+                PeerClientInfo::new(
+                    v.virtual_ip,
+                    v.name,
+                    v.status,
+                    v.client_secret,
+                    v.fingerprint,
+                )
+            })
+            .collect(),
+    );
+}
+
+/// 有路由但是不在设备列表中或者已经下线的地址，网关除外
+fn stale_routes(
+    routes: &[Ipv4Addr],
+    ip_list: &[PeerDeviceInfo],
+    gateway: Ipv4Addr,
+) -> Vec<Ipv4Addr> {
+    let known: HashSet<Ipv4Addr> = ip_list
+        .iter()
+        .filter(|v| v.status.is_online())
+        .map(|v| v.virtual_ip)
+        .collect();
+    routes
+        .iter()
+        .filter(|ip| **ip != gateway && !known.contains(ip))
+        .copied()
+        .collect()
+}
+
+/// 只在替换时持有锁，复制和释放旧列表都在锁外，比当前纪元旧时不替换，返回当前纪元
+fn replace(
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    epoch: u16,
+    ip_list: &[PeerDeviceInfo],
+) -> Result<(), u16> {
+    let copy = ip_list.to_vec();
+    let old = {
+        let mut dev = device_list.lock();
+        if dev.0 != 0 && serial_lt(epoch, dev.0) {
+            return Err(dev.0);
+        }
+        dev.0 = epoch;
+        std::mem::replace(&mut dev.1, copy)
+    };
+    drop(old);
+    Ok(())
+}
+
+/// 名称和指纹来自其他设备，解析时限制长度并去掉控制字符
+fn peer_device_info(info: DeviceInfo) -> PeerDeviceInfo {
+    let mut peer = PeerDeviceInfo::new(
+        Ipv4Addr::from(info.virtual_ip),
+        sanitize::name(&info.name),
+        info.device_status as u8,
+        info.client_secret,
+        info.client_secret_hash,
+        info.observer,
+        sanitize::text(&info.fingerprint, FINGERPRINT_MAX_LEN, false),
+    );
+    peer.closest_server = info.closest_server.parse().ok();
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+
+    use crate::handle::directory::{peer_device_info, replace, stale_routes, Directory};
+    use crate::handle::PeerDeviceInfo;
+    use crate::proto::message::DeviceInfo;
+    use crate::util::metrics::Registry;
+    use crate::util::sanitize;
+
+    fn device(ip: u32) -> DeviceInfo {
+        let mut info = DeviceInfo::new();
+        info.virtual_ip = ip;
+        info.name = format!("pc-{}", ip);
+        info
+    }
+
+    #[test]
+    fn test_peer_device_info() {
+        let mut info = DeviceInfo::new();
+        info.virtual_ip = u32::from(Ipv4Addr::new(10, 26, 0, 3));
+        info.name = format!(
+            "pc\x1b[2J\x1b[1;1H\n2024-01-01 00:00:00 INFO fake log{}",
+            "x".repeat(1000)
+        );
+        info.fingerprint = "ab\rcd".to_string();
+        info.closest_server = "1.2.3.4:29872\n".to_string();
+        let peer = peer_device_info(info);
+        // 不能清屏，也不能伪造一行日志
+        assert!(peer.name.starts_with("pc2024-01-01"));
+        assert!(!peer.name.contains(|c: char| c.is_control()));
+        assert_eq!(peer.name.chars().count(), sanitize::NAME_MAX_LEN);
+        assert_eq!(peer.fingerprint, "abcd");
+        // 无法解析的提示忽略
+        assert_eq!(peer.closest_server, None);
+    }
+
+    #[test]
+    fn test_limit() {
+        let registry = Registry::new();
+        let directory = Directory::new(100, &registry);
+        let base = u32::from(Ipv4Addr::new(10, 26, 0, 2));
+        let mut list: Vec<DeviceInfo> = (0..1000).map(|i| device(base + i)).collect();
+        list.push(device(base));
+        list.insert(0, device(0));
+        list.insert(0, device(base + 1));
+        assert_eq!(directory.limit(&mut list), Some(1003));
+        assert_eq!(list.len(), 100);
+        // 0.0.0.0和重复的丢弃
+        let peers = directory.convert(1, list);
+        assert_eq!(peers.len(), 98);
+        assert_eq!(directory.inner.oversized.get(), 1);
+        assert_eq!(directory.inner.malformed.get(), 1);
+        // 只保留最新的
+        directory.submit(1, vec![device(base)]);
+        directory.submit(2, vec![device(base), device(base + 1)]);
+        let (epoch, list) = directory.take(Duration::from_millis(1)).unwrap();
+        assert_eq!((epoch, list.len()), (2, 2));
+        assert_eq!(directory.inner.superseded.get(), 1);
+        assert!(directory.take(Duration::from_millis(1)).is_none());
+    }
+
+    /// 乱序到达的旧列表不覆盖新的，纪元回绕后仍然是新的
+    #[test]
+    fn test_stale_epoch() {
+        let registry = Registry::new();
+        let directory = Directory::new(100, &registry);
+        let base = u32::from(Ipv4Addr::new(10, 26, 0, 2));
+        directory.submit(u16::MAX, vec![device(base)]);
+        directory.submit(u16::MAX - 1, vec![]);
+        let (epoch, list) = directory.take(Duration::from_millis(1)).unwrap();
+        assert_eq!((epoch, list.len()), (u16::MAX, 1));
+        assert_eq!(directory.inner.stale.count(), 1);
+
+        let device_list = Mutex::new((0, Vec::new()));
+        let ip_list = directory.convert(epoch, list);
+        assert_eq!(replace(&device_list, u16::MAX, &ip_list), Ok(()));
+        assert_eq!(replace(&device_list, u16::MAX - 3, &[]), Err(u16::MAX));
+        assert_eq!(device_list.lock().1.len(), 1);
+        assert_eq!(replace(&device_list, 1, &[]), Ok(()));
+        assert_eq!(device_list.lock().0, 1);
+        // 掉线后归零，接受任意纪元
+        device_list.lock().0 = 0;
+        assert_eq!(replace(&device_list, 40_000, &ip_list), Ok(()));
+    }
+
+    #[test]
+    fn test_stale_routes() {
+        let directory = Directory::new(100, &Registry::new());
+        let gateway = Ipv4Addr::new(10, 26, 0, 1);
+        let a = Ipv4Addr::new(10, 26, 0, 2);
+        let b = Ipv4Addr::new(10, 26, 0, 3);
+        let c = Ipv4Addr::new(10, 26, 0, 4);
+        let ip_list = directory.convert(1, vec![device(u32::from(a)), device(u32::from(c))]);
+        assert_eq!(stale_routes(&[gateway, a, b], &ip_list, gateway), vec![b]);
+        // 还在列表中但是已经下线
+        let mut offline = device(u32::from(c));
+        offline.device_status = 1;
+        let ip_list = directory.convert(2, vec![device(u32::from(a)), offline]);
+        assert_eq!(stale_routes(&[gateway, a, c], &ip_list, gateway), vec![c]);
+        // 服务端重启后列表为空，只保留网关
+        assert_eq!(stale_routes(&[gateway, a, b], &[], gateway), vec![a, b]);
+    }
+
+    /// 应用很大的设备列表时，数据面对设备列表锁的等待时间不受影响
+    #[test]
+    fn test_large_directory() {
+        let registry = Registry::new();
+        let directory = Directory::new(usize::MAX, &registry);
+        let device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>> =
+            Arc::new(Mutex::new((0, Vec::new())));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let device_list = device_list.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut worst = Duration::ZERO;
+                while !stop.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    let len = device_list.lock().1.len();
+                    worst = worst.max(start.elapsed());
+                    std::hint::black_box(len);
+                }
+                worst
+            })
+        };
+        let base = u32::from(Ipv4Addr::new(10, 0, 0, 1));
+        for epoch in 0..3u16 {
+            let list = (0..200_000).map(|i| device(base + i)).collect();
+            directory.submit(epoch, list);
+            let (epoch, list) = directory.take(Duration::from_millis(1)).unwrap();
+            let ip_list = directory.convert(epoch, list);
+            replace(&device_list, epoch, &ip_list).unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        let worst = reader.join().unwrap();
+        assert_eq!(device_list.lock().1.len(), 200_000);
+        assert!(worst < Duration::from_millis(50), "{:?}", worst);
+    }
+}
@@ -0,0 +1,897 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use rand::Rng;
+
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::util::subsystem::Switch;
+use crate::util::StopManager;
+
+/// 默认监听地址
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:53";
+/// 内置解析的域名后缀，<设备名>.vnt解析成设备的虚拟ip
+pub const ZONE: &str = "vnt";
+/// 没有配置--dns时，未匹配规则的查询转发到这里
+const DEFAULT_UPSTREAM: [&str; 2] = ["223.5.5.5:53", "114.114.114.114:53"];
+/// 缓存的应答数量上限
+pub const CACHE_LIMIT: usize = 1024;
+/// 等待上游应答的时间，超时返回SERVFAIL
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+/// 同时等待上游应答的查询数量上限
+const INFLIGHT_LIMIT: usize = 64;
+/// 缓存时间上限，秒
+const MAX_TTL: u32 = 3600;
+/// 内置解析的ttl，秒
+const LOCAL_TTL: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_OPT: u16 = 41;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// 转发规则，匹配后缀的查询通过虚拟网络发给对端的dns服务
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsRoute {
+    /// 小写，不带末尾的点
+    pub suffix: String,
+    pub server: SocketAddr,
+}
+
+impl DnsRoute {
+    fn matches(&self, name: &str) -> bool {
+        name == self.suffix
+            || (name.len() > self.suffix.len()
+                && name.ends_with(&self.suffix)
+                && name.as_bytes()[name.len() - self.suffix.len() - 1] == b'.')
+    }
+}
+
+impl FromStr for DnsRoute {
+    type Err = String;
+
+    /// corp.example=10.26.0.2 或 corp.example=10.26.0.2:5353
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (suffix, server) = match s.split_once('=') {
+            Some(v) => v,
+            None => return Err(format!("{:?} expected <domain>=<ip>", s)),
+        };
+        let suffix = suffix.trim().trim_end_matches('.').to_lowercase();
+        if suffix.is_empty()
+            || suffix.len() > 253
+            || suffix.split('.').any(|label| {
+                label.is_empty()
+                    || label.len() > 63
+                    || !label
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            })
+        {
+            return Err(format!("{:?} invalid domain", suffix));
+        }
+        let server = server.trim();
+        let server = match Ipv4Addr::from_str(server) {
+            Ok(ip) => SocketAddr::from((ip, 53)),
+            Err(_) => match SocketAddr::from_str(server) {
+                Ok(addr) => addr,
+                Err(_) => return Err(format!("{:?} invalid dns server", server)),
+            },
+        };
+        Ok(Self { suffix, server })
+    }
+}
+
+impl fmt::Display for DnsRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.server.port() == 53 {
+            write!(f, "{}={}", self.suffix, self.server.ip())
+        } else {
+            write!(f, "{}={}", self.suffix, self.server)
+        }
+    }
+}
+
+/// 内置解析的数据来源
+pub trait LocalNames: Send + Sync + 'static {
+    /// 设备名(小写)对应的虚拟ip
+    fn lookup(&self, name: &str) -> Option<Ipv4Addr>;
+    /// 规则指向的对端是否在线，不在线时直接返回SERVFAIL，不用等待超时
+    fn online(&self, ip: Ipv4Addr) -> bool;
+}
+
+impl LocalNames for HashMap<String, Ipv4Addr> {
+    fn lookup(&self, name: &str) -> Option<Ipv4Addr> {
+        self.get(name).copied()
+    }
+    fn online(&self, _ip: Ipv4Addr) -> bool {
+        true
+    }
+}
+
+/// 从设备列表解析
+pub struct DeviceNames {
+    pub name: String,
+    pub current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    pub device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+}
+
+impl LocalNames for DeviceNames {
+    fn lookup(&self, name: &str) -> Option<Ipv4Addr> {
+        if self.name.eq_ignore_ascii_case(name) {
+            let ip = self.current_device.load().virtual_ip;
+            return if ip.is_unspecified() { None } else { Some(ip) };
+        }
+        self.device_list
+            .lock()
+            .1
+            .iter()
+            .find(|info| !info.observer && info.name.eq_ignore_ascii_case(name))
+            .map(|info| info.virtual_ip)
+    }
+    fn online(&self, ip: Ipv4Addr) -> bool {
+        // 不在设备列表中的地址(比如子网内的服务器)只能等待超时
+        match self
+            .device_list
+            .lock()
+            .1
+            .iter()
+            .find(|info| info.virtual_ip == ip)
+        {
+            Some(info) => info.status.is_online(),
+            None => true,
+        }
+    }
+}
+
+struct Question {
+    // 小写，不带末尾的点
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // 问题部分结束的位置
+    end: usize,
+}
+
+fn parse_question(data: &[u8]) -> Option<Question> {
+    if data.len() < HEADER_LEN || data[2] & 0x80 != 0 || u16::from_be_bytes([data[4], data[5]]) != 1
+    {
+        return None;
+    }
+    let mut pos = HEADER_LEN;
+    let name = read_name(data, &mut pos)?;
+    let rest = data.get(pos..pos + 4)?;
+    Some(Question {
+        name,
+        qtype: u16::from_be_bytes([rest[0], rest[1]]),
+        qclass: u16::from_be_bytes([rest[2], rest[3]]),
+        end: pos + 4,
+    })
+}
+
+/// 读取域名，支持压缩指针
+fn read_name(data: &[u8], pos: &mut usize) -> Option<String> {
+    let mut name = String::new();
+    let mut cur = *pos;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *data.get(cur)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let ptr = ((len & 0x3F) << 8) | *data.get(cur + 1)? as usize;
+            if !jumped {
+                *pos = cur + 2;
+                jumped = true;
+            }
+            cur = ptr;
+            continue;
+        }
+        if len == 0 {
+            if !jumped {
+                *pos = cur + 1;
+            }
+            return Some(name);
+        }
+        let label = data.get(cur + 1..cur + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label).to_lowercase());
+        cur += 1 + len;
+    }
+    None
+}
+
+fn skip_name(data: &[u8], pos: &mut usize) -> Option<()> {
+    read_name(data, pos).map(|_| ())
+}
+
+/// 应答中所有记录的ttl字段位置，不包括EDNS的OPT记录
+fn ttl_offsets(data: &[u8]) -> Option<Vec<usize>> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let count = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]) as usize;
+    let mut pos = HEADER_LEN;
+    for _ in 0..count(4) {
+        skip_name(data, &mut pos)?;
+        pos += 4;
+    }
+    let mut offsets = Vec::new();
+    for _ in 0..count(6) + count(8) + count(10) {
+        skip_name(data, &mut pos)?;
+        let head = data.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([head[0], head[1]]);
+        let rdlen = u16::from_be_bytes([head[8], head[9]]) as usize;
+        if rtype != TYPE_OPT {
+            offsets.push(pos + 4);
+        }
+        pos += 10 + rdlen;
+        if pos > data.len() {
+            return None;
+        }
+    }
+    Some(offsets)
+}
+
+fn read_ttl(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// 只带问题部分的应答
+fn reply(query: &[u8], question: &Question, rcode: u8, answer: Option<Ipv4Addr>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(question.end + 16);
+    out.extend_from_slice(&query[..2]);
+    // QR，保留opcode和RD，AA只在内置解析时设置
+    let aa = if rcode == RCODE_SERVFAIL { 0 } else { 0x04 };
+    out.push(0x80 | aa | (query[2] & 0x79));
+    // RA
+    out.push(0x80 | rcode);
+    out.extend_from_slice(&[0, 1, 0, answer.is_some() as u8, 0, 0, 0, 0]);
+    out.extend_from_slice(&query[HEADER_LEN..question.end]);
+    if let Some(ip) = answer {
+        out.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        out.extend_from_slice(&TYPE_A.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&LOCAL_TTL.to_be_bytes());
+        out.extend_from_slice(&4u16.to_be_bytes());
+        out.extend_from_slice(&ip.octets());
+    }
+    out
+}
+
+struct CacheEntry {
+    data: Vec<u8>,
+    ttl_offsets: Vec<usize>,
+    inserted: Instant,
+    expire: Instant,
+}
+
+type CacheKey = (String, u16, u16);
+
+/// 按ttl过期的应答缓存，满了先清理过期的，再淘汰最早过期的
+struct DnsCache {
+    map: HashMap<CacheKey, CacheEntry>,
+    limit: usize,
+}
+
+impl DnsCache {
+    fn get(&mut self, key: &CacheKey, id: [u8; 2], now: Instant) -> Option<Vec<u8>> {
+        let entry = self.map.get(key)?;
+        if now >= entry.expire {
+            self.map.remove(key);
+            return None;
+        }
+        let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+        let mut data = entry.data.clone();
+        data[..2].copy_from_slice(&id);
+        for offset in &entry.ttl_offsets {
+            let ttl = read_ttl(&entry.data, *offset).saturating_sub(elapsed);
+            data[*offset..*offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(data)
+    }
+    fn insert(&mut self, key: CacheKey, data: Vec<u8>, now: Instant) {
+        // 截断的应答和SERVFAIL等不缓存
+        if data.len() < HEADER_LEN || data[2] & 0x02 != 0 {
+            return;
+        }
+        let rcode = data[3] & 0x0F;
+        if rcode != 0 && rcode != RCODE_NXDOMAIN {
+            return;
+        }
+        let ttl_offsets = match ttl_offsets(&data) {
+            Some(v) => v,
+            None => return,
+        };
+        // 没有记录的应答无法得知ttl，不缓存
+        let ttl = match ttl_offsets.iter().map(|v| read_ttl(&data, *v)).min() {
+            Some(ttl) => ttl.min(MAX_TTL),
+            None => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+        if self.map.len() >= self.limit && !self.map.contains_key(&key) {
+            self.map.retain(|_, entry| entry.expire > now);
+            if self.map.len() >= self.limit {
+                let oldest = self
+                    .map
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expire)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.map.remove(&oldest);
+                }
+            }
+        }
+        self.map.insert(
+            key,
+            CacheEntry {
+                data,
+                ttl_offsets,
+                inserted: now,
+                expire: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+struct RouteState {
+    route: DnsRoute,
+    hits: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// 一条规则的统计
+#[derive(Clone, Debug)]
+pub struct DnsRouteStat {
+    pub route: DnsRoute,
+    pub hits: u64,
+    pub failures: u64,
+}
+
+/// dns命令显示的内容
+#[derive(Clone, Debug)]
+pub struct DnsStats {
+    pub listen: Option<SocketAddr>,
+    pub routes: Vec<DnsRouteStat>,
+    pub upstream: Vec<SocketAddr>,
+    pub cache_size: usize,
+    pub cache_limit: usize,
+    pub queries: u64,
+    pub cache_hits: u64,
+    pub local: u64,
+    pub servfail: u64,
+}
+
+/// 需要转发的查询
+pub struct Forward {
+    query: Vec<u8>,
+    question: Question,
+    route: Option<usize>,
+}
+
+enum Prepared {
+    Ignore,
+    Reply(Vec<u8>),
+    Forward(Forward),
+}
+
+/// 虚拟网络的dns应答
+///
+/// <设备名>.vnt直接应答，匹配规则的查询按最长后缀转发给对端的dns服务，
+/// 其余转发给普通的上游，转发的应答按ttl缓存
+#[derive(Clone)]
+pub struct DnsServer {
+    inner: Arc<DnsInner>,
+}
+
+struct DnsInner {
+    zone: String,
+    // 按后缀长度从长到短
+    routes: Vec<RouteState>,
+    upstream: Vec<SocketAddr>,
+    timeout: Duration,
+    local: Box<dyn LocalNames>,
+    cache: Mutex<DnsCache>,
+    listen: Mutex<Option<SocketAddr>>,
+    queries: AtomicU64,
+    cache_hits: AtomicU64,
+    local_answers: AtomicU64,
+    servfail: AtomicU64,
+    inflight: AtomicUsize,
+}
+
+impl DnsServer {
+    pub fn new<L: LocalNames>(
+        zone: &str,
+        mut routes: Vec<DnsRoute>,
+        upstream: Vec<SocketAddr>,
+        local: L,
+    ) -> Self {
+        routes.sort_by(|a, b| b.suffix.len().cmp(&a.suffix.len()));
+        let upstream = if upstream.is_empty() {
+            DEFAULT_UPSTREAM
+                .iter()
+                .map(|v| SocketAddr::from_str(v).unwrap())
+                .collect()
+        } else {
+            upstream
+        };
+        Self {
+            inner: Arc::new(DnsInner {
+                zone: zone.to_lowercase(),
+                routes: routes
+                    .into_iter()
+                    .map(|route| RouteState {
+                        route,
+                        hits: AtomicU64::new(0),
+                        failures: AtomicU64::new(0),
+                    })
+                    .collect(),
+                upstream,
+                timeout: UPSTREAM_TIMEOUT,
+                local: Box::new(local),
+                cache: Mutex::new(DnsCache {
+                    map: HashMap::new(),
+                    limit: CACHE_LIMIT,
+                }),
+                listen: Mutex::new(None),
+                queries: AtomicU64::new(0),
+                cache_hits: AtomicU64::new(0),
+                local_answers: AtomicU64::new(0),
+                servfail: AtomicU64::new(0),
+                inflight: AtomicUsize::new(0),
+            }),
+        }
+    }
+    #[cfg(test)]
+    fn with_timeout(mut self, timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.inner).unwrap().timeout = timeout;
+        self
+    }
+    pub fn stats(&self) -> DnsStats {
+        let inner = &self.inner;
+        let (cache_size, cache_limit) = {
+            let cache = inner.cache.lock();
+            (cache.map.len(), cache.limit)
+        };
+        DnsStats {
+            listen: *inner.listen.lock(),
+            routes: inner
+                .routes
+                .iter()
+                .map(|state| DnsRouteStat {
+                    route: state.route.clone(),
+                    hits: state.hits.load(Ordering::Relaxed),
+                    failures: state.failures.load(Ordering::Relaxed),
+                })
+                .collect(),
+            upstream: inner.upstream.clone(),
+            cache_size,
+            cache_limit,
+            queries: inner.queries.load(Ordering::Relaxed),
+            cache_hits: inner.cache_hits.load(Ordering::Relaxed),
+            local: inner.local_answers.load(Ordering::Relaxed),
+            servfail: inner.servfail.load(Ordering::Relaxed),
+        }
+    }
+    /// 同步处理一个查询，不是dns查询时返回None
+    pub fn handle(&self, query: &[u8]) -> Option<Vec<u8>> {
+        match self.prepare(query, Instant::now()) {
+            Prepared::Ignore => None,
+            Prepared::Reply(data) => Some(data),
+            Prepared::Forward(forward) => Some(self.forward(forward)),
+        }
+    }
+    fn local_name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let zone = &self.inner.zone;
+        if name == zone {
+            return Some("");
+        }
+        name.strip_suffix(zone.as_str())
+            .and_then(|v| v.strip_suffix('.'))
+    }
+    fn prepare(&self, query: &[u8], now: Instant) -> Prepared {
+        let question = match parse_question(query) {
+            Some(question) => question,
+            None => return Prepared::Ignore,
+        };
+        let inner = &self.inner;
+        inner.queries.fetch_add(1, Ordering::Relaxed);
+        if let Some(host) = self.local_name(&question.name) {
+            inner.local_answers.fetch_add(1, Ordering::Relaxed);
+            let ip = if host.is_empty() {
+                None
+            } else {
+                inner.local.lookup(host)
+            };
+            return Prepared::Reply(match ip {
+                Some(ip)
+                    if question.qclass == CLASS_IN
+                        && (question.qtype == TYPE_A || question.qtype == TYPE_ANY) =>
+                {
+                    reply(query, &question, 0, Some(ip))
+                }
+                // 名字存在但没有这个类型的记录
+                Some(_) => reply(query, &question, 0, None),
+                None if host.is_empty() => reply(query, &question, 0, None),
+                None => reply(query, &question, RCODE_NXDOMAIN, None),
+            });
+        }
+        let key = (question.name.clone(), question.qtype, question.qclass);
+        if let Some(data) = inner.cache.lock().get(&key, [query[0], query[1]], now) {
+            inner.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Prepared::Reply(data);
+        }
+        let route = inner
+            .routes
+            .iter()
+            .position(|state| state.route.matches(&question.name));
+        if let Some(index) = route {
+            let state = &inner.routes[index];
+            state.hits.fetch_add(1, Ordering::Relaxed);
+            if let SocketAddr::V4(addr) = state.route.server {
+                if !inner.local.online(*addr.ip()) {
+                    log::warn!("dns {} 转发失败,对端{}不在线", question.name, addr.ip());
+                    state.failures.fetch_add(1, Ordering::Relaxed);
+                    inner.servfail.fetch_add(1, Ordering::Relaxed);
+                    return Prepared::Reply(reply(query, &question, RCODE_SERVFAIL, None));
+                }
+            }
+        }
+        Prepared::Forward(Forward {
+            query: query.to_vec(),
+            question,
+            route,
+        })
+    }
+    fn servfail(&self, forward: &Forward) -> Vec<u8> {
+        self.inner.servfail.fetch_add(1, Ordering::Relaxed);
+        if let Some(index) = forward.route {
+            self.inner.routes[index]
+                .failures
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        reply(&forward.query, &forward.question, RCODE_SERVFAIL, None)
+    }
+    /// 转发给上游，超时返回SERVFAIL
+    fn forward(&self, forward: Forward) -> Vec<u8> {
+        let inner = &self.inner;
+        let servers = match forward.route {
+            Some(index) => vec![inner.routes[index].route.server],
+            None => inner.upstream.clone(),
+        };
+        let mut last_err = None;
+        for server in servers {
+            match self.exchange(&forward.query, server) {
+                Ok(data) => {
+                    let key = (
+                        forward.question.name.clone(),
+                        forward.question.qtype,
+                        forward.question.qclass,
+                    );
+                    inner.cache.lock().insert(key, data.clone(), Instant::now());
+                    return data;
+                }
+                Err(e) => last_err = Some((server, e)),
+            }
+        }
+        if let Some((server, e)) = last_err {
+            log::warn!(
+                "dns {} 转发到{}失败,返回SERVFAIL {:?}",
+                forward.question.name,
+                server,
+                e
+            );
+        }
+        self.servfail(&forward)
+    }
+    fn exchange(&self, query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+        let socket = if server.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        // 使用新的id，避免伪造的应答
+        let id: u16 = rand::thread_rng().gen();
+        let mut request = query.to_vec();
+        request[..2].copy_from_slice(&id.to_be_bytes());
+        socket.send_to(&request, server)?;
+        let deadline = Instant::now() + self.inner.timeout;
+        let mut buf = [0u8; 4096];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+                }
+                Err(e) => return Err(e),
+            };
+            if addr != server
+                || len < HEADER_LEN
+                || buf[..2] != id.to_be_bytes()
+                || buf[2] & 0x80 == 0
+            {
+                continue;
+            }
+            let mut data = buf[..len].to_vec();
+            data[..2].copy_from_slice(&query[..2]);
+            return Ok(data);
+        }
+    }
+}
+
+/// dns服务监听的socket，第一次绑定成功后一直保留
+///
+/// '--user'切换到非特权用户后不能再绑定53这样的特权端口，重新启动dns服务时复用这个socket
+#[derive(Clone)]
+pub struct Listener {
+    addr: SocketAddr,
+    socket: Arc<Mutex<Option<UdpSocket>>>,
+}
+
+impl Listener {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            socket: Arc::new(Mutex::new(None)),
+        }
+    }
+    fn socket(&self) -> io::Result<UdpSocket> {
+        let mut guard = self.socket.lock();
+        if let Some(socket) = guard.as_ref() {
+            return socket.try_clone();
+        }
+        let socket = UdpSocket::bind(self.addr)?;
+        let clone = socket.try_clone()?;
+        guard.replace(socket);
+        Ok(clone)
+    }
+}
+
+/// 启动dns服务，返回实际监听的地址
+/// switch关闭后线程在1秒内退出，不再处理查询
+pub fn start(
+    stop_manager: StopManager,
+    server: DnsServer,
+    listener: &Listener,
+    switch: &Switch,
+) -> io::Result<SocketAddr> {
+    let socket = listener.socket()?;
+    let local_addr = socket.local_addr()?;
+    let worker = stop_manager.add_worker("dns".into())?;
+    server.inner.listen.lock().replace(local_addr);
+    let switch_ = switch.clone();
+    switch.spawn("dns", move || {
+        serve(socket, server.clone(), || {
+            stop_manager.is_stop() || switch_.is_off()
+        });
+        server.inner.listen.lock().take();
+        drop(worker);
+    })?;
+    Ok(local_addr)
+}
+
+fn serve(socket: UdpSocket, server: DnsServer, is_stop: impl Fn() -> bool) {
+    if let Err(e) = socket.set_read_timeout(Some(Duration::from_secs(1))) {
+        log::warn!("dns {:?}", e);
+    }
+    let mut buf = [0u8; 1500];
+    while !is_stop() {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let forward = match server.prepare(&buf[..len], Instant::now()) {
+            Prepared::Ignore => continue,
+            Prepared::Reply(data) => {
+                let _ = socket.send_to(&data, src);
+                continue;
+            }
+            Prepared::Forward(forward) => forward,
+        };
+        let inflight = &server.inner.inflight;
+        if inflight.fetch_add(1, Ordering::Relaxed) >= INFLIGHT_LIMIT {
+            inflight.fetch_sub(1, Ordering::Relaxed);
+            let _ = socket.send_to(&server.servfail(&forward), src);
+            continue;
+        }
+        let socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                inflight.fetch_sub(1, Ordering::Relaxed);
+                log::warn!("dns {:?}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        let rs = std::thread::Builder::new()
+            .name("dnsForward".into())
+            .spawn(move || {
+                let data = server.forward(forward);
+                let _ = socket.send_to(&data, src);
+                server.inner.inflight.fetch_sub(1, Ordering::Relaxed);
+            });
+        if let Err(e) = rs {
+            inflight.fetch_sub(1, Ordering::Relaxed);
+            log::warn!("dns {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{DnsRoute, DnsServer, ZONE};
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut out = id.to_be_bytes().to_vec();
+        out.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out.extend_from_slice(&qtype.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out
+    }
+
+    fn rcode(data: &[u8]) -> u8 {
+        data[3] & 0x0F
+    }
+
+    fn answer_ip(data: &[u8]) -> Ipv4Addr {
+        let n = data.len();
+        Ipv4Addr::new(data[n - 4], data[n - 3], data[n - 2], data[n - 1])
+    }
+
+    /// 另一个实例作为对端的dns服务，解析corp.example
+    fn mock_upstream(stop: Arc<AtomicBool>) -> SocketAddr {
+        let mut names = HashMap::new();
+        names.insert("db".to_string(), Ipv4Addr::new(10, 26, 0, 9));
+        let server = DnsServer::new("corp.example", vec![], vec![], names);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || super::serve(socket, server, || stop.load(Ordering::Relaxed)));
+        addr
+    }
+
+    #[test]
+    fn test_listener_reuse() {
+        let listener = super::Listener::new("127.0.0.1:0".parse().unwrap());
+        let first = listener.socket().unwrap().local_addr().unwrap();
+        // 不重新绑定，端口不变
+        let second = listener.socket().unwrap().local_addr().unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first.port(), 0);
+    }
+
+    #[test]
+    fn test_route() {
+        let route: DnsRoute = "Corp.Example.=10.26.0.2".parse().unwrap();
+        assert_eq!(route.suffix, "corp.example");
+        assert_eq!(route.server, "10.26.0.2:53".parse().unwrap());
+        assert_eq!(route.to_string(), "corp.example=10.26.0.2");
+        assert!(route.matches("corp.example"));
+        assert!(route.matches("db.corp.example"));
+        assert!(!route.matches("xcorp.example"));
+        assert!("corp.example".parse::<DnsRoute>().is_err());
+        assert!("a..b=10.26.0.2".parse::<DnsRoute>().is_err());
+        assert!("a=10.26.0".parse::<DnsRoute>().is_err());
+    }
+
+    #[test]
+    fn test_forward_and_cache() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let upstream = mock_upstream(stop.clone());
+        // 最长后缀优先
+        let routes = vec![
+            format!("example={}", "127.0.0.1:9").parse().unwrap(),
+            format!("corp.example={}", upstream).parse().unwrap(),
+        ];
+        let mut names = HashMap::new();
+        names.insert("nas".to_string(), Ipv4Addr::new(10, 26, 0, 5));
+        let server = DnsServer::new(ZONE, routes, vec![], names);
+
+        let data = server.handle(&query(7, "nas.vnt", 1)).unwrap();
+        assert_eq!(&data[..2], &7u16.to_be_bytes());
+        assert_eq!(answer_ip(&data), Ipv4Addr::new(10, 26, 0, 5));
+        let data = server.handle(&query(8, "none.vnt", 1)).unwrap();
+        assert_eq!(rcode(&data), 3);
+
+        let data = server.handle(&query(9, "db.corp.example", 1)).unwrap();
+        assert_eq!(rcode(&data), 0);
+        assert_eq!(&data[..2], &9u16.to_be_bytes());
+        assert_eq!(answer_ip(&data), Ipv4Addr::new(10, 26, 0, 9));
+        // 上游停止后仍然从缓存应答
+        stop.store(true, Ordering::Relaxed);
+        let data = server.handle(&query(10, "DB.corp.example", 1)).unwrap();
+        assert_eq!(&data[..2], &10u16.to_be_bytes());
+        assert_eq!(answer_ip(&data), Ipv4Addr::new(10, 26, 0, 9));
+
+        let stats = server.stats();
+        assert_eq!(stats.queries, 4);
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_size, 1);
+        assert_eq!(stats.routes[0].route.suffix, "corp.example");
+        assert_eq!(stats.routes[0].hits, 1);
+        assert_eq!(stats.routes[1].hits, 0);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut names = HashMap::new();
+        names.insert("db".to_string(), Ipv4Addr::new(10, 26, 0, 9));
+        let upstream = DnsServer::new("corp.example", vec![], vec![], names);
+        let data = upstream.handle(&query(1, "db.corp.example", 1)).unwrap();
+        let mut cache = super::DnsCache {
+            map: HashMap::new(),
+            limit: 2,
+        };
+        let now = Instant::now();
+        let key = |name: &str| (name.to_string(), 1u16, 1u16);
+        cache.insert(key("a"), data.clone(), now);
+        let hit = cache
+            .get(&key("a"), [0, 2], now + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(super::read_ttl(&hit, hit.len() - 10), 40);
+        assert!(cache
+            .get(&key("a"), [0, 3], now + Duration::from_secs(60))
+            .is_none());
+        // 超过上限时淘汰最早过期的
+        cache.insert(key("a"), data.clone(), now);
+        cache.insert(key("b"), data.clone(), now + Duration::from_secs(1));
+        cache.insert(key("c"), data, now + Duration::from_secs(2));
+        assert_eq!(cache.map.len(), 2);
+        assert!(!cache.map.contains_key(&key("a")));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        // 不应答的对端
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let route = format!("corp.example={}", silent.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = DnsServer::new(ZONE, vec![route], vec![], HashMap::new())
+            .with_timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        let data = server.handle(&query(3, "db.corp.example", 1)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(rcode(&data), 2);
+        assert_eq!(&data[..2], &3u16.to_be_bytes());
+        let stats = server.stats();
+        assert_eq!(stats.servfail, 1);
+        assert_eq!(stats.routes[0].failures, 1);
+        assert_eq!(stats.cache_size, 0);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 默认最多记录的流数量，超出时淘汰最久没有活动的
+pub const FLOW_CAPACITY: usize = 1024;
+/// 超过这个时间没有活动的流不再显示
+const FLOW_IDLE: Duration = Duration::from_secs(120);
+/// 统计近期流量的时间窗口
+const RECENT_WINDOW: Duration = Duration::from_secs(10);
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMP: u8 = 1;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// 流的标识，只包含ip头和传输层头中的地址信息
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FlowKey {
+    /// 对端虚拟ip
+    pub peer: Ipv4Addr,
+    /// ip头中的协议号
+    pub protocol: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    pub fn protocol_name(&self) -> String {
+        match self.protocol {
+            TCP => "tcp".to_string(),
+            UDP => "udp".to_string(),
+            ICMP => "icmp".to_string(),
+            v => format!("ip({})", v),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FlowEntry {
+    bytes: u64,
+    packets: u64,
+    recent_bytes: u64,
+    recent_start: Instant,
+    last_active: Instant,
+}
+
+/// 流的统计信息
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlowInfo {
+    pub key: FlowKey,
+    pub bytes: u64,
+    pub packets: u64,
+    /// 最近一个统计窗口内的字节数
+    pub recent_bytes: u64,
+    /// 距离上次活动的时间
+    pub idle: Duration,
+}
+
+impl Display for FlowInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.key.dst_port == 0 {
+            write!(f, "from {} {}", self.key.peer, self.key.protocol_name())
+        } else {
+            write!(
+                f,
+                "from {} {}/{}",
+                self.key.peer,
+                self.key.protocol_name(),
+                self.key.dst_port
+            )
+        }
+    }
+}
+
+type NotifyFn = Arc<dyn Fn(FlowInfo) + Send + Sync>;
+
+/// 接收方向的流统计
+///
+/// 只读取ip头和传输层头中的协议和端口，不保存任何负载。
+/// 数量有上限，满了之后淘汰最久没有活动的流
+#[derive(Clone)]
+pub struct FlowTable {
+    enabled: Arc<AtomicBool>,
+    capacity: usize,
+    inner: Arc<Mutex<HashMap<FlowKey, FlowEntry>>>,
+    notify: Option<NotifyFn>,
+    notify_enabled: Arc<AtomicBool>,
+}
+
+impl FlowTable {
+    /// notify在首次看到一个入站流时调用
+    pub fn new(enabled: bool, capacity: usize, notify: Option<NotifyFn>) -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+            capacity: capacity.max(1),
+            inner: Arc::new(Mutex::new(HashMap::with_capacity(if enabled {
+                capacity.min(64)
+            } else {
+                0
+            }))),
+            notify_enabled: Arc::new(AtomicBool::new(notify.is_some())),
+            notify,
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    /// 运行中开关，关闭时清空已记录的流
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            let mut guard = self.inner.lock();
+            guard.clear();
+            guard.shrink_to_fit();
+        }
+    }
+    /// 创建时没有传入notify则不会通知
+    pub fn set_notify(&self, enabled: bool) {
+        self.notify_enabled.store(enabled, Ordering::Relaxed);
+    }
+    pub fn is_notify(&self) -> bool {
+        self.notify.is_some() && self.notify_enabled.load(Ordering::Relaxed)
+    }
+    /// 记录收到的ipv4包
+    #[inline]
+    pub fn record(&self, peer: Ipv4Addr, ipv4: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.record_at(peer, ipv4, Instant::now())
+    }
+    fn record_at(&self, peer: Ipv4Addr, ipv4: &[u8], now: Instant) {
+        let (key, inbound) = match parse(peer, ipv4) {
+            Some(v) => v,
+            None => return,
+        };
+        let len = ipv4.len() as u64;
+        let new_flow = {
+            let mut guard = self.inner.lock();
+            if let Some(entry) = guard.get_mut(&key) {
+                if now.saturating_duration_since(entry.recent_start) > RECENT_WINDOW {
+                    entry.recent_start = now;
+                    entry.recent_bytes = 0;
+                }
+                entry.bytes += len;
+                entry.packets += 1;
+                entry.recent_bytes += len;
+                entry.last_active = now;
+                false
+            } else {
+                if guard.len() >= self.capacity {
+                    // 淘汰最久没有活动的
+                    if let Some(oldest) = guard
+                        .iter()
+                        .min_by_key(|(_, v)| v.last_active)
+                        .map(|(k, _)| *k)
+                    {
+                        guard.remove(&oldest);
+                    }
+                }
+                guard.insert(
+                    key,
+                    FlowEntry {
+                        bytes: len,
+                        packets: 1,
+                        recent_bytes: len,
+                        recent_start: now,
+                        last_active: now,
+                    },
+                );
+                inbound
+            }
+        };
+        if new_flow && self.notify_enabled.load(Ordering::Relaxed) {
+            if let Some(notify) = &self.notify {
+                notify(FlowInfo {
+                    key,
+                    bytes: len,
+                    packets: 1,
+                    recent_bytes: len,
+                    idle: Duration::ZERO,
+                });
+            }
+        }
+    }
+    /// 活跃的流，按近期流量从大到小排序
+    pub fn list(&self) -> Vec<FlowInfo> {
+        self.list_at(Instant::now())
+    }
+    fn list_at(&self, now: Instant) -> Vec<FlowInfo> {
+        let mut list: Vec<FlowInfo> = self
+            .inner
+            .lock()
+            .iter()
+            .filter(|(_, v)| now.saturating_duration_since(v.last_active) <= FLOW_IDLE)
+            .map(|(k, v)| FlowInfo {
+                key: *k,
+                bytes: v.bytes,
+                packets: v.packets,
+                recent_bytes: if now.saturating_duration_since(v.recent_start) > RECENT_WINDOW {
+                    0
+                } else {
+                    v.recent_bytes
+                },
+                idle: now.saturating_duration_since(v.last_active),
+            })
+            .collect();
+        list.sort_by(|a, b| {
+            b.recent_bytes
+                .cmp(&a.recent_bytes)
+                .then(a.idle.cmp(&b.idle))
+                .then(b.bytes.cmp(&a.bytes))
+        });
+        list
+    }
+    fn len(&self) -> usize {
+        self.inner.lock().len()
+    }
+}
+
+/// 解析流的标识，返回是否是对端发起的流
+///
+/// tcp只有不带ack的syn才算对端发起，本机发起的连接收到的第一个包是syn+ack；
+/// udp等无法区分方向，首次收到即算作对端发起
+fn parse(peer: Ipv4Addr, ipv4: &[u8]) -> Option<(FlowKey, bool)> {
+    if ipv4.len() < 20 || ipv4[0] >> 4 != 4 {
+        return None;
+    }
+    let head_len = ((ipv4[0] & 0x0F) as usize) * 4;
+    let protocol = ipv4[9];
+    // 分片的后续部分没有传输层头
+    let fragment_offset = u16::from_be_bytes([ipv4[6], ipv4[7]]) & 0x1FFF;
+    let transport = ipv4.get(head_len..).filter(|_| fragment_offset == 0);
+    let (src_port, dst_port, inbound) = match (protocol, transport) {
+        (TCP, Some(tcp)) if tcp.len() >= 14 => {
+            let flags = tcp[13];
+            (
+                u16::from_be_bytes([tcp[0], tcp[1]]),
+                u16::from_be_bytes([tcp[2], tcp[3]]),
+                flags & TCP_SYN != 0 && flags & TCP_ACK == 0,
+            )
+        }
+        (UDP, Some(udp)) if udp.len() >= 4 => (
+            u16::from_be_bytes([udp[0], udp[1]]),
+            u16::from_be_bytes([udp[2], udp[3]]),
+            true,
+        ),
+        (TCP, _) | (UDP, _) => return None,
+        _ => (0, 0, true),
+    };
+    Some((
+        FlowKey {
+            peer,
+            protocol,
+            src_port,
+            dst_port,
+        },
+        inbound,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+
+    use crate::handle::flow_table::{FlowInfo, FlowTable};
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 9);
+
+    fn packet(protocol: u8, src_port: u16, dst_port: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 20];
+        buf[0] = 0x45;
+        buf[9] = protocol;
+        buf[20..22].copy_from_slice(&src_port.to_be_bytes());
+        buf[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        buf[33] = flags;
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_eviction() {
+        let table = FlowTable::new(true, 2, None);
+        let now = Instant::now();
+        table.record_at(PEER, &packet(6, 1000, 22, 0x02, &[]), now);
+        table.record_at(
+            PEER,
+            &packet(6, 1001, 22, 0x02, &[]),
+            now + Duration::from_secs(1),
+        );
+        // 刷新第一个流，第二个流变成最久没有活动的
+        table.record_at(
+            PEER,
+            &packet(6, 1000, 22, 0x10, &[]),
+            now + Duration::from_secs(2),
+        );
+        table.record_at(
+            PEER,
+            &packet(17, 53, 53, 0, &[]),
+            now + Duration::from_secs(3),
+        );
+        assert_eq!(table.len(), 2);
+        let list = table.list_at(now + Duration::from_secs(3));
+        assert!(list.iter().all(|v| v.key.src_port != 1001));
+        let first = list.iter().find(|v| v.key.src_port == 1000).unwrap();
+        assert_eq!(first.packets, 2);
+        // 长时间没有活动的不再显示
+        assert!(table.list_at(now + Duration::from_secs(600)).is_empty());
+    }
+
+    #[test]
+    fn test_sort_by_recent_bytes() {
+        let table = FlowTable::new(true, 16, None);
+        let now = Instant::now();
+        // 总量大但是已经不活跃
+        for _ in 0..10 {
+            table.record_at(PEER, &packet(6, 1000, 80, 0x10, &[0; 1000]), now);
+        }
+        table.record_at(
+            PEER,
+            &packet(6, 1001, 22, 0x10, &[0; 100]),
+            now + Duration::from_secs(20),
+        );
+        table.record_at(
+            PEER,
+            &packet(17, 5000, 53, 0, &[0; 10]),
+            now + Duration::from_secs(20),
+        );
+        let list = table.list_at(now + Duration::from_secs(21));
+        let ports: Vec<u16> = list.iter().map(|v| v.key.dst_port).collect();
+        assert_eq!(ports, vec![22, 53, 80]);
+        assert_eq!(list[2].recent_bytes, 0);
+        assert_eq!(list[2].bytes, 10 * (40 + 1000));
+    }
+
+    #[test]
+    fn test_payload_not_stored() {
+        let events: Arc<Mutex<Vec<FlowInfo>>> = Arc::new(Mutex::new(Vec::new()));
+        let notify = {
+            let events = events.clone();
+            Arc::new(move |info: FlowInfo| events.lock().push(info))
+        };
+        let table = FlowTable::new(true, 16, Some(notify));
+        let secret = b"password=hunter2";
+        let now = Instant::now();
+        table.record_at(PEER, &packet(6, 40000, 22, 0x02, secret), now);
+        table.record_at(PEER, &packet(6, 40000, 22, 0x10, secret), now);
+        // 本机发起的连接，对端回复syn+ack，不算入站
+        table.record_at(PEER, &packet(6, 22, 40001, 0x12, secret), now);
+        let events = events.lock();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].to_string(), "from 10.26.0.9 tcp/22");
+        let dump = format!("{:?}", table.list_at(now));
+        assert!(!dump.contains("hunter2"));
+        let secret_bytes = format!("{:?}", &secret[..]);
+        assert!(!dump.contains(&secret_bytes[1..secret_bytes.len() - 1]));
+        // 只保存固定大小的统计，和负载长度无关
+        assert_eq!(
+            std::mem::size_of::<super::FlowEntry>(),
+            std::mem::size_of::<[u64; 3]>() + 2 * std::mem::size_of::<Instant>()
+        );
+    }
+}
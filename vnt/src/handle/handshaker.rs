@@ -1,5 +1,6 @@
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -28,7 +29,7 @@ pub enum HandshakeEnum {
 }
 #[derive(Clone)]
 pub struct Handshake {
-    time: Arc<AtomicCell<Instant>>,
+    time: Arc<AtomicCell<Option<Instant>>>,
     #[cfg(feature = "server_encrypt")]
     rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
 }
@@ -37,7 +38,8 @@ impl Handshake {
         #[cfg(feature = "server_encrypt")] rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
     ) -> Self {
         Handshake {
-            time: Arc::new(AtomicCell::new(Instant::now() - Duration::from_secs(60))),
+            // 开机不久时Instant::now()减去一段时间会panic，用None表示没有发送过
+            time: Arc::new(AtomicCell::new(None)),
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
         }
@@ -45,19 +47,25 @@ impl Handshake {
     pub fn send(&self, context: &ChannelContext, secret: bool, addr: SocketAddr) -> io::Result<()> {
         let last = self.time.load();
         //短时间不重复发送
-        if last.elapsed() < Duration::from_secs(3) {
+        if last.is_some_and(|v| v.elapsed() < Duration::from_secs(3)) {
             return Ok(());
         }
-        let request_packet = self.handshake_request_packet(secret)?;
+        let compact = context.compact_encoding.load(Ordering::Relaxed);
+        let request_packet = self.handshake_request_packet(secret, compact)?;
         log::info!("发送握手请求,secret={},{:?}", secret, addr);
         context.send_default(request_packet.buffer(), addr)?;
-        self.time.store(Instant::now());
+        self.time.store(Some(Instant::now()));
         Ok(())
     }
     /// 第一次握手数据
-    pub fn handshake_request_packet(&self, secret: bool) -> io::Result<NetPacket<Vec<u8>>> {
+    pub fn handshake_request_packet(
+        &self,
+        secret: bool,
+        compact: bool,
+    ) -> io::Result<NetPacket<Vec<u8>>> {
         let mut request = HandshakeRequest::new();
         request.secret = secret;
+        request.compact_encoding = compact;
         request.version = crate::VNT_VERSION.to_string();
         #[cfg(feature = "server_encrypt")]
         if let Some(finger) = self.rsa_cipher.lock().as_ref().map(|v| v.finger().clone()) {
@@ -0,0 +1,754 @@
+//! 模拟大量客户端对服务器压测，供中继服务器运营者评估容量
+//!
+//! 少量udp socket承载所有模拟客户端，注册之后的包都在协议头中带上客户端的虚拟ip，
+//! 按目的地址区分应答。注册应答没有客户端标识，所以每个socket同时只有一个注册请求。
+//! 服务器如果按来源地址区分客户端，同一个socket上的客户端会被当作一个，
+//! 这时需要让socket数等于客户端数。不支持服务端加密
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use protobuf::Message;
+use rand::Rng;
+
+use crate::channel::punch::NatClass;
+use crate::cipher::Cipher;
+use crate::handle::{GATEWAY_IP, SELF_IP};
+use crate::proto::message::{HandshakeRequest, HandshakeResponse, RegistrationResponse};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{ControlPacket, PingPacket};
+use crate::protocol::error_packet::InErrorPacket;
+use crate::protocol::{
+    control_packet, ip_turn_packet, service_packet, NetPacket, Protocol, MAX_TTL,
+};
+
+/// 注册请求的超时时间和重试次数
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(3);
+const REGISTER_ATTEMPTS: usize = 3;
+/// 发送结束后继续接收应答的时间
+const DRAIN: Duration = Duration::from_secs(2);
+/// 没有定时任务时接收的最长等待
+const POLL: Duration = Duration::from_millis(50);
+
+pub struct LoadTestConfig {
+    pub server: SocketAddr,
+    pub token: String,
+    /// 不同token的数量，大于1时第i个客户端使用'token-(i%tokens)'
+    pub tokens: usize,
+    pub clients: usize,
+    pub sockets: usize,
+    /// 包括逐步注册的时间
+    pub duration: Duration,
+    /// 在这段时间内均匀地发起注册
+    pub ramp_up: Duration,
+    /// 心跳间隔，实际间隔在±20%内随机
+    pub heartbeat: Duration,
+    /// 拉取设备列表的间隔，0表示只在纪元变化时拉取
+    pub list_interval: Duration,
+    /// 经服务器中继的总流量，字节/秒，0表示不发送
+    pub traffic: u64,
+    pub packet_size: usize,
+}
+
+/// 压测结果
+#[derive(Default)]
+pub struct LoadTestReport {
+    pub clients: usize,
+    pub registered: usize,
+    /// 注册失败的原因和次数
+    pub errors: HashMap<String, usize>,
+    /// 从发出第一个注册请求到收到应答，从小到大排序
+    pub register_latency: Vec<Duration>,
+    pub heartbeat_sent: u64,
+    pub heartbeat_received: u64,
+    pub list_sent: u64,
+    pub list_received: u64,
+    pub relay_tx: u64,
+    pub relay_rx: u64,
+    pub elapsed: Duration,
+}
+
+impl LoadTestReport {
+    /// p取值0~100
+    pub fn latency_percentile(&self, p: u32) -> Option<Duration> {
+        if self.register_latency.is_empty() {
+            return None;
+        }
+        let index = (self.register_latency.len() - 1) * p.min(100) as usize / 100;
+        Some(self.register_latency[index])
+    }
+    pub fn heartbeat_loss(&self) -> f64 {
+        if self.heartbeat_sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.heartbeat_received.min(self.heartbeat_sent) as f64 / self.heartbeat_sent as f64
+    }
+    /// 收到的中继流量，字节/秒
+    pub fn relay_throughput(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return 0;
+        }
+        (self.relay_rx as f64 / secs) as u64
+    }
+}
+
+impl std::fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "clients      {}/{} registered",
+            self.registered, self.clients
+        )?;
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort();
+        for (reason, count) in errors {
+            writeln!(f, "  failed     {} {}", reason, count)?;
+        }
+        match (
+            self.latency_percentile(50),
+            self.latency_percentile(90),
+            self.latency_percentile(99),
+            self.register_latency.last(),
+        ) {
+            (Some(p50), Some(p90), Some(p99), Some(max)) => writeln!(
+                f,
+                "register     p50={}ms p90={}ms p99={}ms max={}ms",
+                p50.as_millis(),
+                p90.as_millis(),
+                p99.as_millis(),
+                max.as_millis()
+            )?,
+            _ => writeln!(f, "register     no response")?,
+        }
+        writeln!(
+            f,
+            "heartbeat    {}/{} answered, loss {:.2}%",
+            self.heartbeat_received,
+            self.heartbeat_sent,
+            self.heartbeat_loss() * 100.0
+        )?;
+        writeln!(
+            f,
+            "device list  {}/{} answered",
+            self.list_received, self.list_sent
+        )?;
+        write!(
+            f,
+            "relay        sent {} bytes, received {} bytes, {} kbps over {}s",
+            self.relay_tx,
+            self.relay_rx,
+            self.relay_throughput() * 8 / 1000,
+            self.elapsed.as_secs()
+        )
+    }
+}
+
+/// 所有socket线程共享的统计
+#[derive(Default)]
+struct Shared {
+    heartbeat_sent: AtomicU64,
+    heartbeat_received: AtomicU64,
+    list_sent: AtomicU64,
+    list_received: AtomicU64,
+    relay_tx: AtomicU64,
+    relay_rx: AtomicU64,
+    /// 每个token下已经注册的虚拟ip，用于挑选中继流量的目的地
+    online: Mutex<Vec<Vec<Ipv4Addr>>>,
+    latency: Mutex<Vec<Duration>>,
+    errors: Mutex<HashMap<String, usize>>,
+    sending: AtomicBool,
+}
+
+impl Shared {
+    fn error(&self, reason: &str) {
+        *self.errors.lock().entry(reason.to_string()).or_insert(0) += 1;
+    }
+}
+
+enum State {
+    Pending,
+    /// 第一次发送的时刻，用于计算注册耗时
+    Registering {
+        first: Instant,
+        sent: Instant,
+        attempt: usize,
+    },
+    Registered {
+        ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        epoch: u16,
+        next_heartbeat: Instant,
+        /// None表示只在纪元变化时拉取
+        next_list: Option<Instant>,
+    },
+    Failed,
+}
+
+struct Client {
+    group: usize,
+    token: String,
+    device_id: String,
+    name: String,
+    start_at: Instant,
+    state: State,
+}
+
+/// 运行压测直到结束，返回统计
+pub fn run(config: &LoadTestConfig) -> io::Result<LoadTestReport> {
+    if config.clients == 0 || config.sockets == 0 || config.tokens == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "clients, sockets and tokens must be positive",
+        ));
+    }
+    let sockets = config.sockets.min(config.clients);
+    let mut list = Vec::with_capacity(sockets);
+    for _ in 0..sockets {
+        let socket = UdpSocket::bind(if config.server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        socket.connect(config.server)?;
+        handshake(&socket)?;
+        list.push(socket);
+    }
+    let start = Instant::now();
+    let shared = Arc::new(Shared {
+        online: Mutex::new(vec![Vec::new(); config.tokens]),
+        sending: AtomicBool::new(true),
+        ..Default::default()
+    });
+    let pid = std::process::id();
+    let mut clients: Vec<Vec<Client>> = (0..sockets).map(|_| Vec::new()).collect();
+    for i in 0..config.clients {
+        let group = i % config.tokens;
+        let token = if config.tokens > 1 {
+            format!("{}-{}", config.token, group)
+        } else {
+            config.token.clone()
+        };
+        let offset = config.ramp_up.mul_f64(i as f64 / config.clients as f64);
+        clients[i % sockets].push(Client {
+            group,
+            token,
+            device_id: format!("loadtest-{}-{}", pid, i),
+            name: format!("loadtest-{}", i),
+            start_at: start + offset,
+            state: State::Pending,
+        });
+    }
+    let mut handles = Vec::with_capacity(sockets);
+    for (socket, clients) in list.into_iter().zip(clients) {
+        let shared = shared.clone();
+        let lane = Lane {
+            socket,
+            clients,
+            registering: None,
+            by_ip: HashMap::new(),
+            heartbeat: config.heartbeat,
+            list_interval: config.list_interval,
+            traffic: config.traffic / sockets as u64,
+            packet_size: config.packet_size,
+            allowance: 0.0,
+            last_fill: start,
+        };
+        handles.push(
+            thread::Builder::new()
+                .name("loadtest".into())
+                .spawn(move || lane.run(&shared))?,
+        );
+    }
+    thread::sleep(config.duration);
+    shared.sending.store(false, Ordering::Relaxed);
+    let elapsed = start.elapsed();
+    let mut registered = 0;
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(n)) => registered += n,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "loadtest panic")),
+        }
+    }
+    let mut register_latency = std::mem::take(&mut *shared.latency.lock());
+    register_latency.sort();
+    let errors = std::mem::take(&mut *shared.errors.lock());
+    Ok(LoadTestReport {
+        clients: config.clients,
+        registered,
+        errors,
+        register_latency,
+        heartbeat_sent: shared.heartbeat_sent.load(Ordering::Relaxed),
+        heartbeat_received: shared.heartbeat_received.load(Ordering::Relaxed),
+        list_sent: shared.list_sent.load(Ordering::Relaxed),
+        list_received: shared.list_received.load(Ordering::Relaxed),
+        relay_tx: shared.relay_tx.load(Ordering::Relaxed),
+        relay_rx: shared.relay_rx.load(Ordering::Relaxed),
+        elapsed,
+    })
+}
+
+/// 每个socket一个线程，负责其上所有客户端的收发
+struct Lane {
+    socket: UdpSocket,
+    clients: Vec<Client>,
+    /// 正在注册的客户端下标
+    registering: Option<usize>,
+    /// 注册后按虚拟ip区分应答
+    by_ip: HashMap<Ipv4Addr, usize>,
+    heartbeat: Duration,
+    list_interval: Duration,
+    /// 这个socket的中继流量，字节/秒
+    traffic: u64,
+    packet_size: usize,
+    allowance: f64,
+    last_fill: Instant,
+}
+
+impl Lane {
+    /// 返回注册成功的客户端数
+    fn run(mut self, shared: &Shared) -> io::Result<usize> {
+        let mut buf = [0u8; 65536];
+        let mut drain_until = None;
+        loop {
+            let now = Instant::now();
+            if !shared.sending.load(Ordering::Relaxed) {
+                let until = *drain_until.get_or_insert(now + DRAIN);
+                if now >= until {
+                    break;
+                }
+            } else {
+                self.register(now, shared)?;
+                self.maintain(now, shared)?;
+                self.relay(now, shared)?;
+            }
+            self.socket.set_read_timeout(Some(self.wait(now)))?;
+            match self.socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Err(e) = self.recv(&mut buf[..len], shared) {
+                        log::debug!("压测应答解析失败 {:?}", e);
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                // 服务器不可达等错误，继续等待
+                Err(e) => log::debug!("压测接收失败 {:?}", e),
+            }
+        }
+        Ok(self
+            .clients
+            .iter()
+            .filter(|c| matches!(c.state, State::Registered { .. }))
+            .count())
+    }
+    /// 下一次定时任务前的等待时间
+    fn wait(&self, now: Instant) -> Duration {
+        if self.traffic > 0 && !self.by_ip.is_empty() {
+            return Duration::from_millis(5);
+        }
+        let mut next = now + POLL;
+        for client in &self.clients {
+            let at = match &client.state {
+                State::Pending if self.registering.is_none() => client.start_at,
+                State::Registering { sent, .. } => *sent + REGISTER_TIMEOUT,
+                State::Registered {
+                    next_heartbeat,
+                    next_list,
+                    ..
+                } => next_list.map_or(*next_heartbeat, |v| v.min(*next_heartbeat)),
+                _ => continue,
+            };
+            next = next.min(at);
+        }
+        next.saturating_duration_since(now)
+            .max(Duration::from_millis(1))
+    }
+    fn register(&mut self, now: Instant, shared: &Shared) -> io::Result<()> {
+        if let Some(index) = self.registering {
+            let client = &mut self.clients[index];
+            if let State::Registering {
+                first,
+                sent,
+                attempt,
+            } = client.state
+            {
+                if now < sent + REGISTER_TIMEOUT {
+                    return Ok(());
+                }
+                if attempt >= REGISTER_ATTEMPTS {
+                    shared.error("timeout");
+                    client.state = State::Failed;
+                    self.registering = None;
+                } else {
+                    client.state = State::Registering {
+                        first,
+                        sent: now,
+                        attempt: attempt + 1,
+                    };
+                    return self.send_register(index);
+                }
+            }
+        }
+        let next = self
+            .clients
+            .iter()
+            .position(|c| matches!(c.state, State::Pending) && c.start_at <= now);
+        if let Some(index) = next {
+            self.clients[index].state = State::Registering {
+                first: now,
+                sent: now,
+                attempt: 1,
+            };
+            self.registering = Some(index);
+            self.send_register(index)?;
+        }
+        Ok(())
+    }
+    fn send_register(&self, index: usize) -> io::Result<()> {
+        let client = &self.clients[index];
+        let packet = crate::handle::registrar::registration_request_packet(
+            &Cipher::None,
+            client.token.clone(),
+            client.device_id.clone(),
+            client.name.clone(),
+            None,
+            false,
+            false,
+            None,
+            false,
+            String::new(),
+            NatClass::Unknown,
+        )?;
+        self.send(packet.buffer())
+    }
+    /// 心跳和拉取设备列表
+    fn maintain(&mut self, now: Instant, shared: &Shared) -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        for client in self.clients.iter_mut() {
+            if let State::Registered {
+                ip,
+                gateway,
+                epoch,
+                next_heartbeat,
+                next_list,
+            } = &mut client.state
+            {
+                if *next_heartbeat <= now {
+                    *next_heartbeat = now + jitter(&mut rng, self.heartbeat);
+                    send(&self.socket, ping_packet(*ip, *gateway, *epoch)?.buffer())?;
+                    shared.heartbeat_sent.fetch_add(1, Ordering::Relaxed);
+                }
+                if next_list.map_or(false, |v| v <= now) {
+                    *next_list = next_pull(&mut rng, now, self.list_interval);
+                    send(&self.socket, pull_packet(*ip)?.buffer())?;
+                    shared.list_sent.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// 按速率向同一token下的其他客户端发送中继数据
+    fn relay(&mut self, now: Instant, shared: &Shared) -> io::Result<()> {
+        if self.traffic == 0 || self.by_ip.is_empty() {
+            self.last_fill = now;
+            return Ok(());
+        }
+        let elapsed = now.saturating_duration_since(self.last_fill).as_secs_f64();
+        self.last_fill = now;
+        // 最多积累100ms，避免卡顿后突发
+        self.allowance = (self.allowance + elapsed * self.traffic as f64)
+            .min(self.traffic as f64 / 10.0 + self.packet_size as f64);
+        let mut rng = rand::thread_rng();
+        let sources: Vec<(Ipv4Addr, usize)> = self
+            .by_ip
+            .iter()
+            .map(|(ip, index)| (*ip, self.clients[*index].group))
+            .collect();
+        let mut packet = turn_packet(self.packet_size)?;
+        while self.allowance >= self.packet_size as f64 {
+            let (src, group) = sources[rng.gen_range(0..sources.len())];
+            let dest = {
+                let online = shared.online.lock();
+                let peers = &online[group];
+                if peers.len() < 2 {
+                    // 这个token下没有其他客户端
+                    None
+                } else {
+                    let dest = peers[rng.gen_range(0..peers.len())];
+                    (dest != src).then_some(dest)
+                }
+            };
+            self.allowance -= self.packet_size as f64;
+            let dest = match dest {
+                Some(dest) => dest,
+                None => continue,
+            };
+            packet.set_source(src);
+            packet.set_destination(dest);
+            send(&self.socket, packet.buffer())?;
+            shared
+                .relay_tx
+                .fetch_add(packet.buffer().len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+    fn recv(&mut self, buf: &mut [u8], shared: &Shared) -> io::Result<()> {
+        let packet = NetPacket::new(buf)?;
+        let dest = packet.destination();
+        match packet.protocol() {
+            Protocol::Service => {
+                match service_packet::Protocol::from(packet.transport_protocol()) {
+                    service_packet::Protocol::RegistrationResponse => {
+                        let response = RegistrationResponse::parse_from_bytes(packet.payload())
+                            .map_err(|e| {
+                                io::Error::new(
+                                    io::ErrorKind::Other,
+                                    format!("RegistrationResponse {:?}", e),
+                                )
+                            })?;
+                        self.registered(response, shared);
+                    }
+                    service_packet::Protocol::PushDeviceList => {
+                        if self.by_ip.contains_key(&dest) {
+                            shared.list_received.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Protocol::Error => {
+                let reason =
+                    match InErrorPacket::new(packet.transport_protocol(), packet.payload())? {
+                        InErrorPacket::TokenError => "token_error",
+                        InErrorPacket::Disconnect => "disconnect",
+                        InErrorPacket::AddressExhausted => "address_exhausted",
+                        InErrorPacket::IpAlreadyExists => "ip_already_exists",
+                        InErrorPacket::InvalidIp => "invalid_ip",
+                        InErrorPacket::NoKey => "no_key",
+                        InErrorPacket::OtherError(_) => "other",
+                    };
+                shared.error(reason);
+                if let Some(index) = self.by_ip.remove(&dest) {
+                    // 被服务器断开，重新注册
+                    self.offline(index, shared);
+                    self.clients[index].state = State::Pending;
+                } else if let Some(index) = self.registering.take() {
+                    self.clients[index].state = State::Failed;
+                }
+            }
+            Protocol::Control => {
+                if let ControlPacket::PongPacket(pong) =
+                    ControlPacket::new(packet.transport_protocol(), packet.payload())?
+                {
+                    if let Some(index) = self.by_ip.get(&dest) {
+                        shared.heartbeat_received.fetch_add(1, Ordering::Relaxed);
+                        if let State::Registered {
+                            epoch, next_list, ..
+                        } = &mut self.clients[*index].state
+                        {
+                            if pong.epoch() != *epoch {
+                                // 和真实客户端一样，纪元变化时拉取设备列表
+                                *epoch = pong.epoch();
+                                *next_list = Some(Instant::now());
+                            }
+                        }
+                    }
+                }
+            }
+            Protocol::IpTurn => {
+                if self.by_ip.contains_key(&dest) {
+                    shared
+                        .relay_rx
+                        .fetch_add(packet.buffer().len() as u64, Ordering::Relaxed);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn registered(&mut self, response: RegistrationResponse, shared: &Shared) {
+        let index = match self.registering.take() {
+            Some(index) => index,
+            // 超时后的应答
+            None => return,
+        };
+        let first = match self.clients[index].state {
+            State::Registering { first, .. } => first,
+            _ => return,
+        };
+        let now = Instant::now();
+        let ip = Ipv4Addr::from(response.virtual_ip);
+        shared.latency.lock().push(now - first);
+        let client = &mut self.clients[index];
+        shared.online.lock()[client.group].push(ip);
+        client.state = State::Registered {
+            ip,
+            gateway: Ipv4Addr::from(response.virtual_gateway),
+            epoch: response.epoch as u16,
+            next_heartbeat: now + jitter(&mut rand::thread_rng(), self.heartbeat),
+            next_list: next_pull(&mut rand::thread_rng(), now, self.list_interval),
+        };
+        self.by_ip.insert(ip, index);
+    }
+    fn offline(&mut self, index: usize, shared: &Shared) {
+        if let State::Registered { ip, .. } = self.clients[index].state {
+            shared.online.lock()[self.clients[index].group].retain(|v| *v != ip);
+        }
+    }
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        send(&self.socket, buf)
+    }
+}
+
+fn send(socket: &UdpSocket, buf: &[u8]) -> io::Result<()> {
+    match socket.send(buf) {
+        Ok(_) => Ok(()),
+        // 服务器返回icmp不可达时，下一次收发会报错，不中断压测
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 在0.8~1.2倍之间随机
+fn jitter(rng: &mut impl Rng, interval: Duration) -> Duration {
+    interval.mul_f64(rng.gen_range(0.8..1.2))
+}
+
+fn next_pull(rng: &mut impl Rng, now: Instant, interval: Duration) -> Option<Instant> {
+    (!interval.is_zero()).then(|| now + jitter(rng, interval))
+}
+
+/// 不加密的握手，确认服务器可用
+fn handshake(socket: &UdpSocket) -> io::Result<()> {
+    let mut request = HandshakeRequest::new();
+    request.version = crate::VNT_VERSION.to_string();
+    let bytes = request
+        .write_to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("HandshakeRequest {:?}", e)))?;
+    let mut packet = NetPacket::new(vec![0u8; 12 + bytes.len()])?;
+    packet.set_default_version();
+    packet.set_gateway_flag(true);
+    packet.set_destination(GATEWAY_IP);
+    packet.set_source(SELF_IP);
+    packet.set_protocol(Protocol::Service);
+    packet.set_transport_protocol(service_packet::Protocol::HandshakeRequest.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_payload(&bytes)?;
+    socket.set_read_timeout(Some(REGISTER_TIMEOUT))?;
+    let mut buf = [0u8; 65536];
+    for _ in 0..REGISTER_ATTEMPTS {
+        socket.send(packet.buffer())?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let response = NetPacket::new(&buf[..len])?;
+        if response.protocol() != Protocol::Service
+            || response.transport_protocol() != service_packet::Protocol::HandshakeResponse.into()
+        {
+            continue;
+        }
+        let response = HandshakeResponse::parse_from_bytes(response.payload()).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("HandshakeResponse {:?}", e))
+        })?;
+        if response.secret {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "server requires encryption, not supported by loadtest",
+            ));
+        }
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no handshake response from server",
+    ))
+}
+
+fn ping_packet(src: Ipv4Addr, dest: Ipv4Addr, epoch: u16) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut packet = NetPacket::new(vec![0u8; 12 + 4])?;
+    packet.set_default_version();
+    packet.set_gateway_flag(true);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::Ping.into());
+    packet.first_set_ttl(5);
+    packet.set_source(src);
+    packet.set_destination(dest);
+    let mut ping = PingPacket::new(packet.payload_mut())?;
+    ping.set_time(crate::handle::now_time() as u16);
+    ping.set_epoch(epoch);
+    Ok(packet)
+}
+
+fn pull_packet(src: Ipv4Addr) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut packet = NetPacket::new_encrypt(vec![0u8; 12 + ENCRYPTION_RESERVED])?;
+    packet.set_source(src);
+    packet.set_destination(GATEWAY_IP);
+    packet.set_default_version();
+    packet.set_gateway_flag(true);
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_protocol(Protocol::Service);
+    packet.set_transport_protocol(service_packet::Protocol::PullDeviceList.into());
+    Ok(packet)
+}
+
+/// 源地址和目的地址在发送前填写，载荷是随机数据
+fn turn_packet(size: usize) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut buf = vec![0u8; size.max(12 + 20)];
+    rand::thread_rng().fill(&mut buf[12..]);
+    let mut packet = NetPacket::new(buf)?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::IpTurn);
+    packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+    packet.first_set_ttl(MAX_TTL);
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::LoadTestReport;
+
+    #[test]
+    fn test_report() {
+        let report = LoadTestReport {
+            clients: 4,
+            registered: 3,
+            register_latency: (1..=100).map(Duration::from_millis).collect(),
+            heartbeat_sent: 200,
+            heartbeat_received: 190,
+            relay_rx: 1_000_000,
+            elapsed: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            report.latency_percentile(50),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            report.latency_percentile(99),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            report.latency_percentile(100),
+            Some(Duration::from_millis(100))
+        );
+        assert!((report.heartbeat_loss() - 0.05).abs() < 1e-9);
+        assert_eq!(report.relay_throughput(), 100_000);
+        assert!(LoadTestReport::default().latency_percentile(50).is_none());
+    }
+}
@@ -4,6 +4,7 @@ use std::time::Duration;
 use crossbeam_utils::atomic::AtomicCell;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::probe_budget::ProbeKind;
 use crate::channel::punch::NatType;
 use crate::cipher::Cipher;
 use crate::handle::{BaseConfigInfo, CurrentDeviceInfo};
@@ -98,9 +99,21 @@ fn addr_request0(
         packet.set_source(src_ip);
         packet.set_destination(gateway_ip);
         server_cipher.encrypt_ipv4(&mut packet)?;
+        if !context
+            .probe_budget
+            .allow(ProbeKind::AddrRequest, packet.buffer().len())
+        {
+            return Ok(());
+        }
         context.send_main_udp(index, packet.buffer(), current_dev.connect_server)?;
     } else {
         let (data, addr) = nat_test.send_data()?;
+        if !context
+            .probe_budget
+            .allow(ProbeKind::AddrRequest, data.len())
+        {
+            return Ok(());
+        }
         context.send_main_udp(index, &data, addr)?;
     }
     Ok(())
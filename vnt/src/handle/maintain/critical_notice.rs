@@ -0,0 +1,93 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::critical_notice::{CriticalNotice, NoticeKind};
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{NoticePacket, NOTICE_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::Scheduler;
+
+/// 定时重传未确认的通知
+pub fn critical_notice(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    notices: CriticalNotice,
+) {
+    let current_device = current_device_info.load();
+    notices.tick(
+        Instant::now(),
+        |ip| is_online(&device_list, ip),
+        |id, kind, ip| send_notice(&context, &current_device, &client_cipher, id, kind, ip),
+    );
+    let rs = scheduler.timeout(Duration::from_millis(200), move |s| {
+        critical_notice(
+            s,
+            context,
+            current_device_info,
+            device_list,
+            client_cipher,
+            notices,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+/// 在线的对端
+pub fn online_peers(device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>) -> Vec<Ipv4Addr> {
+    device_list
+        .lock()
+        .1
+        .iter()
+        .filter(|v| v.status.is_online())
+        .map(|v| v.virtual_ip)
+        .collect()
+}
+
+pub fn is_online(device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>, ip: &Ipv4Addr) -> bool {
+    device_list
+        .lock()
+        .1
+        .iter()
+        .any(|v| v.virtual_ip == *ip && v.status.is_online())
+}
+
+/// 发送一次通知，优先直连，没有直连路由时经服务器转发
+pub fn send_notice(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    id: u32,
+    kind: NoticeKind,
+    dest: &Ipv4Addr,
+) -> io::Result<()> {
+    let mut net_packet = NetPacket::new_encrypt([0; 12 + NOTICE_LEN + ENCRYPTION_RESERVED])?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(Protocol::Control);
+    net_packet.set_transport_protocol(control_packet::Protocol::Notice.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(current_device.virtual_ip);
+    net_packet.set_destination(*dest);
+    let mut notice_packet = NoticePacket::new(net_packet.payload_mut())?;
+    notice_packet.set_id(id);
+    notice_packet.set_kind(kind.into());
+    client_cipher.encrypt_ipv4(&mut net_packet)?;
+    context.send_ipv4_by_id(
+        net_packet.buffer(),
+        dest,
+        current_device.connect_server,
+        current_device.status.online(),
+    )
+}
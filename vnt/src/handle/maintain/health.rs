@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use crate::channel::context::ChannelContext;
+use crate::handle::callback::HealthInfo;
+use crate::util::health::{HealthReport, Watchdog, WATCHDOG_STALE};
+use crate::util::Scheduler;
+use crate::VntCallback;
+
+/// 定时检查存活和就绪状态，变化时回调，同时作为定时器线程的看门狗
+pub fn health_check<Call: VntCallback>(scheduler: &Scheduler, context: ChannelContext, call: Call) {
+    let watchdog = context.health.watchdog("scheduler", WATCHDOG_STALE);
+    // 启动时还没有注册，不回调，从这个状态开始比较
+    let ready = context.health.readyz();
+    health_check0(
+        scheduler,
+        context,
+        call,
+        watchdog,
+        HealthReport::default(),
+        ready,
+    )
+}
+
+fn health_check0<Call: VntCallback>(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    call: Call,
+    watchdog: Watchdog,
+    live: HealthReport,
+    ready: HealthReport,
+) {
+    watchdog.beat();
+    let new_live = context.health.livez();
+    if changed(&live, &new_live) {
+        log::info!("存活状态变化 {}", new_live);
+        call.health(HealthInfo::new(true, new_live.clone()));
+    }
+    let new_ready = context.health.readyz();
+    if changed(&ready, &new_ready) {
+        log::info!("就绪状态变化 {}", new_ready);
+        call.health(HealthInfo::new(false, new_ready.clone()));
+    }
+    let rs = scheduler.timeout(Duration::from_secs(1), move |s| {
+        health_check0(s, context, call, watchdog, new_live, new_ready)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+/// 失败的检查项变化才算变化，原因里的持续时间每次都不同
+fn changed(old: &HealthReport, new: &HealthReport) -> bool {
+    old.failed.len() != new.failed.len()
+        || old
+            .failed
+            .iter()
+            .zip(new.failed.iter())
+            .any(|(a, b)| a.0 != b.0)
+}
@@ -1,5 +1,7 @@
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use protobuf::Message;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,13 +10,30 @@ use parking_lot::Mutex;
 use rand::prelude::SliceRandom;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::diary::EvictReason;
+use crate::channel::probe_budget::ProbeKind;
+use crate::channel::relay_stats::RelayStats;
+use crate::channel::rendezvous::MAX_STANDBY;
+use crate::channel::RouteKey;
 use crate::cipher::Cipher;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::nat::upnp::Mapper;
+use crate::proto::message::{RelayUsage, ServerRtt};
 use crate::protocol::body::ENCRYPTION_RESERVED;
-use crate::protocol::control_packet::PingPacket;
+use crate::protocol::compat::WireVersion;
+use crate::protocol::control_packet::{PingPacket, PING_WIRE_VERSION_LEN};
 use crate::protocol::{control_packet, NetPacket, Protocol};
 use crate::util::Scheduler;
 
+/// 序列化后的中继流量最大长度，两个uint64字段
+const RELAY_USAGE_MAX_LEN: usize = 22;
+/// 序列化后一个服务器延迟的最大长度，地址最长47个字符
+const SERVER_RTT_MAX_LEN: usize = 57;
+const EXTENSION_MAX_LEN: usize = RELAY_USAGE_MAX_LEN + SERVER_RTT_MAX_LEN * (MAX_STANDBY + 1);
+
+type HeartbeatPacket =
+    NetPacket<[u8; 12 + PING_WIRE_VERSION_LEN + EXTENSION_MAX_LEN + ENCRYPTION_RESERVED]>;
+
 /// 定时发送心跳包
 pub fn heartbeat(
     scheduler: &Scheduler,
@@ -23,6 +42,8 @@ pub fn heartbeat(
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     client_cipher: Cipher,
     server_cipher: Cipher,
+    report_usage: bool,
+    upnp: Option<Mapper>,
 ) {
     heartbeat0(
         &context,
@@ -30,7 +51,12 @@ pub fn heartbeat(
         &device_list,
         &client_cipher,
         &server_cipher,
+        report_usage,
     );
+    if let Some(upnp) = &upnp {
+        // 租期过半时续期端口映射
+        upnp.refresh();
+    }
     // 心跳包 3秒发送一次
     let rs = scheduler.timeout(Duration::from_secs(3), |s| {
         heartbeat(
@@ -40,6 +66,8 @@ pub fn heartbeat(
             device_list,
             client_cipher,
             server_cipher,
+            report_usage,
+            upnp,
         )
     });
     if !rs {
@@ -47,19 +75,42 @@ pub fn heartbeat(
     }
 }
 
-fn heartbeat0(
+pub(super) fn heartbeat0(
     context: &ChannelContext,
     current_device: &CurrentDeviceInfo,
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
     client_cipher: &Cipher,
     server_cipher: &Cipher,
+    report_usage: bool,
 ) {
+    // 开启上报时在发给服务端的心跳包中带上中继流量
+    let relay_usage = if report_usage {
+        Some(&context.relay_stats)
+    } else {
+        None
+    };
+    // 配置了备用服务器时上报到各个服务器的延迟，服务端据此给出最近的服务器
+    let server_rtt = if context.rendezvous.has_standby() {
+        context.rendezvous.report(current_device.connect_server)
+    } else {
+        Vec::new()
+    };
     let gateway_ip = current_device.virtual_gateway;
     let src_ip = current_device.virtual_ip;
     // 可能服务器ip发生变化，导致发送失败
     let mut is_send_gateway = false;
-    match heartbeat_packet_server(device_list, server_cipher, src_ip, gateway_ip) {
+    match heartbeat_packet_server(
+        device_list,
+        server_cipher,
+        relay_usage,
+        &server_rtt,
+        src_ip,
+        gateway_ip,
+    ) {
         Ok(net_packet) => {
+            context
+                .probe_budget
+                .allow(ProbeKind::ServerHeartbeat, net_packet.buffer().len());
             if let Err(e) = context.send_default(net_packet.buffer(), current_device.connect_server)
             {
                 log::warn!("heartbeat err={:?}", e)
@@ -73,11 +124,19 @@ fn heartbeat0(
     }
 
     for (dest_ip, routes) in context.route_table.route_table() {
-        let net_packet = if current_device.is_gateway(&dest_ip) {
+        let is_gateway = current_device.is_gateway(&dest_ip);
+        let net_packet = if is_gateway {
             if is_send_gateway {
                 continue;
             }
-            heartbeat_packet_server(device_list, server_cipher, src_ip, gateway_ip)
+            heartbeat_packet_server(
+                device_list,
+                server_cipher,
+                relay_usage,
+                &server_rtt,
+                src_ip,
+                gateway_ip,
+            )
         } else {
             heartbeat_packet_client(client_cipher, src_ip, dest_ip)
         };
@@ -88,9 +147,38 @@ fn heartbeat0(
                 continue;
             }
         };
-        for route in routes {
+        for (index, route) in routes.iter().enumerate() {
+            // 第一条是正在使用的路径，其他路径只用于刷新延迟，预算不足时跳过
+            let kind = if is_gateway {
+                ProbeKind::ServerHeartbeat
+            } else if index == 0 {
+                ProbeKind::PeerKeepalive
+            } else {
+                ProbeKind::LatencyProbe
+            };
+            if !context.probe_budget.allow(kind, net_packet.buffer().len()) {
+                continue;
+            }
+            if kind == ProbeKind::PeerKeepalive
+                && route.is_p2p()
+                && context.route_health.probe(dest_ip, route.route_key())
+            {
+                log::warn!("直连路径连续多次没有回应,目标:{},路径:{:?}", dest_ip, route);
+                context.remove_route(&dest_ip, route.route_key(), EvictReason::Unreachable);
+                if context.route_table.route_one_p2p(&dest_ip).is_none() {
+                    // 改用中继，定时打洞会重新建立直连
+                    context.route_health.fell_back(dest_ip);
+                }
+                continue;
+            }
             if let Err(e) = context.send_by_key(net_packet.buffer(), route.route_key()) {
                 log::warn!("heartbeat err={:?}", e)
+            } else if route.is_p2p() {
+                // 统计直连路径的丢包率
+                context
+                    .route_table
+                    .path_scores
+                    .probe(dest_ip, route.route_key());
             }
         }
     }
@@ -105,6 +193,9 @@ fn heartbeat0(
         if current_device.status.offline() {
             continue;
         }
+        if context.block_list.contains(&peer.virtual_ip) {
+            continue;
+        }
         if context.route_table.route_one(&peer.virtual_ip).is_none() {
             //路由为空，则向服务端地址发送
             let net_packet = match heartbeat_packet_client(client_cipher, src_ip, peer.virtual_ip) {
@@ -114,6 +205,12 @@ fn heartbeat0(
                     continue;
                 }
             };
+            if !context
+                .probe_budget
+                .allow(ProbeKind::RelayEcho, net_packet.buffer().len())
+            {
+                continue;
+            }
             if let Err(e) = context.send_default(net_packet.buffer(), current_device.connect_server)
             {
                 log::error!("heartbeat_packet send_default err={:?}", e);
@@ -175,7 +272,10 @@ fn client_relay0(
     let peer_list = { device_list.lock().1.clone() };
     let mut routes = context.route_table.route_table_p2p();
     for peer in &peer_list {
-        if !peer.status.is_online() || peer.virtual_ip == current_device.virtual_ip {
+        if !peer.status.is_online()
+            || peer.virtual_ip == current_device.virtual_ip
+            || context.block_list.contains(&peer.virtual_ip)
+        {
             continue;
         }
         if context
@@ -196,6 +296,12 @@ fn client_relay0(
             if current_device.is_gateway(ip) {
                 continue;
             }
+            if !context
+                .probe_budget
+                .allow(ProbeKind::RelayProbe, client_packet.buffer().len())
+            {
+                break;
+            }
             if let Err(e) = context.send_by_key(client_packet.buffer(), route.route_key()) {
                 log::error!("{:?}", e);
             }
@@ -206,13 +312,42 @@ fn client_relay0(
     }
     Ok(())
 }
+/// 立即向一条直连路由发送心跳，用回应确认路由是否还可用
+pub(super) fn probe_route(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    src_ip: Ipv4Addr,
+    dest_ip: Ipv4Addr,
+    route_key: RouteKey,
+) {
+    let net_packet = match heartbeat_packet_client(client_cipher, src_ip, dest_ip) {
+        Ok(net_packet) => net_packet,
+        Err(e) => {
+            log::error!("heartbeat_packet err={:?}", e);
+            return;
+        }
+    };
+    if !context
+        .probe_budget
+        .allow(ProbeKind::LatencyProbe, net_packet.buffer().len())
+    {
+        return;
+    }
+    if let Err(e) = context.send_by_key(net_packet.buffer(), route_key) {
+        log::warn!("probe_route err={:?}", e)
+    }
+}
 
 /// 构建心跳包
 fn heartbeat_packet(
     src: Ipv4Addr,
     dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
-    let mut net_packet = NetPacket::new_encrypt([0u8; 12 + 4 + ENCRYPTION_RESERVED])?;
+    payload_len: usize,
+) -> io::Result<HeartbeatPacket> {
+    let mut net_packet = NetPacket::new0(
+        12 + payload_len,
+        [0u8; 12 + PING_WIRE_VERSION_LEN + EXTENSION_MAX_LEN + ENCRYPTION_RESERVED],
+    )?;
     net_packet.set_default_version();
     net_packet.set_protocol(Protocol::Control);
     net_packet.set_transport_protocol(control_packet::Protocol::Ping.into());
@@ -220,6 +355,7 @@ fn heartbeat_packet(
     net_packet.set_source(src);
     net_packet.set_destination(dest);
     let mut ping = PingPacket::new(net_packet.payload_mut())?;
+    // 只保留毫秒的低16位，收到pong时用serial::rtt_ms计算延迟
     ping.set_time(crate::handle::now_time() as u16);
     Ok(net_packet)
 }
@@ -228,22 +364,121 @@ fn heartbeat_packet_client(
     client_cipher: &Cipher,
     src: Ipv4Addr,
     dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
-    let mut net_packet = heartbeat_packet(src, dest)?;
+) -> io::Result<HeartbeatPacket> {
+    let mut net_packet = heartbeat_packet(src, dest, PING_WIRE_VERSION_LEN)?;
+    // 客户端之间协商协议版本
+    let mut ping = PingPacket::new(net_packet.payload_mut())?;
+    ping.set_wire_version(WireVersion::MAX);
     client_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
 
-fn heartbeat_packet_server(
+/// 中继流量的累计值，只有总量，不包含任何对端信息，以及到各个服务器的延迟
+fn extension_bytes(
+    relay_usage: Option<&RelayStats>,
+    server_rtt: &[(SocketAddr, u32)],
+) -> io::Result<Vec<u8>> {
+    let mut usage = RelayUsage::new();
+    if let Some(relay_stats) = relay_usage {
+        usage.relay_tx = relay_stats.tx();
+        usage.relay_rx = relay_stats.rx();
+    }
+    for (server, rtt) in server_rtt.iter().take(MAX_STANDBY + 1) {
+        let mut item = ServerRtt::new();
+        item.server = server.to_string();
+        item.rtt_ms = *rtt;
+        usage.server_rtt.push(item);
+    }
+    usage
+        .write_to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("relay_usage {:?}", e)))
+}
+
+pub(super) fn heartbeat_packet_server(
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
     server_cipher: &Cipher,
+    relay_usage: Option<&RelayStats>,
+    server_rtt: &[(SocketAddr, u32)],
     src: Ipv4Addr,
     dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
-    let mut net_packet = heartbeat_packet(src, dest)?;
+) -> io::Result<HeartbeatPacket> {
+    let mut net_packet = if relay_usage.is_some() || !server_rtt.is_empty() {
+        // 协议版本字段保持为0，服务端按旧版本处理，扩展数据跟在后面
+        let usage = extension_bytes(relay_usage, server_rtt)?;
+        let mut net_packet = heartbeat_packet(src, dest, PING_WIRE_VERSION_LEN + usage.len())?;
+        PingPacket::new(net_packet.payload_mut())?.set_extension(&usage)?;
+        net_packet
+    } else {
+        heartbeat_packet(src, dest, 4)?
+    };
     let mut ping = PingPacket::new(net_packet.payload_mut())?;
     ping.set_epoch(device_list.lock().0);
     net_packet.set_gateway_flag(true);
     server_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
+
+#[test]
+fn test_relay_usage() {
+    let relay_stats = RelayStats::new(&crate::util::metrics::Registry::new());
+    relay_stats.add_tx(u32::MAX as usize);
+    relay_stats.add_tx(100);
+    relay_stats.add_rx(1500);
+    let device_list = Mutex::new((0, Vec::new()));
+    let src = Ipv4Addr::new(10, 26, 0, 2);
+    let dest = Ipv4Addr::new(10, 26, 0, 1);
+    let net_packet = heartbeat_packet_server(
+        &device_list,
+        &Cipher::None,
+        Some(&relay_stats),
+        &[],
+        src,
+        dest,
+    )
+    .unwrap();
+    let ping = PingPacket::new(net_packet.payload()).unwrap();
+    assert_eq!(ping.wire_version(), WireVersion::V1);
+    let usage = RelayUsage::parse_from_bytes(ping.extension()).unwrap();
+    assert_eq!(usage.relay_tx, relay_stats.tx());
+    assert_eq!(usage.relay_rx, relay_stats.rx());
+    // 最大值也不能超出缓冲区
+    let max = RelayUsage {
+        relay_tx: u64::MAX,
+        relay_rx: u64::MAX,
+        ..Default::default()
+    };
+    assert_eq!(max.write_to_bytes().unwrap().len(), RELAY_USAGE_MAX_LEN);
+    let server_rtt = ServerRtt {
+        server: "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".to_string(),
+        rtt_ms: u32::MAX,
+        ..Default::default()
+    };
+    let max = RelayUsage {
+        server_rtt: vec![server_rtt],
+        ..Default::default()
+    };
+    assert_eq!(max.write_to_bytes().unwrap().len(), SERVER_RTT_MAX_LEN);
+    // 只有延迟，没有开启中继流量上报
+    let server: SocketAddr = "1.2.3.4:29872".parse().unwrap();
+    let net_packet = heartbeat_packet_server(
+        &device_list,
+        &Cipher::None,
+        None,
+        &[(server, 12)],
+        src,
+        dest,
+    )
+    .unwrap();
+    let ping = PingPacket::new(net_packet.payload()).unwrap();
+    let usage = RelayUsage::parse_from_bytes(ping.extension()).unwrap();
+    assert_eq!(usage.relay_tx, 0);
+    assert_eq!(usage.server_rtt[0].server, "1.2.3.4:29872");
+    assert_eq!(usage.server_rtt[0].rtt_ms, 12);
+    // 未开启上报时不携带
+    let net_packet =
+        heartbeat_packet_server(&device_list, &Cipher::None, None, &[], src, dest).unwrap();
+    assert!(PingPacket::new(net_packet.payload())
+        .unwrap()
+        .extension()
+        .is_empty());
+}
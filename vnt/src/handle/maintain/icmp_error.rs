@@ -0,0 +1,78 @@
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::diary::DiaryEvent;
+use crate::channel::icmp_error::IcmpError;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::util::health::ReadyCheck;
+
+/// 立即处理外层udp socket收到的icmp错误，作为路径不可用的信号，不用等心跳超时
+pub fn icmp_error(
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+) {
+    let receiver = match context.icmp_errors.take_receiver() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    thread::Builder::new()
+        .name("icmpError".into())
+        .spawn(move || {
+            while !context.is_stop() {
+                match receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(error) => handle(&context, &current_device.load(), &client_cipher, error),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        })
+        .expect("icmpError");
+}
+
+fn handle(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    error: IcmpError,
+) {
+    let IcmpError { route_key, kind } = error;
+    if route_key.addr == current_device.connect_server {
+        // 服务器不可达，下一次收到心跳回应时恢复
+        log::warn!("服务器不可达 {} {}", route_key.addr, kind);
+        context.health.not_ready(
+            ReadyCheck::DataPath,
+            format!("{} from server {}", kind, route_key.addr),
+        );
+        return;
+    }
+    if let Some(ip) = context.route_table.route_to_id(&route_key) {
+        // 直连路由不再优先使用，并立即探测一次
+        context.diary.record(
+            ip,
+            DiaryEvent::Unreachable {
+                addr: route_key.addr,
+                kind: kind.name(),
+            },
+        );
+        if context.route_table.mark_suspect(&ip, &route_key) {
+            log::info!("直连路由不可达 {} {:?} {}", ip, route_key, kind);
+            super::heartbeat::probe_route(
+                context,
+                client_cipher,
+                current_device.virtual_ip,
+                ip,
+                route_key,
+            );
+        }
+        return;
+    }
+    // 不是路由的地址一般是打洞的候选地址，打洞时会跳过
+    log::debug!("打洞候选地址不可达 {:?} {}", route_key, kind);
+}
@@ -1,5 +1,6 @@
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -7,14 +8,21 @@ use crossbeam_utils::atomic::AtomicCell;
 use mio::net::TcpStream;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::diary::EvictReason;
 use crate::channel::idle::{Idle, IdleType};
 use crate::channel::sender::AcceptSocketSender;
 use crate::handle::callback::{ConnectInfo, ErrorType};
 use crate::handle::handshaker::Handshake;
 use crate::handle::{BaseConfigInfo, ConnectStatus, CurrentDeviceInfo};
+use crate::util::health::ReadyCheck;
 use crate::util::{address_choose, dns_query_all, Scheduler};
 use crate::{ErrorInfo, VntCallback};
 
+/// 重连间隔从1秒开始翻倍，最多60秒
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// 在线时检查连接状态的间隔
+const ONLINE_CHECK: Duration = Duration::from_secs(1);
+
 pub fn idle_route<Call: VntCallback>(
     scheduler: &Scheduler,
     idle: Idle,
@@ -39,18 +47,20 @@ pub fn idle_gateway<Call: VntCallback>(
     tcp_socket_sender: AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
     call: Call,
     mut connect_count: usize,
+    mut attempt: usize,
     handshake: Handshake,
 ) {
-    idle_gateway0(
+    let delay = idle_gateway0(
         &context,
         &current_device_info,
         &config,
         &tcp_socket_sender,
         &call,
         &mut connect_count,
+        &mut attempt,
         &handshake,
     );
-    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+    let rs = scheduler.timeout(delay, move |s| {
         idle_gateway(
             s,
             context,
@@ -59,6 +69,7 @@ pub fn idle_gateway<Call: VntCallback>(
             tcp_socket_sender,
             call,
             connect_count,
+            attempt,
             handshake,
         )
     });
@@ -74,8 +85,10 @@ fn idle_gateway0<Call: VntCallback>(
     tcp_socket_sender: &AcceptSocketSender<(TcpStream, SocketAddr, Option<Vec<u8>>)>,
     call: &Call,
     connect_count: &mut usize,
+    attempt: &mut usize,
     handshake: &Handshake,
-) {
+) -> Duration {
+    let before = *connect_count;
     if let Err(e) = check_gateway_channel(
         context,
         current_device,
@@ -86,11 +99,33 @@ fn idle_gateway0<Call: VntCallback>(
         handshake,
     ) {
         let cur = current_device.load();
+        log::warn!("连接服务器{}失败 {:?}", cur.connect_server, e);
         call.error(ErrorInfo::new_msg(
             ErrorType::Disconnect,
             format!("connect:{},error:{:?}", cur.connect_server, e),
         ));
     }
+    // 服务端重启或者网络中断时一直重连，不退出，间隔逐渐变长
+    if *connect_count == before {
+        // 连接成功后重新从1秒开始
+        *attempt = 0;
+        return ONLINE_CHECK;
+    }
+    *attempt += 1;
+    let delay = reconnect_delay(*attempt);
+    log::info!(
+        "第{}次连接服务器{},{:?}后重试",
+        attempt,
+        current_device.load().connect_server,
+        delay
+    );
+    delay
+}
+
+/// 第n次重连之后的等待时间：1、2、4...秒，最多60秒
+fn reconnect_delay(attempt: usize) -> Duration {
+    let shift = attempt.saturating_sub(1).min(6) as u32;
+    Duration::from_secs(1 << shift).min(RECONNECT_MAX_DELAY)
 }
 
 fn idle_route0<Call: VntCallback>(
@@ -103,10 +138,14 @@ fn idle_route0<Call: VntCallback>(
     match idle.next_idle() {
         IdleType::Timeout(ip, route) => {
             log::info!("route Timeout {:?},{:?}", ip, route);
-            context.remove_route(&ip, route.route_key());
+            context.remove_route(&ip, route.route_key(), EvictReason::Idle);
             if cur.is_gateway(&ip) {
                 //网关路由过期，则需要改变状态
                 crate::handle::change_status(current_device, ConnectStatus::Connecting);
+                context
+                    .health
+                    .not_ready(ReadyCheck::Registered, "server timeout");
+                log::warn!("服务器超时，重新连接");
                 call.error(ErrorInfo::new(ErrorType::Disconnect));
             }
             Duration::from_millis(100)
@@ -128,6 +167,8 @@ fn check_gateway_channel<Call: VntCallback>(
     let mut current_device = current_device_info.load();
     if current_device.status.offline() {
         *count += 1;
+        // udp被过滤时改用tcp，切换后send_default没有tcp连接，走下面的重连
+        context.transport.attempt();
         // 探测服务器地址
         current_device = domain_request0(current_device_info, config);
         //需要重连
@@ -137,7 +178,10 @@ fn check_gateway_channel<Call: VntCallback>(
         {
             log::warn!("{:?}", e);
             if context.is_main_tcp() {
-                let request_packet = handshake.handshake_request_packet(config.server_secret)?;
+                let request_packet = handshake.handshake_request_packet(
+                    config.server_secret,
+                    context.compact_encoding.load(Ordering::Relaxed),
+                )?;
                 //tcp需要重连
                 let tcp_stream = std::net::TcpStream::connect_timeout(
                     &current_device.connect_server,
@@ -153,6 +197,8 @@ fn check_gateway_channel<Call: VntCallback>(
                 }
             }
         }
+    } else {
+        context.transport.connected();
     }
     Ok(())
 }
@@ -201,3 +247,17 @@ pub fn domain_request0(
     }
     current_dev
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::handle::maintain::idle::reconnect_delay;
+
+    #[test]
+    fn test_reconnect_delay() {
+        let delays: Vec<u64> = (1..=9).map(|n| reconnect_delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(reconnect_delay(usize::MAX), Duration::from_secs(60));
+    }
+}
@@ -17,3 +17,39 @@ pub use idle::idle_route;
 
 mod up_status;
 pub use up_status::*;
+
+mod critical_notice;
+pub use critical_notice::*;
+
+mod resume;
+pub use resume::*;
+
+mod route_guard;
+pub use route_guard::*;
+
+mod mtu_guard;
+pub use mtu_guard::*;
+
+mod reorder;
+pub use reorder::*;
+
+mod health;
+pub use health::*;
+
+mod socket_audit;
+pub use socket_audit::*;
+
+mod peer_stats;
+pub use peer_stats::*;
+
+mod reverse;
+pub use reverse::*;
+
+mod standby;
+pub use standby::*;
+
+mod icmp_error;
+pub use icmp_error::*;
+
+mod server_action;
+pub use server_action::*;
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::channel::context::ChannelContext;
+use crate::handle::callback::ErrorType;
+use crate::util::Scheduler;
+use crate::{ErrorInfo, VntCallback};
+
+/// 定时判断是否出现路径mtu黑洞，出现时降低对该对端的mss并通知
+pub fn mtu_blackhole<Call: VntCallback>(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    call: Call,
+) {
+    for incident in context.mtu_guard.check() {
+        log::warn!(
+            "路径mtu黑洞 对端={},大包长度={},送达长度={},mss={}",
+            incident.peer,
+            incident.stalled_len,
+            incident.delivered_len,
+            incident.mss
+        );
+        call.error(ErrorInfo::new_msg(ErrorType::PathMtu, incident.to_string()));
+    }
+    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+        mtu_blackhole(s, context, call)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
@@ -0,0 +1,14 @@
+use std::time::Instant;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::peer_stats::SAMPLE_INTERVAL;
+use crate::util::Scheduler;
+
+/// 定时采样对端流量，计算stats命令显示的速率
+pub fn peer_stats_sample(scheduler: &Scheduler, context: ChannelContext) {
+    context.peer_stats.sample(Instant::now());
+    let rs = scheduler.timeout(SAMPLE_INTERVAL, move |s| peer_stats_sample(s, context));
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
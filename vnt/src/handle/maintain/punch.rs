@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::sync::Arc;
 use std::time::Duration;
@@ -11,8 +12,12 @@ use protobuf::Message;
 use rand::prelude::SliceRandom;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::diary::DiaryEvent;
+use crate::channel::probe_budget::ProbeKind;
 use crate::channel::punch::{NatInfo, NatType, Punch};
 use crate::cipher::Cipher;
+use crate::handle::negative_path::NegativePathCache;
+use crate::handle::notice::NoticeHolder;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::nat::NatTest;
 use crate::proto::message::{PunchInfo, PunchNatType};
@@ -22,19 +27,21 @@ use crate::util::Scheduler;
 
 #[derive(Clone)]
 pub struct PunchSender {
-    sender_self: SyncSender<(Ipv4Addr, NatInfo)>,
-    sender_peer: SyncSender<(Ipv4Addr, NatInfo)>,
-    sender_cone_self: SyncSender<(Ipv4Addr, NatInfo)>,
-    sender_cone_peer: SyncSender<(Ipv4Addr, NatInfo)>,
+    sender_self: SyncSender<(Ipv4Addr, NatInfo, u64)>,
+    sender_peer: SyncSender<(Ipv4Addr, NatInfo, u64)>,
+    sender_cone_self: SyncSender<(Ipv4Addr, NatInfo, u64)>,
+    sender_cone_peer: SyncSender<(Ipv4Addr, NatInfo, u64)>,
 }
 
 impl PunchSender {
-    pub fn send(&self, src_peer: bool, ip: Ipv4Addr, info: NatInfo) -> bool {
+    /// fire_at为约定的打洞时刻，服务器时间，0表示立即打洞
+    pub fn send(&self, src_peer: bool, ip: Ipv4Addr, info: NatInfo, fire_at: u64) -> bool {
         log::info!(
-            "发送打洞协商消息,是否对端发起:{},ip:{},info:{:?}",
+            "发送打洞协商消息,是否对端发起:{},ip:{},info:{:?},fire_at:{}",
             src_peer,
             ip,
-            info
+            info,
+            fire_at
         );
         let sender = match info.nat_type {
             NatType::Symmetric => {
@@ -52,15 +59,15 @@ impl PunchSender {
                 }
             }
         };
-        sender.try_send((ip, info)).is_ok()
+        sender.try_send((ip, info, fire_at)).is_ok()
     }
 }
 
 pub struct PunchReceiver {
-    receiver_peer: Receiver<(Ipv4Addr, NatInfo)>,
-    receiver_self: Receiver<(Ipv4Addr, NatInfo)>,
-    receiver_cone_peer: Receiver<(Ipv4Addr, NatInfo)>,
-    receiver_cone_self: Receiver<(Ipv4Addr, NatInfo)>,
+    receiver_peer: Receiver<(Ipv4Addr, NatInfo, u64)>,
+    receiver_self: Receiver<(Ipv4Addr, NatInfo, u64)>,
+    receiver_cone_peer: Receiver<(Ipv4Addr, NatInfo, u64)>,
+    receiver_cone_self: Receiver<(Ipv4Addr, NatInfo, u64)>,
 }
 
 pub fn punch_channel() -> (PunchSender, PunchReceiver) {
@@ -93,29 +100,43 @@ pub fn punch(
     client_cipher: Cipher,
     receiver: PunchReceiver,
     punch: Punch,
+    notice: NoticeHolder,
+    negative_path: NegativePathCache,
 ) {
     let punch_record = Arc::new(Mutex::new(HashMap::new()));
     let last_punch_record = HashMap::new();
     punch_request(
         scheduler,
-        context,
-        nat_test,
+        context.clone(),
+        nat_test.clone(),
         device_list,
         current_device.clone(),
         client_cipher.clone(),
         0,
         punch_record.clone(),
         last_punch_record,
+        notice,
+        negative_path,
     );
-    let f = |receiver: Receiver<(Ipv4Addr, NatInfo)>| {
+    let f = |receiver: Receiver<(Ipv4Addr, NatInfo, u64)>| {
         let punch = punch.clone();
+        let context = context.clone();
+        let nat_test = nat_test.clone();
         let current_device = current_device.clone();
         let client_cipher = client_cipher.clone();
         let punch_record = punch_record.clone();
         thread::Builder::new()
             .name("punch".into())
             .spawn(move || {
-                punch_start(receiver, punch, current_device, client_cipher, punch_record);
+                punch_start(
+                    receiver,
+                    punch,
+                    context,
+                    nat_test,
+                    current_device,
+                    client_cipher,
+                    punch_record,
+                );
             })
             .expect("punch");
     };
@@ -127,20 +148,15 @@ pub fn punch(
 
 /// 接收打洞消息，配合对端打洞
 fn punch_start(
-    receiver: Receiver<(Ipv4Addr, NatInfo)>,
+    receiver: Receiver<(Ipv4Addr, NatInfo, u64)>,
     mut punch: Punch,
+    context: ChannelContext,
+    nat_test: NatTest,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
     punch_record: Arc<Mutex<HashMap<Ipv4Addr, usize>>>,
 ) {
-    while let Ok((peer_ip, nat_info)) = receiver.recv() {
-        let mut packet = NetPacket::new_encrypt([0u8; 12 + ENCRYPTION_RESERVED]).unwrap();
-        packet.set_default_version();
-        packet.first_set_ttl(1);
-        packet.set_protocol(Protocol::Control);
-        packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
-        packet.set_source(current_device.load().virtual_ip());
-        packet.set_destination(peer_ip);
+    while let Ok((peer_ip, nat_info, fire_at)) = receiver.recv() {
         let count = {
             let mut guard = punch_record.lock();
             if let Some(v) = guard.get_mut(&peer_ip) {
@@ -152,17 +168,76 @@ fn punch_start(
             }
         };
         log::info!("第{}次发起打洞,目标:{:?},{:?} ", count, peer_ip, nat_info);
-
-        if let Err(e) = client_cipher.encrypt_ipv4(&mut packet) {
-            log::error!("{:?}", e);
-            continue;
+        if fire_at != 0 {
+            // 等到和对端约定的时刻
+            let wait = context.punch_sync.wait(fire_at);
+            log::info!("等待{:?}后打洞,目标:{:?}", wait, peer_ip);
+            thread::sleep(wait);
         }
+        let (fired, skew) = context.punch_sync.fire(peer_ip);
+        if let Some(skew) = skew {
+            record_skew(
+                &context,
+                peer_ip,
+                nat_test.nat_info().nat_type,
+                nat_info.nat_type,
+                skew,
+            );
+        }
+        let packet = match punch_request_packet(
+            &client_cipher,
+            current_device.load().virtual_ip(),
+            peer_ip,
+            fired,
+        ) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::error!("{:?}", e);
+                continue;
+            }
+        };
         if let Err(e) = punch.punch(packet.buffer(), peer_ip, nat_info, count < 2) {
             log::warn!("{:?}", e)
         }
     }
 }
 
+/// 双方开始打洞的时间差
+pub fn record_skew(
+    context: &ChannelContext,
+    peer: Ipv4Addr,
+    local: NatType,
+    remote: NatType,
+    skew: Duration,
+) {
+    log::info!("打洞时间差{:?},目标:{:?}", skew, peer);
+    context.punch_history.skewed(local, remote, skew);
+    context.diary.record(peer, DiaryEvent::PunchSkew { skew });
+}
+
+/// 打洞请求包，局域网发现的地址也用它打洞。
+/// fired为服务器时间表示的开始时刻，不为0时放在负载中，对端据此得到双方的时间差
+pub fn punch_request_packet(
+    client_cipher: &Cipher,
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    fired: u64,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let payload_len = if fired == 0 { 0 } else { 8 };
+    let mut packet = NetPacket::new_encrypt(vec![0u8; 12 + payload_len + ENCRYPTION_RESERVED])?;
+    if fired != 0 {
+        packet.set_payload(&fired.to_be_bytes())?;
+    }
+    packet.set_default_version();
+    packet.first_set_ttl(1);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
+    packet.set_source(src);
+    packet.set_destination(dest);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
+
 /// 定时发起打洞请求
 fn punch_request(
     scheduler: &Scheduler,
@@ -174,9 +249,15 @@ fn punch_request(
     count: usize,
     punch_record: Arc<Mutex<HashMap<Ipv4Addr, usize>>>,
     mut last_punch_record: HashMap<Ipv4Addr, usize>,
+    notice: NoticeHolder,
+    negative_path: NegativePathCache,
 ) {
     let curr = current_device.load();
-    let secs = if curr.status.online() {
+    let secs = if notice.in_maintenance() {
+        // 服务端维护期间暂停打洞，避免大量无效的打洞请求
+        log::info!("服务端维护中，暂停打洞");
+        Duration::from_secs(30)
+    } else if curr.status.online() {
         if let Err(e) = punch0(
             &context,
             &nat_test,
@@ -186,6 +267,7 @@ fn punch_request(
             &punch_record,
             &mut last_punch_record,
             count,
+            &negative_path,
         ) {
             log::warn!("{:?}", e)
         }
@@ -205,6 +287,8 @@ fn punch_request(
             count + 1,
             punch_record,
             last_punch_record,
+            notice,
+            negative_path,
         );
     });
     if !rs {
@@ -212,6 +296,11 @@ fn punch_request(
     }
 }
 
+/// 只由ip大的一方发起打洞，观察者不是流量的目标，不对它打洞
+fn is_punch_target(info: &PeerDeviceInfo, current_ip: Ipv4Addr) -> bool {
+    info.status.is_online() && info.virtual_ip > current_ip && !info.observer
+}
+
 /// 随机对需要打洞的客户端发起打洞请求
 fn punch0(
     context: &ChannelContext,
@@ -222,6 +311,7 @@ fn punch0(
     punch_record: &Mutex<HashMap<Ipv4Addr, usize>>,
     last_punch_record: &mut HashMap<Ipv4Addr, usize>,
     total_count: usize,
+    negative_path: &NegativePathCache,
 ) -> io::Result<()> {
     let nat_info = nat_test.nat_info();
     if total_count < 10
@@ -239,7 +329,14 @@ fn punch0(
         .lock()
         .1
         .iter()
-        .filter(|info| info.status.is_online() && info.virtual_ip > current_ip)
+        .filter(|info| {
+            is_punch_target(info, current_ip)
+                && !context.block_list.contains(&info.virtual_ip)
+                // 严格入站时只向有许可的对端打洞
+                && context.reverse.allowed(&info.virtual_ip)
+                // 无法直连的直接使用中继
+                && negative_path.check(&info.virtual_ip).is_none()
+        })
         .cloned()
         .collect();
     list.shuffle(&mut rand::thread_rng());
@@ -252,6 +349,7 @@ fn punch0(
         let p2p_num = context.route_table.p2p_num(&info.virtual_ip);
         let mut max_punch_interval = 70;
         if p2p_num > 0 {
+            negative_path.clear(&info.virtual_ip);
             if punch_count == 0 {
                 continue;
             }
@@ -271,13 +369,37 @@ fn punch0(
             .unwrap_or(0);
         // 梯度增加打洞时间间隔
         if total_count > last_punch + punch_count.min(max_punch_interval) {
-            last_punch_record.insert(info.virtual_ip, total_count);
             let packet = punch_packet(
                 client_cipher,
                 current_device.virtual_ip(),
                 &nat_info,
                 info.virtual_ip,
+                context.punch_sync.rtt(),
+                context.compact_encoding.load(Ordering::Relaxed),
             )?;
+            if !context
+                .probe_budget
+                .allow(ProbeKind::Punch, packet.buffer().len())
+            {
+                // 超出预算，下一轮再发起
+                break;
+            }
+            if p2p_num == 0 && last_punch_record.contains_key(&info.virtual_ip) {
+                // 上一次发起的打洞没有成功
+                negative_path.punch_failed(info.virtual_ip);
+                context.diary.record(info.virtual_ip, DiaryEvent::PunchFail);
+                if negative_path.check(&info.virtual_ip).is_some()
+                    && context.bring_up.give_up(info.virtual_ip)
+                {
+                    context.diary.record(
+                        info.virtual_ip,
+                        DiaryEvent::HandshakeFail {
+                            reason: "punch failed repeatedly",
+                        },
+                    );
+                }
+            }
+            last_punch_record.insert(info.virtual_ip, total_count);
             log::info!(
                 "目标:{:?},当前nat:{:?} 第{}次发起打洞协商请求， 第:{}轮",
                 info.virtual_ip,
@@ -285,21 +407,69 @@ fn punch0(
                 punch_count,
                 total_count,
             );
-            context.send_default(packet.buffer(), current_device.connect_server)?;
+            context.bring_up.begin(info.virtual_ip);
+            context
+                .diary
+                .record(info.virtual_ip, DiaryEvent::HandshakeStart);
+            let server = context
+                .rendezvous
+                .choose(current_device.connect_server, info.closest_server);
+            if server == current_device.connect_server {
+                context.send_default(packet.buffer(), server)?;
+            } else {
+                // 双方都离备用服务器更近，通过它协商，备用服务器只使用udp
+                log::info!("通过备用服务器{}和{}协商打洞", server, info.virtual_ip);
+                context.send_main_udp(0, packet.buffer(), server)?;
+            }
             break;
         }
     }
     Ok(())
 }
 
+/// 手动发起打洞，忽略无法直连的判定
+pub fn punch_now(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    negative_path: &NegativePathCache,
+    dest: Ipv4Addr,
+) -> io::Result<()> {
+    negative_path.clear(&dest);
+    let nat_info = nat_test.nat_info();
+    let packet = punch_packet(
+        client_cipher,
+        current_device.virtual_ip(),
+        &nat_info,
+        dest,
+        context.punch_sync.rtt(),
+        context.compact_encoding.load(Ordering::Relaxed),
+    )?;
+    log::info!(
+        "手动发起打洞协商请求,目标:{:?},当前nat:{:?}",
+        dest,
+        nat_info
+    );
+    context.bring_up.begin(dest);
+    context.diary.record(dest, DiaryEvent::HandshakeStart);
+    context.send_default(packet.buffer(), current_device.connect_server)
+}
+
 fn punch_packet(
     client_cipher: &Cipher,
     virtual_ip: Ipv4Addr,
     nat_info: &NatInfo,
     dest: Ipv4Addr,
+    server_rtt: u32,
+    compact: bool,
 ) -> io::Result<NetPacket<Vec<u8>>> {
     let mut punch_reply = PunchInfo::new();
     punch_reply.reply = false;
+    // 请求总是用protobuf，对端可能是旧版本，声明支持后由对端选择回复的编码
+    punch_reply.compact_encoding = compact;
+    // 有服务器时钟的估计时，由对端约定打洞时刻
+    punch_reply.server_rtt = server_rtt;
     punch_reply.public_ip_list = nat_info
         .public_ips
         .iter()
@@ -332,3 +502,26 @@ fn punch_packet(
     client_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
+
+#[test]
+fn test_observer_not_punch_target() {
+    let peer = |ip: [u8; 4], observer: bool| {
+        PeerDeviceInfo::new(
+            Ipv4Addr::from(ip),
+            "peer".into(),
+            0,
+            false,
+            vec![],
+            observer,
+            String::new(),
+        )
+    };
+    let current_ip = Ipv4Addr::new(10, 26, 0, 2);
+    assert!(is_punch_target(&peer([10, 26, 0, 3], false), current_ip));
+    assert!(!is_punch_target(&peer([10, 26, 0, 3], true), current_ip));
+    assert!(!is_punch_target(&peer([10, 26, 0, 1], false), current_ip));
+    // 离线
+    let mut info = peer([10, 26, 0, 4], false);
+    info.status = crate::handle::PeerDeviceStatus::Offline;
+    assert!(!is_punch_target(&info, current_ip));
+}
@@ -1,29 +1,99 @@
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::punch::NatInfo;
 use crate::channel::sender::AcceptSocketSender;
+use crate::handle::critical_notice::{CriticalNotice, NoticeKind};
+use crate::handle::PeerDeviceInfo;
 use crate::nat;
 use crate::nat::NatTest;
 use crate::util::Scheduler;
 
-/// 10分钟探测一次nat
+/// 定时重新探测nat的间隔
+const RETEST_INTERVAL: Duration = Duration::from_secs(60 * 10);
+/// 检查公网ip是否变化的间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 10分钟探测一次nat，服务端看到的公网ip变化时立即重新探测
 pub fn retrieve_nat_type(
     scheduler: &Scheduler,
     context: ChannelContext,
     nat_test: NatTest,
     udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    critical_notice: CriticalNotice,
 ) {
-    retrieve_nat_type0(context.clone(), nat_test.clone(), udp_socket_sender.clone());
-    scheduler.timeout(Duration::from_secs(60 * 10), move |s| {
-        retrieve_nat_type(s, context, nat_test, udp_socket_sender)
+    retrieve_nat_type0(
+        context.clone(),
+        nat_test.clone(),
+        udp_socket_sender.clone(),
+        device_list.clone(),
+        critical_notice.clone(),
+    );
+    watch(
+        scheduler,
+        context,
+        nat_test,
+        udp_socket_sender,
+        device_list,
+        critical_notice,
+        Instant::now() + RETEST_INTERVAL,
+    );
+}
+
+fn watch(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    nat_test: NatTest,
+    udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    critical_notice: CriticalNotice,
+    next: Instant,
+) {
+    let rs = scheduler.timeout(WATCH_INTERVAL, move |s| {
+        let now = Instant::now();
+        let changed = nat_test.public_ip_changed();
+        let next = if changed || now >= next {
+            if changed {
+                log::info!("公网ip变化,重新探测nat类型");
+            }
+            // 距离上次探测不足10秒时跳过，标记保留到下次
+            retrieve_nat_type0(
+                context.clone(),
+                nat_test.clone(),
+                udp_socket_sender.clone(),
+                device_list.clone(),
+                critical_notice.clone(),
+            );
+            now + RETEST_INTERVAL
+        } else {
+            next
+        };
+        watch(
+            s,
+            context,
+            nat_test,
+            udp_socket_sender,
+            device_list,
+            critical_notice,
+            next,
+        )
     });
+    if !rs {
+        log::info!("定时任务停止");
+    }
 }
 
-fn retrieve_nat_type0(
+pub(super) fn retrieve_nat_type0(
     context: ChannelContext,
     nat_test: NatTest,
     udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    critical_notice: CriticalNotice,
 ) {
     thread::Builder::new()
         .name("natTest".into())
@@ -31,9 +101,21 @@ fn retrieve_nat_type0(
             if nat_test.can_update() {
                 let local_ipv4 = nat::local_ipv4();
                 let local_ipv6 = nat::local_ipv6();
-                match nat_test.re_test(local_ipv4, local_ipv6) {
+                let old = nat_test.nat_info();
+                match nat_test.re_test(local_ipv4, local_ipv6, &context.socket_pool) {
                     Ok(nat_info) => {
                         log::info!("当前nat信息:{:?}", nat_info);
+                        if endpoint_changed(&old, &nat_info) {
+                            // 对端到本机的直连路由可能已经失效，通知对端
+                            let peers = super::online_peers(&device_list);
+                            if !peers.is_empty() {
+                                if let Err(e) =
+                                    critical_notice.send(NoticeKind::EndpointChange, peers)
+                                {
+                                    log::warn!("地址变化通知 {:?}", e);
+                                }
+                            }
+                        }
                         if let Err(e) = context.switch(nat_info.nat_type, &udp_socket_sender) {
                             log::warn!("{:?}", e);
                         }
@@ -46,3 +128,15 @@ fn retrieve_nat_type0(
         })
         .expect("natTest");
 }
+
+/// 本机地址是否发生变化，初次探测不算
+fn endpoint_changed(old: &NatInfo, new: &NatInfo) -> bool {
+    if old.public_ips.is_empty() && old.local_ipv4().is_none() {
+        return false;
+    }
+    let mut old_ips = old.public_ips.clone();
+    let mut new_ips = new.public_ips.clone();
+    old_ips.sort();
+    new_ips.sort();
+    old_ips != new_ips || old.local_ipv4() != new.local_ipv4() || old.ipv6() != new.ipv6()
+}
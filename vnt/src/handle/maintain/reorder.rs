@@ -0,0 +1,22 @@
+use std::time::{Duration, Instant};
+
+use crate::channel::context::ChannelContext;
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+use crate::util::Scheduler;
+
+/// 定时写入重排等待超时的包，间隔为最长等待时间的一半
+pub fn reorder_flush(scheduler: &Scheduler, context: ChannelContext, device: DeviceAdapter) {
+    context.reorder.expire(Instant::now(), |buf| {
+        if !buf.is_empty() {
+            if let Err(e) = device.write(buf) {
+                log::warn!("重排写入网卡失败 {:?}", e);
+            }
+        }
+    });
+    let rs = scheduler.timeout(Duration::from_millis(5), move |s| {
+        reorder_flush(s, context, device)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
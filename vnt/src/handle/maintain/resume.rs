@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::diary::EvictReason;
+use crate::channel::sender::AcceptSocketSender;
+use crate::cipher::Cipher;
+use crate::handle::callback::ResumeInfo;
+use crate::handle::critical_notice::CriticalNotice;
+use crate::handle::negative_path::NegativePathCache;
+use crate::handle::resume::{recovery_plan, RecoveryStep, ResumeDetector, RESUME_CHECK_INTERVAL};
+use crate::handle::{ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::nat::NatTest;
+use crate::util::health::ReadyCheck;
+use crate::util::Scheduler;
+use crate::VntCallback;
+
+/// 唤醒后需要用到的状态
+#[derive(Clone)]
+pub struct ResumeContext {
+    pub context: ChannelContext,
+    pub current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
+    pub device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    pub client_cipher: Cipher,
+    pub server_cipher: Cipher,
+    pub report_usage: bool,
+    pub negative_path: NegativePathCache,
+    pub nat_test: NatTest,
+    // 只使用中继时不需要探测nat
+    pub udp_socket_sender: Option<AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>>,
+    pub critical_notice: CriticalNotice,
+}
+
+/// 定时检测系统休眠，唤醒后立即恢复，不用等心跳超时
+pub fn resume_check<Call: VntCallback>(
+    scheduler: &Scheduler,
+    mut detector: ResumeDetector,
+    resume_context: ResumeContext,
+    call: Call,
+) {
+    if let Some(slept) = detector.check() {
+        recover(&resume_context, &call, slept);
+    }
+    let rs = scheduler.timeout(RESUME_CHECK_INTERVAL, move |s| {
+        resume_check(s, detector, resume_context, call)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+pub fn resume_detector() -> ResumeDetector {
+    ResumeDetector::new(RESUME_CHECK_INTERVAL)
+}
+
+fn recover<Call: VntCallback>(ctx: &ResumeContext, call: &Call, slept: Duration) {
+    let plan = recovery_plan(slept);
+    let reconnect = plan.contains(&RecoveryStep::Reconnect);
+    log::info!("休眠{:?}后唤醒,恢复步骤{:?}", slept, plan);
+    for step in plan {
+        match step {
+            RecoveryStep::Notify => {
+                call.resumed(ResumeInfo::new(slept, reconnect));
+            }
+            RecoveryStep::Heartbeat => {
+                super::heartbeat::heartbeat0(
+                    &ctx.context,
+                    &ctx.current_device_info.load(),
+                    &ctx.device_list,
+                    &ctx.client_cipher,
+                    &ctx.server_cipher,
+                    ctx.report_usage,
+                );
+            }
+            RecoveryStep::ClearDirectRoutes => {
+                for (ip, routes) in ctx.context.route_table.route_table() {
+                    for route in routes {
+                        if route.is_p2p() {
+                            ctx.context
+                                .remove_route(&ip, route.route_key(), EvictReason::Resume);
+                        }
+                    }
+                }
+                for peer in ctx.device_list.lock().1.iter() {
+                    ctx.negative_path.clear(&peer.virtual_ip);
+                }
+            }
+            RecoveryStep::Reconnect => {
+                // 网关检测任务会重新握手注册
+                crate::handle::change_status(&ctx.current_device_info, ConnectStatus::Connecting);
+                ctx.context
+                    .health
+                    .not_ready(ReadyCheck::Registered, "reconnecting after resume");
+            }
+            RecoveryStep::RefreshNat => {
+                if let Some(udp_socket_sender) = &ctx.udp_socket_sender {
+                    super::re_nat_type::retrieve_nat_type0(
+                        ctx.context.clone(),
+                        ctx.nat_test.clone(),
+                        udp_socket_sender.clone(),
+                        ctx.device_list.clone(),
+                        ctx.critical_notice.clone(),
+                    );
+                }
+            }
+        }
+    }
+}
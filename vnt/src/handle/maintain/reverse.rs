@@ -0,0 +1,15 @@
+use std::time::{Duration, Instant};
+
+use crate::channel::context::ChannelContext;
+use crate::util::Scheduler;
+
+/// 严格入站时定时清理到期的直连许可
+pub fn reverse_expire(scheduler: &Scheduler, context: ChannelContext) {
+    if context.reverse.strict() {
+        context.expire_allowances(Instant::now());
+    }
+    let rs = scheduler.timeout(Duration::from_secs(1), move |s| reverse_expire(s, context));
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::handle::callback::ErrorType;
+use crate::handle::CurrentDeviceInfo;
+use crate::tun_tap_device::route_guard::{self, find_conflicts, RouteGuard, RouteHop};
+use crate::util::Scheduler;
+use crate::{ErrorInfo, VntCallback};
+
+/// 定时检查到服务器和直连对端的路由，系统没有统一的路由变化通知，这里用轮询代替
+pub fn route_guard<Call: VntCallback>(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
+    guard: RouteGuard,
+    call: Call,
+) {
+    route_guard0(&context, &current_device_info.load(), &guard, &call);
+    let rs = scheduler.timeout(Duration::from_secs(30), move |s| {
+        route_guard(s, context, current_device_info, guard, call)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn query(ip: Ipv4Addr) -> Option<RouteHop> {
+    match route_guard::query(ip) {
+        Ok(hop) => hop,
+        Err(e) => {
+            log::debug!("查询路由{}失败 {:?}", ip, e);
+            None
+        }
+    }
+}
+
+fn route_guard0<Call: VntCallback>(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    guard: &RouteGuard,
+    call: &Call,
+) {
+    if current_device.virtual_ip.is_unspecified() {
+        return;
+    }
+    let mut targets = Vec::new();
+    let server = match current_device.connect_server {
+        SocketAddr::V4(addr) => Some(*addr.ip()),
+        SocketAddr::V6(_) => None,
+    };
+    targets.extend(server);
+    for (_, route) in context.route_table.route_table_p2p() {
+        if let SocketAddr::V4(addr) = route.addr {
+            if !targets.contains(addr.ip()) {
+                targets.push(*addr.ip());
+            }
+        }
+    }
+    let tun_name = guard.tun_name();
+    let virtual_ip = current_device.virtual_ip;
+    let conflicts = find_conflicts(&targets, tun_name, virtual_ip, query);
+    for conflict in &conflicts {
+        if guard.first_warning(&conflict.to_string()) {
+            log::warn!("{}", conflict);
+            call.error(ErrorInfo::new_msg(
+                ErrorType::RouteWarning,
+                conflict.to_string(),
+            ));
+        }
+        match guard.protect(conflict.target) {
+            Ok(true) => {
+                log::info!(
+                    "已添加主机路由 {} {}",
+                    conflict.target,
+                    guard.original().map(|v| v.to_string()).unwrap_or_default()
+                );
+            }
+            Ok(false) => {}
+            Err(e) => {
+                log::warn!("添加主机路由{}失败 {:?}", conflict.target, e);
+            }
+        }
+    }
+    let unresolved = if conflicts.is_empty() {
+        false
+    } else {
+        let targets: Vec<Ipv4Addr> = conflicts.iter().map(|v| v.target).collect();
+        !find_conflicts(&targets, tun_name, virtual_ip, query).is_empty()
+    };
+    let was_unresolved = context.route_conflict.swap(unresolved, Ordering::AcqRel);
+    if unresolved && !was_unresolved {
+        log::warn!("路由冲突未解决，暂停转发到'-i'的网段");
+        call.error(ErrorInfo::new_msg(
+            ErrorType::RouteWarning,
+            "route conflict unresolved, forwarding to external routes ('-i') is paused".to_string(),
+        ));
+    } else if !unresolved && was_unresolved {
+        log::info!("路由回环已解决,恢复转发外部路由");
+    }
+    // 嵌套在其他vpn里面时，两层封装会超过mtu
+    if let Some(hop) = server.and_then(query) {
+        if hop.is_other_vpn(tun_name) && guard.first_warning(&hop.interface) {
+            let msg = format!(
+                "server is reached through another VPN interface ({}), packets are encapsulated twice, consider lowering the mtu, e.g. '-u 1300'",
+                hop
+            );
+            log::warn!("{}", msg);
+            call.error(ErrorInfo::new_msg(ErrorType::RouteWarning, msg));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::time::Duration;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::callback::ServerActionInfo;
+use crate::handle::server_action::{ack_packet, Action, ActionRequest};
+use crate::handle::{ConnectStatus, CurrentDeviceInfo, GATEWAY_IP};
+use crate::proto::message::ServerActionStatus;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{service_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::health::ReadyCheck;
+use crate::util::Scheduler;
+use crate::VntCallback;
+
+use super::ResumeContext;
+
+/// 执行服务端请求的操作，和休眠唤醒的恢复使用同样的状态
+pub fn server_action<Call: VntCallback>(scheduler: &Scheduler, ctx: ResumeContext, call: Call) {
+    while let Some(request) = ctx.context.server_actions.take() {
+        let (status, detail) = match execute(&ctx, &call, &request) {
+            Ok(detail) => (ServerActionStatus::Done, detail),
+            Err(detail) => (ServerActionStatus::Failed, detail),
+        };
+        log::warn!(
+            "服务端请求的操作 id={} action={} {:?} {}",
+            request.id,
+            request.action,
+            status,
+            detail
+        );
+        if let Err(e) = send_ack(
+            &ctx.context,
+            &ctx.current_device_info.load(),
+            &ctx.server_cipher,
+            request.id,
+            request.action.name(),
+            status,
+            &detail,
+        ) {
+            log::warn!("回应服务端请求的操作失败 id={} {:?}", request.id, e);
+        }
+    }
+    let rs = scheduler.timeout(Duration::from_secs(1), move |s| server_action(s, ctx, call));
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn execute<Call: VntCallback>(
+    ctx: &ResumeContext,
+    call: &Call,
+    request: &ActionRequest,
+) -> Result<String, String> {
+    match request.action {
+        Action::Resync => {
+            let current_device = ctx.current_device_info.load();
+            pull_device_list(&ctx.context, &current_device, &ctx.server_cipher)
+                .map_err(|e| format!("{:?}", e))?;
+            Ok("device list requested".to_string())
+        }
+        Action::Renat => {
+            let udp_socket_sender = ctx
+                .udp_socket_sender
+                .as_ref()
+                .ok_or_else(|| "relay only, nat detection is disabled".to_string())?;
+            super::re_nat_type::retrieve_nat_type0(
+                ctx.context.clone(),
+                ctx.nat_test.clone(),
+                udp_socket_sender.clone(),
+                ctx.device_list.clone(),
+                ctx.critical_notice.clone(),
+            );
+            Ok("nat detection started".to_string())
+        }
+        Action::Rekey => {
+            // 网关检测任务会重新握手注册，协商新的密钥
+            crate::handle::change_status(&ctx.current_device_info, ConnectStatus::Connecting);
+            ctx.context
+                .health
+                .not_ready(ReadyCheck::Registered, "rekey requested by server");
+            Ok("reconnecting".to_string())
+        }
+        Action::CollectBundle | Action::SetLogLevel => call.server_action(ServerActionInfo::new(
+            request.id,
+            request.action,
+            request.arg.clone(),
+        )),
+    }
+}
+
+/// 回应服务端，拒绝的请求在收到时立即回应
+pub fn send_ack(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    server_cipher: &Cipher,
+    id: u32,
+    action: &str,
+    status: ServerActionStatus,
+    detail: &str,
+) -> io::Result<()> {
+    let packet = ack_packet(
+        server_cipher,
+        current_device.virtual_ip,
+        current_device.virtual_gateway,
+        id,
+        action,
+        status,
+        detail,
+    )?;
+    context.send_default(packet.buffer(), current_device.connect_server)
+}
+
+fn pull_device_list(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    server_cipher: &Cipher,
+) -> io::Result<()> {
+    let mut poll_device = NetPacket::new_encrypt([0; 12 + ENCRYPTION_RESERVED])?;
+    poll_device.set_source(current_device.virtual_ip);
+    poll_device.set_destination(GATEWAY_IP);
+    poll_device.set_default_version();
+    poll_device.set_gateway_flag(true);
+    poll_device.first_set_ttl(MAX_TTL);
+    poll_device.set_protocol(Protocol::Service);
+    poll_device.set_transport_protocol(service_packet::Protocol::PullDeviceList.into());
+    server_cipher.encrypt_ipv4(&mut poll_device)?;
+    context.send_default(poll_device.buffer(), current_device.connect_server)
+}
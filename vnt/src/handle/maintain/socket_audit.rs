@@ -0,0 +1,20 @@
+use crate::channel::context::ChannelContext;
+use crate::channel::socket_pool::AUDIT_INTERVAL;
+use crate::util::Scheduler;
+
+/// 定时检查辅助socket，超过用途最长存活时间还没关闭的记录日志
+pub fn socket_audit(scheduler: &Scheduler, context: ChannelContext) {
+    for stat in context.socket_pool.audit() {
+        log::warn!(
+            "辅助socket可能泄漏 用途={} 端口={} 存活{:?} 字节={}",
+            stat.purpose,
+            stat.local_port,
+            stat.age,
+            stat.bytes
+        );
+    }
+    let rs = scheduler.timeout(AUDIT_INTERVAL, move |s| socket_audit(s, context));
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
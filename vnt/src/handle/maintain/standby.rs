@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::probe_budget::ProbeKind;
+use crate::cipher::Cipher;
+use crate::handle::maintain::heartbeat::heartbeat_packet_server;
+use crate::handle::{registrar, BaseConfigInfo, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::nat::NatTest;
+use crate::util::Scheduler;
+
+/// 备用服务器的心跳间隔
+const INTERVAL: Duration = Duration::from_secs(5);
+/// 没有注册成功时每隔这么多轮重试一次
+const RETRY_ROUNDS: usize = 6;
+
+/// 维持备用服务器上的注册，只用于打洞协商
+///
+/// 在主服务器上线后以相同的虚拟ip注册，注册成功后定时发送心跳保持会话并测量延迟
+pub fn standby(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    config_info: BaseConfigInfo,
+    nat_test: NatTest,
+    round: usize,
+) {
+    standby0(
+        &context,
+        &current_device.load(),
+        &device_list,
+        &config_info,
+        &nat_test,
+        round,
+    );
+    let rs = scheduler.timeout(INTERVAL, move |s| {
+        standby(
+            s,
+            context,
+            current_device,
+            device_list,
+            config_info,
+            nat_test,
+            round.wrapping_add(1),
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn standby0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    config_info: &BaseConfigInfo,
+    nat_test: &NatTest,
+    round: usize,
+) {
+    if current_device.status.offline() {
+        return;
+    }
+    for server in context.rendezvous.standby() {
+        let packet = if server.registered {
+            heartbeat_packet_server(
+                device_list,
+                &Cipher::None,
+                None,
+                &[],
+                current_device.virtual_ip,
+                current_device.virtual_gateway,
+            )
+            .map(|v| v.buffer().to_vec())
+        } else if round % RETRY_ROUNDS == 0 {
+            // 不允许服务端分配其他ip，ip冲突时注册失败
+            registrar::registration_request_packet(
+                &Cipher::None,
+                config_info.token.clone(),
+                config_info.device_id.clone(),
+                config_info.name.clone(),
+                Some(current_device.virtual_ip),
+                false,
+                false,
+                config_info.client_secret_hash.as_ref().map(|v| v.as_ref()),
+                config_info.observer,
+                config_info.fingerprint.clone(),
+                nat_test.nat_class(),
+            )
+            .map(|v| v.buffer().to_vec())
+        } else {
+            continue;
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("备用服务器数据包 {:?}", e);
+                continue;
+            }
+        };
+        if !context
+            .probe_budget
+            .allow(ProbeKind::ServerHeartbeat, packet.len())
+        {
+            continue;
+        }
+        // 备用服务器只使用udp
+        if let Err(e) = context.send_main_udp(0, &packet, server.addr) {
+            log::debug!("发送到备用服务器{}失败 {:?}", server.addr, e);
+        }
+    }
+}
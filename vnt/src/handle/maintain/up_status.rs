@@ -3,7 +3,8 @@ use crate::handle::CurrentDeviceInfo;
 use crate::proto::message::{ClientStatusInfo, PunchNatType, RouteItem};
 use crate::protocol::body::ENCRYPTION_RESERVED;
 use crate::protocol::{service_packet, NetPacket, Protocol, HEAD_LEN, MAX_TTL};
-use crate::util::{Scheduler, WatchSingleU64Adder, WatchU64Adder};
+use crate::util::metrics::Counter;
+use crate::util::Scheduler;
 use crossbeam_utils::atomic::AtomicCell;
 use protobuf::Message;
 use std::io;
@@ -15,8 +16,8 @@ pub fn up_status(
     scheduler: &Scheduler,
     context: ChannelContext,
     current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
-    down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
+    down_count_watcher: Counter,
+    up_count_watcher: Counter,
 ) {
     let _ = scheduler.timeout(Duration::from_secs(60), move |x| {
         up_status0(
@@ -33,8 +34,8 @@ fn up_status0(
     scheduler: &Scheduler,
     context: ChannelContext,
     current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
-    down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
+    down_count_watcher: Counter,
+    up_count_watcher: Counter,
 ) {
     if let Err(e) = send_up_status_packet(
         &context,
@@ -61,8 +62,8 @@ fn up_status0(
 fn send_up_status_packet(
     context: &ChannelContext,
     current_device_info: &AtomicCell<CurrentDeviceInfo>,
-    down_count_watcher: &WatchU64Adder,
-    up_count_watcher: &WatchSingleU64Adder,
+    down_count_watcher: &Counter,
+    up_count_watcher: &Counter,
 ) -> io::Result<()> {
     let device_info = current_device_info.load();
     if device_info.status.offline() {
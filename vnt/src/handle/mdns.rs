@@ -0,0 +1,880 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use socket2::{Domain, SockRef, Socket, Type};
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::maintain::punch_request_packet;
+use crate::handle::CurrentDeviceInfo;
+use crate::util::subsystem::Switch;
+use crate::util::StopManager;
+
+/// 局域网内广播的服务名
+pub const SERVICE: &str = "_vnt._udp.local";
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const PORT: u16 = 5353;
+/// 公告和查询的间隔
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// 挑战等待应答的时间
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 已验证的地址多久后重新验证
+const REVERIFY: Duration = Duration::from_secs(300);
+const PENDING_LIMIT: usize = 64;
+const TTL: u32 = 120;
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// 唯一记录的cache-flush位，查询中表示希望单播应答
+const CLASS_FLAG: u16 = 0x8000;
+
+/// 由token和密码派生的密钥，公告中只出现它的哈希，不会泄露token
+struct MdnsKey {
+    key: [u8; 32],
+    // 公告中的组网标识，用于快速过滤其他组网的设备
+    tag: String,
+}
+
+impl MdnsKey {
+    fn new(token: &str, password: Option<&str>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"vnt-mdns-key");
+        for part in [token, password.unwrap_or("")] {
+            hasher.update((part.len() as u32).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+        let key: [u8; 32] = hasher.finalize().into();
+        let tag = hex(&hmac(&key, b"tag")[..4]);
+        Self { key, tag }
+    }
+    /// 对挑战的应答，绑定对端公告的虚拟ip和端口
+    fn proof(&self, nonce: &str, virtual_ip: Ipv4Addr, port: u16) -> String {
+        let mut msg = nonce.as_bytes().to_vec();
+        msg.extend_from_slice(&virtual_ip.octets());
+        msg.extend_from_slice(&port.to_be_bytes());
+        hex(&hmac(&self.key, &msg)[..16])
+    }
+}
+
+fn hmac(key: &[u8; 32], msg: &[u8]) -> [u8; 32] {
+    let mut ipad = [0x36u8; 64];
+    let mut opad = [0x5cu8; 64];
+    for i in 0..32 {
+        ipad[i] ^= key[i];
+        opad[i] ^= key[i];
+    }
+    let inner = Sha256::new()
+        .chain_update(ipad)
+        .chain_update(msg)
+        .finalize();
+    Sha256::new()
+        .chain_update(opad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+/// 本机的虚拟ip和数据端口
+#[derive(Copy, Clone, Debug)]
+pub struct Local {
+    pub virtual_ip: Ipv4Addr,
+    pub port: u16,
+}
+
+/// 需要发出的包
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outgoing {
+    Multicast(Vec<u8>),
+    Unicast(Vec<u8>, SocketAddr),
+}
+
+/// 局域网发现
+///
+/// 公告`_vnt._udp`服务，内容是虚拟ip、数据端口、设备名称和组网标识，
+/// 同时查询其他设备的公告。组网标识相同的设备先发送挑战，
+/// 对端用token派生的密钥应答，验证通过后才把(局域网地址,虚拟ip)加入打洞的候选地址，
+/// 不经过服务器也能直连
+pub struct Mdns {
+    key: MdnsKey,
+    name: String,
+    // 挑战 -> 对端公告的虚拟ip和地址
+    pending: Mutex<HashMap<String, (Ipv4Addr, SocketAddr, Instant)>>,
+    verified: Mutex<HashMap<Ipv4Addr, (SocketAddr, Instant)>>,
+}
+
+impl Mdns {
+    pub fn new(token: &str, password: Option<&str>, name: &str) -> Self {
+        Self {
+            key: MdnsKey::new(token, password),
+            name: name.chars().take(64).collect(),
+            pending: Mutex::new(HashMap::new()),
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+    /// 查询局域网内的其他设备
+    pub fn browse(&self) -> Vec<u8> {
+        message(false, &[(SERVICE.to_string(), TYPE_PTR, CLASS_IN)], &[])
+    }
+    /// 本机的公告
+    pub fn announce(&self, local: &Local) -> Vec<u8> {
+        let instance = instance(local.virtual_ip);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&local.port.to_be_bytes());
+        write_name(
+            &mut srv,
+            &format!("{}.local", instance_label(local.virtual_ip)),
+        );
+        let txt = txt(&[
+            format!("vip={}", local.virtual_ip),
+            format!("net={}", self.key.tag),
+            format!("name={}", self.name),
+        ]);
+        let mut ptr = Vec::new();
+        write_name(&mut ptr, &instance);
+        message(
+            true,
+            &[],
+            &[
+                (SERVICE.to_string(), TYPE_PTR, CLASS_IN, ptr),
+                (instance.clone(), TYPE_SRV, CLASS_IN | CLASS_FLAG, srv),
+                (instance, TYPE_TXT, CLASS_IN | CLASS_FLAG, txt),
+            ],
+        )
+    }
+    /// 处理收到的包，返回需要发送的包和验证通过的对端
+    pub fn handle(
+        &self,
+        data: &[u8],
+        src: SocketAddr,
+        local: &Local,
+    ) -> (Vec<Outgoing>, Option<(Ipv4Addr, SocketAddr)>) {
+        let mut out = Vec::new();
+        let msg = match parse(data) {
+            Some(msg) => msg,
+            None => return (out, None),
+        };
+        if !msg.response {
+            if local.virtual_ip.is_unspecified() {
+                return (out, None);
+            }
+            let suffix = format!(".{}", instance(local.virtual_ip));
+            for (name, qtype) in &msg.questions {
+                if (*qtype == TYPE_PTR || *qtype == TYPE_ANY) && name.eq_ignore_ascii_case(SERVICE)
+                {
+                    out.push(Outgoing::Multicast(self.announce(local)));
+                } else if *qtype == TYPE_TXT {
+                    if let Some(nonce) = strip_suffix(name, &suffix).filter(|v| is_nonce(v)) {
+                        let proof = self.key.proof(nonce, local.virtual_ip, local.port);
+                        let data = message(
+                            true,
+                            &[],
+                            &[(
+                                name.clone(),
+                                TYPE_TXT,
+                                CLASS_IN | CLASS_FLAG,
+                                txt(&[format!("proof={}", proof)]),
+                            )],
+                        );
+                        out.push(Outgoing::Unicast(data, src));
+                    }
+                }
+            }
+            return (out, None);
+        }
+        let mut verified = None;
+        for record in &msg.records {
+            match &record.data {
+                RecordData::Ptr(instance) if record.name.eq_ignore_ascii_case(SERVICE) => {
+                    if let Some(challenge) = self.challenge(&msg, instance, src, local) {
+                        out.push(challenge);
+                    }
+                }
+                RecordData::Txt(list) => {
+                    if let Some(proof) = list.iter().find_map(|v| v.strip_prefix("proof=")) {
+                        let nonce = record.name.split('.').next().unwrap_or("");
+                        if let Some(v) = self.check_proof(nonce, proof) {
+                            verified = Some(v);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        (out, verified)
+    }
+    /// 收到其他设备的公告，组网标识相同时发送挑战
+    fn challenge(
+        &self,
+        msg: &Message,
+        instance: &str,
+        src: SocketAddr,
+        local: &Local,
+    ) -> Option<Outgoing> {
+        let mut port = 0;
+        let mut virtual_ip = None;
+        let mut tag = None;
+        for record in msg
+            .records
+            .iter()
+            .filter(|v| v.name.eq_ignore_ascii_case(instance))
+        {
+            match &record.data {
+                RecordData::Srv(v) => port = *v,
+                RecordData::Txt(list) => {
+                    for item in list {
+                        if let Some(v) = item.strip_prefix("vip=") {
+                            virtual_ip = v.parse::<Ipv4Addr>().ok();
+                        } else if let Some(v) = item.strip_prefix("net=") {
+                            tag = Some(v);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let virtual_ip = virtual_ip?;
+        if tag != Some(self.key.tag.as_str())
+            || port == 0
+            || virtual_ip == local.virtual_ip
+            || virtual_ip.is_unspecified()
+        {
+            return None;
+        }
+        // 保留ipv6链路本地地址的网卡序号
+        let addr = match src {
+            SocketAddr::V4(src) => SocketAddr::V4(SocketAddrV4::new(*src.ip(), port)),
+            SocketAddr::V6(src) => SocketAddr::V6(SocketAddrV6::new(
+                *src.ip(),
+                port,
+                src.flowinfo(),
+                src.scope_id(),
+            )),
+        };
+        let now = Instant::now();
+        if let Some((v, time)) = self.verified.lock().get(&virtual_ip) {
+            if *v == addr && now.duration_since(*time) < REVERIFY {
+                return None;
+            }
+        }
+        let nonce = hex(&rand::thread_rng().gen::<[u8; 16]>());
+        {
+            let mut pending = self.pending.lock();
+            pending.retain(|_, (_, _, time)| now.duration_since(*time) < CHALLENGE_TIMEOUT);
+            if pending.len() >= PENDING_LIMIT {
+                return None;
+            }
+            pending.insert(nonce.clone(), (virtual_ip, addr, now));
+        }
+        let name = format!("{}.{}", nonce, instance);
+        let data = message(false, &[(name, TYPE_TXT, CLASS_IN | CLASS_FLAG)], &[]);
+        Some(Outgoing::Unicast(data, src))
+    }
+    fn check_proof(&self, nonce: &str, proof: &str) -> Option<(Ipv4Addr, SocketAddr)> {
+        let (virtual_ip, addr, time) = self.pending.lock().remove(nonce)?;
+        if time.elapsed() >= CHALLENGE_TIMEOUT {
+            return None;
+        }
+        let expect = self.key.proof(nonce, virtual_ip, addr.port());
+        // 长度固定，逐字节比较全部内容
+        if expect.len() != proof.len()
+            || expect
+                .bytes()
+                .zip(proof.bytes())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                != 0
+        {
+            log::warn!("局域网发现的挑战应答错误 {} {}", virtual_ip, addr);
+            return None;
+        }
+        self.verified
+            .lock()
+            .insert(virtual_ip, (addr, Instant::now()));
+        Some((virtual_ip, addr))
+    }
+}
+
+fn instance_label(virtual_ip: Ipv4Addr) -> String {
+    format!("vnt-{}", virtual_ip.to_string().replace('.', "-"))
+}
+
+fn instance(virtual_ip: Ipv4Addr) -> String {
+    format!("{}.{}", instance_label(virtual_ip), SERVICE)
+}
+
+fn strip_suffix<'a>(name: &'a str, suffix: &str) -> Option<&'a str> {
+    if name.len() > suffix.len() && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix) {
+        name.get(..name.len() - suffix.len())
+    } else {
+        None
+    }
+}
+
+fn is_nonce(v: &str) -> bool {
+    v.len() == 32 && v.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+fn txt(list: &[String]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for item in list {
+        let item = &item.as_bytes()[..item.len().min(255)];
+        data.push(item.len() as u8);
+        data.extend_from_slice(item);
+    }
+    data
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|v| !v.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+/// 构造dns消息，dns-parser只能构造查询，这里自己编码
+fn message(
+    response: bool,
+    questions: &[(String, u16, u16)],
+    records: &[(String, u16, u16, Vec<u8>)],
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(256);
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(&(if response { 0x8400u16 } else { 0 }).to_be_bytes());
+    buf.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    for (name, qtype, class) in questions {
+        write_name(&mut buf, name);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&class.to_be_bytes());
+    }
+    for (name, rtype, class, data) in records {
+        write_name(&mut buf, name);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&class.to_be_bytes());
+        buf.extend_from_slice(&TTL.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+    buf
+}
+
+#[derive(Debug)]
+enum RecordData {
+    Ptr(String),
+    Srv(u16),
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+#[derive(Debug)]
+struct Message {
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+/// 只解析需要的记录类型，其他记录跳过
+fn parse(data: &[u8]) -> Option<Message> {
+    let u16_at = |pos: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+    };
+    let response = u16_at(2)? & 0x8000 != 0;
+    let qd = u16_at(4)?;
+    // 回答、授权、附加记录都当作记录处理
+    let rr = u16_at(6)? as usize + u16_at(8)? as usize + u16_at(10)? as usize;
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..qd {
+        let name = read_name(data, &mut pos)?;
+        let qtype = u16_at(pos)?;
+        pos += 4;
+        questions.push((name, qtype));
+    }
+    let mut records = Vec::new();
+    for _ in 0..rr {
+        let name = read_name(data, &mut pos)?;
+        let rtype = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let start = pos + 10;
+        let end = start + len;
+        let rdata = data.get(start..end)?;
+        let record = match rtype {
+            TYPE_PTR => {
+                let mut p = start;
+                RecordData::Ptr(read_name(data, &mut p)?)
+            }
+            TYPE_SRV if len >= 6 => RecordData::Srv(u16::from_be_bytes([rdata[4], rdata[5]])),
+            TYPE_TXT => {
+                let mut list = Vec::new();
+                let mut i = 0;
+                while i < rdata.len() {
+                    let n = rdata[i] as usize;
+                    let item = rdata.get(i + 1..i + 1 + n)?;
+                    list.push(String::from_utf8_lossy(item).to_string());
+                    i += 1 + n;
+                }
+                RecordData::Txt(list)
+            }
+            _ => RecordData::Other,
+        };
+        records.push(Record { name, data: record });
+        pos = end;
+    }
+    Some(Message {
+        response,
+        questions,
+        records,
+    })
+}
+
+/// 读取名称，支持压缩指针
+fn read_name(data: &[u8], pos: &mut usize) -> Option<String> {
+    let mut name = String::new();
+    let mut p = *pos;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *data.get(p)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let target = ((len & 0x3f) << 8) | *data.get(p + 1)? as usize;
+            if !jumped {
+                *pos = p + 2;
+                jumped = true;
+            }
+            p = target;
+            continue;
+        }
+        if len == 0 {
+            if !jumped {
+                *pos = p + 1;
+            }
+            return Some(name);
+        }
+        let label = data.get(p + 1..p + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label));
+        p += 1 + len;
+    }
+    None
+}
+
+/// 本机参与组播的网卡地址，排除虚拟网卡
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn interfaces() -> (Vec<Ipv4Addr>, Vec<u32>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return (v4, v6);
+        }
+        let mut cur = addrs;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+            let flags = ifa.ifa_flags as libc::c_int;
+            if ifa.ifa_addr.is_null()
+                || flags & libc::IFF_UP == 0
+                || flags & libc::IFF_LOOPBACK != 0
+                || flags & libc::IFF_MULTICAST == 0
+            {
+                continue;
+            }
+            match (*ifa.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let sin = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    v4.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+                }
+                libc::AF_INET6 => {
+                    // 链路本地地址的网卡序号
+                    let sin6 = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    if sin6.sin6_scope_id != 0 && !v6.contains(&sin6.sin6_scope_id) {
+                        v6.push(sin6.sin6_scope_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(addrs);
+    }
+    (v4, v6)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn interfaces() -> (Vec<Ipv4Addr>, Vec<u32>) {
+    (crate::nat::local_ipv4().into_iter().collect(), vec![0])
+}
+
+fn bind(domain: Domain, addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain, Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    socket.set_reuse_port(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+    Ok(socket)
+}
+
+/// 绑定的组播socket和所在的网卡
+struct Endpoint {
+    socket: UdpSocket,
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<u32>,
+}
+
+impl Endpoint {
+    fn multicast(&self, data: &[u8], virtual_ip: Ipv4Addr) {
+        let sock = SockRef::from(&self.socket);
+        for ip in self.v4.iter().filter(|ip| **ip != virtual_ip) {
+            if sock.set_multicast_if_v4(ip).is_ok() {
+                let _ = self.socket.send_to(data, (GROUP_V4, PORT));
+            }
+        }
+        for index in &self.v6 {
+            if sock.set_multicast_if_v6(*index).is_ok() {
+                let _ = self.socket.send_to(data, (GROUP_V6, PORT));
+            }
+        }
+    }
+}
+
+/// 启动局域网发现
+pub fn start(
+    stop_manager: StopManager,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+    token: &str,
+    password: Option<&str>,
+    name: &str,
+    switch: &Switch,
+) -> io::Result<()> {
+    let (v4, v6) = interfaces();
+    let mut endpoints = Vec::new();
+    let socket = bind(
+        Domain::IPV4,
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)),
+    )?;
+    for ip in &v4 {
+        if let Err(e) = socket.join_multicast_v4(&GROUP_V4, ip) {
+            log::warn!("局域网发现加入组播失败 {} {:?}", ip, e);
+        }
+    }
+    endpoints.push(Endpoint {
+        socket,
+        v4,
+        v6: Vec::new(),
+    });
+    match bind(
+        Domain::IPV6,
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, PORT)),
+    ) {
+        Ok(socket) => {
+            let v6: Vec<u32> = v6
+                .into_iter()
+                .filter(|index| socket.join_multicast_v6(&GROUP_V6, *index).is_ok())
+                .collect();
+            if !v6.is_empty() {
+                endpoints.push(Endpoint {
+                    socket,
+                    v4: Vec::new(),
+                    v6,
+                });
+            }
+        }
+        Err(e) => log::info!("局域网发现不使用ipv6 {:?}", e),
+    }
+    let mdns = Mdns::new(token, password, name);
+    let worker = stop_manager.add_worker("mdns".into())?;
+    let switch_ = switch.clone();
+    switch.spawn("mdns", move || {
+        let mut last: Option<Instant> = None;
+        let mut buf = [0u8; 1500];
+        while !stop_manager.is_stop() && !switch_.is_off() {
+            let device = current_device.load();
+            let local = Local {
+                virtual_ip: device.virtual_ip,
+                port: context
+                    .main_local_udp_port()
+                    .ok()
+                    .and_then(|v| v.first().copied())
+                    .unwrap_or(0),
+            };
+            if last.map_or(true, |v| v.elapsed() >= ANNOUNCE_INTERVAL) {
+                last = Some(Instant::now());
+                for endpoint in &endpoints {
+                    if !local.virtual_ip.is_unspecified() {
+                        endpoint.multicast(&mdns.announce(&local), local.virtual_ip);
+                    }
+                    endpoint.multicast(&mdns.browse(), local.virtual_ip);
+                }
+            }
+            for endpoint in &endpoints {
+                let (len, src) = match endpoint.socket.recv_from(&mut buf) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let (out, verified) = mdns.handle(&buf[..len], src, &local);
+                for outgoing in out {
+                    match outgoing {
+                        Outgoing::Multicast(data) => endpoint.multicast(&data, local.virtual_ip),
+                        Outgoing::Unicast(data, addr) => {
+                            let _ = endpoint.socket.send_to(&data, addr);
+                        }
+                    }
+                }
+                if let Some((virtual_ip, addr)) = verified {
+                    lan_punch(&context, &client_cipher, &local, virtual_ip, addr);
+                }
+            }
+        }
+        drop(worker);
+    })?;
+    Ok(())
+}
+
+/// 验证通过的局域网地址加入候选，并立即向这个地址打洞
+fn lan_punch(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    local: &Local,
+    virtual_ip: Ipv4Addr,
+    addr: SocketAddr,
+) {
+    log::info!("局域网发现 {} {}", virtual_ip, addr);
+    context.lan_peers.insert(virtual_ip, addr);
+    if local.virtual_ip.is_unspecified()
+        || context.block_list.contains(&virtual_ip)
+        || context.use_channel_type().is_only_relay()
+        || context.route_table.no_need_punch(&virtual_ip)
+    {
+        return;
+    }
+    let rs = punch_request_packet(client_cipher, local.virtual_ip, virtual_ip, 0)
+        .and_then(|packet| context.send_main_udp(0, packet.buffer(), addr));
+    if let Err(e) = rs {
+        log::debug!("局域网打洞失败 {} {} {:?}", virtual_ip, addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+    use std::time::Duration;
+
+    use super::{parse, Local, Mdns, Outgoing, RecordData};
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    struct Node {
+        mdns: Mdns,
+        local: Local,
+        socket: UdpSocket,
+        found: Vec<(Ipv4Addr, SocketAddr)>,
+    }
+
+    impl Node {
+        fn new(token: &str, virtual_ip: Ipv4Addr) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            Self {
+                mdns: Mdns::new(token, Some("password"), "node"),
+                local: Local {
+                    virtual_ip,
+                    port: 29870 + virtual_ip.octets()[3] as u16,
+                },
+                socket,
+                found: Vec::new(),
+            }
+        }
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+        /// 组播改成发给另一个节点
+        fn poll(&mut self, group: SocketAddr) -> bool {
+            let mut buf = [0u8; 1500];
+            let (len, src) = match self.socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(_) => return false,
+            };
+            let (out, verified) = self.mdns.handle(&buf[..len], src, &self.local);
+            for outgoing in out {
+                match outgoing {
+                    Outgoing::Multicast(data) => self.socket.send_to(&data, group).unwrap(),
+                    Outgoing::Unicast(data, addr) => self.socket.send_to(&data, addr).unwrap(),
+                };
+            }
+            self.found.extend(verified);
+            true
+        }
+    }
+
+    fn run(a: &mut Node, b: &mut Node) {
+        let (addr_a, addr_b) = (a.addr(), b.addr());
+        a.socket.send_to(&a.mdns.browse(), addr_b).unwrap();
+        b.socket.send_to(&b.mdns.browse(), addr_a).unwrap();
+        loop {
+            let x = a.poll(addr_b);
+            let y = b.poll(addr_a);
+            if !x && !y {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let mut a = Node::new("token", A);
+        let mut b = Node::new("token", B);
+        run(&mut a, &mut b);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, b.local.port));
+        assert_eq!(a.found, vec![(B, addr)]);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, a.local.port));
+        assert_eq!(b.found, vec![(A, addr)]);
+        // 已验证的地址不再重复挑战
+        run(&mut a, &mut b);
+        assert_eq!(a.found.len(), 1);
+    }
+
+    #[test]
+    fn test_other_token() {
+        let mut a = Node::new("token", A);
+        let mut b = Node::new("other", B);
+        run(&mut a, &mut b);
+        assert!(a.found.is_empty());
+        assert!(b.found.is_empty());
+    }
+
+    #[test]
+    fn test_forged_proof() {
+        let a = Mdns::new("token", None, "a");
+        let b = Mdns::new("token", None, "b");
+        let local_a = Local {
+            virtual_ip: A,
+            port: 1000,
+        };
+        let local_b = Local {
+            virtual_ip: B,
+            port: 2000,
+        };
+        let src = SocketAddr::from(([192, 168, 1, 3], 5353));
+        let (out, _) = a.handle(&b.announce(&local_b), src, &local_a);
+        let challenge = match &out[0] {
+            Outgoing::Unicast(data, _) => data.clone(),
+            v => panic!("{:?}", v),
+        };
+        // 不知道密钥的设备只能乱填
+        let (out, _) = Mdns::new("guess", None, "x").handle(&challenge, src, &local_b);
+        let (out, _) = match &out[0] {
+            Outgoing::Unicast(data, _) => a.handle(data, src, &local_a),
+            v => panic!("{:?}", v),
+        };
+        assert!(out.is_empty());
+        assert!(a.verified.lock().is_empty());
+    }
+
+    #[test]
+    fn test_link_local_ipv6() {
+        let a = Mdns::new("token", None, "a");
+        let b = Mdns::new("token", None, "b");
+        let local_a = Local {
+            virtual_ip: A,
+            port: 1000,
+        };
+        let local_b = Local {
+            virtual_ip: B,
+            port: 2000,
+        };
+        let src = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 3),
+            5353,
+            0,
+            7,
+        ));
+        let (out, _) = a.handle(&b.announce(&local_b), src, &local_a);
+        let challenge = match &out[0] {
+            Outgoing::Unicast(data, to) => {
+                assert_eq!(*to, src);
+                data.clone()
+            }
+            v => panic!("{:?}", v),
+        };
+        let (out, _) = b.handle(&challenge, src, &local_b);
+        let proof = match &out[0] {
+            Outgoing::Unicast(data, _) => data.clone(),
+            v => panic!("{:?}", v),
+        };
+        let (_, verified) = a.handle(&proof, src, &local_a);
+        let (ip, addr) = verified.unwrap();
+        assert_eq!(ip, B);
+        match addr {
+            SocketAddr::V6(addr) => {
+                assert_eq!(addr.scope_id(), 7);
+                assert_eq!(addr.port(), 2000);
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_no_token_in_announcement() {
+        let mdns = Mdns::new("secret-token", Some("secret-password"), "laptop");
+        let data = mdns.announce(&Local {
+            virtual_ip: A,
+            port: 1000,
+        });
+        for secret in [&b"secret-token"[..], b"secret-password"] {
+            assert!(!data.windows(secret.len()).any(|w| w == secret));
+        }
+        let msg = parse(&data).unwrap();
+        assert!(msg.response);
+        assert_eq!(msg.records.len(), 3);
+        assert!(matches!(msg.records[1].data, RecordData::Srv(1000)));
+    }
+
+    #[test]
+    fn test_compressed_name() {
+        // 第二个问题的名称指向第一个问题
+        let mut data = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        super::write_name(&mut data, super::SERVICE);
+        data.extend_from_slice(&[0, 12, 0, 1]);
+        data.extend_from_slice(&[3, b'f', b'o', b'o', 0xc0, 12, 0, 16, 0, 1]);
+        let msg = parse(&data).unwrap();
+        assert_eq!(msg.questions[1].0, format!("foo.{}", super::SERVICE));
+        // 指针循环
+        let mut data = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(parse(&data).is_none());
+    }
+}
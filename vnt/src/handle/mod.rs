@@ -1,11 +1,33 @@
 use crossbeam_utils::atomic::AtomicCell;
 use std::net::{Ipv4Addr, SocketAddr};
 
+use crate::util::subnet::Subnet;
+
+pub mod bandwidth;
 pub mod callback;
+pub mod checksum;
+pub mod critical_notice;
+pub mod crypto_pool;
+pub mod directory;
+pub mod dns;
+pub mod flow_table;
 pub mod handshaker;
+#[cfg(feature = "loadtest")]
+pub mod loadtest;
 pub mod maintain;
+pub mod mdns;
+pub mod negative_path;
+pub mod notice;
+pub mod packet_hook;
+pub mod ping;
 pub mod recv_data;
 pub mod registrar;
+#[cfg(feature = "replay")]
+pub mod replay;
+pub mod resume;
+pub mod reverse;
+pub mod selftest;
+pub mod server_action;
 pub mod tun_tap;
 
 const SELF_IP: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 2);
@@ -20,12 +42,6 @@ pub fn now_time() -> u64 {
     }
 }
 
-/// 是否在一个网段
-fn check_dest(dest: Ipv4Addr, virtual_netmask: Ipv4Addr, virtual_network: Ipv4Addr) -> bool {
-    u32::from_be_bytes(dest.octets()) & u32::from_be_bytes(virtual_netmask.octets())
-        == u32::from_be_bytes(virtual_network.octets())
-}
-
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct PeerDeviceInfo {
     pub virtual_ip: Ipv4Addr,
@@ -33,6 +49,12 @@ pub struct PeerDeviceInfo {
     pub status: PeerDeviceStatus,
     pub client_secret: bool,
     pub client_secret_hash: Vec<u8>,
+    /// 观察者，不是流量的目标
+    pub observer: bool,
+    /// 设备指纹，对端没有公开时为空
+    pub fingerprint: String,
+    /// 服务端给出的离对端最近的服务器
+    pub closest_server: Option<SocketAddr>,
 }
 
 impl PeerDeviceInfo {
@@ -42,6 +64,9 @@ impl PeerDeviceInfo {
         status: u8,
         client_secret: bool,
         client_secret_hash: Vec<u8>,
+        observer: bool,
+        fingerprint: String,
+        ip_fallback: bool,
     ) -> Self {
         Self {
             virtual_ip,
@@ -49,6 +74,9 @@ impl PeerDeviceInfo {
             status: PeerDeviceStatus::from(status),
             client_secret,
             client_secret_hash,
+            observer,
+            fingerprint,
+            closest_server: None,
         }
     }
 }
@@ -63,6 +91,14 @@ pub struct BaseConfigInfo {
     pub device_id: String,
     pub server_addr: String,
     pub name_servers: Vec<String>,
+    // 使用预先创建的网卡，不安装路由
+    pub existing_tun: bool,
+    // 观察者模式，没有网卡
+    pub observer: bool,
+    // 注册时公开的设备指纹，关闭时为空
+    pub fingerprint: String,
+    // 指定的ip被占用或者不在网段内时改为由服务端分配
+    pub ip_fallback: bool,
 }
 
 impl BaseConfigInfo {
@@ -75,6 +111,9 @@ impl BaseConfigInfo {
         device_id: String,
         server_addr: String,
         name_servers: Vec<String>,
+        existing_tun: bool,
+        observer: bool,
+        fingerprint: String,
     ) -> Self {
         Self {
             name,
@@ -85,6 +124,10 @@ impl BaseConfigInfo {
             device_id,
             server_addr,
             name_servers,
+            existing_tun,
+            observer,
+            fingerprint,
+            ip_fallback,
         }
     }
 }
@@ -215,6 +258,10 @@ impl CurrentDeviceInfo {
     pub fn is_gateway(&self, ip: &Ipv4Addr) -> bool {
         &self.virtual_gateway == ip || ip == &GATEWAY_IP
     }
+    /// 本机所在的虚拟网段
+    pub fn subnet(&self) -> Subnet {
+        Subnet::new(self.virtual_ip, self.virtual_netmask)
+    }
 }
 pub fn change_status(
     current_device: &AtomicCell<CurrentDeviceInfo>,
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+/// 连续打洞失败多少次后不再自动打洞
+pub const MAX_PUNCH_FAILURES: usize = 8;
+
+/// 判定无法直连的依据
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NoDirectEvidence {
+    /// 对端声明仅使用中继
+    RelayOnly,
+    /// 连续打洞失败的次数
+    PunchFailures(usize),
+}
+
+impl Display for NoDirectEvidence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoDirectEvidence::RelayOnly => f.write_str("relay-only"),
+            NoDirectEvidence::PunchFailures(count) => write!(f, "{} punch failures", count),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerRecord {
+    relay_only: bool,
+    failures: usize,
+}
+
+/// 记录无法直连的对端，避免一直对其打洞
+#[derive(Clone, Default)]
+pub struct NegativePathCache {
+    inner: Arc<Mutex<HashMap<Ipv4Addr, PeerRecord>>>,
+}
+
+impl NegativePathCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 对端声明的能力，声明可以直连时立即清除记录
+    pub fn set_relay_only(&self, ip: Ipv4Addr, relay_only: bool) {
+        let mut guard = self.inner.lock();
+        if relay_only {
+            guard.entry(ip).or_default().relay_only = true;
+        } else if let Some(record) = guard.get(&ip) {
+            if record.relay_only {
+                log::info!("对端{}不再仅使用中继", ip);
+                guard.remove(&ip);
+            }
+        }
+    }
+    /// 记录一次失败的打洞
+    pub fn punch_failed(&self, ip: Ipv4Addr) {
+        let mut guard = self.inner.lock();
+        let record = guard.entry(ip).or_default();
+        record.failures += 1;
+        if record.failures == MAX_PUNCH_FAILURES {
+            log::info!("对端{}连续打洞失败{}次，不再自动打洞", ip, record.failures);
+        }
+    }
+    /// 打洞成功、收到对端的打洞或者手动打洞时清除
+    pub fn clear(&self, ip: &Ipv4Addr) {
+        if self.inner.lock().remove(ip).is_some() {
+            log::info!("清除对端{}的直连失败记录", ip);
+        }
+    }
+    /// 无法直连时返回依据
+    pub fn check(&self, ip: &Ipv4Addr) -> Option<NoDirectEvidence> {
+        let guard = self.inner.lock();
+        let record = guard.get(ip)?;
+        if record.relay_only {
+            Some(NoDirectEvidence::RelayOnly)
+        } else if record.failures >= MAX_PUNCH_FAILURES {
+            Some(NoDirectEvidence::PunchFailures(record.failures))
+        } else {
+            None
+        }
+    }
+    pub fn list(&self) -> Vec<(Ipv4Addr, NoDirectEvidence)> {
+        let ips: Vec<Ipv4Addr> = self.inner.lock().keys().copied().collect();
+        ips.into_iter()
+            .filter_map(|ip| self.check(&ip).map(|v| (ip, v)))
+            .collect()
+    }
+}
+
+#[test]
+fn test_negative_path() {
+    let cache = NegativePathCache::new();
+    let ip = Ipv4Addr::new(10, 26, 0, 3);
+    for _ in 0..MAX_PUNCH_FAILURES - 1 {
+        cache.punch_failed(ip);
+    }
+    assert_eq!(cache.check(&ip), None);
+    cache.punch_failed(ip);
+    assert_eq!(
+        cache.check(&ip),
+        Some(NoDirectEvidence::PunchFailures(MAX_PUNCH_FAILURES))
+    );
+    cache.clear(&ip);
+    assert_eq!(cache.check(&ip), None);
+
+    cache.set_relay_only(ip, true);
+    assert_eq!(cache.check(&ip), Some(NoDirectEvidence::RelayOnly));
+    // 对端能力变化后立即清除
+    cache.set_relay_only(ip, false);
+    assert_eq!(cache.check(&ip), None);
+}
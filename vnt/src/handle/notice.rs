@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::handle::callback::NoticeInfo;
+use crate::proto::message::ServerNotice;
+use crate::util::sanitize::{self, NOTICE_MAX_LEN};
+
+/// 维护开始前多久进入维护模式(秒)
+const MAINTENANCE_BEFORE: i64 = 60;
+/// 维护开始后多久退出维护模式(秒)
+const MAINTENANCE_AFTER: i64 = 600;
+/// 没有维护时间的公告，多久没再收到就丢弃(秒)
+const NOTICE_TTL: i64 = 24 * 3600;
+
+/// 记录服务端下发的公告
+#[derive(Clone, Default)]
+pub struct NoticeHolder {
+    /// 公告和最近一次收到的时间(秒)
+    inner: Arc<Mutex<Option<(NoticeInfo, i64)>>>,
+}
+
+impl NoticeHolder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 更新公告，内容有变化时返回清洗后的公告，空公告表示服务端撤回了公告
+    pub fn update(&self, notice: &ServerNotice) -> Option<NoticeInfo> {
+        self.update_at(notice, now())
+    }
+    fn update_at(&self, notice: &ServerNotice, now: i64) -> Option<NoticeInfo> {
+        // 公告是单行的，不能用换行伪造其他输出
+        let message = sanitize::text(&notice.message, NOTICE_MAX_LEN, false);
+        let mut guard = self.inner.lock();
+        if message.is_empty() && notice.maintenance_at <= 0 {
+            guard.take();
+            return None;
+        }
+        let info = NoticeInfo::new(message, notice.maintenance_at);
+        let changed = match guard.as_ref() {
+            Some((old, _)) => old != &info,
+            None => true,
+        };
+        guard.replace((info.clone(), now));
+        if changed {
+            Some(info)
+        } else {
+            None
+        }
+    }
+    /// 重新连接服务端时清除，之后以新的注册响应为准
+    pub fn clear(&self) {
+        self.inner.lock().take();
+    }
+    pub fn get(&self) -> Option<NoticeInfo> {
+        self.get_at(now())
+    }
+    fn get_at(&self, now: i64) -> Option<NoticeInfo> {
+        let mut guard = self.inner.lock();
+        let expired = match guard.as_ref() {
+            Some((info, received)) => expired(info, *received, now),
+            None => return None,
+        };
+        if expired {
+            guard.take();
+            return None;
+        }
+        guard.as_ref().map(|(info, _)| info.clone())
+    }
+    /// 是否处于服务端维护时间段，维护期间暂停非必要的流量
+    pub fn in_maintenance(&self) -> bool {
+        let now = now();
+        let maintenance_at = match self.get_at(now) {
+            Some(info) if info.maintenance_at > 0 => info.maintenance_at,
+            _ => return false,
+        };
+        // 时间来自服务端，不能溢出
+        now >= maintenance_at.saturating_sub(MAINTENANCE_BEFORE)
+            && now <= maintenance_at.saturating_add(MAINTENANCE_AFTER)
+    }
+}
+
+fn now() -> i64 {
+    (crate::handle::now_time() / 1000) as i64
+}
+
+/// 维护结束后，或者长时间没再收到时过期
+fn expired(info: &NoticeInfo, received: i64, now: i64) -> bool {
+    if info.maintenance_at > 0 {
+        now > info.maintenance_at.saturating_add(MAINTENANCE_AFTER)
+    } else {
+        now > received.saturating_add(NOTICE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handle::notice::{NoticeHolder, MAINTENANCE_AFTER, NOTICE_TTL};
+    use crate::proto::message::ServerNotice;
+
+    fn notice(message: &str, maintenance_at: i64) -> ServerNotice {
+        let mut notice = ServerNotice::new();
+        notice.message = message.to_string();
+        notice.maintenance_at = maintenance_at;
+        notice
+    }
+
+    #[test]
+    fn test_notice_sanitize() {
+        let holder = NoticeHolder::new();
+        let info = holder
+            .update_at(&notice("restart\n[vnt] fake\x1b[2J\r", 0), 100)
+            .unwrap();
+        assert_eq!(info.message, "restart[vnt] fake");
+        assert!(!info.message.chars().any(|c| c.is_control()));
+        // 内容没变不重复提示
+        assert!(holder
+            .update_at(&notice("restart\n[vnt] fake", 0), 200)
+            .is_none());
+    }
+
+    #[test]
+    fn test_notice_expire() {
+        let holder = NoticeHolder::new();
+        holder.update_at(&notice("motd", 0), 100);
+        assert!(holder.get_at(100 + NOTICE_TTL).is_some());
+        assert!(holder.get_at(101 + NOTICE_TTL).is_none());
+        // 过期后再收到相同的公告要重新提示
+        assert!(holder.update_at(&notice("motd", 0), 200).is_some());
+
+        holder.update_at(&notice("restart", 1000), 200);
+        assert!(holder.get_at(1000 + MAINTENANCE_AFTER).is_some());
+        assert!(holder.get_at(1001 + MAINTENANCE_AFTER).is_none());
+
+        holder.update_at(&notice("motd", 0), 300);
+        holder.update_at(&notice("", 0), 301);
+        assert!(holder.get_at(302).is_none());
+
+        holder.update_at(&notice("motd", 0), 300);
+        holder.clear();
+        assert!(holder.get_at(301).is_none());
+        assert!(!holder.in_maintenance());
+    }
+}
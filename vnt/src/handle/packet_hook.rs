@@ -0,0 +1,366 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use packet::icmp::icmp::IcmpPacket;
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::tcp::tcp::TcpPacket;
+use packet::udp::udp::UdpPacket;
+use parking_lot::Mutex;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMP: u8 = 1;
+/// 钩子panic的日志间隔
+const PANIC_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 钩子的处理结果
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// 不做修改，继续执行下一个钩子
+    Accept,
+    /// 丢弃，后面的钩子不再执行
+    Drop,
+    /// 已修改，继续执行下一个钩子，全部执行完后自动更新校验和
+    Modify,
+}
+
+/// 钩子看到的ipv4包，可以原地修改，但不能改变长度
+pub struct PacketView<'a> {
+    buf: &'a mut [u8],
+    head_len: usize,
+}
+
+impl<'a> PacketView<'a> {
+    pub fn new(buf: &'a mut [u8]) -> io::Result<Self> {
+        if buf.len() < 20 || buf[0] >> 4 != 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not ipv4"));
+        }
+        let head_len = ((buf[0] & 0x0F) as usize) * 4;
+        if head_len < 20 || head_len > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ipv4 head len"));
+        }
+        Ok(Self { buf, head_len })
+    }
+    /// 整个ip包
+    pub fn buffer(&self) -> &[u8] {
+        self.buf
+    }
+    /// 整个ip包，修改后需要返回`Verdict::Modify`
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+    /// ip头中的协议号
+    pub fn protocol(&self) -> u8 {
+        self.buf[9]
+    }
+    pub fn source(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buf[12], self.buf[13], self.buf[14], self.buf[15])
+    }
+    pub fn destination(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buf[16], self.buf[17], self.buf[18], self.buf[19])
+    }
+    pub fn set_source(&mut self, ip: Ipv4Addr) {
+        self.buf[12..16].copy_from_slice(&ip.octets());
+    }
+    pub fn set_destination(&mut self, ip: Ipv4Addr) {
+        self.buf[16..20].copy_from_slice(&ip.octets());
+    }
+    /// ip负载，分片的后续部分没有传输层头
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.head_len..]
+    }
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.head_len..]
+    }
+    fn is_first_fragment(&self) -> bool {
+        u16::from_be_bytes([self.buf[6], self.buf[7]]) & 0x1FFF == 0
+    }
+    /// tcp/udp端口所在的位置
+    fn ports(&self) -> Option<Range<usize>> {
+        let min_len = match self.protocol() {
+            TCP => 20,
+            UDP => 8,
+            _ => return None,
+        };
+        if !self.is_first_fragment() || self.payload().len() < min_len {
+            return None;
+        }
+        Some(self.head_len..self.head_len + 4)
+    }
+    pub fn source_port(&self) -> Option<u16> {
+        let range = self.ports()?;
+        Some(u16::from_be_bytes([
+            self.buf[range.start],
+            self.buf[range.start + 1],
+        ]))
+    }
+    pub fn destination_port(&self) -> Option<u16> {
+        let range = self.ports()?;
+        Some(u16::from_be_bytes([
+            self.buf[range.start + 2],
+            self.buf[range.start + 3],
+        ]))
+    }
+    /// 不是tcp/udp时返回false
+    pub fn set_source_port(&mut self, port: u16) -> bool {
+        match self.ports() {
+            Some(range) => {
+                self.buf[range.start..range.start + 2].copy_from_slice(&port.to_be_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+    /// 不是tcp/udp时返回false
+    pub fn set_destination_port(&mut self, port: u16) -> bool {
+        match self.ports() {
+            Some(range) => {
+                self.buf[range.start + 2..range.end].copy_from_slice(&port.to_be_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+    /// 重新计算ip头和tcp/udp/icmp的校验和，分片的包只更新ip头
+    pub fn fix_checksums(&mut self) {
+        let src = self.source();
+        let dest = self.destination();
+        let protocol = self.protocol();
+        let first_fragment = self.is_first_fragment();
+        let more_fragments = self.buf[6] & 0x20 != 0;
+        let head_len = self.head_len;
+        if first_fragment && !more_fragments {
+            let payload = &mut self.buf[head_len..];
+            match protocol {
+                TCP if payload.len() >= 20 => {
+                    TcpPacket::unchecked(src, dest, payload).update_checksum();
+                }
+                UDP if payload.len() >= 8 => {
+                    // 校验和为0表示没有使用校验和
+                    if payload[6] != 0 || payload[7] != 0 {
+                        UdpPacket::unchecked(src, dest, payload).update_checksum();
+                    }
+                }
+                ICMP => {
+                    if let Ok(mut icmp) = IcmpPacket::new(payload) {
+                        icmp.update_checksum();
+                    }
+                }
+                _ => {}
+            }
+        }
+        IpV4Packet::unchecked(&mut self.buf[..]).update_checksum();
+    }
+}
+
+pub type HookFn = Arc<dyn Fn(&mut PacketView) -> Verdict + Send + Sync>;
+
+/// 用户注册的数据包钩子
+///
+/// 出站钩子在读取虚拟网卡之后、路由和加密之前执行，入站钩子在解密之后、写入虚拟网卡之前执行。
+/// 同一方向的钩子按注册顺序执行，任一钩子返回Drop时丢弃并停止；
+/// 钩子panic时按Drop处理(编译配置为`panic = 'abort'`时无法捕获)。没有注册钩子时只有一次判断
+#[derive(Clone, Default)]
+pub struct PacketHooks {
+    outbound: Option<Arc<[HookFn]>>,
+    inbound: Option<Arc<[HookFn]>>,
+    last_panic_log: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Debug for PacketHooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketHooks")
+            .field("outbound", &self.outbound.as_ref().map_or(0, |v| v.len()))
+            .field("inbound", &self.inbound.as_ref().map_or(0, |v| v.len()))
+            .finish()
+    }
+}
+
+impl PacketHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 注册出站钩子，处理发往对端的包
+    pub fn on_outbound<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut PacketView) -> Verdict + Send + Sync + 'static,
+    {
+        Self::push(&mut self.outbound, Arc::new(hook));
+    }
+    /// 注册入站钩子，处理来自对端的包
+    pub fn on_inbound<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut PacketView) -> Verdict + Send + Sync + 'static,
+    {
+        Self::push(&mut self.inbound, Arc::new(hook));
+    }
+    fn push(hooks: &mut Option<Arc<[HookFn]>>, hook: HookFn) {
+        let mut list: Vec<HookFn> = hooks.as_ref().map_or(Vec::new(), |v| v.to_vec());
+        list.push(hook);
+        *hooks = Some(list.into());
+    }
+    /// 执行出站钩子，返回false表示丢弃
+    #[inline]
+    pub fn run_outbound(&self, ipv4: &mut [u8]) -> bool {
+        match &self.outbound {
+            None => true,
+            Some(hooks) => self.run(hooks, ipv4).is_some(),
+        }
+    }
+    /// 执行入站钩子，返回false表示丢弃
+    #[inline]
+    pub fn run_inbound(&self, ipv4: &mut [u8]) -> bool {
+        self.inbound(ipv4).is_some()
+    }
+    /// 执行入站钩子，返回None表示丢弃，Some(true)表示有钩子修改了包
+    #[inline]
+    pub(crate) fn inbound(&self, ipv4: &mut [u8]) -> Option<bool> {
+        match &self.inbound {
+            None => Some(false),
+            Some(hooks) => self.run(hooks, ipv4),
+        }
+    }
+    fn run(&self, hooks: &[HookFn], ipv4: &mut [u8]) -> Option<bool> {
+        let mut view = match PacketView::new(ipv4) {
+            Ok(view) => view,
+            // 不是ipv4的包不经过钩子
+            Err(_) => return Some(false),
+        };
+        let mut modified = false;
+        for hook in hooks {
+            let verdict = match catch_unwind(AssertUnwindSafe(|| hook(&mut view))) {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    self.log_panic(e);
+                    Verdict::Drop
+                }
+            };
+            match verdict {
+                Verdict::Accept => {}
+                Verdict::Drop => return None,
+                Verdict::Modify => modified = true,
+            }
+        }
+        if modified {
+            view.fix_checksums();
+        }
+        Some(modified)
+    }
+    fn log_panic(&self, e: Box<dyn std::any::Any + Send>) {
+        let mut last = self.last_panic_log.lock();
+        let now = Instant::now();
+        if last.map_or(true, |v| {
+            now.saturating_duration_since(v) >= PANIC_LOG_INTERVAL
+        }) {
+            *last = Some(now);
+            let msg = e
+                .downcast_ref::<&str>()
+                .map(|v| v.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            log::error!("数据包钩子panic,已丢弃该包:{}", msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use packet::ip::ipv4::packet::IpV4Packet;
+    use packet::tcp::tcp::TcpPacket;
+    use packet::udp::udp::UdpPacket;
+
+    use crate::handle::packet_hook::{PacketHooks, Verdict};
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const DEST: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+
+    fn udp_packet(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 28 + payload.len()];
+        let total_len = buf.len() as u16;
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+        buf[8] = 64;
+        buf[9] = 17;
+        buf[12..16].copy_from_slice(&SRC.octets());
+        buf[16..20].copy_from_slice(&DEST.octets());
+        buf[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        buf[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        buf[24..26].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        buf[28..].copy_from_slice(payload);
+        UdpPacket::unchecked(SRC, DEST, &mut buf[20..]).update_checksum();
+        IpV4Packet::unchecked(&mut buf[..]).update_checksum();
+        buf
+    }
+
+    #[test]
+    fn test_modify_checksum() {
+        let mut hooks = PacketHooks::new();
+        hooks.on_outbound(|view| {
+            if view.destination_port() == Some(80) {
+                view.set_destination_port(8080);
+                view.set_destination(Ipv4Addr::new(10, 26, 0, 9));
+                Verdict::Modify
+            } else {
+                Verdict::Accept
+            }
+        });
+        let mut buf = udp_packet(80, b"hello");
+        assert!(hooks.run_outbound(&mut buf));
+        let ipv4 = IpV4Packet::new(&buf[..]).unwrap();
+        assert!(ipv4.is_valid());
+        assert_eq!(ipv4.destination_ip(), Ipv4Addr::new(10, 26, 0, 9));
+        let udp = UdpPacket::new(SRC, ipv4.destination_ip(), ipv4.payload()).unwrap();
+        assert!(udp.is_valid());
+        assert_eq!(udp.destination_port(), 8080);
+        assert_eq!(udp.payload(), b"hello");
+
+        // tcp同样更新校验和
+        let mut tcp = vec![0u8; 40];
+        tcp[0] = 0x45;
+        tcp[2..4].copy_from_slice(&40u16.to_be_bytes());
+        tcp[9] = 6;
+        tcp[12..16].copy_from_slice(&SRC.octets());
+        tcp[16..20].copy_from_slice(&DEST.octets());
+        tcp[22..24].copy_from_slice(&80u16.to_be_bytes());
+        tcp[32] = 0x50;
+        assert!(hooks.run_outbound(&mut tcp));
+        let ipv4 = IpV4Packet::new(&tcp[..]).unwrap();
+        assert!(ipv4.is_valid());
+        let tcp = TcpPacket::new(SRC, ipv4.destination_ip(), ipv4.payload()).unwrap();
+        assert!(tcp.is_valid());
+        assert_eq!(tcp.destination_port(), 8080);
+    }
+
+    #[test]
+    fn test_order_drop_panic() {
+        let mut hooks = PacketHooks::new();
+        // 按注册顺序执行，第一个修改的结果对第二个可见
+        hooks.on_inbound(|view| {
+            view.set_destination_port(53);
+            Verdict::Modify
+        });
+        hooks.on_inbound(|view| {
+            if view.destination_port() == Some(53) {
+                Verdict::Drop
+            } else {
+                Verdict::Accept
+            }
+        });
+        hooks.on_inbound(|_| panic!("never reached"));
+        assert!(!hooks.run_inbound(&mut udp_packet(80, b"")));
+        // 没有出站钩子
+        assert!(hooks.run_outbound(&mut udp_packet(80, b"")));
+
+        let mut hooks = PacketHooks::new();
+        hooks.on_inbound(|_| panic!("bad hook"));
+        assert!(!hooks.run_inbound(&mut udp_packet(80, b"")));
+        assert!(!hooks.run_inbound(&mut udp_packet(80, b"")));
+    }
+}
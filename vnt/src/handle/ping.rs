@@ -0,0 +1,139 @@
+//! 虚拟网络协议上的ping
+//!
+//! 使用客户端之间心跳的ping/pong，在协议版本之后带上序号，不依赖对端系统的icmp。
+//! 有直连时走直连，否则经过服务器中继，回复按到达的路径标记
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::ping::PingSummary;
+use crate::channel::RouteKey;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::compat::WireVersion;
+use crate::protocol::control_packet::{PingPacket, PING_ECHO_LEN, PING_WIRE_VERSION_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 每个包等待回复的时间
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+/// 发送间隔，回复较快时等到间隔结束再发下一个
+const INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_COUNT: u32 = 100;
+
+/// 一个包的结果
+#[derive(Copy, Clone, Debug)]
+pub struct EchoResult {
+    pub seq: u32,
+    /// None表示超时
+    pub rtt: Option<Duration>,
+    pub p2p: bool,
+}
+
+/// 一次ping的结果
+#[derive(Clone, Debug)]
+pub struct PingReport {
+    pub peer: Ipv4Addr,
+    /// 发送使用的路径
+    pub path: String,
+    pub results: Vec<EchoResult>,
+    pub summary: PingSummary,
+}
+
+fn other(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+pub(crate) struct EchoTest<'a> {
+    pub context: &'a ChannelContext,
+    pub current_device: CurrentDeviceInfo,
+    pub client_cipher: &'a Cipher,
+}
+
+impl EchoTest<'_> {
+    pub fn run(&self, peer: Ipv4Addr, count: u32) -> io::Result<PingReport> {
+        if self.current_device.virtual_ip.is_unspecified() {
+            return Err(other("no virtual ip yet".to_string()));
+        }
+        let mut results = Vec::with_capacity(count as usize);
+        let mut path = String::new();
+        for i in 0..count {
+            let start = Instant::now();
+            // 每个包重新选路，ping期间打洞成功或者直连断开都能反映出来
+            let route = self.context.route_table.route_one(&peer);
+            let route_key = match route {
+                Some(route) if route.is_p2p() => Some(route.route_key()),
+                _ => None,
+            };
+            if route_key.is_none() && !self.current_device.status.online() {
+                return Err(other(
+                    "no p2p route and not connected to the server".to_string(),
+                ));
+            }
+            if i == 0 {
+                path = match route {
+                    Some(route) if route.is_p2p() => {
+                        let transport = if route.is_tcp { "tcp" } else { "udp" };
+                        format!("p2p {} {}", transport, route.addr)
+                    }
+                    _ => "relay via server".to_string(),
+                };
+            }
+            results.push(self.echo(peer, route_key)?);
+            if i + 1 < count {
+                if let Some(rest) = INTERVAL.checked_sub(start.elapsed()) {
+                    std::thread::sleep(rest);
+                }
+            }
+        }
+        let rtts: Vec<Option<Duration>> = results.iter().map(|v| v.rtt).collect();
+        Ok(PingReport {
+            peer,
+            path,
+            summary: PingSummary::new(&rtts),
+            results,
+        })
+    }
+    fn echo(&self, peer: Ipv4Addr, route_key: Option<RouteKey>) -> io::Result<EchoResult> {
+        let (seq, receiver) = self.context.ping.expect();
+        let packet = self.packet(peer, seq)?;
+        let sent_at = Instant::now();
+        let rs = match route_key {
+            Some(route_key) => self.context.send_by_key(packet.buffer(), route_key),
+            None => self.context.send_ipv4_by_id(
+                packet.buffer(),
+                &peer,
+                self.current_device.connect_server,
+                self.current_device.status.online(),
+            ),
+        };
+        if let Err(e) = rs {
+            log::warn!("ping发送失败 {}:{:?}", peer, e);
+        }
+        let reply = receiver.recv_timeout(ECHO_TIMEOUT).ok();
+        self.context.ping.cancel(seq);
+        Ok(EchoResult {
+            seq,
+            rtt: reply.map(|v| v.at.saturating_duration_since(sent_at)),
+            p2p: reply.map_or(route_key.is_some(), |v| v.p2p),
+        })
+    }
+    fn packet(&self, peer: Ipv4Addr, seq: u32) -> io::Result<NetPacket<Vec<u8>>> {
+        let payload_len = PING_WIRE_VERSION_LEN + PING_ECHO_LEN;
+        let mut packet = NetPacket::new_encrypt(vec![0; 12 + payload_len + ENCRYPTION_RESERVED])?;
+        packet.set_default_version();
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::Ping.into());
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_source(self.current_device.virtual_ip);
+        packet.set_destination(peer);
+        let mut ping = PingPacket::new(packet.payload_mut())?;
+        ping.set_time(crate::handle::now_time() as u16);
+        ping.set_wire_version(WireVersion::MAX);
+        ping.set_extension(&seq.to_be_bytes())?;
+        self.client_cipher.encrypt_ipv4(&mut packet)?;
+        Ok(packet)
+    }
+}
@@ -3,32 +3,48 @@ use protobuf::Message;
 use std::collections::HashMap;
 use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use packet::icmp::{icmp, Kind};
 use packet::ip::ipv4;
 use packet::ip::ipv4::packet::IpV4Packet;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::cookie::Verdict;
+use crate::channel::diary::{DiaryEvent, EvictReason};
+use crate::channel::drop_reason::DropReason;
+use crate::channel::peer_feature::Feature;
 use crate::channel::punch::NatInfo;
+use crate::channel::reverse::Intent;
 use crate::channel::{Route, RouteKey};
 use crate::cipher::Cipher;
 use crate::external_route::AllowExternalRoute;
-use crate::handle::maintain::PunchSender;
+use crate::handle::bandwidth::{parse_reply, reply_packet};
+use crate::handle::critical_notice::{CriticalNotice, NoticeKind};
+use crate::handle::flow_table::FlowTable;
+use crate::handle::maintain::{punch_now, record_skew, PunchSender};
+use crate::handle::negative_path::NegativePathCache;
 use crate::handle::recv_data::PacketHandler;
+use crate::handle::reverse::send_reverse;
 use crate::handle::CurrentDeviceInfo;
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::{IpProxyMap, ProxyHandler};
 use crate::nat::NatTest;
 use crate::proto::message::{PunchInfo, PunchNatType};
 use crate::protocol::body::ENCRYPTION_RESERVED;
-use crate::protocol::control_packet::ControlPacket;
+use crate::protocol::codec::Encoding;
+use crate::protocol::compat::WireVersion;
+use crate::protocol::control_packet::{
+    ControlPacket, CookiePacket, FeaturePacket, PingPacket, PongPacket, COOKIE_LEN,
+    PING_WIRE_VERSION_LEN,
+};
 use crate::protocol::{
     control_packet, ip_turn_packet, other_turn_packet, NetPacket, Protocol, MAX_TTL,
 };
 use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
-#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-use tun::device::IFace;
+use crate::util::serial::rtt_ms;
 
 /// 处理来源于客户端的包
 #[derive(Clone)]
@@ -41,6 +57,9 @@ pub struct ClientPacketHandler {
     route: AllowExternalRoute,
     #[cfg(feature = "ip_proxy")]
     ip_proxy_map: Option<IpProxyMap>,
+    negative_path: NegativePathCache,
+    critical_notice: CriticalNotice,
+    flow_table: FlowTable,
 }
 
 impl ClientPacketHandler {
@@ -52,6 +71,9 @@ impl ClientPacketHandler {
         nat_test: NatTest,
         route: AllowExternalRoute,
         #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
+        negative_path: NegativePathCache,
+        critical_notice: CriticalNotice,
+        flow_table: FlowTable,
     ) -> Self {
         Self {
             device,
@@ -62,6 +84,9 @@ impl ClientPacketHandler {
             route,
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
+            negative_path,
+            critical_notice,
+            flow_table,
         }
     }
 }
@@ -74,7 +99,40 @@ impl PacketHandler for ClientPacketHandler {
         context: &ChannelContext,
         current_device: &CurrentDeviceInfo,
     ) -> io::Result<()> {
-        self.client_cipher.decrypt_ipv4(&mut net_packet)?;
+        self.decrypt(&mut net_packet, context)?;
+        self.handle_sequenced(net_packet, route_key, context, current_device, None)
+    }
+}
+
+impl ClientPacketHandler {
+    /// 解密客户端包，失败说明不是对端发来的
+    pub fn decrypt(
+        &self,
+        net_packet: &mut NetPacket<&mut [u8]>,
+        context: &ChannelContext,
+    ) -> io::Result<()> {
+        if !net_packet.is_encrypt() && context.peer_features.accept_plaintext(&net_packet.source())
+        {
+            // 和该对端手动关闭了加密
+            return Ok(());
+        }
+        if let Err(e) = self.client_cipher.decrypt_ipv4(net_packet) {
+            context
+                .drop_stats
+                .add_peer(DropReason::DecryptFailed, net_packet.source());
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// 处理已经解密的包，seq为扩展头中的序号，路径切换期间用于重排
+    pub fn handle_sequenced(
+        &self,
+        mut net_packet: NetPacket<&mut [u8]>,
+        route_key: RouteKey,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        seq: Option<u16>,
+    ) -> io::Result<()> {
         context
             .route_table
             .update_read_time(&net_packet.source(), &route_key);
@@ -85,7 +143,25 @@ impl PacketHandler for ClientPacketHandler {
                 self.control(context, current_device, net_packet, route_key)?;
             }
             Protocol::IpTurn => {
-                self.ip_turn(net_packet, context, current_device, route_key)?;
+                let source = net_packet.source();
+                let mut delivered = false;
+                let rs = self.ip_turn(
+                    net_packet,
+                    context,
+                    current_device,
+                    route_key,
+                    seq,
+                    &mut delivered,
+                );
+                if !delivered && seq.is_some() {
+                    // 没有写入网卡的包也要占用序号，否则后面的包会等待超时
+                    context.reorder.inbound(source, seq, false, &[], |buf| {
+                        if !buf.is_empty() {
+                            let _ = self.device.write(buf);
+                        }
+                    });
+                }
+                rs?;
             }
             Protocol::OtherTurn => {
                 self.other_turn(context, current_device, net_packet, route_key)?;
@@ -94,15 +170,14 @@ impl PacketHandler for ClientPacketHandler {
         }
         Ok(())
     }
-}
-
-impl ClientPacketHandler {
     fn ip_turn(
         &self,
         mut net_packet: NetPacket<&mut [u8]>,
         context: &ChannelContext,
         current_device: &CurrentDeviceInfo,
         route_key: RouteKey,
+        seq: Option<u16>,
+        delivered: &mut bool,
     ) -> io::Result<()> {
         let destination = net_packet.destination();
         let source = net_packet.source();
@@ -131,38 +206,44 @@ impl ClientPacketHandler {
                     }
                     _ => {}
                 }
+                // 改写过传输层头部的包按校验和策略处理
+                let mut rewritten = false;
                 // ip代理只关心实际目标
                 let real_dest = ipv4.destination_ip();
                 if real_dest != destination
-                    && !(real_dest.is_broadcast()
-                        || real_dest.is_multicast()
-                        || real_dest == current_device.broadcast_ip
-                        || real_dest.is_unspecified())
+                    && !(real_dest.is_multicast()
+                        || real_dest.is_unspecified()
+                        || current_device.subnet().is_reserved(real_dest))
                 {
                     if !self.route.allow(&real_dest) {
                         //拦截不符合的目标
+                        context.drop_stats.add_peer(DropReason::Denied, source);
                         return Ok(());
                     }
                     match ipv4.protocol() {
                         ipv4::protocol::Protocol::Tcp => {
                             let payload = ipv4.payload();
                             if payload.len() < 20 {
+                                context.drop_stats.add_peer(DropReason::Malformed, source);
                                 return Ok(());
                             }
                             let destination_port =
                                 u16::from_be_bytes(payload[2..4].try_into().unwrap());
                             if self.nat_test.is_local_tcp(real_dest, destination_port) {
+                                context.drop_stats.add_peer(DropReason::Loop, source);
                                 return Ok(());
                             }
                         }
                         ipv4::protocol::Protocol::Udp => {
                             let payload = ipv4.payload();
                             if payload.len() < 8 {
+                                context.drop_stats.add_peer(DropReason::Malformed, source);
                                 return Ok(());
                             }
                             let destination_port =
                                 u16::from_be_bytes(payload[2..4].try_into().unwrap());
                             if self.nat_test.is_local_udp(real_dest, destination_port) {
+                                context.drop_stats.add_peer(DropReason::Loop, source);
                                 return Ok(());
                             }
                         }
@@ -173,9 +254,39 @@ impl ClientPacketHandler {
                         if ip_proxy_map.recv_handle(&mut ipv4, source, destination)? {
                             return Ok(());
                         }
+                        rewritten = true;
+                    }
+                }
+                match context.packet_hooks.inbound(net_packet.payload_mut()) {
+                    Some(modified) => rewritten |= modified,
+                    None => {
+                        context.drop_stats.add_peer(DropReason::Hook, source);
+                        return Ok(());
                     }
                 }
-                self.device.write(net_packet.payload())?;
+                rewritten |= context.mtu_guard.inbound(source, net_packet.payload_mut());
+                rewritten |= context.source_policy.inbound(net_packet.payload_mut());
+                if rewritten {
+                    context.checksum.apply(net_packet.payload_mut());
+                }
+                if context.self_probe.deliver(net_packet.payload()) {
+                    // 自检包，不写入网卡
+                    return Ok(());
+                }
+                self.flow_table.record(source, net_packet.payload());
+                *delivered = true;
+                let relayed = route_key.addr == current_device.connect_server;
+                let mut rs = Ok(0);
+                context
+                    .reorder
+                    .inbound(source, seq, relayed, net_packet.payload(), |buf| {
+                        if !buf.is_empty() {
+                            if let Err(e) = self.device.write(buf) {
+                                rs = Err(e);
+                            }
+                        }
+                    });
+                rs?;
             }
             ip_turn_packet::Protocol::Ipv4Broadcast => {
                 //客户端不帮忙转发广播包，所以不会出现这种类型的数据
@@ -191,31 +302,99 @@ impl ClientPacketHandler {
         mut net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
     ) -> io::Result<()> {
+        // 接收时已经丢弃了ttl大于source_ttl的包
         let metric = net_packet.source_ttl() - net_packet.ttl() + 1;
         let source = net_packet.source();
         match ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
-            ControlPacket::PingPacket(_) => {
+            ControlPacket::PingPacket(ping_packet) => {
+                let wire_version = ping_packet.wire_version();
+                if metric == 1 && !context.route_table.has_route(&source, &route_key) {
+                    // 严格入站时不回应没有许可的对端，也不暴露这个地址
+                    if !context.reverse.allowed(&source) {
+                        return Ok(());
+                    }
+                    // 未知路径上的ping会添加路由，负载高时先确认对端能收到发往这个地址的包
+                    match context
+                        .cookie_guard
+                        .check(route_key.addr, ping_packet.cookie())
+                    {
+                        Verdict::Accept => {}
+                        Verdict::Challenge(cookie) => {
+                            return self.cookie_reply(
+                                context,
+                                current_device,
+                                source,
+                                route_key,
+                                &cookie,
+                            );
+                        }
+                        Verdict::Drop => return Ok(()),
+                    }
+                }
                 net_packet.set_transport_protocol(control_packet::Protocol::Pong.into());
                 net_packet.set_source(current_device.virtual_ip);
                 net_packet.set_destination(source);
                 net_packet.first_set_ttl(MAX_TTL);
+                // 告知对端自己支持的协议版本
+                PongPacket::new(net_packet.payload_mut())?.set_peer_wire_version(WireVersion::MAX);
                 self.client_cipher.encrypt_ipv4(&mut net_packet)?;
                 context.send_by_key(net_packet.buffer(), route_key)?;
-                let route = Route::from_default_rt(route_key, metric);
+                let route =
+                    Route::from_default_rt(route_key, metric).with_wire_version(wire_version);
                 context.route_table.add_route_if_absent(source, route);
             }
             ControlPacket::PongPacket(pong_packet) => {
+                if let Some(seq) = pong_packet.echo_seq() {
+                    context.ping.deliver(seq, metric == 1);
+                }
                 let current_time = crate::handle::now_time() as u16;
-                if current_time < pong_packet.time() {
-                    return Ok(());
+                let rt = match rtt_ms(current_time, pong_packet.time()) {
+                    Some(rt) => rt as i64,
+                    None => {
+                        context.stale_pong.record(format_args!(
+                            "{} now={} time={}",
+                            source,
+                            current_time,
+                            pong_packet.time()
+                        ));
+                        return Ok(());
+                    }
+                };
+                let route = Route::from(route_key, metric, rt)
+                    .with_wire_version(pong_packet.peer_wire_version());
+                if route.is_p2p() && context.bring_up.validated(source) {
+                    let elapsed = context
+                        .bring_up
+                        .get(&source)
+                        .map(|v| v.elapsed)
+                        .unwrap_or_default();
+                    context
+                        .diary
+                        .record(source, DiaryEvent::HandshakeOk { elapsed });
+                }
+                if route.is_p2p() {
+                    context.route_table.path_scores.pong(source, route_key, rt);
+                    context.route_health.ack(source, route_key);
+                    if !context
+                        .route_health
+                        .accept(source, rt, context.server_rt.get())
+                    {
+                        // 直连断开过，重新建立的延迟还不如中继，继续使用中继
+                        return Ok(());
+                    }
                 }
-                let rt = (current_time - pong_packet.time()) as i64;
-                let route = Route::from(route_key, metric, rt);
                 context.route_table.add_route(source, route);
+                if route.is_p2p() && context.route_table.path_scores.active() {
+                    context.route_table.path_scores.compare(
+                        source,
+                        context.route_table.route_one_p2p(&source).as_ref(),
+                        context.server_rt.get(),
+                    );
+                }
             }
             ControlPacket::PunchRequest => {
                 log::info!("PunchRequest={:?},source={}", route_key, source);
-                if context.use_channel_type().is_only_relay() {
+                if context.use_channel_type().is_only_relay() || !context.reverse.allowed(&source) {
                     return Ok(());
                 }
                 //忽略掉来源于自己的包
@@ -225,6 +404,27 @@ impl ClientPacketHandler {
                 {
                     return Ok(());
                 }
+                // 对端的打洞能到达，说明可以直连
+                self.negative_path.clear(&source);
+                if let Ok(fired) = <[u8; 8]>::try_from(net_packet.payload()) {
+                    let peer_nat_type = self
+                        .peer_nat_info_map
+                        .read()
+                        .get(&source)
+                        .map(|v| v.nat_type);
+                    let skew = context
+                        .punch_sync
+                        .peer_fired(source, u64::from_be_bytes(fired));
+                    if let (Some(skew), Some(remote)) = (skew, peer_nat_type) {
+                        record_skew(
+                            context,
+                            source,
+                            self.nat_test.nat_info().nat_type,
+                            remote,
+                            skew,
+                        );
+                    }
+                }
 
                 //回应
                 net_packet.set_transport_protocol(control_packet::Protocol::PunchResponse.into());
@@ -239,7 +439,7 @@ impl ClientPacketHandler {
             }
             ControlPacket::PunchResponse => {
                 log::info!("PunchResponse={:?},source={}", route_key, source);
-                if context.use_channel_type().is_only_relay() {
+                if context.use_channel_type().is_only_relay() || !context.reverse.allowed(&source) {
                     return Ok(());
                 }
                 if self
@@ -250,6 +450,15 @@ impl ClientPacketHandler {
                 }
                 let route = Route::from_default_rt(route_key, 1);
                 context.route_table.add_route_if_absent(source, route);
+                self.negative_path.clear(&source);
+                context.bring_up.punched(source);
+                context.punch_history.succeeded(&source);
+                context.diary.record(
+                    source,
+                    DiaryEvent::PunchOk {
+                        addr: route_key.addr,
+                    },
+                );
             }
             ControlPacket::AddrRequest => match route_key.addr.ip() {
                 std::net::IpAddr::V4(ipv4) => {
@@ -269,6 +478,167 @@ impl ClientPacketHandler {
                 std::net::IpAddr::V6(_) => {}
             },
             ControlPacket::AddrResponse(_) => {}
+            ControlPacket::Notice(notice_packet) => {
+                let id = notice_packet.id();
+                let kind = NoticeKind::from(notice_packet.kind());
+                if self.critical_notice.first_seen(source, id) {
+                    log::info!("收到通知 id={},{:?},source={}", id, kind, source);
+                    match kind {
+                        NoticeKind::Offline => {
+                            context.route_table.remove_ip(&source, EvictReason::Offline);
+                        }
+                        NoticeKind::EndpointChange => {
+                            // 旧的直连路由可能已经失效，之前的打洞失败记录也不再有参考价值
+                            context.diary.record(source, DiaryEvent::EndpointChange);
+                            context
+                                .route_table
+                                .remove_ip(&source, EvictReason::EndpointChange);
+                            self.negative_path.clear(&source);
+                        }
+                        NoticeKind::Unknown(_) => {}
+                    }
+                }
+                // 重传的副本也要回复，之前的确认可能丢了
+                net_packet.set_transport_protocol(control_packet::Protocol::NoticeAck.into());
+                net_packet.set_source(current_device.virtual_ip);
+                net_packet.set_destination(source);
+                net_packet.first_set_ttl(MAX_TTL);
+                self.client_cipher.encrypt_ipv4(&mut net_packet)?;
+                context.send_by_key(net_packet.buffer(), route_key)?;
+            }
+            ControlPacket::NoticeAck(ack_packet) => {
+                self.critical_notice.ack(ack_packet.id(), source);
+            }
+            ControlPacket::FeatureRequest(feature_packet) => {
+                let feature = Feature::from(feature_packet.feature());
+                let accepted =
+                    context
+                        .peer_features
+                        .handle_request(source, feature, feature_packet.enabled());
+                net_packet.set_transport_protocol(control_packet::Protocol::FeatureReply.into());
+                net_packet.set_source(current_device.virtual_ip);
+                net_packet.set_destination(source);
+                net_packet.first_set_ttl(MAX_TTL);
+                FeaturePacket::new(net_packet.payload_mut())?.set_accepted(accepted);
+                self.client_cipher.encrypt_ipv4(&mut net_packet)?;
+                context.send_by_key(net_packet.buffer(), route_key)?;
+            }
+            ControlPacket::FeatureReply(feature_packet) => {
+                context.peer_features.handle_reply(
+                    source,
+                    feature_packet.seq(),
+                    Feature::from(feature_packet.feature()),
+                    feature_packet.accepted(),
+                );
+            }
+            ControlPacket::BandwidthRequest(request) => {
+                let id = request.id();
+                let (consent, samples) = if request.is_finish() {
+                    match context.bandwidth.finish(source, id) {
+                        Some(samples) => (true, samples),
+                        None => (false, Vec::new()),
+                    }
+                } else {
+                    let consent = context.bandwidth.start(
+                        source,
+                        id,
+                        request.bursts(),
+                        request.burst_len(),
+                        request.size(),
+                    );
+                    (consent, Vec::new())
+                };
+                let mut packet =
+                    reply_packet(current_device.virtual_ip, source, id, consent, &samples)?;
+                self.client_cipher.encrypt_ipv4(&mut packet)?;
+                context.send_by_key(packet.buffer(), route_key)?;
+            }
+            ControlPacket::BandwidthReply(reply) => {
+                context.bandwidth.deliver(reply.id(), parse_reply(&reply));
+            }
+            ControlPacket::BandwidthProbe(probe) => {
+                context
+                    .bandwidth
+                    .arrive(source, probe.id(), probe.burst(), net_packet.data_len());
+            }
+            ControlPacket::Cookie(cookie_packet) => {
+                if metric != 1 {
+                    return Ok(());
+                }
+                // 对端负载高，立即带上cookie重新ping，不用等下一次心跳
+                log::info!("对端要求回显cookie,source={},{:?}", source, route_key);
+                self.cookie_ping(
+                    context,
+                    current_device,
+                    source,
+                    route_key,
+                    &cookie_packet.cookie(),
+                )?;
+            }
+            ControlPacket::ConnectIntent(intent) => {
+                // 只接受经服务器转发的请求，不在未知路径上回复
+                if route_key.addr != current_device.connect_server
+                    || context.use_channel_type().is_only_relay()
+                {
+                    return Ok(());
+                }
+                let nonce = intent.nonce();
+                let duration = Duration::from_secs(intent.duration() as u64);
+                match context
+                    .reverse
+                    .intent(source, nonce, duration, Instant::now())
+                {
+                    Intent::Approve => {
+                        log::info!("收到连接请求,未开启严格入站,直接同意,source={}", source);
+                        send_reverse(
+                            context,
+                            current_device,
+                            &self.client_cipher,
+                            control_packet::Protocol::ConnectApprove,
+                            source,
+                            nonce,
+                            Duration::ZERO,
+                        )?;
+                        punch_now(
+                            context,
+                            &self.nat_test,
+                            current_device,
+                            &self.client_cipher,
+                            &self.negative_path,
+                            source,
+                        )?;
+                    }
+                    Intent::Queued(id) => {
+                        log::info!("收到连接请求,等待批准,id={},source={}", id, source);
+                    }
+                    Intent::Duplicate => {}
+                    Intent::Limited => {
+                        log::warn!("连接请求过于频繁,丢弃,source={}", source);
+                    }
+                }
+            }
+            ControlPacket::ConnectApprove(approve) => {
+                if route_key.addr != current_device.connect_server {
+                    return Ok(());
+                }
+                if !context
+                    .reverse
+                    .approved(&source, approve.nonce(), Instant::now())
+                {
+                    return Ok(());
+                }
+                log::info!(
+                    "对端同意直连,source={},时长{}秒",
+                    source,
+                    approve.duration()
+                );
+                // 双方都是严格入站时，本地也要允许对端发起的打洞
+                if context.reverse.strict() {
+                    context.allow_peer(source, Duration::from_secs(approve.duration() as u64));
+                }
+                // 对端随后发起打洞，之前的失败记录不再有效
+                self.negative_path.clear(&source);
+            }
         }
         Ok(())
     }
@@ -279,16 +649,39 @@ impl ClientPacketHandler {
         net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
     ) -> io::Result<()> {
-        if context.use_channel_type().is_only_relay() {
-            return Ok(());
-        }
         let source = net_packet.source();
-        match other_turn_packet::Protocol::from(net_packet.transport_protocol()) {
-            other_turn_packet::Protocol::Punch => {
-                let mut punch_info =
-                    PunchInfo::parse_from_bytes(net_packet.payload()).map_err(|e| {
-                        io::Error::new(io::ErrorKind::Other, format!("PunchInfo {:?}", e))
-                    })?;
+        let protocol = other_turn_packet::Protocol::from(net_packet.transport_protocol());
+        match Encoding::of_punch(protocol) {
+            Some(encoding) => {
+                let mut punch_info = encoding.codec().decode_punch(net_packet.payload())?;
+                if context.use_channel_type().is_only_relay() {
+                    if !punch_info.reply {
+                        // 告知对端不要再打洞
+                        self.relay_only_reply(context, current_device, source, route_key)?;
+                    }
+                    return Ok(());
+                }
+                if !context.reverse.allowed(&source) {
+                    // 严格入站，对端需要先经服务器请求直连
+                    if !punch_info.reply {
+                        self.relay_only_reply(context, current_device, source, route_key)?;
+                    }
+                    return Ok(());
+                }
+                self.negative_path
+                    .set_relay_only(source, punch_info.relay_only);
+                if punch_info.relay_only {
+                    if context.bring_up.give_up(source) {
+                        context.diary.record(
+                            source,
+                            DiaryEvent::HandshakeFail {
+                                reason: "peer is relay only",
+                            },
+                        );
+                    }
+                    return Ok(());
+                }
+                context.bring_up.handshake(source);
                 let public_ips = punch_info
                     .public_ip_list
                     .iter()
@@ -325,8 +718,14 @@ impl ClientPacketHandler {
                     self.peer_nat_info_map.write().insert(source, peer_nat_info);
                 }
                 if !punch_info.reply {
+                    // 双方都有服务器时钟的估计时约定打洞时刻，否则收到后立即打洞
+                    let fire_at = context.punch_sync.plan(punch_info.server_rtt).unwrap_or(0);
                     let mut punch_reply = PunchInfo::new();
                     punch_reply.reply = true;
+                    punch_reply.server_rtt = context.punch_sync.rtt();
+                    let compact = context.compact_encoding.load(Ordering::Relaxed);
+                    punch_reply.compact_encoding = compact;
+                    punch_reply.fire_at = fire_at;
                     let nat_info = self.nat_test.nat_info();
                     punch_reply.public_ip_list = nat_info
                         .public_ips
@@ -348,30 +747,103 @@ impl ClientPacketHandler {
                         punch_reply.ipv6 = ipv6.octets().to_vec();
                         punch_reply.ipv6_port = nat_info.udp_ports[0] as u32;
                     }
-                    let bytes = punch_reply.write_to_bytes().map_err(|e| {
-                        io::Error::new(io::ErrorKind::Other, format!("punch_reply {:?}", e))
-                    })?;
+                    // 对端声明支持时回复紧凑编码
+                    let encoding = Encoding::negotiate(compact, punch_info.compact_encoding);
+                    let bytes = encoding.codec().encode_punch(&punch_reply)?;
                     let mut punch_packet =
                         NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
                     punch_packet.set_default_version();
                     punch_packet.set_protocol(Protocol::OtherTurn);
-                    punch_packet.set_transport_protocol(other_turn_packet::Protocol::Punch.into());
+                    punch_packet.set_transport_protocol(encoding.punch().into());
                     punch_packet.first_set_ttl(MAX_TTL);
                     punch_packet.set_source(current_device.virtual_ip());
                     punch_packet.set_destination(source);
                     punch_packet.set_payload(&bytes)?;
                     self.client_cipher.encrypt_ipv4(&mut punch_packet)?;
-                    if self.punch_sender.send(true, source, peer_nat_info) {
+                    if self.punch_sender.send(true, source, peer_nat_info, fire_at) {
                         context.send_by_key(punch_packet.buffer(), route_key)?;
                     }
                 } else {
-                    self.punch_sender.send(false, source, peer_nat_info);
+                    self.punch_sender
+                        .send(false, source, peer_nat_info, punch_info.fire_at);
                 }
             }
-            other_turn_packet::Protocol::Unknown(e) => {
-                log::warn!("不支持的转发协议 {:?},source:{:?}", e, source);
+            None => {
+                log::warn!("不支持的转发协议 {:?},source:{:?}", protocol, source);
             }
         }
         Ok(())
     }
+    fn relay_only_reply(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        source: Ipv4Addr,
+        route_key: RouteKey,
+    ) -> io::Result<()> {
+        let mut punch_reply = PunchInfo::new();
+        punch_reply.reply = true;
+        punch_reply.relay_only = true;
+        let bytes = punch_reply
+            .write_to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("punch_reply {:?}", e)))?;
+        let mut punch_packet =
+            NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+        punch_packet.set_default_version();
+        punch_packet.set_protocol(Protocol::OtherTurn);
+        punch_packet.set_transport_protocol(other_turn_packet::Protocol::Punch.into());
+        punch_packet.first_set_ttl(MAX_TTL);
+        punch_packet.set_source(current_device.virtual_ip());
+        punch_packet.set_destination(source);
+        punch_packet.set_payload(&bytes)?;
+        self.client_cipher.encrypt_ipv4(&mut punch_packet)?;
+        context.send_by_key(punch_packet.buffer(), route_key)
+    }
+    fn cookie_reply(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        source: Ipv4Addr,
+        route_key: RouteKey,
+        cookie: &[u8; COOKIE_LEN],
+    ) -> io::Result<()> {
+        let mut net_packet =
+            NetPacket::new_encrypt(vec![0u8; 12 + COOKIE_LEN + ENCRYPTION_RESERVED])?;
+        net_packet.set_default_version();
+        net_packet.set_protocol(Protocol::Control);
+        net_packet.set_transport_protocol(control_packet::Protocol::Cookie.into());
+        net_packet.first_set_ttl(1);
+        net_packet.set_source(current_device.virtual_ip);
+        net_packet.set_destination(source);
+        CookiePacket::new(net_packet.payload_mut())?.set_cookie(cookie);
+        self.client_cipher.encrypt_ipv4(&mut net_packet)?;
+        context.send_by_key(net_packet.buffer(), route_key)
+    }
+    fn cookie_ping(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        source: Ipv4Addr,
+        route_key: RouteKey,
+        cookie: &[u8; COOKIE_LEN],
+    ) -> io::Result<()> {
+        let mut net_packet = NetPacket::new_encrypt(vec![
+            0u8;
+            12 + PING_WIRE_VERSION_LEN
+                + COOKIE_LEN
+                + ENCRYPTION_RESERVED
+        ])?;
+        net_packet.set_default_version();
+        net_packet.set_protocol(Protocol::Control);
+        net_packet.set_transport_protocol(control_packet::Protocol::Ping.into());
+        net_packet.first_set_ttl(5);
+        net_packet.set_source(current_device.virtual_ip);
+        net_packet.set_destination(source);
+        let mut ping = PingPacket::new(net_packet.payload_mut())?;
+        ping.set_time(crate::handle::now_time() as u16);
+        ping.set_wire_version(WireVersion::MAX);
+        ping.set_extension(cookie)?;
+        self.client_cipher.encrypt_ipv4(&mut net_packet)?;
+        context.send_by_key(net_packet.buffer(), route_key)
+    }
 }
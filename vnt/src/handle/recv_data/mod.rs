@@ -7,16 +7,25 @@ use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::{Mutex, RwLock};
 
 use crate::channel::context::ChannelContext;
+use crate::channel::drop_reason::DropReason;
 use crate::channel::handler::RecvChannelHandler;
+use crate::channel::inbound_limit::Verdict;
 use crate::channel::punch::NatInfo;
 use crate::channel::RouteKey;
 use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
-use crate::handle::callback::VntCallback;
+use crate::handle::callback::{FloodInfo, VntCallback};
+use crate::handle::critical_notice::CriticalNotice;
+#[cfg(feature = "server_encrypt")]
+use crate::handle::crypto_pool::CryptoPool;
+use crate::handle::directory::Directory;
+use crate::handle::flow_table::FlowTable;
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchSender;
+use crate::handle::negative_path::NegativePathCache;
+use crate::handle::notice::NoticeHolder;
 use crate::handle::recv_data::client::ClientPacketHandler;
 use crate::handle::recv_data::server::ServerPacketHandler;
 use crate::handle::recv_data::turn::TurnPacketHandler;
@@ -24,12 +33,13 @@ use crate::handle::{BaseConfigInfo, CurrentDeviceInfo, PeerDeviceInfo, SELF_IP};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
 use crate::nat::NatTest;
-use crate::protocol::NetPacket;
+use crate::protocol::{compat, NetPacket};
 use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
-use crate::util::U64Adder;
+use crate::util::metrics::Counter;
 
 mod client;
 mod server;
+mod standby;
 mod turn;
 
 #[derive(Clone)]
@@ -38,8 +48,9 @@ pub struct RecvDataHandler<Call> {
     turn: TurnPacketHandler,
     client: ClientPacketHandler,
     server: ServerPacketHandler<Call>,
-    counter: U64Adder,
+    counter: Counter,
     nat_test: NatTest,
+    callback: Call,
 }
 
 impl<Call: VntCallback> RecvChannelHandler for RecvDataHandler<Call> {
@@ -64,6 +75,7 @@ impl<Call: VntCallback> RecvChannelHandler for RecvDataHandler<Call> {
 impl<Call: VntCallback> RecvDataHandler<Call> {
     pub fn new(
         #[cfg(feature = "server_encrypt")] rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+        #[cfg(feature = "server_encrypt")] crypto_pool: CryptoPool,
         server_cipher: Cipher,
         client_cipher: Cipher,
         current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
@@ -77,21 +89,30 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
         external_route: ExternalRoute,
         route: AllowExternalRoute,
         #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
-        counter: U64Adder,
+        counter: Counter,
         handshake: Handshake,
+        notice: NoticeHolder,
+        negative_path: NegativePathCache,
+        critical_notice: CriticalNotice,
+        flow_table: FlowTable,
+        directory: Directory,
     ) -> Self {
         let server = ServerPacketHandler::new(
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
+            #[cfg(feature = "server_encrypt")]
+            crypto_pool,
             server_cipher,
             current_device.clone(),
             device.clone(),
             device_list,
             config_info,
             nat_test.clone(),
-            callback,
+            callback.clone(),
             external_route.clone(),
             handshake,
+            notice,
+            directory,
         );
         let client = ClientPacketHandler::new(
             device.clone(),
@@ -102,6 +123,9 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
             route,
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
+            negative_path,
+            critical_notice,
+            flow_table,
         );
         let turn = TurnPacketHandler::new();
         Self {
@@ -111,6 +135,7 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
             server,
             counter,
             nat_test,
+            callback,
         }
     }
     fn handle0(
@@ -121,19 +146,41 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
     ) -> io::Result<()> {
         // 统计流量
         self.counter.add(buf.len() as _);
-        let net_packet = NetPacket::new(buf)?;
+        // 路径切换期间的序号在扩展头中，解码前读取
+        let seq = compat::sequence(buf);
+        // 兼容新旧协议头，统一转换成旧格式处理
+        let len = match compat::decode_in_place(buf) {
+            Ok(len) => len,
+            Err(e) => {
+                context.drop_stats.add(DropReason::Malformed);
+                return Err(e);
+            }
+        };
+        let mut net_packet = match NetPacket::new(&mut buf[..len]) {
+            Ok(net_packet) => net_packet,
+            Err(e) => {
+                context.drop_stats.add(DropReason::Malformed);
+                return Err(e);
+            }
+        };
         if net_packet.ttl() == 0 || net_packet.source_ttl() < net_packet.ttl() {
             log::warn!("丢弃过时包:{:?}", net_packet.head());
+            context
+                .drop_stats
+                .add_peer(DropReason::TtlExpired, net_packet.source());
             return Ok(());
         }
         let current_device = self.current_device.load();
+        if net_packet.is_gateway() && context.rendezvous.is_standby(&route_key.addr) {
+            // 备用服务器只用于打洞协商
+            return standby::handle(context, &current_device, net_packet, route_key);
+        }
         let dest = net_packet.destination();
         if dest == current_device.virtual_ip
-            || dest.is_broadcast()
             || dest.is_multicast()
             || dest == SELF_IP
             || dest.is_unspecified()
-            || dest == current_device.broadcast_ip
+            || current_device.subnet().is_reserved(dest)
         {
             //发给自己的包
             if net_packet.is_gateway() {
@@ -141,11 +188,51 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
                 self.server
                     .handle(net_packet, route_key, context, &current_device)
             } else {
+                if drop_blocked(context, &[net_packet.source()]) {
+                    //已屏蔽的设备，包括打洞协商
+                    return Ok(());
+                }
+                let relayed = route_key.addr == current_device.connect_server;
+                if relayed {
+                    //经过服务器中继的客户端包
+                    context.relay_stats.add_rx(len);
+                }
+                let source = net_packet.source();
+                // 解密成功后才计入限速和对端统计，伪造源地址的包不会耗尽对端的配额和统计的槽位
+                self.client.decrypt(&mut net_packet, context)?;
+                match context
+                    .inbound_limit
+                    .check(source, net_packet.protocol(), len)
+                {
+                    Verdict::Pass => {}
+                    verdict => {
+                        context
+                            .drop_stats
+                            .add_peer(DropReason::InboundLimited, source);
+                        let info = match verdict {
+                            Verdict::Exceeded(limit) => {
+                                FloodInfo::new(source, limit.pps, limit.bps, None)
+                            }
+                            Verdict::Blocked(limit, cooldown) => {
+                                FloodInfo::new(source, limit.pps, limit.bps, Some(cooldown))
+                            }
+                            _ => return Ok(()),
+                        };
+                        log::warn!("{}", info);
+                        self.callback.inbound_flood(info);
+                        return Ok(());
+                    }
+                }
+                context.peer_stats.rx(source, len, !relayed);
                 //客户端-客户端包
                 self.client
-                    .handle(net_packet, route_key, context, &current_device)
+                    .handle_sequenced(net_packet, route_key, context, &current_device, seq)
             }
         } else {
+            if drop_blocked(context, &[net_packet.source(), dest]) {
+                //不为屏蔽的设备转发
+                return Ok(());
+            }
             //转发包
             self.turn
                 .handle(net_packet, route_key, context, &current_device)
@@ -153,6 +240,17 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
     }
 }
 
+/// 来源或目标是屏蔽的设备时记录并返回true
+fn drop_blocked(context: &ChannelContext, peers: &[Ipv4Addr]) -> bool {
+    match peers.iter().find(|ip| context.block_list.contains(ip)) {
+        Some(ip) => {
+            context.drop_stats.add_peer(DropReason::Blocked, *ip);
+            true
+        }
+        None => false,
+    }
+}
+
 pub trait PacketHandler {
     fn handle(
         &self,
@@ -162,3 +260,19 @@ pub trait PacketHandler {
         current_device: &CurrentDeviceInfo,
     ) -> io::Result<()>;
 }
+
+#[test]
+fn test_drop_blocked() {
+    let context = crate::channel::loopback_context();
+    let blocked = Ipv4Addr::new(10, 26, 0, 3);
+    let other = Ipv4Addr::new(10, 26, 0, 4);
+    assert!(!drop_blocked(&context, &[blocked, other]));
+    context.block_peer(blocked, "");
+    // 屏蔽的设备发来的包，以及经过本机转发给屏蔽设备的包
+    assert!(drop_blocked(&context, &[blocked]));
+    assert!(drop_blocked(&context, &[other, blocked]));
+    assert!(!drop_blocked(&context, &[other]));
+    assert_eq!(context.drop_stats.count(DropReason::Blocked), 2);
+    context.unblock_peer(&blocked);
+    assert!(!drop_blocked(&context, &[blocked]));
+}
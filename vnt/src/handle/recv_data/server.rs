@@ -1,5 +1,6 @@
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 #[cfg(feature = "server_encrypt")]
 use std::time::{Duration, Instant};
@@ -20,21 +21,35 @@ use crate::cipher::RsaCipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::callback::{ErrorInfo, ErrorType, HandshakeInfo, RegisterInfo, VntCallback};
 #[cfg(feature = "server_encrypt")]
+use crate::handle::crypto_pool::{CryptoPool, Rejected};
+use crate::handle::directory::Directory;
+#[cfg(feature = "server_encrypt")]
 use crate::handle::handshaker;
 use crate::handle::handshaker::Handshake;
+use crate::handle::notice::NoticeHolder;
 use crate::handle::recv_data::PacketHandler;
 use crate::handle::{
     registrar, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo, GATEWAY_IP,
 };
 use crate::nat::NatTest;
-use crate::proto::message::{DeviceList, HandshakeResponse, RegistrationResponse};
+use crate::proto::message::{
+    HandshakeResponse, RegistrationResponse, ServerAction, ServerClock, ServerNotice,
+};
 use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::codec::Encoding;
 use crate::protocol::control_packet::ControlPacket;
 use crate::protocol::error_packet::InErrorPacket;
 use crate::protocol::{ip_turn_packet, service_packet, NetPacket, Protocol, MAX_TTL};
+#[cfg(target_os = "linux")]
+use crate::tun_tap_device::intent_log::Intent;
 use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
-use crate::{proto, PeerClientInfo};
-#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+#[cfg(not(target_os = "android"))]
+use crate::tun_tap_device::tun_setup::{TunOps, TunSetup};
+use crate::util::health::ReadyCheck;
+use crate::util::sanitize;
+use crate::util::serial::rtt_ms;
+use crate::util::subnet::Subnet;
+#[cfg(target_os = "linux")]
 use tun::device::IFace;
 
 /// 处理来源于服务端的包
@@ -42,6 +57,8 @@ use tun::device::IFace;
 pub struct ServerPacketHandler<Call> {
     #[cfg(feature = "server_encrypt")]
     rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+    #[cfg(feature = "server_encrypt")]
+    crypto_pool: CryptoPool,
     server_cipher: Cipher,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     device: DeviceAdapter,
@@ -50,16 +67,19 @@ pub struct ServerPacketHandler<Call> {
     nat_test: NatTest,
     callback: Call,
     #[cfg(feature = "server_encrypt")]
-    up_key_time: Arc<AtomicCell<Instant>>,
-    #[cfg(not(target_os = "android"))]
-    route_record: Arc<Mutex<Vec<(Ipv4Addr, Ipv4Addr)>>>,
+    up_key_time: Arc<AtomicCell<Option<Instant>>>,
     external_route: ExternalRoute,
     handshake: Handshake,
+    notice: NoticeHolder,
+    directory: Directory,
+    // 注册时指定的ip，改为自动分配后为None
+    requested_ip: Arc<AtomicCell<Option<Ipv4Addr>>>,
 }
 
 impl<Call> ServerPacketHandler<Call> {
     pub fn new(
         #[cfg(feature = "server_encrypt")] rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+        #[cfg(feature = "server_encrypt")] crypto_pool: CryptoPool,
         server_cipher: Cipher,
         current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
         device: DeviceAdapter,
@@ -69,10 +89,15 @@ impl<Call> ServerPacketHandler<Call> {
         callback: Call,
         external_route: ExternalRoute,
         handshake: Handshake,
+        notice: NoticeHolder,
+        directory: Directory,
     ) -> Self {
+        let requested_ip = Arc::new(AtomicCell::new(config_info.ip));
         Self {
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
+            #[cfg(feature = "server_encrypt")]
+            crypto_pool,
             server_cipher,
             current_device,
             device,
@@ -81,11 +106,12 @@ impl<Call> ServerPacketHandler<Call> {
             nat_test,
             callback,
             #[cfg(feature = "server_encrypt")]
-            up_key_time: Arc::new(AtomicCell::new(Instant::now() - Duration::from_secs(60))),
-            #[cfg(not(target_os = "android"))]
-            route_record: Arc::new(Mutex::default()),
+            up_key_time: Arc::new(AtomicCell::new(None)),
             external_route,
             handshake,
+            notice,
+            directory,
+            requested_ip,
         }
     }
 }
@@ -107,28 +133,34 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
         {
             //服务端通知客户端上传密钥
             #[cfg(feature = "server_encrypt")]
-            {
-                let mutex_guard = self.rsa_cipher.lock();
-                if let Some(rsa_cipher) = mutex_guard.as_ref() {
-                    let last = self.up_key_time.load();
-                    if last.elapsed() < Duration::from_secs(1)
-                        || self
-                            .up_key_time
-                            .compare_exchange(last, Instant::now())
-                            .is_err()
-                    {
-                        //短时间不重复上传服务端密钥
-                        return Ok(());
-                    }
-                    if let Some(key) = self.server_cipher.key() {
-                        log::info!("上传密钥到服务端:{:?}", route_key);
-                        let packet = handshaker::secret_handshake_request_packet(
-                            rsa_cipher,
-                            self.config_info.token.clone(),
-                            key,
-                        )?;
-                        context.send_by_key(packet.buffer(), route_key)?;
-                    }
+            if self.rsa_cipher.lock().is_some() {
+                let last = self.up_key_time.load();
+                if last.is_some_and(|v| v.elapsed() < Duration::from_secs(1))
+                    || self
+                        .up_key_time
+                        .compare_exchange(last, Some(Instant::now()))
+                        .is_err()
+                {
+                    //短时间不重复上传服务端密钥
+                    return Ok(());
+                }
+                if let Some(key) = self.server_cipher.key() {
+                    log::info!("上传密钥到服务端:{:?}", route_key);
+                    let key = key.to_vec();
+                    let rsa_cipher = self.rsa_cipher.clone();
+                    let token = self.config_info.token.clone();
+                    let context = context.clone();
+                    self.submit_crypto(route_key, move || {
+                        let guard = rsa_cipher.lock();
+                        if let Some(rsa_cipher) = guard.as_ref() {
+                            let packet = handshaker::secret_handshake_request_packet(
+                                rsa_cipher, token, &key,
+                            )?;
+                            drop(guard);
+                            context.send_by_key(packet.buffer(), route_key)?;
+                        }
+                        Ok(())
+                    });
                 }
             }
             return Ok(());
@@ -140,57 +172,17 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                     io::Error::new(io::ErrorKind::Other, format!("HandshakeResponse {:?}", e))
                 })?;
             log::info!("握手响应:{:?},{}", route_key, response);
+            context
+                .relay_passthrough
+                .store(response.relay_passthrough, Ordering::Relaxed);
             //如果开启了加密，则发送加密握手请求
             #[cfg(feature = "server_encrypt")]
-            if let Some(key) = self.server_cipher.key() {
-                {
-                    let guard = self.rsa_cipher.lock();
-                    if let Some(rsa_cipher) = guard.as_ref() {
-                        if rsa_cipher.finger() == &response.key_finger {
-                            let packet = handshaker::secret_handshake_request_packet(
-                                rsa_cipher,
-                                self.config_info.token.clone(),
-                                key,
-                            )?;
-                            drop(guard);
-                            context.send_by_key(packet.buffer(), route_key)?;
-                            return Ok(());
-                        }
-                        log::warn!(
-                            "拒绝服务端密钥对变化,原指纹:{:?}，新指纹:{:?}，addr:{:?}",
-                            rsa_cipher.finger(),
-                            response.key_finger,
-                            route_key
-                        );
-                        return Ok(());
-                    }
-                    drop(guard);
-                }
-                let rsa_cipher = RsaCipher::new(&response.public_key)?;
-                if rsa_cipher.finger() != &response.key_finger {
-                    log::info!(
-                        "服务端密钥和指纹不匹 配拒绝握手,指纹1:{:?}，指纹2:{:?}",
-                        rsa_cipher.finger(),
-                        response.key_finger
-                    );
-                    return Ok(());
-                }
-                let handshake_info = HandshakeInfo::new(
-                    rsa_cipher.public_key()?.clone(),
-                    response.key_finger,
-                    response.version,
-                );
-                log::info!("加密握手请求:{:?}", handshake_info);
-
-                if self.callback.handshake(handshake_info) {
-                    let packet = handshaker::secret_handshake_request_packet(
-                        &rsa_cipher,
-                        self.config_info.token.clone(),
-                        key,
-                    )?;
-                    context.send_by_key(packet.buffer(), route_key)?;
-                    self.rsa_cipher.lock().replace(rsa_cipher);
-                }
+            if self.server_cipher.key().is_some() {
+                let handler = self.clone();
+                let context = context.clone();
+                self.submit_crypto(route_key, move || {
+                    handler.secret_handshake(response, route_key, &context)
+                });
                 return Ok(());
             }
             #[cfg(feature = "server_encrypt")]
@@ -227,7 +219,9 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                                     let icmp_packet = icmp::IcmpPacket::new(ipv4.payload())?;
                                     if icmp_packet.kind() == Kind::EchoReply {
                                         //网关ip ping的回应
-                                        self.device.write(net_packet.payload())?;
+                                        if !context.self_probe.deliver(net_packet.payload()) {
+                                            self.device.write(net_packet.payload())?;
+                                        }
                                         return Ok(());
                                     }
                                 }
@@ -247,6 +241,96 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
 }
 
 impl<Call: VntCallback> ServerPacketHandler<Call> {
+    /// rsa运算放到单独的线程中，收包线程不等待
+    #[cfg(feature = "server_encrypt")]
+    fn submit_crypto<F>(&self, route_key: RouteKey, f: F)
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        let rs = self.crypto_pool.submit(
+            route_key.addr,
+            move || {
+                if let Err(e) = f() {
+                    log::warn!("加密握手失败 {:?},{:?}", route_key, e);
+                }
+            },
+            move || {
+                //等待下一次握手重试
+                log::warn!("加密握手排队超时 {:?}", route_key);
+            },
+        );
+        match rs {
+            Ok(_) => {}
+            Err(Rejected::InFlight) => {
+                log::debug!("加密握手正在进行 {:?}", route_key);
+            }
+            Err(e) => {
+                log::warn!("加密握手被拒绝 {:?},{:?}", route_key, e);
+            }
+        }
+    }
+    /// 收到握手响应后发送加密握手请求，在加密线程池中执行
+    #[cfg(feature = "server_encrypt")]
+    fn secret_handshake(
+        &self,
+        response: HandshakeResponse,
+        route_key: RouteKey,
+        context: &ChannelContext,
+    ) -> io::Result<()> {
+        let key = match self.server_cipher.key() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        {
+            let guard = self.rsa_cipher.lock();
+            if let Some(rsa_cipher) = guard.as_ref() {
+                if rsa_cipher.finger() == &response.key_finger {
+                    let packet = handshaker::secret_handshake_request_packet(
+                        rsa_cipher,
+                        self.config_info.token.clone(),
+                        key,
+                    )?;
+                    drop(guard);
+                    context.send_by_key(packet.buffer(), route_key)?;
+                    return Ok(());
+                }
+                log::warn!(
+                    "拒绝服务端密钥对变化,原指纹:{:?}，新指纹:{:?}，addr:{:?}",
+                    rsa_cipher.finger(),
+                    response.key_finger,
+                    route_key
+                );
+                return Ok(());
+            }
+            drop(guard);
+        }
+        let rsa_cipher = RsaCipher::new(&response.public_key)?;
+        if rsa_cipher.finger() != &response.key_finger {
+            log::info!(
+                "服务端密钥和指纹不匹 配拒绝握手,指纹1:{:?}，指纹2:{:?}",
+                rsa_cipher.finger(),
+                response.key_finger
+            );
+            return Ok(());
+        }
+        let handshake_info = HandshakeInfo::new(
+            rsa_cipher.public_key()?.clone(),
+            response.key_finger,
+            response.version,
+        );
+        log::info!("加密握手请求:{:?}", handshake_info);
+
+        if self.callback.handshake(handshake_info) {
+            let packet = handshaker::secret_handshake_request_packet(
+                &rsa_cipher,
+                self.config_info.token.clone(),
+                key,
+            )?;
+            context.send_by_key(packet.buffer(), route_key)?;
+            self.rsa_cipher.lock().replace(rsa_cipher);
+        }
+        Ok(())
+    }
     fn service(
         &self,
         context: &ChannelContext,
@@ -268,6 +352,46 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                 let virtual_gateway = Ipv4Addr::from(response.virtual_gateway);
                 let virtual_network =
                     Ipv4Addr::from(response.virtual_ip & response.virtual_netmask);
+                if let Err(e) = Subnet::new(virtual_ip, virtual_netmask).check_host(virtual_ip) {
+                    // 网络地址和广播地址不能作为本机地址，否则广播和单播无法区分
+                    log::error!(
+                        "服务器分配的地址不可用 {}/{} {}",
+                        virtual_ip,
+                        virtual_netmask,
+                        e
+                    );
+                    let msg = format!("server assigned {}/{}: {}", virtual_ip, virtual_netmask, e);
+                    context
+                        .health
+                        .not_ready(ReadyCheck::Registered, msg.clone());
+                    self.callback
+                        .error(ErrorInfo::new_msg(ErrorType::InvalidIp, msg));
+                    return Ok(());
+                }
+                if let Some(requested) = self.requested_ip.load() {
+                    if let Err((code, msg)) = registrar::check_requested_ip(
+                        requested,
+                        virtual_ip,
+                        virtual_netmask,
+                        virtual_gateway,
+                    ) {
+                        if !self.config_info.ip_fallback {
+                            log::error!("{}", msg);
+                            context
+                                .health
+                                .not_ready(ReadyCheck::Registered, msg.clone());
+                            self.callback.error(ErrorInfo::new_msg(code, msg));
+                            return Ok(());
+                        }
+                        // 使用服务端分配的地址
+                        log::warn!("{},改为使用服务端分配的{}", msg, virtual_ip);
+                        self.requested_ip.store(None);
+                        self.callback.error(ErrorInfo::new_msg(
+                            ErrorType::Unknown,
+                            format!("{}, falling back to {}", msg, virtual_ip),
+                        ));
+                    }
+                }
                 let register_info = RegisterInfo::new(virtual_ip, virtual_netmask, virtual_gateway);
                 log::info!("注册成功：{:?}", register_info);
                 if self.callback.register(register_info) {
@@ -297,6 +421,7 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                             break;
                         }
                     }
+                    context.health.ready(ReadyCheck::Registered);
 
                     if old.virtual_ip != virtual_ip
                         || old.virtual_gateway != virtual_gateway
@@ -306,7 +431,7 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                             log::info!("ip发生变化,old:{:?},response={:?}", old, response);
                         }
                         #[cfg(target_os = "android")]
-                        {
+                        if !self.config_info.observer {
                             let device_config = crate::handle::callback::DeviceConfig::new(
                                 virtual_ip,
                                 virtual_netmask,
@@ -316,90 +441,157 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                             );
                             let device_fd = self.callback.generate_tun(device_config);
                             if device_fd == 0 {
+                                log::error!("创建网卡失败 device_fd == 0");
                                 self.callback.error(ErrorInfo::new_msg(
                                     ErrorType::Unknown,
                                     "device_fd == 0".into(),
                                 ));
                             } else {
                                 if let Err(e) = self.device.start(device_fd as _) {
+                                    context
+                                        .health
+                                        .not_ready(ReadyCheck::Tun, format!("{:?}", e));
+                                    log::error!("启动网卡失败 {:?}", e);
                                     self.callback.error(ErrorInfo::new_msg(
                                         ErrorType::Unknown,
                                         format!("{:?}", e),
                                     ));
+                                } else {
+                                    self.tun_ready(context, virtual_ip, virtual_gateway);
                                 }
                             }
                         }
+                        #[cfg(target_os = "linux")]
+                        if self.config_info.existing_tun {
+                            self.setup_existing_tun(
+                                context,
+                                virtual_ip,
+                                virtual_netmask,
+                                virtual_network,
+                            );
+                            self.tun_ready(context, virtual_ip, virtual_gateway);
+                        }
+                        // 使用预先创建的网卡时不安装路由，观察者没有网卡
                         #[cfg(not(target_os = "android"))]
+                        if let Some(device) =
+                            self.device.tun().filter(|_| !self.config_info.existing_tun)
                         {
-                            if let Err(e) = self.device.set_ip(virtual_ip, virtual_netmask) {
+                            let current_ip = if old.virtual_ip != Ipv4Addr::UNSPECIFIED {
+                                Some((old.virtual_ip, old.virtual_netmask))
+                            } else {
+                                None
+                            };
+                            let mut guard = context.tun_routes.lock();
+                            let mut setup = TunSetup::new(&**device, current_ip)
+                                .with_log(context.intent_log.clone());
+                            for (dest, mask) in guard.iter() {
+                                if let Err(e) = setup.delete_route(*dest, *mask, 1) {
+                                    log::warn!("删除路由失败 ={:?}", e);
+                                }
+                            }
+                            if let Err(e) = setup.set_ip(virtual_ip, virtual_netmask) {
+                                // setup被drop时回滚
                                 log::error!("LocalIpExists {:?}", e);
+                                context
+                                    .health
+                                    .not_ready(ReadyCheck::Tun, format!("set_ip {:?}", e));
                                 self.callback.error(ErrorInfo::new_msg(
                                     ErrorType::LocalIpExists,
                                     format!("set_ip {:?}", e),
                                 ));
                                 return Ok(());
                             }
-                            let mut guard = self.route_record.lock();
-                            for (dest, mask) in guard.drain(..) {
-                                if let Err(e) = self.device.delete_route(dest, mask) {
-                                    log::warn!("删除路由失败 ={:?}", e);
-                                }
-                            }
-                            if let Err(e) =
-                                self.device.add_route(virtual_network, virtual_netmask, 1)
-                            {
-                                log::warn!("添加默认路由失败 ={:?}", e);
-                            } else {
-                                guard.push((virtual_network, virtual_netmask));
-                            }
-                            if let Err(e) =
-                                self.device
-                                    .add_route(Ipv4Addr::BROADCAST, Ipv4Addr::BROADCAST, 1)
-                            {
-                                log::warn!("添加广播路由失败 ={:?}", e);
-                            } else {
-                                guard.push((Ipv4Addr::BROADCAST, Ipv4Addr::BROADCAST));
+                            if let Err(e) = setup.add_route(virtual_network, virtual_netmask, 1) {
+                                log::error!("添加默认路由失败 ={:?}", e);
+                                context
+                                    .health
+                                    .not_ready(ReadyCheck::Tun, format!("add_route {:?}", e));
+                                self.callback.error(ErrorInfo::new_msg(
+                                    ErrorType::Unknown,
+                                    format!("add_route {:?}", e),
+                                ));
+                                return Ok(());
                             }
-
-                            if let Err(e) = self.device.add_route(
+                            setup.try_add_route(Ipv4Addr::BROADCAST, Ipv4Addr::BROADCAST, 1);
+                            setup.try_add_route(
                                 Ipv4Addr::from([224, 0, 0, 0]),
                                 Ipv4Addr::from([240, 0, 0, 0]),
                                 1,
-                            ) {
-                                log::warn!("添加组播路由失败 ={:?}", e);
-                            } else {
-                                guard.push((
-                                    Ipv4Addr::from([224, 0, 0, 0]),
-                                    Ipv4Addr::from([240, 0, 0, 0]),
-                                ));
-                            }
-
+                            );
                             for (dest, mask) in self.external_route.to_route() {
-                                if let Err(e) = self.device.add_route(dest, mask, 1) {
-                                    log::warn!("添加路由失败 ={:?}", e);
-                                } else {
-                                    guard.push((dest, mask));
+                                setup.try_add_route(dest, mask, 1);
+                            }
+                            *guard = setup.commit();
+                            self.tun_ready(context, virtual_ip, virtual_gateway);
+                            // 只影响没有绑定地址的程序，失败时由网卡读取时的源地址检查兜底
+                            for (dest, mask) in guard.iter() {
+                                if let Err(e) = device.set_source_hint(*dest, *mask, virtual_ip) {
+                                    log::debug!("设置路由源地址失败 {}/{} {:?}", dest, mask, e);
                                 }
                             }
                         }
                     }
-                    self.set_device_info_list(response.device_info_list, response.epoch as _);
+                    // 重新注册后服务端的纪元重新开始，不和之前的比较
+                    self.directory.clear();
+                    self.device_list.lock().0 = 0;
+                    // ping和pong里只有16位的纪元
+                    self.directory
+                        .submit(response.epoch as u16, response.device_info_list);
+                    // 重新注册后以服务端当前的公告为准，没有公告时清除之前的
+                    match response.notice.as_ref() {
+                        Some(notice) => self.set_notice(notice),
+                        None => self.notice.clear(),
+                    }
                     if old.status.offline() {
                         self.callback.success();
                     }
                 }
             }
-            service_packet::Protocol::PushDeviceList => {
-                let response = DeviceList::parse_from_bytes(net_packet.payload()).map_err(|e| {
-                    io::Error::new(io::ErrorKind::Other, format!("PushDeviceList {:?}", e))
-                })?;
-                self.set_device_info_list(response.device_info_list, response.epoch as _);
+            protocol @ (service_packet::Protocol::PushDeviceList
+            | service_packet::Protocol::PushDeviceListCompact) => {
+                // 两种编码解出相同的结构，设备数上限在Directory中统一检查
+                let encoding = Encoding::of_device_list(protocol).unwrap_or_default();
+                let response = match encoding.codec().decode_device_list(net_packet.payload()) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let epoch = self.device_list.lock().0;
+                        self.directory
+                            .malformed(epoch, &format!("{:?} {:?}", protocol, e));
+                        return Ok(());
+                    }
+                };
+                self.directory
+                    .submit(response.epoch as u16, response.device_info_list);
+                if let Some(notice) = response.notice.as_ref() {
+                    self.set_notice(notice);
+                }
             }
             service_packet::Protocol::SecretHandshakeResponse => {
                 log::info!("SecretHandshakeResponse");
                 //加密握手结束，发送注册数据
                 self.register(current_device, context)?;
             }
+            service_packet::Protocol::ServerAction => {
+                let request = match ServerAction::parse_from_bytes(net_packet.payload()) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        log::warn!("ServerAction {:?}", e);
+                        return Ok(());
+                    }
+                };
+                // 接受的请求由维护任务执行后回应，拒绝的立即回应
+                if let Err((status, reason)) = context.server_actions.receive(&request) {
+                    crate::handle::maintain::send_ack(
+                        context,
+                        current_device,
+                        &self.server_cipher,
+                        request.id,
+                        &request.action,
+                        status,
+                        &reason,
+                    )?;
+                }
+            }
             _ => {
                 log::warn!(
                     "service_packet::Protocol::Unknown = {:?}",
@@ -409,31 +601,65 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
         }
         Ok(())
     }
-    fn set_device_info_list(&self, device_info_list: Vec<proto::message::DeviceInfo>, epoch: u16) {
-        let ip_list: Vec<PeerDeviceInfo> = device_info_list
-            .into_iter()
-            .map(|info| {
-                PeerDeviceInfo::new(
-                    Ipv4Addr::from(info.virtual_ip),
-                    info.name,
-                    info.device_status as u8,
-                    info.client_secret,
-                    info.client_secret_hash,
-                )
-            })
-            .collect();
-        {
-            let mut dev = self.device_list.lock();
-            //这里可能会收到旧的消息，但是随着时间推移总会收到新的
-            dev.0 = epoch;
-            dev.1 = ip_list.clone();
+    /// 使用预先创建的网卡时，只在有权限时设置ip，路由由管理员配置
+    #[cfg(target_os = "linux")]
+    fn setup_existing_tun(
+        &self,
+        context: &ChannelContext,
+        virtual_ip: Ipv4Addr,
+        virtual_netmask: Ipv4Addr,
+        virtual_network: Ipv4Addr,
+    ) {
+        let device = match self.device.tun() {
+            Some(device) => device,
+            None => return,
+        };
+        let name = device.name().unwrap_or_default();
+        let mut missing_ip = None;
+        match device.ip() {
+            Ok(ip) if ip == (virtual_ip, virtual_netmask) => {}
+            current => {
+                let rs = context
+                    .intent_log
+                    .run(Intent::SetIp(virtual_ip, virtual_netmask), || {
+                        device.set_ip(virtual_ip, virtual_netmask)
+                    });
+                if let Err(e) = rs {
+                    log::warn!(
+                        "设置网卡ip失败,当前{:?},需要{}/{} {:?}",
+                        current,
+                        virtual_ip,
+                        virtual_netmask,
+                        e
+                    );
+                    missing_ip = Some((virtual_ip, virtual_netmask));
+                }
+            }
+        }
+        let mut routes = vec![(virtual_network, virtual_netmask)];
+        routes.extend(self.external_route.to_route());
+        let commands =
+            crate::tun_tap_device::existing_tun::setup_commands(&name, missing_ip, &routes);
+        log::warn!("使用已有网卡,不安装路由,需要管理员执行 {:?}", commands);
+        self.callback.error(ErrorInfo::new_msg(
+            ErrorType::Unknown,
+            format!(
+                "existing tun {}, routes are not installed, the administrator must run:\n  {}",
+                name,
+                commands.join("\n  ")
+            ),
+        ));
+    }
+    /// 网卡配置好地址后检查校验和策略
+    fn tun_ready(&self, context: &ChannelContext, virtual_ip: Ipv4Addr, gateway: Ipv4Addr) {
+        context.health.ready(ReadyCheck::Tun);
+        crate::handle::checksum::detect(context, &self.device, virtual_ip, gateway);
+    }
+    fn set_notice(&self, notice: &ServerNotice) {
+        if let Some(info) = self.notice.update(notice) {
+            log::info!("服务端公告:{:?}", info);
+            self.callback.notice(info);
         }
-        self.callback.peer_client_list(
-            ip_list
-                .into_iter()
-                .map(|v| PeerClientInfo::new(v.virtual_ip, v.name, v.status, v.client_secret))
-                .collect(),
-        );
     }
     fn register(
         &self,
@@ -452,7 +678,7 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             .client_secret_hash
             .as_ref()
             .map(|v| v.as_ref());
-        let mut ip = self.config_info.ip;
+        let mut ip = self.requested_ip.load();
         if ip.is_none() {
             ip = Some(current_device.virtual_ip)
         }
@@ -465,29 +691,71 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             false,
             false,
             client_secret,
+            self.config_info.observer,
+            self.config_info.fingerprint.clone(),
+            self.nat_test.nat_class(),
         )?;
         log::info!("发送注册请求，{:?}", self.config_info);
         //注册请求只发送到默认通道
         context.send_default(response.buffer(), current_device.connect_server)
     }
+    /// 服务端拒绝了注册的ip，开启了'--ip-fallback'时改为自动分配并重新注册
+    fn ip_refused(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        code: ErrorType,
+        reason: &str,
+    ) -> io::Result<()> {
+        let requested = self.requested_ip.load();
+        let msg = match requested {
+            Some(ip) => registrar::requested_ip_error(code, ip),
+            None => reason.to_string(),
+        };
+        if requested.is_some() && self.config_info.ip_fallback {
+            log::warn!("{},改为由服务端分配", msg);
+            self.requested_ip.store(None);
+            self.callback.error(ErrorInfo::new_msg(
+                ErrorType::Unknown,
+                format!("{}, falling back to automatic assignment", msg),
+            ));
+            return self.register(current_device, context);
+        }
+        context
+            .health
+            .not_ready(ReadyCheck::Registered, msg.clone());
+        log::error!("{}", msg);
+        self.callback.error(ErrorInfo::new_msg(code, msg));
+        Ok(())
+    }
     fn error(
         &self,
         context: &ChannelContext,
-        _current_device: &CurrentDeviceInfo,
+        current_device: &CurrentDeviceInfo,
         net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
     ) -> io::Result<()> {
         match InErrorPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
             InErrorPacket::TokenError => {
                 // token错误，可能是服务端设置了白名单
+                context
+                    .health
+                    .not_ready(ReadyCheck::Registered, "token error");
+                log::error!("token错误");
                 let err = ErrorInfo::new(ErrorType::TokenError);
                 self.callback.error(err);
             }
             InErrorPacket::Disconnect => {
                 crate::handle::change_status(&self.current_device, ConnectStatus::Connecting);
+                context
+                    .health
+                    .not_ready(ReadyCheck::Registered, "disconnected by server");
+                log::warn!("服务端断开连接");
                 let err = ErrorInfo::new(ErrorType::Disconnect);
                 self.callback.error(err);
                 //掉线epoch要归零
+                self.directory.clear();
+                self.notice.clear();
                 {
                     let mut dev = self.device_list.lock();
                     dev.0 = 0;
@@ -499,20 +767,33 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             }
             InErrorPacket::AddressExhausted => {
                 // 地址用尽
+                context
+                    .health
+                    .not_ready(ReadyCheck::Registered, "address exhausted");
+                log::error!("地址用尽");
                 let err = ErrorInfo::new(ErrorType::AddressExhausted);
                 self.callback.error(err);
             }
             InErrorPacket::OtherError(e) => {
-                let err = ErrorInfo::new_msg(ErrorType::Unknown, e.message()?);
+                let message = e.message()?;
+                context.health.not_ready(
+                    ReadyCheck::Registered,
+                    sanitize::text(&message, sanitize::NOTICE_MAX_LEN, false),
+                );
+                log::error!("服务端错误 {:?}", message);
+                let err = ErrorInfo::new_msg(ErrorType::Unknown, message);
                 self.callback.error(err);
             }
             InErrorPacket::IpAlreadyExists => {
-                let err = ErrorInfo::new(ErrorType::IpAlreadyExists);
-                self.callback.error(err);
+                self.ip_refused(
+                    context,
+                    current_device,
+                    ErrorType::IpAlreadyExists,
+                    "ip already exists",
+                )?;
             }
             InErrorPacket::InvalidIp => {
-                let err = ErrorInfo::new(ErrorType::InvalidIp);
-                self.callback.error(err);
+                self.ip_refused(context, current_device, ErrorType::InvalidIp, "invalid ip")?;
             }
             InErrorPacket::NoKey => {
                 //这个类型最开头已经处理过，这里忽略
@@ -530,11 +811,32 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
         match ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
             ControlPacket::PongPacket(pong_packet) => {
                 let current_time = crate::handle::now_time() as u16;
-                if current_time < pong_packet.time() {
-                    return Ok(());
-                }
+                let rt = match rtt_ms(current_time, pong_packet.time()) {
+                    Some(rt) => rt as i64,
+                    None => {
+                        context.stale_pong.record(format_args!(
+                            "{:?} now={} time={}",
+                            route_key.addr,
+                            current_time,
+                            pong_packet.time()
+                        ));
+                        return Ok(());
+                    }
+                };
+                // 接收时已经丢弃了ttl大于source_ttl的包
                 let metric = net_packet.source_ttl() - net_packet.ttl() + 1;
-                let rt = (current_time - pong_packet.time()) as i64;
+                context.server_rt.set(rt);
+                context.rendezvous.record_rtt(&route_key.addr, rt as u32);
+                context.health.ready(ReadyCheck::DataPath);
+                context.server_rt_histogram.observe(rt as u64);
+                if route_key.addr == current_device.connect_server
+                    && pong_packet.echo_seq().is_none()
+                {
+                    // 旧版本的服务端不带时间，不协调打洞时刻
+                    if let Ok(clock) = ServerClock::parse_from_bytes(pong_packet.extension()) {
+                        context.punch_sync.sample(clock.server_time_ms, rt as u32);
+                    }
+                }
                 let route = Route::from(route_key, metric, rt);
                 context.route_table.add_route(net_packet.source(), route);
                 let epoch = self.device_list.lock().0;
@@ -0,0 +1,79 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
+use crate::handle::CurrentDeviceInfo;
+use crate::proto::message::RegistrationResponse;
+use crate::protocol::control_packet::ControlPacket;
+use crate::protocol::{service_packet, NetPacket, Protocol};
+use crate::util::serial::rtt_ms;
+
+/// 备用服务器发来的服务端包，只处理注册结果和心跳回应，其他的忽略，不影响主服务器上的状态
+pub fn handle(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    net_packet: NetPacket<&mut [u8]>,
+    route_key: RouteKey,
+) -> io::Result<()> {
+    let addr = route_key.addr;
+    match net_packet.protocol() {
+        Protocol::Service => {
+            if net_packet.transport_protocol()
+                != service_packet::Protocol::RegistrationResponse.into()
+            {
+                return Ok(());
+            }
+            let response =
+                RegistrationResponse::parse_from_bytes(net_packet.payload()).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("RegistrationResponse {:?}", e),
+                    )
+                })?;
+            let ip = Ipv4Addr::from(response.virtual_ip);
+            if ip == current_device.virtual_ip {
+                log::info!("备用服务器{}注册成功,用于打洞协商", addr);
+                context.rendezvous.set_registered(&addr, true);
+            } else {
+                // 不会向这个服务器发送心跳，注册随后超时
+                log::warn!(
+                    "备用服务器{}分配了不同的ip {},当前{},不使用",
+                    addr,
+                    ip,
+                    current_device.virtual_ip
+                );
+                context.rendezvous.set_registered(&addr, false);
+            }
+        }
+        Protocol::Control => {
+            if let ControlPacket::PongPacket(pong_packet) =
+                ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())?
+            {
+                let current_time = crate::handle::now_time() as u16;
+                match rtt_ms(current_time, pong_packet.time()) {
+                    Some(rt) => context.rendezvous.record_rtt(&addr, rt as u32),
+                    None => context.stale_pong.record(format_args!(
+                        "{} now={} time={}",
+                        addr,
+                        current_time,
+                        pong_packet.time()
+                    )),
+                }
+            }
+        }
+        Protocol::Error => {
+            // 未注册、ip冲突等，下一轮重新注册
+            log::debug!(
+                "备用服务器{}返回错误 {:?}",
+                addr,
+                net_packet.transport_protocol()
+            );
+            context.rendezvous.set_registered(&addr, false);
+        }
+        _ => {}
+    }
+    Ok(())
+}
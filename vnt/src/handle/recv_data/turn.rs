@@ -1,4 +1,5 @@
 use crate::channel::context::ChannelContext;
+use crate::channel::drop_reason::DropReason;
 use crate::channel::RouteKey;
 use crate::handle::recv_data::PacketHandler;
 use crate::handle::CurrentDeviceInfo;
@@ -30,6 +31,7 @@ impl PacketHandler for TurnPacketHandler {
                 if route.addr == route_key.addr {
                     //防止环路
                     log::warn!("来源和目标相同 {:?},{:?}", route_key, net_packet.head());
+                    context.drop_stats.add_peer(DropReason::Loop, destination);
                     return Ok(());
                 }
                 if route.metric <= ttl {
@@ -37,8 +39,16 @@ impl PacketHandler for TurnPacketHandler {
                 }
             }
             //其他没有路由的不转发
+            log::info!("没有路由 {:?},{:?}", route_key, net_packet.head());
+            context
+                .drop_stats
+                .add_peer(DropReason::NoRoute, destination);
+        } else {
+            log::info!("ttl耗尽 {:?},{:?}", route_key, net_packet.head());
+            context
+                .drop_stats
+                .add_peer(DropReason::TtlExpired, net_packet.source());
         }
-        log::info!("没有路由 {:?},{:?}", route_key, net_packet.head());
         Ok(())
     }
 }
@@ -3,11 +3,14 @@ use std::net::Ipv4Addr;
 
 use protobuf::Message;
 
+use crate::channel::punch::NatClass;
 use crate::cipher::Cipher;
+use crate::handle::callback::ErrorType;
 use crate::handle::{GATEWAY_IP, SELF_IP};
 use crate::proto::message::RegistrationRequest;
 use crate::protocol::body::ENCRYPTION_RESERVED;
 use crate::protocol::{service_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::subnet::Subnet;
 
 /// 注册数据
 pub fn registration_request_packet(
@@ -19,6 +22,9 @@ pub fn registration_request_packet(
     is_fast: bool,
     allow_ip_change: bool,
     client_secret_hash: Option<&[u8]>,
+    observer: bool,
+    fingerprint: String,
+    nat_class: NatClass,
 ) -> io::Result<NetPacket<Vec<u8>>> {
     let mut request = RegistrationRequest::new();
     request.token = token;
@@ -30,6 +36,9 @@ pub fn registration_request_packet(
     request.allow_ip_change = allow_ip_change;
     request.is_fast = is_fast;
     request.version = crate::VNT_VERSION.to_string();
+    request.observer = observer;
+    request.fingerprint = fingerprint;
+    request.nat_class = protobuf::EnumOrUnknown::new(nat_class.into());
     if let Some(client_secret_hash) = client_secret_hash {
         request.client_secret = true;
         request
@@ -52,3 +61,93 @@ pub fn registration_request_packet(
     server_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
+
+/// 检查服务端是否按指定的ip注册，不符合时返回错误类型和说明
+pub fn check_requested_ip(
+    requested: Ipv4Addr,
+    assigned: Ipv4Addr,
+    netmask: Ipv4Addr,
+    gateway: Ipv4Addr,
+) -> Result<(), (ErrorType, String)> {
+    let mask = u32::from(netmask);
+    let network = Ipv4Addr::from(u32::from(gateway) & mask);
+    if u32::from(requested) & mask != u32::from(network) {
+        return Err((
+            ErrorType::InvalidIp,
+            format!("ip {} out of range {}/{}", requested, network, netmask),
+        ));
+    }
+    if let Err(e) = Subnet::new(requested, netmask).check_host(requested) {
+        return Err((
+            ErrorType::InvalidIp,
+            format!("ip {} out of range: {}", requested, e),
+        ));
+    }
+    if requested != assigned {
+        return Err((
+            ErrorType::IpAlreadyExists,
+            format!(
+                "ip {} already taken, the server assigned {}",
+                requested, assigned
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// 服务端拒绝指定的ip时的说明
+pub fn requested_ip_error(code: ErrorType, requested: Ipv4Addr) -> String {
+    match code {
+        ErrorType::IpAlreadyExists => format!("ip {} already taken", requested),
+        _ => format!("ip {} out of range", requested),
+    }
+}
+
+/// 退出时的注销数据，没有内容，服务端按来源识别设备
+pub fn deregistration_packet(
+    server_cipher: &Cipher,
+    virtual_ip: Ipv4Addr,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let buf = vec![0u8; 12 + ENCRYPTION_RESERVED];
+    let mut net_packet = NetPacket::new_encrypt(buf)?;
+    net_packet.set_destination(GATEWAY_IP);
+    net_packet.set_source(virtual_ip);
+    net_packet.set_default_version();
+    net_packet.set_gateway_flag(true);
+    net_packet.set_protocol(Protocol::Service);
+    net_packet.set_transport_protocol(service_packet::Protocol::Deregistration.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    server_cipher.encrypt_ipv4(&mut net_packet)?;
+    Ok(net_packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::handle::callback::ErrorType;
+    use crate::handle::registrar::check_requested_ip;
+
+    #[test]
+    fn test_check_requested_ip() {
+        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        let gateway = Ipv4Addr::new(10, 26, 0, 1);
+        let ip = Ipv4Addr::new(10, 26, 0, 5);
+        assert!(check_requested_ip(ip, ip, netmask, gateway).is_ok());
+        let (code, msg) =
+            check_requested_ip(ip, Ipv4Addr::new(10, 26, 0, 6), netmask, gateway).unwrap_err();
+        assert_eq!(code, ErrorType::IpAlreadyExists);
+        assert_eq!(
+            msg,
+            "ip 10.26.0.5 already taken, the server assigned 10.26.0.6"
+        );
+        let other = Ipv4Addr::new(10, 27, 0, 5);
+        let (code, msg) = check_requested_ip(other, ip, netmask, gateway).unwrap_err();
+        assert_eq!(code, ErrorType::InvalidIp);
+        assert_eq!(msg, "ip 10.27.0.5 out of range 10.26.0.0/255.255.255.0");
+        // 广播地址不能作为本机地址
+        let broadcast = Ipv4Addr::new(10, 26, 0, 255);
+        let (code, _) = check_requested_ip(broadcast, broadcast, netmask, gateway).unwrap_err();
+        assert_eq!(code, ErrorType::InvalidIp);
+    }
+}
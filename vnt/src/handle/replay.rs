@@ -0,0 +1,398 @@
+//! 回放抓包文件，用于复现数据处理的问题
+//!
+//! 入站的外层数据包按照 `RecvDataHandler` 的顺序解码、检查ttl、解密，写网卡的数据由模拟网卡记录；
+//! 网卡读到的ip包按照 `tun_handler` 的顺序封装、加密，发送的数据由模拟socket记录。
+//! 接收处理和网卡处理直接持有网卡和socket，替换之前这里只覆盖两者共用的解码、加解密部分，
+//! 服务端包、控制包只计数不处理。
+//!
+//! 输出的摘要只和输入及处理逻辑有关，可以在不同版本之间对比。
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use packet::ip::ipv4::packet::IpV4Packet;
+
+use crate::channel::drop_reason::DropReason;
+use crate::cipher::Cipher;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{compat, ip_turn_packet, NetPacket, Protocol};
+use crate::util::capture::{CaptureReader, Direction, Record};
+use crate::util::subnet::{Destination, Subnet};
+
+pub struct ReplayConfig {
+    pub virtual_ip: Ipv4Addr,
+    pub virtual_netmask: Ipv4Addr,
+    pub virtual_gateway: Ipv4Addr,
+    pub client_cipher: Cipher,
+    /// 按照抓包时的间隔回放，false则尽快回放
+    pub timing: bool,
+}
+
+impl ReplayConfig {
+    fn subnet(&self) -> Subnet {
+        Subnet::new(self.virtual_ip, self.virtual_netmask)
+    }
+}
+
+/// 回放结果
+#[derive(Default, Debug)]
+pub struct Summary {
+    pub records: u64,
+    /// 每种处理结果的数量
+    pub counts: BTreeMap<&'static str, u64>,
+    /// 写网卡和发送的明文数据的摘要(FNV-1a)，密文和加密方式有关所以不参与计算
+    pub digest: u64,
+    /// 违反约束的记录，正常应该为空
+    pub violations: Vec<String>,
+}
+
+impl Summary {
+    fn count(&mut self, key: &'static str) {
+        *self.counts.entry(key).or_insert(0) += 1;
+    }
+    fn drop(&mut self, reason: DropReason) {
+        self.count(reason.name());
+    }
+    fn digest(&mut self, data: &[u8]) {
+        if self.digest == 0 {
+            self.digest = 0xcbf29ce484222325;
+        }
+        for b in data {
+            self.digest ^= *b as u64;
+            self.digest = self.digest.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "records {}", self.records)?;
+        for (k, v) in &self.counts {
+            writeln!(f, "{} {}", k, v)?;
+        }
+        writeln!(f, "digest {:016x}", self.digest)?;
+        for v in &self.violations {
+            writeln!(f, "violation {}", v)?;
+        }
+        Ok(())
+    }
+}
+
+/// 模拟网卡，检查写入的是完整的ipv4包
+struct MockTun;
+
+impl MockTun {
+    fn write(&self, summary: &mut Summary, index: u64, buf: &[u8]) {
+        match IpV4Packet::new(buf) {
+            Ok(ipv4) if ipv4.length() as usize == buf.len() => {
+                summary.count("tun_write");
+                summary.digest(buf);
+            }
+            Ok(ipv4) => summary.violations.push(format!(
+                "#{} tun write length {} != ip length {}",
+                index,
+                buf.len(),
+                ipv4.length()
+            )),
+            Err(e) => summary
+                .violations
+                .push(format!("#{} tun write malformed {}", index, e)),
+        }
+    }
+}
+
+/// 模拟socket，检查发出的包对端能解析，并且解密后和加密前一致
+struct MockSocket<'a> {
+    cipher: &'a Cipher,
+}
+
+impl MockSocket<'_> {
+    fn send(&self, summary: &mut Summary, index: u64, buf: &[u8], plain: &[u8]) {
+        let mut copy = buf.to_vec();
+        copy.resize(buf.len() + ENCRYPTION_RESERVED, 0);
+        let rs = NetPacket::new0(buf.len(), &mut copy[..])
+            .and_then(|mut packet| self.cipher.decrypt_ipv4(&mut packet).map(|_| packet));
+        match rs {
+            Ok(packet) if packet.buffer() == plain => {
+                summary.count("send");
+                summary.digest(plain);
+            }
+            Ok(_) => summary
+                .violations
+                .push(format!("#{} send does not decrypt to the original", index)),
+            Err(e) => summary
+                .violations
+                .push(format!("#{} send malformed {}", index, e)),
+        }
+    }
+}
+
+pub fn replay<R: Read>(mut reader: CaptureReader<R>, config: &ReplayConfig) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let tun = MockTun;
+    let socket = MockSocket {
+        cipher: &config.client_cipher,
+    };
+    let start = Instant::now();
+    while let Some(record) = reader.next_record()? {
+        if config.timing {
+            let at = Duration::from_micros(record.time_us);
+            if let Some(wait) = at.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        let index = summary.records;
+        summary.records += 1;
+        let rs = std::panic::catch_unwind(AssertUnwindSafe(|| match record.direction {
+            Direction::Inbound => inbound(&mut summary, config, &tun, index, record),
+            Direction::Outbound => outbound(&mut summary, config, &socket, index, record),
+        }));
+        if rs.is_err() {
+            summary.violations.push(format!("#{} panic", index));
+        }
+    }
+    Ok(summary)
+}
+
+fn inbound(
+    summary: &mut Summary,
+    config: &ReplayConfig,
+    tun: &MockTun,
+    index: u64,
+    record: Record,
+) {
+    let mut buf = record.data;
+    let len = match compat::decode_in_place(&mut buf) {
+        Ok(len) => len,
+        Err(_) => return summary.drop(DropReason::Malformed),
+    };
+    let mut net_packet = match NetPacket::new(&mut buf[..len]) {
+        Ok(net_packet) => net_packet,
+        Err(_) => return summary.drop(DropReason::Malformed),
+    };
+    if net_packet.ttl() == 0 || net_packet.source_ttl() < net_packet.ttl() {
+        return summary.drop(DropReason::TtlExpired);
+    }
+    let dest = net_packet.destination();
+    if !(dest == config.virtual_ip
+        || dest.is_multicast()
+        || dest.is_unspecified()
+        || config.subnet().is_reserved(dest))
+    {
+        return summary.count("forward");
+    }
+    if net_packet.is_gateway() {
+        return summary.count("server");
+    }
+    match net_packet.protocol() {
+        Protocol::IpTurn => {}
+        Protocol::Control => return summary.count("control"),
+        _ => return summary.count("other"),
+    }
+    if config.client_cipher.decrypt_ipv4(&mut net_packet).is_err() {
+        return summary.drop(DropReason::DecryptFailed);
+    }
+    if ip_turn_packet::Protocol::from(net_packet.transport_protocol())
+        != ip_turn_packet::Protocol::Ipv4
+    {
+        return summary.count("other");
+    }
+    if IpV4Packet::new(net_packet.payload()).is_err() {
+        return summary.drop(DropReason::Malformed);
+    }
+    tun.write(summary, index, net_packet.payload());
+}
+
+fn outbound(
+    summary: &mut Summary,
+    config: &ReplayConfig,
+    socket: &MockSocket,
+    index: u64,
+    record: Record,
+) {
+    let data_len = 12 + record.data.len();
+    let mut buf = vec![0u8; data_len + ENCRYPTION_RESERVED];
+    buf[12..data_len].copy_from_slice(&record.data);
+    let (src_ip, dest_ip) = match IpV4Packet::new(&buf[12..data_len]) {
+        Ok(ipv4) => (ipv4.source_ip(), ipv4.destination_ip()),
+        Err(_) => return summary.drop(DropReason::Malformed),
+    };
+    let mut net_packet = match NetPacket::new0(data_len, &mut buf[..]) {
+        Ok(net_packet) => net_packet,
+        Err(_) => return summary.drop(DropReason::Malformed),
+    };
+    net_packet.set_default_version();
+    net_packet.set_protocol(Protocol::IpTurn);
+    net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+    net_packet.first_set_ttl(6);
+    net_packet.set_source(src_ip);
+    net_packet.set_destination(dest_ip);
+    if dest_ip == config.virtual_gateway {
+        return summary.count("gateway");
+    }
+    let destination = config.subnet().classify(dest_ip);
+    if destination == Destination::Multicast {
+        net_packet.set_destination(Ipv4Addr::BROADCAST);
+    }
+    if destination == Destination::External {
+        // 外部路由依赖运行时配置，这里当作没有路由
+        return summary.drop(DropReason::NoRoute);
+    }
+    let plain = net_packet.buffer().to_vec();
+    if let Err(e) = config.client_cipher.encrypt_ipv4(&mut net_packet) {
+        return summary
+            .violations
+            .push(format!("#{} encrypt failed {}", index, e));
+    }
+    socket.send(summary, index, net_packet.buffer(), &plain);
+}
+
+#[cfg(all(test, any(feature = "aes_gcm", feature = "server_encrypt")))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::cipher::{Cipher, CipherModel};
+    use crate::protocol::body::ENCRYPTION_RESERVED;
+    use crate::protocol::{ip_turn_packet, NetPacket, Protocol};
+    use crate::util::capture::{CaptureReader, CaptureWriter, Record};
+
+    use super::{replay, ReplayConfig};
+
+    fn config(cipher: Cipher) -> ReplayConfig {
+        ReplayConfig {
+            virtual_ip: Ipv4Addr::new(10, 26, 0, 2),
+            virtual_netmask: Ipv4Addr::new(255, 255, 255, 0),
+            virtual_gateway: Ipv4Addr::new(10, 26, 0, 1),
+            client_cipher: cipher,
+            timing: false,
+        }
+    }
+
+    fn ipv4(src: Ipv4Addr, dest: Ipv4Addr) -> Vec<u8> {
+        let mut buf = vec![0u8; 28];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&28u16.to_be_bytes());
+        buf[8] = 64;
+        buf[9] = 17;
+        buf[12..16].copy_from_slice(&src.octets());
+        buf[16..20].copy_from_slice(&dest.octets());
+        buf
+    }
+
+    /// 对端发来的加密数据包
+    fn peer_packet(cipher: &Cipher, src: Ipv4Addr, dest: Ipv4Addr) -> Vec<u8> {
+        let ip = ipv4(src, dest);
+        let data_len = 12 + ip.len();
+        let mut buf = vec![0u8; data_len + ENCRYPTION_RESERVED];
+        buf[12..data_len].copy_from_slice(&ip);
+        let mut net_packet = NetPacket::new0(data_len, &mut buf[..]).unwrap();
+        net_packet.set_default_version();
+        net_packet.set_protocol(Protocol::IpTurn);
+        net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+        net_packet.first_set_ttl(6);
+        net_packet.set_source(src);
+        net_packet.set_destination(dest);
+        cipher.encrypt_ipv4(&mut net_packet).unwrap();
+        net_packet.buffer().to_vec()
+    }
+
+    /// 一段包含正常、损坏、其他密码的会话
+    fn session(cipher: &Cipher, other: &Cipher) -> Vec<u8> {
+        let me = Ipv4Addr::new(10, 26, 0, 2);
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        let addr = "192.168.1.3:29872".parse().unwrap();
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        let records = vec![
+            Record::outbound(0, ipv4(me, peer)),
+            Record::inbound(100, addr, peer_packet(cipher, peer, me)),
+            Record::inbound(200, addr, vec![1, 2, 3]),
+            Record::inbound(300, addr, peer_packet(other, peer, me)),
+            Record::outbound(400, ipv4(me, Ipv4Addr::new(8, 8, 8, 8))),
+            Record::outbound(500, ipv4(me, Ipv4Addr::new(224, 0, 0, 251))),
+            Record::outbound(600, vec![0x45; 10]),
+        ];
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_replay_session() {
+        let cipher = Cipher::new_password(
+            CipherModel::AesGcm,
+            Some("password".to_string()),
+            Some("token".to_string()),
+        );
+        let other = Cipher::new_password(
+            CipherModel::AesGcm,
+            Some("other-password".to_string()),
+            Some("token".to_string()),
+        );
+        let capture = session(&cipher, &other);
+        let summary = replay(CaptureReader::new(&capture[..]).unwrap(), &config(cipher)).unwrap();
+        assert!(summary.violations.is_empty(), "{}", summary);
+        assert_eq!(summary.records, 7);
+        assert_eq!(summary.counts.get("send"), Some(&2));
+        assert_eq!(summary.counts.get("tun_write"), Some(&1));
+        assert_eq!(summary.counts.get("malformed"), Some(&2));
+        assert_eq!(summary.counts.get("decrypt_failed"), Some(&1));
+        assert_eq!(summary.counts.get("no_route"), Some(&1));
+        // 相同的输入得到相同的摘要
+        let cipher = Cipher::new_password(
+            CipherModel::AesGcm,
+            Some("password".to_string()),
+            Some("token".to_string()),
+        );
+        let again = replay(
+            CaptureReader::new(&session(&cipher, &other)[..]).unwrap(),
+            &config(cipher),
+        )
+        .unwrap();
+        assert_eq!(summary.to_string(), again.to_string());
+    }
+}
+
+/// 提交到仓库的抓包，处理逻辑变化导致摘要不同时需要确认后更新.summary文件
+#[cfg(test)]
+mod captures {
+    use std::net::Ipv4Addr;
+
+    use crate::cipher::Cipher;
+    use crate::util::capture::CaptureReader;
+
+    use super::{replay, ReplayConfig};
+
+    const CAPTURES: [(&str, &[u8], &str); 2] = [
+        (
+            "session",
+            include_bytes!("../../tests/captures/session.vntcap"),
+            include_str!("../../tests/captures/session.summary"),
+        ),
+        (
+            "v2_sequence",
+            include_bytes!("../../tests/captures/v2_sequence.vntcap"),
+            include_str!("../../tests/captures/v2_sequence.summary"),
+        ),
+    ];
+
+    #[test]
+    fn test_replay_captures() {
+        for (name, capture, expected) in CAPTURES {
+            let config = ReplayConfig {
+                virtual_ip: Ipv4Addr::new(10, 26, 0, 2),
+                virtual_netmask: Ipv4Addr::new(255, 255, 255, 0),
+                virtual_gateway: Ipv4Addr::new(10, 26, 0, 1),
+                client_cipher: Cipher::None,
+                timing: false,
+            };
+            let summary = replay(CaptureReader::new(capture).unwrap(), &config).unwrap();
+            assert!(summary.violations.is_empty(), "{}\n{}", name, summary);
+            assert_eq!(summary.to_string(), expected, "{}", name);
+        }
+    }
+}
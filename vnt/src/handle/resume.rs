@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant, SystemTime};
+
+/// 检测的时间间隔
+pub const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// 超过这个时长的时钟跳变才认为是休眠，避免把调度延迟当成休眠
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(5);
+/// 小于这个时长的休眠，nat映射和服务端会话大概率还在，只需要验证
+const NAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 休眠检测
+///
+/// 比较两次检测之间单调时钟和墙上时钟的差值：
+/// linux的单调时钟在休眠时不走，墙上时钟会跳变；
+/// windows和macos的单调时钟会走，表现为检测被推迟了很久。
+/// 两种情况都能覆盖，不依赖系统的电源通知
+pub struct ResumeDetector {
+    interval: Duration,
+    last: Option<(Instant, SystemTime)>,
+}
+
+impl ResumeDetector {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+    /// 返回休眠的时长，没有休眠时返回None
+    pub fn check(&mut self) -> Option<Duration> {
+        self.check_at(Instant::now(), SystemTime::now())
+    }
+    fn check_at(&mut self, mono: Instant, wall: SystemTime) -> Option<Duration> {
+        let (last_mono, last_wall) = self.last.replace((mono, wall))?;
+        let mono_elapsed = mono.saturating_duration_since(last_mono);
+        // 墙上时钟回拨不算休眠
+        let wall_elapsed = wall.duration_since(last_wall).unwrap_or(Duration::ZERO);
+        let slept = wall_elapsed
+            .saturating_sub(mono_elapsed)
+            .max(mono_elapsed.saturating_sub(self.interval));
+        if slept >= SLEEP_THRESHOLD {
+            Some(slept)
+        } else {
+            None
+        }
+    }
+}
+
+/// 唤醒后的恢复步骤
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecoveryStep {
+    /// 通知上层
+    Notify,
+    /// 立即向服务端和所有路由发送心跳，服务端回应即说明会话还有效，同时探测所有直连路由
+    Heartbeat,
+    /// 删除所有直连路由和无法直连的判定，重新打洞
+    ClearDirectRoutes,
+    /// 重新握手注册
+    Reconnect,
+    /// 重新探测nat
+    RefreshNat,
+}
+
+/// 休眠时间短时只验证，超过nat超时时间则重新注册
+pub fn recovery_plan(slept: Duration) -> Vec<RecoveryStep> {
+    if slept < NAT_TIMEOUT {
+        vec![
+            RecoveryStep::Notify,
+            RecoveryStep::Heartbeat,
+            RecoveryStep::RefreshNat,
+        ]
+    } else {
+        vec![
+            RecoveryStep::Notify,
+            RecoveryStep::ClearDirectRoutes,
+            RecoveryStep::Reconnect,
+            RecoveryStep::RefreshNat,
+        ]
+    }
+}
+
+#[test]
+fn test_clock_jump() {
+    let mut detector = ResumeDetector::new(RESUME_CHECK_INTERVAL);
+    let mono = Instant::now();
+    let wall = SystemTime::now();
+    let sec = Duration::from_secs;
+    assert_eq!(detector.check_at(mono, wall), None);
+    // 正常的调度延迟
+    assert_eq!(detector.check_at(mono + sec(1), wall + sec(1)), None);
+    assert_eq!(detector.check_at(mono + sec(4), wall + sec(4)), None);
+    // 墙上时钟回拨
+    assert_eq!(detector.check_at(mono + sec(5), wall), None);
+    // 单调时钟在休眠时不走
+    let slept = detector.check_at(mono + sec(6), wall + sec(21)).unwrap();
+    assert_eq!(slept, sec(20));
+    assert_eq!(
+        recovery_plan(slept),
+        vec![
+            RecoveryStep::Notify,
+            RecoveryStep::Heartbeat,
+            RecoveryStep::RefreshNat
+        ]
+    );
+    // 单调时钟在休眠时也走
+    let slept = detector.check_at(mono + sec(607), wall + sec(622)).unwrap();
+    assert_eq!(slept, sec(600));
+    let plan = recovery_plan(slept);
+    assert!(plan.contains(&RecoveryStep::Reconnect));
+    assert!(!plan.contains(&RecoveryStep::Heartbeat));
+    assert_eq!(detector.check_at(mono + sec(608), wall + sec(623)), None);
+}
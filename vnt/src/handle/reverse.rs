@@ -0,0 +1,64 @@
+//! 严格入站模式下的反向连接
+//!
+//! 请求方经服务器中继发送ConnectIntent，严格入站的一方批准后回复ConnectApprove并主动发起打洞，
+//! 请求和批准都只通过服务器转发，不会在未知路径上回复任何内容
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{ReversePacket, REVERSE_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+fn reverse_packet(
+    client_cipher: &Cipher,
+    src: Ipv4Addr,
+    dest: Ipv4Addr,
+    protocol: control_packet::Protocol,
+    nonce: u32,
+    duration: Duration,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut packet = NetPacket::new_encrypt(vec![0; 12 + REVERSE_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(protocol.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(src);
+    packet.set_destination(dest);
+    let mut reverse = ReversePacket::new(packet.payload_mut())?;
+    reverse.set_nonce(nonce);
+    reverse.set_duration(duration.as_secs().min(u32::MAX as u64) as u32);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
+
+/// 经服务器发送请求或者批准
+pub(crate) fn send_reverse(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    protocol: control_packet::Protocol,
+    dest: Ipv4Addr,
+    nonce: u32,
+    duration: Duration,
+) -> io::Result<()> {
+    if !current_device.status.online() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "not connected to the server",
+        ));
+    }
+    let packet = reverse_packet(
+        client_cipher,
+        current_device.virtual_ip,
+        dest,
+        protocol,
+        nonce,
+        duration,
+    )?;
+    context.send_default(packet.buffer(), current_device.connect_server)
+}
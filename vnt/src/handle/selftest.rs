@@ -0,0 +1,374 @@
+//! 本机的端到端自检，不需要对端配合
+//!
+//! 依次检查：发往自己虚拟ip的包经过发送路径封装、加密，从主端口发给自己，再经过接收路径
+//! 解密后交给自检接收端；向服务器发送mtu大小的回显请求；用本机的会话密钥加解密；
+//! 路由表的一致性；有网卡时按校验和策略注入改写过的包。指定对端时再检查到对端的回显和路径
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::self_probe::{icmp_echo, payload, udp_packet, Expect};
+use crate::channel::Route;
+use crate::cipher::Cipher;
+use crate::handle::checksum::probe;
+use crate::handle::tun_tap::tun_handler::turn_packet;
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+
+/// 回环不经过网络
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(2);
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+/// 回环和加解密使用的负载长度
+const PROBE_LEN: usize = 256;
+
+/// 一项检查的结果
+#[derive(Clone, Debug)]
+pub struct StageResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub elapsed: Duration,
+    /// 成功时是测量结果，失败时是原因
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SelfTestReport {
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        !self.stages.is_empty() && self.stages.iter().all(|v| v.ok)
+    }
+}
+
+type Outcome = Result<String, String>;
+
+fn stage(name: &'static str, f: impl FnOnce() -> Outcome) -> StageResult {
+    let start = Instant::now();
+    let rs = f();
+    let elapsed = start.elapsed();
+    let (ok, detail) = match rs {
+        Ok(detail) => (true, detail),
+        Err(detail) => (false, detail),
+    };
+    if !ok {
+        log::warn!("自检'{}'失败 {}", name, detail);
+    }
+    StageResult {
+        name,
+        ok,
+        elapsed,
+        detail,
+    }
+}
+
+fn io_err(e: io::Error) -> String {
+    e.to_string()
+}
+
+/// |12字节协议头|ip包|加密预留|
+fn frame(ipv4: &[u8]) -> (Vec<u8>, usize) {
+    let mut buf = vec![0u8; 12 + ipv4.len() + ENCRYPTION_RESERVED];
+    buf[12..12 + ipv4.len()].copy_from_slice(ipv4);
+    (buf, 12 + ipv4.len())
+}
+
+fn wait(expect: Expect, timeout: Duration, len: usize) -> Outcome {
+    match expect.wait(timeout) {
+        Some((rtt, true)) => Ok(format!("{} bytes, rtt {:?}", len, rtt)),
+        Some((_, false)) => Err("reply payload corrupted".to_string()),
+        None => Err(format!("no reply within {:?}", timeout)),
+    }
+}
+
+/// 主端口监听所有地址时通过本机回环地址发给自己
+fn loopback_addr(local: SocketAddr) -> SocketAddr {
+    if local.ip().is_unspecified() {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, local.port()))
+    } else {
+        local
+    }
+}
+
+/// 加密后再解密，返回是否加密
+fn cipher_round_trip(cipher: &Cipher, ip: Ipv4Addr) -> Result<bool, String> {
+    let packet = udp_packet(ip, ip, &payload(rand::random(), PROBE_LEN)).map_err(io_err)?;
+    let (mut buf, len) = frame(&packet);
+    let mut net_packet = turn_packet(&mut buf, len, ip, ip).map_err(io_err)?;
+    cipher.encrypt_ipv4(&mut net_packet).map_err(io_err)?;
+    let encrypted = net_packet.is_encrypt();
+    if encrypted && net_packet.payload().starts_with(&packet) {
+        return Err("encrypted payload equals plaintext".to_string());
+    }
+    cipher.decrypt_ipv4(&mut net_packet).map_err(io_err)?;
+    if net_packet.payload() != packet.as_slice() {
+        return Err("decrypted payload differs".to_string());
+    }
+    Ok(encrypted)
+}
+
+/// 路由表中的问题，没有问题时返回空
+pub(crate) fn audit_routes(
+    table: &[(Ipv4Addr, Vec<Route>)],
+    virtual_ip: Ipv4Addr,
+    channel_num: usize,
+    peers: &[Ipv4Addr],
+) -> Vec<String> {
+    let mut issues = Vec::new();
+    for (ip, routes) in table {
+        if *ip == virtual_ip {
+            issues.push(format!("route to own ip {}", ip));
+        }
+        if routes.is_empty() {
+            issues.push(format!("{} has an empty route list", ip));
+        } else if !peers.contains(ip) {
+            issues.push(format!("{} is not in the device list", ip));
+        }
+        for (i, route) in routes.iter().enumerate() {
+            let key = route.route_key();
+            if route.metric == 0 {
+                issues.push(format!("{} route {} has metric 0", ip, key.addr));
+            }
+            if !key.is_tcp() && key.index() >= channel_num {
+                issues.push(format!(
+                    "{} route {} uses socket {} of {}",
+                    ip,
+                    key.addr,
+                    key.index(),
+                    channel_num
+                ));
+            }
+            if routes[..i].iter().any(|v| v.route_key() == key) {
+                issues.push(format!("{} route {} is duplicated", ip, key.addr));
+            }
+        }
+    }
+    issues
+}
+
+pub(crate) struct SelfTest<'a> {
+    pub context: &'a ChannelContext,
+    pub current_device: CurrentDeviceInfo,
+    pub client_cipher: &'a Cipher,
+    pub server_cipher: &'a Cipher,
+    pub device_list: Vec<PeerDeviceInfo>,
+    pub mtu: u32,
+    pub device: &'a DeviceAdapter,
+}
+
+impl SelfTest<'_> {
+    pub fn run(&self, peer: Option<Ipv4Addr>) -> SelfTestReport {
+        let mut stages = vec![
+            stage("loopback", || self.loopback()),
+            stage("server echo", || self.server_echo()),
+            stage("cipher", || self.cipher()),
+            stage("routes", || self.routes()),
+        ];
+        // 观察者没有网卡
+        if self.device.attached() {
+            stages.push(stage("checksum", || self.checksum()));
+        }
+        if let Some(peer) = peer {
+            stages.push(stage("peer echo", || self.peer_echo(peer)));
+            stages.push(stage("peer path", || self.peer_path(peer)));
+        }
+        SelfTestReport { stages }
+    }
+    fn virtual_ip(&self) -> Result<Ipv4Addr, String> {
+        let ip = self.current_device.virtual_ip;
+        if ip.is_unspecified() {
+            return Err("no virtual ip yet".to_string());
+        }
+        Ok(ip)
+    }
+    fn loopback(&self) -> Outcome {
+        let ip = self.virtual_ip()?;
+        let local = match self.context.main_local_udp_addr().first() {
+            Some(addr) => loopback_addr(*addr),
+            None => return Err("no local udp socket".to_string()),
+        };
+        let token = rand::random();
+        let mut packet = udp_packet(ip, ip, &payload(token, PROBE_LEN)).map_err(io_err)?;
+        if !self.context.packet_hooks.run_outbound(&mut packet) {
+            return Err("dropped by outbound packet hook".to_string());
+        }
+        let (mut buf, len) = frame(&packet);
+        let mut net_packet = turn_packet(&mut buf, len, ip, ip).map_err(io_err)?;
+        self.client_cipher
+            .encrypt_ipv4(&mut net_packet)
+            .map_err(io_err)?;
+        let expect = self.context.self_probe.expect(token);
+        self.context
+            .send_main_udp(0, net_packet.buffer(), local)
+            .map_err(io_err)?;
+        wait(expect, LOOPBACK_TIMEOUT, packet.len())
+    }
+    fn server_echo(&self) -> Outcome {
+        let ip = self.virtual_ip()?;
+        if !self.current_device.status.online() {
+            return Err("not connected to the server".to_string());
+        }
+        let gateway = self.current_device.virtual_gateway;
+        let token: u64 = rand::random();
+        // ip头20字节，icmp头8字节
+        let len = (self.mtu as usize).saturating_sub(28).max(PROBE_LEN);
+        let packet = icmp_echo(ip, gateway, token as u16, &payload(token, len)).map_err(io_err)?;
+        let (mut buf, data_len) = frame(&packet);
+        let mut net_packet = turn_packet(&mut buf, data_len, ip, gateway).map_err(io_err)?;
+        net_packet.set_gateway_flag(true);
+        self.server_cipher
+            .encrypt_ipv4(&mut net_packet)
+            .map_err(io_err)?;
+        let expect = self.context.self_probe.expect(token);
+        self.context
+            .send_default(net_packet.buffer(), self.current_device.connect_server)
+            .map_err(io_err)?;
+        wait(expect, ECHO_TIMEOUT, packet.len())
+    }
+    fn cipher(&self) -> Outcome {
+        let ip = self.virtual_ip()?;
+        let describe = |encrypted: bool| if encrypted { "encrypted" } else { "plaintext" };
+        let client =
+            cipher_round_trip(self.client_cipher, ip).map_err(|e| format!("client {}", e))?;
+        let server =
+            cipher_round_trip(self.server_cipher, ip).map_err(|e| format!("server {}", e))?;
+        Ok(format!(
+            "client {}, server {}",
+            describe(client),
+            describe(server)
+        ))
+    }
+    fn routes(&self) -> Outcome {
+        let table = self.context.route_table.route_table();
+        let peers: Vec<Ipv4Addr> = self.device_list.iter().map(|v| v.virtual_ip).collect();
+        let issues = audit_routes(
+            &table,
+            self.current_device.virtual_ip,
+            self.context.channel_num(),
+            &peers,
+        );
+        if !issues.is_empty() {
+            return Err(issues.join("; "));
+        }
+        let routes: usize = table.iter().map(|(_, v)| v.len()).sum();
+        Ok(format!("{} peers, {} routes", table.len(), routes))
+    }
+    fn checksum(&self) -> Outcome {
+        let ip = self.virtual_ip()?;
+        let policy = &self.context.checksum;
+        let mode = policy.effective();
+        let rtt = probe(self.device, ip, self.current_device.virtual_gateway, mode)
+            .map_err(|e| format!("{} {}", mode.name(), e))?;
+        Ok(format!(
+            "{} (configured {}), rtt {:?}",
+            mode.name(),
+            policy.mode().name(),
+            rtt
+        ))
+    }
+    fn peer_echo(&self, peer: Ipv4Addr) -> Outcome {
+        let ip = self.virtual_ip()?;
+        let token: u64 = rand::random();
+        let len = (self.mtu as usize).saturating_sub(28).max(PROBE_LEN);
+        let packet = icmp_echo(ip, peer, token as u16, &payload(token, len)).map_err(io_err)?;
+        let (mut buf, data_len) = frame(&packet);
+        let mut net_packet = turn_packet(&mut buf, data_len, ip, peer).map_err(io_err)?;
+        if !self.context.peer_features.is_plaintext(&peer) {
+            self.client_cipher
+                .encrypt_ipv4(&mut net_packet)
+                .map_err(io_err)?;
+        }
+        let expect = self.context.self_probe.expect(token);
+        self.context
+            .send_ipv4_by_id(
+                net_packet.buffer(),
+                &peer,
+                self.current_device.connect_server,
+                self.current_device.status.online(),
+            )
+            .map_err(io_err)?;
+        wait(expect, ECHO_TIMEOUT, packet.len())
+    }
+    fn peer_path(&self, peer: Ipv4Addr) -> Outcome {
+        match self.device_list.iter().find(|v| v.virtual_ip == peer) {
+            Some(device) if device.status.is_online() => {}
+            Some(_) => return Err(format!("{} is offline", peer)),
+            None => return Err(format!("{} is not in the device list", peer)),
+        }
+        let routes = self.context.route_table.route(&peer).unwrap_or_default();
+        let first = match routes.first() {
+            Some(route) => route,
+            None => {
+                if self.current_device.status.online() {
+                    return Ok("relay via server".to_string());
+                }
+                return Err("no route and not connected to the server".to_string());
+            }
+        };
+        let kind = if first.is_p2p() { "p2p" } else { "relay" };
+        let transport = if first.is_tcp { "tcp" } else { "udp" };
+        Ok(format!(
+            "{} {} {} rt {}ms, {} routes",
+            kind,
+            transport,
+            first.addr,
+            first.rt,
+            routes.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::channel::Route;
+    use crate::handle::selftest::{audit_routes, loopback_addr};
+
+    #[test]
+    fn test_audit_routes() {
+        let own = Ipv4Addr::new(10, 26, 0, 2);
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        let addr = SocketAddr::from(([192, 168, 1, 3], 40000));
+        let good = Route::new(false, 1, addr, 1, 10);
+        assert!(audit_routes(&[(peer, vec![good])], own, 2, &[peer]).is_empty());
+
+        let issues = audit_routes(
+            &[
+                (own, vec![good]),
+                (peer, vec![good, good, Route::new(false, 2, addr, 1, 10)]),
+            ],
+            own,
+            2,
+            &[peer],
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "route to own ip 10.26.0.2",
+                "10.26.0.2 is not in the device list",
+                "10.26.0.3 route 192.168.1.3:40000 is duplicated",
+                "10.26.0.3 route 192.168.1.3:40000 uses socket 2 of 2",
+            ]
+        );
+        // tcp连接没有端口下标
+        let tcp = Route::new(true, 5, addr, 1, 10);
+        assert!(audit_routes(&[(peer, vec![tcp])], own, 2, &[peer]).is_empty());
+    }
+
+    #[test]
+    fn test_loopback_addr() {
+        assert_eq!(
+            loopback_addr("[::]:29872".parse().unwrap()).to_string(),
+            "127.0.0.1:29872"
+        );
+        assert_eq!(
+            loopback_addr("192.168.1.2:29872".parse().unwrap()).to_string(),
+            "192.168.1.2:29872"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! 服务端请求客户端执行的操作
+//!
+//! 只支持固定的几种无害操作，默认全部关闭，用'--allow-server-actions'逐个允许。
+//! 服务端通过已认证的服务端通道下发请求，不在列表中、不认识或者过于频繁的请求立即回应拒绝并计数，
+//! 接受的请求排队，由维护任务逐个执行后回应结果
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use protobuf::Message;
+
+use crate::cipher::Cipher;
+use crate::proto::message::{ServerAction, ServerActionAck, ServerActionStatus};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{service_packet, NetPacket, Protocol, HEAD_LEN, MAX_TTL};
+use crate::util::metrics::{Counter, Registry};
+use crate::util::sanitize;
+
+/// 同一种操作两次执行的最小间隔
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// 等待执行的请求上限
+const MAX_PENDING: usize = 4;
+/// 参数和结果说明的最大长度
+pub const ARG_MAX_LEN: usize = 256;
+
+/// 允许服务端请求的操作
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Action {
+    /// 重新拉取设备列表
+    Resync,
+    /// 重新探测nat类型
+    Renat,
+    /// 重新握手，更换和服务端之间的密钥
+    Rekey,
+    /// 在本地生成诊断包，结果中给出路径
+    CollectBundle,
+    /// 修改日志级别
+    SetLogLevel,
+}
+
+impl Action {
+    pub const ALL: [Action; 5] = [
+        Action::Resync,
+        Action::Renat,
+        Action::Rekey,
+        Action::CollectBundle,
+        Action::SetLogLevel,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Resync => "resync",
+            Action::Renat => "renat",
+            Action::Rekey => "rekey",
+            Action::CollectBundle => "collect-bundle",
+            Action::SetLogLevel => "set-log-level",
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Action::ALL
+            .into_iter()
+            .find(|v| v.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Action::ALL.iter().map(|v| v.name()).collect();
+                format!("unknown action '{}', available: {}", s, names.join(","))
+            })
+    }
+}
+
+/// 等待执行的请求
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ActionRequest {
+    pub id: u32,
+    pub action: Action,
+    pub arg: String,
+}
+
+struct Inner {
+    allowed: Vec<Action>,
+    pending: VecDeque<ActionRequest>,
+    // 各操作最近一次接受的时间
+    last: HashMap<Action, Instant>,
+}
+
+pub struct ServerActions {
+    inner: Mutex<Inner>,
+    accepted: Counter,
+    denied: Counter,
+    unsupported: Counter,
+    rate_limited: Counter,
+}
+
+impl ServerActions {
+    pub fn new(registry: &Registry) -> Self {
+        let rejected = |reason| registry.counter("server_action_rejected", &[("reason", reason)]);
+        Self {
+            inner: Mutex::new(Inner {
+                allowed: Vec::new(),
+                pending: VecDeque::with_capacity(MAX_PENDING),
+                last: HashMap::new(),
+            }),
+            accepted: registry.counter("server_action_accepted", &[]),
+            denied: rejected("denied"),
+            unsupported: rejected("unsupported"),
+            rate_limited: rejected("rate_limited"),
+        }
+    }
+    /// 启动时设置允许的操作，为空时全部拒绝
+    pub fn set_allowed(&self, allowed: &[Action]) {
+        self.inner.lock().allowed = allowed.to_vec();
+    }
+    pub fn allowed(&self) -> Vec<Action> {
+        self.inner.lock().allowed.clone()
+    }
+    /// 收到服务端的请求，接受时排队等待执行，拒绝时返回状态和原因，由调用方立即回应
+    pub fn receive(&self, request: &ServerAction) -> Result<(), (ServerActionStatus, String)> {
+        self.receive_at(request, Instant::now())
+    }
+    fn receive_at(
+        &self,
+        request: &ServerAction,
+        now: Instant,
+    ) -> Result<(), (ServerActionStatus, String)> {
+        let name = sanitize::text(&request.action, ARG_MAX_LEN, false);
+        let arg = sanitize::text(&request.arg, ARG_MAX_LEN, false);
+        log::warn!(
+            "服务端请求执行操作 id={} action={} arg={:?}",
+            request.id,
+            name,
+            arg
+        );
+        let rs = self.check(request.id, &name, arg, now);
+        match &rs {
+            Ok(()) => self.accepted.inc(),
+            Err((status, reason)) => {
+                match status {
+                    ServerActionStatus::Denied => self.denied.inc(),
+                    ServerActionStatus::RateLimited => self.rate_limited.inc(),
+                    _ => self.unsupported.inc(),
+                }
+                log::warn!(
+                    "拒绝服务端请求的操作 id={} action={} {:?} {}",
+                    request.id,
+                    name,
+                    status,
+                    reason
+                );
+            }
+        }
+        rs
+    }
+    fn check(
+        &self,
+        id: u32,
+        name: &str,
+        arg: String,
+        now: Instant,
+    ) -> Result<(), (ServerActionStatus, String)> {
+        let action = name
+            .parse::<Action>()
+            .map_err(|e| (ServerActionStatus::Unsupported, e))?;
+        let mut guard = self.inner.lock();
+        if !guard.allowed.contains(&action) {
+            return Err((
+                ServerActionStatus::Denied,
+                format!("'{}' is not allowed by this client", action),
+            ));
+        }
+        if let Some(last) = guard.last.get(&action) {
+            let elapsed = now.saturating_duration_since(*last);
+            if elapsed < MIN_INTERVAL {
+                return Err((
+                    ServerActionStatus::RateLimited,
+                    format!("retry in {}s", (MIN_INTERVAL - elapsed).as_secs() + 1),
+                ));
+            }
+        }
+        if guard.pending.len() >= MAX_PENDING {
+            return Err((
+                ServerActionStatus::RateLimited,
+                format!("{} actions pending", guard.pending.len()),
+            ));
+        }
+        guard.last.insert(action, now);
+        guard.pending.push_back(ActionRequest { id, action, arg });
+        Ok(())
+    }
+    /// 维护任务取出下一个等待执行的请求
+    pub fn take(&self) -> Option<ActionRequest> {
+        self.inner.lock().pending.pop_front()
+    }
+}
+
+/// 发给服务端的执行结果
+pub fn ack_packet(
+    server_cipher: &Cipher,
+    src: Ipv4Addr,
+    gateway: Ipv4Addr,
+    id: u32,
+    action: &str,
+    status: ServerActionStatus,
+    detail: &str,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut ack = ServerActionAck::new();
+    ack.id = id;
+    ack.action = sanitize::text(action, ARG_MAX_LEN, false);
+    ack.status = protobuf::EnumOrUnknown::new(status);
+    ack.detail = sanitize::text(detail, ARG_MAX_LEN, false);
+    let buf = ack
+        .write_to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("ack_packet {:?}", e)))?;
+    let mut net_packet =
+        NetPacket::new_encrypt(vec![0; HEAD_LEN + buf.len() + ENCRYPTION_RESERVED])?;
+    net_packet.set_default_version();
+    net_packet.set_gateway_flag(true);
+    net_packet.set_protocol(Protocol::Service);
+    net_packet.set_transport_protocol_into(service_packet::Protocol::ServerActionAck);
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(src);
+    net_packet.set_destination(gateway);
+    net_packet.set_payload(&buf)?;
+    server_cipher.encrypt_ipv4(&mut net_packet)?;
+    Ok(net_packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use protobuf::Message;
+
+    use super::{ack_packet, Action, ServerActions, MAX_PENDING, MIN_INTERVAL};
+    use crate::cipher::Cipher;
+    use crate::proto::message::{ServerAction, ServerActionAck, ServerActionStatus};
+    use crate::protocol::{service_packet, NetPacket, Protocol, HEAD_LEN};
+    use crate::util::metrics::Registry;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 1);
+
+    fn request(id: u32, action: &str, arg: &str) -> ServerAction {
+        let mut request = ServerAction::new();
+        request.id = id;
+        request.action = action.to_string();
+        request.arg = arg.to_string();
+        request
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let actions = ServerActions::new(&Registry::new());
+        let now = Instant::now();
+        // 默认全部拒绝
+        let rs = actions.receive_at(&request(1, "resync", ""), now);
+        assert_eq!(rs.unwrap_err().0, ServerActionStatus::Denied);
+        actions.set_allowed(&[Action::Resync, Action::SetLogLevel]);
+        assert_eq!(actions.allowed(), vec![Action::Resync, Action::SetLogLevel]);
+        // 不认识的操作
+        let rs = actions.receive_at(&request(2, "exec", "rm -rf /"), now);
+        assert_eq!(rs.unwrap_err().0, ServerActionStatus::Unsupported);
+        let rs = actions.receive_at(&request(3, "renat", ""), now);
+        assert_eq!(rs.unwrap_err().0, ServerActionStatus::Denied);
+        assert!(actions.receive_at(&request(4, "resync", ""), now).is_ok());
+        // 同一种操作限速，其他操作不受影响
+        let rs = actions.receive_at(&request(5, "resync", ""), now + Duration::from_secs(10));
+        let (status, reason) = rs.unwrap_err();
+        assert_eq!(status, ServerActionStatus::RateLimited);
+        assert_eq!(reason, "retry in 51s");
+        let arg = "punch\u{1b}[2J debug";
+        assert!(actions
+            .receive_at(&request(6, "set-log-level", arg), now)
+            .is_ok());
+        assert!(actions
+            .receive_at(&request(7, "resync", ""), now + MIN_INTERVAL)
+            .is_ok());
+        assert_eq!(actions.accepted.get(), 3);
+        assert_eq!(actions.denied.get(), 2);
+        assert_eq!(actions.unsupported.get(), 1);
+        assert_eq!(actions.rate_limited.get(), 1);
+
+        let first = actions.take().unwrap();
+        assert_eq!((first.id, first.action), (4, Action::Resync));
+        // 参数去除了控制字符
+        let second = actions.take().unwrap();
+        assert_eq!(second.action, Action::SetLogLevel);
+        assert!(!second.arg.contains('\u{1b}'));
+        assert_eq!(actions.take().unwrap().id, 7);
+        assert_eq!(actions.take(), None);
+    }
+
+    #[test]
+    fn test_pending_limit() {
+        let actions = ServerActions::new(&Registry::new());
+        actions.set_allowed(&Action::ALL);
+        let mut now = Instant::now();
+        for i in 0..MAX_PENDING as u32 {
+            now += MIN_INTERVAL;
+            assert!(actions.receive_at(&request(i, "resync", ""), now).is_ok());
+        }
+        now += MIN_INTERVAL;
+        let rs = actions.receive_at(&request(9, "resync", ""), now);
+        assert_eq!(rs.unwrap_err().0, ServerActionStatus::RateLimited);
+        // 被拒绝的请求不占用限速
+        actions.take();
+        assert!(actions.receive_at(&request(10, "resync", ""), now).is_ok());
+    }
+
+    /// 模拟服务端下发请求，客户端接受、执行后回应，服务端解析回应
+    #[test]
+    fn test_ack_flow() {
+        let buf = request(42, "rekey", "").write_to_bytes().unwrap();
+        let mut packet = NetPacket::new(vec![0; HEAD_LEN + buf.len()]).unwrap();
+        packet.set_default_version();
+        packet.set_gateway_flag(true);
+        packet.set_protocol(Protocol::Service);
+        packet.set_transport_protocol_into(service_packet::Protocol::ServerAction);
+        packet.set_source(GATEWAY);
+        packet.set_destination(LOCAL);
+        packet.set_payload(&buf).unwrap();
+
+        let received = NetPacket::new(packet.buffer()).unwrap();
+        assert_eq!(
+            service_packet::Protocol::from(received.transport_protocol()),
+            service_packet::Protocol::ServerAction
+        );
+        let request = ServerAction::parse_from_bytes(received.payload()).unwrap();
+        let actions = ServerActions::new(&Registry::new());
+        actions.set_allowed(&[Action::Rekey]);
+        actions.receive(&request).unwrap();
+        let pending = actions.take().unwrap();
+
+        let cipher = Cipher::None;
+        let ack = ack_packet(
+            &cipher,
+            LOCAL,
+            GATEWAY,
+            pending.id,
+            pending.action.name(),
+            ServerActionStatus::Done,
+            "reconnecting",
+        )
+        .unwrap();
+        // 服务端收到的回应
+        let ack = NetPacket::new(ack.buffer()).unwrap();
+        assert!(ack.is_gateway());
+        assert_eq!(ack.source(), LOCAL);
+        assert_eq!(ack.destination(), GATEWAY);
+        assert_eq!(
+            service_packet::Protocol::from(ack.transport_protocol()),
+            service_packet::Protocol::ServerActionAck
+        );
+        let ack = ServerActionAck::parse_from_bytes(ack.payload()).unwrap();
+        assert_eq!((ack.id, ack.action.as_str()), (42, "rekey"));
+        assert_eq!(ack.status.enum_value(), Ok(ServerActionStatus::Done));
+        assert_eq!(ack.detail, "reconnecting");
+
+        // 同一个请求再次下发被限速，拒绝的原因带在回应里
+        let (status, reason) = actions.receive(&request).unwrap_err();
+        let ack = ack_packet(&cipher, LOCAL, GATEWAY, 42, "rekey", status, &reason).unwrap();
+        let ack =
+            ServerActionAck::parse_from_bytes(NetPacket::new(ack.buffer()).unwrap().payload())
+                .unwrap();
+        assert_eq!(ack.status.enum_value(), Ok(ServerActionStatus::RateLimited));
+        assert!(ack.detail.starts_with("retry in "));
+    }
+}
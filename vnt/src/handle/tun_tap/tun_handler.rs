@@ -1,4 +1,5 @@
 use std::net::Ipv4Addr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::{io, thread};
 
@@ -13,19 +14,27 @@ use tun::device::IFace;
 use tun::Device;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::drop_reason::DropReason;
+use crate::channel::route_cache::RouteCache;
+use crate::channel::source_policy::SourceCheck;
 use crate::cipher::Cipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::tun_tap::channel_group::channel_group;
-use crate::handle::{check_dest, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::ProxyHandler;
 use crate::protocol;
 use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::compat::EXT_HEAD_LEN;
 use crate::protocol::ip_turn_packet::BroadcastPacket;
 use crate::protocol::{ip_turn_packet, NetPacket, MAX_TTL};
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::buffer_pool::buffer_pool;
+use crate::util::metrics::Counter;
+use crate::util::subnet::Destination;
+use crate::util::workers::Worker;
+use crate::util::StopManager;
 
 fn icmp(device_writer: &Device, mut ipv4_packet: IpV4Packet<&mut [u8]>) -> io::Result<()> {
     if ipv4_packet.protocol() == Protocol::Icmp {
@@ -43,7 +52,10 @@ fn icmp(device_writer: &Device, mut ipv4_packet: IpV4Packet<&mut [u8]>) -> io::R
     Ok(())
 }
 
-/// 接收tun数据，并且转发到udp上
+/// 网卡读取的ip报文前面留出的空间，协议头和V2扩展头
+pub(crate) const HEAD_ROOM: usize = EXT_HEAD_LEN + 12;
+
+/// 接收tun数据，并且转发到udp上，data的结构同base_handle
 pub(crate) fn handle(
     context: &ChannelContext,
     data: &mut [u8],
@@ -55,12 +67,17 @@ pub(crate) fn handle(
     client_cipher: &Cipher,
     server_cipher: &Cipher,
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    route_cache: &mut RouteCache,
 ) -> io::Result<()> {
     //忽略掉结构不对的情况（ipv6数据、win tap会读到空数据），不然日志打印太多了
-    let ipv4_packet = match IpV4Packet::new(&mut data[12..len]) {
+    let ipv4_packet = match IpV4Packet::new(&mut data[HEAD_ROOM..len]) {
         Ok(packet) => packet,
         Err(_) => return Ok(()),
     };
+    if context.mtu_guard.oversize(len - HEAD_ROOM) {
+        context.drop_stats.add(DropReason::Oversize);
+        return Ok(());
+    }
     let src_ip = ipv4_packet.source_ip();
     let dest_ip = ipv4_packet.destination_ip();
     if src_ip == dest_ip {
@@ -77,6 +94,7 @@ pub(crate) fn handle(
         client_cipher,
         server_cipher,
         device_list,
+        route_cache,
     );
 }
 
@@ -90,11 +108,17 @@ pub fn start(
     client_cipher: Cipher,
     server_cipher: Cipher,
     parallel: usize,
-    mut up_counter: SingleU64Adder,
+    up_counter: Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     if parallel > 1 {
         let (sender, receivers) = channel_group::<(Vec<u8>, usize)>(parallel, 16);
+        // 每个处理线程排队的和正在处理的，加上读取线程手里的一个
+        let (buffers, recycler) = buffer_pool(
+            parallel * 17 + 1,
+            BUFFER_SIZE,
+            context.metrics.counter("buffer_alloc", &[("pool", "tun")]),
+        );
         for (index, receiver) in receivers.into_iter().enumerate() {
             let context = context.clone();
             let device = device.clone();
@@ -105,9 +129,12 @@ pub fn start(
             let client_cipher = client_cipher.clone();
             let server_cipher = server_cipher.clone();
             let device_list = device_list.clone();
+            let recycler = recycler.clone();
             thread::Builder::new()
                 .name(format!("tunHandler-{}", index))
                 .spawn(move || {
+                    context.workers.enter(Worker::Crypto);
+                    let mut route_cache = RouteCache::new();
                     while let Ok((mut buf, len)) = receiver.recv() {
                         #[cfg(not(target_os = "macos"))]
                         let start = 0;
@@ -125,23 +152,28 @@ pub fn start(
                             &client_cipher,
                             &server_cipher,
                             &device_list,
+                            &mut route_cache,
                         ) {
                             Ok(_) => {}
                             Err(e) => {
                                 log::warn!("{:?}", e)
                             }
                         }
+                        recycler.put(buf);
                     }
                 })?;
         }
         thread::Builder::new()
             .name("tunHandlerM".into())
             .spawn(move || {
+                context.workers.enter(Worker::Tun);
                 if let Err(e) = crate::handle::tun_tap::start_multi(
                     stop_manager,
+                    &context,
                     device,
                     sender,
-                    &mut up_counter,
+                    buffers,
+                    &up_counter,
                 ) {
                     log::warn!("stop:{}", e);
                 }
@@ -150,6 +182,7 @@ pub fn start(
         thread::Builder::new()
             .name("tunHandlerS".into())
             .spawn(move || {
+                context.workers.enter(Worker::Tun);
                 if let Err(e) = crate::handle::tun_tap::start_simple(
                     stop_manager,
                     &context,
@@ -160,7 +193,7 @@ pub fn start(
                     ip_proxy_map,
                     client_cipher,
                     server_cipher,
-                    &mut up_counter,
+                    &up_counter,
                     device_list,
                 ) {
                     log::warn!("stop:{}", e);
@@ -184,6 +217,10 @@ fn broadcast(
         .filter(|info| info.status.is_online())
         .map(|info| info.virtual_ip)
         .collect();
+    // 屏蔽的设备当作已发送，服务端转发时会跳过
+    let (blocked_ips, list): (Vec<Ipv4Addr>, Vec<Ipv4Addr>) = list
+        .into_iter()
+        .partition(|ip| sender.block_list.contains(ip));
     const MAX_COUNT: usize = 8;
     let mut p2p_ips = Vec::with_capacity(8);
     let mut relay_ips = Vec::with_capacity(8);
@@ -209,7 +246,7 @@ fn broadcast(
         return Ok(());
     }
 
-    if p2p_ips.is_empty() {
+    if p2p_ips.is_empty() && blocked_ips.is_empty() {
         //都没有p2p则直接由服务器转发
         if current_device.status.online() {
             sender.send_default(net_packet.buffer(), current_device.connect_server)?;
@@ -232,10 +269,27 @@ fn broadcast(
     }
     if current_device.status.offline() {
         //离线的不再转发
+        sender.drop_stats.add(DropReason::Offline);
         return Ok(());
     }
-    let buf = vec![0u8; 12 + 1 + p2p_ips.len() * 4 + net_packet.data_len() + ENCRYPTION_RESERVED];
+    p2p_ips.extend(blocked_ips);
     //剩余的发送到服务端，需要告知哪些已发送过
+    let server_packet = relay_broadcast_packet(
+        server_cipher,
+        net_packet,
+        &p2p_ips,
+        sender.relay_passthrough.load(Ordering::Relaxed),
+    )?;
+    sender.send_default(server_packet.buffer(), current_device.connect_server)
+}
+
+fn relay_broadcast_packet(
+    server_cipher: &Cipher,
+    net_packet: &NetPacket<&mut [u8]>,
+    p2p_ips: &[Ipv4Addr],
+    passthrough: bool,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let buf = vec![0u8; 12 + 1 + p2p_ips.len() * 4 + net_packet.data_len() + ENCRYPTION_RESERVED];
     let mut server_packet = NetPacket::new_encrypt(buf)?;
     server_packet.set_default_version();
     server_packet.set_gateway_flag(true);
@@ -247,38 +301,103 @@ fn broadcast(
     server_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4Broadcast.into());
 
     let mut broadcast = BroadcastPacket::unchecked(server_packet.payload_mut());
-    broadcast.set_address(&p2p_ips)?;
+    broadcast.set_address(p2p_ips)?;
     broadcast.set_data(net_packet.buffer())?;
-    server_cipher.encrypt_ipv4(&mut server_packet)?;
-    sender.send_default(server_packet.buffer(), current_device.connect_server)
+    match relay_protection(net_packet.is_encrypt(), passthrough) {
+        RelayProtection::Encrypt => server_cipher.encrypt_ipv4(&mut server_packet)?,
+        RelayProtection::AuthOnly => server_cipher.authenticate_ipv4(&mut server_packet)?,
+    }
+    Ok(server_packet)
 }
 
-/// 实现一个原地发送，必须保证是如下结构
-/// |12字节开头|ip报文|至少1024字节结尾|
+/// 经服务器中继的广播包外层的保护方式
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RelayProtection {
+    Encrypt,
+    AuthOnly,
+}
+
+/// 中继广播包有两层保护:
+///
+/// - 内层是组网密码(client_cipher)的端到端加密，只有同一组网的客户端能解密，
+///   服务端看不到ip报文，也无法修改而不被发现
+/// - 外层是和服务端协商的密钥(server_cipher)，服务端据此确认数据来自已注册的客户端，
+///   并读取已经直连发送过的地址列表
 ///
+/// 内层已经加密时，外层再加密一次只多隐藏了已发送地址列表和内层协议头中的虚拟ip，
+/// 这些信息在外层协议头(源地址、目的地址)和流量特征中基本已经暴露，对载荷没有额外的保护，
+/// 所以服务端支持时外层只认证：协议头、地址列表和内层数据都参与认证，载荷不再加密。
+/// 内层没有加密时必须完整加密外层，不能因为协商了只认证就退化成明文
+fn relay_protection(inner_encrypted: bool, passthrough: bool) -> RelayProtection {
+    if inner_encrypted && passthrough {
+        RelayProtection::AuthOnly
+    } else {
+        RelayProtection::Encrypt
+    }
+}
+
+/// 填写转发包的协议头，网卡读到的包和自检包共用，buf结构为|12字节开头|ip报文|至少1024字节结尾|
+pub(crate) fn turn_packet(
+    buf: &mut [u8],
+    data_len: usize,
+    src_ip: Ipv4Addr,
+    dest_ip: Ipv4Addr,
+) -> io::Result<NetPacket<&mut [u8]>> {
+    let mut net_packet = NetPacket::new0(data_len, buf)?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(protocol::Protocol::IpTurn);
+    net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+    net_packet.first_set_ttl(6);
+    net_packet.set_source(src_ip);
+    net_packet.set_destination(dest_ip);
+    Ok(net_packet)
+}
+
+/// 实现一个原地发送，必须保证是如下结构
+/// |EXT_HEAD_LEN字节预留|12字节开头|ip报文|至少1024字节结尾|
+/// 预留的空间用于发往支持V2的对端时原地编码
 #[inline]
 fn base_handle(
     context: &ChannelContext,
     buf: &mut [u8],
-    data_len: usize, //数据总长度=12+ip包长度
+    data_len: usize, //数据总长度=EXT_HEAD_LEN+12+ip包长度
     current_device: CurrentDeviceInfo,
     ip_route: &ExternalRoute,
     #[cfg(feature = "ip_proxy")] proxy_map: &Option<IpProxyMap>,
     client_cipher: &Cipher,
     server_cipher: &Cipher,
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    route_cache: &mut RouteCache,
 ) -> io::Result<()> {
-    let ipv4_packet = IpV4Packet::new(&buf[12..data_len])?;
+    if !context
+        .packet_hooks
+        .run_outbound(&mut buf[HEAD_ROOM..data_len])
+    {
+        context.drop_stats.add(DropReason::Hook);
+        return Ok(());
+    }
+    if context
+        .source_policy
+        .outbound(&mut buf[HEAD_ROOM..data_len], current_device.virtual_ip)
+        == SourceCheck::Drop
+    {
+        context.drop_stats.add(DropReason::SourceAddress);
+        return Ok(());
+    }
+    if !context.rate_limit.take(data_len - HEAD_ROOM) {
+        context.drop_stats.add(DropReason::RateLimited);
+        return Ok(());
+    }
+    let ipv4_packet = IpV4Packet::new(&buf[HEAD_ROOM..data_len])?;
     let protocol = ipv4_packet.protocol();
     let src_ip = ipv4_packet.source_ip();
     let mut dest_ip = ipv4_packet.destination_ip();
-    let mut net_packet = NetPacket::new0(data_len, buf)?;
-    net_packet.set_default_version();
-    net_packet.set_protocol(protocol::Protocol::IpTurn);
-    net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
-    net_packet.first_set_ttl(6);
-    net_packet.set_source(src_ip);
-    net_packet.set_destination(dest_ip);
+    let mut net_packet = turn_packet(
+        &mut buf[EXT_HEAD_LEN..],
+        data_len - EXT_HEAD_LEN,
+        src_ip,
+        dest_ip,
+    )?;
     if dest_ip == current_device.virtual_gateway {
         // 发到网关的加密方式不一样，要单独处理
         if protocol == Protocol::Icmp {
@@ -288,12 +407,16 @@ fn base_handle(
         }
         return Ok(());
     }
-    if dest_ip.is_multicast() {
-        //当作广播处理
-        dest_ip = Ipv4Addr::BROADCAST;
-        net_packet.set_destination(Ipv4Addr::BROADCAST);
-    }
-    if dest_ip.is_broadcast() || current_device.broadcast_ip == dest_ip {
+    let destination = current_device.subnet().classify(dest_ip);
+    if destination == Destination::Broadcast || destination == Destination::Multicast {
+        if !context.broadcast.load(Ordering::Relaxed) {
+            context.drop_stats.add(DropReason::Reserved);
+            return Ok(());
+        }
+        if destination == Destination::Multicast {
+            //当作广播处理
+            net_packet.set_destination(Ipv4Addr::BROADCAST);
+        }
         // 广播 发送到直连目标
         client_cipher.encrypt_ipv4(&mut net_packet)?;
         broadcast(
@@ -305,33 +428,144 @@ fn base_handle(
         )?;
         return Ok(());
     }
-    if !check_dest(
-        dest_ip,
-        current_device.virtual_netmask,
-        current_device.virtual_network,
-    ) {
+    if destination == Destination::External {
+        if context.route_conflict.load(Ordering::Relaxed) {
+            // 外部路由可能把服务器的流量也引入了网卡，修复前不转发
+            context.drop_stats.add(DropReason::Loop);
+            return Ok(());
+        }
         if let Some(r_dest_ip) = ip_route.route(&dest_ip) {
             //路由的目标不能是自己
             if r_dest_ip == src_ip {
+                context.drop_stats.add(DropReason::Loop);
                 return Ok(());
             }
             //需要修改目的地址
             dest_ip = r_dest_ip;
             net_packet.set_destination(r_dest_ip);
         } else {
+            context.drop_stats.add(DropReason::NoRoute);
             return Ok(());
         }
     }
+    if context.block_list.contains(&dest_ip) {
+        //已屏蔽的设备
+        context.drop_stats.add_peer(DropReason::Blocked, dest_ip);
+        return Ok(());
+    }
+    context
+        .mtu_guard
+        .outbound(dest_ip, net_packet.payload_mut());
     #[cfg(feature = "ip_proxy")]
     if let Some(proxy_map) = proxy_map {
         let mut ipv4_packet = IpV4Packet::new(net_packet.payload_mut())?;
         proxy_map.send_handle(&mut ipv4_packet)?;
     }
-    client_cipher.encrypt_ipv4(&mut net_packet)?;
-    context.send_ipv4_by_id(
-        net_packet.buffer(),
+    if !context.peer_features.is_plaintext(&dest_ip) {
+        client_cipher.encrypt_ipv4(&mut net_packet)?;
+    }
+    let packet_len = net_packet.buffer().len();
+    context.send_ipv4_by_id_cache(
+        &mut buf[..EXT_HEAD_LEN + packet_len],
         &dest_ip,
         current_device.connect_server,
         current_device.status.online(),
+        route_cache,
+    )
+}
+
+#[test]
+fn test_relay_protection() {
+    assert_eq!(relay_protection(true, true), RelayProtection::AuthOnly);
+    assert_eq!(relay_protection(true, false), RelayProtection::Encrypt);
+    // 内层是明文时不管是否协商都完整加密
+    assert_eq!(relay_protection(false, true), RelayProtection::Encrypt);
+    assert_eq!(relay_protection(false, false), RelayProtection::Encrypt);
+}
+
+#[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+#[test]
+fn test_relay_single_encryption() {
+    use crate::cipher::CipherModel;
+    let client_cipher = Cipher::new_password(
+        CipherModel::AesGcm,
+        Some("password123".to_string()),
+        Some("token".to_string()),
+    );
+    let server_cipher = Cipher::new_key([3; 32], "token".to_string()).unwrap();
+    let p2p_ips = [Ipv4Addr::new(10, 26, 0, 3)];
+    for (client_encrypt, passthrough) in
+        [(true, true), (true, false), (false, true), (false, false)]
+    {
+        let mut buf = vec![0u8; 12 + 64 + ENCRYPTION_RESERVED];
+        buf[12..76].fill(0x5A);
+        let mut net_packet = NetPacket::new_encrypt(buf.as_mut_slice()).unwrap();
+        net_packet.set_default_version();
+        net_packet.set_protocol(protocol::Protocol::IpTurn);
+        net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+        net_packet.first_set_ttl(6);
+        net_packet.set_source(Ipv4Addr::new(10, 26, 0, 2));
+        net_packet.set_destination(Ipv4Addr::BROADCAST);
+        if client_encrypt {
+            client_cipher.encrypt_ipv4(&mut net_packet).unwrap();
+        }
+        let inner = net_packet.buffer().to_vec();
+        let mut server_packet =
+            relay_broadcast_packet(&server_cipher, &net_packet, &p2p_ips, passthrough).unwrap();
+        let wire = server_packet.buffer().to_vec();
+        // 只有内层已加密且协商了只认证时内层原样发送，载荷只加密一次
+        let pass_through = wire.windows(inner.len()).any(|w| w == inner.as_slice());
+        assert_eq!(pass_through, client_encrypt && passthrough);
+        assert_eq!(server_packet.is_auth_only(), client_encrypt && passthrough);
+        assert!(server_packet.is_encrypt());
+        // 任何情况下明文都不会出现在线路上
+        assert!(!wire.windows(16).any(|w| w == [0x5A; 16]));
+        // 服务端得到相同的地址列表和内层数据
+        server_cipher.decrypt_ipv4(&mut server_packet).unwrap();
+        let broadcast = BroadcastPacket::new(server_packet.payload()).unwrap();
+        assert_eq!(broadcast.addresses(), p2p_ips.to_vec());
+        assert_eq!(broadcast.data().unwrap(), inner.as_slice());
+    }
+}
+
+#[test]
+fn test_blocked_peer_not_sent() {
+    use crate::cipher::CipherModel;
+    let context = crate::channel::loopback_context();
+    let local = Ipv4Addr::new(10, 26, 0, 2);
+    let blocked = Ipv4Addr::new(10, 26, 0, 3);
+    context.block_peer(blocked, "");
+    let current_device = CurrentDeviceInfo::new(
+        local,
+        Ipv4Addr::new(255, 255, 255, 0),
+        Ipv4Addr::new(10, 26, 0, 1),
+        "127.0.0.1:9".parse().unwrap(),
+    );
+    let cipher = Cipher::new_password(CipherModel::None, None, None);
+    let device_list = Mutex::new((0, Vec::new()));
+    let mut route_cache = RouteCache::new();
+    let ip = crate::channel::self_probe::ipv4(local, blocked, 17, vec![0; 16]).unwrap();
+    let mut buf = vec![0u8; HEAD_ROOM + ip.len() + 1024];
+    buf[HEAD_ROOM..HEAD_ROOM + ip.len()].copy_from_slice(&ip);
+    base_handle(
+        &context,
+        &mut buf,
+        HEAD_ROOM + ip.len(),
+        current_device,
+        &ExternalRoute::new(Vec::new()),
+        #[cfg(feature = "ip_proxy")]
+        &None,
+        &cipher,
+        &cipher,
+        &device_list,
+        &mut route_cache,
     )
+    .unwrap();
+    assert_eq!(context.drop_stats.count(DropReason::Blocked), 1);
+    let snapshot = context.drop_stats.snapshot();
+    let stat = snapshot
+        .iter()
+        .find(|v| v.reason == DropReason::Blocked)
+        .unwrap();
+    assert_eq!(stat.peers, vec![(blocked, 1)]);
 }
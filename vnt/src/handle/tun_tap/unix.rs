@@ -1,11 +1,16 @@
 use crate::channel::context::ChannelContext;
+use crate::channel::route_cache::RouteCache;
 use crate::cipher::Cipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::tun_tap::channel_group::GroupSyncSender;
+use crate::handle::tun_tap::tun_handler::HEAD_ROOM;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::buffer_pool::BufferSource;
+use crate::util::health::{WATCHDOG_INTERVAL, WATCHDOG_STALE};
+use crate::util::metrics::Counter;
+use crate::util::StopManager;
 use crossbeam_utils::atomic::AtomicCell;
 use mio::event::Source;
 use mio::unix::SourceFd;
@@ -14,6 +19,7 @@ use parking_lot::Mutex;
 use std::io;
 use std::os::fd::AsRawFd;
 use std::sync::Arc;
+use std::thread;
 use tun::Device;
 
 const STOP: Token = Token(0);
@@ -28,7 +34,7 @@ pub(crate) fn start_simple(
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
     client_cipher: Cipher,
     server_cipher: Cipher,
-    up_counter: &mut SingleU64Adder,
+    up_counter: &Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     let poll = Poll::new()?;
@@ -66,25 +72,32 @@ fn start_simple0(
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
     client_cipher: Cipher,
     server_cipher: Cipher,
-    up_counter: &mut SingleU64Adder,
+    up_counter: &Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     let mut buf = [0; 1024 * 16];
+    let mut route_cache = RouteCache::new();
     let fd = device.as_tun_fd();
     fd.set_nonblock()?;
     SourceFd(&fd.as_raw_fd()).register(poll.registry(), FD, Interest::READABLE)?;
     let mut evnets = Events::with_capacity(4);
     #[cfg(not(target_os = "macos"))]
-    let start = 12;
+    let start = HEAD_ROOM;
     #[cfg(target_os = "macos")]
-    let start = 12 - 4;
+    let start = HEAD_ROOM - 4;
+    let watchdog = context.health.watchdog("tun", WATCHDOG_STALE);
     loop {
-        poll.poll(&mut evnets, None)?;
+        poll.poll(&mut evnets, Some(WATCHDOG_INTERVAL))?;
+        watchdog.beat();
         for event in evnets.iter() {
             if event.token() == STOP {
                 return Ok(());
             }
             loop {
+                if let Some(pause) = context.backpressure.pause() {
+                    // 出口已满，暂停读取让网卡队列积压
+                    thread::sleep(pause);
+                }
                 let len = match fd.read(&mut buf[start..]) {
                     Ok(len) => len + start,
                     Err(e) => {
@@ -97,7 +110,7 @@ fn start_simple0(
                 //单线程的
                 up_counter.add(len as u64);
                 // buf是重复利用的，需要重置头部
-                buf[..12].fill(0);
+                buf[..HEAD_ROOM].fill(0);
                 match crate::handle::tun_tap::tun_handler::handle(
                     context,
                     &mut buf,
@@ -110,6 +123,7 @@ fn start_simple0(
                     &client_cipher,
                     &server_cipher,
                     &device_list,
+                    &mut route_cache,
                 ) {
                     Ok(_) => {}
                     Err(e) => {
@@ -123,9 +137,11 @@ fn start_simple0(
 
 pub(crate) fn start_multi(
     stop_manager: StopManager,
+    context: &ChannelContext,
     device: Arc<Device>,
     group_sync_sender: GroupSyncSender<(Vec<u8>, usize)>,
-    up_counter: &mut SingleU64Adder,
+    buffers: BufferSource,
+    up_counter: &Counter,
 ) -> io::Result<()> {
     let poll = Poll::new()?;
     let waker = Arc::new(Waker::new(poll.registry(), STOP)?);
@@ -133,7 +149,14 @@ pub(crate) fn start_multi(
     let worker = stop_manager.add_listener("tun_device".into(), move || {
         let _ = waker.wake();
     })?;
-    if let Err(e) = start_multi0(poll, device, group_sync_sender, up_counter) {
+    if let Err(e) = start_multi0(
+        poll,
+        context,
+        device,
+        group_sync_sender,
+        buffers,
+        up_counter,
+    ) {
         log::error!("{:?}", e);
     };
     worker.stop_all();
@@ -143,26 +166,33 @@ pub(crate) fn start_multi(
 
 fn start_multi0(
     mut poll: Poll,
+    context: &ChannelContext,
     device: Arc<Device>,
     mut group_sync_sender: GroupSyncSender<(Vec<u8>, usize)>,
-    up_counter: &mut SingleU64Adder,
+    buffers: BufferSource,
+    up_counter: &Counter,
 ) -> io::Result<()> {
     let fd = device.as_tun_fd();
     fd.set_nonblock()?;
     SourceFd(&fd.as_raw_fd()).register(poll.registry(), FD, Interest::READABLE)?;
     let mut evnets = Events::with_capacity(4);
-    let mut buf = vec![0; 1024 * 16];
+    let mut buf = buffers.get();
     #[cfg(not(target_os = "macos"))]
-    let start = 12;
+    let start = HEAD_ROOM;
     #[cfg(target_os = "macos")]
-    let start = 12 - 4;
+    let start = HEAD_ROOM - 4;
+    let watchdog = context.health.watchdog("tun", WATCHDOG_STALE);
     loop {
-        poll.poll(&mut evnets, None)?;
+        poll.poll(&mut evnets, Some(WATCHDOG_INTERVAL))?;
+        watchdog.beat();
         for event in evnets.iter() {
             if event.token() == STOP {
                 return Ok(());
             }
             loop {
+                if let Some(pause) = context.backpressure.pause() {
+                    thread::sleep(pause);
+                }
                 let len = match fd.read(&mut buf[start..]) {
                     Ok(len) => len + start,
                     Err(e) => {
@@ -174,10 +204,12 @@ fn start_multi0(
                 };
                 //单线程的
                 up_counter.add(len as u64);
+                // 缓冲区是复用的，需要重置头部
+                buf[..HEAD_ROOM].fill(0);
                 if group_sync_sender.send((buf, len)).is_err() {
                     return Ok(());
                 }
-                buf = vec![0; 1024 * 16];
+                buf = buffers.get();
             }
         }
     }
@@ -1,15 +1,20 @@
 use crate::channel::context::ChannelContext;
+use crate::channel::route_cache::RouteCache;
 use crate::cipher::Cipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::tun_tap::channel_group::GroupSyncSender;
+use crate::handle::tun_tap::tun_handler::HEAD_ROOM;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::buffer_pool::BufferSource;
+use crate::util::metrics::Counter;
+use crate::util::StopManager;
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 use std::io;
 use std::sync::Arc;
+use std::thread;
 use tun::device::IFace;
 use tun::Device;
 
@@ -22,7 +27,7 @@ pub(crate) fn start_simple(
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
     client_cipher: Cipher,
     server_cipher: Cipher,
-    up_counter: &mut SingleU64Adder,
+    up_counter: &Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     let worker = {
@@ -58,16 +63,21 @@ fn start_simple0(
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
     client_cipher: Cipher,
     server_cipher: Cipher,
-    up_counter: &mut SingleU64Adder,
+    up_counter: &Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     let mut buf = [0; 1024 * 16];
+    let mut route_cache = RouteCache::new();
     loop {
-        let len = device.read(&mut buf[12..])? + 12;
+        if let Some(pause) = context.backpressure.pause() {
+            // 出口已满，暂停读取让网卡队列积压
+            thread::sleep(pause);
+        }
+        let len = device.read(&mut buf[HEAD_ROOM..])? + HEAD_ROOM;
         //单线程的
         up_counter.add(len as u64);
         // buf是重复利用的，需要重置头部
-        buf[..12].fill(0);
+        buf[..HEAD_ROOM].fill(0);
         match crate::handle::tun_tap::tun_handler::handle(
             context,
             &mut buf,
@@ -80,6 +90,7 @@ fn start_simple0(
             &client_cipher,
             &server_cipher,
             &device_list,
+            &mut route_cache,
         ) {
             Ok(_) => {}
             Err(e) => {
@@ -90,9 +101,11 @@ fn start_simple0(
 }
 pub(crate) fn start_multi(
     stop_manager: StopManager,
+    context: &ChannelContext,
     device: Arc<Device>,
     group_sync_sender: GroupSyncSender<(Vec<u8>, usize)>,
-    up_counter: &mut SingleU64Adder,
+    buffers: BufferSource,
+    up_counter: &Counter,
 ) -> io::Result<()> {
     let worker = {
         let device = device.clone();
@@ -102,20 +115,27 @@ pub(crate) fn start_multi(
             }
         })?
     };
-    if let Err(e) = start_multi0(device, group_sync_sender, up_counter) {
+    if let Err(e) = start_multi0(context, device, group_sync_sender, buffers, up_counter) {
         log::error!("{:?}", e);
     };
     worker.stop_all();
     Ok(())
 }
 fn start_multi0(
+    context: &ChannelContext,
     device: Arc<Device>,
     mut group_sync_sender: GroupSyncSender<(Vec<u8>, usize)>,
-    up_counter: &mut SingleU64Adder,
+    buffers: BufferSource,
+    up_counter: &Counter,
 ) -> io::Result<()> {
     loop {
-        let mut buf = vec![0; 1024 * 16];
-        let len = device.read(&mut buf[12..])? + 12;
+        if let Some(pause) = context.backpressure.pause() {
+            thread::sleep(pause);
+        }
+        let mut buf = buffers.get();
+        let len = device.read(&mut buf[HEAD_ROOM..])? + HEAD_ROOM;
+        // 缓冲区是复用的，需要重置头部
+        buf[..HEAD_ROOM].fill(0);
         //单线程的
         up_counter.add(len as u64);
         if group_sync_sender.send((buf, len)).is_err() {
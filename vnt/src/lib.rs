@@ -1,3 +1,16 @@
+//! vnt的核心库，vnt-cli和vnt-jni只负责解析参数和展示
+//!
+//! [`core::Vnt`]是一个运行中的实例，所有状态(设备列表、路由表、nat信息等)都属于实例本身，
+//! 同一个进程中可以用不同的token启动多个实例:
+//! ```ignore
+//! let vnt = vnt::core::Vnt::new(config, callback)?;
+//! let current = vnt.current_device();
+//! for peer in vnt.device_list() {
+//!     let route = vnt.route(&peer.virtual_ip);
+//! }
+//! vnt.stop();
+//! vnt.wait();
+//! ```
 pub const VNT_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 pub mod channel;
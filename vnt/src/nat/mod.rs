@@ -1,8 +1,9 @@
 use anyhow::Context;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
 use std::ops::Sub;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -10,10 +11,13 @@ use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 use rand::Rng;
 
-use crate::channel::punch::{NatInfo, NatType};
+use crate::channel::punch::{NatClass, NatInfo, NatType};
+use crate::channel::socket_pool::SocketPool;
+use crate::proto::message;
 use crate::proto::message::PunchNatType;
 
 mod stun;
+pub mod upnp;
 
 pub fn local_ipv4_() -> io::Result<Ipv4Addr> {
     let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -60,6 +64,10 @@ pub struct NatTest {
     stun_server: Vec<String>,
     info: Arc<Mutex<NatInfo>>,
     time: Arc<AtomicCell<Instant>>,
+    // 服务端或stun看到的公网ip发生了变化，需要重新探测nat类型
+    public_ip_changed: Arc<AtomicBool>,
+    // 路由器上映射的主端口外部地址
+    mapping: Arc<AtomicCell<Option<SocketAddrV4>>>,
     udp_ports: Vec<u16>,
     tcp_port: u16,
 }
@@ -73,6 +81,17 @@ impl From<NatType> for PunchNatType {
     }
 }
 
+impl From<NatClass> for message::NatClass {
+    fn from(value: NatClass) -> Self {
+        match value {
+            NatClass::Unknown => message::NatClass::NatUnknown,
+            NatClass::OpenInternet => message::NatClass::NatOpenInternet,
+            NatClass::Cone => message::NatClass::NatCone,
+            NatClass::Symmetric => message::NatClass::NatSymmetric,
+        }
+    }
+}
+
 impl Into<NatType> for PunchNatType {
     fn into(self) -> NatType {
         match self {
@@ -112,6 +131,8 @@ impl NatTest {
             time: Arc::new(AtomicCell::new(
                 Instant::now().sub(Duration::from_secs(100)),
             )),
+            public_ip_changed: Arc::new(AtomicBool::new(false)),
+            mapping: Arc::new(AtomicCell::new(None)),
             udp_ports,
             tcp_port,
         }
@@ -123,7 +144,15 @@ impl NatTest {
     }
 
     pub fn nat_info(&self) -> NatInfo {
-        self.info.lock().clone()
+        let mut info = self.info.lock().clone();
+        if let Some(external) = self.mapping.load() {
+            info.apply_mapping(external);
+        }
+        info
+    }
+    /// 端口映射成功或失效时更新，探测到的地址保持不变
+    pub fn set_mapping(&self, external: Option<SocketAddrV4>) {
+        self.mapping.store(external);
     }
     pub fn is_local_udp(&self, ipv4: Ipv4Addr, port: u16) -> bool {
         for x in &self.udp_ports {
@@ -187,14 +216,28 @@ impl NatTest {
     }
     pub fn update_addr(&self, index: usize, ip: Ipv4Addr, port: u16) {
         let mut guard = self.info.lock();
-        guard.update_addr(index, ip, port)
+        let before = guard.public_ips.len();
+        guard.update_addr(index, ip, port);
+        // 初次探测之前的地址不算变化
+        if before != 0 && guard.public_ips.len() != before {
+            self.public_ip_changed.store(true, Ordering::Relaxed);
+        }
+    }
+    pub fn public_ip_changed(&self) -> bool {
+        self.public_ip_changed.load(Ordering::Relaxed)
+    }
+    pub fn nat_class(&self) -> NatClass {
+        self.nat_info().nat_class()
     }
     pub fn re_test(
         &self,
         local_ipv4: Option<Ipv4Addr>,
         ipv6: Option<Ipv6Addr>,
+        socket_pool: &SocketPool,
     ) -> io::Result<NatInfo> {
-        let (nat_type, public_ips, port_range) = stun::stun_test_nat(self.stun_server.clone())?;
+        self.public_ip_changed.store(false, Ordering::Relaxed);
+        let (nat_type, public_ips, port_range) =
+            stun::stun_test_nat(self.stun_server.clone(), socket_pool)?;
         let mut guard = self.info.lock();
         guard.nat_type = nat_type;
         guard.public_ips = public_ips;
@@ -4,15 +4,21 @@ use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Duration;
 
 use crate::channel::punch::NatType;
+use crate::channel::socket_pool::{PooledSocket, SocketPool, SocketPurpose};
 use rand::RngCore;
-use std::net::UdpSocket;
 use stun_format::Attr;
 
-pub fn stun_test_nat(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+pub fn stun_test_nat(
+    stun_servers: Vec<String>,
+    socket_pool: &SocketPool,
+) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
     let mut th = Vec::new();
     for _ in 0..2 {
         let stun_servers = stun_servers.clone();
-        let handle = std::thread::spawn(move || stun_test_nat0(stun_servers));
+        let socket_pool = socket_pool.clone();
+        let handle = std::thread::Builder::new()
+            .name("stunTest".into())
+            .spawn(move || stun_test_nat0(stun_servers, &socket_pool))?;
         th.push(handle);
     }
     let mut nat_type = NatType::Cone;
@@ -39,8 +45,11 @@ pub fn stun_test_nat(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4
     Ok((nat_type, hash_set.into_iter().collect(), port_range))
 }
 
-pub fn stun_test_nat0(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
-    let udp = UdpSocket::bind("0.0.0.0:0")?;
+pub fn stun_test_nat0(
+    stun_servers: Vec<String>,
+    socket_pool: &SocketPool,
+) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+    let udp = socket_pool.acquire(SocketPurpose::Stun)?;
     udp.set_read_timeout(Some(Duration::from_millis(500)))?;
     let mut nat_type = NatType::Cone;
     let mut min_port = u16::MAX;
@@ -78,7 +87,7 @@ pub fn stun_test_nat0(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv
     ))
 }
 
-fn test_nat(udp: &UdpSocket, stun_server: &String) -> io::Result<HashSet<SocketAddr>> {
+fn test_nat(udp: &PooledSocket, stun_server: &String) -> io::Result<HashSet<SocketAddr>> {
     udp.connect(stun_server)?;
     let tid = rand::thread_rng().next_u64() as u128;
     let mut addr = HashSet::new();
@@ -111,7 +120,7 @@ fn test_nat(udp: &UdpSocket, stun_server: &String) -> io::Result<HashSet<SocketA
 }
 
 fn test_nat_(
-    udp: &UdpSocket,
+    udp: &PooledSocket,
     change_ip: bool,
     change_port: bool,
     tid: u128,
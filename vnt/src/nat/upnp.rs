@@ -0,0 +1,733 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::nat::NatTest;
+
+/// 请求的租期，路由器可能给出更短的
+const LEASE: Duration = Duration::from_secs(3600);
+/// 只支持永久映射的路由器也定时重新添加，路由器重启后能恢复
+const PERMANENT_REFRESH: Duration = Duration::from_secs(30 * 60);
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+const NAT_PMP_PORT: u16 = 5351;
+/// 外部端口被占用时随机换端口重试的次数
+const CONFLICT_RETRY: usize = 3;
+const DESCRIPTION: &str = "vnt";
+
+/// 映射的方式
+#[derive(Clone, Debug)]
+enum Method {
+    Upnp {
+        control_url: String,
+        service: String,
+        internal: Ipv4Addr,
+    },
+    NatPmp {
+        gateway: Ipv4Addr,
+    },
+}
+
+impl Method {
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Upnp { .. } => "upnp",
+            Method::NatPmp { .. } => "nat-pmp",
+        }
+    }
+}
+
+struct Active {
+    method: Method,
+    external: SocketAddrV4,
+    // 0表示永久
+    lease: Duration,
+    refresh_at: Instant,
+}
+
+/// 当前生效的映射，用于显示
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pub method: &'static str,
+    pub external: SocketAddrV4,
+    pub lease: Duration,
+}
+
+/// 在路由器上映射主udp端口
+///
+/// 启动时先用UPnP IGD映射，失败再用NAT-PMP，成功后把外部地址作为公网地址的一部分发给对端，
+/// 由心跳线程在租期过半时续期，退出时删除映射。任何一步失败都只记录日志，不影响原有的打洞和中继
+#[derive(Clone)]
+pub struct Mapper {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    local_port: u16,
+    // 到服务器的出口网关，NAT-PMP发往这里
+    gateway: Option<Ipv4Addr>,
+    nat_test: NatTest,
+    state: Mutex<Option<Active>>,
+    released: AtomicBool,
+}
+
+impl Mapper {
+    pub fn new(local_port: u16, gateway: Option<Ipv4Addr>, nat_test: NatTest) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                local_port,
+                gateway,
+                nat_test,
+                state: Mutex::new(None),
+                released: AtomicBool::new(false),
+            }),
+        }
+    }
+    /// 添加映射，阻塞几秒，在单独的线程中调用
+    pub fn map(&self) {
+        let mut errors = Vec::new();
+        let mut ssdp_gateway = None;
+        let active = match self.map_upnp(&mut ssdp_gateway) {
+            Ok(active) => Some(active),
+            Err(e) => {
+                errors.push(format!("upnp: {}", e));
+                match self.inner.gateway.or(ssdp_gateway) {
+                    Some(gateway) => match self.map_nat_pmp(gateway) {
+                        Ok(active) => Some(active),
+                        Err(e) => {
+                            errors.push(format!("nat-pmp {}: {}", gateway, e));
+                            None
+                        }
+                    },
+                    None => {
+                        errors.push("nat-pmp: gateway unknown".to_string());
+                        None
+                    }
+                }
+            }
+        };
+        let active = match active {
+            Some(active) => active,
+            None => {
+                log::warn!("端口映射失败,继续使用打洞和中继 {}", errors.join("; "));
+                return;
+            }
+        };
+        if self.inner.released.load(Ordering::Acquire) {
+            // 映射过程中已经退出
+            let _ = delete(
+                &active.method,
+                self.inner.local_port,
+                active.external.port(),
+            );
+            return;
+        }
+        log::info!(
+            "端口映射成功 {} {} -> {} 租期{:?}",
+            active.method.name(),
+            active.external,
+            self.inner.local_port,
+            active.lease
+        );
+        self.inner.nat_test.set_mapping(Some(active.external));
+        self.inner.state.lock().replace(active);
+    }
+    /// 心跳线程调用，租期过半时续期，续期失败时重新发现路由器
+    pub fn refresh(&self) {
+        let (method, port) = {
+            let guard = self.inner.state.lock();
+            match guard.as_ref() {
+                Some(active) if Instant::now() >= active.refresh_at => {
+                    (active.method.clone(), active.external.port())
+                }
+                _ => return,
+            }
+        };
+        match add(&method, self.inner.local_port, port) {
+            Ok((external, lease)) => {
+                log::debug!("端口映射续期 {} {}", method.name(), external);
+                self.inner.nat_test.set_mapping(Some(external));
+                self.inner.state.lock().replace(Active {
+                    method,
+                    external,
+                    lease,
+                    refresh_at: refresh_at(lease),
+                });
+            }
+            Err(e) => {
+                log::warn!("端口映射续期失败 {} {:?},重新映射", method.name(), e);
+                self.inner.nat_test.set_mapping(None);
+                self.inner.state.lock().take();
+                self.map();
+            }
+        }
+    }
+    /// 退出时删除映射
+    pub fn release(&self) {
+        self.inner.released.store(true, Ordering::Release);
+        let active = self.inner.state.lock().take();
+        if let Some(active) = active {
+            self.inner.nat_test.set_mapping(None);
+            match delete(
+                &active.method,
+                self.inner.local_port,
+                active.external.port(),
+            ) {
+                Ok(()) => log::info!("删除端口映射 {}", active.external),
+                Err(e) => log::warn!("删除端口映射失败 {} {:?}", active.external, e),
+            }
+        }
+    }
+    pub fn mapping(&self) -> Option<Mapping> {
+        self.inner.state.lock().as_ref().map(|active| Mapping {
+            method: active.method.name(),
+            external: active.external,
+            lease: active.lease,
+        })
+    }
+    fn map_upnp(&self, ssdp_gateway: &mut Option<Ipv4Addr>) -> io::Result<Active> {
+        let (location, responder) = discover()?;
+        *ssdp_gateway = Some(responder);
+        let (host, path) = split_url(&location)?;
+        let (status, body) = http(
+            &host,
+            &format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host),
+        )?;
+        if status != 200 {
+            return Err(other(format!("description {} status {}", location, status)));
+        }
+        let (service, control) = control_url(&body)
+            .ok_or_else(|| other(format!("no WAN connection service in {}", location)))?;
+        let control_url = resolve_url(&host, &control);
+        // 路由器看到的本机地址，即映射的内部地址
+        let internal = local_ip_towards(&host)?;
+        let method = Method::Upnp {
+            control_url,
+            service,
+            internal,
+        };
+        let mut port = self.inner.local_port;
+        let mut last_err = None;
+        for _ in 0..=CONFLICT_RETRY {
+            match add(&method, self.inner.local_port, port) {
+                Ok((external, lease)) => {
+                    return Ok(Active {
+                        method,
+                        external,
+                        lease,
+                        refresh_at: refresh_at(lease),
+                    })
+                }
+                Err(e) => {
+                    // 718:外部端口已被其他映射占用
+                    if soap_error_code(&e) != Some(718) {
+                        return Err(e);
+                    }
+                    last_err = Some(e);
+                    port = rand::random::<u16>().max(1024);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| other("port conflict".into())))
+    }
+    fn map_nat_pmp(&self, gateway: Ipv4Addr) -> io::Result<Active> {
+        let method = Method::NatPmp { gateway };
+        let (external, lease) = add(&method, self.inner.local_port, self.inner.local_port)?;
+        Ok(Active {
+            method,
+            external,
+            lease,
+            refresh_at: refresh_at(lease),
+        })
+    }
+}
+
+fn refresh_at(lease: Duration) -> Instant {
+    if lease.is_zero() {
+        Instant::now() + PERMANENT_REFRESH
+    } else {
+        Instant::now() + lease / 2
+    }
+}
+
+fn other(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, reason)
+}
+
+/// 添加或续期映射，返回外部地址和租期
+fn add(
+    method: &Method,
+    local_port: u16,
+    external_port: u16,
+) -> io::Result<(SocketAddrV4, Duration)> {
+    match method {
+        Method::Upnp {
+            control_url,
+            service,
+            internal,
+        } => {
+            let ip = soap(control_url, service, "GetExternalIPAddress", "")?;
+            let ip: Ipv4Addr = xml_tag(&ip, "NewExternalIPAddress")
+                .and_then(|v| v.trim().parse().ok())
+                .ok_or_else(|| other("no external ip".into()))?;
+            check_public(ip)?;
+            let mut lease = LEASE;
+            let args = |lease: Duration| {
+                format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>UDP</NewProtocol><NewInternalPort>{}</NewInternalPort>\
+                     <NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+                     <NewPortMappingDescription>{}</NewPortMappingDescription>\
+                     <NewLeaseDuration>{}</NewLeaseDuration>",
+                    external_port,
+                    local_port,
+                    internal,
+                    DESCRIPTION,
+                    lease.as_secs()
+                )
+            };
+            if let Err(e) = soap(control_url, service, "AddPortMapping", &args(lease)) {
+                // 725:只支持永久映射
+                if soap_error_code(&e) != Some(725) {
+                    return Err(e);
+                }
+                lease = Duration::ZERO;
+                soap(control_url, service, "AddPortMapping", &args(lease))?;
+            }
+            Ok((SocketAddrV4::new(ip, external_port), lease))
+        }
+        Method::NatPmp { gateway } => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(SocketAddrV4::new(*gateway, NAT_PMP_PORT))?;
+            let response = nat_pmp_request(&socket, &[0, 0])?;
+            let ip = parse_nat_pmp_address(&response)?;
+            check_public(ip)?;
+            let request = nat_pmp_map_request(local_port, external_port, LEASE);
+            let response = nat_pmp_request(&socket, &request)?;
+            let (port, lease) = parse_nat_pmp_map(&response, local_port)?;
+            Ok((SocketAddrV4::new(ip, port), lease))
+        }
+    }
+}
+
+fn delete(method: &Method, local_port: u16, external_port: u16) -> io::Result<()> {
+    match method {
+        Method::Upnp {
+            control_url,
+            service,
+            ..
+        } => {
+            let args = format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                 <NewProtocol>UDP</NewProtocol>",
+                external_port
+            );
+            soap(control_url, service, "DeletePortMapping", &args)?;
+            Ok(())
+        }
+        Method::NatPmp { gateway } => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(SocketAddrV4::new(*gateway, NAT_PMP_PORT))?;
+            // 租期为0表示删除，外部端口必须为0
+            let request = nat_pmp_map_request(local_port, 0, Duration::ZERO);
+            nat_pmp_request(&socket, &request)?;
+            Ok(())
+        }
+    }
+}
+
+/// 路由器在多层nat之后时映射没有意义
+fn check_public(ip: Ipv4Addr) -> io::Result<()> {
+    if ip.is_private()
+        || ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_link_local()
+        // 运营商级nat的100.64.0.0/10
+        || (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64)
+    {
+        return Err(other(format!("external ip {} is not public", ip)));
+    }
+    Ok(())
+}
+
+/// 发送SSDP搜索，返回第一个网关的描述地址和网关ip
+fn discover() -> io::Result<(String, Ipv4Addr)> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    for st in [
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+    ] {
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+            SSDP_ADDR, st
+        );
+        socket.send_to(request.as_bytes(), SSDP_ADDR)?;
+    }
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    while Instant::now() < deadline {
+        match socket.recv_from(&mut buf) {
+            Ok((len, SocketAddr::V4(addr))) => {
+                let response = String::from_utf8_lossy(&buf[..len]);
+                if let Some(location) = ssdp_location(&response) {
+                    return Ok((location, *addr.ip()));
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no gateway answered ssdp search",
+    ))
+}
+
+/// 网关的回应中的LOCATION，不区分大小写
+fn ssdp_location(response: &str) -> Option<String> {
+    let mut lines = response.lines();
+    if !lines.next()?.contains(" 200") {
+        return None;
+    }
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("location") {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+/// 拆分http地址，返回host:port和路径
+fn split_url(url: &str) -> io::Result<(String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| other(format!("unsupported url {}", url)))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path.to_string()))
+}
+
+/// 控制地址可能是相对路径
+fn resolve_url(host: &str, url: &str) -> String {
+    if url.starts_with("http://") {
+        url.to_string()
+    } else if url.starts_with('/') {
+        format!("http://{}{}", host, url)
+    } else {
+        format!("http://{}/{}", host, url)
+    }
+}
+
+fn xml_tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(&xml[start..end])
+}
+
+/// 从设备描述中找出WAN连接服务的类型和控制地址
+fn control_url(description: &str) -> Option<(String, String)> {
+    let mut rest = description;
+    while let Some(start) = rest.find("<service>") {
+        let block = &rest[start..];
+        let end = block.find("</service>").map_or(block.len(), |v| v + 10);
+        let service = &block[..end];
+        if let Some(service_type) = xml_tag(service, "serviceType") {
+            let service_type = service_type.trim();
+            if service_type.contains(":WANIPConnection:")
+                || service_type.contains(":WANPPPConnection:")
+            {
+                let control = xml_tag(service, "controlURL")?.trim();
+                return Some((service_type.to_string(), control.to_string()));
+            }
+        }
+        rest = &block[end..];
+    }
+    None
+}
+
+/// 到路由器的连接使用的本机地址
+fn local_ip_towards(host: &str) -> io::Result<Ipv4Addr> {
+    let addr = resolve(host)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(addr) => Err(other(format!("local address {}", addr))),
+    }
+}
+
+fn resolve(host: &str) -> io::Result<SocketAddr> {
+    host.to_socket_addrs()?
+        .find(|v| v.is_ipv4())
+        .ok_or_else(|| other(format!("resolve {}", host)))
+}
+
+fn http(host: &str, request: &str) -> io::Result<(u16, String)> {
+    let addr = resolve(host)?;
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    // 描述文件一般只有几KB，限制大小避免异常的设备占用内存
+    stream.take(256 * 1024).read_to_end(&mut response)?;
+    parse_http(&String::from_utf8_lossy(&response))
+}
+
+fn parse_http(response: &str) -> io::Result<(u16, String)> {
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| other("invalid http response".into()))?;
+    let body = response
+        .split_once("\r\n\r\n")
+        .map_or("", |(_, body)| body)
+        .to_string();
+    Ok((status, body))
+}
+
+/// 调用控制服务，失败时错误信息中带上UPnP错误码
+fn soap(control_url: &str, service: &str, action: &str, args: &str) -> io::Result<String> {
+    let (host, path) = split_url(control_url)?;
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>\r\n",
+        action, service, args, action
+    );
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        host,
+        service,
+        action,
+        body.len(),
+        body
+    );
+    let (status, body) = http(&host, &request)?;
+    if status == 200 {
+        return Ok(body);
+    }
+    match xml_tag(&body, "errorCode") {
+        Some(code) => Err(other(format!(
+            "{} upnp error {} {}",
+            action,
+            code.trim(),
+            xml_tag(&body, "errorDescription").unwrap_or("").trim()
+        ))),
+        None => Err(other(format!("{} http status {}", action, status))),
+    }
+}
+
+fn soap_error_code(e: &io::Error) -> Option<u32> {
+    let text = e.to_string();
+    let (_, rest) = text.split_once("upnp error ")?;
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+/*
+   NAT-PMP(RFC 6886)，udp发往网关的5351端口
+   查询外部地址：版本(8)=0 | 操作(8)=0
+   回应：版本(8) | 操作(8)=128 | 结果(16) | 秒数(32) | 外部ip(32)
+   映射udp：版本(8) | 操作(8)=1 | 保留(16) | 内部端口(16) | 建议的外部端口(16) | 租期(32)
+   回应：版本(8) | 操作(8)=129 | 结果(16) | 秒数(32) | 内部端口(16) | 外部端口(16) | 租期(32)
+*/
+fn nat_pmp_map_request(local_port: u16, external_port: u16, lease: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = 1;
+    request[4..6].copy_from_slice(&local_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+    request
+}
+
+/// 按RFC从250ms开始倍增重传，这里最多等待约2秒
+fn nat_pmp_request(socket: &UdpSocket, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut timeout = Duration::from_millis(250);
+    let mut buf = [0u8; 16];
+    for _ in 0..3 {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            Ok(len) => return Ok(buf[..len].to_vec()),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                timeout *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "gateway did not answer",
+    ))
+}
+
+fn nat_pmp_result(response: &[u8], op: u8, len: usize) -> io::Result<()> {
+    if response.len() < len || response[0] != 0 || response[1] != op {
+        return Err(other(format!("invalid nat-pmp response {:?}", response)));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(other(format!("nat-pmp result {}", result)));
+    }
+    Ok(())
+}
+
+fn parse_nat_pmp_address(response: &[u8]) -> io::Result<Ipv4Addr> {
+    nat_pmp_result(response, 128, 12)?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+fn parse_nat_pmp_map(response: &[u8], local_port: u16) -> io::Result<(u16, Duration)> {
+    nat_pmp_result(response, 129, 16)?;
+    let internal = u16::from_be_bytes([response[8], response[9]]);
+    if internal != local_port {
+        return Err(other(format!("nat-pmp mapped port {}", internal)));
+    }
+    let external = u16::from_be_bytes([response[10], response[11]]);
+    let lease = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((external, Duration::from_secs(lease as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    use crate::channel::punch::NatType;
+    use crate::nat::upnp::{
+        check_public, control_url, nat_pmp_map_request, other, parse_http, parse_nat_pmp_address,
+        parse_nat_pmp_map, resolve_url, soap_error_code, split_url, ssdp_location, xml_tag,
+    };
+    use crate::nat::NatTest;
+
+    #[test]
+    fn test_discovery_parse() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+                        ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        let location = ssdp_location(response).unwrap();
+        assert_eq!(location, "http://192.168.1.1:5000/rootDesc.xml");
+        assert_eq!(ssdp_location("NOTIFY * HTTP/1.1\r\nLOCATION: x\r\n"), None);
+        let (host, path) = split_url(&location).unwrap();
+        assert_eq!(host, "192.168.1.1:5000");
+        assert_eq!(path, "/rootDesc.xml");
+        assert_eq!(split_url("http://10.0.0.1").unwrap().0, "10.0.0.1:80");
+        assert!(split_url("https://10.0.0.1/").is_err());
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let (service, control) = control_url(description).unwrap();
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(
+            resolve_url(&host, &control),
+            "http://192.168.1.1:5000/ctl/IPConn"
+        );
+        assert_eq!(resolve_url(&host, "ctl"), "http://192.168.1.1:5000/ctl");
+        assert_eq!(control_url("<root></root>"), None);
+    }
+
+    #[test]
+    fn test_soap_parse() {
+        let (status, body) = parse_http(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n<s:Envelope><s:Body>\
+             <u:GetExternalIPAddressResponse><NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+             </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+        )
+        .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(xml_tag(&body, "NewExternalIPAddress"), Some("203.0.113.7"));
+        let e = other("AddPortMapping upnp error 718 ConflictInMappingEntry".into());
+        assert_eq!(soap_error_code(&e), Some(718));
+        assert_eq!(soap_error_code(&other("timeout".into())), None);
+        assert!(check_public(Ipv4Addr::new(203, 0, 113, 7)).is_ok());
+        // 多层nat时路由器的外部地址不是公网地址
+        assert!(check_public(Ipv4Addr::new(192, 168, 0, 2)).is_err());
+        assert!(check_public(Ipv4Addr::new(100, 64, 0, 2)).is_err());
+    }
+
+    #[test]
+    fn test_nat_pmp() {
+        let request = nat_pmp_map_request(29872, 29872, Duration::from_secs(3600));
+        assert_eq!(
+            request,
+            [0, 1, 0, 0, 0x74, 0xb0, 0x74, 0xb0, 0, 0, 0x0e, 0x10]
+        );
+        let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(
+            parse_nat_pmp_address(&address).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 7)
+        );
+        let map = [
+            0, 129, 0, 0, 0, 0, 0, 9, 0x74, 0xb0, 0x9c, 0x40, 0, 0, 0x07, 0x08,
+        ];
+        assert_eq!(
+            parse_nat_pmp_map(&map, 29872).unwrap(),
+            (40000, Duration::from_secs(1800))
+        );
+        assert!(parse_nat_pmp_map(&map, 1).is_err());
+        // 结果码不为0
+        let refused = [0, 129, 0, 2, 0, 0, 0, 9, 0x74, 0xb0, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_map(&refused, 29872).is_err());
+        assert!(parse_nat_pmp_address(&address[..8]).is_err());
+    }
+
+    /// 映射的外部地址和服务端看到的地址一起发给对端，按锥形打洞
+    #[test]
+    fn test_overlay() {
+        let nat_test = NatTest::new(
+            1,
+            Vec::new(),
+            Some(Ipv4Addr::new(192, 168, 1, 3)),
+            None,
+            vec![29872],
+            29873,
+        );
+        nat_test.update_addr(0, Ipv4Addr::new(198, 51, 100, 1), 51000);
+        let external = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 29872);
+        nat_test.set_mapping(Some(external));
+        let info = nat_test.nat_info();
+        assert_eq!(
+            info.public_ips,
+            vec![
+                Ipv4Addr::new(203, 0, 113, 7),
+                Ipv4Addr::new(198, 51, 100, 1)
+            ]
+        );
+        assert_eq!(info.public_ports, vec![29872]);
+        assert_eq!(info.nat_type, NatType::Cone);
+        nat_test.set_mapping(None);
+        assert_eq!(nat_test.nat_info().public_ports, vec![51000]);
+    }
+}
@@ -86,6 +86,17 @@ impl<B: AsRef<[u8]>> SecretBody<B> {
     pub fn buffer(&self) -> &[u8] {
         self.buffer.as_ref()
     }
+    /// 只认证不加密时的附加数据: 协议头+数据部分
+    ///
+    /// 剩余ttl在转发时会变化，不参与认证
+    pub fn auth_only_aad(&self, head: &[u8]) -> Vec<u8> {
+        let body = self.body();
+        let mut aad = Vec::with_capacity(head.len() + body.len());
+        aad.extend_from_slice(head);
+        aad[3] &= super::MAX_SOURCE;
+        aad.extend_from_slice(body);
+        aad
+    }
 }
 
 impl<B: AsRef<[u8]> + AsMut<[u8]>> SecretBody<B> {
@@ -0,0 +1,579 @@
+use std::io;
+
+use protobuf::Message;
+
+use crate::proto::message::{DeviceInfo, DeviceList, PunchInfo, ServerNotice};
+use crate::protocol::{other_turn_packet, service_packet};
+
+/*
+   控制面消息的紧凑编码，和protobuf共用proto中的消息结构，只改变线上格式
+   整数使用varint，有符号数和差值先做zigzag，字符串和字节为 长度(varint)+内容
+
+   设备列表：
+   +--------+-----------+---------+------------------------------------+-------------+---------+
+   | 版本(1) | 纪元(var) | 公告(1) | [公告内容(字符串) 维护时间(zigzag)] | 设备数(var) | 设备... |
+   +--------+-----------+---------+------------------------------------+-------------+---------+
+   设备：ip差值(zigzag，和上一个设备相减) | 标志(1) | [状态(var)] | 名称 | [密钥哈希] | [指纹] | [最近的服务器]
+   设备按服务端给出的顺序编码，列表按ip排序时差值通常只占1字节；
+   密钥哈希和最近的服务器和上一个设备相同时只设置标志，不重复写入
+
+   打洞信息：
+   版本(1) | 标志(1) | nat类型(zigzag) | 公网端口 | 端口范围 | 本地ip(4) | 本地端口 | tcp端口 | 服务器延迟 | 打洞时刻
+   | 公网ip数 公网ip(4)... | 本地端口数 端口差值... | 公网端口数 端口差值... | [ipv6 ipv6端口]
+
+   注：解码时设备数量只受载荷长度约束，设备数上限由Directory在解码之后统一截断，和编码方式无关
+*/
+const VERSION: u8 = 1;
+
+const DEVICE_CLIENT_SECRET: u8 = 0x01;
+const DEVICE_OBSERVER: u8 = 0x02;
+const DEVICE_STATUS: u8 = 0x04;
+const DEVICE_HASH: u8 = 0x08;
+const DEVICE_SAME_HASH: u8 = 0x10;
+const DEVICE_FINGERPRINT: u8 = 0x20;
+const DEVICE_SERVER: u8 = 0x40;
+const DEVICE_SAME_SERVER: u8 = 0x80;
+/// 一个设备最少占用的字节数：ip差值、标志、名称长度
+const MIN_DEVICE_LEN: usize = 3;
+
+const PUNCH_REPLY: u8 = 0x01;
+const PUNCH_RELAY_ONLY: u8 = 0x02;
+const PUNCH_IPV6: u8 = 0x04;
+const PUNCH_COMPACT: u8 = 0x08;
+
+/// 控制面消息的编码方式
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// 默认的编码，所有版本都支持，对端没有声明支持紧凑编码时使用
+    Protobuf,
+    /// 差值和varint压缩的编码，适合设备多、链路带宽小的网络
+    Compact,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Protobuf
+    }
+}
+
+impl Encoding {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Encoding::Protobuf => &Protobuf,
+            Encoding::Compact => &Compact,
+        }
+    }
+    /// 对端声明支持时使用紧凑编码，否则回退到protobuf
+    pub fn negotiate(local: bool, peer: bool) -> Encoding {
+        if local && peer {
+            Encoding::Compact
+        } else {
+            Encoding::Protobuf
+        }
+    }
+    pub fn push_device_list(self) -> service_packet::Protocol {
+        match self {
+            Encoding::Protobuf => service_packet::Protocol::PushDeviceList,
+            Encoding::Compact => service_packet::Protocol::PushDeviceListCompact,
+        }
+    }
+    pub fn punch(self) -> other_turn_packet::Protocol {
+        match self {
+            Encoding::Protobuf => other_turn_packet::Protocol::Punch,
+            Encoding::Compact => other_turn_packet::Protocol::PunchCompact,
+        }
+    }
+    pub fn of_device_list(protocol: service_packet::Protocol) -> Option<Encoding> {
+        match protocol {
+            service_packet::Protocol::PushDeviceList => Some(Encoding::Protobuf),
+            service_packet::Protocol::PushDeviceListCompact => Some(Encoding::Compact),
+            _ => None,
+        }
+    }
+    pub fn of_punch(protocol: other_turn_packet::Protocol) -> Option<Encoding> {
+        match protocol {
+            other_turn_packet::Protocol::Punch => Some(Encoding::Protobuf),
+            other_turn_packet::Protocol::PunchCompact => Some(Encoding::Compact),
+            _ => None,
+        }
+    }
+}
+
+/// 设备列表和打洞信息的编解码
+pub trait Codec: Sync {
+    fn encode_device_list(&self, list: &DeviceList) -> io::Result<Vec<u8>>;
+    fn decode_device_list(&self, buf: &[u8]) -> io::Result<DeviceList>;
+    fn encode_punch(&self, punch: &PunchInfo) -> io::Result<Vec<u8>>;
+    fn decode_punch(&self, buf: &[u8]) -> io::Result<PunchInfo>;
+}
+
+pub struct Protobuf;
+
+impl Codec for Protobuf {
+    fn encode_device_list(&self, list: &DeviceList) -> io::Result<Vec<u8>> {
+        list.write_to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("DeviceList {:?}", e)))
+    }
+    fn decode_device_list(&self, buf: &[u8]) -> io::Result<DeviceList> {
+        DeviceList::parse_from_bytes(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("DeviceList {:?}", e)))
+    }
+    fn encode_punch(&self, punch: &PunchInfo) -> io::Result<Vec<u8>> {
+        punch
+            .write_to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("PunchInfo {:?}", e)))
+    }
+    fn decode_punch(&self, buf: &[u8]) -> io::Result<PunchInfo> {
+        PunchInfo::parse_from_bytes(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("PunchInfo {:?}", e)))
+    }
+}
+
+pub struct Compact;
+
+impl Codec for Compact {
+    fn encode_device_list(&self, list: &DeviceList) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(16 + list.device_info_list.len() * 16);
+        out.push(VERSION);
+        put_varint(&mut out, list.epoch as u64);
+        match list.notice.as_ref() {
+            Some(notice) => {
+                out.push(1);
+                put_bytes(&mut out, notice.message.as_bytes());
+                put_varint(&mut out, zigzag(notice.maintenance_at));
+            }
+            None => out.push(0),
+        }
+        put_varint(&mut out, list.device_info_list.len() as u64);
+        let mut prev: Option<&DeviceInfo> = None;
+        for device in &list.device_info_list {
+            let prev_ip = prev.map_or(0, |v| v.virtual_ip);
+            put_varint(&mut out, zigzag(device.virtual_ip as i64 - prev_ip as i64));
+            let same_hash = !device.client_secret_hash.is_empty()
+                && prev.map_or(false, |v| v.client_secret_hash == device.client_secret_hash);
+            let same_server = !device.closest_server.is_empty()
+                && prev.map_or(false, |v| v.closest_server == device.closest_server);
+            let mut flags = 0;
+            if device.client_secret {
+                flags |= DEVICE_CLIENT_SECRET;
+            }
+            if device.observer {
+                flags |= DEVICE_OBSERVER;
+            }
+            if device.device_status != 0 {
+                flags |= DEVICE_STATUS;
+            }
+            if same_hash {
+                flags |= DEVICE_SAME_HASH;
+            } else if !device.client_secret_hash.is_empty() {
+                flags |= DEVICE_HASH;
+            }
+            if !device.fingerprint.is_empty() {
+                flags |= DEVICE_FINGERPRINT;
+            }
+            if same_server {
+                flags |= DEVICE_SAME_SERVER;
+            } else if !device.closest_server.is_empty() {
+                flags |= DEVICE_SERVER;
+            }
+            out.push(flags);
+            if flags & DEVICE_STATUS != 0 {
+                put_varint(&mut out, device.device_status as u64);
+            }
+            put_bytes(&mut out, device.name.as_bytes());
+            if flags & DEVICE_HASH != 0 {
+                put_bytes(&mut out, &device.client_secret_hash);
+            }
+            if flags & DEVICE_FINGERPRINT != 0 {
+                put_bytes(&mut out, device.fingerprint.as_bytes());
+            }
+            if flags & DEVICE_SERVER != 0 {
+                put_bytes(&mut out, device.closest_server.as_bytes());
+            }
+            prev = Some(device);
+        }
+        Ok(out)
+    }
+    fn decode_device_list(&self, buf: &[u8]) -> io::Result<DeviceList> {
+        let mut reader = Reader::new(buf);
+        reader.version()?;
+        let mut list = DeviceList::new();
+        list.epoch = reader.u32()?;
+        match reader.u8()? {
+            0 => {}
+            1 => {
+                let mut notice = ServerNotice::new();
+                notice.message = reader.string()?;
+                notice.maintenance_at = unzigzag(reader.varint()?);
+                list.notice = Some(notice).into();
+            }
+            v => return Err(invalid(format!("notice flag {}", v))),
+        }
+        let count = reader.varint()?;
+        // 每个设备至少占用几个字节，超过载荷能容纳的数量直接拒绝，避免按声明的数量分配内存
+        if count > (reader.remaining() / MIN_DEVICE_LEN) as u64 {
+            return Err(invalid(format!("device count {}", count)));
+        }
+        list.device_info_list.reserve(count as usize);
+        let mut prev_ip = 0u32;
+        for _ in 0..count {
+            let ip = prev_ip as i64 + unzigzag(reader.varint()?);
+            let ip = u32::try_from(ip).map_err(|_| invalid(format!("virtual ip {}", ip)))?;
+            let flags = reader.u8()?;
+            let mut device = DeviceInfo::new();
+            device.virtual_ip = ip;
+            device.client_secret = flags & DEVICE_CLIENT_SECRET != 0;
+            device.observer = flags & DEVICE_OBSERVER != 0;
+            if flags & DEVICE_STATUS != 0 {
+                device.device_status = reader.u32()?;
+            }
+            device.name = reader.string()?;
+            let prev = list.device_info_list.last();
+            if flags & DEVICE_SAME_HASH != 0 {
+                device.client_secret_hash = prev
+                    .map(|v| v.client_secret_hash.clone())
+                    .ok_or_else(|| invalid("same hash without previous".into()))?;
+            } else if flags & DEVICE_HASH != 0 {
+                device.client_secret_hash = reader.bytes()?.to_vec();
+            }
+            if flags & DEVICE_FINGERPRINT != 0 {
+                device.fingerprint = reader.string()?;
+            }
+            if flags & DEVICE_SAME_SERVER != 0 {
+                device.closest_server = prev
+                    .map(|v| v.closest_server.clone())
+                    .ok_or_else(|| invalid("same server without previous".into()))?;
+            } else if flags & DEVICE_SERVER != 0 {
+                device.closest_server = reader.string()?;
+            }
+            prev_ip = ip;
+            list.device_info_list.push(device);
+        }
+        reader.finish()?;
+        Ok(list)
+    }
+    fn encode_punch(&self, punch: &PunchInfo) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(64);
+        out.push(VERSION);
+        let mut flags = 0;
+        if punch.reply {
+            flags |= PUNCH_REPLY;
+        }
+        if punch.relay_only {
+            flags |= PUNCH_RELAY_ONLY;
+        }
+        if !punch.ipv6.is_empty() {
+            flags |= PUNCH_IPV6;
+        }
+        if punch.compact_encoding {
+            flags |= PUNCH_COMPACT;
+        }
+        out.push(flags);
+        put_varint(&mut out, zigzag(punch.nat_type.value() as i64));
+        put_varint(&mut out, punch.public_port as u64);
+        put_varint(&mut out, punch.public_port_range as u64);
+        out.extend_from_slice(&punch.local_ip.to_be_bytes());
+        put_varint(&mut out, punch.local_port as u64);
+        put_varint(&mut out, punch.tcp_port as u64);
+        put_varint(&mut out, punch.server_rtt as u64);
+        put_varint(&mut out, punch.fire_at);
+        put_varint(&mut out, punch.public_ip_list.len() as u64);
+        for ip in &punch.public_ip_list {
+            out.extend_from_slice(&ip.to_be_bytes());
+        }
+        put_ports(&mut out, &punch.udp_ports);
+        put_ports(&mut out, &punch.public_ports);
+        if flags & PUNCH_IPV6 != 0 {
+            put_bytes(&mut out, &punch.ipv6);
+            put_varint(&mut out, punch.ipv6_port as u64);
+        }
+        Ok(out)
+    }
+    fn decode_punch(&self, buf: &[u8]) -> io::Result<PunchInfo> {
+        let mut reader = Reader::new(buf);
+        reader.version()?;
+        let flags = reader.u8()?;
+        let mut punch = PunchInfo::new();
+        punch.reply = flags & PUNCH_REPLY != 0;
+        punch.relay_only = flags & PUNCH_RELAY_ONLY != 0;
+        punch.compact_encoding = flags & PUNCH_COMPACT != 0;
+        let nat_type = unzigzag(reader.varint()?);
+        let nat_type = i32::try_from(nat_type).map_err(|_| invalid(format!("nat {}", nat_type)))?;
+        punch.nat_type = protobuf::EnumOrUnknown::from_i32(nat_type);
+        punch.public_port = reader.u32()?;
+        punch.public_port_range = reader.u32()?;
+        punch.local_ip = reader.fixed32()?;
+        punch.local_port = reader.u32()?;
+        punch.tcp_port = reader.u32()?;
+        punch.server_rtt = reader.u32()?;
+        punch.fire_at = reader.varint()?;
+        let count = reader.varint()?;
+        if count > (reader.remaining() / 4) as u64 {
+            return Err(invalid(format!("public ip count {}", count)));
+        }
+        for _ in 0..count {
+            punch.public_ip_list.push(reader.fixed32()?);
+        }
+        punch.udp_ports = reader.ports()?;
+        punch.public_ports = reader.ports()?;
+        if flags & PUNCH_IPV6 != 0 {
+            punch.ipv6 = reader.bytes()?.to_vec();
+            punch.ipv6_port = reader.u32()?;
+        }
+        reader.finish()?;
+        Ok(punch)
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// 端口列表通常是连续的，写入和前一个端口的差值
+fn put_ports(out: &mut Vec<u8>, ports: &[u32]) {
+    put_varint(out, ports.len() as u64);
+    let mut prev = 0u32;
+    for port in ports {
+        put_varint(out, zigzag(*port as i64 - prev as i64));
+        prev = *port;
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+    fn version(&mut self) -> io::Result<()> {
+        match self.u8()? {
+            VERSION => Ok(()),
+            v => Err(invalid(format!("version {}", v))),
+        }
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        let (v, rest) = self
+            .buf
+            .split_first()
+            .ok_or_else(|| invalid("unexpected end".into()))?;
+        self.buf = rest;
+        Ok(*v)
+    }
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(invalid(format!("len {} > {}", len, self.buf.len())));
+        }
+        let (v, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(v)
+    }
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(invalid("varint overflow".into()));
+            }
+            v |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(invalid("varint overflow".into()))
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        let v = self.varint()?;
+        u32::try_from(v).map_err(|_| invalid(format!("u32 {}", v)))
+    }
+    fn fixed32(&mut self) -> io::Result<u32> {
+        let v = self.take(4)?;
+        Ok(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.varint()?;
+        if len > self.buf.len() as u64 {
+            return Err(invalid(format!("len {} > {}", len, self.buf.len())));
+        }
+        self.take(len as usize)
+    }
+    fn string(&mut self) -> io::Result<String> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(format!("{:?}", e)))
+    }
+    fn ports(&mut self) -> io::Result<Vec<u32>> {
+        let count = self.varint()?;
+        if count > self.buf.len() as u64 {
+            return Err(invalid(format!("port count {}", count)));
+        }
+        let mut ports = Vec::with_capacity(count as usize);
+        let mut prev = 0u32;
+        for _ in 0..count {
+            let port = prev as i64 + unzigzag(self.varint()?);
+            prev = u32::try_from(port).map_err(|_| invalid(format!("port {}", port)))?;
+            ports.push(prev);
+        }
+        Ok(ports)
+    }
+    fn finish(&self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid(format!("{} trailing bytes", self.buf.len())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::proto::message::{DeviceInfo, DeviceList, PunchInfo, PunchNatType, ServerNotice};
+    use crate::protocol::codec::{Codec, Compact, Encoding, Protobuf};
+
+    fn device_list(count: u32) -> DeviceList {
+        let mut list = DeviceList::new();
+        list.epoch = 1234;
+        for i in 0..count {
+            let mut device = DeviceInfo::new();
+            device.name = format!("device-{}", i);
+            device.virtual_ip = u32::from(Ipv4Addr::new(10, 26, 0, 2)) + i;
+            device.device_status = i % 3 / 2;
+            device.client_secret = true;
+            device.client_secret_hash = vec![7; 16];
+            if i % 4 == 0 {
+                device.fingerprint = format!("{:016x}", i);
+            }
+            device.closest_server = "1.2.3.4:29872".to_string();
+            list.device_info_list.push(device);
+        }
+        list
+    }
+
+    fn punch_info() -> PunchInfo {
+        let mut punch = PunchInfo::new();
+        punch.public_ip_list = vec![0x01020304, 0x05060708];
+        punch.public_port = 40000;
+        punch.public_ports = vec![40000, 40001, 40007];
+        punch.public_port_range = 100;
+        punch.nat_type = protobuf::EnumOrUnknown::new(PunchNatType::Cone);
+        punch.local_ip = u32::from(Ipv4Addr::new(192, 168, 1, 3));
+        punch.local_port = 29872;
+        punch.udp_ports = vec![29872, 29873];
+        punch.ipv6 = vec![0x20; 16];
+        punch.ipv6_port = 29872;
+        punch.tcp_port = 29872;
+        punch.server_rtt = 35;
+        punch.fire_at = 1_700_000_000_000;
+        punch.compact_encoding = true;
+        punch
+    }
+
+    #[test]
+    fn test_device_list_roundtrip() {
+        let mut list = device_list(50);
+        // 乱序和回退的ip也要能还原
+        list.device_info_list.swap(3, 40);
+        list.device_info_list[5].client_secret_hash.clear();
+        list.device_info_list[6].closest_server.clear();
+        list.device_info_list[7].observer = true;
+        let mut notice = ServerNotice::new();
+        notice.message = "维护".to_string();
+        notice.maintenance_at = -5;
+        list.notice = Some(notice).into();
+        let buf = Compact.encode_device_list(&list).unwrap();
+        assert_eq!(Compact.decode_device_list(&buf).unwrap(), list);
+        let list = DeviceList::new();
+        let buf = Compact.encode_device_list(&list).unwrap();
+        assert_eq!(Compact.decode_device_list(&buf).unwrap(), list);
+    }
+
+    #[test]
+    fn test_punch_roundtrip() {
+        let punch = punch_info();
+        for encoding in [Encoding::Protobuf, Encoding::Compact] {
+            let codec = encoding.codec();
+            let buf = codec.encode_punch(&punch).unwrap();
+            assert_eq!(codec.decode_punch(&buf).unwrap(), punch);
+        }
+        let mut reply = PunchInfo::new();
+        reply.reply = true;
+        reply.relay_only = true;
+        let buf = Compact.encode_punch(&reply).unwrap();
+        assert_eq!(Compact.decode_punch(&buf).unwrap(), reply);
+        assert!(
+            Compact.encode_punch(&punch).unwrap().len()
+                < Protobuf.encode_punch(&punch).unwrap().len()
+        );
+    }
+
+    /// 设备越多，差值和重复字段节省的比例越稳定
+    #[test]
+    fn test_size_reduction() {
+        for count in [100, 1000, 5000] {
+            let list = device_list(count);
+            let protobuf = Protobuf.encode_device_list(&list).unwrap().len();
+            let compact = Compact.encode_device_list(&list).unwrap().len();
+            assert!(
+                compact * 2 < protobuf,
+                "{} devices: compact={} protobuf={}",
+                count,
+                compact,
+                protobuf
+            );
+            assert_eq!(
+                Compact
+                    .decode_device_list(&Compact.encode_device_list(&list).unwrap())
+                    .unwrap()
+                    .device_info_list
+                    .len(),
+                count as usize
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let buf = Compact.encode_device_list(&device_list(10)).unwrap();
+        for len in 0..buf.len() {
+            assert!(Compact.decode_device_list(&buf[..len]).is_err());
+        }
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert!(Compact.decode_device_list(&trailing).is_err());
+        // 声明了大量设备但没有内容，不按声明的数量分配
+        let huge = [1, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F];
+        assert!(Compact.decode_device_list(&huge).is_err());
+        let buf = Compact.encode_punch(&punch_info()).unwrap();
+        for len in 0..buf.len() {
+            assert!(Compact.decode_punch(&buf[..len]).is_err());
+        }
+        assert!(Compact.decode_punch(&[2, 0]).is_err());
+        assert!(Compact.decode_punch(&[1; 64]).is_err());
+    }
+}
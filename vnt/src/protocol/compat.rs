@@ -0,0 +1,241 @@
+use std::io;
+
+use crate::protocol::{Version, HEAD_LEN};
+
+/*
+   V2扩展头，紧跟在12字节的基础头之后
+   0                                            15                                              31
+   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |   扩展头长度(8)      |        标志(8)         |                    序号(16)                    |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  注：扩展头长度包含自身，以4字节对齐，便于后续增加字段时旧的V2实现也能跳过
+  序号只在标志包含FLAG_SEQUENCE时有效，否则为0，旧的V2实现当作保留字段忽略
+*/
+pub const EXT_HEAD_LEN: usize = 4;
+/// 路径切换期间带有序号，用于接收端重排
+pub const FLAG_SEQUENCE: u8 = 0x01;
+
+/// 线上协议头版本
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum WireVersion {
+    /// 原始的12字节协议头(头部版本号为2)，所有客户端都支持
+    V1,
+    /// 12字节协议头+扩展头(头部版本号为3)，只发往确认支持的对端
+    V2,
+}
+
+impl Default for WireVersion {
+    fn default() -> Self {
+        WireVersion::V1
+    }
+}
+
+impl From<u8> for WireVersion {
+    fn from(value: u8) -> Self {
+        match value {
+            2 => WireVersion::V2,
+            // 旧版本不会携带这个字段，值为0
+            _ => WireVersion::V1,
+        }
+    }
+}
+
+impl Into<u8> for WireVersion {
+    fn into(self) -> u8 {
+        match self {
+            WireVersion::V1 => 1,
+            WireVersion::V2 => 2,
+        }
+    }
+}
+
+impl WireVersion {
+    /// 当前客户端支持的最高版本
+    pub const MAX: WireVersion = WireVersion::V2;
+    pub fn is_legacy(&self) -> bool {
+        self == &WireVersion::V1
+    }
+    /// 根据头部版本号判断线上版本
+    pub fn of(buf: &[u8]) -> Option<WireVersion> {
+        if buf.len() < HEAD_LEN {
+            return None;
+        }
+        match Version::from(buf[0] & 0x0F) {
+            Version::V2 => Some(WireVersion::V1),
+            Version::V3 => Some(WireVersion::V2),
+            Version::Unknown(_) => None,
+        }
+    }
+}
+
+/// 将V1格式的包编码成V2格式，写入out，返回编码后的长度
+pub fn encode_v2(buf: &[u8], flags: u8, out: &mut [u8]) -> io::Result<usize> {
+    if buf.len() < HEAD_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 12"));
+    }
+    let len = buf.len() + EXT_HEAD_LEN;
+    if out.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "length overflow",
+        ));
+    }
+    let v: u8 = Version::V3.into();
+    out[..HEAD_LEN].copy_from_slice(&buf[..HEAD_LEN]);
+    out[0] = (out[0] & 0xF0) | (0x0F & v);
+    out[HEAD_LEN] = EXT_HEAD_LEN as u8;
+    out[HEAD_LEN + 1] = flags;
+    out[HEAD_LEN + 2..HEAD_LEN + EXT_HEAD_LEN].fill(0);
+    out[HEAD_LEN + EXT_HEAD_LEN..len].copy_from_slice(&buf[HEAD_LEN..]);
+    Ok(len)
+}
+
+/// 原地编码成V2格式，buf的结构为|EXT_HEAD_LEN字节预留|V1格式的包|，
+/// 只需要把12字节的基础头前移，不复制负载
+pub fn encode_v2_in_place(buf: &mut [u8], flags: u8) -> io::Result<()> {
+    if buf.len() < EXT_HEAD_LEN + HEAD_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 16"));
+    }
+    buf.copy_within(EXT_HEAD_LEN..EXT_HEAD_LEN + HEAD_LEN, 0);
+    let v: u8 = Version::V3.into();
+    buf[0] = (buf[0] & 0xF0) | (0x0F & v);
+    buf[HEAD_LEN] = EXT_HEAD_LEN as u8;
+    buf[HEAD_LEN + 1] = flags;
+    buf[HEAD_LEN + 2..HEAD_LEN + EXT_HEAD_LEN].fill(0);
+    Ok(())
+}
+
+/// encode_v2_in_place的逆操作，恢复成|EXT_HEAD_LEN字节预留|V1格式的包|
+pub fn restore_v1_in_place(buf: &mut [u8]) {
+    if buf.len() < EXT_HEAD_LEN + HEAD_LEN {
+        return;
+    }
+    buf.copy_within(..HEAD_LEN, EXT_HEAD_LEN);
+    let v: u8 = Version::V2.into();
+    buf[EXT_HEAD_LEN] = (buf[EXT_HEAD_LEN] & 0xF0) | (0x0F & v);
+}
+
+/// 给已编码的V2格式的包加上序号
+pub fn set_sequence(buf: &mut [u8], seq: u16) {
+    if WireVersion::of(buf) == Some(WireVersion::V2) && buf.len() >= HEAD_LEN + EXT_HEAD_LEN {
+        buf[HEAD_LEN + 1] |= FLAG_SEQUENCE;
+        buf[HEAD_LEN + 2..HEAD_LEN + 4].copy_from_slice(&seq.to_be_bytes());
+    }
+}
+
+/// V2格式的包中的序号，需要在解码前读取
+pub fn sequence(buf: &[u8]) -> Option<u16> {
+    if WireVersion::of(buf) != Some(WireVersion::V2)
+        || buf.len() < HEAD_LEN + EXT_HEAD_LEN
+        || buf[HEAD_LEN + 1] & FLAG_SEQUENCE == 0
+    {
+        return None;
+    }
+    Some(u16::from_be_bytes([buf[HEAD_LEN + 2], buf[HEAD_LEN + 3]]))
+}
+
+/// 将V2格式的包原地解码成V1格式，返回解码后的长度，其他版本的包原样返回
+pub fn decode_in_place(buf: &mut [u8]) -> io::Result<usize> {
+    match WireVersion::of(buf) {
+        Some(WireVersion::V1) => Ok(buf.len()),
+        Some(WireVersion::V2) => {
+            if buf.len() < HEAD_LEN + EXT_HEAD_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 16"));
+            }
+            let ext_len = buf[HEAD_LEN] as usize;
+            if ext_len < EXT_HEAD_LEN || ext_len % 4 != 0 || HEAD_LEN + ext_len > buf.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "ext head len invalid",
+                ));
+            }
+            let v: u8 = Version::V2.into();
+            buf[0] = (buf[0] & 0xF0) | (0x0F & v);
+            buf.copy_within(HEAD_LEN + ext_len.., HEAD_LEN);
+            Ok(buf.len() - ext_len)
+        }
+        // 未知版本保持原有处理逻辑
+        None => Ok(buf.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v1_packet() -> Vec<u8> {
+        let mut buf = vec![0x82, 4, 4, 0x55, 10, 0, 0, 2, 10, 0, 0, 3];
+        buf.extend_from_slice(b"payload");
+        buf
+    }
+
+    #[test]
+    fn test_v1_to_v1() {
+        let mut buf = v1_packet();
+        let len = decode_in_place(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &v1_packet()[..]);
+    }
+
+    #[test]
+    fn test_v2_to_v2() {
+        let src = v1_packet();
+        let mut out = [0u8; 64];
+        let len = encode_v2(&src, 0, &mut out).unwrap();
+        assert_eq!(len, src.len() + EXT_HEAD_LEN);
+        assert_eq!(WireVersion::of(&out[..len]), Some(WireVersion::V2));
+        let len = decode_in_place(&mut out[..len]).unwrap();
+        assert_eq!(&out[..len], &src[..]);
+    }
+
+    #[test]
+    fn test_encode_in_place() {
+        let src = v1_packet();
+        let mut buf = vec![0u8; EXT_HEAD_LEN];
+        buf.extend_from_slice(&src);
+        encode_v2_in_place(&mut buf, 0).unwrap();
+        let mut out = [0u8; 64];
+        let len = encode_v2(&src, 0, &mut out).unwrap();
+        assert_eq!(&buf[..], &out[..len]);
+        set_sequence(&mut buf, 7);
+        assert_eq!(sequence(&buf), Some(7));
+        restore_v1_in_place(&mut buf);
+        assert_eq!(&buf[EXT_HEAD_LEN..], &src[..]);
+        assert!(encode_v2_in_place(&mut buf[..HEAD_LEN], 0).is_err());
+    }
+
+    #[test]
+    fn test_v2_ext_skip() {
+        // 更高版本的V2实现可能携带更长的扩展头
+        let src = v1_packet();
+        let mut buf = src[..HEAD_LEN].to_vec();
+        buf[0] = (buf[0] & 0xF0) | 3;
+        buf.extend_from_slice(&[8, 0, 0, 0, 1, 2, 3, 4]);
+        buf.extend_from_slice(&src[HEAD_LEN..]);
+        let len = decode_in_place(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &src[..]);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let src = v1_packet();
+        assert_eq!(sequence(&src), None);
+        let mut out = [0u8; 64];
+        let len = encode_v2(&src, 0, &mut out).unwrap();
+        assert_eq!(sequence(&out[..len]), None);
+        set_sequence(&mut out[..len], 0x1234);
+        assert_eq!(sequence(&out[..len]), Some(0x1234));
+        // 序号不影响解码
+        let len = decode_in_place(&mut out[..len]).unwrap();
+        assert_eq!(&out[..len], &src[..]);
+    }
+
+    #[test]
+    fn test_wire_version_negotiate() {
+        // 旧版本不携带版本字段
+        assert_eq!(WireVersion::from(0), WireVersion::V1);
+        assert_eq!(WireVersion::from(1), WireVersion::V1);
+        let max: u8 = WireVersion::MAX.into();
+        assert_eq!(WireVersion::from(max), WireVersion::V2);
+    }
+}
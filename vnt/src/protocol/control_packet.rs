@@ -1,6 +1,8 @@
 use std::net::Ipv4Addr;
 use std::{fmt, io};
 
+use crate::protocol::compat::WireVersion;
+
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum Protocol {
     /// ping请求
@@ -10,6 +12,12 @@ pub enum Protocol {
         +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
         |              time                          |                    echo                        |
         +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |   ping方协议版本(8)    |   pong方协议版本(8)    |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                           echo_seq(32)                                        |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：客户端之间的ping携带协议版本，旧版本只有前4字节，并且会原样回复，所以两个方向分开存放；
+        ping命令发出的包在协议版本之后带有序号，pong原样带回，和心跳同时进行时按序号匹配
     */
     Ping,
     /*
@@ -27,6 +35,98 @@ pub enum Protocol {
     ///获取对端看到的地址
     AddrRequest,
     AddrResponse,
+    /// 需要确认的通知
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                              id                                               |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |     kind(8)           |
+        +-+-+-+-+-+-+-+-+-+-+-+-+
+        注：确认包原样带回id和kind
+    */
+    Notice,
+    NoticeAck,
+    /// 修改和对端之间的功能(压缩/加密)
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                              seq                                              |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |     feature(8)        |     enabled(8)        |     accepted(8)       |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：accepted只在回复中有效
+    */
+    FeatureRequest,
+    FeatureReply,
+    /// 带宽测量的协商和结果
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                              id                                               |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |     flags(8)          |     bursts(8)         |     burst_len(8)      |     reserved(8)       |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |              size(16)                      |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：flags带有BANDWIDTH_FINISH时表示探测包已经发完，请求接收结果
+    */
+    BandwidthRequest,
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                              id                                               |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |     flags(8)          |     count(8)          |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |              packets(16)                   |                  span_us(32)...                |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |            ...span_us                      |                  bytes(32)...                  |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |            ...bytes                        |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：flags带有BANDWIDTH_CONSENT表示对端同意测量，之后是count组每轮的接收情况，
+        bytes是每轮第一个包之后收到的字节数，span_us是第一个包到最后一个包的间隔
+    */
+    BandwidthReply,
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                              id                                               |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |     burst(8)          |     index(8)          |                  padding                       |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：一轮内的包连续发出，接收方记录到达时间
+    */
+    BandwidthProbe,
+    /// 负载高时回复没有路由的直连ping，对端在ping的扩展数据中带回后才建立路由
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                          cookie(64)                                           |
+        |                                                                                               |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    */
+    Cookie,
+    /// 严格入站的对端不响应打洞，经服务器中继请求对端批准后由它发起打洞
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                             nonce                                             |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                         duration(秒)                                          |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        注：批准包原样带回请求的nonce，duration是许可的时长；请求中duration为0表示使用对端的默认时长
+    */
+    ConnectIntent,
+    ConnectApprove,
     Unknown(u8),
 }
 
@@ -39,6 +139,16 @@ impl From<u8> for Protocol {
             4 => Protocol::PunchResponse,
             5 => Protocol::AddrRequest,
             6 => Protocol::AddrResponse,
+            7 => Protocol::Notice,
+            8 => Protocol::NoticeAck,
+            9 => Protocol::FeatureRequest,
+            10 => Protocol::FeatureReply,
+            11 => Protocol::BandwidthRequest,
+            12 => Protocol::BandwidthReply,
+            13 => Protocol::BandwidthProbe,
+            14 => Protocol::Cookie,
+            15 => Protocol::ConnectIntent,
+            16 => Protocol::ConnectApprove,
             val => Protocol::Unknown(val),
         }
     }
@@ -53,6 +163,16 @@ impl Into<u8> for Protocol {
             Protocol::PunchResponse => 4,
             Protocol::AddrRequest => 5,
             Protocol::AddrResponse => 6,
+            Protocol::Notice => 7,
+            Protocol::NoticeAck => 8,
+            Protocol::FeatureRequest => 9,
+            Protocol::FeatureReply => 10,
+            Protocol::BandwidthRequest => 11,
+            Protocol::BandwidthReply => 12,
+            Protocol::BandwidthProbe => 13,
+            Protocol::Cookie => 14,
+            Protocol::ConnectIntent => 15,
+            Protocol::ConnectApprove => 16,
             Protocol::Unknown(val) => val,
         }
     }
@@ -65,6 +185,16 @@ pub enum ControlPacket<B> {
     PunchResponse,
     AddrRequest,
     AddrResponse(AddrPacket<B>),
+    Notice(NoticePacket<B>),
+    NoticeAck(NoticeAckPacket<B>),
+    FeatureRequest(FeaturePacket<B>),
+    FeatureReply(FeaturePacket<B>),
+    BandwidthRequest(BandwidthRequestPacket<B>),
+    BandwidthReply(BandwidthReplyPacket<B>),
+    BandwidthProbe(BandwidthProbePacket<B>),
+    Cookie(CookiePacket<B>),
+    ConnectIntent(ReversePacket<B>),
+    ConnectApprove(ReversePacket<B>),
 }
 
 impl<B: AsRef<[u8]>> ControlPacket<B> {
@@ -76,6 +206,28 @@ impl<B: AsRef<[u8]>> ControlPacket<B> {
             Protocol::PunchResponse => Ok(ControlPacket::PunchResponse),
             Protocol::AddrRequest => Ok(ControlPacket::AddrRequest),
             Protocol::AddrResponse => Ok(ControlPacket::AddrResponse(AddrPacket::new(buffer)?)),
+            Protocol::Notice => Ok(ControlPacket::Notice(NoticePacket::new(buffer)?)),
+            Protocol::NoticeAck => Ok(ControlPacket::NoticeAck(NoticePacket::new(buffer)?)),
+            Protocol::FeatureRequest => {
+                Ok(ControlPacket::FeatureRequest(FeaturePacket::new(buffer)?))
+            }
+            Protocol::FeatureReply => Ok(ControlPacket::FeatureReply(FeaturePacket::new(buffer)?)),
+            Protocol::BandwidthRequest => Ok(ControlPacket::BandwidthRequest(
+                BandwidthRequestPacket::new(buffer)?,
+            )),
+            Protocol::BandwidthReply => Ok(ControlPacket::BandwidthReply(
+                BandwidthReplyPacket::new(buffer)?,
+            )),
+            Protocol::BandwidthProbe => Ok(ControlPacket::BandwidthProbe(
+                BandwidthProbePacket::new(buffer)?,
+            )),
+            Protocol::Cookie => Ok(ControlPacket::Cookie(CookiePacket::new(buffer)?)),
+            Protocol::ConnectIntent => {
+                Ok(ControlPacket::ConnectIntent(ReversePacket::new(buffer)?))
+            }
+            Protocol::ConnectApprove => {
+                Ok(ControlPacket::ConnectApprove(ReversePacket::new(buffer)?))
+            }
             Protocol::Unknown(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported")),
         }
     }
@@ -88,6 +240,13 @@ pub struct PingPacket<B> {
 
 pub type PongPacket<B> = PingPacket<B>;
 
+/// 携带协议版本的ping包长度
+pub const PING_WIRE_VERSION_LEN: usize = 6;
+/// ping命令在协议版本之后携带的序号长度
+pub const PING_ECHO_LEN: usize = 4;
+/// 回显给对端的cookie长度，和序号一样放在协议版本之后
+pub const COOKIE_LEN: usize = 8;
+
 impl<B: AsRef<[u8]>> PingPacket<B> {
     pub fn new(buffer: B) -> io::Result<PingPacket<B>> {
         let len = buffer.as_ref().len();
@@ -105,6 +264,42 @@ impl<B: AsRef<[u8]>> PingPacket<B> {
     pub fn epoch(&self) -> u16 {
         u16::from_be_bytes(self.buffer.as_ref()[2..4].try_into().unwrap())
     }
+    /// 发起ping的一方支持的协议版本
+    pub fn wire_version(&self) -> WireVersion {
+        let buf = self.buffer.as_ref();
+        if buf.len() < PING_WIRE_VERSION_LEN {
+            return WireVersion::V1;
+        }
+        WireVersion::from(buf[4])
+    }
+    /// 回复pong的一方支持的协议版本
+    pub fn peer_wire_version(&self) -> WireVersion {
+        let buf = self.buffer.as_ref();
+        if buf.len() < PING_WIRE_VERSION_LEN {
+            return WireVersion::V1;
+        }
+        WireVersion::from(buf[5])
+    }
+    /// 协议版本之后的扩展数据，目前只有发给服务端的中继流量
+    pub fn extension(&self) -> &[u8] {
+        let buf = self.buffer.as_ref();
+        if buf.len() <= PING_WIRE_VERSION_LEN {
+            return &[];
+        }
+        &buf[PING_WIRE_VERSION_LEN..]
+    }
+    /// ping命令的序号，心跳包没有
+    pub fn echo_seq(&self) -> Option<u32> {
+        let extension = self.extension();
+        if extension.len() != PING_ECHO_LEN {
+            return None;
+        }
+        Some(u32::from_be_bytes(extension.try_into().unwrap()))
+    }
+    /// 对端要求回显的cookie
+    pub fn cookie(&self) -> Option<[u8; COOKIE_LEN]> {
+        self.extension().try_into().ok()
+    }
 }
 
 impl<B: AsRef<[u8]> + AsMut<[u8]>> PingPacket<B> {
@@ -114,6 +309,27 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> PingPacket<B> {
     pub fn set_epoch(&mut self, epoch: u16) {
         self.buffer.as_mut()[2..4].copy_from_slice(&epoch.to_be_bytes())
     }
+    pub fn set_wire_version(&mut self, wire_version: WireVersion) {
+        if self.buffer.as_ref().len() >= PING_WIRE_VERSION_LEN {
+            self.buffer.as_mut()[4] = wire_version.into();
+        }
+    }
+    pub fn set_peer_wire_version(&mut self, wire_version: WireVersion) {
+        if self.buffer.as_ref().len() >= PING_WIRE_VERSION_LEN {
+            self.buffer.as_mut()[5] = wire_version.into();
+        }
+    }
+    pub fn set_extension(&mut self, extension: &[u8]) -> io::Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() != PING_WIRE_VERSION_LEN + extension.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "extension len error",
+            ));
+        }
+        buf[PING_WIRE_VERSION_LEN..].copy_from_slice(extension);
+        Ok(())
+    }
 }
 
 impl<B: AsRef<[u8]>> fmt::Debug for PingPacket<B> {
@@ -121,6 +337,8 @@ impl<B: AsRef<[u8]>> fmt::Debug for PingPacket<B> {
         f.debug_struct("PingPacket")
             .field("time", &self.time())
             .field("epoch", &self.epoch())
+            .field("wire_version", &self.wire_version())
+            .field("peer_wire_version", &self.peer_wire_version())
             .finish()
     }
 }
@@ -163,3 +381,369 @@ impl<B: AsRef<[u8]>> fmt::Debug for AddrPacket<B> {
             .finish()
     }
 }
+
+/// 通知包长度
+pub const NOTICE_LEN: usize = 5;
+
+/// 需要确认的通知
+pub struct NoticePacket<B> {
+    buffer: B,
+}
+
+pub type NoticeAckPacket<B> = NoticePacket<B>;
+
+impl<B: AsRef<[u8]>> NoticePacket<B> {
+    pub fn new(buffer: B) -> io::Result<NoticePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < NOTICE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 5"));
+        }
+        Ok(NoticePacket { buffer })
+    }
+    pub fn id(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn kind(&self) -> u8 {
+        self.buffer.as_ref()[4]
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> NoticePacket<B> {
+    pub fn set_id(&mut self, id: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&id.to_be_bytes())
+    }
+    pub fn set_kind(&mut self, kind: u8) {
+        self.buffer.as_mut()[4] = kind
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for NoticePacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoticePacket")
+            .field("id", &self.id())
+            .field("kind", &self.kind())
+            .finish()
+    }
+}
+
+/// 功能修改包长度
+pub const FEATURE_LEN: usize = 7;
+
+/// 修改和对端之间的功能
+pub struct FeaturePacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> FeaturePacket<B> {
+    pub fn new(buffer: B) -> io::Result<FeaturePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < FEATURE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 7"));
+        }
+        Ok(FeaturePacket { buffer })
+    }
+    pub fn seq(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn feature(&self) -> u8 {
+        self.buffer.as_ref()[4]
+    }
+    pub fn enabled(&self) -> bool {
+        self.buffer.as_ref()[5] != 0
+    }
+    pub fn accepted(&self) -> bool {
+        self.buffer.as_ref()[6] != 0
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> FeaturePacket<B> {
+    pub fn set_seq(&mut self, seq: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&seq.to_be_bytes())
+    }
+    pub fn set_feature(&mut self, feature: u8) {
+        self.buffer.as_mut()[4] = feature
+    }
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.buffer.as_mut()[5] = enabled as u8
+    }
+    pub fn set_accepted(&mut self, accepted: bool) {
+        self.buffer.as_mut()[6] = accepted as u8
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for FeaturePacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FeaturePacket")
+            .field("seq", &self.seq())
+            .field("feature", &self.feature())
+            .field("enabled", &self.enabled())
+            .field("accepted", &self.accepted())
+            .finish()
+    }
+}
+
+/// 带宽测量请求长度
+pub const BANDWIDTH_REQUEST_LEN: usize = 10;
+/// 探测包已经发完，请求接收结果
+pub const BANDWIDTH_FINISH: u8 = 0x01;
+
+/// 带宽测量请求
+pub struct BandwidthRequestPacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> BandwidthRequestPacket<B> {
+    pub fn new(buffer: B) -> io::Result<BandwidthRequestPacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < BANDWIDTH_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 10"));
+        }
+        Ok(BandwidthRequestPacket { buffer })
+    }
+    pub fn id(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn is_finish(&self) -> bool {
+        self.buffer.as_ref()[4] & BANDWIDTH_FINISH != 0
+    }
+    pub fn bursts(&self) -> u8 {
+        self.buffer.as_ref()[5]
+    }
+    pub fn burst_len(&self) -> u8 {
+        self.buffer.as_ref()[6]
+    }
+    /// 探测包负载长度
+    pub fn size(&self) -> u16 {
+        u16::from_be_bytes(self.buffer.as_ref()[8..10].try_into().unwrap())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> BandwidthRequestPacket<B> {
+    pub fn set_id(&mut self, id: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&id.to_be_bytes())
+    }
+    pub fn set_finish(&mut self, finish: bool) {
+        self.buffer.as_mut()[4] = if finish { BANDWIDTH_FINISH } else { 0 }
+    }
+    pub fn set_bursts(&mut self, bursts: u8) {
+        self.buffer.as_mut()[5] = bursts
+    }
+    pub fn set_burst_len(&mut self, burst_len: u8) {
+        self.buffer.as_mut()[6] = burst_len
+    }
+    pub fn set_size(&mut self, size: u16) {
+        self.buffer.as_mut()[8..10].copy_from_slice(&size.to_be_bytes())
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for BandwidthRequestPacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BandwidthRequestPacket")
+            .field("id", &self.id())
+            .field("finish", &self.is_finish())
+            .field("bursts", &self.bursts())
+            .field("burst_len", &self.burst_len())
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+/// 带宽测量回复的固定部分长度
+pub const BANDWIDTH_REPLY_LEN: usize = 6;
+/// 每轮接收情况的长度
+pub const BANDWIDTH_BURST_LEN: usize = 10;
+/// 对端同意测量
+pub const BANDWIDTH_CONSENT: u8 = 0x01;
+
+/// 带宽测量回复
+pub struct BandwidthReplyPacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> BandwidthReplyPacket<B> {
+    pub fn new(buffer: B) -> io::Result<BandwidthReplyPacket<B>> {
+        let buf = buffer.as_ref();
+        if buf.len() < BANDWIDTH_REPLY_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 6"));
+        }
+        if buf.len() < BANDWIDTH_REPLY_LEN + buf[5] as usize * BANDWIDTH_BURST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "burst count error",
+            ));
+        }
+        Ok(BandwidthReplyPacket { buffer })
+    }
+    pub fn id(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn consent(&self) -> bool {
+        self.buffer.as_ref()[4] & BANDWIDTH_CONSENT != 0
+    }
+    pub fn count(&self) -> u8 {
+        self.buffer.as_ref()[5]
+    }
+    /// 第i轮的(收到的包数,第一个包到最后一个包的微秒数,第一个包之后的字节数)
+    pub fn burst(&self, i: usize) -> (u16, u32, u32) {
+        let start = BANDWIDTH_REPLY_LEN + i * BANDWIDTH_BURST_LEN;
+        let buf = &self.buffer.as_ref()[start..start + BANDWIDTH_BURST_LEN];
+        (
+            u16::from_be_bytes(buf[..2].try_into().unwrap()),
+            u32::from_be_bytes(buf[2..6].try_into().unwrap()),
+            u32::from_be_bytes(buf[6..10].try_into().unwrap()),
+        )
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> BandwidthReplyPacket<B> {
+    pub fn set_id(&mut self, id: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&id.to_be_bytes())
+    }
+    pub fn set_consent(&mut self, consent: bool) {
+        self.buffer.as_mut()[4] = if consent { BANDWIDTH_CONSENT } else { 0 }
+    }
+    /// 缓冲区需要能放下count组
+    pub fn set_count(&mut self, count: u8) {
+        self.buffer.as_mut()[5] = count
+    }
+    pub fn set_burst(&mut self, i: usize, packets: u16, span_us: u32, bytes: u32) {
+        let start = BANDWIDTH_REPLY_LEN + i * BANDWIDTH_BURST_LEN;
+        let buf = &mut self.buffer.as_mut()[start..start + BANDWIDTH_BURST_LEN];
+        buf[..2].copy_from_slice(&packets.to_be_bytes());
+        buf[2..6].copy_from_slice(&span_us.to_be_bytes());
+        buf[6..10].copy_from_slice(&bytes.to_be_bytes());
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for BandwidthReplyPacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BandwidthReplyPacket")
+            .field("id", &self.id())
+            .field("consent", &self.consent())
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+/// 带宽探测包的头部长度，之后是填充
+pub const BANDWIDTH_PROBE_LEN: usize = 6;
+
+/// 带宽探测包
+pub struct BandwidthProbePacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> BandwidthProbePacket<B> {
+    pub fn new(buffer: B) -> io::Result<BandwidthProbePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < BANDWIDTH_PROBE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 6"));
+        }
+        Ok(BandwidthProbePacket { buffer })
+    }
+    pub fn id(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn burst(&self) -> u8 {
+        self.buffer.as_ref()[4]
+    }
+    pub fn index(&self) -> u8 {
+        self.buffer.as_ref()[5]
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> BandwidthProbePacket<B> {
+    pub fn set_id(&mut self, id: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&id.to_be_bytes())
+    }
+    pub fn set_burst(&mut self, burst: u8) {
+        self.buffer.as_mut()[4] = burst
+    }
+    pub fn set_index(&mut self, index: u8) {
+        self.buffer.as_mut()[5] = index
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for BandwidthProbePacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BandwidthProbePacket")
+            .field("id", &self.id())
+            .field("burst", &self.burst())
+            .field("index", &self.index())
+            .finish()
+    }
+}
+
+/// 负载高时要求对端回显的cookie
+pub struct CookiePacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> CookiePacket<B> {
+    pub fn new(buffer: B) -> io::Result<CookiePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len != COOKIE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len != 8"));
+        }
+        Ok(CookiePacket { buffer })
+    }
+    pub fn cookie(&self) -> [u8; COOKIE_LEN] {
+        self.buffer.as_ref().try_into().unwrap()
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> CookiePacket<B> {
+    pub fn set_cookie(&mut self, cookie: &[u8; COOKIE_LEN]) {
+        self.buffer.as_mut().copy_from_slice(cookie)
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for CookiePacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookiePacket")
+            .field("cookie", &self.cookie())
+            .finish()
+    }
+}
+
+/// 反向连接请求和批准的长度
+pub const REVERSE_LEN: usize = 8;
+
+/// 反向连接的请求和批准
+pub struct ReversePacket<B> {
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> ReversePacket<B> {
+    pub fn new(buffer: B) -> io::Result<ReversePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < REVERSE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 8"));
+        }
+        Ok(ReversePacket { buffer })
+    }
+    pub fn nonce(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn duration(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[4..8].try_into().unwrap())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> ReversePacket<B> {
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&nonce.to_be_bytes())
+    }
+    pub fn set_duration(&mut self, duration: u32) {
+        self.buffer.as_mut()[4..8].copy_from_slice(&duration.to_be_bytes())
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for ReversePacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReversePacket")
+            .field("nonce", &self.nonce())
+            .field("duration", &self.duration())
+            .finish()
+    }
+}
@@ -39,7 +39,7 @@ impl<B: AsRef<[u8]>> BroadcastPacket<B> {
     pub fn new(buffer: B) -> io::Result<Self> {
         let len = buffer.as_ref().len();
         let packet = Self::unchecked(buffer);
-        if len < 2 + 4 || packet.addr_num() == 0 {
+        if len < 2 + 4 || packet.addr_num() == 0 || len < 1 + packet.addr_num() as usize * 4 {
             Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "BroadcastPacket InvalidData",
@@ -1,6 +1,8 @@
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Protocol {
     Punch,
+    /// 紧凑编码的打洞信息，只用于回复声明支持的对端
+    PunchCompact,
     Unknown(u8),
 }
 
@@ -8,6 +10,7 @@ impl From<u8> for Protocol {
     fn from(value: u8) -> Self {
         match value {
             1 => Protocol::Punch,
+            2 => Protocol::PunchCompact,
             val => Protocol::Unknown(val),
         }
     }
@@ -17,6 +20,7 @@ impl Into<u8> for Protocol {
     fn into(self) -> u8 {
         match self {
             Protocol::Punch => 1,
+            Protocol::PunchCompact => 2,
             Protocol::Unknown(val) => val,
         }
     }
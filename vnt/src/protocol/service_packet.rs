@@ -15,6 +15,14 @@ pub enum Protocol {
     SecretHandshakeResponse,
     /// 客户端上报状态
     ClientStatusInfo,
+    /// 客户端正常退出，服务端立即移除设备并推进纪元
+    Deregistration,
+    /// 服务端请求执行操作
+    ServerAction,
+    /// 操作的执行结果
+    ServerActionAck,
+    /// 紧凑编码的设备列表，只推送给握手时声明支持的客户端
+    PushDeviceListCompact,
     Unknown(u8),
 }
 
@@ -30,6 +38,10 @@ impl From<u8> for Protocol {
             7 => Self::SecretHandshakeRequest,
             8 => Self::SecretHandshakeResponse,
             9 => Self::ClientStatusInfo,
+            10 => Self::Deregistration,
+            11 => Self::ServerAction,
+            12 => Self::ServerActionAck,
+            13 => Self::PushDeviceListCompact,
             val => Self::Unknown(val),
         }
     }
@@ -47,6 +59,10 @@ impl Into<u8> for Protocol {
             Self::SecretHandshakeRequest => 7,
             Self::SecretHandshakeResponse => 8,
             Self::ClientStatusInfo => 9,
+            Self::Deregistration => 10,
+            Self::ServerAction => 11,
+            Self::ServerActionAck => 12,
+            Self::PushDeviceListCompact => 13,
             Self::Unknown(val) => val,
         }
     }
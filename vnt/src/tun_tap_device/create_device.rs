@@ -16,8 +16,21 @@ pub fn create_device(config: &crate::core::Config) -> io::Result<Arc<Device>> {
     } else {
         DEFAULT_TUN_NAME
     };
+    #[cfg(not(target_os = "linux"))]
+    if config.existing_tun.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "existing tun is only supported on linux",
+        ));
+    }
     #[cfg(target_os = "linux")]
-    let device = {
+    let device = if let Some(device) = adopt_device(config) {
+        device
+    } else if let Some(existing) = &config.existing_tun {
+        let device = super::existing_tun::open(existing)?;
+        log::info!("使用已有网卡 {:?}", device.name());
+        Arc::new(device)
+    } else {
         let device_name = config
             .device_name
             .clone()
@@ -37,17 +50,41 @@ pub fn create_device(config: &crate::core::Config) -> io::Result<Arc<Device>> {
             .unwrap_or(default_name.to_string()),
         config.tap,
     )?);
-    let mtu = config.mtu.unwrap_or_else(|| {
-        if config.password.is_none() {
-            1450
-        } else {
-            1410
+    let mtu = config.device_mtu();
+    if config.existing_tun.is_some() {
+        // 没有权限时由管理员配置
+        if device.mtu().ok() != Some(mtu) {
+            if let Err(e) = device.set_mtu(mtu) {
+                log::warn!(
+                    "设置mtu失败,需要管理员执行'ip link set dev {} mtu {}' {:?}",
+                    device.name()?,
+                    mtu,
+                    e
+                );
+            }
         }
-    });
-    device.set_mtu(mtu)?;
+    } else {
+        device.set_mtu(mtu)?;
+    }
     Ok(device)
 }
 
+/// 热重启时沿用上一个进程的tun描述符，网卡上的ip和路由都会保留
+#[cfg(target_os = "linux")]
+fn adopt_device(config: &crate::core::Config) -> Option<Arc<Device>> {
+    let fd = config.warm_state.as_ref()?.tun_fd?;
+    match Device::from_fd(fd) {
+        Ok(device) => {
+            log::info!("沿用网卡 {:?}", device.name());
+            Some(Arc::new(device))
+        }
+        Err(e) => {
+            log::warn!("沿用网卡失败,fd={},{:?}", fd, e);
+            None
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn delete_device(name: &str) {
     // 删除默认网卡，此操作有风险，后续可能去除
@@ -0,0 +1,100 @@
+use std::net::Ipv4Addr;
+
+/// 使用管理员预先创建的tun网卡，不创建网卡也不需要root权限
+///
+/// 网卡的ip在有权限时才会设置，路由始终不会安装，需要管理员按日志提示配置
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExistingTun {
+    /// 按名称打开持久化的tun设备
+    Name(String),
+    /// 使用从父进程继承的tun描述符
+    Fd(i32),
+}
+
+/// 管理员需要执行的配置命令，ip为None表示网卡上的ip已经正确
+pub fn setup_commands(
+    name: &str,
+    ip: Option<(Ipv4Addr, Ipv4Addr)>,
+    routes: &[(Ipv4Addr, Ipv4Addr)],
+) -> Vec<String> {
+    let mut commands = Vec::new();
+    if let Some((ip, mask)) = ip {
+        commands.push(format!(
+            "ip addr add {}/{} dev {}",
+            ip,
+            u32::from(mask).count_ones(),
+            name
+        ));
+    }
+    for (dest, mask) in routes {
+        commands.push(format!(
+            "ip route add {}/{} dev {}",
+            dest,
+            u32::from(*mask).count_ones(),
+            name
+        ));
+    }
+    commands
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn open(existing: &ExistingTun) -> std::io::Result<tun::Device> {
+    use tun::device::IFace;
+    let device = match existing {
+        ExistingTun::Name(name) => tun::Device::open(name)?,
+        ExistingTun::Fd(fd) => tun::Device::from_fd(*fd)?,
+    };
+    match device.is_up() {
+        Ok(true) => {}
+        _ => {
+            if let Err(e) = device.up() {
+                log::warn!(
+                    "启用网卡失败,需要管理员执行'ip link set dev {} up' {:?}",
+                    device.name()?,
+                    e
+                );
+            }
+        }
+    }
+    Ok(device)
+}
+
+#[test]
+fn test_setup_commands() {
+    let commands = setup_commands(
+        "vnt0",
+        Some((Ipv4Addr::new(10, 26, 0, 2), Ipv4Addr::new(255, 255, 255, 0))),
+        &[(Ipv4Addr::new(10, 26, 0, 0), Ipv4Addr::new(255, 255, 255, 0))],
+    );
+    assert_eq!(
+        commands,
+        vec![
+            "ip addr add 10.26.0.2/24 dev vnt0",
+            "ip route add 10.26.0.0/24 dev vnt0",
+        ]
+    );
+}
+
+/// 需要root权限预先创建网卡: cargo test -- --ignored test_open_existing
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore]
+fn test_open_existing() {
+    use std::process::Command;
+    use tun::device::IFace;
+    let name = "vnt-test0";
+    let _ = Command::new("ip")
+        .args(["tuntap", "add", "mode", "tun", "name", name])
+        .status();
+    let device = open(&ExistingTun::Name(name.to_string())).unwrap();
+    assert_eq!(device.name().unwrap(), name);
+    assert!(device.is_up().unwrap());
+    let fd = open(&ExistingTun::Fd(device.as_tun_fd().0)).unwrap();
+    assert_eq!(fd.name().unwrap(), name);
+    // 两个Device共用同一个描述符
+    std::mem::forget(fd);
+    drop(device);
+    let _ = Command::new("ip")
+        .args(["tuntap", "del", "mode", "tun", "name", name])
+        .status();
+}
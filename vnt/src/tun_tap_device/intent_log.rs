@@ -0,0 +1,543 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::tun_tap_device::route_guard::{self, RouteHop};
+use crate::util::state_store;
+
+/// 修改系统配置之前记录的意图
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Intent {
+    /// 虚拟网卡上的配置，网卡随进程退出一起消失，或者由下次启动时的TunSetup接管
+    SetIp(Ipv4Addr, Ipv4Addr),
+    AddRoute(Ipv4Addr, Ipv4Addr, u16),
+    DeleteRoute(Ipv4Addr, Ipv4Addr),
+    /// 经过物理网卡的主机路由，进程退出后仍然存在
+    AddHostRoute(Ipv4Addr, RouteHop),
+    DeleteHostRoute(Ipv4Addr),
+}
+
+impl Intent {
+    fn encode(&self) -> String {
+        match self {
+            Intent::SetIp(address, mask) => format!("set-ip {} {}", address, mask),
+            Intent::AddRoute(dest, netmask, metric) => {
+                format!("add-route {} {} {}", dest, netmask, metric)
+            }
+            Intent::DeleteRoute(dest, netmask) => format!("del-route {} {}", dest, netmask),
+            Intent::AddHostRoute(ip, hop) => format!(
+                "add-host {} {} {}",
+                ip,
+                hop.gateway
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                hop.interface
+            ),
+            Intent::DeleteHostRoute(ip) => format!("del-host {}", ip),
+        }
+    }
+    fn decode(text: &str) -> Option<Intent> {
+        let mut tokens = text.splitn(4, ' ');
+        let kind = tokens.next()?;
+        let mut ip = || tokens.next()?.parse::<Ipv4Addr>().ok();
+        let intent = match kind {
+            "set-ip" => Intent::SetIp(ip()?, ip()?),
+            "add-route" => Intent::AddRoute(ip()?, ip()?, tokens.next()?.parse().ok()?),
+            "del-route" => Intent::DeleteRoute(ip()?, ip()?),
+            "add-host" => {
+                let ip = ip()?;
+                let gateway = match tokens.next()? {
+                    "-" => None,
+                    gateway => Some(gateway.parse().ok()?),
+                };
+                let interface = tokens.next()?.to_string();
+                Intent::AddHostRoute(ip, RouteHop { gateway, interface })
+            }
+            "del-host" => Intent::DeleteHostRoute(ip()?),
+            _ => return None,
+        };
+        Some(intent)
+    }
+    /// 是否是虚拟网卡上的配置
+    fn on_tun(&self) -> bool {
+        matches!(
+            self,
+            Intent::SetIp(..) | Intent::AddRoute(..) | Intent::DeleteRoute(..)
+        )
+    }
+}
+
+impl Display for Intent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Intent::AddHostRoute(ip, hop) => write!(f, "add host route {} {}", ip, hop),
+            Intent::DeleteHostRoute(ip) => write!(f, "delete host route {}", ip),
+            Intent::SetIp(address, mask) => write!(f, "set tun ip {}/{}", address, mask),
+            Intent::AddRoute(dest, netmask, _) => write!(f, "add tun route {}/{}", dest, netmask),
+            Intent::DeleteRoute(dest, netmask) => {
+                write!(f, "delete tun route {}/{}", dest, netmask)
+            }
+        }
+    }
+}
+
+/// 修改系统配置的预写日志
+///
+/// 每次修改前追加`<id> begin <意图>`并落盘，完成后追加`<id> done`，执行失败追加`<id> abort`。
+/// 进程被杀死时留下没有结束的意图，下次启动时由[`reconcile`]检查并撤销。
+/// 没有指定文件时不记录，写日志失败只告警一次，不影响实际的修改
+#[derive(Clone, Default)]
+pub struct IntentLog {
+    inner: Arc<Mutex<LogInner>>,
+}
+
+#[derive(Default)]
+struct LogInner {
+    file: Option<File>,
+    next_id: u64,
+    warned: bool,
+}
+
+impl IntentLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 之后的修改记录到这个文件，应当先调用[`reconcile`]处理上次留下的记录
+    pub fn attach(&self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.inner.lock().file = Some(file);
+        Ok(())
+    }
+    /// 记录意图后执行修改，返回修改的结果
+    pub fn run<T, F: FnOnce() -> io::Result<T>>(&self, intent: Intent, f: F) -> io::Result<T> {
+        let id = self.begin(&intent);
+        let rs = f();
+        self.end(id, if rs.is_ok() { "done" } else { "abort" });
+        rs
+    }
+    fn begin(&self, intent: &Intent) -> u64 {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.write(&format!("{} begin {}\n", id, intent.encode()));
+        id
+    }
+    fn end(&self, id: u64, state: &str) {
+        self.inner.lock().write(&format!("{} {}\n", id, state));
+    }
+}
+
+impl LogInner {
+    fn write(&mut self, line: &str) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        // 意图要先于修改落盘
+        let rs = file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.sync_data());
+        if let Err(e) = rs {
+            if !self.warned {
+                self.warned = true;
+                log::warn!("写入系统配置日志失败,异常退出后需要手动清理 {:?}", e);
+            }
+        }
+    }
+}
+
+/// 日志中一条可能还在生效的修改
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Leftover {
+    pub intent: Intent,
+    /// 修改已经完成，只是没有撤销
+    pub completed: bool,
+}
+
+/// 解析日志，返回可能留在系统中的修改
+///
+/// 没有结束的意图都算在内；主机路由在进程退出后仍然存在，添加完成但没有删除完成的也算在内
+pub fn leftovers(text: &str) -> Vec<Leftover> {
+    let mut begun: HashMap<u64, Intent> = HashMap::new();
+    let mut unfinished: BTreeMap<u64, Intent> = BTreeMap::new();
+    // 按目标ip，值为(意图编号, 添加是否完成)
+    let mut hosts: BTreeMap<Ipv4Addr, (u64, Intent, bool)> = BTreeMap::new();
+    for line in text.lines() {
+        let (id, rest) = match line.split_once(' ') {
+            Some((id, rest)) => match id.parse::<u64>() {
+                Ok(id) => (id, rest),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if let Some(intent) = rest.strip_prefix("begin ") {
+            // 写了一半的行直接忽略
+            let intent = match Intent::decode(intent) {
+                Some(intent) => intent,
+                None => continue,
+            };
+            match &intent {
+                Intent::AddHostRoute(ip, _) => {
+                    hosts.insert(*ip, (id, intent.clone(), false));
+                }
+                Intent::DeleteHostRoute(_) => {}
+                _ => {
+                    unfinished.insert(id, intent.clone());
+                }
+            }
+            begun.insert(id, intent);
+            continue;
+        }
+        let intent = match begun.remove(&id) {
+            Some(intent) => intent,
+            None => continue,
+        };
+        unfinished.remove(&id);
+        let done = rest == "done";
+        match intent {
+            Intent::AddHostRoute(ip, _) => {
+                if done {
+                    if let Some(v) = hosts.get_mut(&ip).filter(|v| v.0 == id) {
+                        v.2 = true;
+                    }
+                } else if matches!(hosts.get(&ip), Some(v) if v.0 == id) {
+                    hosts.remove(&ip);
+                }
+            }
+            Intent::DeleteHostRoute(ip) if done => {
+                hosts.remove(&ip);
+            }
+            _ => {}
+        }
+    }
+    let mut list: Vec<Leftover> = unfinished
+        .into_values()
+        .map(|intent| Leftover {
+            intent,
+            completed: false,
+        })
+        .collect();
+    list.extend(
+        hosts
+            .into_values()
+            .map(|(_, intent, completed)| Leftover { intent, completed }),
+    );
+    list
+}
+
+/// 检查和撤销主机路由，单独抽出来方便测试
+pub trait HostRoutes {
+    fn exists(&self, ip: Ipv4Addr) -> io::Result<bool>;
+    fn delete(&self, ip: Ipv4Addr) -> io::Result<()>;
+}
+
+/// 系统路由表
+pub struct SystemRoutes;
+
+impl HostRoutes for SystemRoutes {
+    fn exists(&self, ip: Ipv4Addr) -> io::Result<bool> {
+        route_guard::host_route_exists(ip)
+    }
+
+    fn delete(&self, ip: Ipv4Addr) -> io::Result<()> {
+        route_guard::delete_host_route(ip)
+    }
+}
+
+/// 对一条残留修改的处理
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// 虚拟网卡上的配置，留给下次配置网卡时接管
+    Adopted,
+    /// 修改没有生效，不需要处理
+    Absent,
+    /// 已撤销
+    RolledBack,
+    /// 只检查时，表示需要撤销
+    Pending,
+    Failed(String),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Adopted => f.write_str("adopted, reconfigured with the tun device"),
+            Action::Absent => f.write_str("not applied"),
+            Action::RolledBack => f.write_str("rolled back"),
+            Action::Pending => f.write_str("still applied, run 'clean --reconcile-os' to revert"),
+            Action::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// 读取日志并处理上次留下的修改，apply为false时只检查
+///
+/// 全部处理完成后清空日志，有失败的保留日志，下次启动时重试
+pub fn reconcile<R: HostRoutes>(
+    path: &Path,
+    routes: &R,
+    apply: bool,
+) -> io::Result<Vec<(Leftover, Action)>> {
+    let text = match std::fs::read(path) {
+        Ok(data) => String::from_utf8_lossy(&data).to_string(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut result = Vec::new();
+    for leftover in leftovers(&text) {
+        let action = resolve(&leftover.intent, routes, apply);
+        result.push((leftover, action));
+    }
+    if apply && !result.iter().any(|(_, a)| matches!(a, Action::Failed(_))) {
+        state_store::atomic_write(path, b"")?;
+    }
+    Ok(result)
+}
+
+fn resolve<R: HostRoutes>(intent: &Intent, routes: &R, apply: bool) -> Action {
+    if intent.on_tun() {
+        return Action::Adopted;
+    }
+    let ip = match intent {
+        Intent::AddHostRoute(ip, _) | Intent::DeleteHostRoute(ip) => *ip,
+        _ => unreachable!(),
+    };
+    match routes.exists(ip) {
+        Ok(false) => Action::Absent,
+        Ok(true) if !apply => Action::Pending,
+        Ok(true) => match routes.delete(ip) {
+            Ok(()) => Action::RolledBack,
+            Err(e) => Action::Failed(e.to_string()),
+        },
+        Err(e) => Action::Failed(e.to_string()),
+    }
+}
+
+/// 启动时处理上次异常退出留下的修改，之后的修改记录到同一个文件
+pub fn recover(log: &IntentLog, path: &Path) {
+    match reconcile(path, &SystemRoutes, true) {
+        Ok(list) => {
+            for (leftover, action) in list {
+                log::warn!("上次运行留下的系统配置 {}: {}", leftover.intent, action);
+            }
+        }
+        Err(e) => log::warn!("读取系统配置日志失败 {:?} {:?}", path, e),
+    }
+    if let Err(e) = log.attach(path) {
+        log::warn!(
+            "打开系统配置日志失败,异常退出后需要手动清理 {:?} {:?}",
+            path,
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::io;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    use crate::tun_tap_device::intent_log::*;
+    use crate::tun_tap_device::tun_setup::{TunOps, TunSetup};
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(43, 139, 56, 10);
+    const PEER: Ipv4Addr = Ipv4Addr::new(120, 1, 2, 3);
+    const IP: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    const NETWORK: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 0);
+
+    /// 模拟系统，同时有虚拟网卡和主机路由
+    #[derive(Default)]
+    struct MockOs {
+        ip: Cell<Option<(Ipv4Addr, Ipv4Addr)>>,
+        routes: RefCell<HashSet<(Ipv4Addr, Ipv4Addr)>>,
+        hosts: RefCell<HashSet<Ipv4Addr>>,
+    }
+
+    impl TunOps for MockOs {
+        fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+            self.ip.set(Some((address, mask)));
+            Ok(())
+        }
+
+        fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, _metric: u16) -> io::Result<()> {
+            if !self.routes.borrow_mut().insert((dest, netmask)) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists"));
+            }
+            Ok(())
+        }
+
+        fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+            self.routes.borrow_mut().remove(&(dest, netmask));
+            Ok(())
+        }
+    }
+
+    impl HostRoutes for MockOs {
+        fn exists(&self, ip: Ipv4Addr) -> io::Result<bool> {
+            Ok(self.hosts.borrow().contains(&ip))
+        }
+
+        fn delete(&self, ip: Ipv4Addr) -> io::Result<()> {
+            self.hosts.borrow_mut().remove(&ip);
+            Ok(())
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vnt-intent-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn hop() -> RouteHop {
+        RouteHop {
+            gateway: Some(Ipv4Addr::new(192, 168, 1, 1)),
+            interface: "eth0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let list = [
+            Intent::SetIp(IP, MASK),
+            Intent::AddRoute(NETWORK, MASK, 1),
+            Intent::DeleteRoute(NETWORK, MASK),
+            Intent::AddHostRoute(SERVER, hop()),
+            Intent::AddHostRoute(
+                SERVER,
+                RouteHop {
+                    gateway: None,
+                    interface: "Wi-Fi 2".to_string(),
+                },
+            ),
+            Intent::DeleteHostRoute(SERVER),
+        ];
+        for intent in list {
+            assert_eq!(Intent::decode(&intent.encode()), Some(intent));
+        }
+        assert_eq!(Intent::decode("add-host 1.2.3"), None);
+        assert_eq!(Intent::decode("format-disk /"), None);
+    }
+
+    /// 在每个位置模拟进程被杀死：写意图之前、写意图之后修改之前、修改之后写完成之前。
+    /// 之后重新启动时处理日志，系统中不能留下主机路由
+    #[test]
+    fn test_fault_injection() {
+        let plan = [
+            Intent::AddHostRoute(SERVER, hop()),
+            Intent::AddHostRoute(PEER, hop()),
+            Intent::DeleteHostRoute(PEER),
+            Intent::DeleteHostRoute(SERVER),
+        ];
+        for kill_at in 0..=plan.len() * 3 {
+            let path = temp_path(&format!("fault-{}", kill_at));
+            let os = MockOs::default();
+            let log = IntentLog::new();
+            log.attach(&path).unwrap();
+            let mut point = 0;
+            'run: for intent in &plan {
+                for stage in 0..3 {
+                    if point == kill_at {
+                        break 'run;
+                    }
+                    point += 1;
+                    match (stage, intent) {
+                        (0, _) => {
+                            let _ = log.begin(intent);
+                        }
+                        (1, Intent::AddHostRoute(ip, _)) => {
+                            os.hosts.borrow_mut().insert(*ip);
+                        }
+                        (1, Intent::DeleteHostRoute(ip)) => {
+                            os.hosts.borrow_mut().remove(ip);
+                        }
+                        (2, _) => {
+                            let id = log.inner.lock().next_id - 1;
+                            log.end(id, "done");
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            drop(log);
+            let result = reconcile(&path, &os, true).unwrap();
+            assert!(os.hosts.borrow().is_empty(), "kill_at={}", kill_at);
+            assert!(
+                result.iter().all(|(_, a)| *a != Action::Adopted),
+                "kill_at={} {:?}",
+                kill_at,
+                result
+            );
+            // 处理完成后清空，再次处理没有残留
+            assert!(reconcile(&path, &os, true).unwrap().is_empty());
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let path = temp_path("reconcile");
+        let os = MockOs::default();
+        let log = IntentLog::new();
+        log.attach(&path).unwrap();
+        log.run(Intent::AddHostRoute(SERVER, hop()), || {
+            os.hosts.borrow_mut().insert(SERVER);
+            Ok(())
+        })
+        .unwrap();
+        // 执行失败的不算残留
+        assert!(log
+            .run(Intent::AddHostRoute(PEER, hop()), || -> io::Result<()> {
+                Err(io::Error::new(io::ErrorKind::Other, "injected"))
+            })
+            .is_err());
+        // 配置网卡的过程中被杀死，没有回滚
+        let mut setup = TunSetup::new(&os, None).with_log(log.clone());
+        setup.set_ip(IP, MASK).unwrap();
+        let _ = log.begin(&Intent::AddRoute(NETWORK, MASK, 1));
+        std::mem::forget(setup);
+        drop(log);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            leftovers(&text),
+            vec![
+                Leftover {
+                    intent: Intent::AddRoute(NETWORK, MASK, 1),
+                    completed: false
+                },
+                Leftover {
+                    intent: Intent::AddHostRoute(SERVER, hop()),
+                    completed: true
+                },
+            ]
+        );
+        // 只检查时不修改
+        let result = reconcile(&path, &os, false).unwrap();
+        assert_eq!(result[0].1, Action::Adopted);
+        assert_eq!(result[1].1, Action::Pending);
+        assert!(os.hosts.borrow().contains(&SERVER));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+
+        let result = reconcile(&path, &os, true).unwrap();
+        assert_eq!(result[1].1, Action::RolledBack);
+        assert!(os.hosts.borrow().is_empty());
+        // 网卡上留下的配置由下次配置接管
+        let mut setup = TunSetup::new(&os, os.ip.get());
+        setup.set_ip(IP, MASK).unwrap();
+        setup.add_route(NETWORK, MASK, 1).unwrap();
+        assert_eq!(setup.commit(), vec![(NETWORK, MASK)]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
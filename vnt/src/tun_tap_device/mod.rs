@@ -3,4 +3,8 @@ pub use create_device::create_device;
 
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 mod create_device;
+pub mod existing_tun;
+pub mod intent_log;
+pub mod route_guard;
 pub mod tun_create_helper;
+pub mod tun_setup;
@@ -0,0 +1,512 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::tun_tap_device::intent_log::{Intent, IntentLog};
+
+/// 其他vpn常用的网卡名前缀
+const VPN_INTERFACE_PREFIXES: [&str; 10] = [
+    "tun",
+    "tap",
+    "wg",
+    "utun",
+    "ppp",
+    "tailscale",
+    "zt",
+    "ipsec",
+    "nordlynx",
+    "proton",
+];
+
+/// 路由的出口
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouteHop {
+    pub gateway: Option<Ipv4Addr>,
+    /// 出口网卡，windows上是网卡的ip
+    pub interface: String,
+}
+
+impl RouteHop {
+    /// 是否经过虚拟网卡
+    pub fn is_tun(&self, tun_name: &str, virtual_ip: Ipv4Addr) -> bool {
+        self.interface == tun_name || self.interface == virtual_ip.to_string()
+    }
+    /// 是否经过其他vpn的网卡，windows上的网卡是ip，无法判断
+    pub fn is_other_vpn(&self, tun_name: &str) -> bool {
+        if self.interface == tun_name {
+            return false;
+        }
+        let interface = self.interface.to_lowercase();
+        VPN_INTERFACE_PREFIXES
+            .iter()
+            .any(|prefix| interface.starts_with(prefix))
+    }
+}
+
+impl Display for RouteHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.gateway {
+            Some(gateway) => write!(f, "via {} dev {}", gateway, self.interface),
+            None => write!(f, "dev {}", self.interface),
+        }
+    }
+}
+
+/// 路由表中的一条路由
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouteEntry {
+    pub dest: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub hop: RouteHop,
+    pub metric: u32,
+}
+
+/// 最长前缀匹配，前缀相同时取跃点数小的
+pub fn lookup(table: &[RouteEntry], ip: Ipv4Addr) -> Option<&RouteEntry> {
+    let ip = u32::from(ip);
+    table
+        .iter()
+        .filter(|r| ip & u32::from(r.netmask) == u32::from(r.dest) & u32::from(r.netmask))
+        .max_by(|a, b| {
+            u32::from(a.netmask)
+                .count_ones()
+                .cmp(&u32::from(b.netmask).count_ones())
+                .then(b.metric.cmp(&a.metric))
+        })
+}
+
+/// 目标地址的流量会进入虚拟网卡，形成回环
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouteConflict {
+    pub target: Ipv4Addr,
+    pub hop: RouteHop,
+}
+
+impl Display for RouteConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "route to {} goes through the vnt device ({}), traffic would loop back into the tunnel",
+            self.target, self.hop
+        )
+    }
+}
+
+/// 检查到服务器和直连对端的路由是否经过虚拟网卡
+pub fn find_conflicts<F: Fn(Ipv4Addr) -> Option<RouteHop>>(
+    targets: &[Ipv4Addr],
+    tun_name: &str,
+    virtual_ip: Ipv4Addr,
+    query: F,
+) -> Vec<RouteConflict> {
+    targets
+        .iter()
+        .filter_map(|target| {
+            let hop = query(*target)?;
+            if hop.is_tun(tun_name, virtual_ip) {
+                Some(RouteConflict {
+                    target: *target,
+                    hop,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// 查询到目标地址的路由
+#[cfg(target_os = "linux")]
+pub fn query(ip: Ipv4Addr) -> io::Result<Option<RouteHop>> {
+    let out = command("ip", &["-4", "route", "get", &ip.to_string()])?;
+    Ok(parse_ip_route_get(&out))
+}
+
+/// 查询到目标地址的路由
+#[cfg(target_os = "macos")]
+pub fn query(ip: Ipv4Addr) -> io::Result<Option<RouteHop>> {
+    let out = command("route", &["-n", "get", &ip.to_string()])?;
+    Ok(parse_route_get(&out))
+}
+
+/// 查询到目标地址的路由
+#[cfg(target_os = "windows")]
+pub fn query(ip: Ipv4Addr) -> io::Result<Option<RouteHop>> {
+    let out = command("route", &["print", "-4"])?;
+    let table = parse_route_print(&out);
+    Ok(lookup(&table, ip).map(|r| r.hop.clone()))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub fn query(_ip: Ipv4Addr) -> io::Result<Option<RouteHop>> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+fn command(program: &str, args: &[&str]) -> io::Result<String> {
+    let mut command = std::process::Command::new(program);
+    command.args(args);
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        // CREATE_NO_WINDOW
+        command.creation_flags(0x08000000);
+    }
+    let out = command.output()?;
+    if !out.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} {:?} failed,{}",
+                program,
+                args,
+                String::from_utf8_lossy(&out.stderr)
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// 解析`ip -4 route get`的输出，会考虑策略路由
+///
+/// 1.1.1.1 via 192.168.1.1 dev eth0 src 192.168.1.5 uid 0
+pub fn parse_ip_route_get(out: &str) -> Option<RouteHop> {
+    let line = out.lines().next()?;
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let value = |key: &str| {
+        tokens
+            .iter()
+            .position(|v| *v == key)
+            .and_then(|i| tokens.get(i + 1))
+    };
+    let interface = value("dev")?.to_string();
+    let gateway = value("via").and_then(|v| v.parse().ok());
+    Some(RouteHop { gateway, interface })
+}
+
+/// 解析macos上`route -n get`的输出
+pub fn parse_route_get(out: &str) -> Option<RouteHop> {
+    let mut gateway = None;
+    let mut interface = None;
+    for line in out.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue,
+        };
+        match key.trim() {
+            "gateway" => gateway = value.trim().parse().ok(),
+            "interface" => interface = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    Some(RouteHop {
+        gateway,
+        interface: interface?,
+    })
+}
+
+/// 解析windows上`route print -4`的活动路由
+///
+/// 标题是本地化的，所以按格式识别：目标 掩码 网关 接口 跃点数，直连的网关不是ip
+pub fn parse_route_print(out: &str) -> Vec<RouteEntry> {
+    let mut table = Vec::new();
+    for line in out.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() != 5 {
+            continue;
+        }
+        let (dest, netmask, interface, metric) = match (
+            tokens[0].parse::<Ipv4Addr>(),
+            tokens[1].parse::<Ipv4Addr>(),
+            tokens[3].parse::<Ipv4Addr>(),
+            tokens[4].parse::<u32>(),
+        ) {
+            (Ok(dest), Ok(netmask), Ok(interface), Ok(metric)) => {
+                (dest, netmask, interface, metric)
+            }
+            _ => continue,
+        };
+        table.push(RouteEntry {
+            dest,
+            netmask,
+            hop: RouteHop {
+                gateway: tokens[2].parse().ok(),
+                interface: interface.to_string(),
+            },
+            metric,
+        });
+    }
+    table
+}
+
+/// 添加或删除(hop为None)主机路由的命令，windows上直连的网关使用接口的ip
+fn host_route_command(ip: Ipv4Addr, hop: Option<&RouteHop>) -> (&'static str, Vec<String>) {
+    let ip = ip.to_string();
+    let add = hop.is_some();
+    if cfg!(target_os = "windows") {
+        let mut args = vec![if add { "add" } else { "delete" }.to_string(), ip];
+        if let Some(hop) = hop {
+            args.push("mask".to_string());
+            args.push("255.255.255.255".to_string());
+            args.push(
+                hop.gateway
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| hop.interface.clone()),
+            );
+        }
+        ("route", args)
+    } else if cfg!(target_os = "macos") {
+        let mut args = vec![
+            "-n".to_string(),
+            if add { "add" } else { "delete" }.to_string(),
+            "-host".to_string(),
+            ip,
+        ];
+        if let Some(hop) = hop {
+            match hop.gateway {
+                Some(gateway) => args.push(gateway.to_string()),
+                None => {
+                    args.push("-interface".to_string());
+                    args.push(hop.interface.clone());
+                }
+            }
+        }
+        ("route", args)
+    } else {
+        let mut args = vec![
+            "-4".to_string(),
+            "route".to_string(),
+            if add { "replace" } else { "del" }.to_string(),
+            format!("{}/32", ip),
+        ];
+        if let Some(hop) = hop {
+            if let Some(gateway) = hop.gateway {
+                args.push("via".to_string());
+                args.push(gateway.to_string());
+            }
+            args.push("dev".to_string());
+            args.push(hop.interface.clone());
+        }
+        ("ip", args)
+    }
+}
+
+fn run_host_route_command(ip: Ipv4Addr, hop: Option<&RouteHop>) -> io::Result<()> {
+    let (program, args) = host_route_command(ip, hop);
+    let args: Vec<&str> = args.iter().map(|v| v.as_str()).collect();
+    command(program, &args).map(|_| ())
+}
+
+/// 删除主机路由
+pub fn delete_host_route(ip: Ipv4Addr) -> io::Result<()> {
+    run_host_route_command(ip, None)
+}
+
+/// 是否存在到目标地址的主机路由
+#[cfg(target_os = "linux")]
+pub fn host_route_exists(ip: Ipv4Addr) -> io::Result<bool> {
+    let out = command(
+        "ip",
+        &["-4", "route", "show", "exact", &format!("{}/32", ip)],
+    )?;
+    Ok(!out.trim().is_empty())
+}
+
+/// 是否存在到目标地址的主机路由
+#[cfg(target_os = "macos")]
+pub fn host_route_exists(ip: Ipv4Addr) -> io::Result<bool> {
+    let out = command("route", &["-n", "get", &ip.to_string()])?;
+    let destination = format!("destination: {}", ip);
+    Ok(out.lines().any(|line| line.trim() == destination))
+}
+
+/// 是否存在到目标地址的主机路由
+#[cfg(target_os = "windows")]
+pub fn host_route_exists(ip: Ipv4Addr) -> io::Result<bool> {
+    let out = command("route", &["print", "-4"])?;
+    Ok(parse_route_print(&out)
+        .iter()
+        .any(|r| r.dest == ip && r.netmask == Ipv4Addr::BROADCAST))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub fn host_route_exists(_ip: Ipv4Addr) -> io::Result<bool> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// 防止服务器和直连对端的流量进入虚拟网卡
+///
+/// 启动时创建网卡前记录到服务器的原始出口，发现回环时通过这个出口添加主机路由。
+/// 主机路由在进程退出后仍然存在，增删前都写入[`IntentLog`]，异常退出后下次启动时清理
+#[derive(Clone)]
+pub struct RouteGuard {
+    inner: Arc<GuardInner>,
+}
+
+struct GuardInner {
+    tun_name: String,
+    original: Option<RouteHop>,
+    protected: Mutex<HashSet<Ipv4Addr>>,
+    warned: Mutex<HashSet<String>>,
+    log: IntentLog,
+}
+
+impl RouteGuard {
+    pub fn new(tun_name: String, original: Option<RouteHop>, log: IntentLog) -> Self {
+        Self {
+            inner: Arc::new(GuardInner {
+                tun_name,
+                original,
+                protected: Mutex::new(HashSet::new()),
+                warned: Mutex::new(HashSet::new()),
+                log,
+            }),
+        }
+    }
+    pub fn tun_name(&self) -> &str {
+        &self.inner.tun_name
+    }
+    /// 创建网卡前到服务器的出口
+    pub fn original(&self) -> Option<&RouteHop> {
+        self.inner.original.as_ref()
+    }
+    /// 通过原始出口添加主机路由，返回是否是新添加的
+    pub fn protect(&self, ip: Ipv4Addr) -> io::Result<bool> {
+        let hop = match &self.inner.original {
+            Some(hop) => hop,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "original gateway unknown",
+                ))
+            }
+        };
+        if hop.interface == self.inner.tun_name {
+            // 启动前就经过同名网卡，比如另一个vnt
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("original route {} is also a tunnel", hop),
+            ));
+        }
+        let mut protected = self.inner.protected.lock();
+        if protected.contains(&ip) {
+            return Ok(false);
+        }
+        self.inner
+            .log
+            .run(Intent::AddHostRoute(ip, hop.clone()), || {
+                run_host_route_command(ip, Some(hop))
+            })?;
+        protected.insert(ip);
+        Ok(true)
+    }
+    /// 相同的告警只提示一次
+    pub fn first_warning(&self, key: &str) -> bool {
+        self.inner.warned.lock().insert(key.to_string())
+    }
+    /// 删除添加过的主机路由
+    pub fn release(&self) {
+        for ip in self.inner.protected.lock().drain() {
+            let rs = self
+                .inner
+                .log
+                .run(Intent::DeleteHostRoute(ip), || delete_host_route(ip));
+            if let Err(e) = rs {
+                log::warn!("删除主机路由{}失败 {:?}", ip, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::tun_tap_device::route_guard::*;
+
+    fn entry(
+        dest: [u8; 4],
+        netmask: [u8; 4],
+        gateway: Option<[u8; 4]>,
+        interface: &str,
+    ) -> RouteEntry {
+        RouteEntry {
+            dest: dest.into(),
+            netmask: netmask.into(),
+            hop: RouteHop {
+                gateway: gateway.map(|v| v.into()),
+                interface: interface.to_string(),
+            },
+            metric: 10,
+        }
+    }
+
+    #[test]
+    fn test_conflict() {
+        let virtual_ip = Ipv4Addr::new(10, 26, 0, 2);
+        let server = Ipv4Addr::new(43, 139, 56, 10);
+        let peer = Ipv4Addr::new(120, 1, 2, 3);
+        // -i 0.0.0.0/0 安装了默认路由
+        let mut table = vec![
+            entry([0, 0, 0, 0], [0, 0, 0, 0], Some([192, 168, 1, 1]), "eth0"),
+            entry([0, 0, 0, 0], [128, 0, 0, 0], None, "vnt-tun"),
+            entry([128, 0, 0, 0], [128, 0, 0, 0], None, "vnt-tun"),
+            entry([10, 26, 0, 0], [255, 255, 255, 0], None, "vnt-tun"),
+        ];
+        let conflicts = find_conflicts(&[server, peer], "vnt-tun", virtual_ip, |ip| {
+            lookup(&table, ip).map(|r| r.hop.clone())
+        });
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            conflicts[0].to_string(),
+            "route to 43.139.56.10 goes through the vnt device (dev vnt-tun), traffic would loop back into the tunnel"
+        );
+        // 添加主机路由之后
+        table.push(entry(
+            [43, 139, 56, 10],
+            [255; 4],
+            Some([192, 168, 1, 1]),
+            "eth0",
+        ));
+        let conflicts = find_conflicts(&[server, peer], "vnt-tun", virtual_ip, |ip| {
+            lookup(&table, ip).map(|r| r.hop.clone())
+        });
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].target, peer);
+    }
+
+    #[test]
+    fn test_parse() {
+        let hop = parse_ip_route_get(
+            "43.139.56.10 via 192.168.1.1 dev eth0 src 192.168.1.5 uid 0 \n    cache \n",
+        )
+        .unwrap();
+        assert_eq!(hop.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(hop.interface, "eth0");
+        let hop =
+            parse_ip_route_get("43.139.56.10 dev wg0 table 51820 src 10.2.0.2 uid 0\n").unwrap();
+        assert!(hop.is_other_vpn("vnt-tun"));
+        assert!(!hop.is_tun("vnt-tun", Ipv4Addr::new(10, 26, 0, 2)));
+
+        let hop = parse_route_get(
+            "   route to: 43.139.56.10\ndestination: default\n       mask: default\n    gateway: 192.168.1.1\n  interface: en0\n      flags: <UP,GATEWAY,DONE,STATIC,PRCLONING>\n",
+        )
+        .unwrap();
+        assert_eq!(hop.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(hop.interface, "en0");
+
+        // 中文系统上标题和直连网关都是本地化的
+        let table = parse_route_print(
+            "IPv4 路由表\n===========================================================================\n活动路由:\n网络目标        网络掩码          网关       接口   跃点数\n          0.0.0.0          0.0.0.0      192.168.1.1    192.168.1.100     25\n        10.26.0.0    255.255.255.0            在链路上         10.26.0.2    261\n===========================================================================\n永久路由:\n  网络地址          网络掩码  网关地址  跃点数\n          0.0.0.0          0.0.0.0      10.26.0.1       1\n",
+        );
+        assert_eq!(table.len(), 2);
+        let hop = &lookup(&table, Ipv4Addr::new(10, 26, 0, 9)).unwrap().hop;
+        assert_eq!(hop.gateway, None);
+        assert!(hop.is_tun("{A1B2}", Ipv4Addr::new(10, 26, 0, 2)));
+        let hop = &lookup(&table, Ipv4Addr::new(8, 8, 8, 8)).unwrap().hop;
+        assert_eq!(hop.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+}
@@ -4,6 +4,8 @@ use std::sync::Arc;
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+use tun::device::IFace;
 use tun::Device;
 
 use crate::channel::context::ChannelContext;
@@ -12,16 +14,18 @@ use crate::external_route::ExternalRoute;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::metrics::Counter;
+use crate::util::StopManager;
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[repr(transparent)]
 #[derive(Clone)]
 pub struct DeviceAdapter {
-    tun: Arc<Device>,
+    // 观察者模式没有网卡
+    tun: Option<Arc<Device>>,
 }
 impl DeviceAdapter {
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-    pub fn new(tun: Arc<Device>) -> Self {
+    pub fn new(tun: Option<Arc<Device>>) -> Self {
         Self { tun }
     }
     #[cfg(target_os = "android")]
@@ -33,11 +37,19 @@ impl DeviceAdapter {
     }
 }
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-impl std::ops::Deref for DeviceAdapter {
-    type Target = Arc<Device>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.tun
+impl DeviceAdapter {
+    pub fn tun(&self) -> Option<&Arc<Device>> {
+        self.tun.as_ref()
+    }
+    pub fn attached(&self) -> bool {
+        self.tun.is_some()
+    }
+    /// 没有网卡时直接丢弃
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        match &self.tun {
+            Some(tun) => tun.write(buf),
+            None => Ok(buf.len()),
+        }
     }
 }
 
@@ -53,6 +65,9 @@ impl DeviceAdapter {
         let fd = self.tun.load();
         tun::Fd(fd).write(buf)
     }
+    pub fn attached(&self) -> bool {
+        self.tun.load() >= 0
+    }
     pub fn start(&self, fd: std::os::fd::RawFd) -> io::Result<()> {
         //安卓端fd是由外部释放的，所以这里这么搞免得加锁
         self.tun_device_helper.start(Arc::new(Device::new(fd)?))?;
@@ -76,7 +91,7 @@ struct TunDeviceHelperInner {
     client_cipher: Cipher,
     server_cipher: Cipher,
     parallel: usize,
-    up_counter: SingleU64Adder,
+    up_counter: Counter,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 }
 
@@ -90,7 +105,7 @@ impl TunDeviceHelper {
         client_cipher: Cipher,
         server_cipher: Cipher,
         parallel: usize,
-        up_counter: SingleU64Adder,
+        up_counter: Counter,
         device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     ) -> Self {
         Self {
@@ -0,0 +1,392 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use tun::device::IFace;
+
+use crate::tun_tap_device::intent_log::{Intent, IntentLog};
+
+/// 网卡配置用到的操作，单独抽出来方便测试
+pub trait TunOps {
+    fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()>;
+    fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()>;
+    fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()>;
+    /// 指定经过网卡的路由使用的源地址，没有绑定地址的程序发往虚拟网络时使用虚拟ip。
+    /// windows和mac按出口网卡的地址选择源地址，不需要处理
+    fn set_source_hint(
+        &self,
+        _dest: Ipv4Addr,
+        _netmask: Ipv4Addr,
+        _src: Ipv4Addr,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: IFace + ?Sized> TunOps for T {
+    fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+        IFace::set_ip(self, address, mask)
+    }
+
+    fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()> {
+        IFace::add_route(self, dest, netmask, metric)
+    }
+
+    fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        IFace::delete_route(self, dest, netmask)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_source_hint(&self, dest: Ipv4Addr, netmask: Ipv4Addr, src: Ipv4Addr) -> io::Result<()> {
+        let output = std::process::Command::new("ip")
+            .arg("route")
+            .arg("change")
+            .arg(format!("{}/{}", dest, netmask))
+            .arg("dev")
+            .arg(IFace::name(self)?)
+            .arg("src")
+            .arg(src.to_string())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 已完成的配置步骤，回滚时逆序撤销
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SetupStep {
+    /// 设置ip，记录之前的ip
+    SetIp(Option<(Ipv4Addr, Ipv4Addr)>),
+    AddRoute(Ipv4Addr, Ipv4Addr),
+    DeleteRoute(Ipv4Addr, Ipv4Addr, u16),
+}
+
+/// 以事务的方式配置网卡
+///
+/// 每完成一步就记录下来，没有调用commit就被drop时(包括用`?`提前返回)，
+/// 按相反的顺序撤销已完成的步骤，避免留下配置了一半的网卡导致下次启动失败。
+/// 撤销失败只记录日志，继续撤销剩下的步骤。每一步修改前都写入[`IntentLog`]
+pub struct TunSetup<'a, D: TunOps + ?Sized> {
+    device: &'a D,
+    // 网卡当前的ip
+    current_ip: Option<(Ipv4Addr, Ipv4Addr)>,
+    done: Vec<SetupStep>,
+    committed: bool,
+    log: IntentLog,
+}
+
+impl<'a, D: TunOps + ?Sized> TunSetup<'a, D> {
+    /// current_ip 网卡上已有的ip，首次配置时为None
+    pub fn new(device: &'a D, current_ip: Option<(Ipv4Addr, Ipv4Addr)>) -> Self {
+        Self {
+            device,
+            current_ip,
+            done: Vec::new(),
+            committed: false,
+            log: IntentLog::new(),
+        }
+    }
+    /// 修改前记录到日志
+    pub fn with_log(mut self, log: IntentLog) -> Self {
+        self.log = log;
+        self
+    }
+    pub fn set_ip(&mut self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+        if self.current_ip == Some((address, mask)) {
+            // 之前的实例留下的配置和目标一致，直接沿用
+            return Ok(());
+        }
+        // 先记录再执行，执行到一半失败时也会尝试恢复
+        self.done.push(SetupStep::SetIp(self.current_ip));
+        self.set_ip0(address, mask)?;
+        self.current_ip = Some((address, mask));
+        Ok(())
+    }
+    /// 添加路由，已存在残留的同名路由时先删除再添加
+    pub fn add_route(&mut self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()> {
+        if let Err(e) = self.add_route0(dest, netmask, metric) {
+            log::warn!(
+                "添加路由失败,删除残留路由后重试 {}/{} {:?}",
+                dest,
+                netmask,
+                e
+            );
+            if self.delete_route0(dest, netmask).is_err() {
+                return Err(e);
+            }
+            // 残留的路由已经删除，回滚时要加回去，原来的跃点未知，使用相同的跃点
+            self.done
+                .push(SetupStep::DeleteRoute(dest, netmask, metric));
+            self.add_route0(dest, netmask, metric)?;
+        }
+        self.done.push(SetupStep::AddRoute(dest, netmask));
+        Ok(())
+    }
+    /// 添加路由，失败时只记录日志
+    pub fn try_add_route(&mut self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> bool {
+        if let Err(e) = self.add_route(dest, netmask, metric) {
+            log::warn!("添加路由失败 {}/{} {:?}", dest, netmask, e);
+            false
+        } else {
+            true
+        }
+    }
+    /// 删除路由，回滚时以相同的跃点重新添加
+    pub fn delete_route(
+        &mut self,
+        dest: Ipv4Addr,
+        netmask: Ipv4Addr,
+        metric: u16,
+    ) -> io::Result<()> {
+        self.delete_route0(dest, netmask)?;
+        self.done
+            .push(SetupStep::DeleteRoute(dest, netmask, metric));
+        Ok(())
+    }
+    /// 配置完成，返回本次添加的路由
+    pub fn commit(mut self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        self.committed = true;
+        self.done
+            .iter()
+            .filter_map(|step| match step {
+                SetupStep::AddRoute(dest, netmask) => Some((*dest, *netmask)),
+                _ => None,
+            })
+            .collect()
+    }
+    fn rollback(&mut self) {
+        while let Some(step) = self.done.pop() {
+            let rs = match step {
+                SetupStep::SetIp(Some((address, mask))) => self.set_ip0(address, mask),
+                SetupStep::SetIp(None) => {
+                    self.set_ip0(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED)
+                }
+                SetupStep::AddRoute(dest, netmask) => self.delete_route0(dest, netmask),
+                SetupStep::DeleteRoute(dest, netmask, metric) => {
+                    self.add_route0(dest, netmask, metric)
+                }
+            };
+            if let Err(e) = rs {
+                log::warn!("回滚网卡配置失败 {:?} {:?}", step, e);
+            }
+        }
+    }
+    fn set_ip0(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+        self.log.run(Intent::SetIp(address, mask), || {
+            self.device.set_ip(address, mask)
+        })
+    }
+    fn add_route0(&self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()> {
+        self.log.run(Intent::AddRoute(dest, netmask, metric), || {
+            self.device.add_route(dest, netmask, metric)
+        })
+    }
+    fn delete_route0(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        self.log.run(Intent::DeleteRoute(dest, netmask), || {
+            self.device.delete_route(dest, netmask)
+        })
+    }
+}
+
+/// 退出时逆序删除本次添加的路由，失败的只记录日志，返回删除失败的数量
+pub fn teardown<D: TunOps + ?Sized>(
+    device: &D,
+    routes: &[(Ipv4Addr, Ipv4Addr)],
+    log: &IntentLog,
+) -> usize {
+    let mut failed = 0;
+    for (dest, netmask) in routes.iter().rev() {
+        if let Err(e) = log.run(Intent::DeleteRoute(*dest, *netmask), || {
+            device.delete_route(*dest, *netmask)
+        }) {
+            log::warn!("删除路由失败 {}/{} {:?}", dest, netmask, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+impl<'a, D: TunOps + ?Sized> Drop for TunSetup<'a, D> {
+    fn drop(&mut self) {
+        if !self.committed && !self.done.is_empty() {
+            log::warn!("网卡配置失败,回滚{}个步骤", self.done.len());
+            self.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    use crate::tun_tap_device::intent_log::IntentLog;
+    use crate::tun_tap_device::tun_setup::{teardown, TunOps, TunSetup};
+
+    #[derive(Clone, Debug, Default, Eq, PartialEq)]
+    struct State {
+        ip: Option<(Ipv4Addr, Ipv4Addr)>,
+        routes: HashSet<(Ipv4Addr, Ipv4Addr)>,
+    }
+
+    /// 模拟网卡，第fail_at次操作失败，回滚操作也可以失败
+    struct MockDevice {
+        state: RefCell<State>,
+        ops: Cell<usize>,
+        fail_at: Option<usize>,
+    }
+
+    impl MockDevice {
+        fn new(state: State, fail_at: Option<usize>) -> Self {
+            Self {
+                state: RefCell::new(state),
+                ops: Cell::new(0),
+                fail_at,
+            }
+        }
+        fn check(&self) -> io::Result<()> {
+            let n = self.ops.get();
+            self.ops.set(n + 1);
+            if self.fail_at == Some(n) {
+                return Err(io::Error::new(io::ErrorKind::Other, "injected"));
+            }
+            Ok(())
+        }
+    }
+
+    impl TunOps for MockDevice {
+        fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+            self.check()?;
+            self.state.borrow_mut().ip = if address.is_unspecified() {
+                None
+            } else {
+                Some((address, mask))
+            };
+            Ok(())
+        }
+
+        fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, _metric: u16) -> io::Result<()> {
+            self.check()?;
+            if !self.state.borrow_mut().routes.insert((dest, netmask)) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists"));
+            }
+            Ok(())
+        }
+
+        fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+            self.check()?;
+            if !self.state.borrow_mut().routes.remove(&(dest, netmask)) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "not found"));
+            }
+            Ok(())
+        }
+    }
+
+    const IP: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+    const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    const NETWORK: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 0);
+    const OLD_IP: Ipv4Addr = Ipv4Addr::new(10, 27, 0, 2);
+    const OLD_NETWORK: Ipv4Addr = Ipv4Addr::new(10, 27, 0, 0);
+
+    fn setup(device: &MockDevice, current_ip: Option<(Ipv4Addr, Ipv4Addr)>) -> io::Result<()> {
+        let mut setup = TunSetup::new(device, current_ip);
+        if current_ip.is_some() {
+            setup.delete_route(OLD_NETWORK, MASK, 1)?;
+        }
+        setup.set_ip(IP, MASK)?;
+        setup.add_route(NETWORK, MASK, 1)?;
+        setup.add_route(Ipv4Addr::BROADCAST, Ipv4Addr::BROADCAST, 1)?;
+        setup.commit();
+        Ok(())
+    }
+
+    fn old_state() -> State {
+        let mut state = State {
+            ip: Some((OLD_IP, MASK)),
+            ..Default::default()
+        };
+        state.routes.insert((OLD_NETWORK, MASK));
+        state
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        for initial in [State::default(), old_state()] {
+            let current_ip = initial.ip;
+            let ok = MockDevice::new(initial.clone(), None);
+            setup(&ok, current_ip).unwrap();
+            let steps = ok.ops.get();
+            assert_eq!(ok.state.borrow().ip, Some((IP, MASK)));
+            for fail_at in 0..steps {
+                let device = MockDevice::new(initial.clone(), Some(fail_at));
+                assert!(setup(&device, current_ip).is_err());
+                assert_eq!(*device.state.borrow(), initial, "fail_at={}", fail_at);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rollback_continue_on_error() {
+        // 添加广播路由失败，回滚时删除网段路由也失败，仍然要恢复ip
+        let device = MockDevice::new(State::default(), Some(2));
+        let mut setup = TunSetup::new(&device, None);
+        setup.set_ip(IP, MASK).unwrap();
+        setup.add_route(NETWORK, MASK, 1).unwrap();
+        // 下一次操作(回滚时删除路由)失败
+        device.ops.set(2);
+        drop(setup);
+        assert_eq!(device.state.borrow().ip, None);
+    }
+
+    #[test]
+    fn test_teardown() {
+        let device = MockDevice::new(State::default(), None);
+        let mut setup = TunSetup::new(&device, None);
+        setup.set_ip(IP, MASK).unwrap();
+        setup.add_route(NETWORK, MASK, 1).unwrap();
+        setup
+            .add_route(Ipv4Addr::BROADCAST, Ipv4Addr::BROADCAST, 1)
+            .unwrap();
+        let routes = setup.commit();
+        // 网段路由已经被外部删除，继续删除剩下的
+        device.state.borrow_mut().routes.remove(&(NETWORK, MASK));
+        assert_eq!(teardown(&device, &routes, &IntentLog::new()), 1);
+        assert!(device.state.borrow().routes.is_empty());
+        assert_eq!(teardown(&device, &[], &IntentLog::new()), 0);
+    }
+
+    #[test]
+    fn test_rollback_leftover_route() {
+        // 已有残留的网段路由，删除后重新添加，之后的步骤失败时要恢复残留的路由
+        let mut initial = State::default();
+        initial.routes.insert((NETWORK, MASK));
+        // 依次是 设置ip、添加(已存在)、删除残留、重新添加、添加广播路由
+        for fail_at in [3, 4] {
+            let device = MockDevice::new(initial.clone(), Some(fail_at));
+            assert!(setup(&device, None).is_err());
+            assert_eq!(*device.state.borrow(), initial, "fail_at={}", fail_at);
+        }
+    }
+
+    #[test]
+    fn test_adopt_leftover() {
+        // 之前异常退出的实例留下了相同的ip和路由
+        let mut state = State {
+            ip: Some((IP, MASK)),
+            ..Default::default()
+        };
+        state.routes.insert((NETWORK, MASK));
+        let device = MockDevice::new(state.clone(), None);
+        let mut setup = TunSetup::new(&device, Some((IP, MASK)));
+        setup.set_ip(IP, MASK).unwrap();
+        setup.add_route(NETWORK, MASK, 1).unwrap();
+        assert_eq!(setup.commit(), vec![(NETWORK, MASK)]);
+        assert_eq!(*device.state.borrow(), state);
+    }
+}
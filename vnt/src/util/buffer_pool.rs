@@ -0,0 +1,78 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use crate::util::metrics::Counter;
+
+/// 在线程之间传递数据包时复用缓冲区
+///
+/// 读取线程从BufferSource取缓冲区，处理线程用完后通过BufferRecycler还回来，
+/// 稳定后收发路径上不再分配内存。归还的通道已满时直接丢弃，池子里的缓冲区不会超过capacity
+pub fn buffer_pool(capacity: usize, size: usize, alloc: Counter) -> (BufferSource, BufferRecycler) {
+    let (sender, receiver) = sync_channel(capacity);
+    (
+        BufferSource {
+            receiver,
+            size,
+            alloc,
+        },
+        BufferRecycler { sender },
+    )
+}
+
+pub struct BufferSource {
+    receiver: Receiver<Vec<u8>>,
+    size: usize,
+    // 池子为空时新分配的次数
+    alloc: Counter,
+}
+
+impl BufferSource {
+    /// 取一个长度为size的缓冲区，复用的缓冲区保留上次的内容
+    pub fn get(&self) -> Vec<u8> {
+        match self.receiver.try_recv() {
+            Ok(buf) => buf,
+            Err(_) => {
+                self.alloc.inc();
+                vec![0; self.size]
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BufferRecycler {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl BufferRecycler {
+    pub fn put(&self, buf: Vec<u8>) {
+        let _ = self.sender.try_send(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::buffer_pool;
+    use crate::util::metrics::Registry;
+
+    #[test]
+    fn test_reuse() {
+        let alloc = Registry::new().counter("buffer_alloc", &[]);
+        let (source, recycler) = buffer_pool(2, 16, alloc.clone());
+        let a = source.get();
+        let b = source.get();
+        let c = source.get();
+        assert_eq!(alloc.get(), 3);
+        let ptr = a.as_ptr();
+        recycler.put(a);
+        recycler.put(b);
+        // 超过容量的丢弃
+        recycler.put(c);
+        let a = source.get();
+        assert_eq!(a.as_ptr(), ptr);
+        assert_eq!(a.len(), 16);
+        source.get();
+        assert_eq!(alloc.get(), 3);
+        source.get();
+        assert_eq!(alloc.get(), 4);
+    }
+}
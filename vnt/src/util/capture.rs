@@ -0,0 +1,183 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// 抓包文件头
+pub const MAGIC: &[u8; 8] = b"VNTCAP01";
+
+/// 数据包的方向
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// 从socket收到的外层数据包
+    Inbound,
+    /// 从网卡读到的ip包
+    Outbound,
+}
+
+/// 一条抓包记录
+///
+/// 格式(大端): 时间(微秒,u64) | 方向(u8) | 地址族(u8,4/6) | ip | 端口(u16) | 长度(u32) | 数据
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// 距离抓包开始的时间
+    pub time_us: u64,
+    pub direction: Direction,
+    /// 入站为对端地址，出站没有地址
+    pub addr: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn inbound(time_us: u64, addr: SocketAddr, data: Vec<u8>) -> Self {
+        Self {
+            time_us,
+            direction: Direction::Inbound,
+            addr,
+            data,
+        }
+    }
+    pub fn outbound(time_us: u64, data: Vec<u8>) -> Self {
+        Self {
+            time_us,
+            direction: Direction::Outbound,
+            addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            data,
+        }
+    }
+}
+
+pub struct CaptureWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(Self { writer })
+    }
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let w = &mut self.writer;
+        w.write_all(&record.time_us.to_be_bytes())?;
+        w.write_all(&[match record.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }])?;
+        match record.addr.ip() {
+            IpAddr::V4(ip) => {
+                w.write_all(&[4])?;
+                w.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                w.write_all(&[6])?;
+                w.write_all(&ip.octets())?;
+            }
+        }
+        w.write_all(&record.addr.port().to_be_bytes())?;
+        w.write_all(&(record.data.len() as u32).to_be_bytes())?;
+        w.write_all(&record.data)
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a vnt capture file",
+            ));
+        }
+        Ok(Self { reader })
+    }
+    /// 读取下一条记录，文件结束时返回None，截断的记录返回错误
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut time = [0u8; 8];
+        match self.reader.read_exact(&mut time) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut head = [0u8; 2];
+        self.reader.read_exact(&mut head)?;
+        let direction = match head[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {}", v),
+                ))
+            }
+        };
+        let ip = match head[1] {
+            4 => {
+                let mut ip = [0u8; 4];
+                self.reader.read_exact(&mut ip)?;
+                IpAddr::V4(Ipv4Addr::from(ip))
+            }
+            6 => {
+                let mut ip = [0u8; 16];
+                self.reader.read_exact(&mut ip)?;
+                IpAddr::V6(Ipv6Addr::from(ip))
+            }
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid address family {}", v),
+                ))
+            }
+        };
+        let mut port = [0u8; 2];
+        self.reader.read_exact(&mut port)?;
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > 65535 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record too long {}", len),
+            ));
+        }
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            time_us: u64::from_be_bytes(time),
+            direction,
+            addr: SocketAddr::new(ip, u16::from_be_bytes(port)),
+            data,
+        }))
+    }
+}
+
+#[test]
+fn test_capture_round_trip() {
+    let records = vec![
+        Record::inbound(0, "192.168.1.2:29872".parse().unwrap(), vec![1, 2, 3]),
+        Record::outbound(1500, vec![0x45; 20]),
+        Record::inbound(3000, "[::1]:29872".parse().unwrap(), vec![]),
+    ];
+    let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    let buf = writer.into_inner();
+    let mut reader = CaptureReader::new(&buf[..]).unwrap();
+    for record in &records {
+        assert_eq!(reader.next_record().unwrap().as_ref(), Some(record));
+    }
+    assert_eq!(reader.next_record().unwrap(), None);
+    // 截断的记录
+    let mut reader = CaptureReader::new(&buf[..buf.len() - 1]).unwrap();
+    reader.next_record().unwrap();
+    reader.next_record().unwrap();
+    assert!(reader.next_record().is_err());
+    assert!(CaptureReader::new(&b"VNTCAP00"[..]).is_err());
+}
@@ -0,0 +1,97 @@
+#![allow(deprecated)]
+//! 旧的计数器接口，保留给外部使用者，内部已经改用`metrics::Counter`
+
+use crate::util::metrics::Counter;
+
+#[deprecated(note = "use vnt::util::metrics::Counter")]
+#[derive(Clone)]
+pub struct U64Adder {
+    inner: Counter,
+}
+
+impl U64Adder {
+    /// 条带数由`metrics::Counter`决定，capacity不再使用
+    pub fn with_capacity(_capacity: usize) -> Self {
+        Self {
+            inner: Counter::new(),
+        }
+    }
+    pub fn add(&mut self, num: u64) {
+        self.inner.add(num);
+    }
+    pub fn get(&self) -> u64 {
+        self.inner.get()
+    }
+    pub fn watch(&self) -> WatchU64Adder {
+        WatchU64Adder {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[deprecated(note = "use vnt::util::metrics::Counter")]
+pub struct SingleU64Adder {
+    inner: Counter,
+}
+
+impl Default for SingleU64Adder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SingleU64Adder {
+    pub fn new() -> Self {
+        Self {
+            inner: Counter::new(),
+        }
+    }
+    pub fn add(&mut self, num: u64) {
+        self.inner.add(num);
+    }
+    pub fn get(&self) -> u64 {
+        self.inner.get()
+    }
+    pub fn watch(&self) -> WatchSingleU64Adder {
+        WatchSingleU64Adder {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[deprecated(note = "use vnt::util::metrics::Counter")]
+#[derive(Clone)]
+pub struct WatchU64Adder {
+    inner: Counter,
+}
+
+impl WatchU64Adder {
+    pub fn get(&self) -> u64 {
+        self.inner.get()
+    }
+}
+
+#[deprecated(note = "use vnt::util::metrics::Counter")]
+#[derive(Clone)]
+pub struct WatchSingleU64Adder {
+    inner: Counter,
+}
+
+impl WatchSingleU64Adder {
+    pub fn get(&self) -> u64 {
+        self.inner.get()
+    }
+}
+
+#[test]
+fn test_adder() {
+    let mut adder = U64Adder::with_capacity(4);
+    let mut other = adder.clone();
+    let watch = adder.watch();
+    adder.add(2);
+    other.add(3);
+    assert_eq!(watch.get(), 5);
+    let mut single = SingleU64Adder::new();
+    single.add(7);
+    assert_eq!(single.watch().get(), 7);
+}
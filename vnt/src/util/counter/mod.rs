@@ -1,2 +0,0 @@
-mod adder;
-pub use adder::*;
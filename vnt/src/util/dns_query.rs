@@ -124,12 +124,16 @@ pub fn dns_query_all(
                 let th1 = {
                     let host = host.to_string();
                     let name_server = name_server.clone();
-                    thread::spawn(move || a_dns(host, name_server))
+                    thread::Builder::new()
+                        .name("dnsQueryA".into())
+                        .spawn(move || a_dns(host, name_server))?
                 };
                 let th2 = {
                     let host = host.to_string();
                     let name_server = name_server.clone();
-                    thread::spawn(move || aaaa_dns(host, name_server))
+                    thread::Builder::new()
+                        .name("dnsQueryAAAA".into())
+                        .spawn(move || aaaa_dns(host, name_server))?
                 };
                 let mut addr = Vec::new();
                 match th1.join().unwrap() {
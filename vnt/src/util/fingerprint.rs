@@ -0,0 +1,32 @@
+use sha2::Digest;
+
+const DOMAIN: &[u8] = b"vnt-device-fingerprint";
+
+/// 设备指纹，用于审计虚拟ip背后是哪台设备
+///
+/// 由设备id和组网token一起计算，同一台设备在不同token下的指纹没有关联，
+/// 拿到指纹也无法反推出设备id
+pub fn device_fingerprint(token: &str, device_id: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(DOMAIN);
+    // 带上长度，避免token和设备id拼接后产生歧义
+    hasher.update((token.len() as u32).to_be_bytes());
+    hasher.update(token.as_bytes());
+    hasher.update(device_id.as_bytes());
+    let hash = hasher.finalize();
+    hash[..8].iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+#[test]
+fn test_device_fingerprint() {
+    let a = device_fingerprint("token-a", "device-1");
+    assert_eq!(a.len(), 16);
+    // 稳定
+    assert_eq!(a, device_fingerprint("token-a", "device-1"));
+    // 不同token下同一台设备的指纹不同
+    assert_ne!(a, device_fingerprint("token-b", "device-1"));
+    assert_ne!(a, device_fingerprint("token-a", "device-2"));
+    // 拼接相同但边界不同
+    assert_ne!(device_fingerprint("ab", "c"), device_fingerprint("a", "bc"));
+    assert!(!a.contains("device"));
+}
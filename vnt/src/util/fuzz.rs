@@ -0,0 +1,1167 @@
+//! 模糊测试目标
+//!
+//! 每个目标接收任意字节，覆盖远端数据或者本地文件能到达的解码器，任何输入都不能panic。
+//! `vnt/fuzz`下的cargo-fuzz目标直接调用这里的函数，种子语料由`seeds`生成(`examples/fuzz_corpus.rs`)；
+//! 没有安装cargo-fuzz时，`smoke`用确定性的变异执行固定次数，作为普通单元测试的一部分。
+//!
+//! 新增解码类型(带缓冲区参数的包结构、proto中的消息、状态文件)时需要加入某个目标的`covers`，
+//! 否则`test_decoders_registered`失败。
+use std::net::Ipv4Addr;
+use std::panic::catch_unwind;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use packet::arp::arp::ArpPacket;
+use packet::ethernet::packet::EthernetPacket;
+use packet::ethernet::protocol::Protocol as EthernetProtocol;
+use packet::icmp::icmp::IcmpPacket;
+use packet::icmp::Kind;
+use packet::igmp::igmp_v1::IgmpV1Packet;
+use packet::igmp::igmp_v2::IgmpV2Packet;
+use packet::igmp::igmp_v3::{IgmpV3QueryPacket, IgmpV3RecordPacket, IgmpV3ReportPacket};
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol as IpProtocol;
+use packet::ip::IpPacket;
+use packet::tcp::tcp::TcpPacket;
+use packet::udp::udp::UdpPacket;
+use protobuf::Message;
+
+use crate::channel::block_list::BlockList;
+use crate::channel::mtu_guard::MtuGuard;
+use crate::channel::path_score::{PathPreferences, PeerPreference, Preference};
+use crate::channel::self_probe::{self, SelfProbe};
+use crate::cipher::{Cipher, CipherModel};
+use crate::handle::flow_table::{FlowInfo, FlowTable};
+use crate::handle::packet_hook::PacketView;
+use crate::handle::server_action::{ack_packet, Action, ServerActions};
+use crate::proto::message::{
+    ClientStatusInfo, DeviceInfo, DeviceList, HandshakeRequest, HandshakeResponse, PunchInfo,
+    PunchNatType, RegistrationRequest, RegistrationResponse, RelayUsage, RouteItem,
+    SecretHandshakeRequest, ServerAction, ServerActionAck, ServerActionStatus, ServerClock,
+    ServerNotice, ServerRtt,
+};
+use crate::protocol::body::{AesCbcSecretBody, RsaSecretBody, SecretBody, ENCRYPTION_RESERVED};
+use crate::protocol::codec::{Codec, Compact, Encoding};
+use crate::protocol::control_packet::{
+    AddrPacket, BandwidthProbePacket, BandwidthReplyPacket, BandwidthRequestPacket, ControlPacket,
+    CookiePacket, FeaturePacket, NoticePacket, PingPacket, ReversePacket, BANDWIDTH_REPLY_LEN,
+};
+use crate::protocol::error_packet::InErrorPacket;
+use crate::protocol::{
+    compat, control_packet, error_packet, ip_turn_packet, ip_turn_packet::BroadcastPacket,
+    other_turn_packet, service_packet, NetPacket, Protocol, MAX_TTL,
+};
+use crate::util::metrics::Registry;
+use crate::util::state_store::{self, StateFile};
+use crate::util::subsystem::SubsystemOverrides;
+
+/// 变异后的最大长度，超过udp载荷的输入在接收时就被截断了
+pub const MAX_INPUT: usize = 4096;
+
+const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 3);
+const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 26, 0, 2);
+
+pub struct Target {
+    pub name: &'static str,
+    /// 覆盖的解码类型，和源码中的类型名一致
+    pub covers: fn() -> Vec<&'static str>,
+    pub run: fn(&[u8]),
+    pub seeds: fn() -> Vec<Vec<u8>>,
+}
+
+pub const TARGETS: &[Target] = &[
+    Target {
+        name: "dispatch",
+        covers: || {
+            vec![
+                "NetPacket",
+                "InErrorPacket",
+                "ErrorPacket",
+                "BroadcastPacket",
+            ]
+        },
+        run: dispatch,
+        seeds: dispatch_seeds,
+    },
+    Target {
+        name: "control",
+        covers: || {
+            vec![
+                "ControlPacket",
+                "PingPacket",
+                "AddrPacket",
+                "NoticePacket",
+                "FeaturePacket",
+                "BandwidthRequestPacket",
+                "BandwidthReplyPacket",
+                "BandwidthProbePacket",
+                "CookiePacket",
+                "ReversePacket",
+            ]
+        },
+        run: control,
+        seeds: control_seeds,
+    },
+    Target {
+        name: "body",
+        covers: || vec!["SecretBody", "AesCbcSecretBody", "RsaSecretBody"],
+        run: body,
+        seeds: body_seeds,
+    },
+    Target {
+        name: "proto",
+        covers: || MESSAGES.iter().map(|(name, _)| *name).collect(),
+        run: proto,
+        seeds: proto_seeds,
+    },
+    Target {
+        name: "ip",
+        covers: || {
+            vec![
+                "IpPacket",
+                "IpV4Packet",
+                "IcmpPacket",
+                "Description",
+                "UdpPacket",
+                "TcpPacket",
+                "IgmpV1Packet",
+                "IgmpV2Packet",
+                "IgmpV3QueryPacket",
+                "IgmpV3ReportPacket",
+                "IgmpV3RecordPacket",
+                "EthernetPacket",
+                "ArpPacket",
+            ]
+        },
+        run: ip,
+        seeds: ip_seeds,
+    },
+    Target {
+        name: "state_file",
+        covers: || vec!["BlockList", "PathPreferences", "SubsystemOverrides"],
+        run: state_file,
+        seeds: state_file_seeds,
+    },
+];
+
+pub fn target(name: &str) -> Option<&'static Target> {
+    TARGETS.iter().find(|v| v.name == name)
+}
+
+/// 收到的数据包，按照`RecvDataHandler`的顺序解码、检查ttl、解密，再按协议分发到各个解码器。
+/// 接收处理直接持有socket和网卡，这里只覆盖其中解析远端数据的部分
+pub fn dispatch(data: &[u8]) {
+    let mut buf = data.to_vec();
+    let _ = compat::sequence(&buf);
+    let len = match compat::decode_in_place(&mut buf) {
+        Ok(len) => len,
+        Err(_) => return,
+    };
+    let buf = &mut buf[..len];
+    let net_packet = match NetPacket::new(&mut *buf) {
+        Ok(net_packet) => net_packet,
+        Err(_) => return,
+    };
+    if net_packet.ttl() == 0 || net_packet.source_ttl() < net_packet.ttl() {
+        return;
+    }
+    let _ = (
+        net_packet.is_gateway(),
+        net_packet.version(),
+        net_packet.head(),
+    );
+    if !net_packet.is_encrypt() {
+        plain(net_packet);
+        return;
+    }
+    for cipher in ciphers() {
+        // 解密是原地进行的，每种加密方式使用一份拷贝
+        let mut copy = buf.to_vec();
+        let mut net_packet = match NetPacket::new(&mut copy[..]) {
+            Ok(net_packet) => net_packet,
+            Err(_) => return,
+        };
+        if cipher.decrypt_ipv4(&mut net_packet).is_ok() {
+            plain(net_packet);
+        }
+    }
+}
+
+/// 密码固定，变异出的包通常在校验时失败，种子中有用这些密码加密的包
+fn ciphers() -> &'static [Cipher] {
+    static CIPHERS: OnceLock<Vec<Cipher>> = OnceLock::new();
+    CIPHERS.get_or_init(|| {
+        let mut list = Vec::new();
+        for (model, token) in [
+            ("aes_gcm", None),
+            ("aes_gcm", Some("fuzz")),
+            ("aes_cbc", None),
+            ("aes_ecb", Some("fuzz")),
+            ("sm4_cbc", None),
+        ] {
+            if let Ok(model) = CipherModel::from_str(model) {
+                list.push(Cipher::new_password(
+                    model,
+                    Some("fuzz".to_string()),
+                    token.map(|v| v.to_string()),
+                ));
+            }
+        }
+        list
+    })
+}
+
+fn plain(mut net_packet: NetPacket<&mut [u8]>) {
+    let protocol = net_packet.transport_protocol();
+    match net_packet.protocol() {
+        Protocol::Service => service(protocol, net_packet.payload()),
+        Protocol::Error => {
+            if let Ok(InErrorPacket::OtherError(packet)) =
+                InErrorPacket::new(protocol, net_packet.payload())
+            {
+                let _ = packet.message();
+            }
+        }
+        Protocol::Control => control_packet(protocol, net_packet.payload()),
+        Protocol::IpTurn => match ip_turn_packet::Protocol::from(protocol) {
+            ip_turn_packet::Protocol::Ipv4 => {
+                let (source, destination) = (net_packet.source(), net_packet.destination());
+                inbound_ipv4(source, destination, net_packet.payload_mut());
+            }
+            ip_turn_packet::Protocol::Ipv4Broadcast => {
+                if let Ok(packet) = BroadcastPacket::new(net_packet.payload()) {
+                    let _ = packet.addresses();
+                    let _ = packet.data();
+                }
+            }
+            ip_turn_packet::Protocol::Unknown(_) => {}
+        },
+        Protocol::OtherTurn => {
+            if let Some(encoding) = Encoding::of_punch(other_turn_packet::Protocol::from(protocol))
+            {
+                let _ = encoding.codec().decode_punch(net_packet.payload());
+            }
+        }
+        Protocol::Unknown(_) => {}
+    }
+}
+
+fn service(protocol: u8, payload: &[u8]) {
+    match service_packet::Protocol::from(protocol) {
+        service_packet::Protocol::RegistrationResponse => {
+            let _ = RegistrationResponse::parse_from_bytes(payload);
+        }
+        service_packet::Protocol::PushDeviceList => {
+            let _ = DeviceList::parse_from_bytes(payload);
+        }
+        service_packet::Protocol::PushDeviceListCompact => {
+            let _ = Compact.decode_device_list(payload);
+        }
+        service_packet::Protocol::HandshakeResponse => {
+            let _ = HandshakeResponse::parse_from_bytes(payload);
+        }
+        service_packet::Protocol::ServerAction => {
+            if let Ok(request) = ServerAction::parse_from_bytes(payload) {
+                let actions = ServerActions::new(&Registry::new());
+                actions.set_allowed(&Action::ALL);
+                let status = match actions.receive(&request) {
+                    Ok(()) => ServerActionStatus::Done,
+                    Err((status, _)) => status,
+                };
+                let _ = ack_packet(
+                    &Cipher::None,
+                    Ipv4Addr::new(10, 26, 0, 2),
+                    Ipv4Addr::new(10, 26, 0, 1),
+                    request.id,
+                    &request.action,
+                    status,
+                    &request.arg,
+                );
+                let _ = actions.take();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 发给本机的ip包，和`ClientPacketHandler::ip_turn`一样先处理icmp回显，再经过写网卡前的检查
+fn inbound_ipv4(source: Ipv4Addr, destination: Ipv4Addr, buf: &mut [u8]) {
+    {
+        let mut ipv4 = match IpV4Packet::new(&mut *buf) {
+            Ok(ipv4) => ipv4,
+            Err(_) => return,
+        };
+        if ipv4.protocol() == IpProtocol::Icmp && ipv4.destination_ip() == destination {
+            if let Ok(mut icmp_packet) = IcmpPacket::new(ipv4.payload_mut()) {
+                if icmp_packet.kind() == Kind::EchoRequest {
+                    icmp_packet.set_kind(Kind::EchoReply);
+                    icmp_packet.update_checksum();
+                    ipv4.set_source_ip(destination);
+                    ipv4.set_destination_ip(source);
+                    ipv4.update_checksum();
+                    return;
+                }
+            }
+        }
+    }
+    let mtu_guard = MtuGuard::new();
+    mtu_guard.set_clamp(source, 536);
+    mtu_guard.inbound(source, buf);
+    let probe = SelfProbe::new();
+    let _expect = probe.expect(0);
+    probe.deliver(buf);
+    let notify: Arc<dyn Fn(FlowInfo) + Send + Sync> = Arc::new(|info| {
+        let _ = info.to_string();
+    });
+    FlowTable::new(true, 16, Some(notify)).record(source, buf);
+}
+
+/// 控制包，第一个字节是控制协议
+pub fn control(data: &[u8]) {
+    if let Some((protocol, payload)) = data.split_first() {
+        control_packet(*protocol, payload);
+    }
+}
+
+fn control_packet(protocol: u8, payload: &[u8]) {
+    let packet = match ControlPacket::new(protocol, payload) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    match packet {
+        ControlPacket::PingPacket(packet) | ControlPacket::PongPacket(packet) => {
+            let _ = format!("{:?}", packet);
+            let _ = packet.echo_seq();
+            let _ = RelayUsage::parse_from_bytes(packet.extension());
+            let _ = ServerClock::parse_from_bytes(packet.extension());
+        }
+        ControlPacket::AddrResponse(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::Notice(packet) | ControlPacket::NoticeAck(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::FeatureRequest(packet) | ControlPacket::FeatureReply(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::BandwidthRequest(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::BandwidthReply(packet) => {
+            let _ = format!("{:?}", packet);
+            for i in 0..packet.count() as usize {
+                let _ = packet.burst(i);
+            }
+        }
+        ControlPacket::BandwidthProbe(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::Cookie(packet) => {
+            let _ = format!("{:?}", packet);
+        }
+        ControlPacket::ConnectIntent(packet) | ControlPacket::ConnectApprove(packet) => {
+            let _ = format!("{:?}", packet);
+            let _ = (packet.nonce(), packet.duration());
+        }
+        ControlPacket::PunchRequest | ControlPacket::PunchResponse | ControlPacket::AddrRequest => {
+        }
+    }
+}
+
+/// 加密数据体，第一个字节的最低位表示是否带有指纹
+pub fn body(data: &[u8]) {
+    let (flags, payload) = match data.split_first() {
+        Some(v) => v,
+        None => return,
+    };
+    let exist_finger = flags & 1 == 1;
+    let mut buf = payload.to_vec();
+    if let Ok(mut body) = SecretBody::new(&mut buf[..], exist_finger) {
+        let _ = format!("{:?}", body);
+        let _ = (body.en_body(), body.buffer(), body.auth_only_aad(&[0; 12]));
+        body.set_random(0);
+        let _ = body.set_tag(&[0; 16]);
+        let _ = body.set_finger(&[0; 12]);
+        let _ = body.body_mut();
+        let _ = body.tag_mut();
+    }
+    let mut buf = payload.to_vec();
+    if let Ok(mut body) = AesCbcSecretBody::new(&mut buf[..], exist_finger) {
+        let _ = (body.en_body(), body.finger());
+        body.set_random(0);
+        let _ = body.set_finger(&[0; 12]);
+    }
+    let mut buf = payload.to_vec();
+    if let Ok(mut body) = RsaSecretBody::new(&mut buf[..]) {
+        let _ = (body.data(), body.random(), body.body(), body.finger());
+        let _ = body.set_random(&[0; 16]);
+        let _ = body.set_finger(&[0; 16]);
+    }
+}
+
+/// 第一个字节选择消息类型，解析成功的消息重新编码后必须能解析出相同的内容
+pub fn proto(data: &[u8]) {
+    if let Some((index, payload)) = data.split_first() {
+        let (_, run) = MESSAGES[*index as usize % MESSAGES.len()];
+        run(payload);
+    }
+}
+
+const MESSAGES: &[(&str, fn(&[u8]))] = &[
+    ("HandshakeRequest", round_trip::<HandshakeRequest>),
+    ("HandshakeResponse", round_trip::<HandshakeResponse>),
+    (
+        "SecretHandshakeRequest",
+        round_trip::<SecretHandshakeRequest>,
+    ),
+    ("RegistrationRequest", round_trip::<RegistrationRequest>),
+    ("RegistrationResponse", round_trip::<RegistrationResponse>),
+    ("DeviceInfo", round_trip::<DeviceInfo>),
+    ("DeviceList", round_trip::<DeviceList>),
+    ("ServerNotice", round_trip::<ServerNotice>),
+    ("PunchInfo", round_trip::<PunchInfo>),
+    ("ClientStatusInfo", round_trip::<ClientStatusInfo>),
+    ("RouteItem", round_trip::<RouteItem>),
+    ("RelayUsage", round_trip::<RelayUsage>),
+    ("ServerRtt", round_trip::<ServerRtt>),
+    ("ServerClock", round_trip::<ServerClock>),
+    ("ServerAction", round_trip::<ServerAction>),
+    ("ServerActionAck", round_trip::<ServerActionAck>),
+];
+
+fn round_trip<M: Message>(data: &[u8]) {
+    if let Ok(message) = M::parse_from_bytes(data) {
+        let bytes = message.write_to_bytes().unwrap();
+        assert_eq!(M::parse_from_bytes(&bytes).unwrap(), message);
+    }
+}
+
+/// ip包，同样的字节也当作以太网帧和arp包解析
+pub fn ip(data: &[u8]) {
+    let mut buf = data.to_vec();
+    if let Ok(IpPacket::V4(ipv4)) = IpPacket::new(&buf[..]) {
+        let _ = format!("{:?}", ipv4);
+        let _ = (ipv4.header(), ipv4.options(), ipv4.payload());
+        transport(
+            ipv4.source_ip(),
+            ipv4.destination_ip(),
+            ipv4.protocol(),
+            ipv4.payload(),
+        );
+    }
+    if let Ok(mut ipv4) = IpV4Packet::new(&mut buf[..]) {
+        // 修改后重新计算的校验和必须正确
+        ipv4.set_ttl(MAX_TTL);
+        ipv4.update_checksum();
+        assert!(ipv4.is_valid());
+        let (source, destination) = (ipv4.source_ip(), ipv4.destination_ip());
+        match ipv4.protocol() {
+            IpProtocol::Icmp => {
+                if let Ok(mut icmp_packet) = IcmpPacket::new(ipv4.payload_mut()) {
+                    icmp_packet.update_checksum();
+                    assert!(icmp_packet.is_valid());
+                }
+            }
+            IpProtocol::Udp => {
+                if let Ok(mut udp) = UdpPacket::new(source, destination, ipv4.payload_mut()) {
+                    udp.set_source_port(1);
+                    udp.update_checksum();
+                    assert!(udp.is_valid());
+                }
+            }
+            IpProtocol::Tcp => {
+                if let Ok(mut tcp) = TcpPacket::new(source, destination, ipv4.payload_mut()) {
+                    tcp.set_destination_port(1);
+                    tcp.update_checksum();
+                    assert!(tcp.is_valid());
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Ok(mut view) = PacketView::new(&mut buf[..]) {
+        let _ = (view.source_port(), view.destination_port());
+        view.set_source_port(1);
+        view.fix_checksums();
+    }
+    let mtu_guard = MtuGuard::new();
+    mtu_guard.set_clamp(DESTINATION, 536);
+    mtu_guard.outbound(DESTINATION, &mut buf);
+    if let Ok(ethernet) = EthernetPacket::new(data) {
+        let _ = format!("{:?}", ethernet);
+        if ethernet.protocol() == EthernetProtocol::Arp {
+            arp(ethernet.payload());
+        }
+    }
+    arp(data);
+}
+
+fn transport(source: Ipv4Addr, destination: Ipv4Addr, protocol: IpProtocol, payload: &[u8]) {
+    match protocol {
+        IpProtocol::Icmp => {
+            if let Ok(icmp_packet) = IcmpPacket::new(payload) {
+                let _ = format!("{:?}", icmp_packet);
+                let _ = format!("{:?}", icmp_packet.header_other());
+                let _ = format!("{:?}", icmp_packet.description());
+            }
+        }
+        IpProtocol::Udp => {
+            if let Ok(udp) = UdpPacket::new(source, destination, payload) {
+                let _ = format!("{:?}", udp);
+            }
+        }
+        IpProtocol::Tcp => {
+            if let Ok(tcp) = TcpPacket::new(source, destination, payload) {
+                let _ = format!("{:?}", tcp);
+            }
+        }
+        IpProtocol::Igmp => igmp(payload),
+        _ => {}
+    }
+}
+
+fn igmp(payload: &[u8]) {
+    if let Ok(packet) = IgmpV1Packet::new(payload) {
+        let _ = format!("{:?}", packet);
+    }
+    if let Ok(packet) = IgmpV2Packet::new(payload) {
+        let _ = format!("{:?}", packet);
+    }
+    if let Ok(packet) = IgmpV3QueryPacket::new(payload) {
+        let _ = format!("{:?}", packet);
+        for i in 0..=packet.source_number().min(8) {
+            let _ = packet.source_address(i);
+        }
+    }
+    if let Ok(packet) = IgmpV3ReportPacket::new(payload) {
+        let _ = format!("{:?}", packet);
+        for record in packet.group_records().unwrap_or_default() {
+            for i in 0..=record.source_number().min(8) {
+                let _ = record.source_address(i);
+            }
+        }
+    }
+    if let Ok(packet) = IgmpV3RecordPacket::new(payload) {
+        let _ = format!("{:?}", packet);
+    }
+}
+
+fn arp(data: &[u8]) {
+    if let Ok(packet) = ArpPacket::new(data) {
+        let _ = format!("{:?}", packet);
+    }
+}
+
+/// 状态文件，包括文件头、屏蔽列表和路径偏好的内容
+pub fn state_file(data: &[u8]) {
+    let _ = state_store::decode::<Raw>(data);
+    if let Some(block_list) = state_store::decode::<BlockList>(data) {
+        let again = state_store::decode::<BlockList>(&state_store::encode(&block_list)).unwrap();
+        assert_eq!(again.list(), block_list.list());
+    }
+    if let Some(preferences) = state_store::decode::<PathPreferences>(data) {
+        let again =
+            state_store::decode::<PathPreferences>(&state_store::encode(&preferences)).unwrap();
+        assert_eq!(again.0, preferences.0);
+    }
+    if let Some(overrides) = state_store::decode::<SubsystemOverrides>(data) {
+        let again =
+            state_store::decode::<SubsystemOverrides>(&state_store::encode(&overrides)).unwrap();
+        assert_eq!(again, overrides);
+    }
+}
+
+/// 接受任何版本，只检查文件头
+struct Raw;
+
+impl StateFile for Raw {
+    const VERSION: u32 = u32::MAX;
+    fn encode(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn decode(_version: u32, _body: &[u8]) -> Option<Self> {
+        Some(Raw)
+    }
+}
+
+fn net_packet(protocol: Protocol, transport_protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; 12 + payload.len()];
+    let mut packet = NetPacket::new(&mut buf[..]).unwrap();
+    packet.set_default_version();
+    packet.set_protocol(protocol);
+    packet.set_transport_protocol(transport_protocol);
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(SOURCE);
+    packet.set_destination(DESTINATION);
+    packet.payload_mut().copy_from_slice(payload);
+    buf
+}
+
+fn encrypted(cipher: &Cipher, plain: &[u8]) -> Option<Vec<u8>> {
+    let mut buf = plain.to_vec();
+    buf.resize(plain.len() + ENCRYPTION_RESERVED, 0);
+    let mut packet = NetPacket::new0(plain.len(), &mut buf[..]).ok()?;
+    cipher.encrypt_ipv4(&mut packet).ok()?;
+    let len = packet.data_len();
+    buf.truncate(len);
+    Some(buf)
+}
+
+fn dispatch_seeds() -> Vec<Vec<u8>> {
+    let mut seeds: Vec<Vec<u8>> = control_seeds()
+        .iter()
+        .map(|v| net_packet(Protocol::Control, v[0], &v[1..]))
+        .collect();
+    let ipv4_seed = seeds.len();
+    for ipv4 in ip_seeds().into_iter().take(3) {
+        seeds.push(net_packet(
+            Protocol::IpTurn,
+            ip_turn_packet::Protocol::Ipv4.into(),
+            &ipv4,
+        ));
+    }
+    let mut broadcast = vec![0u8; 1 + 8 + 4];
+    let mut packet = BroadcastPacket::unchecked(&mut broadcast[..]);
+    packet.set_address(&[SOURCE, DESTINATION]).unwrap();
+    packet.set_data(&[1, 2, 3, 4]).unwrap();
+    seeds.push(net_packet(
+        Protocol::IpTurn,
+        ip_turn_packet::Protocol::Ipv4Broadcast.into(),
+        &broadcast,
+    ));
+    seeds.push(net_packet(
+        Protocol::Error,
+        error_packet::Protocol::Other(100).into(),
+        b"error message",
+    ));
+    seeds.push(net_packet(
+        Protocol::Error,
+        error_packet::Protocol::TokenError.into(),
+        &[],
+    ));
+    let proto = proto_seeds();
+    for (protocol, index) in [
+        (service_packet::Protocol::RegistrationResponse, 4),
+        (service_packet::Protocol::PushDeviceList, 6),
+        (service_packet::Protocol::HandshakeResponse, 1),
+        (service_packet::Protocol::ServerAction, 14),
+    ] {
+        seeds.push(net_packet(
+            Protocol::Service,
+            protocol.into(),
+            &proto[index][1..],
+        ));
+    }
+    seeds.push(net_packet(
+        Protocol::OtherTurn,
+        other_turn_packet::Protocol::Punch.into(),
+        &proto[8][1..],
+    ));
+    // 紧凑编码的设备列表和打洞信息
+    seeds.push(net_packet(
+        Protocol::Service,
+        service_packet::Protocol::PushDeviceListCompact.into(),
+        &Compact.encode_device_list(&sample_device_list()).unwrap(),
+    ));
+    seeds.push(net_packet(
+        Protocol::OtherTurn,
+        other_turn_packet::Protocol::PunchCompact.into(),
+        &Compact.encode_punch(&sample_punch()).unwrap(),
+    ));
+    // 用固定的密码加密的控制包和ip包
+    for cipher in ciphers() {
+        for i in [0, ipv4_seed] {
+            if let Some(seed) = encrypted(cipher, &seeds[i]) {
+                seeds.push(seed);
+            }
+        }
+    }
+    // 新格式的协议头，带序号，加密在转换格式之前
+    for i in [0, seeds.len() - 1] {
+        let v2 = v2(&seeds[i]);
+        seeds.push(v2);
+    }
+    seeds
+}
+
+fn v2(v1: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; v1.len() + compat::EXT_HEAD_LEN];
+    let len = compat::encode_v2(v1, compat::FLAG_SEQUENCE, &mut buf).unwrap();
+    buf.truncate(len);
+    compat::set_sequence(&mut buf, 7);
+    buf
+}
+
+fn control_seeds() -> Vec<Vec<u8>> {
+    fn seed(protocol: control_packet::Protocol, payload: Vec<u8>) -> Vec<u8> {
+        let mut buf = vec![protocol.into()];
+        buf.extend_from_slice(&payload);
+        buf
+    }
+    let mut ping = vec![0u8; control_packet::PING_WIRE_VERSION_LEN + 4];
+    let mut packet = PingPacket::new(&mut ping[..]).unwrap();
+    packet.set_time(1000);
+    packet.set_epoch(3);
+    packet.set_wire_version(compat::WireVersion::MAX);
+    packet.set_extension(&7u32.to_be_bytes()).unwrap();
+    let mut addr = vec![0u8; 6];
+    let mut packet = AddrPacket::new(&mut addr[..]).unwrap();
+    packet.set_ipv4(Ipv4Addr::new(1, 2, 3, 4));
+    packet.set_port(29872);
+    let mut notice = vec![0u8; control_packet::NOTICE_LEN];
+    let mut packet = NoticePacket::new(&mut notice[..]).unwrap();
+    packet.set_id(9);
+    packet.set_kind(1);
+    let mut feature = vec![0u8; control_packet::FEATURE_LEN];
+    let mut packet = FeaturePacket::new(&mut feature[..]).unwrap();
+    packet.set_seq(5);
+    packet.set_feature(1);
+    packet.set_enabled(true);
+    let mut request = vec![0u8; control_packet::BANDWIDTH_REQUEST_LEN];
+    let mut packet = BandwidthRequestPacket::new(&mut request[..]).unwrap();
+    packet.set_id(1);
+    packet.set_bursts(3);
+    packet.set_burst_len(10);
+    packet.set_size(1200);
+    let mut reply = vec![0u8; BANDWIDTH_REPLY_LEN + 2 * control_packet::BANDWIDTH_BURST_LEN];
+    let mut packet = BandwidthReplyPacket::new(&mut reply[..]).unwrap();
+    packet.set_id(1);
+    packet.set_consent(true);
+    packet.set_count(2);
+    packet.set_burst(1, 10, 2000, 12000);
+    let mut probe = vec![0u8; 64];
+    let mut packet = BandwidthProbePacket::new(&mut probe[..]).unwrap();
+    packet.set_id(1);
+    packet.set_burst(2);
+    packet.set_index(3);
+    let mut cookie = vec![0u8; control_packet::COOKIE_LEN];
+    CookiePacket::new(&mut cookie[..])
+        .unwrap()
+        .set_cookie(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    let mut reverse = vec![0u8; control_packet::REVERSE_LEN];
+    let mut packet = ReversePacket::new(&mut reverse[..]).unwrap();
+    packet.set_nonce(7);
+    packet.set_duration(3600);
+    let mut relay_usage = RelayUsage::new();
+    relay_usage.relay_tx = 1000;
+    relay_usage.server_rtt.push(ServerRtt {
+        server: "1.2.3.4:29872".to_string(),
+        rtt_ms: 12,
+        ..Default::default()
+    });
+    let mut heartbeat = vec![0u8; control_packet::PING_WIRE_VERSION_LEN];
+    heartbeat.extend_from_slice(&relay_usage.write_to_bytes().unwrap());
+    let mut pong = vec![0u8; control_packet::PING_WIRE_VERSION_LEN];
+    pong.extend_from_slice(
+        &ServerClock {
+            server_time_ms: 1_700_000_000_000,
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+    );
+    vec![
+        seed(control_packet::Protocol::Ping, ping.clone()),
+        seed(control_packet::Protocol::Pong, ping),
+        seed(control_packet::Protocol::Ping, heartbeat),
+        seed(control_packet::Protocol::Pong, pong),
+        seed(control_packet::Protocol::AddrRequest, Vec::new()),
+        seed(control_packet::Protocol::AddrResponse, addr),
+        seed(control_packet::Protocol::Notice, notice),
+        seed(control_packet::Protocol::FeatureRequest, feature),
+        seed(control_packet::Protocol::BandwidthRequest, request),
+        seed(control_packet::Protocol::BandwidthReply, reply),
+        seed(control_packet::Protocol::BandwidthProbe, probe),
+        seed(control_packet::Protocol::Cookie, cookie),
+        seed(control_packet::Protocol::ConnectIntent, reverse.clone()),
+        seed(control_packet::Protocol::ConnectApprove, reverse),
+    ]
+}
+
+fn body_seeds() -> Vec<Vec<u8>> {
+    let mut seeds = Vec::new();
+    for len in [4, 16, 20, 32, 48, 64] {
+        for flags in [0u8, 1] {
+            let mut seed = vec![flags];
+            seed.extend((0..len).map(|v| v as u8));
+            seeds.push(seed);
+        }
+    }
+    seeds
+}
+
+fn sample_device() -> DeviceInfo {
+    DeviceInfo {
+        name: "test".to_string(),
+        virtual_ip: u32::from(SOURCE),
+        device_status: 0,
+        client_secret: true,
+        client_secret_hash: vec![1; 16],
+        fingerprint: "fp".to_string(),
+        closest_server: "1.2.3.4:29872".to_string(),
+        ..Default::default()
+    }
+}
+
+fn sample_device_list() -> DeviceList {
+    let mut device_list = DeviceList::new();
+    device_list.epoch = 4;
+    device_list.device_info_list.push(sample_device());
+    device_list
+}
+
+fn sample_punch() -> PunchInfo {
+    let mut punch = PunchInfo::new();
+    punch.public_ip_list.push(0x01020304);
+    punch.public_port = 29872;
+    punch.nat_type = protobuf::EnumOrUnknown::new(PunchNatType::Cone);
+    punch.udp_ports.extend([1, 2, 3]);
+    punch.ipv6 = vec![0; 16];
+    punch.server_rtt = 40;
+    punch.fire_at = 1_700_000_000_000;
+    punch
+}
+
+fn proto_seeds() -> Vec<Vec<u8>> {
+    let notice = ServerNotice {
+        message: "maintenance".to_string(),
+        maintenance_at: 1_700_000_000,
+        ..Default::default()
+    };
+    let device = sample_device();
+    let mut registration = RegistrationResponse::new();
+    registration.virtual_ip = u32::from(DESTINATION);
+    registration.epoch = 3;
+    registration.device_info_list.push(device.clone());
+    registration.public_port = 29872;
+    *registration.notice.mut_or_insert_default() = notice.clone();
+    let device_list = sample_device_list();
+    let punch = sample_punch();
+    let mut status = ClientStatusInfo::new();
+    status.source = u32::from(SOURCE);
+    status.p2p_list.push(RouteItem {
+        next_ip: u32::from(DESTINATION),
+        ..Default::default()
+    });
+    status.up_stream = 100;
+    let mut relay_usage = RelayUsage::new();
+    relay_usage.relay_rx = 100;
+    relay_usage.server_rtt.push(ServerRtt {
+        server: "1.2.3.4:29872".to_string(),
+        rtt_ms: 12,
+        ..Default::default()
+    });
+    let messages: Vec<Vec<u8>> = vec![
+        HandshakeRequest {
+            version: crate::VNT_VERSION.to_string(),
+            secret: true,
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        HandshakeResponse {
+            version: crate::VNT_VERSION.to_string(),
+            public_key: vec![1; 32],
+            relay_passthrough: true,
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        SecretHandshakeRequest {
+            token: "token".to_string(),
+            key: vec![2; 32],
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        RegistrationRequest {
+            token: "token".to_string(),
+            device_id: "id".to_string(),
+            name: "test".to_string(),
+            virtual_ip: u32::from(SOURCE),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        registration.write_to_bytes().unwrap(),
+        device.write_to_bytes().unwrap(),
+        device_list.write_to_bytes().unwrap(),
+        notice.write_to_bytes().unwrap(),
+        punch.write_to_bytes().unwrap(),
+        status.write_to_bytes().unwrap(),
+        RouteItem {
+            next_ip: 1,
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        relay_usage.write_to_bytes().unwrap(),
+        relay_usage.server_rtt[0].write_to_bytes().unwrap(),
+        ServerClock {
+            server_time_ms: 1_700_000_000_000,
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        ServerAction {
+            id: 1,
+            action: "resync".to_string(),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+        ServerActionAck {
+            id: 1,
+            action: "set-log-level".to_string(),
+            status: protobuf::EnumOrUnknown::new(ServerActionStatus::Denied),
+            detail: "not allowed".to_string(),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap(),
+    ];
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let mut seed = vec![index as u8];
+            seed.extend_from_slice(&message);
+            seed
+        })
+        .collect()
+}
+
+fn ip_seeds() -> Vec<Vec<u8>> {
+    let mut seeds = vec![
+        self_probe::icmp_echo(SOURCE, DESTINATION, 1, b"echo").unwrap(),
+        self_probe::udp_packet(SOURCE, DESTINATION, b"udp payload").unwrap(),
+    ];
+    // 带mss选项的syn
+    let mut tcp = vec![0u8; 24];
+    tcp[0..4].copy_from_slice(&[0x1F, 0x90, 0x00, 0x50]);
+    tcp[12] = 6 << 4;
+    tcp[13] = 0x02;
+    tcp[20..24].copy_from_slice(&[2, 4, 0x05, 0xB4]);
+    seeds.push(self_probe::ipv4(SOURCE, DESTINATION, 6, tcp).unwrap());
+    // icmp时间戳请求和差错报文
+    let mut timestamp = vec![13, 0, 0, 0, 0, 1, 0, 1];
+    timestamp.extend_from_slice(&[0; 12]);
+    seeds.push(self_probe::ipv4(SOURCE, DESTINATION, 1, timestamp).unwrap());
+    let mut unreachable = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    unreachable.extend_from_slice(&seeds[1][..28]);
+    seeds.push(self_probe::ipv4(SOURCE, DESTINATION, 1, unreachable).unwrap());
+    // igmp v3 报告，一个组记录带两个源
+    let mut igmp = vec![0x22, 0, 0, 0, 0, 0, 0, 1];
+    igmp.extend_from_slice(&[1, 0, 0, 2, 224, 0, 0, 251, 10, 26, 0, 3, 10, 26, 0, 4]);
+    seeds.push(self_probe::ipv4(SOURCE, DESTINATION, 2, igmp).unwrap());
+    let query = vec![0x11, 100, 0, 0, 224, 0, 0, 251, 2, 125, 0, 1, 10, 26, 0, 3];
+    seeds.push(self_probe::ipv4(SOURCE, DESTINATION, 2, query).unwrap());
+    // 以太网帧中的arp请求
+    let mut frame = vec![0xFF; 6];
+    frame.extend_from_slice(&[2, 0, 0, 0, 0, 1, 0x08, 0x06]);
+    frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1, 2, 0, 0, 0, 0, 1]);
+    frame.extend_from_slice(&SOURCE.octets());
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&DESTINATION.octets());
+    seeds.push(frame[14..].to_vec());
+    seeds.push(frame);
+    seeds
+}
+
+fn state_file_seeds() -> Vec<Vec<u8>> {
+    let block_list = BlockList::new();
+    block_list.block(SOURCE);
+    block_list.block(DESTINATION);
+    vec![
+        state_store::encode(&block_list),
+        // 加入文件头之前的格式
+        b"10.26.0.3\n10.26.0.4\n".to_vec(),
+        state_store::encode(&BlockList::new()),
+        state_store::encode(&PathPreferences(vec![
+            (
+                SOURCE,
+                PeerPreference {
+                    preference: Preference::Relay,
+                    weights: None,
+                },
+            ),
+            (
+                DESTINATION,
+                PeerPreference {
+                    preference: Preference::Auto,
+                    weights: "rtt=1,loss=50".parse().ok(),
+                },
+            ),
+        ])),
+        state_store::encode(&SubsystemOverrides(vec![
+            ("dns".to_string(), false),
+            ("mdns".to_string(), true),
+        ])),
+    ]
+}
+
+/// 确定性的变异，没有安装cargo-fuzz时使用
+pub struct Mutator {
+    state: u64,
+}
+
+const INTERESTING: [u8; 10] = [0, 1, 2, 4, 0x0F, 0x10, 0x40, 0x7F, 0x80, 0xFF];
+const INTERESTING_U16: [u16; 8] = [0, 1, 0x7F, 0x80, 0xFF, 0x100, 0x7FFF, 0xFFFF];
+
+impl Mutator {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed | 1 }
+    }
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+    pub fn mutate(&mut self, data: &mut Vec<u8>, corpus: &[Vec<u8>]) {
+        for _ in 0..1 + self.below(4) {
+            let len = data.len();
+            match self.below(8) {
+                0 if len > 0 => {
+                    let i = self.below(len);
+                    data[i] ^= 1 << self.below(8);
+                }
+                1 if len > 0 => {
+                    let i = self.below(len);
+                    data[i] = INTERESTING[self.below(INTERESTING.len())];
+                }
+                2 if len > 1 => {
+                    // 长度和计数字段通常是大端的u16
+                    let i = self.below(len - 1);
+                    let v = INTERESTING_U16[self.below(INTERESTING_U16.len())];
+                    data[i..i + 2].copy_from_slice(&v.to_be_bytes());
+                }
+                3 if len > 0 => {
+                    let end = self.below(len);
+                    data.truncate(end);
+                }
+                4 if len > 0 => {
+                    let start = self.below(len);
+                    let end = start + 1 + self.below((len - start).min(16));
+                    data.drain(start..end);
+                }
+                5 if !corpus.is_empty() => {
+                    let other = &corpus[self.below(corpus.len())];
+                    let start = self.below(len + 1);
+                    let from = self.below(other.len() + 1);
+                    data.truncate(start);
+                    data.extend_from_slice(&other[from..]);
+                }
+                _ => {
+                    let i = self.below(len + 1);
+                    let n = 1 + self.below(16);
+                    let bytes: Vec<u8> = (0..n).map(|_| self.next() as u8).collect();
+                    data.splice(i..i, bytes);
+                }
+            }
+        }
+        data.truncate(MAX_INPUT);
+    }
+}
+
+/// 用种子和变异后的输入执行iterations次，panic时返回触发的输入
+pub fn smoke(target: &Target, iterations: usize, seed: u64) -> Result<(), Vec<u8>> {
+    let run = target.run;
+    let corpus = (target.seeds)();
+    let mut mutator = Mutator::new(seed);
+    for i in 0..corpus.len() + iterations {
+        let mut data = corpus
+            .get(i % corpus.len().max(1))
+            .cloned()
+            .unwrap_or_default();
+        if i >= corpus.len() {
+            mutator.mutate(&mut data, &corpus);
+        }
+        if catch_unwind(|| run(&data)).is_err() {
+            return Err(data);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use super::{smoke, target, TARGETS};
+
+    /// 对应`vnt/fuzz`中的`fuzz-smoke`，每个目标执行固定次数
+    #[test]
+    fn test_fuzz_smoke() {
+        for target in TARGETS {
+            for seed in (target.seeds)() {
+                (target.run)(&seed);
+            }
+            if let Err(data) = smoke(target, 3000, 0x5EED) {
+                panic!("fuzz target {} panicked on {:02x?}", target.name, data);
+            }
+        }
+        assert!(target("dispatch").is_some());
+    }
+
+    fn decoders(dir: &Path, list: &mut Vec<String>) {
+        for entry in std::fs::read_dir(dir).unwrap().flatten() {
+            let path = entry.path();
+            if path.ends_with("util/fuzz.rs") {
+                continue;
+            }
+            if path.is_dir() {
+                decoders(&path, list);
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            for line in text.lines() {
+                let name = line
+                    .strip_prefix("pub struct ")
+                    .or_else(|| line.strip_prefix("pub enum "))
+                    .and_then(|v| v.split_once("<B>"))
+                    .map(|(name, _)| name);
+                if let Some(name) = name {
+                    list.push(name.to_string());
+                }
+                if let Some(name) = line.strip_prefix("impl StateFile for ") {
+                    list.push(name.trim_end_matches(" {").to_string());
+                }
+            }
+        }
+    }
+
+    /// 带缓冲区参数的包结构、proto中的消息和状态文件都要有模糊测试目标
+    #[test]
+    fn test_decoders_registered() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut list = Vec::new();
+        decoders(&root.join("src"), &mut list);
+        decoders(&root.join("packet/src"), &mut list);
+        let proto = std::fs::read_to_string(root.join("proto/message.proto")).unwrap();
+        for line in proto.lines() {
+            if let Some(name) = line.strip_prefix("message ") {
+                list.push(name.trim_end_matches(" {").to_string());
+            }
+        }
+        assert!(list.contains(&"NetPacket".to_string()));
+        assert!(list.contains(&"PunchInfo".to_string()));
+        assert!(list.contains(&"BlockList".to_string()));
+        let covered: HashSet<&str> = TARGETS.iter().flat_map(|v| (v.covers)()).collect();
+        let missing: Vec<&String> = list
+            .iter()
+            .filter(|v| !covered.contains(v.as_str()))
+            .collect();
+        assert!(missing.is_empty(), "no fuzz target covers {:?}", missing);
+        let stale: Vec<&&str> = covered
+            .iter()
+            .filter(|v| !list.iter().any(|n| n == **v))
+            .collect();
+        assert!(stale.is_empty(), "unknown types in covers {:?}", stale);
+    }
+}
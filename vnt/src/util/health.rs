@@ -0,0 +1,313 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 处理线程超过这个时间没有完成一轮循环就认为卡住了
+pub const WATCHDOG_STALE: Duration = Duration::from_secs(10);
+/// 没有数据时处理线程也至少每隔这么久醒来一次
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// 超过这个时间没有收到服务器的心跳回应就认为数据通道不通
+pub const DATA_PATH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// 就绪检查项
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReadyCheck {
+    /// 已在服务器注册
+    Registered,
+    /// 网卡已启动
+    Tun,
+    /// 最近收到过服务器的心跳回应
+    DataPath,
+}
+
+impl ReadyCheck {
+    pub const ALL: [ReadyCheck; 3] = [
+        ReadyCheck::Registered,
+        ReadyCheck::Tun,
+        ReadyCheck::DataPath,
+    ];
+    fn index(&self) -> usize {
+        match self {
+            ReadyCheck::Registered => 0,
+            ReadyCheck::Tun => 1,
+            ReadyCheck::DataPath => 2,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadyCheck::Registered => "registered",
+            ReadyCheck::Tun => "tun",
+            ReadyCheck::DataPath => "data_path",
+        }
+    }
+    fn pending(&self) -> &'static str {
+        match self {
+            ReadyCheck::Registered => "not registered yet",
+            ReadyCheck::Tun => "tun not started",
+            ReadyCheck::DataPath => "no heartbeat reply yet",
+        }
+    }
+}
+
+/// 检查结果，failed为空表示正常
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HealthReport {
+    /// (检查项, 原因)
+    pub failed: Vec<(String, String)>,
+}
+
+impl HealthReport {
+    pub fn ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+    /// {"status":"ok","failed":[{"check":"tun","reason":"..."}]}
+    pub fn to_json(&self) -> String {
+        let mut out = String::with_capacity(64);
+        out.push_str(if self.ok() {
+            "{\"status\":\"ok\",\"failed\":["
+        } else {
+            "{\"status\":\"fail\",\"failed\":["
+        });
+        for (index, (check, reason)) in self.failed.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"check\":");
+            json_str(&mut out, check);
+            out.push_str(",\"reason\":");
+            json_str(&mut out, reason);
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ok() {
+            return f.write_str("ok");
+        }
+        for (index, (check, reason)) in self.failed.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}", check, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// 处理线程每轮循环调用一次beat，超过stale没有调用则存活检查失败
+#[derive(Clone)]
+pub struct Watchdog {
+    start: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    pub fn beat(&self) {
+        self.beat_at(Instant::now())
+    }
+    pub fn beat_at(&self, now: Instant) {
+        self.last.store(millis(self.start, now), Ordering::Relaxed);
+    }
+}
+
+struct Dog {
+    name: String,
+    last: Arc<AtomicU64>,
+    stale: Duration,
+}
+
+#[derive(Clone)]
+enum CheckState {
+    Pending,
+    // 成功的时间
+    Ok(u64),
+    Failed(String),
+}
+
+fn millis(start: Instant, now: Instant) -> u64 {
+    now.saturating_duration_since(start).as_millis() as u64
+}
+
+/// 存活和就绪状态
+///
+/// 存活只看各处理线程是否还在循环，就绪看注册、网卡和数据通道，
+/// 前者失败应该重启进程，后者失败只是暂时不能承载流量
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<HealthInner>,
+}
+
+struct HealthInner {
+    start: Instant,
+    dogs: Mutex<Vec<Dog>>,
+    checks: Mutex<[CheckState; 3]>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(HealthInner {
+                start: Instant::now(),
+                dogs: Mutex::new(Vec::new()),
+                checks: Mutex::new([
+                    CheckState::Pending,
+                    CheckState::Pending,
+                    CheckState::Pending,
+                ]),
+            }),
+        }
+    }
+    /// 注册一个需要监视的处理线程
+    pub fn watchdog(&self, name: &str, stale: Duration) -> Watchdog {
+        let watchdog = Watchdog {
+            start: self.inner.start,
+            last: Arc::new(AtomicU64::new(0)),
+        };
+        watchdog.beat();
+        self.inner.dogs.lock().push(Dog {
+            name: name.to_string(),
+            last: watchdog.last.clone(),
+            stale,
+        });
+        watchdog
+    }
+    pub fn ready(&self, check: ReadyCheck) {
+        self.ready_at(check, Instant::now())
+    }
+    pub fn ready_at(&self, check: ReadyCheck, now: Instant) {
+        self.inner.checks.lock()[check.index()] = CheckState::Ok(millis(self.inner.start, now));
+    }
+    pub fn not_ready(&self, check: ReadyCheck, reason: impl Into<String>) {
+        self.inner.checks.lock()[check.index()] = CheckState::Failed(reason.into());
+    }
+    pub fn livez(&self) -> HealthReport {
+        self.livez_at(Instant::now())
+    }
+    pub fn livez_at(&self, now: Instant) -> HealthReport {
+        let now = millis(self.inner.start, now);
+        let mut report = HealthReport::default();
+        for dog in self.inner.dogs.lock().iter() {
+            let idle = now.saturating_sub(dog.last.load(Ordering::Relaxed));
+            if idle > dog.stale.as_millis() as u64 {
+                report.failed.push((
+                    dog.name.clone(),
+                    format!("no progress for {}s", idle / 1000),
+                ));
+            }
+        }
+        report
+    }
+    pub fn readyz(&self) -> HealthReport {
+        self.readyz_at(Instant::now())
+    }
+    pub fn readyz_at(&self, now: Instant) -> HealthReport {
+        let now = millis(self.inner.start, now);
+        let checks = self.inner.checks.lock().clone();
+        let mut report = HealthReport::default();
+        for check in ReadyCheck::ALL {
+            let reason = match &checks[check.index()] {
+                CheckState::Pending => check.pending().to_string(),
+                CheckState::Ok(time) => {
+                    let elapsed = now.saturating_sub(*time);
+                    if check == ReadyCheck::DataPath
+                        && elapsed > DATA_PATH_TIMEOUT.as_millis() as u64
+                    {
+                        format!("no heartbeat reply for {}s", elapsed / 1000)
+                    } else {
+                        continue;
+                    }
+                }
+                CheckState::Failed(reason) => reason.clone(),
+            };
+            report.failed.push((check.name().to_string(), reason));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Health, ReadyCheck, WATCHDOG_STALE};
+
+    #[test]
+    fn test_livez_wedged() {
+        let health = Health::new();
+        let udp = health.watchdog("main_udp", WATCHDOG_STALE);
+        let tun = health.watchdog("tun", WATCHDOG_STALE);
+        let start = Instant::now();
+        assert!(health.livez_at(start).ok());
+        // tun线程卡住不再循环，udp线程正常
+        for i in 1..=15 {
+            let now = start + Duration::from_secs(i);
+            udp.beat_at(now);
+            if i < 3 {
+                tun.beat_at(now);
+            }
+        }
+        let report = health.livez_at(start + Duration::from_secs(15));
+        assert!(!report.ok());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "tun");
+        assert!(report.to_json().starts_with("{\"status\":\"fail\""));
+        // 恢复后存活检查通过
+        tun.beat_at(start + Duration::from_secs(16));
+        assert!(health.livez_at(start + Duration::from_secs(16)).ok());
+    }
+
+    #[test]
+    fn test_readyz() {
+        let health = Health::new();
+        let start = Instant::now();
+        assert_eq!(health.readyz_at(start).failed.len(), 3);
+        health.ready_at(ReadyCheck::Tun, start);
+        health.ready_at(ReadyCheck::DataPath, start);
+        health.not_ready(ReadyCheck::Registered, "token error");
+        let report = health.readyz_at(start + Duration::from_secs(1));
+        assert_eq!(
+            report.failed,
+            vec![("registered".to_string(), "token error".to_string())]
+        );
+        assert_eq!(
+            report.to_json(),
+            "{\"status\":\"fail\",\"failed\":[{\"check\":\"registered\",\"reason\":\"token error\"}]}"
+        );
+        // 注册失败不影响存活
+        assert!(health.livez_at(start + Duration::from_secs(1)).ok());
+        health.ready_at(ReadyCheck::Registered, start + Duration::from_secs(2));
+        assert_eq!(
+            health.readyz_at(start + Duration::from_secs(3)).to_json(),
+            "{\"status\":\"ok\",\"failed\":[]}"
+        );
+        // 心跳回应超时
+        let report = health.readyz_at(start + Duration::from_secs(30));
+        assert_eq!(report.failed[0].0, "data_path");
+    }
+}
@@ -0,0 +1,339 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crossbeam_utils::CachePadded;
+use parking_lot::RwLock;
+
+/// 计数器的条带数，热点路径上不同线程落到不同的缓存行，减少竞争
+const STRIPES: usize = 8;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: Cell<usize> = Cell::new(NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES);
+}
+
+/// 只增不减的计数器，clone后共享同一份数据
+#[derive(Clone)]
+pub struct Counter {
+    stripes: Arc<[CachePadded<AtomicU64>; STRIPES]>,
+}
+
+impl Counter {
+    pub(crate) fn new() -> Self {
+        Self {
+            stripes: Arc::new(Default::default()),
+        }
+    }
+    #[inline]
+    pub fn add(&self, num: u64) {
+        let index = STRIPE.with(|v| v.get());
+        self.stripes[index].fetch_add(num, Ordering::Relaxed);
+    }
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1)
+    }
+    /// 各条带的和，并发写入时可能不包含最近的几次写入，但不会丢失
+    pub fn get(&self) -> u64 {
+        self.stripes
+            .iter()
+            .fold(0u64, |sum, v| sum.wrapping_add(v.load(Ordering::Relaxed)))
+    }
+}
+
+/// 可增可减的瞬时值
+#[derive(Clone)]
+pub struct Gauge {
+    value: Arc<AtomicI64>,
+}
+
+impl Gauge {
+    fn new() -> Self {
+        Self {
+            value: Arc::new(AtomicI64::new(0)),
+        }
+    }
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed)
+    }
+    pub fn add(&self, value: i64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+struct HistogramInner {
+    // 各桶的上界(包含)，最后一个桶记录超过所有上界的值
+    bounds: &'static [u64],
+    buckets: Box<[AtomicU64]>,
+    sum: AtomicU64,
+}
+
+/// 固定分桶的直方图，分桶在注册时确定，记录时不分配内存
+#[derive(Clone)]
+pub struct Histogram {
+    inner: Arc<HistogramInner>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [u64],
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        debug_assert!(bounds.windows(2).all(|v| v[0] < v[1]));
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            inner: Arc::new(HistogramInner {
+                bounds,
+                buckets,
+                sum: AtomicU64::new(0),
+            }),
+        }
+    }
+    pub fn observe(&self, value: u64) {
+        let index = self.inner.bounds.partition_point(|bound| *bound < value);
+        self.inner.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.inner.sum.fetch_add(value, Ordering::Relaxed);
+    }
+    /// 总数由各桶相加得到，和桶一定是一致的，sum可能略微落后
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self
+            .inner
+            .buckets
+            .iter()
+            .map(|v| v.load(Ordering::Relaxed))
+            .collect();
+        HistogramSnapshot {
+            bounds: self.inner.bounds,
+            count: buckets.iter().sum(),
+            buckets,
+            sum: self.inner.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(HistogramSnapshot),
+}
+
+/// 快照中的一项
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: MetricValue,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// 统计指标注册表
+///
+/// 指标用名称和标签注册一次，之后通过返回的句柄更新，热点路径上不需要查表和加锁。
+/// 相同名称和标签重复注册时返回同一个指标，类型不同时panic。
+/// 所有展示统计信息的地方都通过snapshot读取
+#[derive(Clone, Default)]
+pub struct Registry {
+    metrics: Arc<RwLock<Vec<(&'static str, Labels, Metric)>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn register(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &str)],
+        create: impl FnOnce() -> Metric,
+    ) -> Metric {
+        let labels: Labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let mut guard = self.metrics.write();
+        if let Some((_, _, metric)) = guard.iter().find(|(n, l, _)| *n == name && *l == labels) {
+            return metric.clone();
+        }
+        let metric = create();
+        guard.push((name, labels, metric.clone()));
+        metric
+    }
+    pub fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Counter {
+        match self.register(name, labels, || Metric::Counter(Counter::new())) {
+            Metric::Counter(v) => v,
+            _ => panic!("metric {} is not a counter", name),
+        }
+    }
+    pub fn gauge(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Gauge {
+        match self.register(name, labels, || Metric::Gauge(Gauge::new())) {
+            Metric::Gauge(v) => v,
+            _ => panic!("metric {} is not a gauge", name),
+        }
+    }
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &str)],
+        bounds: &'static [u64],
+    ) -> Histogram {
+        match self.register(name, labels, || Metric::Histogram(Histogram::new(bounds))) {
+            Metric::Histogram(v) => v,
+            _ => panic!("metric {} is not a histogram", name),
+        }
+    }
+    /// 所有指标的快照，按名称和标签排序
+    ///
+    /// 各指标分别用Relaxed读取，同一时刻的并发写入可能只有一部分被包含，
+    /// 但计数器在前后两次快照之间不会变小
+    pub fn snapshot(&self) -> Vec<Sample> {
+        let mut list: Vec<Sample> = self
+            .metrics
+            .read()
+            .iter()
+            .map(|(name, labels, metric)| Sample {
+                name,
+                labels: labels.clone(),
+                value: match metric {
+                    Metric::Counter(v) => MetricValue::Counter(v.get()),
+                    Metric::Gauge(v) => MetricValue::Gauge(v.get()),
+                    Metric::Histogram(v) => MetricValue::Histogram(v.snapshot()),
+                },
+            })
+            .collect();
+        list.sort_by(|a, b| (a.name, &a.labels).cmp(&(b.name, &b.labels)));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::util::metrics::{MetricValue, Registry};
+
+    const THREADS: u64 = 16;
+    const ROUNDS: u64 = 100_000;
+
+    #[test]
+    fn test_register() {
+        let registry = Registry::new();
+        let a = registry.counter("bytes", &[("direction", "up")]);
+        let b = registry.counter("bytes", &[("direction", "up")]);
+        let c = registry.counter("bytes", &[("direction", "down")]);
+        a.add(2);
+        b.add(3);
+        c.inc();
+        assert_eq!(a.get(), 5);
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].labels, vec![("direction", "down".to_string())]);
+        assert_eq!(snapshot[1].value, MetricValue::Counter(5));
+        let gauge = registry.gauge("rt", &[]);
+        gauge.set(10);
+        gauge.add(-3);
+        assert_eq!(gauge.get(), 7);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let registry = Registry::new();
+        let histogram = registry.histogram("latency", &[], &[10, 100]);
+        for v in [0, 10, 11, 100, 101, 1000] {
+            histogram.observe(v);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.buckets, vec![2, 2, 2]);
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.sum, 1222);
+    }
+
+    /// 多线程并发更新，结束后不能丢失任何一次更新
+    #[test]
+    fn test_concurrent_no_lost_updates() {
+        let registry = Registry::new();
+        let histogram_bounds: &'static [u64] = &[0, 1, 2];
+        let mut handles = Vec::new();
+        for i in 0..THREADS {
+            let registry = registry.clone();
+            handles.push(thread::spawn(move || {
+                // 每个线程各自注册，拿到的是同一个指标
+                let counter = registry.counter("packets", &[]);
+                let gauge = registry.gauge("inflight", &[]);
+                let histogram = registry.histogram("size", &[], histogram_bounds);
+                for n in 0..ROUNDS {
+                    counter.inc();
+                    gauge.add(1);
+                    gauge.add(-1);
+                    histogram.observe((n + i) % 4);
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let total = THREADS * ROUNDS;
+        assert_eq!(registry.counter("packets", &[]).get(), total);
+        assert_eq!(registry.gauge("inflight", &[]).get(), 0);
+        let snapshot = registry.histogram("size", &[], histogram_bounds).snapshot();
+        assert_eq!(snapshot.count, total);
+        assert_eq!(snapshot.buckets, vec![total / 4; 4]);
+    }
+
+    /// 更新的同时不断读取快照，计数器不能变小，也不能超过实际写入的次数
+    #[test]
+    fn test_concurrent_snapshot() {
+        let registry = Registry::new();
+        let counter = registry.counter("packets", &[]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let registry = registry.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut last = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    for sample in registry.snapshot() {
+                        if let MetricValue::Counter(v) = sample.value {
+                            assert!(v >= last);
+                            assert!(v <= THREADS * ROUNDS);
+                            last = v;
+                        }
+                    }
+                }
+            })
+        };
+        let writers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        counter.inc();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+        assert_eq!(counter.get(), THREADS * ROUNDS);
+    }
+}
@@ -4,7 +4,24 @@ pub use notify::StopManager;
 pub use scheduler::Scheduler;
 
 mod counter;
+#[allow(deprecated)]
 pub use counter::*;
 
+pub mod buffer_pool;
+pub mod fingerprint;
+pub mod health;
+pub mod metrics;
+pub mod sanitize;
+pub mod serial;
+pub mod state_store;
+pub mod subnet;
+pub mod subsystem;
+pub mod workers;
+
+#[cfg(feature = "replay")]
+pub mod capture;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+
 mod dns_query;
 pub use dns_query::*;
@@ -26,6 +26,10 @@ impl StopManager {
     {
         self.inner.add_listener(name, f)
     }
+    /// 只计数不注册停止回调，用于自己检查停止状态、可以单独启停的线程
+    pub fn add_worker(&self, name: String) -> io::Result<Worker> {
+        self.inner.add_worker(name)
+    }
     pub fn stop(&self) {
         self.inner.stop("");
     }
@@ -83,6 +87,12 @@ impl StopManagerInner {
         guard.1.push((name.clone(), Box::new(f)));
         Ok(Worker::new(name, self.clone()))
     }
+    fn add_worker(self: &Arc<Self>, name: String) -> io::Result<Worker> {
+        if self.listeners.lock().0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "stopped"));
+        }
+        Ok(Worker::new(name, self.clone()))
+    }
     fn stop(&self, skip_name: &str) {
         self.state.store(true, Ordering::Release);
         let mut guard = self.listeners.lock();
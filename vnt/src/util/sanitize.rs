@@ -0,0 +1,212 @@
+use std::net::Ipv4Addr;
+
+/// 设备名称最大长度(字符数)
+pub const NAME_MAX_LEN: usize = 64;
+/// 设备指纹最大长度(字符数)
+pub const FINGERPRINT_MAX_LEN: usize = 128;
+/// 公告最大长度(字符数)
+pub const NOTICE_MAX_LEN: usize = 512;
+/// dns标签最大长度(字节)
+const DNS_LABEL_MAX_LEN: usize = 63;
+
+/// 清洗来自服务端或其他设备的文本
+///
+/// 去掉完整的ANSI转义序列(CSI、OSC及单字符转义)、其他控制字符和双向文本控制字符，
+/// 制表符换成空格，multiline为true时保留换行，最后截断到max_len个字符并去掉首尾空白。
+/// 名称、公告等在解析时就经过这里，后续输出到终端、日志、json都不会被注入
+pub fn text(input: &str, max_len: usize, multiline: bool) -> String {
+    let mut out = String::with_capacity(input.len().min(max_len * 4));
+    let mut chars = input.chars().peekable();
+    let mut count = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: ESC [ 参数 结束符
+                Some('[') => skip_csi(&mut chars),
+                // OSC/DCS等字符串: 以BEL或ESC \结束
+                Some(']') | Some('P') | Some('X') | Some('^') | Some('_') => {
+                    skip_string(&mut chars)
+                }
+                _ => {}
+            },
+            '\u{9b}' => skip_csi(&mut chars),
+            '\u{9d}' | '\u{90}' | '\u{98}' | '\u{9e}' | '\u{9f}' => skip_string(&mut chars),
+            '\n' if multiline => {
+                out.push('\n');
+                count += 1;
+            }
+            '\t' => {
+                out.push(' ');
+                count += 1;
+            }
+            c if c.is_control() || is_bidi_control(c) => {}
+            c => {
+                out.push(c);
+                count += 1;
+            }
+        }
+        if count >= max_len {
+            break;
+        }
+    }
+    out.trim().to_string()
+}
+
+fn skip_csi<I: Iterator<Item = char>>(chars: &mut I) {
+    for c in chars {
+        if ('\u{40}'..='\u{7e}').contains(&c) {
+            return;
+        }
+    }
+}
+
+fn skip_string<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{7}' | '\u{9c}' => return,
+            '\x1b' => {
+                if chars.peek() == Some(&'\\') {
+                    chars.next();
+                }
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 可以让终端里的文本反向显示，伪造其他内容
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// 设备名称，单行
+pub fn name(input: &str) -> String {
+    text(input, NAME_MAX_LEN, false)
+}
+
+/// 转换成dns标签，只保留字母、数字和连字符，无法转换时使用由虚拟ip生成的名称
+pub fn dns_label(name: &str, virtual_ip: Ipv4Addr) -> String {
+    let mut label = String::with_capacity(name.len().min(DNS_LABEL_MAX_LEN));
+    for c in name.chars() {
+        let c = match c {
+            'a'..='z' | '0'..='9' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            ' ' | '_' | '.' => '-',
+            _ => continue,
+        };
+        if c == '-' && (label.is_empty() || label.ends_with('-')) {
+            continue;
+        }
+        label.push(c);
+        if label.len() >= DNS_LABEL_MAX_LEN {
+            break;
+        }
+    }
+    while label.ends_with('-') {
+        label.pop();
+    }
+    if label.is_empty() {
+        let [a, b, c, d] = virtual_ip.octets();
+        return format!("vnt-{}-{}-{}-{}", a, b, c, d);
+    }
+    label
+}
+
+/// 统计指标的标签值，转义分隔符
+pub fn metric_label(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in text(input, NAME_MAX_LEN, false).chars() {
+        match c {
+            '\\' | ',' | '=' | '"' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{dns_label, metric_label, name, text, NAME_MAX_LEN, NOTICE_MAX_LEN};
+
+    /// 生成任意字节串，偏向控制字符和转义序列
+    fn arbitrary(seed: &mut u64) -> String {
+        let mut next = || {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *seed
+        };
+        let len = (next() % 200) as usize;
+        let mut bytes = Vec::with_capacity(len);
+        for _ in 0..len {
+            let v = next();
+            match v % 8 {
+                0 => bytes.push(0x1b),
+                1 => bytes.extend_from_slice(&[0x1b, b'[']),
+                2 => bytes.extend_from_slice(&[0x1b, b']']),
+                3 => bytes.extend_from_slice("\u{202e}\u{9b}".as_bytes()),
+                4 => bytes.push((v >> 8) as u8 % 0x20),
+                _ => bytes.push((v >> 8) as u8),
+            }
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    #[test]
+    fn test_arbitrary_input() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..10000 {
+            let input = arbitrary(&mut seed);
+            for multiline in [false, true] {
+                let out = text(&input, NOTICE_MAX_LEN, multiline);
+                assert!(out.chars().count() <= NOTICE_MAX_LEN);
+                assert!(out
+                    .chars()
+                    .all(|c| (!c.is_control() || (multiline && c == '\n'))
+                        && !super::is_bidi_control(c)));
+                // 清洗结果再清洗不变
+                assert_eq!(text(&out, NOTICE_MAX_LEN, multiline), out);
+            }
+            let out = name(&input);
+            assert!(out.chars().count() <= NAME_MAX_LEN && !out.contains('\n'));
+            let label = dns_label(&input, Ipv4Addr::new(10, 26, 0, 3));
+            assert!(!label.is_empty() && label.len() <= 63, "{:?}", label);
+            assert!(label
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-'));
+            assert!(!label.starts_with('-') && !label.ends_with('-'));
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(text("\x1b[31mhello\x1b[0m", 64, true), "hello");
+        assert_eq!(text("a\rb\u{7}c\nd", 64, true), "abc\nd");
+        assert_eq!(text("a\nb", 64, false), "ab");
+        assert_eq!(text("维护通知:02:00 UTC", 4, true), "维护通知");
+        // 设置终端标题
+        assert_eq!(name("\x1b]0;pwned\x07pc"), "pc");
+        assert_eq!(name("\x1b]8;;http://x\x1b\\link"), "link");
+        assert_eq!(name("\u{202e}cod.exe"), "cod.exe");
+    }
+
+    #[test]
+    fn test_dns_label() {
+        let ip = Ipv4Addr::new(10, 26, 0, 3);
+        assert_eq!(dns_label("My PC_1", ip), "my-pc-1");
+        assert_eq!(dns_label("--a..b--", ip), "a-b");
+        assert_eq!(dns_label("办公室", ip), "vnt-10-26-0-3");
+        assert_eq!(dns_label(&"a".repeat(100), ip).len(), 63);
+    }
+
+    #[test]
+    fn test_metric_label() {
+        assert_eq!(metric_label("a,b=c\"\\\n"), "a\\,b\\=c\\\"\\\\");
+    }
+}
@@ -0,0 +1,186 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use crate::util::metrics::Counter;
+
+/// 回绕的序号，按RFC 1982比较先后
+///
+/// 两个序号相差不到一半时，数值小的在前，跨过最大值回绕后仍然正确；
+/// 相差正好一半时无法判断先后
+pub trait Serial: Copy + Eq {
+    /// self相对other的有符号距离，正数表示self在后，相差正好一半时返回None
+    fn serial_diff(self, other: Self) -> Option<i64>;
+}
+
+impl Serial for u16 {
+    fn serial_diff(self, other: Self) -> Option<i64> {
+        let diff = self.wrapping_sub(other);
+        if diff == 1 << 15 {
+            None
+        } else {
+            Some(diff as i16 as i64)
+        }
+    }
+}
+
+impl Serial for u32 {
+    fn serial_diff(self, other: Self) -> Option<i64> {
+        let diff = self.wrapping_sub(other);
+        if diff == 1 << 31 {
+            None
+        } else {
+            Some(diff as i32 as i64)
+        }
+    }
+}
+
+/// 无法判断先后时返回None
+pub fn serial_cmp<T: Serial>(a: T, b: T) -> Option<Ordering> {
+    a.serial_diff(b).map(|diff| diff.cmp(&0))
+}
+
+/// a在b之前
+pub fn serial_lt<T: Serial>(a: T, b: T) -> bool {
+    serial_cmp(a, b) == Some(Ordering::Less)
+}
+
+/// 超过这个时间的pong当作过期的，ping里的毫秒时间戳只有16位，65.536秒回绕一次，
+/// 回绕整圈以后的旧包无法识别
+pub const MAX_RTT_MS: u16 = 30_000;
+
+/// ping里的16位毫秒时间戳到现在的时间，回绕时也正确。
+/// 时间戳在现在之后或者过期时返回None，不能用来计算延迟
+pub fn rtt_ms(now: u16, sent: u16) -> Option<u16> {
+    let rtt = now.wrapping_sub(sent);
+    if rtt > MAX_RTT_MS {
+        None
+    } else {
+        Some(rtt)
+    }
+}
+
+/// 不应该出现的情况，第一次出现时告警，之后只计数
+pub struct Anomaly {
+    what: &'static str,
+    counter: Counter,
+    logged: AtomicBool,
+}
+
+impl Anomaly {
+    pub fn new(what: &'static str, counter: Counter) -> Self {
+        Self {
+            what,
+            counter,
+            logged: AtomicBool::new(false),
+        }
+    }
+    pub fn record(&self, detail: impl Display) {
+        self.counter.inc();
+        if !self.logged.swap(true, AtomicOrdering::Relaxed) {
+            log::warn!("{} {},之后只计数", self.what, detail);
+        } else {
+            log::debug!("{} {}", self.what, detail);
+        }
+    }
+    pub fn count(&self) -> u64 {
+        self.counter.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use proptest::prelude::*;
+
+    use crate::util::metrics::Registry;
+    use crate::util::serial::{rtt_ms, serial_cmp, serial_lt, Anomaly, Serial, MAX_RTT_MS};
+
+    #[test]
+    fn test_serial() {
+        assert!(serial_lt(1u16, 2));
+        assert!(serial_lt(u16::MAX, 0));
+        assert!(serial_lt(65_000u16, 100));
+        assert!(!serial_lt(100u16, 65_000));
+        assert_eq!(serial_cmp(7u16, 7), Some(Ordering::Equal));
+        assert_eq!(serial_cmp(0u16, 1 << 15), None);
+        assert_eq!(0u16.serial_diff(u16::MAX), Some(1));
+        assert_eq!(u16::MAX.serial_diff(0), Some(-1));
+        assert!(serial_lt(u32::MAX - 5, 5));
+        assert_eq!(serial_cmp(1u32, 1 + (1 << 31)), None);
+    }
+
+    /// 旧版本用current_time - pong.time()计算延迟，时间戳比现在大时是负数，回绕时的pong也被丢弃
+    #[test]
+    fn test_stale_timestamp() {
+        // 回复的时间戳在现在之后，不是本次运行发出的ping
+        assert_eq!(rtt_ms(1000, 1500), None);
+        assert_eq!(rtt_ms(1000, 1001), None);
+        // 发出后时间戳回绕
+        assert_eq!(rtt_ms(200, 65_000), Some(736));
+        assert_eq!(rtt_ms(5, 5), Some(0));
+        assert_eq!(rtt_ms(MAX_RTT_MS, 0), Some(MAX_RTT_MS));
+        assert_eq!(rtt_ms(MAX_RTT_MS + 1, 0), None);
+    }
+
+    #[test]
+    fn test_anomaly() {
+        let registry = Registry::new();
+        let anomaly = Anomaly::new("test", registry.counter("anomaly", &[]));
+        anomaly.record(1);
+        anomaly.record(2);
+        assert_eq!(anomaly.count(), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_antisymmetric(a: u16, b: u16) {
+            prop_assert_eq!(serial_cmp(a, b), serial_cmp(b, a).map(Ordering::reverse));
+        }
+
+        #[test]
+        fn prop_antisymmetric_u32(a: u32, b: u32) {
+            prop_assert_eq!(serial_cmp(a, b), serial_cmp(b, a).map(Ordering::reverse));
+        }
+
+        /// 往后不到一半的序号都在后面，包括跨过回绕点的
+        #[test]
+        fn prop_successor(a: u16, step in 1u16..(1 << 15)) {
+            let b = a.wrapping_add(step);
+            prop_assert!(serial_lt(a, b));
+            prop_assert!(!serial_lt(b, a));
+            prop_assert_eq!(b.serial_diff(a), Some(step as i64));
+        }
+
+        #[test]
+        fn prop_successor_u32(a: u32, step in 1u32..(1 << 31)) {
+            let b = a.wrapping_add(step);
+            prop_assert!(serial_lt(a, b));
+            prop_assert_eq!(b.serial_diff(a), Some(step as i64));
+        }
+
+        /// 回绕点附近一段连续的序号，打乱后按距离排序能还原顺序
+        #[test]
+        fn prop_random_order(
+            offset in 0u16..64,
+            order in Just((0u16..64).collect::<Vec<_>>()).prop_shuffle(),
+        ) {
+            let start = u16::MAX - offset;
+            let mut seqs: Vec<u16> = order.iter().map(|v| start.wrapping_add(*v)).collect();
+            seqs.sort_by(|a, b| serial_cmp(*a, *b).unwrap());
+            let expect: Vec<u16> = (0u16..64).map(|v| start.wrapping_add(v)).collect();
+            prop_assert_eq!(seqs, expect);
+        }
+
+        #[test]
+        fn prop_rtt(sent: u16, rtt in 0u16..=MAX_RTT_MS) {
+            prop_assert_eq!(rtt_ms(sent.wrapping_add(rtt), sent), Some(rtt));
+        }
+
+        #[test]
+        fn prop_stale_rtt(sent: u16, ahead in 1u16..=(u16::MAX - MAX_RTT_MS)) {
+            prop_assert_eq!(rtt_ms(sent.wrapping_sub(ahead), sent), None);
+        }
+    }
+}
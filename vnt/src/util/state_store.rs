@@ -0,0 +1,326 @@
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 文件头，格式: vnt-state <版本> <crc32> <长度>\n，后面是内容
+const MAGIC: &str = "vnt-state";
+
+/// 保存在数据目录下的状态
+///
+/// 每个文件带有版本和校验和。没有文件头的是加入版本之前写的文件，按版本0交给decode迁移。
+/// 读取时校验失败、版本比当前新、或者decode返回None，都把文件改名为`<文件名>.corrupt-<时间>`
+/// 并使用默认值，只输出一次警告
+pub trait StateFile: Sized {
+    /// 当前格式的版本，从1开始
+    const VERSION: u32;
+    fn encode(&self) -> Vec<u8>;
+    /// version为文件中的版本，旧版本在这里迁移，无法迁移时返回None
+    fn decode(version: u32, body: &[u8]) -> Option<Self>;
+}
+
+/// 读取状态文件，文件不存在或者损坏时返回None
+pub fn load<T: StateFile>(path: &Path) -> Option<T> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("读取状态文件失败 {:?} {:?}", path, e);
+            }
+            return None;
+        }
+    };
+    let value = decode::<T>(&data);
+    if value.is_none() {
+        let aside = corrupt_path(path);
+        log::warn!(
+            "状态文件损坏或版本不兼容，使用默认值 {:?} -> {:?}",
+            path,
+            aside
+        );
+        if let Err(e) = std::fs::rename(path, &aside) {
+            log::warn!("移走损坏的状态文件失败 {:?} {:?}", path, e);
+        }
+    }
+    value
+}
+
+/// 读取状态文件，不存在或者损坏时使用默认值
+pub fn load_or_default<T: StateFile + Default>(path: &Path) -> T {
+    load(path).unwrap_or_default()
+}
+
+/// 立即保存
+pub fn save<T: StateFile>(path: &Path, value: &T) -> io::Result<()> {
+    atomic_write(path, &encode(value))
+}
+
+/// 解析文件内容，校验失败、版本比当前新或者无法迁移时返回None
+pub fn decode<T: StateFile>(data: &[u8]) -> Option<T> {
+    match parse(data) {
+        Ok((version, body)) if version <= T::VERSION => T::decode(version, body),
+        _ => None,
+    }
+}
+
+/// 带文件头的完整内容
+pub fn encode<T: StateFile>(value: &T) -> Vec<u8> {
+    with_header(T::VERSION, &value.encode())
+}
+
+/// 先写临时文件再改名，中途崩溃不会留下写了一半的文件。
+/// 临时文件名带进程号，热重启时新旧进程同时保存也不会写到同一个临时文件
+pub fn atomic_write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let rs = write_sync(&tmp, data).and_then(|_| std::fs::rename(&tmp, path));
+    if rs.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    rs
+}
+
+fn write_sync(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+fn with_header(version: u32, body: &[u8]) -> Vec<u8> {
+    let mut data =
+        format!("{} {} {:08x} {}\n", MAGIC, version, crc32(body), body.len()).into_bytes();
+    data.extend_from_slice(body);
+    data
+}
+
+/// 解析文件头，返回版本和内容
+fn parse(data: &[u8]) -> Result<(u32, &[u8]), ()> {
+    if !data.starts_with(MAGIC.as_bytes()) {
+        if !data.is_empty() && MAGIC.as_bytes().starts_with(data) {
+            // 文件头写了一半
+            return Err(());
+        }
+        return Ok((0, data));
+    }
+    let end = data.iter().position(|v| *v == b'\n').ok_or(())?;
+    let header = std::str::from_utf8(&data[..end]).map_err(|_| ())?;
+    let body = &data[end + 1..];
+    let mut split = header.split(' ').skip(1);
+    let version: u32 = split.next().ok_or(())?.parse().map_err(|_| ())?;
+    let checksum = u32::from_str_radix(split.next().ok_or(())?, 16).map_err(|_| ())?;
+    let len: usize = split.next().ok_or(())?.parse().map_err(|_| ())?;
+    if version == 0 || len != body.len() || checksum != crc32(body) {
+        return Err(());
+    }
+    Ok((version, body))
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0);
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".corrupt-{}", ts));
+    PathBuf::from(aside)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// 合并写入，各功能修改状态时先暂存，由flush统一写入
+pub struct StateStore {
+    pending: Mutex<Vec<(PathBuf, Vec<u8>)>>,
+}
+
+impl StateStore {
+    pub const fn new() -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+    /// 暂存，同一个文件只保留最后一次
+    pub fn stage<T: StateFile>(&self, path: PathBuf, value: &T) {
+        let data = encode(value);
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|(v, _)| *v != path);
+        pending.push((path, data));
+    }
+    /// 删除状态文件和暂存的写入，清除对端等数据时使用，避免之后的flush把文件写回。
+    /// 文件原先存在时返回true
+    pub fn discard(&self, path: &Path) -> io::Result<bool> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let staged = pending.len();
+        pending.retain(|(v, _)| v != path);
+        let staged = pending.len() != staged;
+        match std::fs::remove_file(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(staged),
+            Err(e) => Err(e),
+        }
+    }
+    /// 写入所有暂存的状态，失败的留到下次
+    pub fn flush(&self) {
+        let list = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut failed = Vec::new();
+        for (path, data) in list {
+            if let Err(e) = atomic_write(&path, &data) {
+                log::warn!("保存状态文件失败 {:?} {:?}", path, e);
+                failed.push((path, data));
+            }
+        }
+        if !failed.is_empty() {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            for (path, data) in failed {
+                // 期间有更新的版本时丢弃旧的
+                if !pending.iter().any(|(v, _)| *v == path) {
+                    pending.push((path, data));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{crc32, load, load_or_default, save, StateFile, StateStore};
+
+    #[derive(Debug, Default, Eq, PartialEq)]
+    struct Peers(Vec<String>);
+
+    impl StateFile for Peers {
+        const VERSION: u32 = 2;
+        fn encode(&self) -> Vec<u8> {
+            self.0.join(",").into_bytes()
+        }
+        fn decode(version: u32, body: &[u8]) -> Option<Self> {
+            let text = std::str::from_utf8(body).ok()?;
+            match version {
+                // 旧版本每行一个
+                0 | 1 => Some(Peers(text.lines().map(|v| v.to_string()).collect())),
+                2 => Some(Peers(
+                    text.split(',')
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.to_string())
+                        .collect(),
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vnt-state-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn corrupt_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .filter(|v| v.file_name().to_string_lossy().contains(".corrupt-"))
+            .count()
+    }
+
+    fn peers() -> Peers {
+        Peers(vec!["10.26.0.3".to_string(), "10.26.0.4".to_string()])
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_truncate_and_bit_flip() {
+        let dir = test_dir("damage");
+        let path = dir.join("peers");
+        assert_eq!(load::<Peers>(&path), None);
+        save(&path, &peers()).unwrap();
+        assert_eq!(load::<Peers>(&path), Some(peers()));
+        let data = std::fs::read(&path).unwrap();
+        for len in [data.len() - 1, data.len() / 2, 5] {
+            std::fs::write(&path, &data[..len]).unwrap();
+            assert_eq!(load_or_default::<Peers>(&path), Peers::default());
+            // 损坏的文件被移走，再次读取不会重复警告
+            assert!(!path.exists());
+        }
+        for i in 0..data.len() {
+            let mut flipped = data.clone();
+            flipped[i] ^= 0x10;
+            std::fs::write(&path, &flipped).unwrap();
+            let value = load::<Peers>(&path);
+            // 改动文件头的魔数会被当成无头的旧文件，只要结果不是原来的内容就行
+            assert_ne!(value, Some(peers()), "flip {}", i);
+        }
+        assert!(corrupt_files(&dir) >= 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_old_version() {
+        let dir = test_dir("version");
+        let path = dir.join("peers");
+        // 加入文件头之前的格式
+        std::fs::write(&path, "10.26.0.3\n10.26.0.4\n").unwrap();
+        assert_eq!(load::<Peers>(&path), Some(peers()));
+        assert_eq!(corrupt_files(&dir), 0);
+        // 更新的版本写的文件无法识别，重置
+        std::fs::write(&path, super::with_header(3, b"10.26.0.3")).unwrap();
+        assert_eq!(load_or_default::<Peers>(&path), Peers::default());
+        assert_eq!(corrupt_files(&dir), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_staged_flush() {
+        let dir = test_dir("flush");
+        let store = StateStore::new();
+        store.stage(dir.join("a"), &Peers(vec!["1".to_string()]));
+        store.stage(dir.join("a"), &peers());
+        store.stage(dir.join("b"), &Peers(vec!["2".to_string()]));
+        assert!(!dir.join("a").exists());
+        store.flush();
+        assert_eq!(load::<Peers>(&dir.join("a")), Some(peers()));
+        assert_eq!(
+            load::<Peers>(&dir.join("b")),
+            Some(Peers(vec!["2".to_string()]))
+        );
+        // 目录不存在时留到下次
+        store.stage(dir.join("missing").join("c"), &peers());
+        store.flush();
+        std::fs::create_dir_all(dir.join("missing")).unwrap();
+        store.flush();
+        assert_eq!(load::<Peers>(&dir.join("missing").join("c")), Some(peers()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_discard() {
+        let dir = test_dir("discard");
+        let store = StateStore::new();
+        store.stage(dir.join("a"), &peers());
+        store.flush();
+        // 已写入的文件和之后暂存的写入都删除
+        store.stage(dir.join("a"), &Peers(vec!["1".to_string()]));
+        assert!(store.discard(&dir.join("a")).unwrap());
+        store.flush();
+        assert!(!dir.join("a").exists());
+        assert!(!store.discard(&dir.join("a")).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
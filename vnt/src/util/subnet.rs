@@ -0,0 +1,226 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// 虚拟网段，由虚拟ip和掩码计算
+///
+/// 前缀不超过30时网络地址和广播地址保留，不能分配给设备；
+/// /31两个地址都可以使用(RFC 3021)，/32只有一个地址
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Subnet {
+    network: u32,
+    mask: u32,
+}
+
+/// 目的地址的分类
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Destination {
+    /// 255.255.255.255、网段的广播地址或网络地址
+    Broadcast,
+    /// 组播，当作广播处理
+    Multicast,
+    /// 网段内的设备
+    Peer,
+    /// 网段外，需要点对网路由
+    External,
+}
+
+/// 服务器分配的地址不可用的原因
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InvalidHost {
+    /// 掩码不连续
+    Netmask,
+    Unspecified,
+    Multicast,
+    /// 是网段的网络地址
+    Network,
+    /// 是网段的广播地址或255.255.255.255
+    Broadcast,
+}
+
+impl fmt::Display for InvalidHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InvalidHost::Netmask => "netmask is not contiguous",
+            InvalidHost::Unspecified => "address is unspecified",
+            InvalidHost::Multicast => "address is multicast",
+            InvalidHost::Network => "address is the network address of the subnet",
+            InvalidHost::Broadcast => "address is the broadcast address of the subnet",
+        })
+    }
+}
+
+impl Subnet {
+    pub fn new(ip: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        let mask = u32::from(netmask);
+        Self {
+            network: u32::from(ip) & mask,
+            mask,
+        }
+    }
+    /// 掩码是否连续
+    pub fn is_valid(&self) -> bool {
+        self.mask.leading_ones() + self.mask.trailing_zeros() == 32
+    }
+    pub fn prefix_len(&self) -> u32 {
+        self.mask.leading_ones()
+    }
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network)
+    }
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network | !self.mask)
+    }
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask == self.network
+    }
+    /// 网络地址和广播地址是否保留
+    pub fn has_reserved(&self) -> bool {
+        self.prefix_len() <= 30
+    }
+    /// 255.255.255.255，或者网段的网络地址、广播地址
+    pub fn is_reserved(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast()
+            || (self.has_reserved() && (ip == self.network() || ip == self.broadcast()))
+    }
+    pub fn classify(&self, dest: Ipv4Addr) -> Destination {
+        if dest.is_multicast() {
+            Destination::Multicast
+        } else if self.is_reserved(dest) {
+            Destination::Broadcast
+        } else if self.contains(dest) {
+            Destination::Peer
+        } else {
+            Destination::External
+        }
+    }
+    /// 检查地址能否作为本机的虚拟ip
+    pub fn check_host(&self, ip: Ipv4Addr) -> Result<(), InvalidHost> {
+        if !self.is_valid() {
+            return Err(InvalidHost::Netmask);
+        }
+        if ip.is_unspecified() {
+            return Err(InvalidHost::Unspecified);
+        }
+        if ip.is_multicast() {
+            return Err(InvalidHost::Multicast);
+        }
+        if ip.is_broadcast() || (self.has_reserved() && ip == self.broadcast()) {
+            return Err(InvalidHost::Broadcast);
+        }
+        if self.has_reserved() && ip == self.network() {
+            return Err(InvalidHost::Network);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{Destination, InvalidHost, Subnet};
+
+    fn mask(prefix: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0))
+    }
+
+    #[test]
+    fn test_all_prefix() {
+        let base = u32::from(Ipv4Addr::new(10, 26, 0, 0));
+        for prefix in 0..=32u32 {
+            let subnet = Subnet::new(Ipv4Addr::from(base | 1), mask(prefix));
+            assert!(subnet.is_valid());
+            assert_eq!(subnet.prefix_len(), prefix);
+            let size = 1u64 << (32 - prefix);
+            let network = u32::from(subnet.network());
+            let broadcast = u32::from(subnet.broadcast());
+            assert_eq!(broadcast as u64 - network as u64 + 1, size);
+            assert_eq!(network, (base | 1) & u32::from(mask(prefix)));
+            assert!(subnet.contains(subnet.network()));
+            assert!(subnet.contains(subnet.broadcast()));
+            if prefix > 0 {
+                assert!(!subnet.contains(Ipv4Addr::from(broadcast.wrapping_add(1))));
+            }
+            assert_eq!(subnet.classify(Ipv4Addr::BROADCAST), Destination::Broadcast);
+            assert_eq!(
+                subnet.classify(Ipv4Addr::new(224, 0, 0, 251)),
+                Destination::Multicast
+            );
+            if prefix >= 8 {
+                assert_eq!(
+                    subnet.classify(Ipv4Addr::new(192, 168, 1, 1)),
+                    Destination::External
+                );
+            }
+            match prefix {
+                32 => {
+                    // 唯一的地址就是本机
+                    assert_eq!(subnet.network(), subnet.broadcast());
+                    assert!(!subnet.is_reserved(subnet.network()));
+                    assert_eq!(subnet.classify(subnet.network()), Destination::Peer);
+                    assert!(subnet.check_host(subnet.network()).is_ok());
+                }
+                31 => {
+                    // 两个地址都可以分配
+                    for ip in [subnet.network(), subnet.broadcast()] {
+                        assert!(!subnet.is_reserved(ip));
+                        assert_eq!(subnet.classify(ip), Destination::Peer);
+                        assert!(subnet.check_host(ip).is_ok());
+                    }
+                }
+                _ => {
+                    assert_eq!(subnet.classify(subnet.network()), Destination::Broadcast);
+                    assert_eq!(subnet.classify(subnet.broadcast()), Destination::Broadcast);
+                    // 前缀很短时网络地址是0.0.0.0
+                    let expect = if network == 0 {
+                        InvalidHost::Unspecified
+                    } else {
+                        InvalidHost::Network
+                    };
+                    assert_eq!(subnet.check_host(subnet.network()), Err(expect));
+                    assert_eq!(
+                        subnet.check_host(subnet.broadcast()),
+                        Err(InvalidHost::Broadcast)
+                    );
+                    let first = Ipv4Addr::from(network + 1);
+                    let last = Ipv4Addr::from(broadcast - 1);
+                    for ip in [first, last] {
+                        assert_eq!(subnet.classify(ip), Destination::Peer);
+                        assert!(subnet.check_host(ip).is_ok());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_host() {
+        let subnet = Subnet::new(Ipv4Addr::new(10, 26, 0, 2), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(subnet.network(), Ipv4Addr::new(10, 26, 0, 0));
+        assert_eq!(subnet.broadcast(), Ipv4Addr::new(10, 26, 0, 255));
+        assert!(subnet.check_host(Ipv4Addr::new(10, 26, 0, 2)).is_ok());
+        assert_eq!(
+            subnet.check_host(Ipv4Addr::UNSPECIFIED),
+            Err(InvalidHost::Unspecified)
+        );
+        assert_eq!(
+            subnet.check_host(Ipv4Addr::BROADCAST),
+            Err(InvalidHost::Broadcast)
+        );
+        assert_eq!(
+            subnet.check_host(Ipv4Addr::new(239, 1, 1, 1)),
+            Err(InvalidHost::Multicast)
+        );
+        let subnet = Subnet::new(Ipv4Addr::new(10, 26, 0, 2), Ipv4Addr::new(255, 0, 255, 0));
+        assert!(!subnet.is_valid());
+        assert_eq!(
+            subnet.check_host(Ipv4Addr::new(10, 26, 0, 2)),
+            Err(InvalidHost::Netmask)
+        );
+        // 0.0.0.0/0
+        let subnet = Subnet::new(Ipv4Addr::new(10, 26, 0, 2), Ipv4Addr::UNSPECIFIED);
+        assert!(subnet.is_valid());
+        assert_eq!(subnet.prefix_len(), 0);
+        assert!(subnet.contains(Ipv4Addr::new(8, 8, 8, 8)));
+    }
+}
@@ -0,0 +1,541 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::handle::callback::SubsystemInfo;
+use crate::util::state_store::StateFile;
+
+pub type StartFn = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+pub type StopFn = Box<dyn Fn() + Send + Sync>;
+pub type ProbeFn = Box<dyn Fn() -> Probe + Send + Sync>;
+type Listener = Arc<dyn Fn(SubsystemInfo) + Send + Sync>;
+
+/// 子系统的健康状态和资源占用
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Probe {
+    pub healthy: bool,
+    // 资源占用或者异常原因的说明，如监听地址、表项数
+    pub detail: String,
+}
+
+impl Probe {
+    pub fn new(healthy: bool, detail: impl Into<String>) -> Self {
+        Self {
+            healthy,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// 注册到Subsystems的组件
+pub struct Subsystem {
+    name: &'static str,
+    depends: Vec<&'static str>,
+    // 核心组件没有启停函数，只显示状态
+    control: Option<(StartFn, StopFn)>,
+    probe: ProbeFn,
+    running: bool,
+}
+
+impl Subsystem {
+    /// 可以单独启停的组件，注册时已经在运行
+    pub fn new(
+        name: &'static str,
+        start: impl Fn() -> io::Result<()> + Send + Sync + 'static,
+        stop: impl Fn() + Send + Sync + 'static,
+        probe: impl Fn() -> Probe + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            depends: Vec::new(),
+            control: Some((Box::new(start), Box::new(stop))),
+            probe: Box::new(probe),
+            running: true,
+        }
+    }
+    /// 数据通道的核心组件，只列出不能停止
+    pub fn core(name: &'static str, probe: impl Fn() -> Probe + Send + Sync + 'static) -> Self {
+        Self {
+            name,
+            depends: Vec::new(),
+            control: None,
+            probe: Box::new(probe),
+            running: true,
+        }
+    }
+    /// 依赖的子系统需要先注册
+    pub fn depends_on(mut self, name: &'static str) -> Self {
+        self.depends.push(name);
+        self
+    }
+    /// 按配置没有启动
+    pub fn stopped(mut self) -> Self {
+        self.running = false;
+        self
+    }
+}
+
+struct Entry {
+    subsystem: Subsystem,
+    // 注册时的状态，和它不同的状态保存为覆盖设置
+    initial: bool,
+    since: Option<Instant>,
+}
+
+/// 子系统的当前状态
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubsystemStatus {
+    pub name: &'static str,
+    pub core: bool,
+    pub running: bool,
+    pub uptime: Duration,
+    pub depends: Vec<&'static str>,
+    pub probe: Probe,
+}
+
+/// 运行中可以单独启停的子系统
+///
+/// 每个可选组件注册启动、停止函数和健康探测，声明依赖的其他子系统。
+/// 停止被其他运行中的子系统依赖的组件时，不带cascade拒绝，带cascade先停止依赖它的子系统；
+/// 启动时依赖的子系统必须在运行。启停函数在锁内调用，不能再调用Subsystems
+#[derive(Clone, Default)]
+pub struct Subsystems {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    listener: Arc<Mutex<Option<Listener>>>,
+}
+
+impl Subsystems {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 状态变化时回调
+    pub fn set_listener(&self, listener: impl Fn(SubsystemInfo) + Send + Sync + 'static) {
+        self.listener.lock().replace(Arc::new(listener));
+    }
+    pub fn register(&self, subsystem: Subsystem) {
+        let mut entries = self.entries.lock();
+        for depend in &subsystem.depends {
+            if !entries.iter().any(|v| v.subsystem.name == *depend) {
+                log::warn!("子系统{}依赖的{}没有注册", subsystem.name, depend);
+            }
+        }
+        if let Some(index) = entries
+            .iter()
+            .position(|v| v.subsystem.name == subsystem.name)
+        {
+            log::warn!("子系统{}重复注册", subsystem.name);
+            entries.remove(index);
+        }
+        let running = subsystem.running;
+        entries.push(Entry {
+            subsystem,
+            initial: running,
+            since: if running { Some(Instant::now()) } else { None },
+        });
+    }
+    pub fn list(&self) -> Vec<SubsystemStatus> {
+        let entries = self.entries.lock();
+        entries
+            .iter()
+            .map(|entry| SubsystemStatus {
+                name: entry.subsystem.name,
+                core: entry.subsystem.control.is_none(),
+                running: entry.since.is_some(),
+                uptime: entry.since.map(|v| v.elapsed()).unwrap_or_default(),
+                depends: entry.subsystem.depends.clone(),
+                probe: (entry.subsystem.probe)(),
+            })
+            .collect()
+    }
+    /// 启动，依赖的子系统没有运行时拒绝。返回启动了的子系统，已经在运行时为空
+    pub fn start(&self, name: &str, reason: &str) -> Result<Vec<&'static str>, String> {
+        let mut events = Vec::new();
+        let rs = {
+            let mut entries = self.entries.lock();
+            start0(&mut entries, name, reason, &mut events)
+        };
+        self.emit(events);
+        rs
+    }
+    /// 停止，被运行中的子系统依赖时，cascade为false则拒绝，否则先停止依赖它的子系统。
+    /// 返回停止了的子系统，已经停止时为空
+    pub fn stop(
+        &self,
+        name: &str,
+        cascade: bool,
+        reason: &str,
+    ) -> Result<Vec<&'static str>, String> {
+        let mut events = Vec::new();
+        let rs = {
+            let mut entries = self.entries.lock();
+            stop0(&mut entries, name, cascade, reason, &mut events)
+        };
+        self.emit(events);
+        rs
+    }
+    /// 和注册时状态不同的子系统，按注册顺序
+    pub fn overrides(&self) -> SubsystemOverrides {
+        let entries = self.entries.lock();
+        SubsystemOverrides(
+            entries
+                .iter()
+                .filter(|v| v.since.is_some() != v.initial)
+                .map(|v| (v.subsystem.name.to_string(), v.since.is_some()))
+                .collect(),
+        )
+    }
+    /// 重启后恢复保存的覆盖设置，已经不存在的子系统忽略
+    pub fn apply(&self, overrides: &SubsystemOverrides) {
+        for (name, running) in &overrides.0 {
+            let rs = if *running {
+                self.start(name, "saved override")
+            } else {
+                self.stop(name, true, "saved override")
+            };
+            if let Err(e) = rs {
+                log::warn!("恢复子系统{}状态失败 {}", name, e);
+            }
+        }
+    }
+    fn emit(&self, events: Vec<SubsystemInfo>) {
+        if events.is_empty() {
+            return;
+        }
+        let listener = self.listener.lock().clone();
+        for info in events {
+            log::info!("{}", info);
+            if let Some(listener) = &listener {
+                listener(info);
+            }
+        }
+    }
+}
+
+fn find(entries: &[Entry], name: &str) -> Result<usize, String> {
+    entries
+        .iter()
+        .position(|v| v.subsystem.name == name)
+        .ok_or_else(|| format!("unknown subsystem '{}'", name))
+}
+
+fn start0(
+    entries: &mut [Entry],
+    name: &str,
+    reason: &str,
+    events: &mut Vec<SubsystemInfo>,
+) -> Result<Vec<&'static str>, String> {
+    let index = find(entries, name)?;
+    let entry = &entries[index];
+    let (start, _) = match &entry.subsystem.control {
+        Some(control) => control,
+        None => return Err(format!("{} is a core component", name)),
+    };
+    if entry.since.is_some() {
+        return Ok(Vec::new());
+    }
+    for depend in &entry.subsystem.depends {
+        let running = entries
+            .iter()
+            .any(|v| v.subsystem.name == *depend && v.since.is_some());
+        if !running {
+            return Err(format!("{} depends on {}, start it first", name, depend));
+        }
+    }
+    start().map_err(|e| format!("{} failed to start: {}", name, e))?;
+    let entry = &mut entries[index];
+    entry.since = Some(Instant::now());
+    events.push(SubsystemInfo::new(entry.subsystem.name, true, reason));
+    Ok(vec![entry.subsystem.name])
+}
+
+fn stop0(
+    entries: &mut [Entry],
+    name: &str,
+    cascade: bool,
+    reason: &str,
+    events: &mut Vec<SubsystemInfo>,
+) -> Result<Vec<&'static str>, String> {
+    let index = find(entries, name)?;
+    let entry = &entries[index];
+    if entry.subsystem.control.is_none() {
+        return Err(format!(
+            "{} is a core component and cannot be stopped",
+            name
+        ));
+    }
+    if entry.since.is_none() {
+        return Ok(Vec::new());
+    }
+    let name = entry.subsystem.name;
+    let dependents: Vec<&'static str> = entries
+        .iter()
+        .filter(|v| v.since.is_some() && v.subsystem.depends.contains(&name))
+        .map(|v| v.subsystem.name)
+        .collect();
+    let mut stopped = Vec::new();
+    if !dependents.is_empty() {
+        if !cascade {
+            return Err(format!(
+                "{} is needed by {}, stop them first or use --cascade",
+                name,
+                dependents.join(",")
+            ));
+        }
+        log::warn!("停止子系统{}，同时停止依赖它的{:?}", name, dependents);
+        let reason = format!("{} stopped", name);
+        for dependent in dependents {
+            stopped.extend(stop0(entries, dependent, true, &reason, events)?);
+        }
+    }
+    let entry = &mut entries[index];
+    if let Some((_, stop)) = &entry.subsystem.control {
+        stop();
+    }
+    entry.since = None;
+    events.push(SubsystemInfo::new(name, false, reason));
+    stopped.push(name);
+    Ok(stopped)
+}
+
+/// 可以单独停止的后台线程，线程在循环中检查is_off，stop等待线程退出
+#[derive(Clone, Default)]
+pub struct Switch {
+    inner: Arc<SwitchInner>,
+}
+
+#[derive(Default)]
+struct SwitchInner {
+    off: AtomicBool,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Switch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_off(&self) -> bool {
+        self.inner.off.load(Ordering::Acquire)
+    }
+    pub fn spawn(&self, name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+        self.inner.off.store(false, Ordering::Release);
+        let handle = std::thread::Builder::new().name(name.into()).spawn(f)?;
+        self.inner.handle.lock().replace(handle);
+        Ok(())
+    }
+    pub fn stop(&self) {
+        self.stop_with(|| {})
+    }
+    /// wake用于唤醒阻塞在读取上的线程
+    pub fn stop_with(&self, wake: impl FnOnce()) {
+        self.inner.off.store(true, Ordering::Release);
+        wake();
+        let handle = self.inner.handle.lock().take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                log::warn!("子系统线程异常退出");
+            }
+        }
+    }
+}
+
+/// 保存在数据目录下的子系统启停设置，每行'<名称> <on|off>'
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SubsystemOverrides(pub Vec<(String, bool)>);
+
+impl StateFile for SubsystemOverrides {
+    const VERSION: u32 = 1;
+    fn encode(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for (name, running) in &self.0 {
+            text.push_str(&format!(
+                "{} {}\n",
+                name,
+                if *running { "on" } else { "off" }
+            ));
+        }
+        text.into_bytes()
+    }
+    fn decode(_version: u32, body: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(body).ok()?;
+        let mut list = Vec::new();
+        for line in text.lines().map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let mut parts = line.split_whitespace();
+            let name = parts.next().unwrap_or_default();
+            match parts.next() {
+                Some("on") => list.push((name.to_string(), true)),
+                Some("off") => list.push((name.to_string(), false)),
+                _ => log::warn!("子系统设置格式错误 {:?}", line),
+            }
+        }
+        Some(SubsystemOverrides(list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::util::state_store;
+
+    /// 用一个标记模拟子系统，记录启动次数
+    fn flag(name: &'static str) -> (Subsystem, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        let on = Arc::new(AtomicBool::new(true));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let (on1, on2, on3, starts1) = (on.clone(), on.clone(), on.clone(), starts.clone());
+        let subsystem = Subsystem::new(
+            name,
+            move || {
+                starts1.fetch_add(1, Ordering::Relaxed);
+                on1.store(true, Ordering::Relaxed);
+                Ok(())
+            },
+            move || on2.store(false, Ordering::Relaxed),
+            move || {
+                Probe::new(
+                    true,
+                    if on3.load(Ordering::Relaxed) {
+                        "on"
+                    } else {
+                        "off"
+                    },
+                )
+            },
+        );
+        (subsystem, on, starts)
+    }
+
+    fn running(subsystems: &Subsystems, name: &str) -> bool {
+        subsystems
+            .list()
+            .into_iter()
+            .find(|v| v.name == name)
+            .unwrap()
+            .running
+    }
+
+    #[test]
+    fn test_stop_start() {
+        let subsystems = Subsystems::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events1 = events.clone();
+        subsystems.set_listener(move |info| events1.lock().push(info));
+        let (dns, dns_on, dns_starts) = flag("dns");
+        let (mdns, mdns_on, _) = flag("mdns");
+        subsystems.register(Subsystem::core("tun", || Probe::new(true, "")));
+        subsystems.register(dns);
+        subsystems.register(mdns.stopped());
+        mdns_on.store(false, Ordering::Relaxed);
+        for _ in 0..2 {
+            assert_eq!(subsystems.stop("dns", false, "test"), Ok(vec!["dns"]));
+            assert!(!dns_on.load(Ordering::Relaxed));
+            assert!(!running(&subsystems, "dns"));
+            assert_eq!(subsystems.list()[1].probe.detail, "off");
+            // 重复停止不会再调用
+            assert_eq!(subsystems.stop("dns", false, "test"), Ok(vec![]));
+            assert_eq!(subsystems.start("dns", "test"), Ok(vec!["dns"]));
+            assert!(dns_on.load(Ordering::Relaxed));
+            assert!(running(&subsystems, "dns"));
+        }
+        assert_eq!(dns_starts.load(Ordering::Relaxed), 2);
+        assert_eq!(subsystems.start("mdns", "test"), Ok(vec!["mdns"]));
+        assert!(mdns_on.load(Ordering::Relaxed));
+        assert_eq!(subsystems.stop("mdns", false, "test"), Ok(vec!["mdns"]));
+        assert!(!mdns_on.load(Ordering::Relaxed));
+        assert!(subsystems.stop("tun", true, "test").is_err());
+        assert!(subsystems.start("tun", "test").is_err());
+        assert!(subsystems.stop("fec", false, "test").is_err());
+        let events = events.lock();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0], SubsystemInfo::new("dns", false, "test"));
+        assert_eq!(events[1], SubsystemInfo::new("dns", true, "test"));
+    }
+
+    #[test]
+    fn test_dependency() {
+        let subsystems = Subsystems::new();
+        let (flows, flows_on, _) = flag("flows");
+        let (notify, notify_on, _) = flag("flow-notify");
+        subsystems.register(flows);
+        subsystems.register(notify.depends_on("flows"));
+        // 被依赖时拒绝，状态不变
+        let err = subsystems.stop("flows", false, "test").unwrap_err();
+        assert!(err.contains("flow-notify"), "{}", err);
+        assert!(flows_on.load(Ordering::Relaxed));
+        assert!(running(&subsystems, "flow-notify"));
+        // 级联停止先停依赖它的
+        assert_eq!(
+            subsystems.stop("flows", true, "test"),
+            Ok(vec!["flow-notify", "flows"])
+        );
+        assert!(!flows_on.load(Ordering::Relaxed));
+        assert!(!notify_on.load(Ordering::Relaxed));
+        // 依赖没有运行时不能启动
+        assert!(subsystems.start("flow-notify", "test").is_err());
+        assert!(!notify_on.load(Ordering::Relaxed));
+        assert_eq!(subsystems.start("flows", "test"), Ok(vec!["flows"]));
+        assert_eq!(
+            subsystems.start("flow-notify", "test"),
+            Ok(vec!["flow-notify"])
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let subsystems = Subsystems::new();
+        let (dns, _, _) = flag("dns");
+        let (mdns, _, _) = flag("mdns");
+        subsystems.register(dns);
+        subsystems.register(mdns.stopped());
+        assert_eq!(subsystems.overrides(), SubsystemOverrides::default());
+        subsystems.stop("dns", false, "test").unwrap();
+        subsystems.start("mdns", "test").unwrap();
+        let overrides = subsystems.overrides();
+        assert_eq!(
+            overrides.0,
+            vec![("dns".to_string(), false), ("mdns".to_string(), true)]
+        );
+        let data = state_store::encode(&overrides);
+        let decoded: SubsystemOverrides = state_store::decode(&data).unwrap();
+        assert_eq!(decoded, overrides);
+
+        // 重启后按保存的设置恢复
+        let restarted = Subsystems::new();
+        let (dns, dns_on, _) = flag("dns");
+        let (mdns, mdns_on, _) = flag("mdns");
+        restarted.register(dns);
+        restarted.register(mdns.stopped());
+        mdns_on.store(false, Ordering::Relaxed);
+        restarted.apply(&decoded);
+        assert!(!dns_on.load(Ordering::Relaxed));
+        assert!(mdns_on.load(Ordering::Relaxed));
+        assert_eq!(restarted.overrides(), overrides);
+    }
+
+    #[test]
+    fn test_switch() {
+        let switch = Switch::new();
+        let loops = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let switch1 = switch.clone();
+            let loops1 = loops.clone();
+            switch
+                .spawn("test", move || {
+                    while !switch1.is_off() {
+                        loops1.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                })
+                .unwrap();
+            switch.stop();
+            assert!(switch.is_off());
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use parking_lot::Mutex;
+
+/// 可以绑定核心的工作线程
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Worker {
+    /// 接收udp数据，mainUdp和subUdp
+    Udp,
+    /// 接收tcp数据，tcpRead
+    Tcp,
+    /// 读取网卡，tunHandlerS和tunHandlerM
+    Tun,
+    /// '--par'大于1时加解密的线程，tunHandler-N
+    Crypto,
+}
+
+impl Worker {
+    pub const ALL: [Worker; 4] = [Worker::Udp, Worker::Tcp, Worker::Tun, Worker::Crypto];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Worker::Udp => "udp",
+            Worker::Tcp => "tcp",
+            Worker::Tun => "tun",
+            Worker::Crypto => "crypto",
+        }
+    }
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Worker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Worker::ALL.iter().find(|w| w.name() == s) {
+            Some(worker) => Ok(*worker),
+            None => Err(format!(
+                "unknown worker '{}', available: {}",
+                s,
+                Worker::ALL
+                    .iter()
+                    .map(|w| w.name())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+        }
+    }
+}
+
+/// 工作线程绑定的核心，如"udp=2,tun=3,crypto=4-5"
+///
+/// 同一种线程有多个时按顺序轮流分配列出的核心，每个线程只绑定一个核心
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PinSpec {
+    cores: Vec<(Worker, Vec<usize>)>,
+}
+
+impl PinSpec {
+    pub fn is_empty(&self) -> bool {
+        self.cores.is_empty()
+    }
+    pub fn cores(&self, worker: Worker) -> Option<&[usize]> {
+        self.cores
+            .iter()
+            .find(|(w, _)| *w == worker)
+            .map(|(_, cores)| cores.as_slice())
+    }
+    /// 检查核心是否都允许当前进程使用
+    pub fn validate(&self, allowed: &[usize]) -> Result<(), String> {
+        for (worker, cores) in &self.cores {
+            if let Some(core) = cores.iter().find(|core| !allowed.contains(core)) {
+                return Err(format!(
+                    "core {} for {} is not available, allowed: {}",
+                    core,
+                    worker,
+                    core_list(allowed)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PinSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cores: Vec<(Worker, Vec<usize>)> = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let (name, list) = match item.split_once('=') {
+                Some(v) => v,
+                None => return Err(format!("'{}' should be <worker>=<cores>", item)),
+            };
+            let worker = name.trim().parse::<Worker>()?;
+            if cores.iter().any(|(w, _)| *w == worker) {
+                return Err(format!("worker '{}' specified more than once", worker));
+            }
+            cores.push((worker, parse_cores(list.trim())?));
+        }
+        if cores.is_empty() {
+            return Err("empty worker spec".to_string());
+        }
+        Ok(Self { cores })
+    }
+}
+
+impl fmt::Display for PinSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = self
+            .cores
+            .iter()
+            .map(|(worker, cores)| format!("{}={}", worker, core_list(cores)))
+            .collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+/// "3"或者"4-5"
+fn parse_cores(list: &str) -> Result<Vec<usize>, String> {
+    let number = |v: &str| {
+        v.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid core '{}'", list))
+    };
+    match list.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (number(start)?, number(end)?);
+            if start > end {
+                return Err(format!("invalid core range '{}'", list));
+            }
+            Ok((start..=end).collect())
+        }
+        None => Ok(vec![number(list)?]),
+    }
+}
+
+fn core_list(cores: &[usize]) -> String {
+    match cores {
+        [single] => single.to_string(),
+        [first, .., last] if last - first + 1 == cores.len() => format!("{}-{}", first, last),
+        _ => cores
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// 工作线程的优先级
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// 需要权限，没有权限时保持默认并告警
+    High,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority '{}', available: normal,high", s)),
+        }
+    }
+}
+
+/// 工作线程实际的位置
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub name: String,
+    pub worker: Worker,
+    // 操作系统的线程id，不支持时为0
+    pub tid: u64,
+    pub core: Option<usize>,
+    pub priority: Priority,
+    // 绑定核心或者调整优先级失败的原因
+    pub error: Option<String>,
+}
+
+/// 工作线程的核心绑定和优先级，线程启动时调用enter
+pub struct Workers {
+    config: Mutex<(PinSpec, Priority)>,
+    next: [AtomicUsize; 4],
+    threads: Mutex<Vec<Placement>>,
+}
+
+impl Workers {
+    pub fn new() -> Self {
+        Self {
+            config: Mutex::new((PinSpec::default(), Priority::Normal)),
+            next: Default::default(),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+    /// 在工作线程启动前调用
+    pub fn configure(&self, spec: PinSpec, priority: Priority) {
+        *self.config.lock() = (spec, priority);
+    }
+    /// 在工作线程中调用，按配置绑定核心和调整优先级，失败只告警
+    pub fn enter(&self, worker: Worker) {
+        let (core, priority) = {
+            let config = self.config.lock();
+            let core = config.0.cores(worker).map(|cores| {
+                let index = self.next[worker.index()].fetch_add(1, Ordering::Relaxed);
+                cores[index % cores.len()]
+            });
+            (core, config.1)
+        };
+        let name = thread::current().name().unwrap_or("").to_string();
+        let mut errors = Vec::new();
+        let mut pinned = None;
+        if let Some(core) = core {
+            match set_affinity(core) {
+                Ok(_) => pinned = Some(core),
+                Err(e) => {
+                    log::warn!("线程{}绑定核心{}失败 {}", name, core, e);
+                    errors.push(format!("affinity: {}", e));
+                }
+            }
+        }
+        let mut actual = Priority::Normal;
+        if priority == Priority::High {
+            match set_high_priority() {
+                Ok(_) => actual = Priority::High,
+                Err(e) => {
+                    log::warn!("线程{}提高优先级失败 {}", name, e);
+                    errors.push(format!("priority: {}", e));
+                }
+            }
+        }
+        if core.is_some() || priority != Priority::Normal {
+            log::info!(
+                "线程{}({}) 核心:{} 优先级:{}",
+                name,
+                worker,
+                pinned.map(|v| v.to_string()).unwrap_or("-".into()),
+                actual.name()
+            );
+        }
+        self.threads.lock().push(Placement {
+            name,
+            worker,
+            tid: current_tid(),
+            core: pinned,
+            priority: actual,
+            error: if errors.is_empty() {
+                None
+            } else {
+                Some(errors.join("; "))
+            },
+        });
+    }
+    /// 已启动的工作线程
+    pub fn placements(&self) -> Vec<Placement> {
+        self.threads.lock().clone()
+    }
+}
+
+/// 允许当前进程使用的核心，容器中可能只是一部分
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn allowed_cores() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|core| libc::CPU_ISSET(*core, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn allowed_cores() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "cpu affinity is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_affinity(core: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_affinity(_core: usize) -> io::Result<()> {
+    allowed_cores().map(|_| ())
+}
+
+/// linux上nice值是线程级别的
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_high_priority() -> io::Result<()> {
+    let tid = current_tid() as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, -10) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_high_priority() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "thread priority is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn current_tid() -> u64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn current_tid() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::workers::{PinSpec, Priority, Worker};
+
+    #[test]
+    fn test_pin_spec() {
+        let spec: PinSpec = "udp=2, tun=3,crypto=4-5".parse().unwrap();
+        assert_eq!(spec.cores(Worker::Udp), Some(&[2][..]));
+        assert_eq!(spec.cores(Worker::Tun), Some(&[3][..]));
+        assert_eq!(spec.cores(Worker::Crypto), Some(&[4, 5][..]));
+        assert_eq!(spec.cores(Worker::Tcp), None);
+        assert_eq!(spec.to_string(), "udp=2,tun=3,crypto=4-5");
+
+        assert_eq!(
+            "udp=2,rx=3".parse::<PinSpec>(),
+            Err("unknown worker 'rx', available: udp,tcp,tun,crypto".to_string())
+        );
+        for spec in [
+            "",
+            "udp",
+            "udp=",
+            "udp=a",
+            "udp=5-4",
+            "udp=1,udp=2",
+            "udp=-1",
+        ] {
+            assert!(spec.parse::<PinSpec>().is_err(), "{:?}", spec);
+        }
+
+        assert!(spec.validate(&[0, 1, 2, 3, 4, 5]).is_ok());
+        assert_eq!(
+            spec.validate(&[0, 1, 2, 3]),
+            Err("core 4 for crypto is not available, allowed: 0-3".to_string())
+        );
+        assert_eq!("high".parse(), Ok(Priority::High));
+        assert!("realtime".parse::<Priority>().is_err());
+    }
+}
@@ -1,12 +1,12 @@
 #![allow(dead_code)]
 use std::ffi::{CStr, CString};
 use std::net::Ipv4Addr;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 use std::{io, mem, ptr};
 
 use libc::{
-    c_char, c_short, ifreq, AF_INET, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_RUNNING, IFF_TUN,
-    IFF_UP, IFNAMSIZ, O_RDWR, SOCK_DGRAM,
+    c_char, c_short, ifreq, AF_INET, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_RUNNING, IFF_TUN, IFF_UP,
+    IFNAMSIZ, O_RDWR, SOCK_DGRAM,
 };
 
 use crate::device::IFace;
@@ -80,6 +80,72 @@ impl Device {
         device.enabled(true)?;
         Ok(device)
     }
+    /// 使用已经打开的tun描述符，例如热重启时从上一个进程继承的
+    pub fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let tun = Fd::new(fd)?;
+        let name = unsafe {
+            let mut req: ifreq = mem::zeroed();
+            // 同时校验描述符确实是tun设备，不是的话不能关闭别人的描述符
+            if tungetiff(tun.0, &mut req as *mut _ as *mut _) < 0 {
+                let e = io::Error::last_os_error();
+                mem::forget(tun);
+                return Err(e);
+            }
+            CStr::from_ptr(req.ifr_name.as_ptr())
+                .to_string_lossy()
+                .to_string()
+        };
+        let ctl = Fd::new(unsafe { libc::socket(AF_INET, SOCK_DGRAM, 0) })?;
+        Ok(Device { name, tun, ctl })
+    }
+    /// 打开管理员预先创建的持久化tun设备(ip tuntap add mode tun user <user> name <name>)，
+    /// 设备所有者是当前用户时不需要root权限
+    pub fn open(name: &str) -> io::Result<Self> {
+        let c_name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if c_name.as_bytes_with_nul().len() > IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "name too long"));
+        }
+        let device = unsafe {
+            let mut req: ifreq = mem::zeroed();
+            ptr::copy_nonoverlapping(
+                c_name.as_ptr() as *const c_char,
+                req.ifr_name.as_mut_ptr(),
+                c_name.as_bytes().len(),
+            );
+            req.ifr_ifru.ifru_flags = (IFF_TUN | IFF_NO_PI) as c_short;
+            let tun = Fd::new(libc::open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR))
+                .map_err(|_| io::Error::last_os_error())?;
+            if tunsetiff(tun.0, &mut req as *mut _ as *mut _) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ctl = Fd::new(libc::socket(AF_INET, SOCK_DGRAM, 0))?;
+            Device {
+                name: name.to_string(),
+                tun,
+                ctl,
+            }
+        };
+        Ok(device)
+    }
+    /// 网卡是否已启用
+    pub fn is_up(&self) -> io::Result<bool> {
+        unsafe {
+            let mut req = self.request();
+            if siocgifflags(self.ctl.as_raw_fd(), &mut req) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(req.ifr_ifru.ifru_flags & IFF_UP as c_short != 0)
+        }
+    }
+    /// 启用网卡，需要CAP_NET_ADMIN
+    pub fn up(&self) -> io::Result<()> {
+        self.enabled(true)
+    }
+    /// 网卡当前的ip和掩码
+    pub fn ip(&self) -> io::Result<(Ipv4Addr, Ipv4Addr)> {
+        Ok((self.address()?, self.netmask()?))
+    }
 }
 
 impl Device {
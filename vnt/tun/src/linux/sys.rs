@@ -1,5 +1,5 @@
 use ioctl::*;
-use libc::{c_int, ifreq};
+use libc::{c_int, c_uint, ifreq};
 
 ioctl!(bad read siocgifflags with 0x8913; ifreq);
 ioctl!(bad write siocsifflags with 0x8914; ifreq);
@@ -19,3 +19,4 @@ ioctl!(write tunsetiff with b'T', 202; c_int);
 ioctl!(write tunsetpersist with b'T', 203; c_int);
 ioctl!(write tunsetowner with b'T', 204; c_int);
 ioctl!(write tunsetgroup with b'T', 206; c_int);
+ioctl!(read tungetiff with b'T', 210; c_uint);
@@ -246,6 +246,9 @@ impl IFace for Device {
 
     fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
         self.set_address(address)?;
+        // utun是点对点网卡，和'ifconfig utunX <ip> <ip> netmask <mask>'一样把对端地址设为自己，
+        // 不设置时网卡没有可用的地址，网段路由由add_route添加
+        self.set_destination(address)?;
         self.set_netmask(mask)
     }
 
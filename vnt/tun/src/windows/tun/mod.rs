@@ -78,7 +78,10 @@ impl Device {
                 ));
             }
             wintun_log::set_default_logger_if_unset(&win_tun);
-            let _ = Self::delete_for_name(&win_tun, &name_utf16);
+            // 上次异常退出残留的同名网卡
+            if Self::delete_for_name(&win_tun, &name_utf16).is_ok() {
+                log::info!("删除残留网卡 {:?}", name);
+            }
             let mut guid_bytes: [u8; 16] = [0u8; 16];
             rand::thread_rng().fill(&mut guid_bytes);
             let guid = u128::from_ne_bytes(guid_bytes);
@@ -104,6 +107,8 @@ impl Device {
             let session = win_tun.WintunStartSession(adapter, MAX_RING_CAPACITY);
             if session.is_null() {
                 log::error!("session.is_null {:?}", io::Error::last_os_error());
+                // 创建一半的网卡要删除，否则下次启动会冲突
+                win_tun.WintunCloseAdapter(adapter);
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
                     "WintunStartSession failed",
@@ -116,7 +121,17 @@ impl Device {
             let read_event = win_tun.WintunGetReadWaitEvent(session) as winnt::HANDLE;
             let mut luid: wintun_raw::NET_LUID = std::mem::zeroed();
             win_tun.WintunGetAdapterLUID(adapter, &mut luid as *mut wintun_raw::NET_LUID);
-            let index = ffi::luid_to_index(&std::mem::transmute(luid)).map(|index| index as u32)?;
+            let index = match ffi::luid_to_index(&std::mem::transmute(luid)) {
+                Ok(index) => index as u32,
+                Err(e) => {
+                    if let Err(e) = ffi::close_handle(shutdown_event) {
+                        log::warn!("close shutdown_event={:?}", e)
+                    }
+                    win_tun.WintunEndSession(session);
+                    win_tun.WintunCloseAdapter(adapter);
+                    return Err(e);
+                }
+            };
             // 设置网卡跃点
             if let Err(e) = netsh::set_interface_metric(index, 0) {
                 log::warn!("{:?}", e);
@@ -138,7 +153,7 @@ impl Device {
     ) -> io::Result<()> {
         let adapter = win_tun.WintunOpenAdapter(name_utf16.as_ptr());
         if adapter.is_null() {
-            log::error!(
+            log::debug!(
                 "delete_for_name adapter.is_null {:?}",
                 io::Error::last_os_error()
             );